@@ -0,0 +1,81 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+// Track the throughput of metadata requests handled by the server, with replies written to
+// /dev/null so the fuse device doesn't get in the way.
+
+use std::fs::OpenOptions;
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use fuse_backend_rs::abi::fuse_abi::{GetattrIn, InHeader, Opcode, ROOT_ID};
+use fuse_backend_rs::api::server::Server;
+use fuse_backend_rs::passthrough::{Config, PassthroughFs};
+use fuse_backend_rs::transport::{FuseBuf, FuseDevWriter, Reader};
+use vm_memory::ByteValued;
+use vmm_sys_util::tempdir::TempDir;
+
+fn request(opcode: Opcode, nodeid: u64, body: &[u8]) -> Vec<u8> {
+    let in_header = InHeader {
+        len: (size_of::<InHeader>() + body.len()) as u32,
+        opcode: opcode as u32,
+        unique: 2,
+        nodeid,
+        ..Default::default()
+    };
+    let mut buf = in_header.as_slice().to_vec();
+    buf.extend_from_slice(body);
+    buf
+}
+
+fn metadata(c: &mut Criterion) {
+    let source = TempDir::new().unwrap();
+    std::fs::write(source.as_path().join("a"), b"a").unwrap();
+    let server = |tweak: fn(&mut Config)| {
+        let mut cfg = Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        tweak(&mut cfg);
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        fs.import().unwrap();
+        Server::new(fs)
+    };
+    let dev = OpenOptions::new().write(true).open("/dev/null").unwrap();
+
+    let lookup = request(Opcode::Lookup, ROOT_ID, b"a\0");
+    let getattr = request(Opcode::Getattr, ROOT_ID, GetattrIn::default().as_slice());
+    let mut r_buf = Vec::with_capacity(256);
+    let mut w_buf = vec![0u8; 256];
+    let mut send = |server: &Server<PassthroughFs>, req: &[u8]| {
+        r_buf.clear();
+        r_buf.extend_from_slice(req);
+        let r = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut r_buf)).unwrap();
+        let w = FuseDevWriter::<()>::new(dev.as_raw_fd(), &mut w_buf)
+            .unwrap()
+            .into();
+        server.handle_message(r, w, None, None).unwrap();
+    };
+
+    let mut group = c.benchmark_group("metadata");
+    group.throughput(Throughput::Elements(1));
+    let plain = server(|_| {});
+    group.bench_function("lookup", |b| b.iter(|| send(&plain, &lookup)));
+    group.bench_function("getattr", |b| b.iter(|| send(&plain, &getattr)));
+    // Directories referenced by file handles are opened again by each lookup in them, unless
+    // they're kept open by the directory fd cache.
+    let handles = server(|cfg| cfg.inode_file_handles = true);
+    group.bench_function("lookup_file_handles", |b| {
+        b.iter(|| send(&handles, &lookup))
+    });
+    let cached = server(|cfg| {
+        cfg.inode_file_handles = true;
+        cfg.dir_fd_cache_size = 64;
+    });
+    group.bench_function("lookup_dir_fd_cache", |b| b.iter(|| send(&cached, &lookup)));
+    group.finish();
+}
+
+criterion_group!(benches, metadata);
+criterion_main!(benches);
@@ -0,0 +1,112 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+// Compare 128K random reads served by the synchronous server and by the asynchronous one on
+// io_uring, with replies written to /dev/null so the fuse device doesn't get in the way.
+
+use std::ffi::CString;
+use std::fs::OpenOptions;
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::time::Instant;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use fuse_backend_rs::abi::fuse_abi::{InHeader, Opcode, OutHeader, ReadIn, ROOT_ID};
+use fuse_backend_rs::api::filesystem::{Context, FileSystem};
+use fuse_backend_rs::api::server::Server;
+use fuse_backend_rs::passthrough::{Config, PassthroughFs};
+use fuse_backend_rs::transport::{FuseBuf, FuseDevWriter, Reader};
+use vm_memory::ByteValued;
+use vmm_sys_util::tempdir::TempDir;
+
+const FILE_SIZE: u64 = 64 << 20;
+const READ_SIZE: u32 = 128 << 10;
+
+// Build read requests of handle `fh` of `nodeid` at pseudo random offsets aligned to 4K.
+fn requests(nodeid: u64, fh: u64, count: usize) -> Vec<Vec<u8>> {
+    let mut seed = 0x2545_f491_4f6c_dd1du64;
+    (0..count)
+        .map(|_| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            let offset = ((seed >> 33) % ((FILE_SIZE - READ_SIZE as u64) >> 12)) << 12;
+            let read_in = ReadIn {
+                fh,
+                offset,
+                size: READ_SIZE,
+                ..Default::default()
+            };
+            let in_header = InHeader {
+                len: (size_of::<InHeader>() + size_of::<ReadIn>()) as u32,
+                opcode: Opcode::Read as u32,
+                unique: 2,
+                nodeid,
+                ..Default::default()
+            };
+            let mut buf = in_header.as_slice().to_vec();
+            buf.extend_from_slice(read_in.as_slice());
+            buf
+        })
+        .collect()
+}
+
+fn read(c: &mut Criterion) {
+    let source = TempDir::new().unwrap();
+    let data: Vec<u8> = (0..FILE_SIZE).map(|i| (i % 251) as u8).collect();
+    std::fs::write(source.as_path().join("a"), data).unwrap();
+    let cfg = Config {
+        root_dir: source.as_path().to_str().unwrap().to_string(),
+        ..Default::default()
+    };
+    let fs = PassthroughFs::<()>::new(cfg).unwrap();
+    fs.import().unwrap();
+    let ctx = Context::default();
+    let entry = fs
+        .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+        .unwrap();
+    let (fh, _) = fs
+        .open(&ctx, entry.inode, libc::O_RDONLY as u32, 0)
+        .unwrap();
+    let server = Server::new(fs);
+    let dev = OpenOptions::new().write(true).open("/dev/null").unwrap();
+
+    let reqs = requests(entry.inode, fh.unwrap(), 1024);
+    let mut r_buf = Vec::with_capacity(256);
+    let mut w_buf = vec![0u8; size_of::<OutHeader>() + READ_SIZE as usize];
+
+    let mut group = c.benchmark_group("read");
+    group.throughput(Throughput::Bytes(READ_SIZE as u64));
+    group.bench_function("sync_128k_random", |b| {
+        let mut next = reqs.iter().cycle();
+        b.iter(|| {
+            r_buf.clear();
+            r_buf.extend_from_slice(next.next().unwrap());
+            let r = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut r_buf)).unwrap();
+            let w = FuseDevWriter::<()>::new(dev.as_raw_fd(), &mut w_buf)
+                .unwrap()
+                .into();
+            server.handle_message(r, w, None, None).unwrap();
+        })
+    });
+    group.bench_function("async_uring_128k_random", |b| {
+        let mut next = reqs.iter().cycle();
+        b.iter_custom(|iters| {
+            tokio_uring::start(async {
+                let start = Instant::now();
+                for _ in 0..iters {
+                    r_buf.clear();
+                    r_buf.extend_from_slice(next.next().unwrap());
+                    let r = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut r_buf)).unwrap();
+                    let w = FuseDevWriter::<()>::new(dev.as_raw_fd(), &mut w_buf)
+                        .unwrap()
+                        .into();
+                    unsafe { server.async_handle_message(r, w, None, None).await }.unwrap();
+                }
+                start.elapsed()
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, read);
+criterion_main!(benches);
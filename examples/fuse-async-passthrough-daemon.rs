@@ -0,0 +1,101 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A single-threaded fusedev daemon serving a passthrough file system on a tokio-uring runtime.
+//!
+//! Run with `cargo run --example fuse-async-passthrough-daemon --features async-io -- <source>
+//! <mountpoint>`. Requests are handled concurrently by the asynchronous server, on the thread
+//! running the runtime. Send `SIGINT` or `SIGTERM` to stop.
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process;
+
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+use tokio::io::unix::AsyncFd;
+
+use fuse_backend_rs::api::server::Server;
+use fuse_backend_rs::passthrough::{Config, PassthroughFs};
+use fuse_backend_rs::transport::{AsyncFuseSession, FuseSession};
+
+// Wait for `SIGINT` or `SIGTERM` without blocking the runtime.
+async fn wait_signal(sfd: SignalFd) {
+    let sfd = match AsyncFd::new(sfd) {
+        Ok(sfd) => sfd,
+        Err(e) => {
+            log::error!("failed to register signalfd: {}", e);
+            return;
+        }
+    };
+    loop {
+        let mut guard = match sfd.readable().await {
+            Ok(guard) => guard,
+            Err(e) => {
+                log::error!("failed to poll signalfd: {}", e);
+                return;
+            }
+        };
+        // Reading a signal takes `&mut SignalFd`, so read the raw fd instead.
+        let mut info = [0u8; 128];
+        match guard
+            .try_io(|fd| nix::unistd::read(fd.as_raw_fd(), &mut info).map_err(io::Error::from))
+        {
+            Ok(Ok(_)) => {
+                log::info!("received signal, stopping");
+                return;
+            }
+            Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => {}
+            Ok(Err(e)) => {
+                log::error!("failed to read signals: {}", e);
+                return;
+            }
+            Err(_would_block) => {}
+        }
+    }
+}
+
+fn serve(source: &str, mountpoint: &str) -> io::Result<()> {
+    let cfg = Config {
+        root_dir: source.to_string(),
+        do_import: true,
+        ..Default::default()
+    };
+    let fs = PassthroughFs::<()>::new(cfg)?;
+    let server = Server::new(fs);
+
+    let mut mask = SigSet::empty();
+    mask.add(Signal::SIGINT);
+    mask.add(Signal::SIGTERM);
+    mask.thread_block()?;
+    let sfd = SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC)?;
+
+    let mut session = FuseSession::new(Path::new(mountpoint), "passthrough", "", false)
+        .map_err(io::Error::other)?;
+    session.mount().map_err(io::Error::other)?;
+    let mut session = AsyncFuseSession::new(session);
+
+    let res = tokio_uring::start(session.run(&server, wait_signal(sfd)));
+    session.session_mut().umount().map_err(io::Error::other)?;
+    res.map_err(io::Error::other)
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <source> <mountpoint>", args[0]);
+        process::exit(1);
+    }
+
+    stderrlog::new()
+        .timestamp(stderrlog::Timestamp::Millisecond)
+        .verbosity(2)
+        .init()
+        .unwrap();
+
+    if let Err(e) = serve(&args[1], &args[2]) {
+        eprintln!("daemon failed: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,51 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A multi-threaded fusedev daemon serving passthrough backends, configured by a TOML file.
+//!
+//! Run with `cargo run --example fuse-passthrough-daemon --features daemon -- -c daemon.toml`,
+//! see the documentation of the `daemon` module for the format of the configuration file.
+//! Send `SIGUSR1` to log a snapshot of the metrics, and `SIGINT` or `SIGTERM` to stop.
+
+use std::process;
+
+use fuse_backend_rs::daemon::{Daemon, DaemonConfig};
+
+fn usage(prog: &str) -> ! {
+    eprintln!("usage: {} -c <config.toml> [-v]...", prog);
+    process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let mut config = None;
+    let mut verbosity = 2;
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-c" | "--config" => config = iter.next().cloned(),
+            "-v" => verbosity += 1,
+            _ => usage(&args[0]),
+        }
+    }
+    let config = config.unwrap_or_else(|| usage(&args[0]));
+
+    stderrlog::new()
+        .timestamp(stderrlog::Timestamp::Millisecond)
+        .verbosity(verbosity)
+        .init()
+        .unwrap();
+
+    let cfg = match DaemonConfig::load(&config) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            eprintln!("failed to load {}: {}", config, e);
+            process::exit(1);
+        }
+    };
+    let res = Daemon::new(cfg).and_then(|mut daemon| daemon.run());
+    if let Err(e) = res {
+        eprintln!("daemon failed: {}", e);
+        process::exit(1);
+    }
+}
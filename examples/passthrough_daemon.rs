@@ -0,0 +1,73 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A multi-threaded fusedev daemon sharing a host directory, with the basic options of virtiofsd.
+//!
+//! Run with `cargo run --example passthrough_daemon --features daemon -- --shared-dir
+//! /path/to/shared --mountpoint /mnt`. The options negotiated with the kernel are logged at
+//! INIT. Send `SIGUSR1` to log a snapshot of the metrics, and `SIGINT` or `SIGTERM` to stop: the
+//! in-flight requests are drained and the file system is umounted before exiting.
+
+use std::path::PathBuf;
+use std::process;
+
+use fuse_backend_rs::daemon::{BackendConfig, Daemon, DaemonConfig};
+use fuse_backend_rs::passthrough::CachePolicy;
+
+fn usage(prog: &str) -> ! {
+    eprintln!(
+        "usage: {} --shared-dir <dir> --mountpoint <dir> [--thread-pool-size <threads>] \
+         [--cache <never|auto|always>] [--xattr] [--writeback] [--readonly] [-v]...",
+        prog
+    );
+    process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let (mut shared_dir, mut mountpoint) = (None, None);
+    let mut backend = BackendConfig {
+        path: String::from("/"),
+        source: String::new(),
+        xattr: false,
+        writeback: false,
+        cache: CachePolicy::Auto,
+    };
+    let mut cfg = DaemonConfig::default();
+    let mut verbosity = 2;
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-s" | "--shared-dir" => shared_dir = iter.next().cloned(),
+            "-m" | "--mountpoint" => mountpoint = iter.next().cloned(),
+            "-t" | "--thread-pool-size" => match iter.next().and_then(|t| t.parse().ok()) {
+                Some(t) if t > 0 => cfg.threads = t,
+                _ => usage(&args[0]),
+            },
+            "-c" | "--cache" => match iter.next().and_then(|c| c.parse().ok()) {
+                Some(c) => backend.cache = c,
+                None => usage(&args[0]),
+            },
+            "--xattr" => backend.xattr = true,
+            "--writeback" => backend.writeback = true,
+            "--readonly" => cfg.readonly = true,
+            "-v" => verbosity += 1,
+            _ => usage(&args[0]),
+        }
+    }
+    backend.source = shared_dir.unwrap_or_else(|| usage(&args[0]));
+    cfg.mountpoint = PathBuf::from(mountpoint.unwrap_or_else(|| usage(&args[0])));
+    cfg.backends.push(backend);
+
+    stderrlog::new()
+        .timestamp(stderrlog::Timestamp::Millisecond)
+        .verbosity(verbosity)
+        .init()
+        .unwrap();
+
+    let res = Daemon::new(cfg).and_then(|mut daemon| daemon.run());
+    if let Err(e) = res {
+        eprintln!("passthrough daemon failed: {}", e);
+        process::exit(1);
+    }
+}
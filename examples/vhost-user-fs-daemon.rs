@@ -0,0 +1,81 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A vhost-user-fs daemon sharing a host directory with a virtual machine.
+//!
+//! Run with `cargo run --example vhost-user-fs-daemon --features vhost-user-backend -- -s
+//! /tmp/vhost-fs.sock -d /path/to/shared`, then start QEMU with:
+//!
+//! ```text
+//! -chardev socket,id=char0,path=/tmp/vhost-fs.sock
+//! -device vhost-user-fs-pci,queue-size=1024,chardev=char0,tag=myfs
+//! -object memory-backend-memfd,id=mem,size=4G,share=on -numa node,memdev=mem
+//! ```
+//!
+//! and mount the directory in the guest with `mount -t virtiofs myfs /mnt`. The daemon exits when
+//! QEMU disconnects.
+
+use std::process;
+use std::sync::Arc;
+
+use fuse_backend_rs::api::server::Server;
+use fuse_backend_rs::passthrough::{Config, PassthroughFs};
+use fuse_backend_rs::transport::VhostUserFsBackend;
+
+fn usage(prog: &str) -> ! {
+    eprintln!(
+        "usage: {} -s <socket> -d <shared dir> [-t <tag>] [-q <request queues>] [-v]...",
+        prog
+    );
+    process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let (mut socket, mut shared_dir, mut tag) = (None, None, None);
+    let mut queues = 1;
+    let mut verbosity = 2;
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-s" | "--socket" => socket = iter.next().cloned(),
+            "-d" | "--shared-dir" => shared_dir = iter.next().cloned(),
+            "-t" | "--tag" => tag = iter.next().cloned(),
+            "-q" | "--queues" => match iter.next().and_then(|q| q.parse().ok()) {
+                Some(q) => queues = q,
+                None => usage(&args[0]),
+            },
+            "-v" => verbosity += 1,
+            _ => usage(&args[0]),
+        }
+    }
+    let socket = socket.unwrap_or_else(|| usage(&args[0]));
+    let shared_dir = shared_dir.unwrap_or_else(|| usage(&args[0]));
+
+    stderrlog::new()
+        .timestamp(stderrlog::Timestamp::Millisecond)
+        .verbosity(verbosity)
+        .init()
+        .unwrap();
+
+    let cfg = Config {
+        root_dir: shared_dir,
+        do_import: true,
+        xattr: true,
+        ..Default::default()
+    };
+    let res = PassthroughFs::<()>::new(cfg)
+        .and_then(|fs| fs.import().map(|_| fs))
+        .and_then(|fs| {
+            let mut backend = VhostUserFsBackend::<_, ()>::new(Arc::new(Server::new(fs)))
+                .with_request_queues(queues);
+            if let Some(tag) = tag {
+                backend = backend.with_tag(&tag)?;
+            }
+            backend.serve(&socket)
+        });
+    if let Err(e) = res {
+        eprintln!("vhost-user-fs daemon failed: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,110 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A vhost-user-fs daemon sharing a host directory, with the basic options of virtiofsd.
+//!
+//! Run with `cargo run --example virtiofs_daemon --features vhost-user-backend -- --socket-path
+//! /tmp/vhost-fs.sock --shared-dir /path/to/shared`, then start the VMM as documented by the
+//! `vhost-user-fs-daemon` example. Each request queue is served by its own thread, so
+//! `--thread-pool-size` sets the number of request queues, which must not be more than the
+//! number of queues of the device in the VMM.
+//!
+//! The options negotiated with the guest are logged at INIT. The daemon exits when the VMM
+//! disconnects, or on `SIGINT` or `SIGTERM`, removing the socket.
+
+use std::process;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+
+use fuse_backend_rs::api::server::Server;
+use fuse_backend_rs::passthrough::{CachePolicy, Config, PassthroughFs};
+use fuse_backend_rs::transport::VhostUserFsBackend;
+use nix::sys::signal::{SigSet, Signal};
+
+fn usage(prog: &str) -> ! {
+    eprintln!(
+        "usage: {} --socket-path <socket> --shared-dir <dir> [--thread-pool-size <threads>] \
+         [--cache <never|auto|always>] [--xattr] [--writeback] [--tag <tag>] [-v]...",
+        prog
+    );
+    process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let (mut socket, mut shared_dir, mut tag) = (None, None, None);
+    let mut threads = 1;
+    let mut cfg = Config {
+        do_import: true,
+        ..Default::default()
+    };
+    let mut verbosity = 2;
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-s" | "--socket-path" => socket = iter.next().cloned(),
+            "-d" | "--shared-dir" => shared_dir = iter.next().cloned(),
+            "-t" | "--thread-pool-size" => match iter.next().and_then(|t| t.parse().ok()) {
+                Some(t) if t > 0 => threads = t,
+                _ => usage(&args[0]),
+            },
+            "-c" | "--cache" => match iter.next().and_then(|c| c.parse::<CachePolicy>().ok()) {
+                Some(c) => cfg.cache_policy = c,
+                None => usage(&args[0]),
+            },
+            "--xattr" => cfg.xattr = true,
+            "--writeback" => cfg.writeback = true,
+            "--tag" => tag = iter.next().cloned(),
+            "-v" => verbosity += 1,
+            _ => usage(&args[0]),
+        }
+    }
+    let socket = socket.unwrap_or_else(|| usage(&args[0]));
+    cfg.root_dir = shared_dir.unwrap_or_else(|| usage(&args[0]));
+
+    stderrlog::new()
+        .timestamp(stderrlog::Timestamp::Millisecond)
+        .verbosity(verbosity)
+        .init()
+        .unwrap();
+
+    // Block the signals before spawning any thread, so they are only delivered to `sigwait`.
+    let mut mask = SigSet::empty();
+    mask.add(Signal::SIGINT);
+    mask.add(Signal::SIGTERM);
+    if let Err(e) = mask.thread_block() {
+        eprintln!("failed to block signals: {}", e);
+        process::exit(1);
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let serve_tx = tx.clone();
+    let serve_socket = socket.clone();
+    thread::spawn(move || {
+        let res = PassthroughFs::<()>::new(cfg)
+            .and_then(|fs| fs.import().map(|_| fs))
+            .and_then(|fs| {
+                let mut backend = VhostUserFsBackend::<_, ()>::new(Arc::new(Server::new(fs)))
+                    .with_request_queues(threads);
+                if let Some(tag) = tag {
+                    backend = backend.with_tag(&tag)?;
+                }
+                backend.serve(&serve_socket)
+            });
+        let _ = serve_tx.send(res);
+    });
+    thread::spawn(move || {
+        if let Ok(signal) = mask.wait() {
+            log::info!("virtiofs daemon: received {}, exiting", signal);
+            let _ = tx.send(Ok(()));
+        }
+    });
+
+    let res = rx.recv().unwrap();
+    let _ = std::fs::remove_file(&socket);
+    if let Err(e) = res {
+        eprintln!("virtiofs daemon failed: {}", e);
+        process::exit(1);
+    }
+}
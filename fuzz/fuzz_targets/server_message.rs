@@ -0,0 +1,34 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Feed arbitrary bytes to `Server::handle_message` as a request read from `/dev/fuse`.
+//!
+//! Malformed requests must be failed or dropped, never crash the server. The file system is an
+//! empty `Vfs`, so requests are decoded and answered without touching the host.
+
+#![no_main]
+
+use std::fs::OpenOptions;
+use std::os::unix::io::AsRawFd;
+
+use fuse_backend_rs::api::server::Server;
+use fuse_backend_rs::api::{Vfs, VfsOptions};
+use fuse_backend_rs::transport::{FuseBuf, FuseDevWriter, Reader};
+use lazy_static::lazy_static;
+use libfuzzer_sys::fuzz_target;
+
+lazy_static! {
+    static ref SERVER: Server<Vfs> = Server::new(Vfs::new(VfsOptions::default()));
+    static ref DEV_NULL: std::fs::File = OpenOptions::new().write(true).open("/dev/null").unwrap();
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut r_buf = data.to_vec();
+    let mut w_buf = vec![0u8; 0x2_1000];
+    let r = match Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut r_buf)) {
+        Ok(r) => r,
+        Err(_) => return,
+    };
+    let w = FuseDevWriter::<()>::new(DEV_NULL.as_raw_fd(), &mut w_buf).unwrap();
+    let _ = SERVER.handle_message(r, w.into(), None, None);
+});
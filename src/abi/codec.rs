@@ -0,0 +1,1038 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Decoding and encoding of FUSE messages from and to byte buffers.
+//!
+//! The functions here depend neither on file systems nor on transports, for tools working on the
+//! protocol itself, like proxies recording requests on a transport to replay them on another,
+//! and for protocol-level tests and fuzzing of servers. Requests are decoded into [Request]s,
+//! borrowing names and data from the buffer, and encoded back into the same bytes. Replies are
+//! encoded from an [OutHeader] and the typed structs of their bodies.
+//!
+//! Messages are in the layout of the newest protocol minor version known by this crate, except
+//! for `FUSE_INIT`, whose request is decoded from the shorter body of kernels older than 7.36
+//! too. Lengths are validated: the body of a request must have exactly the size of its structs,
+//! names and data, and names may only be followed by nul characters.
+
+use std::ffi::CStr;
+use std::fmt;
+use std::mem::size_of;
+
+use vm_memory::ByteValued;
+
+use super::fuse_abi::*;
+
+/// Errors of decoding messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The message is shorter than its header, or than the length in its header.
+    Truncated,
+    /// The length in the header of the message is invalid, or its body is larger than the
+    /// structs, names and data of the opcode.
+    InvalidLength,
+    /// A name of the request isn't terminated by a nul character, or is empty.
+    InvalidName,
+    /// The size of the data announced by the request doesn't match its data.
+    SizeMismatch,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Truncated => write!(f, "truncated fuse message"),
+            Error::InvalidLength => write!(f, "invalid length of fuse message"),
+            Error::InvalidName => write!(f, "invalid name in fuse request"),
+            Error::SizeMismatch => write!(f, "data size mismatch in fuse request"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Result of decoding messages.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Body of a request, depending on its opcode.
+///
+/// Fields named `arg` are the fixed-size structs of the body, and names are in the order of the
+/// wire, like the arguments of the matching system calls.
+#[allow(missing_docs)]
+#[derive(Debug, Clone)]
+pub enum Request<'a> {
+    /// `FUSE_LOOKUP` of `name` in the directory of the header.
+    Lookup { name: &'a CStr },
+    /// `FUSE_FORGET` of the inode of the header.
+    Forget(ForgetIn),
+    /// `FUSE_BATCH_FORGET`.
+    BatchForget(Vec<ForgetOne>),
+    /// `FUSE_GETATTR`.
+    Getattr(GetattrIn),
+    /// `FUSE_SETATTR`.
+    Setattr(SetattrIn),
+    /// `FUSE_READLINK`.
+    Readlink,
+    /// `FUSE_SYMLINK` creating `name` pointing to `target`.
+    Symlink { name: &'a CStr, target: &'a CStr },
+    /// `FUSE_MKNOD`.
+    Mknod { arg: MknodIn, name: &'a CStr },
+    /// `FUSE_MKDIR`.
+    Mkdir { arg: MkdirIn, name: &'a CStr },
+    /// `FUSE_UNLINK`.
+    Unlink { name: &'a CStr },
+    /// `FUSE_RMDIR`.
+    Rmdir { name: &'a CStr },
+    /// `FUSE_RENAME`.
+    Rename {
+        arg: RenameIn,
+        name: &'a CStr,
+        newname: &'a CStr,
+    },
+    /// `FUSE_RENAME2`.
+    Rename2 {
+        arg: Rename2In,
+        name: &'a CStr,
+        newname: &'a CStr,
+    },
+    /// `FUSE_LINK` of the inode of the body as `name` in the directory of the header.
+    Link { arg: LinkIn, name: &'a CStr },
+    /// `FUSE_OPEN`.
+    Open(OpenIn),
+    /// `FUSE_OPENDIR`.
+    Opendir(OpenIn),
+    /// `FUSE_READ`.
+    Read(ReadIn),
+    /// `FUSE_READDIR`.
+    Readdir(ReadIn),
+    /// `FUSE_READDIRPLUS`.
+    Readdirplus(ReadIn),
+    /// `FUSE_WRITE` of `data`.
+    Write { arg: WriteIn, data: &'a [u8] },
+    /// `FUSE_STATFS`.
+    Statfs,
+    /// `FUSE_RELEASE`.
+    Release(ReleaseIn),
+    /// `FUSE_RELEASEDIR`.
+    Releasedir(ReleaseIn),
+    /// `FUSE_FSYNC`.
+    Fsync(FsyncIn),
+    /// `FUSE_FSYNCDIR`.
+    Fsyncdir(FsyncIn),
+    /// `FUSE_SETXATTR` of `name` to `value`.
+    Setxattr {
+        arg: SetxattrIn,
+        name: &'a CStr,
+        value: &'a [u8],
+    },
+    /// `FUSE_GETXATTR`.
+    Getxattr { arg: GetxattrIn, name: &'a CStr },
+    /// `FUSE_LISTXATTR`.
+    Listxattr(GetxattrIn),
+    /// `FUSE_REMOVEXATTR`.
+    Removexattr { name: &'a CStr },
+    /// `FUSE_FLUSH`.
+    Flush(FlushIn),
+    /// `FUSE_INIT`, with the second part of the body sent by kernels since 7.36.
+    Init { arg: InitIn, ext: Option<InitIn2> },
+    /// `FUSE_ACCESS`.
+    Access(AccessIn),
+    /// `FUSE_CREATE`.
+    Create { arg: CreateIn, name: &'a CStr },
+    /// `FUSE_TMPFILE`.
+    Tmpfile { arg: CreateIn, name: &'a CStr },
+    /// `FUSE_ATOMIC_OPEN`, an extension of patched kernels.
+    AtomicOpen { arg: CreateIn, name: &'a CStr },
+    /// `FUSE_INTERRUPT`.
+    Interrupt(InterruptIn),
+    /// `FUSE_DESTROY`.
+    Destroy,
+    /// `FUSE_GETLK`.
+    Getlk(LkIn),
+    /// `FUSE_SETLK`.
+    Setlk(LkIn),
+    /// `FUSE_SETLKW`.
+    Setlkw(LkIn),
+    /// `FUSE_FALLOCATE`.
+    Fallocate(FallocateIn),
+    /// `FUSE_LSEEK`.
+    Lseek(LseekIn),
+    /// `FUSE_COPY_FILE_RANGE`.
+    CopyFileRange(CopyFileRangeIn),
+    /// `FUSE_SYNCFS`.
+    Syncfs(SyncfsIn),
+    /// `FUSE_STATX`.
+    Statx(StatxIn),
+    /// Requests of other opcodes, with their undecoded body.
+    Other { opcode: u32, body: &'a [u8] },
+}
+
+/// A request with its header.
+#[derive(Debug, Clone)]
+pub struct RequestMessage<'a> {
+    /// Header of the request. Its length and the length of its extensions are the ones of the
+    /// message it was decoded from, and are ignored when encoding.
+    pub header: InHeader,
+    /// Body of the request.
+    pub request: Request<'a>,
+    /// Extensions following the body, like supplementary groups, since 7.38.
+    pub extensions: &'a [u8],
+}
+
+impl Request<'_> {
+    /// Get the opcode of the request.
+    pub fn opcode(&self) -> u32 {
+        let opcode = match self {
+            Request::Lookup { .. } => Opcode::Lookup,
+            Request::Forget(_) => Opcode::Forget,
+            Request::BatchForget(_) => Opcode::BatchForget,
+            Request::Getattr(_) => Opcode::Getattr,
+            Request::Setattr(_) => Opcode::Setattr,
+            Request::Readlink => Opcode::Readlink,
+            Request::Symlink { .. } => Opcode::Symlink,
+            Request::Mknod { .. } => Opcode::Mknod,
+            Request::Mkdir { .. } => Opcode::Mkdir,
+            Request::Unlink { .. } => Opcode::Unlink,
+            Request::Rmdir { .. } => Opcode::Rmdir,
+            Request::Rename { .. } => Opcode::Rename,
+            Request::Rename2 { .. } => Opcode::Rename2,
+            Request::Link { .. } => Opcode::Link,
+            Request::Open(_) => Opcode::Open,
+            Request::Opendir(_) => Opcode::Opendir,
+            Request::Read(_) => Opcode::Read,
+            Request::Readdir(_) => Opcode::Readdir,
+            Request::Readdirplus(_) => Opcode::Readdirplus,
+            Request::Write { .. } => Opcode::Write,
+            Request::Statfs => Opcode::Statfs,
+            Request::Release(_) => Opcode::Release,
+            Request::Releasedir(_) => Opcode::Releasedir,
+            Request::Fsync(_) => Opcode::Fsync,
+            Request::Fsyncdir(_) => Opcode::Fsyncdir,
+            Request::Setxattr { .. } => Opcode::Setxattr,
+            Request::Getxattr { .. } => Opcode::Getxattr,
+            Request::Listxattr(_) => Opcode::Listxattr,
+            Request::Removexattr { .. } => Opcode::Removexattr,
+            Request::Flush(_) => Opcode::Flush,
+            Request::Init { .. } => Opcode::Init,
+            Request::Access(_) => Opcode::Access,
+            Request::Create { .. } => Opcode::Create,
+            Request::Tmpfile { .. } => Opcode::Tmpfile,
+            Request::AtomicOpen { .. } => Opcode::AtomicOpen,
+            Request::Interrupt(_) => Opcode::Interrupt,
+            Request::Destroy => Opcode::Destroy,
+            Request::Getlk(_) => Opcode::Getlk,
+            Request::Setlk(_) => Opcode::Setlk,
+            Request::Setlkw(_) => Opcode::Setlkw,
+            Request::Fallocate(_) => Opcode::Fallocate,
+            Request::Lseek(_) => Opcode::Lseek,
+            Request::CopyFileRange(_) => Opcode::CopyFileRange,
+            Request::Syncfs(_) => Opcode::Syncfs,
+            Request::Statx(_) => Opcode::Statx,
+            Request::Other { opcode, .. } => return *opcode,
+        };
+        opcode as u32
+    }
+}
+
+/// Decode the first request of `buf`, which may be followed by other messages.
+pub fn decode_request(buf: &[u8]) -> Result<RequestMessage<'_>> {
+    let (header, _) = decode_struct::<InHeader>(buf)?;
+    let len = header.len as usize;
+    if len < size_of::<InHeader>() {
+        return Err(Error::InvalidLength);
+    }
+    if len > buf.len() {
+        return Err(Error::Truncated);
+    }
+    let body = &buf[size_of::<InHeader>()..len];
+    let extlen = header.total_extlen as usize * 8;
+    if extlen > body.len() {
+        return Err(Error::InvalidLength);
+    }
+    let (body, extensions) = body.split_at(body.len() - extlen);
+    let request = decode_body(header.opcode, body)?;
+
+    Ok(RequestMessage {
+        header,
+        request,
+        extensions,
+    })
+}
+
+fn decode_body(opcode: u32, body: &[u8]) -> Result<Request<'_>> {
+    let req = match Opcode::from(opcode) {
+        Opcode::Lookup => Request::Lookup {
+            name: decode_name(body)?,
+        },
+        Opcode::Forget => Request::Forget(decode_exact(body)?),
+        Opcode::BatchForget => {
+            let (arg, mut rest) = decode_struct::<BatchForgetIn>(body)?;
+            let size = (arg.count as usize)
+                .checked_mul(size_of::<ForgetOne>())
+                .ok_or(Error::SizeMismatch)?;
+            if size != rest.len() {
+                return Err(Error::SizeMismatch);
+            }
+            let mut nodes = Vec::with_capacity(arg.count as usize);
+            while !rest.is_empty() {
+                let (node, next) = decode_struct::<ForgetOne>(rest)?;
+                nodes.push(node);
+                rest = next;
+            }
+            Request::BatchForget(nodes)
+        }
+        Opcode::Getattr => Request::Getattr(decode_exact(body)?),
+        Opcode::Setattr => Request::Setattr(decode_exact(body)?),
+        Opcode::Readlink => decode_empty(body, Request::Readlink)?,
+        Opcode::Symlink => {
+            let (name, target) = decode_two_names(body)?;
+            Request::Symlink { name, target }
+        }
+        Opcode::Mknod => {
+            let (arg, rest) = decode_struct(body)?;
+            Request::Mknod {
+                arg,
+                name: decode_name(rest)?,
+            }
+        }
+        Opcode::Mkdir => {
+            let (arg, rest) = decode_struct(body)?;
+            Request::Mkdir {
+                arg,
+                name: decode_name(rest)?,
+            }
+        }
+        Opcode::Unlink => Request::Unlink {
+            name: decode_name(body)?,
+        },
+        Opcode::Rmdir => Request::Rmdir {
+            name: decode_name(body)?,
+        },
+        Opcode::Rename => {
+            let (arg, rest) = decode_struct(body)?;
+            let (name, newname) = decode_two_names(rest)?;
+            Request::Rename { arg, name, newname }
+        }
+        Opcode::Rename2 => {
+            let (arg, rest) = decode_struct(body)?;
+            let (name, newname) = decode_two_names(rest)?;
+            Request::Rename2 { arg, name, newname }
+        }
+        Opcode::Link => {
+            let (arg, rest) = decode_struct(body)?;
+            Request::Link {
+                arg,
+                name: decode_name(rest)?,
+            }
+        }
+        Opcode::Open => Request::Open(decode_exact(body)?),
+        Opcode::Opendir => Request::Opendir(decode_exact(body)?),
+        Opcode::Read => Request::Read(decode_exact(body)?),
+        Opcode::Readdir => Request::Readdir(decode_exact(body)?),
+        Opcode::Readdirplus => Request::Readdirplus(decode_exact(body)?),
+        Opcode::Write => {
+            let (arg, data) = decode_struct::<WriteIn>(body)?;
+            if arg.size as usize != data.len() {
+                return Err(Error::SizeMismatch);
+            }
+            Request::Write { arg, data }
+        }
+        Opcode::Statfs => decode_empty(body, Request::Statfs)?,
+        Opcode::Release => Request::Release(decode_exact(body)?),
+        Opcode::Releasedir => Request::Releasedir(decode_exact(body)?),
+        Opcode::Fsync => Request::Fsync(decode_exact(body)?),
+        Opcode::Fsyncdir => Request::Fsyncdir(decode_exact(body)?),
+        Opcode::Setxattr => {
+            let (arg, rest) = decode_struct::<SetxattrIn>(body)?;
+            let pos = rest
+                .iter()
+                .position(|c| *c == 0)
+                .ok_or(Error::InvalidName)?;
+            let (name, value) = rest.split_at(pos + 1);
+            if arg.size as usize != value.len() {
+                return Err(Error::SizeMismatch);
+            }
+            Request::Setxattr {
+                arg,
+                name: decode_name(name)?,
+                value,
+            }
+        }
+        Opcode::Getxattr => {
+            let (arg, rest) = decode_struct(body)?;
+            Request::Getxattr {
+                arg,
+                name: decode_name(rest)?,
+            }
+        }
+        Opcode::Listxattr => Request::Listxattr(decode_exact(body)?),
+        Opcode::Removexattr => Request::Removexattr {
+            name: decode_name(body)?,
+        },
+        Opcode::Flush => Request::Flush(decode_exact(body)?),
+        Opcode::Init => {
+            // Kernels may know larger bodies than this crate, ignore the unknown part.
+            let (arg, rest) = decode_struct(body)?;
+            let ext = match decode_struct::<InitIn2>(rest) {
+                Ok((ext, _)) => Some(ext),
+                Err(_) if rest.is_empty() => None,
+                Err(e) => return Err(e),
+            };
+            Request::Init { arg, ext }
+        }
+        Opcode::Access => Request::Access(decode_exact(body)?),
+        Opcode::Create => {
+            let (arg, rest) = decode_struct(body)?;
+            Request::Create {
+                arg,
+                name: decode_name(rest)?,
+            }
+        }
+        Opcode::Tmpfile => {
+            let (arg, rest) = decode_struct(body)?;
+            Request::Tmpfile {
+                arg,
+                name: decode_name(rest)?,
+            }
+        }
+        Opcode::AtomicOpen => {
+            let (arg, rest) = decode_struct(body)?;
+            Request::AtomicOpen {
+                arg,
+                name: decode_name(rest)?,
+            }
+        }
+        Opcode::Interrupt => Request::Interrupt(decode_exact(body)?),
+        Opcode::Destroy => decode_empty(body, Request::Destroy)?,
+        Opcode::Getlk => Request::Getlk(decode_exact(body)?),
+        Opcode::Setlk => Request::Setlk(decode_exact(body)?),
+        Opcode::Setlkw => Request::Setlkw(decode_exact(body)?),
+        Opcode::Fallocate => Request::Fallocate(decode_exact(body)?),
+        Opcode::Lseek => Request::Lseek(decode_exact(body)?),
+        Opcode::CopyFileRange => Request::CopyFileRange(decode_exact(body)?),
+        Opcode::Syncfs => Request::Syncfs(decode_exact(body)?),
+        Opcode::Statx => Request::Statx(decode_exact(body)?),
+        _ => Request::Other { opcode, body },
+    };
+
+    Ok(req)
+}
+
+/// Encode `msg`, with the length and the length of extensions of its header computed from its
+/// body and extensions.
+///
+/// The length of extensions must be a multiple of 8 bytes.
+pub fn encode_request(msg: &RequestMessage) -> Vec<u8> {
+    let mut buf = Vec::new();
+    push_struct(&mut buf, &msg.header);
+    match &msg.request {
+        Request::Lookup { name } | Request::Unlink { name } | Request::Rmdir { name } => {
+            push_name(&mut buf, name)
+        }
+        Request::Removexattr { name } => push_name(&mut buf, name),
+        Request::Forget(arg) => push_struct(&mut buf, arg),
+        Request::BatchForget(nodes) => {
+            let arg = BatchForgetIn {
+                count: nodes.len() as u32,
+                dummy: 0,
+            };
+            push_struct(&mut buf, &arg);
+            for node in nodes {
+                push_struct(&mut buf, node);
+            }
+        }
+        Request::Getattr(arg) => push_struct(&mut buf, arg),
+        Request::Setattr(arg) => push_struct(&mut buf, arg),
+        Request::Readlink | Request::Statfs | Request::Destroy => {}
+        Request::Symlink { name, target } => {
+            push_name(&mut buf, name);
+            push_name(&mut buf, target);
+        }
+        Request::Mknod { arg, name } => {
+            push_struct(&mut buf, arg);
+            push_name(&mut buf, name);
+        }
+        Request::Mkdir { arg, name } => {
+            push_struct(&mut buf, arg);
+            push_name(&mut buf, name);
+        }
+        Request::Rename { arg, name, newname } => {
+            push_struct(&mut buf, arg);
+            push_name(&mut buf, name);
+            push_name(&mut buf, newname);
+        }
+        Request::Rename2 { arg, name, newname } => {
+            push_struct(&mut buf, arg);
+            push_name(&mut buf, name);
+            push_name(&mut buf, newname);
+        }
+        Request::Link { arg, name } => {
+            push_struct(&mut buf, arg);
+            push_name(&mut buf, name);
+        }
+        Request::Open(arg) | Request::Opendir(arg) => push_struct(&mut buf, arg),
+        Request::Read(arg) | Request::Readdir(arg) | Request::Readdirplus(arg) => {
+            push_struct(&mut buf, arg)
+        }
+        Request::Write { arg, data } => {
+            push_struct(&mut buf, arg);
+            buf.extend_from_slice(data);
+        }
+        Request::Release(arg) | Request::Releasedir(arg) => push_struct(&mut buf, arg),
+        Request::Fsync(arg) | Request::Fsyncdir(arg) => push_struct(&mut buf, arg),
+        Request::Setxattr { arg, name, value } => {
+            push_struct(&mut buf, arg);
+            push_name(&mut buf, name);
+            buf.extend_from_slice(value);
+        }
+        Request::Getxattr { arg, name } => {
+            push_struct(&mut buf, arg);
+            push_name(&mut buf, name);
+        }
+        Request::Listxattr(arg) => push_struct(&mut buf, arg),
+        Request::Flush(arg) => push_struct(&mut buf, arg),
+        Request::Init { arg, ext } => {
+            push_struct(&mut buf, arg);
+            if let Some(ext) = ext {
+                push_struct(&mut buf, ext);
+            }
+        }
+        Request::Access(arg) => push_struct(&mut buf, arg),
+        Request::Create { arg, name }
+        | Request::Tmpfile { arg, name }
+        | Request::AtomicOpen { arg, name } => {
+            push_struct(&mut buf, arg);
+            push_name(&mut buf, name);
+        }
+        Request::Interrupt(arg) => push_struct(&mut buf, arg),
+        Request::Getlk(arg) | Request::Setlk(arg) | Request::Setlkw(arg) => {
+            push_struct(&mut buf, arg)
+        }
+        Request::Fallocate(arg) => push_struct(&mut buf, arg),
+        Request::Lseek(arg) => push_struct(&mut buf, arg),
+        Request::CopyFileRange(arg) => push_struct(&mut buf, arg),
+        Request::Syncfs(arg) => push_struct(&mut buf, arg),
+        Request::Statx(arg) => push_struct(&mut buf, arg),
+        Request::Other { body, .. } => buf.extend_from_slice(body),
+    }
+    buf.extend_from_slice(msg.extensions);
+
+    let header = InHeader {
+        len: buf.len() as u32,
+        opcode: msg.request.opcode(),
+        total_extlen: (msg.extensions.len() / 8) as u16,
+        ..msg.header
+    };
+    buf[..size_of::<InHeader>()].copy_from_slice(header.as_slice());
+    buf
+}
+
+/// Encode a successful reply to request `unique` with a body made of `body` followed by `data`,
+/// like an `EntryOut`, or a `ReadIn` followed by the data read.
+pub fn encode_reply<T: ByteValued>(unique: u64, body: &T, data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(size_of::<OutHeader>() + size_of::<T>() + data.len());
+    push_struct(&mut buf, &OutHeader::default());
+    push_struct(&mut buf, body);
+    buf.extend_from_slice(data);
+    set_out_header(&mut buf, unique, 0);
+    buf
+}
+
+/// Encode a successful reply to request `unique` with only `data` as body, like the data of
+/// `FUSE_READ` or the target of `FUSE_READLINK`.
+pub fn encode_data_reply(unique: u64, data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(size_of::<OutHeader>() + data.len());
+    push_struct(&mut buf, &OutHeader::default());
+    buf.extend_from_slice(data);
+    set_out_header(&mut buf, unique, 0);
+    buf
+}
+
+/// Encode the failure of request `unique` with the positive error number `errno`.
+pub fn encode_error(unique: u64, errno: i32) -> Vec<u8> {
+    let mut buf = OutHeader::default().as_slice().to_vec();
+    set_out_header(&mut buf, unique, -errno);
+    buf
+}
+
+/// Append the directory entry `dirent` named `name` to `buf`, the body of a `FUSE_READDIR` reply,
+/// padded to 8 bytes. The length of the name of `dirent` is ignored.
+pub fn encode_dirent(buf: &mut Vec<u8>, dirent: &Dirent, name: &[u8]) {
+    let dirent = Dirent {
+        namelen: name.len() as u32,
+        ..*dirent
+    };
+    push_struct(buf, &dirent);
+    buf.extend_from_slice(name);
+    pad_dirent(buf);
+}
+
+/// Append the directory entry `dirent` named `name` with its entry to `buf`, the body of a
+/// `FUSE_READDIRPLUS` reply, padded to 8 bytes. The length of the name is ignored.
+pub fn encode_direntplus(buf: &mut Vec<u8>, dirent: &Direntplus, name: &[u8]) {
+    let mut dirent = *dirent;
+    dirent.dirent.namelen = name.len() as u32;
+    push_struct(buf, &dirent);
+    buf.extend_from_slice(name);
+    pad_dirent(buf);
+}
+
+/// Decode the first reply of `buf`, which may be followed by other messages, returning its
+/// header and body.
+pub fn decode_reply(buf: &[u8]) -> Result<(OutHeader, &[u8])> {
+    let (header, _) = decode_struct::<OutHeader>(buf)?;
+    let len = header.len as usize;
+    if len < size_of::<OutHeader>() || (header.error != 0 && len != size_of::<OutHeader>()) {
+        return Err(Error::InvalidLength);
+    }
+    if len > buf.len() {
+        return Err(Error::Truncated);
+    }
+    Ok((header, &buf[size_of::<OutHeader>()..len]))
+}
+
+/// Decode the directory entries of `body`, the body of a `FUSE_READDIR` reply, with their names.
+pub fn decode_dirents(mut body: &[u8]) -> Result<Vec<(Dirent, &[u8])>> {
+    let mut entries = Vec::new();
+    while !body.is_empty() {
+        let (dirent, rest) = decode_struct::<Dirent>(body)?;
+        let (name, rest) = split_dirent_name(rest, dirent.namelen)?;
+        entries.push((dirent, name));
+        body = rest;
+    }
+    Ok(entries)
+}
+
+/// Decode the directory entries of `body`, the body of a `FUSE_READDIRPLUS` reply, with their
+/// names.
+pub fn decode_direntplus(mut body: &[u8]) -> Result<Vec<(Direntplus, &[u8])>> {
+    let mut entries = Vec::new();
+    while !body.is_empty() {
+        let (dirent, rest) = decode_struct::<Direntplus>(body)?;
+        let (name, rest) = split_dirent_name(rest, dirent.dirent.namelen)?;
+        entries.push((dirent, name));
+        body = rest;
+    }
+    Ok(entries)
+}
+
+/// Decode a struct from the start of `buf`, which needs no alignment, returning it with the rest
+/// of `buf`.
+pub fn decode_struct<T: ByteValued + Default>(buf: &[u8]) -> Result<(T, &[u8])> {
+    if buf.len() < size_of::<T>() {
+        return Err(Error::Truncated);
+    }
+    let (head, rest) = buf.split_at(size_of::<T>());
+    let mut obj = T::default();
+    obj.as_mut_slice().copy_from_slice(head);
+    Ok((obj, rest))
+}
+
+/// Decode a non-empty name terminated by a nul character, which may be followed by padding nul
+/// characters only.
+pub fn decode_name(buf: &[u8]) -> Result<&CStr> {
+    match buf.iter().position(|c| *c == 0) {
+        Some(pos) if pos > 0 && buf[pos..].iter().all(|c| *c == 0) => {
+            CStr::from_bytes_with_nul(&buf[..=pos]).map_err(|_| Error::InvalidName)
+        }
+        _ => Err(Error::InvalidName),
+    }
+}
+
+/// Decode two names following each other, the second one like `decode_name()`.
+pub fn decode_two_names(buf: &[u8]) -> Result<(&CStr, &CStr)> {
+    let pos = buf.iter().position(|c| *c == 0).ok_or(Error::InvalidName)?;
+    let first = decode_name(&buf[..=pos])?;
+    let second = decode_name(&buf[pos + 1..])?;
+    Ok((first, second))
+}
+
+// Decode a body made of a single struct.
+fn decode_exact<T: ByteValued + Default>(body: &[u8]) -> Result<T> {
+    match decode_struct(body)? {
+        (obj, []) => Ok(obj),
+        _ => Err(Error::InvalidLength),
+    }
+}
+
+fn decode_empty<'a>(body: &[u8], req: Request<'a>) -> Result<Request<'a>> {
+    if body.is_empty() {
+        Ok(req)
+    } else {
+        Err(Error::InvalidLength)
+    }
+}
+
+// Split the name of `namelen` bytes and its padding off the start of `buf`.
+fn split_dirent_name(buf: &[u8], namelen: u32) -> Result<(&[u8], &[u8])> {
+    let namelen = namelen as usize;
+    let padded = (namelen + 7) & !7;
+    if buf.len() < padded {
+        return Err(Error::Truncated);
+    }
+    Ok((&buf[..namelen], &buf[padded..]))
+}
+
+fn push_struct<T: ByteValued>(buf: &mut Vec<u8>, obj: &T) {
+    buf.extend_from_slice(obj.as_slice());
+}
+
+fn push_name(buf: &mut Vec<u8>, name: &CStr) {
+    buf.extend_from_slice(name.to_bytes_with_nul());
+}
+
+fn pad_dirent(buf: &mut Vec<u8>) {
+    buf.resize((buf.len() + 7) & !7, 0);
+}
+
+fn set_out_header(buf: &mut [u8], unique: u64, error: i32) {
+    let header = OutHeader {
+        len: buf.len() as u32,
+        error,
+        unique,
+    };
+    buf[..size_of::<OutHeader>()].copy_from_slice(header.as_slice());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(unique: u64, nodeid: u64) -> InHeader {
+        InHeader {
+            unique,
+            nodeid,
+            uid: 1000,
+            gid: 1000,
+            pid: 42,
+            ..Default::default()
+        }
+    }
+
+    fn cstr(s: &[u8]) -> &CStr {
+        CStr::from_bytes_with_nul(s).unwrap()
+    }
+
+    // Encode `request`, check it is decoded back into the same bytes and return them.
+    fn round_trip(request: Request) -> Vec<u8> {
+        let msg = RequestMessage {
+            header: header(7, 3),
+            request,
+            extensions: &[],
+        };
+        let buf = encode_request(&msg);
+        let decoded = decode_request(&buf).unwrap();
+        assert_eq!(decoded.header.len as usize, buf.len());
+        assert_eq!(decoded.header.opcode, msg.request.opcode());
+        assert_eq!(decoded.header.unique, 7);
+        assert_eq!(decoded.header.nodeid, 3);
+        assert_eq!(encode_request(&decoded), buf);
+        buf
+    }
+
+    #[test]
+    fn test_request_round_trip() {
+        let name = cstr(b"file\0");
+        let other = cstr(b"other\0");
+        let requests = vec![
+            Request::Lookup { name },
+            Request::Forget(ForgetIn { nlookup: 2 }),
+            Request::BatchForget(vec![
+                ForgetOne {
+                    nodeid: 2,
+                    nlookup: 1,
+                },
+                ForgetOne {
+                    nodeid: 3,
+                    nlookup: 5,
+                },
+            ]),
+            Request::Getattr(GetattrIn::default()),
+            Request::Setattr(SetattrIn::default()),
+            Request::Readlink,
+            Request::Symlink {
+                name,
+                target: other,
+            },
+            Request::Mknod {
+                arg: MknodIn::default(),
+                name,
+            },
+            Request::Mkdir {
+                arg: MkdirIn::default(),
+                name,
+            },
+            Request::Unlink { name },
+            Request::Rmdir { name },
+            Request::Rename {
+                arg: RenameIn { newdir: 4 },
+                name,
+                newname: other,
+            },
+            Request::Rename2 {
+                arg: Rename2In::default(),
+                name,
+                newname: other,
+            },
+            Request::Link {
+                arg: LinkIn { oldnodeid: 5 },
+                name,
+            },
+            Request::Open(OpenIn::default()),
+            Request::Opendir(OpenIn::default()),
+            Request::Read(ReadIn::default()),
+            Request::Readdir(ReadIn::default()),
+            Request::Readdirplus(ReadIn::default()),
+            Request::Write {
+                arg: WriteIn {
+                    size: 4,
+                    ..Default::default()
+                },
+                data: b"data",
+            },
+            Request::Statfs,
+            Request::Release(ReleaseIn::default()),
+            Request::Releasedir(ReleaseIn::default()),
+            Request::Fsync(FsyncIn::default()),
+            Request::Fsyncdir(FsyncIn::default()),
+            Request::Setxattr {
+                arg: SetxattrIn { size: 5, flags: 0 },
+                name: cstr(b"user.a\0"),
+                value: b"va\0ue",
+            },
+            Request::Getxattr {
+                arg: GetxattrIn::default(),
+                name: cstr(b"user.a\0"),
+            },
+            Request::Listxattr(GetxattrIn::default()),
+            Request::Removexattr {
+                name: cstr(b"user.a\0"),
+            },
+            Request::Flush(FlushIn::default()),
+            Request::Init {
+                arg: InitIn::default(),
+                ext: None,
+            },
+            Request::Init {
+                arg: InitIn::default(),
+                ext: Some(InitIn2::default()),
+            },
+            Request::Access(AccessIn::default()),
+            Request::Create {
+                arg: CreateIn::default(),
+                name,
+            },
+            Request::Tmpfile {
+                arg: CreateIn::default(),
+                name: cstr(b"/\0"),
+            },
+            Request::Interrupt(InterruptIn { unique: 6 }),
+            Request::Destroy,
+            Request::Getlk(LkIn::default()),
+            Request::Setlk(LkIn::default()),
+            Request::Setlkw(LkIn::default()),
+            Request::Fallocate(FallocateIn::default()),
+            Request::Lseek(LseekIn::default()),
+            Request::CopyFileRange(CopyFileRangeIn::default()),
+            Request::Syncfs(SyncfsIn::default()),
+            Request::Statx(StatxIn::default()),
+            Request::Other {
+                opcode: 4096,
+                body: b"opaque",
+            },
+        ];
+        for request in requests {
+            round_trip(request);
+        }
+
+        let buf = round_trip(Request::Symlink {
+            name,
+            target: other,
+        });
+        assert_eq!(&buf[size_of::<InHeader>()..], b"file\0other\0");
+        match decode_request(&buf).unwrap().request {
+            Request::Symlink { name, target } => {
+                assert_eq!(name.to_bytes(), b"file");
+                assert_eq!(target.to_bytes(), b"other");
+            }
+            r => panic!("unexpected request {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_decode_request_lengths() {
+        let msg = RequestMessage {
+            header: header(1, 1),
+            request: Request::Getattr(GetattrIn::default()),
+            extensions: &[],
+        };
+        let buf = encode_request(&msg);
+
+        // Messages may be followed by other ones, but not be shorter than their header.
+        let mut longer = buf.clone();
+        longer.extend_from_slice(&[0xff; 16]);
+        assert!(decode_request(&longer).is_ok());
+        assert_eq!(
+            decode_request(&buf[..buf.len() - 1]).unwrap_err(),
+            Error::Truncated
+        );
+        assert_eq!(decode_request(&buf[..20]).unwrap_err(), Error::Truncated);
+
+        let mut bad = buf.clone();
+        bad[..4].copy_from_slice(&8u32.to_ne_bytes());
+        assert_eq!(decode_request(&bad).unwrap_err(), Error::InvalidLength);
+
+        // Fixed-size bodies must be exact.
+        let mut bad = buf.clone();
+        bad.extend_from_slice(&[0; 8]);
+        let len = bad.len() as u32;
+        bad[..4].copy_from_slice(&len.to_ne_bytes());
+        assert_eq!(decode_request(&bad).unwrap_err(), Error::InvalidLength);
+        let mut bad = buf[..buf.len() - 4].to_vec();
+        let len = bad.len() as u32;
+        bad[..4].copy_from_slice(&len.to_ne_bytes());
+        assert_eq!(decode_request(&bad).unwrap_err(), Error::Truncated);
+
+        // Extensions are split off the end of the body.
+        let msg = RequestMessage {
+            extensions: &[1; 16],
+            ..msg
+        };
+        let buf = encode_request(&msg);
+        let decoded = decode_request(&buf).unwrap();
+        assert_eq!(decoded.header.total_extlen, 2);
+        assert_eq!(decoded.extensions, &[1; 16]);
+        assert!(matches!(decoded.request, Request::Getattr(_)));
+        let mut bad = buf;
+        bad[36..38].copy_from_slice(&10u16.to_ne_bytes());
+        assert_eq!(decode_request(&bad).unwrap_err(), Error::InvalidLength);
+    }
+
+    #[test]
+    fn test_decode_request_payloads() {
+        let encode = |opcode: Opcode, body: &[u8]| {
+            let mut buf = InHeader {
+                len: (size_of::<InHeader>() + body.len()) as u32,
+                opcode: opcode as u32,
+                ..Default::default()
+            }
+            .as_slice()
+            .to_vec();
+            buf.extend_from_slice(body);
+            buf
+        };
+
+        // Names may be padded with nul characters, but not empty nor followed by anything else.
+        let buf = encode(Opcode::Lookup, b"name\0\0\0\0");
+        match decode_request(&buf).unwrap().request {
+            Request::Lookup { name } => assert_eq!(name.to_bytes(), b"name"),
+            r => panic!("unexpected request {:?}", r),
+        }
+        for body in [&b"name"[..], b"\0", b"na\0me\0", b""] {
+            let buf = encode(Opcode::Lookup, body);
+            assert_eq!(decode_request(&buf).unwrap_err(), Error::InvalidName);
+        }
+        let buf = encode(Opcode::Rename, b"\0\0\0\0\0\0\0\0a\0");
+        assert_eq!(decode_request(&buf).unwrap_err(), Error::InvalidName);
+
+        // Data must have the size announced by the request.
+        let mut body = WriteIn {
+            size: 8,
+            ..Default::default()
+        }
+        .as_slice()
+        .to_vec();
+        body.extend_from_slice(b"data");
+        let buf = encode(Opcode::Write, &body);
+        assert_eq!(decode_request(&buf).unwrap_err(), Error::SizeMismatch);
+        let mut body = SetxattrIn { size: 2, flags: 0 }.as_slice().to_vec();
+        body.extend_from_slice(b"user.a\0v");
+        let buf = encode(Opcode::Setxattr, &body);
+        assert_eq!(decode_request(&buf).unwrap_err(), Error::SizeMismatch);
+        let mut body = BatchForgetIn { count: 2, dummy: 0 }.as_slice().to_vec();
+        body.extend_from_slice(ForgetOne::default().as_slice());
+        let buf = encode(Opcode::BatchForget, &body);
+        assert_eq!(decode_request(&buf).unwrap_err(), Error::SizeMismatch);
+
+        // Bodies of init larger than known by this crate are accepted, but not partial ones.
+        let mut body = vec![0u8; size_of::<InitIn>() + size_of::<InitIn2>() + 16];
+        body[..4].copy_from_slice(&7u32.to_ne_bytes());
+        let buf = encode(Opcode::Init, &body);
+        match decode_request(&buf).unwrap().request {
+            Request::Init { arg, ext } => {
+                assert_eq!(arg.major, 7);
+                assert!(ext.is_some());
+            }
+            r => panic!("unexpected request {:?}", r),
+        }
+        let buf = encode(Opcode::Init, &body[..size_of::<InitIn>() + 8]);
+        assert_eq!(decode_request(&buf).unwrap_err(), Error::Truncated);
+
+        let buf = encode(Opcode::Statfs, b"x");
+        assert_eq!(decode_request(&buf).unwrap_err(), Error::InvalidLength);
+    }
+
+    #[test]
+    fn test_replies() {
+        let entry = EntryOut {
+            nodeid: 5,
+            generation: 1,
+            ..Default::default()
+        };
+        let buf = encode_reply(9, &entry, &[]);
+        assert_eq!(buf.len(), size_of::<OutHeader>() + size_of::<EntryOut>());
+        let (header, body) = decode_reply(&buf).unwrap();
+        assert_eq!(header.unique, 9);
+        assert_eq!(header.error, 0);
+        let (decoded, rest) = decode_struct::<EntryOut>(body).unwrap();
+        assert_eq!(decoded.nodeid, 5);
+        assert!(rest.is_empty());
+
+        let buf = encode_data_reply(10, b"target");
+        assert_eq!(decode_reply(&buf).unwrap().1, b"target");
+
+        let buf = encode_error(11, libc::ENOENT);
+        let (header, body) = decode_reply(&buf).unwrap();
+        assert_eq!(header.error, -libc::ENOENT);
+        assert_eq!(header.len as usize, size_of::<OutHeader>());
+        assert!(body.is_empty());
+
+        // Errors have no body.
+        let mut bad = encode_data_reply(12, b"data");
+        bad[4..8].copy_from_slice(&(-libc::EIO).to_ne_bytes());
+        assert_eq!(decode_reply(&bad).unwrap_err(), Error::InvalidLength);
+        assert_eq!(decode_reply(&buf[..8]).unwrap_err(), Error::Truncated);
+    }
+
+    #[test]
+    fn test_dirents() {
+        let mut body = Vec::new();
+        for (ino, name) in [(2u64, &b"a"[..]), (3, b"longer_name")] {
+            let dirent = Dirent {
+                ino,
+                off: ino,
+                namelen: 0,
+                type_: libc::DT_REG as u32,
+            };
+            encode_dirent(&mut body, &dirent, name);
+            assert_eq!(body.len() % 8, 0);
+        }
+        let entries = decode_dirents(&body).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].0.ino, 3);
+        assert_eq!(entries[1].0.namelen, 11);
+        assert_eq!(entries[1].1, b"longer_name");
+        assert_eq!(
+            decode_dirents(&body[..body.len() - 8]).unwrap_err(),
+            Error::Truncated
+        );
+
+        let mut body = Vec::new();
+        let mut dirent = Direntplus::default();
+        dirent.entry_out.nodeid = 4;
+        dirent.dirent.ino = 4;
+        encode_direntplus(&mut body, &dirent, b"dir");
+        let entries = decode_direntplus(&body).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0.entry_out.nodeid, 4);
+        assert_eq!(entries[0].1, b"dir");
+    }
+}
@@ -29,10 +29,14 @@ pub const KERNEL_VERSION: u32 = 7;
 
 /// Minor version number of this interface.
 #[cfg(target_os = "linux")]
-pub const KERNEL_MINOR_VERSION: u32 = 33;
+pub const KERNEL_MINOR_VERSION: u32 = 39;
 #[cfg(target_os = "macos")]
 pub const KERNEL_MINOR_VERSION: u32 = 19;
 
+/// Oldest minor version whose requests are decoded, `fuse_mknod_in` and `fuse_create_in` grew
+/// to their current layout in 7.12.
+pub const MIN_KERNEL_MINOR_VERSION: u32 = 12;
+
 /// Init reply size is FUSE_COMPAT_INIT_OUT_SIZE
 pub const KERNEL_MINOR_VERSION_INIT_OUT_SIZE: u32 = 5;
 
@@ -58,6 +62,14 @@ const FATTR_MTIME_NOW: u32 = 0x100;
 pub const FATTR_LOCKOWNER: u32 = 0x200;
 const FATTR_CTIME: u32 = 0x400;
 const FATTR_KILL_SUIDGID: u32 = 0x800;
+#[cfg(target_os = "macos")]
+const FATTR_CRTIME: u32 = 1 << 28;
+#[cfg(target_os = "macos")]
+const FATTR_CHGTIME: u32 = 1 << 29;
+#[cfg(target_os = "macos")]
+const FATTR_BKUPTIME: u32 = 1 << 30;
+#[cfg(target_os = "macos")]
+const FATTR_FLAGS: u32 = 1 << 31;
 
 bitflags! {
     pub struct SetattrValid: u32 {
@@ -71,6 +83,14 @@ bitflags! {
         const MTIME_NOW = FATTR_MTIME_NOW;
         const CTIME = FATTR_CTIME;
         const KILL_SUIDGID = FATTR_KILL_SUIDGID;
+        #[cfg(target_os = "macos")]
+        const CRTIME = FATTR_CRTIME;
+        #[cfg(target_os = "macos")]
+        const CHGTIME = FATTR_CHGTIME;
+        #[cfg(target_os = "macos")]
+        const BKUPTIME = FATTR_BKUPTIME;
+        #[cfg(target_os = "macos")]
+        const FLAGS = FATTR_FLAGS;
     }
 }
 
@@ -94,6 +114,14 @@ const FOPEN_CACHE_DIR: u32 = 8;
 /// the file is stream-like (no file position at all)
 const FOPEN_STREAM: u32 = 16;
 
+/// Purge the attribute cache of the file on open, macFUSE only.
+#[cfg(target_os = "macos")]
+const FOPEN_PURGE_ATTR: u32 = 1 << 30;
+
+/// Purge the unified buffer cache of the file on open, macFUSE only.
+#[cfg(target_os = "macos")]
+const FOPEN_PURGE_UBC: u32 = 1 << 31;
+
 bitflags! {
     /// Options controlling the behavior of files opened by the server in response
     /// to an open or create request.
@@ -103,101 +131,115 @@ bitflags! {
         const NONSEEKABLE = FOPEN_NONSEEKABLE;
         const CACHE_DIR = FOPEN_CACHE_DIR;
         const STREAM = FOPEN_STREAM;
+        #[cfg(target_os = "macos")]
+        const PURGE_ATTR = FOPEN_PURGE_ATTR;
+        #[cfg(target_os = "macos")]
+        const PURGE_UBC = FOPEN_PURGE_UBC;
     }
 }
 
 // INIT request/reply flags.
 
 /// Asynchronous read requests.
-const ASYNC_READ: u32 = 0x1;
+const ASYNC_READ: u64 = 0x1;
 
 /// Remote locking for POSIX file locks.
-const POSIX_LOCKS: u32 = 0x2;
+const POSIX_LOCKS: u64 = 0x2;
 
 /// Kernel sends file handle for fstat, etc... (not yet supported).
-const FILE_OPS: u32 = 0x4;
+const FILE_OPS: u64 = 0x4;
 
 /// Handles the O_TRUNC open flag in the filesystem.
-const ATOMIC_O_TRUNC: u32 = 0x8;
+const ATOMIC_O_TRUNC: u64 = 0x8;
 
 /// FileSystem handles lookups of "." and "..".
-const EXPORT_SUPPORT: u32 = 0x10;
+const EXPORT_SUPPORT: u64 = 0x10;
 
 /// FileSystem can handle write size larger than 4kB.
-const BIG_WRITES: u32 = 0x20;
+const BIG_WRITES: u64 = 0x20;
 
 /// Don't apply umask to file mode on create operations.
-const DONT_MASK: u32 = 0x40;
+const DONT_MASK: u64 = 0x40;
 
 /// Kernel supports splice write on the device.
-const SPLICE_WRITE: u32 = 0x80;
+const SPLICE_WRITE: u64 = 0x80;
 
 /// Kernel supports splice move on the device.
-const SPLICE_MOVE: u32 = 0x100;
+const SPLICE_MOVE: u64 = 0x100;
 
 /// Kernel supports splice read on the device.
-const SPLICE_READ: u32 = 0x200;
+const SPLICE_READ: u64 = 0x200;
 
 /// Remote locking for BSD style file locks.
-const FLOCK_LOCKS: u32 = 0x400;
+const FLOCK_LOCKS: u64 = 0x400;
 
 /// Kernel supports ioctl on directories.
-const HAS_IOCTL_DIR: u32 = 0x800;
+const HAS_IOCTL_DIR: u64 = 0x800;
 
 /// Automatically invalidate cached pages.
-const AUTO_INVAL_DATA: u32 = 0x1000;
+const AUTO_INVAL_DATA: u64 = 0x1000;
 
 /// Do READDIRPLUS (READDIR+LOOKUP in one).
-const DO_READDIRPLUS: u32 = 0x2000;
+const DO_READDIRPLUS: u64 = 0x2000;
 
 /// Adaptive readdirplus.
-const READDIRPLUS_AUTO: u32 = 0x4000;
+const READDIRPLUS_AUTO: u64 = 0x4000;
 
 /// Asynchronous direct I/O submission.
-const ASYNC_DIO: u32 = 0x8000;
+const ASYNC_DIO: u64 = 0x8000;
 
 /// Use writeback cache for buffered writes.
-const WRITEBACK_CACHE: u32 = 0x1_0000;
+const WRITEBACK_CACHE: u64 = 0x1_0000;
 
 /// Kernel supports zero-message opens.
-const NO_OPEN_SUPPORT: u32 = 0x2_0000;
+const NO_OPEN_SUPPORT: u64 = 0x2_0000;
 
 /// Allow parallel lookups and readdir.
-const PARALLEL_DIROPS: u32 = 0x4_0000;
+const PARALLEL_DIROPS: u64 = 0x4_0000;
 
 /// Fs handles killing suid/sgid/cap on write/chown/trunc.
-const HANDLE_KILLPRIV: u32 = 0x8_0000;
+const HANDLE_KILLPRIV: u64 = 0x8_0000;
 
 /// FileSystem supports posix acls.
-const POSIX_ACL: u32 = 0x10_0000;
+const POSIX_ACL: u64 = 0x10_0000;
 
 // Reading the fuse device after abort returns ECONNABORTED
-const ABORT_ERROR: u32 = 0x20_0000;
+const ABORT_ERROR: u64 = 0x20_0000;
 
 // INIT response init_out.max_pages contains the max number of req pages
-const MAX_PAGES: u32 = 0x40_0000;
+const MAX_PAGES: u64 = 0x40_0000;
 
 // Kernel caches READLINK responses
-const CACHE_SYMLINKS: u32 = 0x80_0000;
+const CACHE_SYMLINKS: u64 = 0x80_0000;
 
 // Kernel supports zero-message opendir
-const NO_OPENDIR_SUPPORT: u32 = 0x100_0000;
+const NO_OPENDIR_SUPPORT: u64 = 0x100_0000;
 
 // Only invalidate cached pages on explicit request
-const EXPLICIT_INVAL_DATA: u32 = 0x200_0000;
+const EXPLICIT_INVAL_DATA: u64 = 0x200_0000;
 
 // INIT response init_out.map_alignment contains byte alignment for foffset and
 // moffset fields in struct fuse_setupmapping_out and fuse_removemapping_one.
-const MAP_ALIGNMENT: u32 = 0x400_0000;
+const MAP_ALIGNMENT: u64 = 0x400_0000;
 
 // Kernel supports auto-mounting directory submounts
-const SUBMOUNTS: u32 = 0x800_0000;
+const SUBMOUNTS: u64 = 0x800_0000;
 
 // Filesystem responsible for clearing security.capability xattr and setuid/setgid bits.
-const HANDLE_KILLPRIV_V2: u32 = 0x1000_0000;
+const HANDLE_KILLPRIV_V2: u64 = 0x1000_0000;
 
 // This flag indicates whether the guest kernel enable per-file dax
-const PERFILE_DAX: u32 = 0x4000_0000;
+const PERFILE_DAX: u64 = 0x4000_0000;
+
+/// The `FUSE_INIT` request and reply carry `flags2`, the upper 32 bits of the options. Since
+/// 7.36, it shares its bit with `PERFILE_DAX` used by older kernels.
+pub const INIT_EXT: u32 = 0x4000_0000;
+
+// Add supplementary group info to requests creating files.
+const CREATE_SUPP_GROUP: u64 = 1 << 34;
+
+// Look up and open files by a single request, an extension of patched kernels.
+const ATOMIC_OPEN: u64 = 1 << 43;
 
 /**
  *
@@ -213,7 +255,7 @@ pub const FUSE_ATTR_DAX: u32 = 1 << 1;
 bitflags! {
     /// A bitfield passed in as a parameter to and returned from the `init` method of the
     /// `FileSystem` trait.
-    pub struct FsOptions: u32 {
+    pub struct FsOptions: u64 {
         /// Indicates that the filesystem supports asynchronous read requests.
         ///
         /// If this capability is not requested/available, the kernel will ensure that there is at
@@ -449,6 +491,20 @@ bitflags! {
         /// If this feature is enabled, filesystem will notify guest kernel whether file
         /// enable DAX by EntryOut.Attr.flags of inode when lookup
         const PERFILE_DAX = PERFILE_DAX;
+
+        /// Indicates that the kernel sends the supplementary group of the caller with requests
+        /// creating files, when the caller is a member of the group owning the parent directory
+        /// without it being its primary group. Available since 7.38, with `INIT_EXT`.
+        ///
+        /// The group is passed to the file system by `Context::supp_gid`.
+        const CREATE_SUPP_GROUP = CREATE_SUPP_GROUP;
+
+        /// Indicates that the kernel looks up and opens files by a single `FUSE_ATOMIC_OPEN`
+        /// request, handled by `FileSystem::atomic_open`, instead of a lookup followed by an
+        /// open. Only patched kernels support it, with `INIT_EXT`.
+        ///
+        /// This feature is disabled by default.
+        const ATOMIC_OPEN = ATOMIC_OPEN;
     }
 }
 
@@ -459,6 +515,13 @@ pub const RELEASE_FLOCK_UNLOCK: u32 = 2;
 // Getattr flags.
 pub const GETATTR_FH: u32 = 1;
 
+/// Statx masks, the fields of `Statx` which are filled, from `<linux/stat.h>`
+///
+/// The fields of `struct stat`, type, mode, nlink, uid, gid, times, ino, size and blocks.
+pub const STATX_BASIC_STATS: u32 = 0x7ff;
+/// The creation time in `btime`.
+pub const STATX_BTIME: u32 = 0x800;
+
 // Lock flags.
 pub const LK_FLOCK: u32 = 1;
 
@@ -531,6 +594,12 @@ pub const ATTR_SUBMOUNT: u32 = 1;
 /// Request poll notify.
 pub const POLL_SCHEDULE_NOTIFY: u32 = 1;
 
+/// Notify inval entry flags
+///
+/// Only expire the entry, so it's looked up again on next use, rather than dropping it right
+/// away. Open files and the current directory of processes keep working, since 7.38.
+pub const FUSE_EXPIRE_ONLY: u32 = 1 << 0;
+
 /// Fsync flags
 ///
 /// Sync data only, not metadata
@@ -732,7 +801,24 @@ pub enum Opcode {
     CopyFileRange = 47,
     SetupMapping = 48,
     RemoveMapping = 49,
-    MaxOpcode = 50,
+    Syncfs = 50,
+    /* Arguments are a `CreateIn`, followed by the name of the dentry, which is always "/" */
+    Tmpfile = 51,
+    /* Arguments are a `StatxIn`, since 7.39 */
+    Statx = 52,
+
+    /* Arguments are a `CreateIn` followed by the name, like for `FUSE_CREATE`. An extension of
+     * patched kernels, numbered after `FUSE_STATX` of 7.39. */
+    AtomicOpen = 53,
+    MaxOpcode = 54,
+
+    /* macFUSE opcodes, for volume renames, safe-saves and the times of Finder */
+    #[cfg(target_os = "macos")]
+    Setvolname = 61,
+    #[cfg(target_os = "macos")]
+    Getxtimes = 62,
+    #[cfg(target_os = "macos")]
+    Exchange = 63,
 
     /* Reserved opcodes: helpful to detect structure endian-ness in case of e.g. virtiofs */
     CuseInitBswapReserved = 1_048_576, /* CUSE_INIT << 8 */
@@ -741,10 +827,17 @@ pub enum Opcode {
 
 impl From<u32> for Opcode {
     fn from(op: u32) -> Opcode {
-        if op >= Opcode::MaxOpcode as u32 {
-            return Opcode::MaxOpcode;
+        #[cfg(target_os = "macos")]
+        if (Opcode::Setvolname as u32..=Opcode::Exchange as u32).contains(&op) {
+            return unsafe { mem::transmute(op) };
+        }
+        // Unknown opcodes, including the unused ones below `MaxOpcode`, have no variant to
+        // transmute to.
+        match op {
+            0 | 7 | 19 => Opcode::MaxOpcode,
+            op if op >= Opcode::MaxOpcode as u32 => Opcode::MaxOpcode,
+            op => unsafe { mem::transmute::<u32, Opcode>(op) },
         }
-        unsafe { mem::transmute(op as u32) }
     }
 }
 
@@ -815,6 +908,152 @@ pub struct AttrOut {
 }
 unsafe impl ByteValued for AttrOut {}
 
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SxTime {
+    pub tv_sec: i64,
+    pub tv_nsec: u32,
+    pub reserved: i32,
+}
+unsafe impl ByteValued for SxTime {}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Statx {
+    pub mask: u32,
+    pub blksize: u32,
+    pub attributes: u64,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u16,
+    pub spare0: [u16; 1],
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub attributes_mask: u64,
+    pub atime: SxTime,
+    pub btime: SxTime,
+    pub ctime: SxTime,
+    pub mtime: SxTime,
+    pub rdev_major: u32,
+    pub rdev_minor: u32,
+    pub dev_major: u32,
+    pub dev_minor: u32,
+    pub spare2: [u64; 14],
+}
+unsafe impl ByteValued for Statx {}
+
+// Split `dev` into its major and minor numbers, the same encoding as `makedev()` of glibc.
+fn split_dev(dev: u64) -> (u32, u32) {
+    let major = ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0x0000_0fff);
+    let minor = ((dev >> 12) & 0xffff_ff00) | (dev & 0x0000_00ff);
+    (major as u32, minor as u32)
+}
+
+fn join_dev(major: u32, minor: u32) -> u64 {
+    let (major, minor) = (major as u64, minor as u64);
+    ((major & 0xffff_f000) << 32)
+        | ((major & 0x0000_0fff) << 8)
+        | ((minor & 0xffff_ff00) << 12)
+        | (minor & 0x0000_00ff)
+}
+
+impl From<stat64> for Statx {
+    /// Fill the basic fields from `st`, without creation time.
+    // The types of the fields of `stat64` vary across architectures.
+    #[allow(clippy::unnecessary_cast)]
+    fn from(st: stat64) -> Statx {
+        let (dev_major, dev_minor) = split_dev(st.st_dev as u64);
+        let (rdev_major, rdev_minor) = split_dev(st.st_rdev as u64);
+        let time = |sec: i64, nsec: i64| SxTime {
+            tv_sec: sec,
+            tv_nsec: nsec as u32,
+            reserved: 0,
+        };
+        Statx {
+            mask: STATX_BASIC_STATS,
+            blksize: st.st_blksize as u32,
+            nlink: st.st_nlink as u32,
+            uid: st.st_uid,
+            gid: st.st_gid,
+            mode: st.st_mode as u16,
+            ino: st.st_ino,
+            size: st.st_size as u64,
+            blocks: st.st_blocks as u64,
+            atime: time(st.st_atime as i64, st.st_atime_nsec as i64),
+            ctime: time(st.st_ctime as i64, st.st_ctime_nsec as i64),
+            mtime: time(st.st_mtime as i64, st.st_mtime_nsec as i64),
+            rdev_major,
+            rdev_minor,
+            dev_major,
+            dev_minor,
+            ..Default::default()
+        }
+    }
+}
+
+impl Statx {
+    /// Replace the basic fields by the ones of `st`, keeping the mask, attributes and creation
+    /// time.
+    pub fn with_stat(self, st: stat64) -> Statx {
+        Statx {
+            mask: self.mask,
+            attributes: self.attributes,
+            attributes_mask: self.attributes_mask,
+            btime: self.btime,
+            ..Statx::from(st)
+        }
+    }
+}
+
+impl From<Statx> for stat64 {
+    fn from(stx: Statx) -> stat64 {
+        // Safe because we are zero-initializing a struct
+        let mut out: stat64 = unsafe { mem::zeroed() };
+        out.st_dev = join_dev(stx.dev_major, stx.dev_minor) as _;
+        out.st_ino = stx.ino;
+        out.st_nlink = stx.nlink as _;
+        out.st_mode = stx.mode as _;
+        out.st_uid = stx.uid;
+        out.st_gid = stx.gid;
+        out.st_rdev = join_dev(stx.rdev_major, stx.rdev_minor) as _;
+        out.st_size = stx.size as _;
+        out.st_blksize = stx.blksize as _;
+        out.st_blocks = stx.blocks as _;
+        out.st_atime = stx.atime.tv_sec as _;
+        out.st_atime_nsec = stx.atime.tv_nsec as _;
+        out.st_mtime = stx.mtime.tv_sec as _;
+        out.st_mtime_nsec = stx.mtime.tv_nsec as _;
+        out.st_ctime = stx.ctime.tv_sec as _;
+        out.st_ctime_nsec = stx.ctime.tv_nsec as _;
+        out
+    }
+}
+
+/* Since 7.39 */
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct StatxIn {
+    pub getattr_flags: u32,
+    pub reserved: u32,
+    pub fh: u64,
+    pub sx_flags: u32,
+    pub sx_mask: u32,
+}
+unsafe impl ByteValued for StatxIn {}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct StatxOut {
+    pub attr_valid: u64, /* Cache timeout for the attributes */
+    pub attr_valid_nsec: u32,
+    pub flags: u32,
+    pub spare: [u64; 2],
+    pub stat: Statx,
+}
+unsafe impl ByteValued for StatxOut {}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct MknodIn {
@@ -879,6 +1118,20 @@ pub struct SetattrIn {
     pub uid: u32,
     pub gid: u32,
     pub unused5: u32,
+    #[cfg(target_os = "macos")]
+    pub bkuptime: u64,
+    #[cfg(target_os = "macos")]
+    pub chgtime: u64,
+    #[cfg(target_os = "macos")]
+    pub crtime: u64,
+    #[cfg(target_os = "macos")]
+    pub bkuptimensec: u32,
+    #[cfg(target_os = "macos")]
+    pub chgtimensec: u32,
+    #[cfg(target_os = "macos")]
+    pub crtimensec: u32,
+    #[cfg(target_os = "macos")]
+    pub flags: u32,
 }
 unsafe impl ByteValued for SetattrIn {}
 
@@ -896,6 +1149,12 @@ impl From<SetattrIn> for stat64 {
         out.st_atime_nsec = i64::from(setattr.atimensec);
         out.st_mtime_nsec = i64::from(setattr.mtimensec);
         out.st_ctime_nsec = i64::from(setattr.ctimensec);
+        #[cfg(target_os = "macos")]
+        {
+            out.st_birthtime = setattr.crtime as i64;
+            out.st_birthtime_nsec = i64::from(setattr.crtimensec);
+            out.st_flags = setattr.flags;
+        }
 
         out
     }
@@ -1003,6 +1262,10 @@ unsafe impl ByteValued for FsyncIn {}
 pub struct SetxattrIn {
     pub size: u32,
     pub flags: u32,
+    #[cfg(target_os = "macos")]
+    pub position: u32,
+    #[cfg(target_os = "macos")]
+    pub padding: u32,
 }
 unsafe impl ByteValued for SetxattrIn {}
 
@@ -1062,6 +1325,15 @@ pub struct InitIn {
 }
 unsafe impl ByteValued for InitIn {}
 
+/* Since 7.36, following `InitIn` with FUSE_INIT_EXT */
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct InitIn2 {
+    pub flags2: u32,
+    pub unused: [u32; 11],
+}
+unsafe impl ByteValued for InitIn2 {}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct InitOut {
@@ -1075,7 +1347,11 @@ pub struct InitOut {
     pub time_gran: u32,
     pub max_pages: u16,
     pub map_alignment: u16,
-    pub unused: [u32; 8],
+    /* Since 7.36, with FUSE_INIT_EXT */
+    pub flags2: u32,
+    /* Since 7.40 */
+    pub max_stack_depth: u32,
+    pub unused: [u32; 6],
 }
 unsafe impl ByteValued for InitOut {}
 
@@ -1102,6 +1378,29 @@ pub struct BmapOut {
 }
 unsafe impl ByteValued for BmapOut {}
 
+#[cfg(target_os = "macos")]
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ExchangeIn {
+    pub olddir: u64,
+    pub newdir: u64,
+    pub options: u64,
+}
+#[cfg(target_os = "macos")]
+unsafe impl ByteValued for ExchangeIn {}
+
+#[cfg(target_os = "macos")]
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct GetxtimesOut {
+    pub bkuptime: u64,
+    pub crtime: u64,
+    pub bkuptimensec: u32,
+    pub crtimensec: u32,
+}
+#[cfg(target_os = "macos")]
+unsafe impl ByteValued for GetxtimesOut {}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct IoctlIn {
@@ -1115,7 +1414,7 @@ pub struct IoctlIn {
 unsafe impl ByteValued for IoctlIn {}
 
 #[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
 pub struct IoctlIovec {
     pub base: u64,
     pub len: u64,
@@ -1178,10 +1477,33 @@ pub struct InHeader {
     pub uid: u32,
     pub gid: u32,
     pub pid: u32,
-    pub padding: u32,
+    /* Since 7.38, length of the request extensions in units of 8 bytes */
+    pub total_extlen: u16,
+    pub padding: u16,
 }
 unsafe impl ByteValued for InHeader {}
 
+/// Type of the request extension carrying supplementary groups.
+pub const FUSE_EXT_GROUPS: u32 = 32;
+
+/// Header of request extensions, appended to requests after their arguments.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ExtHeader {
+    /// Size of the extension including this header, a multiple of 8 bytes.
+    pub size: u32,
+    pub type_: u32,
+}
+unsafe impl ByteValued for ExtHeader {}
+
+/// Supplementary groups extension, followed by `nr_groups` group ids.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SuppGroups {
+    pub nr_groups: u32,
+}
+unsafe impl ByteValued for SuppGroups {}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct OutHeader {
@@ -1224,7 +1546,8 @@ unsafe impl ByteValued for NotifyInvalInodeOut {}
 pub struct NotifyInvalEntryOut {
     pub parent: u64,
     pub namelen: u32,
-    pub padding: u32,
+    /// Flags like `FUSE_EXPIRE_ONLY`, padding which must be zero before 7.38.
+    pub flags: u32,
 }
 unsafe impl ByteValued for NotifyInvalEntryOut {}
 
@@ -1303,9 +1626,106 @@ pub struct CopyFileRangeIn {
 }
 unsafe impl ByteValued for CopyFileRangeIn {}
 
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SyncfsIn {
+    pub padding: u64,
+}
+unsafe impl ByteValued for SyncfsIn {}
+
+// Layouts of `struct fuse_*` in the kernel UAPI header <linux/fuse.h>. `fuse_init_in`,
+// `fuse_setxattr_in` and `fuse_getxattr_in` are mirrored in their compat layout, the one every
+// kernel sends at least.
+assert_wire_layout! {
+    Kstatfs: 80, 8;
+    FileLock: 24, 8;
+    ForgetIn: 8, 8;
+    ForgetOne: 16, 8;
+    BatchForgetIn: 8, 4;
+    GetattrIn: 16, 8;
+    SxTime: 16, 8;
+    Statx: 256, 8;
+    StatxIn: 24, 8;
+    StatxOut: 288, 8;
+    MknodIn: 16, 4;
+    MkdirIn: 8, 4;
+    Rename2In: 16, 8;
+    LinkIn: 8, 8;
+    OpenIn: 8, 4;
+    CreateIn: 16, 4;
+    OpenOut: 16, 8;
+    ReleaseIn: 24, 8;
+    FlushIn: 24, 8;
+    ReadIn: 40, 8;
+    WriteIn: 40, 8;
+    WriteOut: 8, 4;
+    StatfsOut: 80, 8;
+    FsyncIn: 16, 8;
+    GetxattrOut: 8, 4;
+    LkIn: 48, 8;
+    LkOut: 24, 8;
+    AccessIn: 8, 4;
+    InitIn: 16, 4;
+    InitIn2: 48, 4;
+    InitOut: 64, 4;
+    InterruptIn: 8, 8;
+    BmapIn: 16, 8;
+    BmapOut: 8, 8;
+    IoctlIn: 32, 8;
+    IoctlIovec: 16, 8;
+    IoctlOut: 16, 4;
+    PollIn: 24, 8;
+    PollOut: 8, 4;
+    NotifyPollWakeupOut: 8, 8;
+    FallocateIn: 32, 8;
+    InHeader: 40, 8;
+    ExtHeader: 8, 4;
+    SuppGroups: 4, 4;
+    OutHeader: 16, 8;
+    Dirent: 24, 8;
+    NotifyInvalInodeOut: 24, 8;
+    NotifyInvalEntryOut: 16, 8;
+    NotifyDeleteOut: 24, 8;
+    NotifyStoreOut: 24, 8;
+    Notify_Retrieve_Out: 32, 8;
+    NotifyRetrieveIn: 40, 8;
+    LseekIn: 24, 8;
+    LseekOut: 8, 8;
+    CopyFileRangeIn: 56, 8;
+    SyncfsIn: 8, 8;
+}
+
+// Structs extended by macFUSE.
+#[cfg(target_os = "linux")]
+assert_wire_layout! {
+    Attr: 88, 8;
+    EntryOut: 128, 8;
+    AttrOut: 104, 8;
+    RenameIn: 8, 8;
+    SetattrIn: 88, 8;
+    SetxattrIn: 8, 4;
+    GetxattrIn: 8, 4;
+    Direntplus: 152, 8;
+}
+
+#[cfg(target_os = "macos")]
+assert_wire_layout! {
+    Attr: 104, 8;
+    EntryOut: 144, 8;
+    AttrOut: 120, 8;
+    RenameIn: 16, 8;
+    SetattrIn: 128, 8;
+    SetxattrIn: 16, 4;
+    GetxattrIn: 16, 4;
+    Direntplus: 168, 8;
+    ExchangeIn: 24, 8;
+    GetxtimesOut: 24, 8;
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::mem::size_of;
 
     #[test]
     fn test_struct_size() {
@@ -1327,6 +1747,10 @@ mod tests {
         assert_eq!(std::mem::size_of::<AttrOut>(), 104);
         #[cfg(target_os = "macos")]
         assert_eq!(std::mem::size_of::<AttrOut>(), 120);
+        #[cfg(target_os = "linux")]
+        assert_eq!(std::mem::size_of::<SetattrIn>(), 88);
+        #[cfg(target_os = "macos")]
+        assert_eq!(std::mem::size_of::<SetattrIn>(), 128);
         assert_eq!(std::mem::size_of::<MknodIn>(), 16);
         assert_eq!(std::mem::size_of::<MkdirIn>(), 8);
         assert_eq!(std::mem::size_of::<InHeader>(), 40);
@@ -1353,4 +1777,213 @@ mod tests {
         assert_eq!(buf[8], 0x5u8);
         assert_eq!(buf[9], 0x6u8);
     }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn test_macos_opcodes() {
+        assert!(matches!(Opcode::from(61), Opcode::Setvolname));
+        assert!(matches!(Opcode::from(62), Opcode::Getxtimes));
+        assert!(matches!(Opcode::from(63), Opcode::Exchange));
+        assert!(matches!(Opcode::from(60), Opcode::MaxOpcode));
+        assert!(matches!(Opcode::from(64), Opcode::MaxOpcode));
+
+        let exchange = ExchangeIn {
+            olddir: 0x0102,
+            newdir: 0x0304,
+            options: 0x05,
+        };
+        assert_eq!(
+            exchange.as_slice(),
+            &[
+                0x02, 0x01, 0, 0, 0, 0, 0, 0, 0x04, 0x03, 0, 0, 0, 0, 0, 0, 0x05, 0, 0, 0, 0, 0, 0,
+                0,
+            ]
+        );
+        let xtimes = GetxtimesOut {
+            bkuptime: 0x10,
+            crtime: 0x0201,
+            bkuptimensec: 0x30,
+            crtimensec: 0x0403,
+        };
+        assert_eq!(
+            xtimes.as_slice(),
+            &[
+                0x10, 0, 0, 0, 0, 0, 0, 0, 0x01, 0x02, 0, 0, 0, 0, 0, 0, 0x30, 0, 0, 0, 0x03, 0x04,
+                0, 0,
+            ]
+        );
+    }
+
+    // Offset of the field at `field` in the struct at `base`.
+    fn offset<T, F>(base: &T, field: &F) -> usize {
+        field as *const F as usize - base as *const T as usize
+    }
+
+    #[test]
+    fn test_field_offsets() {
+        let init = InitOut::default();
+        assert_eq!(offset(&init, &init.max_pages), 28);
+        assert_eq!(offset(&init, &init.flags2), 32);
+        assert_eq!(offset(&init, &init.max_stack_depth), 36);
+        assert_eq!(offset(&init, &init.unused), 40);
+
+        let statx = Statx::default();
+        assert_eq!(offset(&statx, &statx.mode), 28);
+        assert_eq!(offset(&statx, &statx.ino), 32);
+        assert_eq!(offset(&statx, &statx.atime), 64);
+        assert_eq!(offset(&statx, &statx.rdev_major), 128);
+        assert_eq!(offset(&statx, &statx.spare2), 144);
+        let out = StatxOut::default();
+        assert_eq!(offset(&out, &out.stat), 32);
+
+        let entry = EntryOut::default();
+        assert_eq!(offset(&entry, &entry.attr), 40);
+        #[cfg(target_os = "linux")]
+        assert_eq!(offset(&entry.attr, &entry.attr.flags), 84);
+        let dirent = Direntplus::default();
+        assert_eq!(offset(&dirent, &dirent.dirent), size_of::<EntryOut>());
+        let inval = NotifyInvalEntryOut::default();
+        assert_eq!(offset(&inval, &inval.flags), 12);
+    }
+
+    #[test]
+    fn test_statx_opcode_and_stat() {
+        assert!(matches!(Opcode::from(52), Opcode::Statx));
+        assert!(matches!(Opcode::from(53), Opcode::AtomicOpen));
+        assert!(matches!(Opcode::from(54), Opcode::MaxOpcode));
+
+        // Safe because we are zero-initializing a struct
+        let mut st: stat64 = unsafe { mem::zeroed() };
+        st.st_dev = join_dev(0x1_2345, 0x67_8901) as _;
+        st.st_rdev = join_dev(8, 17) as _;
+        st.st_ino = 42;
+        st.st_mode = libc::S_IFREG | 0o640;
+        st.st_nlink = 2;
+        st.st_uid = 1000;
+        st.st_gid = 100;
+        st.st_size = 4097;
+        st.st_blocks = 16;
+        st.st_blksize = 4096;
+        st.st_mtime = 1_700_000_000;
+        st.st_mtime_nsec = 123;
+        st.st_ctime = -1;
+
+        let stx = Statx::from(st);
+        assert_eq!(stx.mask, STATX_BASIC_STATS);
+        assert_eq!((stx.dev_major, stx.dev_minor), (0x1_2345, 0x67_8901));
+        assert_eq!((stx.rdev_major, stx.rdev_minor), (8, 17));
+        assert_eq!((stx.mtime.tv_sec, stx.mtime.tv_nsec), (1_700_000_000, 123));
+        assert_eq!(stx.btime.tv_sec, 0);
+
+        let back = stat64::from(stx);
+        assert_eq!(back.st_dev, st.st_dev);
+        assert_eq!(back.st_rdev, st.st_rdev);
+        assert_eq!(back.st_mode, st.st_mode);
+        assert_eq!((back.st_uid, back.st_gid), (1000, 100));
+        assert_eq!((back.st_size, back.st_blocks), (4097, 16));
+        assert_eq!((back.st_mtime, back.st_mtime_nsec), (1_700_000_000, 123));
+        assert_eq!(back.st_ctime, -1);
+    }
+
+    #[test]
+    fn test_notify_inval_entry_layouts() {
+        // 7.38 took the padding of older minors for flags, keeping the size of the struct.
+        let out = NotifyInvalEntryOut {
+            parent: 0x0102,
+            namelen: 3,
+            flags: FUSE_EXPIRE_ONLY,
+        };
+        assert_eq!(
+            out.as_slice(),
+            &[0x02, 0x01, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 1, 0, 0, 0]
+        );
+        let mut copy = NotifyInvalEntryOut::default();
+        copy.as_mut_slice().copy_from_slice(out.as_slice());
+        assert_eq!((copy.parent, copy.namelen, copy.flags), (0x0102, 3, 1));
+
+        // Messages of older minors decode with no flags.
+        let old = [0x02, 0x01, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0];
+        copy.as_mut_slice().copy_from_slice(&old);
+        assert_eq!((copy.parent, copy.namelen, copy.flags), (0x0102, 3, 0));
+    }
+
+    // Copy a buffer of non-zero sentinel bytes into a `T` and back, any byte lost or moved
+    // means `T` has implicit padding or a field `ByteValued` can't represent.
+    fn round_trip<T: ByteValued + Default>() {
+        let sentinel: Vec<u8> = (0..size_of::<T>()).map(|i| (i % 251) as u8 + 1).collect();
+        let mut val = T::default();
+        assert!(val.as_slice().iter().all(|b| *b == 0));
+        val.as_mut_slice().copy_from_slice(&sentinel);
+        let copy = val;
+        assert_eq!(
+            copy.as_slice(),
+            &sentinel[..],
+            "{}",
+            std::any::type_name::<T>()
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip::<Attr>();
+        round_trip::<Kstatfs>();
+        round_trip::<FileLock>();
+        round_trip::<EntryOut>();
+        round_trip::<ForgetIn>();
+        round_trip::<ForgetOne>();
+        round_trip::<BatchForgetIn>();
+        round_trip::<GetattrIn>();
+        round_trip::<AttrOut>();
+        round_trip::<SxTime>();
+        round_trip::<Statx>();
+        round_trip::<StatxIn>();
+        round_trip::<StatxOut>();
+        round_trip::<MknodIn>();
+        round_trip::<MkdirIn>();
+        round_trip::<RenameIn>();
+        round_trip::<Rename2In>();
+        round_trip::<LinkIn>();
+        round_trip::<SetattrIn>();
+        round_trip::<OpenIn>();
+        round_trip::<CreateIn>();
+        round_trip::<OpenOut>();
+        round_trip::<ReleaseIn>();
+        round_trip::<FlushIn>();
+        round_trip::<ReadIn>();
+        round_trip::<WriteIn>();
+        round_trip::<WriteOut>();
+        round_trip::<StatfsOut>();
+        round_trip::<FsyncIn>();
+        round_trip::<SetxattrIn>();
+        round_trip::<GetxattrIn>();
+        round_trip::<GetxattrOut>();
+        round_trip::<LkIn>();
+        round_trip::<LkOut>();
+        round_trip::<AccessIn>();
+        round_trip::<InitIn>();
+        round_trip::<InitOut>();
+        round_trip::<InterruptIn>();
+        round_trip::<BmapIn>();
+        round_trip::<BmapOut>();
+        round_trip::<IoctlIn>();
+        round_trip::<IoctlIovec>();
+        round_trip::<IoctlOut>();
+        round_trip::<PollIn>();
+        round_trip::<PollOut>();
+        round_trip::<NotifyPollWakeupOut>();
+        round_trip::<FallocateIn>();
+        round_trip::<InHeader>();
+        round_trip::<OutHeader>();
+        round_trip::<Dirent>();
+        round_trip::<Direntplus>();
+        round_trip::<NotifyInvalInodeOut>();
+        round_trip::<NotifyInvalEntryOut>();
+        round_trip::<NotifyDeleteOut>();
+        round_trip::<NotifyStoreOut>();
+        round_trip::<Notify_Retrieve_Out>();
+        round_trip::<NotifyRetrieveIn>();
+        round_trip::<LseekIn>();
+        round_trip::<LseekOut>();
+        round_trip::<CopyFileRangeIn>();
+    }
 }
@@ -3,9 +3,27 @@
 
 //! Fuse Application Binary Interfaces(ABI).
 
+// Check the size and alignment of wire structs at compile time, against the values of the C
+// structs they mirror, so a field missing or added upstream fails the build instead of shifting
+// every field decoded after it.
+macro_rules! assert_wire_layout {
+    ($($ty:ty: $size:expr, $align:expr;)*) => {
+        $(
+            const _: () = assert!(
+                std::mem::size_of::<$ty>() == $size && std::mem::align_of::<$ty>() == $align,
+                concat!("wire layout mismatch of ", stringify!($ty)),
+            );
+        )*
+    };
+}
+
 /// Linux/Macos Fuse Application Binary Interfaces.
 #[cfg(any(target_os = "macos", target_os = "linux"))]
 pub mod fuse_abi;
 
+/// Standalone decoding and encoding of Fuse messages.
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+pub mod codec;
+
 #[cfg(feature = "virtiofs")]
 pub mod virtio_fs;
@@ -58,3 +58,11 @@ pub struct RemovemappingOne {
 }
 
 unsafe impl ByteValued for RemovemappingOne {}
+
+// Layouts of `struct fuse_setupmapping_in`, `fuse_removemapping_in` and
+// `fuse_removemapping_one` in <linux/fuse.h>.
+assert_wire_layout! {
+    SetupmappingIn: 40, 8;
+    RemovemappingIn: 4, 4;
+    RemovemappingOne: 16, 8;
+}
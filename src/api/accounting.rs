@@ -0,0 +1,202 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Accounting of bytes written at each layer, to diagnose write amplification.
+//!
+//! A guest write may turn into much more IO on the host: layered file systems copy files up
+//! before the first write, and copies the kernel can't offload are pumped through bounce buffers
+//! in the daemon. A [WriteAccounting] shared by the [Server](super::server::Server), the
+//! [Vfs](super::vfs::Vfs) and backend file systems counts the bytes at each layer, so
+//! [WriteStats] shows where the extra bytes come from:
+//!
+//! - `guest_write_bytes`: payload of write requests received from the guest.
+//! - `backend_write_bytes`: bytes written to backing storage, including copy-ups and copies.
+//! - `copyup_bytes`: part of the backend writes copying files up to a writable layer.
+//! - `bounce_bytes`: part of the backend writes staged in bounce buffers of the daemon.
+//!
+//! Counters are relaxed atomics, and nothing is counted by layers without an accounting set.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters of bytes written at each layer.
+#[derive(Debug, Default)]
+pub struct WriteAccounting {
+    guest: AtomicU64,
+    backend: AtomicU64,
+    copyup: AtomicU64,
+    bounce: AtomicU64,
+}
+
+impl WriteAccounting {
+    /// Account `bytes` of write payload received from the guest.
+    pub fn add_guest(&self, bytes: u64) {
+        self.guest.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Account `bytes` written to backing storage.
+    pub fn add_backend(&self, bytes: u64) {
+        self.backend.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Account `bytes` written to backing storage to copy a file up.
+    pub fn add_copyup(&self, bytes: u64) {
+        self.backend.fetch_add(bytes, Ordering::Relaxed);
+        self.copyup.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Account `bytes` staged in a bounce buffer, which are written to backing storage by a
+    /// layer accounting them with [WriteAccounting::add_backend].
+    pub fn add_bounce(&self, bytes: u64) {
+        self.bounce.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Get a snapshot of the counters.
+    pub fn stats(&self) -> WriteStats {
+        WriteStats {
+            guest_write_bytes: self.guest.load(Ordering::Relaxed),
+            backend_write_bytes: self.backend.load(Ordering::Relaxed),
+            copyup_bytes: self.copyup.load(Ordering::Relaxed),
+            bounce_bytes: self.bounce.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Snapshot of [WriteAccounting] counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WriteStats {
+    /// Bytes of write payload received from the guest.
+    pub guest_write_bytes: u64,
+    /// Bytes written to backing storage.
+    pub backend_write_bytes: u64,
+    /// Bytes of `backend_write_bytes` copying files up.
+    pub copyup_bytes: u64,
+    /// Bytes of `backend_write_bytes` staged in bounce buffers.
+    pub bounce_bytes: u64,
+}
+
+impl WriteStats {
+    /// Get the ratio of bytes written to backing storage to bytes written by the guest, or
+    /// `None` if the guest hasn't written anything.
+    pub fn amplification(&self) -> Option<f64> {
+        if self.guest_write_bytes == 0 {
+            None
+        } else {
+            Some(self.backend_write_bytes as f64 / self.guest_write_bytes as f64)
+        }
+    }
+}
+
+#[cfg(all(test, feature = "fusedev"))]
+mod tests {
+    use super::*;
+    use crate::abi::fuse_abi::{InHeader, Opcode, WriteIn};
+    use crate::api::filesystem::{Context, FileSystem, ZeroCopyReader};
+    use crate::api::server::Server;
+    use crate::transport::{FuseBuf, FuseDevWriter, Reader};
+    use std::io;
+    use std::mem::size_of;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::{Arc, Mutex};
+    use vm_memory::ByteValued;
+
+    // A layered file system keeping files in memory, which copies a file of the lower layer up
+    // before its first write.
+    struct CopyUpFs {
+        lower: Vec<u8>,
+        upper: Arc<Mutex<Option<Vec<u8>>>>,
+        acct: Arc<WriteAccounting>,
+    }
+
+    impl FileSystem for CopyUpFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn write(
+            &self,
+            _: &Context,
+            _: u64,
+            _: u64,
+            r: &mut dyn ZeroCopyReader,
+            size: u32,
+            offset: u64,
+            _: Option<u64>,
+            _: bool,
+            _: u32,
+            _: u32,
+        ) -> io::Result<usize> {
+            let mut upper = self.upper.lock().unwrap();
+            let file = upper.get_or_insert_with(|| {
+                self.acct.add_copyup(self.lower.len() as u64);
+                self.lower.clone()
+            });
+            let mut data = vec![0u8; size as usize];
+            io::Read::read_exact(r, &mut data)?;
+            let end = offset as usize + data.len();
+            if file.len() < end {
+                file.resize(end, 0);
+            }
+            file[offset as usize..end].copy_from_slice(&data);
+            self.acct.add_backend(data.len() as u64);
+            Ok(data.len())
+        }
+    }
+
+    fn write(server: &Server<CopyUpFs>, offset: u64, data: &[u8]) {
+        let write_in = WriteIn {
+            fh: 1,
+            offset,
+            size: data.len() as u32,
+            ..Default::default()
+        };
+        let in_header = InHeader {
+            len: (size_of::<InHeader>() + size_of::<WriteIn>() + data.len()) as u32,
+            opcode: Opcode::Write as u32,
+            unique: 1,
+            nodeid: 2,
+            ..Default::default()
+        };
+        let mut r_buf = in_header.as_slice().to_vec();
+        r_buf.extend_from_slice(write_in.as_slice());
+        r_buf.extend_from_slice(data);
+        let r = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut r_buf)).unwrap();
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        let mut w_buf = vec![0x0u8; 1024];
+        let w = FuseDevWriter::<()>::new(file.as_raw_fd(), &mut w_buf)
+            .unwrap()
+            .into();
+        server.handle_message(r, w, None, None).unwrap();
+    }
+
+    #[test]
+    fn test_write_accounting_copyup() {
+        let acct = Arc::new(WriteAccounting::default());
+        let upper = Arc::new(Mutex::new(None));
+        let fs = CopyUpFs {
+            lower: vec![7u8; 8192],
+            upper: upper.clone(),
+            acct: acct.clone(),
+        };
+        let server = Server::new(fs).with_write_accounting(acct.clone());
+        assert_eq!(acct.stats().amplification(), None);
+
+        // The first write copies the file up, the second one doesn't.
+        write(&server, 0, &[1u8; 4096]);
+        write(&server, 4096, &[2u8; 4096]);
+        let stats = acct.stats();
+        assert_eq!(
+            stats,
+            WriteStats {
+                guest_write_bytes: 8192,
+                backend_write_bytes: 16384,
+                copyup_bytes: 8192,
+                bounce_bytes: 0,
+            }
+        );
+        assert_eq!(
+            stats.backend_write_bytes - stats.copyup_bytes,
+            stats.guest_write_bytes
+        );
+        assert_eq!(stats.amplification(), Some(2.0));
+        assert_eq!(upper.lock().unwrap().as_ref().unwrap()[4095..4097], [1, 2]);
+    }
+}
@@ -0,0 +1,560 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Time-bounded attribute and directory entry cache for network backed file systems.
+//!
+//! File systems backed by remote origins can't afford a round trip for every `getattr` or
+//! `lookup`, but they also need to drop cached state when the origin changes. The [AttrCache]
+//! caches attributes by inode and directory entries by `(parent, name)`, each with its own time
+//! to live, and explicit invalidations are forwarded to a [Notifier] so that the guest kernel
+//! drops its own cached copies too.
+//!
+//! The cache is sharded by inode number to reduce lock contention. Critical sections are short
+//! and never block, so it may be used from both synchronous and asynchronous file systems.
+//!
+//! ```
+//! use std::time::Duration;
+//! use fuse_backend_rs::abi::fuse_abi::stat64;
+//! use fuse_backend_rs::api::attr_cache::AttrCache;
+//!
+//! let cache = AttrCache::new(16);
+//! let mut st: stat64 = unsafe { std::mem::zeroed() };
+//! st.st_ino = 2;
+//!
+//! // Cache attributes fetched from the origin in `getattr`.
+//! cache.insert(2, st, Duration::from_secs(5));
+//! let (attr, ttl) = cache.get(2).unwrap();
+//! assert_eq!(attr.st_ino, 2);
+//! assert!(ttl <= Duration::from_secs(5));
+//!
+//! // Drop cached state when the origin reports a change.
+//! cache.invalidate(2);
+//! assert!(cache.get(2).is_none());
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{CStr, CString};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::abi::fuse_abi::stat64;
+use crate::api::clock::{Clock, SystemClock};
+use crate::api::filesystem::Entry;
+use crate::transport::FileVolatileSlice;
+
+/// Receiver of cache invalidations, to propagate them to the guest kernel.
+///
+/// Implementations are called without holding any cache lock, but they should not block since
+/// they may be called from file system request handlers.
+pub trait Notifier: Send + Sync {
+    /// Attributes and data of inode `ino` have changed.
+    fn inval_inode(&self, ino: u64);
+
+    /// Data of inode `ino` in range `[off, off + len)` has changed.
+    ///
+    /// A negative `off` invalidates attributes only, and a `len` of 0 or less invalidates data up
+    /// to the end of file. The default falls back to invalidating the whole inode.
+    fn inval_inode_range(&self, ino: u64, off: i64, len: i64) {
+        let _ = (off, len);
+        self.inval_inode(ino);
+    }
+
+    /// Directory entry `name` under directory `parent` has changed.
+    fn inval_entry(&self, parent: u64, name: &CStr);
+
+    /// Directory entry `name` under directory `parent` has changed, invalidate it with `flags`.
+    ///
+    /// With `FUSE_EXPIRE_ONLY`, the guest kernel only expires the entry, so it's looked up again
+    /// on next use while open files and working directories below it keep working. The default
+    /// falls back to fully invalidating the entry.
+    fn inval_entry_flags(&self, parent: u64, name: &CStr, flags: u32) {
+        let _ = flags;
+        self.inval_entry(parent, name);
+    }
+
+    /// Directory entry `name` under directory `parent`, referring to inode `child`, has been
+    /// removed.
+    ///
+    /// Unlike [Notifier::inval_entry], the guest kernel also drops the dentry if it's in use, and
+    /// marks `child` as deleted when it has no other names. The kernel still sends `FORGET` for
+    /// `child` once it's evicted, so lookup counts are accounted as usual. The default falls back
+    /// to invalidating the entry.
+    fn delete(&self, parent: u64, child: u64, name: &CStr) {
+        let _ = child;
+        self.inval_entry(parent, name);
+    }
+
+    /// Push `data` into the page cache of inode `inode` at `offset`.
+    ///
+    /// Return the number of bytes stored, which is less than the length of `data` if the guest
+    /// doesn't have the inode cached anymore.
+    #[allow(unused_variables)]
+    fn store(&self, inode: u64, offset: u64, data: &[FileVolatileSlice]) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Ask the guest kernel to send back `size` bytes at `offset` of the page cache of inode
+    /// `inode`.
+    ///
+    /// The kernel replies with a `FUSE_NOTIFY_REPLY` request, whose unique ID is `notify_unique`,
+    /// passed to `FileSystem::notify_reply()`.
+    #[allow(unused_variables)]
+    fn retrieve(&self, notify_unique: u64, inode: u64, offset: u64, size: u32) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+}
+
+/// An invalidation recorded by [NotifyQueue].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NotifyEvent {
+    /// Invalidate attributes and data of an inode.
+    InvalInode(u64),
+    /// Invalidate a directory entry, as `(parent, name)`.
+    InvalEntry(u64, CString),
+    /// Delete a directory entry, as `(parent, child, name)`.
+    Delete(u64, u64, CString),
+}
+
+/// A bounded [Notifier] which queues invalidations, to be sent to the guest kernel by the
+/// transport layer with `Server::notify_inval_inode()`, `Server::notify_inval_entry()` and
+/// `Server::notify_delete()`.
+///
+/// The oldest events are dropped when the queue is full.
+pub struct NotifyQueue {
+    capacity: usize,
+    events: Mutex<VecDeque<NotifyEvent>>,
+}
+
+impl NotifyQueue {
+    /// Create a new queue holding at most `capacity` events.
+    pub fn new(capacity: usize) -> Self {
+        NotifyQueue {
+            capacity: capacity.max(1),
+            events: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Take all queued events, ordered from the oldest to the newest.
+    pub fn take(&self) -> Vec<NotifyEvent> {
+        self.events.lock().unwrap().drain(..).collect()
+    }
+
+    fn push(&self, event: NotifyEvent) {
+        let mut events = self.events.lock().unwrap();
+        if events.len() >= self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+}
+
+impl Notifier for NotifyQueue {
+    fn inval_inode(&self, ino: u64) {
+        self.push(NotifyEvent::InvalInode(ino));
+    }
+
+    fn inval_entry(&self, parent: u64, name: &CStr) {
+        self.push(NotifyEvent::InvalEntry(parent, name.to_owned()));
+    }
+
+    fn delete(&self, parent: u64, child: u64, name: &CStr) {
+        self.push(NotifyEvent::Delete(parent, child, name.to_owned()));
+    }
+}
+
+struct CachedAttr {
+    attr: stat64,
+    // Monotonic time of the clock after which the cached value expires.
+    deadline: Duration,
+}
+
+struct CachedEntry {
+    inode: u64,
+    generation: u64,
+    deadline: Duration,
+}
+
+#[derive(Default)]
+struct Shard {
+    attrs: HashMap<u64, CachedAttr>,
+    // Directory entries, keyed by the parent inode owning the shard.
+    entries: HashMap<u64, HashMap<CString, CachedEntry>>,
+}
+
+/// Sharded cache of inode attributes and directory entries with time to live.
+pub struct AttrCache {
+    shards: Vec<Mutex<Shard>>,
+    notifier: Option<Arc<dyn Notifier>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl AttrCache {
+    /// Create a new cache with `shards` lock shards, rounded up to a power of two.
+    pub fn new(shards: usize) -> Self {
+        let shards = shards.max(1).next_power_of_two();
+        AttrCache {
+            shards: (0..shards).map(|_| Mutex::new(Shard::default())).collect(),
+            notifier: None,
+            clock: Arc::new(SystemClock::default()),
+        }
+    }
+
+    /// Forward explicit invalidations to `notifier`.
+    pub fn with_notifier(mut self, notifier: Arc<dyn Notifier>) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    /// Expire cached attributes and entries according to `clock`, instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn shard(&self, ino: u64) -> &Mutex<Shard> {
+        &self.shards[ino as usize & (self.shards.len() - 1)]
+    }
+
+    /// Cache attributes of `inode` for `ttl`.
+    pub fn insert(&self, inode: u64, attr: stat64, ttl: Duration) {
+        let mut shard = self.shard(inode).lock().unwrap();
+        if ttl == Duration::from_secs(0) {
+            shard.attrs.remove(&inode);
+        } else {
+            let deadline = self.clock.monotonic() + ttl;
+            shard.attrs.insert(inode, CachedAttr { attr, deadline });
+        }
+    }
+
+    /// Get cached attributes of `inode`, with the remaining time to live.
+    ///
+    /// Return `None` if there are no attributes cached or they have expired.
+    pub fn get(&self, inode: u64) -> Option<(stat64, Duration)> {
+        let now = self.clock.monotonic();
+        let mut shard = self.shard(inode).lock().unwrap();
+        let cached = shard.attrs.get(&inode)?;
+        if cached.deadline <= now {
+            shard.attrs.remove(&inode);
+            return None;
+        }
+
+        Some((cached.attr, cached.deadline - now))
+    }
+
+    /// Cache the directory entry `name` under `parent` for `entry.entry_timeout`, and the
+    /// attributes of the entry for `entry.attr_timeout`.
+    pub fn insert_entry(&self, parent: u64, name: &CStr, entry: &Entry) {
+        if entry.inode != 0 {
+            self.insert(entry.inode, entry.attr, entry.attr_timeout);
+        }
+
+        let mut shard = self.shard(parent).lock().unwrap();
+        if entry.entry_timeout == Duration::from_secs(0) {
+            if let Some(entries) = shard.entries.get_mut(&parent) {
+                entries.remove(name);
+            }
+            return;
+        }
+        let cached = CachedEntry {
+            inode: entry.inode,
+            generation: entry.generation,
+            deadline: self.clock.monotonic() + entry.entry_timeout,
+        };
+        shard
+            .entries
+            .entry(parent)
+            .or_default()
+            .insert(name.to_owned(), cached);
+    }
+
+    /// Get the cached directory entry `name` under `parent`, with timeouts clamped to the
+    /// remaining time to live.
+    ///
+    /// Negative entries are returned with `inode` set to zero. Return `None` if the entry isn't
+    /// cached or has expired, or if attributes of a positive entry are not cached.
+    pub fn get_entry(&self, parent: u64, name: &CStr) -> Option<Entry> {
+        let now = self.clock.monotonic();
+        let (inode, generation, remaining) = {
+            let mut shard = self.shard(parent).lock().unwrap();
+            let entries = shard.entries.get_mut(&parent)?;
+            let cached = entries.get(name)?;
+            if cached.deadline <= now {
+                entries.remove(name);
+                return None;
+            }
+            (cached.inode, cached.generation, cached.deadline - now)
+        };
+
+        let mut entry = Entry {
+            inode,
+            generation,
+            entry_timeout: remaining,
+            ..Default::default()
+        };
+        if inode != 0 {
+            let (attr, attr_timeout) = self.get(inode)?;
+            entry.attr = attr;
+            entry.attr_timeout = attr_timeout;
+        }
+
+        Some(entry)
+    }
+
+    /// Drop cached attributes of `inode`, and notify the guest.
+    pub fn invalidate(&self, inode: u64) {
+        self.shard(inode).lock().unwrap().attrs.remove(&inode);
+        if let Some(notifier) = self.notifier.as_ref() {
+            notifier.inval_inode(inode);
+        }
+    }
+
+    /// Drop the cached directory entry `name` under `parent`, and notify the guest.
+    pub fn invalidate_entry(&self, parent: u64, name: &CStr) {
+        if let Some(entries) = self.shard(parent).lock().unwrap().entries.get_mut(&parent) {
+            entries.remove(name);
+        }
+        if let Some(notifier) = self.notifier.as_ref() {
+            notifier.inval_entry(parent, name);
+        }
+    }
+
+    /// Drop the cached directory entry `name` under `parent`, which has been removed from the
+    /// origin, and notify the guest.
+    ///
+    /// The guest is told to delete the entry if it's cached as a positive entry, and to invalidate
+    /// it otherwise, since the inode it refers to is unknown. Cached attributes of the inode are
+    /// dropped too, as its link count has changed.
+    pub fn remove_entry(&self, parent: u64, name: &CStr) {
+        let child = self
+            .shard(parent)
+            .lock()
+            .unwrap()
+            .entries
+            .get_mut(&parent)
+            .and_then(|entries| entries.remove(name))
+            .map(|e| e.inode)
+            .filter(|inode| *inode != 0);
+        if let Some(child) = child {
+            self.shard(child).lock().unwrap().attrs.remove(&child);
+        }
+        if let Some(notifier) = self.notifier.as_ref() {
+            match child {
+                Some(child) => notifier.delete(parent, child, name),
+                None => notifier.inval_entry(parent, name),
+            }
+        }
+    }
+
+    /// Drop all cached directory entries under `parent` and cached attributes of `parent`, and
+    /// notify the guest.
+    ///
+    /// Attributes of the children are kept, since they may be reachable through other names.
+    pub fn invalidate_parent(&self, parent: u64) {
+        let entries = self
+            .shard(parent)
+            .lock()
+            .unwrap()
+            .entries
+            .remove(&parent)
+            .unwrap_or_default();
+        if let Some(notifier) = self.notifier.as_ref() {
+            for name in entries.keys() {
+                notifier.inval_entry(parent, name);
+            }
+        }
+        self.invalidate(parent);
+    }
+
+    /// Drop all expired attributes and entries, it should be called periodically.
+    pub fn flush(&self) {
+        let now = self.clock.monotonic();
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            shard.attrs.retain(|_, a| a.deadline > now);
+            shard.entries.retain(|_, entries| {
+                entries.retain(|_, e| e.deadline > now);
+                !entries.is_empty()
+            });
+        }
+    }
+
+    /// Get the number of cached attributes and directory entries, including expired ones.
+    pub fn len(&self) -> (usize, usize) {
+        self.shards.iter().fold((0, 0), |(attrs, entries), shard| {
+            let shard = shard.lock().unwrap();
+            let n: usize = shard.entries.values().map(|e| e.len()).sum();
+            (attrs + shard.attrs.len(), entries + n)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::clock::ManualClock;
+
+    fn attr(ino: u64) -> stat64 {
+        let mut st: stat64 = unsafe { std::mem::zeroed() };
+        st.st_ino = ino;
+        st
+    }
+
+    fn entry(ino: u64, timeout: Duration) -> Entry {
+        Entry {
+            inode: ino,
+            attr: attr(ino),
+            attr_timeout: timeout,
+            entry_timeout: timeout,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_attr_cache_ttl() {
+        let clock = Arc::new(ManualClock::default());
+        let cache = AttrCache::new(3).with_clock(clock.clone());
+        assert_eq!(cache.shards.len(), 4);
+
+        cache.insert(2, attr(2), Duration::from_secs(10));
+        cache.insert(3, attr(3), Duration::from_millis(10));
+        cache.insert(4, attr(4), Duration::from_secs(0));
+        assert_eq!(cache.get(2).unwrap().0.st_ino, 2);
+        assert_eq!(cache.get(2).unwrap().1, Duration::from_secs(10));
+        assert_eq!(cache.get(3).unwrap().0.st_ino, 3);
+        assert!(cache.get(4).is_none());
+
+        let name = CString::new("a").unwrap();
+        cache.insert_entry(1, &name, &entry(5, Duration::from_millis(10)));
+        cache.insert_entry(
+            1,
+            &CString::new("b").unwrap(),
+            &entry(0, Duration::from_secs(10)),
+        );
+        assert_eq!(cache.get_entry(1, &name).unwrap().inode, 5);
+        assert_eq!(
+            cache
+                .get_entry(1, &CString::new("b").unwrap())
+                .unwrap()
+                .inode,
+            0
+        );
+        assert_eq!(cache.len(), (3, 2));
+
+        clock.advance(Duration::from_millis(5));
+        assert_eq!(
+            cache.get(3).unwrap().1,
+            Duration::from_millis(5),
+            "remaining ttl follows the clock"
+        );
+        clock.advance(Duration::from_millis(5));
+        assert!(cache.get(3).is_none());
+        assert!(cache.get_entry(1, &name).is_none());
+        cache.flush();
+        assert_eq!(cache.len(), (1, 1));
+    }
+
+    #[test]
+    fn test_attr_cache_invalidate() {
+        let queue = Arc::new(NotifyQueue::new(16));
+        let cache = AttrCache::new(4).with_notifier(queue.clone());
+        let a = CString::new("a").unwrap();
+        let b = CString::new("b").unwrap();
+        let ttl = Duration::from_secs(10);
+
+        cache.insert_entry(1, &a, &entry(2, ttl));
+        cache.insert_entry(1, &b, &entry(3, ttl));
+        cache.insert(1, attr(1), ttl);
+
+        cache.invalidate(2);
+        assert!(cache.get(2).is_none());
+        // A positive entry without cached attributes can't be served.
+        assert!(cache.get_entry(1, &a).is_none());
+        assert_eq!(queue.take(), vec![NotifyEvent::InvalInode(2)]);
+
+        cache.invalidate_entry(1, &b);
+        assert!(cache.get_entry(1, &b).is_none());
+        assert!(cache.get(3).is_some());
+        assert_eq!(queue.take(), vec![NotifyEvent::InvalEntry(1, b.clone())]);
+
+        cache.insert_entry(1, &a, &entry(2, ttl));
+        cache.insert_entry(1, &b, &entry(3, ttl));
+        cache.invalidate_parent(1);
+        assert!(cache.get_entry(1, &a).is_none());
+        assert!(cache.get_entry(1, &b).is_none());
+        assert!(cache.get(1).is_none());
+        assert!(cache.get(2).is_some());
+        let mut events = queue.take();
+        assert_eq!(events.pop(), Some(NotifyEvent::InvalInode(1)));
+        events.sort_by(|x, y| format!("{:?}", x).cmp(&format!("{:?}", y)));
+        assert_eq!(
+            events,
+            vec![NotifyEvent::InvalEntry(1, a), NotifyEvent::InvalEntry(1, b)]
+        );
+    }
+
+    #[test]
+    fn test_attr_cache_remove_entry() {
+        let queue = Arc::new(NotifyQueue::new(16));
+        let cache = AttrCache::new(4).with_notifier(queue.clone());
+        let a = CString::new("a").unwrap();
+        let b = CString::new("b").unwrap();
+        let c = CString::new("c").unwrap();
+        let ttl = Duration::from_secs(10);
+
+        cache.insert_entry(1, &a, &entry(2, ttl));
+        cache.insert_entry(1, &b, &entry(0, ttl));
+
+        // Positive entries are deleted, with attributes of the child dropped.
+        cache.remove_entry(1, &a);
+        assert!(cache.get_entry(1, &a).is_none());
+        assert!(cache.get(2).is_none());
+        assert_eq!(queue.take(), vec![NotifyEvent::Delete(1, 2, a.clone())]);
+
+        // Negative or unknown entries fall back to invalidations.
+        cache.remove_entry(1, &b);
+        cache.remove_entry(1, &c);
+        cache.remove_entry(1, &a);
+        assert_eq!(
+            queue.take(),
+            vec![
+                NotifyEvent::InvalEntry(1, b),
+                NotifyEvent::InvalEntry(1, c),
+                NotifyEvent::InvalEntry(1, a),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_notifier_delete_fallback() {
+        struct InvalOnly(NotifyQueue);
+
+        impl Notifier for InvalOnly {
+            fn inval_inode(&self, ino: u64) {
+                self.0.inval_inode(ino)
+            }
+
+            fn inval_entry(&self, parent: u64, name: &CStr) {
+                self.0.inval_entry(parent, name)
+            }
+        }
+
+        let notifier = InvalOnly(NotifyQueue::new(4));
+        let a = CString::new("a").unwrap();
+        notifier.delete(1, 2, &a);
+        assert_eq!(notifier.0.take(), vec![NotifyEvent::InvalEntry(1, a)]);
+    }
+
+    #[test]
+    fn test_notify_queue_overflow() {
+        let queue = NotifyQueue::new(2);
+        queue.inval_inode(1);
+        queue.inval_inode(2);
+        queue.inval_inode(3);
+        assert_eq!(
+            queue.take(),
+            vec![NotifyEvent::InvalInode(2), NotifyEvent::InvalInode(3)]
+        );
+        assert!(queue.take().is_empty());
+    }
+}
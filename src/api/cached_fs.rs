@@ -0,0 +1,1348 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Caching adapter memoizing lookups and attributes of another file system.
+//!
+//! Read-mostly backends, like a network backed [FileSystem] mounted into a Vfs, pay a round trip
+//! for every lookup and getattr although their data rarely changes. A [CachedFs] wraps such a
+//! file system, caches the attributes it replies by inode and the directory entries by
+//! `(parent, name)` for configurable times to live, and serves repeated requests from the cache.
+//! Negative lookups may be cached too, for their own time to live. The cache is bounded by an
+//! LRU of [CachedFsConfig::capacity] attributes and entries.
+//!
+//! Cached state is dropped by any operation through the wrapper changing it, like unlink, rename,
+//! setattr or write. Changes made to the backend by other means are only seen once the cached
+//! state expires, so the times to live should stay short for backends shared with other clients.
+//! Link counts of other names of an inode unlinked by a name not cached may be stale as well.
+//!
+//! Lookups served from the cache don't reach the backend, so the adapter keeps track of the
+//! lookup counts of inodes and only forwards forgets of lookups the backend has seen. Cached
+//! state of an inode is kept until the guest forgets all its lookups, as the backend may reuse
+//! the inode number only after that.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::{CStr, CString};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[cfg(not(feature = "async-io"))]
+use std::any::Any;
+
+use super::filesystem::{
+    Context, DirEntry, Entry, FileLock, FileSystem, GetxattrReply, IoctlReply, ListxattrReply,
+    ZeroCopyReader, ZeroCopyWriter,
+};
+#[cfg(target_os = "macos")]
+use crate::abi::fuse_abi::GetxtimesOut;
+use crate::abi::fuse_abi::{
+    stat64, statvfs64, CreateIn, FsOptions, OpenOptions, SetattrValid, Statx,
+};
+#[cfg(feature = "virtiofs")]
+use crate::abi::virtio_fs::RemovemappingOne;
+use crate::api::clock::{Clock, SystemClock};
+#[cfg(not(feature = "async-io"))]
+use crate::api::vfs::{BackendFileSystem, BackendTimeouts};
+#[cfg(feature = "virtiofs")]
+use crate::transport::FsCacheReqHandler;
+
+/// Configuration of a [CachedFs].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CachedFsConfig {
+    /// How long attributes replied by getattr and lookup are cached.
+    ///
+    /// The default value for this option is 1 second.
+    pub attr_ttl: Duration,
+    /// How long positive directory entries are cached.
+    ///
+    /// The default value for this option is 1 second.
+    pub entry_ttl: Duration,
+    /// How long negative directory entries are cached, `None` to always look up missing names.
+    ///
+    /// The default value for this option is `None`.
+    pub negative_ttl: Option<Duration>,
+    /// Max number of attributes and entries cached, the least recently used are evicted first.
+    ///
+    /// The default value for this option is 65536.
+    pub capacity: usize,
+}
+
+impl Default for CachedFsConfig {
+    fn default() -> Self {
+        CachedFsConfig {
+            attr_ttl: Duration::from_secs(1),
+            entry_ttl: Duration::from_secs(1),
+            negative_ttl: None,
+            capacity: 65536,
+        }
+    }
+}
+
+/// Counters of the requests served by a [CachedFs].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CachedFsStats {
+    /// Getattr requests served from the cache.
+    pub attr_hits: u64,
+    /// Getattr requests forwarded to the backend.
+    pub attr_misses: u64,
+    /// Lookups served from the cache, negative ones included.
+    pub entry_hits: u64,
+    /// Lookups forwarded to the backend.
+    pub entry_misses: u64,
+    /// Attributes and entries evicted to stay within the capacity.
+    pub evictions: u64,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Attr(u64),
+    Entry(u64, CString),
+}
+
+#[derive(Clone, Copy)]
+enum Cached {
+    // Attributes with the timeout replied by the backend.
+    Attr(stat64, Duration),
+    // A positive entry, whose attributes are cached separately, or a negative entry of inode 0.
+    Entry(Entry),
+    // A negative entry replied as `ENOENT`.
+    Enoent,
+}
+
+struct Item {
+    value: Cached,
+    // Monotonic time of the clock after which the item expires.
+    deadline: Duration,
+    // Position in the LRU.
+    tick: u64,
+}
+
+// Lookups of an inode replied by the adapter, and how many of them were served from the cache
+// without reaching the backend.
+#[derive(Default)]
+struct Lookups {
+    total: u64,
+    served: u64,
+}
+
+#[derive(Default)]
+struct State {
+    items: HashMap<Key, Item>,
+    lru: BTreeMap<u64, Key>,
+    tick: u64,
+    // Names of the cached entries in each directory, and names of the cached positive entries
+    // referring to each inode, to drop them when an inode is forgotten.
+    children: HashMap<u64, HashSet<CString>>,
+    names: HashMap<u64, HashSet<(u64, CString)>>,
+    lookups: HashMap<u64, Lookups>,
+    // Bumped by each invalidation, so replies of requests racing with one aren't cached.
+    epoch: u64,
+    stats: CachedFsStats,
+}
+
+impl State {
+    // Get the cached value of `key` with its remaining time to live, and mark it used.
+    fn get(&mut self, key: &Key, now: Duration) -> Option<(Cached, Duration)> {
+        let deadline = self.items.get(key)?.deadline;
+        if deadline <= now {
+            self.remove(key);
+            return None;
+        }
+        self.tick += 1;
+        let item = self.items.get_mut(key).unwrap();
+        let old = std::mem::replace(&mut item.tick, self.tick);
+        let value = item.value;
+        self.lru.remove(&old);
+        self.lru.insert(self.tick, key.clone());
+        Some((value, deadline - now))
+    }
+
+    fn insert(&mut self, key: Key, value: Cached, deadline: Duration, capacity: usize) {
+        self.remove(&key);
+        if let (Key::Entry(parent, name), Cached::Entry(e)) = (&key, &value) {
+            if e.inode != 0 {
+                self.names
+                    .entry(e.inode)
+                    .or_default()
+                    .insert((*parent, name.clone()));
+            }
+        }
+        if let Key::Entry(parent, name) = &key {
+            self.children
+                .entry(*parent)
+                .or_default()
+                .insert(name.clone());
+        }
+        self.tick += 1;
+        self.lru.insert(self.tick, key.clone());
+        let tick = self.tick;
+        self.items.insert(
+            key,
+            Item {
+                value,
+                deadline,
+                tick,
+            },
+        );
+
+        while self.items.len() > capacity.max(1) {
+            let oldest = self.lru.keys().next().copied().unwrap();
+            let key = self.lru[&oldest].clone();
+            self.remove(&key);
+            self.stats.evictions += 1;
+        }
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Cached> {
+        let item = self.items.remove(key)?;
+        self.lru.remove(&item.tick);
+        if let Key::Entry(parent, name) = key {
+            remove_from(&mut self.children, *parent, name);
+            if let Cached::Entry(e) = item.value {
+                remove_from(&mut self.names, e.inode, &(*parent, name.clone()));
+            }
+        }
+        Some(item.value)
+    }
+
+    // Drop the entry `name` under `parent`, return the inode it referred to.
+    fn remove_entry(&mut self, parent: u64, name: &CStr) -> Option<u64> {
+        self.epoch += 1;
+        match self.remove(&Key::Entry(parent, name.to_owned())) {
+            Some(Cached::Entry(e)) if e.inode != 0 => Some(e.inode),
+            _ => None,
+        }
+    }
+
+    fn remove_attr(&mut self, inode: u64) {
+        self.epoch += 1;
+        self.remove(&Key::Attr(inode));
+    }
+
+    // Drop all cached state of `inode`, which the backend may reuse.
+    fn remove_inode(&mut self, inode: u64) {
+        self.remove_attr(inode);
+        for (parent, name) in self.names.remove(&inode).unwrap_or_default() {
+            self.remove(&Key::Entry(parent, name));
+        }
+        for name in self.children.remove(&inode).unwrap_or_default() {
+            self.remove(&Key::Entry(inode, name));
+        }
+    }
+
+    // Account a lookup of `inode` replied to the guest, `served` from the cache.
+    fn add_lookup(&mut self, inode: u64, served: bool) {
+        if inode == 0 {
+            return;
+        }
+        let lookups = self.lookups.entry(inode).or_default();
+        lookups.total += 1;
+        if served {
+            lookups.served += 1;
+        }
+    }
+
+    // Account `count` lookups of `inode` forgotten by the guest, return how many of them the
+    // backend has seen.
+    fn forget(&mut self, inode: u64, count: u64) -> u64 {
+        let lookups = match self.lookups.get_mut(&inode) {
+            Some(l) => l,
+            // Looked up before the adapter was set up, or by requests it doesn't track.
+            None => return count,
+        };
+        let served = lookups.served.min(count);
+        lookups.served -= served;
+        lookups.total = lookups.total.saturating_sub(count);
+        if lookups.total == 0 {
+            self.lookups.remove(&inode);
+            self.remove_inode(inode);
+        }
+        count - served
+    }
+}
+
+fn remove_from<K: std::hash::Hash + Eq, V: std::hash::Hash + Eq>(
+    map: &mut HashMap<K, HashSet<V>>,
+    key: K,
+    value: &V,
+) {
+    if let Some(set) = map.get_mut(&key) {
+        set.remove(value);
+        if set.is_empty() {
+            map.remove(&key);
+        }
+    }
+}
+
+/// A [FileSystem] caching lookups and attributes of the file system `F`.
+pub struct CachedFs<F: FileSystem> {
+    fs: F,
+    cfg: CachedFsConfig,
+    clock: Arc<dyn Clock>,
+    state: Mutex<State>,
+}
+
+impl<F: FileSystem> CachedFs<F> {
+    /// Create an adapter caching lookups and attributes of `fs` as configured by `cfg`.
+    pub fn new(fs: F, cfg: CachedFsConfig) -> Self {
+        CachedFs {
+            fs,
+            cfg,
+            clock: Arc::new(SystemClock::default()),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Expire cached attributes and entries according to `clock`, instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Get the wrapped file system.
+    pub fn inner(&self) -> &F {
+        &self.fs
+    }
+
+    /// Get the counters of cache hits and misses.
+    pub fn stats(&self) -> CachedFsStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// Get the number of cached attributes and entries, including expired ones.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().items.len()
+    }
+
+    /// Whether nothing is cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drop all cached attributes and entries, lookup counts are kept.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.epoch += 1;
+        state.items.clear();
+        state.lru.clear();
+        state.children.clear();
+        state.names.clear();
+    }
+
+    fn deadline(&self, ttl: Duration) -> Duration {
+        self.clock.monotonic() + ttl
+    }
+
+    // Serve the lookup of `name` under `parent` from the cache.
+    fn cached_lookup(&self, parent: u64, name: &CStr) -> Option<io::Result<Entry>> {
+        let now = self.clock.monotonic();
+        let mut state = self.state.lock().unwrap();
+        let mut entry = match state.get(&Key::Entry(parent, name.to_owned()), now)? {
+            (Cached::Enoent, _) => {
+                state.stats.entry_hits += 1;
+                return Some(Err(io::Error::from_raw_os_error(libc::ENOENT)));
+            }
+            (Cached::Entry(e), remaining) => Entry {
+                entry_timeout: e.entry_timeout.min(remaining),
+                ..e
+            },
+            (Cached::Attr(..), _) => return None,
+        };
+        if entry.inode != 0 {
+            // Positive entries can't be served without the attributes of their inode.
+            match state.get(&Key::Attr(entry.inode), now) {
+                Some((Cached::Attr(attr, timeout), remaining)) => {
+                    entry.attr = attr;
+                    entry.attr_timeout = timeout.min(remaining);
+                }
+                _ => return None,
+            }
+            state.add_lookup(entry.inode, true);
+        }
+        state.stats.entry_hits += 1;
+        Some(Ok(entry))
+    }
+
+    fn cache_lookup(&self, parent: u64, name: &CStr, epoch: u64, res: &io::Result<Entry>) {
+        let mut state = self.state.lock().unwrap();
+        state.stats.entry_misses += 1;
+        let entry = match res {
+            Ok(e) => *e,
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                if let (Some(ttl), true) = (self.cfg.negative_ttl, state.epoch == epoch) {
+                    let key = Key::Entry(parent, name.to_owned());
+                    state.insert(key, Cached::Enoent, self.deadline(ttl), self.cfg.capacity);
+                }
+                return;
+            }
+            Err(_) => return,
+        };
+        state.add_lookup(entry.inode, false);
+        if state.epoch != epoch {
+            return;
+        }
+
+        let key = Key::Entry(parent, name.to_owned());
+        if entry.inode == 0 {
+            if let Some(ttl) = self.cfg.negative_ttl {
+                let value = Cached::Entry(entry);
+                state.insert(key, value, self.deadline(ttl), self.cfg.capacity);
+            }
+            return;
+        }
+        let attr = Cached::Attr(entry.attr, entry.attr_timeout);
+        let deadline = self.deadline(self.cfg.attr_ttl);
+        state.insert(Key::Attr(entry.inode), attr, deadline, self.cfg.capacity);
+        let deadline = self.deadline(self.cfg.entry_ttl);
+        state.insert(key, Cached::Entry(entry), deadline, self.cfg.capacity);
+    }
+
+    fn epoch(&self) -> u64 {
+        self.state.lock().unwrap().epoch
+    }
+
+    // Drop the cached attributes of `inode`, changed by a request.
+    fn changed(&self, inode: u64) {
+        self.state.lock().unwrap().remove_attr(inode);
+    }
+
+    // Account the lookup of the entry created by a request, and drop the cached entry of the
+    // same name and the attributes of the parent directory.
+    fn created(&self, parent: u64, name: Option<&CStr>, entry: Option<&Entry>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(name) = name {
+            state.remove_entry(parent, name);
+        }
+        state.remove_attr(parent);
+        if let Some(entry) = entry {
+            state.add_lookup(entry.inode, false);
+        }
+    }
+
+    // Drop the cached entry `name` under `parent` being removed, with the attributes of the
+    // parent directory and of the inode it referred to.
+    fn removed(&self, parent: u64, name: &CStr) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(child) = state.remove_entry(parent, name) {
+            state.remove_attr(child);
+        }
+        state.remove_attr(parent);
+    }
+}
+
+#[allow(unused_variables)]
+impl<F: FileSystem> FileSystem for CachedFs<F> {
+    type Inode = F::Inode;
+    type Handle = F::Handle;
+
+    fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
+        self.fs.init(capable)
+    }
+
+    fn time_gran(&self) -> u32 {
+        self.fs.time_gran()
+    }
+
+    fn destroy(&self) {
+        self.clear();
+        self.state.lock().unwrap().lookups.clear();
+        self.fs.destroy()
+    }
+
+    fn forget_all(&self) {
+        self.clear();
+        self.state.lock().unwrap().lookups.clear();
+        self.fs.forget_all()
+    }
+
+    fn interrupt(&self, ctx: &Context, unique: u64) {
+        self.fs.interrupt(ctx, unique)
+    }
+
+    fn prepare_destroy(&self) -> io::Result<()> {
+        self.fs.prepare_destroy()
+    }
+
+    fn lookup(&self, ctx: &Context, parent: Self::Inode, name: &CStr) -> io::Result<Entry> {
+        let parent = parent.into();
+        if let Some(res) = self.cached_lookup(parent, name) {
+            return res;
+        }
+        let epoch = self.epoch();
+        let res = self.fs.lookup(ctx, parent.into(), name);
+        self.cache_lookup(parent, name, epoch, &res);
+        res
+    }
+
+    fn forget(&self, ctx: &Context, inode: Self::Inode, count: u64) {
+        let inode = inode.into();
+        let count = self.state.lock().unwrap().forget(inode, count);
+        if count > 0 {
+            self.fs.forget(ctx, inode.into(), count)
+        }
+    }
+
+    fn batch_forget(&self, ctx: &Context, requests: Vec<(Self::Inode, u64)>) {
+        let requests = {
+            let mut state = self.state.lock().unwrap();
+            requests
+                .into_iter()
+                .filter_map(|(inode, count)| {
+                    let inode = inode.into();
+                    match state.forget(inode, count) {
+                        0 => None,
+                        count => Some((inode.into(), count)),
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+        if !requests.is_empty() {
+            self.fs.batch_forget(ctx, requests)
+        }
+    }
+
+    fn getattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Option<Self::Handle>,
+    ) -> io::Result<(stat64, Duration)> {
+        let inode = inode.into();
+        let epoch = {
+            let now = self.clock.monotonic();
+            let mut state = self.state.lock().unwrap();
+            if let Some((Cached::Attr(attr, timeout), remaining)) =
+                state.get(&Key::Attr(inode), now)
+            {
+                state.stats.attr_hits += 1;
+                return Ok((attr, timeout.min(remaining)));
+            }
+            state.stats.attr_misses += 1;
+            state.epoch
+        };
+
+        let res = self.fs.getattr(ctx, inode.into(), handle);
+        if let Ok((attr, timeout)) = res.as_ref() {
+            let mut state = self.state.lock().unwrap();
+            if state.epoch == epoch {
+                let deadline = self.deadline(self.cfg.attr_ttl);
+                let value = Cached::Attr(*attr, *timeout);
+                state.insert(Key::Attr(inode), value, deadline, self.cfg.capacity);
+            }
+        }
+        res
+    }
+
+    // Replies to statx aren't cached, they carry fields beyond the cached attributes.
+    fn statx(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Option<Self::Handle>,
+        flags: u32,
+        mask: u32,
+    ) -> io::Result<(Statx, Duration)> {
+        self.fs.statx(ctx, inode, handle, flags, mask)
+    }
+
+    fn setattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        attr: stat64,
+        handle: Option<Self::Handle>,
+        valid: SetattrValid,
+    ) -> io::Result<(stat64, Duration)> {
+        let inode = inode.into();
+        let res = self.fs.setattr(ctx, inode.into(), attr, handle, valid);
+        self.changed(inode);
+        res
+    }
+
+    fn readlink(&self, ctx: &Context, inode: Self::Inode) -> io::Result<Vec<u8>> {
+        self.fs.readlink(ctx, inode)
+    }
+
+    fn symlink(
+        &self,
+        ctx: &Context,
+        linkname: &CStr,
+        parent: Self::Inode,
+        name: &CStr,
+    ) -> io::Result<Entry> {
+        let parent = parent.into();
+        let res = self.fs.symlink(ctx, linkname, parent.into(), name);
+        self.created(parent, Some(name), res.as_ref().ok());
+        res
+    }
+
+    fn mknod(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        name: &CStr,
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+    ) -> io::Result<Entry> {
+        let parent = inode.into();
+        let res = self.fs.mknod(ctx, parent.into(), name, mode, rdev, umask);
+        self.created(parent, Some(name), res.as_ref().ok());
+        res
+    }
+
+    fn mkdir(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        name: &CStr,
+        mode: u32,
+        umask: u32,
+    ) -> io::Result<Entry> {
+        let parent = parent.into();
+        let res = self.fs.mkdir(ctx, parent.into(), name, mode, umask);
+        self.created(parent, Some(name), res.as_ref().ok());
+        res
+    }
+
+    fn unlink(&self, ctx: &Context, parent: Self::Inode, name: &CStr) -> io::Result<()> {
+        let parent = parent.into();
+        let res = self.fs.unlink(ctx, parent.into(), name);
+        self.removed(parent, name);
+        res
+    }
+
+    fn rmdir(&self, ctx: &Context, parent: Self::Inode, name: &CStr) -> io::Result<()> {
+        let parent = parent.into();
+        let res = self.fs.rmdir(ctx, parent.into(), name);
+        self.removed(parent, name);
+        res
+    }
+
+    fn rename(
+        &self,
+        ctx: &Context,
+        olddir: Self::Inode,
+        oldname: &CStr,
+        newdir: Self::Inode,
+        newname: &CStr,
+        flags: u32,
+    ) -> io::Result<()> {
+        let (olddir, newdir) = (olddir.into(), newdir.into());
+        let res = self
+            .fs
+            .rename(ctx, olddir.into(), oldname, newdir.into(), newname, flags);
+        self.removed(olddir, oldname);
+        self.removed(newdir, newname);
+        res
+    }
+
+    fn link(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        newparent: Self::Inode,
+        newname: &CStr,
+    ) -> io::Result<Entry> {
+        let (inode, newparent) = (inode.into(), newparent.into());
+        let res = self.fs.link(ctx, inode.into(), newparent.into(), newname);
+        self.changed(inode);
+        self.created(newparent, Some(newname), res.as_ref().ok());
+        res
+    }
+
+    fn open(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+        fuse_flags: u32,
+    ) -> io::Result<(Option<Self::Handle>, OpenOptions)> {
+        let inode = inode.into();
+        let res = self.fs.open(ctx, inode.into(), flags, fuse_flags);
+        if flags as i32 & libc::O_TRUNC != 0 {
+            self.changed(inode);
+        }
+        res
+    }
+
+    fn create(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        name: &CStr,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions)> {
+        let parent = parent.into();
+        let res = self.fs.create(ctx, parent.into(), name, args);
+        self.created(parent, Some(name), res.as_ref().ok().map(|r| &r.0));
+        res
+    }
+
+    fn tmpfile(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions)> {
+        let parent = parent.into();
+        let res = self.fs.tmpfile(ctx, parent.into(), args);
+        self.created(parent, None, res.as_ref().ok().map(|r| &r.0));
+        res
+    }
+
+    fn atomic_open(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        name: &CStr,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions)> {
+        let parent = parent.into();
+        let res = self.fs.atomic_open(ctx, parent.into(), name, args);
+        self.created(parent, Some(name), res.as_ref().ok().map(|r| &r.0));
+        if let Ok((entry, ..)) = res.as_ref() {
+            // An existing file may have been truncated.
+            self.changed(entry.inode);
+        }
+        res
+    }
+
+    fn read(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        w: &mut dyn ZeroCopyWriter,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        flags: u32,
+    ) -> io::Result<usize> {
+        self.fs
+            .read(ctx, inode, handle, w, size, offset, lock_owner, flags)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        r: &mut dyn ZeroCopyReader,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        delayed_write: bool,
+        flags: u32,
+        fuse_flags: u32,
+    ) -> io::Result<usize> {
+        let inode = inode.into();
+        let res = self.fs.write(
+            ctx,
+            inode.into(),
+            handle,
+            r,
+            size,
+            offset,
+            lock_owner,
+            delayed_write,
+            flags,
+            fuse_flags,
+        );
+        self.changed(inode);
+        res
+    }
+
+    fn flush(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        lock_owner: u64,
+    ) -> io::Result<()> {
+        self.fs.flush(ctx, inode, handle, lock_owner)
+    }
+
+    fn fsync(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        datasync: bool,
+        handle: Self::Handle,
+    ) -> io::Result<()> {
+        self.fs.fsync(ctx, inode, datasync, handle)
+    }
+
+    fn fallocate(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        mode: u32,
+        offset: u64,
+        length: u64,
+    ) -> io::Result<()> {
+        let inode = inode.into();
+        let res = self
+            .fs
+            .fallocate(ctx, inode.into(), handle, mode, offset, length);
+        self.changed(inode);
+        res
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn release(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+        handle: Self::Handle,
+        flush: bool,
+        flock_release: bool,
+        lock_owner: Option<u64>,
+    ) -> io::Result<()> {
+        self.fs
+            .release(ctx, inode, flags, handle, flush, flock_release, lock_owner)
+    }
+
+    fn statfs(&self, ctx: &Context, inode: Self::Inode) -> io::Result<statvfs64> {
+        self.fs.statfs(ctx, inode)
+    }
+
+    fn syncfs(&self, ctx: &Context, inode: Self::Inode) -> io::Result<()> {
+        self.fs.syncfs(ctx, inode)
+    }
+
+    fn setxattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        name: &CStr,
+        value: &[u8],
+        flags: u32,
+    ) -> io::Result<()> {
+        let inode = inode.into();
+        let res = self.fs.setxattr(ctx, inode.into(), name, value, flags);
+        self.changed(inode);
+        res
+    }
+
+    fn getxattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        name: &CStr,
+        size: u32,
+    ) -> io::Result<GetxattrReply> {
+        self.fs.getxattr(ctx, inode, name, size)
+    }
+
+    fn listxattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        size: u32,
+    ) -> io::Result<ListxattrReply> {
+        self.fs.listxattr(ctx, inode, size)
+    }
+
+    fn removexattr(&self, ctx: &Context, inode: Self::Inode, name: &CStr) -> io::Result<()> {
+        let inode = inode.into();
+        let res = self.fs.removexattr(ctx, inode.into(), name);
+        self.changed(inode);
+        res
+    }
+
+    fn opendir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+    ) -> io::Result<(Option<Self::Handle>, OpenOptions)> {
+        self.fs.opendir(ctx, inode, flags)
+    }
+
+    fn readdir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        size: u32,
+        offset: u64,
+        add_entry: &mut dyn FnMut(DirEntry) -> io::Result<usize>,
+    ) -> io::Result<()> {
+        self.fs.readdir(ctx, inode, handle, size, offset, add_entry)
+    }
+
+    fn readdirplus(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        size: u32,
+        offset: u64,
+        add_entry: &mut dyn FnMut(DirEntry, Entry) -> io::Result<usize>,
+    ) -> io::Result<()> {
+        // Entries added to the reply are looked up, the backend forgets the others itself.
+        self.fs
+            .readdirplus(ctx, inode, handle, size, offset, &mut |dir_entry, entry| {
+                let res = add_entry(dir_entry, entry);
+                if let Ok(n) = res {
+                    if n > 0 {
+                        self.state.lock().unwrap().add_lookup(entry.inode, false);
+                    }
+                }
+                res
+            })
+    }
+
+    fn fsyncdir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        datasync: bool,
+        handle: Self::Handle,
+    ) -> io::Result<()> {
+        self.fs.fsyncdir(ctx, inode, datasync, handle)
+    }
+
+    fn releasedir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+        handle: Self::Handle,
+    ) -> io::Result<()> {
+        self.fs.releasedir(ctx, inode, flags, handle)
+    }
+
+    #[cfg(feature = "virtiofs")]
+    #[allow(clippy::too_many_arguments)]
+    fn setupmapping(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        foffset: u64,
+        len: u64,
+        flags: u64,
+        moffset: u64,
+        vu_req: &mut dyn FsCacheReqHandler,
+    ) -> io::Result<()> {
+        self.fs
+            .setupmapping(ctx, inode, handle, foffset, len, flags, moffset, vu_req)
+    }
+
+    #[cfg(feature = "virtiofs")]
+    fn removemapping(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        requests: Vec<RemovemappingOne>,
+        vu_req: &mut dyn FsCacheReqHandler,
+    ) -> io::Result<()> {
+        self.fs.removemapping(ctx, inode, requests, vu_req)
+    }
+
+    fn access(&self, ctx: &Context, inode: Self::Inode, mask: u32) -> io::Result<()> {
+        self.fs.access(ctx, inode, mask)
+    }
+
+    fn lseek(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        offset: u64,
+        whence: u32,
+    ) -> io::Result<u64> {
+        self.fs.lseek(ctx, inode, handle, offset, whence)
+    }
+
+    fn copyfilerange(
+        &self,
+        ctx: &Context,
+        inode_in: Self::Inode,
+        handle_in: Self::Handle,
+        offset_in: u64,
+        inode_out: Self::Inode,
+        handle_out: Self::Handle,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> io::Result<usize> {
+        let inode = inode_out.into();
+        let res = self.fs.copyfilerange(
+            ctx,
+            inode_in,
+            handle_in,
+            offset_in,
+            inode.into(),
+            handle_out,
+            offset_out,
+            len,
+            flags,
+        );
+        self.changed(inode);
+        res
+    }
+
+    fn getlk(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<FileLock> {
+        self.fs.getlk(ctx, inode, handle, owner, lock, flags)
+    }
+
+    fn setlk(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.fs.setlk(ctx, inode, handle, owner, lock, flags)
+    }
+
+    fn setlkw(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.fs.setlkw(ctx, inode, handle, owner, lock, flags)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn ioctl(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        flags: u32,
+        cmd: u32,
+        arg: u64,
+        in_data: &[u8],
+        out_size: u32,
+    ) -> io::Result<IoctlReply> {
+        // Ioctls may change inode flags, project ids or the size of files.
+        let inode = inode.into();
+        let res = self.fs.ioctl(
+            ctx,
+            inode.into(),
+            handle,
+            flags,
+            cmd,
+            arg,
+            in_data,
+            out_size,
+        );
+        self.changed(inode);
+        res
+    }
+
+    fn bmap(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        block: u64,
+        blocksize: u32,
+    ) -> io::Result<u64> {
+        self.fs.bmap(ctx, inode, block, blocksize)
+    }
+
+    fn poll(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        khandle: u64,
+        flags: u32,
+        events: u32,
+    ) -> io::Result<u32> {
+        self.fs.poll(ctx, inode, handle, khandle, flags, events)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn setvolname(&self, ctx: &Context, name: &CStr) -> io::Result<()> {
+        self.fs.setvolname(ctx, name)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn exchange(
+        &self,
+        ctx: &Context,
+        olddir: Self::Inode,
+        oldname: &CStr,
+        newdir: Self::Inode,
+        newname: &CStr,
+        options: u64,
+    ) -> io::Result<()> {
+        let (olddir, newdir) = (olddir.into(), newdir.into());
+        let res = self
+            .fs
+            .exchange(ctx, olddir.into(), oldname, newdir.into(), newname, options);
+        self.removed(olddir, oldname);
+        self.removed(newdir, newname);
+        res
+    }
+
+    #[cfg(target_os = "macos")]
+    fn getxtimes(&self, ctx: &Context, inode: Self::Inode) -> io::Result<GetxtimesOut> {
+        self.fs.getxtimes(ctx, inode)
+    }
+
+    fn notify_reply(&self) -> io::Result<()> {
+        self.fs.notify_reply()
+    }
+
+    fn debug_nlookup(&self, inode: Self::Inode) -> Option<u64> {
+        self.fs.debug_nlookup(inode)
+    }
+}
+
+// Vfs backends must also be asynchronous file systems with `async-io`, which the adapter isn't.
+#[cfg(not(feature = "async-io"))]
+impl<F: BackendFileSystem + 'static> BackendFileSystem for CachedFs<F> {
+    fn mount(&self) -> io::Result<(Entry, u64)> {
+        self.fs.mount()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn max_read(&self) -> Option<u32> {
+        self.fs.max_read()
+    }
+
+    fn max_write(&self) -> Option<u32> {
+        self.fs.max_write()
+    }
+
+    fn timeouts(&self) -> BackendTimeouts {
+        self.fs.timeouts()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abi::fuse_abi::ROOT_ID;
+    use crate::api::clock::ManualClock;
+
+    // A root directory holding files named after their inode numbers, which counts the requests
+    // reaching it.
+    #[derive(Default)]
+    struct NumFs {
+        files: Mutex<HashSet<u64>>,
+        calls: Mutex<HashMap<&'static str, u64>>,
+        forgets: Mutex<Vec<(u64, u64)>>,
+    }
+
+    impl NumFs {
+        fn new(files: &[u64]) -> Self {
+            let fs = NumFs::default();
+            fs.files.lock().unwrap().extend(files);
+            fs
+        }
+
+        fn count(&self, op: &'static str) {
+            *self.calls.lock().unwrap().entry(op).or_default() += 1;
+        }
+
+        fn calls(&self, op: &'static str) -> u64 {
+            self.calls.lock().unwrap().get(op).copied().unwrap_or(0)
+        }
+
+        fn attr(&self, inode: u64) -> io::Result<stat64> {
+            if !self.files.lock().unwrap().contains(&inode) {
+                return Err(io::Error::from_raw_os_error(libc::ENOENT));
+            }
+            let mut st: stat64 = unsafe { std::mem::zeroed() };
+            st.st_ino = inode;
+            Ok(st)
+        }
+    }
+
+    impl FileSystem for NumFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn lookup(&self, _ctx: &Context, parent: u64, name: &CStr) -> io::Result<Entry> {
+            self.count("lookup");
+            let inode = name.to_str().unwrap().parse().unwrap();
+            assert_eq!(parent, ROOT_ID);
+            Ok(Entry {
+                inode,
+                attr: self.attr(inode)?,
+                attr_timeout: Duration::from_secs(10),
+                entry_timeout: Duration::from_secs(10),
+                ..Default::default()
+            })
+        }
+
+        fn forget(&self, _ctx: &Context, inode: u64, count: u64) {
+            self.forgets.lock().unwrap().push((inode, count));
+        }
+
+        fn batch_forget(&self, _ctx: &Context, requests: Vec<(u64, u64)>) {
+            self.forgets.lock().unwrap().extend(requests);
+        }
+
+        fn getattr(
+            &self,
+            _ctx: &Context,
+            inode: u64,
+            _handle: Option<u64>,
+        ) -> io::Result<(stat64, Duration)> {
+            self.count("getattr");
+            Ok((self.attr(inode)?, Duration::from_secs(10)))
+        }
+
+        fn setattr(
+            &self,
+            _ctx: &Context,
+            inode: u64,
+            _attr: stat64,
+            _handle: Option<u64>,
+            _valid: SetattrValid,
+        ) -> io::Result<(stat64, Duration)> {
+            Ok((self.attr(inode)?, Duration::from_secs(10)))
+        }
+
+        fn unlink(&self, _ctx: &Context, _parent: u64, name: &CStr) -> io::Result<()> {
+            let inode = name.to_str().unwrap().parse().unwrap();
+            self.files.lock().unwrap().remove(&inode);
+            Ok(())
+        }
+    }
+
+    fn cached_fs(files: &[u64], cfg: CachedFsConfig) -> (Arc<ManualClock>, CachedFs<NumFs>) {
+        let clock = Arc::new(ManualClock::default());
+        let fs = CachedFs::new(NumFs::new(files), cfg).with_clock(clock.clone());
+        (clock, fs)
+    }
+
+    fn name(inode: u64) -> CString {
+        CString::new(inode.to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_cached_fs_ttl() {
+        let (clock, fs) = cached_fs(
+            &[2],
+            CachedFsConfig {
+                negative_ttl: Some(Duration::from_millis(500)),
+                ..Default::default()
+            },
+        );
+        let ctx = Context::default();
+
+        // Attributes replied by lookups are cached too, timeouts are clamped to the time left.
+        assert_eq!(fs.lookup(&ctx, ROOT_ID, &name(2)).unwrap().inode, 2);
+        clock.advance(Duration::from_millis(200));
+        let entry = fs.lookup(&ctx, ROOT_ID, &name(2)).unwrap();
+        assert_eq!(entry.attr.st_ino, 2);
+        assert_eq!(entry.entry_timeout, Duration::from_millis(800));
+        assert_eq!(entry.attr_timeout, Duration::from_millis(800));
+        assert_eq!(fs.getattr(&ctx, 2, None).unwrap().0.st_ino, 2);
+        assert_eq!(
+            (fs.inner().calls("lookup"), fs.inner().calls("getattr")),
+            (1, 0)
+        );
+
+        // Negative entries expire on their own.
+        for _ in 0..2 {
+            let res = fs.lookup(&ctx, ROOT_ID, &name(3)).map(|e| e.inode);
+            assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::ENOENT));
+        }
+        assert_eq!(fs.inner().calls("lookup"), 2);
+        clock.advance(Duration::from_millis(600));
+        assert!(fs.lookup(&ctx, ROOT_ID, &name(3)).is_err());
+        fs.lookup(&ctx, ROOT_ID, &name(2)).unwrap();
+        assert_eq!(fs.inner().calls("lookup"), 3);
+        clock.advance(Duration::from_millis(200));
+        fs.lookup(&ctx, ROOT_ID, &name(2)).unwrap();
+        assert_eq!(fs.inner().calls("lookup"), 4);
+
+        // Changes through the adapter drop cached state.
+        fs.setattr(&ctx, 2, entry.attr, None, SetattrValid::MODE)
+            .unwrap();
+        fs.getattr(&ctx, 2, None).unwrap();
+        assert_eq!(fs.inner().calls("getattr"), 1);
+        fs.unlink(&ctx, ROOT_ID, &name(2)).unwrap();
+        assert!(fs.lookup(&ctx, ROOT_ID, &name(2)).is_err());
+        assert!(fs.getattr(&ctx, 2, None).is_err());
+
+        assert_eq!(
+            fs.stats(),
+            CachedFsStats {
+                attr_hits: 1,
+                attr_misses: 2,
+                entry_hits: 3,
+                entry_misses: 5,
+                evictions: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_cached_fs_forget() {
+        let (_, fs) = cached_fs(&[2], CachedFsConfig::default());
+        let ctx = Context::default();
+
+        // One lookup reached the backend, the other was served from the cache.
+        fs.lookup(&ctx, ROOT_ID, &name(2)).unwrap();
+        fs.lookup(&ctx, ROOT_ID, &name(2)).unwrap();
+        fs.forget(&ctx, 2, 1);
+        assert!(fs.inner().forgets.lock().unwrap().is_empty());
+
+        // Entries stay cached while the inode is looked up.
+        fs.lookup(&ctx, ROOT_ID, &name(2)).unwrap();
+        assert_eq!(fs.inner().calls("lookup"), 1);
+        fs.forget(&ctx, 2, 2);
+        assert_eq!(*fs.inner().forgets.lock().unwrap(), vec![(2, 1)]);
+        assert!(fs.is_empty());
+
+        // Unknown inodes are forgotten as is.
+        fs.lookup(&ctx, ROOT_ID, &name(2)).unwrap();
+        assert_eq!(fs.inner().calls("lookup"), 2);
+        fs.batch_forget(&ctx, vec![(2, 1), (5, 3)]);
+        assert_eq!(
+            *fs.inner().forgets.lock().unwrap(),
+            vec![(2, 1), (2, 1), (5, 3)]
+        );
+    }
+
+    #[test]
+    fn test_cached_fs_lru() {
+        let (_, fs) = cached_fs(
+            &[2, 3, 4],
+            CachedFsConfig {
+                capacity: 2,
+                ..Default::default()
+            },
+        );
+        let ctx = Context::default();
+
+        for inode in [2, 3, 2, 4, 2, 3] {
+            fs.getattr(&ctx, inode, None).unwrap();
+        }
+        assert_eq!(fs.len(), 2);
+        let stats = fs.stats();
+        assert_eq!((stats.attr_hits, stats.attr_misses), (2, 4));
+        assert_eq!(stats.evictions, 2);
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Pluggable time source for caches, timeouts and backoff.
+//!
+//! Attribute caches, idle backend detection, retry backoff and the request profiler all depend
+//! on the passage of time. Reading the system clock directly makes their behavior hard to test
+//! without sleeping, so they read time from a [Clock] instead. [SystemClock] is used by default,
+//! and [ManualClock] only moves when advanced explicitly.
+//!
+//! ```
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//! use fuse_backend_rs::abi::fuse_abi::stat64;
+//! use fuse_backend_rs::api::attr_cache::AttrCache;
+//! use fuse_backend_rs::api::clock::ManualClock;
+//!
+//! let clock = Arc::new(ManualClock::default());
+//! let cache = AttrCache::new(16).with_clock(clock.clone());
+//! let st: stat64 = unsafe { std::mem::zeroed() };
+//!
+//! cache.insert(2, st, Duration::from_secs(5));
+//! clock.advance(Duration::from_secs(5));
+//! assert!(cache.get(2).is_none());
+//! ```
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Source of wall clock and monotonic time.
+pub trait Clock: Send + Sync {
+    /// Get the current wall clock time.
+    fn now(&self) -> SystemTime;
+
+    /// Get the monotonic time elapsed since an arbitrary fixed point.
+    fn monotonic(&self) -> Duration;
+
+    /// Block the current thread for `dur`.
+    fn sleep(&self, dur: Duration) {
+        thread::sleep(dur);
+    }
+}
+
+/// [Clock] backed by the system clock.
+pub struct SystemClock {
+    base: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock {
+            base: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn monotonic(&self) -> Duration {
+        self.base.elapsed()
+    }
+}
+
+/// [Clock] which only moves when advanced, sleeping advances the clock instead of blocking.
+pub struct ManualClock {
+    // Wall clock time at monotonic time zero.
+    epoch: SystemTime,
+    elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+    /// Create a new clock starting at wall clock time `epoch`.
+    pub fn new(epoch: SystemTime) -> Self {
+        ManualClock {
+            epoch,
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    /// Move both the wall clock and the monotonic time forward by `dur`.
+    pub fn advance(&self, dur: Duration) {
+        *self.elapsed.lock().unwrap() += dur;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new(SystemTime::UNIX_EPOCH)
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        self.epoch + self.monotonic()
+    }
+
+    fn monotonic(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+
+    fn sleep(&self, dur: Duration) {
+        self.advance(dur);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manual_clock() {
+        let epoch = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let clock = ManualClock::new(epoch);
+        assert_eq!(clock.monotonic(), Duration::ZERO);
+        assert_eq!(clock.now(), epoch);
+
+        clock.advance(Duration::from_secs(3));
+        clock.sleep(Duration::from_secs(2));
+        assert_eq!(clock.monotonic(), Duration::from_secs(5));
+        assert_eq!(clock.now(), epoch + Duration::from_secs(5));
+
+        let clock = SystemClock::default();
+        let t = clock.monotonic();
+        assert!(clock.monotonic() >= t);
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helpers to attach context to errors without losing the errno replied to Fuse clients.
+//!
+//! The server replies to failed requests with the OS error code of the `io::Error` returned by
+//! filesystem drivers, and falls back to a code guessed from the error kind, mostly `EIO`, for
+//! errors created by `io::Error::new()`. Errors created by [fuse_errno] or wrapped by
+//! [ErrnoContext] carry a context message for logs along with the errno, which is retrieved by
+//! [errno_of] and sent to the client.
+//!
+//! ```
+//! use fuse_backend_rs::api::errno::{errno_of, fuse_errno, ErrnoContext};
+//!
+//! let err = fuse_errno(libc::ENOENT, "no such entry");
+//! assert_eq!(errno_of(&err), Some(libc::ENOENT));
+//!
+//! let res: std::io::Result<()> = Err(err);
+//! let err = res.errno_context("lookup").unwrap_err();
+//! assert_eq!(errno_of(&err), Some(libc::ENOENT));
+//! assert!(err.to_string().starts_with("lookup: no such entry"));
+//! ```
+
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::encode_io_error_kind;
+
+/// An error carrying an errno to be replied to Fuse clients, and a context message.
+#[derive(Debug)]
+pub struct ErrnoError {
+    errno: i32,
+    context: Cow<'static, str>,
+    source: io::Error,
+}
+
+impl ErrnoError {
+    /// Get the errno to reply to Fuse clients.
+    pub fn errno(&self) -> i32 {
+        self.errno
+    }
+
+    /// Get the context message of the error.
+    pub fn context(&self) -> &str {
+        &self.context
+    }
+}
+
+impl fmt::Display for ErrnoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.context, self.source)
+    }
+}
+
+impl Error for ErrnoError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+// Wrap `source` with `context`, keeping its errno.
+fn wrap(source: io::Error, context: Cow<'static, str>) -> io::Error {
+    let errno = errno_of(&source).unwrap_or_else(|| encode_io_error_kind(source.kind()));
+    io::Error::new(
+        source.kind(),
+        ErrnoError {
+            errno,
+            context,
+            source,
+        },
+    )
+}
+
+/// Errno of missing extended attributes, `ENODATA` on Linux and `ENOATTR` on macOS.
+///
+/// Replies of `getxattr`, `removexattr` and `setxattr` with `XATTR_REPLACE` on missing
+/// attributes should use it, so clients get the code their xattr syscalls return.
+#[cfg(target_os = "linux")]
+pub const ENOATTR: i32 = libc::ENODATA;
+/// Errno of missing extended attributes, `ENODATA` on Linux and `ENOATTR` on macOS.
+///
+/// Replies of `getxattr`, `removexattr` and `setxattr` with `XATTR_REPLACE` on missing
+/// attributes should use it, so clients get the code their xattr syscalls return.
+#[cfg(target_os = "macos")]
+pub const ENOATTR: i32 = libc::ENOATTR;
+
+/// Create an error replied to Fuse clients with `errno`, described by `context` in logs.
+pub fn fuse_errno<C: Into<Cow<'static, str>>>(errno: i32, context: C) -> io::Error {
+    wrap(io::Error::from_raw_os_error(errno), context.into())
+}
+
+/// Get the errno of `err` to reply to Fuse clients, looking through errors wrapped by
+/// [fuse_errno] and [ErrnoContext].
+///
+/// Return `None` if `err` carries no errno.
+pub fn errno_of(err: &io::Error) -> Option<i32> {
+    err.raw_os_error().or_else(|| {
+        err.get_ref()
+            .and_then(|e| e.downcast_ref::<ErrnoError>())
+            .map(|e| e.errno)
+    })
+}
+
+/// Extension trait to attach context messages to errors of `io::Result`, keeping their errno.
+pub trait ErrnoContext<T> {
+    /// Wrap the error with `context`.
+    fn errno_context(self, context: &'static str) -> io::Result<T>;
+
+    /// Wrap the error with the context message built by `f`, which is only called on failure.
+    fn with_errno_context<F: FnOnce() -> String>(self, f: F) -> io::Result<T>;
+}
+
+impl<T> ErrnoContext<T> for io::Result<T> {
+    fn errno_context(self, context: &'static str) -> io::Result<T> {
+        self.map_err(|e| wrap(e, Cow::Borrowed(context)))
+    }
+
+    fn with_errno_context<F: FnOnce() -> String>(self, f: F) -> io::Result<T> {
+        self.map_err(|e| wrap(e, Cow::Owned(f())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fuse_errno() {
+        let err = fuse_errno(libc::ENOSPC, "quota exceeded");
+        assert_eq!(errno_of(&err), Some(libc::ENOSPC));
+        assert_eq!(err.raw_os_error(), None);
+        let msg = err.to_string();
+        assert!(msg.starts_with("quota exceeded: "), "{}", msg);
+        let source = err.get_ref().unwrap().source().unwrap();
+        let source = source.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(source.raw_os_error(), Some(libc::ENOSPC));
+
+        // Errors of other kinds keep the errno guessed from the kind.
+        assert_eq!(errno_of(&io::Error::other("other")), None);
+        let err = fuse_errno(libc::EPERM, format!("inode {}", 5));
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_errno_context() {
+        let res: io::Result<()> = Err(io::Error::from_raw_os_error(libc::EROFS));
+        let err = res
+            .errno_context("write")
+            .with_errno_context(|| format!("inode {}", 2))
+            .unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::EROFS));
+        let msg = err.to_string();
+        assert!(msg.starts_with("inode 2: write: "), "{}", msg);
+        let inner = err.get_ref().unwrap().downcast_ref::<ErrnoError>().unwrap();
+        assert_eq!(inner.errno(), libc::EROFS);
+        assert_eq!(inner.context(), "inode 2");
+
+        let res: io::Result<()> = Err(io::Error::from(io::ErrorKind::NotFound));
+        assert_eq!(
+            errno_of(&res.errno_context("lookup").unwrap_err()),
+            Some(libc::ENOENT)
+        );
+
+        let mut called = false;
+        let res: io::Result<u32> = Ok(1);
+        let res = res.with_errno_context(|| {
+            called = true;
+            String::new()
+        });
+        assert_eq!(res.unwrap(), 1);
+        assert!(!called);
+    }
+}
@@ -0,0 +1,356 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Pluggable executors to spawn tasks of the asynchronous request path.
+//!
+//! Embedders of the asynchronous server usually own the runtime already, a tokio runtime, a
+//! tokio-uring one or a custom executor. So the crate never creates a runtime by itself, and
+//! spawns background tasks, runs blocking syscalls and waits for timers through the [Executor]
+//! configured by [Server::with_executor](super::server::Server::with_executor) and
+//! [PassthroughFs::with_executor](crate::passthrough::PassthroughFs::with_executor).
+//!
+//! [TokioUringExecutor], the default, runs on the tokio-uring runtime polling the request,
+//! [TokioExecutor] runs on a given tokio runtime. A custom executor implements [Executor]:
+//!
+//! ```
+//! use std::io;
+//! use std::time::Duration;
+//!
+//! use fuse_backend_rs::api::executor::{BoxFuture, Executor};
+//!
+//! struct ThreadExecutor;
+//!
+//! impl Executor for ThreadExecutor {
+//!     fn spawn(&self, task: BoxFuture) -> io::Result<()> {
+//!         std::thread::Builder::new().spawn(move || futures::executor::block_on(task))?;
+//!         Ok(())
+//!     }
+//!
+//!     fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) -> io::Result<()> {
+//!         std::thread::Builder::new().spawn(task)?;
+//!         Ok(())
+//!     }
+//!
+//!     fn sleep(&self, duration: Duration) -> BoxFuture {
+//!         let (tx, rx) = futures::channel::oneshot::channel::<()>();
+//!         std::thread::spawn(move || {
+//!             std::thread::sleep(duration);
+//!             let _ = tx.send(());
+//!         });
+//!         Box::pin(async move {
+//!             let _ = rx.await;
+//!         })
+//!     }
+//! }
+//! ```
+
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use futures::channel::oneshot;
+use tokio::runtime::Handle;
+
+/// A boxed task to be run by an [Executor].
+pub type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// An executor to spawn tasks of the asynchronous request path.
+pub trait Executor: Send + Sync {
+    /// Run `task` in background.
+    fn spawn(&self, task: BoxFuture) -> io::Result<()>;
+
+    /// Run `task`, which may block, without blocking other tasks of the executor.
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) -> io::Result<()>;
+
+    /// Get a future completing after `duration`.
+    fn sleep(&self, duration: Duration) -> BoxFuture;
+
+    /// Whether requests run on a tokio-uring runtime, so file systems may submit their IO to
+    /// its io_uring instead of doing it synchronously.
+    fn uring(&self) -> bool {
+        false
+    }
+}
+
+/// Run `f` by [Executor::spawn_blocking] and get its result.
+///
+/// Fail with `EIO` if the executor drops `f` without running it, when shutting down for example.
+pub async fn run_blocking<T, F>(executor: &dyn Executor, f: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    executor.spawn_blocking(Box::new(move || {
+        let _ = tx.send(f());
+    }))?;
+    rx.await
+        .map_err(|_| io::Error::from_raw_os_error(libc::EIO))
+}
+
+// Completion of a task run by `run_blocking_scoped()`.
+#[derive(Default)]
+struct TaskDone {
+    done: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl TaskDone {
+    fn signal(&self) {
+        *self.done.lock().unwrap() = true;
+        self.cond.notify_all();
+    }
+
+    fn wait(&self) {
+        let mut done = self.done.lock().unwrap();
+        while !*done {
+            done = self.cond.wait(done).unwrap();
+        }
+    }
+}
+
+// A task with borrows, signaling once it's done with them: after it's run, or when dropped
+// without being run.
+struct ScopedTask<F> {
+    f: Option<F>,
+    done: Arc<TaskDone>,
+}
+
+impl<F> Drop for ScopedTask<F> {
+    fn drop(&mut self) {
+        self.f.take();
+        self.done.signal();
+    }
+}
+
+// Wait for a scoped task to be done with its borrows when dropped.
+struct ScopedWait(Arc<TaskDone>);
+
+impl Drop for ScopedWait {
+    fn drop(&mut self) {
+        self.0.wait();
+    }
+}
+
+/// Run `f`, which may borrow data of the caller, by [Executor::spawn_blocking] and get its
+/// result.
+///
+/// The returned future waits for `f` to complete when dropped before, blocking the thread
+/// dropping it, so borrows of `f` are valid while it runs. Fail with `EIO` if the executor drops
+/// `f` without running it.
+///
+/// # Safety
+///
+/// The returned future must not be leaked, with `std::mem::forget()` for example, before it
+/// completes. Like the data buffers of [Server::async_handle_message], futures of the
+/// asynchronous request path are always polled to completion or dropped.
+///
+/// [Server::async_handle_message]: super::server::Server::async_handle_message
+pub(crate) async unsafe fn run_blocking_scoped<'a, T, F>(
+    executor: &dyn Executor,
+    f: F,
+) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'a,
+{
+    let (tx, rx) = oneshot::channel();
+    let done = Arc::new(TaskDone::default());
+    let mut task = ScopedTask {
+        f: Some(f),
+        done: done.clone(),
+    };
+    let task: Box<dyn FnOnce() + Send + 'a> = Box::new(move || {
+        let res = (task.f.take().unwrap())();
+        // Done with the borrows, only owned data is accessed from now on.
+        drop(task);
+        let _ = tx.send(res);
+    });
+    let wait = ScopedWait(done);
+    // Safe because `wait` keeps the borrows of `task` valid until it's run or dropped, see the
+    // safety requirements of the function.
+    let task: Box<dyn FnOnce() + Send + 'static> = mem::transmute(task);
+    executor.spawn_blocking(task)?;
+
+    let res = rx
+        .await
+        .map_err(|_| io::Error::from_raw_os_error(libc::EIO));
+    drop(wait);
+    res
+}
+
+// Get the tokio runtime of the current thread, without creating one.
+fn current_handle() -> io::Result<Handle> {
+    Handle::try_current().map_err(io::Error::other)
+}
+
+/// An executor running on a tokio runtime.
+///
+/// The runtime must have the time driver enabled for [Executor::sleep].
+#[derive(Clone)]
+pub struct TokioExecutor {
+    handle: Handle,
+}
+
+impl TokioExecutor {
+    /// Create an executor running on the runtime of `handle`.
+    pub fn new(handle: Handle) -> Self {
+        TokioExecutor { handle }
+    }
+
+    /// Create an executor running on the runtime of the current thread.
+    ///
+    /// Fail if the current thread isn't in the context of a tokio runtime.
+    pub fn current() -> io::Result<Self> {
+        current_handle().map(Self::new)
+    }
+}
+
+impl Executor for TokioExecutor {
+    fn spawn(&self, task: BoxFuture) -> io::Result<()> {
+        self.handle.spawn(task);
+        Ok(())
+    }
+
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) -> io::Result<()> {
+        self.handle.spawn_blocking(task);
+        Ok(())
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture {
+        // The timer binds to the runtime entered when it's created.
+        let _guard = self.handle.enter();
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// An executor running on the tokio-uring runtime of the calling thread.
+///
+/// Tasks are spawned onto the thread driving the io_uring, so it must only be used from tasks
+/// started by `tokio_uring::start()`.
+#[derive(Clone, Copy, Default)]
+pub struct TokioUringExecutor;
+
+impl Executor for TokioUringExecutor {
+    fn spawn(&self, task: BoxFuture) -> io::Result<()> {
+        current_handle()?;
+        tokio_uring::spawn(task);
+        Ok(())
+    }
+
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) -> io::Result<()> {
+        current_handle()?.spawn_blocking(task);
+        Ok(())
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture {
+        Box::pin(tokio::time::sleep(duration))
+    }
+
+    fn uring(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Instant;
+
+    #[test]
+    fn test_no_implicit_runtime() {
+        assert!(TokioExecutor::current().is_err());
+        assert!(TokioUringExecutor.spawn_blocking(Box::new(|| {})).is_err());
+    }
+
+    #[test]
+    fn test_tokio_executor() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_time()
+            .build()
+            .unwrap();
+        let executor = TokioExecutor::new(rt.handle().clone());
+
+        rt.block_on(async {
+            let current = TokioExecutor::current().unwrap();
+            assert_eq!(run_blocking(&current, || 1 + 1).await.unwrap(), 2);
+
+            let done = Arc::new(AtomicBool::new(false));
+            let (tx, rx) = oneshot::channel();
+            let done2 = done.clone();
+            executor
+                .spawn(Box::pin(async move {
+                    done2.store(true, Ordering::SeqCst);
+                    let _ = tx.send(());
+                }))
+                .unwrap();
+            rx.await.unwrap();
+            assert!(done.load(Ordering::SeqCst));
+
+            let start = Instant::now();
+            executor.sleep(Duration::from_millis(20)).await;
+            assert!(start.elapsed() >= Duration::from_millis(20));
+        });
+
+        // Timers may be created outside of the runtime.
+        let sleep = executor.sleep(Duration::from_millis(1));
+        rt.block_on(sleep);
+    }
+
+    #[test]
+    fn test_tokio_uring_executor() {
+        tokio_uring::start(async {
+            let executor = TokioUringExecutor;
+            let (tx, rx) = oneshot::channel();
+            executor
+                .spawn(Box::pin(async move {
+                    let _ = tx.send(3);
+                }))
+                .unwrap();
+            assert_eq!(rx.await.unwrap(), 3);
+            assert_eq!(run_blocking(&executor, || 4).await.unwrap(), 4);
+            executor.sleep(Duration::from_millis(1)).await;
+        });
+    }
+
+    #[test]
+    fn test_run_blocking_scoped() {
+        tokio_uring::start(async {
+            let executor = TokioUringExecutor;
+            let data = [1, 2, 3];
+            let sum = unsafe { run_blocking_scoped(&executor, || data.iter().sum::<i32>()) }
+                .await
+                .unwrap();
+            assert_eq!(sum, 6);
+
+            // Dropping the future waits for the task to be done with its borrows.
+            let running = AtomicBool::new(false);
+            let finished = AtomicBool::new(false);
+            let mut fut = Box::pin(unsafe {
+                run_blocking_scoped(&executor, || {
+                    running.store(true, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(20));
+                    finished.store(true, Ordering::SeqCst);
+                })
+            });
+            assert!(futures::poll!(fut.as_mut()).is_pending());
+            while !running.load(Ordering::SeqCst) {
+                std::thread::yield_now();
+            }
+            drop(fut);
+            assert!(finished.load(Ordering::SeqCst));
+        });
+
+        // Tasks dropped without being run fail.
+        let data = 1;
+        let res = futures::executor::block_on(unsafe {
+            run_blocking_scoped(&TokioUringExecutor, || data)
+        });
+        assert!(res.is_err());
+    }
+}
@@ -238,6 +238,10 @@ pub trait AsyncFileSystem: FileSystem {
         /// exists and must return an error instead. If `libc::RENAME_EXCHANGE` is specified, the
         /// implementation must atomically exchange the two files, i.e., both must exist and neither may
         /// be deleted.
+        ///
+        /// `flags` may also be `libc::RENAME_WHITEOUT`, to leave a whiteout device in place of
+        /// `oldname`, as overlay file systems do. Implementations passing the flags on to the host
+        /// should fail like the host does when the backing file system doesn't support them.
         fn rename(
             &self,
             ctx: Context,
@@ -378,7 +382,17 @@ pub trait AsyncFileSystem: FileSystem {
     /// If the `FsOptions::HANDLE_KILLPRIV` feature is not enabled then then the file system is
     /// expected to clear the setuid and setgid bits.
     ///
-    /// If `delayed_write` is true then it indicates that this is a write for buffered data.
+    /// If `delayed_write` is true then it indicates that this is a write for buffered data, that
+    /// is `FUSE_WRITE_CACHE` is set. Such writes flush dirty pages of the kernel writeback cache,
+    /// they are not issued on behalf of the process which dirtied the pages, so the credentials
+    /// in `ctx` should not be used, setuid and setgid bits should not be cleared, and `offset`
+    /// should be honored even if the handle was opened with `O_APPEND`, because the kernel has
+    /// already positioned appended data.
+    ///
+    /// `lock_owner` is the owner of the locks held by the writer, and is only valid when
+    /// `FUSE_WRITE_LOCKOWNER` is set, otherwise it's `None`. File systems implementing POSIX locks
+    /// may use it to check for conflicting locks. `fuse_flags` contains the raw `FUSE_WRITE_*`
+    /// flags of the request.
     ///
     /// This method should return exactly the number of bytes requested by the kernel, except in the
     /// case of error. An exception to this rule is if the file was opened with the "direct I/O"
@@ -709,6 +723,30 @@ pub trait AsyncFileSystem: FileSystem {
         handle: Self::Handle,
     ) -> io::Result<()>;
 
+    /// Synchronize the file system containing `inode`.
+    ///
+    /// The kernel sends the root inode of the mount being synced, on `syncfs(2)` and when the
+    /// guest unmounts the file system. File systems must ensure that all their data and metadata
+    /// have been flushed to disk before returning from this method.
+    ///
+    /// If this method returns an `ENOSYS` error then the kernel will treat it as success and all
+    /// subsequent calls to `syncfs` will be handled by the kernel without being forwarded to the
+    /// file system.
+    //
+    // Spelled out as expanded by `async_trait`, whose default methods require `Self: Sync`.
+    fn async_syncfs<'a, 'b, 'async_trait>(
+        &'a self,
+        ctx: &'b Context,
+        inode: Self::Inode,
+    ) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + 'async_trait>>
+    where
+        'a: 'async_trait,
+        'b: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(async { Err(io::Error::from_raw_os_error(libc::ENOSYS)) })
+    }
+
     /*
     /// Release an open directory.
     ///
@@ -1019,4 +1057,17 @@ impl<FS: AsyncFileSystem> AsyncFileSystem for Arc<FS> {
     {
         self.deref().async_fsyncdir(ctx, inode, datasync, handle)
     }
+
+    fn async_syncfs<'a, 'b, 'async_trait>(
+        &'a self,
+        ctx: &'b Context,
+        inode: Self::Inode,
+    ) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + 'async_trait>>
+    where
+        'a: 'async_trait,
+        'b: 'async_trait,
+        Self: 'async_trait,
+    {
+        self.deref().async_syncfs(ctx, inode)
+    }
 }
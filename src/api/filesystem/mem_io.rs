@@ -0,0 +1,308 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Zero copy readers and writers over memory buffers.
+//!
+//! Backends receive data of write requests through a [ZeroCopyReader] and send data of read
+//! requests through a [ZeroCopyWriter], which are backed by transport buffers in the server.
+//! [SliceReader] and [VecWriter] behave like them, including short reads at the end of data and
+//! failures when running out of space, but over plain memory, so backends can be exercised
+//! directly without building fuse buffers or descriptor chains.
+
+use std::cmp;
+use std::io::{self, Read, Write};
+
+use super::{ZeroCopyReader, ZeroCopyWriter};
+use crate::transport::{FileReadWriteVolatile, FileVolatileSlice};
+
+/// Default space of a [VecWriter], the max buffer size of the fuse device transport.
+const DEFAULT_WRITER_LIMIT: usize = 1 << 20;
+
+fn out_of_range(available: usize, count: usize, offset: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "data out of range, available {} requested {} at offset {}",
+            available, count, offset
+        ),
+    )
+}
+
+/// A [ZeroCopyReader] over a slice of bytes, like the data of a write request.
+pub struct SliceReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> SliceReader<'a> {
+    /// Create a reader of the data in `buf`.
+    pub fn new(buf: &'a [u8]) -> Self {
+        SliceReader { buf, pos: 0 }
+    }
+
+    /// Get the number of bytes left to read.
+    pub fn available_bytes(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    /// Get the number of bytes read so far.
+    pub fn bytes_read(&self) -> usize {
+        self.pos
+    }
+
+    // Write `count` bytes at `buf_offset` from the current position to `f` at `off`.
+    fn write_at(
+        &self,
+        f: &mut dyn FileReadWriteVolatile,
+        count: usize,
+        off: u64,
+        buf_offset: usize,
+    ) -> io::Result<usize> {
+        let data = &self.buf[self.pos + buf_offset..][..count];
+        // Safe because the slice covers `data`, which is only read by `f`.
+        let slice = unsafe { FileVolatileSlice::new(data.as_ptr() as *mut u8, data.len()) };
+        f.write_at_volatile(slice, off)
+    }
+}
+
+impl Read for SliceReader<'_> {
+    fn read(&mut self, data: &mut [u8]) -> io::Result<usize> {
+        let count = (&self.buf[self.pos..]).read(data)?;
+        self.pos += count;
+        Ok(count)
+    }
+}
+
+impl ZeroCopyReader for SliceReader<'_> {
+    fn read_to(
+        &mut self,
+        f: &mut dyn FileReadWriteVolatile,
+        count: usize,
+        off: u64,
+    ) -> io::Result<usize> {
+        let count = cmp::min(count, self.available_bytes());
+        let written = self.write_at(f, count, off, 0)?;
+        self.pos += written;
+        Ok(written)
+    }
+
+    fn read_to_at_offset(
+        &mut self,
+        f: &mut dyn FileReadWriteVolatile,
+        count: usize,
+        off: u64,
+        buf_offset: usize,
+    ) -> io::Result<usize> {
+        let available = self.available_bytes();
+        if !matches!(buf_offset.checked_add(count), Some(end) if end <= available) {
+            return Err(out_of_range(available, count, buf_offset));
+        }
+        self.write_at(f, count, off, buf_offset)
+    }
+}
+
+/// A [ZeroCopyWriter] appending to a vector of bytes, like the reply to a read request.
+///
+/// Like transport buffers, the writer has limited space, and writes not fitting in the space left
+/// fail without writing anything.
+pub struct VecWriter<'a> {
+    buf: &'a mut Vec<u8>,
+    // Length of `buf` when the writer was created.
+    start: usize,
+    // Current position, relative to `start`.
+    pos: usize,
+    // End of data written, including data written at offsets, relative to `start`.
+    end: usize,
+    limit: usize,
+}
+
+impl<'a> VecWriter<'a> {
+    /// Create a writer appending to `buf`, with space for 1MB.
+    pub fn new(buf: &'a mut Vec<u8>) -> Self {
+        let start = buf.len();
+        VecWriter {
+            buf,
+            start,
+            pos: 0,
+            end: 0,
+            limit: DEFAULT_WRITER_LIMIT,
+        }
+    }
+
+    /// Set the number of bytes which may be written.
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Get the number of bytes which may still be written from the current position.
+    pub fn available_bytes(&self) -> usize {
+        self.limit.saturating_sub(self.pos)
+    }
+
+    /// Get the number of bytes written from the start, not counting data written at offsets
+    /// beyond the current position.
+    pub fn bytes_written(&self) -> usize {
+        self.pos
+    }
+
+    // Read at most `count` bytes from `f` at `off` into `buf_offset` from the current position,
+    // after checking the space left.
+    fn read_at(
+        &mut self,
+        f: &mut dyn FileReadWriteVolatile,
+        count: usize,
+        off: u64,
+        buf_offset: usize,
+    ) -> io::Result<usize> {
+        let available = self.available_bytes();
+        if !matches!(buf_offset.checked_add(count), Some(end) if end <= available) {
+            return Err(out_of_range(available, count, buf_offset));
+        }
+
+        let at = self.start + self.pos + buf_offset;
+        if self.buf.len() < at + count {
+            self.buf.resize(at + count, 0);
+        }
+        // Safe because the slice covers memory of `buf` just checked above.
+        let slice = unsafe { FileVolatileSlice::new(self.buf[at..].as_mut_ptr(), count) };
+        let res = f.read_at_volatile(slice, off);
+        if let Ok(cnt) = res {
+            self.end = cmp::max(self.end, self.pos + buf_offset + cnt);
+        }
+        self.buf.truncate(self.start + self.end);
+        res
+    }
+}
+
+impl Write for VecWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let available = self.available_bytes();
+        if data.len() > available {
+            return Err(out_of_range(available, data.len(), 0));
+        }
+
+        let at = self.start + self.pos;
+        let overlap = cmp::min(data.len(), self.buf.len() - at);
+        self.buf[at..at + overlap].copy_from_slice(&data[..overlap]);
+        self.buf.extend_from_slice(&data[overlap..]);
+        self.pos += data.len();
+        self.end = cmp::max(self.end, self.pos);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ZeroCopyWriter for VecWriter<'_> {
+    fn write_from(
+        &mut self,
+        f: &mut dyn FileReadWriteVolatile,
+        count: usize,
+        off: u64,
+    ) -> io::Result<usize> {
+        let cnt = self.read_at(f, count, off, 0)?;
+        self.pos += cnt;
+        Ok(cnt)
+    }
+
+    fn write_from_at_offset(
+        &mut self,
+        f: &mut dyn FileReadWriteVolatile,
+        count: usize,
+        off: u64,
+        buf_offset: usize,
+    ) -> io::Result<usize> {
+        self.read_at(f, count, off, buf_offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Seek, SeekFrom};
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn file_content(file: &mut std::fs::File) -> Vec<u8> {
+        let mut content = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn test_slice_reader() {
+        let data: Vec<u8> = (0..32u8).collect();
+        let mut reader = SliceReader::new(&data);
+        let mut file = TempFile::new().unwrap().into_file();
+
+        let mut head = [0u8; 4];
+        reader.read_exact(&mut head).unwrap();
+        assert_eq!(head, [0, 1, 2, 3]);
+        assert_eq!(reader.read_to(&mut file, 8, 0).unwrap(), 8);
+        assert_eq!(reader.bytes_read(), 12);
+
+        // Segments are read at offsets without consuming them.
+        assert_eq!(reader.read_to_at_offset(&mut file, 4, 8, 16).unwrap(), 4);
+        reader.read_to_at_offset(&mut file, 17, 0, 4).unwrap_err();
+        reader
+            .read_to_at_offset(&mut file, 1, 0, usize::MAX)
+            .unwrap_err();
+        assert_eq!(reader.available_bytes(), 20);
+
+        // Reads are short at the end of data.
+        assert_eq!(reader.read_to(&mut file, 100, 12).unwrap(), 20);
+        assert_eq!(reader.read_to(&mut file, 100, 32).unwrap(), 0);
+        reader.read_exact_to(&mut file, 1, 32).unwrap_err();
+        let content = file_content(&mut file);
+        assert_eq!(&content[..12], &[4, 5, 6, 7, 8, 9, 10, 11, 28, 29, 30, 31]);
+        assert_eq!(&content[12..], &data[12..]);
+
+        let mut reader = SliceReader::new(&data);
+        let mut file = TempFile::new().unwrap().into_file();
+        assert_eq!(reader.copy_to_end(&mut file, 0).unwrap(), 32);
+        assert_eq!(file_content(&mut file), data);
+    }
+
+    #[test]
+    fn test_vec_writer() {
+        let mut file = TempFile::new().unwrap().into_file();
+        file.write_all(&(0..32u8).collect::<Vec<_>>()).unwrap();
+
+        let mut buf = vec![0xffu8];
+        let mut writer = VecWriter::new(&mut buf).with_limit(24);
+        writer.write_all(&[0xaa; 2]).unwrap();
+        assert_eq!(writer.write_from(&mut file, 4, 0).unwrap(), 4);
+        assert_eq!(writer.bytes_written(), 6);
+
+        // Writes beyond the space left fail without writing anything.
+        writer.write_from(&mut file, 19, 0).unwrap_err();
+        writer.write_all(&[0; 19]).unwrap_err();
+        writer
+            .write_from_at_offset(&mut file, 10, 0, 9)
+            .unwrap_err();
+        assert_eq!(writer.available_bytes(), 18);
+
+        // Reads are short at the end of the file.
+        assert_eq!(writer.write_from(&mut file, 8, 28).unwrap(), 4);
+        assert_eq!(writer.write_from(&mut file, 8, 32).unwrap(), 0);
+        writer.write_all_from(&mut file, 1, 32).unwrap_err();
+
+        // Data written at offsets doesn't move the current position.
+        assert_eq!(writer.write_from_at_offset(&mut file, 2, 16, 2).unwrap(), 2);
+        assert_eq!(writer.bytes_written(), 10);
+        writer.write_all(&[0xbb; 2]).unwrap();
+        assert_eq!(
+            buf,
+            vec![0xff, 0xaa, 0xaa, 0, 1, 2, 3, 28, 29, 30, 31, 0xbb, 0xbb, 16, 17]
+        );
+
+        let mut buf = Vec::new();
+        let mut writer = VecWriter::new(&mut buf).with_limit(64);
+        writer.copy_to_end(&mut file, 0).unwrap_err();
+        assert_eq!(writer.write_from(&mut file, 64, 0).unwrap(), 32);
+        assert_eq!(buf, (0..32u8).collect::<Vec<_>>());
+    }
+}
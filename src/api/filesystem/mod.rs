@@ -10,7 +10,10 @@
 
 use std::convert::TryInto;
 use std::io;
-use std::time::Duration;
+#[cfg(feature = "tracing")]
+use std::num::NonZeroU64;
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
 
 use crate::abi::fuse_abi as fuse;
 use crate::transport::FileReadWriteVolatile;
@@ -27,6 +30,12 @@ mod async_io;
 #[cfg(feature = "async-io")]
 pub use async_io::{AsyncFileSystem, AsyncZeroCopyReader, AsyncZeroCopyWriter};
 
+mod mem_io;
+pub use mem_io::{SliceReader, VecWriter};
+
+mod raw;
+pub use raw::{RawFileSystem, RawHandled};
+
 mod sync_io;
 pub use sync_io::FileSystem;
 
@@ -148,13 +157,36 @@ impl From<FileLock> for fuse::FileLock {
     }
 }
 
-/// ioctl data and result
-#[derive(Default, Clone)]
-pub struct IoctlData<'a> {
-    /// ioctl result
-    pub result: i32,
-    /// ioctl data
-    pub data: Option<&'a [u8]>,
+/// A reply to an `ioctl` method call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IoctlReply {
+    /// The ioctl is done.
+    Done {
+        /// Return value of the ioctl.
+        result: i32,
+        /// Output data of the ioctl, at most `out_size` bytes.
+        data: Vec<u8>,
+    },
+
+    /// Retry an ioctl with `IoctlFlags::IOCTL_UNRESTRICTED`, the kernel copying in the memory
+    /// areas `input` of the caller as input data, and copying out to the memory areas `output`.
+    /// Replying this to restricted ioctls fails them with `EIO`.
+    Retry {
+        /// Memory areas of the caller copied in as input data of the retried ioctl.
+        input: Vec<fuse::IoctlIovec>,
+        /// Memory areas of the caller the output data of the retried ioctl is copied out to.
+        output: Vec<fuse::IoctlIovec>,
+    },
+}
+
+impl IoctlReply {
+    /// A reply of a done ioctl without output data.
+    pub fn done(result: i32) -> Self {
+        IoctlReply::Done {
+            result,
+            data: Vec::new(),
+        }
+    }
 }
 
 /// A reply to a `getxattr` method call.
@@ -208,6 +240,39 @@ pub trait ZeroCopyReader: io::Read {
         off: u64,
     ) -> io::Result<usize>;
 
+    /// Copies at most `count` bytes at offset `buf_offset` from the current position of `self`
+    /// into `f` at offset `off`, without consuming them from `self`.
+    ///
+    /// This allows to process segments of a request out of order or in parallel, for example to
+    /// fan a write out to multiple target files.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if `buf_offset + count` is beyond the remaining data in `self`. The default
+    /// implementation returns `ENOSYS`.
+    #[allow(unused_variables)]
+    fn read_to_at_offset(
+        &mut self,
+        f: &mut dyn FileReadWriteVolatile,
+        count: usize,
+        off: u64,
+        buf_offset: usize,
+    ) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Moves at most `count` bytes from `self` into the file `fd` at offset `off` with `splice(2)`,
+    /// without copying them through memory.
+    ///
+    /// Return `Ok(None)` if the data can't be spliced, for example because the transport has
+    /// already read it into memory, `read_to` should be used then. Files not supporting splice
+    /// fail with `EINVAL` without consuming data from `self`. The default implementation returns
+    /// `Ok(None)`.
+    #[allow(unused_variables)]
+    fn splice_to(&mut self, fd: RawFd, count: usize, off: u64) -> io::Result<Option<usize>> {
+        Ok(None)
+    }
+
     /// Copies exactly `count` bytes of data from `self` into `f` at offset `off`. `off + count`
     /// must be less than `u64::MAX`.
     ///
@@ -300,6 +365,28 @@ pub trait ZeroCopyWriter: io::Write {
         off: u64,
     ) -> io::Result<usize>;
 
+    /// Copies at most `count` bytes from `f` at offset `off` into `self` at offset `buf_offset`
+    /// from the current position, without advancing the current position.
+    ///
+    /// This allows to assemble a reply from segments out of order or in parallel, for example to
+    /// gather a read from multiple source files. Data written at offsets is accounted up to the
+    /// highest offset written once the request completes, so callers must not leave gaps.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if `buf_offset + count` is beyond the available space in `self`. The
+    /// default implementation returns `ENOSYS`.
+    #[allow(unused_variables)]
+    fn write_from_at_offset(
+        &mut self,
+        f: &mut dyn FileReadWriteVolatile,
+        count: usize,
+        off: u64,
+        buf_offset: usize,
+    ) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
     /// Copies exactly `count` bytes of data from `f` at offset `off` into `self`. `off + count`
     /// must be less than `u64::MAX`.
     ///
@@ -383,6 +470,23 @@ pub struct Context {
 
     /// The thread group ID of the calling process.
     pub pid: libc::pid_t,
+
+    /// A supplementary group ID of the calling process, sent with requests creating files when
+    /// `FsOptions::CREATE_SUPP_GROUP` is enabled. The kernel sends the group owning the parent
+    /// directory, when the caller is a member of it but it's not the caller's primary group.
+    pub supp_gid: Option<libc::gid_t>,
+
+    /// The unique ID of the request, to match interrupts delivered by `FileSystem::interrupt`.
+    pub unique: u64,
+
+    /// When the request must be completed, if the server has a deadline for its class of
+    /// opcodes. Backends may pass the remaining budget on to the requests they make.
+    pub deadline: Option<Instant>,
+
+    /// The id of the tracing span of the request, `None` if no subscriber records it. See
+    /// `Context::span_id()`.
+    #[cfg(feature = "tracing")]
+    pub span: Option<NonZeroU64>,
 }
 
 impl Context {
@@ -390,6 +494,22 @@ impl Context {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Get the time left until the deadline of the request, `None` if it has no deadline.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|d| d.saturating_duration_since(Instant::now()))
+    }
+
+    /// Get the tracing span of the request, opened by the server with the unique id, opcode,
+    /// node id and caller of the request, and entered while the request is handled.
+    ///
+    /// Events emitted while handling the request belong to the span already, the id allows
+    /// attaching events or spans to it from other threads, by their `parent:` argument.
+    #[cfg(feature = "tracing")]
+    pub fn span_id(&self) -> Option<tracing::Id> {
+        self.span.map(tracing::Id::from_non_zero_u64)
+    }
 }
 
 impl From<&fuse::InHeader> for Context {
@@ -398,6 +518,11 @@ impl From<&fuse::InHeader> for Context {
             uid: source.uid,
             gid: source.gid,
             pid: source.pid as i32,
+            supp_gid: None,
+            unique: source.unique,
+            deadline: None,
+            #[cfg(feature = "tracing")]
+            span: None,
         }
     }
 }
@@ -417,6 +542,7 @@ mod tests {
             uid: 3,
             gid: 4,
             pid: 5,
+            total_extlen: 0,
             padding: 0,
         };
         let header: Context = fuse_header.into();
@@ -424,6 +550,7 @@ mod tests {
         assert_eq!(header.uid, 3);
         assert_eq!(header.gid, 4);
         assert_eq!(header.pid, 5);
+        assert_eq!(header.unique, 1);
     }
 
     #[test]
@@ -0,0 +1,50 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Escape hatch for file systems which want protocol-level control over Fuse requests.
+
+use std::io;
+
+use crate::abi::fuse_abi::InHeader;
+
+/// Result of [RawFileSystem::handle_raw].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RawHandled {
+    /// The request has been handled, and the complete reply, if any, has been written.
+    Handled,
+    /// The request has not been touched, and should be dispatched through the typed
+    /// [FileSystem](trait.FileSystem.html) interface.
+    NotHandled,
+}
+
+/// A handler to intercept raw Fuse requests before they get decoded.
+///
+/// When installed by `Server::with_raw_handler()`, the handler is consulted for every request
+/// before the server dispatches it to the typed `FileSystem` methods. It's intended for
+/// protocol translators and proxies, which want to access the raw `InHeader` or to forward the
+/// undecoded payload verbatim.
+///
+/// # Reply obligations
+/// - On `Ok(RawHandled::Handled)`, the handler must have written the complete reply into `reply`,
+///   starting with an `OutHeader` whose `unique` and `len` fields match the request and reply,
+///   unless the opcode expects no reply, such as `FUSE_FORGET`. The reply is buffered and sent by
+///   the server as a single message.
+/// - On `Ok(RawHandled::NotHandled)`, the handler must neither read from `payload` nor write
+///   into `reply`, otherwise the server fails the request with `EIO`.
+/// - On `Err(e)`, anything written into `reply` is discarded, and the server replies with the
+///   error.
+///
+/// # Reentrancy
+/// The handler may be called concurrently from multiple server threads, and it must not call
+/// back into the server to handle another request from within `handle_raw()`. It may call the
+/// typed `FileSystem` methods of the backend directly.
+pub trait RawFileSystem: Send + Sync {
+    /// Handle the request described by `header`, with the request body available from
+    /// `payload` and the reply to be written into `reply`.
+    fn handle_raw(
+        &self,
+        header: &InHeader,
+        payload: &mut dyn io::Read,
+        reply: &mut dyn io::Write,
+    ) -> io::Result<RawHandled>;
+}
@@ -11,10 +11,14 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use super::{
-    Context, DirEntry, Entry, FileLock, GetxattrReply, IoctlData, ListxattrReply, ZeroCopyReader,
+    Context, DirEntry, Entry, FileLock, GetxattrReply, IoctlReply, ListxattrReply, ZeroCopyReader,
     ZeroCopyWriter,
 };
-use crate::abi::fuse_abi::{stat64, statvfs64, CreateIn, FsOptions, OpenOptions, SetattrValid};
+#[cfg(target_os = "macos")]
+use crate::abi::fuse_abi::GetxtimesOut;
+use crate::abi::fuse_abi::{
+    stat64, statvfs64, CreateIn, FsOptions, OpenOptions, SetattrValid, Statx,
+};
 #[cfg(feature = "virtiofs")]
 pub use crate::abi::virtio_fs::RemovemappingOne;
 #[cfg(feature = "virtiofs")]
@@ -60,6 +64,15 @@ pub trait FileSystem {
         Ok(FsOptions::empty())
     }
 
+    /// Get the granularity of timestamps stored by the file system, in nanoseconds.
+    ///
+    /// The value is replied to the kernel by `FUSE_INIT` after calling `init`, and the kernel
+    /// truncates timestamps of cached inodes to it. It must be a power of ten of at most one
+    /// second, other values are ignored by the server.
+    fn time_gran(&self) -> u32 {
+        1
+    }
+
     /// Clean up the file system.
     ///
     /// Called when the filesystem exits. All open `Handle`s should be closed and the lookup count
@@ -68,6 +81,34 @@ pub trait FileSystem {
     /// communicate with the kernel.
     fn destroy(&self) {}
 
+    /// Forget all inodes and close all handles.
+    ///
+    /// Called before `destroy` when the kernel sends `FUSE_DESTROY` and when the file system is
+    /// umounted from a `Vfs`. The kernel doesn't send forgets for inodes cached when the connection
+    /// goes away, so the lookup count of every `Inode` except the root implicitly goes to zero and
+    /// per-inode resources like fds may be released here. The server waits for other in-flight
+    /// requests to complete before calling it.
+    fn forget_all(&self) {}
+
+    /// Interrupt the request `unique` being handled.
+    ///
+    /// Called when the kernel sends `FUSE_INTERRUPT` because the process waiting for the request
+    /// got a signal, if interrupts are enabled by `Server::with_interrupts`. `ctx.unique` of the
+    /// interrupted request matches `unique`. The implementation may make the request fail with
+    /// `EINTR`, or just let it complete. The request may be completing concurrently, so unknown
+    /// values of `unique` must be ignored.
+    fn interrupt(&self, ctx: &Context, unique: u64) {}
+
+    /// Prepare the file system to be destroyed.
+    ///
+    /// Called during graceful shutdown after all in-flight requests have completed, and before
+    /// `destroy`. The file system may return `EBUSY` or `EAGAIN` to delay destruction briefly,
+    /// for example to flush dirty state, and it will be called again until it returns `Ok` or the
+    /// shutdown timeout expires. The file system will be destroyed anyway once the timeout expires.
+    fn prepare_destroy(&self) -> io::Result<()> {
+        Ok(())
+    }
+
     /// Look up a directory entry by name and get its attributes.
     ///
     /// If this call is successful then the lookup count of the `Inode` associated with the returned
@@ -116,6 +157,27 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
+    /// Get extended attributes for a file / directory, like `statx(2)`.
+    ///
+    /// The kernel sends `FUSE_STATX` since protocol 7.39 when the guest asks for fields beyond
+    /// the ones of `getattr`, like the creation time with `STATX_BTIME`. `flags` are the
+    /// `AT_STATX_*` synchronization flags and `mask` the `STATX_*` fields wanted by the guest.
+    /// The `mask` of the returned struct tells the fields which are filled, which may be more or
+    /// less than the ones wanted. `handle` is like for `getattr`.
+    ///
+    /// If this method returns an `ENOSYS` error then the kernel falls back to `getattr`, and
+    /// never sends `FUSE_STATX` again.
+    fn statx(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Option<Self::Handle>,
+        flags: u32,
+        mask: u32,
+    ) -> io::Result<(Statx, Duration)> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
     /// Set attributes for a file / directory.
     ///
     /// If `handle` is not `None`, then it contains the handle previously returned by the
@@ -239,6 +301,10 @@ pub trait FileSystem {
     /// exists and must return an error instead. If `libc::RENAME_EXCHANGE` is specified, the
     /// implementation must atomically exchange the two files, i.e., both must exist and neither may
     /// be deleted.
+    ///
+    /// `flags` may also be `libc::RENAME_WHITEOUT`, to leave a whiteout device in place of
+    /// `oldname`, as overlay file systems do. Implementations passing the flags on to the host
+    /// should fail like the host does when the backing file system doesn't support them.
     fn rename(
         &self,
         ctx: &Context,
@@ -342,6 +408,49 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
+    /// Create and open an unnamed file in the directory `parent`, for `open(2)` with
+    /// `O_TMPFILE`.
+    ///
+    /// Like `create`, the file should be created with `args.mode` and opened with `args.flags`,
+    /// and the method returns an `Entry` increasing the lookup count of the new inode by 1, with
+    /// the optional `Handle` and `OpenOptions` of the file. The file has no name and a link count
+    /// of 0, but the inode must stay valid until it's forgotten, because the guest may give it a
+    /// name by `link` unless `args.flags` contains `O_EXCL`.
+    ///
+    /// If the file system returns an `ENOSYS` error, then the kernel will treat this method as
+    /// unimplemented and all future calls to `open(2)` with `O_TMPFILE` will fail with
+    /// `EOPNOTSUPP` without being forwarded to the file system.
+    fn tmpfile(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions)> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Look up and open the file `name` in the directory `parent` by a single request, instead
+    /// of a `lookup` followed by an `open`.
+    ///
+    /// The file is opened with `args.flags`, and created with `args.mode` like by `create` if
+    /// `args.flags` contains `O_CREAT` and it doesn't exist yet. The method returns an `Entry`
+    /// increasing the lookup count of the inode by 1, with the optional `Handle` and
+    /// `OpenOptions` of the file. Files which can't be opened by this request, like symlinks
+    /// which need to be resolved by the kernel, are returned without a `Handle`, as a plain
+    /// lookup.
+    ///
+    /// The method is only called if `FsOptions::ATOMIC_OPEN` is enabled. If the file system
+    /// returns an `ENOSYS` error, then the kernel looks up and opens files separately instead.
+    fn atomic_open(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        name: &CStr,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions)> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
     /// Read data from a file.
     ///
     /// Returns `size` bytes of data starting from offset `off` from the file associated with
@@ -385,7 +494,17 @@ pub trait FileSystem {
     /// If the `FsOptions::HANDLE_KILLPRIV` feature is not enabled then then the file system is
     /// expected to clear the setuid and setgid bits.
     ///
-    /// If `delayed_write` is true then it indicates that this is a write for buffered data.
+    /// If `delayed_write` is true then it indicates that this is a write for buffered data, that
+    /// is `FUSE_WRITE_CACHE` is set. Such writes flush dirty pages of the kernel writeback cache,
+    /// they are not issued on behalf of the process which dirtied the pages, so the credentials
+    /// in `ctx` should not be used, setuid and setgid bits should not be cleared, and `offset`
+    /// should be honored even if the handle was opened with `O_APPEND`, because the kernel has
+    /// already positioned appended data.
+    ///
+    /// `lock_owner` is the owner of the locks held by the writer, and is only valid when
+    /// `FUSE_WRITE_LOCKOWNER` is set, otherwise it's `None`. File systems implementing POSIX locks
+    /// may use it to check for conflicting locks. `fuse_flags` contains the raw `FUSE_WRITE_*`
+    /// flags of the request.
     ///
     /// This method should return exactly the number of bytes requested by the kernel, except in the
     /// case of error. An exception to this rule is if the file was opened with the "direct I/O"
@@ -531,6 +650,19 @@ pub trait FileSystem {
         Ok(st)
     }
 
+    /// Synchronize the file system containing `inode`.
+    ///
+    /// The kernel sends the root inode of the mount being synced, on `syncfs(2)` and when the
+    /// guest unmounts the file system. File systems must ensure that all their data and metadata
+    /// have been flushed to disk before returning from this method.
+    ///
+    /// If this method returns an `ENOSYS` error then the kernel will treat it as success and all
+    /// subsequent calls to `syncfs` will be handled by the kernel without being forwarded to the
+    /// file system.
+    fn syncfs(&self, ctx: &Context, inode: Self::Inode) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
     /// Set an extended attribute.
     ///
     /// If this method fails with an `ENOSYS` error, then the kernel will treat that as a permanent
@@ -812,6 +944,32 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
+    /// Copy a range of data from one file to another.
+    ///
+    /// Copy at most `len` bytes from the file `inode_in` opened as `handle_in` at `offset_in`, to
+    /// the file `inode_out` opened as `handle_out` at `offset_out`, and return the number of bytes
+    /// copied, which may be less than `len`. `flags` are the flags of the `copy_file_range()`
+    /// system call.
+    ///
+    /// If this method returns an `ENOSYS` error, then the kernel will treat it as a permanent
+    /// failure: all future calls to `copy_file_range()` fall back to copying data by reads and
+    /// writes without being forwarded to the file system.
+    #[allow(clippy::too_many_arguments)]
+    fn copyfilerange(
+        &self,
+        ctx: &Context,
+        inode_in: Self::Inode,
+        handle_in: Self::Handle,
+        offset_in: u64,
+        inode_out: Self::Inode,
+        handle_out: Self::Handle,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
     /// Query file lock status
     fn getlk(
         &self,
@@ -851,7 +1009,16 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
-    /// send ioctl to the file
+    /// Send an ioctl to the file.
+    ///
+    /// `flags` are the `IoctlFlags` of the request, `cmd` and `arg` the command and argument of
+    /// the caller, `in_data` the input data copied in by the kernel and `out_size` the maximum
+    /// size of the output data. The command numbers of 32-bit callers on 64-bit hosts differ
+    /// for ioctls with `long` or pointer sized arguments, and `IoctlFlags::IOCTL_COMPAT` is set.
+    ///
+    /// Restricted ioctls, the only ones of regular FUSE mounts, have the input and output sizes
+    /// encoded in `cmd`. Unrestricted ioctls may ask for the memory areas of the caller to copy in
+    /// and out with [IoctlReply::Retry], typically after reading the structure pointed by `arg`.
     #[allow(clippy::too_many_arguments)]
     fn ioctl(
         &self,
@@ -860,9 +1027,10 @@ pub trait FileSystem {
         handle: Self::Handle,
         flags: u32,
         cmd: u32,
-        data: IoctlData,
+        arg: u64,
+        in_data: &[u8],
         out_size: u32,
-    ) -> io::Result<IoctlData> {
+    ) -> io::Result<IoctlReply> {
         // Rather than ENOSYS, let's return ENOTTY so simulate that the ioctl call is implemented
         // but no ioctl number is supported.
         Err(io::Error::from_raw_os_error(libc::ENOTTY))
@@ -880,22 +1048,71 @@ pub trait FileSystem {
     }
 
     /// Poll a file's events
+    ///
+    /// Return the events of the `events` mask which are ready, without blocking. When none is and
+    /// `flags` has `POLL_SCHEDULE_NOTIFY`, the kernel waits for a wakeup of the kernel poll handle
+    /// `khandle`, which is sent by `PollNotifier::wakeup()` of a notifier shared with the server.
     fn poll(
         &self,
         ctx: &Context,
         inode: Self::Inode,
         handle: Self::Handle,
-        khandle: Self::Handle,
+        khandle: u64,
         flags: u32,
         events: u32,
     ) -> io::Result<u32> {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
+    /// Rename the volume, for `FUSE_SETVOLNAME` sent by macFUSE when the volume is renamed in
+    /// Finder.
+    #[cfg(target_os = "macos")]
+    fn setvolname(&self, ctx: &Context, name: &CStr) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Atomically exchange the contents of `oldname` in `olddir` and `newname` in `newdir`, like
+    /// `exchangedata(2)` used by applications saving files safely.
+    ///
+    /// `options` holds the `FSOPT_*` flags of `exchangedata(2)`. Both files keep their inodes,
+    /// so implementations over macOS may rely on `renamex_np(2)` with `RENAME_SWAP` when the file
+    /// system has no native exchange.
+    #[cfg(target_os = "macos")]
+    fn exchange(
+        &self,
+        ctx: &Context,
+        olddir: Self::Inode,
+        oldname: &CStr,
+        newdir: Self::Inode,
+        newname: &CStr,
+        options: u64,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Get the backup and creation times of an inode, for `FUSE_GETXTIMES`.
+    #[cfg(target_os = "macos")]
+    fn getxtimes(&self, ctx: &Context, inode: Self::Inode) -> io::Result<GetxtimesOut> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
     /// TODO: support this
     fn notify_reply(&self) -> io::Result<()> {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
+
+    /// Get the lookup count of an inode, for debugging purpose only.
+    ///
+    /// The lookup count is the number of times the inode has been returned to the kernel by
+    /// `lookup`, `mknod`, `mkdir`, `symlink`, `link`, `create` and `readdirplus`, minus the
+    /// `count` passed to `forget` and `batch_forget`. It's used by the Fuse server to audit lookup
+    /// count accounting of the file system, see `Server::with_lookup_audit()`.
+    ///
+    /// Return `None` if the file system doesn't track lookup counts, or if the inode shouldn't be
+    /// audited, such as the root inode.
+    fn debug_nlookup(&self, inode: Self::Inode) -> Option<u64> {
+        None
+    }
 }
 
 impl<FS: FileSystem> FileSystem for Arc<FS> {
@@ -906,10 +1123,26 @@ impl<FS: FileSystem> FileSystem for Arc<FS> {
         self.deref().init(capable)
     }
 
+    fn time_gran(&self) -> u32 {
+        self.deref().time_gran()
+    }
+
     fn destroy(&self) {
         self.deref().destroy()
     }
 
+    fn forget_all(&self) {
+        self.deref().forget_all()
+    }
+
+    fn interrupt(&self, ctx: &Context, unique: u64) {
+        self.deref().interrupt(ctx, unique)
+    }
+
+    fn prepare_destroy(&self) -> io::Result<()> {
+        self.deref().prepare_destroy()
+    }
+
     fn lookup(&self, ctx: &Context, parent: Self::Inode, name: &CStr) -> io::Result<Entry> {
         self.deref().lookup(ctx, parent, name)
     }
@@ -931,6 +1164,17 @@ impl<FS: FileSystem> FileSystem for Arc<FS> {
         self.deref().getattr(ctx, inode, handle)
     }
 
+    fn statx(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Option<Self::Handle>,
+        flags: u32,
+        mask: u32,
+    ) -> io::Result<(Statx, Duration)> {
+        self.deref().statx(ctx, inode, handle, flags, mask)
+    }
+
     fn setattr(
         &self,
         ctx: &Context,
@@ -1030,6 +1274,25 @@ impl<FS: FileSystem> FileSystem for Arc<FS> {
         self.deref().create(ctx, parent, name, args)
     }
 
+    fn tmpfile(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions)> {
+        self.deref().tmpfile(ctx, parent, args)
+    }
+
+    fn atomic_open(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        name: &CStr,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions)> {
+        self.deref().atomic_open(ctx, parent, name, args)
+    }
+
     fn read(
         &self,
         ctx: &Context,
@@ -1125,6 +1388,10 @@ impl<FS: FileSystem> FileSystem for Arc<FS> {
         self.deref().statfs(ctx, inode)
     }
 
+    fn syncfs(&self, ctx: &Context, inode: Self::Inode) -> io::Result<()> {
+        self.deref().syncfs(ctx, inode)
+    }
+
     fn setxattr(
         &self,
         ctx: &Context,
@@ -1257,6 +1524,23 @@ impl<FS: FileSystem> FileSystem for Arc<FS> {
         self.deref().lseek(ctx, inode, handle, offset, whence)
     }
 
+    fn copyfilerange(
+        &self,
+        ctx: &Context,
+        inode_in: Self::Inode,
+        handle_in: Self::Handle,
+        offset_in: u64,
+        inode_out: Self::Inode,
+        handle_out: Self::Handle,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> io::Result<usize> {
+        self.deref().copyfilerange(
+            ctx, inode_in, handle_in, offset_in, inode_out, handle_out, offset_out, len, flags,
+        )
+    }
+
     /// Query file lock status
     fn getlk(
         &self,
@@ -1296,7 +1580,7 @@ impl<FS: FileSystem> FileSystem for Arc<FS> {
         self.deref().setlkw(ctx, inode, handle, owner, lock, flags)
     }
 
-    /// send ioctl to the file
+    /// Send an ioctl to the file.
     #[allow(clippy::too_many_arguments)]
     fn ioctl(
         &self,
@@ -1305,11 +1589,12 @@ impl<FS: FileSystem> FileSystem for Arc<FS> {
         handle: Self::Handle,
         flags: u32,
         cmd: u32,
-        data: IoctlData,
+        arg: u64,
+        in_data: &[u8],
         out_size: u32,
-    ) -> io::Result<IoctlData> {
+    ) -> io::Result<IoctlReply> {
         self.deref()
-            .ioctl(ctx, inode, handle, flags, cmd, data, out_size)
+            .ioctl(ctx, inode, handle, flags, cmd, arg, in_data, out_size)
     }
 
     /// Query a file's block mapping info
@@ -1329,7 +1614,7 @@ impl<FS: FileSystem> FileSystem for Arc<FS> {
         ctx: &Context,
         inode: Self::Inode,
         handle: Self::Handle,
-        khandle: Self::Handle,
+        khandle: u64,
         flags: u32,
         events: u32,
     ) -> io::Result<u32> {
@@ -1337,8 +1622,36 @@ impl<FS: FileSystem> FileSystem for Arc<FS> {
             .poll(ctx, inode, handle, khandle, flags, events)
     }
 
+    #[cfg(target_os = "macos")]
+    fn setvolname(&self, ctx: &Context, name: &CStr) -> io::Result<()> {
+        self.deref().setvolname(ctx, name)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn exchange(
+        &self,
+        ctx: &Context,
+        olddir: Self::Inode,
+        oldname: &CStr,
+        newdir: Self::Inode,
+        newname: &CStr,
+        options: u64,
+    ) -> io::Result<()> {
+        self.deref()
+            .exchange(ctx, olddir, oldname, newdir, newname, options)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn getxtimes(&self, ctx: &Context, inode: Self::Inode) -> io::Result<GetxtimesOut> {
+        self.deref().getxtimes(ctx, inode)
+    }
+
     /// Send notify reply.
     fn notify_reply(&self) -> io::Result<()> {
         self.deref().notify_reply()
     }
+
+    fn debug_nlookup(&self, inode: Self::Inode) -> Option<u64> {
+        self.deref().debug_nlookup(inode)
+    }
 }
@@ -0,0 +1,1926 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! In-memory file system for tests of code built on the [FileSystem] and Vfs APIs.
+//!
+//! [MemFs] keeps directories, regular files with their contents, symbolic links, special files
+//! and extended attributes in memory, so tests can drive a [Vfs](crate::api::Vfs) or a
+//! [Server](crate::api::server::Server) without touching the host file system. Its behavior
+//! follows POSIX closely enough to validate callers:
+//!
+//! - Inode numbers are allocated sequentially from 2 and never reused, in the order nodes are
+//!   preloaded by [MemFsBuilder] then created.
+//! - Lookups are counted, and removed nodes are freed once forgotten and released.
+//! - Namespace changes fail like on the host, with `EEXIST`, `ENOTEMPTY`, `ENOTDIR`, `EISDIR`,
+//!   and permission checks apply to callers other than root.
+//! - Timestamps come from a [Clock], a [ManualClock](crate::api::clock::ManualClock) makes them
+//!   deterministic.
+//!
+//! The module is built with the `test-utils` feature.
+//!
+//! ```
+//! use std::ffi::CString;
+//! use fuse_backend_rs::api::filesystem::{Context, FileSystem, ROOT_ID};
+//! use fuse_backend_rs::api::mem_fs::MemFs;
+//!
+//! let fs = MemFs::builder()
+//!     .with_file("/etc/hostname", 0o644, b"guest\n")
+//!     .with_symlink("/hostname", "etc/hostname")
+//!     .build()
+//!     .unwrap();
+//! let ctx = Context::default();
+//! let etc = fs.lookup(&ctx, ROOT_ID, &CString::new("etc").unwrap()).unwrap();
+//! let file = fs.lookup(&ctx, etc.inode, &CString::new("hostname").unwrap()).unwrap();
+//! assert_eq!(file.attr.st_size, 6);
+//! ```
+
+// Types of `stat64` fields vary with the platform.
+#![allow(clippy::unnecessary_cast)]
+
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::io::{self, Read};
+use std::mem;
+use std::path::{Component, Path};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::abi::fuse_abi::{stat64, statvfs64, CreateIn, Statx};
+use crate::api::clock::{Clock, SystemClock};
+use crate::api::filesystem::*;
+#[cfg(not(feature = "async-io"))]
+use crate::api::{BackendFileSystem, VFS_MAX_INO};
+
+const MEMFS_NEXT_INODE: u64 = 2;
+const MEMFS_DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+const MEMFS_BLOCK_SIZE: u64 = 4096;
+const MEMFS_NAME_MAX: usize = 255;
+// Largest size of files, so offsets fit in memory.
+const MEMFS_MAX_FILE_SIZE: u64 = 1 << 32;
+
+// Timestamps to update.
+const ATIME: u8 = 1;
+const MTIME: u8 = 2;
+const CTIME: u8 = 4;
+
+fn ebadf() -> io::Error {
+    io::Error::from_raw_os_error(libc::EBADF)
+}
+
+fn enoent() -> io::Error {
+    io::Error::from_raw_os_error(libc::ENOENT)
+}
+
+fn errno(e: libc::c_int) -> io::Error {
+    io::Error::from_raw_os_error(e)
+}
+
+fn file_type(mode: u32) -> u32 {
+    mode & libc::S_IFMT as u32
+}
+
+fn touch(attr: &mut stat64, times: u8, now: SystemTime) {
+    let t = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let (sec, nsec) = (t.as_secs() as i64, t.subsec_nanos() as i64);
+    if times & ATIME != 0 {
+        attr.st_atime = sec as _;
+        attr.st_atime_nsec = nsec as _;
+    }
+    if times & MTIME != 0 {
+        attr.st_mtime = sec as _;
+        attr.st_mtime_nsec = nsec as _;
+    }
+    if times & CTIME != 0 {
+        attr.st_ctime = sec as _;
+        attr.st_ctime_nsec = nsec as _;
+    }
+}
+
+// Check the permissions of `ctx` for the access `mask` of `R_OK`, `W_OK` and `X_OK` bits.
+fn check_access(attr: &stat64, ctx: &Context, mask: u32) -> io::Result<()> {
+    let mask = mask & (libc::R_OK | libc::W_OK | libc::X_OK) as u32;
+    let mode = attr.st_mode as u32;
+    if ctx.uid == 0 {
+        // Root reads and writes anything, and executes files with an execute bit.
+        if mask & libc::X_OK as u32 == 0
+            || mode & 0o111 != 0
+            || file_type(mode) == libc::S_IFDIR as u32
+        {
+            return Ok(());
+        }
+        return Err(errno(libc::EACCES));
+    }
+
+    let bits = if ctx.uid == attr.st_uid as u32 {
+        mode >> 6
+    } else if ctx.gid == attr.st_gid as u32 || ctx.supp_gid == Some(attr.st_gid as u32) {
+        mode >> 3
+    } else {
+        mode
+    };
+    if bits & mask == mask {
+        Ok(())
+    } else {
+        Err(errno(libc::EACCES))
+    }
+}
+
+fn check_name(name: &[u8]) -> io::Result<()> {
+    if name.is_empty() || name.contains(&b'/') {
+        return Err(errno(libc::EINVAL));
+    }
+    if name.len() > MEMFS_NAME_MAX {
+        return Err(errno(libc::ENAMETOOLONG));
+    }
+    Ok(())
+}
+
+enum Node {
+    Dir(BTreeMap<Vec<u8>, u64>),
+    File(Vec<u8>),
+    Symlink(Vec<u8>),
+    Special,
+}
+
+struct MemInode {
+    attr: stat64,
+    node: Node,
+    // Parent of directories, for "..".
+    parent: u64,
+    xattrs: BTreeMap<Vec<u8>, Vec<u8>>,
+    nlookup: u64,
+    opens: u64,
+}
+
+impl MemInode {
+    fn children(&self) -> io::Result<&BTreeMap<Vec<u8>, u64>> {
+        match &self.node {
+            Node::Dir(children) => Ok(children),
+            _ => Err(errno(libc::ENOTDIR)),
+        }
+    }
+
+    fn children_mut(&mut self) -> io::Result<&mut BTreeMap<Vec<u8>, u64>> {
+        match &mut self.node {
+            Node::Dir(children) => Ok(children),
+            _ => Err(errno(libc::ENOTDIR)),
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        matches!(self.node, Node::Dir(_))
+    }
+
+    fn set_size(&mut self, size: u64) -> io::Result<()> {
+        match &mut self.node {
+            Node::File(data) => {
+                if size > MEMFS_MAX_FILE_SIZE {
+                    return Err(errno(libc::EFBIG));
+                }
+                data.resize(size as usize, 0);
+                self.attr.st_size = size as _;
+                self.attr.st_blocks = (size.div_ceil(MEMFS_BLOCK_SIZE) * 8) as _;
+                Ok(())
+            }
+            Node::Dir(_) => Err(errno(libc::EISDIR)),
+            _ => Err(errno(libc::EINVAL)),
+        }
+    }
+}
+
+struct MemHandle {
+    ino: u64,
+    flags: u32,
+}
+
+struct MemState {
+    inodes: BTreeMap<u64, MemInode>,
+    next_ino: u64,
+    handles: BTreeMap<u64, MemHandle>,
+    next_handle: u64,
+}
+
+impl MemState {
+    fn get(&self, ino: u64) -> io::Result<&MemInode> {
+        self.inodes.get(&ino).ok_or_else(ebadf)
+    }
+
+    fn get_mut(&mut self, ino: u64) -> io::Result<&mut MemInode> {
+        self.inodes.get_mut(&ino).ok_or_else(ebadf)
+    }
+
+    fn handle(&self, ino: u64, handle: u64) -> io::Result<&MemHandle> {
+        match self.handles.get(&handle) {
+            Some(h) if h.ino == ino => Ok(h),
+            _ => Err(ebadf()),
+        }
+    }
+
+    fn child(&self, parent: u64, name: &[u8]) -> io::Result<Option<u64>> {
+        Ok(self.get(parent)?.children()?.get(name).copied())
+    }
+
+    // Free `ino` once it has no link, lookup nor open handle left.
+    fn evict(&mut self, ino: u64) {
+        if let Some(inode) = self.inodes.get(&ino) {
+            if ino != ROOT_ID && inode.attr.st_nlink == 0 && inode.nlookup == 0 && inode.opens == 0
+            {
+                self.inodes.remove(&ino);
+            }
+        }
+    }
+
+    fn open_handle(&mut self, ino: u64, flags: u32) -> io::Result<u64> {
+        self.get_mut(ino)?.opens += 1;
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert(handle, MemHandle { ino, flags });
+        Ok(handle)
+    }
+
+    fn close_handle(&mut self, ino: u64, handle: u64) -> io::Result<()> {
+        self.handle(ino, handle)?;
+        self.handles.remove(&handle);
+        if let Ok(inode) = self.get_mut(ino) {
+            inode.opens -= 1;
+        }
+        self.evict(ino);
+        Ok(())
+    }
+
+    // Check whether `ino` is `dir` or one of its descendants.
+    fn is_within(&self, mut ino: u64, dir: u64) -> bool {
+        loop {
+            if ino == dir {
+                return true;
+            }
+            match self.inodes.get(&ino) {
+                Some(inode) if ino != ROOT_ID => ino = inode.parent,
+                _ => return false,
+            }
+        }
+    }
+}
+
+/// An in-memory [FileSystem], see the [module documentation](self).
+pub struct MemFs {
+    state: Mutex<MemState>,
+    clock: Arc<dyn Clock>,
+    attr_timeout: Duration,
+    entry_timeout: Duration,
+}
+
+impl MemFs {
+    /// Create an empty file system, with a root directory owned by root with mode 0755.
+    pub fn new() -> Self {
+        Self::with_clock(Arc::new(SystemClock::default()))
+    }
+
+    /// Create a builder of a file system preloaded with a tree.
+    pub fn builder() -> MemFsBuilder {
+        MemFsBuilder::default()
+    }
+
+    fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        let mut state = MemState {
+            inodes: BTreeMap::new(),
+            next_ino: MEMFS_NEXT_INODE,
+            handles: BTreeMap::new(),
+            next_handle: 1,
+        };
+        let mut attr = Self::new_attr(ROOT_ID, libc::S_IFDIR as u32 | 0o755, 0, 0, clock.now());
+        attr.st_nlink = 2;
+        state.inodes.insert(
+            ROOT_ID,
+            MemInode {
+                attr,
+                node: Node::Dir(BTreeMap::new()),
+                parent: ROOT_ID,
+                xattrs: BTreeMap::new(),
+                nlookup: 0,
+                opens: 0,
+            },
+        );
+
+        MemFs {
+            state: Mutex::new(state),
+            clock,
+            attr_timeout: MEMFS_DEFAULT_TIMEOUT,
+            entry_timeout: MEMFS_DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Get the number of inodes of the file system, including removed ones still referenced.
+    pub fn inode_count(&self) -> usize {
+        self.lock().inodes.len()
+    }
+
+    /// Get the number of open file and directory handles.
+    pub fn handle_count(&self) -> usize {
+        self.lock().handles.len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MemState> {
+        self.state.lock().unwrap()
+    }
+
+    fn new_attr(ino: u64, mode: u32, uid: u32, gid: u32, now: SystemTime) -> stat64 {
+        // Safe because `stat64` only has plain integer fields.
+        let mut attr: stat64 = unsafe { mem::zeroed() };
+        attr.st_ino = ino as _;
+        attr.st_mode = mode as _;
+        attr.st_nlink = 1;
+        attr.st_uid = uid as _;
+        attr.st_gid = gid as _;
+        attr.st_blksize = MEMFS_BLOCK_SIZE as _;
+        touch(&mut attr, ATIME | MTIME | CTIME, now);
+        attr
+    }
+
+    fn entry(&self, state: &mut MemState, ino: u64) -> io::Result<Entry> {
+        let inode = state.get_mut(ino)?;
+        inode.nlookup += 1;
+        Ok(Entry {
+            inode: ino,
+            generation: 0,
+            attr: inode.attr,
+            attr_flags: 0,
+            attr_timeout: self.attr_timeout,
+            entry_timeout: self.entry_timeout,
+        })
+    }
+
+    // Add a node named `name` to `parent`, owned by the caller.
+    fn add_node(
+        &self,
+        state: &mut MemState,
+        ctx: &Context,
+        parent: u64,
+        name: &[u8],
+        mode: u32,
+        node: Node,
+    ) -> io::Result<u64> {
+        check_name(name)?;
+        let now = self.clock.now();
+        let dir = state.get(parent)?;
+        if dir.children()?.contains_key(name) {
+            return Err(errno(libc::EEXIST));
+        }
+        check_access(&dir.attr, ctx, (libc::W_OK | libc::X_OK) as u32)?;
+        let dir_attr = dir.attr;
+
+        // Files created in set-group-ID directories belong to the group of the directory.
+        let mut mode = mode;
+        let gid = if dir_attr.st_mode as u32 & libc::S_ISGID as u32 != 0 {
+            if matches!(node, Node::Dir(_)) {
+                mode |= libc::S_ISGID as u32;
+            }
+            dir_attr.st_gid as u32
+        } else {
+            ctx.gid
+        };
+
+        let ino = state.next_ino;
+        state.next_ino += 1;
+        let is_dir = matches!(node, Node::Dir(_));
+        let mut attr = Self::new_attr(ino, mode, ctx.uid, gid, now);
+        if let Node::Symlink(target) = &node {
+            attr.st_size = target.len() as _;
+        }
+        if is_dir {
+            attr.st_nlink = 2;
+        }
+        state.inodes.insert(
+            ino,
+            MemInode {
+                attr,
+                node,
+                parent,
+                xattrs: BTreeMap::new(),
+                nlookup: 0,
+                opens: 0,
+            },
+        );
+
+        let dir = state.get_mut(parent)?;
+        dir.children_mut()?.insert(name.to_vec(), ino);
+        if is_dir {
+            dir.attr.st_nlink += 1;
+        }
+        touch(&mut dir.attr, MTIME | CTIME, now);
+        Ok(ino)
+    }
+
+    // Remove the entry `name` of `parent`, a directory if `dir`.
+    fn remove_node(&self, ctx: &Context, parent: u64, name: &CStr, dir: bool) -> io::Result<()> {
+        let mut state = self.lock();
+        let name = name.to_bytes();
+        let ino = state.child(parent, name)?.ok_or_else(enoent)?;
+        check_access(
+            &state.get(parent)?.attr,
+            ctx,
+            (libc::W_OK | libc::X_OK) as u32,
+        )?;
+        let inode = state.get(ino)?;
+        match (dir, inode.is_dir()) {
+            (true, false) => return Err(errno(libc::ENOTDIR)),
+            (false, true) => return Err(errno(libc::EISDIR)),
+            (true, true) if !inode.children()?.is_empty() => return Err(errno(libc::ENOTEMPTY)),
+            _ => {}
+        }
+
+        let now = self.clock.now();
+        let dir_inode = state.get_mut(parent)?;
+        dir_inode.children_mut()?.remove(name);
+        if dir {
+            dir_inode.attr.st_nlink -= 1;
+        }
+        touch(&mut dir_inode.attr, MTIME | CTIME, now);
+        let inode = state.get_mut(ino)?;
+        inode.attr.st_nlink = if dir { 0 } else { inode.attr.st_nlink - 1 };
+        touch(&mut inode.attr, CTIME, now);
+        state.evict(ino);
+        Ok(())
+    }
+
+    fn open_flags_access(flags: u32) -> u32 {
+        match flags as i32 & libc::O_ACCMODE {
+            libc::O_RDONLY => libc::R_OK as u32,
+            libc::O_WRONLY => libc::W_OK as u32,
+            _ => (libc::R_OK | libc::W_OK) as u32,
+        }
+    }
+
+    fn do_open(
+        &self,
+        state: &mut MemState,
+        ctx: &Context,
+        ino: u64,
+        flags: u32,
+    ) -> io::Result<u64> {
+        let writable = flags as i32 & libc::O_ACCMODE != libc::O_RDONLY;
+        let inode = state.get(ino)?;
+        check_access(&inode.attr, ctx, Self::open_flags_access(flags))?;
+        if inode.is_dir() && writable {
+            return Err(errno(libc::EISDIR));
+        }
+        if writable && flags as i32 & libc::O_TRUNC != 0 {
+            let now = self.clock.now();
+            let inode = state.get_mut(ino)?;
+            if let Node::File(_) = inode.node {
+                inode.set_size(0)?;
+                touch(&mut inode.attr, MTIME | CTIME, now);
+            }
+        }
+        state.open_handle(ino, flags)
+    }
+
+    fn do_readdir(
+        &self,
+        ctx: &Context,
+        inode: u64,
+        handle: u64,
+        offset: u64,
+        plus: bool,
+        add_entry: &mut dyn FnMut(DirEntry, Option<Entry>) -> io::Result<usize>,
+    ) -> io::Result<()> {
+        let mut state = self.lock();
+        state.handle(inode, handle)?;
+        let dir = state.get(inode)?;
+        let mut entries = vec![(b".".to_vec(), inode), (b"..".to_vec(), dir.parent)];
+        entries.extend(dir.children()?.iter().map(|(n, i)| (n.clone(), *i)));
+        let _ = ctx;
+
+        for (idx, (name, ino)) in entries.iter().enumerate().skip(offset as usize) {
+            let attr = state.get(*ino)?.attr;
+            let dirent = DirEntry {
+                ino: *ino,
+                offset: idx as u64 + 1,
+                type_: file_type(attr.st_mode as u32) >> 12,
+                name,
+            };
+            // The kernel doesn't look up "." and "..", their entries carry no inode.
+            let dot = idx < 2;
+            let entry = if !plus {
+                None
+            } else if dot {
+                Some(Entry::default())
+            } else {
+                Some(Entry {
+                    inode: *ino,
+                    attr,
+                    attr_timeout: self.attr_timeout,
+                    entry_timeout: self.entry_timeout,
+                    ..Default::default()
+                })
+            };
+            if add_entry(dirent, entry)? == 0 {
+                break;
+            }
+            if plus && !dot {
+                state.get_mut(*ino)?.nlookup += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn do_setattr(
+        &self,
+        ctx: &Context,
+        inode: u64,
+        attr: stat64,
+        valid: SetattrValid,
+    ) -> io::Result<stat64> {
+        let mut state = self.lock();
+        let now = self.clock.now();
+        let node = state.get_mut(inode)?;
+        let owner = ctx.uid == 0 || ctx.uid == node.attr.st_uid as u32;
+
+        if valid.contains(SetattrValid::MODE) {
+            if !owner {
+                return Err(errno(libc::EPERM));
+            }
+            node.attr.st_mode = ((node.attr.st_mode as u32 & libc::S_IFMT as u32)
+                | (attr.st_mode as u32 & !(libc::S_IFMT as u32)))
+                as _;
+        }
+        if valid.intersects(SetattrValid::UID | SetattrValid::GID) {
+            let uid_change = valid.contains(SetattrValid::UID) && attr.st_uid != node.attr.st_uid;
+            let gid_change = valid.contains(SetattrValid::GID)
+                && attr.st_gid != node.attr.st_gid
+                && attr.st_gid as u32 != ctx.gid
+                && Some(attr.st_gid as u32) != ctx.supp_gid;
+            if ctx.uid != 0 && (!owner || uid_change || gid_change) {
+                return Err(errno(libc::EPERM));
+            }
+            if valid.contains(SetattrValid::UID) {
+                node.attr.st_uid = attr.st_uid;
+            }
+            if valid.contains(SetattrValid::GID) {
+                node.attr.st_gid = attr.st_gid;
+            }
+        }
+        if valid.contains(SetattrValid::SIZE) {
+            check_access(&node.attr, ctx, libc::W_OK as u32)?;
+            node.set_size(attr.st_size as u64)?;
+            touch(&mut node.attr, MTIME, now);
+        }
+
+        let times = valid
+            & (SetattrValid::ATIME
+                | SetattrValid::MTIME
+                | SetattrValid::ATIME_NOW
+                | SetattrValid::MTIME_NOW);
+        if !times.is_empty() {
+            // Setting explicit times needs ownership, setting them to now write access.
+            if !owner {
+                if times.intersects(SetattrValid::ATIME | SetattrValid::MTIME)
+                    && !times.contains(SetattrValid::ATIME_NOW | SetattrValid::MTIME_NOW)
+                {
+                    return Err(errno(libc::EPERM));
+                }
+                check_access(&node.attr, ctx, libc::W_OK as u32)?;
+            }
+            if valid.contains(SetattrValid::ATIME_NOW) {
+                touch(&mut node.attr, ATIME, now);
+            } else if valid.contains(SetattrValid::ATIME) {
+                node.attr.st_atime = attr.st_atime;
+                node.attr.st_atime_nsec = attr.st_atime_nsec;
+            }
+            if valid.contains(SetattrValid::MTIME_NOW) {
+                touch(&mut node.attr, MTIME, now);
+            } else if valid.contains(SetattrValid::MTIME) {
+                node.attr.st_mtime = attr.st_mtime;
+                node.attr.st_mtime_nsec = attr.st_mtime_nsec;
+            }
+        }
+        touch(&mut node.attr, CTIME, now);
+        Ok(node.attr)
+    }
+
+    fn do_rename(
+        &self,
+        ctx: &Context,
+        olddir: u64,
+        oldname: &[u8],
+        newdir: u64,
+        newname: &[u8],
+        flags: u32,
+    ) -> io::Result<()> {
+        let noreplace = flags & libc::RENAME_NOREPLACE != 0;
+        let exchange = flags & libc::RENAME_EXCHANGE != 0;
+        if flags & !(libc::RENAME_NOREPLACE | libc::RENAME_EXCHANGE) != 0 || (noreplace && exchange)
+        {
+            return Err(errno(libc::EINVAL));
+        }
+        check_name(newname)?;
+
+        let mut state = self.lock();
+        let src = state.child(olddir, oldname)?.ok_or_else(enoent)?;
+        let dst = state.child(newdir, newname)?;
+        for dir in [olddir, newdir] {
+            check_access(&state.get(dir)?.attr, ctx, (libc::W_OK | libc::X_OK) as u32)?;
+        }
+        if dst == Some(src) {
+            return Ok(());
+        }
+        let src_dir = state.get(src)?.is_dir();
+        // A directory can't be moved into itself.
+        if src_dir && state.is_within(newdir, src) {
+            return Err(errno(libc::EINVAL));
+        }
+        match dst {
+            Some(_) if noreplace => return Err(errno(libc::EEXIST)),
+            None if exchange => return Err(enoent()),
+            Some(dst) if exchange && state.get(dst)?.is_dir() && state.is_within(olddir, dst) => {
+                return Err(errno(libc::EINVAL))
+            }
+            Some(_) if exchange => {}
+            Some(dst) => {
+                let dst_inode = state.get(dst)?;
+                match (src_dir, dst_inode.is_dir()) {
+                    (true, false) => return Err(errno(libc::ENOTDIR)),
+                    (false, true) => return Err(errno(libc::EISDIR)),
+                    (true, true) if !dst_inode.children()?.is_empty() => {
+                        return Err(errno(libc::ENOTEMPTY))
+                    }
+                    _ => {}
+                }
+            }
+            None => {}
+        }
+
+        let now = self.clock.now();
+        if exchange {
+            let dst = dst.unwrap();
+            let dst_dir = state.get(dst)?.is_dir();
+            state
+                .get_mut(olddir)?
+                .children_mut()?
+                .insert(oldname.to_vec(), dst);
+            state
+                .get_mut(newdir)?
+                .children_mut()?
+                .insert(newname.to_vec(), src);
+            for (ino, parent, is_dir) in [(src, newdir, src_dir), (dst, olddir, dst_dir)] {
+                let inode = state.get_mut(ino)?;
+                inode.parent = parent;
+                touch(&mut inode.attr, CTIME, now);
+                if is_dir && olddir != newdir {
+                    state.get_mut(parent)?.attr.st_nlink += 1;
+                    let other = if parent == newdir { olddir } else { newdir };
+                    state.get_mut(other)?.attr.st_nlink -= 1;
+                }
+            }
+        } else {
+            if let Some(dst) = dst {
+                let inode = state.get_mut(dst)?;
+                let dst_dir = inode.is_dir();
+                inode.attr.st_nlink = if dst_dir { 0 } else { inode.attr.st_nlink - 1 };
+                touch(&mut inode.attr, CTIME, now);
+                if dst_dir {
+                    state.get_mut(newdir)?.attr.st_nlink -= 1;
+                }
+                state.evict(dst);
+            }
+            state.get_mut(olddir)?.children_mut()?.remove(oldname);
+            state
+                .get_mut(newdir)?
+                .children_mut()?
+                .insert(newname.to_vec(), src);
+            let inode = state.get_mut(src)?;
+            inode.parent = newdir;
+            touch(&mut inode.attr, CTIME, now);
+            if src_dir {
+                state.get_mut(olddir)?.attr.st_nlink -= 1;
+                state.get_mut(newdir)?.attr.st_nlink += 1;
+            }
+        }
+        for dir in [olddir, newdir] {
+            touch(&mut state.get_mut(dir)?.attr, MTIME | CTIME, now);
+        }
+        Ok(())
+    }
+}
+
+impl Default for MemFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for MemFs {
+    type Inode = u64;
+    type Handle = u64;
+
+    fn init(&self, _capable: FsOptions) -> io::Result<FsOptions> {
+        Ok(FsOptions::empty())
+    }
+
+    fn lookup(&self, ctx: &Context, parent: u64, name: &CStr) -> io::Result<Entry> {
+        let mut state = self.lock();
+        let dir = state.get(parent)?;
+        check_access(&dir.attr, ctx, libc::X_OK as u32)?;
+        let ino = match name.to_bytes() {
+            b"." => parent,
+            b".." => dir.parent,
+            name => {
+                check_name(name)?;
+                dir.children()?.get(name).copied().ok_or_else(enoent)?
+            }
+        };
+        self.entry(&mut state, ino)
+    }
+
+    fn forget(&self, _ctx: &Context, inode: u64, count: u64) {
+        let mut state = self.lock();
+        if let Ok(node) = state.get_mut(inode) {
+            node.nlookup = node.nlookup.saturating_sub(count);
+            state.evict(inode);
+        }
+    }
+
+    fn getattr(
+        &self,
+        _ctx: &Context,
+        inode: u64,
+        _handle: Option<u64>,
+    ) -> io::Result<(stat64, Duration)> {
+        Ok((self.lock().get(inode)?.attr, self.attr_timeout))
+    }
+
+    fn statx(
+        &self,
+        _ctx: &Context,
+        inode: u64,
+        _handle: Option<u64>,
+        _flags: u32,
+        _mask: u32,
+    ) -> io::Result<(Statx, Duration)> {
+        Ok((Statx::from(self.lock().get(inode)?.attr), self.attr_timeout))
+    }
+
+    fn setattr(
+        &self,
+        ctx: &Context,
+        inode: u64,
+        attr: stat64,
+        _handle: Option<u64>,
+        valid: SetattrValid,
+    ) -> io::Result<(stat64, Duration)> {
+        Ok((self.do_setattr(ctx, inode, attr, valid)?, self.attr_timeout))
+    }
+
+    fn readlink(&self, _ctx: &Context, inode: u64) -> io::Result<Vec<u8>> {
+        match &self.lock().get(inode)?.node {
+            Node::Symlink(target) => Ok(target.clone()),
+            _ => Err(errno(libc::EINVAL)),
+        }
+    }
+
+    fn symlink(
+        &self,
+        ctx: &Context,
+        linkname: &CStr,
+        parent: u64,
+        name: &CStr,
+    ) -> io::Result<Entry> {
+        let mut state = self.lock();
+        let node = Node::Symlink(linkname.to_bytes().to_vec());
+        let mode = libc::S_IFLNK as u32 | 0o777;
+        let ino = self.add_node(&mut state, ctx, parent, name.to_bytes(), mode, node)?;
+        self.entry(&mut state, ino)
+    }
+
+    fn mknod(
+        &self,
+        ctx: &Context,
+        parent: u64,
+        name: &CStr,
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+    ) -> io::Result<Entry> {
+        let (mode, node) = match file_type(mode) {
+            0 => (mode | libc::S_IFREG as u32, Node::File(Vec::new())),
+            t if t == libc::S_IFREG as u32 => (mode, Node::File(Vec::new())),
+            t if t == libc::S_IFIFO as u32
+                || t == libc::S_IFCHR as u32
+                || t == libc::S_IFBLK as u32
+                || t == libc::S_IFSOCK as u32 =>
+            {
+                (mode, Node::Special)
+            }
+            t if t == libc::S_IFDIR as u32 => return Err(errno(libc::EPERM)),
+            _ => return Err(errno(libc::EINVAL)),
+        };
+        let mut state = self.lock();
+        let ino = self.add_node(
+            &mut state,
+            ctx,
+            parent,
+            name.to_bytes(),
+            mode & !umask,
+            node,
+        )?;
+        state.get_mut(ino)?.attr.st_rdev = rdev as _;
+        self.entry(&mut state, ino)
+    }
+
+    fn mkdir(
+        &self,
+        ctx: &Context,
+        parent: u64,
+        name: &CStr,
+        mode: u32,
+        umask: u32,
+    ) -> io::Result<Entry> {
+        let mut state = self.lock();
+        let mode = libc::S_IFDIR as u32 | (mode & !umask & 0o7777);
+        let node = Node::Dir(BTreeMap::new());
+        let ino = self.add_node(&mut state, ctx, parent, name.to_bytes(), mode, node)?;
+        self.entry(&mut state, ino)
+    }
+
+    fn unlink(&self, ctx: &Context, parent: u64, name: &CStr) -> io::Result<()> {
+        self.remove_node(ctx, parent, name, false)
+    }
+
+    fn rmdir(&self, ctx: &Context, parent: u64, name: &CStr) -> io::Result<()> {
+        match name.to_bytes() {
+            b"." => Err(errno(libc::EINVAL)),
+            b".." => Err(errno(libc::ENOTEMPTY)),
+            _ => self.remove_node(ctx, parent, name, true),
+        }
+    }
+
+    fn rename(
+        &self,
+        ctx: &Context,
+        olddir: u64,
+        oldname: &CStr,
+        newdir: u64,
+        newname: &CStr,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.do_rename(
+            ctx,
+            olddir,
+            oldname.to_bytes(),
+            newdir,
+            newname.to_bytes(),
+            flags,
+        )
+    }
+
+    fn link(&self, ctx: &Context, inode: u64, newparent: u64, newname: &CStr) -> io::Result<Entry> {
+        let mut state = self.lock();
+        let newname = newname.to_bytes();
+        check_name(newname)?;
+        if state.get(inode)?.is_dir() {
+            return Err(errno(libc::EPERM));
+        }
+        let dir = state.get(newparent)?;
+        if dir.children()?.contains_key(newname) {
+            return Err(errno(libc::EEXIST));
+        }
+        check_access(&dir.attr, ctx, (libc::W_OK | libc::X_OK) as u32)?;
+
+        let now = self.clock.now();
+        let dir = state.get_mut(newparent)?;
+        dir.children_mut()?.insert(newname.to_vec(), inode);
+        touch(&mut dir.attr, MTIME | CTIME, now);
+        let node = state.get_mut(inode)?;
+        node.attr.st_nlink += 1;
+        touch(&mut node.attr, CTIME, now);
+        self.entry(&mut state, inode)
+    }
+
+    fn open(
+        &self,
+        ctx: &Context,
+        inode: u64,
+        flags: u32,
+        _fuse_flags: u32,
+    ) -> io::Result<(Option<u64>, OpenOptions)> {
+        let mut state = self.lock();
+        if let Node::Symlink(_) = state.get(inode)?.node {
+            return Err(errno(libc::ELOOP));
+        }
+        let handle = self.do_open(&mut state, ctx, inode, flags)?;
+        Ok((Some(handle), OpenOptions::empty()))
+    }
+
+    fn create(
+        &self,
+        ctx: &Context,
+        parent: u64,
+        name: &CStr,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<u64>, OpenOptions)> {
+        let mut state = self.lock();
+        let ino = match state.child(parent, name.to_bytes())? {
+            Some(_) if args.flags as i32 & libc::O_EXCL != 0 => return Err(errno(libc::EEXIST)),
+            Some(ino) => {
+                if state.get(ino)?.is_dir() {
+                    return Err(errno(libc::EISDIR));
+                }
+                self.do_open(&mut state, ctx, ino, args.flags)?;
+                ino
+            }
+            None => {
+                let mode = libc::S_IFREG as u32 | (args.mode & !args.umask & 0o7777);
+                let node = Node::File(Vec::new());
+                let ino = self.add_node(&mut state, ctx, parent, name.to_bytes(), mode, node)?;
+                // The creator may write the file, whatever its mode.
+                state.open_handle(ino, args.flags)?;
+                ino
+            }
+        };
+        let handle = state.next_handle - 1;
+        let entry = self.entry(&mut state, ino)?;
+        Ok((entry, Some(handle), OpenOptions::empty()))
+    }
+
+    fn tmpfile(
+        &self,
+        ctx: &Context,
+        parent: u64,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<u64>, OpenOptions)> {
+        let mut state = self.lock();
+        let dir = state.get(parent)?;
+        dir.children()?;
+        check_access(&dir.attr, ctx, (libc::W_OK | libc::X_OK) as u32)?;
+
+        let ino = state.next_ino;
+        state.next_ino += 1;
+        let mode = libc::S_IFREG as u32 | (args.mode & !args.umask & 0o7777);
+        let mut attr = Self::new_attr(ino, mode, ctx.uid, ctx.gid, self.clock.now());
+        attr.st_nlink = 0;
+        state.inodes.insert(
+            ino,
+            MemInode {
+                attr,
+                node: Node::File(Vec::new()),
+                parent,
+                xattrs: BTreeMap::new(),
+                nlookup: 0,
+                opens: 0,
+            },
+        );
+        let handle = state.open_handle(ino, args.flags)?;
+        let entry = self.entry(&mut state, ino)?;
+        Ok((entry, Some(handle), OpenOptions::empty()))
+    }
+
+    fn read(
+        &self,
+        _ctx: &Context,
+        inode: u64,
+        handle: u64,
+        w: &mut dyn ZeroCopyWriter,
+        size: u32,
+        offset: u64,
+        _lock_owner: Option<u64>,
+        _flags: u32,
+    ) -> io::Result<usize> {
+        let mut state = self.lock();
+        if state.handle(inode, handle)?.flags as i32 & libc::O_ACCMODE == libc::O_WRONLY {
+            return Err(ebadf());
+        }
+        let now = self.clock.now();
+        let node = state.get_mut(inode)?;
+        let data = match &node.node {
+            Node::File(data) => data,
+            Node::Dir(_) => return Err(errno(libc::EISDIR)),
+            _ => return Err(errno(libc::EINVAL)),
+        };
+        let start = std::cmp::min(offset, data.len() as u64) as usize;
+        let end = std::cmp::min(start + size as usize, data.len());
+        w.write_all(&data[start..end])?;
+        touch(&mut node.attr, ATIME, now);
+        Ok(end - start)
+    }
+
+    fn write(
+        &self,
+        _ctx: &Context,
+        inode: u64,
+        handle: u64,
+        r: &mut dyn ZeroCopyReader,
+        size: u32,
+        offset: u64,
+        _lock_owner: Option<u64>,
+        _delayed_write: bool,
+        _flags: u32,
+        _fuse_flags: u32,
+    ) -> io::Result<usize> {
+        let mut state = self.lock();
+        let flags = state.handle(inode, handle)?.flags as i32;
+        if flags & libc::O_ACCMODE == libc::O_RDONLY {
+            return Err(ebadf());
+        }
+        let mut buf = Vec::with_capacity(size as usize);
+        (&mut *r).take(size as u64).read_to_end(&mut buf)?;
+
+        let now = self.clock.now();
+        let node = state.get_mut(inode)?;
+        let len = match &node.node {
+            Node::File(data) => data.len() as u64,
+            Node::Dir(_) => return Err(errno(libc::EISDIR)),
+            _ => return Err(errno(libc::EINVAL)),
+        };
+        let offset = if flags & libc::O_APPEND != 0 {
+            len
+        } else {
+            offset
+        };
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .filter(|end| *end <= MEMFS_MAX_FILE_SIZE)
+            .ok_or_else(|| errno(libc::EFBIG))?;
+        if end > len {
+            node.set_size(end)?;
+        }
+        if let Node::File(data) = &mut node.node {
+            data[offset as usize..end as usize].copy_from_slice(&buf);
+        }
+        touch(&mut node.attr, MTIME | CTIME, now);
+        Ok(buf.len())
+    }
+
+    fn flush(&self, _ctx: &Context, inode: u64, handle: u64, _lock_owner: u64) -> io::Result<()> {
+        self.lock().handle(inode, handle).map(|_| ())
+    }
+
+    fn fsync(&self, _ctx: &Context, inode: u64, _datasync: bool, handle: u64) -> io::Result<()> {
+        self.lock().handle(inode, handle).map(|_| ())
+    }
+
+    fn fallocate(
+        &self,
+        _ctx: &Context,
+        inode: u64,
+        handle: u64,
+        mode: u32,
+        offset: u64,
+        length: u64,
+    ) -> io::Result<()> {
+        let mut state = self.lock();
+        if state.handle(inode, handle)?.flags as i32 & libc::O_ACCMODE == libc::O_RDONLY {
+            return Err(ebadf());
+        }
+        let end = offset
+            .checked_add(length)
+            .filter(|end| *end <= MEMFS_MAX_FILE_SIZE)
+            .ok_or_else(|| errno(libc::EFBIG))?;
+        let now = self.clock.now();
+        let node = state.get_mut(inode)?;
+        let len = match &node.node {
+            Node::File(data) => data.len() as u64,
+            _ => return Err(errno(libc::ENODEV)),
+        };
+
+        let keep_size = mode as i32 & libc::FALLOC_FL_KEEP_SIZE != 0;
+        match mode as i32 & !libc::FALLOC_FL_KEEP_SIZE {
+            0 => {
+                if !keep_size && end > len {
+                    node.set_size(end)?;
+                }
+            }
+            libc::FALLOC_FL_PUNCH_HOLE if keep_size => {
+                if let Node::File(data) = &mut node.node {
+                    let end = std::cmp::min(end, len) as usize;
+                    let start = std::cmp::min(offset as usize, end);
+                    data[start..end].fill(0);
+                }
+            }
+            _ => return Err(errno(libc::EOPNOTSUPP)),
+        }
+        touch(&mut node.attr, MTIME | CTIME, now);
+        Ok(())
+    }
+
+    fn release(
+        &self,
+        _ctx: &Context,
+        inode: u64,
+        _flags: u32,
+        handle: u64,
+        _flush: bool,
+        _flock_release: bool,
+        _lock_owner: Option<u64>,
+    ) -> io::Result<()> {
+        self.lock().close_handle(inode, handle)
+    }
+
+    fn statfs(&self, _ctx: &Context, inode: u64) -> io::Result<statvfs64> {
+        let state = self.lock();
+        state.get(inode)?;
+        let blocks = state
+            .inodes
+            .values()
+            .map(|i| i.attr.st_blocks as u64 / 8)
+            .sum::<u64>();
+        // Safe because `statvfs64` only has plain integer fields.
+        let mut st: statvfs64 = unsafe { mem::zeroed() };
+        st.f_bsize = MEMFS_BLOCK_SIZE as _;
+        st.f_frsize = MEMFS_BLOCK_SIZE as _;
+        st.f_blocks = blocks as _;
+        st.f_files = state.inodes.len() as _;
+        st.f_namemax = MEMFS_NAME_MAX as _;
+        Ok(st)
+    }
+
+    fn syncfs(&self, _ctx: &Context, _inode: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn setxattr(
+        &self,
+        ctx: &Context,
+        inode: u64,
+        name: &CStr,
+        value: &[u8],
+        flags: u32,
+    ) -> io::Result<()> {
+        let mut state = self.lock();
+        let now = self.clock.now();
+        let node = state.get_mut(inode)?;
+        if ctx.uid != 0 && ctx.uid != node.attr.st_uid as u32 {
+            check_access(&node.attr, ctx, libc::W_OK as u32)?;
+        }
+        let name = name.to_bytes();
+        let exists = node.xattrs.contains_key(name);
+        if flags as i32 & libc::XATTR_CREATE != 0 && exists {
+            return Err(errno(libc::EEXIST));
+        }
+        if flags as i32 & libc::XATTR_REPLACE != 0 && !exists {
+            return Err(errno(libc::ENODATA));
+        }
+        node.xattrs.insert(name.to_vec(), value.to_vec());
+        touch(&mut node.attr, CTIME, now);
+        Ok(())
+    }
+
+    fn getxattr(
+        &self,
+        _ctx: &Context,
+        inode: u64,
+        name: &CStr,
+        size: u32,
+    ) -> io::Result<GetxattrReply> {
+        let state = self.lock();
+        let value = state
+            .get(inode)?
+            .xattrs
+            .get(name.to_bytes())
+            .ok_or_else(|| errno(libc::ENODATA))?;
+        if size == 0 {
+            Ok(GetxattrReply::Count(value.len() as u32))
+        } else if value.len() > size as usize {
+            Err(errno(libc::ERANGE))
+        } else {
+            Ok(GetxattrReply::Value(value.clone()))
+        }
+    }
+
+    fn listxattr(&self, _ctx: &Context, inode: u64, size: u32) -> io::Result<ListxattrReply> {
+        let state = self.lock();
+        let mut names = Vec::new();
+        for name in state.get(inode)?.xattrs.keys() {
+            names.extend_from_slice(name);
+            names.push(0);
+        }
+        if size == 0 {
+            Ok(ListxattrReply::Count(names.len() as u32))
+        } else if names.len() > size as usize {
+            Err(errno(libc::ERANGE))
+        } else {
+            Ok(ListxattrReply::Names(names))
+        }
+    }
+
+    fn removexattr(&self, ctx: &Context, inode: u64, name: &CStr) -> io::Result<()> {
+        let mut state = self.lock();
+        let now = self.clock.now();
+        let node = state.get_mut(inode)?;
+        if ctx.uid != 0 && ctx.uid != node.attr.st_uid as u32 {
+            check_access(&node.attr, ctx, libc::W_OK as u32)?;
+        }
+        node.xattrs
+            .remove(name.to_bytes())
+            .ok_or_else(|| errno(libc::ENODATA))?;
+        touch(&mut node.attr, CTIME, now);
+        Ok(())
+    }
+
+    fn opendir(
+        &self,
+        ctx: &Context,
+        inode: u64,
+        flags: u32,
+    ) -> io::Result<(Option<u64>, OpenOptions)> {
+        let mut state = self.lock();
+        let dir = state.get(inode)?;
+        dir.children()?;
+        check_access(&dir.attr, ctx, libc::R_OK as u32)?;
+        let handle = state.open_handle(inode, flags)?;
+        Ok((Some(handle), OpenOptions::empty()))
+    }
+
+    fn readdir(
+        &self,
+        ctx: &Context,
+        inode: u64,
+        handle: u64,
+        _size: u32,
+        offset: u64,
+        add_entry: &mut dyn FnMut(DirEntry) -> io::Result<usize>,
+    ) -> io::Result<()> {
+        self.do_readdir(ctx, inode, handle, offset, false, &mut |dirent, _| {
+            add_entry(dirent)
+        })
+    }
+
+    fn readdirplus(
+        &self,
+        ctx: &Context,
+        inode: u64,
+        handle: u64,
+        _size: u32,
+        offset: u64,
+        add_entry: &mut dyn FnMut(DirEntry, Entry) -> io::Result<usize>,
+    ) -> io::Result<()> {
+        self.do_readdir(ctx, inode, handle, offset, true, &mut |dirent, entry| {
+            add_entry(dirent, entry.unwrap_or_default())
+        })
+    }
+
+    fn fsyncdir(&self, _ctx: &Context, inode: u64, _datasync: bool, handle: u64) -> io::Result<()> {
+        self.lock().handle(inode, handle).map(|_| ())
+    }
+
+    fn releasedir(&self, _ctx: &Context, inode: u64, _flags: u32, handle: u64) -> io::Result<()> {
+        self.lock().close_handle(inode, handle)
+    }
+
+    fn access(&self, ctx: &Context, inode: u64, mask: u32) -> io::Result<()> {
+        check_access(&self.lock().get(inode)?.attr, ctx, mask)
+    }
+
+    fn lseek(
+        &self,
+        _ctx: &Context,
+        inode: u64,
+        handle: u64,
+        offset: u64,
+        whence: u32,
+    ) -> io::Result<u64> {
+        let state = self.lock();
+        state.handle(inode, handle)?;
+        let len = state.get(inode)?.attr.st_size as u64;
+        // Files have no holes, but the virtual one at their end.
+        match whence as i32 {
+            libc::SEEK_DATA if offset < len => Ok(offset),
+            libc::SEEK_HOLE if offset < len => Ok(len),
+            libc::SEEK_DATA | libc::SEEK_HOLE => Err(errno(libc::ENXIO)),
+            _ => Err(errno(libc::EINVAL)),
+        }
+    }
+
+    fn copyfilerange(
+        &self,
+        _ctx: &Context,
+        inode_in: u64,
+        handle_in: u64,
+        offset_in: u64,
+        inode_out: u64,
+        handle_out: u64,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> io::Result<usize> {
+        if flags != 0 {
+            return Err(errno(libc::EINVAL));
+        }
+        let mut state = self.lock();
+        if state.handle(inode_in, handle_in)?.flags as i32 & libc::O_ACCMODE == libc::O_WRONLY
+            || state.handle(inode_out, handle_out)?.flags as i32 & libc::O_ACCMODE == libc::O_RDONLY
+        {
+            return Err(ebadf());
+        }
+        let data = match &state.get(inode_in)?.node {
+            Node::File(data) => {
+                let start = std::cmp::min(offset_in, data.len() as u64) as usize;
+                let end = std::cmp::min(offset_in.saturating_add(len), data.len() as u64);
+                data[start..end as usize].to_vec()
+            }
+            Node::Dir(_) => return Err(errno(libc::EISDIR)),
+            _ => return Err(errno(libc::EINVAL)),
+        };
+        let end = offset_out
+            .checked_add(data.len() as u64)
+            .filter(|end| *end <= MEMFS_MAX_FILE_SIZE)
+            .ok_or_else(|| errno(libc::EFBIG))?;
+
+        let now = self.clock.now();
+        let node = state.get_mut(inode_out)?;
+        match &node.node {
+            Node::File(out) if out.len() < end as usize => node.set_size(end)?,
+            Node::File(_) => {}
+            Node::Dir(_) => return Err(errno(libc::EISDIR)),
+            _ => return Err(errno(libc::EINVAL)),
+        }
+        if let Node::File(out) = &mut node.node {
+            out[offset_out as usize..end as usize].copy_from_slice(&data);
+        }
+        touch(&mut node.attr, MTIME | CTIME, now);
+        Ok(data.len())
+    }
+
+    fn debug_nlookup(&self, inode: u64) -> Option<u64> {
+        self.lock().inodes.get(&inode).map(|i| i.nlookup)
+    }
+}
+
+// Vfs backends must also be asynchronous file systems with `async-io`, which MemFs isn't.
+#[cfg(not(feature = "async-io"))]
+impl BackendFileSystem for MemFs {
+    fn mount(&self) -> io::Result<(Entry, u64)> {
+        let mut state = self.lock();
+        Ok((self.entry(&mut state, ROOT_ID)?, VFS_MAX_INO))
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+// A node to preload, at a path relative to the root.
+enum Preload {
+    Dir(String, u32),
+    File(String, u32, Vec<u8>),
+    Symlink(String, Vec<u8>),
+    Xattr(String, Vec<u8>, Vec<u8>),
+}
+
+/// Builder of a [MemFs] preloaded with a tree.
+///
+/// Paths are relative to the root of the file system, a leading `/` is ignored. Missing parent
+/// directories are created with mode 0755. Nodes are created in the order they're added, which
+/// determines their inode numbers, and belong to root.
+#[derive(Default)]
+pub struct MemFsBuilder {
+    nodes: Vec<Preload>,
+    clock: Option<Arc<dyn Clock>>,
+    timeout: Option<Duration>,
+}
+
+impl MemFsBuilder {
+    /// Preload the directory `path` with mode `mode`.
+    pub fn with_dir(mut self, path: &str, mode: u32) -> Self {
+        self.nodes.push(Preload::Dir(path.to_string(), mode));
+        self
+    }
+
+    /// Preload the regular file `path` with mode `mode` and content `data`.
+    pub fn with_file(mut self, path: &str, mode: u32, data: &[u8]) -> Self {
+        self.nodes
+            .push(Preload::File(path.to_string(), mode, data.to_vec()));
+        self
+    }
+
+    /// Preload the symbolic link `path` to `target`.
+    pub fn with_symlink(mut self, path: &str, target: &str) -> Self {
+        self.nodes.push(Preload::Symlink(
+            path.to_string(),
+            target.as_bytes().to_vec(),
+        ));
+        self
+    }
+
+    /// Set the extended attribute `name` of the node `path`, preloaded before.
+    pub fn with_xattr(mut self, path: &str, name: &str, value: &[u8]) -> Self {
+        self.nodes.push(Preload::Xattr(
+            path.to_string(),
+            name.as_bytes().to_vec(),
+            value.to_vec(),
+        ));
+        self
+    }
+
+    /// Take timestamps from `clock`.
+    ///
+    /// The default value for this option is the [SystemClock].
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Set the attribute and entry timeouts of replies to `timeout`.
+    ///
+    /// The default value for this option is 1 second.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Build the file system, failing like the equivalent requests if a node can't be created.
+    pub fn build(self) -> io::Result<MemFs> {
+        let mut fs = MemFs::with_clock(
+            self.clock
+                .unwrap_or_else(|| Arc::new(SystemClock::default())),
+        );
+        if let Some(timeout) = self.timeout {
+            fs.attr_timeout = timeout;
+            fs.entry_timeout = timeout;
+        }
+
+        let ctx = Context::default();
+        for node in self.nodes {
+            match node {
+                Preload::Dir(path, mode) => {
+                    let (parent, name) = fs.preload_parent(&path)?;
+                    let node = Node::Dir(BTreeMap::new());
+                    let mode = libc::S_IFDIR as u32 | (mode & 0o7777);
+                    fs.add_node(&mut fs.lock(), &ctx, parent, &name, mode, node)?;
+                }
+                Preload::File(path, mode, data) => {
+                    let (parent, name) = fs.preload_parent(&path)?;
+                    let size = data.len() as u64;
+                    let mode = libc::S_IFREG as u32 | (mode & 0o7777);
+                    let mut state = fs.lock();
+                    let ino =
+                        fs.add_node(&mut state, &ctx, parent, &name, mode, Node::File(data))?;
+                    let inode = state.get_mut(ino)?;
+                    inode.set_size(size)?;
+                }
+                Preload::Symlink(path, target) => {
+                    let (parent, name) = fs.preload_parent(&path)?;
+                    let mode = libc::S_IFLNK as u32 | 0o777;
+                    let node = Node::Symlink(target);
+                    fs.add_node(&mut fs.lock(), &ctx, parent, &name, mode, node)?;
+                }
+                Preload::Xattr(path, name, value) => {
+                    let ino = fs.preload_walk(Path::new(&path), false)?;
+                    fs.lock().get_mut(ino)?.xattrs.insert(name, value);
+                }
+            }
+        }
+
+        Ok(fs)
+    }
+}
+
+impl MemFs {
+    // Walk `path` from the root, creating missing directories if `create`.
+    fn preload_walk(&self, path: &Path, create: bool) -> io::Result<u64> {
+        let ctx = Context::default();
+        let mut ino = ROOT_ID;
+        for c in path.components() {
+            let name = match c {
+                Component::Normal(name) => name.to_str().ok_or_else(|| errno(libc::EINVAL))?,
+                Component::RootDir | Component::CurDir => continue,
+                _ => return Err(errno(libc::EINVAL)),
+            };
+            let mut state = self.lock();
+            ino = match state.child(ino, name.as_bytes())? {
+                Some(child) => child,
+                None if create => {
+                    let mode = libc::S_IFDIR as u32 | 0o755;
+                    let node = Node::Dir(BTreeMap::new());
+                    self.add_node(&mut state, &ctx, ino, name.as_bytes(), mode, node)?
+                }
+                None => return Err(enoent()),
+            };
+        }
+        Ok(ino)
+    }
+
+    // Get the parent directory of `path`, created if missing, and the name of its last component.
+    fn preload_parent(&self, path: &str) -> io::Result<(u64, Vec<u8>)> {
+        let path = Path::new(path);
+        let name = path.file_name().ok_or_else(|| errno(libc::EINVAL))?;
+        let parent = self.preload_walk(path.parent().unwrap_or_else(|| Path::new("")), true)?;
+        Ok((
+            parent,
+            name.to_str().unwrap_or_default().as_bytes().to_vec(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::clock::ManualClock;
+    use crate::api::errno::errno_of;
+    use std::ffi::CString;
+
+    fn name(n: &str) -> CString {
+        CString::new(n).unwrap()
+    }
+
+    fn err<T>(res: io::Result<T>) -> libc::c_int {
+        match res {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => errno_of(&e).unwrap(),
+        }
+    }
+
+    fn read_all(fs: &MemFs, ino: u64, handle: u64) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut w = VecWriter::new(&mut data);
+        fs.read(
+            &Context::default(),
+            ino,
+            handle,
+            &mut w,
+            1 << 16,
+            0,
+            None,
+            0,
+        )
+        .unwrap();
+        data
+    }
+
+    fn dir_names(fs: &MemFs, ino: u64) -> Vec<String> {
+        let ctx = Context::default();
+        let (handle, _) = fs.opendir(&ctx, ino, 0).unwrap();
+        let mut names = Vec::new();
+        fs.readdir(&ctx, ino, handle.unwrap(), 4096, 0, &mut |d| {
+            names.push(String::from_utf8(d.name.to_vec()).unwrap());
+            Ok(1)
+        })
+        .unwrap();
+        fs.releasedir(&ctx, ino, 0, handle.unwrap()).unwrap();
+        names
+    }
+
+    #[test]
+    fn test_mem_fs_builder() {
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1000)));
+        let fs = MemFs::builder()
+            .with_dir("/a/b", 0o700)
+            .with_file("a/b/f", 0o600, b"hello")
+            .with_symlink("/l", "a/b/f")
+            .with_xattr("/a/b/f", "user.k", b"v")
+            .with_clock(clock)
+            .with_timeout(Duration::from_secs(5))
+            .build()
+            .unwrap();
+        let ctx = Context::default();
+
+        // Inode numbers follow the order of creation, "a" being created for "a/b".
+        let a = fs.lookup(&ctx, ROOT_ID, &name("a")).unwrap();
+        assert_eq!(a.inode, 2);
+        assert_eq!(a.attr.st_mode as u32, libc::S_IFDIR as u32 | 0o755);
+        assert_eq!(a.attr.st_nlink, 3);
+        assert_eq!(a.attr_timeout, Duration::from_secs(5));
+        let b = fs.lookup(&ctx, a.inode, &name("b")).unwrap();
+        assert_eq!(b.inode, 3);
+        let f = fs.lookup(&ctx, b.inode, &name("f")).unwrap();
+        assert_eq!(f.inode, 4);
+        assert_eq!(f.attr.st_size, 5);
+        assert_eq!(f.attr.st_mtime, 1000);
+        let l = fs.lookup(&ctx, ROOT_ID, &name("l")).unwrap();
+        assert_eq!(fs.readlink(&ctx, l.inode).unwrap(), b"a/b/f");
+        match fs.getxattr(&ctx, f.inode, &name("user.k"), 8).unwrap() {
+            GetxattrReply::Value(v) => assert_eq!(v, b"v"),
+            _ => panic!("unexpected getxattr reply"),
+        }
+        assert_eq!(
+            fs.lookup(&ctx, b.inode, &name("..")).unwrap().inode,
+            a.inode
+        );
+
+        let (h, _) = fs.open(&ctx, f.inode, libc::O_RDONLY as u32, 0).unwrap();
+        assert_eq!(read_all(&fs, f.inode, h.unwrap()), b"hello");
+        assert_eq!(dir_names(&fs, ROOT_ID), vec![".", "..", "a", "l"]);
+
+        // Conflicting nodes fail the build.
+        let res = MemFs::builder()
+            .with_file("/f", 0o644, b"")
+            .with_dir("/f/d", 0o755)
+            .build();
+        assert_eq!(err(res), libc::ENOTDIR);
+        let res = MemFs::builder()
+            .with_xattr("/missing", "user.k", b"")
+            .build();
+        assert_eq!(err(res), libc::ENOENT);
+    }
+
+    #[test]
+    fn test_mem_fs_namespace() {
+        let fs = MemFs::new();
+        let ctx = Context::default();
+
+        let d = fs.mkdir(&ctx, ROOT_ID, &name("d"), 0o755, 0o022).unwrap();
+        assert_eq!(
+            err(fs.mkdir(&ctx, ROOT_ID, &name("d"), 0o755, 0)),
+            libc::EEXIST
+        );
+        let args = CreateIn {
+            flags: (libc::O_RDWR | libc::O_CREAT | libc::O_EXCL) as u32,
+            mode: 0o666,
+            umask: 0o022,
+            fuse_flags: 0,
+        };
+        let (f, h, _) = fs.create(&ctx, d.inode, &name("f"), args).unwrap();
+        assert_eq!(f.attr.st_mode as u32, libc::S_IFREG as u32 | 0o644);
+        assert_eq!(
+            err(fs.create(&ctx, d.inode, &name("f"), args)),
+            libc::EEXIST
+        );
+        fs.release(&ctx, f.inode, 0, h.unwrap(), false, false, None)
+            .unwrap();
+
+        // Removing directories.
+        assert_eq!(err(fs.rmdir(&ctx, ROOT_ID, &name("d"))), libc::ENOTEMPTY);
+        assert_eq!(err(fs.rmdir(&ctx, d.inode, &name("f"))), libc::ENOTDIR);
+        assert_eq!(err(fs.unlink(&ctx, ROOT_ID, &name("d"))), libc::EISDIR);
+        assert_eq!(err(fs.unlink(&ctx, ROOT_ID, &name("x"))), libc::ENOENT);
+
+        // Renaming.
+        let e = fs.mkdir(&ctx, d.inode, &name("e"), 0o755, 0).unwrap();
+        assert_eq!(
+            err(fs.rename(&ctx, ROOT_ID, &name("d"), e.inode, &name("d"), 0)),
+            libc::EINVAL
+        );
+        assert_eq!(
+            err(fs.rename(&ctx, d.inode, &name("f"), d.inode, &name("e"), 0)),
+            libc::EISDIR
+        );
+        assert_eq!(
+            err(fs.rename(&ctx, d.inode, &name("e"), d.inode, &name("f"), 0)),
+            libc::ENOTDIR
+        );
+        let flags = libc::RENAME_NOREPLACE;
+        assert_eq!(
+            err(fs.rename(&ctx, d.inode, &name("f"), d.inode, &name("e"), flags)),
+            libc::EEXIST
+        );
+        fs.rename(&ctx, d.inode, &name("e"), ROOT_ID, &name("e"), 0)
+            .unwrap();
+        assert_eq!(fs.getattr(&ctx, d.inode, None).unwrap().0.st_nlink, 2);
+        assert_eq!(fs.getattr(&ctx, ROOT_ID, None).unwrap().0.st_nlink, 4);
+        fs.rename(
+            &ctx,
+            ROOT_ID,
+            &name("e"),
+            d.inode,
+            &name("f"),
+            libc::RENAME_EXCHANGE,
+        )
+        .unwrap();
+        assert_eq!(dir_names(&fs, d.inode), vec![".", "..", "f"]);
+        assert_eq!(dir_names(&fs, ROOT_ID), vec![".", "..", "d", "e"]);
+        assert_eq!(fs.lookup(&ctx, d.inode, &name("f")).unwrap().inode, e.inode);
+
+        // Hard links.
+        let f = fs.lookup(&ctx, ROOT_ID, &name("e")).unwrap();
+        let l = fs.link(&ctx, f.inode, d.inode, &name("g")).unwrap();
+        assert_eq!(l.attr.st_nlink, 2);
+        assert_eq!(
+            err(fs.link(&ctx, d.inode, ROOT_ID, &name("h"))),
+            libc::EPERM
+        );
+        fs.unlink(&ctx, ROOT_ID, &name("e")).unwrap();
+        assert_eq!(fs.getattr(&ctx, f.inode, None).unwrap().0.st_nlink, 1);
+
+        // Special files.
+        let mode = libc::S_IFIFO as u32 | 0o644;
+        let p = fs.mknod(&ctx, ROOT_ID, &name("p"), mode, 0, 0).unwrap();
+        assert_eq!(p.attr.st_mode as u32, mode);
+        let mode = libc::S_IFDIR as u32 | 0o755;
+        assert_eq!(
+            err(fs.mknod(&ctx, ROOT_ID, &name("q"), mode, 0, 0)),
+            libc::EPERM
+        );
+    }
+
+    #[test]
+    fn test_mem_fs_nlookup() {
+        let fs = MemFs::builder()
+            .with_file("/f", 0o644, b"data")
+            .build()
+            .unwrap();
+        let ctx = Context::default();
+
+        let f = fs.lookup(&ctx, ROOT_ID, &name("f")).unwrap();
+        fs.lookup(&ctx, ROOT_ID, &name("f")).unwrap();
+        assert_eq!(fs.debug_nlookup(f.inode), Some(2));
+        let (h, _) = fs.open(&ctx, f.inode, libc::O_RDONLY as u32, 0).unwrap();
+
+        // Unlinked inodes live until forgotten and released.
+        fs.unlink(&ctx, ROOT_ID, &name("f")).unwrap();
+        assert_eq!(fs.getattr(&ctx, f.inode, None).unwrap().0.st_nlink, 0);
+        fs.forget(&ctx, f.inode, 1);
+        assert_eq!(fs.debug_nlookup(f.inode), Some(1));
+        fs.forget(&ctx, f.inode, 1);
+        assert_eq!(fs.debug_nlookup(f.inode), Some(0));
+        assert_eq!(read_all(&fs, f.inode, h.unwrap()), b"data");
+        fs.release(&ctx, f.inode, 0, h.unwrap(), false, false, None)
+            .unwrap();
+        assert_eq!(fs.debug_nlookup(f.inode), None);
+        assert_eq!(err(fs.getattr(&ctx, f.inode, None)), libc::EBADF);
+        assert_eq!(fs.inode_count(), 1);
+        assert_eq!(fs.handle_count(), 0);
+
+        // Readdirplus looks up entries added to the reply, but "." and "..".
+        let fs = MemFs::builder()
+            .with_file("/a", 0o644, b"")
+            .with_file("/b", 0o644, b"")
+            .build()
+            .unwrap();
+        let (h, _) = fs.opendir(&ctx, ROOT_ID, 0).unwrap();
+        let mut entries = Vec::new();
+        fs.readdirplus(&ctx, ROOT_ID, h.unwrap(), 4096, 0, &mut |d, e| {
+            if d.name == b"b" {
+                return Ok(0);
+            }
+            entries.push((d.offset, e.inode));
+            Ok(1)
+        })
+        .unwrap();
+        assert_eq!(entries, vec![(1, 0), (2, 0), (3, 2)]);
+        assert_eq!(fs.debug_nlookup(2), Some(1));
+        assert_eq!(fs.debug_nlookup(3), Some(0));
+    }
+
+    #[test]
+    fn test_mem_fs_data() {
+        let fs = MemFs::new();
+        let ctx = Context::default();
+        let args = CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        let (f, h, _) = fs.create(&ctx, ROOT_ID, &name("f"), args).unwrap();
+        let h = h.unwrap();
+
+        let mut r = SliceReader::new(b"hello world");
+        assert_eq!(
+            fs.write(&ctx, f.inode, h, &mut r, 11, 4, None, false, 0, 0)
+                .unwrap(),
+            11
+        );
+        assert_eq!(read_all(&fs, f.inode, h), b"\0\0\0\0hello world");
+        assert_eq!(fs.getattr(&ctx, f.inode, None).unwrap().0.st_size, 15);
+
+        // Truncating.
+        let mut attr = f.attr;
+        attr.st_size = 9;
+        fs.setattr(&ctx, f.inode, attr, None, SetattrValid::SIZE)
+            .unwrap();
+        assert_eq!(read_all(&fs, f.inode, h), b"\0\0\0\0hello");
+        let (h2, _) = fs
+            .open(&ctx, f.inode, (libc::O_WRONLY | libc::O_TRUNC) as u32, 0)
+            .unwrap();
+        assert!(read_all(&fs, f.inode, h).is_empty());
+        let mut data = Vec::new();
+        let res = fs.read(
+            &ctx,
+            f.inode,
+            h2.unwrap(),
+            &mut VecWriter::new(&mut data),
+            8,
+            0,
+            None,
+            0,
+        );
+        assert_eq!(err(res), libc::EBADF);
+
+        // Appending.
+        let (h3, _) = fs
+            .open(&ctx, f.inode, (libc::O_WRONLY | libc::O_APPEND) as u32, 0)
+            .unwrap();
+        for chunk in [&b"ab"[..], b"cd"] {
+            let mut r = SliceReader::new(chunk);
+            fs.write(&ctx, f.inode, h3.unwrap(), &mut r, 2, 0, None, false, 0, 0)
+                .unwrap();
+        }
+        assert_eq!(read_all(&fs, f.inode, h), b"abcd");
+        assert_eq!(
+            fs.lseek(&ctx, f.inode, h, 1, libc::SEEK_HOLE as u32)
+                .unwrap(),
+            4
+        );
+        assert_eq!(
+            err(fs.lseek(&ctx, f.inode, h, 4, libc::SEEK_DATA as u32)),
+            libc::ENXIO
+        );
+
+        // Extended attributes.
+        let k = name("user.k");
+        fs.setxattr(&ctx, f.inode, &k, b"v1", 0).unwrap();
+        assert_eq!(
+            err(fs.setxattr(&ctx, f.inode, &k, b"v2", libc::XATTR_CREATE as u32)),
+            libc::EEXIST
+        );
+        assert_eq!(err(fs.getxattr(&ctx, f.inode, &k, 1)), libc::ERANGE);
+        match fs.listxattr(&ctx, f.inode, 64).unwrap() {
+            ListxattrReply::Names(names) => assert_eq!(names, b"user.k\0"),
+            _ => panic!("unexpected listxattr reply"),
+        }
+        fs.removexattr(&ctx, f.inode, &k).unwrap();
+        assert_eq!(err(fs.removexattr(&ctx, f.inode, &k)), libc::ENODATA);
+        assert_eq!(
+            err(fs.setxattr(&ctx, f.inode, &k, b"v", libc::XATTR_REPLACE as u32)),
+            libc::ENODATA
+        );
+    }
+
+    #[test]
+    fn test_mem_fs_permissions() {
+        let fs = MemFs::builder()
+            .with_dir("/ro", 0o555)
+            .with_file("/secret", 0o600, b"s")
+            .build()
+            .unwrap();
+        let user = Context {
+            uid: 1000,
+            gid: 1000,
+            ..Default::default()
+        };
+        let ro = fs.lookup(&user, ROOT_ID, &name("ro")).unwrap();
+        let secret = fs.lookup(&user, ROOT_ID, &name("secret")).unwrap();
+
+        assert_eq!(
+            err(fs.mkdir(&user, ro.inode, &name("d"), 0o755, 0)),
+            libc::EACCES
+        );
+        assert_eq!(
+            err(fs.open(&user, secret.inode, libc::O_RDONLY as u32, 0)),
+            libc::EACCES
+        );
+        assert_eq!(
+            err(fs.access(&user, secret.inode, libc::R_OK as u32)),
+            libc::EACCES
+        );
+        fs.access(&user, ro.inode, (libc::R_OK | libc::X_OK) as u32)
+            .unwrap();
+        let mut attr = secret.attr;
+        attr.st_mode = 0o644;
+        assert_eq!(
+            err(fs.setattr(&user, secret.inode, attr, None, SetattrValid::MODE)),
+            libc::EPERM
+        );
+
+        // Root bypasses permission checks.
+        let root = Context::default();
+        fs.mkdir(&root, ro.inode, &name("d"), 0o755, 0).unwrap();
+        let mut attr = secret.attr;
+        attr.st_uid = 1000;
+        fs.setattr(&root, secret.inode, attr, None, SetattrValid::UID)
+            .unwrap();
+        fs.open(&user, secret.inode, libc::O_RDWR as u32, 0)
+            .unwrap();
+    }
+
+    #[cfg(not(feature = "async-io"))]
+    #[test]
+    fn test_mem_fs_vfs_mount() {
+        use crate::api::{Vfs, VfsOptions};
+
+        let vfs = Vfs::new(VfsOptions {
+            no_open: false,
+            ..Default::default()
+        });
+        let fs = MemFs::builder()
+            .with_file("/f", 0o644, b"mounted")
+            .build()
+            .unwrap();
+        vfs.mount(Box::new(fs), "/mnt").unwrap();
+
+        let ctx = Context::default();
+        let mnt = vfs.lookup(&ctx, ROOT_ID.into(), &name("mnt")).unwrap();
+        let f = vfs.lookup(&ctx, mnt.inode.into(), &name("f")).unwrap();
+        assert_eq!(f.attr.st_size, 7);
+        let (h, _) = vfs
+            .open(&ctx, f.inode.into(), libc::O_RDONLY as u32, 0)
+            .unwrap();
+        let mut data = Vec::new();
+        vfs.read(
+            &ctx,
+            f.inode.into(),
+            h.unwrap(),
+            &mut VecWriter::new(&mut data),
+            64,
+            0,
+            None,
+            0,
+        )
+        .unwrap();
+        assert_eq!(data, b"mounted");
+        assert_eq!(
+            err(vfs.unlink(&ctx, mnt.inode.into(), &name("missing"))),
+            libc::ENOENT
+        );
+    }
+}
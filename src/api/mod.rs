@@ -11,14 +11,50 @@
 //!   implement fs operations.
 //! - [struct Vfs](vfs/struct.Vfs.html), a simple union file system to help organize multiple
 //!   backend file systems.
+//! - [struct AttrCache](attr_cache/struct.AttrCache.html) to help network backed file systems
+//!   cache attributes and directory entries.
+//! - [struct CachedFs](cached_fs/struct.CachedFs.html) to cache lookups and attributes of read
+//!   mostly backend file systems.
+//! - [trait Clock](clock/trait.Clock.html) as the time source of caches, timeouts and backoff.
+//! - [trait LoadShedder](shedder/trait.LoadShedder.html) to pause or abort long running
+//!   operations between chunks.
+//! - [struct MemFs](mem_fs/struct.MemFs.html), an in-memory file system for tests, with the
+//!   `test-utils` feature.
 
 mod pseudo_fs;
 
+pub mod accounting;
+pub use accounting::{WriteAccounting, WriteStats};
+
+pub mod attr_cache;
+pub use attr_cache::AttrCache;
+
+pub mod cached_fs;
+pub use cached_fs::{CachedFs, CachedFsConfig, CachedFsStats};
+
+pub mod clock;
+pub use clock::{Clock, ManualClock, SystemClock};
+
+#[cfg(feature = "test-utils")]
+pub mod mem_fs;
+#[cfg(feature = "test-utils")]
+pub use mem_fs::{MemFs, MemFsBuilder};
+
+pub mod shedder;
+pub use shedder::{DrainShedder, LoadShedder, ShedDecision, YieldChunks, YieldPoints};
+
 pub mod vfs;
 pub use vfs::{
-    validate_path_component, BackFileSystem, BackendFileSystem, Vfs, VfsIndex, VfsOptions,
-    CURRENT_DIR_CSTR, EMPTY_CSTR, PARENT_DIR_CSTR, PROC_SELF_FD_CSTR, SLASH_ASCII, VFS_MAX_INO,
+    validate_path_component, BackFileSystem, BackendFileSystem, BackendTimeouts, IdlePolicy,
+    MountOptions, ReadonlyPolicy, RootStatfs, Vfs, VfsIndex, VfsOptions, CURRENT_DIR_CSTR,
+    EMPTY_CSTR, PARENT_DIR_CSTR, PROC_SELF_FD_CSTR, SLASH_ASCII, VFS_MAX_INO,
 };
 
+pub mod errno;
+#[cfg(feature = "async-io")]
+pub mod executor;
 pub mod filesystem;
+#[cfg(feature = "persist")]
+pub(crate) mod persist;
+pub mod scratch;
 pub mod server;
@@ -0,0 +1,158 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Binary encoding of states saved across live upgrade.
+//!
+//! States start with a magic number and a format version, followed by little endian integers and
+//! length prefixed byte strings. A format version is bumped whenever the layout changes, and
+//! states of unknown formats are refused instead of being misinterpreted.
+
+use std::io;
+
+use crate::api::errno::fuse_errno;
+
+// Encode a state.
+pub(crate) struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub(crate) fn new(magic: u32, format: u32) -> Self {
+        let mut writer = StateWriter { buf: Vec::new() };
+        writer.put_u32(magic);
+        writer.put_u32(format);
+        writer
+    }
+
+    pub(crate) fn put_u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub(crate) fn put_u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub(crate) fn put_bytes(&mut self, v: &[u8]) {
+        self.put_u32(v.len() as u32);
+        self.buf.extend_from_slice(v);
+    }
+
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+// Decode a state encoded by `StateWriter`, `what` names the state in error messages.
+pub(crate) struct StateReader<'a> {
+    buf: &'a [u8],
+    what: &'static str,
+}
+
+impl<'a> StateReader<'a> {
+    pub(crate) fn new(
+        buf: &'a [u8],
+        magic: u32,
+        format: u32,
+        what: &'static str,
+    ) -> io::Result<Self> {
+        let mut reader = StateReader { buf, what };
+        if reader.u32()? != magic {
+            return Err(fuse_errno(libc::EINVAL, format!("invalid {} state", what)));
+        }
+        let version = reader.u32()?;
+        if version != format {
+            return Err(fuse_errno(
+                libc::EINVAL,
+                format!("unsupported format {} of {} state", version, what),
+            ));
+        }
+        Ok(reader)
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(fuse_errno(
+                libc::EINVAL,
+                format!("truncated {} state", self.what),
+            ));
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    pub(crate) fn u32(&mut self) -> io::Result<u32> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub(crate) fn u64(&mut self) -> io::Result<u64> {
+        let b = self.take(8)?;
+        let mut v = [0u8; 8];
+        v.copy_from_slice(b);
+        Ok(u64::from_le_bytes(v))
+    }
+
+    pub(crate) fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    pub(crate) fn string(&mut self) -> io::Result<String> {
+        let b = self.bytes()?;
+        String::from_utf8(b.to_vec()).map_err(|_| {
+            fuse_errno(
+                libc::EINVAL,
+                format!("invalid string in {} state", self.what),
+            )
+        })
+    }
+
+    // Check that the whole state has been decoded.
+    pub(crate) fn finish(self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            return Err(fuse_errno(
+                libc::EINVAL,
+                format!("trailing bytes in {} state", self.what),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::errno::errno_of;
+
+    #[test]
+    fn test_state_encoding() {
+        let mut w = StateWriter::new(0x1234, 2);
+        w.put_u32(7);
+        w.put_u64(u64::MAX - 1);
+        w.put_bytes(b"/a/b");
+        let buf = w.finish();
+
+        let mut r = StateReader::new(&buf, 0x1234, 2, "test").unwrap();
+        assert_eq!(r.u32().unwrap(), 7);
+        assert_eq!(r.u64().unwrap(), u64::MAX - 1);
+        assert_eq!(r.string().unwrap(), "/a/b");
+        r.finish().unwrap();
+
+        let errno = |res: io::Result<()>| errno_of(&res.unwrap_err());
+        assert_eq!(
+            errno(StateReader::new(&buf, 0x1235, 2, "test").map(|_| ())),
+            Some(libc::EINVAL)
+        );
+        assert_eq!(
+            errno(StateReader::new(&buf, 0x1234, 1, "test").map(|_| ())),
+            Some(libc::EINVAL)
+        );
+        let mut r = StateReader::new(&buf[..buf.len() - 1], 0x1234, 2, "test").unwrap();
+        r.u32().unwrap();
+        r.u64().unwrap();
+        assert_eq!(errno(r.bytes().map(|_| ())), Some(libc::EINVAL));
+        let r = StateReader::new(&buf, 0x1234, 2, "test").unwrap();
+        assert_eq!(errno(r.finish()), Some(libc::EINVAL));
+    }
+}
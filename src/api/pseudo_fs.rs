@@ -8,6 +8,14 @@
 //! - There won't be too much directories/sub-directories managed by a PseudoFs instance, so linear
 //!   search is used when searching for child inodes.
 //! - Inodes managed by the PseudoFs is readonly, even for the permission bits.
+//! - Inode numbers are allocated sequentially by default. With
+//!   [PseudoFs::with_path_inodes], they're derived from the path of the directory instead, so
+//!   they don't depend on the order directories are created in: the 64-bit FNV-1a hash of the
+//!   absolute path, like "/a/b", masked by `VFS_MAX_INO`, and incremented until it's neither 0,
+//!   `ROOT_ID` nor the number of another inode.
+//! - Directories are kept after backends mounted on them are umounted, until they're removed by
+//!   [PseudoFs::evict]. Lookups of the guest are counted, so directories still known to the guest
+//!   are never removed.
 
 use arc_swap::ArcSwap;
 use std::collections::HashMap;
@@ -21,11 +29,14 @@ use std::time::{Duration, SystemTime};
 
 use crate::abi::fuse_abi::{stat64, Attr};
 use crate::api::filesystem::*;
+use crate::api::VFS_MAX_INO;
 
 // ID 0 is reserved for invalid entry, and ID 1 is used for ROOT_ID.
 const PSEUDOFS_NEXT_INODE: u64 = 2;
 const PSEUDOFS_DEFAULT_ATTR_TIMEOUT: u64 = 1 << 32;
 const PSEUDOFS_DEFAULT_ENTRY_TIMEOUT: u64 = PSEUDOFS_DEFAULT_ATTR_TIMEOUT;
+// Lookup count of evicted inodes, so that lookups racing with the eviction fail.
+const PSEUDOFS_EVICTED: u64 = u64::MAX;
 
 type Inode = u64;
 type Handle = u64;
@@ -35,6 +46,7 @@ struct PseudoInode {
     parent: u64,
     children: ArcSwap<Vec<Arc<PseudoInode>>>,
     name: String,
+    lookups: AtomicU64,
 }
 
 impl PseudoInode {
@@ -44,9 +56,35 @@ impl PseudoInode {
             parent,
             children: ArcSwap::new(Arc::new(Vec::new())),
             name,
+            lookups: AtomicU64::new(0),
         }
     }
 
+    // Take a lookup reference for the guest, fails if the inode has been evicted.
+    fn get(&self) -> bool {
+        self.lookups
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n != PSEUDOFS_EVICTED).then(|| n + 1)
+            })
+            .is_ok()
+    }
+
+    fn put(&self, count: u64) {
+        let _ = self
+            .lookups
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n != PSEUDOFS_EVICTED).then(|| n.saturating_sub(count))
+            });
+    }
+
+    // Mark the inode evicted if the guest has no reference to it. It's protected by
+    // Pseudofs.lock.
+    fn try_evict(&self) -> bool {
+        self.lookups
+            .compare_exchange(0, PSEUDOFS_EVICTED, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+
     // It's protected by Pseudofs.lock.
     fn insert_child(&self, child: Arc<PseudoInode>) {
         let mut children = self.children.load().deref().deref().clone();
@@ -71,6 +109,7 @@ impl PseudoInode {
 
 pub struct PseudoFs {
     next_inode: AtomicU64,
+    path_inodes: bool,
     root_inode: Arc<PseudoInode>,
     inodes: ArcSwap<HashMap<u64, Arc<PseudoInode>>>,
     lock: Mutex<()>, // Write protect PseudoFs.inodes and PseudoInode.children
@@ -81,6 +120,7 @@ impl PseudoFs {
         let root_inode = Arc::new(PseudoInode::new(ROOT_ID, ROOT_ID, String::from("/")));
         let fs = PseudoFs {
             next_inode: AtomicU64::new(PSEUDOFS_NEXT_INODE),
+            path_inodes: false,
             root_inode: root_inode.clone(),
             inodes: ArcSwap::new(Arc::new(HashMap::new())),
             lock: Mutex::new(()),
@@ -94,6 +134,13 @@ impl PseudoFs {
         fs
     }
 
+    /// Derive inode numbers from the paths of directories, instead of allocating them
+    /// sequentially, for inode numbers not depending on the order of mounts.
+    pub fn with_path_inodes(mut self) -> Self {
+        self.path_inodes = true;
+        self
+    }
+
     // mount creates path walk nodes all the way from root
     // to @path, and returns pseudo fs inode number for the path
     pub fn mount(&self, mountpoint: &str) -> Result<u64> {
@@ -103,6 +150,8 @@ impl PseudoFs {
             return Err(Error::from_raw_os_error(libc::EINVAL));
         }
 
+        // Hold the writer lock for the whole walk, so directories can't be evicted under it.
+        let _guard = self.lock.lock().unwrap();
         let mut inodes = self.inodes.load();
         let mut inode = &self.root_inode;
 
@@ -119,16 +168,6 @@ impl PseudoFs {
                 Component::Normal(path) => {
                     let name = path.to_str().unwrap();
 
-                    // Optimistic check without lock.
-                    for child in inode.children.load().iter() {
-                        if child.name == name {
-                            inode = inodes.get(&child.ino).unwrap();
-                            continue 'outer;
-                        }
-                    }
-
-                    // Double check with writer lock held.
-                    let _guard = self.lock.lock();
                     for child in inode.children.load().iter() {
                         if child.name == name {
                             inode = inodes.get(&child.ino).unwrap();
@@ -202,12 +241,72 @@ impl PseudoFs {
         Ok(Some(inode.ino))
     }
 
+    // Get all inodes but the root as `(ino, parent, name)`, ordered by inode number so parents
+    // come before their children.
+    #[cfg(feature = "persist")]
+    pub fn saved_inodes(&self) -> Vec<(u64, u64, String)> {
+        let mut inodes: Vec<_> = self
+            .inodes
+            .load()
+            .values()
+            .filter(|inode| inode.ino != ROOT_ID)
+            .map(|inode| (inode.ino, inode.parent, inode.name.clone()))
+            .collect();
+        inodes.sort_unstable();
+        inodes
+    }
+
+    // Recreate an inode saved by `saved_inodes()`, with the same inode number.
+    #[cfg(feature = "persist")]
+    pub fn restore_inode(&self, ino: u64, parent: u64, name: &str) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        let inodes = self.inodes.load();
+        let parent = match inodes.get(&parent) {
+            Some(parent) if !inodes.contains_key(&ino) && ino != 0 => parent,
+            _ => return Err(Error::from_raw_os_error(libc::EINVAL)),
+        };
+        if parent.children.load().iter().any(|c| c.name == name) {
+            return Err(Error::from_raw_os_error(libc::EEXIST));
+        }
+
+        let inode = Arc::new(PseudoInode::new(ino, parent.ino, name.to_owned()));
+        self.insert_inode(inode.clone());
+        parent.insert_child(inode);
+        self.next_inode.fetch_max(ino + 1, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    // Caller must hold PseudoFs.lock when deriving inode numbers from paths.
     fn new_inode(&self, parent: u64, name: &str) -> Arc<PseudoInode> {
-        let ino = self.next_inode.fetch_add(1, Ordering::Relaxed);
+        let ino = if self.path_inodes {
+            self.path_inode(parent, name)
+        } else {
+            self.next_inode.fetch_add(1, Ordering::Relaxed)
+        };
 
         Arc::new(PseudoInode::new(ino, parent, name.to_owned()))
     }
 
+    // Derive the inode number of the child `name` of `parent` from its path.
+    fn path_inode(&self, parent: u64, name: &str) -> u64 {
+        let inodes = self.inodes.load();
+        let mut names = vec![name.to_owned()];
+        let mut ino = parent;
+        while ino != ROOT_ID {
+            let inode = inodes.get(&ino).unwrap();
+            names.push(inode.name.clone());
+            ino = inode.parent;
+        }
+        let path: String = names.iter().rev().map(|n| format!("/{}", n)).collect();
+
+        let mut ino = fnv1a(path.as_bytes()) & VFS_MAX_INO;
+        while ino < PSEUDOFS_NEXT_INODE || inodes.contains_key(&ino) {
+            ino = ino.wrapping_add(1) & VFS_MAX_INO;
+        }
+        ino
+    }
+
     // Caller must hold PseudoFs.lock.
     fn insert_inode(&self, inode: Arc<PseudoInode>) {
         let mut hashmap = self.inodes.load().deref().deref().clone();
@@ -253,6 +352,79 @@ impl PseudoFs {
         self.remove_inode(inode);
     }
 
+    /// Remove the directory at `path`, then its ancestors until one isn't empty, returning the
+    /// number of directories removed.
+    ///
+    /// Directories with a lookup reference of the guest, and directories for which
+    /// `is_mountpoint` returns true, are kept. Fails with `ENOTEMPTY` or `EBUSY` if the directory
+    /// at `path` can't be removed, and with `ENOENT` if there's none. Lookups racing with the
+    /// eviction either take a reference before it, which keeps the directory, or fail.
+    pub fn evict<F: Fn(u64) -> bool>(&self, path: &str, is_mountpoint: F) -> Result<usize> {
+        let path = Path::new(path);
+        if !path.has_root() {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let _guard = self.lock.lock().unwrap();
+        let mut inodes = self.inodes.load().deref().deref().clone();
+        let mut inode = self.root_inode.clone();
+        for component in path.components() {
+            inode = match component {
+                Component::RootDir | Component::CurDir => continue,
+                Component::ParentDir => inodes.get(&inode.parent).unwrap().clone(),
+                Component::Normal(name) => inode
+                    .children
+                    .load()
+                    .iter()
+                    .find(|child| Some(child.name.as_str()) == name.to_str())
+                    .cloned()
+                    .ok_or_else(|| Error::from_raw_os_error(libc::ENOENT))?,
+                Component::Prefix(_) => return Err(Error::from_raw_os_error(libc::EINVAL)),
+            };
+        }
+
+        let mut evicted = 0;
+        while inode.ino != ROOT_ID {
+            if !inode.children.load().is_empty() {
+                break;
+            }
+            if is_mountpoint(inode.ino) || !inode.try_evict() {
+                break;
+            }
+            let parent = inodes.get(&inode.parent).unwrap().clone();
+            parent.remove_child(inode.clone());
+            inodes.remove(&inode.ino);
+            evicted += 1;
+            inode = parent;
+        }
+
+        match evicted {
+            0 if inode.ino != ROOT_ID && !inode.children.load().is_empty() => {
+                Err(Error::from_raw_os_error(libc::ENOTEMPTY))
+            }
+            0 => Err(Error::from_raw_os_error(libc::EBUSY)),
+            n => {
+                self.inodes.store(Arc::new(inodes));
+                Ok(n)
+            }
+        }
+    }
+
+    /// Get all directories as `(path, inode)`, ordered by path.
+    pub fn tree(&self) -> Vec<(String, u64)> {
+        let mut tree = vec![(String::from("/"), ROOT_ID)];
+        let mut dirs = vec![(String::new(), self.root_inode.clone())];
+        while let Some((path, inode)) = dirs.pop() {
+            for child in inode.children.load().iter() {
+                let path = format!("{}/{}", path, child.name);
+                tree.push((path.clone(), child.ino));
+                dirs.push((path, child.clone()));
+            }
+        }
+        tree.sort_unstable();
+        tree
+    }
+
     fn get_entry(&self, ino: u64) -> Entry {
         let mut attr = Attr {
             ..Default::default()
@@ -328,25 +500,36 @@ impl FileSystem for PseudoFs {
         let child_name = name
             .to_str()
             .map_err(|_| Error::from_raw_os_error(libc::EINVAL))?;
-        let mut ino: u64 = 0;
-        if child_name == "." {
-            ino = pinode.ino;
+        let inode = if child_name == "." {
+            Some(pinode.clone())
         } else if child_name == ".." {
-            ino = pinode.parent;
+            inodes.get(&pinode.parent).cloned()
         } else {
-            for child in pinode.children.load().iter() {
-                if child.name == child_name {
-                    ino = child.ino;
-                    break;
-                }
-            }
-        }
+            pinode
+                .children
+                .load()
+                .iter()
+                .find(|child| child.name == child_name)
+                .cloned()
+        };
 
-        if ino == 0 {
+        match inode {
+            // The inode may have been evicted since it was found.
+            Some(inode) if inode.get() => Ok(self.get_entry(inode.ino)),
             // not found
-            Err(Error::from_raw_os_error(libc::ENOENT))
-        } else {
-            Ok(self.get_entry(ino))
+            _ => Err(Error::from_raw_os_error(libc::ENOENT)),
+        }
+    }
+
+    fn forget(&self, _: &Context, inode: u64, count: u64) {
+        if let Some(inode) = self.inodes.load().get(&inode) {
+            inode.put(count);
+        }
+    }
+
+    fn forget_all(&self) {
+        for inode in self.inodes.load().values() {
+            inode.put(u64::MAX);
         }
     }
 
@@ -383,9 +566,20 @@ impl FileSystem for PseudoFs {
         offset: u64,
         add_entry: &mut dyn FnMut(DirEntry, Entry) -> Result<usize>,
     ) -> Result<()> {
+        let inodes = self.inodes.load();
         self.do_readdir(inode, size, offset, &mut |dir_entry| {
+            let child = match inodes.get(&dir_entry.ino) {
+                Some(child) if child.get() => child,
+                // Skip directories evicted since the listing was loaded.
+                _ => return Ok(1),
+            };
             let entry = self.get_entry(dir_entry.ino);
-            add_entry(dir_entry, entry)
+            let res = add_entry(dir_entry, entry);
+            // The entry isn't returned to the guest, so drop its reference.
+            if !matches!(res, Ok(n) if n > 0) {
+                child.put(1);
+            }
+            res
         })
     }
 
@@ -394,6 +588,13 @@ impl FileSystem for PseudoFs {
     }
 }
 
+// 64-bit FNV-1a hash of `bytes`.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -413,6 +614,30 @@ mod tests {
         assert_eq!(fs.inodes.load().len(), 1);
     }
 
+    #[test]
+    fn test_pseudofs_path_inodes() {
+        let fs = PseudoFs::new().with_path_inodes();
+        let other = PseudoFs::new().with_path_inodes();
+        let b = fs.mount("/a/b").unwrap();
+        let c = fs.mount("/c").unwrap();
+        assert_eq!(other.mount("/c").unwrap(), c);
+        assert_eq!(other.mount("/a/b").unwrap(), b);
+        assert_eq!(fs.path_walk("/a").unwrap(), other.path_walk("/a").unwrap());
+        assert_eq!(c, fnv1a(b"/c") & VFS_MAX_INO);
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+
+        // Collisions are resolved by probing the next numbers.
+        let _guard = fs.lock.lock().unwrap();
+        fs.insert_inode(Arc::new(PseudoInode::new(
+            fnv1a(b"/d") & VFS_MAX_INO,
+            ROOT_ID,
+            String::from("x"),
+        )));
+        let d = fs.new_inode(ROOT_ID, "d");
+        assert_eq!(d.ino, (fnv1a(b"/d") + 1) & VFS_MAX_INO);
+    }
+
     #[test]
     fn test_pseudofs_mount() {
         let fs = PseudoFs::new();
@@ -513,6 +738,69 @@ mod tests {
         fs.evict_inode(a1);
     }
 
+    #[test]
+    fn test_pseudofs_evict() {
+        let fs = PseudoFs::new();
+        let ctx = create_fuse_context();
+        let a = fs.mount("/a").unwrap();
+        let b = fs.mount("/a/b").unwrap();
+        let c = fs.mount("/a/b/c").unwrap();
+        let d = fs.mount("/a/d").unwrap();
+        let err = |res: Result<usize>| res.unwrap_err().raw_os_error().unwrap();
+
+        assert_eq!(err(fs.evict("a", |_| false)), libc::EINVAL);
+        assert_eq!(err(fs.evict("/a/x", |_| false)), libc::ENOENT);
+        assert_eq!(err(fs.evict("/", |_| false)), libc::EBUSY);
+        assert_eq!(err(fs.evict("/a/b", |_| false)), libc::ENOTEMPTY);
+        assert_eq!(err(fs.evict("/a/b/c", |ino| ino == c)), libc::EBUSY);
+
+        // Directories referenced by the guest are kept.
+        let entry = fs.lookup(&ctx, b, &CString::new("c").unwrap()).unwrap();
+        assert_eq!(entry.inode, c);
+        assert_eq!(err(fs.evict("/a/b/c", |_| false)), libc::EBUSY);
+        fs.forget(&ctx, c, 1);
+
+        // Eviction stops at the first directory which isn't empty.
+        assert_eq!(fs.evict("/a/b/c", |_| false).unwrap(), 2);
+        assert_eq!(fs.path_walk("/a/b").unwrap(), None);
+        assert!(fs.inodes.load().get(&c).is_none());
+        assert!(fs.inodes.load().get(&b).is_none());
+        assert!(fs.lookup(&ctx, a, &CString::new("b").unwrap()).is_err());
+
+        // Lookups racing with the eviction fail.
+        let inode = fs.inodes.load().get(&d).unwrap().clone();
+        assert_eq!(fs.evict("/a/d", |ino| ino == a).unwrap(), 1);
+        assert!(!inode.get());
+        assert_eq!(fs.path_walk("/a").unwrap(), Some(a));
+
+        // Directories may be created again.
+        let b2 = fs.mount("/a/b").unwrap();
+        assert_ne!(b2, b);
+        fs.readdirplus(&ctx, a, 0, 1, 0, &mut |_, _| Ok(1)).unwrap();
+        assert_eq!(err(fs.evict("/a/b", |_| false)), libc::EBUSY);
+        fs.forget_all();
+        assert_eq!(fs.evict("/a/b", |_| false).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_pseudofs_tree() {
+        let fs = PseudoFs::new();
+        assert_eq!(fs.tree(), vec![(String::from("/"), ROOT_ID)]);
+
+        let b = fs.mount("/a/b").unwrap();
+        let a = fs.path_walk("/a").unwrap().unwrap();
+        let c = fs.mount("/c").unwrap();
+        assert_eq!(
+            fs.tree(),
+            vec![
+                (String::from("/"), ROOT_ID),
+                (String::from("/a"), a),
+                (String::from("/a/b"), b),
+                (String::from("/c"), c),
+            ]
+        );
+    }
+
     #[test]
     fn test_pseudofs_getattr() {
         let fs = PseudoFs::new();
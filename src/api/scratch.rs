@@ -0,0 +1,223 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Thread local scratch buffers for variable sized replies.
+//!
+//! Replies to getxattr, listxattr and readlink carry a `Vec<u8>` whose size is only known after
+//! the syscall. Instead of allocating a fresh buffer for each request, file systems may [take]
+//! the scratch buffer of the current thread, and the [Server](super::server::Server) [recycle]s
+//! the buffer once the reply has been sent. So these requests don't allocate at steady state.
+//! Sizes of xattr buffers are bounded by [XattrLimits](super::server::XattrLimits), so hostile
+//! guests can't grow scratch buffers beyond the limits.
+
+use std::cell::RefCell;
+use std::cmp;
+use std::io;
+
+/// Initial size of scratch buffers.
+pub const SCRATCH_INITIAL_SIZE: usize = 4096;
+
+/// Scratch buffers with larger capacity are released instead of being recycled.
+pub const SCRATCH_MAX_RETAINED: usize = 0x10000;
+
+// Maximum number of retries on ERANGE, for data growing again each time it's probed.
+const MAX_ERANGE_RETRIES: usize = 8;
+
+thread_local! {
+    static SCRATCH: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Take the scratch buffer of the current thread, which is empty and has at least `size` bytes
+/// of capacity.
+pub fn take(size: usize) -> Vec<u8> {
+    let mut buf = SCRATCH.with(|s| std::mem::take(&mut *s.borrow_mut()));
+    buf.clear();
+    buf.reserve(size);
+    buf
+}
+
+/// Give a buffer back to the current thread for reuse.
+///
+/// The buffer with larger capacity is kept, unless it's larger than [SCRATCH_MAX_RETAINED].
+pub fn recycle(buf: Vec<u8>) {
+    if buf.capacity() > SCRATCH_MAX_RETAINED {
+        return;
+    }
+    SCRATCH.with(|s| {
+        let mut s = s.borrow_mut();
+        if buf.capacity() > s.capacity() {
+            *s = buf;
+        }
+    });
+}
+
+/// Fill a scratch buffer of at most `size` bytes by `op`, probing the size of the data and
+/// retrying when `op` fails with `ERANGE`.
+///
+/// `op` receives a buffer pointer and its length, and returns the number of bytes filled or a
+/// negative value with `errno` set, like the getxattr(2) family, which returns the size of the
+/// data for a null buffer of zero bytes. Buffers are grown to the probed size, and the data is
+/// probed again if it has grown before being filled. `ERANGE` is returned if the data doesn't fit
+/// in `size` bytes.
+pub fn fill<F>(size: usize, mut op: F) -> io::Result<Vec<u8>>
+where
+    F: FnMut(*mut u8, usize) -> isize,
+{
+    let mut buf = take(cmp::min(size, SCRATCH_INITIAL_SIZE));
+    for _ in 0..=MAX_ERANGE_RETRIES {
+        let len = cmp::min(buf.capacity(), size);
+        let res = op(buf.as_mut_ptr(), len);
+        if res >= 0 {
+            // Safe because `op` has filled `res` bytes, which is no more than the capacity.
+            unsafe { buf.set_len(cmp::min(res as usize, len)) };
+            return Ok(buf);
+        }
+
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::ERANGE) || len >= size {
+            recycle(buf);
+            return Err(e);
+        }
+        let probed = op(std::ptr::null_mut(), 0);
+        if probed < 0 {
+            let e = io::Error::last_os_error();
+            recycle(buf);
+            return Err(e);
+        }
+        if probed as usize > size {
+            recycle(buf);
+            return Err(io::Error::from_raw_os_error(libc::ERANGE));
+        }
+        buf.reserve_exact(probed as usize);
+    }
+
+    recycle(buf);
+    Err(io::Error::from_raw_os_error(libc::ERANGE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    fn set_errno(errno: i32) {
+        // Safe because we only set errno of the current thread.
+        unsafe { *libc::__errno_location() = errno };
+    }
+
+    #[test]
+    fn test_scratch_take_recycle() {
+        let mut buf = take(100);
+        assert!(buf.capacity() >= 100);
+        buf.extend_from_slice(b"data");
+        let ptr = buf.as_ptr();
+        recycle(buf);
+
+        let buf = take(10);
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr(), ptr);
+        // Smaller buffers don't replace the scratch.
+        recycle(Vec::with_capacity(1));
+        recycle(buf);
+        let buf = take(0);
+        assert_eq!(buf.as_ptr(), ptr);
+
+        // Buffers beyond the high-water limit are released.
+        let big = take(SCRATCH_MAX_RETAINED + 1);
+        recycle(big);
+        assert!(take(0).capacity() <= SCRATCH_MAX_RETAINED);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_scratch_fill() {
+        let value = vec![0x5au8; SCRATCH_INITIAL_SIZE * 3];
+        let mut calls = Vec::new();
+        let fill_value = |ptr: *mut u8, len: usize, calls: &mut Vec<usize>| -> isize {
+            calls.push(len);
+            if len == 0 {
+                return value.len() as isize;
+            }
+            if len < value.len() {
+                set_errno(libc::ERANGE);
+                return -1;
+            }
+            // Safe because `ptr` is valid for `len` bytes.
+            unsafe { std::ptr::copy_nonoverlapping(value.as_ptr(), ptr, value.len()) };
+            value.len() as isize
+        };
+
+        let buf = fill(0x10000, |p, l| fill_value(p, l, &mut calls)).unwrap();
+        assert_eq!(buf, value);
+        // The buffer is grown to the probed size at once.
+        assert_eq!(calls, vec![SCRATCH_INITIAL_SIZE, 0, value.len()]);
+        recycle(buf);
+
+        // The grown scratch is reused.
+        calls.clear();
+        let buf = fill(0x10000, |p, l| fill_value(p, l, &mut calls)).unwrap();
+        assert_eq!(buf, value);
+        assert_eq!(calls.len(), 1);
+        recycle(buf);
+
+        // The value doesn't fit in the requested size.
+        let e = fill(value.len() - 1, |p, l| fill_value(p, l, &mut calls)).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ERANGE));
+
+        // Other errors are returned directly.
+        calls.clear();
+        let e = fill(0x10000, |_, l| {
+            calls.push(l);
+            set_errno(libc::ENODATA);
+            -1
+        })
+        .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENODATA));
+        assert_eq!(calls.len(), 1);
+
+        // Data growing between the probe and the fetch is probed again.
+        calls.clear();
+        let mut grown = value.len() + 1;
+        let buf = fill(0x10000, |p, l| {
+            calls.push(l);
+            if l == 0 {
+                let probed = grown;
+                if calls.len() == 2 {
+                    grown += 1;
+                }
+                return probed as isize;
+            }
+            if l < grown {
+                set_errno(libc::ERANGE);
+                return -1;
+            }
+            // Safe because `p` is valid for `l` bytes.
+            unsafe { std::ptr::write_bytes(p, 0x5a, grown) };
+            grown as isize
+        })
+        .unwrap();
+        assert_eq!(buf.len(), value.len() + 2);
+        assert_eq!(
+            calls,
+            vec![value.len(), 0, value.len() + 1, 0, value.len() + 2]
+        );
+        recycle(buf);
+
+        // The retry loop is bounded.
+        calls.clear();
+        let e = fill(usize::MAX, |_, l| {
+            calls.push(l);
+            if l == 0 {
+                return calls.len() as isize * SCRATCH_INITIAL_SIZE as isize;
+            }
+            set_errno(libc::ERANGE);
+            -1
+        })
+        .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ERANGE));
+        assert_eq!(
+            calls.iter().filter(|l| **l != 0).count(),
+            MAX_ERANGE_RETRIES + 1
+        );
+    }
+}
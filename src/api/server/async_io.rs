@@ -4,26 +4,28 @@
 use std::io;
 use std::mem::size_of;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 use vm_memory::ByteValued;
 
 use crate::abi::fuse_abi::{
-    stat64, AttrOut, CreateIn, EntryOut, FallocateIn, FsyncIn, GetattrIn, Opcode, OpenIn, OpenOut,
-    OutHeader, ReadIn, SetattrIn, SetattrValid, WriteIn, WriteOut, FATTR_FH, GETATTR_FH,
-    KERNEL_MINOR_VERSION_LOOKUP_NEGATIVE_ENTRY_ZERO, READ_LOCKOWNER, WRITE_CACHE, WRITE_LOCKOWNER,
+    stat64, AttrOut, CreateIn, FallocateIn, FlushIn, FsyncIn, GetattrIn, InHeader, Opcode, OpenIn,
+    OpenOut, OutHeader, ReadIn, ReleaseIn, SetattrIn, SetattrValid, SyncfsIn, WriteIn, WriteOut,
+    FATTR_FH, GETATTR_FH, READ_LOCKOWNER, WRITE_CACHE, WRITE_LOCKOWNER,
 };
+use crate::api::errno::errno_of;
+use crate::api::executor::Executor;
 use crate::api::filesystem::{
-    AsyncFileSystem, AsyncZeroCopyReader, AsyncZeroCopyWriter, ZeroCopyReader, ZeroCopyWriter,
-};
-use crate::api::server::{
-    MetricsHook, Server, ServerUtil, SrvContext, BUFFER_HEADER_SIZE, MAX_BUFFER_SIZE,
+    AsyncFileSystem, AsyncZeroCopyReader, AsyncZeroCopyWriter, Entry, FileSystem, ZeroCopyReader,
+    ZeroCopyWriter,
 };
+use crate::api::server::compat::{self, ProtocolFeature};
+use crate::api::server::{Access, MetricsHook, Server, ServerUtil, SrvContext, MAX_BUFFER_SIZE};
 use crate::transport::{
     AsyncFileReadWriteVolatile, FileReadWriteVolatile, FsCacheReqHandler, Reader, Writer,
 };
-use crate::{bytes_to_cstr, encode_io_error_kind, BitmapSlice, Error, Result};
+use crate::{encode_io_error_kind, BitmapSlice, Error, Result};
 
 struct AsyncZcReader<'a, S: BitmapSlice = ()>(Reader<'a, S>);
 
@@ -101,6 +103,43 @@ impl<'a, S: BitmapSlice> io::Write for AsyncZcWriter<'a, S> {
     }
 }
 
+// Interval to poll the number of inflight requests when draining asynchronously.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+impl<F: FileSystem + Sync> Server<F> {
+    /// Set the executor to spawn tasks, run blocking calls and wait for timers of the
+    /// asynchronous request path, [TokioUringExecutor](crate::api::executor::TokioUringExecutor)
+    /// by default.
+    pub fn with_executor(mut self, executor: Arc<dyn Executor>) -> Self {
+        self.executor = executor;
+        self
+    }
+
+    /// Get the executor of the asynchronous request path.
+    pub fn executor(&self) -> &Arc<dyn Executor> {
+        &self.executor
+    }
+
+    /// Wait for requests being handled by the server to complete without blocking the thread,
+    /// return false on timeout.
+    ///
+    /// It's the asynchronous version of [Server::wait_drained], waiting by timers of the executor.
+    pub async fn async_wait_drained(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.inflight.count() > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            self.executor
+                .sleep(DRAIN_POLL_INTERVAL.min(deadline - now))
+                .await;
+        }
+
+        true
+    }
+}
+
 impl<F: AsyncFileSystem + Sync> Server<F> {
     /// Main entrance to handle requests from the transport layer.
     ///
@@ -113,31 +152,89 @@ impl<F: AsyncFileSystem + Sync> Server<F> {
     /// must ensure all data buffers managed by the `Reader` and `Writer` are valid until the
     /// `Future` object returned has completed. Other subsystems, such as the transport layer, rely
     /// on the invariant.
-    #[allow(unused_variables)]
+    #[allow(unused_variables, unused_mut)]
     pub async unsafe fn async_handle_message<S: BitmapSlice>(
         &self,
         mut r: Reader<'_, S>,
         w: Writer<'_, S>,
-        vu_req: Option<&mut dyn FsCacheReqHandler>,
+        mut vu_req: Option<&mut dyn FsCacheReqHandler>,
         hook: Option<&dyn MetricsHook>,
     ) -> Result<usize> {
-        let in_header = r.read_obj().map_err(Error::DecodeMessage)?;
-        let mut ctx = SrvContext::<F, S>::new(in_header, r, w);
-        if ctx.in_header.len > (MAX_BUFFER_SIZE + BUFFER_HEADER_SIZE)
+        let _inflight = self.inflight.enter();
+        let in_header: InHeader = r.read_obj().map_err(Error::DecodeMessage)?;
+        #[cfg(feature = "tracing")]
+        {
+            use tracing::Instrument;
+
+            let span = super::span::request_span(&in_header);
+            self.async_handle_request(in_header, r, w, vu_req, hook)
+                .instrument(span)
+                .await
+        }
+        #[cfg(not(feature = "tracing"))]
+        self.async_handle_request(in_header, r, w, vu_req, hook)
+            .await
+    }
+
+    // Handle the request with header `in_header`, under the contract of `async_handle_message()`.
+    #[allow(unused_variables, unused_mut)]
+    async fn async_handle_request<S: BitmapSlice>(
+        &self,
+        in_header: InHeader,
+        r: Reader<'_, S>,
+        w: Writer<'_, S>,
+        mut vu_req: Option<&mut dyn FsCacheReqHandler>,
+        hook: Option<&dyn MetricsHook>,
+    ) -> Result<usize> {
+        let metrics = self.metrics_guard(hook, &in_header);
+        let _interruptible = self.interruptible(&in_header);
+        let _watched = self.watch(&in_header);
+        let mut sample = self.sample(&in_header);
+        let mut ctx = SrvContext::<F, S>::new(in_header, r, w)
+            .with_timer(sample.as_ref().map(|s| s.timer()))
+            .with_reply_recorder(metrics.as_ref().map(|m| m.recorder()))
+            .with_minor(self.vers.load().minor);
+        #[cfg(feature = "tracing")]
+        {
+            ctx.context.span = super::span::span_id(&tracing::Span::current());
+        }
+        if self.oversized(&ctx.in_header, &ctx.r)
             || ctx.w.available_bytes() < size_of::<OutHeader>()
         {
             return ctx
                 .async_do_reply_error(io::Error::from_raw_os_error(libc::ENOMEM), true)
                 .await;
         }
-        let in_header = &ctx.in_header;
+        if Self::malformed(&ctx.in_header, &ctx.r) || Self::truncated(&ctx.in_header, &ctx.r) {
+            warn!("fuse: malformed request {:?}", ctx.in_header);
+            if Self::no_reply(ctx.in_header.opcode) {
+                return Err(Error::InvalidHeaderLength);
+            }
+            return ctx
+                .async_do_reply_error(io::Error::from_raw_os_error(libc::EINVAL), true)
+                .await;
+        }
+        let in_header = &in_header;
+        self.start_deadline(&mut ctx);
+        let deadline = ctx.context.deadline;
 
         trace!(
             "fuse: new req {:?}: {:?}",
             Opcode::from(in_header.opcode),
             in_header
         );
-        hook.map_or((), |h| h.collect(in_header));
+
+        if let Some(throttle) = self.throttle.as_ref() {
+            throttle.async_throttle(in_header.opcode, &ctx.r).await;
+        }
+
+        let _permit = match self.limits.as_ref() {
+            Some(limits) => limits.async_acquire(in_header.opcode).await,
+            None => None,
+        };
+        if let Some(e) = self.deadline_expired(in_header.opcode, deadline) {
+            return ctx.async_reply_error(e).await;
+        }
 
         let res = match in_header.opcode {
             x if x == Opcode::Lookup as u32 => self.async_lookup(ctx).await,
@@ -156,13 +253,13 @@ impl<F: AsyncFileSystem + Sync> Server<F> {
             x if x == Opcode::Read as u32 => self.async_read(ctx).await,
             x if x == Opcode::Write as u32 => self.async_write(ctx).await,
             x if x == Opcode::Statfs as u32 => self.statfs(ctx),
-            x if x == Opcode::Release as u32 => self.release(ctx),
+            x if x == Opcode::Release as u32 => self.async_release(ctx).await,
             x if x == Opcode::Fsync as u32 => self.async_fsync(ctx).await,
             x if x == Opcode::Setxattr as u32 => self.setxattr(ctx),
             x if x == Opcode::Getxattr as u32 => self.getxattr(ctx),
             x if x == Opcode::Listxattr as u32 => self.listxattr(ctx),
             x if x == Opcode::Removexattr as u32 => self.removexattr(ctx),
-            x if x == Opcode::Flush as u32 => self.flush(ctx),
+            x if x == Opcode::Flush as u32 => self.async_flush(ctx).await,
             x if x == Opcode::Init as u32 => self.init(ctx),
             x if x == Opcode::Opendir as u32 => self.opendir(ctx),
             x if x == Opcode::Readdir as u32 => self.readdir(ctx),
@@ -182,18 +279,23 @@ impl<F: AsyncFileSystem + Sync> Server<F> {
             x if x == Opcode::Readdirplus as u32 => self.readdirplus(ctx),
             x if x == Opcode::Rename2 as u32 => self.rename2(ctx),
             x if x == Opcode::Lseek as u32 => self.lseek(ctx),
+            x if x == Opcode::CopyFileRange as u32 => self.copyfilerange(ctx),
+            x if x == Opcode::Syncfs as u32 => self.async_syncfs(ctx).await,
+            x if x == Opcode::Tmpfile as u32 => self.tmpfile(ctx),
+            x if x == Opcode::Statx as u32 => self.statx(ctx),
+            x if x == Opcode::AtomicOpen as u32 => self.atomic_open(ctx),
             #[cfg(feature = "virtiofs")]
-            x if x == Opcode::SetupMapping as u32 => self.setupmapping(ctx, vu_req),
+            x if x == Opcode::SetupMapping as u32 => self.setupmapping(ctx, vu_req.as_deref_mut()),
             #[cfg(feature = "virtiofs")]
-            x if x == Opcode::RemoveMapping as u32 => self.removemapping(ctx, vu_req),
+            x if x == Opcode::RemoveMapping as u32 => {
+                self.removemapping(ctx, vu_req.as_deref_mut())
+            }
             // Group reqeusts don't need reply together
             x => match x {
-                x if x == Opcode::Interrupt as u32 => {
-                    self.interrupt(ctx);
-                    Ok(0)
-                }
+                x if x == Opcode::Interrupt as u32 => self.interrupt(ctx),
                 x if x == Opcode::Destroy as u32 => {
-                    self.destroy(ctx);
+                    // Racing requests may run on this executor, so don't block waiting for them.
+                    self.destroy(ctx, Duration::from_secs(0));
                     Ok(0)
                 }
                 _ => {
@@ -203,35 +305,42 @@ impl<F: AsyncFileSystem + Sync> Server<F> {
             },
         };
 
-        // Pass `None` because current API handler's design does not allow us to catch
-        // the `out_header`. Hopefully, we can reach to `out_header` after some
-        // refactoring work someday.
-        hook.map_or((), |h| h.release(None));
+        #[cfg(feature = "virtiofs")]
+        if let Some(req) = vu_req {
+            let _ = self.unmap_reclaimed(req);
+        }
+
+        self.deadline_missed(in_header.opcode, deadline);
+        if let Some(sample) = sample.as_mut() {
+            sample.finish(&res);
+        }
 
         res
     }
 
     async fn async_lookup<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
         let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, 0)?;
-        let name = bytes_to_cstr(buf.as_ref())?;
-        let version = self.vers.load();
-        let result = self
-            .fs
-            .async_lookup(ctx.context(), ctx.nodeid(), name)
-            .await;
+        let name = match ServerUtil::extract_name(&buf, self.dot_lookups) {
+            Ok(name) => name,
+            Err(e) => return ctx.async_reply_error(e).await,
+        };
+        let fut =
+            self.async_retry_transient(|| self.fs.async_lookup(ctx.context(), ctx.nodeid(), name));
+        let result = self.async_deadline(ctx.context.deadline, fut).await;
 
         match result {
             // before ABI 7.4 inode == 0 was invalid, only ENOENT means negative dentry
             Ok(entry)
-                if version.minor < KERNEL_MINOR_VERSION_LOOKUP_NEGATIVE_ENTRY_ZERO
-                    && entry.inode == 0 =>
+                if !ProtocolFeature::NegativeEntry.supported_by(ctx.minor) && entry.inode == 0 =>
             {
                 ctx.async_reply_error(io::Error::from_raw_os_error(libc::ENOENT))
                     .await
             }
             Ok(entry) => {
-                let out = EntryOut::from(entry);
-                ctx.async_reply_ok(Some(out), None).await
+                self.audit_lookup(entry.inode);
+                #[cfg(feature = "virtiofs")]
+                self.dax_lookup(entry.inode);
+                ctx.async_reply_entry(entry, None).await
             }
             Err(e) => ctx.async_reply_error(e).await,
         }
@@ -239,15 +348,17 @@ impl<F: AsyncFileSystem + Sync> Server<F> {
 
     async fn async_getattr<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
         let GetattrIn { flags, fh, .. } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
-        let handle = if (flags & GETATTR_FH) != 0 {
-            Some(fh.into())
-        } else {
-            None
+        // Handles aren't `Copy`, convert the raw one for every attempt.
+        let handle = || {
+            if (flags & GETATTR_FH) != 0 {
+                Some(fh.into())
+            } else {
+                None
+            }
         };
-        let result = self
-            .fs
-            .async_getattr(ctx.context(), ctx.nodeid(), handle)
-            .await;
+        let fut = self
+            .async_retry_transient(|| self.fs.async_getattr(ctx.context(), ctx.nodeid(), handle()));
+        let result = self.async_deadline(ctx.context.deadline, fut).await;
 
         ctx.async_handle_attr_result(result).await
     }
@@ -262,8 +373,11 @@ impl<F: AsyncFileSystem + Sync> Server<F> {
         let valid = SetattrValid::from_bits_truncate(setattr_in.valid);
         let st: stat64 = setattr_in.into();
         let result = self
-            .fs
-            .async_setattr(ctx.context(), ctx.nodeid(), st, handle, valid)
+            .async_deadline(
+                ctx.context.deadline,
+                self.fs
+                    .async_setattr(ctx.context(), ctx.nodeid(), st, handle, valid),
+            )
             .await;
 
         ctx.async_handle_attr_result(result).await
@@ -272,15 +386,22 @@ impl<F: AsyncFileSystem + Sync> Server<F> {
     async fn async_open<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
         let OpenIn { flags, fuse_flags } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
         let result = self
-            .fs
-            .async_open(ctx.context(), ctx.nodeid(), flags, fuse_flags)
+            .async_deadline(
+                ctx.context.deadline,
+                self.fs
+                    .async_open(ctx.context(), ctx.nodeid(), flags, fuse_flags),
+            )
             .await;
 
         match result {
             Ok((handle, opts)) => {
+                let fh: Option<u64> = handle.map(Into::into);
+                self.access_open(ctx.in_header.nodeid, fh, flags);
+                #[cfg(feature = "virtiofs")]
+                self.dax_open(ctx.in_header.nodeid);
                 let out = OpenOut {
-                    fh: handle.map(Into::into).unwrap_or(0),
-                    open_flags: opts.bits(),
+                    fh: fh.unwrap_or(0),
+                    open_flags: compat::open_flags(opts, ctx.minor),
                     ..Default::default()
                 };
 
@@ -306,6 +427,9 @@ impl<F: AsyncFileSystem + Sync> Server<F> {
                 .async_reply_error_explicit(io::Error::from_raw_os_error(libc::ENOMEM))
                 .await;
         }
+        if let Err(e) = self.access_check(ctx.in_header.nodeid, fh, Access::Read) {
+            return ctx.async_reply_error_explicit(e).await;
+        }
 
         let owner = if read_flags & READ_LOCKOWNER != 0 {
             Some(lock_owner)
@@ -320,21 +444,24 @@ impl<F: AsyncFileSystem + Sync> Server<F> {
         };
         let mut data_writer = AsyncZcWriter(w2);
         let result = self
-            .fs
-            .async_read(
-                ctx.context(),
-                ctx.nodeid(),
-                fh.into(),
-                &mut data_writer,
-                size,
-                offset,
-                owner,
-                flags,
+            .async_deadline(
+                ctx.context.deadline,
+                self.fs.async_read(
+                    ctx.context(),
+                    ctx.nodeid(),
+                    fh.into(),
+                    &mut data_writer,
+                    size,
+                    offset,
+                    owner,
+                    flags,
+                ),
             )
             .await;
 
         match result {
             Ok(count) => {
+                ctx.mark_replying();
                 // Don't use `reply_ok` because we need to set a custom size length for the
                 // header.
                 let out = OutHeader {
@@ -342,6 +469,7 @@ impl<F: AsyncFileSystem + Sync> Server<F> {
                     error: 0,
                     unique: ctx.unique(),
                 };
+                ctx.record_reply(&out);
 
                 ctx.w
                     .async_write_all(out.as_slice())
@@ -373,27 +501,40 @@ impl<F: AsyncFileSystem + Sync> Server<F> {
                 .async_reply_error_explicit(io::Error::from_raw_os_error(libc::ENOMEM))
                 .await;
         }
+        if let Err(e) = self.access_check(ctx.in_header.nodeid, fh, Access::Write) {
+            return ctx.async_reply_error_explicit(e).await;
+        }
+
+        let _write = self
+            .writes
+            .as_ref()
+            .map(|w| w.enter(ctx.in_header.nodeid, fh));
 
         let owner = if fuse_flags & WRITE_LOCKOWNER != 0 {
             Some(lock_owner)
         } else {
             None
         };
+        if let Some(acct) = self.write_accounting.as_ref() {
+            acct.add_guest(size as u64);
+        }
         let delayed_write = fuse_flags & WRITE_CACHE != 0;
         let mut data_reader = AsyncZcReader(ctx.take_reader());
         let result = self
-            .fs
-            .async_write(
-                ctx.context(),
-                ctx.nodeid(),
-                fh.into(),
-                &mut data_reader,
-                size,
-                offset,
-                owner,
-                delayed_write,
-                flags,
-                fuse_flags,
+            .async_deadline(
+                ctx.context.deadline,
+                self.fs.async_write(
+                    ctx.context(),
+                    ctx.nodeid(),
+                    fh.into(),
+                    &mut data_reader,
+                    size,
+                    offset,
+                    owner,
+                    delayed_write,
+                    flags,
+                    fuse_flags,
+                ),
             )
             .await;
 
@@ -414,10 +555,14 @@ impl<F: AsyncFileSystem + Sync> Server<F> {
             fh, fsync_flags, ..
         } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
         let datasync = fsync_flags & 0x1 != 0;
+        self.write_barrier(ctx.in_header.nodeid, fh).await;
 
         match self
-            .fs
-            .async_fsync(ctx.context(), ctx.nodeid(), datasync, fh.into())
+            .async_deadline(
+                ctx.context.deadline,
+                self.fs
+                    .async_fsync(ctx.context(), ctx.nodeid(), datasync, fh.into()),
+            )
             .await
         {
             Ok(()) => ctx.async_reply_ok(None::<u8>, None).await,
@@ -425,14 +570,52 @@ impl<F: AsyncFileSystem + Sync> Server<F> {
         }
     }
 
+    async fn async_flush<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
+        let arg: FlushIn = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        self.write_barrier(ctx.in_header.nodeid, arg.fh).await;
+        self.do_flush(ctx, arg)
+    }
+
+    async fn async_release<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
+        let arg: ReleaseIn = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        self.write_barrier(ctx.in_header.nodeid, arg.fh).await;
+        self.do_release(ctx, arg)
+    }
+
+    // Wait for writes to handle `fh` of `nodeid` received before to complete, if enabled by
+    // `with_write_barrier()`.
+    async fn write_barrier(&self, nodeid: u64, fh: u64) {
+        if let Some(writes) = self.writes.as_ref() {
+            writes.barrier(nodeid, fh).await;
+        }
+    }
+
+    async fn async_syncfs<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
+        let _: SyncfsIn = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        let result = self
+            .async_deadline(
+                ctx.context.deadline,
+                self.fs.async_syncfs(ctx.context(), ctx.nodeid()),
+            )
+            .await;
+
+        match result {
+            Ok(()) => ctx.async_reply_ok(None::<u8>, None).await,
+            Err(e) => ctx.async_reply_error(e).await,
+        }
+    }
+
     async fn async_fsyncdir<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
         let FsyncIn {
             fh, fsync_flags, ..
         } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
         let datasync = fsync_flags & 0x1 != 0;
         let result = self
-            .fs
-            .async_fsyncdir(ctx.context(), ctx.nodeid(), datasync, fh.into())
+            .async_deadline(
+                ctx.context.deadline,
+                self.fs
+                    .async_fsyncdir(ctx.context(), ctx.nodeid(), datasync, fh.into()),
+            )
             .await;
 
         match result {
@@ -444,32 +627,38 @@ impl<F: AsyncFileSystem + Sync> Server<F> {
     async fn async_create<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
         let args: CreateIn = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
         let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, size_of::<CreateIn>())?;
-        let name = bytes_to_cstr(&buf)?;
+        let name = match ServerUtil::extract_name(&buf, false) {
+            Ok(name) => name,
+            Err(e) => return ctx.async_reply_error(e).await,
+        };
+        if let Err(e) = ctx.read_extensions() {
+            return ctx.async_reply_error(e).await;
+        }
         let result = self
-            .fs
-            .async_create(ctx.context(), ctx.nodeid(), name, args)
+            .async_deadline(
+                ctx.context.deadline,
+                self.fs
+                    .async_create(ctx.context(), ctx.nodeid(), name, args),
+            )
             .await;
 
         match result {
             Ok((entry, handle, opts)) => {
-                let entry_out = EntryOut {
-                    nodeid: entry.inode,
-                    generation: entry.generation,
-                    entry_valid: entry.entry_timeout.as_secs(),
-                    attr_valid: entry.attr_timeout.as_secs(),
-                    entry_valid_nsec: entry.entry_timeout.subsec_nanos(),
-                    attr_valid_nsec: entry.attr_timeout.subsec_nanos(),
-                    attr: entry.attr.into(),
-                };
+                let fh: Option<u64> = handle.map(Into::into);
+                self.access_open(entry.inode, fh, args.flags);
+                #[cfg(feature = "virtiofs")]
+                self.dax_open(entry.inode);
+                self.publish_inval(ctx.in_header.nodeid, name);
+                self.audit_lookup(entry.inode);
+                #[cfg(feature = "virtiofs")]
+                self.dax_lookup(entry.inode);
                 let open_out = OpenOut {
-                    fh: handle.map(Into::into).unwrap_or(0),
-                    open_flags: opts.bits(),
+                    fh: fh.unwrap_or(0),
+                    open_flags: compat::open_flags(opts, ctx.minor),
                     ..Default::default()
                 };
 
-                // Kind of a hack to write both structs.
-                ctx.async_reply_ok(Some(entry_out), Some(open_out.as_slice()))
-                    .await
+                ctx.async_reply_entry(entry, Some(open_out)).await
             }
             Err(e) => ctx.async_reply_error(e).await,
         }
@@ -486,9 +675,21 @@ impl<F: AsyncFileSystem + Sync> Server<F> {
             mode,
             ..
         } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        if let Err(e) = self.access_check(ctx.in_header.nodeid, fh, Access::Write) {
+            return ctx.async_reply_error(e).await;
+        }
         let result = self
-            .fs
-            .async_fallocate(ctx.context(), ctx.nodeid(), fh.into(), mode, offset, length)
+            .async_deadline(
+                ctx.context.deadline,
+                self.fs.async_fallocate(
+                    ctx.context(),
+                    ctx.nodeid(),
+                    fh.into(),
+                    mode,
+                    offset,
+                    length,
+                ),
+            )
             .await;
 
         match result {
@@ -505,7 +706,19 @@ impl<'a, F: AsyncFileSystem, S: BitmapSlice> SrvContext<'a, F, S> {
         data: Option<&[u8]>,
     ) -> Result<usize> {
         let data2 = out.as_ref().map(|v| v.as_slice()).unwrap_or(&[]);
-        let data3 = data.unwrap_or(&[]);
+        self.async_reply_slices(data2, data.unwrap_or(&[])).await
+    }
+
+    // Reply `entry`, followed by `open` for create, in the layout known by the kernel.
+    async fn async_reply_entry(&mut self, entry: Entry, open: Option<OpenOut>) -> Result<usize> {
+        let out = compat::entry_out(entry, self.minor);
+        let data2 = &out.as_slice()[..compat::entry_out_size(self.minor)];
+        let data3 = open.as_ref().map(|v| v.as_slice()).unwrap_or(&[]);
+        self.async_reply_slices(data2, data3).await
+    }
+
+    async fn async_reply_slices(&mut self, data2: &[u8], data3: &[u8]) -> Result<usize> {
+        self.mark_replying();
         let len = size_of::<OutHeader>() + data2.len() + data3.len();
         let header = OutHeader {
             len: len as u32,
@@ -513,6 +726,7 @@ impl<'a, F: AsyncFileSystem, S: BitmapSlice> SrvContext<'a, F, S> {
             unique: self.in_header.unique,
         };
         trace!("fuse: new reply {:?}", header);
+        self.record_reply(&header);
 
         let result = match (data2.len(), data3.len()) {
             (0, 0) => self.w.async_write(header.as_slice()).await,
@@ -527,11 +741,10 @@ impl<'a, F: AsyncFileSystem, S: BitmapSlice> SrvContext<'a, F, S> {
     }
 
     async fn async_do_reply_error(&mut self, err: io::Error, internal_err: bool) -> Result<usize> {
+        self.mark_replying();
         let header = OutHeader {
             len: size_of::<OutHeader>() as u32,
-            error: -err
-                .raw_os_error()
-                .unwrap_or_else(|| encode_io_error_kind(err.kind())),
+            error: -errno_of(&err).unwrap_or_else(|| encode_io_error_kind(err.kind())),
             unique: self.in_header.unique,
         };
 
@@ -539,6 +752,7 @@ impl<'a, F: AsyncFileSystem, S: BitmapSlice> SrvContext<'a, F, S> {
         if internal_err {
             error!("fuse: reply error header {:?}, error {:?}", header, err);
         }
+        self.record_reply(&header);
         self.w
             .async_write_all(header.as_slice())
             .await
@@ -577,7 +791,9 @@ impl<'a, F: AsyncFileSystem, S: BitmapSlice> SrvContext<'a, F, S> {
                     dummy: 0,
                     attr: st.into(),
                 };
-                self.async_reply_ok(Some(out), None).await
+                let size = compat::attr_out_size(self.minor);
+                self.async_reply_ok(None::<u8>, Some(&out.as_slice()[..size]))
+                    .await
             }
             Err(e) => self.async_reply_error(e).await,
         }
@@ -588,8 +804,12 @@ impl<'a, F: AsyncFileSystem, S: BitmapSlice> SrvContext<'a, F, S> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::abi::fuse_abi::{CreateIn, SetattrValid};
+    use crate::api::filesystem::{Context, OpenOptions};
+    use crate::api::server::{DeadlineViolations, RequestDeadlines};
     use crate::api::Vfs;
     use crate::transport::{FuseBuf, FuseDevWriter};
+    use std::ffi::CStr;
 
     use std::os::unix::io::AsRawFd;
 
@@ -610,4 +830,302 @@ mod tests {
         });
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_async_server_on_tokio_runtime() {
+        use crate::abi::fuse_abi::ROOT_ID;
+        use crate::api::executor::TokioExecutor;
+        use std::io::{Read, Seek, SeekFrom};
+
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_time()
+            .build()
+            .unwrap();
+        let server = Arc::new(
+            Server::new(Vfs::default())
+                .with_executor(Arc::new(TokioExecutor::new(rt.handle().clone()))),
+        );
+
+        let mut file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        let fd = file.as_raw_fd();
+        let server2 = server.clone();
+        let handle = rt.spawn_blocking(move || {
+            let in_header = InHeader {
+                len: (size_of::<InHeader>() + size_of::<GetattrIn>()) as u32,
+                opcode: Opcode::Getattr as u32,
+                unique: 3,
+                nodeid: ROOT_ID,
+                ..Default::default()
+            };
+            let mut r_buf = in_header.as_slice().to_vec();
+            r_buf.extend_from_slice(GetattrIn::default().as_slice());
+            let r = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut r_buf)).unwrap();
+            let mut buf = vec![0x0u8; 1000];
+            let w = FuseDevWriter::<()>::new(fd, &mut buf).unwrap().into();
+
+            // Handle the request on the runtime supplied by the embedder, which must not start
+            // another runtime within it.
+            tokio::runtime::Handle::current()
+                .block_on(unsafe { server2.async_handle_message(r, w, None, None) })
+        });
+        rt.block_on(async {
+            assert!(handle.await.unwrap().is_ok());
+            assert!(server.async_wait_drained(Duration::from_secs(1)).await);
+        });
+
+        let mut reply = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut reply).unwrap();
+        let out = OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap();
+        assert_eq!(out.unique, 3);
+        assert_eq!(out.error, 0);
+        assert_eq!(reply.len(), size_of::<OutHeader>() + size_of::<AttrOut>());
+    }
+
+    // File system delaying writes, and getattr of inode 3, until `gate` is notified.
+    #[derive(Default)]
+    struct GatedFs {
+        gate: tokio::sync::Notify,
+        events: std::sync::Mutex<Vec<&'static str>>,
+        budgets: std::sync::Mutex<Vec<Option<Duration>>>,
+    }
+
+    impl FileSystem for GatedFs {
+        type Inode = u64;
+        type Handle = u64;
+    }
+
+    fn enosys<T>() -> io::Result<T> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    #[async_trait]
+    impl AsyncFileSystem for GatedFs {
+        async fn async_lookup(&self, _: &Context, _: u64, _: &CStr) -> io::Result<Entry> {
+            enosys()
+        }
+
+        async fn async_getattr(
+            &self,
+            ctx: &Context,
+            inode: u64,
+            _: Option<u64>,
+        ) -> io::Result<(stat64, Duration)> {
+            self.budgets.lock().unwrap().push(ctx.remaining());
+            if inode == 3 {
+                self.gate.notified().await;
+            }
+            let mut st: stat64 = unsafe { std::mem::zeroed() };
+            st.st_ino = inode;
+            Ok((st, Duration::from_secs(1)))
+        }
+
+        async fn async_setattr(
+            &self,
+            _: &Context,
+            _: u64,
+            _: stat64,
+            _: Option<u64>,
+            _: SetattrValid,
+        ) -> io::Result<(stat64, Duration)> {
+            enosys()
+        }
+
+        async fn async_open(
+            &self,
+            _: &Context,
+            _: u64,
+            _: u32,
+            _: u32,
+        ) -> io::Result<(Option<u64>, OpenOptions)> {
+            enosys()
+        }
+
+        async fn async_create(
+            &self,
+            _: &Context,
+            _: u64,
+            _: &CStr,
+            _: CreateIn,
+        ) -> io::Result<(Entry, Option<u64>, OpenOptions)> {
+            enosys()
+        }
+
+        async fn async_read(
+            &self,
+            _: &Context,
+            _: u64,
+            _: u64,
+            _: &mut (dyn AsyncZeroCopyWriter + Send),
+            _: u32,
+            _: u64,
+            _: Option<u64>,
+            _: u32,
+        ) -> io::Result<usize> {
+            enosys()
+        }
+
+        async fn async_write(
+            &self,
+            _: &Context,
+            _: u64,
+            _: u64,
+            _: &mut (dyn AsyncZeroCopyReader + Send),
+            size: u32,
+            _: u64,
+            _: Option<u64>,
+            _: bool,
+            _: u32,
+            _: u32,
+        ) -> io::Result<usize> {
+            self.gate.notified().await;
+            self.events.lock().unwrap().push("write");
+            Ok(size as usize)
+        }
+
+        async fn async_fsync(&self, _: &Context, _: u64, _: bool, _: u64) -> io::Result<()> {
+            self.events.lock().unwrap().push("fsync");
+            Ok(())
+        }
+
+        async fn async_fallocate(
+            &self,
+            _: &Context,
+            _: u64,
+            _: u64,
+            _: u32,
+            _: u64,
+            _: u64,
+        ) -> io::Result<()> {
+            enosys()
+        }
+
+        async fn async_fsyncdir(&self, _: &Context, _: u64, _: bool, _: u64) -> io::Result<()> {
+            enosys()
+        }
+    }
+
+    // Handle a write to handle 1 of inode 2 delayed by the file system, and a fsync of the handle
+    // received after it, return the order they reached the file system.
+    fn write_then_fsync(server: Server<GatedFs>) -> Vec<&'static str> {
+        fn message(opcode: Opcode, arg: &[u8]) -> Vec<u8> {
+            let in_header = InHeader {
+                len: (size_of::<InHeader>() + arg.len()) as u32,
+                opcode: opcode as u32,
+                unique: opcode as u64,
+                nodeid: 2,
+                ..Default::default()
+            };
+            let mut buf = in_header.as_slice().to_vec();
+            buf.extend_from_slice(arg);
+            buf
+        }
+
+        let write_in = WriteIn {
+            fh: 1,
+            size: 4,
+            ..Default::default()
+        };
+        let mut write_buf = message(Opcode::Write, write_in.as_slice());
+        write_buf.extend_from_slice(b"data");
+        let fsync_in = FsyncIn {
+            fh: 1,
+            ..Default::default()
+        };
+        let mut fsync_buf = message(Opcode::Fsync, fsync_in.as_slice());
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        let fd = file.as_file().as_raw_fd();
+        let (mut out1, mut out2) = (vec![0u8; 100], vec![0u8; 100]);
+
+        tokio_uring::start(async {
+            let write = async {
+                let r = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut write_buf)).unwrap();
+                let w = FuseDevWriter::<()>::new(fd, &mut out1).unwrap().into();
+                unsafe { server.async_handle_message(r, w, None, None).await }
+            };
+            let fsync = async {
+                let r = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut fsync_buf)).unwrap();
+                let w = FuseDevWriter::<()>::new(fd, &mut out2).unwrap().into();
+                unsafe { server.async_handle_message(r, w, None, None).await }
+            };
+            let open_gate = async {
+                for _ in 0..10 {
+                    tokio::task::yield_now().await;
+                }
+                server.fs.gate.notify_one();
+            };
+            let (write, fsync, _) = futures::join!(write, fsync, open_gate);
+            write.unwrap();
+            fsync.unwrap();
+        });
+
+        server.fs.events.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn test_async_write_barrier() {
+        let server = Server::new(GatedFs::default());
+        assert_eq!(write_then_fsync(server), vec!["fsync", "write"]);
+
+        let server = Server::new(GatedFs::default()).with_write_barrier();
+        assert_eq!(write_then_fsync(server), vec!["write", "fsync"]);
+    }
+
+    // Handle a getattr of `nodeid`, return the error replied.
+    fn getattr(server: &Server<GatedFs>, nodeid: u64) -> i32 {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let in_header = InHeader {
+            len: (size_of::<InHeader>() + size_of::<GetattrIn>()) as u32,
+            opcode: Opcode::Getattr as u32,
+            unique: 1,
+            nodeid,
+            ..Default::default()
+        };
+        let mut r_buf = in_header.as_slice().to_vec();
+        r_buf.extend_from_slice(GetattrIn::default().as_slice());
+        let mut file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        let mut buf = vec![0u8; 1000];
+
+        tokio_uring::start(async {
+            let r = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut r_buf)).unwrap();
+            let w = FuseDevWriter::<()>::new(file.as_raw_fd(), &mut buf)
+                .unwrap()
+                .into();
+            unsafe { server.async_handle_message(r, w, None, None).await }.unwrap();
+        });
+        let mut reply = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut reply).unwrap();
+        OutHeader::from_slice(&reply[..size_of::<OutHeader>()])
+            .unwrap()
+            .error
+    }
+
+    #[test]
+    fn test_async_deadlines() {
+        let budget = Duration::from_millis(100);
+        let server = Server::new(GatedFs::default()).with_deadlines(RequestDeadlines {
+            metadata: Some(budget),
+            errno: libc::EIO,
+            ..Default::default()
+        });
+
+        // The backend gets the remaining budget.
+        assert_eq!(getattr(&server, 2), 0);
+        let remaining = server.fs.budgets.lock().unwrap()[0].unwrap();
+        assert!(remaining > Duration::ZERO && remaining <= budget);
+        assert_eq!(server.deadline_violations(), DeadlineViolations::default());
+
+        // Backends not completing in time are abandoned.
+        assert_eq!(getattr(&server, 3), -libc::EIO);
+        assert_eq!(server.deadline_violations().metadata, 1);
+        assert_eq!(server.deadline_violations().read, 0);
+
+        // Requests get no deadline by default.
+        let server = Server::new(GatedFs::default());
+        assert_eq!(getattr(&server, 2), 0);
+        assert_eq!(server.fs.budgets.lock().unwrap()[0], None);
+    }
 }
@@ -0,0 +1,249 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Encoding of replies for the protocol minor version negotiated with the kernel.
+//!
+//! Several reply structs grew over protocol minor versions, and kernels speaking an older minor
+//! misparse replies in a newer layout. So replies are built in the newest layout, then truncated
+//! to the size known by the kernel, and fields or flags it doesn't know are cleared.
+//!
+//! Kernels speaking a minor older than 7.23, like the 3.10-era kernels of enterprise
+//! distributions, are served in a legacy mode on top of that. Such kernels often carry backported
+//! capabilities they advertise but don't handle reliably, so readdirplus, writeback caching and
+//! large requests are never negotiated with them, whatever the file system wants. Kernels older
+//! than `MIN_KERNEL_MINOR_VERSION` are rejected by `FUSE_INIT`, as their requests have layouts
+//! the server doesn't decode.
+
+use std::mem::size_of;
+
+use super::DEFAULT_REQ_PAGES;
+use crate::abi::fuse_abi::{
+    AttrOut, EntryOut, FsOptions, InitOut, Kstatfs, OpenOptions, FUSE_COMPAT_22_INIT_OUT_SIZE,
+    FUSE_COMPAT_ATTR_OUT_SIZE, FUSE_COMPAT_ENTRY_OUT_SIZE, FUSE_COMPAT_INIT_OUT_SIZE,
+    FUSE_COMPAT_STATFS_SIZE,
+};
+use crate::api::filesystem::Entry;
+use crate::transport::pagesize;
+
+/// Protocol features depending on the minor version negotiated by `FUSE_INIT`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtocolFeature {
+    /// Statfs replies carry `frsize`, since 7.4.
+    StatfsFrsize,
+    /// Lookup replies with a zero inode mean a negative entry, since 7.4.
+    NegativeEntry,
+    /// `fuse_init_out` carries `max_background` and `congestion_threshold`, since 7.5.
+    InitBackground,
+    /// Attributes carry `blksize`, since 7.9.
+    AttrBlksize,
+    /// Open replies may set `FOPEN_NONSEEKABLE`, since 7.10.
+    OpenNonseekable,
+    /// `fuse_init_out` carries `max_pages` and `time_gran`, since 7.23.
+    InitMaxPages,
+    /// Open replies may set `FOPEN_CACHE_DIR`, since 7.28.
+    OpenCacheDir,
+    /// Open replies may set `FOPEN_STREAM`, since 7.31.
+    OpenStream,
+    /// Attributes carry flags, like `FUSE_ATTR_SUBMOUNT`, since 7.32.
+    AttrFlags,
+    /// `fuse_init_in` and `fuse_init_out` carry `flags2` with `FUSE_INIT_EXT`, since 7.36.
+    InitExt,
+    /// The kernel sends `FUSE_TMPFILE` to create unnamed files, since 7.37.
+    Tmpfile,
+    /// Entry invalidations may carry `FUSE_EXPIRE_ONLY`, since 7.38.
+    ExpireOnly,
+    /// The kernel sends `FUSE_STATX` for attributes beyond the ones of `fuse_attr`, like the
+    /// creation time, since 7.39.
+    Statx,
+}
+
+impl ProtocolFeature {
+    /// Get the first protocol minor version supporting the feature.
+    pub fn min_minor(&self) -> u32 {
+        match self {
+            ProtocolFeature::StatfsFrsize | ProtocolFeature::NegativeEntry => 4,
+            ProtocolFeature::InitBackground => 5,
+            ProtocolFeature::AttrBlksize => 9,
+            ProtocolFeature::OpenNonseekable => 10,
+            ProtocolFeature::InitMaxPages => 23,
+            ProtocolFeature::OpenCacheDir => 28,
+            ProtocolFeature::OpenStream => 31,
+            ProtocolFeature::AttrFlags => 32,
+            ProtocolFeature::InitExt => 36,
+            ProtocolFeature::Tmpfile => 37,
+            ProtocolFeature::ExpireOnly => 38,
+            ProtocolFeature::Statx => 39,
+        }
+    }
+
+    /// Check whether the protocol minor version `minor` supports the feature.
+    pub fn supported_by(&self, minor: u32) -> bool {
+        minor >= self.min_minor()
+    }
+}
+
+/// Check whether kernels speaking `minor` are served in the legacy mode.
+pub(super) fn legacy(minor: u32) -> bool {
+    // macFUSE speaks 7.19 whatever its version, so the mode only applies to Linux kernels.
+    cfg!(target_os = "linux") && !ProtocolFeature::InitMaxPages.supported_by(minor)
+}
+
+/// Clear options never negotiated with kernels speaking `minor`.
+pub(super) fn init_flags(enabled: FsOptions, minor: u32) -> FsOptions {
+    if legacy(minor) {
+        enabled
+            - FsOptions::DO_READDIRPLUS
+            - FsOptions::READDIRPLUS_AUTO
+            - FsOptions::WRITEBACK_CACHE
+            - FsOptions::MAX_PAGES
+    } else {
+        enabled
+    }
+}
+
+/// Clamp the max size of write requests to what kernels speaking `minor` accept.
+pub(super) fn max_write(max_write: u32, minor: u32) -> u32 {
+    if legacy(minor) {
+        max_write.min(DEFAULT_REQ_PAGES as u32 * pagesize() as u32)
+    } else {
+        max_write
+    }
+}
+
+/// Get the size of `fuse_init_out` known by `minor`.
+pub(super) fn init_out_size(minor: u32) -> usize {
+    if !ProtocolFeature::InitBackground.supported_by(minor) {
+        FUSE_COMPAT_INIT_OUT_SIZE
+    } else if !ProtocolFeature::InitMaxPages.supported_by(minor) {
+        FUSE_COMPAT_22_INIT_OUT_SIZE
+    } else {
+        size_of::<InitOut>()
+    }
+}
+
+/// Get the size of `fuse_entry_out` known by `minor`.
+pub(super) fn entry_out_size(minor: u32) -> usize {
+    if ProtocolFeature::AttrBlksize.supported_by(minor) {
+        size_of::<EntryOut>()
+    } else {
+        FUSE_COMPAT_ENTRY_OUT_SIZE
+    }
+}
+
+/// Get the size of `fuse_attr_out` known by `minor`.
+pub(super) fn attr_out_size(minor: u32) -> usize {
+    if ProtocolFeature::AttrBlksize.supported_by(minor) {
+        size_of::<AttrOut>()
+    } else {
+        FUSE_COMPAT_ATTR_OUT_SIZE
+    }
+}
+
+/// Get the size of `fuse_statfs_out` known by `minor`.
+pub(super) fn statfs_out_size(minor: u32) -> usize {
+    if ProtocolFeature::StatfsFrsize.supported_by(minor) {
+        size_of::<Kstatfs>()
+    } else {
+        FUSE_COMPAT_STATFS_SIZE
+    }
+}
+
+/// Clear attribute flags unknown by `minor`.
+pub(super) fn attr_flags(flags: u32, minor: u32) -> u32 {
+    if ProtocolFeature::AttrFlags.supported_by(minor) {
+        flags
+    } else {
+        0
+    }
+}
+
+/// Clear entry invalidation flags unknown by `minor`, older kernels take them for padding and
+/// fully invalidate the entry.
+pub(super) fn inval_entry_flags(flags: u32, minor: u32) -> u32 {
+    if ProtocolFeature::ExpireOnly.supported_by(minor) {
+        flags
+    } else {
+        0
+    }
+}
+
+/// Build the `fuse_entry_out` of `entry` for `minor`, to be truncated to `entry_out_size()`.
+pub(super) fn entry_out(entry: Entry, minor: u32) -> EntryOut {
+    let mut out = EntryOut::from(entry);
+    out.attr.flags = attr_flags(out.attr.flags, minor);
+    out
+}
+
+/// Clear open flags unknown by `minor`.
+pub(super) fn open_flags(opts: OpenOptions, minor: u32) -> u32 {
+    let mut opts = opts;
+    for (flag, feature) in [
+        (OpenOptions::NONSEEKABLE, ProtocolFeature::OpenNonseekable),
+        (OpenOptions::CACHE_DIR, ProtocolFeature::OpenCacheDir),
+        (OpenOptions::STREAM, ProtocolFeature::OpenStream),
+    ] {
+        if !feature.supported_by(minor) {
+            opts.remove(flag);
+        }
+    }
+    opts.bits()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abi::fuse_abi::FUSE_EXPIRE_ONLY;
+
+    #[test]
+    fn test_reply_sizes() {
+        // Sizes of replies of historical protocol minor versions, from the kernel headers.
+        for (minor, init, entry, attr, statfs) in [
+            (3, 8, 120, 96, 48),
+            (8, 24, 120, 96, 80),
+            (22, 24, 128, 104, 80),
+            (26, 64, 128, 104, 80),
+            (33, 64, 128, 104, 80),
+            (37, 64, 128, 104, 80),
+            (38, 64, 128, 104, 80),
+            (39, 64, 128, 104, 80),
+        ] {
+            assert_eq!(init_out_size(minor), init, "minor {}", minor);
+            assert_eq!(entry_out_size(minor), entry, "minor {}", minor);
+            assert_eq!(attr_out_size(minor), attr, "minor {}", minor);
+            assert_eq!(statfs_out_size(minor), statfs, "minor {}", minor);
+        }
+    }
+
+    #[test]
+    fn test_reply_flags() {
+        let all = OpenOptions::all();
+        assert_eq!(
+            open_flags(all, 9),
+            (OpenOptions::DIRECT_IO | OpenOptions::KEEP_CACHE).bits()
+        );
+        assert_eq!(open_flags(all, 28), (all - OpenOptions::STREAM).bits());
+        assert_eq!(open_flags(all, 31), all.bits());
+        assert_eq!(attr_flags(3, 31), 0);
+        assert_eq!(attr_flags(3, 32), 3);
+        assert_eq!(inval_entry_flags(FUSE_EXPIRE_ONLY, 37), 0);
+        assert_eq!(inval_entry_flags(FUSE_EXPIRE_ONLY, 38), FUSE_EXPIRE_ONLY);
+
+        assert!(!ProtocolFeature::AttrBlksize.supported_by(8));
+        assert!(ProtocolFeature::AttrBlksize.supported_by(9));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_legacy_init() {
+        let all = FsOptions::all();
+        let legacy = FsOptions::DO_READDIRPLUS
+            | FsOptions::READDIRPLUS_AUTO
+            | FsOptions::WRITEBACK_CACHE
+            | FsOptions::MAX_PAGES;
+        assert_eq!(init_flags(all, 22), all - legacy);
+        assert_eq!(init_flags(all, 23), all);
+        assert_eq!(max_write(1 << 20, 22), 32 * pagesize() as u32);
+        assert_eq!(max_write(4096, 22), 4096);
+        assert_eq!(max_write(1 << 20, 23), 1 << 20);
+    }
+}
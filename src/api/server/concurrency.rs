@@ -0,0 +1,292 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Limits of concurrent requests per class of opcodes.
+//!
+//! Backends built on libraries which aren't thread safe may only run one modification at a time,
+//! while reads could go on concurrently. Serializing all requests with a global lock kills read
+//! throughput, so [Server::with_concurrency_limits] bounds the number of concurrent requests of
+//! each class of opcodes independently.
+
+use std::io;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "async-io")]
+use tokio::sync::Notify;
+
+use super::Server;
+use crate::abi::fuse_abi::Opcode;
+use crate::api::filesystem::FileSystem;
+
+/// Limits of concurrent requests per class of opcodes.
+///
+/// A limit of `usize::MAX` leaves the class unlimited, without any accounting. Requests outside
+/// of the classes, like `FUSE_INIT`, `FUSE_FORGET` or `FUSE_RELEASE`, are never limited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConcurrencyLimits {
+    /// Max number of concurrent `FUSE_READ`, `FUSE_READDIR`, `FUSE_READDIRPLUS`,
+    /// `FUSE_READLINK`, `FUSE_LSEEK`, `FUSE_GETXATTR` and `FUSE_LISTXATTR` requests.
+    ///
+    /// The default value for this option is `usize::MAX`.
+    pub read: usize,
+    /// Max number of concurrent `FUSE_WRITE`, `FUSE_FALLOCATE`, `FUSE_COPY_FILE_RANGE`,
+    /// `FUSE_FSYNC`, `FUSE_FSYNCDIR` and `FUSE_FLUSH` requests.
+    ///
+    /// The default value for this option is `usize::MAX`.
+    pub write: usize,
+    /// Max number of concurrent requests looking up, opening, creating, removing or renaming
+    /// entries, or getting and changing their attributes.
+    ///
+    /// The default value for this option is `usize::MAX`.
+    pub metadata: usize,
+    /// How long requests handled by the synchronous path wait for their class to get below its
+    /// limit, before failing with `EBUSY`. `None` waits forever. Requests handled by the
+    /// asynchronous path always wait.
+    ///
+    /// The default value for this option is `None`.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for ConcurrencyLimits {
+    fn default() -> Self {
+        ConcurrencyLimits {
+            read: usize::MAX,
+            write: usize::MAX,
+            metadata: usize::MAX,
+            timeout: None,
+        }
+    }
+}
+
+pub(super) const READ_CLASS: usize = 0;
+pub(super) const WRITE_CLASS: usize = 1;
+pub(super) const METADATA_CLASS: usize = 2;
+
+pub(super) fn opcode_class(opcode: u32) -> Option<usize> {
+    let class = match Opcode::from(opcode) {
+        Opcode::Read
+        | Opcode::Readdir
+        | Opcode::Readdirplus
+        | Opcode::Readlink
+        | Opcode::Lseek
+        | Opcode::Getxattr
+        | Opcode::Listxattr => READ_CLASS,
+        Opcode::Write
+        | Opcode::Fallocate
+        | Opcode::CopyFileRange
+        | Opcode::Fsync
+        | Opcode::Fsyncdir
+        | Opcode::Syncfs
+        | Opcode::Flush => WRITE_CLASS,
+        Opcode::Lookup
+        | Opcode::Getattr
+        | Opcode::Statx
+        | Opcode::Setattr
+        | Opcode::Symlink
+        | Opcode::Mknod
+        | Opcode::Mkdir
+        | Opcode::Unlink
+        | Opcode::Rmdir
+        | Opcode::Rename
+        | Opcode::Rename2
+        | Opcode::Link
+        | Opcode::Open
+        | Opcode::Opendir
+        | Opcode::Create
+        | Opcode::Tmpfile
+        | Opcode::AtomicOpen
+        | Opcode::Statfs
+        | Opcode::Access
+        | Opcode::Setxattr
+        | Opcode::Removexattr
+        | Opcode::Getlk
+        | Opcode::Setlk
+        | Opcode::Setlkw => METADATA_CLASS,
+        #[cfg(target_os = "macos")]
+        Opcode::Setvolname | Opcode::Getxtimes | Opcode::Exchange => METADATA_CLASS,
+        _ => return None,
+    };
+    Some(class)
+}
+
+// Counting semaphore shared by the synchronous and asynchronous request paths.
+struct Semaphore {
+    max: usize,
+    used: Mutex<usize>,
+    freed: Condvar,
+    #[cfg(feature = "async-io")]
+    async_freed: Notify,
+}
+
+impl Semaphore {
+    fn new(max: usize) -> Self {
+        Semaphore {
+            max,
+            used: Mutex::new(0),
+            freed: Condvar::new(),
+            #[cfg(feature = "async-io")]
+            async_freed: Notify::new(),
+        }
+    }
+
+    #[cfg(feature = "async-io")]
+    fn try_acquire(&self) -> Option<Permit<'_>> {
+        let mut used = self.used.lock().unwrap();
+        if *used < self.max {
+            *used += 1;
+            Some(Permit { sem: self })
+        } else {
+            None
+        }
+    }
+
+    fn acquire(&self, timeout: Option<Duration>) -> io::Result<Permit<'_>> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut used = self.used.lock().unwrap();
+        while *used >= self.max {
+            used = match deadline {
+                None => self.freed.wait(used).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::Error::from_raw_os_error(libc::EBUSY));
+                    }
+                    self.freed.wait_timeout(used, deadline - now).unwrap().0
+                }
+            };
+        }
+        *used += 1;
+        Ok(Permit { sem: self })
+    }
+
+    #[cfg(feature = "async-io")]
+    async fn async_acquire(&self) -> Permit<'_> {
+        loop {
+            let notified = self.async_freed.notified();
+            tokio::pin!(notified);
+            // Register before checking, not to miss a release in between.
+            notified.as_mut().enable();
+            if let Some(permit) = self.try_acquire() {
+                return permit;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// A request counted against the limit of its class until dropped.
+pub(crate) struct Permit<'a> {
+    sem: &'a Semaphore,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.sem.used.lock().unwrap() -= 1;
+        // Waiters of both request paths may wait for the slot, wake one of each.
+        self.sem.freed.notify_one();
+        #[cfg(feature = "async-io")]
+        self.sem.async_freed.notify_one();
+    }
+}
+
+/// Semaphores of the limited classes of opcodes.
+pub(crate) struct ConcurrencyLimiter {
+    classes: [Option<Semaphore>; 3],
+    timeout: Option<Duration>,
+}
+
+impl ConcurrencyLimiter {
+    pub(crate) fn new(limits: ConcurrencyLimits) -> Self {
+        let class = |max| (max != usize::MAX).then(|| Semaphore::new(max));
+        ConcurrencyLimiter {
+            classes: [
+                class(limits.read),
+                class(limits.write),
+                class(limits.metadata),
+            ],
+            timeout: limits.timeout,
+        }
+    }
+
+    fn semaphore(&self, opcode: u32) -> Option<&Semaphore> {
+        opcode_class(opcode).and_then(|c| self.classes[c].as_ref())
+    }
+
+    /// Wait for the class of `opcode` to get below its limit, fail with `EBUSY` on timeout.
+    pub(crate) fn acquire(&self, opcode: u32) -> io::Result<Option<Permit<'_>>> {
+        self.semaphore(opcode)
+            .map(|sem| sem.acquire(self.timeout))
+            .transpose()
+    }
+
+    /// Wait for the class of `opcode` to get below its limit.
+    #[cfg(feature = "async-io")]
+    pub(crate) async fn async_acquire(&self, opcode: u32) -> Option<Permit<'_>> {
+        match self.semaphore(opcode) {
+            Some(sem) => Some(sem.async_acquire().await),
+            None => None,
+        }
+    }
+}
+
+impl<F: FileSystem + Sync> Server<F> {
+    /// Limit the number of concurrent requests of each class of opcodes, to protect backends
+    /// which can't handle many concurrent modifications.
+    pub fn with_concurrency_limits(mut self, limits: ConcurrencyLimits) -> Self {
+        self.limits = Some(ConcurrencyLimiter::new(limits));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concurrency_limiter() {
+        let limiter = ConcurrencyLimiter::new(ConcurrencyLimits {
+            write: 1,
+            metadata: 2,
+            timeout: Some(Duration::from_millis(10)),
+            ..Default::default()
+        });
+
+        // Unlimited classes and unclassified opcodes aren't accounted.
+        assert!(limiter.acquire(Opcode::Read as u32).unwrap().is_none());
+        assert!(limiter.acquire(Opcode::Release as u32).unwrap().is_none());
+
+        let write = limiter.acquire(Opcode::Write as u32).unwrap();
+        assert!(write.is_some());
+        let err = limiter.acquire(Opcode::Fsync as u32).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EBUSY));
+        let _lookup = limiter.acquire(Opcode::Lookup as u32).unwrap().unwrap();
+        let _getattr = limiter.acquire(Opcode::Getattr as u32).unwrap().unwrap();
+        assert!(limiter.acquire(Opcode::Mkdir as u32).is_err());
+
+        drop(write);
+        assert!(limiter.acquire(Opcode::Fsync as u32).unwrap().is_some());
+    }
+
+    #[cfg(feature = "async-io")]
+    #[test]
+    fn test_concurrency_limiter_async() {
+        use futures::FutureExt;
+
+        let limiter = ConcurrencyLimiter::new(ConcurrencyLimits {
+            write: 1,
+            ..Default::default()
+        });
+        let waker = futures::task::noop_waker();
+        let mut cx = std::task::Context::from_waker(&waker);
+
+        let write = limiter.acquire(Opcode::Write as u32).unwrap();
+        let mut pending = Box::pin(limiter.async_acquire(Opcode::Write as u32));
+        assert!(pending.poll_unpin(&mut cx).is_pending());
+        drop(write);
+        assert!(matches!(
+            pending.poll_unpin(&mut cx),
+            std::task::Poll::Ready(Some(_))
+        ));
+    }
+}
@@ -0,0 +1,491 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Save and restore the state negotiated with the kernel by `FUSE_INIT`, for live upgrade.
+//!
+//! A daemon restored from a saved session doesn't see `FUSE_INIT` again, and a newer build may
+//! support, and enable by default, capabilities the kernel never agreed on. So the negotiated
+//! state is saved by [Server::connection_info], and handed to [Server::restore_connection] of the
+//! new daemon instead of its defaults. The restored state is authoritative: the filesystem driver
+//! is initialized with exactly the negotiated options, and the restore is refused if the new build
+//! can't honor something negotiated by the old one.
+
+use std::io;
+use std::mem::size_of;
+use std::sync::Arc;
+
+use super::{
+    ProtocolFeature, Server, ServerVersion, BUFFER_HEADER_SIZE, DEFAULT_REQ_PAGES, MAX_REQ_PAGES,
+    MIN_READ_BUFFER,
+};
+use crate::abi::fuse_abi::{
+    AccessIn, BatchForgetIn, BmapIn, CopyFileRangeIn, CreateIn, FallocateIn, FlushIn, ForgetIn,
+    FsOptions, FsyncIn, GetattrIn, GetxattrIn, InHeader, InitIn, InterruptIn, IoctlIn, LinkIn,
+    LkIn, LseekIn, MkdirIn, MknodIn, Opcode, OpenIn, PollIn, ReadIn, ReleaseIn, Rename2In,
+    RenameIn, SetattrIn, SetxattrIn, StatxIn, SyncfsIn, WriteIn, KERNEL_MINOR_VERSION,
+    KERNEL_VERSION, MIN_KERNEL_MINOR_VERSION,
+};
+#[cfg(feature = "virtiofs")]
+use crate::abi::virtio_fs::{RemovemappingIn, SetupmappingIn};
+use crate::api::errno::fuse_errno;
+use crate::api::filesystem::FileSystem;
+use crate::transport::{pagesize, Reader};
+use crate::BitmapSlice;
+
+// Magic number and format version of saved connection states.
+const CONNECTION_MAGIC: u32 = 0x4655_5343;
+const CONNECTION_FORMAT: u32 = 1;
+const CONNECTION_SIZE: usize = 44;
+
+/// State negotiated with the kernel by `FUSE_INIT`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// Major version of the protocol.
+    pub major: u32,
+    /// Minor version of the protocol, as sent by the kernel.
+    pub minor: u32,
+    /// Options enabled for the connection, including the extended options of `FUSE_INIT_EXT`.
+    pub flags: FsOptions,
+    /// Max readahead replied to the kernel.
+    pub max_readahead: u32,
+    /// Max size of write requests replied to the kernel.
+    pub max_write: u32,
+    /// Max pages of requests replied to the kernel, zero without `FsOptions::MAX_PAGES`.
+    pub max_pages: u16,
+    /// Timestamp granularity in nanoseconds replied to the kernel.
+    pub time_gran: u32,
+}
+
+impl ConnectionInfo {
+    /// Encode the state to be saved across live upgrade.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(CONNECTION_SIZE);
+        for v in [
+            CONNECTION_MAGIC,
+            CONNECTION_FORMAT,
+            self.major,
+            self.minor,
+            self.flags.bits() as u32,
+            (self.flags.bits() >> 32) as u32,
+            self.max_readahead,
+            self.max_write,
+            self.max_pages as u32,
+            self.time_gran,
+            0,
+        ] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        buf
+    }
+
+    /// Decode a state encoded by [ConnectionInfo::to_bytes].
+    ///
+    /// Fail with `EPROTO` if the state has options unknown to this build, which can't be honored.
+    pub fn from_bytes(buf: &[u8]) -> io::Result<Self> {
+        if buf.len() != CONNECTION_SIZE {
+            return Err(fuse_errno(libc::EINVAL, "invalid size of connection state"));
+        }
+        let mut words = buf
+            .chunks_exact(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]));
+        let mut next = || words.next().unwrap();
+        if next() != CONNECTION_MAGIC {
+            return Err(fuse_errno(libc::EINVAL, "invalid connection state"));
+        }
+        let format = next();
+        if format != CONNECTION_FORMAT {
+            return Err(fuse_errno(
+                libc::EINVAL,
+                format!("unsupported format {} of connection state", format),
+            ));
+        }
+
+        let (major, minor) = (next(), next());
+        let flags = next() as u64 | (next() as u64) << 32;
+        let flags = FsOptions::from_bits(flags).ok_or_else(|| {
+            fuse_errno(
+                libc::EPROTO,
+                format!(
+                    "unsupported options {:#x}",
+                    flags & !FsOptions::all().bits()
+                ),
+            )
+        })?;
+
+        Ok(ConnectionInfo {
+            major,
+            minor,
+            flags,
+            max_readahead: next(),
+            max_write: next(),
+            max_pages: next() as u16,
+            time_gran: next(),
+        })
+    }
+
+    /// Check whether the negotiated protocol minor version supports `feature`.
+    pub fn supports(&self, feature: ProtocolFeature) -> bool {
+        feature.supported_by(self.minor)
+    }
+
+    /// Check whether this build is able to honor the state.
+    pub fn validate(&self) -> io::Result<()> {
+        let refuse = |msg: String| Err(fuse_errno(libc::EPROTO, msg));
+
+        if self.major != KERNEL_VERSION
+            || self.minor > KERNEL_MINOR_VERSION
+            || self.minor < MIN_KERNEL_MINOR_VERSION
+        {
+            return refuse(format!(
+                "unsupported protocol version {}.{}",
+                self.major, self.minor
+            ));
+        }
+        if self.flags.contains(FsOptions::MAX_PAGES) != (self.max_pages != 0)
+            || self.max_pages > MAX_REQ_PAGES
+        {
+            return refuse(format!("unsupported max pages {}", self.max_pages));
+        }
+        if self.max_write == 0 || self.max_write > MAX_REQ_PAGES as u32 * pagesize() as u32 {
+            return refuse(format!("unsupported max write {}", self.max_write));
+        }
+        if self.time_gran == 0 || self.time_gran > 1_000_000_000 {
+            return refuse(format!("unsupported time granularity {}", self.time_gran));
+        }
+
+        Ok(())
+    }
+}
+
+impl<F: FileSystem + Sync> Server<F> {
+    /// Negotiate a `max_write` of up to `max_write` bytes with the kernel.
+    ///
+    /// Kernels supporting `FUSE_MAX_PAGES` are told to send writes of up to `max_write` bytes,
+    /// rounded up to pages, as single requests, others up to 128KB. The value is clamped to
+    /// [4KB, 1MB]. Transport buffers must hold requests of [Server::max_request_size] bytes.
+    ///
+    /// The default value for this option is 1MB.
+    pub fn with_max_write(mut self, max_write: u32) -> Self {
+        let max = MAX_REQ_PAGES as u32 * pagesize() as u32;
+        self.max_write = max_write.clamp(MIN_READ_BUFFER - BUFFER_HEADER_SIZE, max);
+        self
+    }
+
+    /// Get the max size of requests sent by the kernel, including their headers.
+    ///
+    /// Requests are bounded by the `max_write` and `max_pages` negotiated by `FUSE_INIT`, or by
+    /// the ones the server would negotiate before it. Larger requests, or virtio-fs descriptor
+    /// chains with more readable bytes, are refused with `ENOMEM`.
+    pub fn max_request_size(&self) -> usize {
+        let (max_write, max_pages) = match self.conn.load().as_deref() {
+            Some(conn) => (conn.max_write, conn.max_pages),
+            None => (self.max_write, self.max_pages()),
+        };
+        let max_pages = if max_pages == 0 {
+            DEFAULT_REQ_PAGES
+        } else {
+            max_pages
+        };
+        let payload = (max_write as usize).max(max_pages as usize * pagesize());
+        payload + BUFFER_HEADER_SIZE as usize
+    }
+
+    // Get the negotiated `max_write`, or the one the server would negotiate before `FUSE_INIT`.
+    pub(super) fn negotiated_max_write(&self) -> u32 {
+        match self.conn.load().as_deref() {
+            Some(conn) => conn.max_write,
+            None => self.max_write,
+        }
+    }
+
+    // Get the `max_pages` to negotiate for the configured `max_write`.
+    pub(super) fn max_pages(&self) -> u16 {
+        let pages = (self.max_write as usize).div_ceil(pagesize());
+        pages.min(MAX_REQ_PAGES as usize) as u16
+    }
+
+    // Check whether the request of `in_header` with body `r` exceeds the negotiated limits.
+    pub(super) fn oversized<S: BitmapSlice>(
+        &self,
+        in_header: &InHeader,
+        r: &Reader<'_, S>,
+    ) -> bool {
+        let max = self.max_request_size();
+        in_header.len as usize > max || r.available_bytes() + size_of::<InHeader>() > max
+    }
+
+    // Check whether the `len` field of `in_header` is inconsistent with the body `r` of the
+    // request: shorter than the header, running past the data available, or leaving no room for
+    // the extensions.
+    pub(super) fn malformed<S: BitmapSlice>(in_header: &InHeader, r: &Reader<'_, S>) -> bool {
+        match (in_header.len as usize).checked_sub(size_of::<InHeader>()) {
+            Some(len) => len > r.available_bytes() || len < in_header.total_extlen as usize * 8,
+            None => true,
+        }
+    }
+
+    // Check whether the body `r` of the request of `in_header` is too short for the fixed
+    // arguments of its builtin handler.
+    pub(super) fn truncated<S: BitmapSlice>(in_header: &InHeader, r: &Reader<'_, S>) -> bool {
+        r.available_bytes() < in_header.total_extlen as usize * 8 + args_size(in_header.opcode)
+    }
+
+    // Check whether requests of `opcode` get no reply, so malformed ones are dropped.
+    pub(super) fn no_reply(opcode: u32) -> bool {
+        opcode == Opcode::Forget as u32
+            || opcode == Opcode::BatchForget as u32
+            || opcode == Opcode::Interrupt as u32
+            || opcode == Opcode::NotifyReply as u32
+    }
+
+    /// Get the oldest protocol minor version accepted from the kernel by `FUSE_INIT`.
+    ///
+    /// Kernels speaking an older minor fail to mount with `EPROTO`, as the server doesn't
+    /// decode the layouts of their requests.
+    pub fn min_supported_minor(&self) -> u32 {
+        MIN_KERNEL_MINOR_VERSION
+    }
+
+    /// Get the state negotiated by `FUSE_INIT`, or restored by [Server::restore_connection].
+    ///
+    /// Return `None` if the connection isn't initialized yet.
+    pub fn connection_info(&self) -> Option<ConnectionInfo> {
+        self.conn.load().as_deref().copied()
+    }
+
+    /// Restore the state negotiated by a previous daemon, instead of handling `FUSE_INIT`.
+    ///
+    /// The filesystem driver is initialized with exactly the options enabled for the connection,
+    /// so capabilities enabled by default in this build but not negotiated are never exercised.
+    /// Fail with `EPROTO`, before initializing the driver, if this build doesn't support the
+    /// state, and after initializing it if the driver doesn't want all the enabled options. The
+    /// server should be dropped on failure.
+    pub fn restore_connection(&self, info: ConnectionInfo) -> io::Result<()> {
+        if self.conn.load().is_some() {
+            return Err(fuse_errno(libc::EBUSY, "connection is already initialized"));
+        }
+        info.validate()?;
+        if info.max_write > self.max_write {
+            // Transport buffers are sized for the configured `max_write`.
+            return Err(fuse_errno(
+                libc::EPROTO,
+                format!("negotiated max write {} is too large", info.max_write),
+            ));
+        }
+
+        let want = self.fs.init(info.flags)?;
+        if !want.contains(info.flags) {
+            return Err(fuse_errno(
+                libc::EPROTO,
+                format!(
+                    "file system can't honor negotiated options {:?}",
+                    info.flags - want
+                ),
+            ));
+        }
+
+        if let Some(access) = self.access.as_ref() {
+            access.set_writeback(info.flags.contains(FsOptions::WRITEBACK_CACHE));
+        }
+        self.vers.store(Arc::new(ServerVersion {
+            major: info.major,
+            minor: info.minor,
+        }));
+        self.conn.store(Some(Arc::new(info)));
+        info!("FUSE connection restored: {:?}", info);
+
+        Ok(())
+    }
+}
+
+// Get the size of the fixed arguments following the header in requests of `opcode`.
+fn args_size(opcode: u32) -> usize {
+    match Opcode::from(opcode) {
+        Opcode::Forget => size_of::<ForgetIn>(),
+        Opcode::Getattr => size_of::<GetattrIn>(),
+        Opcode::Setattr => size_of::<SetattrIn>(),
+        Opcode::Mknod => size_of::<MknodIn>(),
+        Opcode::Mkdir => size_of::<MkdirIn>(),
+        Opcode::Rename => size_of::<RenameIn>(),
+        Opcode::Link => size_of::<LinkIn>(),
+        Opcode::Open | Opcode::Opendir => size_of::<OpenIn>(),
+        Opcode::Read | Opcode::Readdir | Opcode::Readdirplus => size_of::<ReadIn>(),
+        Opcode::Write => size_of::<WriteIn>(),
+        Opcode::Release | Opcode::Releasedir => size_of::<ReleaseIn>(),
+        Opcode::Fsync | Opcode::Fsyncdir => size_of::<FsyncIn>(),
+        Opcode::Setxattr => size_of::<SetxattrIn>(),
+        Opcode::Getxattr | Opcode::Listxattr => size_of::<GetxattrIn>(),
+        Opcode::Flush => size_of::<FlushIn>(),
+        Opcode::Init => size_of::<InitIn>(),
+        Opcode::Getlk | Opcode::Setlk | Opcode::Setlkw => size_of::<LkIn>(),
+        Opcode::Access => size_of::<AccessIn>(),
+        Opcode::Create | Opcode::Tmpfile | Opcode::AtomicOpen => size_of::<CreateIn>(),
+        Opcode::Interrupt => size_of::<InterruptIn>(),
+        Opcode::Bmap => size_of::<BmapIn>(),
+        Opcode::Ioctl => size_of::<IoctlIn>(),
+        Opcode::Poll => size_of::<PollIn>(),
+        Opcode::BatchForget => size_of::<BatchForgetIn>(),
+        Opcode::Fallocate => size_of::<FallocateIn>(),
+        Opcode::Rename2 => size_of::<Rename2In>(),
+        Opcode::Lseek => size_of::<LseekIn>(),
+        Opcode::CopyFileRange => size_of::<CopyFileRangeIn>(),
+        #[cfg(feature = "virtiofs")]
+        Opcode::SetupMapping => size_of::<SetupmappingIn>(),
+        #[cfg(feature = "virtiofs")]
+        Opcode::RemoveMapping => size_of::<RemovemappingIn>(),
+        Opcode::Syncfs => size_of::<SyncfsIn>(),
+        Opcode::Statx => size_of::<StatxIn>(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::errno::errno_of;
+    use std::sync::Mutex;
+
+    // A file system recording the options it's initialized with, and wanting `want`.
+    struct InitFs {
+        want: FsOptions,
+        capable: Mutex<Option<FsOptions>>,
+    }
+
+    impl FileSystem for InitFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
+            *self.capable.lock().unwrap() = Some(capable);
+            Ok(self.want)
+        }
+    }
+
+    fn init_server(want: FsOptions) -> Server<InitFs> {
+        Server::new(InitFs {
+            want,
+            capable: Mutex::new(None),
+        })
+    }
+
+    fn old_state() -> ConnectionInfo {
+        ConnectionInfo {
+            major: KERNEL_VERSION,
+            minor: 31,
+            flags: FsOptions::ASYNC_READ | FsOptions::DO_READDIRPLUS,
+            max_readahead: 0x20000,
+            max_write: 0x1000,
+            max_pages: 0,
+            time_gran: 1,
+        }
+    }
+
+    #[test]
+    fn test_connection_info_bytes() {
+        let info = old_state();
+        let buf = info.to_bytes();
+        assert_eq!(buf.len(), CONNECTION_SIZE);
+        assert_eq!(ConnectionInfo::from_bytes(&buf).unwrap(), info);
+
+        let errno = |buf: &[u8]| errno_of(&ConnectionInfo::from_bytes(buf).unwrap_err());
+        assert_eq!(errno(&buf[..40]), Some(libc::EINVAL));
+        let mut bad = buf.clone();
+        bad[0] ^= 1;
+        assert_eq!(errno(&bad), Some(libc::EINVAL));
+        let mut bad = buf.clone();
+        bad[4] = 2;
+        assert_eq!(errno(&bad), Some(libc::EINVAL));
+
+        // Options unknown to this build can't be honored.
+        let mut unknown = buf.clone();
+        unknown[19] = 0x80;
+        assert_eq!(errno(&unknown), Some(libc::EPROTO));
+        let mut unknown = buf;
+        unknown[23] = 0x80;
+        assert_eq!(errno(&unknown), Some(libc::EPROTO));
+
+        // Extended options are kept in the upper word.
+        let mut info = old_state();
+        info.flags |= FsOptions::CREATE_SUPP_GROUP;
+        let buf = info.to_bytes();
+        assert_eq!(buf[20], 0x4);
+        assert_eq!(ConnectionInfo::from_bytes(&buf).unwrap(), info);
+    }
+
+    #[test]
+    fn test_connection_info_validate() {
+        assert!(old_state().validate().is_ok());
+
+        let refused = |info: ConnectionInfo| errno_of(&info.validate().unwrap_err());
+        let mut info = old_state();
+        info.minor = KERNEL_MINOR_VERSION + 1;
+        assert_eq!(refused(info), Some(libc::EPROTO));
+        info = old_state();
+        info.major = KERNEL_VERSION + 1;
+        assert_eq!(refused(info), Some(libc::EPROTO));
+        info = old_state();
+        info.max_pages = 16;
+        assert_eq!(refused(info), Some(libc::EPROTO));
+        info.flags |= FsOptions::MAX_PAGES;
+        assert!(info.validate().is_ok());
+        info.max_pages = MAX_REQ_PAGES + 1;
+        assert_eq!(refused(info), Some(libc::EPROTO));
+        info = old_state();
+        info.max_write = MAX_REQ_PAGES as u32 * pagesize() as u32 + 1;
+        assert_eq!(refused(info), Some(libc::EPROTO));
+        info = old_state();
+        info.time_gran = 0;
+        assert_eq!(refused(info), Some(libc::EPROTO));
+    }
+
+    #[test]
+    fn test_restore_connection() {
+        // The new build wants writeback cache by default, but the old connection didn't enable it.
+        let new_defaults = FsOptions::ASYNC_READ
+            | FsOptions::DO_READDIRPLUS
+            | FsOptions::WRITEBACK_CACHE
+            | FsOptions::MAX_PAGES;
+        let server = init_server(new_defaults);
+        assert!(server.connection_info().is_none());
+        let blob = old_state().to_bytes();
+        server
+            .restore_connection(ConnectionInfo::from_bytes(&blob).unwrap())
+            .unwrap();
+
+        let capable = server.fs.capable.lock().unwrap().unwrap();
+        assert_eq!(capable, FsOptions::ASYNC_READ | FsOptions::DO_READDIRPLUS);
+        assert!(!capable.contains(FsOptions::WRITEBACK_CACHE));
+        assert_eq!(server.connection_info(), Some(old_state()));
+        assert_eq!(server.vers.load().minor, 31);
+
+        // Restoring twice is refused.
+        let err = server.restore_connection(old_state()).unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::EBUSY));
+    }
+
+    #[test]
+    fn test_restore_connection_refused() {
+        // Unsupported states are refused before initializing the file system.
+        let server = init_server(FsOptions::all());
+        let mut info = old_state();
+        info.minor = KERNEL_MINOR_VERSION + 1;
+        let err = server.restore_connection(info).unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::EPROTO));
+        assert!(server.fs.capable.lock().unwrap().is_none());
+        assert!(server.connection_info().is_none());
+
+        // The new build has buffers too small for the negotiated writes.
+        let server = init_server(FsOptions::all()).with_max_write(0x1000);
+        let mut info = old_state();
+        info.max_write = 0x2000;
+        let err = server.restore_connection(info).unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::EPROTO));
+        assert!(server.connection_info().is_none());
+
+        // The new build doesn't want an option negotiated by the old one.
+        let server = init_server(FsOptions::ASYNC_READ);
+        let err = server.restore_connection(old_state()).unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::EPROTO));
+        assert!(server.connection_info().is_none());
+    }
+}
@@ -0,0 +1,385 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Accounting of virtio-fs DAX window mappings, to reclaim mappings leaked by guests.
+//!
+//! Guests normally tear down DAX mappings by `FUSE_REMOVEMAPPING` before evicting inodes. But a
+//! guest which crashes or gets killed never sends them, and the ranges of the DAX window stay
+//! mapped until the window fills up and `FUSE_SETUPMAPPING` starts failing with `ENOSPC`.
+//!
+//! [DaxWindow] tracks the ranges mapped through a session, along with lookup counts and open
+//! handles of inodes, and reclaims leaked ranges with the following policy:
+//! * if enabled, all ranges of an inode are unmapped once the inode has no open handles and its
+//!   lookup count has dropped to zero, that is when the last of its release and forget requests
+//!   arrives, because the guest can't reach the inode anymore,
+//! * all ranges are unmapped when the session gets destroyed or disconnected.
+//!
+//! The guest may still hold stale mappings of reclaimed ranges. That's fine, as it has either
+//! crashed or lost the inode, and accesses through stale mappings just hit unmapped ranges of the
+//! window.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::Mutex;
+
+use super::Server;
+use crate::abi::virtio_fs::RemovemappingOne;
+use crate::api::filesystem::FileSystem;
+use crate::transport::FsCacheReqHandler;
+
+/// Statistics of mappings of the DAX window.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DaxWindowStats {
+    /// Number of bytes currently mapped.
+    pub bytes_mapped: u64,
+    /// Number of ranges currently mapped.
+    pub mappings: usize,
+    /// Number of inodes with ranges currently mapped.
+    pub inodes: usize,
+    /// Max number of bytes ever mapped at once.
+    pub high_water: u64,
+    /// Number of ranges reclaimed without `FUSE_REMOVEMAPPING` from the guest.
+    pub reclaimed: u64,
+}
+
+#[derive(Default)]
+struct InodeState {
+    nlookup: u64,
+    opens: u64,
+    mappings: usize,
+}
+
+struct Mapping {
+    nodeid: u64,
+    len: u64,
+}
+
+#[derive(Default)]
+struct WindowState {
+    // Mapped ranges keyed by their offset in the DAX window.
+    mappings: BTreeMap<u64, Mapping>,
+    inodes: HashMap<u64, InodeState>,
+    // Ranges reclaimed but not unmapped yet.
+    reclaim: Vec<RemovemappingOne>,
+    stats: DaxWindowStats,
+}
+
+impl WindowState {
+    fn remove(&mut self, moffset: u64) -> Option<Mapping> {
+        let mapping = self.mappings.remove(&moffset)?;
+        self.stats.bytes_mapped -= mapping.len;
+        self.stats.mappings -= 1;
+        if let Some(inode) = self.inodes.get_mut(&mapping.nodeid) {
+            inode.mappings -= 1;
+            if inode.mappings == 0 {
+                self.stats.inodes -= 1;
+            }
+        }
+        self.put_inode(mapping.nodeid);
+        Some(mapping)
+    }
+
+    // Remove ranges overlapping with `[moffset, moffset + len)`.
+    fn remove_range(&mut self, moffset: u64, len: u64) -> Vec<(u64, Mapping)> {
+        let end = moffset.saturating_add(len);
+        let overlapped: Vec<u64> = self
+            .mappings
+            .range(..end)
+            .filter(|(start, m)| start.saturating_add(m.len) > moffset)
+            .map(|(start, _)| *start)
+            .collect();
+        overlapped
+            .into_iter()
+            .filter_map(|start| self.remove(start).map(|m| (start, m)))
+            .collect()
+    }
+
+    // Move ranges starting at `moffsets` into the reclaim list.
+    fn reclaim(&mut self, moffsets: Vec<u64>) {
+        for moffset in moffsets {
+            if let Some(mapping) = self.remove(moffset) {
+                self.stats.reclaimed += 1;
+                self.reclaim.push(RemovemappingOne {
+                    moffset,
+                    len: mapping.len,
+                });
+            }
+        }
+    }
+
+    // Drop the state of `nodeid` if there's nothing left to track.
+    fn put_inode(&mut self, nodeid: u64) {
+        if let Some(inode) = self.inodes.get(&nodeid) {
+            if inode.nlookup == 0 && inode.opens == 0 && inode.mappings == 0 {
+                self.inodes.remove(&nodeid);
+            }
+        }
+    }
+}
+
+/// Tracker of the ranges of the DAX window mapped through a session.
+pub(crate) struct DaxWindow {
+    reclaim_on_forget: bool,
+    state: Mutex<WindowState>,
+}
+
+impl DaxWindow {
+    pub(crate) fn new(reclaim_on_forget: bool) -> Self {
+        DaxWindow {
+            reclaim_on_forget,
+            state: Mutex::new(WindowState::default()),
+        }
+    }
+
+    pub(crate) fn lookup(&self, nodeid: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.inodes.entry(nodeid).or_default().nlookup += 1;
+    }
+
+    pub(crate) fn forget(&self, nodeid: u64, nlookup: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(inode) = state.inodes.get_mut(&nodeid) {
+            inode.nlookup = inode.nlookup.saturating_sub(nlookup);
+        }
+        self.check_inode(&mut state, nodeid);
+    }
+
+    pub(crate) fn open(&self, nodeid: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.inodes.entry(nodeid).or_default().opens += 1;
+    }
+
+    pub(crate) fn release(&self, nodeid: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(inode) = state.inodes.get_mut(&nodeid) {
+            inode.opens = inode.opens.saturating_sub(1);
+        }
+        self.check_inode(&mut state, nodeid);
+    }
+
+    /// Record range `[moffset, moffset + len)` mapped for `nodeid`, replacing overlapped ranges.
+    pub(crate) fn map(&self, nodeid: u64, moffset: u64, len: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.remove_range(moffset, len);
+
+        let inode = state.inodes.entry(nodeid).or_default();
+        inode.mappings += 1;
+        if inode.mappings == 1 {
+            state.stats.inodes += 1;
+        }
+        state.mappings.insert(moffset, Mapping { nodeid, len });
+        state.stats.mappings += 1;
+        state.stats.bytes_mapped += len;
+        state.stats.high_water = state.stats.high_water.max(state.stats.bytes_mapped);
+    }
+
+    /// Record ranges unmapped by `FUSE_REMOVEMAPPING`.
+    pub(crate) fn unmap(&self, requests: &[RemovemappingOne]) {
+        let mut state = self.state.lock().unwrap();
+        for req in requests {
+            state.remove_range(req.moffset, req.len);
+        }
+    }
+
+    /// Reclaim all mapped ranges, when the session gets destroyed or disconnected.
+    pub(crate) fn reclaim_all(&self) {
+        let mut state = self.state.lock().unwrap();
+        let moffsets = state.mappings.keys().copied().collect();
+        state.reclaim(moffsets);
+    }
+
+    /// Take ranges reclaimed but not unmapped yet.
+    pub(crate) fn take_reclaimed(&self) -> Vec<RemovemappingOne> {
+        std::mem::take(&mut self.state.lock().unwrap().reclaim)
+    }
+
+    pub(crate) fn stats(&self) -> DaxWindowStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// Get the number of ranges mapped for each inode, ordered by inode number.
+    pub(crate) fn inode_mappings(&self) -> Vec<(u64, usize)> {
+        let state = self.state.lock().unwrap();
+        let mut mappings: Vec<(u64, usize)> = state
+            .inodes
+            .iter()
+            .filter(|(_, inode)| inode.mappings > 0)
+            .map(|(nodeid, inode)| (*nodeid, inode.mappings))
+            .collect();
+        mappings.sort_unstable();
+        mappings
+    }
+
+    // Reclaim ranges of `nodeid` if the guest can't reach it anymore.
+    fn check_inode(&self, state: &mut WindowState, nodeid: u64) {
+        let unreachable = match state.inodes.get(&nodeid) {
+            Some(inode) => inode.nlookup == 0 && inode.opens == 0,
+            None => return,
+        };
+        if unreachable && self.reclaim_on_forget {
+            let moffsets = state
+                .mappings
+                .iter()
+                .filter(|(_, m)| m.nodeid == nodeid)
+                .map(|(moffset, _)| *moffset)
+                .collect();
+            state.reclaim(moffsets);
+        }
+        state.put_inode(nodeid);
+    }
+}
+
+impl<F: FileSystem + Sync> Server<F> {
+    /// Enable accounting of DAX window mappings, to reclaim ranges leaked by guests.
+    ///
+    /// If `reclaim_on_forget` is true, all ranges of an inode get unmapped once the inode has no
+    /// open handles and its lookup count has dropped to zero. All ranges get unmapped when the
+    /// session is destroyed, or disconnected by [Server::reclaim_dax_window]. Reclaimed ranges
+    /// are unmapped through the [FsCacheReqHandler] passed with the next request.
+    pub fn with_dax_accounting(mut self, reclaim_on_forget: bool) -> Self {
+        self.dax = Some(DaxWindow::new(reclaim_on_forget));
+        self
+    }
+
+    /// Get statistics of the DAX window, all zeros if accounting is not enabled.
+    pub fn dax_window_stats(&self) -> DaxWindowStats {
+        self.dax.as_ref().map(|d| d.stats()).unwrap_or_default()
+    }
+
+    /// Get the number of ranges of the DAX window mapped for each inode, as `(inode, count)`
+    /// pairs ordered by inode number.
+    pub fn dax_inode_mappings(&self) -> Vec<(u64, usize)> {
+        self.dax
+            .as_ref()
+            .map(|d| d.inode_mappings())
+            .unwrap_or_default()
+    }
+
+    /// Unmap all ranges of the DAX window mapped through this session.
+    ///
+    /// It should be called when the session gets disconnected without `FUSE_DESTROY`.
+    pub fn reclaim_dax_window(&self, vu_req: &mut dyn FsCacheReqHandler) -> io::Result<()> {
+        if let Some(dax) = self.dax.as_ref() {
+            dax.reclaim_all();
+        }
+        self.unmap_reclaimed(vu_req)
+    }
+
+    // Account a lookup reference of `inode` taken by the guest.
+    pub(super) fn dax_lookup(&self, inode: u64) {
+        if let Some(dax) = self.dax.as_ref() {
+            dax.lookup(inode);
+        }
+    }
+
+    // Account `nlookup` lookup references of `inode` dropped by the guest.
+    pub(super) fn dax_forget(&self, inode: u64, nlookup: u64) {
+        if let Some(dax) = self.dax.as_ref() {
+            dax.forget(inode, nlookup);
+        }
+    }
+
+    // Account a handle of `nodeid` opened by the guest.
+    pub(super) fn dax_open(&self, nodeid: u64) {
+        if let Some(dax) = self.dax.as_ref() {
+            dax.open(nodeid);
+        }
+    }
+
+    // Account a handle of `nodeid` released by the guest.
+    pub(super) fn dax_release(&self, nodeid: u64) {
+        if let Some(dax) = self.dax.as_ref() {
+            dax.release(nodeid);
+        }
+    }
+
+    // Unmap ranges reclaimed by the accounting of DAX window mappings.
+    pub(super) fn unmap_reclaimed(&self, vu_req: &mut dyn FsCacheReqHandler) -> io::Result<()> {
+        let reclaimed = match self.dax.as_ref() {
+            Some(dax) => dax.take_reclaimed(),
+            None => return Ok(()),
+        };
+        if reclaimed.is_empty() {
+            return Ok(());
+        }
+
+        debug!("fuse: reclaim {} ranges of DAX window", reclaimed.len());
+        vu_req.unmap(reclaimed).map_err(|e| {
+            warn!("fuse: failed to reclaim ranges of DAX window, {}", e);
+            e
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dax_window_accounting() {
+        let dax = DaxWindow::new(true);
+        dax.lookup(2);
+        dax.lookup(3);
+        dax.map(2, 0, 0x1000);
+        dax.map(2, 0x1000, 0x1000);
+        dax.map(3, 0x2000, 0x2000);
+        assert_eq!(
+            dax.stats(),
+            DaxWindowStats {
+                bytes_mapped: 0x4000,
+                mappings: 3,
+                inodes: 2,
+                high_water: 0x4000,
+                reclaimed: 0,
+            }
+        );
+        assert_eq!(dax.inode_mappings(), vec![(2, 2), (3, 1)]);
+
+        // Remapping a range replaces overlapped ranges.
+        dax.map(3, 0x800, 0x1000);
+        assert_eq!(dax.inode_mappings(), vec![(3, 2)]);
+        assert_eq!(dax.stats().bytes_mapped, 0x3000);
+
+        dax.unmap(&[RemovemappingOne {
+            moffset: 0x2000,
+            len: 0x2000,
+        }]);
+        assert_eq!(dax.stats().bytes_mapped, 0x1000);
+        assert_eq!(dax.stats().high_water, 0x4000);
+        assert!(dax.take_reclaimed().is_empty());
+    }
+
+    #[test]
+    fn test_dax_window_reclaim_on_forget() {
+        let dax = DaxWindow::new(true);
+        dax.lookup(2);
+        dax.lookup(2);
+        dax.open(2);
+        dax.map(2, 0, 0x1000);
+        dax.map(2, 0x2000, 0x1000);
+
+        dax.forget(2, 1);
+        dax.release(2);
+        assert_eq!(dax.stats().mappings, 2);
+        dax.forget(2, 1);
+        assert_eq!(dax.stats().mappings, 0);
+        let reclaimed: Vec<(u64, u64)> = dax
+            .take_reclaimed()
+            .iter()
+            .map(|r| (r.moffset, r.len))
+            .collect();
+        assert_eq!(reclaimed, vec![(0, 0x1000), (0x2000, 0x1000)]);
+        assert_eq!(dax.stats().reclaimed, 2);
+        assert!(dax.state.lock().unwrap().inodes.is_empty());
+
+        // Ranges are kept until the session gets disconnected if disabled.
+        let dax = DaxWindow::new(false);
+        dax.lookup(2);
+        dax.map(2, 0, 0x1000);
+        dax.forget(2, 1);
+        assert_eq!(dax.stats().mappings, 1);
+        dax.reclaim_all();
+        assert_eq!(dax.take_reclaimed().len(), 1);
+        assert_eq!(dax.stats().bytes_mapped, 0);
+    }
+}
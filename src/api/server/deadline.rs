@@ -0,0 +1,166 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Deadlines of requests per class of opcodes.
+//!
+//! Guests hang on requests of a degraded backend, while they'd rather get a prompt error. With
+//! [Server::with_deadlines], each request of a class with a deadline gets it started when its
+//! header is decoded, and carried to the backend by [Context::deadline], so the backend may pass
+//! the remaining budget on to its own RPCs. Requests whose deadline expires while they wait for
+//! throttling or concurrency limits fail with the configured errno before reaching the backend.
+//!
+//! Futures of asynchronous backends are dropped when the deadline expires, and the request fails
+//! with the configured errno. Synchronous backends can't be preempted, they must check the
+//! deadline themselves. Requests completing late are counted as violations of their class
+//! either way, see [Server::deadline_violations].
+//!
+//! [Context::deadline]: crate::api::filesystem::Context::deadline
+
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use super::concurrency::{opcode_class, METADATA_CLASS, READ_CLASS, WRITE_CLASS};
+use super::{Server, SrvContext};
+use crate::api::filesystem::FileSystem;
+use crate::BitmapSlice;
+
+/// Deadlines of requests per class of opcodes, `None` leaves a class without deadline.
+///
+/// The classes are the ones of [ConcurrencyLimits](super::ConcurrencyLimits), requests outside
+/// of them, like `FUSE_INIT`, `FUSE_FORGET` or `FUSE_RELEASE`, never have a deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestDeadlines {
+    /// Deadline of requests reading data, directories, links or extended attributes.
+    ///
+    /// The default value for this option is `None`.
+    pub read: Option<Duration>,
+    /// Deadline of requests writing or syncing data.
+    ///
+    /// The default value for this option is `None`.
+    pub write: Option<Duration>,
+    /// Deadline of requests looking up, opening, creating, removing or renaming entries, or
+    /// getting and changing their attributes.
+    ///
+    /// The default value for this option is `None`.
+    pub metadata: Option<Duration>,
+    /// Error replied to requests whose deadline expired.
+    ///
+    /// The default value for this option is `ETIMEDOUT`.
+    pub errno: i32,
+}
+
+impl Default for RequestDeadlines {
+    fn default() -> Self {
+        RequestDeadlines {
+            read: None,
+            write: None,
+            metadata: None,
+            errno: libc::ETIMEDOUT,
+        }
+    }
+}
+
+/// Number of requests of each class which completed after their deadline, or failed because it
+/// expired.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlineViolations {
+    /// Violations of requests of the read class.
+    pub read: u64,
+    /// Violations of requests of the write class.
+    pub write: u64,
+    /// Violations of requests of the metadata class.
+    pub metadata: u64,
+}
+
+pub(super) struct DeadlineTracker {
+    deadlines: RequestDeadlines,
+    violations: [AtomicU64; 3],
+}
+
+impl DeadlineTracker {
+    fn budget(&self, class: usize) -> Option<Duration> {
+        match class {
+            READ_CLASS => self.deadlines.read,
+            WRITE_CLASS => self.deadlines.write,
+            _ => self.deadlines.metadata,
+        }
+    }
+}
+
+impl<F: FileSystem + Sync> Server<F> {
+    /// Fail requests not completed within the deadline of their class of opcodes.
+    pub fn with_deadlines(mut self, deadlines: RequestDeadlines) -> Self {
+        self.deadlines = Some(DeadlineTracker {
+            deadlines,
+            violations: Default::default(),
+        });
+        self
+    }
+
+    /// Get the number of requests which missed their deadline, per class.
+    pub fn deadline_violations(&self) -> DeadlineViolations {
+        let count = |class: usize| {
+            self.deadlines
+                .as_ref()
+                .map_or(0, |d| d.violations[class].load(Ordering::Relaxed))
+        };
+        DeadlineViolations {
+            read: count(READ_CLASS),
+            write: count(WRITE_CLASS),
+            metadata: count(METADATA_CLASS),
+        }
+    }
+
+    // Start the deadline of the request decoded into `ctx`, and hand it to the backend.
+    pub(super) fn start_deadline<S: BitmapSlice>(&self, ctx: &mut SrvContext<'_, F, S>) {
+        let tracker = match self.deadlines.as_ref() {
+            Some(t) => t,
+            None => return,
+        };
+        let budget = opcode_class(ctx.in_header.opcode).and_then(|c| tracker.budget(c));
+        ctx.context.deadline = budget.map(|b| Instant::now() + b);
+    }
+
+    // Check whether the request of `opcode` with `deadline` may still be dispatched, or count
+    // the violation and return the error to reply.
+    pub(super) fn deadline_expired(
+        &self,
+        opcode: u32,
+        deadline: Option<Instant>,
+    ) -> Option<io::Error> {
+        if !self.deadline_missed(opcode, deadline) {
+            return None;
+        }
+        let errno = self.deadlines.as_ref().map_or(0, |d| d.deadlines.errno);
+        Some(io::Error::from_raw_os_error(errno))
+    }
+
+    // Count a violation if the request of `opcode` completed after its `deadline`.
+    pub(super) fn deadline_missed(&self, opcode: u32, deadline: Option<Instant>) -> bool {
+        match (self.deadlines.as_ref(), deadline, opcode_class(opcode)) {
+            (Some(tracker), Some(deadline), Some(class)) if Instant::now() >= deadline => {
+                tracker.violations[class].fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Run the backend operation `fut` until `deadline`, fail with the errno of the deadlines once
+    // expired. The violation is counted when the request completes.
+    #[cfg(feature = "async-io")]
+    pub(super) async fn async_deadline<T>(
+        &self,
+        deadline: Option<Instant>,
+        fut: impl std::future::Future<Output = io::Result<T>>,
+    ) -> io::Result<T> {
+        let (errno, deadline) = match (self.deadlines.as_ref(), deadline) {
+            (Some(tracker), Some(deadline)) => (tracker.deadlines.errno, deadline),
+            _ => return fut.await,
+        };
+        tokio::time::timeout_at(deadline.into(), fut)
+            .await
+            .unwrap_or_else(|_| Err(io::Error::from_raw_os_error(errno)))
+    }
+}
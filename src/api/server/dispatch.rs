@@ -0,0 +1,189 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Per-opcode handlers of requests.
+//!
+//! [Server::handle_message] dispatches requests of the opcodes it knows to builtin handlers.
+//! Embedders may replace or wrap the handlers of individual opcodes with a [DispatchTable]
+//! installed by [Server::with_dispatch_table], to trace `FUSE_READ` or to take over `FUSE_FSYNC`
+//! for example, while requests of other opcodes are handled as usual.
+//!
+//! A handler receives the request as an [OpRequest], decodes its arguments like the builtin
+//! handler does, then either replies itself, or calls through to the handler it wraps with
+//! [OpRequest::call_default], which ends with the builtin handler. Handlers are consulted after
+//! the raw handler, custom opcode handlers and concurrency limits, the asynchronous request path
+//! doesn't consult them.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+use vm_memory::ByteValued;
+
+use super::Server;
+use crate::abi::fuse_abi::{InHeader, Opcode};
+use crate::api::filesystem::{Context, FileSystem};
+use crate::Result;
+
+/// Handler of the requests of an opcode.
+pub type OpHandler = Arc<dyn Fn(OpRequest<'_>) -> Result<usize> + Send + Sync>;
+
+// Request being dispatched, with the transport and the server type erased.
+pub(super) trait PendingRequest {
+    fn header(&self) -> &InHeader;
+    fn context(&self) -> &Context;
+    // Fill `buf` with the request body following the header, without consuming it.
+    fn peek(&self, buf: &mut [u8]) -> io::Result<()>;
+    fn body_len(&self) -> usize;
+    fn reply(&mut self, data: &[u8]) -> Result<usize>;
+    fn reply_error(&mut self, err: io::Error) -> Result<usize>;
+    fn no_reply(&mut self) -> Result<usize>;
+    fn builtin(&mut self) -> Result<usize>;
+}
+
+/// A request dispatched to an [OpHandler].
+///
+/// Each request must be consumed by exactly one of the methods replying to it, calling through
+/// to the wrapped handler, or dropping it without reply. Requests left unanswered by handlers
+/// fail with `EIO`.
+pub struct OpRequest<'a> {
+    pending: &'a mut dyn PendingRequest,
+    // Handlers wrapped by the current one, the innermost first.
+    wrapped: &'a [OpHandler],
+}
+
+impl<'a> OpRequest<'a> {
+    pub(super) fn new(pending: &'a mut dyn PendingRequest, wrapped: &'a [OpHandler]) -> Self {
+        OpRequest { pending, wrapped }
+    }
+
+    /// Get the header of the request.
+    pub fn header(&self) -> &InHeader {
+        self.pending.header()
+    }
+
+    /// Get the context of the request.
+    pub fn context(&self) -> &Context {
+        self.pending.context()
+    }
+
+    /// Decode the fixed size arguments of the request, such as `ReadIn` for `FUSE_READ`.
+    ///
+    /// The arguments aren't consumed, so the wrapped handler decodes them again.
+    pub fn args<T: ByteValued>(&self) -> io::Result<T> {
+        let mut args = T::default();
+        self.pending.peek(args.as_mut_slice())?;
+        Ok(args)
+    }
+
+    /// Get the whole request body following the header, such as the arguments followed by the
+    /// name for `FUSE_LOOKUP`.
+    pub fn body(&self) -> io::Result<Vec<u8>> {
+        let mut body = vec![0u8; self.pending.body_len()];
+        self.pending.peek(&mut body)?;
+        Ok(body)
+    }
+
+    /// Reply to the request with `data` following the `OutHeader`.
+    pub fn reply(self, data: &[u8]) -> Result<usize> {
+        self.pending.reply(data)
+    }
+
+    /// Reply to the request with `err`.
+    pub fn reply_error(self, err: io::Error) -> Result<usize> {
+        self.pending.reply_error(err)
+    }
+
+    /// Drop the request without reply, for opcodes like `FUSE_FORGET` the kernel expects no
+    /// reply for.
+    pub fn no_reply(self) -> Result<usize> {
+        self.pending.no_reply()
+    }
+
+    /// Call through to the wrapped handler, or to the builtin handler of the opcode.
+    pub fn call_default(self) -> Result<usize> {
+        match self.wrapped.split_last() {
+            Some((handler, wrapped)) => handler(OpRequest {
+                pending: self.pending,
+                wrapped,
+            }),
+            None => self.pending.builtin(),
+        }
+    }
+}
+
+/// Handlers of opcodes replacing or wrapping the builtin ones, see [DispatchTable::builder].
+#[derive(Clone, Default)]
+pub struct DispatchTable {
+    // Handlers of each opcode, the outermost last.
+    entries: HashMap<u32, Vec<OpHandler>>,
+}
+
+impl DispatchTable {
+    /// Create a builder of a table, where all opcodes have their builtin handler.
+    pub fn builder() -> DispatchTableBuilder {
+        DispatchTableBuilder::default()
+    }
+
+    /// Check whether the builtin handler of `opcode` is replaced or wrapped.
+    pub fn is_customized(&self, opcode: Opcode) -> bool {
+        self.entries.contains_key(&(opcode as u32))
+    }
+
+    pub(super) fn get(&self, opcode: u32) -> Option<&[OpHandler]> {
+        self.entries.get(&opcode).map(|h| h.as_slice())
+    }
+}
+
+/// Builder of a [DispatchTable].
+#[derive(Default)]
+pub struct DispatchTableBuilder {
+    entries: HashMap<u32, Vec<OpHandler>>,
+}
+
+impl DispatchTableBuilder {
+    /// Replace the handler of `opcode` with `handler`, dropping wrappers added before.
+    ///
+    /// [OpRequest::call_default] still reaches the builtin handler from `handler`.
+    pub fn replace<H>(mut self, opcode: Opcode, handler: H) -> Self
+    where
+        H: Fn(OpRequest<'_>) -> Result<usize> + Send + Sync + 'static,
+    {
+        self.entries.insert(opcode as u32, vec![Arc::new(handler)]);
+        self
+    }
+
+    /// Wrap the handler of `opcode` with `handler`, which calls through to it with
+    /// [OpRequest::call_default].
+    ///
+    /// Wrappers added later are called first.
+    pub fn wrap<H>(mut self, opcode: Opcode, handler: H) -> Self
+    where
+        H: Fn(OpRequest<'_>) -> Result<usize> + Send + Sync + 'static,
+    {
+        self.entries
+            .entry(opcode as u32)
+            .or_default()
+            .push(Arc::new(handler));
+        self
+    }
+
+    /// Build the table.
+    pub fn build(self) -> DispatchTable {
+        DispatchTable {
+            entries: self.entries,
+        }
+    }
+}
+
+impl<F: FileSystem + Sync> Server<F> {
+    /// Dispatch requests through `table`, replacing or wrapping the builtin handlers of some
+    /// opcodes.
+    ///
+    /// Replacing the handlers of `FUSE_INIT`, `FUSE_DESTROY` or `FUSE_FORGET` bypasses the
+    /// protocol state kept by the server, wrap them instead.
+    pub fn with_dispatch_table(mut self, table: DispatchTable) -> Self {
+        self.dispatch = Some(Arc::new(table));
+        self
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Offloading of forget messages to a dedicated worker.
+//!
+//! When the guest drops caches, the kernel may emit bursts of hundreds of thousands of
+//! `FUSE_FORGET` and `FUSE_BATCH_FORGET` messages. Handling them inline delays reading the
+//! latency sensitive requests queued behind them. With offloading enabled, the server just copies
+//! the forgets decoded from the message into a bounded [ForgetQueue] and goes on reading, while a
+//! low priority worker thread drains the queue by [FileSystem::batch_forget]. When the queue is
+//! full, forgets are handled inline, so they never get dropped.
+
+use std::collections::VecDeque;
+use std::mem;
+use std::sync::{Condvar, Mutex};
+
+use super::Server;
+use crate::api::filesystem::{Context, FileSystem};
+
+// Nice value of the forget worker thread.
+#[cfg(target_os = "linux")]
+const FORGET_WORKER_NICE: libc::c_int = 10;
+
+#[derive(Default)]
+struct ForgetState {
+    // Batches of `(nodeid, nlookup)` pairs, one per forget message.
+    batches: VecDeque<Vec<(u64, u64)>>,
+    stopped: bool,
+}
+
+/// Bounded queue of forget messages waiting for the forget worker.
+pub(crate) struct ForgetQueue {
+    capacity: usize,
+    state: Mutex<ForgetState>,
+    cond: Condvar,
+}
+
+impl ForgetQueue {
+    pub(crate) fn new(capacity: usize) -> Self {
+        ForgetQueue {
+            capacity,
+            state: Mutex::new(ForgetState::default()),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Queue forgets of a message, give them back if the queue is full or stopped.
+    pub(crate) fn push(&self, forgets: Vec<(u64, u64)>) -> Result<(), Vec<(u64, u64)>> {
+        let mut state = self.state.lock().unwrap();
+        if state.stopped || state.batches.len() >= self.capacity {
+            return Err(forgets);
+        }
+        state.batches.push_back(forgets);
+        self.cond.notify_one();
+        Ok(())
+    }
+
+    /// Take all queued forgets, waiting for some if `wait` is true.
+    ///
+    /// Return `None` if there's nothing queued and the queue is stopped or `wait` is false.
+    fn pop_all(&self, wait: bool) -> Option<Vec<(u64, u64)>> {
+        let mut state = self.state.lock().unwrap();
+        while wait && state.batches.is_empty() && !state.stopped {
+            state = self.cond.wait(state).unwrap();
+        }
+        let mut batches = mem::take(&mut state.batches);
+        drop(state);
+
+        let mut forgets = batches.pop_front()?;
+        for batch in batches {
+            forgets.extend(batch);
+        }
+        Some(forgets)
+    }
+
+    fn stop(&self) {
+        self.state.lock().unwrap().stopped = true;
+        self.cond.notify_all();
+    }
+
+    /// Get the number of messages queued.
+    pub(crate) fn len(&self) -> usize {
+        self.state.lock().unwrap().batches.len()
+    }
+}
+
+impl<F: FileSystem + Sync> Server<F> {
+    /// Offload forget messages to a dedicated worker, queuing at most `capacity` messages.
+    ///
+    /// Forgets get handled by [Server::run_forget_worker], which must be invoked on a dedicated
+    /// thread. Forgets are handled inline when the queue is full or the worker has been stopped.
+    pub fn with_forget_offload(mut self, capacity: usize) -> Self {
+        self.forgets = Some(ForgetQueue::new(capacity));
+        self
+    }
+
+    /// Handle offloaded forget messages until [Server::stop_forget_worker] is called.
+    ///
+    /// The calling thread gets a lower scheduling priority, so forget floods don't compete with
+    /// other requests. Return immediately if forget offloading is not enabled.
+    pub fn run_forget_worker(&self) {
+        let queue = match self.forgets.as_ref() {
+            Some(q) => q,
+            None => return,
+        };
+
+        #[cfg(target_os = "linux")]
+        // Safe because this doesn't modify any memory and we check the return value.
+        unsafe {
+            let tid = libc::syscall(libc::SYS_gettid) as libc::id_t;
+            if libc::setpriority(libc::PRIO_PROCESS, tid, FORGET_WORKER_NICE) < 0 {
+                debug!(
+                    "fuse: failed to lower priority of forget worker, {}",
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+
+        while let Some(forgets) = queue.pop_all(true) {
+            self.do_batch_forget(forgets);
+        }
+    }
+
+    /// Stop the forget worker after handling queued forgets, later forgets are handled inline.
+    pub fn stop_forget_worker(&self) {
+        if let Some(queue) = self.forgets.as_ref() {
+            queue.stop();
+        }
+    }
+
+    /// Get the number of forget messages waiting for the forget worker.
+    pub fn pending_forgets(&self) -> usize {
+        self.forgets.as_ref().map(|q| q.len()).unwrap_or(0)
+    }
+
+    // Queue forgets for the forget worker, give them back if they must be handled inline.
+    pub(super) fn offload_forgets(&self, forgets: Vec<(u64, u64)>) -> Option<Vec<(u64, u64)>> {
+        match self.forgets.as_ref() {
+            Some(queue) => queue.push(forgets).err(),
+            None => Some(forgets),
+        }
+    }
+
+    // Handle forgets still queued, so they reach the filesystem driver before it gets destroyed.
+    pub(super) fn flush_forgets(&self) {
+        if let Some(forgets) = self.forgets.as_ref().and_then(|q| q.pop_all(false)) {
+            self.do_batch_forget(forgets);
+        }
+    }
+
+    fn do_batch_forget(&self, forgets: Vec<(u64, u64)>) {
+        let forgets = forgets
+            .into_iter()
+            .map(|(nodeid, nlookup)| (nodeid.into(), nlookup))
+            .collect();
+        self.fs.batch_forget(&Context::default(), forgets);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forget_queue() {
+        let queue = ForgetQueue::new(2);
+        assert!(queue.pop_all(false).is_none());
+
+        queue.push(vec![(1, 1)]).unwrap();
+        queue.push(vec![(2, 1), (3, 2)]).unwrap();
+        assert_eq!(queue.push(vec![(4, 1)]), Err(vec![(4, 1)]));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.pop_all(true), Some(vec![(1, 1), (2, 1), (3, 2)]));
+        assert_eq!(queue.len(), 0);
+
+        queue.push(vec![(4, 1)]).unwrap();
+        queue.stop();
+        assert_eq!(queue.push(vec![(5, 1)]), Err(vec![(5, 1)]));
+        assert_eq!(queue.pop_all(true), Some(vec![(4, 1)]));
+        assert_eq!(queue.pop_all(true), None);
+    }
+}
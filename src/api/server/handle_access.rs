@@ -0,0 +1,127 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Enforcement of the access mode of file handles at the server boundary.
+//!
+//! A buggy or malicious Fuse client may send `FUSE_WRITE` against a handle opened with
+//! `O_RDONLY`, or `FUSE_READ` against a handle opened with `O_WRONLY`. The [HandleAccess] table
+//! records the access mode of handles from open/create requests, so mismatched requests can be
+//! rejected with `EBADF` before they reach filesystem drivers which forget to check.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Max number of handles tracked, requests against untracked handles are not checked.
+const MAX_TRACKED_HANDLES: usize = 1 << 18;
+
+/// Kind of access to the data of an open file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Access {
+    Read,
+    Write,
+}
+
+pub(crate) struct HandleAccess {
+    // Access mode of handles, keyed by `(nodeid, fh)`.
+    modes: Mutex<HashMap<(u64, u64), u32>>,
+    writeback: AtomicBool,
+    overflowed: AtomicBool,
+}
+
+impl HandleAccess {
+    pub(crate) fn new() -> Self {
+        HandleAccess {
+            modes: Mutex::new(HashMap::new()),
+            writeback: AtomicBool::new(false),
+            overflowed: AtomicBool::new(false),
+        }
+    }
+
+    /// Record whether writeback cache is enabled, the kernel then reads pages through handles
+    /// opened with `O_WRONLY` to fill partially written pages.
+    pub(crate) fn set_writeback(&self, enabled: bool) {
+        self.writeback.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Record the access mode of handle `fh` of `nodeid`, opened with `flags`.
+    pub(crate) fn open(&self, nodeid: u64, fh: u64, flags: u32) {
+        let mut modes = self.modes.lock().unwrap();
+        if modes.len() >= MAX_TRACKED_HANDLES && !modes.contains_key(&(nodeid, fh)) {
+            if !self.overflowed.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "handle access: more than {} open handles, stop tracking new ones",
+                    MAX_TRACKED_HANDLES
+                );
+            }
+            return;
+        }
+        modes.insert((nodeid, fh), flags & libc::O_ACCMODE as u32);
+    }
+
+    /// Forget handle `fh` of `nodeid` on release.
+    pub(crate) fn release(&self, nodeid: u64, fh: u64) {
+        self.modes.lock().unwrap().remove(&(nodeid, fh));
+    }
+
+    /// Check whether handle `fh` of `nodeid` allows `access`.
+    pub(crate) fn check(&self, nodeid: u64, fh: u64, access: Access) -> io::Result<()> {
+        let mode = match self.modes.lock().unwrap().get(&(nodeid, fh)) {
+            Some(mode) => *mode as i32,
+            None => return Ok(()),
+        };
+        let allowed = match access {
+            Access::Read => mode != libc::O_WRONLY || self.writeback.load(Ordering::Relaxed),
+            Access::Write => mode != libc::O_RDONLY,
+        };
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(io::Error::from_raw_os_error(libc::EBADF))
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.modes.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::errno::errno_of;
+
+    fn errno(res: io::Result<()>) -> Option<i32> {
+        res.err().and_then(|e| errno_of(&e))
+    }
+
+    #[test]
+    fn test_handle_access() {
+        let access = HandleAccess::new();
+        access.open(1, 10, libc::O_RDONLY as u32);
+        access.open(1, 11, (libc::O_WRONLY | libc::O_APPEND) as u32);
+        access.open(2, 10, libc::O_RDWR as u32);
+        assert_eq!(access.len(), 3);
+
+        assert!(access.check(1, 10, Access::Read).is_ok());
+        assert_eq!(errno(access.check(1, 10, Access::Write)), Some(libc::EBADF));
+        assert_eq!(errno(access.check(1, 11, Access::Read)), Some(libc::EBADF));
+        assert!(access.check(1, 11, Access::Write).is_ok());
+        assert!(access.check(2, 10, Access::Read).is_ok());
+        assert!(access.check(2, 10, Access::Write).is_ok());
+        // Untracked handles are not checked.
+        assert!(access.check(3, 10, Access::Write).is_ok());
+
+        access.set_writeback(true);
+        assert!(access.check(1, 11, Access::Read).is_ok());
+
+        access.release(1, 10);
+        assert!(access.check(1, 10, Access::Write).is_ok());
+        access.release(1, 11);
+        access.release(2, 10);
+        assert_eq!(access.len(), 0);
+    }
+}
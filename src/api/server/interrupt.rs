@@ -0,0 +1,96 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Interruption of in-flight requests.
+//!
+//! When a process waiting for a Fuse request gets a signal, the kernel sends a `FUSE_INTERRUPT`
+//! message carrying the unique ID of the original request. Without interrupt support a blocked
+//! request, like a `setlkw` or a read from a hung remote file system, can't be cancelled and the
+//! process can't be killed.
+//!
+//! With interrupt support enabled, the server tracks the unique IDs of requests being handled.
+//! An interrupt of a tracked request is delivered by [FileSystem::interrupt], and the driver may
+//! reply `EINTR` to the original request by failing it. An interrupt of a request the server
+//! doesn't know about, because it hasn't been received yet or has already completed, gets an
+//! `EAGAIN` reply, so the kernel queues the interrupt again in the former case and ignores it in
+//! the latter one.
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use super::Server;
+use crate::abi::fuse_abi::{InHeader, Opcode};
+use crate::api::filesystem::FileSystem;
+
+/// Unique IDs of requests which may be interrupted.
+#[derive(Default)]
+pub(crate) struct InterruptRegistry {
+    inflight: Mutex<HashSet<u64>>,
+}
+
+impl InterruptRegistry {
+    /// Track request `unique` until the returned guard gets dropped.
+    pub(crate) fn enter(&self, unique: u64) -> InterruptGuard<'_> {
+        self.inflight.lock().unwrap().insert(unique);
+        InterruptGuard {
+            registry: self,
+            unique,
+        }
+    }
+
+    /// Check whether request `unique` is being handled.
+    pub(crate) fn contains(&self, unique: u64) -> bool {
+        self.inflight.lock().unwrap().contains(&unique)
+    }
+}
+
+pub(crate) struct InterruptGuard<'a> {
+    registry: &'a InterruptRegistry,
+    unique: u64,
+}
+
+impl Drop for InterruptGuard<'_> {
+    fn drop(&mut self) {
+        self.registry.inflight.lock().unwrap().remove(&self.unique);
+    }
+}
+
+impl<F: FileSystem + Sync> Server<F> {
+    /// Deliver `FUSE_INTERRUPT` messages to the filesystem driver by [FileSystem::interrupt].
+    ///
+    /// Interrupts are ignored by default, and interrupted requests complete as usual.
+    pub fn with_interrupts(mut self) -> Self {
+        self.interrupts = Some(InterruptRegistry::default());
+        self
+    }
+
+    // Track a request so it may be interrupted, until the returned guard gets dropped.
+    pub(super) fn interruptible(&self, in_header: &InHeader) -> Option<InterruptGuard<'_>> {
+        if in_header.opcode == Opcode::Interrupt as u32 {
+            return None;
+        }
+        self.interrupts.as_ref().map(|i| i.enter(in_header.unique))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interrupt_registry() {
+        let registry = InterruptRegistry::default();
+        assert!(!registry.contains(2));
+
+        let guard = registry.enter(2);
+        let other = registry.enter(4);
+        assert!(registry.contains(2));
+        assert!(registry.contains(4));
+
+        drop(guard);
+        assert!(!registry.contains(2));
+        assert!(registry.contains(4));
+        drop(other);
+        assert!(!registry.contains(4));
+    }
+}
@@ -0,0 +1,234 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Cross-session directory entry invalidation.
+//!
+//! When multiple sessions (guests) are served from the same backend filesystem, changes made
+//! through one session are only seen by other sessions after their entry timeouts expire. The
+//! [InvalidationBus] is an in-process pub/sub channel to propagate namespace changes between
+//! sessions, so that other sessions may send `FUSE_NOTIFY_INVAL_ENTRY` to their guests.
+//!
+//! Subscribers created by [InvalidationBus::subscribe_notifier] get events sent to the guest
+//! right away by their [Notifier], like a [FuseDevNotifier] of the session. Others queue events
+//! until the transport takes them with [InvalidationSubscriber::take]. Queues are bounded, events
+//! are dropped and accounted when a subscriber falls behind.
+//!
+//! [FuseDevNotifier]: crate::transport::FuseDevNotifier
+
+use std::collections::VecDeque;
+use std::ffi::{CStr, CString};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::api::attr_cache::Notifier;
+
+/// An event to invalidate the directory entry `name` under directory `parent`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalEntryEvent {
+    /// Inode number of the parent directory.
+    pub parent: u64,
+    /// Name of the directory entry.
+    pub name: CString,
+}
+
+struct Subscriber {
+    id: u64,
+    backend: u64,
+    queue: Mutex<VecDeque<InvalEntryEvent>>,
+    notifier: Option<Arc<dyn Notifier>>,
+}
+
+/// In-process pub/sub channel to propagate directory entry invalidations between sessions.
+///
+/// Subscribers are keyed by a backend identifier, events published by a subscriber are only
+/// delivered to other subscribers of the same backend.
+pub struct InvalidationBus {
+    capacity: usize,
+    next_id: AtomicU64,
+    dropped: AtomicU64,
+    subscribers: RwLock<Vec<Arc<Subscriber>>>,
+}
+
+impl InvalidationBus {
+    /// Create a new bus, with at most `capacity` pending events per subscriber.
+    pub fn new(capacity: usize) -> Arc<Self> {
+        Arc::new(InvalidationBus {
+            capacity: capacity.max(1),
+            next_id: AtomicU64::new(1),
+            dropped: AtomicU64::new(0),
+            subscribers: RwLock::new(Vec::new()),
+        })
+    }
+
+    /// Subscribe to events of backend `backend`.
+    pub fn subscribe(self: &Arc<Self>, backend: u64) -> InvalidationSubscriber {
+        self.add_subscriber(backend, None)
+    }
+
+    /// Subscribe to events of backend `backend`, sent to the guest by `notifier` when published.
+    pub fn subscribe_notifier(
+        self: &Arc<Self>,
+        backend: u64,
+        notifier: Arc<dyn Notifier>,
+    ) -> InvalidationSubscriber {
+        self.add_subscriber(backend, Some(notifier))
+    }
+
+    fn add_subscriber(
+        self: &Arc<Self>,
+        backend: u64,
+        notifier: Option<Arc<dyn Notifier>>,
+    ) -> InvalidationSubscriber {
+        let sub = Arc::new(Subscriber {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            backend,
+            queue: Mutex::new(VecDeque::new()),
+            notifier,
+        });
+        self.subscribers.write().unwrap().push(sub.clone());
+
+        InvalidationSubscriber {
+            bus: self.clone(),
+            sub,
+        }
+    }
+
+    /// Get the number of events dropped due to overloaded subscribers.
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn publish(&self, from: &Subscriber, parent: u64, name: &CStr) {
+        let mut notifiers = Vec::new();
+        let subscribers = self.subscribers.read().unwrap();
+        for sub in subscribers
+            .iter()
+            .filter(|s| s.backend == from.backend && s.id != from.id)
+        {
+            if let Some(notifier) = sub.notifier.as_ref() {
+                notifiers.push(notifier.clone());
+                continue;
+            }
+            let mut queue = sub.queue.lock().unwrap();
+            if queue.len() >= self.capacity {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            } else {
+                queue.push_back(InvalEntryEvent {
+                    parent,
+                    name: name.to_owned(),
+                });
+            }
+        }
+        drop(subscribers);
+
+        // Notifiers may write to the guest, don't hold the lock meanwhile.
+        for notifier in notifiers {
+            notifier.inval_entry(parent, name);
+        }
+    }
+
+    fn unsubscribe(&self, id: u64) {
+        self.subscribers.write().unwrap().retain(|s| s.id != id);
+    }
+}
+
+/// A subscription to an [InvalidationBus], unsubscribed when dropped.
+pub struct InvalidationSubscriber {
+    bus: Arc<InvalidationBus>,
+    sub: Arc<Subscriber>,
+}
+
+impl InvalidationSubscriber {
+    /// Publish an invalidation event to other subscribers of the same backend.
+    pub fn publish(&self, parent: u64, name: &CStr) {
+        self.bus.publish(&self.sub, parent, name);
+    }
+
+    /// Take all pending events published by other subscribers.
+    ///
+    /// Subscribers with a notifier never have pending events.
+    pub fn take(&self) -> Vec<InvalEntryEvent> {
+        self.sub.queue.lock().unwrap().drain(..).collect()
+    }
+}
+
+impl Drop for InvalidationSubscriber {
+    fn drop(&mut self) {
+        self.bus.unsubscribe(self.sub.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalidation_bus() {
+        let bus = InvalidationBus::new(2);
+        let a = bus.subscribe(1);
+        let b = bus.subscribe(1);
+        let c = bus.subscribe(2);
+        let name = CString::new("file").unwrap();
+
+        a.publish(1, &name);
+        assert!(a.take().is_empty());
+        assert!(c.take().is_empty());
+        assert_eq!(
+            b.take(),
+            vec![InvalEntryEvent {
+                parent: 1,
+                name: name.clone()
+            }]
+        );
+
+        // Overloaded subscriber drops events.
+        a.publish(1, &name);
+        a.publish(2, &name);
+        a.publish(3, &name);
+        assert_eq!(bus.dropped_events(), 1);
+        let events = b.take();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].parent, 1);
+        assert_eq!(events[1].parent, 2);
+
+        drop(b);
+        a.publish(1, &name);
+        assert_eq!(bus.dropped_events(), 1);
+        assert_eq!(bus.subscribers.read().unwrap().len(), 2);
+    }
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<(u64, CString)>>);
+
+    impl Notifier for Recorder {
+        fn inval_inode(&self, _ino: u64) {}
+
+        fn inval_entry(&self, parent: u64, name: &CStr) {
+            self.0.lock().unwrap().push((parent, name.to_owned()));
+        }
+    }
+
+    #[test]
+    fn test_invalidation_bus_notifier() {
+        let bus = InvalidationBus::new(1);
+        let recorder = Arc::new(Recorder::default());
+        let a = bus.subscribe(1);
+        let b = bus.subscribe_notifier(1, recorder.clone());
+        let name = CString::new("file").unwrap();
+
+        // Events are sent right away, without bounds, and never queued.
+        a.publish(1, &name);
+        a.publish(2, &name);
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![(1, name.clone()), (2, name.clone())]
+        );
+        assert!(b.take().is_empty());
+        assert_eq!(bus.dropped_events(), 0);
+
+        // Events published by the notified subscriber are queued for others.
+        b.publish(3, &name);
+        assert_eq!(a.take().len(), 1);
+        assert_eq!(recorder.0.lock().unwrap().len(), 2);
+    }
+}
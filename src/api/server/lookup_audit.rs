@@ -0,0 +1,156 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Strict accounting of inode lookup counts, to detect forget/nlookup bugs in filesystem drivers.
+//!
+//! Every reply conveying an `Entry` to the Fuse client increases the lookup count of the inode by
+//! one, and `FORGET`/`BATCH_FORGET` requests decrease it by `nlookup`. Filesystem drivers must
+//! keep the inode alive until its lookup count drops to zero, getting it wrong leads to slow
+//! memory leaks or to use-after-free of inodes. The [LookupAudit] tracks the expected lookup
+//! counts at the server boundary, independently of the filesystem driver's own accounting, so the
+//! two may be compared.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// An inode whose lookup count reported by the filesystem driver diverges from the expected one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LookupDivergence {
+    /// Inode number.
+    pub inode: u64,
+    /// Lookup count expected from the requests handled by the server.
+    pub expected: u64,
+    /// Lookup count reported by the filesystem driver.
+    pub reported: u64,
+}
+
+pub(crate) struct LookupAudit {
+    assert_on_destroy: bool,
+    counts: Mutex<HashMap<u64, u64>>,
+}
+
+impl LookupAudit {
+    pub(crate) fn new(assert_on_destroy: bool) -> Self {
+        LookupAudit {
+            assert_on_destroy,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Account an `Entry` for `inode` sent to the client.
+    pub(crate) fn lookup(&self, inode: u64) {
+        if inode != 0 {
+            *self.counts.lock().unwrap().entry(inode).or_insert(0) += 1;
+        }
+    }
+
+    /// Account a forget request for `inode`.
+    pub(crate) fn forget(&self, inode: u64, nlookup: u64) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&inode) {
+            if *count > nlookup {
+                *count -= nlookup;
+            } else {
+                if *count < nlookup {
+                    warn!(
+                        "lookup audit: forget {} lookups of inode {} with {} lookups",
+                        nlookup, inode, count
+                    );
+                }
+                counts.remove(&inode);
+            }
+        } else {
+            warn!("lookup audit: forget unknown inode {}", inode);
+        }
+    }
+
+    /// Get a snapshot of inodes with non-zero expected lookup counts, ordered by inode number.
+    pub(crate) fn counts(&self) -> Vec<(u64, u64)> {
+        let mut counts: Vec<(u64, u64)> = self
+            .counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (*k, *v))
+            .collect();
+        counts.sort_unstable();
+        counts
+    }
+
+    /// Compare expected lookup counts with the counts reported by `nlookup`.
+    ///
+    /// Inodes for which `nlookup` returns `None` are not audited.
+    pub(crate) fn audit<N: Fn(u64) -> Option<u64>>(&self, nlookup: N) -> Vec<LookupDivergence> {
+        self.counts()
+            .into_iter()
+            .filter_map(|(inode, expected)| match nlookup(inode) {
+                Some(reported) if reported != expected => Some(LookupDivergence {
+                    inode,
+                    expected,
+                    reported,
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Called when the filesystem gets destroyed, optionally asserting all counts are zero.
+    pub(crate) fn destroy(&self) {
+        let leaked = self.counts();
+        self.counts.lock().unwrap().clear();
+        if self.assert_on_destroy {
+            assert!(
+                leaked.is_empty(),
+                "lookup audit: inodes not forgotten on destroy (inode, nlookup): {:?}",
+                leaked
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_audit() {
+        let audit = LookupAudit::new(true);
+
+        audit.lookup(0);
+        audit.lookup(2);
+        audit.lookup(2);
+        audit.lookup(3);
+        assert_eq!(audit.counts(), vec![(2, 2), (3, 1)]);
+
+        let divergences = audit.audit(|inode| match inode {
+            2 => Some(1),
+            _ => None,
+        });
+        assert_eq!(
+            divergences,
+            vec![LookupDivergence {
+                inode: 2,
+                expected: 2,
+                reported: 1,
+            }]
+        );
+
+        audit.forget(2, 1);
+        audit.forget(3, 1);
+        audit.forget(4, 1);
+        assert_eq!(audit.counts(), vec![(2, 1)]);
+        assert!(audit.audit(|_| Some(1)).is_empty());
+
+        audit.forget(2, 5);
+        audit.destroy();
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_lookup_audit_leak_on_destroy() {
+        let audit = LookupAudit::new(true);
+
+        audit.lookup(2);
+        audit.destroy();
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Metrics hooks around request handling.
+//!
+//! A [MetricsHook] gets notified before a request is dispatched and after its reply has been
+//! written to the transport layer, so daemons may collect per-opcode latency and error rate
+//! metrics without patching the server. The hook is invoked for every request with a valid
+//! header, including requests failed by the server itself and requests which don't expect a
+//! reply, like `FUSE_FORGET`.
+
+use std::sync::{Arc, Mutex};
+
+use super::Server;
+use crate::abi::fuse_abi::{InHeader, OutHeader};
+use crate::api::filesystem::FileSystem;
+
+/// Provide concrete backend filesystem a way to catch information/metrics from fuse.
+///
+/// Hooks are shared by all threads handling requests, so they must be `Send + Sync`.
+pub trait MetricsHook: Send + Sync {
+    /// `collect()` will be invoked before the real request is processed
+    fn collect(&self, ih: &InHeader);
+    /// `release()` will be invoked after the real request is processed
+    ///
+    /// `oh` is the header of the reply sent to the transport layer, or `None` if the request
+    /// doesn't get a reply.
+    fn release(&self, oh: Option<&OutHeader>);
+}
+
+/// Header of the reply to a request, shared with the request context.
+#[derive(Default)]
+pub(crate) struct ReplyRecorder {
+    header: Mutex<Option<OutHeader>>,
+}
+
+impl ReplyRecorder {
+    /// Remember `header` as the reply to the request.
+    pub(crate) fn record(&self, header: &OutHeader) {
+        *self.header.lock().unwrap() = Some(*header);
+    }
+
+    fn take(&self) -> Option<OutHeader> {
+        self.header.lock().unwrap().take()
+    }
+}
+
+/// Invoke [MetricsHook::release] with the recorded reply header when dropped.
+pub(crate) struct MetricsGuard<'a> {
+    hook: &'a dyn MetricsHook,
+    reply: Arc<ReplyRecorder>,
+}
+
+impl MetricsGuard<'_> {
+    pub(crate) fn recorder(&self) -> Arc<ReplyRecorder> {
+        self.reply.clone()
+    }
+}
+
+impl Drop for MetricsGuard<'_> {
+    fn drop(&mut self) {
+        self.hook.release(self.reply.take().as_ref());
+    }
+}
+
+impl<F: FileSystem + Sync> Server<F> {
+    /// Notify `hook` of every request handled by the server.
+    ///
+    /// A hook passed to [Server::handle_message] takes precedence over the registered one.
+    pub fn with_metrics_hook(mut self, hook: Arc<dyn MetricsHook>) -> Self {
+        self.metrics = Some(hook);
+        self
+    }
+
+    // Invoke `collect()` of the effective hook, which gets released when the guard is dropped.
+    pub(super) fn metrics_guard<'a>(
+        &'a self,
+        hook: Option<&'a dyn MetricsHook>,
+        in_header: &InHeader,
+    ) -> Option<MetricsGuard<'a>> {
+        let hook = hook.or(self.metrics.as_deref())?;
+        hook.collect(in_header);
+        Some(MetricsGuard {
+            hook,
+            reply: Arc::new(ReplyRecorder::default()),
+        })
+    }
+}
+
+#[cfg(all(test, feature = "fusedev"))]
+mod tests {
+    use super::*;
+    use crate::abi::fuse_abi::Opcode;
+    use crate::api::filesystem::Context;
+    use std::collections::HashMap;
+    use std::io;
+    use std::time::{Duration, Instant};
+
+    // Count requests and errors per opcode, and track the maximum latency.
+    #[derive(Default)]
+    struct CountingHook {
+        pending: Mutex<Vec<(u32, Instant)>>,
+        requests: Mutex<HashMap<u32, u64>>,
+        errors: Mutex<HashMap<u32, u64>>,
+        no_reply: Mutex<u64>,
+        max_latency: Mutex<Duration>,
+    }
+
+    impl MetricsHook for CountingHook {
+        fn collect(&self, ih: &InHeader) {
+            *self.requests.lock().unwrap().entry(ih.opcode).or_default() += 1;
+            self.pending
+                .lock()
+                .unwrap()
+                .push((ih.opcode, Instant::now()));
+        }
+
+        fn release(&self, oh: Option<&OutHeader>) {
+            let (opcode, start) = self.pending.lock().unwrap().pop().unwrap();
+            let mut max = self.max_latency.lock().unwrap();
+            *max = (*max).max(start.elapsed());
+            match oh {
+                Some(oh) if oh.error != 0 => {
+                    *self.errors.lock().unwrap().entry(opcode).or_default() += 1
+                }
+                Some(_) => {}
+                None => *self.no_reply.lock().unwrap() += 1,
+            }
+        }
+    }
+
+    struct SlowFs;
+
+    impl FileSystem for SlowFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn getattr(
+            &self,
+            _ctx: &Context,
+            inode: u64,
+            _handle: Option<u64>,
+        ) -> io::Result<(libc::stat64, Duration)> {
+            std::thread::sleep(Duration::from_millis(20));
+            if inode == 1 {
+                Ok((unsafe { std::mem::zeroed() }, Duration::from_secs(1)))
+            } else {
+                Err(io::Error::from_raw_os_error(libc::ENOENT))
+            }
+        }
+    }
+
+    #[test]
+    fn test_server_metrics_hook() {
+        use crate::abi::fuse_abi::{ForgetIn, GetattrIn};
+        use crate::transport::{FuseBuf, FuseDevWriter, Reader};
+        use std::mem::size_of;
+        use std::os::unix::io::AsRawFd;
+        use vm_memory::ByteValued;
+
+        let request = |server: &Server<SlowFs>, opcode: Opcode, nodeid: u64, body: &[u8]| {
+            let in_header = InHeader {
+                len: (size_of::<InHeader>() + body.len()) as u32,
+                opcode: opcode as u32,
+                unique: 1,
+                nodeid,
+                ..Default::default()
+            };
+            let mut r_buf = in_header.as_slice().to_vec();
+            r_buf.extend_from_slice(body);
+            let r = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut r_buf)).unwrap();
+            let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+            let mut w_buf = vec![0x0u8; 1024];
+            let w = FuseDevWriter::<()>::new(file.as_raw_fd(), &mut w_buf)
+                .unwrap()
+                .into();
+            server.handle_message(r, w, None, None).unwrap();
+        };
+
+        let hook = Arc::new(CountingHook::default());
+        let server = Server::new(SlowFs).with_metrics_hook(hook.clone());
+        let getattr = GetattrIn::default();
+        request(&server, Opcode::Getattr, 1, getattr.as_slice());
+        request(&server, Opcode::Getattr, 2, getattr.as_slice());
+        request(
+            &server,
+            Opcode::Forget,
+            1,
+            ForgetIn { nlookup: 1 }.as_slice(),
+        );
+        request(&server, Opcode::Readlink, 1, &[]);
+
+        let requests = hook.requests.lock().unwrap();
+        assert_eq!(requests.get(&(Opcode::Getattr as u32)), Some(&2));
+        assert_eq!(requests.get(&(Opcode::Forget as u32)), Some(&1));
+        assert_eq!(requests.get(&(Opcode::Readlink as u32)), Some(&1));
+        let errors = hook.errors.lock().unwrap();
+        assert_eq!(errors.get(&(Opcode::Getattr as u32)), Some(&1));
+        assert_eq!(errors.get(&(Opcode::Readlink as u32)), Some(&1));
+        assert_eq!(*hook.no_reply.lock().unwrap(), 1);
+        assert!(hook.pending.lock().unwrap().is_empty());
+        assert!(*hook.max_latency.lock().unwrap() >= Duration::from_millis(20));
+    }
+}
@@ -16,22 +16,95 @@
 //! The Fuse API server is performance critical, so it's designed to support multi-threading by
 //! adopting interior-mutability. And the arcswap crate is used to implement interior-mutability.
 
+use std::cmp;
+use std::collections::HashMap;
 use std::ffi::CStr;
-use std::io::{self, Read};
+use std::io::{self, Read, Write};
 use std::marker::PhantomData;
 use std::mem::size_of;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::RawFd;
 use std::sync::Arc;
 
-use arc_swap::ArcSwap;
+use arc_swap::{ArcSwap, ArcSwapOption};
 
+use crate::abi::codec;
 use crate::abi::fuse_abi::*;
-use crate::api::filesystem::{Context, FileSystem, ZeroCopyReader, ZeroCopyWriter};
-use crate::transport::{FileReadWriteVolatile, Reader, Writer};
-use crate::{bytes_to_cstr, BitmapSlice, Error, Result};
+use crate::api::accounting::WriteAccounting;
+use crate::api::attr_cache::Notifier;
+use crate::api::clock::{Clock, SystemClock};
+use crate::api::filesystem::{Context, FileSystem, RawFileSystem, ZeroCopyReader, ZeroCopyWriter};
+use crate::api::scratch;
+use crate::api::shedder::DrainShedder;
+use crate::transport::{pagesize, FileReadWriteVolatile, Reader, Writer};
+use crate::{BitmapSlice, Error, Result};
 
 #[cfg(feature = "async-io")]
 mod async_io;
+mod compat;
+mod concurrency;
+mod connection;
+#[cfg(feature = "virtiofs")]
+mod dax_window;
+mod deadline;
+mod dispatch;
+mod forget_queue;
+mod handle_access;
+mod interrupt;
+mod invalidation;
+mod lookup_audit;
+mod metrics;
+mod notify;
+mod opcode_ext;
+mod poll;
+mod profiler;
+mod retry;
+mod scheduler;
+mod shutdown;
+#[cfg(feature = "tracing")]
+mod span;
 mod sync_io;
+mod throttle;
+mod watchdog;
+#[cfg(feature = "async-io")]
+mod write_barrier;
+mod xattr_limits;
+
+pub use compat::ProtocolFeature;
+use concurrency::ConcurrencyLimiter;
+pub use concurrency::ConcurrencyLimits;
+pub use connection::ConnectionInfo;
+#[cfg(feature = "virtiofs")]
+use dax_window::DaxWindow;
+#[cfg(feature = "virtiofs")]
+pub use dax_window::DaxWindowStats;
+use deadline::DeadlineTracker;
+pub use deadline::{DeadlineViolations, RequestDeadlines};
+pub use dispatch::{DispatchTable, DispatchTableBuilder, OpHandler, OpRequest};
+use forget_queue::ForgetQueue;
+use handle_access::{Access, HandleAccess};
+use interrupt::InterruptRegistry;
+pub use invalidation::{InvalEntryEvent, InvalidationBus, InvalidationSubscriber};
+use lookup_audit::LookupAudit;
+pub use lookup_audit::LookupDivergence;
+pub use metrics::MetricsHook;
+use metrics::ReplyRecorder;
+pub(crate) use notify::NotifyMessage;
+pub use opcode_ext::RawOpcodeHandler;
+pub use poll::PollNotifier;
+pub use profiler::{format_traces, RequestTrace, SamplingConfig, SamplingPolicy};
+use profiler::{RequestSampler, SampleGuard, SampleTimer};
+use retry::TransientRetry;
+pub use scheduler::{BackendClassifier, BackendLimits, BackendScheduler, BackendSlot};
+pub(crate) use shutdown::InflightTracker;
+pub use shutdown::{GracefulShutdown, ShutdownEvent, ShutdownSession};
+pub use throttle::{Throttle, ThrottleLimits};
+pub use watchdog::{
+    HungRequestCallback, InflightRequest, Watchdog, WatchdogMonitor, DEFAULT_WATCHDOG_SLOTS,
+};
+#[cfg(feature = "async-io")]
+use write_barrier::WriteBarrier;
+pub use xattr_limits::{XattrLimits, XATTR_LIST_MAX, XATTR_NAME_MAX, XATTR_SIZE_MAX};
 
 /// Maximum buffer size of FUSE requests.
 #[cfg(target_os = "linux")]
@@ -45,11 +118,44 @@ const DIRENT_PADDING: [u8; 8] = [0; 8];
 
 /// Maximum number of pages required for FUSE requests.
 pub const MAX_REQ_PAGES: u16 = 256; // 1MB
+                                    // Maximum number of pages of requests without `FsOptions::MAX_PAGES`.
+const DEFAULT_REQ_PAGES: u16 = 32;
 
 /// Fuse Server to handle requests from the Fuse client and vhost user master.
 pub struct Server<F: FileSystem + Sync> {
     fs: F,
     vers: ArcSwap<ServerVersion>,
+    conn: ArcSwapOption<ConnectionInfo>,
+    sampler: Option<RequestSampler>,
+    metrics: Option<Arc<dyn MetricsHook>>,
+    clock: Arc<dyn Clock>,
+    inval: Option<InvalidationSubscriber>,
+    poll: Option<Arc<PollNotifier>>,
+    audit: Option<LookupAudit>,
+    access: Option<HandleAccess>,
+    forgets: Option<ForgetQueue>,
+    limits: Option<ConcurrencyLimiter>,
+    throttle: Option<Arc<Throttle>>,
+    deadlines: Option<DeadlineTracker>,
+    retry: Option<TransientRetry>,
+    interrupts: Option<InterruptRegistry>,
+    watchdog: Option<Arc<Watchdog>>,
+    #[cfg(feature = "virtiofs")]
+    dax: Option<DaxWindow>,
+    inflight: InflightTracker,
+    raw: Option<Arc<dyn RawFileSystem>>,
+    opcodes: HashMap<u32, Box<dyn RawOpcodeHandler>>,
+    opcode_overrides: bool,
+    dispatch: Option<Arc<DispatchTable>>,
+    dot_lookups: bool,
+    max_write: u32,
+    xattr_limits: XattrLimits,
+    write_accounting: Option<Arc<WriteAccounting>>,
+    shedder: Arc<DrainShedder>,
+    #[cfg(feature = "async-io")]
+    executor: Arc<dyn crate::api::executor::Executor>,
+    #[cfg(feature = "async-io")]
+    writes: Option<WriteBarrier>,
 }
 
 impl<F: FileSystem + Sync> Server<F> {
@@ -61,7 +167,363 @@ impl<F: FileSystem + Sync> Server<F> {
                 major: KERNEL_VERSION,
                 minor: KERNEL_MINOR_VERSION,
             })),
+            conn: ArcSwapOption::empty(),
+            sampler: None,
+            metrics: None,
+            clock: Arc::new(SystemClock::default()),
+            inval: None,
+            poll: None,
+            audit: None,
+            access: None,
+            forgets: None,
+            limits: None,
+            throttle: None,
+            deadlines: None,
+            retry: None,
+            interrupts: None,
+            watchdog: None,
+            #[cfg(feature = "virtiofs")]
+            dax: None,
+            inflight: InflightTracker::default(),
+            raw: None,
+            opcodes: HashMap::new(),
+            opcode_overrides: false,
+            dispatch: None,
+            dot_lookups: true,
+            max_write: MAX_REQ_PAGES as u32 * pagesize() as u32,
+            xattr_limits: XattrLimits::default(),
+            write_accounting: None,
+            shedder: Arc::new(DrainShedder::default()),
+            #[cfg(feature = "async-io")]
+            executor: Arc::new(crate::api::executor::TokioUringExecutor),
+            #[cfg(feature = "async-io")]
+            writes: None,
+        }
+    }
+
+    /// Pass lookups of "." and ".." to the filesystem driver if `allow` is true, the default.
+    ///
+    /// The kernel only sends such lookups for file systems exported by NFS, to reconnect file
+    /// handles to the dentry tree. When not allowed, they fail with `EINVAL` like names of other
+    /// requests which are "." or "..".
+    pub fn with_dot_lookups(mut self, allow: bool) -> Self {
+        self.dot_lookups = allow;
+        self
+    }
+
+    /// Account payload bytes of write requests received from the guest in `acct`.
+    ///
+    /// Register the same accounting in the backends to compare guest writes with the bytes they
+    /// write to backing storage, see [WriteStats](crate::api::accounting::WriteStats).
+    pub fn with_write_accounting(mut self, acct: Arc<WriteAccounting>) -> Self {
+        self.write_accounting = Some(acct);
+        self
+    }
+
+    /// Abort long running operations of backends by `shedder` when draining for shutdown, instead
+    /// of a shedder owned by the server.
+    ///
+    /// Register the same shedder in the [YieldPoints](crate::api::shedder::YieldPoints) of the
+    /// backends, so operations reaching their next yield point fail with `EINTR` once
+    /// [Server::wait_drained] is called.
+    pub fn with_drain_shedder(mut self, shedder: Arc<DrainShedder>) -> Self {
+        self.shedder = shedder;
+        self
+    }
+
+    /// Get the shedder aborting long running operations of backends when draining for shutdown.
+    pub fn drain_shedder(&self) -> Arc<DrainShedder> {
+        self.shedder.clone()
+    }
+
+    /// Join the cross-session invalidation bus as a session serving backend `backend`.
+    ///
+    /// Namespace changes made through this server get published to other sessions of the same
+    /// backend, and changes made by other sessions may be retrieved by
+    /// [Server::pending_invalidations] to notify the guest. Use
+    /// [Server::with_invalidation_notifier] to have them sent as they're published instead.
+    pub fn with_invalidation_bus(mut self, bus: &Arc<InvalidationBus>, backend: u64) -> Self {
+        self.inval = Some(bus.subscribe(backend));
+        self
+    }
+
+    /// Join the cross-session invalidation bus as a session serving backend `backend`, sending
+    /// changes made by other sessions to the guest with `notifier` as they're published.
+    ///
+    /// `notifier` is typically the [FuseDevNotifier] of the session this server serves, sending
+    /// `FUSE_NOTIFY_INVAL_ENTRY` messages to the fuse device. Invalidations are never pending.
+    ///
+    /// [FuseDevNotifier]: crate::transport::FuseDevNotifier
+    pub fn with_invalidation_notifier(
+        mut self,
+        bus: &Arc<InvalidationBus>,
+        backend: u64,
+        notifier: Arc<dyn Notifier>,
+    ) -> Self {
+        self.inval = Some(bus.subscribe_notifier(backend, notifier));
+        self
+    }
+
+    /// Record kernel poll handles waiting for a wakeup in `notifier`, shared with the filesystem.
+    ///
+    /// Without a notifier, filesystems may still reply to `FUSE_POLL`, but can't wake pollers up.
+    pub fn with_poll_notifier(mut self, notifier: Arc<PollNotifier>) -> Self {
+        self.poll = Some(notifier);
+        self
+    }
+
+    /// Take directory entry invalidations published by other sessions of the same backend.
+    pub fn pending_invalidations(&self) -> Vec<InvalEntryEvent> {
+        self.inval.as_ref().map(|s| s.take()).unwrap_or_default()
+    }
+
+    /// Send a `FUSE_NOTIFY_INVAL_ENTRY` message to invalidate the directory entry `name` under
+    /// directory `parent` in the guest kernel.
+    ///
+    /// Notifications are written to `w` like replies. With virtio-fs, `w` must wrap a descriptor
+    /// chain of the notification queue, instead of one of a request queue.
+    pub fn notify_inval_entry<S: BitmapSlice>(
+        &self,
+        w: Writer<'_, S>,
+        parent: u64,
+        name: &CStr,
+    ) -> Result<usize> {
+        self.notify_inval_entry_flags(w, parent, name, 0)
+    }
+
+    /// Send a `FUSE_NOTIFY_INVAL_ENTRY` message with `flags`, like `FUSE_EXPIRE_ONLY` to let the
+    /// guest kernel look the entry up again on next use, without dropping it while in use.
+    ///
+    /// Flags are cleared for kernels speaking a minor older than 7.38, which fully invalidate
+    /// the entry instead.
+    pub fn notify_inval_entry_flags<S: BitmapSlice>(
+        &self,
+        w: Writer<'_, S>,
+        parent: u64,
+        name: &CStr,
+        flags: u32,
+    ) -> Result<usize> {
+        let minor = self.vers.load().minor;
+        Self::notify(w, &NotifyMessage::inval_entry(parent, name, flags, minor))
+    }
+
+    /// Send a `FUSE_NOTIFY_DELETE` message to delete the directory entry `name` under directory
+    /// `parent`, referring to inode `child`, in the guest kernel.
+    pub fn notify_delete<S: BitmapSlice>(
+        &self,
+        w: Writer<'_, S>,
+        parent: u64,
+        child: u64,
+        name: &CStr,
+    ) -> Result<usize> {
+        Self::notify(w, &NotifyMessage::delete(parent, child, name))
+    }
+
+    /// Send a `FUSE_NOTIFY_INVAL_INODE` message to invalidate cached attributes and data of
+    /// inode `ino` in the guest kernel.
+    ///
+    /// A negative `off` only invalidates attributes, otherwise data in the range starting at `off`
+    /// with `len` bytes gets invalidated too, where a `len` of zero means up to the end of file.
+    pub fn notify_inval_inode<S: BitmapSlice>(
+        &self,
+        w: Writer<'_, S>,
+        ino: u64,
+        off: i64,
+        len: i64,
+    ) -> Result<usize> {
+        Self::notify(w, &NotifyMessage::inval_inode(ino, off, len))
+    }
+
+    /// Send `FUSE_NOTIFY_STORE` messages to push `data` into the page cache of inode `nodeid`
+    /// at `offset` in the guest kernel.
+    ///
+    /// Data is split into messages of at most the `max_write` negotiated with the kernel, each
+    /// written to a writer returned by `writer`. Return the number of bytes of `data` stored,
+    /// which is less than its length if the kernel fails a message with `ENOENT` because it
+    /// doesn't have the inode cached.
+    pub fn notify_store<'a, S, W>(
+        &self,
+        mut writer: W,
+        nodeid: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<usize>
+    where
+        S: BitmapSlice + 'a,
+        W: FnMut() -> Result<Writer<'a, S>>,
+    {
+        let max_write = self.negotiated_max_write() as usize;
+        let mut stored = 0;
+        for msg in NotifyMessage::store(nodeid, offset, &[data], max_write) {
+            match Self::notify(writer()?, &msg) {
+                Ok(_) => stored += msg.payload_len(),
+                Err(Error::EncodeMessage(e)) if e.raw_os_error() == Some(libc::ENOENT) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(stored)
+    }
+
+    /// Send a `FUSE_NOTIFY_RETRIEVE` message to retrieve `size` bytes at `offset` of the page
+    /// cache of inode `nodeid` in the guest kernel.
+    ///
+    /// The kernel sends the cached data back by a `FUSE_NOTIFY_REPLY` request, whose unique ID is
+    /// `notify_unique`, and which is passed to [FileSystem::notify_reply].
+    pub fn notify_retrieve<S: BitmapSlice>(
+        &self,
+        w: Writer<'_, S>,
+        notify_unique: u64,
+        nodeid: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<usize> {
+        Self::notify(
+            w,
+            &NotifyMessage::retrieve(notify_unique, nodeid, offset, size),
+        )
+    }
+
+    /// Send a `FUSE_NOTIFY_POLL` message to wake up pollers of the kernel poll handle `kh`.
+    ///
+    /// Handles to wake up are taken from the [PollNotifier] by [PollNotifier::take].
+    pub fn notify_poll_wakeup<S: BitmapSlice>(&self, w: Writer<'_, S>, kh: u64) -> Result<usize> {
+        Self::notify(w, &NotifyMessage::poll_wakeup(kh))
+    }
+
+    // Write the notification message `msg` to `w`.
+    fn notify<S: BitmapSlice>(mut w: Writer<'_, S>, msg: &NotifyMessage) -> Result<usize> {
+        w.write_vectored(&msg.bufs())
+            .map_err(Error::EncodeMessage)?;
+        w.commit(None).map_err(Error::EncodeMessage)?;
+        debug_assert_eq!(msg.len(), w.bytes_written());
+        Ok(w.bytes_written())
+    }
+
+    fn publish_inval(&self, parent: u64, name: &CStr) {
+        if let Some(sub) = self.inval.as_ref() {
+            sub.publish(parent, name);
+        }
+    }
+
+    /// Install a handler to intercept raw requests before they are dispatched to the filesystem
+    /// driver.
+    ///
+    /// The handler is consulted by [Server::handle_message] for every request, and requests it
+    /// doesn't handle are dispatched to the typed [FileSystem] interface as usual. The
+    /// asynchronous request path doesn't consult the handler.
+    pub fn with_raw_handler(mut self, handler: Arc<dyn RawFileSystem>) -> Self {
+        self.raw = Some(handler);
+        self
+    }
+
+    /// Enable strict accounting of inode lookup counts, to debug forget/nlookup handling.
+    ///
+    /// The server tracks the expected lookup count of each inode, increased by every reply
+    /// conveying an `Entry` and decreased by forget requests. Divergences from the counts reported
+    /// by [FileSystem::debug_nlookup] may be retrieved by [Server::lookup_audit]. If
+    /// `assert_on_destroy` is true, the server panics if any lookup count hasn't dropped to zero
+    /// when handling `FUSE_DESTROY`.
+    pub fn with_lookup_audit(mut self, assert_on_destroy: bool) -> Self {
+        self.audit = Some(LookupAudit::new(assert_on_destroy));
+        self
+    }
+
+    /// Get inodes whose lookup counts reported by the filesystem driver diverge from the expected
+    /// ones, ordered by inode number.
+    ///
+    /// Return an empty list if lookup count accounting is not enabled.
+    pub fn lookup_audit(&self) -> Vec<LookupDivergence> {
+        self.audit
+            .as_ref()
+            .map(|a| a.audit(|inode| self.fs.debug_nlookup(inode.into())))
+            .unwrap_or_default()
+    }
+
+    /// Get expected lookup counts of inodes as `(inode, nlookup)` pairs, ordered by inode number.
+    ///
+    /// Return an empty list if lookup count accounting is not enabled.
+    pub fn lookup_counts(&self) -> Vec<(u64, u64)> {
+        self.audit.as_ref().map(|a| a.counts()).unwrap_or_default()
+    }
+
+    fn audit_lookup(&self, inode: u64) {
+        if let Some(audit) = self.audit.as_ref() {
+            audit.lookup(inode);
+        }
+    }
+
+    fn audit_forget(&self, inode: u64, nlookup: u64) {
+        if let Some(audit) = self.audit.as_ref() {
+            audit.forget(inode, nlookup);
+        }
+    }
+
+    /// Enforce the access mode of file handles at the server boundary.
+    ///
+    /// The server records the access mode of handles returned by open and create requests, and
+    /// rejects read requests against handles opened with `O_WRONLY`, and write or fallocate
+    /// requests against handles opened with `O_RDONLY`, with `EBADF` before they reach the
+    /// filesystem driver. Reads through `O_WRONLY` handles are allowed when writeback cache is
+    /// enabled, because the kernel needs them to fill partially written pages.
+    ///
+    /// Filesystem drivers which already validate handles themselves don't need this.
+    pub fn with_handle_access_check(mut self) -> Self {
+        self.access = Some(HandleAccess::new());
+        self
+    }
+
+    fn access_open(&self, nodeid: u64, fh: Option<u64>, flags: u32) {
+        if let (Some(access), Some(fh)) = (self.access.as_ref(), fh) {
+            access.open(nodeid, fh, flags);
+        }
+    }
+
+    fn access_release(&self, nodeid: u64, fh: u64) {
+        if let Some(access) = self.access.as_ref() {
+            access.release(nodeid, fh);
         }
+        #[cfg(feature = "async-io")]
+        if let Some(writes) = self.writes.as_ref() {
+            writes.release(nodeid, fh);
+        }
+    }
+
+    fn access_check(&self, nodeid: u64, fh: u64, mode: Access) -> io::Result<()> {
+        match self.access.as_ref() {
+            Some(access) => access.check(nodeid, fh, mode),
+            None => Ok(()),
+        }
+    }
+
+    /// Enable the request sampling profiler.
+    ///
+    /// Requests selected by `cfg.policy` get a detailed trace recorded into a ring buffer of
+    /// `cfg.capacity` entries, which may be retrieved by [Server::sampled_traces].
+    pub fn with_sampling(mut self, cfg: SamplingConfig) -> Self {
+        self.sampler = Some(RequestSampler::new(cfg).with_clock(self.clock.clone()));
+        self
+    }
+
+    /// Time sampled requests with `clock`, instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.sampler = self.sampler.map(|s| s.with_clock(clock.clone()));
+        self.clock = clock;
+        self
+    }
+
+    /// Get a snapshot of sampled request traces, ordered from the oldest to the newest.
+    ///
+    /// Return an empty list if the sampling profiler is not enabled.
+    pub fn sampled_traces(&self) -> Vec<RequestTrace> {
+        self.sampler
+            .as_ref()
+            .map(|s| s.traces())
+            .unwrap_or_default()
+    }
+
+    // Sample the request of `in_header`, whose trace gets recorded when the guard is dropped.
+    fn sample(&self, in_header: &InHeader) -> Option<SampleGuard<'_>> {
+        self.sampler.as_ref().and_then(|s| s.sample(in_header))
     }
 }
 
@@ -76,6 +538,21 @@ impl<'a, S: BitmapSlice> ZeroCopyReader for ZcReader<'a, S> {
     ) -> io::Result<usize> {
         self.0.read_to_at(f, count, off)
     }
+
+    fn read_to_at_offset(
+        &mut self,
+        f: &mut dyn FileReadWriteVolatile,
+        count: usize,
+        off: u64,
+        buf_offset: usize,
+    ) -> io::Result<usize> {
+        self.0.read_to_at_offset(f, count, off, buf_offset)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn splice_to(&mut self, fd: RawFd, count: usize, off: u64) -> io::Result<Option<usize>> {
+        self.0.splice_to_at(fd, count, off)
+    }
 }
 
 impl<'a, S: BitmapSlice> io::Read for ZcReader<'a, S> {
@@ -84,7 +561,20 @@ impl<'a, S: BitmapSlice> io::Read for ZcReader<'a, S> {
     }
 }
 
-struct ZcWriter<'a, S: BitmapSlice = ()>(Writer<'a, S>);
+// The second field is the end of data written by `write_from_at_offset()`, relative to the
+// start of the writer.
+struct ZcWriter<'a, S: BitmapSlice = ()>(Writer<'a, S>, usize);
+
+impl<'a, S: BitmapSlice> ZcWriter<'a, S> {
+    // Account data written at offsets, which is not covered by the current position yet.
+    fn commit_offset_writes(&mut self) -> io::Result<()> {
+        let written = self.0.bytes_written();
+        if self.1 > written {
+            self.0.advance(self.1 - written)?;
+        }
+        Ok(())
+    }
+}
 
 impl<'a, S: BitmapSlice> ZeroCopyWriter for ZcWriter<'a, S> {
     fn write_from(
@@ -95,6 +585,19 @@ impl<'a, S: BitmapSlice> ZeroCopyWriter for ZcWriter<'a, S> {
     ) -> io::Result<usize> {
         self.0.write_from_at(f, count, off)
     }
+
+    fn write_from_at_offset(
+        &mut self,
+        f: &mut dyn FileReadWriteVolatile,
+        count: usize,
+        off: u64,
+        buf_offset: usize,
+    ) -> io::Result<usize> {
+        let cnt = self.0.write_from_at_offset(f, count, off, buf_offset)?;
+        // No overflow because the writer has checked the range against its available space.
+        self.1 = cmp::max(self.1, self.0.bytes_written() + buf_offset + cnt);
+        Ok(cnt)
+    }
 }
 
 impl<'a, S: BitmapSlice> io::Write for ZcWriter<'a, S> {
@@ -121,13 +624,35 @@ impl ServerUtil {
         in_header: &InHeader,
         sub_hdr_sz: usize,
     ) -> Result<Vec<u8>> {
+        Self::read_message_body(r, in_header, sub_hdr_sz, Vec::new())
+    }
+
+    // Read the message body into the scratch buffer of the current thread, which should be
+    // recycled once the request is handled.
+    fn get_scratch_message_body<S: BitmapSlice>(
+        r: &mut Reader<'_, S>,
+        in_header: &InHeader,
+        sub_hdr_sz: usize,
+    ) -> Result<Vec<u8>> {
+        Self::read_message_body(r, in_header, sub_hdr_sz, scratch::take(0))
+    }
+
+    fn read_message_body<S: BitmapSlice>(
+        r: &mut Reader<'_, S>,
+        in_header: &InHeader,
+        sub_hdr_sz: usize,
+        mut buf: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        // Request extensions follow the body.
         let len = (in_header.len as usize)
             .checked_sub(size_of::<InHeader>())
             .and_then(|l| l.checked_sub(sub_hdr_sz))
+            .and_then(|l| l.checked_sub(in_header.total_extlen as usize * 8))
             .ok_or(Error::InvalidHeaderLength)?;
 
         // Allocate buffer without zeroing out the content for performance.
-        let mut buf = Vec::<u8>::with_capacity(len);
+        buf.clear();
+        buf.reserve(len);
         // It's safe because read_exact() is called to fill all the allocated buffer.
         #[allow(clippy::uninit_vec)]
         unsafe {
@@ -138,27 +663,76 @@ impl ServerUtil {
         Ok(buf)
     }
 
-    fn extract_two_cstrs(buf: &[u8]) -> Result<(&CStr, &CStr)> {
-        if let Some(mut pos) = buf.iter().position(|x| *x == 0) {
-            let first = CStr::from_bytes_with_nul(&buf[0..=pos]).map_err(Error::InvalidCString)?;
-            pos += 1;
-            if pos < buf.len() {
-                return Ok((first, bytes_to_cstr(&buf[pos..])?));
+    // Extract a non-empty string terminated by a nul character from `buf`, which may be padded
+    // with nul characters. Fail with `EINVAL` if the string has embedded nul characters, instead
+    // of silently truncating it.
+    fn extract_cstr(buf: &[u8]) -> io::Result<&CStr> {
+        codec::decode_name(buf).map_err(|_| einval())
+    }
+
+    // Extract the name of a directory entry from `buf` like `extract_cstr()`.
+    //
+    // Names which can't be a single path component, that is ones containing '/', and "." or ".."
+    // unless `allow_dots` is true, are rejected with `EINVAL` so they never reach filesystem
+    // drivers.
+    fn extract_name(buf: &[u8], allow_dots: bool) -> io::Result<&CStr> {
+        let name = Self::extract_cstr(buf)?;
+        Self::check_name(name, allow_dots)?;
+        Ok(name)
+    }
+
+    fn check_name(name: &CStr, allow_dots: bool) -> io::Result<()> {
+        let bytes = name.to_bytes();
+        if bytes.is_empty() || bytes.contains(&b'/') {
+            return Err(einval());
+        }
+        if !allow_dots && (bytes == b"." || bytes == b"..") {
+            return Err(einval());
+        }
+        Ok(())
+    }
+
+    // Extract the first supplementary group of the `FUSE_EXT_GROUPS` extension from the request
+    // extensions in `buf`, skipping other extensions.
+    fn extract_supp_gid(mut buf: &[u8]) -> io::Result<Option<libc::gid_t>> {
+        let word =
+            |b: &[u8], off: usize| u32::from_ne_bytes([b[off], b[off + 1], b[off + 2], b[off + 3]]);
+        let mut gid = None;
+        while !buf.is_empty() {
+            if buf.len() < size_of::<ExtHeader>() {
+                return Err(einval());
             }
+            let size = word(buf, 0) as usize;
+            if size < size_of::<ExtHeader>() || size & 7 != 0 || size > buf.len() {
+                return Err(einval());
+            }
+            let (ext, rest) = buf.split_at(size);
+            if word(ext, 4) == FUSE_EXT_GROUPS {
+                let groups = size_of::<ExtHeader>() + size_of::<SuppGroups>();
+                if ext.len() < groups {
+                    return Err(einval());
+                }
+                let nr_groups = word(ext, size_of::<ExtHeader>()) as usize;
+                if nr_groups > (ext.len() - groups) / 4 {
+                    return Err(einval());
+                }
+                if nr_groups > 0 {
+                    gid = Some(word(ext, groups));
+                }
+            }
+            buf = rest;
         }
+        Ok(gid)
+    }
 
-        Err(Error::DecodeMessage(std::io::Error::from_raw_os_error(
-            libc::EINVAL,
-        )))
+    // Extract two strings separated by a nul character from `buf` like `extract_cstr()`.
+    fn extract_two_cstrs(buf: &[u8]) -> io::Result<(&CStr, &CStr)> {
+        codec::decode_two_names(buf).map_err(|_| einval())
     }
 }
 
-/// Provide concrete backend filesystem a way to catch information/metrics from fuse.
-pub trait MetricsHook {
-    /// `collect()` will be invoked before the real request is processed
-    fn collect(&self, ih: &InHeader);
-    /// `release()` will be invoked after the real request is processed
-    fn release(&self, oh: Option<&OutHeader>);
+fn einval() -> io::Error {
+    io::Error::from_raw_os_error(libc::EINVAL)
 }
 
 struct SrvContext<'a, F, S: BitmapSlice = ()> {
@@ -166,6 +740,10 @@ struct SrvContext<'a, F, S: BitmapSlice = ()> {
     context: Context,
     r: Reader<'a, S>,
     w: Writer<'a, S>,
+    timer: Option<Arc<SampleTimer>>,
+    reply: Option<Arc<ReplyRecorder>>,
+    // Protocol minor version negotiated with the kernel, to encode replies.
+    minor: u32,
     phantom: PhantomData<F>,
     phantom2: PhantomData<S>,
 }
@@ -179,15 +757,56 @@ impl<'a, F: FileSystem, S: BitmapSlice> SrvContext<'a, F, S> {
             context,
             r,
             w,
+            timer: None,
+            reply: None,
+            minor: KERNEL_MINOR_VERSION,
             phantom: PhantomData,
             phantom2: PhantomData,
         }
     }
 
+    fn with_timer(mut self, timer: Option<Arc<SampleTimer>>) -> Self {
+        self.timer = timer;
+        self
+    }
+
+    fn with_reply_recorder(mut self, reply: Option<Arc<ReplyRecorder>>) -> Self {
+        self.reply = reply;
+        self
+    }
+
+    fn with_minor(mut self, minor: u32) -> Self {
+        self.minor = minor;
+        self
+    }
+
+    // The filesystem driver gets invoked right after decoding the request, with the request
+    // context as its first argument, so it's the point to mark the end of the decoding stage.
     fn context(&self) -> &Context {
+        if let Some(timer) = self.timer.as_ref() {
+            timer.mark_decoded();
+        }
         &self.context
     }
 
+    fn mark_replying(&self) {
+        if let Some(timer) = self.timer.as_ref() {
+            timer.mark_replying();
+        }
+    }
+
+    // Remember the header of the reply for the metrics hook and the sampling profiler.
+    fn record_reply(&self, header: &OutHeader) {
+        if let Some(reply) = self.reply.as_ref() {
+            reply.record(header);
+        }
+        if let Some(timer) = self.timer.as_ref() {
+            timer.record_reply(header);
+        }
+        #[cfg(feature = "tracing")]
+        span::record_reply(header);
+    }
+
     fn unique(&self) -> u64 {
         self.in_header.unique
     }
@@ -196,6 +815,19 @@ impl<'a, F: FileSystem, S: BitmapSlice> SrvContext<'a, F, S> {
         self.in_header.nodeid.into()
     }
 
+    // Decode the request extensions following the body of requests creating files, once the body
+    // is read.
+    fn read_extensions(&mut self) -> io::Result<()> {
+        let len = self.in_header.total_extlen as usize * 8;
+        if len == 0 {
+            return Ok(());
+        }
+        let mut buf = vec![0u8; len];
+        self.r.read_exact(&mut buf).map_err(|_| einval())?;
+        self.context.supp_gid = ServerUtil::extract_supp_gid(&buf)?;
+        Ok(())
+    }
+
     fn take_reader(&mut self) -> Reader<'a, S> {
         let mut reader = Reader::default();
 
@@ -208,6 +840,10 @@ impl<'a, F: FileSystem, S: BitmapSlice> SrvContext<'a, F, S> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[cfg(feature = "fusedev")]
+    use crate::api::filesystem::IoctlReply;
+    #[cfg(feature = "fusedev")]
+    use vm_memory::ByteValued;
 
     #[test]
     fn test_extract_cstrs() {
@@ -225,23 +861,3672 @@ mod tests {
                 CStr::from_bytes_with_nul(&[0x3u8, 0x0]).unwrap(),
             )
         );
-        assert_eq!(
-            ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8, 0x0, 0x3, 0x0, 0x4]).unwrap(),
-            (
-                CStr::from_bytes_with_nul(&[0x1u8, 0x2u8, 0x0]).unwrap(),
-                CStr::from_bytes_with_nul(&[0x3u8, 0x0]).unwrap(),
+        // Embedded nul characters and empty strings are rejected.
+        ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8, 0x0, 0x3, 0x0, 0x4]).unwrap_err();
+        ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8, 0x0, 0x0, 0x4]).unwrap_err();
+        ServerUtil::extract_two_cstrs(&[0x0u8, 0x3, 0x0]).unwrap_err();
+        ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8, 0x0, 0x3]).unwrap_err();
+        ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8, 0x0]).unwrap_err();
+        ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8]).unwrap_err();
+    }
+
+    #[test]
+    fn test_extract_name() {
+        let name = |buf: &[u8], dots| {
+            ServerUtil::extract_name(buf, dots)
+                .map(|n| n.to_bytes().to_vec())
+                .map_err(|e| e.raw_os_error().unwrap())
+        };
+        assert_eq!(name(b"abc\0", false), Ok(b"abc".to_vec()));
+        assert_eq!(name(b"abc\0\0\0", false), Ok(b"abc".to_vec()));
+        assert_eq!(name(b"..\0", true), Ok(b"..".to_vec()));
+        for buf in [
+            &b"\0"[..],
+            b"",
+            b"abc",
+            b"a/b\0",
+            b"/\0",
+            b"a\0b\0",
+            b"a\0\0b",
+            b".\0",
+            b"..\0",
+        ] {
+            assert_eq!(name(buf, false), Err(libc::EINVAL), "{:?}", buf);
+        }
+        assert_eq!(name(b"a/..\0", true), Err(libc::EINVAL));
+    }
+
+    #[cfg(feature = "fusedev")]
+    pub(super) fn handle_request<F: FileSystem + Sync>(
+        server: &Server<F>,
+        file: &std::fs::File,
+        opcode: Opcode,
+        nodeid: u64,
+        unique: u64,
+        body: &[u8],
+    ) -> Result<usize> {
+        handle_opcode(server, file, opcode as u32, nodeid, unique, body)
+    }
+
+    #[cfg(feature = "fusedev")]
+    fn handle_opcode<F: FileSystem + Sync>(
+        server: &Server<F>,
+        file: &std::fs::File,
+        opcode: u32,
+        nodeid: u64,
+        unique: u64,
+        body: &[u8],
+    ) -> Result<usize> {
+        let in_header = InHeader {
+            len: (size_of::<InHeader>() + body.len()) as u32,
+            opcode,
+            unique,
+            nodeid,
+            ..Default::default()
+        };
+        let mut msg = in_header.as_slice().to_vec();
+        msg.extend_from_slice(body);
+        handle_bytes(server, file, &msg)
+    }
+
+    // Handle the request made of the raw bytes `msg`, header included.
+    #[cfg(feature = "fusedev")]
+    fn handle_bytes<F: FileSystem + Sync>(
+        server: &Server<F>,
+        file: &std::fs::File,
+        msg: &[u8],
+    ) -> Result<usize> {
+        use crate::transport::{FuseBuf, FuseDevWriter};
+        use std::os::unix::io::AsRawFd;
+
+        let mut r_buf = msg.to_vec();
+        let r = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut r_buf)).unwrap();
+        let mut w_buf = vec![0x0u8; 1024];
+        let w = FuseDevWriter::<()>::new(file.as_raw_fd(), &mut w_buf)
+            .unwrap()
+            .into();
+        server.handle_message(r, w, None, None)
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_concurrency_limits() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::thread;
+        use std::time::Duration;
+
+        // Counts concurrent reads and writes, and records their peaks.
+        #[derive(Default)]
+        struct SlowFs {
+            reads: AtomicUsize,
+            writes: AtomicUsize,
+            max_reads: AtomicUsize,
+            max_writes: AtomicUsize,
+        }
+
+        impl SlowFs {
+            fn run(&self, count: &AtomicUsize, max: &AtomicUsize) {
+                let n = count.fetch_add(1, Ordering::SeqCst) + 1;
+                max.fetch_max(n, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(50));
+                count.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+
+        impl FileSystem for SlowFs {
+            type Inode = u64;
+            type Handle = u64;
+
+            fn read(
+                &self,
+                _: &Context,
+                _: u64,
+                _: u64,
+                _: &mut dyn ZeroCopyWriter,
+                _: u32,
+                _: u64,
+                _: Option<u64>,
+                _: u32,
+            ) -> io::Result<usize> {
+                self.run(&self.reads, &self.max_reads);
+                Ok(0)
+            }
+
+            fn write(
+                &self,
+                _: &Context,
+                _: u64,
+                _: u64,
+                _: &mut dyn ZeroCopyReader,
+                size: u32,
+                _: u64,
+                _: Option<u64>,
+                _: bool,
+                _: u32,
+                _: u32,
+            ) -> io::Result<usize> {
+                self.run(&self.writes, &self.max_writes);
+                Ok(size as usize)
+            }
+        }
+
+        let server = Arc::new(Server::new(SlowFs::default()).with_concurrency_limits(
+            ConcurrencyLimits {
+                write: 1,
+                ..Default::default()
+            },
+        ));
+        let threads = (0..8)
+            .map(|i| {
+                let server = server.clone();
+                thread::spawn(move || {
+                    let file = vmm_sys_util::tempfile::TempFile::new().unwrap();
+                    if i % 2 == 0 {
+                        let read_in = ReadIn {
+                            size: 4,
+                            ..Default::default()
+                        };
+                        handle_request(
+                            &server,
+                            file.as_file(),
+                            Opcode::Read,
+                            2,
+                            i,
+                            read_in.as_slice(),
+                        )
+                    } else {
+                        let write_in = WriteIn {
+                            size: 4,
+                            ..Default::default()
+                        };
+                        let mut body = write_in.as_slice().to_vec();
+                        body.extend_from_slice(b"data");
+                        handle_request(&server, file.as_file(), Opcode::Write, 2, i, &body)
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap().unwrap();
+        }
+
+        assert_eq!(server.fs.max_writes.load(Ordering::SeqCst), 1);
+        assert!(server.fs.max_reads.load(Ordering::SeqCst) > 1);
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_interrupt() {
+        use std::collections::HashSet;
+        use std::io::{Seek, SeekFrom};
+        use std::sync::{Condvar, Mutex};
+        use std::thread;
+        use std::time::Duration;
+        use vmm_sys_util::tempfile::TempFile;
+
+        // Blocks reads until they get interrupted.
+        #[derive(Default)]
+        struct BlockingFs {
+            interrupted: Mutex<HashSet<u64>>,
+            cond: Condvar,
+        }
+
+        impl FileSystem for BlockingFs {
+            type Inode = u64;
+            type Handle = u64;
+
+            fn read(
+                &self,
+                ctx: &Context,
+                _: u64,
+                _: u64,
+                _: &mut dyn ZeroCopyWriter,
+                _: u32,
+                _: u64,
+                _: Option<u64>,
+                _: u32,
+            ) -> io::Result<usize> {
+                let mut interrupted = self.interrupted.lock().unwrap();
+                while !interrupted.remove(&ctx.unique) {
+                    interrupted = self.cond.wait(interrupted).unwrap();
+                }
+                Err(io::Error::from_raw_os_error(libc::EINTR))
+            }
+
+            fn interrupt(&self, _: &Context, unique: u64) {
+                self.interrupted.lock().unwrap().insert(unique);
+                self.cond.notify_all();
+            }
+        }
+
+        // Interrupt request `unique`, return the error replied to the interrupt if any.
+        let interrupt = |server: &Server<BlockingFs>, unique: u64| {
+            let mut file = TempFile::new().unwrap().into_file();
+            let body = InterruptIn { unique };
+            handle_request(
+                server,
+                &file,
+                Opcode::Interrupt,
+                0,
+                unique | 1,
+                body.as_slice(),
+            )
+            .unwrap();
+            let mut reply = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut reply).unwrap();
+            (!reply.is_empty()).then(|| OutHeader::from_slice(&reply).unwrap().error)
+        };
+
+        let server = Arc::new(Server::new(BlockingFs::default()).with_interrupts());
+        let mut file = TempFile::new().unwrap().into_file();
+        let reader = {
+            let server = server.clone();
+            let file = file.try_clone().unwrap();
+            thread::spawn(move || {
+                let read_in = ReadIn {
+                    size: 4,
+                    ..Default::default()
+                };
+                handle_request(&server, &file, Opcode::Read, 2, 8, read_in.as_slice())
+            })
+        };
+
+        // The interrupt may arrive before the read, then the kernel sends it again.
+        while let Some(error) = interrupt(&server, 8) {
+            assert_eq!(error, -libc::EAGAIN);
+            thread::sleep(Duration::from_millis(10));
+        }
+        reader.join().unwrap().unwrap();
+        let mut reply = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut reply).unwrap();
+        let header = OutHeader::from_slice(&reply).unwrap();
+        assert_eq!(header.unique, 8);
+        assert_eq!(header.error, -libc::EINTR);
+
+        // Interrupts of completed requests get `EAGAIN`.
+        assert_eq!(interrupt(&server, 8), Some(-libc::EAGAIN));
+        // Interrupts are ignored unless enabled.
+        let server = Server::new(BlockingFs::default());
+        let file = TempFile::new().unwrap();
+        let body = InterruptIn { unique: 8 };
+        handle_request(
+            &server,
+            file.as_file(),
+            Opcode::Interrupt,
+            0,
+            9,
+            body.as_slice(),
+        )
+        .unwrap();
+        assert_eq!(file.as_file().metadata().unwrap().len(), 0);
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_sampled_traces() {
+        use crate::api::Vfs;
+
+        let server = Server::new(Vfs::default()).with_sampling(SamplingConfig {
+            policy: SamplingPolicy::EveryNth(2),
+            capacity: 2,
+        });
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap();
+
+        for unique in 0..6u64 {
+            let body = GetattrIn::default();
+            handle_request(
+                &server,
+                file.as_file(),
+                Opcode::Getattr,
+                ROOT_ID,
+                unique,
+                body.as_slice(),
             )
+            .unwrap();
+        }
+
+        let traces = server.sampled_traces();
+        assert_eq!(traces.len(), 2);
+        assert_eq!(traces[0].unique, 2);
+        assert_eq!(traces[1].unique, 4);
+        assert_eq!(traces[1].opcode, Opcode::Getattr as u32);
+        assert_eq!(traces[1].nodeid, ROOT_ID);
+        assert_eq!(
+            traces[1].out_len,
+            size_of::<OutHeader>() + size_of::<AttrOut>()
+        );
+        assert!(!traces[1].failed);
+        assert_eq!(format_traces(&traces).lines().count(), 2);
+
+        // Requests rejected before reaching the filesystem driver are sampled too.
+        let server = Server::new(Vfs::default()).with_sampling(SamplingConfig {
+            policy: SamplingPolicy::EveryNth(1),
+            capacity: 2,
+        });
+        let in_header = InHeader {
+            len: (size_of::<InHeader>() + 1) as u32,
+            opcode: Opcode::Getattr as u32,
+            unique: 9,
+            nodeid: ROOT_ID,
+            ..Default::default()
+        };
+        handle_bytes(&server, file.as_file(), in_header.as_slice()).unwrap();
+        let traces = server.sampled_traces();
+        assert_eq!(traces.len(), 1);
+        assert_eq!(traces[0].unique, 9);
+        assert_eq!(traces[0].out_len, size_of::<OutHeader>());
+        assert!(traces[0].failed);
+    }
+
+    #[cfg(all(feature = "fusedev", target_os = "linux"))]
+    #[test]
+    fn test_server_invalidation_bus() {
+        use crate::passthrough::{Config, PassthroughFs};
+        use crate::transport::FuseDevWriter;
+        use std::ffi::CString;
+        use std::io::{Seek, SeekFrom};
+        use std::os::unix::io::AsRawFd;
+        use vmm_sys_util::{tempdir::TempDir, tempfile::TempFile};
+
+        let source = TempDir::new().unwrap();
+        let child = TempFile::new_in(source.as_path()).unwrap();
+        let name = child.as_path().file_name().unwrap().to_str().unwrap();
+        let cfg = Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let fs = Arc::new(PassthroughFs::<()>::new(cfg).unwrap());
+        fs.import().unwrap();
+
+        let bus = InvalidationBus::new(16);
+        let server1 = Server::new(fs.clone()).with_invalidation_bus(&bus, 1);
+        let server2 = Server::new(fs).with_invalidation_bus(&bus, 1);
+        let dev1 = TempFile::new().unwrap();
+        let mut dev2 = TempFile::new().unwrap().into_file();
+
+        // The first guest removes the file.
+        let body = CString::new(name).unwrap();
+        handle_request(
+            &server1,
+            dev1.as_file(),
+            Opcode::Unlink,
+            ROOT_ID,
+            1,
+            body.as_bytes_with_nul(),
+        )
+        .unwrap();
+        assert!(server1.pending_invalidations().is_empty());
+
+        // The second session gets notified and invalidates the guest's dentry cache.
+        let events = server2.pending_invalidations();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].parent, ROOT_ID);
+        assert_eq!(events[0].name.to_str().unwrap(), name);
+
+        let mut buf = vec![0u8; 1024];
+        let w = FuseDevWriter::<()>::new(dev2.as_raw_fd(), &mut buf)
+            .unwrap()
+            .into();
+        let len = server2
+            .notify_inval_entry(w, events[0].parent, &events[0].name)
+            .unwrap();
+        assert_eq!(
+            len,
+            size_of::<OutHeader>() + size_of::<NotifyInvalEntryOut>() + name.len() + 1
         );
+
+        let mut msg = Vec::new();
+        dev2.seek(SeekFrom::Start(0)).unwrap();
+        dev2.read_to_end(&mut msg).unwrap();
+        assert_eq!(msg.len(), len);
+        let header = OutHeader::from_slice(&msg[..size_of::<OutHeader>()]).unwrap();
+        assert_eq!(header.unique, 0);
+        assert_eq!(header.error, NotifyOpcode::InvalEntry as i32);
+        assert_eq!(header.len as usize, len);
+        let out = NotifyInvalEntryOut::from_slice(
+            &msg[size_of::<OutHeader>()..size_of::<OutHeader>() + size_of::<NotifyInvalEntryOut>()],
+        )
+        .unwrap();
+        assert_eq!(out.parent, ROOT_ID);
+        assert_eq!(out.namelen as usize, name.len());
         assert_eq!(
-            ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8, 0x0, 0x0, 0x4]).unwrap(),
-            (
-                CStr::from_bytes_with_nul(&[0x1u8, 0x2u8, 0x0]).unwrap(),
-                CStr::from_bytes_with_nul(&[0x0]).unwrap(),
-            )
+            &msg[size_of::<OutHeader>() + size_of::<NotifyInvalEntryOut>()..len - 1],
+            name.as_bytes()
         );
+    }
 
-        ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8, 0x0, 0x3]).unwrap_err();
-        ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8, 0x0]).unwrap_err();
-        ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8]).unwrap_err();
+    #[cfg(all(feature = "fusedev", target_os = "linux"))]
+    #[test]
+    fn test_server_invalidation_notifier() {
+        use crate::passthrough::{Config, PassthroughFs};
+        use crate::transport::FuseDevNotifier;
+        use std::ffi::CString;
+        use std::io::{Seek, SeekFrom};
+        use vmm_sys_util::{tempdir::TempDir, tempfile::TempFile};
+
+        let source = TempDir::new().unwrap();
+        let child = TempFile::new_in(source.as_path()).unwrap();
+        let name = child.as_path().file_name().unwrap().to_str().unwrap();
+        let cfg = Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let fs = Arc::new(PassthroughFs::<()>::new(cfg).unwrap());
+        fs.import().unwrap();
+
+        // The second session sends invalidations to its fuse device as they're published.
+        let bus = InvalidationBus::new(16);
+        let mut dev2 = TempFile::new().unwrap().into_file();
+        let notifier = FuseDevNotifier::new(dev2.try_clone().unwrap(), 1 << 20);
+        let server1 = Server::new(fs.clone()).with_invalidation_bus(&bus, 1);
+        let server2 = Server::new(fs).with_invalidation_notifier(&bus, 1, Arc::new(notifier));
+        let dev1 = TempFile::new().unwrap();
+
+        let body = CString::new(name).unwrap();
+        handle_request(
+            &server1,
+            dev1.as_file(),
+            Opcode::Unlink,
+            ROOT_ID,
+            1,
+            body.as_bytes_with_nul(),
+        )
+        .unwrap();
+        assert!(server2.pending_invalidations().is_empty());
+
+        let mut msg = Vec::new();
+        dev2.seek(SeekFrom::Start(0)).unwrap();
+        dev2.read_to_end(&mut msg).unwrap();
+        let len = size_of::<OutHeader>() + size_of::<NotifyInvalEntryOut>() + name.len() + 1;
+        assert_eq!(msg.len(), len);
+        let header = OutHeader::from_slice(&msg[..size_of::<OutHeader>()]).unwrap();
+        assert_eq!(header.unique, 0);
+        assert_eq!(header.error, NotifyOpcode::InvalEntry as i32);
+        assert_eq!(header.len as usize, len);
+        let out = NotifyInvalEntryOut::from_slice(
+            &msg[size_of::<OutHeader>()..size_of::<OutHeader>() + size_of::<NotifyInvalEntryOut>()],
+        )
+        .unwrap();
+        assert_eq!((out.parent, out.namelen as usize), (ROOT_ID, name.len()));
+        assert_eq!(
+            &msg[size_of::<OutHeader>() + size_of::<NotifyInvalEntryOut>()..len - 1],
+            name.as_bytes()
+        );
+    }
+
+    #[cfg(feature = "fusedev")]
+    fn request_reply<F: FileSystem + Sync>(
+        server: &Server<F>,
+        opcode: Opcode,
+        nodeid: u64,
+        body: &[u8],
+    ) -> Vec<u8> {
+        use std::io::{Seek, SeekFrom};
+
+        let mut file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        handle_request(server, &file, opcode, nodeid, 1, body).unwrap();
+        let mut reply = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut reply).unwrap();
+        let header = OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap();
+        assert_eq!(header.error, 0, "{:?} failed", opcode);
+        reply.split_off(size_of::<OutHeader>())
+    }
+
+    #[cfg(feature = "fusedev")]
+    fn request_entry<F: FileSystem + Sync>(
+        server: &Server<F>,
+        opcode: Opcode,
+        nodeid: u64,
+        args: &[u8],
+        name: &str,
+    ) -> u64 {
+        let mut body = args.to_vec();
+        body.extend_from_slice(name.as_bytes());
+        body.push(0);
+        let reply = request_reply(server, opcode, nodeid, &body);
+        EntryOut::from_slice(&reply[..size_of::<EntryOut>()])
+            .unwrap()
+            .nodeid
+    }
+
+    #[cfg(all(feature = "fusedev", target_os = "linux"))]
+    #[test]
+    fn test_server_lookup_audit_passthrough() {
+        use crate::passthrough::{Config, PassthroughFs};
+        use vmm_sys_util::tempdir::TempDir;
+
+        let source = TempDir::new().unwrap();
+        std::fs::write(source.as_path().join("a"), b"a").unwrap();
+        std::fs::create_dir(source.as_path().join("d")).unwrap();
+        let cfg = Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let fs = Arc::new(PassthroughFs::<()>::new(cfg).unwrap());
+        fs.import().unwrap();
+        let server = Server::new(fs.clone()).with_lookup_audit(true);
+
+        let a = request_entry(&server, Opcode::Lookup, ROOT_ID, &[], "a");
+        assert_eq!(request_entry(&server, Opcode::Lookup, ROOT_ID, &[], "a"), a);
+        let mkdir = MkdirIn {
+            mode: 0o755,
+            umask: 0,
+        };
+        let e = request_entry(&server, Opcode::Mkdir, ROOT_ID, mkdir.as_slice(), "e");
+        let create = CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            ..Default::default()
+        };
+        let f = request_entry(&server, Opcode::Create, ROOT_ID, create.as_slice(), "f");
+        assert_eq!(server.lookup_counts(), vec![(a, 2), (e, 1), (f, 1)]);
+        assert!(server.lookup_audit().is_empty());
+
+        // Entries not fitting into the reply buffer must not be accounted by the backend.
+        let reply = request_reply(
+            &server,
+            Opcode::Opendir,
+            ROOT_ID,
+            OpenIn::default().as_slice(),
+        );
+        let fh = OpenOut::from_slice(&reply).unwrap().fh;
+        let read = ReadIn {
+            fh,
+            size: (size_of::<EntryOut>() + size_of::<Dirent>() + 8) as u32,
+            ..Default::default()
+        };
+        request_reply(&server, Opcode::Readdirplus, ROOT_ID, read.as_slice());
+        let read = ReadIn {
+            fh,
+            size: 1000,
+            ..Default::default()
+        };
+        request_reply(&server, Opcode::Readdirplus, ROOT_ID, read.as_slice());
+        let counts = server.lookup_counts();
+        assert_eq!(counts.len(), 4);
+        assert_eq!(counts.iter().map(|(_, n)| n).sum::<u64>(), 9);
+        assert!(server.lookup_audit().is_empty());
+
+        // Divergences are reported when the backend is mistaken about the lookup count.
+        fs.forget(&Context::default(), a, 1);
+        assert_eq!(
+            server.lookup_audit(),
+            vec![LookupDivergence {
+                inode: a,
+                expected: 3,
+                reported: 2,
+            }]
+        );
+
+        let forget = ForgetIn { nlookup: 2 };
+        handle_request(
+            &server,
+            &std::fs::File::open("/dev/null").unwrap(),
+            Opcode::Forget,
+            a,
+            2,
+            forget.as_slice(),
+        )
+        .unwrap();
+        assert_eq!(
+            server.lookup_audit(),
+            vec![LookupDivergence {
+                inode: a,
+                expected: 1,
+                reported: 0,
+            }]
+        );
+
+        let counts = server.lookup_counts();
+        let mut body = BatchForgetIn {
+            count: counts.len() as u32,
+            dummy: 0,
+        }
+        .as_slice()
+        .to_vec();
+        for (nodeid, nlookup) in counts {
+            body.extend_from_slice(ForgetOne { nodeid, nlookup }.as_slice());
+        }
+        handle_request(
+            &server,
+            &std::fs::File::open("/dev/null").unwrap(),
+            Opcode::BatchForget,
+            0,
+            3,
+            &body,
+        )
+        .unwrap();
+        assert!(server.lookup_counts().is_empty());
+        assert!(server.lookup_audit().is_empty());
+        assert_eq!(fs.debug_nlookup(e), Some(0));
+
+        // All lookup counts dropped to zero, so destroy doesn't panic.
+        request_reply(&server, Opcode::Destroy, ROOT_ID, &[]);
+    }
+
+    #[cfg(all(feature = "fusedev", target_os = "linux"))]
+    #[test]
+    fn test_server_rename2_flags_passthrough() {
+        use crate::passthrough::{Config, PassthroughFs};
+        use std::io::{Seek, SeekFrom};
+        use vmm_sys_util::tempdir::TempDir;
+
+        let source = TempDir::new().unwrap();
+        std::fs::write(source.as_path().join("a"), b"a").unwrap();
+        std::fs::write(source.as_path().join("b"), b"b").unwrap();
+        let cfg = Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        fs.import().unwrap();
+        let server = Server::new(fs);
+        let rename2 = |flags| {
+            let args = Rename2In {
+                newdir: ROOT_ID,
+                flags,
+                padding: 0,
+            };
+            let body = [args.as_slice(), b"a\0b\0"].concat();
+            let mut file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+            handle_request(&server, &file, Opcode::Rename2, ROOT_ID, 1, &body).unwrap();
+            let mut reply = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut reply).unwrap();
+            OutHeader::from_slice(&reply[..size_of::<OutHeader>()])
+                .unwrap()
+                .error
+        };
+
+        assert_eq!(rename2(libc::RENAME_NOREPLACE), -libc::EEXIST);
+        assert_eq!(rename2(libc::RENAME_EXCHANGE), 0);
+        assert_eq!(std::fs::read(source.as_path().join("a")).unwrap(), b"b");
+        assert_eq!(std::fs::read(source.as_path().join("b")).unwrap(), b"a");
+    }
+
+    #[cfg(all(feature = "fusedev", feature = "test-utils"))]
+    #[test]
+    fn test_server_mem_fs_loopback() {
+        use crate::api::MemFs;
+
+        let fs = Arc::new(
+            MemFs::builder()
+                .with_file("/d/a", 0o644, b"abc")
+                .build()
+                .unwrap(),
+        );
+        let server = Server::new(fs.clone()).with_lookup_audit(true);
+
+        let d = request_entry(&server, Opcode::Lookup, ROOT_ID, &[], "d");
+        let a = request_entry(&server, Opcode::Lookup, d, &[], "a");
+        assert_eq!((d, a), (2, 3));
+        let mkdir = MkdirIn {
+            mode: 0o755,
+            umask: 0,
+        };
+        let e = request_entry(&server, Opcode::Mkdir, d, mkdir.as_slice(), "e");
+        assert_eq!(e, 4);
+
+        let reply = request_reply(&server, Opcode::Open, a, OpenIn::default().as_slice());
+        let fh = OpenOut::from_slice(&reply[..size_of::<OpenOut>()])
+            .unwrap()
+            .fh;
+        let read = ReadIn {
+            fh,
+            size: 16,
+            ..Default::default()
+        };
+        assert_eq!(
+            request_reply(&server, Opcode::Read, a, read.as_slice()),
+            b"abc"
+        );
+
+        // The backend and the server agree on lookup counts, down to forgetting everything.
+        let reply = request_reply(&server, Opcode::Opendir, d, OpenIn::default().as_slice());
+        let fh = OpenOut::from_slice(&reply).unwrap().fh;
+        let read = ReadIn {
+            fh,
+            size: 1000,
+            ..Default::default()
+        };
+        request_reply(&server, Opcode::Readdirplus, d, read.as_slice());
+        assert_eq!(server.lookup_counts(), vec![(d, 1), (a, 2), (e, 2)]);
+        assert!(server.lookup_audit().is_empty());
+        let counts = server.lookup_counts();
+        let mut body = BatchForgetIn {
+            count: counts.len() as u32,
+            dummy: 0,
+        }
+        .as_slice()
+        .to_vec();
+        for (nodeid, nlookup) in counts {
+            body.extend_from_slice(ForgetOne { nodeid, nlookup }.as_slice());
+        }
+        handle_request(
+            &server,
+            &std::fs::File::open("/dev/null").unwrap(),
+            Opcode::BatchForget,
+            0,
+            2,
+            &body,
+        )
+        .unwrap();
+        assert!(server.lookup_audit().is_empty());
+        assert_eq!(fs.debug_nlookup(a), Some(0));
+    }
+
+    #[cfg(all(feature = "fusedev", target_os = "linux"))]
+    #[test]
+    fn test_server_lookup_audit_vfs() {
+        use crate::api::Vfs;
+        use crate::passthrough::{Config, PassthroughFs};
+        use vmm_sys_util::tempdir::TempDir;
+
+        let source = TempDir::new().unwrap();
+        std::fs::write(source.as_path().join("a"), b"a").unwrap();
+        let cfg = Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        fs.import().unwrap();
+        let vfs = Vfs::default();
+        vfs.mount(Box::new(fs), "/x/y").unwrap();
+        let server = Server::new(vfs).with_lookup_audit(true);
+
+        let x = request_entry(&server, Opcode::Lookup, ROOT_ID, &[], "x");
+        let y = request_entry(&server, Opcode::Lookup, x, &[], "y");
+        let a = request_entry(&server, Opcode::Lookup, y, &[], "a");
+        let mkdir = MkdirIn {
+            mode: 0o755,
+            umask: 0,
+        };
+        let e = request_entry(&server, Opcode::Mkdir, y, mkdir.as_slice(), "e");
+        assert_eq!(server.lookup_counts(), vec![(x, 1), (y, 1), (a, 1), (e, 1)]);
+        // Pseudo fs inodes and mount roots are not audited.
+        assert_eq!(server.fs.debug_nlookup(x.into()), None);
+        assert_eq!(server.fs.debug_nlookup(y.into()), None);
+        assert_eq!(server.fs.debug_nlookup(a.into()), Some(1));
+        assert!(server.lookup_audit().is_empty());
+
+        server.fs.forget(&Context::default(), e.into(), 1);
+        assert_eq!(
+            server.lookup_audit(),
+            vec![LookupDivergence {
+                inode: e,
+                expected: 1,
+                reported: 0,
+            }]
+        );
+
+        for (nodeid, nlookup) in server.lookup_counts() {
+            let forget = ForgetIn { nlookup };
+            handle_request(
+                &server,
+                &std::fs::File::open("/dev/null").unwrap(),
+                Opcode::Forget,
+                nodeid,
+                2,
+                forget.as_slice(),
+            )
+            .unwrap();
+        }
+        assert!(server.lookup_counts().is_empty());
+        assert!(server.lookup_audit().is_empty());
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[derive(Clone, Default)]
+    struct ShutdownLog(Arc<std::sync::Mutex<Vec<&'static str>>>);
+
+    #[cfg(feature = "fusedev")]
+    impl ShutdownLog {
+        fn push(&self, event: &'static str) {
+            self.0.lock().unwrap().push(event);
+        }
+
+        fn events(&self) -> Vec<&'static str> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    struct ShutdownFs {
+        log: ShutdownLog,
+        delay: std::time::Duration,
+        busy: std::sync::atomic::AtomicBool,
+    }
+
+    #[cfg(feature = "fusedev")]
+    impl FileSystem for ShutdownFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn getattr(
+            &self,
+            _ctx: &Context,
+            _inode: u64,
+            _handle: Option<u64>,
+        ) -> io::Result<(stat64, std::time::Duration)> {
+            self.log.push("getattr start");
+            std::thread::sleep(self.delay);
+            self.log.push("getattr done");
+            Err(io::Error::from_raw_os_error(libc::ENOSYS))
+        }
+
+        fn prepare_destroy(&self) -> io::Result<()> {
+            if self.busy.swap(false, std::sync::atomic::Ordering::SeqCst) {
+                self.log.push("prepare busy");
+                Err(io::Error::from_raw_os_error(libc::EBUSY))
+            } else {
+                self.log.push("prepare ready");
+                Ok(())
+            }
+        }
+
+        fn forget_all(&self) {
+            self.log.push("forget all");
+        }
+
+        fn destroy(&self) {
+            self.log.push("destroy");
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    struct FakeSession(ShutdownLog);
+
+    #[cfg(feature = "fusedev")]
+    impl ShutdownSession for FakeSession {
+        fn shutdown(&self) -> io::Result<()> {
+            self.0.push("session shutdown");
+            Ok(())
+        }
+
+        fn abort(&self) -> io::Result<()> {
+            self.0.push("session abort");
+            Ok(())
+        }
+
+        fn umount(&mut self) -> io::Result<()> {
+            self.0.push("session umount");
+            Ok(())
+        }
+    }
+
+    // Start a getattr request on another thread, and wait until the server is handling it.
+    #[cfg(feature = "fusedev")]
+    fn start_getattr(
+        server: &Arc<Server<ShutdownFs>>,
+        log: &ShutdownLog,
+    ) -> std::thread::JoinHandle<()> {
+        let srv = server.clone();
+        let handle = std::thread::spawn(move || {
+            let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+            let args = GetattrIn::default();
+            handle_request(&srv, &file, Opcode::Getattr, 1, 1, args.as_slice()).unwrap();
+        });
+        while log.events().is_empty() {
+            std::thread::yield_now();
+        }
+        handle
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_graceful_shutdown_order() {
+        use std::time::Duration;
+
+        let log = ShutdownLog::default();
+        let server = Arc::new(Server::new(ShutdownFs {
+            log: log.clone(),
+            delay: Duration::from_millis(50),
+            busy: std::sync::atomic::AtomicBool::new(true),
+        }));
+        let mut session = FakeSession(log.clone());
+        let observed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let obs = observed.clone();
+        let mut shutdown = GracefulShutdown::new()
+            .with_drain_timeout(Duration::from_secs(10))
+            .with_prepare_timeout(Duration::from_secs(10))
+            .with_observer(move |e| obs.lock().unwrap().push(e));
+
+        let handle = start_getattr(&server, &log);
+        shutdown.run(&mut session, &server).unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(
+            log.events(),
+            vec![
+                "getattr start",
+                "session shutdown",
+                "getattr done",
+                "prepare busy",
+                "prepare ready",
+                "forget all",
+                "destroy",
+                "session umount",
+            ]
+        );
+        assert_eq!(
+            *observed.lock().unwrap(),
+            vec![
+                ShutdownEvent::SessionShutdown,
+                ShutdownEvent::Drained,
+                ShutdownEvent::PrepareDestroy,
+                ShutdownEvent::Destroyed,
+                ShutdownEvent::Unmounted,
+            ]
+        );
+        assert_eq!(server.inflight_requests(), 0);
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_destroy_forget_all() {
+        use std::time::Duration;
+
+        let log = ShutdownLog::default();
+        let server = Arc::new(Server::new(ShutdownFs {
+            log: log.clone(),
+            delay: Duration::from_millis(50),
+            busy: std::sync::atomic::AtomicBool::new(false),
+        }));
+
+        // Inodes are forgotten once the racing request completed.
+        let handle = start_getattr(&server, &log);
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        handle_request(&server, &file, Opcode::Destroy, ROOT_ID, 2, &[]).unwrap();
+        handle.join().unwrap();
+        assert_eq!(
+            log.events(),
+            vec!["getattr start", "getattr done", "forget all", "destroy"]
+        );
+        assert_eq!(server.inflight_requests(), 0);
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_graceful_shutdown_timeout() {
+        use std::time::Duration;
+
+        let log = ShutdownLog::default();
+        let server = Arc::new(Server::new(ShutdownFs {
+            log: log.clone(),
+            delay: Duration::from_millis(500),
+            busy: std::sync::atomic::AtomicBool::new(true),
+        }));
+        let mut session = FakeSession(log.clone());
+        let mut shutdown = GracefulShutdown::new()
+            .with_drain_timeout(Duration::from_millis(20))
+            .with_prepare_timeout(Duration::from_millis(20));
+
+        let handle = start_getattr(&server, &log);
+        let err = shutdown.run(&mut session, &server).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        handle.join().unwrap();
+
+        // The file system must not be destroyed while a request is still in flight.
+        assert_eq!(
+            log.events(),
+            vec![
+                "getattr start",
+                "session shutdown",
+                "session abort",
+                "session umount",
+                "getattr done",
+            ]
+        );
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[derive(Default)]
+    struct TypedFs {
+        reads: std::sync::atomic::AtomicUsize,
+    }
+
+    #[cfg(feature = "fusedev")]
+    impl FileSystem for TypedFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn getattr(
+            &self,
+            _ctx: &Context,
+            inode: u64,
+            _handle: Option<u64>,
+        ) -> io::Result<(stat64, std::time::Duration)> {
+            let mut st: stat64 = unsafe { std::mem::zeroed() };
+            st.st_ino = inode;
+            Ok((st, std::time::Duration::from_secs(1)))
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        fn read(
+            &self,
+            _ctx: &Context,
+            _inode: u64,
+            _handle: u64,
+            w: &mut dyn ZeroCopyWriter,
+            _size: u32,
+            _offset: u64,
+            _lock_owner: Option<u64>,
+            _flags: u32,
+        ) -> io::Result<usize> {
+            self.reads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            w.write(b"typed")
+        }
+
+        // Fail with errors wrapped by context messages.
+        fn readlink(&self, _ctx: &Context, inode: u64) -> io::Result<Vec<u8>> {
+            use crate::api::errno::{fuse_errno, ErrnoContext};
+
+            if inode == 5 {
+                Err(fuse_errno(libc::ENAMETOOLONG, "link too long"))
+                    .with_errno_context(|| format!("readlink inode {}", inode))
+            } else {
+                Err(io::Error::other("unknown link"))
+            }
+        }
+    }
+
+    // Stripe data to a backing file in reverse segment order.
+    #[cfg(feature = "fusedev")]
+    struct StripedFs {
+        file: std::fs::File,
+        stripe: usize,
+    }
+
+    #[cfg(feature = "fusedev")]
+    impl StripedFs {
+        fn segments(&self, size: usize) -> Vec<(usize, usize)> {
+            let mut segs: Vec<(usize, usize)> = (0..size)
+                .step_by(self.stripe)
+                .map(|off| (off, cmp::min(self.stripe, size - off)))
+                .collect();
+            segs.reverse();
+            segs
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    impl FileSystem for StripedFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        #[allow(clippy::too_many_arguments)]
+        fn read(
+            &self,
+            _ctx: &Context,
+            _inode: u64,
+            _handle: u64,
+            w: &mut dyn ZeroCopyWriter,
+            size: u32,
+            offset: u64,
+            _lock_owner: Option<u64>,
+            _flags: u32,
+        ) -> io::Result<usize> {
+            let mut f = self.file.try_clone()?;
+            for (off, len) in self.segments(size as usize) {
+                w.write_from_at_offset(&mut f, len, offset + off as u64, off)?;
+            }
+            Ok(size as usize)
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        fn write(
+            &self,
+            _ctx: &Context,
+            _inode: u64,
+            _handle: u64,
+            r: &mut dyn ZeroCopyReader,
+            size: u32,
+            offset: u64,
+            _lock_owner: Option<u64>,
+            _delayed_write: bool,
+            _flags: u32,
+            _fuse_flags: u32,
+        ) -> io::Result<usize> {
+            let mut f = self.file.try_clone()?;
+            for (off, len) in self.segments(size as usize) {
+                r.read_to_at_offset(&mut f, len, offset + off as u64, off)?;
+            }
+            Ok(size as usize)
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_offset_read_write() {
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        let server = Server::new(StripedFs {
+            file: file.try_clone().unwrap(),
+            stripe: 7,
+        });
+
+        let data: Vec<u8> = (0..50u8).collect();
+        let args = WriteIn {
+            offset: 3,
+            size: data.len() as u32,
+            ..Default::default()
+        };
+        let mut body = args.as_slice().to_vec();
+        body.extend_from_slice(&data);
+        let reply = request_reply(&server, Opcode::Write, 5, &body);
+        let mut out = WriteOut::default();
+        out.as_mut_slice()
+            .copy_from_slice(&reply[..size_of::<WriteOut>()]);
+        assert_eq!(out.size as usize, data.len());
+
+        let mut content = Vec::new();
+        (&file).read_to_end(&mut content).unwrap();
+        assert_eq!(&content[..3], &[0u8; 3]);
+        assert_eq!(&content[3..], &data[..]);
+
+        let args = ReadIn {
+            offset: 1,
+            size: 40,
+            ..Default::default()
+        };
+        let reply = request_reply(&server, Opcode::Read, 5, args.as_slice());
+        assert_eq!(&reply[..], &content[1..41]);
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_dispatch_table() {
+        use std::io::{Seek, SeekFrom};
+        use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+        let mut file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        let data: Vec<u8> = (0..50u8).collect();
+        file.write_all(&data).unwrap();
+
+        let bytes = Arc::new(AtomicUsize::new(0));
+        let offsets = Arc::new(AtomicU64::new(0));
+        let (b, o) = (bytes.clone(), offsets.clone());
+        let table = DispatchTable::builder()
+            .wrap(Opcode::Read, move |req| {
+                let args: ReadIn = req.args().map_err(Error::DecodeMessage)?;
+                o.fetch_add(args.offset, Ordering::Relaxed);
+                let res = req.call_default();
+                if let Ok(n) = res {
+                    b.fetch_add(n, Ordering::Relaxed);
+                }
+                res
+            })
+            .replace(Opcode::Statfs, |req| {
+                let out = StatfsOut {
+                    st: Kstatfs {
+                        blocks: 42,
+                        bsize: 4096,
+                        ..Default::default()
+                    },
+                };
+                req.reply(out.as_slice())
+            })
+            .replace(Opcode::Getattr, |req| {
+                assert_eq!(req.header().nodeid, 5);
+                Ok(0)
+            })
+            .build();
+        assert!(table.is_customized(Opcode::Read));
+        assert!(!table.is_customized(Opcode::Write));
+        let server = Server::new(StripedFs { file, stripe: 7 }).with_dispatch_table(table);
+
+        // The wrapper observes the decoded arguments, and the builtin handler still decodes them.
+        let args = ReadIn {
+            offset: 1,
+            size: 40,
+            ..Default::default()
+        };
+        let reply = request_reply(&server, Opcode::Read, 5, args.as_slice());
+        assert_eq!(&reply[..], &data[1..41]);
+        assert_eq!(offsets.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            bytes.load(Ordering::Relaxed),
+            size_of::<OutHeader>() + reply.len()
+        );
+
+        // StripedFs doesn't implement statfs, the replacement replies on its own.
+        let reply = request_reply(&server, Opcode::Statfs, 1, &[]);
+        let out = StatfsOut::from_slice(&reply).unwrap();
+        assert_eq!(out.st.blocks, 42);
+
+        // Requests left unanswered by handlers fail.
+        let args = GetattrIn::default();
+        let mut file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        handle_request(&server, &file, Opcode::Getattr, 5, 1, args.as_slice()).unwrap();
+        let mut reply = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut reply).unwrap();
+        let header = OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap();
+        assert_eq!(header.error, -libc::EIO);
+    }
+
+    // Intercept FUSE_READ, and optionally misbehave by consuming the payload of other requests.
+    #[cfg(feature = "fusedev")]
+    #[derive(Default)]
+    struct ReadInterceptor {
+        headers: std::sync::Mutex<Vec<InHeader>>,
+        touch_unhandled: bool,
+    }
+
+    #[cfg(feature = "fusedev")]
+    impl RawFileSystem for ReadInterceptor {
+        fn handle_raw(
+            &self,
+            header: &InHeader,
+            payload: &mut dyn io::Read,
+            reply: &mut dyn io::Write,
+        ) -> io::Result<crate::api::filesystem::RawHandled> {
+            use crate::api::filesystem::RawHandled;
+
+            self.headers.lock().unwrap().push(*header);
+            if header.opcode != Opcode::Read as u32 {
+                if self.touch_unhandled {
+                    payload.read_exact(&mut [0u8; 1])?;
+                }
+                return Ok(RawHandled::NotHandled);
+            }
+
+            let mut args = ReadIn::default();
+            payload.read_exact(args.as_mut_slice())?;
+            let data = &b"raw-read"[..args.size as usize];
+            let out = OutHeader {
+                len: (size_of::<OutHeader>() + data.len()) as u32,
+                error: 0,
+                unique: header.unique,
+            };
+            reply.write_all(out.as_slice())?;
+            reply.write_all(data)?;
+            Ok(RawHandled::Handled)
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_raw_handler() {
+        let raw = Arc::new(ReadInterceptor::default());
+        let server = Server::new(TypedFs::default()).with_raw_handler(raw.clone());
+
+        let args = ReadIn {
+            size: 3,
+            ..Default::default()
+        };
+        let reply = request_reply(&server, Opcode::Read, 5, args.as_slice());
+        assert_eq!(reply, b"raw");
+        assert_eq!(server.fs.reads.load(std::sync::atomic::Ordering::SeqCst), 0);
+
+        let reply = request_reply(&server, Opcode::Getattr, 5, GetattrIn::default().as_slice());
+        let out = AttrOut::from_slice(&reply[..size_of::<AttrOut>()]).unwrap();
+        assert_eq!(out.attr.ino, 5);
+
+        let headers = raw.headers.lock().unwrap();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[0].opcode, Opcode::Read as u32);
+        assert_eq!(headers[1].opcode, Opcode::Getattr as u32);
+        assert_eq!(headers[1].nodeid, 5);
+
+        // Without the raw handler, requests go through the typed path.
+        let server = Server::new(TypedFs::default());
+        let reply = request_reply(&server, Opcode::Read, 5, args.as_slice());
+        assert_eq!(reply, b"typed");
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_raw_handler_touch_unhandled() {
+        use std::io::{Seek, SeekFrom};
+
+        let raw = Arc::new(ReadInterceptor {
+            touch_unhandled: true,
+            ..Default::default()
+        });
+        let server = Server::new(TypedFs::default()).with_raw_handler(raw);
+
+        let mut file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        let args = GetattrIn::default();
+        handle_request(&server, &file, Opcode::Getattr, 5, 1, args.as_slice()).unwrap();
+        let mut reply = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut reply).unwrap();
+        let header = OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap();
+        assert_eq!(header.error, -libc::EIO);
+    }
+
+    // Reply with the reversed payload, fail requests without payload.
+    #[cfg(feature = "fusedev")]
+    #[derive(Default)]
+    struct ReverseOpcode {
+        calls: std::sync::atomic::AtomicUsize,
+        no_reply: bool,
+    }
+
+    #[cfg(feature = "fusedev")]
+    impl RawOpcodeHandler for ReverseOpcode {
+        fn handle(
+            &self,
+            header: &InHeader,
+            payload: &mut dyn io::Read,
+            reply: &mut dyn io::Write,
+        ) -> io::Result<()> {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let mut data = vec![0u8; header.len as usize - size_of::<InHeader>()];
+            payload.read_exact(&mut data)?;
+            if data.is_empty() {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            data.reverse();
+            reply.write_all(&data)
+        }
+
+        fn expects_reply(&self) -> bool {
+            !self.no_reply
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    fn opcode_reply<F: FileSystem + Sync>(server: &Server<F>, opcode: u32, body: &[u8]) -> Vec<u8> {
+        use std::io::{Seek, SeekFrom};
+
+        let mut file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        handle_opcode(server, &file, opcode, 5, 7, body).unwrap();
+        let mut reply = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut reply).unwrap();
+        reply
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_malformed_requests() {
+        use std::io::{Seek, SeekFrom};
+
+        let server = Server::new(TypedFs::default());
+        let request = |opcode: u32, len: usize, body: &[u8]| {
+            let mut file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+            let in_header = InHeader {
+                len: len as u32,
+                opcode,
+                unique: 7,
+                nodeid: ROOT_ID,
+                ..Default::default()
+            };
+            let msg = [in_header.as_slice(), body].concat();
+            let res = handle_bytes(&server, &file, &msg);
+            let mut reply = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut reply).unwrap();
+            (res, reply)
+        };
+        let error = |opcode: Opcode, len: usize, body: &[u8]| {
+            let (res, reply) = request(opcode as u32, len, body);
+            assert!(res.is_ok());
+            let header = OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap();
+            assert_eq!(header.unique, 7);
+            header.error
+        };
+        let hdr = size_of::<InHeader>();
+        let getattr = GetattrIn::default();
+
+        // The length is smaller than the header, or runs past the request.
+        for len in [0, 1, hdr - 1] {
+            assert_eq!(
+                error(Opcode::Getattr, len, getattr.as_slice()),
+                -libc::EINVAL
+            );
+        }
+        let len = hdr + size_of::<GetattrIn>();
+        assert_eq!(
+            error(Opcode::Getattr, len + 1, getattr.as_slice()),
+            -libc::EINVAL
+        );
+        assert_eq!(error(Opcode::Getattr, hdr + 0x1000, b""), -libc::EINVAL);
+        assert_ne!(
+            error(Opcode::Getattr, len, getattr.as_slice()),
+            -libc::EINVAL
+        );
+
+        // The body is too short for the arguments of the opcode.
+        let args = &getattr.as_slice()[..4];
+        assert_eq!(error(Opcode::Getattr, hdr + 4, args), -libc::EINVAL);
+
+        // Requests without reply are dropped.
+        let forget = ForgetIn { nlookup: 1 };
+        let (res, reply) = request(Opcode::Forget as u32, hdr - 1, forget.as_slice());
+        assert!(matches!(res, Err(Error::InvalidHeaderLength)));
+        assert!(reply.is_empty());
+        let (res, reply) = request(Opcode::BatchForget as u32, hdr + 4, &[0u8; 4]);
+        assert!(matches!(res, Err(Error::InvalidHeaderLength)));
+        assert!(reply.is_empty());
+
+        // Unknown opcodes, within the range of known ones or not.
+        for opcode in [0, 7, 19, Opcode::MaxOpcode as u32, 1000, u32::MAX] {
+            assert!(matches!(Opcode::from(opcode), Opcode::MaxOpcode));
+            let (res, reply) = request(opcode, hdr, b"");
+            assert!(res.is_ok());
+            let header = OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap();
+            assert_eq!(header.error, -libc::ENOSYS, "opcode {}", opcode);
+        }
+
+        // Strings without a nul terminator.
+        let setxattr = SetxattrIn {
+            size: 1,
+            ..Default::default()
+        };
+        let cases: [(Opcode, Vec<u8>); 5] = [
+            (Opcode::Lookup, b"a".to_vec()),
+            (Opcode::Symlink, b"a\0b".to_vec()),
+            (
+                Opcode::Getxattr,
+                [GetxattrIn::default().as_slice(), b"user.a"].concat(),
+            ),
+            (Opcode::Removexattr, b"user.a".to_vec()),
+            (Opcode::Setxattr, [setxattr.as_slice(), b"user.a"].concat()),
+        ];
+        for (opcode, body) in cases.iter() {
+            let err = error(*opcode, hdr + body.len(), body);
+            assert_eq!(err, -libc::EINVAL, "{:?}", opcode);
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_custom_opcode() {
+        const CUSTOM: u32 = 4096;
+
+        let mut server = Server::new(TypedFs::default());
+        let reply = opcode_reply(&server, CUSTOM, b"abc");
+        let header = OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap();
+        assert_eq!(header.error, -libc::ENOSYS);
+
+        server
+            .register_opcode_handler(CUSTOM, Box::new(ReverseOpcode::default()))
+            .unwrap();
+        let reply = opcode_reply(&server, CUSTOM, b"abc");
+        let header = OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap();
+        assert_eq!(header.error, 0);
+        assert_eq!(header.unique, 7);
+        assert_eq!(header.len as usize, reply.len());
+        assert_eq!(&reply[size_of::<OutHeader>()..], b"cba");
+
+        // Errors of the handler are replied.
+        let reply = opcode_reply(&server, CUSTOM, b"");
+        let header = OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap();
+        assert_eq!(header.error, -libc::EINVAL);
+        assert_eq!(reply.len(), size_of::<OutHeader>());
+
+        let handler = ReverseOpcode {
+            no_reply: true,
+            ..Default::default()
+        };
+        server
+            .register_opcode_handler(CUSTOM + 1, Box::new(handler))
+            .unwrap();
+        assert!(opcode_reply(&server, CUSTOM + 1, b"abc").is_empty());
+
+        let err = server
+            .register_opcode_handler(CUSTOM, Box::new(ReverseOpcode::default()))
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+        for opcode in [Opcode::Init, Opcode::Destroy, Opcode::Forget, Opcode::Read] {
+            let err = server
+                .register_opcode_handler(opcode as u32, Box::new(ReverseOpcode::default()))
+                .unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_custom_opcode_override() {
+        let mut server = Server::new(TypedFs::default()).with_unchecked_opcode_overrides();
+        server
+            .register_opcode_handler(Opcode::Read as u32, Box::new(ReverseOpcode::default()))
+            .unwrap();
+
+        let reply = opcode_reply(&server, Opcode::Read as u32, b"abc");
+        assert_eq!(&reply[size_of::<OutHeader>()..], b"cba");
+        assert_eq!(server.fs.reads.load(std::sync::atomic::Ordering::SeqCst), 0);
+
+        // Other builtin opcodes are still dispatched by the server.
+        let reply = request_reply(&server, Opcode::Getattr, 5, GetattrIn::default().as_slice());
+        let out = AttrOut::from_slice(&reply[..size_of::<AttrOut>()]).unwrap();
+        assert_eq!(out.attr.ino, 5);
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_errno_context() {
+        use std::io::{Seek, SeekFrom};
+
+        // The errno of wrapped errors is replied, and other errors are replied as EIO.
+        let server = Server::new(TypedFs::default());
+        for (nodeid, errno) in [(5, libc::ENAMETOOLONG), (6, libc::EIO)] {
+            let mut file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+            handle_request(&server, &file, Opcode::Readlink, nodeid, 1, &[]).unwrap();
+            let mut reply = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut reply).unwrap();
+            let header = OutHeader::from_slice(&reply).unwrap();
+            assert_eq!(header.error, -errno);
+        }
+    }
+
+    // Ask for the 8 bytes at `arg` as input and output of unrestricted ioctls, and reply them
+    // reversed once retried.
+    #[cfg(feature = "fusedev")]
+    struct IoctlFs;
+
+    #[cfg(feature = "fusedev")]
+    impl FileSystem for IoctlFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        #[allow(clippy::too_many_arguments)]
+        fn ioctl(
+            &self,
+            _ctx: &Context,
+            _inode: u64,
+            _handle: u64,
+            _flags: u32,
+            cmd: u32,
+            arg: u64,
+            in_data: &[u8],
+            out_size: u32,
+        ) -> io::Result<IoctlReply> {
+            let iov = |len| IoctlIovec { base: arg, len };
+            match (cmd, in_data.len()) {
+                (1, 0) => Ok(IoctlReply::Retry {
+                    input: vec![iov(8)],
+                    output: vec![iov(8)],
+                }),
+                (1, _) => Ok(IoctlReply::Done {
+                    result: 0,
+                    data: in_data.iter().rev().copied().collect(),
+                }),
+                (2, _) => Ok(IoctlReply::Retry {
+                    input: vec![iov(1 << 20)],
+                    output: vec![],
+                }),
+                (3, _) => Ok(IoctlReply::Retry {
+                    input: vec![iov(1); 257],
+                    output: vec![],
+                }),
+                _ => Ok(IoctlReply::Done {
+                    result: 7,
+                    data: vec![0; out_size as usize + 1],
+                }),
+            }
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_ioctl_retry() {
+        use std::io::{Seek, SeekFrom};
+
+        let server = Server::new(IoctlFs);
+        let ioctl = |cmd, flags: IoctlFlags, arg, data: &[u8]| {
+            let ioctl_in = IoctlIn {
+                cmd,
+                flags: flags.bits(),
+                arg,
+                in_size: data.len() as u32,
+                out_size: 8,
+                ..Default::default()
+            };
+            let mut body = ioctl_in.as_slice().to_vec();
+            body.extend_from_slice(data);
+            let mut file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+            handle_request(&server, &file, Opcode::Ioctl, 2, 1, &body).unwrap();
+            let mut reply = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut reply).unwrap();
+            let header = OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap();
+            (header.error, reply.split_off(size_of::<OutHeader>()))
+        };
+        let unrestricted = IoctlFlags::IOCTL_UNRESTRICTED;
+
+        // The retry carries the input iovecs followed by the output iovecs.
+        let (error, reply) = ioctl(1, unrestricted, 0x1000, &[]);
+        assert_eq!(error, 0);
+        let out = IoctlOut::from_slice(&reply[..size_of::<IoctlOut>()]).unwrap();
+        assert_eq!(out.flags, IoctlFlags::IOCTL_RETRY.bits());
+        assert_eq!((out.in_iovs, out.out_iovs), (1, 1));
+        let iovs = &reply[size_of::<IoctlOut>()..];
+        assert_eq!(iovs.len(), 2 * size_of::<IoctlIovec>());
+        let iov = IoctlIovec::from_slice(&iovs[size_of::<IoctlIovec>()..]).unwrap();
+        assert_eq!((iov.base, iov.len), (0x1000, 8));
+
+        // The retried ioctl is done with the copied in data.
+        let (error, reply) = ioctl(1, unrestricted, 0x1000, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(error, 0);
+        let out = IoctlOut::from_slice(&reply[..size_of::<IoctlOut>()]).unwrap();
+        assert_eq!(out.flags, 0);
+        assert_eq!(&reply[size_of::<IoctlOut>()..], &[8, 7, 6, 5, 4, 3, 2, 1]);
+
+        // Retries of restricted ioctls, and of 32-bit callers beyond their address space, fail.
+        assert_eq!(ioctl(1, IoctlFlags::empty(), 0x1000, &[]).0, -libc::EIO);
+        let compat = unrestricted | IoctlFlags::IOCTL_COMPAT;
+        assert_eq!(ioctl(1, compat, 0x1000, &[]).0, 0);
+        assert_eq!(ioctl(1, compat, 1 << 32, &[]).0, -libc::EIO);
+
+        // Retries are limited to the pages of a request and to the max number of iovecs.
+        assert_eq!(ioctl(2, unrestricted, 0x1000, &[]).0, -libc::ENOMEM);
+        assert_eq!(ioctl(3, unrestricted, 0x1000, &[]).0, -libc::ENOMEM);
+
+        // Output larger than requested fails.
+        assert_eq!(ioctl(4, IoctlFlags::empty(), 0, &[]).0, -libc::EIO);
+    }
+
+    // Log the requests of the macFUSE opcodes.
+    #[cfg(all(feature = "fusedev", target_os = "macos"))]
+    #[derive(Default)]
+    struct MacFs(std::sync::Mutex<Vec<String>>);
+
+    #[cfg(all(feature = "fusedev", target_os = "macos"))]
+    impl FileSystem for MacFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn setvolname(&self, _ctx: &Context, name: &CStr) -> io::Result<()> {
+            let mut log = self.0.lock().unwrap();
+            log.push(format!("setvolname {}", name.to_str().unwrap()));
+            Ok(())
+        }
+
+        fn exchange(
+            &self,
+            _ctx: &Context,
+            olddir: u64,
+            oldname: &CStr,
+            newdir: u64,
+            newname: &CStr,
+            options: u64,
+        ) -> io::Result<()> {
+            let mut log = self.0.lock().unwrap();
+            log.push(format!(
+                "exchange {} {} {} {} {}",
+                olddir,
+                oldname.to_str().unwrap(),
+                newdir,
+                newname.to_str().unwrap(),
+                options
+            ));
+            Ok(())
+        }
+
+        fn getxtimes(&self, _ctx: &Context, inode: u64) -> io::Result<GetxtimesOut> {
+            Ok(GetxtimesOut {
+                bkuptime: inode,
+                crtime: 0x0201,
+                bkuptimensec: 3,
+                crtimensec: 4,
+            })
+        }
+    }
+
+    #[cfg(all(feature = "fusedev", target_os = "macos"))]
+    #[test]
+    fn test_server_macos_opcodes() {
+        let server = Server::new(MacFs::default());
+
+        let reply = request_reply(&server, Opcode::Getxtimes, 5, &[]);
+        assert_eq!(
+            reply,
+            [5, 0, 0, 0, 0, 0, 0, 0, 1, 2, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0]
+        );
+        request_reply(&server, Opcode::Setvolname, ROOT_ID, b"Backup\0");
+        let args = ExchangeIn {
+            olddir: 2,
+            newdir: 3,
+            options: 1,
+        };
+        let mut body = args.as_slice().to_vec();
+        body.extend_from_slice(b"a\0b\0");
+        assert!(request_reply(&server, Opcode::Exchange, 2, &body).is_empty());
+        assert_eq!(
+            *server.fs.0.lock().unwrap(),
+            ["setvolname Backup", "exchange 2 a 3 b 1"]
+        );
+    }
+
+    // Report files ready for reading once woken up.
+    #[cfg(feature = "fusedev")]
+    struct PollFs(Arc<PollNotifier>);
+
+    #[cfg(feature = "fusedev")]
+    impl FileSystem for PollFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn poll(
+            &self,
+            _ctx: &Context,
+            _inode: u64,
+            _handle: u64,
+            khandle: u64,
+            _flags: u32,
+            events: u32,
+        ) -> io::Result<u32> {
+            if self.0.is_scheduled(khandle) {
+                Ok(0)
+            } else {
+                Ok(events & libc::POLLIN as u32)
+            }
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_poll_notify() {
+        use crate::transport::FuseDevWriter;
+        use std::io::{Seek, SeekFrom};
+        use std::os::unix::io::AsRawFd;
+
+        let notifier = Arc::new(PollNotifier::new());
+        let server = Server::new(PollFs(notifier.clone())).with_poll_notifier(notifier.clone());
+        let poll = |flags| {
+            let poll_in = PollIn {
+                fh: 3,
+                kh: 7,
+                flags,
+                events: libc::POLLIN as u32,
+            };
+            let reply = request_reply(&server, Opcode::Poll, 2, poll_in.as_slice());
+            PollOut::from_slice(&reply).unwrap().revents
+        };
+
+        // Pollers asking for a notification wait for a wakeup, others don't.
+        assert_eq!(poll(0), libc::POLLIN as u32);
+        assert_eq!(poll(POLL_SCHEDULE_NOTIFY), 0);
+        assert!(notifier.wakeup(7));
+        assert!(!notifier.wakeup(7));
+        assert_eq!(notifier.take(), vec![7]);
+
+        let mut file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        let mut buf = vec![0u8; 64];
+        let w = FuseDevWriter::<()>::new(file.as_raw_fd(), &mut buf).unwrap();
+        server.notify_poll_wakeup(w.into(), 7).unwrap();
+        let mut reply = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut reply).unwrap();
+        let header = OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap();
+        assert_eq!(header.error, NotifyOpcode::Poll as i32);
+        let out = NotifyPollWakeupOut::from_slice(&reply[size_of::<OutHeader>()..]).unwrap();
+        assert_eq!(out.kh, 7);
+
+        // Releasing the file handle forgets its poll handles.
+        assert_eq!(poll(POLL_SCHEDULE_NOTIFY), 0);
+        let release = ReleaseIn {
+            fh: 3,
+            ..Default::default()
+        };
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        handle_request(&server, &file, Opcode::Release, 2, 1, release.as_slice()).unwrap();
+        assert!(!notifier.is_scheduled(7));
+    }
+
+    #[cfg(all(feature = "fusedev", target_os = "linux"))]
+    #[test]
+    fn test_server_syncfs() {
+        use crate::api::Vfs;
+        use crate::passthrough::{Config, PassthroughFs};
+        use std::io::{Seek, SeekFrom};
+        use vmm_sys_util::tempdir::TempDir;
+
+        let source = TempDir::new().unwrap();
+        let cfg = Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        fs.import().unwrap();
+        let vfs = Vfs::default();
+        vfs.mount(Box::new(fs), "/x").unwrap();
+        let server = Server::new(vfs);
+
+        // Both the whole Vfs and the submount of the backend may be synced.
+        let syncfs = SyncfsIn::default();
+        request_reply(&server, Opcode::Syncfs, ROOT_ID, syncfs.as_slice());
+        let x = request_entry(&server, Opcode::Lookup, ROOT_ID, &[], "x");
+        request_reply(&server, Opcode::Syncfs, x, syncfs.as_slice());
+
+        // File systems without syncfs reply ENOSYS, for the kernel to stop sending it.
+        let server = Server::new(TypedFs::default());
+        let mut file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        handle_request(&server, &file, Opcode::Syncfs, 1, 1, syncfs.as_slice()).unwrap();
+        let mut reply = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut reply).unwrap();
+        let header = OutHeader::from_slice(&reply).unwrap();
+        assert_eq!(header.error, -libc::ENOSYS);
+    }
+
+    #[cfg(all(feature = "fusedev", target_os = "linux"))]
+    #[test]
+    fn test_server_deterministic_replay() {
+        use crate::api::{Vfs, VfsOptions, VFS_MAX_INO};
+        use crate::passthrough::{Config, PassthroughFs};
+        use vmm_sys_util::tempdir::TempDir;
+
+        let serve = |source: &TempDir| {
+            let cfg = Config {
+                root_dir: source.as_path().to_str().unwrap().to_string(),
+                deterministic: true,
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(cfg).unwrap();
+            fs.import().unwrap();
+            let vfs = Vfs::new(VfsOptions {
+                no_open: false,
+                deterministic: true,
+                ..Default::default()
+            });
+            vfs.mount(Box::new(fs), "/m/x").unwrap();
+            Server::new(vfs)
+        };
+        // Replay lookups and opens, returning the replies.
+        let replay = |server: &Server<Vfs>| {
+            let m = request_entry(server, Opcode::Lookup, ROOT_ID, &[], "m");
+            let x = request_entry(server, Opcode::Lookup, m, &[], "x");
+            let mut replies = Vec::new();
+            for (parent, name) in [(x, "d"), (x, "f"), (x, "g")] {
+                let mut body = name.as_bytes().to_vec();
+                body.push(0);
+                replies.push(request_reply(server, Opcode::Lookup, parent, &body));
+            }
+            for reply in replies.clone().iter().skip(1) {
+                let nodeid = EntryOut::from_slice(&reply[..size_of::<EntryOut>()])
+                    .unwrap()
+                    .nodeid;
+                let open = OpenIn {
+                    flags: libc::O_RDONLY as u32,
+                    fuse_flags: 0,
+                };
+                replies.push(request_reply(server, Opcode::Open, nodeid, open.as_slice()));
+            }
+            replies
+        };
+        let ids = |replies: &[Vec<u8>]| {
+            let mut ids: Vec<u64> = replies[..3]
+                .iter()
+                .map(|r| {
+                    EntryOut::from_slice(&r[..size_of::<EntryOut>()])
+                        .unwrap()
+                        .nodeid
+                })
+                .collect();
+            ids.extend(
+                replies[3..]
+                    .iter()
+                    .map(|r| OpenOut::from_slice(r).unwrap().fh),
+            );
+            ids
+        };
+
+        let populate = |source: &TempDir| {
+            std::fs::create_dir(source.as_path().join("d")).unwrap();
+            for name in ["g", "f"] {
+                std::fs::write(source.as_path().join(name), b"a").unwrap();
+            }
+        };
+        let source = TempDir::new().unwrap();
+        populate(&source);
+
+        // Replaying against the same tree gives the same bytes.
+        let replies = replay(&serve(&source));
+        assert_eq!(replay(&serve(&source)), replies);
+
+        // Inodes and handles are allocated in request order, whatever the host inode numbers.
+        let other = TempDir::new().unwrap();
+        std::fs::write(other.as_path().join("pad"), b"").unwrap();
+        populate(&other);
+        assert_eq!(ids(&replay(&serve(&other))), ids(&replies));
+        let ids = ids(&replies);
+        assert_eq!(
+            ids[..3].iter().map(|i| i & VFS_MAX_INO).collect::<Vec<_>>(),
+            [2, 3, 4]
+        );
+        assert_eq!(ids[3..], [1, 2]);
+    }
+
+    #[cfg(all(feature = "fusedev", target_os = "linux"))]
+    #[test]
+    fn test_server_tmpfile() {
+        use crate::api::Vfs;
+        use crate::passthrough::{Config, PassthroughFs};
+        use std::io::{Seek, SeekFrom};
+        use vmm_sys_util::tempdir::TempDir;
+
+        let source = TempDir::new().unwrap();
+        let cfg = Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        fs.import().unwrap();
+        let vfs = Vfs::default();
+        vfs.mount(Box::new(fs), "/x").unwrap();
+        let server = Server::new(vfs);
+        let reply_error = |server: &Server<Vfs>, nodeid, body: &[u8]| {
+            let mut file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+            handle_request(server, &file, Opcode::Tmpfile, nodeid, 1, body).unwrap();
+            let mut reply = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut reply).unwrap();
+            OutHeader::from_slice(&reply[..size_of::<OutHeader>()])
+                .unwrap()
+                .error
+        };
+
+        // The kernel sends the name "/" of the dentry after the arguments.
+        let create = CreateIn {
+            flags: (libc::O_RDWR | libc::O_TMPFILE) as u32,
+            mode: 0o600,
+            ..Default::default()
+        };
+        let x = request_entry(&server, Opcode::Lookup, ROOT_ID, &[], "x");
+        let mut body = create.as_slice().to_vec();
+        body.extend_from_slice(b"/\0");
+        let reply = request_reply(&server, Opcode::Tmpfile, x, &body);
+        let entry = EntryOut::from_slice(&reply[..size_of::<EntryOut>()]).unwrap();
+        assert_ne!(entry.nodeid, 0);
+        assert_eq!(entry.attr.nlink, 0);
+        let open = OpenOut::from_slice(&reply[size_of::<EntryOut>()..]).unwrap();
+        assert_ne!(open.fh, 0);
+        assert_eq!(std::fs::read_dir(source.as_path()).unwrap().count(), 0);
+
+        // Unnamed files can't be created in directories of the pseudo fs.
+        assert_eq!(reply_error(&server, ROOT_ID, &body), -libc::EOPNOTSUPP);
+    }
+
+    #[cfg(all(feature = "fusedev", target_os = "linux"))]
+    #[test]
+    fn test_server_atomic_open() {
+        use crate::api::Vfs;
+        use crate::passthrough::{Config, PassthroughFs};
+        use vmm_sys_util::tempdir::TempDir;
+
+        let source = TempDir::new().unwrap();
+        std::fs::write(source.as_path().join("f"), b"data").unwrap();
+        let cfg = Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        fs.import().unwrap();
+        let vfs = Vfs::default();
+        vfs.mount(Box::new(fs), "/x").unwrap();
+        vfs.init(FsOptions::ASYNC_READ).unwrap();
+        let server = Server::new(vfs);
+        let x = request_entry(&server, Opcode::Lookup, ROOT_ID, &[], "x");
+
+        // A single request replies both the entry and the opened handle of a file.
+        let open = CreateIn {
+            flags: libc::O_RDONLY as u32,
+            ..Default::default()
+        };
+        let mut body = open.as_slice().to_vec();
+        body.extend_from_slice(b"f\0");
+        let reply = request_reply(&server, Opcode::AtomicOpen, x, &body);
+        let entry = EntryOut::from_slice(&reply[..size_of::<EntryOut>()]).unwrap();
+        assert_ne!(entry.nodeid, 0);
+        assert_eq!(entry.attr.size, 4);
+        let open = OpenOut::from_slice(&reply[size_of::<EntryOut>()..]).unwrap();
+        assert_ne!(open.fh, 0);
+
+        let create = CreateIn {
+            flags: (libc::O_RDWR | libc::O_CREAT) as u32,
+            mode: 0o600,
+            ..Default::default()
+        };
+        let mut body = create.as_slice().to_vec();
+        body.extend_from_slice(b"g\0");
+        let reply = request_reply(&server, Opcode::AtomicOpen, x, &body);
+        let entry = EntryOut::from_slice(&reply[..size_of::<EntryOut>()]).unwrap();
+        assert_eq!(entry.attr.mode, libc::S_IFREG | 0o600);
+        assert!(source.as_path().join("g").exists());
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_notify_inval_inode() {
+        use crate::api::attr_cache::{AttrCache, NotifyEvent, NotifyQueue};
+        use crate::transport::FuseDevWriter;
+        use std::io::{Seek, SeekFrom};
+        use std::os::unix::io::AsRawFd;
+
+        let queue = Arc::new(NotifyQueue::new(16));
+        let cache = AttrCache::new(4).with_notifier(queue.clone());
+        cache.invalidate(5);
+        let events = queue.take();
+        assert_eq!(events, vec![NotifyEvent::InvalInode(5)]);
+
+        let server = Server::new(TypedFs::default());
+        let mut dev = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        let mut buf = vec![0u8; 1024];
+        let w = FuseDevWriter::<()>::new(dev.as_raw_fd(), &mut buf)
+            .unwrap()
+            .into();
+        let len = server.notify_inval_inode(w, 5, 0, 0).unwrap();
+        assert_eq!(
+            len,
+            size_of::<OutHeader>() + size_of::<NotifyInvalInodeOut>()
+        );
+
+        let mut msg = Vec::new();
+        dev.seek(SeekFrom::Start(0)).unwrap();
+        dev.read_to_end(&mut msg).unwrap();
+        assert_eq!(msg.len(), len);
+        let header = OutHeader::from_slice(&msg[..size_of::<OutHeader>()]).unwrap();
+        assert_eq!(header.unique, 0);
+        assert_eq!(header.error, NotifyOpcode::InvalInode as i32);
+        let out = NotifyInvalInodeOut::from_slice(&msg[size_of::<OutHeader>()..]).unwrap();
+        assert_eq!(out.ino, 5);
+        assert_eq!(out.off, 0);
+        assert_eq!(out.len, 0);
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_notify_delete() {
+        use crate::transport::FuseDevWriter;
+        use std::io::{Seek, SeekFrom};
+        use std::os::unix::io::AsRawFd;
+
+        let server = Server::new(TypedFs::default());
+        let mut dev = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        let mut buf = vec![0u8; 1024];
+        let w = FuseDevWriter::<()>::new(dev.as_raw_fd(), &mut buf)
+            .unwrap()
+            .into();
+        let name = std::ffi::CString::new("file").unwrap();
+        let len = server.notify_delete(w, 1, 5, &name).unwrap();
+        assert_eq!(
+            len,
+            size_of::<OutHeader>() + size_of::<NotifyDeleteOut>() + 5
+        );
+
+        let mut msg = Vec::new();
+        dev.seek(SeekFrom::Start(0)).unwrap();
+        dev.read_to_end(&mut msg).unwrap();
+        assert_eq!(msg.len(), len);
+        let header = OutHeader::from_slice(&msg[..size_of::<OutHeader>()]).unwrap();
+        assert_eq!(header.unique, 0);
+        assert_eq!(header.error, NotifyOpcode::Delete as i32);
+        assert_eq!(header.len as usize, len);
+        let body = &msg[size_of::<OutHeader>()..];
+        let out = NotifyDeleteOut::from_slice(&body[..size_of::<NotifyDeleteOut>()]).unwrap();
+        assert_eq!((out.parent, out.child, out.namelen), (1, 5, 4));
+        assert_eq!(&body[size_of::<NotifyDeleteOut>()..], b"file\0");
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_notify_store_retrieve() {
+        use crate::transport::FuseDevWriter;
+        use std::io::{Seek, SeekFrom};
+        use std::os::unix::io::AsRawFd;
+
+        let server = Server::new(TypedFs::default());
+        let mut dev = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        let mut buf = vec![0u8; 1024];
+        let mut w = Some(
+            FuseDevWriter::<()>::new(dev.as_raw_fd(), &mut buf)
+                .unwrap()
+                .into(),
+        );
+        let stored = server
+            .notify_store(|| Ok(w.take().unwrap()), 5, 4096, b"hello")
+            .unwrap();
+        assert_eq!(stored, 5);
+        let store_len = size_of::<OutHeader>() + size_of::<NotifyStoreOut>() + 5;
+        let mut buf = vec![0u8; 1024];
+        let w = FuseDevWriter::<()>::new(dev.as_raw_fd(), &mut buf)
+            .unwrap()
+            .into();
+        let retrieve_len = server.notify_retrieve(w, 7, 5, 4096, 5).unwrap();
+        assert_eq!(
+            retrieve_len,
+            size_of::<OutHeader>() + size_of::<Notify_Retrieve_Out>()
+        );
+
+        let mut msg = Vec::new();
+        dev.seek(SeekFrom::Start(0)).unwrap();
+        dev.read_to_end(&mut msg).unwrap();
+        assert_eq!(msg.len(), store_len + retrieve_len);
+        let header = OutHeader::from_slice(&msg[..size_of::<OutHeader>()]).unwrap();
+        assert_eq!(header.unique, 0);
+        assert_eq!(header.error, NotifyOpcode::Store as i32);
+        assert_eq!(header.len as usize, store_len);
+        let body = &msg[size_of::<OutHeader>()..store_len];
+        let out = NotifyStoreOut::from_slice(&body[..size_of::<NotifyStoreOut>()]).unwrap();
+        assert_eq!((out.nodeid, out.offset, out.size), (5, 4096, 5));
+        assert_eq!(&body[size_of::<NotifyStoreOut>()..], b"hello");
+
+        // The store payload leaves the retrieve message unaligned.
+        let msg = msg[store_len..].to_vec();
+        let header = OutHeader::from_slice(&msg[..size_of::<OutHeader>()]).unwrap();
+        assert_eq!(header.unique, 0);
+        assert_eq!(header.error, NotifyOpcode::Retrieve as i32);
+        assert_eq!(header.len as usize, retrieve_len);
+        let out = Notify_Retrieve_Out::from_slice(&msg[size_of::<OutHeader>()..]).unwrap();
+        assert_eq!(
+            (out.notify_unique, out.nodeid, out.offset, out.size),
+            (7, 5, 4096, 5)
+        );
+    }
+
+    // Hand out a new handle for each open, and count data requests reaching the filesystem.
+    #[cfg(feature = "fusedev")]
+    #[derive(Default)]
+    struct HandleFs {
+        next: std::sync::atomic::AtomicU64,
+        writes: std::sync::atomic::AtomicUsize,
+        fallocates: std::sync::atomic::AtomicUsize,
+    }
+
+    #[cfg(feature = "fusedev")]
+    impl FileSystem for HandleFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn open(
+            &self,
+            _ctx: &Context,
+            _inode: u64,
+            _flags: u32,
+            _fuse_flags: u32,
+        ) -> io::Result<(Option<u64>, crate::api::filesystem::OpenOptions)> {
+            let fh = self.next.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+            Ok((Some(fh), crate::api::filesystem::OpenOptions::empty()))
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        fn release(
+            &self,
+            _ctx: &Context,
+            _inode: u64,
+            _flags: u32,
+            _handle: u64,
+            _flush: bool,
+            _flock_release: bool,
+            _lock_owner: Option<u64>,
+        ) -> io::Result<()> {
+            Ok(())
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        fn write(
+            &self,
+            _ctx: &Context,
+            _inode: u64,
+            _handle: u64,
+            _r: &mut dyn ZeroCopyReader,
+            size: u32,
+            _offset: u64,
+            _lock_owner: Option<u64>,
+            _delayed_write: bool,
+            _flags: u32,
+            _fuse_flags: u32,
+        ) -> io::Result<usize> {
+            self.writes
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(size as usize)
+        }
+
+        fn fallocate(
+            &self,
+            _ctx: &Context,
+            _inode: u64,
+            _handle: u64,
+            _mode: u32,
+            _offset: u64,
+            _length: u64,
+        ) -> io::Result<()> {
+            self.fallocates
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_handle_access_check() {
+        use std::io::{Seek, SeekFrom};
+        use std::sync::atomic::Ordering;
+
+        let fs = Arc::new(HandleFs::default());
+        let server = Server::new(fs.clone()).with_handle_access_check();
+        let reply_error = |opcode: Opcode, body: &[u8]| {
+            let mut file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+            handle_request(&server, &file, opcode, 5, 1, body).unwrap();
+            let mut reply = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut reply).unwrap();
+            OutHeader::from_slice(&reply[..size_of::<OutHeader>()])
+                .unwrap()
+                .error
+        };
+        let open = |flags: i32| {
+            let args = OpenIn {
+                flags: flags as u32,
+                fuse_flags: 0,
+            };
+            let reply = request_reply(&server, Opcode::Open, 5, args.as_slice());
+            OpenOut::from_slice(&reply).unwrap().fh
+        };
+        let write = |fh: u64| {
+            let args = WriteIn {
+                fh,
+                size: 4,
+                ..Default::default()
+            };
+            let mut body = args.as_slice().to_vec();
+            body.extend_from_slice(b"data");
+            body
+        };
+        let fallocate = |fh: u64| FallocateIn {
+            fh,
+            length: 4096,
+            ..Default::default()
+        };
+
+        // Writes through a read-only handle never reach the filesystem.
+        let ro = open(libc::O_RDONLY);
+        assert_eq!(reply_error(Opcode::Write, &write(ro)), -libc::EBADF);
+        assert_eq!(
+            reply_error(Opcode::Fallocate, fallocate(ro).as_slice()),
+            -libc::EBADF
+        );
+        assert_eq!(fs.writes.load(Ordering::SeqCst), 0);
+        assert_eq!(fs.fallocates.load(Ordering::SeqCst), 0);
+
+        let rw = open(libc::O_RDWR);
+        assert_eq!(reply_error(Opcode::Write, &write(rw)), 0);
+        assert_eq!(reply_error(Opcode::Fallocate, fallocate(rw).as_slice()), 0);
+        assert_eq!(fs.writes.load(Ordering::SeqCst), 1);
+        assert_eq!(fs.fallocates.load(Ordering::SeqCst), 1);
+
+        // Reads through a write-only handle are rejected too.
+        let wo = open(libc::O_WRONLY);
+        let read = ReadIn {
+            fh: wo,
+            size: 4,
+            ..Default::default()
+        };
+        assert_eq!(reply_error(Opcode::Read, read.as_slice()), -libc::EBADF);
+
+        // Released handles are dropped from the table.
+        let release = ReleaseIn {
+            fh: ro,
+            ..Default::default()
+        };
+        assert_eq!(reply_error(Opcode::Release, release.as_slice()), 0);
+        assert_eq!(reply_error(Opcode::Write, &write(ro)), 0);
+        assert_eq!(fs.writes.load(Ordering::SeqCst), 2);
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_reject_bad_names() {
+        let reply_error = |server: &Server<TypedFs>, opcode: Opcode, body: &[u8]| {
+            let reply = opcode_reply(server, opcode as u32, body);
+            OutHeader::from_slice(&reply[..size_of::<OutHeader>()])
+                .unwrap()
+                .error
+        };
+        let server = Server::new(TypedFs::default());
+        let bad: [&[u8]; 7] = [b"", b"\0", b"a", b"a/b\0", b"a\0b\0", b".\0", b"..\0"];
+        let ops = [
+            (Opcode::Lookup, Vec::new()),
+            (Opcode::Mknod, MknodIn::default().as_slice().to_vec()),
+            (Opcode::Mkdir, MkdirIn::default().as_slice().to_vec()),
+            (Opcode::Unlink, Vec::new()),
+            (Opcode::Rmdir, Vec::new()),
+            (Opcode::Link, LinkIn { oldnodeid: 5 }.as_slice().to_vec()),
+            (Opcode::Create, CreateIn::default().as_slice().to_vec()),
+        ];
+        // Rejected names never reach the filesystem driver, which fails with ENOSYS.
+        for (opcode, args) in ops.iter() {
+            let body = [&args[..], b"a\0"].concat();
+            assert_eq!(reply_error(&server, *opcode, &body), -libc::ENOSYS);
+            for name in bad {
+                let body = [&args[..], name].concat();
+                let err = reply_error(&server, *opcode, &body);
+                if matches!(opcode, Opcode::Lookup) && name.starts_with(b".") {
+                    assert_eq!(err, -libc::ENOSYS);
+                } else {
+                    assert_eq!(err, -libc::EINVAL, "{:?} {:?}", opcode, name);
+                }
+            }
+        }
+
+        let rename = RenameIn { newdir: 5 };
+        let rename2 = Rename2In {
+            newdir: 5,
+            ..Default::default()
+        };
+        for args in [rename.as_slice(), rename2.as_slice()] {
+            let opcode = if args.len() == size_of::<RenameIn>() {
+                Opcode::Rename
+            } else {
+                Opcode::Rename2
+            };
+            let body = [args, b"a\0b\0"].concat();
+            assert_eq!(reply_error(&server, opcode, &body), -libc::ENOSYS);
+            for name in bad {
+                for body in [[args, b"a\0", name].concat(), [args, name, b"b\0"].concat()] {
+                    assert_eq!(reply_error(&server, opcode, &body), -libc::EINVAL);
+                }
+            }
+        }
+
+        // Symlink targets are paths, but embedded nul characters are rejected.
+        assert_eq!(
+            reply_error(&server, Opcode::Symlink, b"a\0../b/c\0"),
+            -libc::ENOSYS
+        );
+        for body in [&b"a/b\0c\0"[..], b"..\0c\0", b"a\0\0", b"a\0c\0d\0"] {
+            assert_eq!(reply_error(&server, Opcode::Symlink, body), -libc::EINVAL);
+        }
+
+        // Names of extended attributes aren't path components.
+        let getxattr = GetxattrIn::default();
+        for (opcode, args) in [
+            (Opcode::Getxattr, getxattr.as_slice()),
+            (Opcode::Removexattr, &[][..]),
+        ] {
+            let body = [args, b"user.a/b\0"].concat();
+            assert_eq!(reply_error(&server, opcode, &body), -libc::ENOSYS);
+            for name in [&b"\0"[..], b"user.a\0b\0"] {
+                let body = [args, name].concat();
+                assert_eq!(reply_error(&server, opcode, &body), -libc::EINVAL);
+            }
+        }
+        let setxattr = SetxattrIn {
+            size: 1,
+            ..Default::default()
+        };
+        let body = [setxattr.as_slice(), b"\0v"].concat();
+        assert_eq!(reply_error(&server, Opcode::Setxattr, &body), -libc::EINVAL);
+
+        let server = Server::new(TypedFs::default()).with_dot_lookups(false);
+        for name in [&b".\0"[..], b"..\0"] {
+            assert_eq!(reply_error(&server, Opcode::Lookup, name), -libc::EINVAL);
+        }
+    }
+
+    #[cfg(all(feature = "fusedev", target_os = "linux"))]
+    #[test]
+    fn test_server_symlink_embedded_nul() {
+        use crate::passthrough::{Config, PassthroughFs};
+        use std::io::{Seek, SeekFrom};
+        use vmm_sys_util::tempdir::TempDir;
+
+        let source = TempDir::new().unwrap();
+        let cfg = Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        fs.import().unwrap();
+        let server = Server::new(fs);
+        let reply_error = |body: &[u8]| {
+            let mut file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+            handle_request(&server, &file, Opcode::Symlink, 1, 1, body).unwrap();
+            let mut reply = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut reply).unwrap();
+            OutHeader::from_slice(&reply[..size_of::<OutHeader>()])
+                .unwrap()
+                .error
+        };
+
+        assert_eq!(reply_error(b"a\0target\0hidden\0"), -libc::EINVAL);
+        assert!(source.as_path().join("a").symlink_metadata().is_err());
+        assert_eq!(reply_error(b"b\0target\0\0"), 0);
+        assert_eq!(
+            std::fs::read_link(source.as_path().join("b")).unwrap(),
+            std::path::PathBuf::from("target")
+        );
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[derive(Default)]
+    struct ForgetFs {
+        forgotten: std::sync::atomic::AtomicU64,
+    }
+
+    #[cfg(feature = "fusedev")]
+    impl ForgetFs {
+        // Forgets are expensive, about 100us each.
+        fn do_forget(&self, nlookup: u64) {
+            std::thread::sleep(std::time::Duration::from_micros(100));
+            self.forgotten
+                .fetch_add(nlookup, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    impl FileSystem for ForgetFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn lookup(
+            &self,
+            _: &Context,
+            _: u64,
+            _: &CStr,
+        ) -> io::Result<crate::api::filesystem::Entry> {
+            Ok(crate::api::filesystem::Entry {
+                inode: 2,
+                ..Default::default()
+            })
+        }
+
+        fn forget(&self, _: &Context, _: u64, count: u64) {
+            self.do_forget(count);
+        }
+
+        fn batch_forget(&self, _: &Context, requests: Vec<(u64, u64)>) {
+            for (_, count) in requests {
+                self.do_forget(count);
+            }
+        }
+    }
+
+    // Send `count` forget messages, half of them batched, return the number of lookups forgotten.
+    #[cfg(feature = "fusedev")]
+    fn forget_flood(server: &Server<ForgetFs>, file: &std::fs::File, count: u64) -> u64 {
+        for i in 0..count / 2 {
+            let forget = ForgetIn { nlookup: 1 };
+            handle_request(server, file, Opcode::Forget, i + 2, 0, forget.as_slice()).unwrap();
+
+            let mut body = BatchForgetIn {
+                count: 2,
+                ..Default::default()
+            }
+            .as_slice()
+            .to_vec();
+            for nlookup in [1, 2] {
+                let one = ForgetOne {
+                    nodeid: i + 2,
+                    nlookup,
+                };
+                body.extend_from_slice(one.as_slice());
+            }
+            handle_request(server, file, Opcode::BatchForget, 0, 0, &body).unwrap();
+        }
+        count / 2 * 4
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_forget_offload() {
+        use std::sync::atomic::Ordering;
+        use std::time::{Duration, Instant};
+
+        let server = Arc::new(Server::new(ForgetFs::default()).with_forget_offload(1 << 16));
+        let server2 = server.clone();
+        let worker = std::thread::spawn(move || server2.run_forget_worker());
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+
+        // A lookup queued behind the flood doesn't wait for 4000 forgets to be handled.
+        let start = Instant::now();
+        let total = forget_flood(&server, &file, 4000);
+        handle_request(&server, &file, Opcode::Lookup, 1, 1, b"a\0").unwrap();
+        assert!(start.elapsed() < Duration::from_millis(300));
+
+        // All forgets are eventually handled.
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while server.fs.forgotten.load(Ordering::SeqCst) < total {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(server.pending_forgets(), 0);
+        server.stop_forget_worker();
+        worker.join().unwrap();
+        assert_eq!(server.fs.forgotten.load(Ordering::SeqCst), total);
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_forget_offload_overflow() {
+        use std::sync::atomic::Ordering;
+
+        // Forgets which don't fit in the queue are handled inline rather than dropped.
+        let server = Arc::new(Server::new(ForgetFs::default()).with_forget_offload(8));
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        let total = forget_flood(&server, &file, 100);
+        assert_eq!(server.pending_forgets(), 8);
+        assert!(server.fs.forgotten.load(Ordering::SeqCst) >= total - 8 * 3);
+
+        // Queued forgets are handled before the file system gets destroyed.
+        handle_request(&server, &file, Opcode::Destroy, 0, 2, &[]).unwrap();
+        assert_eq!(server.pending_forgets(), 0);
+        assert_eq!(server.fs.forgotten.load(Ordering::SeqCst), total);
+
+        // Forgets are handled inline once the worker is stopped.
+        server.stop_forget_worker();
+        server.run_forget_worker();
+        forget_flood(&server, &file, 2);
+        assert_eq!(server.fs.forgotten.load(Ordering::SeqCst), total + 4);
+    }
+
+    #[cfg(all(feature = "fusedev", feature = "virtiofs"))]
+    #[test]
+    fn test_server_dax_window_reclaim() {
+        use crate::abi::virtio_fs::{RemovemappingIn, RemovemappingOne, SetupmappingIn};
+        use crate::api::filesystem::{Entry, OpenOptions};
+        use crate::transport::{FsCacheReqHandler, FuseBuf, FuseDevWriter};
+        use std::collections::BTreeMap;
+        use std::os::unix::io::{AsRawFd, RawFd};
+
+        struct DaxFs;
+
+        impl FileSystem for DaxFs {
+            type Inode = u64;
+            type Handle = u64;
+
+            fn lookup(&self, _: &Context, _: u64, name: &CStr) -> io::Result<Entry> {
+                Ok(Entry {
+                    inode: if name.to_bytes() == b"a" { 2 } else { 3 },
+                    ..Default::default()
+                })
+            }
+
+            fn open(
+                &self,
+                _: &Context,
+                _: u64,
+                _: u32,
+                _: u32,
+            ) -> io::Result<(Option<u64>, OpenOptions)> {
+                Ok((Some(1), OpenOptions::empty()))
+            }
+
+            #[allow(clippy::too_many_arguments)]
+            fn setupmapping(
+                &self,
+                _: &Context,
+                _: u64,
+                _: u64,
+                foffset: u64,
+                len: u64,
+                flags: u64,
+                moffset: u64,
+                vu_req: &mut dyn FsCacheReqHandler,
+            ) -> io::Result<()> {
+                vu_req.map(foffset, moffset, len, flags, 0)
+            }
+
+            fn removemapping(
+                &self,
+                _: &Context,
+                _: u64,
+                requests: Vec<RemovemappingOne>,
+                vu_req: &mut dyn FsCacheReqHandler,
+            ) -> io::Result<()> {
+                vu_req.unmap(requests)
+            }
+        }
+
+        // DAX window of the VMM.
+        #[derive(Default)]
+        struct Window(BTreeMap<u64, u64>);
+
+        impl FsCacheReqHandler for Window {
+            fn map(&mut self, _: u64, moffset: u64, len: u64, _: u64, _: RawFd) -> io::Result<()> {
+                self.0.insert(moffset, len);
+                Ok(())
+            }
+
+            fn unmap(&mut self, requests: Vec<RemovemappingOne>) -> io::Result<()> {
+                for req in requests {
+                    self.0.remove(&req.moffset);
+                }
+                Ok(())
+            }
+        }
+
+        let server = Server::new(DaxFs).with_dax_accounting(true);
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        let mut window = Window::default();
+        let request = |opcode: Opcode, nodeid: u64, body: &[u8], window: &mut Window| {
+            let in_header = InHeader {
+                len: (size_of::<InHeader>() + body.len()) as u32,
+                opcode: opcode as u32,
+                unique: 1,
+                nodeid,
+                ..Default::default()
+            };
+            let mut r_buf = in_header.as_slice().to_vec();
+            r_buf.extend_from_slice(body);
+            let r = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut r_buf)).unwrap();
+            let mut w_buf = vec![0x0u8; 1024];
+            let w = FuseDevWriter::<()>::new(file.as_raw_fd(), &mut w_buf)
+                .unwrap()
+                .into();
+            server.handle_message(r, w, Some(window), None).unwrap();
+        };
+        let setupmapping = |moffset: u64| {
+            SetupmappingIn {
+                moffset,
+                len: 0x1000,
+                ..Default::default()
+            }
+            .as_slice()
+            .to_vec()
+        };
+
+        request(Opcode::Lookup, 1, b"a\0", &mut window);
+        request(Opcode::Lookup, 1, b"b\0", &mut window);
+        request(Opcode::Open, 2, OpenIn::default().as_slice(), &mut window);
+        for moffset in [0, 0x1000, 0x2000] {
+            request(Opcode::SetupMapping, 2, &setupmapping(moffset), &mut window);
+        }
+        request(Opcode::SetupMapping, 3, &setupmapping(0x3000), &mut window);
+        request(Opcode::SetupMapping, 3, &setupmapping(0x4000), &mut window);
+        assert_eq!(window.0.len(), 5);
+        assert_eq!(server.dax_inode_mappings(), vec![(2, 3), (3, 2)]);
+
+        // Ranges unmapped by the guest are not reclaimed.
+        let mut body = RemovemappingIn { count: 1 }.as_slice().to_vec();
+        body.extend_from_slice(
+            RemovemappingOne {
+                moffset: 0x4000,
+                len: 0x1000,
+            }
+            .as_slice(),
+        );
+        request(Opcode::RemoveMapping, 3, &body, &mut window);
+        let stats = server.dax_window_stats();
+        assert_eq!(stats.bytes_mapped, 0x4000);
+        assert_eq!(stats.high_water, 0x5000);
+        assert_eq!(stats.inodes, 2);
+
+        // Ranges of an inode are reclaimed on its last release and forget.
+        request(
+            Opcode::Forget,
+            2,
+            ForgetIn { nlookup: 1 }.as_slice(),
+            &mut window,
+        );
+        assert_eq!(window.0.len(), 4);
+        let release = ReleaseIn {
+            fh: 1,
+            ..Default::default()
+        };
+        request(Opcode::Release, 2, release.as_slice(), &mut window);
+        assert_eq!(window.0.keys().copied().collect::<Vec<_>>(), vec![0x3000]);
+        assert_eq!(server.dax_inode_mappings(), vec![(3, 1)]);
+
+        // The guest gets killed, everything is reclaimed on disconnect.
+        server.reclaim_dax_window(&mut window).unwrap();
+        assert!(window.0.is_empty());
+        let stats = server.dax_window_stats();
+        assert_eq!(stats.bytes_mapped, 0);
+        assert_eq!(stats.mappings, 0);
+        assert_eq!(stats.inodes, 0);
+        assert_eq!(stats.reclaimed, 4);
+        assert!(server.dax_inode_mappings().is_empty());
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_write_flags() {
+        use std::sync::Mutex;
+
+        // Record `(lock_owner, delayed_write, fuse_flags)` of writes.
+        #[derive(Default)]
+        struct WriteFs(Mutex<Vec<(Option<u64>, bool, u32)>>);
+
+        impl FileSystem for WriteFs {
+            type Inode = u64;
+            type Handle = u64;
+
+            #[allow(clippy::too_many_arguments)]
+            fn write(
+                &self,
+                _: &Context,
+                _: u64,
+                _: u64,
+                _: &mut dyn ZeroCopyReader,
+                size: u32,
+                _: u64,
+                lock_owner: Option<u64>,
+                delayed_write: bool,
+                _: u32,
+                fuse_flags: u32,
+            ) -> io::Result<usize> {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push((lock_owner, delayed_write, fuse_flags));
+                Ok(size as usize)
+            }
+        }
+
+        let server = Server::new(WriteFs::default());
+        let write = |fuse_flags: u32| {
+            let args = WriteIn {
+                fh: 1,
+                size: 4,
+                fuse_flags,
+                lock_owner: 0x1234,
+                ..Default::default()
+            };
+            let mut body = args.as_slice().to_vec();
+            body.extend_from_slice(b"data");
+            let reply = request_reply(&server, Opcode::Write, 2, &body);
+            assert_eq!(WriteOut::from_slice(&reply).unwrap().size, 4);
+        };
+
+        write(0);
+        write(WRITE_LOCKOWNER);
+        write(WRITE_CACHE);
+        write(WRITE_CACHE | WRITE_LOCKOWNER | WRITE_KILL_PRIV);
+        assert_eq!(
+            *server.fs.0.lock().unwrap(),
+            vec![
+                (None, false, 0),
+                (Some(0x1234), false, WRITE_LOCKOWNER),
+                (None, true, WRITE_CACHE),
+                (
+                    Some(0x1234),
+                    true,
+                    WRITE_CACHE | WRITE_LOCKOWNER | WRITE_KILL_PRIV
+                ),
+            ]
+        );
+    }
+
+    #[cfg(feature = "fusedev")]
+    struct GranFs(u32);
+
+    #[cfg(feature = "fusedev")]
+    impl FileSystem for GranFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn time_gran(&self) -> u32 {
+            self.0
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_init_time_gran() {
+        let init = InitIn {
+            major: KERNEL_VERSION,
+            minor: KERNEL_MINOR_VERSION,
+            max_readahead: 0x20000,
+            flags: 0,
+        };
+        let time_gran = |gran: u32| {
+            let server = Server::new(GranFs(gran));
+            let reply = opcode_reply(&server, Opcode::Init as u32, init.as_slice());
+            let out = InitOut::from_slice(&reply[size_of::<OutHeader>()..]).unwrap();
+            assert_eq!(server.connection_info().unwrap().time_gran, out.time_gran);
+            out.time_gran
+        };
+
+        assert_eq!(time_gran(1), 1);
+        assert_eq!(time_gran(1000), 1000);
+        assert_eq!(time_gran(1_000_000_000), 1_000_000_000);
+        // Invalid granularities fall back to nanoseconds.
+        assert_eq!(time_gran(0), 1);
+        assert_eq!(time_gran(3), 1);
+        assert_eq!(time_gran(2_000_000_000), 1);
+    }
+
+    #[cfg(feature = "fusedev")]
+    struct StatxFs;
+
+    #[cfg(feature = "fusedev")]
+    impl FileSystem for StatxFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn statx(
+            &self,
+            _ctx: &Context,
+            inode: u64,
+            handle: Option<u64>,
+            _flags: u32,
+            mask: u32,
+        ) -> io::Result<(Statx, std::time::Duration)> {
+            let stat = Statx {
+                mask: mask & (STATX_BASIC_STATS | STATX_BTIME),
+                ino: inode,
+                size: handle.unwrap_or(0),
+                btime: SxTime {
+                    tv_sec: 1234,
+                    tv_nsec: 5,
+                    reserved: 0,
+                },
+                ..Default::default()
+            };
+            Ok((stat, std::time::Duration::from_secs(2)))
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_statx() {
+        let hdr = size_of::<OutHeader>();
+        let init = |minor: u32| InitIn {
+            major: KERNEL_VERSION,
+            minor,
+            max_readahead: 0x20000,
+            flags: 0,
+        };
+        let statx_in = StatxIn {
+            getattr_flags: GETATTR_FH,
+            fh: 9,
+            sx_mask: STATX_BASIC_STATS | STATX_BTIME,
+            ..Default::default()
+        };
+
+        let server = Server::new(StatxFs);
+        opcode_reply(&server, Opcode::Init as u32, init(39).as_slice());
+        let reply = opcode_reply(&server, Opcode::Statx as u32, statx_in.as_slice());
+        let header = OutHeader::from_slice(&reply[..hdr]).unwrap();
+        assert_eq!(header.error, 0);
+        assert_eq!(reply.len(), hdr + size_of::<StatxOut>());
+        let out = StatxOut::from_slice(&reply[hdr..]).unwrap();
+        assert_eq!(out.attr_valid, 2);
+        assert_eq!(out.stat.ino, 5);
+        assert_eq!(out.stat.size, 9);
+        assert_eq!(out.stat.mask, STATX_BASIC_STATS | STATX_BTIME);
+        assert_eq!((out.stat.btime.tv_sec, out.stat.btime.tv_nsec), (1234, 5));
+
+        // Kernels before 7.39 never send statx requests.
+        let server = Server::new(StatxFs);
+        opcode_reply(&server, Opcode::Init as u32, init(38).as_slice());
+        let reply = opcode_reply(&server, Opcode::Statx as u32, statx_in.as_slice());
+        let header = OutHeader::from_slice(&reply[..hdr]).unwrap();
+        assert_eq!(header.error, -libc::ENOSYS);
+    }
+
+    #[cfg(feature = "fusedev")]
+    struct InitFs(FsOptions);
+
+    #[cfg(feature = "fusedev")]
+    impl FileSystem for InitFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn init(&self, _capable: FsOptions) -> io::Result<FsOptions> {
+            Ok(self.0)
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_init_readdirplus() {
+        let plus = FsOptions::DO_READDIRPLUS | FsOptions::READDIRPLUS_AUTO;
+        let init = InitIn {
+            major: KERNEL_VERSION,
+            minor: KERNEL_MINOR_VERSION,
+            max_readahead: 0x20000,
+            flags: plus.bits() as u32,
+        };
+        let negotiate = |want: FsOptions| {
+            let server = Server::new(InitFs(want));
+            let reply = opcode_reply(&server, Opcode::Init as u32, init.as_slice());
+            let out = InitOut::from_slice(&reply[size_of::<OutHeader>()..]).unwrap();
+            assert_eq!(
+                server.connection_info().unwrap().flags.bits() as u32,
+                out.flags
+            );
+            FsOptions::from_bits_truncate(out.flags as u64)
+        };
+
+        assert_eq!(negotiate(plus), plus);
+        assert_eq!(
+            negotiate(FsOptions::DO_READDIRPLUS),
+            FsOptions::DO_READDIRPLUS
+        );
+        // Capabilities cleared by the file system aren't advertised.
+        assert_eq!(negotiate(FsOptions::empty()), FsOptions::empty());
+        assert_eq!(negotiate(FsOptions::READDIRPLUS_AUTO), FsOptions::empty());
+    }
+
+    #[cfg(all(feature = "fusedev", target_os = "linux"))]
+    #[test]
+    fn test_server_notify_inval_entry_flags() {
+        use crate::abi::fuse_abi::FUSE_EXPIRE_ONLY;
+        use crate::transport::FuseDevWriter;
+        use std::ffi::CString;
+        use std::io::{Read, Seek, SeekFrom};
+        use std::os::unix::io::AsRawFd;
+
+        let notify = |minor: u32| {
+            let server = Server::new(InitFs(FsOptions::empty()));
+            let init = InitIn {
+                major: KERNEL_VERSION,
+                minor,
+                max_readahead: 0x20000,
+                flags: 0,
+            };
+            opcode_reply(&server, Opcode::Init as u32, init.as_slice());
+
+            let mut file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+            let mut buf = vec![0u8; 1024];
+            let w = FuseDevWriter::<()>::new(file.as_raw_fd(), &mut buf)
+                .unwrap()
+                .into();
+            let name = CString::new("abc").unwrap();
+            let len = server
+                .notify_inval_entry_flags(w, ROOT_ID, &name, FUSE_EXPIRE_ONLY)
+                .unwrap();
+            assert_eq!(
+                len,
+                size_of::<OutHeader>() + size_of::<NotifyInvalEntryOut>() + 4
+            );
+
+            let mut msg = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut msg).unwrap();
+            let mut out = NotifyInvalEntryOut::default();
+            out.as_mut_slice()
+                .copy_from_slice(&msg[size_of::<OutHeader>()..len - 4]);
+            assert_eq!((out.parent, out.namelen), (ROOT_ID, 3));
+            out.flags
+        };
+
+        assert_eq!(notify(38), FUSE_EXPIRE_ONLY);
+        // Older kernels take the flags for padding, they must be zero.
+        assert_eq!(notify(37), 0);
+    }
+
+    #[cfg(all(feature = "fusedev", target_os = "linux"))]
+    #[test]
+    fn test_server_notify_store_max_write() {
+        use crate::transport::FuseDevWriter;
+        use std::io::{Read, Seek, SeekFrom};
+        use std::os::unix::io::AsRawFd;
+
+        let server = Server::new(InitFs(FsOptions::empty())).with_max_write(4096);
+        let mut file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+        let data: Vec<u8> = (0..10000u32).map(|i| i as u8).collect();
+        let mut bufs = vec![vec![0u8; 8192]; 3];
+        let mut bufs = bufs.iter_mut();
+        let stored = server
+            .notify_store(
+                || {
+                    Ok(
+                        FuseDevWriter::<()>::new(file.as_raw_fd(), bufs.next().unwrap())
+                            .unwrap()
+                            .into(),
+                    )
+                },
+                5,
+                100,
+                &data,
+            )
+            .unwrap();
+        assert_eq!(stored, data.len());
+
+        // Data is split into messages of at most the negotiated `max_write`.
+        let mut msgs = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut msgs).unwrap();
+        let mut msgs = &msgs[..];
+        let mut offset = 100;
+        for size in [4096, 4096, 1808] {
+            let header = OutHeader::from_slice(&msgs[..size_of::<OutHeader>()]).unwrap();
+            assert_eq!(header.error, NotifyOpcode::Store as i32);
+            let len = size_of::<OutHeader>() + size_of::<NotifyStoreOut>() + size;
+            assert_eq!(header.len as usize, len);
+            let mut out = NotifyStoreOut::default();
+            out.as_mut_slice()
+                .copy_from_slice(&msgs[size_of::<OutHeader>()..len - size]);
+            assert_eq!((out.nodeid, out.offset, out.size), (5, offset, size as u32));
+            let start = (offset - 100) as usize;
+            assert_eq!(&msgs[len - size..len], &data[start..start + size]);
+            offset += size as u64;
+            msgs = &msgs[len..];
+        }
+        assert!(msgs.is_empty());
+    }
+
+    // Record the supplementary groups sent with requests creating files.
+    #[cfg(feature = "fusedev")]
+    #[derive(Default)]
+    struct SuppGroupFs(std::sync::Mutex<Vec<Option<libc::gid_t>>>);
+
+    #[cfg(feature = "fusedev")]
+    impl SuppGroupFs {
+        fn entry(&self, ctx: &Context) -> io::Result<crate::api::filesystem::Entry> {
+            self.0.lock().unwrap().push(ctx.supp_gid);
+            Ok(crate::api::filesystem::Entry {
+                inode: 6,
+                ..Default::default()
+            })
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    impl FileSystem for SuppGroupFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn init(&self, _capable: FsOptions) -> io::Result<FsOptions> {
+            Ok(FsOptions::CREATE_SUPP_GROUP | FsOptions::PERFILE_DAX)
+        }
+
+        fn mkdir(
+            &self,
+            ctx: &Context,
+            _parent: u64,
+            _name: &CStr,
+            _mode: u32,
+            _umask: u32,
+        ) -> io::Result<crate::api::filesystem::Entry> {
+            self.entry(ctx)
+        }
+
+        fn symlink(
+            &self,
+            ctx: &Context,
+            _linkname: &CStr,
+            _parent: u64,
+            _name: &CStr,
+        ) -> io::Result<crate::api::filesystem::Entry> {
+            self.entry(ctx)
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_create_supp_group() {
+        use crate::transport::{FuseBuf, FuseDevWriter};
+        use std::io::{Seek, SeekFrom};
+        use std::os::unix::io::AsRawFd;
+
+        let request = |server: &Server<SuppGroupFs>, opcode: Opcode, body: &[u8], ext: &[u8]| {
+            let in_header = InHeader {
+                len: (size_of::<InHeader>() + body.len() + ext.len()) as u32,
+                opcode: opcode as u32,
+                unique: 1,
+                nodeid: 1,
+                total_extlen: (ext.len() / 8) as u16,
+                ..Default::default()
+            };
+            let mut r_buf = in_header.as_slice().to_vec();
+            r_buf.extend_from_slice(body);
+            r_buf.extend_from_slice(ext);
+            let r = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut r_buf)).unwrap();
+            let mut file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+            let mut w_buf = vec![0x0u8; 1024];
+            let w = FuseDevWriter::<()>::new(file.as_raw_fd(), &mut w_buf)
+                .unwrap()
+                .into();
+            server.handle_message(r, w, None, None).unwrap();
+            let mut reply = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut reply).unwrap();
+            reply
+        };
+        let init = |server: &Server<SuppGroupFs>, minor: u32, flags2: Option<u32>| {
+            let mut body = InitIn {
+                major: KERNEL_VERSION,
+                minor,
+                max_readahead: 0x20000,
+                flags: INIT_EXT,
+            }
+            .as_slice()
+            .to_vec();
+            if let Some(flags2) = flags2 {
+                body.extend_from_slice(
+                    InitIn2 {
+                        flags2,
+                        ..Default::default()
+                    }
+                    .as_slice(),
+                );
+            }
+            let reply = request(server, Opcode::Init, &body, &[]);
+            *InitOut::from_slice(&reply[size_of::<OutHeader>()..]).unwrap()
+        };
+        let groups = |gids: &[u32]| {
+            let size = size_of::<ExtHeader>() + size_of::<SuppGroups>() + gids.len() * 4;
+            let size = (size + 7) & !7;
+            let mut ext = ExtHeader {
+                size: size as u32,
+                type_: FUSE_EXT_GROUPS,
+            }
+            .as_slice()
+            .to_vec();
+            ext.extend_from_slice(
+                SuppGroups {
+                    nr_groups: gids.len() as u32,
+                }
+                .as_slice(),
+            );
+            for gid in gids {
+                ext.extend_from_slice(&gid.to_ne_bytes());
+            }
+            ext.resize(size, 0);
+            ext
+        };
+        let error = |reply: &[u8]| {
+            OutHeader::from_slice(&reply[..size_of::<OutHeader>()])
+                .unwrap()
+                .error
+        };
+
+        // Before 7.36, the bit of INIT_EXT means per-file DAX.
+        let server = Server::new(SuppGroupFs::default());
+        let out = init(&server, 33, None);
+        assert_eq!(out.flags, INIT_EXT);
+        assert_eq!(out.flags2, 0);
+        assert_eq!(
+            server.connection_info().unwrap().flags,
+            FsOptions::PERFILE_DAX
+        );
+
+        let server = Server::new(SuppGroupFs::default());
+        let out = init(
+            &server,
+            38,
+            Some((FsOptions::CREATE_SUPP_GROUP.bits() >> 32) as u32),
+        );
+        assert_eq!(out.flags, INIT_EXT);
+        assert_eq!(out.flags2, 0x4);
+        assert_eq!(
+            server.connection_info().unwrap().flags,
+            FsOptions::CREATE_SUPP_GROUP
+        );
+
+        let mut mkdir = MkdirIn::default().as_slice().to_vec();
+        mkdir.extend_from_slice(b"d\0");
+        assert_eq!(
+            error(&request(&server, Opcode::Mkdir, &mkdir, &groups(&[1001]))),
+            0
+        );
+        // Kernels not sending the extension, and unknown extensions.
+        assert_eq!(error(&request(&server, Opcode::Mkdir, &mkdir, &[])), 0);
+        let mut unknown = groups(&[1002]);
+        unknown[4] = 0;
+        assert_eq!(error(&request(&server, Opcode::Mkdir, &mkdir, &unknown)), 0);
+        let mut ext = unknown;
+        ext.extend_from_slice(&groups(&[1003, 1004]));
+        assert_eq!(
+            error(&request(&server, Opcode::Symlink, b"l\0t\0", &ext)),
+            0
+        );
+        assert_eq!(
+            *server.fs.0.lock().unwrap(),
+            vec![Some(1001), None, None, Some(1003)]
+        );
+
+        // Malformed extensions are refused before reaching the file system.
+        let mut bad = groups(&[1005]);
+        bad[0] = 24;
+        assert_eq!(
+            error(&request(&server, Opcode::Mkdir, &mkdir, &bad)),
+            -libc::EINVAL
+        );
+        let mut bad = groups(&[1005]);
+        bad[8] = 3;
+        assert_eq!(
+            error(&request(&server, Opcode::Mkdir, &mkdir, &bad)),
+            -libc::EINVAL
+        );
+        assert_eq!(server.fs.0.lock().unwrap().len(), 4);
+    }
+
+    // Want all options, and reply entries and opens with all flags, to check they're encoded for
+    // old kernels.
+    #[cfg(feature = "fusedev")]
+    struct CompatFs;
+
+    #[cfg(feature = "fusedev")]
+    impl FileSystem for CompatFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
+            Ok(capable)
+        }
+
+        fn lookup(
+            &self,
+            _ctx: &Context,
+            _parent: u64,
+            _name: &CStr,
+        ) -> io::Result<crate::api::filesystem::Entry> {
+            Ok(crate::api::filesystem::Entry {
+                inode: 6,
+                attr_flags: 1,
+                ..Default::default()
+            })
+        }
+
+        fn getattr(
+            &self,
+            _ctx: &Context,
+            inode: u64,
+            _handle: Option<u64>,
+        ) -> io::Result<(stat64, std::time::Duration)> {
+            let mut st: stat64 = unsafe { std::mem::zeroed() };
+            st.st_ino = inode;
+            Ok((st, std::time::Duration::from_secs(1)))
+        }
+
+        fn open(
+            &self,
+            _ctx: &Context,
+            _inode: u64,
+            _flags: u32,
+            _fuse_flags: u32,
+        ) -> io::Result<(Option<u64>, OpenOptions)> {
+            Ok((Some(1), OpenOptions::all()))
+        }
+
+        fn read(
+            &self,
+            _ctx: &Context,
+            _inode: u64,
+            _handle: u64,
+            w: &mut dyn ZeroCopyWriter,
+            _size: u32,
+            _offset: u64,
+            _lock_owner: Option<u64>,
+            _flags: u32,
+        ) -> io::Result<usize> {
+            w.write(b"legacy")
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_compat_replies() {
+        let hdr = size_of::<OutHeader>();
+        let reply_len = |server: &Server<CompatFs>, opcode: Opcode, body: &[u8]| {
+            let reply = opcode_reply(server, opcode as u32, body);
+            let header = OutHeader::from_slice(&reply[..hdr]).unwrap();
+            assert_eq!(header.error, 0);
+            assert_eq!(header.len as usize, reply.len());
+            reply
+        };
+
+        // Reply sizes of historical protocol minor versions, from the kernel headers.
+        for (minor, init, entry, attr, statfs) in [
+            (12, 24, 128, 104, 80),
+            (22, 24, 128, 104, 80),
+            (26, 64, 128, 104, 80),
+            (KERNEL_MINOR_VERSION, 64, 128, 104, 80),
+        ] {
+            let server = Server::new(CompatFs);
+            let init_in = InitIn {
+                major: KERNEL_VERSION,
+                minor,
+                max_readahead: 0x20000,
+                flags: 0,
+            };
+            let reply = reply_len(&server, Opcode::Init, init_in.as_slice());
+            assert_eq!(reply.len(), hdr + init, "minor {}", minor);
+
+            let reply = reply_len(&server, Opcode::Lookup, b"a\0");
+            assert_eq!(reply.len(), hdr + entry, "minor {}", minor);
+            let nodeid = u64::from_slice(&reply[hdr..hdr + 8]).unwrap();
+            assert_eq!(*nodeid, 6);
+
+            let reply = reply_len(&server, Opcode::Getattr, GetattrIn::default().as_slice());
+            assert_eq!(reply.len(), hdr + attr, "minor {}", minor);
+            let reply = reply_len(&server, Opcode::Statfs, &[]);
+            assert_eq!(reply.len(), hdr + statfs, "minor {}", minor);
+
+            let reply = reply_len(&server, Opcode::Open, OpenIn::default().as_slice());
+            let out = OpenOut::from_slice(&reply[hdr..]).unwrap();
+            let opts = OpenOptions::from_bits(out.open_flags).unwrap();
+            let info = server.connection_info().unwrap();
+            assert_eq!(
+                opts.contains(OpenOptions::STREAM),
+                info.supports(ProtocolFeature::OpenStream)
+            );
+            assert_eq!(
+                opts.contains(OpenOptions::CACHE_DIR),
+                info.supports(ProtocolFeature::OpenCacheDir)
+            );
+            assert_eq!(
+                opts.contains(OpenOptions::NONSEEKABLE),
+                info.supports(ProtocolFeature::OpenNonseekable)
+            );
+            assert!(opts.contains(OpenOptions::DIRECT_IO | OpenOptions::KEEP_CACHE));
+        }
+
+        // Attribute flags are only replied to kernels knowing them.
+        for (minor, flags) in [(31, 0), (32, 1)] {
+            let server = Server::new(CompatFs);
+            let init_in = InitIn {
+                major: KERNEL_VERSION,
+                minor,
+                max_readahead: 0x20000,
+                flags: 0,
+            };
+            reply_len(&server, Opcode::Init, init_in.as_slice());
+            let reply = reply_len(&server, Opcode::Lookup, b"a\0");
+            let out = EntryOut::from_slice(&reply[hdr..]).unwrap();
+            assert_eq!(out.attr.flags, flags);
+        }
+    }
+    #[cfg(all(feature = "fusedev", target_os = "linux"))]
+    #[test]
+    fn test_server_legacy_golden() {
+        let hdr = size_of::<OutHeader>();
+        let request = |server: &Server<CompatFs>, opcode: Opcode, body: &[u8]| {
+            let reply = opcode_reply(server, opcode as u32, body);
+            let header = OutHeader::from_slice(&reply[..hdr]).unwrap();
+            assert_eq!(header.len as usize, reply.len());
+            assert_eq!(header.unique, 7);
+            (header.error, reply[hdr..].to_vec())
+        };
+        let init = |minor: u32, flags: u32| InitIn {
+            major: KERNEL_VERSION,
+            minor,
+            max_readahead: 0x20000,
+            flags,
+        };
+
+        // Kernels older than the oldest supported minor fail to mount.
+        let server = Server::new(CompatFs);
+        assert_eq!(server.min_supported_minor(), MIN_KERNEL_MINOR_VERSION);
+        let (error, body) = request(&server, Opcode::Init, init(11, 0).as_slice());
+        assert_eq!(error, -libc::EPROTO);
+        assert!(body.is_empty());
+        assert!(server.connection_info().is_none());
+
+        // A 7.22 kernel advertising ASYNC_READ, BIG_WRITES, DO_READDIRPLUS, READDIRPLUS_AUTO,
+        // ASYNC_DIO and a backported WRITEBACK_CACHE.
+        let (error, body) = request(&server, Opcode::Init, init(22, 0x1e021).as_slice());
+        assert_eq!(error, 0);
+        #[rustfmt::skip]
+        let want: [u8; 24] = [
+            7, 0, 0, 0,             // major
+            39, 0, 0, 0,            // minor
+            0, 0, 2, 0,             // max_readahead
+            0x21, 0x80, 0, 0,       // flags: ASYNC_READ | BIG_WRITES | ASYNC_DIO
+            0xff, 0xff,             // max_background
+            0xfd, 0xbf,             // congestion_threshold
+            0, 0, 2, 0,             // max_write: 32 pages without MAX_PAGES
+        ];
+        assert_eq!(body, want);
+        let info = server.connection_info().unwrap();
+        assert_eq!(info.minor, 22);
+        assert_eq!(info.max_pages, 0);
+
+        // The 7.22 entry has attributes with blksize but without flags.
+        let (error, body) = request(&server, Opcode::Lookup, b"a\0");
+        assert_eq!(error, 0);
+        let mut want = vec![0u8; FUSE_COMPAT_ENTRY_OUT_SIZE + 8];
+        want[0] = 6;
+        assert_eq!(body, want);
+
+        let (error, body) = request(&server, Opcode::Getattr, GetattrIn::default().as_slice());
+        assert_eq!(error, 0);
+        let mut want = vec![0u8; FUSE_COMPAT_ATTR_OUT_SIZE + 8];
+        want[0] = 1; // attr_valid
+        want[16] = 5; // ino
+        assert_eq!(body, want);
+
+        let read_in = ReadIn {
+            fh: 1,
+            size: 4096,
+            ..Default::default()
+        };
+        let (error, body) = request(&server, Opcode::Read, read_in.as_slice());
+        assert_eq!(error, 0);
+        assert_eq!(body, b"legacy");
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_throttle() {
+        use crate::api::clock::ManualClock;
+        use std::time::Duration;
+
+        let clock = Arc::new(ManualClock::default());
+        let throttle = Arc::new(
+            Throttle::new(ThrottleLimits {
+                data_bytes: Some(8192),
+                ..Default::default()
+            })
+            .with_clock(clock.clone()),
+        );
+        let server = Server::new(CompatFs).with_throttle(throttle.clone());
+        let read = ReadIn {
+            fh: 1,
+            size: 4096,
+            ..Default::default()
+        };
+
+        // Requests over the budget are delayed rather than failed.
+        for _ in 0..3 {
+            request_reply(&server, Opcode::Read, 6, read.as_slice());
+        }
+        assert_eq!(clock.monotonic(), Duration::from_millis(500));
+        request_reply(&server, Opcode::Getattr, 6, GetattrIn::default().as_slice());
+        assert_eq!(clock.monotonic(), Duration::from_millis(500));
+
+        // Limits changed at runtime apply to the following requests.
+        throttle.set_limits(ThrottleLimits {
+            metadata_ops: Some(1),
+            ..Default::default()
+        });
+        request_reply(&server, Opcode::Read, 6, read.as_slice());
+        for _ in 0..2 {
+            request_reply(&server, Opcode::Getattr, 6, GetattrIn::default().as_slice());
+        }
+        assert_eq!(clock.monotonic(), Duration::from_millis(1500));
+        let file = vmm_sys_util::tempfile::TempFile::new().unwrap();
+        let forget = ForgetIn { nlookup: 1 };
+        handle_request(
+            &server,
+            file.as_file(),
+            Opcode::Forget,
+            6,
+            1,
+            forget.as_slice(),
+        )
+        .unwrap();
+        assert_eq!(clock.monotonic(), Duration::from_millis(1500));
+    }
+
+    // Fails `failures` getattr and setattr calls with `EINTR`.
+    #[cfg(feature = "fusedev")]
+    struct FlakyFs {
+        failures: std::sync::atomic::AtomicU32,
+    }
+
+    #[cfg(feature = "fusedev")]
+    impl FlakyFs {
+        fn new(failures: u32) -> Self {
+            FlakyFs {
+                failures: std::sync::atomic::AtomicU32::new(failures),
+            }
+        }
+
+        fn attr(&self, inode: u64) -> io::Result<(stat64, std::time::Duration)> {
+            use std::sync::atomic::Ordering;
+
+            if self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                return Err(io::Error::from_raw_os_error(libc::EINTR));
+            }
+            let mut st: stat64 = unsafe { std::mem::zeroed() };
+            st.st_ino = inode;
+            Ok((st, std::time::Duration::from_secs(1)))
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    impl FileSystem for FlakyFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn getattr(
+            &self,
+            _ctx: &Context,
+            inode: u64,
+            _handle: Option<u64>,
+        ) -> io::Result<(stat64, std::time::Duration)> {
+            self.attr(inode)
+        }
+
+        fn setattr(
+            &self,
+            _ctx: &Context,
+            inode: u64,
+            _attr: stat64,
+            _handle: Option<u64>,
+            _valid: SetattrValid,
+        ) -> io::Result<(stat64, std::time::Duration)> {
+            self.attr(inode)
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_transient_retries() {
+        let error = |server: &Server<FlakyFs>, opcode: Opcode, body: &[u8]| {
+            let reply = opcode_reply(server, opcode as u32, body);
+            OutHeader::from_slice(&reply[..size_of::<OutHeader>()])
+                .unwrap()
+                .error
+        };
+        let getattr = GetattrIn::default();
+        let setattr = SetattrIn::default();
+
+        // Transient failures are replied as is by default.
+        let server = Server::new(FlakyFs::new(1));
+        assert_eq!(
+            error(&server, Opcode::Getattr, getattr.as_slice()),
+            -libc::EINTR
+        );
+        assert_eq!(error(&server, Opcode::Getattr, getattr.as_slice()), 0);
+        assert_eq!(server.transient_retries(), 0);
+
+        let server = Server::new(FlakyFs::new(1)).with_transient_retries(2);
+        request_reply(&server, Opcode::Getattr, 5, getattr.as_slice());
+        assert_eq!(server.transient_retries(), 1);
+
+        // Retries are bounded.
+        let server = Server::new(FlakyFs::new(3)).with_transient_retries(2);
+        assert_eq!(
+            error(&server, Opcode::Getattr, getattr.as_slice()),
+            -libc::EINTR
+        );
+        assert_eq!(server.transient_retries(), 2);
+
+        // State changing requests are never retried.
+        let server = Server::new(FlakyFs::new(1)).with_transient_retries(2);
+        assert_eq!(
+            error(&server, Opcode::Setattr, setattr.as_slice()),
+            -libc::EINTR
+        );
+        assert_eq!(server.transient_retries(), 0);
+
+        // Neither are requests aborted by draining.
+        let server = Server::new(FlakyFs::new(1)).with_transient_retries(2);
+        server.drain_shedder().drain();
+        assert_eq!(
+            error(&server, Opcode::Getattr, getattr.as_slice()),
+            -libc::EINTR
+        );
+        assert_eq!(server.transient_retries(), 0);
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_max_write() {
+        let page = pagesize() as u32;
+        let init = InitIn {
+            major: KERNEL_VERSION,
+            minor: KERNEL_MINOR_VERSION,
+            max_readahead: 0x20000,
+            flags: FsOptions::MAX_PAGES.bits() as u32,
+        };
+        let error = |server: &Server<TypedFs>, opcode: Opcode, body: &[u8]| {
+            let reply = opcode_reply(server, opcode as u32, body);
+            OutHeader::from_slice(&reply[..size_of::<OutHeader>()])
+                .unwrap()
+                .error
+        };
+
+        // Large writes get `FUSE_MAX_PAGES` negotiated, although the file system doesn't ask.
+        let server = Server::new(TypedFs::default()).with_max_write(64 * page);
+        assert_eq!(server.max_request_size(), 64 * page as usize + 0x1000);
+        request_reply(&server, Opcode::Init, 1, init.as_slice());
+        let info = server.connection_info().unwrap();
+        assert!(info.flags.contains(FsOptions::MAX_PAGES));
+        assert_eq!(info.max_pages, 64);
+        assert_eq!(info.max_write, 64 * page);
+
+        // Requests beyond the negotiated size are refused.
+        let body = vec![0u8; server.max_request_size() - size_of::<InHeader>()];
+        assert_eq!(error(&server, Opcode::Getattr, &body), 0);
+        let body = vec![0u8; server.max_request_size()];
+        assert_eq!(error(&server, Opcode::Write, &body), -libc::ENOMEM);
+
+        // Small writes fit into requests of the default max pages.
+        let server = Server::new(TypedFs::default()).with_max_write(1);
+        request_reply(&server, Opcode::Init, 1, init.as_slice());
+        let info = server.connection_info().unwrap();
+        assert!(!info.flags.contains(FsOptions::MAX_PAGES));
+        assert_eq!(info.max_write, 0x1000);
+        assert_eq!(
+            server.max_request_size(),
+            DEFAULT_REQ_PAGES as usize * page as usize + 0x1000
+        );
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_deadline_expired() {
+        let error = |server: &Server<CompatFs>, opcode: Opcode, body: &[u8]| {
+            let reply = opcode_reply(server, opcode as u32, body);
+            OutHeader::from_slice(&reply[..size_of::<OutHeader>()])
+                .unwrap()
+                .error
+        };
+        let server = Server::new(CompatFs).with_deadlines(RequestDeadlines {
+            metadata: Some(std::time::Duration::ZERO),
+            ..Default::default()
+        });
+
+        // Requests whose deadline expired before dispatch fail with the configured errno.
+        let getattr = GetattrIn::default();
+        assert_eq!(
+            error(&server, Opcode::Getattr, getattr.as_slice()),
+            -libc::ETIMEDOUT
+        );
+        let read = ReadIn {
+            fh: 1,
+            size: 6,
+            ..Default::default()
+        };
+        assert_eq!(error(&server, Opcode::Read, read.as_slice()), 0);
+        assert_eq!(
+            server.deadline_violations(),
+            DeadlineViolations {
+                metadata: 1,
+                ..Default::default()
+            }
+        );
     }
 }
@@ -0,0 +1,242 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Encoding of the notification messages sent to the kernel.
+//!
+//! Notifications are unsolicited messages, whose `OutHeader` carries the notification opcode in
+//! its `error` field and a zero `unique`. [NotifyMessage] encodes them once for all senders:
+//! [Server](super::Server) writes them into a [Writer](crate::transport::Writer), while transport
+//! notifiers write them to the fuse device or into a virtio-fs notification queue.
+
+use std::ffi::CStr;
+use std::io::IoSlice;
+use std::mem::size_of;
+
+use vm_memory::ByteValued;
+
+use super::compat;
+use crate::abi::fuse_abi::{
+    NotifyDeleteOut, NotifyInvalEntryOut, NotifyInvalInodeOut, NotifyOpcode, NotifyPollWakeupOut,
+    NotifyStoreOut, Notify_Retrieve_Out, OutHeader,
+};
+
+/// An encoded notification message: the header and the fixed size arguments, followed by the
+/// payload borrowed from the caller, like the name of an entry or the data to store.
+pub(crate) struct NotifyMessage<'a> {
+    opcode: NotifyOpcode,
+    header: OutHeader,
+    args: Vec<u8>,
+    payload: Vec<&'a [u8]>,
+}
+
+impl<'a> NotifyMessage<'a> {
+    fn new<T: ByteValued>(opcode: NotifyOpcode, args: &T, payload: Vec<&'a [u8]>) -> Self {
+        let len = size_of::<OutHeader>()
+            + size_of::<T>()
+            + payload.iter().map(|p| p.len()).sum::<usize>();
+        NotifyMessage {
+            opcode,
+            header: OutHeader {
+                len: len as u32,
+                error: opcode as i32,
+                unique: 0,
+            },
+            args: args.as_slice().to_vec(),
+            payload,
+        }
+    }
+
+    /// Encode a `FUSE_NOTIFY_INVAL_INODE` message.
+    pub(crate) fn inval_inode(ino: u64, off: i64, len: i64) -> Self {
+        Self::new(
+            NotifyOpcode::InvalInode,
+            &NotifyInvalInodeOut { ino, off, len },
+            Vec::new(),
+        )
+    }
+
+    /// Encode a `FUSE_NOTIFY_INVAL_ENTRY` message, with `flags` cleared if protocol minor
+    /// version `minor` doesn't know them.
+    pub(crate) fn inval_entry(parent: u64, name: &'a CStr, flags: u32, minor: u32) -> Self {
+        let name = name.to_bytes_with_nul();
+        let out = NotifyInvalEntryOut {
+            parent,
+            namelen: (name.len() - 1) as u32,
+            flags: compat::inval_entry_flags(flags, minor),
+        };
+        Self::new(NotifyOpcode::InvalEntry, &out, vec![name])
+    }
+
+    /// Encode a `FUSE_NOTIFY_DELETE` message.
+    pub(crate) fn delete(parent: u64, child: u64, name: &'a CStr) -> Self {
+        let name = name.to_bytes_with_nul();
+        let out = NotifyDeleteOut {
+            parent,
+            child,
+            namelen: (name.len() - 1) as u32,
+            padding: 0,
+        };
+        Self::new(NotifyOpcode::Delete, &out, vec![name])
+    }
+
+    /// Encode the `FUSE_NOTIFY_STORE` messages pushing `data` at `offset` of inode `nodeid`,
+    /// each carrying at most `max_write` bytes of data.
+    pub(crate) fn store(
+        nodeid: u64,
+        offset: u64,
+        data: &[&'a [u8]],
+        max_write: usize,
+    ) -> Vec<Self> {
+        let max_write = max_write.max(1);
+        let mut slices: Vec<&[u8]> = data
+            .iter()
+            .rev()
+            .filter(|s| !s.is_empty())
+            .copied()
+            .collect();
+        let mut msgs = Vec::new();
+        let mut stored = 0;
+
+        while !slices.is_empty() {
+            // Gather at most `max_write` bytes from the remaining slices.
+            let mut size = 0;
+            let mut payload = Vec::new();
+            while size < max_write {
+                let slice = match slices.pop() {
+                    Some(s) => s,
+                    None => break,
+                };
+                let len = slice.len().min(max_write - size);
+                payload.push(&slice[..len]);
+                if len < slice.len() {
+                    slices.push(&slice[len..]);
+                }
+                size += len;
+            }
+
+            let out = NotifyStoreOut {
+                nodeid,
+                offset: offset + stored as u64,
+                size: size as u32,
+                padding: 0,
+            };
+            msgs.push(Self::new(NotifyOpcode::Store, &out, payload));
+            stored += size;
+        }
+
+        msgs
+    }
+
+    /// Encode a `FUSE_NOTIFY_RETRIEVE` message.
+    pub(crate) fn retrieve(notify_unique: u64, nodeid: u64, offset: u64, size: u32) -> Self {
+        let out = Notify_Retrieve_Out {
+            notify_unique,
+            nodeid,
+            offset,
+            size,
+            padding: 0,
+        };
+        Self::new(NotifyOpcode::Retrieve, &out, Vec::new())
+    }
+
+    /// Encode a `FUSE_NOTIFY_POLL` message.
+    pub(crate) fn poll_wakeup(kh: u64) -> Self {
+        Self::new(NotifyOpcode::Poll, &NotifyPollWakeupOut { kh }, Vec::new())
+    }
+
+    /// Get the opcode of the message.
+    pub(crate) fn opcode(&self) -> NotifyOpcode {
+        self.opcode
+    }
+
+    /// Get the length of the message.
+    pub(crate) fn len(&self) -> usize {
+        self.header.len as usize
+    }
+
+    /// Get the length of the payload, which is the data stored by `FUSE_NOTIFY_STORE` messages.
+    pub(crate) fn payload_len(&self) -> usize {
+        self.payload.iter().map(|p| p.len()).sum()
+    }
+
+    /// Get the buffers to write the message from.
+    pub(crate) fn bufs(&self) -> Vec<IoSlice<'_>> {
+        let mut bufs = Vec::with_capacity(self.payload.len() + 2);
+        bufs.push(IoSlice::new(self.header.as_slice()));
+        bufs.push(IoSlice::new(&self.args));
+        bufs.extend(self.payload.iter().map(|p| IoSlice::new(p)));
+        bufs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abi::fuse_abi::FUSE_EXPIRE_ONLY;
+    use std::ffi::CString;
+
+    fn encode(msg: &NotifyMessage) -> Vec<u8> {
+        let buf: Vec<u8> = msg.bufs().iter().flat_map(|b| b.to_vec()).collect();
+        assert_eq!(buf.len(), msg.len());
+        let header = OutHeader::from_slice(&buf[..size_of::<OutHeader>()]).unwrap();
+        assert_eq!(header.len as usize, buf.len());
+        assert_eq!(header.error, msg.opcode() as i32);
+        assert_eq!(header.unique, 0);
+        buf[size_of::<OutHeader>()..].to_vec()
+    }
+
+    #[test]
+    fn test_notify_message_store() {
+        let data: [&[u8]; 3] = [b"abc", b"", b"defghij"];
+        let msgs = NotifyMessage::store(5, 100, &data, 4);
+        let msgs: Vec<_> = msgs
+            .iter()
+            .map(|m| {
+                let body = encode(m);
+                let out = NotifyStoreOut::from_slice(&body[..size_of::<NotifyStoreOut>()]).unwrap();
+                let data = body[size_of::<NotifyStoreOut>()..].to_vec();
+                assert_eq!(
+                    (out.size as usize, m.payload_len()),
+                    (data.len(), data.len())
+                );
+                (out.nodeid, out.offset, data)
+            })
+            .collect();
+        assert_eq!(
+            msgs,
+            vec![
+                (5, 100, b"abcd".to_vec()),
+                (5, 104, b"efgh".to_vec()),
+                (5, 108, b"ij".to_vec()),
+            ]
+        );
+        assert!(NotifyMessage::store(5, 0, &[b""], 4).is_empty());
+    }
+
+    #[test]
+    fn test_notify_message_inval_entry() {
+        let name = CString::new("abc").unwrap();
+        let flags = |minor: u32| {
+            let msg = NotifyMessage::inval_entry(1, &name, FUSE_EXPIRE_ONLY, minor);
+            let body = encode(&msg);
+            assert_eq!(body.len(), size_of::<NotifyInvalEntryOut>() + 4);
+            let out =
+                NotifyInvalEntryOut::from_slice(&body[..size_of::<NotifyInvalEntryOut>()]).unwrap();
+            assert_eq!((out.parent, out.namelen), (1, 3));
+            assert_eq!(&body[size_of::<NotifyInvalEntryOut>()..], b"abc\0");
+            out.flags
+        };
+        assert_eq!(flags(38), FUSE_EXPIRE_ONLY);
+        assert_eq!(flags(37), 0);
+    }
+
+    #[test]
+    fn test_notify_message_retrieve() {
+        let body = encode(&NotifyMessage::retrieve(7, 3, 4096, 8192));
+        let out = Notify_Retrieve_Out::from_slice(&body).unwrap();
+        assert_eq!(
+            (out.notify_unique, out.nodeid, out.offset, out.size),
+            (7, 3, 4096, 8192)
+        );
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Dispatching of custom opcodes to handlers registered on the server.
+//!
+//! Experimental kernel patches may add Fuse opcodes the server knows nothing about. Instead of
+//! forking the server to dispatch them, handlers may be registered for such opcodes by
+//! [Server::register_opcode_handler]. Opcodes recognized by the server may only be taken over
+//! after opting in by [Server::with_unchecked_opcode_overrides], because overriding them, `INIT`,
+//! `DESTROY` and `FORGET` in particular, easily breaks the protocol state kept by the server.
+
+use std::io;
+
+use super::Server;
+use crate::abi::fuse_abi::{InHeader, Opcode};
+use crate::api::filesystem::FileSystem;
+
+/// A handler of requests with a custom opcode.
+///
+/// The handler may be called concurrently from multiple server threads, and it must not call back
+/// into the server to handle another request.
+pub trait RawOpcodeHandler: Send + Sync {
+    /// Handle the request described by `header`, with the request body available from `payload`
+    /// and the reply body, without the `OutHeader`, to be written into `reply`.
+    ///
+    /// The server sends the reply as a single message, or replies with the error on failure.
+    fn handle(
+        &self,
+        header: &InHeader,
+        payload: &mut dyn io::Read,
+        reply: &mut dyn io::Write,
+    ) -> io::Result<()>;
+
+    /// Whether requests of the opcode expect a reply, like most opcodes except `FUSE_FORGET`.
+    fn expects_reply(&self) -> bool {
+        true
+    }
+}
+
+// Check whether the server dispatches `opcode` itself.
+fn is_builtin(opcode: u32) -> bool {
+    #[cfg(target_os = "macos")]
+    if (Opcode::Setvolname as u32..=Opcode::Exchange as u32).contains(&opcode) {
+        return true;
+    }
+    opcode > 0 && opcode < Opcode::MaxOpcode as u32 || opcode == Opcode::AtomicOpen as u32
+}
+
+impl<F: FileSystem + Sync> Server<F> {
+    /// Allow [Server::register_opcode_handler] to take over opcodes dispatched by the server.
+    ///
+    /// Overridden opcodes bypass all bookkeeping of the server, such as lookup count accounting,
+    /// handle access checks and protocol negotiation, so it's only meant for prototyping.
+    pub fn with_unchecked_opcode_overrides(mut self) -> Self {
+        self.opcode_overrides = true;
+        self
+    }
+
+    /// Register `handler` to handle requests with `opcode`.
+    ///
+    /// Fail with `EPERM` if the server dispatches `opcode` itself, unless overrides are allowed
+    /// by [Server::with_unchecked_opcode_overrides], and with `EEXIST` if a handler has been
+    /// registered for `opcode`. Handlers are consulted by [Server::handle_message] after the raw
+    /// handler, the asynchronous request path doesn't consult them.
+    pub fn register_opcode_handler(
+        &mut self,
+        opcode: u32,
+        handler: Box<dyn RawOpcodeHandler>,
+    ) -> io::Result<()> {
+        if is_builtin(opcode) && !self.opcode_overrides {
+            warn!("fuse: refuse to override builtin opcode {}", opcode);
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        if self.opcodes.contains_key(&opcode) {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+        self.opcodes.insert(opcode, handler);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_builtin() {
+        assert!(is_builtin(Opcode::Init as u32));
+        assert!(is_builtin(Opcode::RemoveMapping as u32));
+        assert!(is_builtin(Opcode::AtomicOpen as u32));
+        assert!(!is_builtin(0));
+        assert!(!is_builtin(Opcode::MaxOpcode as u32));
+        assert!(!is_builtin(4096));
+        #[cfg(target_os = "macos")]
+        assert!(is_builtin(Opcode::Exchange as u32));
+    }
+}
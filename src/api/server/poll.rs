@@ -0,0 +1,100 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Poll wakeups of the guest kernel.
+//!
+//! `FUSE_POLL` requests carry a kernel poll handle, and `FUSE_POLL_SCHEDULE_NOTIFY` when the
+//! kernel waits for a `FUSE_NOTIFY_POLL` wakeup of that handle before polling again. The server
+//! records such handles in a [PollNotifier] shared with the filesystem, which checks whether a
+//! handle is waited for with [PollNotifier::is_scheduled], and wakes it up with
+//! [PollNotifier::wakeup] once the file becomes ready. Wakeups are queued, to be sent to the
+//! guest kernel by the transport layer with [Server::notify_poll_wakeup].
+//!
+//! Wakeups are one-shot: the kernel schedules a handle again with the next `FUSE_POLL` if it
+//! still waits. Handles are forgotten when their file handle is released.
+//!
+//! [Server::notify_poll_wakeup]: super::Server::notify_poll_wakeup
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[derive(Default)]
+struct PollState {
+    // File handles of the scheduled kernel poll handles.
+    scheduled: HashMap<u64, u64>,
+    pending: Vec<u64>,
+}
+
+/// Kernel poll handles waiting for a wakeup, and wakeups to be sent to the guest kernel.
+#[derive(Default)]
+pub struct PollNotifier {
+    state: Mutex<PollState>,
+}
+
+impl PollNotifier {
+    /// Create a notifier without scheduled handles.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the kernel waits for a wakeup of poll handle `kh`.
+    pub fn is_scheduled(&self, kh: u64) -> bool {
+        self.state.lock().unwrap().scheduled.contains_key(&kh)
+    }
+
+    /// Queue a wakeup of poll handle `kh`, if the kernel waits for one.
+    ///
+    /// Return false if `kh` isn't scheduled, nothing waits for it.
+    pub fn wakeup(&self, kh: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.scheduled.remove(&kh).is_none() {
+            return false;
+        }
+        state.pending.push(kh);
+        true
+    }
+
+    /// Take the queued wakeups, to be sent by `Server::notify_poll_wakeup()`.
+    pub fn take(&self) -> Vec<u64> {
+        std::mem::take(&mut self.state.lock().unwrap().pending)
+    }
+
+    // Record that the kernel waits for a wakeup of `kh`, polling file handle `fh`.
+    pub(super) fn schedule(&self, kh: u64, fh: u64) {
+        self.state.lock().unwrap().scheduled.insert(kh, fh);
+    }
+
+    // Forget the poll handles of the released file handle `fh`.
+    pub(super) fn release(&self, fh: u64) {
+        let mut state = self.state.lock().unwrap();
+        if !state.scheduled.is_empty() {
+            state.scheduled.retain(|_, v| *v != fh);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_poll_notifier() {
+        let notifier = PollNotifier::new();
+        assert!(!notifier.wakeup(1));
+
+        notifier.schedule(1, 10);
+        notifier.schedule(2, 10);
+        notifier.schedule(3, 11);
+        assert!(notifier.is_scheduled(1));
+        assert!(notifier.wakeup(1));
+        // One-shot until scheduled again.
+        assert!(!notifier.is_scheduled(1));
+        assert!(!notifier.wakeup(1));
+        assert!(notifier.wakeup(3));
+        assert_eq!(notifier.take(), vec![1, 3]);
+        assert!(notifier.take().is_empty());
+
+        notifier.release(10);
+        assert!(!notifier.is_scheduled(2));
+    }
+}
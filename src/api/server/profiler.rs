@@ -0,0 +1,457 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Low-overhead request sampling profiler for the Fuse API server.
+//!
+//! Full request tracing is too expensive to enable in production, so the server may instead be
+//! configured to record a detailed trace for a small subset of requests. The sampling decision
+//! costs a single atomic increment on the hot path, and only sampled requests pay for timestamps
+//! and for storing the trace into a fixed-size ring buffer.
+
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::abi::fuse_abi::{InHeader, Opcode, OutHeader};
+use crate::api::clock::{Clock, SystemClock};
+
+/// Policy to select which requests should be sampled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SamplingPolicy {
+    /// Sample every Nth request. A value of `0` or `1` samples every request.
+    EveryNth(u64),
+    /// Sample requests with the given probability, in the range `[0.0, 1.0]`.
+    Probability(f64),
+}
+
+/// Configuration for the request sampling profiler.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplingConfig {
+    /// Policy to select sampled requests.
+    pub policy: SamplingPolicy,
+    /// Number of traces kept in the ring buffer, oldest traces are overwritten first.
+    pub capacity: usize,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        SamplingConfig {
+            policy: SamplingPolicy::EveryNth(1000),
+            capacity: 256,
+        }
+    }
+}
+
+/// Detailed trace of a sampled request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestTrace {
+    /// Sequence number of the trace, increasing monotonically for each recorded trace.
+    pub seq: u64,
+    /// Opcode of the request.
+    pub opcode: u32,
+    /// Unique id of the request.
+    pub unique: u64,
+    /// Inode number the request targets.
+    pub nodeid: u64,
+    /// Size of the request message, including the header.
+    pub in_len: u32,
+    /// Size of the reply message, including the header.
+    pub out_len: usize,
+    /// Whether the server failed to handle the request.
+    pub failed: bool,
+    /// Time spent on decoding the request before invoking the filesystem driver.
+    pub decode_time: Duration,
+    /// Time spent in the filesystem driver.
+    pub fs_time: Duration,
+    /// Time spent on writing the reply to the transport layer.
+    pub reply_time: Duration,
+}
+
+impl fmt::Display for RequestTrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "#{} {:?} unique {} ino {} in {} out {} decode {}us fs {}us reply {}us{}",
+            self.seq,
+            Opcode::from(self.opcode),
+            self.unique,
+            self.nodeid,
+            self.in_len,
+            self.out_len,
+            self.decode_time.as_micros(),
+            self.fs_time.as_micros(),
+            self.reply_time.as_micros(),
+            if self.failed { " failed" } else { "" }
+        )
+    }
+}
+
+/// Format a list of request traces as text, one trace per line.
+pub fn format_traces(traces: &[RequestTrace]) -> String {
+    let mut out = String::new();
+    for t in traces {
+        // Writing to a String never fails.
+        let _ = writeln!(out, "{}", t);
+    }
+    out
+}
+
+// Timestamps collected while handling a sampled request.
+//
+// The intermediate timestamps are stored as nanoseconds relative to `start`, so the timer could
+// be shared with the request context without locking.
+pub(crate) struct SampleTimer {
+    clock: Arc<dyn Clock>,
+    start: Duration,
+    decoded: AtomicU64,
+    replying: AtomicU64,
+    // Length of the reply and whether it's an error reply, once written.
+    reply: Mutex<Option<(usize, bool)>>,
+}
+
+impl SampleTimer {
+    fn new(clock: Arc<dyn Clock>) -> Self {
+        SampleTimer {
+            start: clock.monotonic(),
+            clock,
+            decoded: AtomicU64::new(0),
+            replying: AtomicU64::new(0),
+            reply: Mutex::new(None),
+        }
+    }
+
+    fn elapsed_nanos(&self) -> u64 {
+        // Saturates after ~584 years, good enough.
+        self.clock.monotonic().saturating_sub(self.start).as_nanos() as u64
+    }
+
+    // Mark the end of the decoding stage, only the first call takes effect.
+    pub(crate) fn mark_decoded(&self) {
+        if self.decoded.load(Ordering::Relaxed) == 0 {
+            self.decoded
+                .store(self.elapsed_nanos().max(1), Ordering::Relaxed);
+        }
+    }
+
+    // Mark the beginning of the reply stage, only the first call takes effect.
+    pub(crate) fn mark_replying(&self) {
+        if self.replying.load(Ordering::Relaxed) == 0 {
+            self.replying
+                .store(self.elapsed_nanos().max(1), Ordering::Relaxed);
+        }
+    }
+
+    // Remember the header of the reply written for the request.
+    pub(crate) fn record_reply(&self, header: &OutHeader) {
+        *self.reply.lock().unwrap() = Some((header.len as usize, header.error != 0));
+    }
+}
+
+/// Record the trace of a sampled request when dropped.
+///
+/// The trace carries the result passed to [SampleGuard::finish], or else the reply recorded by
+/// the timer, so requests failed before reaching the filesystem driver get recorded too. Requests
+/// dropped without any reply are recorded as failed.
+pub(crate) struct SampleGuard<'a> {
+    sampler: &'a RequestSampler,
+    timer: Arc<SampleTimer>,
+    in_header: InHeader,
+    result: Option<(usize, bool)>,
+}
+
+impl SampleGuard<'_> {
+    pub(crate) fn timer(&self) -> Arc<SampleTimer> {
+        self.timer.clone()
+    }
+
+    /// Set the result of the request, as returned to the transport layer.
+    pub(crate) fn finish(&mut self, result: &crate::Result<usize>) {
+        self.result = Some((*result.as_ref().unwrap_or(&0), result.is_err()));
+    }
+}
+
+impl Drop for SampleGuard<'_> {
+    fn drop(&mut self) {
+        let (out_len, failed) = self
+            .result
+            .or_else(|| *self.timer.reply.lock().unwrap())
+            .unwrap_or((0, true));
+        self.sampler
+            .record(&self.timer, &self.in_header, out_len, failed);
+    }
+}
+
+/// Request sampler with a fixed-size ring buffer of traces.
+///
+/// The ring is made up of individually locked slots, so retrieving traces never blocks request
+/// handling threads for longer than copying a single slot.
+pub(crate) struct RequestSampler {
+    counter: AtomicU64,
+    every: u64,
+    threshold: Option<u64>,
+    seq: AtomicU64,
+    slots: Vec<Mutex<Option<RequestTrace>>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl RequestSampler {
+    pub(crate) fn new(cfg: SamplingConfig) -> Self {
+        let (every, threshold) = match cfg.policy {
+            SamplingPolicy::EveryNth(n) => (n.max(1), None),
+            SamplingPolicy::Probability(p) => {
+                let p = if p.is_nan() { 0.0 } else { p.clamp(0.0, 1.0) };
+                (1, Some((p * u64::MAX as f64) as u64))
+            }
+        };
+        let capacity = cfg.capacity.max(1);
+
+        RequestSampler {
+            counter: AtomicU64::new(0),
+            every,
+            threshold,
+            seq: AtomicU64::new(0),
+            slots: (0..capacity).map(|_| Mutex::new(None)).collect(),
+            clock: Arc::new(SystemClock::default()),
+        }
+    }
+
+    pub(crate) fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Decide whether the request of `in_header` should be sampled, and start timing it if so.
+    ///
+    /// This is the only cost paid by requests which are not sampled.
+    pub(crate) fn sample(&self, in_header: &InHeader) -> Option<SampleGuard<'_>> {
+        let n = self.counter.fetch_add(1, Ordering::Relaxed);
+        let hit = match self.threshold {
+            None => matches!(n % self.every, 0),
+            Some(threshold) => threshold == u64::MAX || mix64(n) < threshold,
+        };
+
+        if hit {
+            Some(SampleGuard {
+                sampler: self,
+                timer: Arc::new(SampleTimer::new(self.clock.clone())),
+                in_header: *in_header,
+                result: None,
+            })
+        } else {
+            None
+        }
+    }
+
+    // Finish a sampled request and store its trace into the ring buffer.
+    fn record(&self, timer: &SampleTimer, in_header: &InHeader, out_len: usize, failed: bool) {
+        let end = timer.elapsed_nanos();
+        let mut replying = timer.replying.load(Ordering::Relaxed);
+        if replying == 0 {
+            replying = end;
+        }
+        let mut decoded = timer.decoded.load(Ordering::Relaxed);
+        if decoded == 0 || decoded > replying {
+            decoded = replying;
+        }
+
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let trace = RequestTrace {
+            seq,
+            opcode: in_header.opcode,
+            unique: in_header.unique,
+            nodeid: in_header.nodeid,
+            in_len: in_header.len,
+            out_len,
+            failed,
+            decode_time: Duration::from_nanos(decoded),
+            fs_time: Duration::from_nanos(replying - decoded),
+            reply_time: Duration::from_nanos(end.saturating_sub(replying)),
+        };
+
+        let idx = (seq % self.slots.len() as u64) as usize;
+        let mut slot = self.slots[idx].lock().unwrap();
+        // A slower thread may race with a newer trace for the same slot, keep the newer one.
+        if slot.map(|t| t.seq < seq).unwrap_or(true) {
+            *slot = Some(trace);
+        }
+    }
+
+    /// Get a snapshot of traces in the ring buffer, ordered from the oldest to the newest.
+    pub(crate) fn traces(&self) -> Vec<RequestTrace> {
+        let mut traces: Vec<RequestTrace> = self
+            .slots
+            .iter()
+            .filter_map(|s| *s.lock().unwrap())
+            .collect();
+        traces.sort_by_key(|t| t.seq);
+        traces
+    }
+}
+
+// SplitMix64 finalizer, to spread sequential counter values uniformly over u64.
+fn mix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::clock::ManualClock;
+
+    fn in_header(opcode: Opcode, unique: u64, nodeid: u64, len: u32) -> InHeader {
+        InHeader {
+            len,
+            opcode: opcode as u32,
+            unique,
+            nodeid,
+            ..Default::default()
+        }
+    }
+
+    fn record_one(sampler: &RequestSampler, unique: u64) -> bool {
+        if let Some(mut sample) = sampler.sample(&in_header(Opcode::Getattr, unique, 1, 56)) {
+            let timer = sample.timer();
+            timer.mark_decoded();
+            timer.mark_replying();
+            sample.finish(&Ok(16));
+            true
+        } else {
+            false
+        }
+    }
+
+    #[test]
+    fn test_sample_every_nth() {
+        let sampler = RequestSampler::new(SamplingConfig {
+            policy: SamplingPolicy::EveryNth(4),
+            capacity: 64,
+        });
+        let hits = (0..100).filter(|i| record_one(&sampler, *i)).count();
+        assert_eq!(hits, 25);
+
+        let traces = sampler.traces();
+        assert_eq!(traces.len(), 25);
+        assert_eq!(traces[0].unique, 0);
+        assert_eq!(traces[1].unique, 4);
+        assert_eq!(traces[24].unique, 96);
+    }
+
+    #[test]
+    fn test_sample_probability() {
+        let sampler = RequestSampler::new(SamplingConfig {
+            policy: SamplingPolicy::Probability(0.1),
+            capacity: 16,
+        });
+        let hits = (0..10000).filter(|i| record_one(&sampler, *i)).count();
+        assert!(hits > 800 && hits < 1200, "hits {}", hits);
+
+        let none = RequestSampler::new(SamplingConfig {
+            policy: SamplingPolicy::Probability(0.0),
+            capacity: 16,
+        });
+        assert_eq!((0..1000).filter(|i| record_one(&none, *i)).count(), 0);
+
+        let all = RequestSampler::new(SamplingConfig {
+            policy: SamplingPolicy::Probability(1.0),
+            capacity: 16,
+        });
+        assert_eq!((0..1000).filter(|i| record_one(&all, *i)).count(), 1000);
+    }
+
+    #[test]
+    fn test_ring_overwrite_oldest() {
+        let sampler = RequestSampler::new(SamplingConfig {
+            policy: SamplingPolicy::EveryNth(1),
+            capacity: 4,
+        });
+        for i in 0..10 {
+            record_one(&sampler, i);
+        }
+
+        let traces = sampler.traces();
+        let uniques: Vec<u64> = traces.iter().map(|t| t.unique).collect();
+        assert_eq!(uniques, vec![6, 7, 8, 9]);
+        let seqs: Vec<u64> = traces.iter().map(|t| t.seq).collect();
+        assert_eq!(seqs, vec![6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn test_sample_timer_clock() {
+        let clock = Arc::new(ManualClock::default());
+        let sampler = RequestSampler::new(SamplingConfig {
+            policy: SamplingPolicy::EveryNth(1),
+            capacity: 4,
+        })
+        .with_clock(clock.clone());
+
+        let mut sample = sampler.sample(&in_header(Opcode::Read, 1, 2, 80)).unwrap();
+        let timer = sample.timer();
+        clock.advance(Duration::from_micros(10));
+        timer.mark_decoded();
+        clock.advance(Duration::from_micros(200));
+        timer.mark_replying();
+        clock.advance(Duration::from_micros(30));
+        sample.finish(&Ok(4096));
+        drop(sample);
+
+        let trace = sampler.traces()[0];
+        assert_eq!(trace.decode_time, Duration::from_micros(10));
+        assert_eq!(trace.fs_time, Duration::from_micros(200));
+        assert_eq!(trace.reply_time, Duration::from_micros(30));
+    }
+
+    #[test]
+    fn test_format_traces() {
+        let sampler = RequestSampler::new(SamplingConfig {
+            policy: SamplingPolicy::EveryNth(1),
+            capacity: 4,
+        });
+        record_one(&sampler, 7);
+        let mut sample = sampler
+            .sample(&in_header(Opcode::Lookup, 8, 2, 48))
+            .unwrap();
+        sample.finish(&Err(crate::Error::MissingParameter));
+        drop(sample);
+
+        let text = format_traces(&sampler.traces());
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("#0 Getattr unique 7 ino 1 in 56 out 16"));
+        assert!(lines[1].starts_with("#1 Lookup unique 8 ino 2 in 48 out 0"));
+        assert!(lines[1].ends_with(" failed"));
+    }
+
+    #[test]
+    fn test_sample_guard_unfinished() {
+        let sampler = RequestSampler::new(SamplingConfig {
+            policy: SamplingPolicy::EveryNth(1),
+            capacity: 4,
+        });
+
+        // Rejected by an error reply before reaching the filesystem driver.
+        let sample = sampler.sample(&in_header(Opcode::Read, 1, 2, 80)).unwrap();
+        sample.timer().record_reply(&OutHeader {
+            len: 16,
+            error: -libc::ENOMEM,
+            unique: 1,
+        });
+        drop(sample);
+        // Dropped without any reply.
+        drop(
+            sampler
+                .sample(&in_header(Opcode::Forget, 2, 2, 48))
+                .unwrap(),
+        );
+
+        let traces = sampler.traces();
+        assert_eq!(traces.len(), 2);
+        assert_eq!((traces[0].unique, traces[0].out_len), (1, 16));
+        assert!(traces[0].failed);
+        assert_eq!((traces[1].unique, traces[1].out_len), (2, 0));
+        assert!(traces[1].failed);
+    }
+}
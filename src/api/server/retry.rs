@@ -0,0 +1,97 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Retrying of idempotent requests failing transiently.
+//!
+//! Backends built on network storage or FUSE-on-FUSE stacks occasionally fail with `EINTR` or
+//! `EAGAIN`, which the guest kernel reports to applications as is. Enabled by
+//! [Server::with_transient_retries], the server calls the backend again, a bounded number of
+//! times, for `FUSE_LOOKUP`, `FUSE_GETATTR`, `FUSE_STATX`, `FUSE_READLINK`, `FUSE_GETXATTR`,
+//! `FUSE_READDIR` and `FUSE_READDIRPLUS` failing that way. Those requests don't change the state
+//! of the backend, so calling them twice is harmless. Write-class and other state changing
+//! requests are never retried, as their first attempt may have been partially applied.
+//!
+//! Failures while draining for shutdown are never retried, as they are the way the
+//! [DrainShedder](crate::api::shedder::DrainShedder) aborts operations.
+
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::Server;
+use crate::api::errno::errno_of;
+use crate::api::filesystem::FileSystem;
+
+pub(super) struct TransientRetry {
+    max: u32,
+    retries: AtomicU64,
+}
+
+impl<F: FileSystem + Sync> Server<F> {
+    /// Retry idempotent requests failing with `EINTR` or `EAGAIN` up to `max` times, zero to
+    /// reply such failures to the guest.
+    ///
+    /// The default value for this option is 0.
+    pub fn with_transient_retries(mut self, max: u32) -> Self {
+        self.retry = if max > 0 {
+            Some(TransientRetry {
+                max,
+                retries: AtomicU64::new(0),
+            })
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Get the number of times requests were retried after failing transiently.
+    pub fn transient_retries(&self) -> u64 {
+        self.retry
+            .as_ref()
+            .map_or(0, |r| r.retries.load(Ordering::Relaxed))
+    }
+
+    // Check whether an idempotent request should be attempted again after its `attempt`th retry
+    // failed with `err`, and count the retry.
+    pub(super) fn should_retry(&self, err: &io::Error, attempt: u32) -> bool {
+        let retry = match self.retry.as_ref() {
+            Some(r) if attempt < r.max => r,
+            _ => return false,
+        };
+        if !matches!(errno_of(err), Some(libc::EINTR) | Some(libc::EAGAIN))
+            || self.shedder.is_draining()
+        {
+            return false;
+        }
+        retry.retries.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    // Call the idempotent operation `f` until it doesn't fail transiently.
+    pub(super) fn retry_transient<T>(&self, mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Err(e) if self.should_retry(&e, attempt) => attempt += 1,
+                res => return res,
+            }
+        }
+    }
+
+    // Call the idempotent asynchronous operation `f` until it doesn't fail transiently.
+    #[cfg(feature = "async-io")]
+    pub(super) async fn async_retry_transient<T, Fut>(
+        &self,
+        mut f: impl FnMut() -> Fut,
+    ) -> io::Result<T>
+    where
+        Fut: std::future::Future<Output = io::Result<T>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match f().await {
+                Err(e) if self.should_retry(&e, attempt) => attempt += 1,
+                res => return res,
+            }
+        }
+    }
+}
@@ -0,0 +1,172 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Fair scheduling of requests across the backends of a [Vfs](crate::api::Vfs).
+//!
+//! When a [Vfs](crate::api::Vfs) mounts a slow network backend next to a local one, requests
+//! of the slow backend may occupy all worker threads and starve the fast one. A
+//! [BackendScheduler] caps the number of in-flight requests of each backend. Requests of a
+//! backend at its cap are deferred into a queue of the backend instead of blocking a worker, and
+//! the worker completing a request of the backend picks up its next deferred request.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use crate::api::VfsIndex;
+/// Limits of in-flight requests per backend, see [BackendScheduler].
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackendLimits {
+    /// Max number of in-flight requests of each backend, further requests are deferred.
+    ///
+    /// The default value for this option is `usize::MAX`.
+    pub max_inflight: usize,
+}
+
+impl Default for BackendLimits {
+    fn default() -> Self {
+        BackendLimits {
+            max_inflight: usize::MAX,
+        }
+    }
+}
+
+/// Classifier of requests by the `nodeid` of their header, returning the index of the backend
+/// serving the inode, like [Vfs::backend_of](crate::api::Vfs::backend_of).
+pub type BackendClassifier = Arc<dyn Fn(u64) -> Option<VfsIndex> + Send + Sync>;
+
+/// An in-flight request admitted by a [BackendScheduler], which must be passed back to
+/// [BackendScheduler::complete] once the request has been handled.
+#[must_use]
+#[derive(Debug)]
+pub struct BackendSlot {
+    backend: Option<VfsIndex>,
+}
+
+struct BackendQueue<T> {
+    inflight: usize,
+    deferred: VecDeque<T>,
+}
+
+/// Fair scheduler of requests across the backends of a Vfs.
+///
+/// Requests not served by any backend, like `FUSE_INIT` or requests on the pseudo fs, are never
+/// deferred. Deferred requests of a backend are run in arrival order, and a request arriving
+/// while earlier requests of its backend are deferred is deferred too, so requests on the same
+/// handle keep their order.
+pub struct BackendScheduler<T> {
+    limits: BackendLimits,
+    classify: BackendClassifier,
+    backends: Mutex<HashMap<VfsIndex, BackendQueue<T>>>,
+}
+
+impl<T> BackendScheduler<T> {
+    /// Create a scheduler classifying requests by `classify`.
+    pub fn new(limits: BackendLimits, classify: BackendClassifier) -> Self {
+        BackendScheduler {
+            limits,
+            classify,
+            backends: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Admit a request on inode `nodeid`, or defer it if its backend is at its cap.
+    ///
+    /// Return the slot of the request if it should be handled right away. Otherwise `defer` is
+    /// called to take an owned copy of the request, which is returned later by
+    /// [BackendScheduler::complete].
+    pub fn submit(&self, nodeid: u64, defer: impl FnOnce() -> T) -> Option<BackendSlot> {
+        let backend = match (self.classify)(nodeid) {
+            Some(backend) => backend,
+            None => return Some(BackendSlot { backend: None }),
+        };
+
+        let mut backends = self.backends.lock().unwrap();
+        let queue = backends.entry(backend).or_insert_with(|| BackendQueue {
+            inflight: 0,
+            deferred: VecDeque::new(),
+        });
+        if queue.inflight < self.limits.max_inflight && queue.deferred.is_empty() {
+            queue.inflight += 1;
+            Some(BackendSlot {
+                backend: Some(backend),
+            })
+        } else {
+            queue.deferred.push_back(defer());
+            None
+        }
+    }
+
+    /// Complete the request of `slot`, and return the next deferred request of its backend, if
+    /// any, with the slot it's admitted in.
+    pub fn complete(&self, slot: BackendSlot) -> Option<(BackendSlot, T)> {
+        let backend = slot.backend?;
+        let mut backends = self.backends.lock().unwrap();
+        let queue = backends.get_mut(&backend)?;
+        match queue.deferred.pop_front() {
+            // Hand the slot over to the deferred request.
+            Some(next) => Some((slot, next)),
+            None => {
+                queue.inflight -= 1;
+                if queue.inflight == 0 {
+                    backends.remove(&backend);
+                }
+                None
+            }
+        }
+    }
+
+    /// Get the number of deferred requests of backend `backend`.
+    pub fn deferred(&self, backend: VfsIndex) -> usize {
+        self.backends
+            .lock()
+            .unwrap()
+            .get(&backend)
+            .map(|q| q.deferred.len())
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backend_scheduler() {
+        let sched = BackendScheduler::new(
+            BackendLimits { max_inflight: 2 },
+            Arc::new(|nodeid: u64| (nodeid != 0).then_some((nodeid >> 56) as VfsIndex)),
+        );
+        let slow = 1u64 << 56;
+        let fast = 2u64 << 56;
+
+        // Unclassified requests are never deferred nor accounted.
+        let init = sched.submit(0, || unreachable!()).unwrap();
+        assert!(sched.complete(init).is_none());
+
+        let s1 = sched.submit(slow | 1, || unreachable!()).unwrap();
+        let s2 = sched.submit(slow | 2, || unreachable!()).unwrap();
+        assert!(sched.submit(slow | 3, || "a").is_none());
+        assert!(sched.submit(slow | 4, || "b").is_none());
+        assert_eq!(sched.deferred(1), 2);
+        // Other backends aren't affected by the slow one.
+        let f1 = sched.submit(fast | 1, || unreachable!()).unwrap();
+        assert!(sched.complete(f1).is_none());
+
+        // Deferred requests are run in order by workers completing requests of the backend.
+        let (s3, job) = sched.complete(s1).unwrap();
+        assert_eq!(job, "a");
+        // Requests keep their order behind deferred ones, although the backend is below its cap.
+        assert!(sched.submit(slow | 5, || "c").is_none());
+        let (s4, job) = sched.complete(s3).unwrap();
+        assert_eq!(job, "b");
+        let (s5, job) = sched.complete(s2).unwrap();
+        assert_eq!(job, "c");
+        assert_eq!(sched.deferred(1), 0);
+        assert!(sched.complete(s4).is_none());
+        let s6 = sched.submit(slow | 6, || unreachable!()).unwrap();
+        assert!(sched.complete(s5).is_none());
+        assert!(sched.complete(s6).is_none());
+        assert!(sched.backends.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Orderly shutdown of a Fuse service.
+//!
+//! Tearing down a Fuse service involves the transport session, the server and the file system
+//! driver, and the steps must happen in a well defined order:
+//! 1. stop the session from fetching new requests and wake up all channel loops,
+//! 2. wait for requests being handled by the server to complete,
+//! 3. give the file system a chance to delay destruction by [FileSystem::prepare_destroy],
+//! 4. forget all inodes by [FileSystem::forget_all] and destroy the file system,
+//! 5. umount the session.
+//!
+//! Otherwise the file system may get destroyed while requests are still being served, or
+//! requests may get stuck after the file system has been destroyed. [GracefulShutdown] drives
+//! these steps with timeouts, and falls back to aborting the connection if in-flight requests
+//! don't complete in time.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::Server;
+use crate::api::errno::errno_of;
+use crate::api::filesystem::FileSystem;
+
+const PREPARE_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+// How long `FUSE_DESTROY` waits for other in-flight requests before forgetting all inodes.
+pub(super) const DESTROY_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Track requests being handled by the server.
+#[derive(Default)]
+pub(crate) struct InflightTracker {
+    count: AtomicUsize,
+    draining: AtomicBool,
+    lock: Mutex<()>,
+    cond: Condvar,
+}
+
+impl InflightTracker {
+    /// Account a request, which is completed when the returned guard gets dropped.
+    pub(crate) fn enter(&self) -> InflightGuard<'_> {
+        self.begin();
+        InflightGuard { tracker: self }
+    }
+
+    /// Account a request, which is completed by `exit()`. For requests outliving the scope
+    /// accounting them.
+    pub(crate) fn begin(&self) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Get the number of requests being handled.
+    pub(crate) fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    /// Wait until no request is being handled, return false on timeout.
+    pub(crate) fn wait_drained(&self, timeout: Duration) -> bool {
+        self.wait_below(1, timeout)
+    }
+
+    /// Wait until less than `limit` requests are being handled, return false on timeout.
+    ///
+    /// Used by requests waiting for all other requests to complete.
+    pub(crate) fn wait_below(&self, limit: usize, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        // Only wake up waiters when draining, to keep the request path lock free.
+        self.draining.store(true, Ordering::SeqCst);
+        let mut guard = self.lock.lock().unwrap();
+        while self.count() >= limit {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            guard = self.cond.wait_timeout(guard, deadline - now).unwrap().0;
+        }
+
+        true
+    }
+
+    /// Complete a request accounted by `begin()`.
+    pub(crate) fn exit(&self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+        if self.draining.load(Ordering::SeqCst) {
+            let _guard = self.lock.lock().unwrap();
+            self.cond.notify_all();
+        }
+    }
+}
+
+pub(crate) struct InflightGuard<'a> {
+    tracker: &'a InflightTracker,
+}
+
+impl Drop for InflightGuard<'_> {
+    fn drop(&mut self) {
+        self.tracker.exit();
+    }
+}
+
+impl<F: FileSystem + Sync> Server<F> {
+    /// Wait for requests being handled by the server to complete, return false on timeout.
+    ///
+    /// The transport session should have stopped fetching new requests before calling this.
+    /// Long running operations consulting the [Server::drain_shedder] are aborted at their next
+    /// yield point.
+    pub fn wait_drained(&self, timeout: Duration) -> bool {
+        self.shedder.drain();
+        self.inflight.wait_drained(timeout)
+    }
+
+    /// Get the number of requests being handled by the server.
+    pub fn inflight_requests(&self) -> usize {
+        self.inflight.count()
+    }
+
+    // Forget all inodes of the file system when handling `FUSE_DESTROY`, once requests racing
+    // with it have completed within `timeout`. The `FUSE_DESTROY` request itself is in flight.
+    pub(super) fn forget_all_on_destroy(&self, timeout: Duration) {
+        if self.inflight.wait_below(2, timeout) {
+            self.fs.forget_all();
+        } else {
+            warn!(
+                "fuse: {} requests still in flight on destroy, skip forgetting all inodes",
+                self.inflight.count() - 1
+            );
+        }
+    }
+}
+
+/// Transport session operations needed by [GracefulShutdown].
+pub trait ShutdownSession {
+    /// Stop fetching new requests and wake up all channel loops to exit.
+    fn shutdown(&self) -> io::Result<()>;
+
+    /// Forcibly abort the connection, failing all pending requests.
+    fn abort(&self) -> io::Result<()>;
+
+    /// Umount the session and release the connection.
+    fn umount(&mut self) -> io::Result<()>;
+}
+
+/// Steps of a graceful shutdown, reported to the observer of [GracefulShutdown].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownEvent {
+    /// The session has been shut down.
+    SessionShutdown,
+    /// In-flight requests didn't complete in time, so the connection has been aborted.
+    Aborted,
+    /// All in-flight requests have completed.
+    Drained,
+    /// The file system is ready to be destroyed, or the prepare timeout has expired.
+    PrepareDestroy,
+    /// The file system has been destroyed.
+    Destroyed,
+    /// The session has been umounted.
+    Unmounted,
+}
+
+/// Drive the shutdown of a session, a server and its file system in order.
+pub struct GracefulShutdown {
+    drain_timeout: Duration,
+    prepare_timeout: Duration,
+    observer: Option<Box<dyn FnMut(ShutdownEvent) + Send>>,
+}
+
+impl Default for GracefulShutdown {
+    fn default() -> Self {
+        GracefulShutdown {
+            drain_timeout: Duration::from_secs(5),
+            prepare_timeout: Duration::from_secs(1),
+            observer: None,
+        }
+    }
+}
+
+impl GracefulShutdown {
+    /// Create a new instance with default timeouts.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set how long to wait for in-flight requests, before and after aborting the connection.
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Set how long the file system may delay destruction by [FileSystem::prepare_destroy].
+    pub fn with_prepare_timeout(mut self, timeout: Duration) -> Self {
+        self.prepare_timeout = timeout;
+        self
+    }
+
+    /// Set a callback to observe shutdown steps.
+    pub fn with_observer<O: FnMut(ShutdownEvent) + Send + 'static>(mut self, observer: O) -> Self {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    /// Shut down `session`, drain `server` and destroy its file system, then umount `session`.
+    ///
+    /// If in-flight requests don't complete even after aborting the connection, the session is
+    /// umounted without destroying the file system, and an error of kind `TimedOut` is returned.
+    pub fn run<F: FileSystem + Sync, S: ShutdownSession>(
+        &mut self,
+        session: &mut S,
+        server: &Server<F>,
+    ) -> io::Result<()> {
+        if let Err(e) = session.shutdown() {
+            warn!("graceful shutdown: failed to shut down session, {}", e);
+        }
+        self.notify(ShutdownEvent::SessionShutdown);
+
+        if !server.wait_drained(self.drain_timeout) {
+            warn!(
+                "graceful shutdown: {} requests in flight, abort connection",
+                server.inflight_requests()
+            );
+            if let Err(e) = session.abort() {
+                warn!("graceful shutdown: failed to abort connection, {}", e);
+            }
+            self.notify(ShutdownEvent::Aborted);
+            if !server.wait_drained(self.drain_timeout) {
+                let inflight = server.inflight_requests();
+                session.umount()?;
+                self.notify(ShutdownEvent::Unmounted);
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("{} requests still in flight", inflight),
+                ));
+            }
+        }
+        self.notify(ShutdownEvent::Drained);
+
+        let deadline = Instant::now() + self.prepare_timeout;
+        loop {
+            match server.fs.prepare_destroy() {
+                Ok(()) => break,
+                Err(e) if is_retryable(&e) && Instant::now() < deadline => {
+                    thread::sleep(PREPARE_RETRY_INTERVAL)
+                }
+                Err(e) => {
+                    warn!("graceful shutdown: destroy file system anyway, {}", e);
+                    break;
+                }
+            }
+        }
+        self.notify(ShutdownEvent::PrepareDestroy);
+
+        server.fs.forget_all();
+        server.fs.destroy();
+        self.notify(ShutdownEvent::Destroyed);
+
+        session.umount()?;
+        self.notify(ShutdownEvent::Unmounted);
+
+        Ok(())
+    }
+
+    fn notify(&mut self, event: ShutdownEvent) {
+        debug!("graceful shutdown: {:?}", event);
+        if let Some(observer) = self.observer.as_mut() {
+            observer(event);
+        }
+    }
+}
+
+fn is_retryable(e: &io::Error) -> bool {
+    matches!(errno_of(e), Some(libc::EBUSY) | Some(libc::EAGAIN))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_inflight_tracker() {
+        let tracker = Arc::new(InflightTracker::default());
+        assert!(tracker.wait_drained(Duration::from_millis(0)));
+
+        let guard = tracker.enter();
+        assert_eq!(tracker.count(), 1);
+        assert!(!tracker.wait_drained(Duration::from_millis(10)));
+
+        let t = tracker.clone();
+        let handle = thread::spawn(move || t.wait_drained(Duration::from_secs(10)));
+        thread::sleep(Duration::from_millis(10));
+        drop(guard);
+        assert!(handle.join().unwrap());
+        assert_eq!(tracker.count(), 0);
+    }
+
+    #[test]
+    fn test_inflight_tracker_wait_below() {
+        let tracker = Arc::new(InflightTracker::default());
+        let own = tracker.enter();
+        assert!(tracker.wait_below(2, Duration::from_millis(0)));
+
+        let other = tracker.enter();
+        assert!(!tracker.wait_below(2, Duration::from_millis(10)));
+        let t = tracker.clone();
+        let handle = thread::spawn(move || t.wait_below(2, Duration::from_secs(10)));
+        thread::sleep(Duration::from_millis(10));
+        drop(other);
+        assert!(handle.join().unwrap());
+        assert_eq!(tracker.count(), 1);
+        drop(own);
+    }
+}
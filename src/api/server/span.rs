@@ -0,0 +1,169 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Tracing spans of requests, with the `tracing` feature.
+//!
+//! The server opens a `fuse_request` span for every request with a valid header, recording its
+//! unique id, opcode, node id and the pid, uid and gid of the caller, and enters it while the
+//! request is handled, on the asynchronous path too. Events of file systems and of the Vfs then
+//! belong to the request which triggered them, and errors replied to the guest are recorded as
+//! `errno` events of the span. The id of the span is passed to file systems in
+//! [Context::span](crate::api::filesystem::Context::span), so work handed over to other threads
+//! may be attached to the request as well.
+//!
+//! Spans are cheap when no subscriber is installed, and compiled out without the feature.
+
+use std::num::NonZeroU64;
+
+use tracing::Span;
+
+use crate::abi::fuse_abi::{InHeader, Opcode, OutHeader};
+
+// Open the span of the request with header `in_header`.
+pub(super) fn request_span(in_header: &InHeader) -> Span {
+    tracing::info_span!(
+        "fuse_request",
+        unique = in_header.unique,
+        opcode = ?Opcode::from(in_header.opcode),
+        nodeid = in_header.nodeid,
+        pid = in_header.pid,
+        uid = in_header.uid,
+        gid = in_header.gid,
+    )
+}
+
+// Get the id of `span` to pass in the request context.
+pub(super) fn span_id(span: &Span) -> Option<NonZeroU64> {
+    span.id().map(|id| id.into_non_zero_u64())
+}
+
+// Record the error replied with `header` in the current span.
+pub(super) fn record_reply(header: &OutHeader) {
+    if header.error != 0 {
+        tracing::debug!(errno = -header.error, "fuse: reply error");
+    }
+}
+
+#[cfg(feature = "fusedev")]
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fmt::Debug;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    use crate::abi::fuse_abi::{Opcode, ROOT_ID};
+    use crate::api::filesystem::{Context, Entry, FileSystem};
+    use crate::api::server::Server;
+
+    #[derive(Default)]
+    struct Fields(HashMap<String, String>);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    // Spans with their fields, and the events within spans.
+    #[derive(Default)]
+    struct Records {
+        next_id: AtomicU64,
+        spans: Mutex<HashMap<u64, (&'static str, Fields)>>,
+        events: Mutex<Vec<(Option<u64>, Fields)>>,
+        current: Mutex<Vec<u64>>,
+    }
+
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Records>);
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let id = self.0.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+            let mut fields = Fields::default();
+            span.record(&mut fields);
+            let name = span.metadata().name();
+            self.0.spans.lock().unwrap().insert(id, (name, fields));
+            Id::from_u64(id)
+        }
+
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            let parent = match event.parent() {
+                Some(id) => Some(id.into_u64()),
+                None if event.is_contextual() => self.0.current.lock().unwrap().last().copied(),
+                None => None,
+            };
+            self.0.events.lock().unwrap().push((parent, fields));
+        }
+
+        fn enter(&self, span: &Id) {
+            self.0.current.lock().unwrap().push(span.into_u64());
+        }
+
+        fn exit(&self, _: &Id) {
+            self.0.current.lock().unwrap().pop();
+        }
+    }
+
+    // Emits an event within the span of the request.
+    struct SpanFs;
+
+    impl FileSystem for SpanFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn lookup(&self, ctx: &Context, _: u64, _: &std::ffi::CStr) -> std::io::Result<Entry> {
+            tracing::info!(parent: ctx.span_id(), backend = "span", "lookup");
+            Err(std::io::Error::from_raw_os_error(libc::ENOENT))
+        }
+    }
+
+    #[test]
+    fn test_request_span() {
+        let recorder = Recorder::default();
+        let server = Server::new(SpanFs);
+        let file = std::fs::File::create("/dev/null").unwrap();
+
+        tracing::subscriber::with_default(recorder.clone(), || {
+            super::super::tests::handle_request(&server, &file, Opcode::Lookup, ROOT_ID, 7, b"a\0")
+                .unwrap();
+        });
+
+        let spans = recorder.0.spans.lock().unwrap();
+        let (id, fields) = spans
+            .iter()
+            .find(|(_, (name, _))| *name == "fuse_request")
+            .map(|(id, (_, fields))| (*id, &fields.0))
+            .unwrap();
+        assert_eq!(fields["unique"], "7");
+        assert_eq!(fields["opcode"], "Lookup");
+        assert_eq!(fields["nodeid"], ROOT_ID.to_string());
+        assert!(["pid", "uid", "gid"]
+            .iter()
+            .all(|f| fields.contains_key(*f)));
+
+        // The event of the file system and the error replied to the guest belong to the span.
+        let events = recorder.0.events.lock().unwrap();
+        assert!(events
+            .iter()
+            .any(|(parent, fields)| *parent == Some(id) && fields.0.contains_key("backend")));
+        assert!(events.iter().any(|(parent, fields)| {
+            *parent == Some(id) && fields.0.get("errno") == Some(&libc::ENOENT.to_string())
+        }));
+    }
+}
@@ -3,24 +3,30 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE-BSD-3-Clause file.
 
+use std::ffi::CStr;
 use std::io::{self, IoSlice, Read, Write};
 use std::mem::size_of;
 use std::sync::Arc;
 use std::time::Duration;
 use vm_memory::ByteValued;
 
+use super::compat::{self, ProtocolFeature};
+use super::dispatch::PendingRequest;
+use super::shutdown::DESTROY_DRAIN_TIMEOUT;
 use super::{
-    MetricsHook, Server, ServerUtil, ServerVersion, SrvContext, ZcReader, ZcWriter,
-    BUFFER_HEADER_SIZE, DIRENT_PADDING, MAX_BUFFER_SIZE, MAX_REQ_PAGES, MIN_READ_BUFFER,
+    Access, ConnectionInfo, MetricsHook, OpHandler, OpRequest, Server, ServerUtil, ServerVersion,
+    SrvContext, ZcReader, ZcWriter, DEFAULT_REQ_PAGES, DIRENT_PADDING, MAX_BUFFER_SIZE,
 };
 use crate::abi::fuse_abi::*;
 #[cfg(feature = "virtiofs")]
 use crate::abi::virtio_fs::{RemovemappingIn, RemovemappingOne, SetupmappingIn};
+use crate::api::errno::{errno_of, fuse_errno};
 use crate::api::filesystem::{
-    DirEntry, Entry, FileSystem, GetxattrReply, IoctlData, ListxattrReply,
+    Context, DirEntry, Entry, FileSystem, GetxattrReply, IoctlReply, ListxattrReply, RawHandled,
 };
+use crate::api::scratch;
 use crate::transport::{pagesize, FsCacheReqHandler, Reader, Writer};
-use crate::{bytes_to_cstr, encode_io_error_kind, BitmapSlice, Error, Result};
+use crate::{encode_io_error_kind, BitmapSlice, Error, Result};
 
 impl<F: FileSystem + Sync> Server<F> {
     /// Main entrance to handle requests from the transport layer.
@@ -28,19 +34,38 @@ impl<F: FileSystem + Sync> Server<F> {
     /// It receives Fuse requests from transport layers, parses the request according to Fuse ABI,
     /// invokes filesystem drivers to server the requests, and eventually send back the result to
     /// the transport layer.
-    #[allow(unused_variables)]
+    #[allow(unused_variables, unused_mut)]
     pub fn handle_message<S: BitmapSlice>(
         &self,
         mut r: Reader<'_, S>,
         w: Writer<'_, S>,
-        vu_req: Option<&mut dyn FsCacheReqHandler>,
+        mut vu_req: Option<&mut dyn FsCacheReqHandler>,
         hook: Option<&dyn MetricsHook>,
     ) -> Result<usize> {
+        let _inflight = self.inflight.enter();
         let in_header: InHeader = r.read_obj().map_err(Error::DecodeMessage)?;
-        let mut ctx = SrvContext::<F, S>::new(in_header, r, w);
-        if ctx.in_header.len > (MAX_BUFFER_SIZE + BUFFER_HEADER_SIZE) {
+        #[cfg(feature = "tracing")]
+        let span = super::span::request_span(&in_header).entered();
+        let metrics = self.metrics_guard(hook, &in_header);
+        let _interruptible = self.interruptible(&in_header);
+        let _watched = self.watch(&in_header);
+        let mut sample = self.sample(&in_header);
+        let mut ctx = SrvContext::<F, S>::new(in_header, r, w)
+            .with_timer(sample.as_ref().map(|s| s.timer()))
+            .with_reply_recorder(metrics.as_ref().map(|m| m.recorder()))
+            .with_minor(self.vers.load().minor);
+        #[cfg(feature = "tracing")]
+        {
+            ctx.context.span = super::span::span_id(&span);
+        }
+        if self.oversized(&ctx.in_header, &ctx.r) {
             return ctx.reply_error_explicit(io::Error::from_raw_os_error(libc::ENOMEM));
         }
+        if Self::malformed(&ctx.in_header, &ctx.r) {
+            return Self::reject_malformed(ctx);
+        }
+        self.start_deadline(&mut ctx);
+        let deadline = ctx.context.deadline;
 
         trace!(
             "fuse: new req {:?}: {:?}",
@@ -48,9 +73,67 @@ impl<F: FileSystem + Sync> Server<F> {
             in_header
         );
 
-        hook.map_or((), |h| h.collect(&in_header));
+        if let Some(throttle) = self.throttle.as_ref() {
+            throttle.throttle(in_header.opcode, &ctx.r);
+        }
+
+        if let Some(res) = self
+            .handle_raw(&mut ctx)
+            .or_else(|| self.handle_custom_opcode(&mut ctx))
+        {
+            if let Some(sample) = sample.as_mut() {
+                sample.finish(&res);
+            }
+            return res;
+        }
+
+        let _permit = match self.limits.as_ref().map(|l| l.acquire(in_header.opcode)) {
+            Some(Err(e)) => return ctx.reply_error_explicit(e),
+            Some(Ok(permit)) => permit,
+            None => None,
+        };
+        if let Some(e) = self.deadline_expired(in_header.opcode, deadline) {
+            return ctx.reply_error(e);
+        }
+
+        let res = match self.dispatch.as_ref().and_then(|t| t.get(in_header.opcode)) {
+            Some(handlers) => self.dispatch_handlers(ctx, handlers, reborrow(&mut vu_req)),
+            None => self.dispatch_builtin(ctx, reborrow(&mut vu_req)),
+        };
+        self.deadline_missed(in_header.opcode, deadline);
+
+        #[cfg(feature = "virtiofs")]
+        if let Some(req) = vu_req {
+            let _ = self.unmap_reclaimed(req);
+        }
+
+        if let Some(sample) = sample.as_mut() {
+            sample.finish(&res);
+        }
+
+        res
+    }
+
+    // Fail the malformed request of `ctx` with `EINVAL`, or drop it if its opcode gets no reply.
+    fn reject_malformed<S: BitmapSlice>(mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
+        warn!("fuse: malformed request {:?}", ctx.in_header);
+        if Self::no_reply(ctx.in_header.opcode) {
+            return Err(Error::InvalidHeaderLength);
+        }
+        ctx.reply_error_explicit(io::Error::from_raw_os_error(libc::EINVAL))
+    }
 
-        let res = match in_header.opcode {
+    // Dispatch the request to the builtin handler of its opcode.
+    #[allow(unused_variables)]
+    fn dispatch_builtin<S: BitmapSlice>(
+        &self,
+        mut ctx: SrvContext<'_, F, S>,
+        vu_req: Option<&mut dyn FsCacheReqHandler>,
+    ) -> Result<usize> {
+        if Self::truncated(&ctx.in_header, &ctx.r) {
+            return Self::reject_malformed(ctx);
+        }
+        match ctx.in_header.opcode {
             x if x == Opcode::Lookup as u32 => self.lookup(ctx),
             x if x == Opcode::Forget as u32 => self.forget(ctx), // No reply.
             x if x == Opcode::Getattr as u32 => self.getattr(ctx),
@@ -93,50 +176,147 @@ impl<F: FileSystem + Sync> Server<F> {
             x if x == Opcode::Readdirplus as u32 => self.readdirplus(ctx),
             x if x == Opcode::Rename2 as u32 => self.rename2(ctx),
             x if x == Opcode::Lseek as u32 => self.lseek(ctx),
+            x if x == Opcode::CopyFileRange as u32 => self.copyfilerange(ctx),
+            x if x == Opcode::Syncfs as u32 => self.syncfs(ctx),
+            x if x == Opcode::Tmpfile as u32 => self.tmpfile(ctx),
+            x if x == Opcode::Statx as u32 => self.statx(ctx),
+            x if x == Opcode::AtomicOpen as u32 => self.atomic_open(ctx),
+            #[cfg(target_os = "macos")]
+            x if x == Opcode::Setvolname as u32 => self.setvolname(ctx),
+            #[cfg(target_os = "macos")]
+            x if x == Opcode::Getxtimes as u32 => self.getxtimes(ctx),
+            #[cfg(target_os = "macos")]
+            x if x == Opcode::Exchange as u32 => self.exchange(ctx),
             #[cfg(feature = "virtiofs")]
             x if x == Opcode::SetupMapping as u32 => self.setupmapping(ctx, vu_req),
             #[cfg(feature = "virtiofs")]
             x if x == Opcode::RemoveMapping as u32 => self.removemapping(ctx, vu_req),
             // Group reqeusts don't need reply together
             x => match x {
-                x if x == Opcode::Interrupt as u32 => {
-                    self.interrupt(ctx);
-                    Ok(0)
-                }
+                x if x == Opcode::Interrupt as u32 => self.interrupt(ctx),
                 x if x == Opcode::Destroy as u32 => {
-                    self.destroy(ctx);
+                    self.destroy(ctx, DESTROY_DRAIN_TIMEOUT);
                     Ok(0)
                 }
                 _ => ctx.reply_error(io::Error::from_raw_os_error(libc::ENOSYS)),
             },
+        }
+    }
+
+    // Dispatch the request to the outermost of the handlers of its opcode, replying with `EIO` if
+    // the handlers leave it unanswered.
+    fn dispatch_handlers<S: BitmapSlice>(
+        &self,
+        ctx: SrvContext<'_, F, S>,
+        handlers: &[OpHandler],
+        vu_req: Option<&mut dyn FsCacheReqHandler>,
+    ) -> Result<usize> {
+        let mut pending = Pending {
+            server: self,
+            ctx: Some(ctx),
+            vu_req,
         };
+        let res = OpRequest::new(&mut pending, handlers).call_default();
+        match pending.ctx.take() {
+            Some(mut ctx) => {
+                error!(
+                    "fuse: opcode handler left request {:?} unanswered",
+                    ctx.in_header
+                );
+                ctx.reply_error_explicit(io::Error::from_raw_os_error(libc::EIO))
+            }
+            None => res,
+        }
+    }
+
+    // Let the raw handler intercept the request, return `None` if it's not handled.
+    fn handle_raw<S: BitmapSlice>(&self, ctx: &mut SrvContext<'_, F, S>) -> Option<Result<usize>> {
+        let raw = self.raw.as_ref()?;
+        let read = ctx.r.bytes_read();
+        // Buffer the reply so it's sent to the transport layer as a whole message.
+        let mut reply = Vec::new();
+        let res = raw.handle_raw(&ctx.in_header, &mut ctx.r, &mut reply);
+
+        match res {
+            Ok(RawHandled::NotHandled) if ctx.r.bytes_read() == read && reply.is_empty() => None,
+            Ok(RawHandled::NotHandled) => {
+                error!(
+                    "fuse: raw handler touched unhandled request {:?}",
+                    ctx.in_header
+                );
+                Some(ctx.reply_error_explicit(io::Error::from_raw_os_error(libc::EIO)))
+            }
+            Ok(RawHandled::Handled) if reply.is_empty() => Some(Ok(0)),
+            Ok(RawHandled::Handled) => {
+                ctx.mark_replying();
+                let header = reply.get(..size_of::<OutHeader>());
+                if let Some(header) = header.and_then(OutHeader::from_slice) {
+                    ctx.record_reply(header);
+                }
+                let res = ctx
+                    .w
+                    .write_all(&reply)
+                    .and_then(|_| ctx.w.commit(None))
+                    .map(|_| reply.len())
+                    .map_err(Error::EncodeMessage);
+                Some(res)
+            }
+            Err(e) => Some(ctx.reply_error(e)),
+        }
+    }
 
-        // Pass `None` because current API handler's design does not allow us to catch
-        // the `out_header`. Hopefully, we can reach to `out_header` after some
-        // refactoring work someday.
-        hook.map_or((), |h| h.release(None));
+    // Dispatch the request to the handler registered for its opcode, return `None` if there's no
+    // handler.
+    fn handle_custom_opcode<S: BitmapSlice>(
+        &self,
+        ctx: &mut SrvContext<'_, F, S>,
+    ) -> Option<Result<usize>> {
+        let handler = self.opcodes.get(&ctx.in_header.opcode)?;
+        let mut reply = Vec::new();
+        let res = handler.handle(&ctx.in_header, &mut ctx.r, &mut reply);
+
+        if !handler.expects_reply() {
+            if let Err(e) = res {
+                debug!("fuse: custom opcode {} failed, {}", ctx.in_header.opcode, e);
+            }
+            return Some(Ok(0));
+        }
+        match res {
+            Ok(()) => Some(ctx.reply_ok(None::<u8>, Some(&reply))),
+            Err(e) => Some(ctx.reply_error(e)),
+        }
+    }
 
+    fn lookup<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
+        // Lookups are hot, so don't allocate for the name.
+        let buf = ServerUtil::get_scratch_message_body(&mut ctx.r, &ctx.in_header, 0)?;
+        let res = match ServerUtil::extract_name(&buf, self.dot_lookups) {
+            Ok(name) => self.do_lookup(&mut ctx, name),
+            Err(e) => ctx.reply_error(e),
+        };
+        scratch::recycle(buf);
         res
     }
 
-    fn lookup<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
-        let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, 0)?;
-        let name = bytes_to_cstr(buf.as_ref())?;
-        let version = self.vers.load();
-        let result = self.fs.lookup(ctx.context(), ctx.nodeid(), name);
+    fn do_lookup<S: BitmapSlice>(
+        &self,
+        ctx: &mut SrvContext<'_, F, S>,
+        name: &CStr,
+    ) -> Result<usize> {
+        let result = self.retry_transient(|| self.fs.lookup(ctx.context(), ctx.nodeid(), name));
 
         match result {
             // before ABI 7.4 inode == 0 was invalid, only ENOENT means negative dentry
             Ok(entry)
-                if version.minor < KERNEL_MINOR_VERSION_LOOKUP_NEGATIVE_ENTRY_ZERO
-                    && entry.inode == 0 =>
+                if !ProtocolFeature::NegativeEntry.supported_by(ctx.minor) && entry.inode == 0 =>
             {
                 ctx.reply_error(io::Error::from_raw_os_error(libc::ENOENT))
             }
             Ok(entry) => {
-                let out = EntryOut::from(entry);
-
-                ctx.reply_ok(Some(out), None)
+                self.audit_lookup(entry.inode);
+                #[cfg(feature = "virtiofs")]
+                self.dax_lookup(entry.inode);
+                ctx.reply_entry(entry, None)
             }
             Err(e) => ctx.reply_error(e),
         }
@@ -145,7 +325,16 @@ impl<F: FileSystem + Sync> Server<F> {
     pub(super) fn forget<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
         let ForgetIn { nlookup } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
 
-        self.fs.forget(ctx.context(), ctx.nodeid(), nlookup);
+        self.audit_forget(ctx.in_header.nodeid, nlookup);
+        #[cfg(feature = "virtiofs")]
+        self.dax_forget(ctx.in_header.nodeid, nlookup);
+        if self.forgets.is_none()
+            || self
+                .offload_forgets(vec![(ctx.in_header.nodeid, nlookup)])
+                .is_some()
+        {
+            self.fs.forget(ctx.context(), ctx.nodeid(), nlookup);
+        }
 
         // There is no reply for forget messages.
         Ok(0)
@@ -153,16 +342,59 @@ impl<F: FileSystem + Sync> Server<F> {
 
     fn getattr<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
         let GetattrIn { flags, fh, .. } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
-        let handle = if (flags & GETATTR_FH) != 0 {
-            Some(fh.into())
-        } else {
-            None
+        // Handles aren't `Copy`, convert the raw one for every attempt.
+        let handle = || {
+            if (flags & GETATTR_FH) != 0 {
+                Some(fh.into())
+            } else {
+                None
+            }
         };
-        let result = self.fs.getattr(ctx.context(), ctx.nodeid(), handle);
+        let result =
+            self.retry_transient(|| self.fs.getattr(ctx.context(), ctx.nodeid(), handle()));
 
         ctx.handle_attr_result(result)
     }
 
+    pub(super) fn statx<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
+        let StatxIn {
+            getattr_flags,
+            fh,
+            sx_flags,
+            sx_mask,
+            ..
+        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        // The request layout is unknown to kernels older than 7.39, they never send it.
+        if !ProtocolFeature::Statx.supported_by(ctx.minor) {
+            return ctx.reply_error(io::Error::from_raw_os_error(libc::ENOSYS));
+        }
+        let handle = || {
+            if (getattr_flags & GETATTR_FH) != 0 {
+                Some(fh.into())
+            } else {
+                None
+            }
+        };
+        let result = self.retry_transient(|| {
+            self.fs
+                .statx(ctx.context(), ctx.nodeid(), handle(), sx_flags, sx_mask)
+        });
+
+        match result {
+            Ok((stat, timeout)) => {
+                let out = StatxOut {
+                    attr_valid: timeout.as_secs(),
+                    attr_valid_nsec: timeout.subsec_nanos(),
+                    flags: 0,
+                    spare: [0; 2],
+                    stat,
+                };
+                ctx.reply_ok(Some(out), None)
+            }
+            Err(e) => ctx.reply_error(e),
+        }
+    }
+
     fn setattr<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
         let setattr_in: SetattrIn = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
         let handle = if setattr_in.valid & FATTR_FH != 0 {
@@ -180,10 +412,12 @@ impl<F: FileSystem + Sync> Server<F> {
     }
 
     pub(super) fn readlink<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
-        match self.fs.readlink(ctx.context(), ctx.nodeid()) {
+        match self.retry_transient(|| self.fs.readlink(ctx.context(), ctx.nodeid())) {
             Ok(linkname) => {
                 // We need to disambiguate the option type here even though it is `None`.
-                ctx.reply_ok(None::<u8>, Some(&linkname))
+                let res = ctx.reply_ok(None::<u8>, Some(&linkname));
+                scratch::recycle(linkname);
+                res
             }
             Err(e) => ctx.reply_error(e),
         }
@@ -192,10 +426,27 @@ impl<F: FileSystem + Sync> Server<F> {
     pub(super) fn symlink<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
         let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, 0)?;
         // The name and linkname are encoded one after another and separated by a nul character.
-        let (name, linkname) = ServerUtil::extract_two_cstrs(&buf)?;
+        // Targets are paths, so only the name must be a single path component.
+        let (name, linkname) =
+            match ServerUtil::extract_two_cstrs(&buf).and_then(|(name, linkname)| {
+                ServerUtil::check_name(name, false).map(|_| (name, linkname))
+            }) {
+                Ok(names) => names,
+                Err(e) => return ctx.reply_error(e),
+            };
+
+        if let Err(e) = ctx.read_extensions() {
+            return ctx.reply_error(e);
+        }
 
         match self.fs.symlink(ctx.context(), linkname, ctx.nodeid(), name) {
-            Ok(entry) => ctx.reply_ok(Some(EntryOut::from(entry)), None),
+            Ok(entry) => {
+                self.publish_inval(ctx.in_header.nodeid, name);
+                self.audit_lookup(entry.inode);
+                #[cfg(feature = "virtiofs")]
+                self.dax_lookup(entry.inode);
+                ctx.reply_entry(entry, None)
+            }
             Err(e) => ctx.reply_error(e),
         }
     }
@@ -205,13 +456,26 @@ impl<F: FileSystem + Sync> Server<F> {
             mode, rdev, umask, ..
         } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
         let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, size_of::<MknodIn>())?;
-        let name = bytes_to_cstr(buf.as_ref())?;
+        let name = match ServerUtil::extract_name(&buf, false) {
+            Ok(name) => name,
+            Err(e) => return ctx.reply_error(e),
+        };
+
+        if let Err(e) = ctx.read_extensions() {
+            return ctx.reply_error(e);
+        }
 
         match self
             .fs
             .mknod(ctx.context(), ctx.nodeid(), name, mode, rdev, umask)
         {
-            Ok(entry) => ctx.reply_ok(Some(EntryOut::from(entry)), None),
+            Ok(entry) => {
+                self.publish_inval(ctx.in_header.nodeid, name);
+                self.audit_lookup(entry.inode);
+                #[cfg(feature = "virtiofs")]
+                self.dax_lookup(entry.inode);
+                ctx.reply_entry(entry, None)
+            }
             Err(e) => ctx.reply_error(e),
         }
     }
@@ -219,33 +483,58 @@ impl<F: FileSystem + Sync> Server<F> {
     pub(super) fn mkdir<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
         let MkdirIn { mode, umask } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
         let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, size_of::<MkdirIn>())?;
-        let name = bytes_to_cstr(buf.as_ref())?;
+        let name = match ServerUtil::extract_name(&buf, false) {
+            Ok(name) => name,
+            Err(e) => return ctx.reply_error(e),
+        };
+
+        if let Err(e) = ctx.read_extensions() {
+            return ctx.reply_error(e);
+        }
 
         match self
             .fs
             .mkdir(ctx.context(), ctx.nodeid(), name, mode, umask)
         {
-            Ok(entry) => ctx.reply_ok(Some(EntryOut::from(entry)), None),
+            Ok(entry) => {
+                self.publish_inval(ctx.in_header.nodeid, name);
+                self.audit_lookup(entry.inode);
+                #[cfg(feature = "virtiofs")]
+                self.dax_lookup(entry.inode);
+                ctx.reply_entry(entry, None)
+            }
             Err(e) => ctx.reply_error(e),
         }
     }
 
     pub(super) fn unlink<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
         let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, 0)?;
-        let name = bytes_to_cstr(buf.as_ref())?;
+        let name = match ServerUtil::extract_name(&buf, false) {
+            Ok(name) => name,
+            Err(e) => return ctx.reply_error(e),
+        };
 
         match self.fs.unlink(ctx.context(), ctx.nodeid(), name) {
-            Ok(()) => ctx.reply_ok(None::<u8>, None),
+            Ok(()) => {
+                self.publish_inval(ctx.in_header.nodeid, name);
+                ctx.reply_ok(None::<u8>, None)
+            }
             Err(e) => ctx.reply_error(e),
         }
     }
 
     pub(super) fn rmdir<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
         let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, 0)?;
-        let name = bytes_to_cstr(buf.as_ref())?;
+        let name = match ServerUtil::extract_name(&buf, false) {
+            Ok(name) => name,
+            Err(e) => return ctx.reply_error(e),
+        };
 
         match self.fs.rmdir(ctx.context(), ctx.nodeid(), name) {
-            Ok(()) => ctx.reply_ok(None::<u8>, None),
+            Ok(()) => {
+                self.publish_inval(ctx.in_header.nodeid, name);
+                ctx.reply_ok(None::<u8>, None)
+            }
             Err(e) => ctx.reply_error(e),
         }
     }
@@ -258,7 +547,14 @@ impl<F: FileSystem + Sync> Server<F> {
         flags: u32,
     ) -> Result<usize> {
         let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, msg_size)?;
-        let (oldname, newname) = ServerUtil::extract_two_cstrs(&buf)?;
+        let (oldname, newname) = match ServerUtil::extract_two_cstrs(&buf).and_then(|names| {
+            ServerUtil::check_name(names.0, false)?;
+            ServerUtil::check_name(names.1, false)?;
+            Ok(names)
+        }) {
+            Ok(names) => names,
+            Err(e) => return ctx.reply_error(e),
+        };
 
         match self.fs.rename(
             ctx.context(),
@@ -268,7 +564,11 @@ impl<F: FileSystem + Sync> Server<F> {
             newname,
             flags,
         ) {
-            Ok(()) => ctx.reply_ok(None::<u8>, None),
+            Ok(()) => {
+                self.publish_inval(ctx.in_header.nodeid, oldname);
+                self.publish_inval(newdir, newname);
+                ctx.reply_ok(None::<u8>, None)
+            }
             Err(e) => ctx.reply_error(e),
         }
     }
@@ -295,13 +595,22 @@ impl<F: FileSystem + Sync> Server<F> {
     pub(super) fn link<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
         let LinkIn { oldnodeid } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
         let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, size_of::<LinkIn>())?;
-        let name = bytes_to_cstr(buf.as_ref())?;
+        let name = match ServerUtil::extract_name(&buf, false) {
+            Ok(name) => name,
+            Err(e) => return ctx.reply_error(e),
+        };
 
         match self
             .fs
             .link(ctx.context(), oldnodeid.into(), ctx.nodeid(), name)
         {
-            Ok(entry) => ctx.reply_ok(Some(EntryOut::from(entry)), None),
+            Ok(entry) => {
+                self.publish_inval(ctx.in_header.nodeid, name);
+                self.audit_lookup(entry.inode);
+                #[cfg(feature = "virtiofs")]
+                self.dax_lookup(entry.inode);
+                ctx.reply_entry(entry, None)
+            }
             Err(e) => ctx.reply_error(e),
         }
     }
@@ -311,9 +620,13 @@ impl<F: FileSystem + Sync> Server<F> {
 
         match self.fs.open(ctx.context(), ctx.nodeid(), flags, fuse_flags) {
             Ok((handle, opts)) => {
+                let fh: Option<u64> = handle.map(Into::into);
+                self.access_open(ctx.in_header.nodeid, fh, flags);
+                #[cfg(feature = "virtiofs")]
+                self.dax_open(ctx.in_header.nodeid);
                 let out = OpenOut {
-                    fh: handle.map(Into::into).unwrap_or(0),
-                    open_flags: opts.bits(),
+                    fh: fh.unwrap_or(0),
+                    open_flags: compat::open_flags(opts, ctx.minor),
                     ..Default::default()
                 };
 
@@ -337,6 +650,9 @@ impl<F: FileSystem + Sync> Server<F> {
         if size > MAX_BUFFER_SIZE {
             return ctx.reply_error_explicit(io::Error::from_raw_os_error(libc::ENOMEM));
         }
+        if let Err(e) = self.access_check(ctx.in_header.nodeid, fh, Access::Read) {
+            return ctx.reply_error_explicit(e);
+        }
 
         let owner = if read_flags & READ_LOCKOWNER != 0 {
             Some(lock_owner)
@@ -349,7 +665,7 @@ impl<F: FileSystem + Sync> Server<F> {
             Ok(v) => v,
             Err(_e) => return Err(Error::InvalidHeaderLength),
         };
-        let mut data_writer = ZcWriter(w2);
+        let mut data_writer = ZcWriter(w2, 0);
 
         match self.fs.read(
             ctx.context(),
@@ -362,6 +678,10 @@ impl<F: FileSystem + Sync> Server<F> {
             flags,
         ) {
             Ok(count) => {
+                if let Err(e) = data_writer.commit_offset_writes() {
+                    return ctx.reply_error_explicit(e);
+                }
+                ctx.mark_replying();
                 // Don't use `reply_ok` because we need to set a custom size length for the
                 // header.
                 let out = OutHeader {
@@ -369,6 +689,7 @@ impl<F: FileSystem + Sync> Server<F> {
                     error: 0,
                     unique: ctx.unique(),
                 };
+                ctx.record_reply(&out);
 
                 ctx.w
                     .write_all(out.as_slice())
@@ -396,6 +717,9 @@ impl<F: FileSystem + Sync> Server<F> {
         if size > MAX_BUFFER_SIZE {
             return ctx.reply_error_explicit(io::Error::from_raw_os_error(libc::ENOMEM));
         }
+        if let Err(e) = self.access_check(ctx.in_header.nodeid, fh, Access::Write) {
+            return ctx.reply_error_explicit(e);
+        }
 
         let owner = if fuse_flags & WRITE_LOCKOWNER != 0 {
             Some(lock_owner)
@@ -403,6 +727,10 @@ impl<F: FileSystem + Sync> Server<F> {
             None
         };
 
+        if let Some(acct) = self.write_accounting.as_ref() {
+            acct.add_guest(size as u64);
+        }
+
         let delayed_write = fuse_flags & WRITE_CACHE != 0;
 
         let mut data_reader = ZcReader(ctx.take_reader());
@@ -433,19 +761,38 @@ impl<F: FileSystem + Sync> Server<F> {
 
     pub(super) fn statfs<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
         match self.fs.statfs(ctx.context(), ctx.nodeid()) {
-            Ok(st) => ctx.reply_ok(Some(Kstatfs::from(st)), None),
+            Ok(st) => {
+                let out = Kstatfs::from(st);
+                let size = compat::statfs_out_size(ctx.minor);
+                ctx.reply_ok(None::<u8>, Some(&out.as_slice()[..size]))
+            }
             Err(e) => ctx.reply_error(e),
         }
     }
 
     pub(super) fn release<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
+        let arg = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        self.do_release(ctx, arg)
+    }
+
+    pub(super) fn do_release<S: BitmapSlice>(
+        &self,
+        mut ctx: SrvContext<'_, F, S>,
+        arg: ReleaseIn,
+    ) -> Result<usize> {
         let ReleaseIn {
             fh,
             flags,
             release_flags,
             lock_owner,
-        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        } = arg;
 
+        self.access_release(ctx.in_header.nodeid, fh);
+        #[cfg(feature = "virtiofs")]
+        self.dax_release(ctx.in_header.nodeid);
+        if let Some(notifier) = self.poll.as_ref() {
+            notifier.release(fh);
+        }
         let flush = release_flags & RELEASE_FLUSH != 0;
         let flock_release = release_flags & RELEASE_FLOCK_UNLOCK != 0;
         let lock_owner = if flush || flock_release {
@@ -483,30 +830,108 @@ impl<F: FileSystem + Sync> Server<F> {
         }
     }
 
+    pub(super) fn syncfs<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
+        let _: SyncfsIn = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+
+        match self.fs.syncfs(ctx.context(), ctx.nodeid()) {
+            Ok(()) => ctx.reply_ok(None::<u8>, None),
+            Err(e) => ctx.reply_error(e),
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn setvolname<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
+        let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, 0)?;
+        // Volume names aren't path components, so they aren't checked like file names.
+        let name = match ServerUtil::extract_cstr(&buf) {
+            Ok(name) => name,
+            Err(e) => return ctx.reply_error(e),
+        };
+
+        match self.fs.setvolname(ctx.context(), name) {
+            Ok(()) => ctx.reply_ok(None::<u8>, None),
+            Err(e) => ctx.reply_error(e),
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn getxtimes<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
+        match self.fs.getxtimes(ctx.context(), ctx.nodeid()) {
+            Ok(out) => ctx.reply_ok(Some(out), None),
+            Err(e) => ctx.reply_error(e),
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn exchange<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
+        let ExchangeIn {
+            olddir,
+            newdir,
+            options,
+        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        let buf =
+            ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, size_of::<ExchangeIn>())?;
+        let (oldname, newname) = match ServerUtil::extract_two_cstrs(&buf).and_then(|names| {
+            ServerUtil::check_name(names.0, false)?;
+            ServerUtil::check_name(names.1, false)?;
+            Ok(names)
+        }) {
+            Ok(names) => names,
+            Err(e) => return ctx.reply_error(e),
+        };
+
+        match self.fs.exchange(
+            ctx.context(),
+            olddir.into(),
+            oldname,
+            newdir.into(),
+            newname,
+            options,
+        ) {
+            Ok(()) => {
+                self.publish_inval(olddir, oldname);
+                self.publish_inval(newdir, newname);
+                ctx.reply_ok(None::<u8>, None)
+            }
+            Err(e) => ctx.reply_error(e),
+        }
+    }
+
     pub(super) fn setxattr<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
-        let SetxattrIn { size, flags } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        let SetxattrIn { size, flags, .. } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
         let buf =
             ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, size_of::<SetxattrIn>())?;
 
         // The name and value and encoded one after another and separated by a '\0' character.
-        let split_pos = buf
-            .iter()
-            .position(|c| *c == b'\0')
-            .map(|p| p + 1)
-            .ok_or(Error::MissingParameter)?;
+        let split_pos = match buf.iter().position(|c| *c == b'\0') {
+            Some(pos) => pos + 1,
+            None => {
+                warn!("fuse: setxattr: {}", Error::MissingParameter);
+                return ctx.reply_error(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+        };
         let (name, value) = buf.split_at(split_pos);
 
         if size != value.len() as u32 {
-            return Err(Error::InvalidXattrSize((size, value.len())));
+            warn!("fuse: {}", Error::InvalidXattrSize((size, value.len())));
+            return ctx.reply_error(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let name = match ServerUtil::extract_cstr(name) {
+            Ok(name) => name,
+            Err(e) => return ctx.reply_error(e),
+        };
+        let limits = &self.xattr_limits;
+        if let Err(e) = limits
+            .check_name(name)
+            .and_then(|_| limits.check_value(value.len()))
+        {
+            return ctx.reply_error(e);
         }
 
-        match self.fs.setxattr(
-            ctx.context(),
-            ctx.nodeid(),
-            bytes_to_cstr(name)?,
-            value,
-            flags,
-        ) {
+        match self
+            .fs
+            .setxattr(ctx.context(), ctx.nodeid(), name, value, flags)
+        {
             Ok(()) => ctx.reply_ok(None::<u8>, None),
             Err(e) => ctx.reply_error(e),
         }
@@ -514,17 +939,44 @@ impl<F: FileSystem + Sync> Server<F> {
 
     pub(super) fn getxattr<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
         let GetxattrIn { size, .. } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
-        if size > MAX_BUFFER_SIZE {
-            return ctx.reply_error_explicit(io::Error::from_raw_os_error(libc::ENOMEM));
-        }
+        // Replies are capped at the negotiated `max_write`, values beyond it are out of range.
+        let max_reply = self.negotiated_max_write();
+        let size = size.min(max_reply);
 
         let buf =
             ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, size_of::<GetxattrIn>())?;
-        let name = bytes_to_cstr(buf.as_ref())?;
+        let name = match ServerUtil::extract_cstr(&buf) {
+            Ok(name) => name,
+            Err(e) => return ctx.reply_error(e),
+        };
+        let limits = &self.xattr_limits;
+        if let Err(e) = limits.check_name(name) {
+            return ctx.reply_error(e);
+        }
 
-        match self.fs.getxattr(ctx.context(), ctx.nodeid(), name, size) {
-            Ok(GetxattrReply::Value(val)) => ctx.reply_ok(None::<u8>, Some(&val)),
+        let res = self.retry_transient(|| {
+            self.fs.getxattr(
+                ctx.context(),
+                ctx.nodeid(),
+                name,
+                limits.value_buf_size(size),
+            )
+        });
+        match limits.value_result(size, res) {
+            Ok(GetxattrReply::Value(val)) => {
+                let res = ctx.reply_ok(None::<u8>, Some(&val));
+                scratch::recycle(val);
+                res
+            }
             Ok(GetxattrReply::Count(count)) => {
+                // Values which can't be replied are too big, rather than out of range of any
+                // buffer the guest may retry with.
+                if count > max_reply {
+                    return ctx.reply_error(io::Error::from_raw_os_error(libc::E2BIG));
+                }
+                if let Err(e) = limits.check_value(count as usize) {
+                    return ctx.reply_error(e);
+                }
                 let out = GetxattrOut {
                     size: count,
                     ..Default::default()
@@ -538,14 +990,29 @@ impl<F: FileSystem + Sync> Server<F> {
 
     pub(super) fn listxattr<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
         let GetxattrIn { size, .. } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        // Replies are capped at the negotiated `max_write`, lists beyond it are out of range.
+        let max_reply = self.negotiated_max_write();
+        let size = size.min(max_reply);
 
-        if size > MAX_BUFFER_SIZE {
-            return ctx.reply_error_explicit(io::Error::from_raw_os_error(libc::ENOMEM));
-        }
-
-        match self.fs.listxattr(ctx.context(), ctx.nodeid(), size) {
-            Ok(ListxattrReply::Names(val)) => ctx.reply_ok(None::<u8>, Some(&val)),
+        let limits = &self.xattr_limits;
+        let res = self
+            .fs
+            .listxattr(ctx.context(), ctx.nodeid(), limits.list_buf_size(size));
+        match limits.list_result(size, res) {
+            Ok(ListxattrReply::Names(val)) => {
+                let res = ctx.reply_ok(None::<u8>, Some(&val));
+                scratch::recycle(val);
+                res
+            }
             Ok(ListxattrReply::Count(count)) => {
+                // Lists which can't be replied are too big, rather than out of range of any
+                // buffer the guest may retry with.
+                if count > max_reply {
+                    return ctx.reply_error(io::Error::from_raw_os_error(libc::E2BIG));
+                }
+                if let Err(e) = limits.check_list(count as usize) {
+                    return ctx.reply_error(e);
+                }
                 let out = GetxattrOut {
                     size: count,
                     ..Default::default()
@@ -562,7 +1029,13 @@ impl<F: FileSystem + Sync> Server<F> {
         mut ctx: SrvContext<'_, F, S>,
     ) -> Result<usize> {
         let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, 0)?;
-        let name = bytes_to_cstr(&buf)?;
+        let name = match ServerUtil::extract_cstr(&buf) {
+            Ok(name) => name,
+            Err(e) => return ctx.reply_error(e),
+        };
+        if let Err(e) = self.xattr_limits.check_name(name) {
+            return ctx.reply_error(e);
+        }
 
         match self.fs.removexattr(ctx.context(), ctx.nodeid(), name) {
             Ok(()) => ctx.reply_ok(None::<u8>, None),
@@ -571,7 +1044,16 @@ impl<F: FileSystem + Sync> Server<F> {
     }
 
     pub(super) fn flush<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
-        let FlushIn { fh, lock_owner, .. } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        let arg = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        self.do_flush(ctx, arg)
+    }
+
+    pub(super) fn do_flush<S: BitmapSlice>(
+        &self,
+        mut ctx: SrvContext<'_, F, S>,
+        arg: FlushIn,
+    ) -> Result<usize> {
+        let FlushIn { fh, lock_owner, .. } = arg;
 
         match self
             .fs
@@ -582,6 +1064,17 @@ impl<F: FileSystem + Sync> Server<F> {
         }
     }
 
+    // Get the timestamp granularity of the file system to reply to the kernel, in nanoseconds.
+    fn fs_time_gran(&self) -> u32 {
+        let gran = self.fs.time_gran();
+        if (0..10).any(|exp| 10u32.pow(exp) == gran) {
+            gran
+        } else {
+            warn!("fuse: invalid timestamp granularity {}, use 1ns", gran);
+            1
+        }
+    }
+
     pub(super) fn init<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
         let InitIn {
             major,
@@ -595,6 +1088,14 @@ impl<F: FileSystem + Sync> Server<F> {
             return ctx.reply_error_explicit(io::Error::from_raw_os_error(libc::EPROTO));
         }
 
+        if major == KERNEL_VERSION && minor < MIN_KERNEL_MINOR_VERSION {
+            error!(
+                "Unsupported fuse protocol version: {}.{}, the oldest supported is {}.{}",
+                major, minor, KERNEL_VERSION, MIN_KERNEL_MINOR_VERSION
+            );
+            return ctx.reply_error_explicit(io::Error::from_raw_os_error(libc::EPROTO));
+        }
+
         if major > KERNEL_VERSION {
             // Wait for the kernel to reply back with a 7.X version.
             let out = InitOut {
@@ -606,11 +1107,31 @@ impl<F: FileSystem + Sync> Server<F> {
             return ctx.reply_ok(Some(out), None);
         }
 
+        // Before 7.36, the bit of `INIT_EXT` means `PERFILE_DAX`.
+        let init_ext = ProtocolFeature::InitExt.supported_by(minor) && flags & INIT_EXT != 0;
+        let mut flags = flags as u64;
+        if init_ext {
+            let InitIn2 { flags2, .. } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+            flags = (flags & !(INIT_EXT as u64)) | (flags2 as u64) << 32;
+        }
         let capable = FsOptions::from_bits_truncate(flags);
 
         match self.fs.init(capable) {
             Ok(want) => {
-                let enabled = capable & want;
+                let mut want = capable & want;
+                // Writes larger than the default max pages need `FUSE_MAX_PAGES`, whatever the
+                // file system wants, as the server owns the sizing of requests.
+                if self.max_pages() > DEFAULT_REQ_PAGES {
+                    want |= capable & FsOptions::MAX_PAGES;
+                }
+                let mut enabled = compat::init_flags(want, minor);
+                // Letting the kernel pick readdirplus is meaningless without readdirplus.
+                if !enabled.contains(FsOptions::DO_READDIRPLUS) {
+                    enabled.remove(FsOptions::READDIRPLUS_AUTO);
+                }
+                if let Some(access) = self.access.as_ref() {
+                    access.set_writeback(enabled.contains(FsOptions::WRITEBACK_CACHE));
+                }
                 info!(
                     "FUSE INIT major {} minor {}\n in_opts: {:?}\nout_opts: {:?}",
                     major, minor, capable, enabled
@@ -626,42 +1147,37 @@ impl<F: FileSystem + Sync> Server<F> {
                     major: KERNEL_VERSION,
                     minor: KERNEL_MINOR_VERSION,
                     max_readahead: readahead,
-                    flags: enabled.bits(),
+                    flags: enabled.bits() as u32,
                     max_background: ::std::u16::MAX,
                     congestion_threshold: (::std::u16::MAX / 4) * 3,
-                    max_write: MIN_READ_BUFFER - BUFFER_HEADER_SIZE,
-                    time_gran: 1, // nanoseconds
+                    max_write: self
+                        .max_write
+                        .min(DEFAULT_REQ_PAGES as u32 * pagesize() as u32),
+                    time_gran: self.fs_time_gran(),
                     ..Default::default()
                 };
+                if init_ext {
+                    out.flags |= INIT_EXT;
+                    out.flags2 = (enabled.bits() >> 32) as u32;
+                }
                 if enabled.contains(FsOptions::MAX_PAGES) {
-                    out.max_pages = MAX_REQ_PAGES;
-                    out.max_write = MAX_REQ_PAGES as u32 * pagesize() as u32; // 1MB
+                    out.max_pages = self.max_pages();
+                    out.max_write = self.max_write;
                 }
+                out.max_write = compat::max_write(out.max_write, minor);
                 let vers = ServerVersion { major, minor };
                 self.vers.store(Arc::new(vers));
-                if minor < KERNEL_MINOR_VERSION_INIT_OUT_SIZE {
-                    ctx.reply_ok(
-                        Some(
-                            *<[u8; FUSE_COMPAT_INIT_OUT_SIZE]>::from_slice(
-                                out.as_slice().split_at(FUSE_COMPAT_INIT_OUT_SIZE).0,
-                            )
-                            .unwrap(),
-                        ),
-                        None,
-                    )
-                } else if minor < KERNEL_MINOR_VERSION_INIT_22_OUT_SIZE {
-                    ctx.reply_ok(
-                        Some(
-                            *<[u8; FUSE_COMPAT_22_INIT_OUT_SIZE]>::from_slice(
-                                out.as_slice().split_at(FUSE_COMPAT_22_INIT_OUT_SIZE).0,
-                            )
-                            .unwrap(),
-                        ),
-                        None,
-                    )
-                } else {
-                    ctx.reply_ok(Some(out), None)
-                }
+                self.conn.store(Some(Arc::new(ConnectionInfo {
+                    major,
+                    minor,
+                    flags: enabled,
+                    max_readahead: out.max_readahead,
+                    max_write: out.max_write,
+                    max_pages: out.max_pages,
+                    time_gran: out.time_gran,
+                })));
+                let size = compat::init_out_size(minor);
+                ctx.reply_ok(None::<u8>, Some(&out.as_slice()[..size]))
             }
             Err(e) => ctx.reply_error(e),
         }
@@ -674,7 +1190,7 @@ impl<F: FileSystem + Sync> Server<F> {
             Ok((handle, opts)) => {
                 let out = OpenOut {
                     fh: handle.map(Into::into).unwrap_or(0),
-                    open_flags: opts.bits(),
+                    open_flags: compat::open_flags(opts, ctx.minor),
                     ..Default::default()
                 };
 
@@ -708,29 +1224,52 @@ impl<F: FileSystem + Sync> Server<F> {
             Err(_e) => return Err(Error::InvalidHeaderLength),
         };
 
-        let res = if plus {
-            self.fs.readdirplus(
-                ctx.context(),
-                ctx.nodeid(),
-                fh.into(),
-                size,
-                offset,
-                &mut |d, e| add_dirent(&mut cursor, size, d, Some(e)),
-            )
-        } else {
-            self.fs.readdir(
-                ctx.context(),
-                ctx.nodeid(),
-                fh.into(),
-                size,
-                offset,
-                &mut |d| add_dirent(&mut cursor, size, d, None),
-            )
+        // Entries already added can't be taken back, only retry attempts that added none.
+        let mut attempt = 0;
+        let res = loop {
+            let res = if plus {
+                self.fs.readdirplus(
+                    ctx.context(),
+                    ctx.nodeid(),
+                    fh.into(),
+                    size,
+                    offset,
+                    &mut |d, e| {
+                        let inode = e.inode;
+                        let res = add_dirent(&mut cursor, size, d, Some(e), ctx.minor);
+                        // The client only takes a lookup reference if the entry gets sent.
+                        if let Ok(len) = res {
+                            if len > 0 {
+                                self.audit_lookup(inode);
+                                #[cfg(feature = "virtiofs")]
+                                self.dax_lookup(inode);
+                            }
+                        }
+                        res
+                    },
+                )
+            } else {
+                self.fs.readdir(
+                    ctx.context(),
+                    ctx.nodeid(),
+                    fh.into(),
+                    size,
+                    offset,
+                    &mut |d| add_dirent(&mut cursor, size, d, None, ctx.minor),
+                )
+            };
+            match res {
+                Err(e) if cursor.bytes_written() == 0 && self.should_retry(&e, attempt) => {
+                    attempt += 1
+                }
+                res => break res,
+            }
         };
 
         if let Err(e) = res {
             ctx.reply_error_explicit(e)
         } else {
+            ctx.mark_replying();
             // Don't use `reply_ok` because we need to set a custom size length for the
             // header.
             let out = OutHeader {
@@ -738,6 +1277,7 @@ impl<F: FileSystem + Sync> Server<F> {
                 error: 0,
                 unique: ctx.unique(),
             };
+            ctx.record_reply(&out);
 
             ctx.w
                 .write_all(out.as_slice())
@@ -835,7 +1375,7 @@ impl<F: FileSystem + Sync> Server<F> {
             lk_flags,
             ..
         } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
-        match self.fs.setlk(
+        match self.fs.setlkw(
             ctx.context(),
             ctx.nodeid(),
             fh.into(),
@@ -860,33 +1400,123 @@ impl<F: FileSystem + Sync> Server<F> {
     fn create<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
         let args: CreateIn = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
         let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, size_of::<CreateIn>())?;
-        let name = bytes_to_cstr(&buf)?;
+        let name = match ServerUtil::extract_name(&buf, false) {
+            Ok(name) => name,
+            Err(e) => return ctx.reply_error(e),
+        };
+
+        if let Err(e) = ctx.read_extensions() {
+            return ctx.reply_error(e);
+        }
 
         match self.fs.create(ctx.context(), ctx.nodeid(), name, args) {
             Ok((entry, handle, opts)) => {
-                let entry_out = EntryOut {
-                    nodeid: entry.inode,
-                    generation: entry.generation,
-                    entry_valid: entry.entry_timeout.as_secs(),
-                    attr_valid: entry.attr_timeout.as_secs(),
-                    entry_valid_nsec: entry.entry_timeout.subsec_nanos(),
-                    attr_valid_nsec: entry.attr_timeout.subsec_nanos(),
-                    attr: entry.attr.into(),
+                let fh: Option<u64> = handle.map(Into::into);
+                self.access_open(entry.inode, fh, args.flags);
+                #[cfg(feature = "virtiofs")]
+                self.dax_open(entry.inode);
+                self.publish_inval(ctx.in_header.nodeid, name);
+                self.audit_lookup(entry.inode);
+                #[cfg(feature = "virtiofs")]
+                self.dax_lookup(entry.inode);
+                let open_out = OpenOut {
+                    fh: fh.unwrap_or(0),
+                    open_flags: compat::open_flags(opts, ctx.minor),
+                    ..Default::default()
                 };
+
+                ctx.reply_entry(entry, Some(open_out))
+            }
+            Err(e) => ctx.reply_error(e),
+        }
+    }
+
+    pub(super) fn tmpfile<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
+        let args: CreateIn = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        // Skip the name of the dentry, which isn't the name of a file.
+        ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, size_of::<CreateIn>())?;
+
+        if let Err(e) = ctx.read_extensions() {
+            return ctx.reply_error(e);
+        }
+
+        match self.fs.tmpfile(ctx.context(), ctx.nodeid(), args) {
+            Ok((entry, handle, opts)) => {
+                let fh: Option<u64> = handle.map(Into::into);
+                self.access_open(entry.inode, fh, args.flags);
+                #[cfg(feature = "virtiofs")]
+                self.dax_open(entry.inode);
+                self.audit_lookup(entry.inode);
+                #[cfg(feature = "virtiofs")]
+                self.dax_lookup(entry.inode);
                 let open_out = OpenOut {
-                    fh: handle.map(Into::into).unwrap_or(0),
-                    open_flags: opts.bits(),
+                    fh: fh.unwrap_or(0),
+                    open_flags: compat::open_flags(opts, ctx.minor),
+                    ..Default::default()
+                };
+
+                ctx.reply_entry(entry, Some(open_out))
+            }
+            Err(e) => ctx.reply_error(e),
+        }
+    }
+
+    pub(super) fn atomic_open<S: BitmapSlice>(
+        &self,
+        mut ctx: SrvContext<'_, F, S>,
+    ) -> Result<usize> {
+        let args: CreateIn = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, size_of::<CreateIn>())?;
+        let name = match ServerUtil::extract_name(&buf, false) {
+            Ok(name) => name,
+            Err(e) => return ctx.reply_error(e),
+        };
+
+        if let Err(e) = ctx.read_extensions() {
+            return ctx.reply_error(e);
+        }
+
+        match self.fs.atomic_open(ctx.context(), ctx.nodeid(), name, args) {
+            Ok((entry, handle, opts)) => {
+                let fh: Option<u64> = handle.map(Into::into);
+                self.access_open(entry.inode, fh, args.flags);
+                #[cfg(feature = "virtiofs")]
+                self.dax_open(entry.inode);
+                if args.flags & libc::O_CREAT as u32 != 0 {
+                    self.publish_inval(ctx.in_header.nodeid, name);
+                }
+                self.audit_lookup(entry.inode);
+                #[cfg(feature = "virtiofs")]
+                self.dax_lookup(entry.inode);
+                let open_out = OpenOut {
+                    fh: fh.unwrap_or(0),
+                    open_flags: compat::open_flags(opts, ctx.minor),
                     ..Default::default()
                 };
 
-                // Kind of a hack to write both structs.
-                ctx.reply_ok(Some(entry_out), Some(open_out.as_slice()))
+                ctx.reply_entry(entry, Some(open_out))
             }
             Err(e) => ctx.reply_error(e),
         }
     }
 
-    pub(super) fn interrupt<S: BitmapSlice>(&self, _ctx: SrvContext<'_, F, S>) {}
+    pub(super) fn interrupt<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
+        // Interrupts are ignored unless enabled, the interrupted request completes as usual.
+        let interrupts = match self.interrupts.as_ref() {
+            Some(interrupts) => interrupts,
+            None => return Ok(0),
+        };
+        let InterruptIn { unique } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+
+        if interrupts.contains(unique) {
+            self.fs.interrupt(ctx.context(), unique);
+            Ok(0)
+        } else {
+            // The request hasn't been received yet or has already completed, the kernel queues
+            // the interrupt again in the former case.
+            ctx.reply_error(io::Error::from_raw_os_error(libc::EAGAIN))
+        }
+    }
 
     pub(super) fn bmap<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
         let BmapIn {
@@ -899,8 +1529,22 @@ impl<F: FileSystem + Sync> Server<F> {
         }
     }
 
-    pub(super) fn destroy<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) {
+    // Requests racing with `FUSE_DESTROY` are waited for up to `drain_timeout`.
+    pub(super) fn destroy<S: BitmapSlice>(
+        &self,
+        mut ctx: SrvContext<'_, F, S>,
+        drain_timeout: Duration,
+    ) {
+        self.flush_forgets();
+        #[cfg(feature = "virtiofs")]
+        if let Some(dax) = self.dax.as_ref() {
+            dax.reclaim_all();
+        }
+        self.forget_all_on_destroy(drain_timeout);
         self.fs.destroy();
+        if let Some(audit) = self.audit.as_ref() {
+            audit.destroy();
+        }
         if let Err(e) = ctx.reply_ok(None::<u8>, None) {
             warn!("fuse channel reply destroy failed {:?}", e);
         }
@@ -911,25 +1555,20 @@ impl<F: FileSystem + Sync> Server<F> {
             fh,
             flags,
             cmd,
-            arg: _,
+            arg,
             in_size,
             out_size,
         } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
         // TODO: check fs capability of FUSE_CAP_IOCTL_DIR and return ENOTTY if unsupported.
-        let mut buf = IoctlData {
-            ..Default::default()
-        };
         let in_size = in_size as usize;
         // Make sure we have enough bytes to read the ioctl in buffer.
         if in_size > ctx.r.available_bytes() {
             return ctx.reply_error(io::Error::from_raw_os_error(libc::ENOTTY));
         }
         let mut data = vec![0u8; in_size];
+        let mut size = 0;
         if in_size > 0 {
-            let size = ctx.r.read(&mut data).map_err(Error::DecodeMessage)?;
-            if size > 0 {
-                buf.data = Some(&data[..size]);
-            }
+            size = ctx.r.read(&mut data).map_err(Error::DecodeMessage)?;
         }
         match self.fs.ioctl(
             ctx.context(),
@@ -937,20 +1576,88 @@ impl<F: FileSystem + Sync> Server<F> {
             fh.into(),
             flags,
             cmd,
-            buf,
+            arg,
+            &data[..size],
             out_size,
         ) {
-            Ok(res) => ctx.reply_ok(
-                Some(IoctlOut {
-                    result: res.result,
-                    ..Default::default()
-                }),
-                res.data,
-            ),
+            Ok(IoctlReply::Done { result, data }) => {
+                if data.len() > out_size as usize {
+                    return ctx.reply_error_explicit(fuse_errno(
+                        libc::EIO,
+                        "ioctl output larger than requested",
+                    ));
+                }
+                ctx.reply_ok(
+                    Some(IoctlOut {
+                        result,
+                        ..Default::default()
+                    }),
+                    Some(&data),
+                )
+            }
+            Ok(IoctlReply::Retry { input, output }) => {
+                match self.ioctl_retry(flags, &input, &output) {
+                    Ok(out) => {
+                        let iovs: Vec<u8> = input
+                            .iter()
+                            .chain(output.iter())
+                            .flat_map(|iov| iov.as_slice().to_vec())
+                            .collect();
+                        ctx.reply_ok(Some(out), Some(&iovs))
+                    }
+                    Err(e) => ctx.reply_error_explicit(e),
+                }
+            }
             Err(e) => ctx.reply_error(e),
         }
     }
 
+    // Check a retry of an ioctl against the limits of the kernel, which would fail the ioctl
+    // anyway, and build the reply header.
+    fn ioctl_retry(
+        &self,
+        flags: u32,
+        input: &[IoctlIovec],
+        output: &[IoctlIovec],
+    ) -> io::Result<IoctlOut> {
+        let flags = IoctlFlags::from_bits_truncate(flags);
+        if !flags.contains(IoctlFlags::IOCTL_UNRESTRICTED) {
+            return Err(fuse_errno(libc::EIO, "retry of restricted ioctl"));
+        }
+        if input.len() + output.len() > IoctlFlags::IOCTL_MAX_IOV.bits() as usize {
+            return Err(fuse_errno(libc::ENOMEM, "too many ioctl retry iovecs"));
+        }
+        // 32-bit callers can only have 32-bit addresses and lengths.
+        if flags.contains(IoctlFlags::IOCTL_COMPAT)
+            && input
+                .iter()
+                .chain(output.iter())
+                .any(|iov| iov.base > u32::MAX as u64 || iov.len > u32::MAX as u64)
+        {
+            return Err(fuse_errno(libc::EIO, "ioctl retry iovec beyond 32 bits"));
+        }
+        // Both directions are limited to the pages of a request.
+        let max_pages = match self.conn.load().as_deref() {
+            Some(conn) if conn.max_pages != 0 => conn.max_pages,
+            _ => DEFAULT_REQ_PAGES,
+        };
+        let max = max_pages as u64 * pagesize() as u64;
+        for iovs in [input, output] {
+            let len = iovs
+                .iter()
+                .try_fold(0u64, |len, iov| len.checked_add(iov.len));
+            if len.is_none_or(|len| len > max) {
+                return Err(fuse_errno(libc::ENOMEM, "ioctl retry iovecs too large"));
+            }
+        }
+        Ok(IoctlOut {
+            result: 0,
+            flags: IoctlFlags::IOCTL_RETRY.bits(),
+            in_iovs: input.len() as u32,
+            out_iovs: output.len() as u32,
+        })
+    }
+
     pub(super) fn poll<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, S>) -> Result<usize> {
         let PollIn {
             fh,
@@ -959,14 +1666,16 @@ impl<F: FileSystem + Sync> Server<F> {
             events,
         } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
 
-        match self.fs.poll(
-            ctx.context(),
-            ctx.nodeid(),
-            fh.into(),
-            kh.into(),
-            flags,
-            events,
-        ) {
+        // Schedule before polling, not to miss wakeups of files becoming ready meanwhile.
+        if flags & POLL_SCHEDULE_NOTIFY != 0 {
+            if let Some(notifier) = self.poll.as_ref() {
+                notifier.schedule(kh, fh);
+            }
+        }
+        match self
+            .fs
+            .poll(ctx.context(), ctx.nodeid(), fh.into(), kh, flags, events)
+        {
             Ok(revents) => ctx.reply_ok(
                 Some(PollOut {
                     revents,
@@ -1008,12 +1717,23 @@ impl<F: FileSystem + Sync> Server<F> {
             requests.push(
                 ctx.r
                     .read_obj::<ForgetOne>()
-                    .map(|f| (f.nodeid.into(), f.nlookup))
+                    .map(|f| {
+                        self.audit_forget(f.nodeid, f.nlookup);
+                        #[cfg(feature = "virtiofs")]
+                        self.dax_forget(f.nodeid, f.nlookup);
+                        (f.nodeid, f.nlookup)
+                    })
                     .map_err(Error::DecodeMessage)?,
             );
         }
 
-        self.fs.batch_forget(ctx.context(), requests);
+        if let Some(requests) = self.offload_forgets(requests) {
+            let requests = requests
+                .into_iter()
+                .map(|(nodeid, nlookup)| (nodeid.into(), nlookup))
+                .collect();
+            self.fs.batch_forget(ctx.context(), requests);
+        }
 
         // No reply for forget messages.
         Ok(0)
@@ -1028,6 +1748,9 @@ impl<F: FileSystem + Sync> Server<F> {
             ..
         } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
 
+        if let Err(e) = self.access_check(ctx.in_header.nodeid, fh, Access::Write) {
+            return ctx.reply_error(e);
+        }
         match self
             .fs
             .fallocate(ctx.context(), ctx.nodeid(), fh.into(), mode, offset, length)
@@ -1054,6 +1777,114 @@ impl<F: FileSystem + Sync> Server<F> {
             Err(e) => ctx.reply_error(e),
         }
     }
+
+    pub(super) fn copyfilerange<S: BitmapSlice>(
+        &self,
+        mut ctx: SrvContext<'_, F, S>,
+    ) -> Result<usize> {
+        let CopyFileRangeIn {
+            fh_in,
+            offset_in,
+            nodeid_out,
+            fh_out,
+            offset_out,
+            len,
+            flags,
+        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+
+        if let Err(e) = self
+            .access_check(ctx.in_header.nodeid, fh_in, Access::Read)
+            .and_then(|_| self.access_check(nodeid_out, fh_out, Access::Write))
+        {
+            return ctx.reply_error(e);
+        }
+        match self.fs.copyfilerange(
+            ctx.context(),
+            ctx.nodeid(),
+            fh_in.into(),
+            offset_in,
+            nodeid_out.into(),
+            fh_out.into(),
+            offset_out,
+            len,
+            flags,
+        ) {
+            Ok(count) => {
+                let out = WriteOut {
+                    size: count as u32,
+                    ..Default::default()
+                };
+
+                ctx.reply_ok(Some(out), None)
+            }
+            Err(e) => ctx.reply_error(e),
+        }
+    }
+}
+
+// Request dispatched to opcode handlers, consumed once replied to.
+struct Pending<'s, 'v, 'a, F: FileSystem + Sync, S: BitmapSlice> {
+    server: &'s Server<F>,
+    ctx: Option<SrvContext<'a, F, S>>,
+    vu_req: Option<&'v mut dyn FsCacheReqHandler>,
+}
+
+// Reborrow the handler of cache requests for a shorter lifetime.
+fn reborrow<'r>(
+    vu_req: &'r mut Option<&mut dyn FsCacheReqHandler>,
+) -> Option<&'r mut dyn FsCacheReqHandler> {
+    vu_req
+        .as_mut()
+        .map(|r| &mut **r as &mut dyn FsCacheReqHandler)
+}
+
+impl<F: FileSystem + Sync, S: BitmapSlice> Pending<'_, '_, '_, F, S> {
+    fn ctx(&self) -> &SrvContext<'_, F, S> {
+        self.ctx.as_ref().expect("request already consumed")
+    }
+}
+
+impl<F: FileSystem + Sync, S: BitmapSlice> PendingRequest for Pending<'_, '_, '_, F, S> {
+    fn header(&self) -> &InHeader {
+        &self.ctx().in_header
+    }
+
+    fn context(&self) -> &Context {
+        self.ctx().context()
+    }
+
+    fn peek(&self, buf: &mut [u8]) -> io::Result<()> {
+        self.ctx()
+            .r
+            .clone()
+            .read_exact(buf)
+            .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))
+    }
+
+    fn body_len(&self) -> usize {
+        self.ctx().r.available_bytes()
+    }
+
+    fn reply(&mut self, data: &[u8]) -> Result<usize> {
+        let mut ctx = self.ctx.take().expect("request already consumed");
+        ctx.reply_ok(None::<u8>, Some(data))
+    }
+
+    fn reply_error(&mut self, err: io::Error) -> Result<usize> {
+        let mut ctx = self.ctx.take().expect("request already consumed");
+        ctx.reply_error(err)
+    }
+
+    fn no_reply(&mut self) -> Result<usize> {
+        self.ctx.take();
+        Ok(0)
+    }
+
+    fn builtin(&mut self) -> Result<usize> {
+        let ctx = self.ctx.take().expect("request already consumed");
+        self.server
+            .dispatch_builtin(ctx, reborrow(&mut self.vu_req))
+    }
 }
 
 #[cfg(feature = "virtiofs")]
@@ -1082,7 +1913,12 @@ impl<F: FileSystem + Sync> Server<F> {
                 moffset,
                 req,
             ) {
-                Ok(()) => ctx.reply_ok(None::<u8>, None),
+                Ok(()) => {
+                    if let Some(dax) = self.dax.as_ref() {
+                        dax.map(ctx.in_header.nodeid, moffset, len);
+                    }
+                    ctx.reply_ok(None::<u8>, None)
+                }
                 Err(e) => ctx.reply_error(e),
             }
         } else {
@@ -1115,6 +1951,10 @@ impl<F: FileSystem + Sync> Server<F> {
                 );
             }
 
+            // The guest drops the ranges whatever the result.
+            if let Some(dax) = self.dax.as_ref() {
+                dax.unmap(&requests);
+            }
             match self
                 .fs
                 .removemapping(ctx.context(), ctx.nodeid(), requests, req)
@@ -1131,7 +1971,19 @@ impl<F: FileSystem + Sync> Server<F> {
 impl<'a, F: FileSystem, S: BitmapSlice> SrvContext<'a, F, S> {
     fn reply_ok<T: ByteValued>(&mut self, out: Option<T>, data: Option<&[u8]>) -> Result<usize> {
         let data2 = out.as_ref().map(|v| v.as_slice()).unwrap_or(&[]);
-        let data3 = data.unwrap_or(&[]);
+        self.reply_slices(data2, data.unwrap_or(&[]))
+    }
+
+    // Reply `entry`, followed by `open` for create, in the layout known by the kernel.
+    fn reply_entry(&mut self, entry: Entry, open: Option<OpenOut>) -> Result<usize> {
+        let out = compat::entry_out(entry, self.minor);
+        let data2 = &out.as_slice()[..compat::entry_out_size(self.minor)];
+        let data3 = open.as_ref().map(|v| v.as_slice()).unwrap_or(&[]);
+        self.reply_slices(data2, data3)
+    }
+
+    fn reply_slices(&mut self, data2: &[u8], data3: &[u8]) -> Result<usize> {
+        self.mark_replying();
         let len = size_of::<OutHeader>() + data2.len() + data3.len();
         let header = OutHeader {
             len: len as u32,
@@ -1139,6 +1991,7 @@ impl<'a, F: FileSystem, S: BitmapSlice> SrvContext<'a, F, S> {
             unique: self.unique(),
         };
         trace!("fuse: new reply {:?}", header);
+        self.record_reply(&header);
 
         match (data2.len(), data3.len()) {
             (0, 0) => self
@@ -1168,19 +2021,19 @@ impl<'a, F: FileSystem, S: BitmapSlice> SrvContext<'a, F, S> {
     }
 
     fn do_reply_error(&mut self, err: io::Error, explicit: bool) -> Result<usize> {
+        self.mark_replying();
         let header = OutHeader {
             len: size_of::<OutHeader>() as u32,
-            error: -err
-                .raw_os_error()
-                .unwrap_or_else(|| encode_io_error_kind(err.kind())),
+            error: -errno_of(&err).unwrap_or_else(|| encode_io_error_kind(err.kind())),
             unique: self.unique(),
         };
 
-        if explicit || err.raw_os_error().is_none() {
+        if explicit || errno_of(&err).is_none() {
             error!("fuse: reply error header {:?}, error {:?}", header, err);
         } else {
             trace!("fuse: reply error header {:?}, error {:?}", header, err);
         }
+        self.record_reply(&header);
         self.w
             .write_all(header.as_slice())
             .map_err(Error::EncodeMessage)?;
@@ -1214,7 +2067,8 @@ impl<'a, F: FileSystem, S: BitmapSlice> SrvContext<'a, F, S> {
                     dummy: 0,
                     attr: st.into(),
                 };
-                self.reply_ok(Some(out), None)
+                let size = compat::attr_out_size(self.minor);
+                self.reply_ok(None::<u8>, Some(&out.as_slice()[..size]))
             }
             Err(e) => self.reply_error(e),
         }
@@ -1226,6 +2080,7 @@ fn add_dirent<S: BitmapSlice>(
     max: u32,
     d: DirEntry,
     entry: Option<Entry>,
+    minor: u32,
 ) -> io::Result<usize> {
     if d.name.len() > ::std::u32::MAX as usize {
         return Err(io::Error::from_raw_os_error(libc::EOVERFLOW));
@@ -1255,7 +2110,8 @@ fn add_dirent<S: BitmapSlice>(
         Ok(0)
     } else {
         if let Some(entry) = entry {
-            cursor.write_all(EntryOut::from(entry).as_slice())?;
+            // Readdirplus was introduced after `fuse_entry_out` reached its current size.
+            cursor.write_all(compat::entry_out(entry, minor).as_slice())?;
         }
 
         let dirent = Dirent {
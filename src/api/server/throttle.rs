@@ -0,0 +1,308 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Throttling of requests by token buckets.
+//!
+//! Hosts running a virtiofs device per guest need to cap the IOPS and the bandwidth of each
+//! device, so one guest can't starve the host disks of the others. A [Throttle] installed by
+//! [Server::with_throttle] meters `FUSE_READ` and `FUSE_WRITE` requests and their payload sizes,
+//! and other requests separately, by token buckets refilled at the configured rates. Requests
+//! exceeding the budget are delayed until the buckets would be refilled, instead of failing, so
+//! the guest only sees a slower device.
+//!
+//! The throttle is shared by an `Arc`, a control plane may change the limits at any time by
+//! [Throttle::set_limits] without remounting.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::Server;
+use crate::abi::fuse_abi::{Opcode, ReadIn, WriteIn};
+use crate::api::clock::{Clock, SystemClock};
+use crate::api::filesystem::FileSystem;
+use crate::transport::Reader;
+use crate::BitmapSlice;
+
+/// Rates of requests let through by a [Throttle], `None` or zero leaves a rate unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThrottleLimits {
+    /// Max number of `FUSE_READ` and `FUSE_WRITE` requests per second.
+    ///
+    /// The default value for this option is `None`.
+    pub data_ops: Option<u64>,
+    /// Max number of bytes per second read or written by `FUSE_READ` and `FUSE_WRITE`.
+    ///
+    /// The default value for this option is `None`.
+    pub data_bytes: Option<u64>,
+    /// Max number of other requests per second. `FUSE_INIT`, `FUSE_DESTROY`, `FUSE_FORGET`,
+    /// `FUSE_BATCH_FORGET`, `FUSE_INTERRUPT` and `FUSE_NOTIFY_REPLY` are never throttled.
+    ///
+    /// The default value for this option is `None`.
+    pub metadata_ops: Option<u64>,
+    /// How long an idle device accumulates budget for, to be spent by a burst of requests.
+    ///
+    /// The default value for this option is 1 second.
+    pub burst: Duration,
+}
+
+impl Default for ThrottleLimits {
+    fn default() -> Self {
+        ThrottleLimits {
+            data_ops: None,
+            data_bytes: None,
+            metadata_ops: None,
+            burst: Duration::from_secs(1),
+        }
+    }
+}
+
+// Token bucket refilled at `rate` tokens per second, which goes into debt to admit requests
+// larger than the tokens available, delaying them until the debt would be paid off.
+struct Bucket {
+    rate: u64,
+    tokens: f64,
+    // Monotonic time of the last refill.
+    last: Duration,
+}
+
+impl Bucket {
+    // Create a full bucket.
+    fn new(rate: u64, burst: Duration, now: Duration) -> Self {
+        Bucket {
+            rate,
+            tokens: Self::capacity(rate, burst),
+            last: now,
+        }
+    }
+
+    fn capacity(rate: u64, burst: Duration) -> f64 {
+        rate as f64 * burst.as_secs_f64()
+    }
+
+    // Take `n` tokens at `now`, return how long the request must be delayed.
+    fn take(&mut self, n: u64, burst: Duration, now: Duration) -> Duration {
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+        let elapsed = now.saturating_sub(self.last).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * self.rate as f64).min(Self::capacity(self.rate, burst));
+        self.last = now;
+        self.tokens -= n as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+struct ThrottleState {
+    limits: ThrottleLimits,
+    data_ops: Option<Bucket>,
+    data_bytes: Option<Bucket>,
+    metadata_ops: Option<Bucket>,
+}
+
+/// Token bucket throttle of the requests of a [Server], shared with a control plane.
+pub struct Throttle {
+    clock: Arc<dyn Clock>,
+    state: Mutex<ThrottleState>,
+}
+
+impl Throttle {
+    /// Create a throttle letting requests through at the rates of `limits`.
+    pub fn new(limits: ThrottleLimits) -> Self {
+        let throttle = Throttle {
+            clock: Arc::new(SystemClock::default()),
+            state: Mutex::new(ThrottleState {
+                limits: ThrottleLimits::default(),
+                data_ops: None,
+                data_bytes: None,
+                metadata_ops: None,
+            }),
+        };
+        throttle.set_limits(limits);
+        throttle
+    }
+
+    /// Refill the buckets by the time of `clock`, instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        let limits = self.limits();
+        self.clock = clock;
+        // Restart the buckets at the time of the new clock.
+        self.state.get_mut().unwrap().limits = ThrottleLimits::default();
+        self.set_limits(limits);
+        self
+    }
+
+    /// Get the current limits.
+    pub fn limits(&self) -> ThrottleLimits {
+        self.state.lock().unwrap().limits
+    }
+
+    /// Change the limits, taking effect for the requests arriving from now on.
+    ///
+    /// Buckets whose rate is unchanged keep their tokens, newly limited ones start full.
+    pub fn set_limits(&self, limits: ThrottleLimits) {
+        let now = self.clock.monotonic();
+        let mut state = self.state.lock().unwrap();
+        let update = |bucket: &mut Option<Bucket>, old: Option<u64>, new: Option<u64>| {
+            if old != new || new.is_none() {
+                *bucket = new.map(|rate| Bucket::new(rate, limits.burst, now));
+            } else if let Some(b) = bucket.as_mut() {
+                b.tokens = b.tokens.min(Bucket::capacity(b.rate, limits.burst));
+            }
+        };
+        let old = state.limits;
+        update(&mut state.data_ops, old.data_ops, limits.data_ops);
+        update(&mut state.data_bytes, old.data_bytes, limits.data_bytes);
+        update(
+            &mut state.metadata_ops,
+            old.metadata_ops,
+            limits.metadata_ops,
+        );
+        state.limits = limits;
+    }
+
+    /// Account a request of `opcode` moving `bytes` of payload, and return how long it must be
+    /// delayed to stay within the limits.
+    pub fn delay(&self, opcode: u32, bytes: u64) -> Duration {
+        let now = self.clock.monotonic();
+        let mut state = self.state.lock().unwrap();
+        let burst = state.limits.burst;
+        let take = |bucket: &mut Option<Bucket>, n| match bucket.as_mut() {
+            Some(b) => b.take(n, burst, now),
+            None => Duration::ZERO,
+        };
+        match Opcode::from(opcode) {
+            Opcode::Read | Opcode::Write => {
+                let ops = take(&mut state.data_ops, 1);
+                ops.max(take(&mut state.data_bytes, bytes))
+            }
+            Opcode::Init
+            | Opcode::Destroy
+            | Opcode::Forget
+            | Opcode::BatchForget
+            | Opcode::Interrupt
+            | Opcode::NotifyReply => Duration::ZERO,
+            _ => take(&mut state.metadata_ops, 1),
+        }
+    }
+
+    // Delay the request of `opcode` with body `r` by sleeping.
+    pub(super) fn throttle<S: BitmapSlice>(&self, opcode: u32, r: &Reader<'_, S>) {
+        let delay = self.delay(opcode, payload(opcode, r));
+        if !delay.is_zero() {
+            self.clock.sleep(delay);
+        }
+    }
+
+    // Delay the request of `opcode` with body `r` by waiting asynchronously.
+    #[cfg(feature = "async-io")]
+    pub(super) async fn async_throttle<S: BitmapSlice>(&self, opcode: u32, r: &Reader<'_, S>) {
+        let delay = self.delay(opcode, payload(opcode, r));
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+// Get the size of the payload read or written by a request, without consuming its body.
+fn payload<S: BitmapSlice>(opcode: u32, r: &Reader<'_, S>) -> u64 {
+    // Malformed requests fail later on, when decoded by their handlers.
+    let size = match Opcode::from(opcode) {
+        Opcode::Read => r.clone().read_obj::<ReadIn>().map_or(0, |a| a.size),
+        Opcode::Write => r.clone().read_obj::<WriteIn>().map_or(0, |a| a.size),
+        _ => 0,
+    };
+    size as u64
+}
+
+impl<F: FileSystem + Sync> Server<F> {
+    /// Delay requests exceeding the rates of `throttle`, which may be changed at any time.
+    pub fn with_throttle(mut self, throttle: Arc<Throttle>) -> Self {
+        self.throttle = Some(throttle);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::clock::ManualClock;
+
+    fn throttle(limits: ThrottleLimits) -> (Arc<ManualClock>, Throttle) {
+        let clock = Arc::new(ManualClock::default());
+        let throttle = Throttle::new(limits).with_clock(clock.clone());
+        (clock, throttle)
+    }
+
+    #[test]
+    fn test_throttle_buckets() {
+        let (clock, throttle) = throttle(ThrottleLimits {
+            data_ops: Some(10),
+            data_bytes: Some(1000),
+            metadata_ops: Some(2),
+            ..Default::default()
+        });
+        let read = Opcode::Read as u32;
+        let lookup = Opcode::Lookup as u32;
+
+        // A full bucket lets a burst of one second through.
+        for _ in 0..2 {
+            assert_eq!(throttle.delay(lookup, 0), Duration::ZERO);
+        }
+        assert_eq!(throttle.delay(lookup, 0), Duration::from_millis(500));
+        // Requests queue up behind the debt.
+        assert_eq!(throttle.delay(lookup, 0), Duration::from_secs(1));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(throttle.delay(lookup, 0), Duration::from_millis(500));
+
+        // Data requests are limited by both their count and payload sizes, independently of
+        // metadata requests.
+        assert_eq!(throttle.delay(read, 1000), Duration::ZERO);
+        assert_eq!(throttle.delay(read, 500), Duration::from_millis(500));
+        clock.advance(Duration::from_secs(10));
+        for _ in 0..10 {
+            assert_eq!(throttle.delay(read, 0), Duration::ZERO);
+        }
+        assert_eq!(throttle.delay(read, 0), Duration::from_millis(100));
+
+        // Requests releasing resources are never throttled.
+        for opcode in [Opcode::Forget, Opcode::Interrupt, Opcode::Destroy] {
+            assert_eq!(throttle.delay(opcode as u32, 0), Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn test_throttle_set_limits() {
+        let (clock, throttle) = throttle(ThrottleLimits {
+            metadata_ops: Some(1),
+            ..Default::default()
+        });
+        let getattr = Opcode::Getattr as u32;
+        assert_eq!(throttle.delay(getattr, 0), Duration::ZERO);
+        assert_eq!(throttle.delay(getattr, 0), Duration::from_secs(1));
+
+        // Unchanged rates keep their debt, new ones start full.
+        let limits = ThrottleLimits {
+            metadata_ops: Some(1),
+            data_bytes: Some(100),
+            ..Default::default()
+        };
+        throttle.set_limits(limits);
+        assert_eq!(throttle.limits(), limits);
+        assert_eq!(throttle.delay(getattr, 0), Duration::from_secs(2));
+        assert_eq!(throttle.delay(Opcode::Write as u32, 100), Duration::ZERO);
+
+        // Lifting a limit drops its debt.
+        throttle.set_limits(ThrottleLimits::default());
+        assert_eq!(throttle.delay(getattr, 0), Duration::ZERO);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            throttle.delay(Opcode::Write as u32, 1 << 30),
+            Duration::ZERO
+        );
+    }
+}
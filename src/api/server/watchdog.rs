@@ -0,0 +1,369 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Detection of hung requests.
+//!
+//! When a backing network file system hangs, the worker threads block forever inside syscalls
+//! and the whole mount appears dead, without any diagnostics. A [Watchdog] installed by
+//! [Server::with_watchdog] records the opcode and the start time of each request in a slot of a
+//! lock free slab, and [Watchdog::check] invokes a callback once for each request in flight for
+//! longer than a threshold. The callback may log, bump a metric or abort the daemon. Checks run
+//! periodically on a monitor thread spawned by [Watchdog::spawn_monitor], or whenever the daemon
+//! sees fit, and [Server::inflight_snapshot] dumps the requests in flight, say on `SIGUSR1`.
+//!
+//! The watchdog only reports hung requests. Requests served by asynchronous backends may be
+//! cancelled by [RequestDeadlines](super::RequestDeadlines) instead, synchronous backends
+//! can't be preempted.
+//!
+//! `FUSE_FORGET`, `FUSE_BATCH_FORGET` and `FUSE_DESTROY` are never tracked, the latter waits
+//! for other requests on purpose. Requests arriving while all slots are taken aren't tracked
+//! either, they are counted by [Watchdog::untracked_requests].
+
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use super::Server;
+use crate::abi::fuse_abi::{InHeader, Opcode};
+use crate::api::clock::{Clock, SystemClock};
+use crate::api::filesystem::FileSystem;
+
+/// Default number of requests tracked at once by a [Watchdog].
+pub const DEFAULT_WATCHDOG_SLOTS: usize = 1024;
+
+// Start time of free slots, and of slots being filled in.
+const FREE: u64 = 0;
+const FILLING: u64 = u64::MAX;
+
+/// Callback invoked with a request in flight for longer than the threshold of a [Watchdog].
+pub type HungRequestCallback = Arc<dyn Fn(&InflightRequest) + Send + Sync>;
+
+/// A request being handled by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InflightRequest {
+    /// Unique ID of the request.
+    pub unique: u64,
+    /// Opcode of the request.
+    pub opcode: u32,
+    /// Inode the request operates on.
+    pub nodeid: u64,
+    /// Time since the server started handling the request.
+    pub elapsed: Duration,
+}
+
+#[derive(Default)]
+struct Slot {
+    // Time the request started at by `Watchdog::now`, `FREE` or `FILLING` otherwise.
+    start: AtomicU64,
+    unique: AtomicU64,
+    opcode: AtomicU32,
+    nodeid: AtomicU64,
+    // Whether the callback has been invoked for the request.
+    reported: AtomicBool,
+}
+
+/// Tracker of the requests in flight, reporting the ones taking too long.
+pub struct Watchdog {
+    clock: Arc<dyn Clock>,
+    threshold: Duration,
+    callback: HungRequestCallback,
+    slots: Box<[Slot]>,
+    // Slot to start looking for a free one from, spreading requests over the slab.
+    next: AtomicUsize,
+    untracked: AtomicU64,
+}
+
+impl Watchdog {
+    /// Create a watchdog invoking `callback` once for each request in flight for longer than
+    /// `threshold`.
+    pub fn new(threshold: Duration, callback: HungRequestCallback) -> Self {
+        Watchdog {
+            clock: Arc::new(SystemClock::default()),
+            threshold,
+            callback,
+            slots: Self::new_slots(DEFAULT_WATCHDOG_SLOTS),
+            next: AtomicUsize::new(0),
+            untracked: AtomicU64::new(0),
+        }
+    }
+
+    /// Time requests by `clock`, instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Track up to `slots` requests at once, which should exceed the number of requests the
+    /// server may handle concurrently.
+    ///
+    /// The default value for this option is [DEFAULT_WATCHDOG_SLOTS].
+    pub fn with_slots(mut self, slots: usize) -> Self {
+        self.slots = Self::new_slots(slots.max(1));
+        self
+    }
+
+    fn new_slots(count: usize) -> Box<[Slot]> {
+        (0..count).map(|_| Slot::default()).collect()
+    }
+
+    // Get the monotonic time in nanoseconds plus one, so it's never `FREE`.
+    fn now(&self) -> u64 {
+        (self.clock.monotonic().as_nanos() as u64)
+            .saturating_add(1)
+            .min(FILLING - 1)
+    }
+
+    /// Get the number of requests which weren't tracked because all slots were taken.
+    pub fn untracked_requests(&self) -> u64 {
+        self.untracked.load(Ordering::Relaxed)
+    }
+
+    /// Get the requests in flight, oldest first.
+    pub fn snapshot(&self) -> Vec<InflightRequest> {
+        let now = self.now();
+        let mut requests: Vec<InflightRequest> = self
+            .slots
+            .iter()
+            .filter_map(|slot| Self::read_slot(slot, now).map(|(req, _)| req))
+            .collect();
+        requests.sort_by_key(|r| std::cmp::Reverse(r.elapsed));
+        requests
+    }
+
+    /// Invoke the callback for requests newly found in flight for longer than the threshold,
+    /// and return the number of such requests, including the ones already reported.
+    pub fn check(&self) -> usize {
+        let now = self.now();
+        let mut hung = 0;
+        for slot in self.slots.iter() {
+            let (req, start) = match Self::read_slot(slot, now) {
+                Some(r) if r.0.elapsed >= self.threshold => r,
+                _ => continue,
+            };
+            hung += 1;
+            if !slot.reported.swap(true, Ordering::AcqRel) {
+                // The slot may have been reused meanwhile, by a request which isn't hung.
+                if slot.start.load(Ordering::Acquire) == start {
+                    (self.callback)(&req);
+                } else {
+                    slot.reported.store(false, Ordering::Release);
+                }
+            }
+        }
+        hung
+    }
+
+    /// Check for hung requests every `interval` on a monitor thread, which stops when the
+    /// returned [WatchdogMonitor] gets dropped.
+    pub fn spawn_monitor(self: Arc<Self>, interval: Duration) -> io::Result<WatchdogMonitor> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .name(String::from("fuse_watchdog"))
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    self.check();
+                }
+            })?;
+
+        Ok(WatchdogMonitor {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    // Read the request tracked by `slot` at `now`, with its start time, if any.
+    fn read_slot(slot: &Slot, now: u64) -> Option<(InflightRequest, u64)> {
+        let start = slot.start.load(Ordering::Acquire);
+        if start == FREE || start == FILLING {
+            return None;
+        }
+        let req = InflightRequest {
+            unique: slot.unique.load(Ordering::Relaxed),
+            opcode: slot.opcode.load(Ordering::Relaxed),
+            nodeid: slot.nodeid.load(Ordering::Relaxed),
+            elapsed: Duration::from_nanos(now.saturating_sub(start)),
+        };
+        // Discard the request if the slot has been reused while being read.
+        if slot.start.load(Ordering::Acquire) != start {
+            return None;
+        }
+        Some((req, start))
+    }
+
+    // Track the request of `in_header` until the returned guard gets dropped.
+    pub(super) fn enter(&self, in_header: &InHeader) -> Option<WatchGuard<'_>> {
+        if matches!(
+            Opcode::from(in_header.opcode),
+            Opcode::Forget | Opcode::BatchForget | Opcode::Destroy
+        ) {
+            return None;
+        }
+
+        let count = self.slots.len();
+        let first = self.next.fetch_add(1, Ordering::Relaxed);
+        for idx in (first..first + count).map(|i| i % count) {
+            let slot = &self.slots[idx];
+            if slot
+                .start
+                .compare_exchange(FREE, FILLING, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                continue;
+            }
+            slot.unique.store(in_header.unique, Ordering::Relaxed);
+            slot.opcode.store(in_header.opcode, Ordering::Relaxed);
+            slot.nodeid.store(in_header.nodeid, Ordering::Relaxed);
+            slot.reported.store(false, Ordering::Relaxed);
+            slot.start.store(self.now(), Ordering::Release);
+            return Some(WatchGuard { slot });
+        }
+
+        self.untracked.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+}
+
+pub(super) struct WatchGuard<'a> {
+    slot: &'a Slot,
+}
+
+impl Drop for WatchGuard<'_> {
+    fn drop(&mut self) {
+        self.slot.start.store(FREE, Ordering::Release);
+    }
+}
+
+/// Monitor thread of a [Watchdog], stopped and joined when dropped.
+pub struct WatchdogMonitor {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for WatchdogMonitor {
+    fn drop(&mut self) {
+        // Disconnecting the channel wakes up the monitor thread.
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("fuse: watchdog monitor panicked");
+            }
+        }
+    }
+}
+
+impl<F: FileSystem + Sync> Server<F> {
+    /// Track requests in flight by `watchdog`, which reports the ones taking too long.
+    pub fn with_watchdog(mut self, watchdog: Arc<Watchdog>) -> Self {
+        self.watchdog = Some(watchdog);
+        self
+    }
+
+    /// Get the requests in flight, oldest first, empty without a watchdog.
+    pub fn inflight_snapshot(&self) -> Vec<InflightRequest> {
+        self.watchdog
+            .as_ref()
+            .map_or_else(Vec::new, |w| w.snapshot())
+    }
+
+    // Track the request of `in_header` by the watchdog, until the returned guard gets dropped.
+    pub(super) fn watch(&self, in_header: &InHeader) -> Option<WatchGuard<'_>> {
+        self.watchdog.as_ref().and_then(|w| w.enter(in_header))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::clock::ManualClock;
+    use std::sync::Mutex;
+
+    fn header(unique: u64, opcode: Opcode) -> InHeader {
+        InHeader {
+            unique,
+            opcode: opcode as u32,
+            nodeid: 5,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_watchdog_hung_requests() {
+        let clock = Arc::new(ManualClock::default());
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let reported2 = reported.clone();
+        let watchdog = Watchdog::new(
+            Duration::from_secs(10),
+            Arc::new(move |req| reported2.lock().unwrap().push(req.unique)),
+        )
+        .with_clock(clock.clone())
+        .with_slots(2);
+
+        let read = watchdog.enter(&header(1, Opcode::Read)).unwrap();
+        clock.advance(Duration::from_secs(5));
+        let lookup = watchdog.enter(&header(2, Opcode::Lookup)).unwrap();
+        // Forgets and destroy aren't tracked, nor requests beyond the slots.
+        assert!(watchdog.enter(&header(3, Opcode::Forget)).is_none());
+        assert!(watchdog.enter(&header(4, Opcode::Destroy)).is_none());
+        assert!(watchdog.enter(&header(5, Opcode::Getattr)).is_none());
+        assert_eq!(watchdog.untracked_requests(), 1);
+
+        assert_eq!(
+            watchdog.snapshot(),
+            vec![
+                InflightRequest {
+                    unique: 1,
+                    opcode: Opcode::Read as u32,
+                    nodeid: 5,
+                    elapsed: Duration::from_secs(5),
+                },
+                InflightRequest {
+                    unique: 2,
+                    opcode: Opcode::Lookup as u32,
+                    nodeid: 5,
+                    elapsed: Duration::ZERO,
+                },
+            ]
+        );
+        assert_eq!(watchdog.check(), 0);
+
+        // Hung requests are reported once.
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(watchdog.check(), 1);
+        assert_eq!(watchdog.check(), 1);
+        assert_eq!(*reported.lock().unwrap(), vec![1]);
+
+        // Slots of completed requests are reused.
+        drop(read);
+        assert_eq!(watchdog.check(), 0);
+        let _getattr = watchdog.enter(&header(6, Opcode::Getattr)).unwrap();
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(watchdog.check(), 2);
+        reported.lock().unwrap().sort_unstable();
+        assert_eq!(*reported.lock().unwrap(), vec![1, 2, 6]);
+        drop(lookup);
+        assert_eq!(watchdog.snapshot().len(), 1);
+    }
+
+    #[test]
+    fn test_watchdog_monitor() {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let watchdog = Arc::new(Watchdog::new(
+            Duration::ZERO,
+            Arc::new(move |req| tx.lock().unwrap().send(req.unique).unwrap()),
+        ));
+        let _read = watchdog.enter(&header(1, Opcode::Read)).unwrap();
+
+        let monitor = watchdog
+            .clone()
+            .spawn_monitor(Duration::from_millis(1))
+            .unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(10)).unwrap(), 1);
+        drop(monitor);
+        // The monitor thread has been joined and dropped its reference.
+        assert_eq!(Arc::strong_count(&watchdog), 1);
+    }
+}
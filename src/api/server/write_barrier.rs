@@ -0,0 +1,186 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Ordering of `FUSE_FSYNC`, `FUSE_FLUSH` and `FUSE_RELEASE` after writes of the same handle.
+//!
+//! The asynchronous server handles requests concurrently, so a fsync may reach the filesystem
+//! driver while writes of the handle received before it are still in flight, and complete before
+//! their data reaches the backing file. With [Server::with_write_barrier], such requests wait for
+//! the writes of the handle received before them to complete, while later writes go on.
+//!
+//! Writes in flight are counted in one of two slots of the handle, selected by the parity of its
+//! epoch. A barrier bumps the epoch, so later writes are counted in the other slot, and waits for
+//! the slot of earlier writes to drain. Before bumping the epoch, a barrier waits for the slot of
+//! the epoch before to drain, so it may also wait for writes received while an earlier barrier of
+//! the handle was pending.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+use super::Server;
+use crate::api::filesystem::FileSystem;
+
+#[derive(Default)]
+struct HandleWrites {
+    epoch: AtomicU64,
+    inflight: [AtomicU64; 2],
+    drained: Notify,
+}
+
+impl HandleWrites {
+    fn enter(self: &Arc<Self>) -> WriteGuard {
+        loop {
+            let epoch = self.epoch.load(Ordering::SeqCst);
+            let slot = (epoch & 1) as usize;
+            self.inflight[slot].fetch_add(1, Ordering::SeqCst);
+            let guard = WriteGuard {
+                writes: self.clone(),
+                slot,
+            };
+            // Raced with a barrier, count the write in the slot of the new epoch.
+            if self.epoch.load(Ordering::SeqCst) == epoch {
+                return guard;
+            }
+        }
+    }
+
+    async fn wait_drained(&self, slot: usize) {
+        loop {
+            let notified = self.drained.notified();
+            tokio::pin!(notified);
+            // Register before checking, not to miss a notification in between.
+            notified.as_mut().enable();
+            if self.inflight[slot].load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    async fn barrier(&self) {
+        loop {
+            let epoch = self.epoch.load(Ordering::SeqCst);
+            self.wait_drained(((epoch + 1) & 1) as usize).await;
+            if self
+                .epoch
+                .compare_exchange(epoch, epoch + 1, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                self.wait_drained((epoch & 1) as usize).await;
+                return;
+            }
+        }
+    }
+}
+
+/// A write in flight, counted until dropped.
+pub(crate) struct WriteGuard {
+    writes: Arc<HandleWrites>,
+    slot: usize,
+}
+
+impl Drop for WriteGuard {
+    fn drop(&mut self) {
+        if self.writes.inflight[self.slot].fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.writes.drained.notify_waiters();
+        }
+    }
+}
+
+/// Writes in flight of open handles.
+pub(crate) struct WriteBarrier {
+    // Writes of handles, keyed by `(nodeid, fh)`.
+    handles: Mutex<HashMap<(u64, u64), Arc<HandleWrites>>>,
+}
+
+impl WriteBarrier {
+    pub(crate) fn new() -> Self {
+        WriteBarrier {
+            handles: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a write to handle `fh` of `nodeid` until the returned guard is dropped.
+    pub(crate) fn enter(&self, nodeid: u64, fh: u64) -> WriteGuard {
+        let writes = self
+            .handles
+            .lock()
+            .unwrap()
+            .entry((nodeid, fh))
+            .or_default()
+            .clone();
+        writes.enter()
+    }
+
+    /// Wait for the writes to handle `fh` of `nodeid` received so far to complete.
+    pub(crate) async fn barrier(&self, nodeid: u64, fh: u64) {
+        let writes = self.handles.lock().unwrap().get(&(nodeid, fh)).cloned();
+        if let Some(writes) = writes {
+            writes.barrier().await;
+        }
+    }
+
+    /// Forget handle `fh` of `nodeid` on release.
+    pub(crate) fn release(&self, nodeid: u64, fh: u64) {
+        self.handles.lock().unwrap().remove(&(nodeid, fh));
+    }
+}
+
+impl<F: FileSystem + Sync> Server<F> {
+    /// Make `FUSE_FSYNC`, `FUSE_FLUSH` and `FUSE_RELEASE` requests handled by the asynchronous
+    /// request path wait for writes of the same handle received before them to complete.
+    pub fn with_write_barrier(mut self) -> Self {
+        self.writes = Some(WriteBarrier::new());
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::FutureExt;
+    use std::future::Future;
+    use std::task::{Context, Poll};
+
+    // Poll `fut` once, return whether it's complete.
+    fn ready<T>(fut: &mut (impl Future<Output = T> + Unpin)) -> bool {
+        let waker = futures::task::noop_waker();
+        matches!(
+            fut.poll_unpin(&mut Context::from_waker(&waker)),
+            Poll::Ready(_)
+        )
+    }
+
+    #[test]
+    fn test_write_barrier() {
+        let writes = WriteBarrier::new();
+        // Barriers of handles without writes don't wait.
+        block_on(writes.barrier(1, 10));
+
+        let w1 = writes.enter(1, 10);
+        let other = writes.enter(1, 11);
+        let mut fsync = Box::pin(writes.barrier(1, 10));
+        assert!(!ready(&mut fsync));
+
+        // Later writes don't delay the barrier.
+        let w2 = writes.enter(1, 10);
+        assert!(!ready(&mut fsync));
+        drop(w1);
+        assert!(ready(&mut fsync));
+
+        // A second barrier waits for writes received after the first one.
+        let mut flush = Box::pin(writes.barrier(1, 10));
+        assert!(!ready(&mut flush));
+        drop(w2);
+        assert!(ready(&mut flush));
+
+        drop(other);
+        writes.release(1, 10);
+        writes.release(1, 11);
+        assert!(writes.handles.lock().unwrap().is_empty());
+    }
+}
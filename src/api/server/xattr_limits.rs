@@ -0,0 +1,366 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Limits of the sizes of extended attributes.
+//!
+//! A hostile guest may send setxattr requests with values up to `max_write` bytes over and over,
+//! or ask for the names of directories with thousands of xattrs, forcing large allocations in
+//! the daemon. [XattrLimits] bounds the length of names, the size of values and the size of name
+//! lists, and sizes of buffers requested by getxattr and listxattr are clamped to the limits, so
+//! replies are never larger. Exceeding a limit fails like the Linux VFS does:
+//!
+//! * names longer than `name_max` fail with `ERANGE`,
+//! * values larger than `size_max` fail with `E2BIG`,
+//! * name lists larger than `list_max` fail with `E2BIG`.
+//!
+//! The [Server] enforces the limits set by [Server::with_xattr_limits] on all file systems, file
+//! systems allocating buffers for xattrs, like the passthrough one, may enforce them too.
+//!
+//! Replies are also capped at the `max_write` negotiated with the kernel: buffers requested by
+//! getxattr and listxattr are clamped to it, values and lists not fitting because of it fail with
+//! `ERANGE`, and their sizes fail with `E2BIG` instead of being replied.
+
+use std::cmp;
+use std::ffi::CStr;
+use std::io;
+
+use super::Server;
+use crate::api::errno::errno_of;
+use crate::api::filesystem::FileSystem;
+
+/// Max length of xattr names on Linux, see `XATTR_NAME_MAX` in `<linux/limits.h>`.
+pub const XATTR_NAME_MAX: usize = 255;
+/// Max size of xattr values on Linux, see `XATTR_SIZE_MAX` in `<linux/limits.h>`.
+pub const XATTR_SIZE_MAX: usize = 65536;
+/// Max size of xattr name lists on Linux, see `XATTR_LIST_MAX` in `<linux/limits.h>`.
+pub const XATTR_LIST_MAX: usize = 65536;
+
+/// Limits of the sizes of xattr names, values and name lists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config-serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct XattrLimits {
+    /// Max length of xattr names, without the nul terminator.
+    ///
+    /// The default value for this option is [XATTR_NAME_MAX].
+    pub name_max: usize,
+    /// Max size of xattr values.
+    ///
+    /// The default value for this option is [XATTR_SIZE_MAX].
+    pub size_max: usize,
+    /// Max size of xattr name lists, including the nul terminators.
+    ///
+    /// The default value for this option is [XATTR_LIST_MAX].
+    pub list_max: usize,
+}
+
+impl Default for XattrLimits {
+    fn default() -> Self {
+        XattrLimits {
+            name_max: XATTR_NAME_MAX,
+            size_max: XATTR_SIZE_MAX,
+            list_max: XATTR_LIST_MAX,
+        }
+    }
+}
+
+impl XattrLimits {
+    /// Fail with `ERANGE` if `name` is longer than `name_max`.
+    pub fn check_name(&self, name: &CStr) -> io::Result<()> {
+        if name.to_bytes().len() > self.name_max {
+            return Err(io::Error::from_raw_os_error(libc::ERANGE));
+        }
+        Ok(())
+    }
+
+    /// Fail with `E2BIG` if `size`, the size of a value, is larger than `size_max`.
+    pub fn check_value(&self, size: usize) -> io::Result<()> {
+        Self::check_size(size, self.size_max)
+    }
+
+    /// Fail with `E2BIG` if `size`, the size of a name list, is larger than `list_max`.
+    pub fn check_list(&self, size: usize) -> io::Result<()> {
+        Self::check_size(size, self.list_max)
+    }
+
+    /// Clamp `size`, the size of the buffer requested by getxattr, to `size_max`.
+    pub fn value_buf_size(&self, size: u32) -> u32 {
+        Self::clamp(size, self.size_max)
+    }
+
+    /// Clamp `size`, the size of the buffer requested by listxattr, to `list_max`.
+    pub fn list_buf_size(&self, size: u32) -> u32 {
+        Self::clamp(size, self.list_max)
+    }
+
+    /// Translate the result of getxattr with a buffer of `size` bytes clamped by
+    /// [XattrLimits::value_buf_size]: values not fitting because of the limit fail with `E2BIG`
+    /// instead of `ERANGE`.
+    pub fn value_result<T>(&self, size: u32, res: io::Result<T>) -> io::Result<T> {
+        Self::translate_erange(size, self.size_max, res)
+    }
+
+    /// Translate the result of listxattr with a buffer of `size` bytes clamped by
+    /// [XattrLimits::list_buf_size]: lists not fitting because of the limit fail with `E2BIG`
+    /// instead of `ERANGE`.
+    pub fn list_result<T>(&self, size: u32, res: io::Result<T>) -> io::Result<T> {
+        Self::translate_erange(size, self.list_max, res)
+    }
+
+    fn check_size(size: usize, max: usize) -> io::Result<()> {
+        if size > max {
+            return Err(io::Error::from_raw_os_error(libc::E2BIG));
+        }
+        Ok(())
+    }
+
+    fn clamp(size: u32, max: usize) -> u32 {
+        cmp::min(size as usize, max) as u32
+    }
+
+    fn translate_erange<T>(size: u32, max: usize, res: io::Result<T>) -> io::Result<T> {
+        match res {
+            Err(e) if errno_of(&e) == Some(libc::ERANGE) && size as usize > max => {
+                Err(io::Error::from_raw_os_error(libc::E2BIG))
+            }
+            res => res,
+        }
+    }
+}
+
+impl<F: FileSystem + Sync> Server<F> {
+    /// Limit the sizes of xattr names, values and name lists accepted from and replied to the
+    /// guest. Defaults to the limits of the Linux VFS.
+    pub fn with_xattr_limits(mut self, limits: XattrLimits) -> Self {
+        self.xattr_limits = limits;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    #[cfg(feature = "fusedev")]
+    use crate::abi::fuse_abi::*;
+    #[cfg(feature = "fusedev")]
+    use crate::api::filesystem::{Context, GetxattrReply, ListxattrReply};
+
+    fn errno<T>(res: io::Result<T>) -> Option<i32> {
+        res.err().and_then(|e| errno_of(&e))
+    }
+
+    #[test]
+    fn test_xattr_limits() {
+        let limits = XattrLimits::default();
+        let name = |len| CString::new(vec![b'a'; len]).unwrap();
+        limits.check_name(&name(XATTR_NAME_MAX)).unwrap();
+        assert_eq!(
+            errno(limits.check_name(&name(XATTR_NAME_MAX + 1))),
+            Some(libc::ERANGE)
+        );
+        limits.check_value(XATTR_SIZE_MAX).unwrap();
+        assert_eq!(
+            errno(limits.check_value(XATTR_SIZE_MAX + 1)),
+            Some(libc::E2BIG)
+        );
+        limits.check_list(XATTR_LIST_MAX).unwrap();
+        assert_eq!(
+            errno(limits.check_list(XATTR_LIST_MAX + 1)),
+            Some(libc::E2BIG)
+        );
+
+        let limits = XattrLimits {
+            size_max: 16,
+            list_max: 32,
+            ..Default::default()
+        };
+        assert_eq!(limits.value_buf_size(16), 16);
+        assert_eq!(limits.value_buf_size(17), 16);
+        assert_eq!(limits.list_buf_size(1 << 20), 32);
+
+        let erange = || Err::<(), _>(io::Error::from_raw_os_error(libc::ERANGE));
+        // Values not fitting in buffers within the limit are out of range.
+        assert_eq!(errno(limits.value_result(16, erange())), Some(libc::ERANGE));
+        assert_eq!(errno(limits.value_result(17, erange())), Some(libc::E2BIG));
+        assert_eq!(errno(limits.list_result(32, erange())), Some(libc::ERANGE));
+        assert_eq!(errno(limits.list_result(33, erange())), Some(libc::E2BIG));
+        limits.value_result(17, Ok(())).unwrap();
+    }
+
+    // Xattrs of values of the given size and name lists of twice the size, recording the
+    // requested sizes.
+    #[cfg(feature = "fusedev")]
+    struct XattrFs(std::sync::Mutex<Vec<u32>>, u32);
+
+    #[cfg(feature = "fusedev")]
+    impl Default for XattrFs {
+        fn default() -> Self {
+            XattrFs(Default::default(), 100)
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    impl FileSystem for XattrFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn setxattr(
+            &self,
+            _ctx: &Context,
+            _inode: u64,
+            _name: &CStr,
+            value: &[u8],
+            _flags: u32,
+        ) -> io::Result<()> {
+            self.0.lock().unwrap().push(value.len() as u32);
+            Ok(())
+        }
+
+        fn getxattr(
+            &self,
+            _ctx: &Context,
+            _inode: u64,
+            _name: &CStr,
+            size: u32,
+        ) -> io::Result<GetxattrReply> {
+            self.0.lock().unwrap().push(size);
+            match size {
+                0 => Ok(GetxattrReply::Count(self.1)),
+                s if s < self.1 => Err(io::Error::from_raw_os_error(libc::ERANGE)),
+                _ => Ok(GetxattrReply::Value(vec![0; self.1 as usize])),
+            }
+        }
+
+        fn listxattr(&self, _ctx: &Context, _inode: u64, size: u32) -> io::Result<ListxattrReply> {
+            self.0.lock().unwrap().push(size);
+            match size {
+                0 => Ok(ListxattrReply::Count(self.1 * 2)),
+                s if s < self.1 * 2 => Err(io::Error::from_raw_os_error(libc::ERANGE)),
+                _ => Ok(ListxattrReply::Names(vec![0; self.1 as usize * 2])),
+            }
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_server_xattr_limits() {
+        use crate::transport::{FuseBuf, FuseDevWriter, Reader};
+        use std::io::{Read, Seek, SeekFrom};
+        use std::mem::size_of;
+        use std::os::unix::io::AsRawFd;
+        use vm_memory::ByteValued;
+
+        // Return the error of the reply to the request.
+        let request = |server: &Server<XattrFs>, opcode: Opcode, body: &[u8]| {
+            let in_header = InHeader {
+                len: (size_of::<InHeader>() + body.len()) as u32,
+                opcode: opcode as u32,
+                unique: 1,
+                nodeid: 1,
+                ..Default::default()
+            };
+            let mut r_buf = in_header.as_slice().to_vec();
+            r_buf.extend_from_slice(body);
+            let r = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut r_buf)).unwrap();
+            let mut file = vmm_sys_util::tempfile::TempFile::new().unwrap().into_file();
+            let mut w_buf = vec![0x0u8; 0x2000];
+            let w = FuseDevWriter::<()>::new(file.as_raw_fd(), &mut w_buf)
+                .unwrap()
+                .into();
+            server.handle_message(r, w, None, None).unwrap();
+            let mut reply = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut reply).unwrap();
+            OutHeader::from_slice(&reply[..size_of::<OutHeader>()])
+                .unwrap()
+                .error
+        };
+        let setxattr = |server: &Server<XattrFs>, name_len: usize, size: usize| {
+            let mut body = SetxattrIn {
+                size: size as u32,
+                ..Default::default()
+            }
+            .as_slice()
+            .to_vec();
+            body.resize(body.len() + name_len, b'a');
+            body.push(0);
+            body.resize(body.len() + size, 0);
+            request(server, Opcode::Setxattr, &body)
+        };
+        let getxattr = |server: &Server<XattrFs>, name_len: usize, size: u32| {
+            let mut body = GetxattrIn {
+                size,
+                ..Default::default()
+            }
+            .as_slice()
+            .to_vec();
+            body.resize(body.len() + name_len, b'a');
+            body.push(0);
+            request(server, Opcode::Getxattr, &body)
+        };
+        let listxattr = |server: &Server<XattrFs>, size: u32| {
+            let body = GetxattrIn {
+                size,
+                ..Default::default()
+            };
+            request(server, Opcode::Listxattr, body.as_slice())
+        };
+
+        let server = Server::new(XattrFs::default()).with_xattr_limits(XattrLimits {
+            name_max: 8,
+            size_max: 100,
+            list_max: 200,
+        });
+        assert_eq!(setxattr(&server, 8, 100), 0);
+        assert_eq!(setxattr(&server, 9, 100), -libc::ERANGE);
+        assert_eq!(setxattr(&server, 8, 101), -libc::E2BIG);
+        assert_eq!(*server.fs.0.lock().unwrap(), vec![100]);
+        assert_eq!(
+            request(&server, Opcode::Removexattr, b"aaaaaaaaa\0"),
+            -libc::ERANGE
+        );
+
+        // Buffers are clamped to the limits, values not fitting because of them are too big.
+        server.fs.0.lock().unwrap().clear();
+        assert_eq!(getxattr(&server, 8, 100), 0);
+        assert_eq!(getxattr(&server, 8, 101), 0);
+        assert_eq!(getxattr(&server, 9, 100), -libc::ERANGE);
+        assert_eq!(getxattr(&server, 8, 99), -libc::ERANGE);
+        assert_eq!(listxattr(&server, 200), 0);
+        assert_eq!(listxattr(&server, 1 << 16), 0);
+        assert_eq!(listxattr(&server, 199), -libc::ERANGE);
+        assert_eq!(
+            *server.fs.0.lock().unwrap(),
+            vec![100, 100, 99, 200, 200, 199]
+        );
+
+        let server = Server::new(XattrFs::default()).with_xattr_limits(XattrLimits {
+            size_max: 99,
+            list_max: 199,
+            ..Default::default()
+        });
+        assert_eq!(getxattr(&server, 8, 100), -libc::E2BIG);
+        assert_eq!(getxattr(&server, 8, 0), -libc::E2BIG);
+        assert_eq!(listxattr(&server, 200), -libc::E2BIG);
+        assert_eq!(listxattr(&server, 0), -libc::E2BIG);
+
+        // Buffers are clamped to `max_write` too, values not fitting because of it are out of
+        // range, and sizes which can't be replied are too big.
+        let server = Server::new(XattrFs(Default::default(), 0x1800)).with_max_write(0x1000);
+        assert_eq!(getxattr(&server, 8, 0x10000), -libc::ERANGE);
+        assert_eq!(getxattr(&server, 8, 0), -libc::E2BIG);
+        assert_eq!(listxattr(&server, 0x10000), -libc::ERANGE);
+        assert_eq!(listxattr(&server, 0), -libc::E2BIG);
+        assert_eq!(*server.fs.0.lock().unwrap(), vec![0x1000, 0, 0x1000, 0]);
+        let server = Server::new(XattrFs(Default::default(), 0x800)).with_max_write(0x1000);
+        assert_eq!(getxattr(&server, 8, 0x10000), 0);
+        assert_eq!(getxattr(&server, 8, 0), 0);
+        assert_eq!(listxattr(&server, 0x10000), 0);
+        assert_eq!(listxattr(&server, 0), 0);
+    }
+}
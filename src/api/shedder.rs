@@ -0,0 +1,168 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Cooperative yield points of long running synchronous operations.
+//!
+//! Some operations may hold a worker thread for hundreds of milliseconds, like copies of file
+//! ranges the host kernel can't offload, mapping large xattr lists or taking snapshots of large
+//! directories, starving small worker pools. Such operations are split in chunks, and between
+//! chunks they consult a [LoadShedder] through [YieldPoints], which may ask them to pause
+//! briefly, or to abort with `EINTR`. Without a shedder, operations run to completion.
+//!
+//! The [Server](super::server::Server) provides a [DrainShedder], which aborts operations once
+//! the server is draining in-flight requests for shutdown.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::api::errno::fuse_errno;
+
+/// Decision of a [LoadShedder] at a yield point.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShedDecision {
+    /// Go on with the next chunk.
+    Continue,
+    /// Sleep for the duration, then go on with the next chunk.
+    Pause(Duration),
+    /// Stop the operation, which fails with `EINTR`.
+    Abort,
+}
+
+/// Policy consulted by long running operations between chunks.
+pub trait LoadShedder: Send + Sync {
+    /// Decide whether the operation goes on.
+    fn check(&self) -> ShedDecision;
+}
+
+/// A [LoadShedder] aborting operations once draining, and letting them go on otherwise.
+#[derive(Debug, Default)]
+pub struct DrainShedder {
+    draining: AtomicBool,
+}
+
+impl DrainShedder {
+    /// Abort operations reaching their next yield point from now on.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether operations are aborted.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+}
+
+impl LoadShedder for DrainShedder {
+    fn check(&self) -> ShedDecision {
+        if self.is_draining() {
+            ShedDecision::Abort
+        } else {
+            ShedDecision::Continue
+        }
+    }
+}
+
+/// Amount of work done by long running operations between yield points.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct YieldChunks {
+    /// Bytes copied by a copy of file ranges done by the daemon.
+    ///
+    /// The default value is 1MB.
+    pub copy_bytes: u64,
+    /// Names of an xattr list mapped by an xattr map.
+    ///
+    /// The default value is 1024.
+    pub xattr_names: usize,
+    /// Entries read into a directory snapshot.
+    ///
+    /// The default value is 4096.
+    pub snapshot_entries: usize,
+}
+
+impl Default for YieldChunks {
+    fn default() -> Self {
+        YieldChunks {
+            copy_bytes: 1 << 20,
+            xattr_names: 1024,
+            snapshot_entries: 4096,
+        }
+    }
+}
+
+/// Yield points of long running operations, consulting an optional [LoadShedder].
+#[derive(Clone, Default)]
+pub struct YieldPoints {
+    shedder: Option<Arc<dyn LoadShedder>>,
+    chunks: YieldChunks,
+}
+
+impl YieldPoints {
+    /// Create yield points consulting `shedder`, with the default chunk sizes.
+    pub fn new(shedder: Arc<dyn LoadShedder>) -> Self {
+        YieldPoints {
+            shedder: Some(shedder),
+            chunks: YieldChunks::default(),
+        }
+    }
+
+    /// Set the amount of work done between yield points.
+    pub fn with_chunks(mut self, chunks: YieldChunks) -> Self {
+        self.chunks = chunks;
+        self
+    }
+
+    /// Get the amount of work done between yield points.
+    pub fn chunks(&self) -> &YieldChunks {
+        &self.chunks
+    }
+
+    /// Consult the shedder between two chunks of an operation, sleeping if asked to pause, and
+    /// failing with `EINTR` if asked to abort.
+    pub fn check(&self) -> io::Result<()> {
+        match self.shedder.as_ref().map(|s| s.check()) {
+            None | Some(ShedDecision::Continue) => Ok(()),
+            Some(ShedDecision::Pause(duration)) => {
+                thread::sleep(duration);
+                Ok(())
+            }
+            Some(ShedDecision::Abort) => Err(fuse_errno(
+                libc::EINTR,
+                "operation aborted by the load shedder",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::errno::errno_of;
+
+    #[test]
+    fn test_yield_points() {
+        YieldPoints::default().check().unwrap();
+
+        let shedder = Arc::new(DrainShedder::default());
+        let points = YieldPoints::new(shedder.clone());
+        points.check().unwrap();
+        shedder.drain();
+        assert_eq!(errno_of(&points.check().unwrap_err()), Some(libc::EINTR));
+
+        struct Pause;
+        impl LoadShedder for Pause {
+            fn check(&self) -> ShedDecision {
+                ShedDecision::Pause(Duration::from_millis(1))
+            }
+        }
+        let chunks = YieldChunks {
+            copy_bytes: 4096,
+            ..Default::default()
+        };
+        let points = YieldPoints::new(Arc::new(Pause)).with_chunks(chunks);
+        points.check().unwrap();
+        assert_eq!(points.chunks().copy_bytes, 4096);
+    }
+}
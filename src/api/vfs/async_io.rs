@@ -1,11 +1,19 @@
 // Copyright (C) 2021 Alibaba Cloud. All rights reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+//! Asynchronous requests on the Vfs.
+//!
+//! Requests on inodes of mounted backends are routed by the super index of the inode to the
+//! asynchronous methods of the backend, requests on inodes of the pseudo fs are answered inline
+//! by its synchronous methods, as they never block. The translation of inodes, handles and
+//! replies is shared with the synchronous path.
+
 use std::io;
 
 use async_trait::async_trait;
 
 use super::*;
+use crate::api::errno::errno_of;
 
 #[async_trait]
 impl AsyncFileSystem for Vfs {
@@ -20,15 +28,17 @@ impl AsyncFileSystem for Vfs {
             return Err(io::Error::from_raw_os_error(libc::EINVAL));
         }
 
+        let ctx = &self.mount_ctx(ctx, parent, false)?;
         match self.get_real_rootfs(parent)? {
             (Left(fs), idata) => self.lookup_pseudo(fs, idata, ctx, name),
-            (Right(fs), idata) => {
+            (Right(fs), idata) => match self.cached_lookup(parent, name) {
+                Some(entry) => Ok(entry),
                 // parent is in an underlying rootfs
-                let mut entry = fs.async_lookup(ctx, idata.ino(), name).await?;
-                // lookup success, hash it to a real fuse inode
-                entry.inode = self.convert_inode(idata.fs_idx(), entry.inode)?;
-                Ok(entry)
-            }
+                None => {
+                    let res = fs.async_lookup(ctx, idata.ino(), name).await;
+                    self.backend_lookup(idata.fs_idx(), parent, name, res)
+                }
+            },
         }
     }
 
@@ -38,9 +48,13 @@ impl AsyncFileSystem for Vfs {
         inode: <Self as FileSystem>::Inode,
         handle: Option<<Self as FileSystem>::Handle>,
     ) -> Result<(libc::stat64, Duration)> {
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
         match self.get_real_rootfs(inode)? {
             (Left(fs), idata) => fs.getattr(ctx, idata.ino(), handle),
-            (Right(fs), idata) => fs.async_getattr(ctx, idata.ino(), handle).await,
+            (Right(fs), idata) => {
+                let res = fs.async_getattr(ctx, idata.ino(), handle).await;
+                self.backend_attr(idata.fs_idx(), res)
+            }
         }
     }
 
@@ -52,13 +66,17 @@ impl AsyncFileSystem for Vfs {
         handle: Option<<Self as FileSystem>::Handle>,
         valid: SetattrValid,
     ) -> Result<(libc::stat64, Duration)> {
-        match self.get_real_rootfs(inode)? {
+        let ctx = &self.mount_ctx(ctx, inode, true)?;
+        let res = match self.get_real_rootfs(inode)? {
             (Left(fs), idata) => fs.setattr(ctx, idata.ino(), attr, handle, valid),
             (Right(fs), idata) => {
-                fs.async_setattr(ctx, idata.ino(), attr, handle, valid)
-                    .await
+                let res = fs
+                    .async_setattr(ctx, idata.ino(), attr, handle, valid)
+                    .await;
+                self.backend_attr(idata.fs_idx(), res)
             }
-        }
+        };
+        self.modified(inode, res)
     }
 
     async fn async_open(
@@ -71,12 +89,13 @@ impl AsyncFileSystem for Vfs {
         if self.opts.load().no_open {
             Err(Error::from_raw_os_error(libc::ENOSYS))
         } else {
+            let ctx = &self.mount_ctx(ctx, inode, open_writes(flags))?;
             match self.get_real_rootfs(inode)? {
                 (Left(fs), idata) => fs.open(ctx, idata.ino(), flags, fuse_flags),
-                (Right(fs), idata) => fs
-                    .async_open(ctx, idata.ino(), flags, fuse_flags)
-                    .await
-                    .map(|(h, opt)| (h.map(Into::into), opt)),
+                (Right(fs), idata) => {
+                    let res = fs.async_open(ctx, idata.ino(), flags, fuse_flags).await;
+                    self.backend_open(idata.fs_idx(), res)
+                }
             }
         }
     }
@@ -90,17 +109,15 @@ impl AsyncFileSystem for Vfs {
     ) -> Result<(Entry, Option<<Self as FileSystem>::Handle>, OpenOptions)> {
         validate_path_component(name)?;
 
-        match self.get_real_rootfs(parent)? {
+        let ctx = &self.mount_ctx(ctx, parent, true)?;
+        let res = match self.get_real_rootfs(parent)? {
             (Left(fs), idata) => fs.create(ctx, idata.ino(), name, args),
             (Right(fs), idata) => {
-                fs.async_create(ctx, idata.ino(), name, args)
-                    .await
-                    .map(|(mut a, b, c)| {
-                        a.inode = self.convert_inode(idata.fs_idx(), a.inode)?;
-                        Ok((a, b, c))
-                    })?
+                let res = fs.async_create(ctx, idata.ino(), name, args).await;
+                self.backend_create(idata.fs_idx(), res)
             }
-        }
+        };
+        self.created(parent, name, res)
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -115,12 +132,33 @@ impl AsyncFileSystem for Vfs {
         lock_owner: Option<u64>,
         flags: u32,
     ) -> Result<usize> {
-        match self.get_real_rootfs(inode)? {
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
+        match self.get_handle_rootfs(inode)? {
             (Left(_fs), _idata) => Err(io::Error::from_raw_os_error(libc::ENOSYS)),
-            (Right(fs), idata) => {
-                fs.async_read(ctx, idata.ino(), handle, w, size, offset, lock_owner, flags)
-                    .await
-            }
+            (Right(fs), idata) => match split_io::Split::new(size, fs.max_read()) {
+                None => {
+                    fs.async_read(ctx, idata.ino(), handle, w, size, offset, lock_owner, flags)
+                        .await
+                }
+                Some(mut split) => {
+                    while let Some((size, off)) = split.next() {
+                        let res = fs
+                            .async_read(
+                                ctx,
+                                idata.ino(),
+                                handle,
+                                w,
+                                size,
+                                offset + off,
+                                lock_owner,
+                                flags,
+                            )
+                            .await;
+                        split.complete(res)?;
+                    }
+                    Ok(split.done())
+                }
+            },
         }
     }
 
@@ -138,24 +176,51 @@ impl AsyncFileSystem for Vfs {
         flags: u32,
         fuse_flags: u32,
     ) -> Result<usize> {
-        match self.get_real_rootfs(inode)? {
+        let ctx = &self.mount_ctx(ctx, inode, true)?;
+        let res = match self.get_handle_rootfs(inode)? {
             (Left(_fs), _idata) => Err(io::Error::from_raw_os_error(libc::ENOSYS)),
-            (Right(fs), idata) => {
-                fs.async_write(
-                    ctx,
-                    idata.ino(),
-                    handle,
-                    r,
-                    size,
-                    offset,
-                    lock_owner,
-                    delayed_write,
-                    flags,
-                    fuse_flags,
-                )
-                .await
-            }
-        }
+            (Right(fs), idata) => match split_io::Split::new(size, fs.max_write()) {
+                None => {
+                    fs.async_write(
+                        ctx,
+                        idata.ino(),
+                        handle,
+                        r,
+                        size,
+                        offset,
+                        lock_owner,
+                        delayed_write,
+                        flags,
+                        fuse_flags,
+                    )
+                    .await
+                }
+                Some(mut split) => loop {
+                    let (size, off) = match split.next() {
+                        Some(next) => next,
+                        None => break Ok(split.done()),
+                    };
+                    let res = fs
+                        .async_write(
+                            ctx,
+                            idata.ino(),
+                            handle,
+                            r,
+                            size,
+                            offset + off,
+                            lock_owner,
+                            delayed_write,
+                            flags,
+                            fuse_flags,
+                        )
+                        .await;
+                    if let Err(e) = split.complete(res) {
+                        break Err(e);
+                    }
+                },
+            },
+        };
+        self.modified(inode, res)
     }
 
     async fn async_fsync(
@@ -165,7 +230,8 @@ impl AsyncFileSystem for Vfs {
         datasync: bool,
         handle: <Self as FileSystem>::Handle,
     ) -> Result<()> {
-        match self.get_real_rootfs(inode)? {
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
+        match self.get_handle_rootfs(inode)? {
             (Left(fs), idata) => fs.fsync(ctx, idata.ino(), datasync, handle),
             (Right(fs), idata) => fs.async_fsync(ctx, idata.ino(), datasync, handle).await,
         }
@@ -180,13 +246,15 @@ impl AsyncFileSystem for Vfs {
         offset: u64,
         length: u64,
     ) -> Result<()> {
-        match self.get_real_rootfs(inode)? {
+        let ctx = &self.mount_ctx(ctx, inode, true)?;
+        let res = match self.get_handle_rootfs(inode)? {
             (Left(fs), idata) => fs.fallocate(ctx, idata.ino(), handle, mode, offset, length),
             (Right(fs), idata) => {
                 fs.async_fallocate(ctx, idata.ino(), handle, mode, offset, length)
                     .await
             }
-        }
+        };
+        self.modified(inode, res)
     }
 
     async fn async_fsyncdir(
@@ -196,11 +264,30 @@ impl AsyncFileSystem for Vfs {
         datasync: bool,
         handle: <Self as FileSystem>::Handle,
     ) -> Result<()> {
-        match self.get_real_rootfs(inode)? {
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
+        match self.get_handle_rootfs(inode)? {
             (Left(fs), idata) => fs.fsyncdir(ctx, idata.ino(), datasync, handle),
             (Right(fs), idata) => fs.async_fsyncdir(ctx, idata.ino(), datasync, handle).await,
         }
     }
+
+    async fn async_syncfs(&self, ctx: &Context, inode: <Self as FileSystem>::Inode) -> Result<()> {
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
+        match self.get_real_rootfs(inode)? {
+            // Syncing the pseudo fs syncs all backends, failing with the first error.
+            (Left(_), _) => {
+                let mut res = Ok(());
+                for (fs, ino) in self.mounted_roots() {
+                    match fs.async_syncfs(ctx, ino).await {
+                        Err(e) if res.is_ok() && errno_of(&e) != Some(libc::ENOSYS) => res = Err(e),
+                        _ => {}
+                    }
+                }
+                res
+            }
+            (Right(fs), idata) => fs.async_syncfs(ctx, idata.ino()).await,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -210,21 +297,17 @@ mod tests {
     use crate::api::Vfs;
 
     use std::ffi::CString;
+    use std::sync::atomic::AtomicUsize;
 
     #[test]
     fn test_vfs_async_lookup() {
         let vfs = Vfs::new(VfsOptions::default());
         let fs = FakeFileSystemOne {};
-        let ctx = Context {
-            uid: 0,
-            gid: 0,
-            pid: 0,
-        };
-        let executor = futures::executor::ThreadPool::new().unwrap();
+        let ctx = Context::default();
 
         assert!(vfs.mount(Box::new(fs), "/x/y").is_ok());
 
-        executor.spawn_ok(async move {
+        futures::executor::block_on(async move {
             // Lookup inode on pseudo file system.
             let name = CString::new("x").unwrap();
             let future = vfs.async_lookup(&ctx, ROOT_ID.into(), name.as_c_str());
@@ -254,4 +337,213 @@ mod tests {
             assert_eq!(entry3.inode, 0);
         });
     }
+
+    // A backend only answering asynchronous requests, counting them.
+    #[derive(Default)]
+    struct AsyncOnlyFs {
+        calls: AtomicUsize,
+    }
+
+    impl AsyncOnlyFs {
+        fn attr(ino: u64) -> libc::stat64 {
+            let mut attr: libc::stat64 = unsafe { std::mem::zeroed() };
+            attr.st_ino = ino;
+            attr.st_mode = libc::S_IFREG | 0o644;
+            attr.st_size = 42;
+            attr
+        }
+    }
+
+    impl FileSystem for AsyncOnlyFs {
+        type Inode = u64;
+        type Handle = u64;
+    }
+
+    impl BackendFileSystem for AsyncOnlyFs {
+        fn mount(&self) -> Result<(Entry, u64)> {
+            let entry = Entry {
+                inode: ROOT_ID,
+                attr: Self::attr(ROOT_ID),
+                ..Default::default()
+            };
+            Ok((entry, 10))
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[allow(unused_variables)]
+    #[async_trait]
+    impl AsyncFileSystem for AsyncOnlyFs {
+        async fn async_lookup(&self, ctx: &Context, parent: u64, name: &CStr) -> Result<Entry> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            Ok(Entry {
+                inode: 5,
+                attr: Self::attr(5),
+                ..Default::default()
+            })
+        }
+
+        async fn async_getattr(
+            &self,
+            ctx: &Context,
+            inode: u64,
+            handle: Option<u64>,
+        ) -> Result<(libc::stat64, Duration)> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            Ok((Self::attr(inode), Duration::from_secs(1)))
+        }
+
+        async fn async_setattr(
+            &self,
+            ctx: &Context,
+            inode: u64,
+            attr: libc::stat64,
+            handle: Option<u64>,
+            valid: SetattrValid,
+        ) -> Result<(libc::stat64, Duration)> {
+            unimplemented!()
+        }
+
+        async fn async_open(
+            &self,
+            ctx: &Context,
+            inode: u64,
+            flags: u32,
+            fuse_flags: u32,
+        ) -> Result<(Option<u64>, OpenOptions)> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            Ok((Some(inode + 100), OpenOptions::KEEP_CACHE))
+        }
+
+        async fn async_create(
+            &self,
+            ctx: &Context,
+            parent: u64,
+            name: &CStr,
+            args: CreateIn,
+        ) -> Result<(Entry, Option<u64>, OpenOptions)> {
+            unimplemented!()
+        }
+
+        async fn async_read(
+            &self,
+            ctx: &Context,
+            inode: u64,
+            handle: u64,
+            w: &mut (dyn AsyncZeroCopyWriter + Send),
+            size: u32,
+            offset: u64,
+            lock_owner: Option<u64>,
+            flags: u32,
+        ) -> Result<usize> {
+            unimplemented!()
+        }
+
+        async fn async_write(
+            &self,
+            ctx: &Context,
+            inode: u64,
+            handle: u64,
+            r: &mut (dyn AsyncZeroCopyReader + Send),
+            size: u32,
+            offset: u64,
+            lock_owner: Option<u64>,
+            delayed_write: bool,
+            flags: u32,
+            fuse_flags: u32,
+        ) -> Result<usize> {
+            unimplemented!()
+        }
+
+        async fn async_fsync(
+            &self,
+            ctx: &Context,
+            inode: u64,
+            datasync: bool,
+            handle: u64,
+        ) -> Result<()> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        async fn async_fallocate(
+            &self,
+            ctx: &Context,
+            inode: u64,
+            handle: u64,
+            mode: u32,
+            offset: u64,
+            length: u64,
+        ) -> Result<()> {
+            unimplemented!()
+        }
+
+        async fn async_fsyncdir(
+            &self,
+            ctx: &Context,
+            inode: u64,
+            datasync: bool,
+            handle: u64,
+        ) -> Result<()> {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn test_vfs_async_routing() {
+        let vfs = Vfs::new(VfsOptions {
+            no_open: false,
+            ..Default::default()
+        });
+        let ctx = Context::default();
+        let idx = vfs.mount(Box::new(AsyncOnlyFs::default()), "/a").unwrap();
+        let calls = || {
+            let fs = vfs.get_fs_by_idx(idx).unwrap();
+            let fs = fs.as_any().downcast_ref::<AsyncOnlyFs>().unwrap();
+            fs.calls.load(Ordering::Relaxed)
+        };
+
+        futures::executor::block_on(async {
+            // Pseudo fs inodes are answered inline without reaching the backend.
+            let (attr, _) = vfs.async_getattr(&ctx, ROOT_ID.into(), None).await.unwrap();
+            assert_eq!(attr.st_ino, ROOT_ID);
+            let name = CString::new("a").unwrap();
+            let root = vfs.async_lookup(&ctx, ROOT_ID.into(), &name).await.unwrap();
+            assert_eq!(calls(), 0);
+
+            // Requests on backend inodes are translated and routed by the super index.
+            let name = CString::new("f").unwrap();
+            let entry = vfs
+                .async_lookup(&ctx, root.inode.into(), &name)
+                .await
+                .unwrap();
+            assert_eq!(entry.inode >> VFS_INDEX_SHIFT, idx as u64);
+            assert_eq!(vfs.backend_of(entry.inode), Some(idx));
+            let (attr, _) = vfs
+                .async_getattr(&ctx, entry.inode.into(), None)
+                .await
+                .unwrap();
+            assert_eq!((attr.st_ino, attr.st_size), (5, 42));
+            let (handle, opts) = vfs
+                .async_open(&ctx, entry.inode.into(), libc::O_RDONLY as u32, 0)
+                .await
+                .unwrap();
+            assert_eq!((handle, opts), (Some(105), OpenOptions::KEEP_CACHE));
+            vfs.async_fsync(&ctx, entry.inode.into(), false, 105)
+                .await
+                .unwrap();
+            assert_eq!(calls(), 4);
+
+            // Inodes of umounted backends are stale.
+            vfs.umount("/a").unwrap();
+            let e = vfs
+                .async_getattr(&ctx, entry.inode.into(), None)
+                .await
+                .unwrap_err();
+            assert_eq!(e.raw_os_error(), Some(libc::ESTALE));
+        });
+    }
 }
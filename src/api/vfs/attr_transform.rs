@@ -0,0 +1,240 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Transformation of attributes returned by backend file systems.
+//!
+//! Backends composed behind the Vfs sometimes need a final say over the attributes the guest
+//! sees, to map every file to uid 0 or to clear setuid bits globally for example. An
+//! [AttrTransform] installed for a backend by [Vfs::set_attr_transform] rewrites the attributes
+//! of every entry and attribute reply of the backend, right before it leaves the Vfs. It's
+//! applied before the Vfs encodes the inode number of entries, and only gets the attributes, so
+//! it can't change how requests are routed.
+
+use std::collections::HashMap;
+use std::io::Result;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use super::{Vfs, VfsError, VfsIndex, VfsResult};
+use crate::abi::fuse_abi::{stat64, Statx};
+use crate::api::filesystem::Entry;
+
+/// Callback rewriting attributes returned by a backend file system.
+pub type AttrTransform = Arc<dyn Fn(&mut stat64) + Send + Sync>;
+
+impl Vfs {
+    /// Install or remove the attribute transformation for the backend file system mounted at
+    /// `path`.
+    ///
+    /// The transformation applies to attributes of entries replied to lookup, create, mknod,
+    /// mkdir, symlink, link and readdirplus, and to attributes replied to getattr, statx and
+    /// setattr. Only the fields of `stat64` are transformed in statx replies.
+    /// It gets removed when the backend is umounted.
+    pub fn set_attr_transform(
+        &self,
+        path: &str,
+        transform: Option<AttrTransform>,
+    ) -> VfsResult<()> {
+        // Serialize mount operations. Do not expect poisoned lock here.
+        let _guard = self.lock.lock().unwrap();
+        let fs_idx = self.mounted_fs_idx(path)?;
+
+        let mut transforms = self.attr_transforms.load().deref().deref().clone();
+        match transform {
+            Some(t) => transforms.insert(fs_idx, t),
+            None => transforms.remove(&fs_idx),
+        };
+        self.attr_transforms.store(Arc::new(transforms));
+        // Cached entries carry attributes transformed by the previous transformation.
+        if let Some(cache) = self.lookup_cache.as_ref() {
+            cache.evict_fs(fs_idx);
+        }
+
+        Ok(())
+    }
+
+    // Apply the attribute transformation of backend `fs_idx` to `attr`.
+    pub(super) fn transform_attr(&self, fs_idx: VfsIndex, attr: &mut stat64) {
+        let transforms = self.attr_transforms.load();
+        if let Some(transform) = transforms.get(&fs_idx) {
+            transform(attr);
+        }
+    }
+
+    // Apply the attribute transformation of backend `fs_idx` to the basic fields of `stx`.
+    pub(super) fn transform_statx(&self, fs_idx: VfsIndex, stx: &mut Statx) {
+        let transforms = self.attr_transforms.load();
+        if let Some(transform) = transforms.get(&fs_idx) {
+            let mut attr = stat64::from(*stx);
+            transform(&mut attr);
+            *stx = stx.with_stat(attr);
+        }
+    }
+
+    // Turn an entry returned by backend `fs_idx` into a Vfs entry.
+    pub(super) fn convert_entry(&self, fs_idx: VfsIndex, entry: &mut Entry) -> Result<()> {
+        if entry.inode != 0 {
+            self.transform_attr(fs_idx, &mut entry.attr);
+        }
+        self.override_entry_timeouts(fs_idx, entry);
+        entry.inode = self.convert_inode(fs_idx, entry.inode)?;
+        Ok(())
+    }
+
+    // Drop the attribute transformation of backend `fs_idx` once it's umounted.
+    pub(super) fn evict_attr_transform(&self, fs_idx: VfsIndex) {
+        if self.attr_transforms.load().contains_key(&fs_idx) {
+            let mut transforms: HashMap<VfsIndex, AttrTransform> =
+                self.attr_transforms.load().deref().deref().clone();
+            transforms.remove(&fs_idx);
+            self.attr_transforms.store(Arc::new(transforms));
+        }
+    }
+
+    // Get the index of the backend file system mounted at `path`, with `self.lock` held.
+    pub(super) fn mounted_fs_idx(&self, path: &str) -> VfsResult<VfsIndex> {
+        let inode = self
+            .root
+            .path_walk(path)
+            .map_err(VfsError::PathWalk)?
+            .ok_or_else(|| VfsError::NotFound(path.to_string()))?;
+        self.mountpoints
+            .load()
+            .get(&inode)
+            .map(|mnt| mnt.fs_idx)
+            .ok_or_else(|| VfsError::NotFound(path.to_string()))
+    }
+}
+
+#[cfg(all(test, not(feature = "async-io")))]
+mod tests {
+    use super::*;
+    use crate::abi::fuse_abi::{CreateIn, ROOT_ID};
+    use crate::api::filesystem::{Context, DirEntry, FileSystem, OpenOptions};
+    use crate::api::vfs::VfsInode;
+    use crate::api::BackendFileSystem;
+    use std::any::Any;
+    use std::ffi::{CStr, CString};
+    use std::time::Duration;
+
+    // Backend whose inodes are all setuid files, except the root.
+    struct SetuidFs;
+
+    impl SetuidFs {
+        fn entry(inode: u64) -> Entry {
+            let mut entry = Entry {
+                inode,
+                ..Default::default()
+            };
+            entry.attr.st_ino = inode;
+            entry.attr.st_mode = if inode == ROOT_ID {
+                libc::S_IFDIR | 0o755
+            } else {
+                libc::S_IFREG | libc::S_ISUID | 0o755
+            };
+            entry
+        }
+    }
+
+    impl FileSystem for SetuidFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn lookup(&self, _: &Context, _: u64, _: &CStr) -> Result<Entry> {
+            Ok(Self::entry(2))
+        }
+
+        fn getattr(&self, _: &Context, inode: u64, _: Option<u64>) -> Result<(stat64, Duration)> {
+            Ok((Self::entry(inode).attr, Duration::ZERO))
+        }
+
+        fn create(
+            &self,
+            _: &Context,
+            _: u64,
+            _: &CStr,
+            _: CreateIn,
+        ) -> Result<(Entry, Option<u64>, OpenOptions)> {
+            Ok((Self::entry(3), None, OpenOptions::empty()))
+        }
+
+        fn readdirplus(
+            &self,
+            _: &Context,
+            _: u64,
+            _: u64,
+            _: u32,
+            offset: u64,
+            add_entry: &mut dyn FnMut(DirEntry, Entry) -> Result<usize>,
+        ) -> Result<()> {
+            if offset == 0 {
+                let dir_entry = DirEntry {
+                    ino: 4,
+                    offset: 1,
+                    type_: libc::DT_REG as u32,
+                    name: b"f",
+                };
+                add_entry(dir_entry, Self::entry(4))?;
+            }
+            Ok(())
+        }
+    }
+
+    impl BackendFileSystem for SetuidFs {
+        fn mount(&self) -> Result<(Entry, u64)> {
+            Ok((Self::entry(ROOT_ID), 1 << 20))
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn test_attr_transform() {
+        let vfs = Vfs::default();
+        vfs.mount(Box::new(SetuidFs), "/a").unwrap();
+        let transform: AttrTransform = Arc::new(|attr: &mut stat64| {
+            attr.st_mode &= !(libc::S_ISUID | libc::S_ISGID);
+            attr.st_uid = 0;
+        });
+        assert!(vfs
+            .set_attr_transform("/b", Some(transform.clone()))
+            .is_err());
+        vfs.set_attr_transform("/a", Some(transform)).unwrap();
+
+        let ctx = Context::default();
+        let name = CString::new("f").unwrap();
+        let root = vfs
+            .lookup(&ctx, ROOT_ID.into(), &CString::new("a").unwrap())
+            .unwrap();
+        assert_eq!(root.attr.st_mode, libc::S_IFDIR | 0o755);
+
+        let entry = vfs.lookup(&ctx, root.inode.into(), &name).unwrap();
+        assert_eq!(entry.attr.st_mode, libc::S_IFREG | 0o755);
+        // The transformation doesn't affect inode numbers encoded by the Vfs.
+        let inode = VfsInode::from(entry.inode);
+        assert_eq!(inode.ino(), 2);
+        assert_eq!(inode.fs_idx(), VfsInode::from(root.inode).fs_idx());
+
+        let (attr, _) = vfs.getattr(&ctx, entry.inode.into(), None).unwrap();
+        assert_eq!(attr.st_mode, libc::S_IFREG | 0o755);
+
+        let args = CreateIn::default();
+        let (entry, _, _) = vfs.create(&ctx, root.inode.into(), &name, args).unwrap();
+        assert_eq!(entry.attr.st_mode, libc::S_IFREG | 0o755);
+
+        let mut modes = Vec::new();
+        vfs.readdirplus(&ctx, root.inode.into(), 0, 4096, 0, &mut |_, entry| {
+            modes.push(entry.attr.st_mode);
+            Ok(1)
+        })
+        .unwrap();
+        assert_eq!(modes, vec![libc::S_IFREG | 0o755]);
+
+        // Without transformation, attributes of the backend are passed through.
+        vfs.set_attr_transform("/a", None).unwrap();
+        let (attr, _) = vfs.getattr(&ctx, entry.inode.into(), None).unwrap();
+        assert_eq!(attr.st_mode, libc::S_IFREG | libc::S_ISUID | 0o755);
+    }
+}
@@ -0,0 +1,376 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Copy of file ranges across backend file systems.
+//!
+//! `FUSE_COPY_FILE_RANGE` carries two files which may belong to different backends, and a
+//! backend can't copy data into files of another one. So the Vfs pumps data between backends
+//! itself, reading chunks from the source backend into a bounce buffer and writing them to the
+//! destination backend, through their normal `read()` and `write()` methods.
+
+use std::cmp;
+use std::io::{self, Read, Write};
+
+use super::ArcBackFs;
+use crate::api::filesystem::{Context, ZeroCopyReader, ZeroCopyWriter};
+use crate::transport::{FileReadWriteVolatile, FileVolatileSlice};
+
+/// Max number of bytes read from the source backend at once.
+const COPY_CHUNK_SIZE: u64 = 0x20000;
+
+// Bounce buffer filled by the source backend and drained by the destination backend.
+struct BounceBuf {
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl BounceBuf {
+    fn clear(&mut self) {
+        self.buf.clear();
+        self.pos = 0;
+    }
+}
+
+impl Write for BounceBuf {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ZeroCopyWriter for BounceBuf {
+    fn write_from(
+        &mut self,
+        f: &mut dyn FileReadWriteVolatile,
+        count: usize,
+        off: u64,
+    ) -> io::Result<usize> {
+        let len = self.buf.len();
+        self.buf.resize(len + count, 0);
+        // Safe because `buf` has just been resized to hold `count` bytes after `len`, and the
+        // slice is dropped before `buf` is resized again.
+        let slice = unsafe { FileVolatileSlice::new(self.buf[len..].as_mut_ptr(), count) };
+        let res = f.read_at_volatile(slice, off);
+        self.buf.truncate(len + *res.as_ref().unwrap_or(&0));
+        res
+    }
+}
+
+impl Read for BounceBuf {
+    fn read(&mut self, data: &mut [u8]) -> io::Result<usize> {
+        let count = (&self.buf[self.pos..]).read(data)?;
+        self.pos += count;
+        Ok(count)
+    }
+}
+
+impl ZeroCopyReader for BounceBuf {
+    fn read_to(
+        &mut self,
+        f: &mut dyn FileReadWriteVolatile,
+        count: usize,
+        off: u64,
+    ) -> io::Result<usize> {
+        let count = cmp::min(count, self.buf.len() - self.pos);
+        // Safe because `count` is capped to the initialized bytes of `buf` after `pos`, which `f`
+        // only reads, and the slice doesn't outlive this call.
+        let slice = unsafe { FileVolatileSlice::new(self.buf[self.pos..].as_mut_ptr(), count) };
+        let written = f.write_at_volatile(slice, off)?;
+        if written == 0 && count > 0 {
+            return Err(io::Error::from(io::ErrorKind::WriteZero));
+        }
+        self.pos += written;
+        Ok(written)
+    }
+}
+
+/// A range of an open file of a backend file system.
+pub(super) struct FileRange<'a> {
+    pub fs: &'a ArcBackFs,
+    pub ino: u64,
+    pub handle: u64,
+    pub offset: u64,
+}
+
+// Return the number of bytes copied when failing in the middle of a copy, like write(2).
+fn partial(copied: u64, e: io::Error) -> io::Result<usize> {
+    if copied > 0 {
+        debug!("vfs: copy_file_range stops after {} bytes, {}", copied, e);
+        Ok(copied as usize)
+    } else {
+        Err(e)
+    }
+}
+
+/// Copy at most `len` bytes from `src` to `dst` through the read and write methods of their
+/// backends, return the number of bytes copied.
+pub(super) fn copy_range(
+    ctx: &Context,
+    src: FileRange<'_>,
+    dst: FileRange<'_>,
+    len: u64,
+) -> io::Result<usize> {
+    // The number of bytes copied is replied as u32.
+    let len = cmp::min(len, u32::MAX as u64);
+    let mut buf = BounceBuf {
+        buf: Vec::with_capacity(cmp::min(len, COPY_CHUNK_SIZE) as usize),
+        pos: 0,
+    };
+    let mut copied = 0u64;
+
+    while copied < len {
+        let size = cmp::min(len - copied, COPY_CHUNK_SIZE) as u32;
+        buf.clear();
+        let count = match src.fs.read(
+            ctx,
+            src.ino,
+            src.handle,
+            &mut buf,
+            size,
+            src.offset + copied,
+            None,
+            0,
+        ) {
+            Ok(0) => break,
+            Ok(count) => count,
+            Err(e) => return partial(copied, e),
+        };
+
+        let mut written = 0;
+        while written < count {
+            match dst.fs.write(
+                ctx,
+                dst.ino,
+                dst.handle,
+                &mut buf,
+                (count - written) as u32,
+                dst.offset + copied,
+                None,
+                false,
+                0,
+                0,
+            ) {
+                Ok(0) => return partial(copied, io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(n) => {
+                    written += n;
+                    copied += n as u64;
+                }
+                Err(e) => return partial(copied, e),
+            }
+        }
+    }
+
+    Ok(copied as usize)
+}
+
+#[cfg(all(test, not(feature = "async-io")))]
+mod tests {
+    use super::super::{Vfs, VfsIndex, VfsOptions};
+    use crate::api::filesystem::{Entry, FileSystem};
+    use crate::api::BackendFileSystem;
+    use std::any::Any;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    // In memory file system, whose handles are the inode numbers of the files.
+    #[derive(Default)]
+    struct MemFs {
+        files: Mutex<HashMap<u64, Vec<u8>>>,
+        // Fail writes after writing this number of bytes.
+        write_limit: Option<usize>,
+        written: Mutex<usize>,
+    }
+
+    impl FileSystem for MemFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn read(
+            &self,
+            _: &Context,
+            inode: u64,
+            _: u64,
+            w: &mut dyn ZeroCopyWriter,
+            size: u32,
+            offset: u64,
+            _: Option<u64>,
+            _: u32,
+        ) -> io::Result<usize> {
+            let files = self.files.lock().unwrap();
+            let data = files
+                .get(&inode)
+                .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))?;
+            let start = cmp::min(offset as usize, data.len());
+            let end = cmp::min(start + size as usize, data.len());
+            w.write_all(&data[start..end])?;
+            Ok(end - start)
+        }
+
+        fn write(
+            &self,
+            _: &Context,
+            inode: u64,
+            _: u64,
+            r: &mut dyn ZeroCopyReader,
+            size: u32,
+            offset: u64,
+            _: Option<u64>,
+            _: bool,
+            _: u32,
+            _: u32,
+        ) -> io::Result<usize> {
+            let mut written = self.written.lock().unwrap();
+            let mut size = size as usize;
+            if let Some(limit) = self.write_limit {
+                if *written >= limit {
+                    return Err(io::Error::from_raw_os_error(libc::EIO));
+                }
+                // Short writes at the limit.
+                size = cmp::min(size, limit - *written);
+            }
+            let mut buf = vec![0u8; size];
+            r.read_exact(&mut buf)?;
+
+            let mut files = self.files.lock().unwrap();
+            let data = files
+                .get_mut(&inode)
+                .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))?;
+            let end = offset as usize + size;
+            if data.len() < end {
+                data.resize(end, 0);
+            }
+            data[offset as usize..end].copy_from_slice(&buf);
+            *written += size;
+            Ok(size)
+        }
+    }
+
+    impl BackendFileSystem for MemFs {
+        fn mount(&self) -> io::Result<(Entry, u64)> {
+            let entry = Entry {
+                inode: 1,
+                ..Default::default()
+            };
+            Ok((entry, 0))
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    fn ino(idx: VfsIndex, ino: u64) -> u64 {
+        ((idx as u64) << 56) | ino
+    }
+
+    fn prepare_vfs(content: &[u8], write_limit: Option<usize>) -> (Vfs, u64, u64) {
+        let src = MemFs::default();
+        src.files.lock().unwrap().insert(2, content.to_vec());
+        let dst = MemFs {
+            write_limit,
+            ..Default::default()
+        };
+        dst.files.lock().unwrap().insert(2, b"dst".to_vec());
+        dst.files.lock().unwrap().insert(3, Vec::new());
+
+        let vfs = Vfs::new(VfsOptions::default());
+        let a = vfs.mount(Box::new(src), "/a").unwrap();
+        let b = vfs.mount(Box::new(dst), "/b").unwrap();
+        (vfs, ino(a, 2), ino(b, 2))
+    }
+
+    fn dst_content(vfs: &Vfs, inode: u64) -> Vec<u8> {
+        let fs = vfs.get_rootfs("/b").unwrap().unwrap();
+        let fs = fs.as_any().downcast_ref::<MemFs>().unwrap();
+        let files = fs.files.lock().unwrap();
+        files.get(&(inode & 0xff)).unwrap().clone()
+    }
+
+    #[test]
+    fn test_vfs_copy_range_across_backends() {
+        let content: Vec<u8> = (0..0x50000u32).map(|v| (v % 251) as u8).collect();
+        let (vfs, src, dst) = prepare_vfs(&content, None);
+        let ctx = Context::default();
+        let handle = |inode: u64| inode & 0xff;
+
+        // Copy in chunks, stopping at the end of the source file.
+        let count = vfs
+            .copyfilerange(
+                &ctx,
+                src.into(),
+                handle(src),
+                0x100,
+                dst.into(),
+                handle(dst),
+                3,
+                u64::MAX,
+                0,
+            )
+            .unwrap();
+        assert_eq!(count, 0x50000 - 0x100);
+        let data = dst_content(&vfs, dst);
+        assert_eq!(&data[..3], b"dst");
+        assert_eq!(&data[3..], &content[0x100..]);
+
+        // Honor the length.
+        let count = vfs
+            .copyfilerange(
+                &ctx,
+                src.into(),
+                handle(src),
+                0,
+                dst.into(),
+                handle(dst),
+                0,
+                10,
+                0,
+            )
+            .unwrap();
+        assert_eq!(count, 10);
+        assert_eq!(&dst_content(&vfs, dst)[..10], &content[..10]);
+
+        // Backends without copy_file_range() support are pumped too.
+        let dst2 = (dst & !0xff) | 3;
+        let expected = dst_content(&vfs, dst)[..16].to_vec();
+        let count = vfs
+            .copyfilerange(&ctx, dst.into(), 2, 0, dst2.into(), 3, 0, 16, 0)
+            .unwrap();
+        assert_eq!(count, 16);
+        assert_eq!(dst_content(&vfs, dst2), expected);
+
+        let err = vfs
+            .copyfilerange(&ctx, src.into(), 2, 0, dst.into(), 2, 0, 10, 1)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        let err = vfs
+            .copyfilerange(&ctx, 1.into(), 0, 0, dst.into(), 2, 0, 10, 0)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn test_vfs_copy_range_partial() {
+        let content = vec![0x5au8; 0x50000];
+        let (vfs, src, dst) = prepare_vfs(&content, Some(0x30010));
+        let ctx = Context::default();
+
+        // Bytes copied before the failure are reported.
+        let count = vfs
+            .copyfilerange(&ctx, src.into(), 2, 0, dst.into(), 2, 0, 0x50000, 0)
+            .unwrap();
+        assert_eq!(count, 0x30010);
+        assert_eq!(dst_content(&vfs, dst), &content[..0x30010]);
+
+        // The error is reported if nothing has been copied.
+        let err = vfs
+            .copyfilerange(&ctx, src.into(), 2, 0, dst.into(), 2, 0, 0x50000, 0)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    }
+}
@@ -0,0 +1,322 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Umounting backend file systems while the guest is using them.
+//!
+//! The guest may still cache dentries and inodes of a backend after it's umounted from the Vfs,
+//! and keep files open on it. Requests on such stale inodes behave the same for all opcodes:
+//!
+//! - requests addressing an inode, like `getattr` or `lookup`, fail with `ESTALE`;
+//! - requests addressing an open handle, like `read` or `release`, fail with `EBADF`;
+//! - forgets, which have no reply, are dropped.
+//!
+//! [Vfs::umount_with_notifier] asks the guest to drop the dentry of the mountpoint, so the next
+//! lookup resolves it to the pseudo fs directory or to the backend mounted there since.
+//!
+//! The Vfs index of an umounted backend is not reused until the guest has forgotten all inodes it
+//! looked up from the backend. Otherwise forgets of the old inodes would arrive at a new backend
+//! mounted with the same index, and release lookups of unrelated inodes.
+
+use std::ffi::CString;
+use std::path::Path;
+use std::sync::atomic::AtomicU64;
+
+use super::*;
+
+// Lookup counts held by the guest for inodes of each backend.
+pub(super) struct GuestRefs {
+    refs: Vec<AtomicU64>,
+}
+
+impl Default for GuestRefs {
+    fn default() -> Self {
+        GuestRefs {
+            refs: (0..MAX_VFS_INDEX).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+}
+
+impl GuestRefs {
+    // Take a lookup of Vfs inode `ino`, which is ignored for pseudo fs inodes.
+    pub(super) fn get(&self, ino: u64) {
+        let inode = VfsInode(ino);
+        if ino != 0 && !inode.is_pseudo_fs() {
+            self.refs[inode.fs_idx() as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Release `count` lookups of Vfs inode `ino`.
+    pub(super) fn put(&self, ino: u64, count: u64) {
+        let inode = VfsInode(ino);
+        if ino != 0 && !inode.is_pseudo_fs() {
+            let refs = &self.refs[inode.fs_idx() as usize];
+            let _ = refs.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                Some(n.saturating_sub(count))
+            });
+        }
+    }
+
+    // Release all lookups, when the guest forgets all inodes.
+    pub(super) fn clear(&self) {
+        for refs in self.refs.iter() {
+            refs.store(0, Ordering::Relaxed);
+        }
+    }
+
+    // Get non-zero lookup counts as `(fs_idx, count)`, to be saved across live upgrade.
+    #[cfg(feature = "persist")]
+    pub(super) fn saved(&self) -> Vec<(VfsIndex, u64)> {
+        self.refs
+            .iter()
+            .enumerate()
+            .map(|(idx, refs)| (idx as VfsIndex, refs.load(Ordering::Relaxed)))
+            .filter(|(_, n)| *n > 0)
+            .collect()
+    }
+
+    // Restore a lookup count saved by `saved()`.
+    #[cfg(feature = "persist")]
+    pub(super) fn restore(&self, fs_idx: VfsIndex, count: u64) {
+        self.refs[fs_idx as usize].store(count, Ordering::Relaxed);
+    }
+
+    // Check whether the guest still holds inodes of backend `fs_idx`.
+    pub(super) fn busy(&self, fs_idx: VfsIndex) -> bool {
+        self.refs[fs_idx as usize].load(Ordering::Relaxed) > 0
+    }
+}
+
+impl Vfs {
+    /// Umount the backend file system at `path`, and invalidate the mountpoint in the guest by
+    /// `notifier`.
+    ///
+    /// The dentry of the mountpoint is invalidated in its parent directory, and the attributes of
+    /// the mountpoint inode are invalidated. For a backend mounted at "/" only the latter is
+    /// possible, so the guest may keep using cached dentries under the root until they expire.
+    pub fn umount_with_notifier(&self, path: &str, notifier: &dyn Notifier) -> VfsResult<()> {
+        let inode = self
+            .root
+            .path_walk(path)
+            .map_err(VfsError::PathWalk)?
+            .ok_or_else(|| VfsError::NotFound(path.to_string()))?;
+        let root_entry = self.mountpoints.load().get(&inode).map(|m| m.root_entry);
+        let parent = self.mountpoint_parent(path);
+
+        self.umount(path)?;
+
+        if let Some((parent, name)) = parent {
+            notifier.inval_entry(parent, &name);
+        }
+        notifier.inval_inode(inode);
+        if let Some(entry) = root_entry.filter(|e| e.inode != inode) {
+            notifier.inval_inode(entry.inode);
+        }
+
+        Ok(())
+    }
+
+    // Pass a readdirplus entry to `add_entry`, releasing the lookup taken for it if the entry
+    // doesn't reach the guest.
+    pub(super) fn readdirplus_entry(
+        &self,
+        dir_entry: DirEntry,
+        entry: Entry,
+        add_entry: &mut dyn FnMut(DirEntry, Entry) -> Result<usize>,
+    ) -> Result<usize> {
+        let ino = entry.inode;
+        let res = add_entry(dir_entry, entry);
+        if !matches!(res, Ok(n) if n > 0) {
+            self.inode_refs.put(ino, 1);
+        }
+        res
+    }
+
+    // Get the pseudo fs inode of the parent directory of mountpoint `path`, and its name.
+    fn mountpoint_parent(&self, path: &str) -> Option<(u64, CString)> {
+        let path = Path::new(path);
+        let name = CString::new(path.file_name()?.to_str()?).ok()?;
+        let parent = self.root.path_walk(path.parent()?.to_str()?).ok()??;
+        Some((parent, name))
+    }
+}
+
+#[cfg(all(test, not(feature = "async-io")))]
+mod tests {
+    use super::*;
+    use crate::api::filesystem::VecWriter;
+    use std::ffi::CStr;
+
+    #[test]
+    fn test_guest_refs() {
+        let refs = GuestRefs::default();
+        let ino = |fs_idx: u64, ino: u64| (fs_idx << VFS_INDEX_SHIFT) | ino;
+
+        refs.get(ino(1, 2));
+        refs.get(ino(1, 3));
+        refs.get(ino(2, 3));
+        // Pseudo fs inodes and negative entries aren't counted.
+        refs.get(5);
+        refs.get(0);
+        assert!(refs.busy(1));
+        assert!(refs.busy(2));
+        assert!(!refs.busy(0));
+
+        refs.put(ino(1, 2), 1);
+        assert!(refs.busy(1));
+        refs.put(ino(1, 3), 5);
+        assert!(!refs.busy(1));
+        refs.clear();
+        assert!(!refs.busy(2));
+    }
+
+    #[derive(Default)]
+    struct RecordingNotifier(Mutex<Vec<String>>);
+
+    impl Notifier for RecordingNotifier {
+        fn inval_inode(&self, ino: u64) {
+            self.0.lock().unwrap().push(format!("inode {}", ino));
+        }
+
+        fn inval_entry(&self, parent: u64, name: &CStr) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("entry {} {:?}", parent, name));
+        }
+    }
+
+    struct RootFs;
+
+    impl FileSystem for RootFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn lookup(&self, _: &Context, _: u64, _: &CStr) -> Result<Entry> {
+            Ok(Entry {
+                inode: 2,
+                ..Default::default()
+            })
+        }
+
+        fn getattr(&self, _: &Context, _: u64, _: Option<u64>) -> Result<(stat64, Duration)> {
+            Ok((unsafe { std::mem::zeroed() }, Duration::from_secs(1)))
+        }
+    }
+
+    impl BackendFileSystem for RootFs {
+        fn mount(&self) -> Result<(Entry, u64)> {
+            Ok((
+                Entry {
+                    inode: 1,
+                    ..Default::default()
+                },
+                VFS_MAX_INO,
+            ))
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn test_hot_umount() {
+        let vfs = Vfs::new(VfsOptions::default());
+        let ctx = Context::default();
+        let name = CString::new("y").unwrap();
+        let file = CString::new("f").unwrap();
+
+        let idx = vfs.mount(Box::new(RootFs), "/x/y").unwrap();
+        let x = vfs.root.path_walk("/x").unwrap().unwrap();
+        let y = vfs.root.path_walk("/x/y").unwrap().unwrap();
+        let root = vfs.lookup(&ctx, x.into(), &name).unwrap().inode;
+        let f = vfs.lookup(&ctx, root.into(), &file).unwrap().inode;
+        assert_eq!(VfsInode(f).fs_idx(), idx);
+
+        let notifier = RecordingNotifier::default();
+        vfs.umount_with_notifier("/x/y", &notifier).unwrap();
+        assert_eq!(
+            *notifier.0.lock().unwrap(),
+            vec![
+                format!("entry {} \"y\"", x),
+                format!("inode {}", y),
+                format!("inode {}", root),
+            ]
+        );
+
+        // Stale inodes fail consistently, the mountpoint resolves to the pseudo fs.
+        for ino in [root, f] {
+            let err = vfs.getattr(&ctx, ino.into(), None).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::ESTALE));
+        }
+        assert_eq!(vfs.lookup(&ctx, x.into(), &name).unwrap().inode, y);
+
+        // The index isn't reused until the guest forgets the inodes of the old backend.
+        let idx2 = vfs.mount(Box::new(RootFs), "/x/y").unwrap();
+        assert_ne!(idx2, idx);
+        vfs.forget(&ctx, root.into(), 1);
+        vfs.forget(&ctx, f.into(), 1);
+        assert!(!vfs.inode_refs.busy(idx));
+        vfs.umount("/x/y").unwrap();
+        vfs.next_super.store(idx, Ordering::SeqCst);
+        assert_eq!(vfs.mount(Box::new(RootFs), "/x/y").unwrap(), idx);
+    }
+
+    #[test]
+    fn test_stale_requests() {
+        let vfs = Vfs::new(VfsOptions::default());
+        let ctx = Context::default();
+        let name = CString::new("y").unwrap();
+        let file = CString::new("f").unwrap();
+
+        let idx = vfs.mount(Box::new(RootFs), "/x/y").unwrap();
+        let x = vfs.root.path_walk("/x").unwrap().unwrap();
+        let root = vfs.lookup(&ctx, x.into(), &name).unwrap().inode;
+        let f = vfs.lookup(&ctx, root.into(), &file).unwrap().inode;
+        vfs.umount("/x/y").unwrap();
+
+        // Requests on inodes fail with ESTALE.
+        let errno = |res: Result<()>| res.unwrap_err().raw_os_error();
+        assert_eq!(
+            errno(vfs.getattr(&ctx, f.into(), None).map(|_| ())),
+            Some(libc::ESTALE)
+        );
+        assert_eq!(
+            errno(vfs.lookup(&ctx, root.into(), &file).map(|_| ())),
+            Some(libc::ESTALE)
+        );
+        assert_eq!(
+            errno(vfs.readlink(&ctx, f.into()).map(|_| ())),
+            Some(libc::ESTALE)
+        );
+
+        // Requests on handles fail with EBADF.
+        let mut buf = Vec::new();
+        let mut w = VecWriter::new(&mut buf);
+        assert_eq!(
+            errno(
+                vfs.read(&ctx, f.into(), 1, &mut w, 10, 0, None, 0)
+                    .map(|_| ())
+            ),
+            Some(libc::EBADF)
+        );
+        assert_eq!(
+            errno(vfs.fsync(&ctx, f.into(), false, 1)),
+            Some(libc::EBADF)
+        );
+        assert_eq!(
+            errno(vfs.release(&ctx, f.into(), 0, 1, false, false, None)),
+            Some(libc::EBADF)
+        );
+        assert_eq!(
+            errno(vfs.readdir(&ctx, root.into(), 1, 4096, 0, &mut |_| Ok(1))),
+            Some(libc::EBADF)
+        );
+
+        // Forgets are dropped, and release the Vfs index.
+        assert!(vfs.inode_refs.busy(idx));
+        vfs.forget(&ctx, f.into(), 1);
+        vfs.batch_forget(&ctx, vec![(root.into(), 1)]);
+        assert!(!vfs.inode_refs.busy(idx));
+    }
+}
@@ -0,0 +1,267 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Activity tracking of backend file systems, to umount idle backends automatically.
+//!
+//! Each request routed to a backend file system stamps the backend with the current time of a
+//! coarse clock. The coarse clock is cached and only refreshed every [CLOCK_REFRESH_TICKS]
+//! requests or when idle backends are checked, so there's no syscall per request.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{Vfs, VfsIndex, MAX_VFS_INDEX};
+use crate::api::clock::Clock;
+
+// Refresh the coarse clock every `CLOCK_REFRESH_TICKS` routed requests, must be a power of two.
+const CLOCK_REFRESH_TICKS: u64 = 64;
+
+/// Callback invoked with the index and mount path of a backend which became idle.
+pub type IdleCallback = Arc<dyn Fn(&Vfs, VfsIndex, &str) + Send + Sync>;
+
+/// Policy to act on backend file systems idle for too long.
+#[derive(Clone)]
+pub struct IdlePolicy {
+    /// A backend without open handles and without requests for `threshold` is idle.
+    pub threshold: Duration,
+    /// Invoked once each time a backend becomes idle, it may umount the backend.
+    pub callback: IdleCallback,
+}
+
+pub(crate) struct IdleTracker {
+    clock: Arc<dyn Clock>,
+    // Cached time of the coarse clock, in milliseconds.
+    cached: AtomicU64,
+    ticks: AtomicU64,
+    last_active: Vec<AtomicU64>,
+    open_handles: Vec<AtomicU64>,
+    // Whether the idle policy has fired since the last activity.
+    fired: Vec<AtomicBool>,
+}
+
+impl IdleTracker {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Self {
+        let tracker = IdleTracker {
+            clock,
+            cached: AtomicU64::new(0),
+            ticks: AtomicU64::new(0),
+            last_active: (0..MAX_VFS_INDEX).map(|_| AtomicU64::new(0)).collect(),
+            open_handles: (0..MAX_VFS_INDEX).map(|_| AtomicU64::new(0)).collect(),
+            fired: (0..MAX_VFS_INDEX).map(|_| AtomicBool::new(false)).collect(),
+        };
+        tracker.refresh();
+        tracker
+    }
+
+    fn refresh(&self) -> u64 {
+        let now = self.clock.monotonic().as_millis() as u64;
+        self.cached.fetch_max(now, Ordering::Relaxed);
+        self.cached.load(Ordering::Relaxed)
+    }
+
+    /// Reset the state of backend `idx` when it gets mounted.
+    pub(crate) fn mounted(&self, idx: VfsIndex) {
+        let now = self.refresh();
+        self.last_active[idx as usize].store(now, Ordering::Relaxed);
+        self.open_handles[idx as usize].store(0, Ordering::Relaxed);
+        self.fired[idx as usize].store(false, Ordering::Relaxed);
+    }
+
+    /// Record a request routed to backend `idx`.
+    pub(crate) fn touch(&self, idx: VfsIndex) {
+        let ticks = self.ticks.fetch_add(1, Ordering::Relaxed);
+        let now = if ticks & (CLOCK_REFRESH_TICKS - 1) == 0 {
+            self.refresh()
+        } else {
+            self.cached.load(Ordering::Relaxed)
+        };
+        self.last_active[idx as usize].store(now, Ordering::Relaxed);
+        if self.fired[idx as usize].load(Ordering::Relaxed) {
+            self.fired[idx as usize].store(false, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn open(&self, idx: VfsIndex) {
+        self.open_handles[idx as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn release(&self, idx: VfsIndex) {
+        let _ = self.open_handles[idx as usize].fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |v| v.checked_sub(1),
+        );
+    }
+
+    pub(crate) fn idle_time(&self, idx: VfsIndex) -> Duration {
+        let now = self.refresh();
+        let last = self.last_active[idx as usize].load(Ordering::Relaxed);
+        Duration::from_millis(now.saturating_sub(last))
+    }
+
+    /// Check whether backend `idx` has just become idle according to `threshold`.
+    pub(crate) fn became_idle(&self, idx: VfsIndex, threshold: Duration) -> bool {
+        self.open_handles[idx as usize].load(Ordering::Relaxed) == 0
+            && self.idle_time(idx) >= threshold
+            && !self.fired[idx as usize].swap(true, Ordering::Relaxed)
+    }
+}
+
+impl Vfs {
+    /// Act on backend file systems idle for too long, when checked by [Vfs::check_idle].
+    pub fn with_idle_policy(mut self, policy: IdlePolicy) -> Self {
+        self.idle_policy = Some(policy);
+        self
+    }
+
+    /// Get the time elapsed since the last request routed to the backend file system `index`.
+    ///
+    /// Return `None` if there's no backend mounted at `index`.
+    pub fn idle_time(&self, index: VfsIndex) -> Option<Duration> {
+        self.superblocks.load()[index as usize]
+            .as_ref()
+            .map(|_| self.idle.idle_time(index))
+    }
+
+    /// Invoke the callback of the idle policy for backend file systems which have become idle,
+    /// it should be called periodically.
+    ///
+    /// The callback is invoked once per idle period, activity of the backend starts a new one.
+    pub fn check_idle(&self) {
+        let policy = match self.idle_policy.as_ref() {
+            Some(p) => p,
+            None => return,
+        };
+
+        let idle: Vec<(VfsIndex, String)> = self
+            .mountpoints
+            .load()
+            .values()
+            .filter(|mnt| self.idle.became_idle(mnt.fs_idx, policy.threshold))
+            .map(|mnt| (mnt.fs_idx, mnt.path.clone()))
+            .collect();
+        for (idx, path) in idle {
+            info!("vfs: backend {} at {} is idle", idx, path);
+            (policy.callback)(self, idx, &path);
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "async-io")))]
+mod tests {
+    use super::*;
+    use crate::api::clock::ManualClock;
+    use crate::api::filesystem::{Context, Entry, FileSystem};
+    use crate::api::BackendFileSystem;
+    use std::any::Any;
+    use std::ffi::CStr;
+    use std::io::Result;
+    use std::sync::Mutex;
+
+    struct IdleFs;
+
+    impl FileSystem for IdleFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn lookup(&self, _: &Context, _: u64, _: &CStr) -> Result<Entry> {
+            Ok(Entry::default())
+        }
+
+        fn open(
+            &self,
+            _: &Context,
+            _: u64,
+            _: u32,
+            _: u32,
+        ) -> Result<(Option<u64>, crate::api::filesystem::OpenOptions)> {
+            Ok((Some(1), crate::api::filesystem::OpenOptions::empty()))
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        fn release(
+            &self,
+            _: &Context,
+            _: u64,
+            _: u32,
+            _: u64,
+            _: bool,
+            _: bool,
+            _: Option<u64>,
+        ) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl BackendFileSystem for IdleFs {
+        fn mount(&self) -> Result<(Entry, u64)> {
+            let entry = Entry {
+                inode: 1,
+                ..Default::default()
+            };
+            Ok((entry, 0))
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn test_vfs_idle_policy() {
+        let clock = Arc::new(ManualClock::default());
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let fired2 = fired.clone();
+        let opts = super::super::VfsOptions {
+            no_open: false,
+            ..Default::default()
+        };
+        let vfs = Vfs::new(opts)
+            .with_clock(clock.clone())
+            .with_idle_policy(IdlePolicy {
+                threshold: Duration::from_secs(10),
+                callback: Arc::new(move |_vfs: &Vfs, idx: VfsIndex, path: &str| {
+                    fired2.lock().unwrap().push((idx, path.to_string()));
+                }),
+            });
+        let idx = vfs.mount(Box::new(IdleFs), "/idle").unwrap();
+        let ctx = Context::default();
+        let ino = ((idx as u64) << 56) | 1;
+
+        assert_eq!(vfs.idle_time(idx), Some(Duration::from_secs(0)));
+        assert_eq!(vfs.idle_time(idx + 1), None);
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(vfs.idle_time(idx), Some(Duration::from_secs(5)));
+        vfs.check_idle();
+        assert!(fired.lock().unwrap().is_empty());
+
+        // Fires once per idle transition.
+        clock.advance(Duration::from_secs(5));
+        vfs.check_idle();
+        vfs.check_idle();
+        clock.advance(Duration::from_secs(60));
+        vfs.check_idle();
+        assert_eq!(*fired.lock().unwrap(), vec![(idx, "/idle".to_string())]);
+
+        // Activity starts a new idle period, open handles keep the backend busy.
+        vfs.idle.refresh();
+        let (fh, _) = vfs.open(&ctx, ino.into(), 0, 0).unwrap();
+        assert_eq!(vfs.idle_time(idx), Some(Duration::from_secs(0)));
+        clock.advance(Duration::from_secs(20));
+        vfs.check_idle();
+        assert_eq!(fired.lock().unwrap().len(), 1);
+
+        vfs.idle.refresh();
+        vfs.release(&ctx, ino.into(), 0, fh.unwrap(), false, false, None)
+            .unwrap();
+        clock.advance(Duration::from_secs(10));
+        vfs.check_idle();
+        vfs.check_idle();
+        assert_eq!(fired.lock().unwrap().len(), 2);
+
+        vfs.umount("/idle").unwrap();
+        assert_eq!(vfs.idle_time(idx), None);
+    }
+}
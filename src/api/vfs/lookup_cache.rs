@@ -0,0 +1,362 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Positive directory entry cache for hot path lookups through the Vfs.
+//!
+//! Entries are keyed by `(guest parent inode, name)` and store the `Entry` already rewritten
+//! into the Vfs inode namespace, so cache hits skip the backend file system entirely.
+//!
+//! Every `Entry` returned to the guest takes a lookup reference, which is eventually released
+//! by `FORGET`. Cache hits don't reach the backend, so the references they take are accumulated
+//! per inode as pending references instead. Forget requests are first applied to the pending
+//! references, and only the remaining count is forwarded to the backend. This keeps lookup counts
+//! of backends exact without an extra backend call on each hit.
+//!
+//! An entry is only served when the backend still holds references to the inode taken by lookups
+//! through the cache, otherwise the backend may have already released the inode.
+
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{CStr, CString};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::{VfsIndex, VFS_INDEX_SHIFT};
+use crate::api::clock::{Clock, SystemClock};
+use crate::api::filesystem::Entry;
+
+type CacheKey = (u64, CString);
+
+struct CachedEntry {
+    entry: Entry,
+    // Monotonic time of the clock after which the entry expires.
+    deadline: Duration,
+    // Attribute version of the inode when the entry was cached.
+    version: u64,
+    // Insertion sequence number, to match eviction order records.
+    seq: u64,
+}
+
+#[derive(Default)]
+struct InodeRefs {
+    // Lookup references held on the backend, taken by lookups through the cache.
+    backend: u64,
+    // Lookup references taken by cache hits, not forwarded to the backend.
+    pending: u64,
+    // Bumped when attributes of the inode change, to invalidate cached entries.
+    version: u64,
+}
+
+#[derive(Default)]
+struct CacheInner {
+    entries: HashMap<CacheKey, CachedEntry>,
+    // Insertion order of entries, records may be stale if entries have been removed.
+    order: VecDeque<(CacheKey, u64)>,
+    refs: HashMap<u64, InodeRefs>,
+    seq: u64,
+}
+
+impl CacheInner {
+    fn evict(&mut self, capacity: usize) {
+        while self.entries.len() > capacity {
+            match self.order.pop_front() {
+                Some((key, seq)) => {
+                    if self.entries.get(&key).map(|e| e.seq) == Some(seq) {
+                        self.entries.remove(&key);
+                    }
+                }
+                None => break,
+            }
+        }
+
+        // Drop stale records so the order queue stays bounded.
+        if self.order.len() > capacity.saturating_mul(2) {
+            self.compact();
+        }
+    }
+
+    fn compact(&mut self) {
+        let entries = &self.entries;
+        self.order
+            .retain(|(key, seq)| entries.get(key).map(|e| e.seq) == Some(*seq));
+    }
+}
+
+/// Statistics of the Vfs lookup cache.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LookupCacheStats {
+    /// Number of cached entries.
+    pub entries: usize,
+    /// Number of inodes with lookup references tracked by the cache.
+    pub inodes: usize,
+    /// Total lookup references taken by cache hits and not yet released by the guest.
+    pub pending: u64,
+}
+
+pub(super) struct LookupCache {
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+    clock: Arc<dyn Clock>,
+}
+
+impl LookupCache {
+    pub(super) fn new(capacity: usize) -> Self {
+        LookupCache {
+            capacity,
+            inner: Mutex::new(CacheInner::default()),
+            clock: Arc::new(SystemClock::default()),
+        }
+    }
+
+    pub(super) fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Look up a cached entry, taking a pending lookup reference on hit.
+    pub(super) fn get(&self, parent: u64, name: &CStr) -> Option<Entry> {
+        let now = self.clock.monotonic();
+        let mut inner = self.inner.lock().unwrap();
+        let key = (parent, name.to_owned());
+        let cached = inner.entries.get(&key)?;
+        let (inode, version, deadline) = (cached.entry.inode, cached.version, cached.deadline);
+        let live = match inner.refs.get(&inode) {
+            Some(refs) => refs.backend > 0 && refs.version == version,
+            None => false,
+        };
+        if !live || deadline <= now {
+            inner.entries.remove(&key);
+            return None;
+        }
+
+        let mut entry = inner.entries[&key].entry;
+        // Don't extend the validity of the entry beyond what the backend granted.
+        let remaining = deadline - now;
+        entry.entry_timeout = entry.entry_timeout.min(remaining);
+        entry.attr_timeout = entry.attr_timeout.min(remaining);
+        if let Some(refs) = inner.refs.get_mut(&inode) {
+            refs.pending += 1;
+        }
+
+        Some(entry)
+    }
+
+    /// Account a lookup served by the backend, and cache the entry if it's cacheable.
+    pub(super) fn insert(&self, parent: u64, name: &CStr, entry: &Entry) {
+        if entry.inode == 0 {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let refs = inner.refs.entry(entry.inode).or_default();
+        refs.backend += 1;
+        let version = refs.version;
+
+        let ttl = entry.entry_timeout.min(entry.attr_timeout);
+        if ttl == Duration::from_secs(0) || self.capacity == 0 {
+            return;
+        }
+        inner.seq += 1;
+        let seq = inner.seq;
+        let key = (parent, name.to_owned());
+        inner.order.push_back((key.clone(), seq));
+        inner.entries.insert(
+            key,
+            CachedEntry {
+                entry: *entry,
+                // Saturate, backends may ask for effectively infinite timeouts.
+                deadline: self.clock.monotonic().saturating_add(ttl),
+                version,
+                seq,
+            },
+        );
+        inner.evict(self.capacity);
+    }
+
+    /// Account a forget request, and return the count to forward to the backend.
+    pub(super) fn forget(&self, inode: u64, count: u64) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        let refs = match inner.refs.get_mut(&inode) {
+            Some(refs) => refs,
+            None => return count,
+        };
+
+        let compensated = refs.pending.min(count);
+        refs.pending -= compensated;
+        let count = count - compensated;
+        // The backend may hold references not taken through the cache, so saturate.
+        refs.backend = refs.backend.saturating_sub(count);
+        if refs.backend == 0 && refs.pending == 0 {
+            inner.refs.remove(&inode);
+        }
+
+        count
+    }
+
+    /// Get the number of pending lookup references of `inode` taken by cache hits.
+    pub(super) fn pending(&self, inode: u64) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner.refs.get(&inode).map(|r| r.pending).unwrap_or(0)
+    }
+
+    /// Get lookup references of all inodes as `(inode, backend, pending)`, to be saved across
+    /// live upgrade. Cached entries are not saved.
+    #[cfg(feature = "persist")]
+    pub(super) fn saved_refs(&self) -> Vec<(u64, u64, u64)> {
+        let inner = self.inner.lock().unwrap();
+        let mut refs: Vec<_> = inner
+            .refs
+            .iter()
+            .map(|(ino, r)| (*ino, r.backend, r.pending))
+            .collect();
+        refs.sort_unstable();
+        refs
+    }
+
+    /// Restore lookup references of `inode` saved by `saved_refs()`.
+    #[cfg(feature = "persist")]
+    pub(super) fn restore_refs(&self, inode: u64, backend: u64, pending: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let refs = inner.refs.entry(inode).or_default();
+        refs.backend = backend;
+        refs.pending = pending;
+    }
+
+    /// Invalidate the cached entry for `name` under directory `parent`.
+    pub(super) fn invalidate(&self, parent: u64, name: &CStr) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.entries.remove(&(parent, name.to_owned()));
+    }
+
+    /// Invalidate all cached entries for `inode` because its attributes have changed.
+    pub(super) fn invalidate_inode(&self, inode: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(refs) = inner.refs.get_mut(&inode) {
+            refs.version += 1;
+        }
+    }
+
+    /// Drop all state of backend file system `fs_idx`, when it's going to be umounted.
+    pub(super) fn evict_fs(&self, fs_idx: VfsIndex) {
+        let same_fs = |ino: u64| (ino >> VFS_INDEX_SHIFT) as VfsIndex == fs_idx;
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .entries
+            .retain(|(parent, _), e| !same_fs(*parent) && !same_fs(e.entry.inode));
+        inner.refs.retain(|ino, _| !same_fs(*ino));
+    }
+
+    /// Drop entries of directory `parent`, keeping lookup references taken by cache hits.
+    pub(super) fn evict_dir(&self, parent: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.entries.retain(|(p, _), _| *p != parent);
+    }
+
+    /// Drop expired entries, and compact the eviction order queue.
+    pub(super) fn flush(&self) {
+        let now = self.clock.monotonic();
+        let mut inner = self.inner.lock().unwrap();
+        inner.entries.retain(|_, e| e.deadline > now);
+        inner.compact();
+    }
+
+    pub(super) fn stats(&self) -> LookupCacheStats {
+        let inner = self.inner.lock().unwrap();
+        LookupCacheStats {
+            entries: inner.entries.len(),
+            inodes: inner.refs.len(),
+            pending: inner.refs.values().map(|r| r.pending).sum(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(inode: u64, timeout: u64) -> Entry {
+        Entry {
+            inode,
+            attr_timeout: Duration::from_secs(timeout),
+            entry_timeout: Duration::from_secs(timeout),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_lookup_cache_refcount() {
+        let cache = LookupCache::new(16);
+        let name = CString::new("a").unwrap();
+
+        assert!(cache.get(1, &name).is_none());
+        cache.insert(1, &name, &entry(10, 5));
+        assert_eq!(cache.get(1, &name).unwrap().inode, 10);
+        assert_eq!(cache.get(1, &name).unwrap().inode, 10);
+        assert_eq!(cache.pending(10), 2);
+
+        // Guest holds 3 references, but only 1 reference on the backend.
+        assert_eq!(cache.forget(10, 2), 0);
+        assert_eq!(cache.pending(10), 0);
+        assert!(cache.get(1, &name).is_some());
+        assert_eq!(cache.forget(10, 2), 1);
+        assert_eq!(cache.stats().inodes, 0);
+
+        // The backend has released the inode, so the entry can't be served anymore.
+        assert!(cache.get(1, &name).is_none());
+        assert_eq!(cache.stats().entries, 0);
+
+        // Unknown inodes are forwarded as is.
+        assert_eq!(cache.forget(11, 3), 3);
+    }
+
+    #[test]
+    fn test_lookup_cache_invalidate() {
+        let cache = LookupCache::new(16);
+        let a = CString::new("a").unwrap();
+        let b = CString::new("b").unwrap();
+
+        cache.insert(1, &a, &entry(10, 5));
+        cache.invalidate(1, &a);
+        assert!(cache.get(1, &a).is_none());
+
+        cache.insert(1, &a, &entry(10, 5));
+        cache.invalidate_inode(10);
+        assert!(cache.get(1, &a).is_none());
+        cache.insert(1, &a, &entry(10, 5));
+        assert!(cache.get(1, &a).is_some());
+
+        // Entries with zero timeout or negative entries are not cached.
+        cache.insert(1, &b, &entry(11, 0));
+        assert!(cache.get(1, &b).is_none());
+        cache.insert(1, &b, &entry(0, 5));
+        assert!(cache.get(1, &b).is_none());
+
+        let fs2 = 2u64 << VFS_INDEX_SHIFT;
+        cache.insert(fs2 | 1, &a, &entry(fs2 | 10, 5));
+        cache.evict_fs(2);
+        assert!(cache.get(fs2 | 1, &a).is_none());
+        assert_eq!(cache.forget(fs2 | 10, 1), 1);
+        assert!(cache.get(1, &a).is_some());
+    }
+
+    #[test]
+    fn test_lookup_cache_capacity() {
+        let cache = LookupCache::new(2);
+        let names: Vec<CString> = (0..4)
+            .map(|i| CString::new(format!("f{}", i)).unwrap())
+            .collect();
+
+        for (i, name) in names.iter().enumerate() {
+            cache.insert(1, name, &entry(10 + i as u64, 5));
+        }
+        assert_eq!(cache.stats().entries, 2);
+        assert!(cache.get(1, &names[0]).is_none());
+        assert!(cache.get(1, &names[3]).is_some());
+
+        // Re-inserting an entry doesn't evict it through its stale order record.
+        cache.insert(1, &names[3], &entry(13, 5));
+        cache.insert(1, &names[1], &entry(11, 5));
+        assert!(cache.get(1, &names[3]).is_some());
+        cache.flush();
+        assert!(cache.inner.lock().unwrap().order.len() <= 2);
+    }
+}
@@ -16,11 +16,15 @@
 //! another virtio-fs device. This is very convenient to manage container images at runtime.
 
 use std::any::Any;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::CStr;
+#[cfg(feature = "persist")]
+use std::fs::File;
 use std::io;
 use std::io::{Error, ErrorKind, Result};
 use std::ops::Deref;
+#[cfg(feature = "persist")]
+use std::os::unix::io::RawFd;
 use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
@@ -28,12 +32,41 @@ use std::time::Duration;
 use arc_swap::ArcSwap;
 
 use crate::abi::fuse_abi::*;
+use crate::api::accounting::WriteAccounting;
+use crate::api::attr_cache::Notifier;
+use crate::api::clock::{Clock, SystemClock};
+use crate::api::errno::errno_of;
 use crate::api::filesystem::*;
 use crate::api::pseudo_fs::PseudoFs;
 
 #[cfg(feature = "async-io")]
 mod async_io;
+mod attr_transform;
+mod copy_range;
+mod hot_unmount;
+mod idle;
+mod lookup_cache;
+#[cfg(feature = "persist")]
+mod persist;
+mod readonly;
+mod shared_mount;
+mod split_io;
+mod statfs;
 mod sync_io;
+mod timeouts;
+
+pub use attr_transform::AttrTransform;
+use hot_unmount::GuestRefs;
+use idle::IdleTracker;
+pub use idle::{IdleCallback, IdlePolicy};
+use lookup_cache::LookupCache;
+pub use lookup_cache::LookupCacheStats;
+use readonly::ReadonlyTracker;
+pub use readonly::{ReadonlyCallback, ReadonlyPolicy};
+pub use shared_mount::MountOptions;
+use shared_mount::{ioctl_writes, open_writes, MountOrigins};
+pub use statfs::RootStatfs;
+pub use timeouts::BackendTimeouts;
 
 /// Current directory
 pub const CURRENT_DIR_CSTR: &[u8] = b".\0";
@@ -175,10 +208,68 @@ pub trait BackendFileSystem: FileSystem {
     /// the caller have access to the underlying type behind the
     /// trait.
     fn as_any(&self) -> &dyn Any;
+
+    /// Read `size` bytes at `offset` of `inode` and push them into the guest page cache of
+    /// `nodeid` through `notifier`, return the number of bytes pushed.
+    ///
+    /// `nodeid` is the inode number known by the guest, which differs from `inode` when the
+    /// file system is mounted under a Vfs.
+    #[allow(unused_variables)]
+    fn push_file_as(
+        &self,
+        notifier: &dyn Notifier,
+        inode: u64,
+        nodeid: u64,
+        offset: u64,
+        size: u64,
+    ) -> Result<u64> {
+        Err(Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Max number of bytes the file system handles in a read request, larger requests are split
+    /// by the Vfs. `None` means no limit other than the one negotiated with the kernel.
+    fn max_read(&self) -> Option<u32> {
+        None
+    }
+
+    /// Max number of bytes the file system handles in a write request, larger requests are split
+    /// by the Vfs. `None` means no limit other than the one negotiated with the kernel.
+    fn max_write(&self) -> Option<u32> {
+        None
+    }
+
+    /// Timeouts the Vfs replies to entry and attribute requests on the file system with,
+    /// instead of the ones it returns, until replaced by [Vfs::set_timeouts].
+    fn timeouts(&self) -> BackendTimeouts {
+        BackendTimeouts::default()
+    }
+
+    /// Encode the state of the file system to be saved with the Vfs by
+    /// [Vfs::save_with_backends], and the file descriptors to pass to the new daemon with it.
+    /// `None` if the file system has no state to save.
+    #[cfg(feature = "persist")]
+    fn save_state(&self) -> Result<Option<(Vec<u8>, Vec<RawFd>)>> {
+        Ok(None)
+    }
+
+    /// Restore a state encoded by `save_state()` of the file system of the old daemon, with
+    /// `files` passed in the same order, for [Vfs::restore_with_backends].
+    ///
+    /// On failure, the file system must be set up as a fresh one if possible, as it gets mounted
+    /// nevertheless.
+    #[cfg(feature = "persist")]
+    #[allow(unused_variables)]
+    fn restore_state(&self, state: &[u8], files: Vec<File>) -> Result<()> {
+        Err(Error::from_raw_os_error(libc::ENOSYS))
+    }
 }
 
 #[cfg(feature = "async-io")]
 /// BackendFileSystem abstracts all backend file systems under vfs
+///
+/// With `async-io`, the Vfs routes asynchronous requests to the asynchronous methods of backends,
+/// so backends must implement [AsyncFileSystem]. Backends without asynchronous IO may implement
+/// those methods by calling the synchronous ones, which then block the executor thread.
 pub trait BackendFileSystem: AsyncFileSystem {
     /// mount returns the backend file system root inode entry and
     /// the largest inode number it has.
@@ -190,16 +281,81 @@ pub trait BackendFileSystem: AsyncFileSystem {
     /// the caller have access to the underlying type behind the
     /// trait.
     fn as_any(&self) -> &dyn Any;
+
+    /// Read `size` bytes at `offset` of `inode` and push them into the guest page cache of
+    /// `nodeid` through `notifier`, return the number of bytes pushed.
+    ///
+    /// `nodeid` is the inode number known by the guest, which differs from `inode` when the
+    /// file system is mounted under a Vfs.
+    #[allow(unused_variables)]
+    fn push_file_as(
+        &self,
+        notifier: &dyn Notifier,
+        inode: u64,
+        nodeid: u64,
+        offset: u64,
+        size: u64,
+    ) -> Result<u64> {
+        Err(Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Max number of bytes the file system handles in a read request, larger requests are split
+    /// by the Vfs. `None` means no limit other than the one negotiated with the kernel.
+    fn max_read(&self) -> Option<u32> {
+        None
+    }
+
+    /// Max number of bytes the file system handles in a write request, larger requests are split
+    /// by the Vfs. `None` means no limit other than the one negotiated with the kernel.
+    fn max_write(&self) -> Option<u32> {
+        None
+    }
+
+    /// Timeouts the Vfs replies to entry and attribute requests on the file system with,
+    /// instead of the ones it returns, until replaced by [Vfs::set_timeouts].
+    fn timeouts(&self) -> BackendTimeouts {
+        BackendTimeouts::default()
+    }
+
+    /// Encode the state of the file system to be saved with the Vfs by
+    /// [Vfs::save_with_backends], and the file descriptors to pass to the new daemon with it.
+    /// `None` if the file system has no state to save.
+    #[cfg(feature = "persist")]
+    fn save_state(&self) -> Result<Option<(Vec<u8>, Vec<RawFd>)>> {
+        Ok(None)
+    }
+
+    /// Restore a state encoded by `save_state()` of the file system of the old daemon, with
+    /// `files` passed in the same order, for [Vfs::restore_with_backends].
+    ///
+    /// On failure, the file system must be set up as a fresh one if possible, as it gets mounted
+    /// nevertheless.
+    #[cfg(feature = "persist")]
+    #[allow(unused_variables)]
+    fn restore_state(&self, state: &[u8], files: Vec<File>) -> Result<()> {
+        Err(Error::from_raw_os_error(libc::ENOSYS))
+    }
 }
 
 struct MountPointData {
     fs_idx: VfsIndex,
     ino: u64,
+    // Entry of the mount root, carrying the Vfs inode number of the root.
     root_entry: Entry,
-    _path: String,
+    path: String,
+    // Pseudo fs inode of the mountpoint.
+    key: u64,
+    opts: MountOptions,
+    // Whether the backend was already mounted at another path, the root is `key` then.
+    alias: bool,
 }
 
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(
+    feature = "config-serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
 /// vfs init options
 pub struct VfsOptions {
     /// Disable fuse open request handling. When enabled, fuse open
@@ -217,12 +373,41 @@ pub struct VfsOptions {
     /// to remove security.capability xattr and setuid/setgid bits. See details in
     /// comments for HANDLE_KILLPRIV_V2
     pub killpriv_v2: bool,
+    /// Capacity of the positive lookup cache for backend file systems, `0` disables the cache.
+    /// Cached entries are served without invoking the backend file system, until the entry
+    /// timeout or attribute timeout returned by the backend expires.
+    pub lookup_cache_size: usize,
+    /// Allocate inode numbers deterministically, for tests comparing replies across runs.
+    /// Backend file systems get the lowest free index in mount order. Inode numbers of pseudo fs
+    /// directories are derived from their paths, by the 64-bit FNV-1a hash of the absolute path
+    /// masked by `VFS_MAX_INO`, incremented until it's neither 0, `ROOT_ID` nor already used.
+    /// Only allocation choices change, not the wire format.
+    pub deterministic: bool,
+    /// Statistics replied to `statfs` on directories of the pseudo fs, see [RootStatfs].
+    pub root_statfs: RootStatfs,
     /// File system options passed in from client
+    #[cfg_attr(feature = "config-serde", serde(skip))]
     pub in_opts: FsOptions,
     /// File system options returned to client
+    #[cfg_attr(feature = "config-serde", serde(with = "fs_options_bits"))]
     pub out_opts: FsOptions,
 }
 
+// Serialize `FsOptions` as their bits. Unknown bits are dropped on load, so that configs written
+// by newer versions still load.
+#[cfg(feature = "config-serde")]
+mod fs_options_bits {
+    use super::FsOptions;
+
+    pub fn serialize<S: serde::Serializer>(opts: &FsOptions, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u64(opts.bits())
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(d: D) -> Result<FsOptions, D::Error> {
+        <u64 as serde::Deserialize>::deserialize(d).map(FsOptions::from_bits_truncate)
+    }
+}
+
 impl VfsOptions {
     fn new() -> Self {
         VfsOptions::default()
@@ -237,6 +422,9 @@ impl Default for VfsOptions {
             no_writeback: false,
             no_readdir: false,
             killpriv_v2: false,
+            lookup_cache_size: 0,
+            deterministic: false,
+            root_statfs: RootStatfs::Pseudo,
             in_opts: FsOptions::empty(),
             out_opts: FsOptions::ASYNC_READ
                 | FsOptions::PARALLEL_DIROPS
@@ -254,7 +442,9 @@ impl Default for VfsOptions {
                 | FsOptions::EXPLICIT_INVAL_DATA
                 | FsOptions::ZERO_MESSAGE_OPENDIR
                 | FsOptions::HANDLE_KILLPRIV_V2
-                | FsOptions::PERFILE_DAX,
+                | FsOptions::PERFILE_DAX
+                | FsOptions::CREATE_SUPP_GROUP
+                | FsOptions::ATOMIC_OPEN,
         }
     }
 }
@@ -270,6 +460,27 @@ pub struct Vfs {
     opts: ArcSwap<VfsOptions>,
     initialized: AtomicBool,
     lock: Mutex<()>,
+    // indexes of superblocks already destroyed by `destroy()`, protected by `lock`
+    destroyed: Mutex<HashSet<VfsIndex>>,
+    lookup_cache: Option<LookupCache>,
+    // raw request handlers installed per backend file system
+    raw_handlers: ArcSwap<HashMap<VfsIndex, Arc<dyn RawFileSystem>>>,
+    // attribute transformations installed per backend file system
+    attr_transforms: ArcSwap<HashMap<VfsIndex, AttrTransform>>,
+    // timeouts overriding the ones returned per backend file system
+    timeouts: ArcSwap<HashMap<VfsIndex, BackendTimeouts>>,
+    // activity of backend file systems, to act on idle ones with `idle_policy`
+    idle: IdleTracker,
+    idle_policy: Option<IdlePolicy>,
+    // read-only state of backend file systems, flipped by operators or on `readonly_policy`
+    readonly: ReadonlyTracker,
+    readonly_policy: Option<ReadonlyPolicy>,
+    // mountpoints of inodes of backends mounted by `mount_shared()`
+    mount_origins: MountOrigins,
+    // lookups of backend inodes held by the guest, which pin Vfs indexes of umounted backends
+    inode_refs: GuestRefs,
+    // bytes copied through bounce buffers by `copyfilerange()`
+    write_accounting: Option<Arc<WriteAccounting>>,
 }
 
 impl Default for Vfs {
@@ -283,15 +494,50 @@ impl Vfs {
     pub fn new(opts: VfsOptions) -> Self {
         Vfs {
             next_super: AtomicU8::new((VFS_PSEUDO_FS_IDX + 1) as u8),
+            lookup_cache: if opts.lookup_cache_size > 0 {
+                Some(LookupCache::new(opts.lookup_cache_size))
+            } else {
+                None
+            },
             mountpoints: ArcSwap::new(Arc::new(HashMap::new())),
             superblocks: ArcSwap::new(Arc::new(vec![None; MAX_VFS_INDEX])),
-            root: PseudoFs::new(),
+            root: if opts.deterministic {
+                PseudoFs::new().with_path_inodes()
+            } else {
+                PseudoFs::new()
+            },
             opts: ArcSwap::new(Arc::new(opts)),
             lock: Mutex::new(()),
+            destroyed: Mutex::new(HashSet::new()),
+            raw_handlers: ArcSwap::new(Arc::new(HashMap::new())),
+            attr_transforms: ArcSwap::new(Arc::new(HashMap::new())),
+            timeouts: ArcSwap::new(Arc::new(HashMap::new())),
+            idle: IdleTracker::new(Arc::new(SystemClock::default())),
+            idle_policy: None,
+            readonly: ReadonlyTracker::new(),
+            readonly_policy: None,
+            mount_origins: MountOrigins::default(),
+            inode_refs: GuestRefs::default(),
+            write_accounting: None,
             initialized: AtomicBool::new(false),
         }
     }
 
+    /// Use `clock` to track activity of backend file systems and to expire lookup cache entries,
+    /// instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.lookup_cache = self.lookup_cache.map(|c| c.with_clock(clock.clone()));
+        self.idle = IdleTracker::new(clock);
+        self
+    }
+
+    /// Account bytes copied through bounce buffers of the Vfs in `acct`, when copying file ranges
+    /// across backends or for backends without `copyfilerange()`.
+    pub fn with_write_accounting(mut self, acct: Arc<WriteAccounting>) -> Self {
+        self.write_accounting = Some(acct);
+        self
+    }
+
     /// For sake of live-upgrade, only after negotiation is done, it's safe to persist
     /// state of vfs.
     pub fn initialized(&self) -> bool {
@@ -303,12 +549,104 @@ impl Vfs {
         *self.opts.load_full()
     }
 
+    /// Drop expired entries from the lookup cache, it should be called periodically.
+    pub fn flush_lookup_cache(&self) {
+        if let Some(cache) = self.lookup_cache.as_ref() {
+            cache.flush();
+        }
+    }
+
+    /// Get statistics of the lookup cache, or `None` if the lookup cache is disabled.
+    pub fn lookup_cache_stats(&self) -> Option<LookupCacheStats> {
+        self.lookup_cache.as_ref().map(|c| c.stats())
+    }
+
+    fn invalidate_entry(&self, parent: VfsInode, name: &CStr) {
+        if let Some(cache) = self.lookup_cache.as_ref() {
+            cache.invalidate(parent.0, name);
+        }
+    }
+
+    fn invalidate_attr(&self, inode: VfsInode) {
+        if let Some(cache) = self.lookup_cache.as_ref() {
+            cache.invalidate_inode(inode.0);
+        }
+    }
+
+    fn evict_cached_fs(&self, fs_idx: VfsIndex) {
+        if let Some(cache) = self.lookup_cache.as_ref() {
+            cache.evict_fs(fs_idx);
+        }
+        if self.raw_handlers.load().contains_key(&fs_idx) {
+            let mut handlers = self.raw_handlers.load().deref().deref().clone();
+            handlers.remove(&fs_idx);
+            self.raw_handlers.store(Arc::new(handlers));
+        }
+        self.evict_attr_transform(fs_idx);
+        self.evict_timeouts(fs_idx);
+        self.mount_origins.evict_fs(fs_idx);
+    }
+
+    // Drop cached entries of the root of `mnt` if it's represented by the pseudo fs inode, which
+    // may become the root of another backend.
+    fn evict_cached_root(&self, mnt: &MountPointData) {
+        if let Some(cache) = self.lookup_cache.as_ref() {
+            if mnt.alias {
+                cache.evict_dir(mnt.key);
+            }
+        }
+    }
+
+    /// Push `size` bytes at `offset` of guest inode `inode` into the guest page cache, to warm it
+    /// up before the guest reads them. Return the number of bytes pushed, which is less than
+    /// requested at the end of the file or if the guest doesn't have the inode cached.
+    pub fn push_file(
+        &self,
+        notifier: &dyn Notifier,
+        inode: u64,
+        offset: u64,
+        size: u64,
+    ) -> Result<u64> {
+        match self.get_real_rootfs(inode.into())? {
+            (Right(fs), idata) => fs.push_file_as(notifier, idata.ino(), inode, offset, size),
+            (Left(_), _) => Err(Error::from_raw_os_error(libc::EISDIR)),
+        }
+    }
+
+    /// Install or remove the raw request handler for the backend file system mounted at `path`.
+    ///
+    /// Requests targeting inodes of the backend are passed to the handler by the
+    /// [RawFileSystem] implementation of the Vfs, with `nodeid` of the request header
+    /// translated into the backend inode number. Inode numbers carried by replies are not
+    /// translated back, so the handler should only handle requests whose replies don't carry
+    /// inode numbers, for example `FUSE_READ` or `FUSE_WRITE`. The handler gets removed when the
+    /// backend is umounted.
+    pub fn set_raw_handler(
+        &self,
+        path: &str,
+        handler: Option<Arc<dyn RawFileSystem>>,
+    ) -> VfsResult<()> {
+        // Serialize mount operations. Do not expect poisoned lock here.
+        let _guard = self.lock.lock().unwrap();
+        let fs_idx = self.mounted_fs_idx(path)?;
+
+        let mut handlers = self.raw_handlers.load().deref().deref().clone();
+        match handler {
+            Some(h) => handlers.insert(fs_idx, h),
+            None => handlers.remove(&fs_idx),
+        };
+        self.raw_handlers.store(Arc::new(handlers));
+
+        Ok(())
+    }
+
     fn insert_mount_locked(
         &self,
-        fs: BackFileSystem,
+        fs: Arc<BackFileSystem>,
         mut entry: Entry,
         fs_idx: VfsIndex,
         path: &str,
+        opts: MountOptions,
     ) -> Result<()> {
         // The visibility of mountpoints and superblocks:
         // superblock should be committed first because it won't be accessed until
@@ -318,21 +656,43 @@ impl Vfs {
         let inode = self.root.mount(path)?;
         let real_root_ino = entry.inode;
 
-        entry.inode = self.convert_inode(fs_idx, entry.inode)?;
+        // Roots of further mountpoints of a backend are represented by the pseudo fs inode.
+        let alias = Self::is_shared(&mountpoints, inode, fs_idx);
+        entry.inode = if alias {
+            inode
+        } else {
+            self.convert_inode(fs_idx, entry.inode)?
+        };
 
         // Over mount would invalidate previous superblock inodes.
         if let Some(mnt) = mountpoints.get(&inode) {
-            superblocks[mnt.fs_idx as usize] = None;
+            if mnt.fs_idx != fs_idx && !Self::is_shared(&mountpoints, inode, mnt.fs_idx) {
+                superblocks[mnt.fs_idx as usize] = None;
+                self.evict_cached_fs(mnt.fs_idx);
+            } else {
+                self.evict_cached_root(mnt);
+            }
+        }
+        if superblocks[fs_idx as usize].is_none() {
+            self.mounted_timeouts(fs_idx, &fs);
+            superblocks[fs_idx as usize] = Some(fs);
+            self.idle.mounted(fs_idx);
+            self.readonly.mounted(fs_idx);
         }
-        superblocks[fs_idx as usize] = Some(Arc::new(fs));
         self.superblocks.store(Arc::new(superblocks));
         trace!("fs_idx {} inode {}", fs_idx, inode);
 
+        if alias || opts != MountOptions::default() {
+            self.mount_origins.set_used();
+        }
         let mountpoint = Arc::new(MountPointData {
             fs_idx,
             ino: real_root_ino,
             root_entry: entry,
-            _path: path.to_string(),
+            path: path.to_string(),
+            key: inode,
+            opts,
+            alias,
         });
         mountpoints.insert(inode, mountpoint);
         self.mountpoints.store(Arc::new(mountpoints));
@@ -360,8 +720,9 @@ impl Vfs {
             })?;
         }
         let index = self.allocate_fs_idx().map_err(VfsError::FsIndex)?;
-        self.insert_mount_locked(fs, entry, index, path)
+        self.insert_mount_locked(Arc::new(fs), entry, index, path, MountOptions::default())
             .map_err(VfsError::Mount)?;
+        self.destroyed.lock().unwrap().remove(&index);
 
         Ok(index)
     }
@@ -377,34 +738,71 @@ impl Vfs {
             .ok_or_else(|| VfsError::NotFound(path.to_string()))?;
 
         let mut mountpoints = self.mountpoints.load().deref().deref().clone();
-        let fs_idx = mountpoints
-            .get(&inode)
-            .map(Arc::clone)
-            .map(|x| {
-                // Do not remove pseudofs inode. We keep all pseudofs inode so that
-                // 1. they can be reused later on
-                // 2. during live upgrade, it is easier reconstruct pseudofs inodes since
-                //    we do not have to track pseudofs deletions
-                //self.root.evict_inode(inode);
-                mountpoints.remove(&inode);
-                self.mountpoints.store(Arc::new(mountpoints));
-                x.fs_idx
-            })
-            .ok_or_else(|| {
-                error!("{} is not a mount point.", path);
-                VfsError::NotFound(path.to_string())
-            })?;
+        // Do not remove pseudofs inode. We keep all pseudofs inode so that
+        // 1. they can be reused later on
+        // 2. during live upgrade, it is easier reconstruct pseudofs inodes since
+        //    we do not have to track pseudofs deletions
+        // Daemons may remove them with `cleanup_path()` instead.
+        let mnt = mountpoints.remove(&inode).ok_or_else(|| {
+            error!("{} is not a mount point.", path);
+            VfsError::NotFound(path.to_string())
+        })?;
+        self.mountpoints.store(Arc::new(mountpoints));
 
+        let fs_idx = mnt.fs_idx;
         trace!("fs_idx {}", fs_idx);
+        // The backend is still mounted at other paths.
+        if Self::is_shared(&self.mountpoints.load(), inode, fs_idx) {
+            self.evict_cached_root(&mnt);
+            return Ok(());
+        }
         let mut superblocks = self.superblocks.load().deref().deref().clone();
         if let Some(fs) = superblocks[fs_idx as usize].take() {
-            fs.destroy();
+            // Backend may have been destroyed already by `Vfs::destroy()`.
+            if !self.destroyed.lock().unwrap().remove(&fs_idx) {
+                // The kernel won't send forgets for inodes of the backend anymore.
+                fs.forget_all();
+                fs.destroy();
+            }
         }
         self.superblocks.store(Arc::new(superblocks));
+        self.evict_cached_fs(fs_idx);
 
         Ok(())
     }
 
+    /// Remove the pseudo fs directory at `path` and its ancestors left empty, like after
+    /// umounting the backend at `path`, returning the number of directories removed.
+    ///
+    /// Directories still anchoring mountpoints, or still looked up by the guest, are kept. Fails
+    /// if the directory at `path` can't be removed, so daemons may retry after the guest forgot
+    /// it.
+    pub fn cleanup_path(&self, path: &str) -> VfsResult<usize> {
+        // Serialize mount operations. Do not expect poisoned lock here.
+        let _guard = self.lock.lock().unwrap();
+        let mountpoints = self.mountpoints.load();
+        self.root
+            .evict(path, |ino| mountpoints.contains_key(&ino))
+            .map_err(VfsError::PathWalk)
+    }
+
+    /// Get the directories of the pseudo fs as `(path, inode)`, ordered by path, for example to
+    /// expose them in a debug endpoint of the daemon.
+    pub fn pseudo_tree(&self) -> Vec<(String, u64)> {
+        self.root.tree()
+    }
+
+    // Check whether backend `fs_idx` is mounted at paths other than pseudo fs inode `inode`.
+    fn is_shared(
+        mountpoints: &HashMap<u64, Arc<MountPointData>>,
+        inode: u64,
+        fs_idx: VfsIndex,
+    ) -> bool {
+        mountpoints
+            .iter()
+            .any(|(ino, mnt)| *ino != inode && mnt.fs_idx == fs_idx)
+    }
+
     /// Get the mounted backend file system alongside the path if there's one.
     pub fn get_rootfs(&self, path: &str) -> VfsResult<Option<Arc<BackFileSystem>>> {
         // Serialize mount operations. Do not expect poisoned lock here.
@@ -425,6 +823,28 @@ impl Vfs {
         }
     }
 
+    /// Get the index of the backend file system serving guest inode `inode`, without touching
+    /// the backend.
+    ///
+    /// Return `None` for inodes of the pseudo fs, and for inodes of backends no longer mounted.
+    /// It's cheap enough to classify every request, by the `nodeid` of its header for example.
+    pub fn backend_of(&self, inode: u64) -> Option<VfsIndex> {
+        let inode = VfsInode::from(inode);
+        let fs_idx = if inode.is_pseudo_fs() {
+            let mountpoints = self.mountpoints.load();
+            let mnt = mountpoints.get(&inode.ino())?;
+            if inode.ino() != ROOT_ID && !mnt.alias {
+                return None;
+            }
+            mnt.fs_idx
+        } else {
+            inode.fs_idx()
+        };
+        self.superblocks.load()[fs_idx as usize]
+            .as_ref()
+            .map(|_| fs_idx)
+    }
+
     // Inode converting rules:
     // 1. Pseudo fs inode is not hashed
     // 2. Index is always larger than 0 so that pseudo fs inodes are never affected
@@ -456,6 +876,13 @@ impl Vfs {
 
     fn allocate_fs_idx(&self) -> Result<VfsIndex> {
         let superblocks = self.superblocks.load().deref().deref().clone();
+        if self.opts.load().deterministic {
+            return (VFS_PSEUDO_FS_IDX + 1..=VfsIndex::MAX)
+                .find(|index| {
+                    superblocks[*index as usize].is_none() && !self.inode_refs.busy(*index)
+                })
+                .ok_or_else(|| Error::other("vfs maximum mountpoints reached"));
+        }
         let start = self.next_super.load(Ordering::SeqCst);
         let mut found = false;
 
@@ -477,6 +904,9 @@ impl Vfs {
             if (index as usize) < superblocks.len() && superblocks[index as usize].is_some() {
                 // Skip if it's allocated
                 continue;
+            } else if self.inode_refs.busy(index) {
+                // Skip if the guest still holds inodes of an umounted backend
+                continue;
             } else {
                 return Ok(index);
             }
@@ -488,6 +918,22 @@ impl Vfs {
         ))
     }
 
+    // Get the mounted backends with their root inodes, backends mounted several times once.
+    fn mounted_roots(&self) -> Vec<(Arc<BackFileSystem>, u64)> {
+        let mut mounts: Vec<_> = self
+            .mountpoints
+            .load()
+            .values()
+            .filter(|mnt| !mnt.alias)
+            .map(|mnt| (mnt.fs_idx, mnt.ino))
+            .collect();
+        mounts.sort_unstable();
+        mounts
+            .into_iter()
+            .filter_map(|(fs_idx, ino)| self.get_fs_by_idx(fs_idx).ok().map(|fs| (fs, ino)))
+            .collect()
+    }
+
     fn get_fs_by_idx(&self, fs_idx: VfsIndex) -> Result<Arc<BackFileSystem>> {
         let superblocks = self.superblocks.load();
 
@@ -495,25 +941,189 @@ impl Vfs {
             return Ok(fs.clone());
         }
 
-        Err(Error::from_raw_os_error(libc::ENOENT))
+        // The backend has been umounted, inodes still cached by the guest are stale.
+        Err(Error::from_raw_os_error(libc::ESTALE))
     }
 
     fn get_real_rootfs(&self, inode: VfsInode) -> Result<(VfsEitherFs<'_>, VfsInode)> {
         if inode.is_pseudo_fs() {
-            // ROOT_ID is special, we need to check if we have a mountpoint on the vfs root
-            if inode.ino() == ROOT_ID {
-                if let Some(mnt) = self.mountpoints.load().get(&inode.ino()).map(Arc::clone) {
+            // ROOT_ID is special, we need to check if we have a mountpoint on the vfs root.
+            // Roots of further mountpoints of shared backends are pseudo fs inodes too.
+            if let Some(mnt) = self.mountpoints.load().get(&inode.ino()).map(Arc::clone) {
+                if inode.ino() == ROOT_ID || mnt.alias {
                     let fs = self.get_fs_by_idx(mnt.fs_idx)?;
+                    self.idle.touch(mnt.fs_idx);
                     return Ok((Right(fs), VfsInode::new(mnt.fs_idx, mnt.ino)));
                 }
             }
             Ok((Left(&self.root), inode))
         } else {
             let fs = self.get_fs_by_idx(inode.fs_idx())?;
+            self.idle.touch(inode.fs_idx());
+            #[cfg(feature = "tracing")]
+            tracing::trace!(
+                fs_idx = inode.fs_idx(),
+                ino = inode.ino(),
+                "vfs: route to backend"
+            );
             Ok((Right(fs), inode))
         }
     }
 
+    // Like `get_real_rootfs()`, for requests on a handle of `inode`. Handles opened on umounted
+    // backends are closed, so such requests fail with `EBADF` instead of `ESTALE`.
+    fn get_handle_rootfs(&self, inode: VfsInode) -> Result<(VfsEitherFs<'_>, VfsInode)> {
+        self.get_real_rootfs(inode)
+            .map_err(|_| Error::from_raw_os_error(libc::EBADF))
+    }
+
+    // Look up and open `name` by separate requests, for `atomic_open()` on backends without
+    // support for it.
+    fn lookup_open(
+        &self,
+        ctx: &Context,
+        parent: VfsInode,
+        name: &CStr,
+        args: CreateIn,
+    ) -> Result<(Entry, Option<u64>, OpenOptions)> {
+        let flags = args.flags as i32;
+        let entry = match self.lookup(ctx, parent, name) {
+            Ok(entry) if entry.inode != 0 => entry,
+            Ok(_) if flags & libc::O_CREAT == 0 => {
+                return Err(Error::from_raw_os_error(libc::ENOENT))
+            }
+            Err(e) if flags & libc::O_CREAT == 0 || errno_of(&e) != Some(libc::ENOENT) => {
+                return Err(e)
+            }
+            // ENOSYS disables atomic open for the whole Vfs, only fail for this directory instead.
+            _ => {
+                return self.create(ctx, parent, name, args).map_err(|e| {
+                    if errno_of(&e) == Some(libc::ENOSYS) {
+                        Error::from_raw_os_error(libc::EOPNOTSUPP)
+                    } else {
+                        e
+                    }
+                })
+            }
+        };
+
+        let excl = libc::O_CREAT | libc::O_EXCL;
+        let res = if flags & excl == excl {
+            Err(Error::from_raw_os_error(libc::EEXIST))
+        } else if entry.attr.st_mode & libc::S_IFMT != libc::S_IFREG {
+            // Let the kernel resolve symlinks and open directories itself.
+            return Ok((entry, None, OpenOptions::empty()));
+        } else {
+            match self.open(
+                ctx,
+                entry.inode.into(),
+                (flags & !excl) as u32,
+                args.fuse_flags,
+            ) {
+                Err(e) if errno_of(&e) == Some(libc::ENOSYS) && self.opts.load().no_open => {
+                    Ok((None, OpenOptions::empty()))
+                }
+                res => res,
+            }
+        };
+        match res {
+            Ok((handle, opts)) => Ok((entry, handle, opts)),
+            Err(e) => {
+                self.forget(ctx, entry.inode.into(), 1);
+                Err(e)
+            }
+        }
+    }
+
+    // Get the entry of `name` under `parent` served by the lookup cache.
+    fn cached_lookup(&self, parent: VfsInode, name: &CStr) -> Option<Entry> {
+        let entry = self.lookup_cache.as_ref()?.get(parent.0, name)?;
+        self.record_origin(parent, &entry);
+        Some(entry)
+    }
+
+    // Translate the result of looking `name` up under `parent` on the backend `fs_idx` into the
+    // inode space of the Vfs, for both the sync and async paths.
+    fn backend_lookup(
+        &self,
+        fs_idx: VfsIndex,
+        parent: VfsInode,
+        name: &CStr,
+        res: Result<Entry>,
+    ) -> Result<Entry> {
+        let mut entry = match res {
+            Ok(entry) => entry,
+            Err(e) => return self.negative_entry(fs_idx, e),
+        };
+        // lookup success, hash it to a real fuse inode
+        self.convert_entry(fs_idx, &mut entry)?;
+        if let Some(cache) = self.lookup_cache.as_ref() {
+            cache.insert(parent.0, name, &entry);
+        }
+        self.record_origin(parent, &entry);
+        Ok(entry)
+    }
+
+    // Translate attributes returned by the backend `fs_idx`.
+    fn backend_attr(
+        &self,
+        fs_idx: VfsIndex,
+        res: Result<(stat64, Duration)>,
+    ) -> Result<(stat64, Duration)> {
+        res.map(|(mut attr, timeout)| {
+            self.transform_attr(fs_idx, &mut attr);
+            (attr, self.override_attr_timeout(fs_idx, timeout))
+        })
+    }
+
+    // Account a handle opened on the backend `fs_idx`.
+    fn backend_open<T>(
+        &self,
+        fs_idx: VfsIndex,
+        res: Result<(T, OpenOptions)>,
+    ) -> Result<(T, OpenOptions)> {
+        if res.is_ok() {
+            self.idle.open(fs_idx);
+        }
+        res
+    }
+
+    // Account a handle opened with an entry created on the backend `fs_idx`, and translate the
+    // entry.
+    fn backend_create(
+        &self,
+        fs_idx: VfsIndex,
+        res: Result<(Entry, Option<u64>, OpenOptions)>,
+    ) -> Result<(Entry, Option<u64>, OpenOptions)> {
+        res.and_then(|(mut entry, handle, opts)| {
+            self.idle.open(fs_idx);
+            self.convert_entry(fs_idx, &mut entry)?;
+            Ok((entry, handle, opts))
+        })
+    }
+
+    // Finish the creation of `name` under `parent`.
+    fn created(
+        &self,
+        parent: VfsInode,
+        name: &CStr,
+        res: Result<(Entry, Option<u64>, OpenOptions)>,
+    ) -> Result<(Entry, Option<u64>, OpenOptions)> {
+        let res = self.track_readonly(parent, res);
+        if let Ok((entry, _, _)) = &res {
+            self.record_origin(parent, entry);
+        }
+        self.invalidate_entry(parent, name);
+        res
+    }
+
+    // Finish a request which may have changed the attributes of `inode`.
+    fn modified<T>(&self, inode: VfsInode, res: Result<T>) -> Result<T> {
+        let res = self.track_readonly(inode, res);
+        self.invalidate_attr(inode);
+        res
+    }
+
     fn lookup_pseudo(
         &self,
         fs: &PseudoFs,
@@ -527,8 +1137,11 @@ impl Vfs {
         match self.mountpoints.load().get(&entry.inode) {
             Some(mnt) => {
                 // cross mountpoint, return mount root entry
+                fs.forget(ctx, entry.inode, 1);
                 entry = mnt.root_entry;
-                entry.inode = self.convert_inode(mnt.fs_idx, mnt.ino)?;
+                self.transform_attr(mnt.fs_idx, &mut entry.attr);
+                self.override_entry_timeouts(mnt.fs_idx, &mut entry);
+                self.inode_refs.get(entry.inode);
                 trace!(
                     "vfs lookup cross mountpoint, return new mount fs_idx {} inode {} fuse inode {}",
                     mnt.fs_idx,
@@ -543,6 +1156,33 @@ impl Vfs {
     }
 }
 
+impl RawFileSystem for Vfs {
+    fn handle_raw(
+        &self,
+        header: &InHeader,
+        payload: &mut dyn io::Read,
+        reply: &mut dyn io::Write,
+    ) -> Result<RawHandled> {
+        let handlers = self.raw_handlers.load();
+        if handlers.is_empty() {
+            return Ok(RawHandled::NotHandled);
+        }
+        let idata = match self.get_real_rootfs(header.nodeid.into()) {
+            Ok((Right(_), idata)) => idata,
+            _ => return Ok(RawHandled::NotHandled),
+        };
+
+        match handlers.get(&idata.fs_idx()) {
+            Some(handler) => {
+                let mut header = *header;
+                header.nodeid = idata.ino();
+                handler.handle_raw(&header, payload, reply)
+            }
+            None => Ok(RawHandled::NotHandled),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -606,6 +1246,40 @@ mod tests {
         assert!(!is_safe_path_component(name));
     }
 
+    #[cfg(feature = "config-serde")]
+    #[test]
+    fn test_vfs_options_serde() {
+        let opts = VfsOptions {
+            no_open: false,
+            lookup_cache_size: 1024,
+            root_statfs: RootStatfs::Backend(2),
+            out_opts: FsOptions::ASYNC_READ | FsOptions::WRITEBACK_CACHE,
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&opts).unwrap();
+        let loaded: VfsOptions = serde_json::from_str(&json).unwrap();
+        assert!(!loaded.no_open && loaded.no_opendir);
+        assert_eq!(loaded.lookup_cache_size, 1024);
+        assert_eq!(loaded.root_statfs, RootStatfs::Backend(2));
+        assert_eq!(loaded.out_opts, opts.out_opts);
+
+        // Missing fields take their default value, and unknown option bits are dropped.
+        let loaded: VfsOptions = serde_json::from_str(
+            r#"{ "root_statfs": "aggregate", "out_opts": 18446744073709551615 }"#,
+        )
+        .unwrap();
+        assert!(loaded.no_open);
+        assert_eq!(loaded.root_statfs, RootStatfs::Aggregate);
+        assert_eq!(loaded.out_opts, FsOptions::all());
+
+        let mount = MountOptions {
+            read_only: true,
+            squash: Some((1000, 1000)),
+        };
+        let json = serde_json::to_string(&mount).unwrap();
+        assert_eq!(serde_json::from_str::<MountOptions>(&json).unwrap(), mount);
+    }
+
     #[test]
     fn test_is_dot_or_dotdot() {
         let name = CStr::from_bytes_with_nul(b"..\0").unwrap();
@@ -1034,6 +1708,475 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_cleanup_path() {
+        let vfs = Vfs::new(VfsOptions::default());
+        let ctx = Context::new();
+        vfs.mount(Box::new(FakeFileSystemOne {}), "/x/y/z").unwrap();
+        vfs.mount(Box::new(FakeFileSystemOne {}), "/x/w").unwrap();
+        let names = |tree: Vec<(String, u64)>| -> Vec<String> {
+            tree.into_iter().map(|(path, _)| path).collect()
+        };
+        assert_eq!(
+            names(vfs.pseudo_tree()),
+            vec!["/", "/x", "/x/w", "/x/y", "/x/y/z"]
+        );
+
+        // Mountpoints are kept.
+        assert!(matches!(
+            vfs.cleanup_path("/x/y/z"),
+            Err(VfsError::PathWalk(e)) if e.raw_os_error() == Some(libc::EBUSY)
+        ));
+        vfs.umount("/x/y/z").unwrap();
+
+        // Directories looked up by the guest are kept until forgotten. Crossing a mountpoint
+        // doesn't reference its directory.
+        let x = vfs
+            .lookup(&ctx, ROOT_ID.into(), &CString::new("x").unwrap())
+            .unwrap();
+        vfs.lookup(&ctx, x.inode.into(), &CString::new("w").unwrap())
+            .unwrap();
+        let y = vfs
+            .lookup(&ctx, x.inode.into(), &CString::new("y").unwrap())
+            .unwrap();
+        assert!(vfs.cleanup_path("/x/y/z").is_ok());
+        assert_eq!(names(vfs.pseudo_tree()), vec!["/", "/x", "/x/w", "/x/y"]);
+        assert!(vfs.cleanup_path("/x/y").is_err());
+        vfs.forget(&ctx, y.inode.into(), 1);
+        vfs.forget(&ctx, x.inode.into(), 1);
+        assert_eq!(vfs.cleanup_path("/x/y").unwrap(), 1);
+        assert!(vfs
+            .lookup(&ctx, x.inode.into(), &CString::new("y").unwrap())
+            .is_err());
+
+        vfs.umount("/x/w").unwrap();
+        assert_eq!(vfs.cleanup_path("/x/w").unwrap(), 2);
+        assert_eq!(names(vfs.pseudo_tree()), vec!["/"]);
+        assert!(vfs.cleanup_path("/x").is_err());
+    }
+
+    #[test]
+    fn test_backend_of() {
+        let vfs = Vfs::new(VfsOptions::default());
+        assert_eq!(vfs.backend_of(ROOT_ID), None);
+        let idx = vfs.mount(Box::new(FakeFileSystemOne {}), "/x/y").unwrap();
+        let ino = ((idx as u64) << VFS_INDEX_SHIFT) | 5;
+        assert_eq!(vfs.backend_of(ino), Some(idx));
+        // Directories of the pseudo fs aren't served by backends.
+        assert_eq!(vfs.backend_of(2), None);
+
+        let root = vfs.mount(Box::new(FakeFileSystemOne {}), "/").unwrap();
+        assert_eq!(vfs.backend_of(ROOT_ID), Some(root));
+        vfs.umount("/x/y").unwrap();
+        assert_eq!(vfs.backend_of(ino), None);
+    }
+
+    #[cfg(not(feature = "async-io"))]
+    #[test]
+    fn test_destroy_once() {
+        use std::sync::atomic::AtomicUsize;
+
+        struct CountingFileSystem(Arc<AtomicUsize>);
+        impl FileSystem for CountingFileSystem {
+            type Inode = u64;
+            type Handle = u64;
+            fn destroy(&self) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        impl BackendFileSystem for CountingFileSystem {
+            fn mount(&self) -> Result<(Entry, u64)> {
+                Ok((
+                    Entry {
+                        inode: 1,
+                        ..Default::default()
+                    },
+                    0,
+                ))
+            }
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+        }
+
+        let vfs = Vfs::new(VfsOptions::default());
+        let foo = Arc::new(AtomicUsize::new(0));
+        let bar = Arc::new(AtomicUsize::new(0));
+        vfs.mount(Box::new(CountingFileSystem(foo.clone())), "/foo")
+            .unwrap();
+        vfs.mount(Box::new(CountingFileSystem(bar.clone())), "/bar")
+            .unwrap();
+        vfs.init(FsOptions::empty()).unwrap();
+
+        vfs.prepare_destroy().unwrap();
+        vfs.destroy();
+        vfs.destroy();
+        vfs.umount("/foo").unwrap();
+        assert_eq!(foo.load(Ordering::SeqCst), 1);
+        assert_eq!(bar.load(Ordering::SeqCst), 1);
+
+        // Backends mounted after destroy() get destroyed by umount().
+        vfs.mount(Box::new(CountingFileSystem(foo.clone())), "/foo")
+            .unwrap();
+        vfs.umount("/foo").unwrap();
+        assert_eq!(foo.load(Ordering::SeqCst), 2);
+        vfs.umount("/bar").unwrap();
+        assert_eq!(bar.load(Ordering::SeqCst), 1);
+    }
+
+    #[cfg(not(feature = "async-io"))]
+    #[test]
+    fn test_umount_forget_all() {
+        struct SweepFileSystem(Arc<Mutex<Vec<&'static str>>>);
+        impl FileSystem for SweepFileSystem {
+            type Inode = u64;
+            type Handle = u64;
+            fn forget_all(&self) {
+                self.0.lock().unwrap().push("forget all");
+            }
+            fn destroy(&self) {
+                self.0.lock().unwrap().push("destroy");
+            }
+        }
+        impl BackendFileSystem for SweepFileSystem {
+            fn mount(&self) -> Result<(Entry, u64)> {
+                Ok((
+                    Entry {
+                        inode: 1,
+                        ..Default::default()
+                    },
+                    0,
+                ))
+            }
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+        }
+
+        let vfs = Vfs::new(VfsOptions::default());
+        let foo = Arc::new(Mutex::new(Vec::new()));
+        let bar = Arc::new(Mutex::new(Vec::new()));
+        vfs.mount(Box::new(SweepFileSystem(foo.clone())), "/foo")
+            .unwrap();
+        vfs.mount(Box::new(SweepFileSystem(bar.clone())), "/bar")
+            .unwrap();
+
+        // Only the umounted backend is swept.
+        vfs.umount("/foo").unwrap();
+        assert_eq!(*foo.lock().unwrap(), vec!["forget all", "destroy"]);
+        assert!(bar.lock().unwrap().is_empty());
+
+        vfs.forget_all();
+        assert_eq!(*bar.lock().unwrap(), vec!["forget all"]);
+        assert_eq!(foo.lock().unwrap().len(), 2);
+    }
+
+    #[cfg(not(feature = "async-io"))]
+    #[test]
+    fn test_vfs_syncfs() {
+        // A backend recording synced inodes, or failing with `errno`.
+        struct SyncFileSystem(Arc<Mutex<Vec<u64>>>, i32);
+        impl FileSystem for SyncFileSystem {
+            type Inode = u64;
+            type Handle = u64;
+            fn syncfs(&self, _: &Context, inode: u64) -> Result<()> {
+                self.0.lock().unwrap().push(inode);
+                match self.1 {
+                    0 => Ok(()),
+                    errno => Err(Error::from_raw_os_error(errno)),
+                }
+            }
+        }
+        impl BackendFileSystem for SyncFileSystem {
+            fn mount(&self) -> Result<(Entry, u64)> {
+                Ok((
+                    Entry {
+                        inode: 1,
+                        ..Default::default()
+                    },
+                    VFS_MAX_INO,
+                ))
+            }
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+        }
+
+        let ctx = Context::default();
+        let vfs = Vfs::new(VfsOptions::default());
+        let foo = Arc::new(Mutex::new(Vec::new()));
+        let bar = Arc::new(Mutex::new(Vec::new()));
+        let idx = vfs
+            .mount(Box::new(SyncFileSystem(foo.clone(), 0)), "/x/foo")
+            .unwrap();
+        vfs.mount(Box::new(SyncFileSystem(bar.clone(), libc::ENOSYS)), "/bar")
+            .unwrap();
+
+        // Backend inodes are routed to their backend, by the Vfs index of the inode.
+        vfs.syncfs(&ctx, VfsInode::new(idx, 5)).unwrap();
+        assert_eq!(*foo.lock().unwrap(), vec![5]);
+        let name = CString::new("bar").unwrap();
+        let root = vfs.lookup(&ctx, ROOT_ID.into(), &name).unwrap().inode;
+        let err = vfs.syncfs(&ctx, root.into()).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOSYS));
+        assert_eq!(*bar.lock().unwrap(), vec![1]);
+
+        // Pseudo fs inodes sync the roots of all backends, ignoring ones without syncfs.
+        foo.lock().unwrap().clear();
+        bar.lock().unwrap().clear();
+        vfs.syncfs(&ctx, ROOT_ID.into()).unwrap();
+        assert_eq!(*foo.lock().unwrap(), vec![1]);
+        assert_eq!(*bar.lock().unwrap(), vec![1]);
+
+        // Errors of a backend don't stop others from being synced.
+        let baz = Arc::new(Mutex::new(Vec::new()));
+        vfs.mount(Box::new(SyncFileSystem(baz.clone(), libc::EIO)), "/baz")
+            .unwrap();
+        foo.lock().unwrap().clear();
+        let err = vfs.syncfs(&ctx, ROOT_ID.into()).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        assert_eq!(*foo.lock().unwrap(), vec![1]);
+        assert_eq!(*baz.lock().unwrap(), vec![1]);
+    }
+
+    #[cfg(not(feature = "async-io"))]
+    #[test]
+    fn test_vfs_tmpfile() {
+        struct NoTmpfileFs;
+        impl FileSystem for NoTmpfileFs {
+            type Inode = u64;
+            type Handle = u64;
+        }
+        impl BackendFileSystem for NoTmpfileFs {
+            fn mount(&self) -> Result<(Entry, u64)> {
+                Ok((
+                    Entry {
+                        inode: 1,
+                        ..Default::default()
+                    },
+                    VFS_MAX_INO,
+                ))
+            }
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+        }
+
+        // ENOSYS of a backend would disable O_TMPFILE for the whole Vfs.
+        let ctx = Context::default();
+        let vfs = Vfs::new(VfsOptions::default());
+        let idx = vfs.mount(Box::new(NoTmpfileFs), "/x").unwrap();
+        let args = CreateIn::default();
+        let err = vfs
+            .tmpfile(&ctx, VfsInode::new(idx, 1), args)
+            .err()
+            .unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP));
+        let err = vfs.tmpfile(&ctx, ROOT_ID.into(), args).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP));
+    }
+
+    #[cfg(not(feature = "async-io"))]
+    #[test]
+    fn test_vfs_atomic_open() {
+        // Backend with a directory `d` and a file `f`, recording the requests it gets.
+        struct OpenFs(bool, Arc<Mutex<Vec<&'static str>>>);
+        impl OpenFs {
+            fn entry(inode: u64) -> Entry {
+                let mut entry = Entry {
+                    inode,
+                    ..Default::default()
+                };
+                entry.attr.st_mode = if inode == 1 || inode == 3 {
+                    libc::S_IFDIR | 0o755
+                } else {
+                    libc::S_IFREG | 0o644
+                };
+                entry
+            }
+        }
+        impl FileSystem for OpenFs {
+            type Inode = u64;
+            type Handle = u64;
+
+            fn lookup(&self, _: &Context, _: u64, name: &CStr) -> Result<Entry> {
+                self.1.lock().unwrap().push("lookup");
+                match name.to_bytes() {
+                    b"f" => Ok(Self::entry(2)),
+                    b"d" => Ok(Self::entry(3)),
+                    _ => Err(Error::from_raw_os_error(libc::ENOENT)),
+                }
+            }
+
+            fn forget(&self, _: &Context, _: u64, _: u64) {
+                self.1.lock().unwrap().push("forget");
+            }
+
+            fn open(
+                &self,
+                _: &Context,
+                _: u64,
+                flags: u32,
+                _: u32,
+            ) -> Result<(Option<u64>, OpenOptions)> {
+                self.1.lock().unwrap().push("open");
+                if flags & libc::O_TRUNC as u32 != 0 {
+                    return Err(Error::from_raw_os_error(libc::EACCES));
+                }
+                Ok((Some(1), OpenOptions::empty()))
+            }
+
+            fn create(
+                &self,
+                _: &Context,
+                _: u64,
+                _: &CStr,
+                _: CreateIn,
+            ) -> Result<(Entry, Option<u64>, OpenOptions)> {
+                self.1.lock().unwrap().push("create");
+                Ok((Self::entry(4), Some(2), OpenOptions::empty()))
+            }
+
+            fn atomic_open(
+                &self,
+                _: &Context,
+                _: u64,
+                _: &CStr,
+                _: CreateIn,
+            ) -> Result<(Entry, Option<u64>, OpenOptions)> {
+                if !self.0 {
+                    return Err(Error::from_raw_os_error(libc::ENOSYS));
+                }
+                self.1.lock().unwrap().push("atomic_open");
+                Ok((Self::entry(2), Some(3), OpenOptions::empty()))
+            }
+        }
+        impl BackendFileSystem for OpenFs {
+            fn mount(&self) -> Result<(Entry, u64)> {
+                Ok((Self::entry(1), VFS_MAX_INO))
+            }
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+        }
+
+        let ctx = Context::default();
+        let vfs = Vfs::new(VfsOptions::default());
+        vfs.init(FsOptions::ASYNC_READ).unwrap();
+        let atomic = Arc::new(Mutex::new(Vec::new()));
+        let idx = vfs
+            .mount(Box::new(OpenFs(true, atomic.clone())), "/a")
+            .unwrap();
+        let fallback = Arc::new(Mutex::new(Vec::new()));
+        let fallback_idx = vfs
+            .mount(Box::new(OpenFs(false, fallback.clone())), "/b")
+            .unwrap();
+        let name = CString::new("f").unwrap();
+        let args = CreateIn {
+            flags: libc::O_RDWR as u32,
+            ..Default::default()
+        };
+
+        // Backends supporting atomic open get a single request.
+        let (entry, fh, _) = vfs
+            .atomic_open(&ctx, VfsInode::new(idx, 1), &name, args)
+            .unwrap();
+        assert_eq!(entry.inode, u64::from(VfsInode::new(idx, 2)));
+        assert_eq!(fh, Some(3));
+        assert_eq!(*atomic.lock().unwrap(), vec!["atomic_open"]);
+
+        // Others are looked up and opened by separate requests.
+        let parent = VfsInode::new(fallback_idx, 1);
+        let (entry, fh, _) = vfs.atomic_open(&ctx, parent, &name, args).unwrap();
+        assert_eq!(entry.inode, u64::from(VfsInode::new(fallback_idx, 2)));
+        assert_eq!(fh, Some(1));
+        assert_eq!(*fallback.lock().unwrap(), vec!["lookup", "open"]);
+
+        // Lookups are dropped when the open fails, missing files are created with O_CREAT.
+        fallback.lock().unwrap().clear();
+        let trunc = CreateIn {
+            flags: (libc::O_RDWR | libc::O_TRUNC) as u32,
+            ..args
+        };
+        let err = vfs.atomic_open(&ctx, parent, &name, trunc).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+        let missing = CString::new("g").unwrap();
+        let err = vfs.atomic_open(&ctx, parent, &missing, args).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        let create = CreateIn {
+            flags: (libc::O_RDWR | libc::O_CREAT) as u32,
+            ..args
+        };
+        let (_, fh, _) = vfs.atomic_open(&ctx, parent, &missing, create).unwrap();
+        assert_eq!(fh, Some(2));
+        assert_eq!(
+            *fallback.lock().unwrap(),
+            vec!["lookup", "open", "forget", "lookup", "lookup", "create"]
+        );
+
+        // Directories are looked up without being opened, existing files aren't created again.
+        let dir = CString::new("d").unwrap();
+        let (_, fh, _) = vfs.atomic_open(&ctx, parent, &dir, args).unwrap();
+        assert_eq!(fh, None);
+        let excl = CreateIn {
+            flags: (libc::O_RDWR | libc::O_CREAT | libc::O_EXCL) as u32,
+            ..args
+        };
+        let err = vfs.atomic_open(&ctx, parent, &name, excl).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+
+        // Names in the pseudo fs are looked up from it.
+        let a = CString::new("a").unwrap();
+        let (entry, fh, _) = vfs.atomic_open(&ctx, ROOT_ID.into(), &a, args).unwrap();
+        assert_eq!(entry.inode, u64::from(VfsInode::new(idx, 1)));
+        assert_eq!(fh, None);
+    }
+
+    #[test]
+    fn test_vfs_raw_handler() {
+        struct Recorder(Mutex<Vec<u64>>);
+        impl RawFileSystem for Recorder {
+            fn handle_raw(
+                &self,
+                header: &InHeader,
+                _payload: &mut dyn io::Read,
+                _reply: &mut dyn io::Write,
+            ) -> Result<RawHandled> {
+                self.0.lock().unwrap().push(header.nodeid);
+                Ok(RawHandled::Handled)
+            }
+        }
+
+        let vfs = Vfs::new(VfsOptions::default());
+        let raw = Arc::new(Recorder(Mutex::new(Vec::new())));
+        let header = |nodeid| InHeader {
+            nodeid,
+            ..Default::default()
+        };
+        let handle = |nodeid| {
+            vfs.handle_raw(&header(nodeid), &mut io::empty(), &mut io::sink())
+                .unwrap()
+        };
+
+        let foo = vfs.mount(Box::new(FakeFileSystemOne {}), "/foo").unwrap();
+        vfs.mount(Box::new(FakeFileSystemTwo {}), "/bar").unwrap();
+        assert!(vfs.set_raw_handler("/x", Some(raw.clone())).is_err());
+        vfs.set_raw_handler("/foo", Some(raw.clone())).unwrap();
+
+        let ino = VfsInode::new(foo, 5).0;
+        assert_eq!(handle(ino), RawHandled::Handled);
+        assert_eq!(handle(ROOT_ID), RawHandled::NotHandled);
+        assert_eq!(handle(VfsInode::new(foo + 1, 5).0), RawHandled::NotHandled);
+        assert_eq!(*raw.0.lock().unwrap(), vec![5]);
+
+        vfs.set_raw_handler("/foo", None).unwrap();
+        assert_eq!(handle(ino), RawHandled::NotHandled);
+        vfs.set_raw_handler("/foo", Some(raw.clone())).unwrap();
+        vfs.umount("/foo").unwrap();
+        assert_eq!(handle(ino), RawHandled::NotHandled);
+        assert_eq!(raw.0.lock().unwrap().len(), 1);
+    }
+
     #[test]
     fn test_umount_overlap() {
         let vfs = Vfs::new(VfsOptions::default());
@@ -1113,4 +2256,38 @@ mod tests {
             vfs.allocate_fs_idx().unwrap_err();
         }
     }
+
+    #[test]
+    fn test_allocate_fs_idx_deterministic() {
+        let mount_all = |vfs: &Vfs| {
+            for path in ["/a", "/b", "/c"] {
+                vfs.mount(Box::new(FakeFileSystemOne {}), path).unwrap();
+            }
+            vfs.umount("/b").unwrap();
+            vfs.mount(Box::new(FakeFileSystemOne {}), "/d").unwrap()
+        };
+
+        // Freed indexes are reused in deterministic mode, instead of being allocated round robin.
+        assert_eq!(mount_all(&Vfs::default()), 4);
+        let opts = VfsOptions {
+            deterministic: true,
+            ..Default::default()
+        };
+        let vfs = Vfs::new(opts);
+        assert_eq!(mount_all(&vfs), 2);
+
+        // Pseudo fs inode numbers don't depend on the order of mounts.
+        let other = Vfs::new(opts);
+        for path in ["/c/x", "/a/y"] {
+            other.mount(Box::new(FakeFileSystemOne {}), path).unwrap();
+        }
+        vfs.mount(Box::new(FakeFileSystemOne {}), "/a/y").unwrap();
+        vfs.mount(Box::new(FakeFileSystemOne {}), "/c/x").unwrap();
+        for path in ["/a", "/c", "/a/y", "/c/x"] {
+            assert_eq!(
+                vfs.root.path_walk(path).unwrap(),
+                other.root.path_walk(path).unwrap()
+            );
+        }
+    }
 }
@@ -0,0 +1,435 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Save and restore the state of the Vfs, for live upgrade.
+//!
+//! The saved state has the pseudo fs directory tree, the mountpoints with their Vfs indexes and
+//! options, and the lookup counts the guest holds on backends, so a new daemon serves the inode
+//! numbers known by the guest. Backends are not saved by [Vfs::save], the new daemon provides
+//! them on [Vfs::restore] and restores their states on its own, for example by
+//! [PassthroughFs::restore](crate::passthrough::PassthroughFs::restore).
+//!
+//! [Vfs::save_with_backends] embeds the states of the backends, encoded by
+//! [BackendFileSystem::save_state] at the same time as the Vfs, and keyed by their Vfs indexes.
+//! [Vfs::restore_with_backends] then restores them by [BackendFileSystem::restore_state]. A
+//! backend failing to restore its state is mounted as a fresh one and reported, the guest sees
+//! the inodes it knew there as stale, instead of failing the restore of the whole Vfs.
+//!
+//! Raw handlers, attribute transforms, idle and read-only policies are not saved, they should be
+//! registered again after the restore. Options negotiated with the kernel are restored by
+//! [Server::restore_connection](crate::api::server::Server::restore_connection), which
+//! initializes the Vfs and the restored backends.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::os::unix::io::RawFd;
+
+use super::*;
+use crate::api::errno::fuse_errno;
+use crate::api::persist::{StateReader, StateWriter};
+
+// Magic number and format version of saved Vfs states.
+const VFS_STATE_MAGIC: u32 = 0x4655_5653;
+const VFS_STATE_FORMAT: u32 = 2;
+
+struct SavedMount {
+    path: String,
+    fs_idx: VfsIndex,
+    ino: u64,
+    opts: MountOptions,
+}
+
+// State of a backend saved with the Vfs.
+struct SavedBackend {
+    state: Vec<u8>,
+    files: Vec<File>,
+}
+
+impl Vfs {
+    /// Encode the state of the Vfs to be restored by [Vfs::restore] of a new daemon.
+    pub fn save(&self) -> Result<Vec<u8>> {
+        self.encode(false).map(|(state, _)| state)
+    }
+
+    /// Encode the state of the Vfs together with the states of its backends, to be restored by
+    /// [Vfs::restore_with_backends] of a new daemon.
+    ///
+    /// Return the state and the file descriptors of the backends, which must be passed to the
+    /// new daemon in order. Requests must not be handled while the state is saved.
+    pub fn save_with_backends(&self) -> Result<(Vec<u8>, Vec<RawFd>)> {
+        self.encode(true)
+    }
+
+    fn encode(&self, backends: bool) -> Result<(Vec<u8>, Vec<RawFd>)> {
+        // Serialize mount operations. Do not expect poisoned lock here.
+        let _guard = self.lock.lock().unwrap();
+        let mut w = StateWriter::new(VFS_STATE_MAGIC, VFS_STATE_FORMAT);
+        w.put_u32(self.next_super.load(Ordering::SeqCst) as u32);
+
+        let inodes = self.root.saved_inodes();
+        w.put_u32(inodes.len() as u32);
+        for (ino, parent, name) in inodes {
+            w.put_u64(ino);
+            w.put_u64(parent);
+            w.put_bytes(name.as_bytes());
+        }
+
+        // The first mountpoint of shared backends is restored first, the others are aliases.
+        let mut mounts: Vec<_> = self.mountpoints.load().values().cloned().collect();
+        mounts.sort_by_key(|mnt| (mnt.alias, mnt.key));
+        w.put_u32(mounts.len() as u32);
+        for mnt in mounts {
+            w.put_bytes(mnt.path.as_bytes());
+            w.put_u32(mnt.fs_idx as u32);
+            w.put_u64(mnt.ino);
+            w.put_u32(mnt.opts.read_only as u32);
+            let (squash, uid, gid) = match mnt.opts.squash {
+                Some((uid, gid)) => (1, uid, gid),
+                None => (0, 0, 0),
+            };
+            w.put_u32(squash);
+            w.put_u32(uid);
+            w.put_u32(gid);
+        }
+
+        let refs = self.inode_refs.saved();
+        w.put_u32(refs.len() as u32);
+        for (fs_idx, count) in refs {
+            w.put_u32(fs_idx as u32);
+            w.put_u64(count);
+        }
+
+        let origins = self.mount_origins.saved();
+        w.put_u32(origins.len() as u32);
+        for (ino, key, nlookup) in origins {
+            w.put_u64(ino);
+            w.put_u64(key);
+            w.put_u64(nlookup);
+        }
+
+        let cached = self
+            .lookup_cache
+            .as_ref()
+            .map(|c| c.saved_refs())
+            .unwrap_or_default();
+        w.put_u32(cached.len() as u32);
+        for (ino, backend, pending) in cached {
+            w.put_u64(ino);
+            w.put_u64(backend);
+            w.put_u64(pending);
+        }
+
+        let mut states = Vec::new();
+        if backends {
+            for (fs_idx, fs) in self.superblocks.load().iter().enumerate() {
+                if let Some(fs) = fs {
+                    if let Some(state) = fs.save_state()? {
+                        states.push((fs_idx, state));
+                    }
+                }
+            }
+        }
+        let mut fds = Vec::new();
+        w.put_u32(states.len() as u32);
+        for (fs_idx, (state, files)) in states {
+            w.put_u32(fs_idx as u32);
+            w.put_bytes(&state);
+            w.put_u32(files.len() as u32);
+            fds.extend(files);
+        }
+
+        Ok((w.finish(), fds))
+    }
+
+    /// Restore a state encoded by [Vfs::save] into a Vfs without mountpoints.
+    ///
+    /// `backend` is invoked with the path and the Vfs index of each backend to be restored, and
+    /// returns the backend file system with its own state restored. It's invoked once for shared
+    /// backends, with the first path. Fail with `ESTALE` if the root inode of a backend changed,
+    /// and with `EBUSY` if the Vfs has been used. The Vfs is left unchanged if a backend fails.
+    ///
+    /// States of backends saved by [Vfs::save_with_backends] are ignored.
+    pub fn restore<F>(&self, state: &[u8], backend: F) -> Result<()>
+    where
+        F: FnMut(&str, VfsIndex) -> Result<BackFileSystem>,
+    {
+        self.decode(state, None, backend).map(|_| ())
+    }
+
+    /// Restore a state encoded by [Vfs::save_with_backends] into a Vfs without mountpoints.
+    ///
+    /// `files` are the file descriptors returned by `save_with_backends()`, in order. `backend`
+    /// is invoked as by [Vfs::restore], and returns backends not set up yet when they have saved
+    /// states, to be restored by [BackendFileSystem::restore_state]. Return the backends failing
+    /// to restore their states with the errors, they are mounted as fresh ones. Fail as
+    /// [Vfs::restore] otherwise.
+    pub fn restore_with_backends<F>(
+        &self,
+        state: &[u8],
+        files: Vec<File>,
+        backend: F,
+    ) -> Result<Vec<(VfsIndex, Error)>>
+    where
+        F: FnMut(&str, VfsIndex) -> Result<BackFileSystem>,
+    {
+        self.decode(state, Some(files), backend)
+    }
+
+    fn decode<F>(
+        &self,
+        state: &[u8],
+        files: Option<Vec<File>>,
+        mut backend: F,
+    ) -> Result<Vec<(VfsIndex, Error)>>
+    where
+        F: FnMut(&str, VfsIndex) -> Result<BackFileSystem>,
+    {
+        let mut r = StateReader::new(state, VFS_STATE_MAGIC, VFS_STATE_FORMAT, "vfs")?;
+        let fs_idx = |v: u32| match v {
+            v if v as usize >= MAX_VFS_INDEX || v as VfsIndex == VFS_PSEUDO_FS_IDX => {
+                Err(fuse_errno(
+                    libc::EINVAL,
+                    format!("invalid vfs index {} in vfs state", v),
+                ))
+            }
+            v => Ok(v as VfsIndex),
+        };
+
+        let next_super = r.u32()? as VfsIndex;
+        let mut inodes = Vec::new();
+        for _ in 0..r.u32()? {
+            inodes.push((r.u64()?, r.u64()?, r.string()?));
+        }
+        let mut mounts = Vec::new();
+        for _ in 0..r.u32()? {
+            let path = r.string()?;
+            let fs_idx = fs_idx(r.u32()?)?;
+            let ino = r.u64()?;
+            let read_only = r.u32()? != 0;
+            let squash = match (r.u32()?, r.u32()?, r.u32()?) {
+                (0, _, _) => None,
+                (_, uid, gid) => Some((uid, gid)),
+            };
+            let opts = MountOptions { read_only, squash };
+            mounts.push(SavedMount {
+                path,
+                fs_idx,
+                ino,
+                opts,
+            });
+        }
+        let mut refs = Vec::new();
+        for _ in 0..r.u32()? {
+            refs.push((fs_idx(r.u32()?)?, r.u64()?));
+        }
+        let mut origins = Vec::new();
+        for _ in 0..r.u32()? {
+            origins.push((r.u64()?, r.u64()?, r.u64()?));
+        }
+        let mut cached = Vec::new();
+        for _ in 0..r.u32()? {
+            cached.push((r.u64()?, r.u64()?, r.u64()?));
+        }
+        let mut states = Vec::new();
+        for _ in 0..r.u32()? {
+            states.push((fs_idx(r.u32()?)?, r.bytes()?, r.u32()? as usize));
+        }
+        r.finish()?;
+
+        // Hand the file descriptors out to the backends they were saved with.
+        let mut saved: HashMap<VfsIndex, SavedBackend> = HashMap::new();
+        if let Some(files) = files {
+            let total: usize = states.iter().map(|(_, _, n)| n).sum();
+            if total != files.len() {
+                return Err(fuse_errno(
+                    libc::EINVAL,
+                    format!("{} fds saved but {} passed", total, files.len()),
+                ));
+            }
+            let mut files = files.into_iter();
+            for (fs_idx, state, n) in states {
+                let backend = SavedBackend {
+                    state: state.to_vec(),
+                    files: files.by_ref().take(n).collect(),
+                };
+                if saved.insert(fs_idx, backend).is_some() {
+                    return Err(fuse_errno(libc::EINVAL, "duplicate backend in vfs state"));
+                }
+            }
+        }
+
+        // Serialize mount operations. Do not expect poisoned lock here.
+        let _guard = self.lock.lock().unwrap();
+        if !self.mountpoints.load().is_empty() || !self.root.saved_inodes().is_empty() {
+            return Err(fuse_errno(libc::EBUSY, "vfs is already in use"));
+        }
+
+        let mut backends: HashMap<VfsIndex, (Arc<BackFileSystem>, Entry)> = HashMap::new();
+        let mut failed = Vec::new();
+        for mnt in mounts.iter() {
+            if backends.contains_key(&mnt.fs_idx) {
+                continue;
+            }
+            let fs = backend(&mnt.path, mnt.fs_idx)?;
+            if let Some(state) = saved.remove(&mnt.fs_idx) {
+                if let Err(e) = fs.restore_state(&state.state, state.files) {
+                    warn!(
+                        "vfs: failed to restore state of {}, mounted as a fresh backend: {:?}",
+                        mnt.path, e
+                    );
+                    failed.push((mnt.fs_idx, e));
+                }
+            }
+            let (entry, max_ino) = fs.mount()?;
+            if max_ino > VFS_MAX_INO {
+                return Err(fuse_errno(
+                    libc::EINVAL,
+                    format!("unsupported max inode number {}", max_ino),
+                ));
+            }
+            if entry.inode != mnt.ino {
+                return Err(fuse_errno(
+                    libc::ESTALE,
+                    format!(
+                        "root inode of {} changed from {} to {}",
+                        mnt.path, mnt.ino, entry.inode
+                    ),
+                ));
+            }
+            if self.initialized() {
+                fs.init(self.opts.load().out_opts)?;
+            }
+            backends.insert(mnt.fs_idx, (Arc::new(fs), entry));
+        }
+
+        for (ino, parent, name) in inodes {
+            self.root
+                .restore_inode(ino, parent, &name)
+                .map_err(|_| fuse_errno(libc::EINVAL, "invalid pseudo fs inode in vfs state"))?;
+        }
+        for mnt in mounts {
+            let (fs, entry) = &backends[&mnt.fs_idx];
+            self.insert_mount_locked(fs.clone(), *entry, mnt.fs_idx, &mnt.path, mnt.opts)?;
+            self.destroyed.lock().unwrap().remove(&mnt.fs_idx);
+        }
+        self.next_super.store(next_super, Ordering::SeqCst);
+        for (fs_idx, count) in refs {
+            self.inode_refs.restore(fs_idx, count);
+        }
+        for (ino, key, nlookup) in origins {
+            self.mount_origins.restore(ino, key, nlookup);
+        }
+        if let Some(cache) = self.lookup_cache.as_ref() {
+            for (ino, backend, pending) in cached {
+                cache.restore_refs(ino, backend, pending);
+            }
+        }
+
+        Ok(failed)
+    }
+}
+
+#[cfg(all(test, not(feature = "async-io")))]
+mod tests {
+    use super::*;
+    use crate::api::errno::errno_of;
+    use std::ffi::CString;
+
+    // A backend whose root inode is `root`, and which returns inode 2 on lookups.
+    struct RootFs {
+        root: u64,
+    }
+
+    impl FileSystem for RootFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn lookup(&self, _: &Context, _: u64, _: &CStr) -> Result<Entry> {
+            Ok(Entry {
+                inode: 2,
+                ..Default::default()
+            })
+        }
+    }
+
+    impl BackendFileSystem for RootFs {
+        fn mount(&self) -> Result<(Entry, u64)> {
+            Ok((
+                Entry {
+                    inode: self.root,
+                    ..Default::default()
+                },
+                VFS_MAX_INO,
+            ))
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    fn root_fs(root: u64) -> Result<BackFileSystem> {
+        Ok(Box::new(RootFs { root }))
+    }
+
+    #[test]
+    fn test_vfs_save_restore() {
+        let ctx = Context::default();
+        let name = CString::new("f").unwrap();
+        let vfs = Vfs::new(VfsOptions::default());
+        vfs.mount(root_fs(1).unwrap(), "/x/a").unwrap();
+        let shared: Arc<BackFileSystem> = Arc::new(root_fs(1).unwrap());
+        let opts = MountOptions {
+            read_only: true,
+            squash: Some((1, 2)),
+        };
+        let idx = vfs
+            .mount_shared(shared.clone(), "/y", MountOptions::default())
+            .unwrap();
+        vfs.mount_shared(shared, "/z", opts).unwrap();
+        let z = vfs.root.path_walk("/z").unwrap().unwrap();
+        let f = vfs.lookup(&ctx, z.into(), &name).unwrap().inode;
+        assert_eq!(VfsInode(f).fs_idx(), idx);
+        let state = vfs.save().unwrap();
+
+        let restored = Vfs::new(VfsOptions::default());
+        let mut paths = Vec::new();
+        restored
+            .restore(&state, |path, idx| {
+                paths.push((path.to_string(), idx));
+                root_fs(1)
+            })
+            .unwrap();
+        assert_eq!(
+            paths,
+            vec![("/x/a".to_string(), 1), ("/y".to_string(), idx)]
+        );
+        for path in ["/x", "/x/a", "/y", "/z"] {
+            assert_eq!(
+                restored.root.path_walk(path).unwrap(),
+                vfs.root.path_walk(path).unwrap()
+            );
+        }
+        assert_eq!(restored.lookup(&ctx, z.into(), &name).unwrap().inode, f);
+        assert_eq!(restored.mountpoints.load()[&z].opts, opts);
+        assert!(restored.inode_refs.busy(idx));
+        assert_eq!(
+            restored.mount(root_fs(1).unwrap(), "/w").unwrap(),
+            vfs.mount(root_fs(1).unwrap(), "/w").unwrap()
+        );
+
+        // The restored Vfs is in use.
+        let err = restored.restore(&state, |_, _| root_fs(1)).unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::EBUSY));
+
+        // The root of a backend changed.
+        let fresh = Vfs::new(VfsOptions::default());
+        let err = fresh.restore(&state, |_, _| root_fs(3)).unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::ESTALE));
+        assert!(fresh.mountpoints.load().is_empty());
+        assert!(fresh.root.saved_inodes().is_empty());
+        let err = fresh.restore(&state[..state.len() - 1], |_, _| root_fs(1));
+        assert_eq!(errno_of(&err.unwrap_err()), Some(libc::EINVAL));
+        fresh.restore(&state, |_, _| root_fs(1)).unwrap();
+    }
+}
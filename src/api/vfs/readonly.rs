@@ -0,0 +1,265 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Read-only state of backend file systems, set by operators or when the host turns them
+//! read-only.
+//!
+//! When the host remounts a backing file system read-only, after disk errors for example, every
+//! request modifying it fails with `EROFS`, and guests retrying writes cause storms of failing
+//! requests down to the backend. The first `EROFS` of a backend after successful modifications is
+//! a transition: it's logged once and reported to the [ReadonlyPolicy], which may flip the
+//! backend read-only in the Vfs. Requests modifying a read-only backend fail with `EROFS` in the
+//! Vfs without reaching the backend, until it's made writable again by [Vfs::set_readonly].
+
+use std::io::{Error, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::{Vfs, VfsError, VfsIndex, VfsInode, VfsResult, MAX_VFS_INDEX};
+use crate::api::errno::errno_of;
+
+/// Callback invoked with the index of a backend which turned read-only on the host.
+pub type ReadonlyCallback = Arc<dyn Fn(&Vfs, VfsIndex) + Send + Sync>;
+
+/// Policy to act on backend file systems turning read-only on the host.
+#[derive(Clone, Default)]
+pub struct ReadonlyPolicy {
+    /// Flip the backend read-only in the Vfs, so later requests modifying it fail early.
+    pub auto_readonly: bool,
+    /// Invoked once per transition, after the backend is flipped read-only if `auto_readonly`
+    /// is set.
+    pub callback: Option<ReadonlyCallback>,
+}
+
+pub(crate) struct ReadonlyTracker {
+    read_only: Vec<AtomicBool>,
+    // Whether the backend failed with `EROFS` since its last successful modification.
+    failing: Vec<AtomicBool>,
+}
+
+impl ReadonlyTracker {
+    pub(crate) fn new() -> Self {
+        ReadonlyTracker {
+            read_only: (0..MAX_VFS_INDEX).map(|_| AtomicBool::new(false)).collect(),
+            failing: (0..MAX_VFS_INDEX).map(|_| AtomicBool::new(false)).collect(),
+        }
+    }
+
+    // Reset the state of `idx` when a backend gets mounted there.
+    pub(crate) fn mounted(&self, idx: VfsIndex) {
+        self.read_only[idx as usize].store(false, Ordering::Relaxed);
+        self.failing[idx as usize].store(false, Ordering::Relaxed);
+    }
+
+    fn is_readonly(&self, idx: VfsIndex) -> bool {
+        self.read_only[idx as usize].load(Ordering::Relaxed)
+    }
+
+    // Record the result of a modification of `idx`, return whether it's the first `EROFS`.
+    fn track(&self, idx: VfsIndex, erofs: bool) -> bool {
+        let failing = &self.failing[idx as usize];
+        if erofs {
+            !failing.swap(true, Ordering::Relaxed)
+        } else {
+            // Avoid writing the shared flag on each successful modification.
+            if failing.load(Ordering::Relaxed) {
+                failing.store(false, Ordering::Relaxed);
+            }
+            false
+        }
+    }
+}
+
+impl Vfs {
+    /// Act on backend file systems turning read-only on the host.
+    pub fn with_readonly_policy(mut self, policy: ReadonlyPolicy) -> Self {
+        self.readonly_policy = Some(policy);
+        self
+    }
+
+    /// Make the backend file system `index` read-only in the Vfs, or writable again.
+    ///
+    /// Requests modifying a read-only backend fail with `EROFS` without reaching the backend.
+    pub fn set_readonly(&self, index: VfsIndex, read_only: bool) -> VfsResult<()> {
+        if self.superblocks.load()[index as usize].is_none() {
+            return Err(VfsError::NotFound(format!("backend {}", index)));
+        }
+        self.readonly.read_only[index as usize].store(read_only, Ordering::Relaxed);
+        info!(
+            "vfs: backend {} is {}",
+            index,
+            if read_only { "read-only" } else { "writable" }
+        );
+        Ok(())
+    }
+
+    /// Check whether the backend file system `index` is read-only in the Vfs.
+    ///
+    /// Return `None` if there's no backend mounted at `index`.
+    pub fn is_readonly(&self, index: VfsIndex) -> Option<bool> {
+        self.superblocks.load()[index as usize]
+            .as_ref()
+            .map(|_| self.readonly.is_readonly(index))
+    }
+
+    // Fail requests modifying the read-only backend of `inode`.
+    pub(super) fn check_readonly(&self, inode: VfsInode) -> Result<()> {
+        match self.backend_of(inode.into()) {
+            Some(idx) if self.readonly.is_readonly(idx) => {
+                Err(Error::from_raw_os_error(libc::EROFS))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Track the result `res` of a request modifying the backend of `inode`, to detect the
+    // backend turning read-only on the host.
+    pub(super) fn track_readonly<T>(&self, inode: VfsInode, res: Result<T>) -> Result<T> {
+        let erofs = matches!(&res, Err(e) if errno_of(e) == Some(libc::EROFS));
+        if let Some(idx) = self.backend_of(inode.into()) {
+            if self.readonly.track(idx, erofs) && !self.readonly.is_readonly(idx) {
+                self.readonly_transition(idx);
+            }
+        }
+        res
+    }
+
+    fn readonly_transition(&self, idx: VfsIndex) {
+        warn!("vfs: backend {} turned read-only on the host", idx);
+        if let Some(policy) = self.readonly_policy.as_ref() {
+            if policy.auto_readonly {
+                self.readonly.read_only[idx as usize].store(true, Ordering::Relaxed);
+            }
+            if let Some(callback) = policy.callback.as_ref() {
+                callback(self, idx);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "async-io")))]
+mod tests {
+    use super::*;
+    use crate::api::filesystem::{Context, Entry, FileSystem};
+    use crate::api::BackendFileSystem;
+    use std::any::Any;
+    use std::ffi::{CStr, CString};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Mutex;
+
+    // Backend failing directory creations with `EROFS` once the host turns it read-only.
+    #[derive(Clone, Default)]
+    struct RoFs {
+        host_ro: Arc<AtomicBool>,
+        mkdirs: Arc<AtomicUsize>,
+    }
+
+    impl FileSystem for RoFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn mkdir(&self, _: &Context, _: u64, _: &CStr, _: u32, _: u32) -> Result<Entry> {
+            self.mkdirs.fetch_add(1, Ordering::SeqCst);
+            if self.host_ro.load(Ordering::SeqCst) {
+                return Err(Error::from_raw_os_error(libc::EROFS));
+            }
+            Ok(Entry {
+                inode: 2,
+                ..Default::default()
+            })
+        }
+    }
+
+    impl BackendFileSystem for RoFs {
+        fn mount(&self) -> Result<(Entry, u64)> {
+            let entry = Entry {
+                inode: 1,
+                ..Default::default()
+            };
+            Ok((entry, 0))
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    fn errno<T>(res: Result<T>) -> Option<i32> {
+        res.err().and_then(|e| errno_of(&e))
+    }
+
+    #[test]
+    fn test_vfs_readonly_policy() {
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let fired2 = fired.clone();
+        let vfs = Vfs::default().with_readonly_policy(ReadonlyPolicy {
+            auto_readonly: true,
+            callback: Some(Arc::new(move |vfs: &Vfs, idx: VfsIndex| {
+                fired2.lock().unwrap().push((idx, vfs.is_readonly(idx)));
+            })),
+        });
+        let fs = RoFs::default();
+        let idx = vfs.mount(Box::new(fs.clone()), "/ro").unwrap();
+        let ctx = Context::default();
+        let root = VfsInode::new(idx, 1);
+        let name = CString::new("d").unwrap();
+        let mkdir = || vfs.mkdir(&ctx, root, &name, 0o755, 0);
+
+        assert!(mkdir().is_ok());
+        assert_eq!(vfs.is_readonly(idx), Some(false));
+
+        // The first EROFS flips the backend read-only, later requests don't reach it.
+        fs.host_ro.store(true, Ordering::SeqCst);
+        assert_eq!(errno(mkdir()), Some(libc::EROFS));
+        assert_eq!(vfs.is_readonly(idx), Some(true));
+        assert_eq!(errno(mkdir()), Some(libc::EROFS));
+        assert_eq!(errno(vfs.unlink(&ctx, root, &name)), Some(libc::EROFS));
+        assert_eq!(fs.mkdirs.load(Ordering::SeqCst), 2);
+        assert_eq!(*fired.lock().unwrap(), vec![(idx, Some(true))]);
+
+        // Operators make the backend writable again, a new transition fires again.
+        vfs.set_readonly(idx, false).unwrap();
+        assert_eq!(errno(mkdir()), Some(libc::EROFS));
+        assert_eq!(fired.lock().unwrap().len(), 1);
+        vfs.set_readonly(idx, false).unwrap();
+        fs.host_ro.store(false, Ordering::SeqCst);
+        assert!(mkdir().is_ok());
+        fs.host_ro.store(true, Ordering::SeqCst);
+        assert_eq!(errno(mkdir()), Some(libc::EROFS));
+        assert_eq!(fired.lock().unwrap().len(), 2);
+        assert_eq!(fs.mkdirs.load(Ordering::SeqCst), 5);
+
+        assert!(vfs.set_readonly(idx + 1, true).is_err());
+        assert_eq!(vfs.is_readonly(idx + 1), None);
+    }
+
+    #[test]
+    fn test_vfs_set_readonly() {
+        // Without an automatic policy, transitions are only reported.
+        let vfs = Vfs::default();
+        let fs = RoFs::default();
+        let idx = vfs.mount(Box::new(fs.clone()), "/").unwrap();
+        let ctx = Context::default();
+        let root = VfsInode::new(idx, 1);
+        let name = CString::new("d").unwrap();
+
+        fs.host_ro.store(true, Ordering::SeqCst);
+        assert_eq!(
+            errno(vfs.mkdir(&ctx, root, &name, 0o755, 0)),
+            Some(libc::EROFS)
+        );
+        assert_eq!(vfs.is_readonly(idx), Some(false));
+
+        // Manual override, also applying to the root of the backend mounted at `/`.
+        fs.host_ro.store(false, Ordering::SeqCst);
+        vfs.set_readonly(idx, true).unwrap();
+        let pseudo_root = VfsInode::new(0, 1);
+        assert_eq!(
+            errno(vfs.mkdir(&ctx, pseudo_root, &name, 0o755, 0)),
+            Some(libc::EROFS)
+        );
+        assert_eq!(fs.mkdirs.load(Ordering::SeqCst), 1);
+        vfs.set_readonly(idx, false).unwrap();
+        assert!(vfs.mkdir(&ctx, pseudo_root, &name, 0o755, 0).is_ok());
+    }
+}
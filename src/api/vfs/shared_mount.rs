@@ -0,0 +1,553 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Mounting one backend file system at multiple paths of the Vfs.
+//!
+//! A backend mounted by [Vfs::mount_shared] at several paths keeps a single Vfs index, so all
+//! mountpoints share one inode space: an inode reachable from multiple mountpoints has the same
+//! Vfs inode number, and the kernel counts its lookups, and later forgets them, only once. The
+//! root of the first mountpoint is the root inode of the backend, while roots of further
+//! mountpoints are represented by the pseudo fs inode of the mountpoint, so the root can tell
+//! which mountpoint it belongs to.
+//!
+//! Requests are routed to the backend whatever mountpoint they come from, with [MountOptions]
+//! of the mountpoint the target inode belongs to applied. An inode belongs to the mountpoint it
+//! was first resolved through, that is, the mountpoint of the parent directory of the first
+//! lookup, create, mknod, mkdir, symlink, link or readdirplus returning the inode while the
+//! backend is mounted at multiple paths. An inode reachable through multiple mountpoints, a
+//! file looked up by `/ro/data/f` and `/rw/data/f` for example, stays with the first mountpoint
+//! until the kernel has forgotten all its lookups, even if it's resolved through another one in
+//! the meantime. After that, the next resolution decides again. The kernel only knows a single
+//! inode, so there's no way to tell which mountpoint a request on such an inode is issued from.
+//!
+//! Inodes not resolved while the backend is shared, because they were resolved before the
+//! backend got mounted at another path for example, belong to the first mountpoint. Requests on
+//! inodes belonging to an umounted mountpoint of a still mounted backend fail with `ESTALE`.
+
+use super::*;
+
+/// Options applying to requests on inodes of a mountpoint.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config-serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct MountOptions {
+    /// Fail requests modifying the backend file system with `EROFS`.
+    pub read_only: bool,
+    /// Replace the uid and gid of requests with the given ones.
+    pub squash: Option<(u32, u32)>,
+}
+
+// Mountpoints inodes of shared backends belong to.
+#[derive(Default)]
+pub(super) struct MountOrigins {
+    // Set once a backend is mounted with options or at multiple paths, to keep the cost off
+    // requests otherwise.
+    used: AtomicBool,
+    // Map from Vfs inode to the pseudo fs inode of its mountpoint and the lookup count.
+    origins: Mutex<HashMap<u64, (u64, u64)>>,
+}
+
+impl MountOrigins {
+    fn used(&self) -> bool {
+        self.used.load(Ordering::Acquire)
+    }
+
+    pub(super) fn set_used(&self) {
+        self.used.store(true, Ordering::Release);
+    }
+
+    // Get origins as `(inode, mountpoint, nlookup)`, to be saved across live upgrade.
+    #[cfg(feature = "persist")]
+    pub(super) fn saved(&self) -> Vec<(u64, u64, u64)> {
+        let mut origins: Vec<_> = self
+            .origins
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(ino, (key, nlookup))| (*ino, *key, *nlookup))
+            .collect();
+        origins.sort_unstable();
+        origins
+    }
+
+    // Restore an origin saved by `saved()`.
+    #[cfg(feature = "persist")]
+    pub(super) fn restore(&self, inode: u64, key: u64, nlookup: u64) {
+        self.set_used();
+        self.origins.lock().unwrap().insert(inode, (key, nlookup));
+    }
+
+    // Drop the origins of inodes of backend `fs_idx`.
+    pub(super) fn evict_fs(&self, fs_idx: VfsIndex) {
+        if self.used() {
+            let mut origins = self.origins.lock().unwrap();
+            origins.retain(|ino, _| VfsInode(*ino).fs_idx() != fs_idx);
+        }
+    }
+}
+
+impl Vfs {
+    /// Mount the backend file system `fs` at `path`, with `opts` applied to requests on inodes
+    /// of the mountpoint.
+    ///
+    /// If `fs` has been mounted at other paths by this method, the new mountpoint shares the Vfs
+    /// index and inode space with them, and the index is returned. The backend is only destroyed
+    /// when umounted from the last path. See the [module documentation](self) for how requests
+    /// are matched to mountpoints.
+    pub fn mount_shared(
+        &self,
+        fs: Arc<BackFileSystem>,
+        path: &str,
+        opts: MountOptions,
+    ) -> VfsResult<VfsIndex> {
+        // Serialize mount operations. Do not expect poisoned lock here.
+        let _guard = self.lock.lock().unwrap();
+        let shared = self
+            .superblocks
+            .load()
+            .iter()
+            .position(|sb| sb.as_ref().map(|sb| Arc::ptr_eq(sb, &fs)).unwrap_or(false));
+        if let Some(index) = shared {
+            let index = index as VfsIndex;
+            let mnt = self
+                .mountpoints
+                .load()
+                .values()
+                .find(|mnt| mnt.fs_idx == index)
+                .map(Arc::clone);
+            if let Some(mnt) = mnt {
+                let mut entry = mnt.root_entry;
+                entry.inode = mnt.ino;
+                self.insert_mount_locked(fs, entry, index, path, opts)
+                    .map_err(VfsError::Mount)?;
+                return Ok(index);
+            }
+        }
+
+        let (entry, ino) = fs.mount().map_err(VfsError::Mount)?;
+        if ino > VFS_MAX_INO {
+            fs.destroy();
+            return Err(VfsError::InodeIndex(format!(
+                "Unsupported max inode number, requested {} supported {}",
+                ino, VFS_MAX_INO
+            )));
+        }
+        if self.initialized() {
+            let opts = self.opts.load().deref().out_opts;
+            fs.init(opts).map_err(|e| {
+                VfsError::Initialize(format!("Can't initialize with opts {:?}, {:?}", opts, e))
+            })?;
+        }
+        let index = self.allocate_fs_idx().map_err(VfsError::FsIndex)?;
+        self.insert_mount_locked(fs, entry, index, path, opts)
+            .map_err(VfsError::Mount)?;
+        self.destroyed.lock().unwrap().remove(&index);
+
+        Ok(index)
+    }
+
+    // Get the mountpoint `inode` belongs to, or `None` for inodes of the pseudo fs.
+    fn mount_of(&self, inode: VfsInode) -> Result<Option<Arc<MountPointData>>> {
+        let mountpoints = self.mountpoints.load();
+        if inode.is_pseudo_fs() {
+            return Ok(mountpoints.get(&inode.ino()).map(Arc::clone));
+        }
+
+        if let Some((key, _)) = self.mount_origins.origins.lock().unwrap().get(&inode.0) {
+            return match mountpoints.get(key) {
+                Some(mnt) if mnt.fs_idx == inode.fs_idx() => Ok(Some(Arc::clone(mnt))),
+                _ => Err(Error::from_raw_os_error(libc::ESTALE)),
+            };
+        }
+        let mounts: Vec<&Arc<MountPointData>> = mountpoints
+            .values()
+            .filter(|mnt| mnt.fs_idx == inode.fs_idx())
+            .collect();
+        match mounts.as_slice() {
+            [] => Ok(None),
+            [mnt] => Ok(Some(Arc::clone(mnt))),
+            // Inodes not resolved while shared belong to the first mountpoint.
+            _ => mounts
+                .iter()
+                .find(|mnt| !mnt.alias)
+                .map(|mnt| Some(Arc::clone(mnt)))
+                .ok_or_else(|| Error::from_raw_os_error(libc::ESTALE)),
+        }
+    }
+
+    // Take a guest lookup of `entry` returned from directory `parent`, and record its mountpoint
+    // if the backend is mounted at multiple paths.
+    pub(super) fn record_origin(&self, parent: VfsInode, entry: &Entry) {
+        self.inode_refs.get(entry.inode);
+        if !self.mount_origins.used() || entry.inode == 0 {
+            return;
+        }
+        let inode = VfsInode(entry.inode);
+        if inode.is_pseudo_fs() {
+            return;
+        }
+        let shared = self
+            .mountpoints
+            .load()
+            .values()
+            .filter(|mnt| mnt.fs_idx == inode.fs_idx())
+            .count()
+            > 1;
+        if !shared {
+            return;
+        }
+        if let Ok(Some(mnt)) = self.mount_of(parent) {
+            let mut origins = self.mount_origins.origins.lock().unwrap();
+            origins.entry(entry.inode).or_insert((mnt.key, 0)).1 += 1;
+        }
+    }
+
+    // Release `count` lookups of `inode` recorded by `record_origin()`.
+    pub(super) fn forget_origin(&self, inode: VfsInode, count: u64) {
+        if !self.mount_origins.used() {
+            return;
+        }
+        let mut origins = self.mount_origins.origins.lock().unwrap();
+        if let Some((_, nlookup)) = origins.get_mut(&inode.0) {
+            *nlookup = nlookup.saturating_sub(count);
+            if *nlookup == 0 {
+                origins.remove(&inode.0);
+            }
+        }
+    }
+
+    // Get the context to issue a request on `inode` with, applying options of its mountpoint.
+    // Requests modifying the backend, with `write` set, fail on read-only mountpoints.
+    pub(super) fn mount_ctx(&self, ctx: &Context, inode: VfsInode, write: bool) -> Result<Context> {
+        let mut ctx = *ctx;
+        if write {
+            self.check_readonly(inode)?;
+        }
+        if !self.mount_origins.used() {
+            return Ok(ctx);
+        }
+        if let Some(mnt) = self.mount_of(inode)? {
+            if write && mnt.opts.read_only {
+                return Err(Error::from_raw_os_error(libc::EROFS));
+            }
+            if let Some((uid, gid)) = mnt.opts.squash {
+                ctx.uid = uid;
+                ctx.gid = gid;
+                ctx.supp_gid = None;
+            }
+        }
+        Ok(ctx)
+    }
+}
+
+// Check whether opening a file with `flags` may modify it.
+pub(super) fn open_writes(flags: u32) -> bool {
+    let flags = flags as i32;
+    flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0
+}
+
+// Check whether the ioctl `cmd` may modify the file, ioctls taking input may.
+pub(super) fn ioctl_writes(cmd: u32) -> bool {
+    // _IOC_WRITE direction bit.
+    cmd & 0x4000_0000 != 0
+}
+
+#[cfg(all(test, not(feature = "async-io")))]
+mod tests {
+    use super::*;
+    use crate::api::errno::errno_of;
+    use crate::api::filesystem::{FileSystem, OpenOptions, SetattrValid};
+    use crate::api::BackendFileSystem;
+    use std::any::Any;
+    use std::ffi::{CStr, CString};
+
+    struct MemInode {
+        attr: stat64,
+        children: HashMap<CString, u64>,
+        nlookup: u64,
+    }
+
+    impl MemInode {
+        fn new(ino: u64, mode: u32, ctx: &Context) -> Self {
+            let mut attr: stat64 = unsafe { std::mem::zeroed() };
+            attr.st_ino = ino;
+            attr.st_mode = mode;
+            attr.st_uid = ctx.uid;
+            attr.st_gid = ctx.gid;
+            MemInode {
+                attr,
+                children: HashMap::new(),
+                nlookup: 0,
+            }
+        }
+    }
+
+    // Backend keeping a tree of empty files and directories in memory.
+    struct MemFs {
+        inodes: Mutex<HashMap<u64, MemInode>>,
+        destroyed: AtomicBool,
+    }
+
+    impl MemFs {
+        fn new() -> Self {
+            let root = MemInode::new(1, libc::S_IFDIR | 0o755, &Context::default());
+            MemFs {
+                inodes: Mutex::new(HashMap::from([(1, root)])),
+                destroyed: AtomicBool::new(false),
+            }
+        }
+
+        fn entry(ino: u64, inode: &MemInode) -> Entry {
+            Entry {
+                inode: ino,
+                attr: inode.attr,
+                ..Default::default()
+            }
+        }
+    }
+
+    impl FileSystem for MemFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn destroy(&self) {
+            self.destroyed.store(true, Ordering::Relaxed);
+        }
+
+        fn lookup(&self, _: &Context, parent: u64, name: &CStr) -> Result<Entry> {
+            let mut inodes = self.inodes.lock().unwrap();
+            let ino = match inodes[&parent].children.get(name) {
+                Some(ino) => *ino,
+                None => return Err(Error::from_raw_os_error(libc::ENOENT)),
+            };
+            let inode = inodes.get_mut(&ino).unwrap();
+            inode.nlookup += 1;
+            Ok(Self::entry(ino, inode))
+        }
+
+        fn forget(&self, _: &Context, inode: u64, count: u64) {
+            if let Some(inode) = self.inodes.lock().unwrap().get_mut(&inode) {
+                inode.nlookup -= count;
+            }
+        }
+
+        fn debug_nlookup(&self, inode: u64) -> Option<u64> {
+            self.inodes.lock().unwrap().get(&inode).map(|i| i.nlookup)
+        }
+
+        fn getattr(&self, _: &Context, inode: u64, _: Option<u64>) -> Result<(stat64, Duration)> {
+            Ok((self.inodes.lock().unwrap()[&inode].attr, Duration::ZERO))
+        }
+
+        fn setattr(
+            &self,
+            _: &Context,
+            inode: u64,
+            attr: stat64,
+            _: Option<u64>,
+            _: SetattrValid,
+        ) -> Result<(stat64, Duration)> {
+            let mut inodes = self.inodes.lock().unwrap();
+            let inode = inodes.get_mut(&inode).unwrap();
+            inode.attr.st_size = attr.st_size;
+            Ok((inode.attr, Duration::ZERO))
+        }
+
+        fn open(&self, _: &Context, _: u64, _: u32, _: u32) -> Result<(Option<u64>, OpenOptions)> {
+            Ok((None, OpenOptions::empty()))
+        }
+
+        fn create(
+            &self,
+            ctx: &Context,
+            parent: u64,
+            name: &CStr,
+            args: CreateIn,
+        ) -> Result<(Entry, Option<u64>, OpenOptions)> {
+            let mut inodes = self.inodes.lock().unwrap();
+            let ino = inodes.len() as u64 + 1;
+            let mut inode = MemInode::new(ino, libc::S_IFREG | args.mode, ctx);
+            inode.nlookup = 1;
+            let entry = Self::entry(ino, &inode);
+            inodes.insert(ino, inode);
+            inodes
+                .get_mut(&parent)
+                .unwrap()
+                .children
+                .insert(name.to_owned(), ino);
+            Ok((entry, None, OpenOptions::empty()))
+        }
+    }
+
+    impl BackendFileSystem for MemFs {
+        fn mount(&self) -> Result<(Entry, u64)> {
+            let inodes = self.inodes.lock().unwrap();
+            Ok((Self::entry(1, &inodes[&1]), VFS_MAX_INO))
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    fn lookup_path(vfs: &Vfs, path: &str) -> Result<Entry> {
+        let ctx = Context::default();
+        let mut entry = Entry {
+            inode: ROOT_ID,
+            ..Default::default()
+        };
+        for name in path.split('/').filter(|n| !n.is_empty()) {
+            let name = CString::new(name).unwrap();
+            entry = vfs.lookup(&ctx, entry.inode.into(), &name)?;
+        }
+        Ok(entry)
+    }
+
+    fn create(vfs: &Vfs, ctx: &Context, parent: u64, name: &str) -> Result<Entry> {
+        let name = CString::new(name).unwrap();
+        let args = CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            ..Default::default()
+        };
+        vfs.create(ctx, parent.into(), &name, args)
+            .map(|(e, _, _)| e)
+    }
+
+    fn truncate(vfs: &Vfs, inode: u64) -> Result<()> {
+        let ctx = Context::default();
+        let attr: stat64 = unsafe { std::mem::zeroed() };
+        vfs.setattr(&ctx, inode.into(), attr, None, SetattrValid::SIZE)
+            .map(|_| ())
+    }
+
+    fn prepare_vfs() -> (Vfs, Arc<BackFileSystem>) {
+        let vfs = Vfs::new(VfsOptions {
+            no_open: false,
+            ..Default::default()
+        });
+        let fs: Arc<BackFileSystem> = Arc::new(Box::new(MemFs::new()));
+        let rw = vfs
+            .mount_shared(fs.clone(), "/rw/data", MountOptions::default())
+            .unwrap();
+        let opts = MountOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        let ro = vfs.mount_shared(fs.clone(), "/ro/data", opts).unwrap();
+        assert_eq!(rw, ro);
+        (vfs, fs)
+    }
+
+    fn errno<T>(res: Result<T>) -> Option<i32> {
+        res.err().and_then(|e| errno_of(&e))
+    }
+
+    #[test]
+    fn test_mount_shared() {
+        let (vfs, fs) = prepare_vfs();
+        let ctx = Context::default();
+        let rw_root = lookup_path(&vfs, "/rw/data").unwrap();
+        let ro_root = lookup_path(&vfs, "/ro/data").unwrap();
+        assert_ne!(rw_root.inode, ro_root.inode);
+        let (rw_attr, _) = vfs.getattr(&ctx, rw_root.inode.into(), None).unwrap();
+        let (ro_attr, _) = vfs.getattr(&ctx, ro_root.inode.into(), None).unwrap();
+        assert_eq!(rw_attr.st_ino, 1);
+        assert_eq!(ro_attr.st_ino, 1);
+
+        let file = create(&vfs, &ctx, rw_root.inode, "f").unwrap();
+        assert_eq!(
+            errno(create(&vfs, &ctx, ro_root.inode, "g")),
+            Some(libc::EROFS)
+        );
+
+        // Inodes reachable through both mountpoints are the same.
+        let rw_file = lookup_path(&vfs, "/rw/data/f").unwrap();
+        let ro_file = lookup_path(&vfs, "/ro/data/f").unwrap();
+        assert_eq!(rw_file.inode, file.inode);
+        assert_eq!(ro_file.inode, file.inode);
+        assert_eq!(vfs.debug_nlookup(file.inode.into()), Some(3));
+
+        // The file stays with the mountpoint it was created through.
+        truncate(&vfs, file.inode).unwrap();
+        let flags = libc::O_WRONLY as u32;
+        assert!(vfs.open(&ctx, file.inode.into(), flags, 0).is_ok());
+
+        // Forgets are counted once, then the next resolution decides.
+        vfs.forget(&ctx, file.inode.into(), 3);
+        assert_eq!(vfs.debug_nlookup(file.inode.into()), Some(0));
+        let ro_file = lookup_path(&vfs, "/ro/data/f").unwrap();
+        assert_eq!(errno(truncate(&vfs, ro_file.inode)), Some(libc::EROFS));
+        assert_eq!(
+            errno(vfs.open(&ctx, ro_file.inode.into(), flags, 0)),
+            Some(libc::EROFS)
+        );
+        let flags = libc::O_RDONLY as u32;
+        assert!(vfs.open(&ctx, ro_file.inode.into(), flags, 0).is_ok());
+        lookup_path(&vfs, "/rw/data/f").unwrap();
+        assert_eq!(errno(truncate(&vfs, ro_file.inode)), Some(libc::EROFS));
+        vfs.forget(&ctx, ro_file.inode.into(), 2);
+        let rw_file = lookup_path(&vfs, "/rw/data/f").unwrap();
+        truncate(&vfs, rw_file.inode).unwrap();
+
+        // The backend is destroyed when umounted from the last path.
+        let memfs = fs.as_any().downcast_ref::<MemFs>().unwrap();
+        vfs.umount("/ro/data").unwrap();
+        assert!(!memfs.destroyed.load(Ordering::Relaxed));
+        truncate(&vfs, rw_file.inode).unwrap();
+        vfs.umount("/rw/data").unwrap();
+        assert!(memfs.destroyed.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_mount_shared_stale() {
+        let (vfs, _fs) = prepare_vfs();
+        let ctx = Context::default();
+        let rw_root = lookup_path(&vfs, "/rw/data").unwrap();
+        create(&vfs, &ctx, rw_root.inode, "f").unwrap();
+        let file = lookup_path(&vfs, "/ro/data/f").unwrap();
+
+        // Inodes of an umounted mountpoint are stale, others still work.
+        vfs.umount("/rw/data").unwrap();
+        assert_eq!(errno(truncate(&vfs, file.inode)), Some(libc::ESTALE));
+        let ro_root = lookup_path(&vfs, "/ro/data").unwrap();
+        assert!(vfs.getattr(&ctx, ro_root.inode.into(), None).is_ok());
+        assert_eq!(
+            errno(create(&vfs, &ctx, ro_root.inode, "g")),
+            Some(libc::EROFS)
+        );
+    }
+
+    #[test]
+    fn test_mount_squash() {
+        let vfs = Vfs::new(VfsOptions::default());
+        let fs: Arc<BackFileSystem> = Arc::new(Box::new(MemFs::new()));
+        let opts = MountOptions {
+            squash: Some((65534, 65534)),
+            ..Default::default()
+        };
+        vfs.mount_shared(fs, "/squash", opts).unwrap();
+        let ctx = Context {
+            uid: 1000,
+            gid: 1000,
+            pid: 1,
+            supp_gid: Some(1001),
+            ..Default::default()
+        };
+        let root = lookup_path(&vfs, "/squash").unwrap();
+        let entry = create(&vfs, &ctx, root.inode, "f").unwrap();
+        assert_eq!(entry.attr.st_uid, 65534);
+        assert_eq!(entry.attr.st_gid, 65534);
+    }
+
+    #[test]
+    fn test_open_writes() {
+        assert!(!open_writes(libc::O_RDONLY as u32));
+        assert!(open_writes(libc::O_WRONLY as u32));
+        assert!(open_writes(libc::O_RDWR as u32));
+        assert!(open_writes((libc::O_RDONLY | libc::O_TRUNC) as u32));
+    }
+}
@@ -0,0 +1,574 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Splitting of read and write requests exceeding the limits of backend file systems.
+//!
+//! The max size of read and write requests is negotiated with the kernel for the whole Vfs, but
+//! some backends, RPC based ones for example, can't handle that much data at once. Such backends
+//! declare their limits by [BackendFileSystem::max_read](super::BackendFileSystem::max_read) and
+//! [BackendFileSystem::max_write](super::BackendFileSystem::max_write), and the Vfs splits larger
+//! requests into multiple backend calls of consecutive ranges, issued in order of offsets.
+//!
+//! Like `read(2)` and `write(2)`, the reply carries the number of bytes transferred before the
+//! first short or failed backend call, later ranges are not tried. An error is only returned if
+//! the first backend call fails.
+
+use std::io;
+
+/// Progress of a request split into requests of at most `max` bytes.
+///
+/// The async IO path can't pass its reader or writer into a closure issuing futures, so it drives
+/// the requests with [Split::next] and [Split::complete] instead of [split_io].
+pub(super) struct Split {
+    size: u32,
+    max: u32,
+    done: u32,
+    stopped: bool,
+}
+
+impl Split {
+    /// Create the state of a request of `size` bytes, or return `None` if it doesn't need to be
+    /// split.
+    pub(super) fn new(size: u32, max: Option<u32>) -> Option<Self> {
+        match max {
+            Some(max) if max > 0 && max < size => Some(Split {
+                size,
+                max,
+                done: 0,
+                stopped: false,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Get the size of the next request and its offset relative to the start of the whole
+    /// request, or `None` if the whole request is done.
+    pub(super) fn next(&self) -> Option<(u32, u64)> {
+        if self.stopped || self.done >= self.size {
+            return None;
+        }
+        Some(((self.size - self.done).min(self.max), self.done as u64))
+    }
+
+    /// Record the result of the request returned by [Split::next], return an error only if the
+    /// first request fails.
+    pub(super) fn complete(&mut self, res: io::Result<usize>) -> io::Result<()> {
+        let count = (self.size - self.done).min(self.max);
+        match res {
+            Ok(n) => {
+                // Guard against backends claiming more than requested.
+                let n = n.min(count as usize) as u32;
+                self.done += n;
+                self.stopped = n < count;
+            }
+            Err(e) if self.done == 0 => return Err(e),
+            Err(e) => {
+                debug!("vfs: split request stops after {} bytes, {}", self.done, e);
+                self.stopped = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the number of bytes transferred.
+    pub(super) fn done(&self) -> usize {
+        self.done as usize
+    }
+}
+
+/// Issue a request of `size` bytes as requests of at most `max` bytes by `io`, which receives the
+/// size of each request and its offset relative to the start of the whole request.
+///
+/// Return the number of bytes transferred, stopping at the first short or failed request.
+pub(super) fn split_io<F>(size: u32, max: Option<u32>, mut io: F) -> io::Result<usize>
+where
+    F: FnMut(u32, u64) -> io::Result<usize>,
+{
+    let mut split = match Split::new(size, max) {
+        Some(split) => split,
+        None => return io(size, 0),
+    };
+    while let Some((count, off)) = split.next() {
+        split.complete(io(count, off))?;
+    }
+
+    Ok(split.done())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{BackendFileSystem, Vfs, VfsOptions};
+    use crate::api::filesystem::{
+        Context, Entry, FileSystem, SliceReader, VecWriter, ZeroCopyReader, ZeroCopyWriter,
+    };
+    use std::any::Any;
+    use std::io::{self, Read, Write};
+    use std::sync::Mutex;
+
+    use super::*;
+
+    const LIMIT: u32 = 0x1000;
+
+    // Backend serving a single file, which rejects requests larger than `LIMIT`.
+    struct LimitedFs {
+        data: Mutex<Vec<u8>>,
+        // Number of bytes accepted by writes before failing.
+        write_quota: Mutex<usize>,
+        requests: Mutex<Vec<(u64, u32)>>,
+    }
+
+    impl LimitedFs {
+        fn new(data: Vec<u8>, write_quota: usize) -> Self {
+            LimitedFs {
+                data: Mutex::new(data),
+                write_quota: Mutex::new(write_quota),
+                requests: Mutex::new(Vec::new()),
+            }
+        }
+
+        fn do_read<W: Write + ?Sized>(
+            &self,
+            w: &mut W,
+            size: u32,
+            offset: u64,
+        ) -> io::Result<usize> {
+            if size > LIMIT {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            self.requests.lock().unwrap().push((offset, size));
+            let data = self.data.lock().unwrap();
+            let start = (offset as usize).min(data.len());
+            let end = (start + size as usize).min(data.len());
+            w.write_all(&data[start..end])?;
+            Ok(end - start)
+        }
+
+        fn do_write<R: Read + ?Sized>(
+            &self,
+            r: &mut R,
+            size: u32,
+            offset: u64,
+        ) -> io::Result<usize> {
+            if size > LIMIT {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            self.requests.lock().unwrap().push((offset, size));
+            let mut quota = self.write_quota.lock().unwrap();
+            if *quota == 0 {
+                return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+            }
+            let count = (size as usize).min(*quota);
+            *quota -= count;
+            let mut buf = vec![0u8; count];
+            r.read_exact(&mut buf)?;
+            let mut data = self.data.lock().unwrap();
+            let end = offset as usize + count;
+            if data.len() < end {
+                data.resize(end, 0);
+            }
+            data[offset as usize..end].copy_from_slice(&buf);
+            Ok(count)
+        }
+    }
+
+    impl FileSystem for LimitedFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn read(
+            &self,
+            _: &Context,
+            _: u64,
+            _: u64,
+            w: &mut dyn ZeroCopyWriter,
+            size: u32,
+            offset: u64,
+            _: Option<u64>,
+            _: u32,
+        ) -> io::Result<usize> {
+            self.do_read(w, size, offset)
+        }
+
+        fn write(
+            &self,
+            _: &Context,
+            _: u64,
+            _: u64,
+            r: &mut dyn ZeroCopyReader,
+            size: u32,
+            offset: u64,
+            _: Option<u64>,
+            _: bool,
+            _: u32,
+            _: u32,
+        ) -> io::Result<usize> {
+            self.do_write(r, size, offset)
+        }
+    }
+
+    impl BackendFileSystem for LimitedFs {
+        fn mount(&self) -> io::Result<(Entry, u64)> {
+            let entry = Entry {
+                inode: 1,
+                ..Default::default()
+            };
+            Ok((entry, 0))
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn max_read(&self) -> Option<u32> {
+            Some(LIMIT)
+        }
+
+        fn max_write(&self) -> Option<u32> {
+            Some(LIMIT)
+        }
+    }
+
+    // Async reader or writer over the mem_io ones, only their sync part is used by `LimitedFs`.
+
+    fn prepare_vfs(fs: LimitedFs) -> (Vfs, u64) {
+        let vfs = Vfs::new(VfsOptions::default());
+        let idx = vfs.mount(Box::new(fs), "/limited").unwrap();
+        (vfs, ((idx as u64) << 56) | 2)
+    }
+
+    fn with_backend<R>(vfs: &Vfs, f: impl FnOnce(&LimitedFs) -> R) -> R {
+        let fs = vfs.get_rootfs("/limited").unwrap().unwrap();
+        f(fs.as_any().downcast_ref::<LimitedFs>().unwrap())
+    }
+
+    fn take_requests(vfs: &Vfs) -> Vec<(u64, u32)> {
+        with_backend(vfs, |fs| std::mem::take(&mut *fs.requests.lock().unwrap()))
+    }
+
+    #[test]
+    fn test_split_io() {
+        let mut calls = Vec::new();
+        let res = split_io(10, Some(4), |size, off| {
+            calls.push((size, off));
+            Ok(size as usize)
+        });
+        assert_eq!(res.unwrap(), 10);
+        assert_eq!(calls, vec![(4, 0), (4, 4), (2, 8)]);
+
+        // Requests within the limit are not split.
+        let mut calls = Vec::new();
+        split_io(4, Some(4), |size, off| {
+            calls.push((size, off));
+            Ok(size as usize)
+        })
+        .unwrap();
+        split_io(10, None, |size, off| {
+            calls.push((size, off));
+            Ok(size as usize)
+        })
+        .unwrap();
+        assert_eq!(calls, vec![(4, 0), (10, 0)]);
+
+        let err = split_io(10, Some(4), |_, _| {
+            Err(io::Error::from_raw_os_error(libc::EIO))
+        });
+        assert_eq!(err.unwrap_err().raw_os_error(), Some(libc::EIO));
+        let res = split_io(10, Some(4), |size, off| {
+            if off == 0 {
+                Ok(size as usize)
+            } else {
+                Err(io::Error::from_raw_os_error(libc::EIO))
+            }
+        });
+        assert_eq!(res.unwrap(), 4);
+    }
+
+    #[test]
+    fn test_vfs_split_read() {
+        let content: Vec<u8> = (0..0x2800u32).map(|v| (v % 251) as u8).collect();
+        let (vfs, ino) = prepare_vfs(LimitedFs::new(content.clone(), 0));
+        let ctx = Context::default();
+
+        // Replies of split requests are concatenated.
+        let mut buf = Vec::new();
+        let count = vfs
+            .read(
+                &ctx,
+                ino.into(),
+                0,
+                &mut VecWriter::new(&mut buf),
+                0x2000,
+                0x100,
+                None,
+                0,
+            )
+            .unwrap();
+        assert_eq!(count, 0x2000);
+        assert_eq!(buf, &content[0x100..0x2100]);
+        assert_eq!(take_requests(&vfs), vec![(0x100, 0x1000), (0x1100, 0x1000)]);
+
+        // Stop at the first short read.
+        let mut buf = Vec::new();
+        let count = vfs
+            .read(
+                &ctx,
+                ino.into(),
+                0,
+                &mut VecWriter::new(&mut buf),
+                0x4000,
+                0x1000,
+                None,
+                0,
+            )
+            .unwrap();
+        assert_eq!(count, 0x1800);
+        assert_eq!(buf, &content[0x1000..]);
+        assert_eq!(
+            take_requests(&vfs),
+            vec![(0x1000, 0x1000), (0x2000, 0x1000)]
+        );
+    }
+
+    #[test]
+    fn test_vfs_split_write() {
+        let (vfs, ino) = prepare_vfs(LimitedFs::new(Vec::new(), 0x2800));
+        let ctx = Context::default();
+        let content: Vec<u8> = (0..0x4000u32).map(|v| (v % 251) as u8).collect();
+        let write = |offset: u64, size: u32| {
+            let mut r = SliceReader::new(&content[..size as usize]);
+            vfs.write(&ctx, ino.into(), 0, &mut r, size, offset, None, false, 0, 0)
+        };
+
+        // Written counts of split requests are summed.
+        assert_eq!(write(0, 0x2000).unwrap(), 0x2000);
+        let data = with_backend(&vfs, |fs| fs.data.lock().unwrap().clone());
+        assert_eq!(data, &content[..0x2000]);
+        assert_eq!(take_requests(&vfs), vec![(0, 0x1000), (0x1000, 0x1000)]);
+
+        // Stop at the first short write, and report bytes written before a failure.
+        assert_eq!(write(0x2000, 0x3000).unwrap(), 0x800);
+        assert_eq!(take_requests(&vfs), vec![(0x2000, 0x1000)]);
+        let err = write(0x2800, 0x2000).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+    }
+
+    #[cfg(feature = "async-io")]
+    mod async_io {
+        use std::ffi::CStr;
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        use futures::executor::block_on;
+
+        use super::*;
+        use crate::abi::fuse_abi::{stat64, CreateIn, OpenOptions, SetattrValid};
+        use crate::api::filesystem::{AsyncFileSystem, AsyncZeroCopyReader, AsyncZeroCopyWriter};
+        use crate::transport::{AsyncFileReadWriteVolatile, FileReadWriteVolatile};
+
+        fn enosys<T>() -> io::Result<T> {
+            Err(io::Error::from_raw_os_error(libc::ENOSYS))
+        }
+
+        #[async_trait::async_trait]
+        impl AsyncFileSystem for LimitedFs {
+            async fn async_lookup(&self, _: &Context, _: u64, _: &CStr) -> io::Result<Entry> {
+                enosys()
+            }
+
+            async fn async_getattr(
+                &self,
+                _: &Context,
+                _: u64,
+                _: Option<u64>,
+            ) -> io::Result<(stat64, Duration)> {
+                enosys()
+            }
+
+            async fn async_setattr(
+                &self,
+                _: &Context,
+                _: u64,
+                _: stat64,
+                _: Option<u64>,
+                _: SetattrValid,
+            ) -> io::Result<(stat64, Duration)> {
+                enosys()
+            }
+
+            async fn async_open(
+                &self,
+                _: &Context,
+                _: u64,
+                _: u32,
+                _: u32,
+            ) -> io::Result<(Option<u64>, OpenOptions)> {
+                enosys()
+            }
+
+            async fn async_create(
+                &self,
+                _: &Context,
+                _: u64,
+                _: &CStr,
+                _: CreateIn,
+            ) -> io::Result<(Entry, Option<u64>, OpenOptions)> {
+                enosys()
+            }
+
+            async fn async_read(
+                &self,
+                _: &Context,
+                _: u64,
+                _: u64,
+                w: &mut (dyn AsyncZeroCopyWriter + Send),
+                size: u32,
+                offset: u64,
+                _: Option<u64>,
+                _: u32,
+            ) -> io::Result<usize> {
+                self.do_read(w, size, offset)
+            }
+
+            async fn async_write(
+                &self,
+                _: &Context,
+                _: u64,
+                _: u64,
+                r: &mut (dyn AsyncZeroCopyReader + Send),
+                size: u32,
+                offset: u64,
+                _: Option<u64>,
+                _: bool,
+                _: u32,
+                _: u32,
+            ) -> io::Result<usize> {
+                self.do_write(r, size, offset)
+            }
+
+            async fn async_fsync(&self, _: &Context, _: u64, _: bool, _: u64) -> io::Result<()> {
+                enosys()
+            }
+
+            async fn async_fallocate(
+                &self,
+                _: &Context,
+                _: u64,
+                _: u64,
+                _: u32,
+                _: u64,
+                _: u64,
+            ) -> io::Result<()> {
+                enosys()
+            }
+
+            async fn async_fsyncdir(&self, _: &Context, _: u64, _: bool, _: u64) -> io::Result<()> {
+                enosys()
+            }
+        }
+
+        struct AsyncMem<T>(T);
+
+        #[cfg(feature = "async-io")]
+        impl<T: Read> Read for AsyncMem<T> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.0.read(buf)
+            }
+        }
+
+        impl<T: ZeroCopyReader> ZeroCopyReader for AsyncMem<T> {
+            fn read_to(
+                &mut self,
+                f: &mut dyn FileReadWriteVolatile,
+                count: usize,
+                off: u64,
+            ) -> io::Result<usize> {
+                self.0.read_to(f, count, off)
+            }
+        }
+
+        #[async_trait::async_trait(?Send)]
+        impl<T: ZeroCopyReader> AsyncZeroCopyReader for AsyncMem<T> {
+            async fn async_read_to(
+                &mut self,
+                _: Arc<dyn AsyncFileReadWriteVolatile>,
+                _: usize,
+                _: u64,
+            ) -> io::Result<usize> {
+                enosys()
+            }
+        }
+
+        impl<T: Write> Write for AsyncMem<T> {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                self.0.flush()
+            }
+        }
+
+        impl<T: ZeroCopyWriter> ZeroCopyWriter for AsyncMem<T> {
+            fn write_from(
+                &mut self,
+                f: &mut dyn FileReadWriteVolatile,
+                count: usize,
+                off: u64,
+            ) -> io::Result<usize> {
+                self.0.write_from(f, count, off)
+            }
+        }
+
+        #[async_trait::async_trait(?Send)]
+        impl<T: ZeroCopyWriter> AsyncZeroCopyWriter for AsyncMem<T> {
+            async fn async_write_from(
+                &mut self,
+                _: Arc<dyn AsyncFileReadWriteVolatile>,
+                _: usize,
+                _: u64,
+            ) -> io::Result<usize> {
+                enosys()
+            }
+        }
+
+        #[test]
+        fn test_vfs_async_split_io() {
+            let content: Vec<u8> = (0..0x2800u32).map(|v| (v % 251) as u8).collect();
+            let (vfs, ino) = prepare_vfs(LimitedFs::new(content.clone(), 0x1800));
+            let ctx = Context::default();
+
+            let mut buf = Vec::new();
+            let mut w = AsyncMem(VecWriter::new(&mut buf));
+            let read = vfs.async_read(&ctx, ino.into(), 0, &mut w, 0x2000, 0x100, None, 0);
+            assert_eq!(block_on(read).unwrap(), 0x2000);
+            assert_eq!(buf, &content[0x100..0x2100]);
+            assert_eq!(take_requests(&vfs), vec![(0x100, 0x1000), (0x1100, 0x1000)]);
+
+            // Stop at the first short write, and fail if nothing is written.
+            let write = |offset: u64| {
+                let mut r = AsyncMem(SliceReader::new(&content[..0x2000]));
+                block_on(vfs.async_write(
+                    &ctx,
+                    ino.into(),
+                    0,
+                    &mut r,
+                    0x2000,
+                    offset,
+                    None,
+                    false,
+                    0,
+                    0,
+                ))
+            };
+            assert_eq!(write(0x2800).unwrap(), 0x1800);
+            assert_eq!(
+                take_requests(&vfs),
+                vec![(0x2800, 0x1000), (0x3800, 0x1000)]
+            );
+            let data = with_backend(&vfs, |fs| fs.data.lock().unwrap().clone());
+            assert_eq!(&data[0x2800..], &content[..0x1800]);
+            let err = write(0x4000).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+        }
+    }
+}
@@ -0,0 +1,291 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! File system statistics of the pseudo fs.
+//!
+//! Backends reply to `statfs` on their own inodes, but the root of the Vfs, like every directory
+//! of the pseudo fs, has no storage behind it, so tools like `df` run by the guest on the mount
+//! show the made-up numbers of the pseudo fs by default. [RootStatfs] selects the statistics
+//! replied for directories of the pseudo fs instead: the ones of a single mounted backend, or the
+//! sum of the ones of all mounted backends.
+
+use std::io::Result;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use super::{Vfs, VfsIndex};
+use crate::abi::fuse_abi::statvfs64;
+use crate::api::errno::errno_of;
+use crate::api::filesystem::{Context, FileSystem};
+use crate::api::pseudo_fs::PseudoFs;
+
+/// Statistics replied to `statfs` on directories of the pseudo fs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config-serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum RootStatfs {
+    /// The statistics of the pseudo fs, which has no blocks nor inodes.
+    #[default]
+    Pseudo,
+    /// The statistics of the root of the backend mounted as the index, or the ones of the pseudo
+    /// fs while it isn't mounted.
+    Backend(VfsIndex),
+    /// The sums of the blocks and inodes of all mounted backends, counted once for backends
+    /// mounted at several paths. Blocks are counted in units of the largest fragment size of the
+    /// backends, and the name length is the smallest one. Backends sharing storage on the host
+    /// are counted as many times as they're mounted.
+    Aggregate,
+}
+
+impl Vfs {
+    /// Set the statistics replied to `statfs` on directories of the pseudo fs.
+    pub fn set_root_statfs(&self, statfs: RootStatfs) {
+        // Serialize mount operations. Do not expect poisoned lock here.
+        let _guard = self.lock.lock().unwrap();
+        let mut opts = *self.opts.load().deref().deref();
+        opts.root_statfs = statfs;
+        self.opts.store(Arc::new(opts));
+    }
+
+    // Get the statistics of directory `ino` of the pseudo fs `fs`.
+    pub(super) fn pseudo_statfs(
+        &self,
+        fs: &PseudoFs,
+        ctx: &Context,
+        ino: u64,
+    ) -> Result<statvfs64> {
+        match self.opts.load().root_statfs {
+            RootStatfs::Pseudo => fs.statfs(ctx, ino),
+            RootStatfs::Backend(fs_idx) => {
+                let root = self
+                    .mountpoints
+                    .load()
+                    .values()
+                    .find(|mnt| mnt.fs_idx == fs_idx && !mnt.alias)
+                    .map(|mnt| mnt.ino);
+                match (root, self.get_fs_by_idx(fs_idx)) {
+                    (Some(root), Ok(backend)) => backend.statfs(ctx, root),
+                    _ => fs.statfs(ctx, ino),
+                }
+            }
+            RootStatfs::Aggregate => {
+                let mut all = Vec::new();
+                for (backend, root) in self.mounted_roots() {
+                    match backend.statfs(ctx, root) {
+                        Ok(st) => all.push(st),
+                        Err(e) if errno_of(&e) == Some(libc::ENOSYS) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                if all.is_empty() {
+                    fs.statfs(ctx, ino)
+                } else {
+                    Ok(aggregate(&all))
+                }
+            }
+        }
+    }
+}
+
+// Sum the blocks and inodes of `all`, counting blocks in units of the largest fragment size.
+fn aggregate(all: &[statvfs64]) -> statvfs64 {
+    let frsize = |st: &statvfs64| {
+        if st.f_frsize > 0 {
+            st.f_frsize
+        } else {
+            st.f_bsize
+        }
+    };
+    let unit = all.iter().map(frsize).max().unwrap_or(0).max(1);
+    let blocks = |count: fn(&statvfs64) -> u64| -> u64 {
+        let bytes: u128 = all
+            .iter()
+            .map(|st| count(st) as u128 * frsize(st) as u128)
+            .sum();
+        (bytes / unit as u128).min(u64::MAX as u128) as u64
+    };
+    let inodes = |count: fn(&statvfs64) -> u64| -> u64 {
+        all.iter()
+            .fold(0u64, |sum, st| sum.saturating_add(count(st)))
+    };
+
+    // Safe because we are zero-initializing a struct with only POD fields.
+    let mut out: statvfs64 = unsafe { std::mem::zeroed() };
+    out.f_bsize = all.iter().map(|st| st.f_bsize).max().unwrap_or(0);
+    out.f_frsize = unit;
+    out.f_blocks = blocks(|st| st.f_blocks);
+    out.f_bfree = blocks(|st| st.f_bfree);
+    out.f_bavail = blocks(|st| st.f_bavail);
+    out.f_files = inodes(|st| st.f_files);
+    out.f_ffree = inodes(|st| st.f_ffree);
+    out.f_favail = inodes(|st| st.f_favail);
+    out.f_namemax = all
+        .iter()
+        .map(|st| st.f_namemax)
+        .filter(|n| *n > 0)
+        .min()
+        .unwrap_or(255);
+    out
+}
+
+#[cfg(all(test, not(feature = "async-io")))]
+mod tests {
+    use super::*;
+    use crate::abi::fuse_abi::{Attr, ROOT_ID};
+    use crate::api::filesystem::Entry;
+    use crate::api::{BackFileSystem, BackendFileSystem, MountOptions, VfsOptions};
+    use std::any::Any;
+    use std::ffi::CString;
+    use std::io::Error;
+
+    // Backend with `blocks` blocks of `frsize` bytes, and as many inodes.
+    struct StatFs {
+        frsize: u64,
+        blocks: u64,
+        namemax: u64,
+    }
+
+    impl FileSystem for StatFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn statfs(&self, _: &Context, inode: u64) -> Result<statvfs64> {
+            if self.blocks == 0 {
+                return Err(Error::from_raw_os_error(libc::EIO));
+            }
+            // Safe because we are zero-initializing a struct with only POD fields.
+            let mut st: statvfs64 = unsafe { std::mem::zeroed() };
+            st.f_bsize = 4096;
+            st.f_frsize = self.frsize;
+            st.f_blocks = self.blocks;
+            st.f_bfree = self.blocks / 2;
+            st.f_bavail = self.blocks / 4;
+            st.f_files = self.blocks + inode;
+            st.f_ffree = self.blocks;
+            st.f_namemax = self.namemax;
+            Ok(st)
+        }
+    }
+
+    impl BackendFileSystem for StatFs {
+        fn mount(&self) -> Result<(Entry, u64)> {
+            let attr = Attr {
+                ino: ROOT_ID,
+                mode: libc::S_IFDIR | 0o755,
+                ..Default::default()
+            };
+            let entry = Entry {
+                inode: ROOT_ID,
+                attr: attr.into(),
+                ..Default::default()
+            };
+            Ok((entry, 1 << 20))
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    fn statfs(vfs: &Vfs, inode: u64) -> Result<statvfs64> {
+        FileSystem::statfs(vfs, &Context::default(), inode.into())
+    }
+
+    #[test]
+    fn test_root_statfs() {
+        let vfs = Vfs::default();
+        let a = StatFs {
+            frsize: 1024,
+            blocks: 800,
+            namemax: 255,
+        };
+        let b = StatFs {
+            frsize: 4096,
+            blocks: 100,
+            namemax: 128,
+        };
+        let a = vfs.mount(Box::new(a), "/a").unwrap();
+        vfs.mount(Box::new(b), "/x/b").unwrap();
+        let x = vfs
+            .lookup(
+                &Context::default(),
+                ROOT_ID.into(),
+                &CString::new("x").unwrap(),
+            )
+            .unwrap()
+            .inode;
+
+        // The pseudo fs has no blocks by default.
+        let st = statfs(&vfs, ROOT_ID).unwrap();
+        assert_eq!(st.f_blocks, 0);
+        assert_eq!(st.f_namemax, 255);
+
+        vfs.set_root_statfs(RootStatfs::Backend(a));
+        for inode in [ROOT_ID, x] {
+            let st = statfs(&vfs, inode).unwrap();
+            assert_eq!(st.f_blocks, 800);
+            assert_eq!(st.f_frsize, 1024);
+            assert_eq!(st.f_files, 801);
+        }
+
+        // Blocks are counted in the largest fragment size.
+        vfs.set_root_statfs(RootStatfs::Aggregate);
+        let st = statfs(&vfs, ROOT_ID).unwrap();
+        assert_eq!(st.f_frsize, 4096);
+        assert_eq!(st.f_bsize, 4096);
+        assert_eq!(st.f_blocks, 300);
+        assert_eq!(st.f_bfree, 150);
+        assert_eq!(st.f_bavail, 75);
+        assert_eq!(st.f_files, 902);
+        assert_eq!(st.f_ffree, 900);
+        assert_eq!(st.f_namemax, 128);
+
+        // Backends mounted at several paths are counted once.
+        let shared: Arc<BackFileSystem> = Arc::new(Box::new(StatFs {
+            frsize: 512,
+            blocks: 8,
+            namemax: 255,
+        }));
+        vfs.mount_shared(shared.clone(), "/s1", MountOptions::default())
+            .unwrap();
+        vfs.mount_shared(shared, "/s2", MountOptions::default())
+            .unwrap();
+        assert_eq!(statfs(&vfs, ROOT_ID).unwrap().f_blocks, 301);
+        vfs.umount("/s1").unwrap();
+        vfs.umount("/s2").unwrap();
+
+        // Failures of backends fail the aggregation.
+        vfs.mount(
+            Box::new(StatFs {
+                frsize: 4096,
+                blocks: 0,
+                namemax: 255,
+            }),
+            "/broken",
+        )
+        .unwrap();
+        assert_eq!(
+            statfs(&vfs, ROOT_ID).unwrap_err().raw_os_error(),
+            Some(libc::EIO)
+        );
+        vfs.umount("/broken").unwrap();
+
+        // Backends keep replying with their own statistics.
+        let st = statfs(&vfs, (a as u64) << 56 | ROOT_ID).unwrap();
+        assert_eq!(st.f_blocks, 800);
+
+        // The pseudo fs replies again once the backend is umounted.
+        vfs.set_root_statfs(RootStatfs::Backend(a));
+        vfs.umount("/a").unwrap();
+        assert_eq!(statfs(&vfs, ROOT_ID).unwrap().f_blocks, 0);
+
+        let vfs = Vfs::new(VfsOptions {
+            root_statfs: RootStatfs::Aggregate,
+            ..Default::default()
+        });
+        assert_eq!(statfs(&vfs, ROOT_ID).unwrap().f_blocks, 0);
+    }
+}
@@ -5,6 +5,8 @@ use super::*;
 use crate::abi::fuse_abi::{stat64, statvfs64};
 #[cfg(any(feature = "vhost-user-fs", feature = "virtiofs"))]
 use crate::abi::virtio_fs;
+use crate::api::errno::errno_of;
+use crate::api::filesystem::FileLock;
 #[cfg(any(feature = "vhost-user-fs", feature = "virtiofs"))]
 use crate::transport::FsCacheReqHandler;
 
@@ -45,50 +47,144 @@ impl FileSystem for Vfs {
             for fs in superblocks.iter().flatten() {
                 fs.init(n_opts.out_opts)?;
             }
+            self.destroyed.lock().unwrap().clear();
             self.initialized.store(true, Ordering::Release);
         }
 
         Ok(n_opts.out_opts)
     }
 
+    // All backends share the granularity replied to the kernel, so advertise the coarsest one.
+    // Backends mounted after `init()` can't change it anymore.
+    fn time_gran(&self) -> u32 {
+        self.superblocks
+            .load()
+            .iter()
+            .flatten()
+            .map(|fs| fs.time_gran())
+            .max()
+            .unwrap_or(1)
+    }
+
     fn destroy(&self) {
         if self.initialized() {
+            // Serialize with mount operations, and ensure that every backend fs only get
+            // destroy()ed once, even if it's umounted later on.
+            let _guard = self.lock.lock().unwrap();
             let superblocks = self.superblocks.load();
+            let mut destroyed = self.destroyed.lock().unwrap();
 
-            for fs in superblocks.iter().flatten() {
-                fs.destroy();
+            for (idx, fs) in superblocks.iter().enumerate() {
+                if let Some(fs) = fs {
+                    if destroyed.insert(idx as VfsIndex) {
+                        fs.destroy();
+                    }
+                }
             }
 
             self.initialized.store(false, Ordering::Release);
         }
     }
 
+    fn forget_all(&self) {
+        self.inode_refs.clear();
+        self.root.forget_all();
+        let superblocks = self.superblocks.load();
+        let destroyed = self.destroyed.lock().unwrap().clone();
+
+        for (idx, fs) in superblocks.iter().enumerate() {
+            if let Some(fs) = fs {
+                if !destroyed.contains(&(idx as VfsIndex)) {
+                    fs.forget_all();
+                }
+            }
+        }
+    }
+
+    // The request may be handled by any backend, so let all of them match the unique ID.
+    fn interrupt(&self, ctx: &Context, unique: u64) {
+        for fs in self.superblocks.load().iter().flatten() {
+            fs.interrupt(ctx, unique);
+        }
+    }
+
+    fn prepare_destroy(&self) -> Result<()> {
+        let superblocks = self.superblocks.load();
+        let destroyed = self.destroyed.lock().unwrap().clone();
+
+        for (idx, fs) in superblocks.iter().enumerate() {
+            if let Some(fs) = fs {
+                if !destroyed.contains(&(idx as VfsIndex)) {
+                    fs.prepare_destroy()?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     fn lookup(&self, ctx: &Context, parent: VfsInode, name: &CStr) -> Result<Entry> {
         // Don't use is_safe_path_component(), allow "." and ".." for NFS export support
         if name.to_bytes_with_nul().contains(&SLASH_ASCII) {
             return Err(io::Error::from_raw_os_error(libc::EINVAL));
         }
 
+        let ctx = &self.mount_ctx(ctx, parent, false)?;
         match self.get_real_rootfs(parent)? {
             (Left(fs), idata) => self.lookup_pseudo(fs, idata, ctx, name),
-            (Right(fs), idata) => {
+            (Right(fs), idata) => match self.cached_lookup(parent, name) {
+                Some(entry) => Ok(entry),
                 // parent is in an underlying rootfs
-                let mut entry = fs.lookup(ctx, idata.ino(), name)?;
-                // lookup success, hash it to a real fuse inode
-                entry.inode = self.convert_inode(idata.fs_idx(), entry.inode)?;
-                Ok(entry)
-            }
+                None => {
+                    let res = fs.lookup(ctx, idata.ino(), name);
+                    self.backend_lookup(idata.fs_idx(), parent, name, res)
+                }
+            },
         }
     }
 
     fn forget(&self, ctx: &Context, inode: VfsInode, count: u64) {
-        match self.get_real_rootfs(inode) {
-            Ok(real_rootfs) => match real_rootfs {
+        self.inode_refs.put(inode.0, count);
+        // Forgets of inodes of umounted backends are dropped, the inodes are gone already.
+        if let Ok(real_rootfs) = self.get_real_rootfs(inode) {
+            match real_rootfs {
                 (Left(fs), idata) => fs.forget(ctx, idata.ino(), count),
-                (Right(fs), idata) => fs.forget(ctx, idata.ino(), count),
-            },
-            Err(e) => {
-                error!("vfs::forget: failed to get_real_rootfs {:?}", e);
+                (Right(fs), idata) => {
+                    self.forget_origin(inode, count);
+                    // Lookup references taken by cache hits are not forwarded to the backend.
+                    let count = match self.lookup_cache.as_ref() {
+                        Some(cache) => cache.forget(inode.0, count),
+                        None => count,
+                    };
+                    if count > 0 {
+                        fs.forget(ctx, idata.ino(), count)
+                    }
+                }
+            }
+        }
+    }
+
+    fn debug_nlookup(&self, inode: VfsInode) -> Option<u64> {
+        match self.get_real_rootfs(inode).ok()? {
+            // Lookup counts of the pseudo fs are not tracked.
+            (Left(_), _) => None,
+            (Right(fs), idata) => {
+                // Mount roots are returned to the client without taking backend references.
+                let is_mnt_root = self
+                    .mountpoints
+                    .load()
+                    .values()
+                    .any(|mnt| mnt.fs_idx == idata.fs_idx() && mnt.ino == idata.ino());
+                if is_mnt_root {
+                    None
+                } else {
+                    let pending = self
+                        .lookup_cache
+                        .as_ref()
+                        .map(|c| c.pending(inode.0))
+                        .unwrap_or(0);
+                    fs.debug_nlookup(idata.ino()).map(|n| n + pending)
+                }
             }
         }
     }
@@ -99,9 +195,33 @@ impl FileSystem for Vfs {
         inode: VfsInode,
         handle: Option<VfsHandle>,
     ) -> Result<(stat64, Duration)> {
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
         match self.get_real_rootfs(inode)? {
             (Left(fs), idata) => fs.getattr(ctx, idata.ino(), handle),
-            (Right(fs), idata) => fs.getattr(ctx, idata.ino(), handle),
+            (Right(fs), idata) => {
+                self.backend_attr(idata.fs_idx(), fs.getattr(ctx, idata.ino(), handle))
+            }
+        }
+    }
+
+    fn statx(
+        &self,
+        ctx: &Context,
+        inode: VfsInode,
+        handle: Option<VfsHandle>,
+        flags: u32,
+        mask: u32,
+    ) -> Result<(Statx, Duration)> {
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
+        match self.get_real_rootfs(inode)? {
+            (Left(fs), idata) => fs.statx(ctx, idata.ino(), handle, flags, mask),
+            (Right(fs), idata) => {
+                fs.statx(ctx, idata.ino(), handle, flags, mask)
+                    .map(|(mut stx, timeout)| {
+                        self.transform_statx(idata.fs_idx(), &mut stx);
+                        (stx, self.override_attr_timeout(idata.fs_idx(), timeout))
+                    })
+            }
         }
     }
 
@@ -113,13 +233,19 @@ impl FileSystem for Vfs {
         handle: Option<u64>,
         valid: SetattrValid,
     ) -> Result<(stat64, Duration)> {
-        match self.get_real_rootfs(inode)? {
+        let ctx = &self.mount_ctx(ctx, inode, true)?;
+        let res = match self.get_real_rootfs(inode)? {
             (Left(fs), idata) => fs.setattr(ctx, idata.ino(), attr, handle, valid),
-            (Right(fs), idata) => fs.setattr(ctx, idata.ino(), attr, handle, valid),
-        }
+            (Right(fs), idata) => {
+                let res = fs.setattr(ctx, idata.ino(), attr, handle, valid);
+                self.backend_attr(idata.fs_idx(), res)
+            }
+        };
+        self.modified(inode, res)
     }
 
     fn readlink(&self, ctx: &Context, inode: VfsInode) -> Result<Vec<u8>> {
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
         match self.get_real_rootfs(inode)? {
             (Left(fs), idata) => fs.readlink(ctx, idata.ino()),
             (Right(fs), idata) => fs.readlink(ctx, idata.ino()),
@@ -135,13 +261,22 @@ impl FileSystem for Vfs {
     ) -> Result<Entry> {
         validate_path_component(name)?;
 
-        match self.get_real_rootfs(parent)? {
+        let ctx = &self.mount_ctx(ctx, parent, true)?;
+        let res = match self.get_real_rootfs(parent)? {
             (Left(fs), idata) => fs.symlink(ctx, linkname, idata.ino(), name),
-            (Right(fs), idata) => fs.symlink(ctx, linkname, idata.ino(), name).map(|mut e| {
-                e.inode = self.convert_inode(idata.fs_idx(), e.inode)?;
-                Ok(e)
-            })?,
+            (Right(fs), idata) => fs
+                .symlink(ctx, linkname, idata.ino(), name)
+                .and_then(|mut e| {
+                    self.convert_entry(idata.fs_idx(), &mut e)?;
+                    Ok(e)
+                }),
+        };
+        let res = self.track_readonly(parent, res);
+        if let Ok(entry) = &res {
+            self.record_origin(parent, entry);
         }
+        self.invalidate_entry(parent, name);
+        res
     }
 
     fn mknod(
@@ -155,16 +290,22 @@ impl FileSystem for Vfs {
     ) -> Result<Entry> {
         validate_path_component(name)?;
 
-        match self.get_real_rootfs(inode)? {
+        let ctx = &self.mount_ctx(ctx, inode, true)?;
+        let res = match self.get_real_rootfs(inode)? {
             (Left(fs), idata) => fs.mknod(ctx, idata.ino(), name, mode, rdev, umask),
-            (Right(fs), idata) => {
-                fs.mknod(ctx, idata.ino(), name, mode, rdev, umask)
-                    .map(|mut e| {
-                        e.inode = self.convert_inode(idata.fs_idx(), e.inode)?;
-                        Ok(e)
-                    })?
-            }
+            (Right(fs), idata) => fs
+                .mknod(ctx, idata.ino(), name, mode, rdev, umask)
+                .and_then(|mut e| {
+                    self.convert_entry(idata.fs_idx(), &mut e)?;
+                    Ok(e)
+                }),
+        };
+        let res = self.track_readonly(inode, res);
+        if let Ok(entry) = &res {
+            self.record_origin(inode, entry);
         }
+        self.invalidate_entry(inode, name);
+        res
     }
 
     fn mkdir(
@@ -177,31 +318,49 @@ impl FileSystem for Vfs {
     ) -> Result<Entry> {
         validate_path_component(name)?;
 
-        match self.get_real_rootfs(parent)? {
+        let ctx = &self.mount_ctx(ctx, parent, true)?;
+        let res = match self.get_real_rootfs(parent)? {
             (Left(fs), idata) => fs.mkdir(ctx, idata.ino(), name, mode, umask),
-            (Right(fs), idata) => fs.mkdir(ctx, idata.ino(), name, mode, umask).map(|mut e| {
-                e.inode = self.convert_inode(idata.fs_idx(), e.inode)?;
-                Ok(e)
-            })?,
+            (Right(fs), idata) => {
+                fs.mkdir(ctx, idata.ino(), name, mode, umask)
+                    .and_then(|mut e| {
+                        self.convert_entry(idata.fs_idx(), &mut e)?;
+                        Ok(e)
+                    })
+            }
+        };
+        let res = self.track_readonly(parent, res);
+        if let Ok(entry) = &res {
+            self.record_origin(parent, entry);
         }
+        self.invalidate_entry(parent, name);
+        res
     }
 
     fn unlink(&self, ctx: &Context, parent: VfsInode, name: &CStr) -> Result<()> {
         validate_path_component(name)?;
 
-        match self.get_real_rootfs(parent)? {
+        let ctx = &self.mount_ctx(ctx, parent, true)?;
+        let res = match self.get_real_rootfs(parent)? {
             (Left(fs), idata) => fs.unlink(ctx, idata.ino(), name),
             (Right(fs), idata) => fs.unlink(ctx, idata.ino(), name),
-        }
+        };
+        let res = self.track_readonly(parent, res);
+        self.invalidate_entry(parent, name);
+        res
     }
 
     fn rmdir(&self, ctx: &Context, parent: VfsInode, name: &CStr) -> Result<()> {
         validate_path_component(name)?;
 
-        match self.get_real_rootfs(parent)? {
+        let ctx = &self.mount_ctx(ctx, parent, true)?;
+        let res = match self.get_real_rootfs(parent)? {
             (Left(fs), idata) => fs.rmdir(ctx, idata.ino(), name),
             (Right(fs), idata) => fs.rmdir(ctx, idata.ino(), name),
-        }
+        };
+        let res = self.track_readonly(parent, res);
+        self.invalidate_entry(parent, name);
+        res
     }
 
     fn rename(
@@ -216,6 +375,8 @@ impl FileSystem for Vfs {
         validate_path_component(oldname)?;
         validate_path_component(newname)?;
 
+        let ctx = &self.mount_ctx(ctx, olddir, true)?;
+        self.mount_ctx(ctx, newdir, true)?;
         let (root, idata_old) = self.get_real_rootfs(olddir)?;
         let (_, idata_new) = self.get_real_rootfs(newdir)?;
 
@@ -223,7 +384,7 @@ impl FileSystem for Vfs {
             return Err(Error::from_raw_os_error(libc::EINVAL));
         }
 
-        match root {
+        let res = match root {
             Left(fs) => fs.rename(
                 ctx,
                 idata_old.ino(),
@@ -240,7 +401,13 @@ impl FileSystem for Vfs {
                 newname,
                 flags,
             ),
-        }
+        };
+        let res = self.track_readonly(olddir, res);
+        self.invalidate_entry(olddir, oldname);
+        self.invalidate_entry(newdir, newname);
+        self.invalidate_attr(olddir);
+        self.invalidate_attr(newdir);
+        res
     }
 
     fn link(
@@ -252,6 +419,7 @@ impl FileSystem for Vfs {
     ) -> Result<Entry> {
         validate_path_component(newname)?;
 
+        let ctx = &self.mount_ctx(ctx, newparent, true)?;
         let (root, idata_old) = self.get_real_rootfs(inode)?;
         let (_, idata_new) = self.get_real_rootfs(newparent)?;
 
@@ -259,15 +427,24 @@ impl FileSystem for Vfs {
             return Err(Error::from_raw_os_error(libc::EINVAL));
         }
 
-        match root {
+        let res = match root {
             Left(fs) => fs.link(ctx, idata_old.ino(), idata_new.ino(), newname),
             Right(fs) => fs
                 .link(ctx, idata_old.ino(), idata_new.ino(), newname)
-                .map(|mut e| {
-                    e.inode = self.convert_inode(idata_new.fs_idx(), e.inode)?;
+                .and_then(|mut e| {
+                    self.convert_entry(idata_new.fs_idx(), &mut e)?;
                     Ok(e)
-                })?,
+                }),
+        };
+        let res = self.track_readonly(newparent, res);
+        if let Ok(entry) = &res {
+            self.record_origin(newparent, entry);
         }
+        // The link count and ctime of the inode change.
+        self.invalidate_entry(newparent, newname);
+        self.invalidate_attr(inode);
+        self.invalidate_attr(newparent);
+        res
     }
 
     fn open(
@@ -280,11 +457,12 @@ impl FileSystem for Vfs {
         if self.opts.load().no_open {
             Err(Error::from_raw_os_error(libc::ENOSYS))
         } else {
+            let ctx = &self.mount_ctx(ctx, inode, open_writes(flags))?;
             match self.get_real_rootfs(inode)? {
                 (Left(fs), idata) => fs.open(ctx, idata.ino(), flags, fuse_flags),
-                (Right(fs), idata) => fs
-                    .open(ctx, idata.ino(), flags, fuse_flags)
-                    .map(|(h, opt)| (h.map(Into::into), opt)),
+                (Right(fs), idata) => {
+                    self.backend_open(idata.fs_idx(), fs.open(ctx, idata.ino(), flags, fuse_flags))
+                }
             }
         }
     }
@@ -298,14 +476,82 @@ impl FileSystem for Vfs {
     ) -> Result<(Entry, Option<u64>, OpenOptions)> {
         validate_path_component(name)?;
 
-        match self.get_real_rootfs(parent)? {
+        let ctx = &self.mount_ctx(ctx, parent, true)?;
+        let res = match self.get_real_rootfs(parent)? {
             (Left(fs), idata) => fs.create(ctx, idata.ino(), name, args),
             (Right(fs), idata) => {
-                fs.create(ctx, idata.ino(), name, args)
-                    .map(|(mut a, b, c)| {
-                        a.inode = self.convert_inode(idata.fs_idx(), a.inode)?;
+                self.backend_create(idata.fs_idx(), fs.create(ctx, idata.ino(), name, args))
+            }
+        };
+        self.created(parent, name, res)
+    }
+
+    fn tmpfile(
+        &self,
+        ctx: &Context,
+        parent: VfsInode,
+        args: CreateIn,
+    ) -> Result<(Entry, Option<u64>, OpenOptions)> {
+        let ctx = &self.mount_ctx(ctx, parent, true)?;
+        let res = match self.get_real_rootfs(parent)? {
+            (Left(_), _) => Err(Error::from_raw_os_error(libc::EOPNOTSUPP)),
+            (Right(fs), idata) => {
+                self.backend_create(idata.fs_idx(), fs.tmpfile(ctx, idata.ino(), args))
+            }
+        };
+        // ENOSYS disables O_TMPFILE for the whole Vfs, only fail for this directory instead.
+        let res = match res {
+            Err(e) if errno_of(&e) == Some(libc::ENOSYS) => {
+                Err(Error::from_raw_os_error(libc::EOPNOTSUPP))
+            }
+            res => res,
+        };
+        let res = self.track_readonly(parent, res);
+        if let Ok((entry, _, _)) = &res {
+            self.record_origin(parent, entry);
+        }
+        res
+    }
+
+    fn atomic_open(
+        &self,
+        ctx: &Context,
+        parent: VfsInode,
+        name: &CStr,
+        args: CreateIn,
+    ) -> Result<(Entry, Option<u64>, OpenOptions)> {
+        validate_path_component(name)?;
+
+        let create = args.flags & libc::O_CREAT as u32 != 0;
+        // Handles are never released with zero-message opens, so don't open any then.
+        let res = match self.get_real_rootfs(parent)? {
+            (Right(fs), idata) if !self.opts.load().no_open => {
+                let ctx = &self.mount_ctx(ctx, parent, create || open_writes(args.flags))?;
+                fs.atomic_open(ctx, idata.ino(), name, args)
+                    .and_then(|(mut a, b, c)| {
+                        if b.is_some() {
+                            self.idle.open(idata.fs_idx());
+                        }
+                        self.convert_entry(idata.fs_idx(), &mut a)?;
                         Ok((a, b, c))
-                    })?
+                    })
+            }
+            _ => Err(Error::from_raw_os_error(libc::ENOSYS)),
+        };
+        match res {
+            // Backends without atomic open get a lookup followed by an open.
+            Err(e) if errno_of(&e) == Some(libc::ENOSYS) => {
+                self.lookup_open(ctx, parent, name, args)
+            }
+            res => {
+                let res = self.track_readonly(parent, res);
+                if let Ok((entry, _, _)) = &res {
+                    self.record_origin(parent, entry);
+                }
+                if create {
+                    self.invalidate_entry(parent, name);
+                }
+                res
             }
         }
     }
@@ -321,13 +567,23 @@ impl FileSystem for Vfs {
         lock_owner: Option<u64>,
         flags: u32,
     ) -> Result<usize> {
-        match self.get_real_rootfs(inode)? {
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
+        match self.get_handle_rootfs(inode)? {
             (Left(fs), idata) => {
                 fs.read(ctx, idata.ino(), handle, w, size, offset, lock_owner, flags)
             }
-            (Right(fs), idata) => {
-                fs.read(ctx, idata.ino(), handle, w, size, offset, lock_owner, flags)
-            }
+            (Right(fs), idata) => split_io::split_io(size, fs.max_read(), |size, off| {
+                fs.read(
+                    ctx,
+                    idata.ino(),
+                    handle,
+                    w,
+                    size,
+                    offset + off,
+                    lock_owner,
+                    flags,
+                )
+            }),
         }
     }
 
@@ -344,7 +600,8 @@ impl FileSystem for Vfs {
         flags: u32,
         fuse_flags: u32,
     ) -> Result<usize> {
-        match self.get_real_rootfs(inode)? {
+        let ctx = &self.mount_ctx(ctx, inode, true)?;
+        let res = match self.get_handle_rootfs(inode)? {
             (Left(fs), idata) => fs.write(
                 ctx,
                 idata.ino(),
@@ -357,30 +614,83 @@ impl FileSystem for Vfs {
                 flags,
                 fuse_flags,
             ),
-            (Right(fs), idata) => fs.write(
-                ctx,
-                idata.ino(),
-                handle,
-                r,
-                size,
-                offset,
-                lock_owner,
-                delayed_write,
-                flags,
-                fuse_flags,
-            ),
-        }
+            (Right(fs), idata) => split_io::split_io(size, fs.max_write(), |size, off| {
+                fs.write(
+                    ctx,
+                    idata.ino(),
+                    handle,
+                    r,
+                    size,
+                    offset + off,
+                    lock_owner,
+                    delayed_write,
+                    flags,
+                    fuse_flags,
+                )
+            }),
+        };
+        self.modified(inode, res)
     }
 
     fn flush(&self, ctx: &Context, inode: VfsInode, handle: u64, lock_owner: u64) -> Result<()> {
-        match self.get_real_rootfs(inode)? {
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
+        match self.get_handle_rootfs(inode)? {
             (Left(fs), idata) => fs.flush(ctx, idata.ino(), handle, lock_owner),
             (Right(fs), idata) => fs.flush(ctx, idata.ino(), handle, lock_owner),
         }
     }
 
+    fn getlk(
+        &self,
+        ctx: &Context,
+        inode: VfsInode,
+        handle: u64,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> Result<FileLock> {
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
+        match self.get_handle_rootfs(inode)? {
+            (Left(fs), idata) => fs.getlk(ctx, idata.ino(), handle, owner, lock, flags),
+            (Right(fs), idata) => fs.getlk(ctx, idata.ino(), handle, owner, lock, flags),
+        }
+    }
+
+    fn setlk(
+        &self,
+        ctx: &Context,
+        inode: VfsInode,
+        handle: u64,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> Result<()> {
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
+        match self.get_handle_rootfs(inode)? {
+            (Left(fs), idata) => fs.setlk(ctx, idata.ino(), handle, owner, lock, flags),
+            (Right(fs), idata) => fs.setlk(ctx, idata.ino(), handle, owner, lock, flags),
+        }
+    }
+
+    fn setlkw(
+        &self,
+        ctx: &Context,
+        inode: VfsInode,
+        handle: u64,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> Result<()> {
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
+        match self.get_handle_rootfs(inode)? {
+            (Left(fs), idata) => fs.setlkw(ctx, idata.ino(), handle, owner, lock, flags),
+            (Right(fs), idata) => fs.setlkw(ctx, idata.ino(), handle, owner, lock, flags),
+        }
+    }
+
     fn fsync(&self, ctx: &Context, inode: VfsInode, datasync: bool, handle: u64) -> Result<()> {
-        match self.get_real_rootfs(inode)? {
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
+        match self.get_handle_rootfs(inode)? {
             (Left(fs), idata) => fs.fsync(ctx, idata.ino(), datasync, handle),
             (Right(fs), idata) => fs.fsync(ctx, idata.ino(), datasync, handle),
         }
@@ -395,12 +705,141 @@ impl FileSystem for Vfs {
         offset: u64,
         length: u64,
     ) -> Result<()> {
-        match self.get_real_rootfs(inode)? {
+        let ctx = &self.mount_ctx(ctx, inode, true)?;
+        let res = match self.get_handle_rootfs(inode)? {
             (Left(fs), idata) => fs.fallocate(ctx, idata.ino(), handle, mode, offset, length),
             (Right(fs), idata) => fs.fallocate(ctx, idata.ino(), handle, mode, offset, length),
+        };
+        self.modified(inode, res)
+    }
+
+    fn lseek(
+        &self,
+        ctx: &Context,
+        inode: VfsInode,
+        handle: u64,
+        offset: u64,
+        whence: u32,
+    ) -> Result<u64> {
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
+        match self.get_handle_rootfs(inode)? {
+            (Left(fs), idata) => fs.lseek(ctx, idata.ino(), handle, offset, whence),
+            (Right(fs), idata) => fs.lseek(ctx, idata.ino(), handle, offset, whence),
         }
     }
 
+    fn ioctl(
+        &self,
+        ctx: &Context,
+        inode: VfsInode,
+        handle: u64,
+        flags: u32,
+        cmd: u32,
+        arg: u64,
+        in_data: &[u8],
+        out_size: u32,
+    ) -> Result<IoctlReply> {
+        let ctx = &self.mount_ctx(ctx, inode, ioctl_writes(cmd))?;
+        match self.get_handle_rootfs(inode)? {
+            (Left(fs), idata) => {
+                fs.ioctl(ctx, idata.ino(), handle, flags, cmd, arg, in_data, out_size)
+            }
+            (Right(fs), idata) => {
+                fs.ioctl(ctx, idata.ino(), handle, flags, cmd, arg, in_data, out_size)
+            }
+        }
+    }
+
+    fn poll(
+        &self,
+        ctx: &Context,
+        inode: VfsInode,
+        handle: u64,
+        khandle: u64,
+        flags: u32,
+        events: u32,
+    ) -> Result<u32> {
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
+        match self.get_handle_rootfs(inode)? {
+            (Left(fs), idata) => fs.poll(ctx, idata.ino(), handle, khandle, flags, events),
+            (Right(fs), idata) => fs.poll(ctx, idata.ino(), handle, khandle, flags, events),
+        }
+    }
+
+    fn copyfilerange(
+        &self,
+        ctx: &Context,
+        inode_in: VfsInode,
+        handle_in: u64,
+        offset_in: u64,
+        inode_out: VfsInode,
+        handle_out: u64,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> Result<usize> {
+        // Files of the pseudo fs are all directories.
+        let ctx = &self.mount_ctx(ctx, inode_out, true)?;
+        let (fs_in, idata_in) = match self.get_handle_rootfs(inode_in)? {
+            (Right(fs), idata) => (fs, idata),
+            (Left(_), _) => return Err(Error::from_raw_os_error(libc::EINVAL)),
+        };
+        let (fs_out, idata_out) = match self.get_handle_rootfs(inode_out)? {
+            (Right(fs), idata) => (fs, idata),
+            (Left(_), _) => return Err(Error::from_raw_os_error(libc::EINVAL)),
+        };
+
+        let res = if idata_in.fs_idx() == idata_out.fs_idx() {
+            match fs_in.copyfilerange(
+                ctx,
+                idata_in.ino(),
+                handle_in,
+                offset_in,
+                idata_out.ino(),
+                handle_out,
+                offset_out,
+                len,
+                flags,
+            ) {
+                // ENOSYS disables copy_file_range() for the whole Vfs, pump data instead if
+                // the backend doesn't support it.
+                Err(e) if errno_of(&e) == Some(libc::ENOSYS) && flags == 0 => None,
+                res => Some(res),
+            }
+        } else {
+            None
+        };
+        let res = match res {
+            Some(res) => res,
+            // There's no flag defined for copy_file_range() yet.
+            None if flags != 0 => Err(Error::from_raw_os_error(libc::EINVAL)),
+            None => copy_range::copy_range(
+                ctx,
+                copy_range::FileRange {
+                    fs: &fs_in,
+                    ino: idata_in.ino(),
+                    handle: handle_in,
+                    offset: offset_in,
+                },
+                copy_range::FileRange {
+                    fs: &fs_out,
+                    ino: idata_out.ino(),
+                    handle: handle_out,
+                    offset: offset_out,
+                },
+                len,
+            )
+            .inspect(|copied| {
+                if let Some(acct) = self.write_accounting.as_ref() {
+                    acct.add_bounce(*copied as u64);
+                }
+            }),
+        };
+        let res = self.track_readonly(inode_out, res);
+        self.invalidate_attr(inode_out);
+        res
+    }
+
     fn release(
         &self,
         ctx: &Context,
@@ -411,7 +850,7 @@ impl FileSystem for Vfs {
         flock_release: bool,
         lock_owner: Option<u64>,
     ) -> Result<()> {
-        match self.get_real_rootfs(inode)? {
+        match self.get_handle_rootfs(inode)? {
             (Left(fs), idata) => fs.release(
                 ctx,
                 idata.ino(),
@@ -421,25 +860,47 @@ impl FileSystem for Vfs {
                 flock_release,
                 lock_owner,
             ),
-            (Right(fs), idata) => fs.release(
-                ctx,
-                idata.ino(),
-                flags,
-                handle,
-                flush,
-                flock_release,
-                lock_owner,
-            ),
+            (Right(fs), idata) => {
+                self.idle.release(idata.fs_idx());
+                fs.release(
+                    ctx,
+                    idata.ino(),
+                    flags,
+                    handle,
+                    flush,
+                    flock_release,
+                    lock_owner,
+                )
+            }
         }
     }
 
     fn statfs(&self, ctx: &Context, inode: VfsInode) -> Result<statvfs64> {
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
         match self.get_real_rootfs(inode)? {
-            (Left(fs), idata) => fs.statfs(ctx, idata.ino()),
+            (Left(fs), idata) => self.pseudo_statfs(fs, ctx, idata.ino()),
             (Right(fs), idata) => fs.statfs(ctx, idata.ino()),
         }
     }
 
+    fn syncfs(&self, ctx: &Context, inode: VfsInode) -> Result<()> {
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
+        match self.get_real_rootfs(inode)? {
+            // Syncing the pseudo fs syncs all backends, failing with the first error.
+            (Left(_), _) => {
+                let mut res = Ok(());
+                for (fs, ino) in self.mounted_roots() {
+                    match fs.syncfs(ctx, ino) {
+                        Err(e) if res.is_ok() && errno_of(&e) != Some(libc::ENOSYS) => res = Err(e),
+                        _ => {}
+                    }
+                }
+                res
+            }
+            (Right(fs), idata) => fs.syncfs(ctx, idata.ino()),
+        }
+    }
+
     fn setxattr(
         &self,
         ctx: &Context,
@@ -450,10 +911,13 @@ impl FileSystem for Vfs {
     ) -> Result<()> {
         validate_path_component(name)?;
 
-        match self.get_real_rootfs(inode)? {
+        // Extended attribute changes update the ctime.
+        let ctx = &self.mount_ctx(ctx, inode, true)?;
+        let res = match self.get_real_rootfs(inode)? {
             (Left(fs), idata) => fs.setxattr(ctx, idata.ino(), name, value, flags),
             (Right(fs), idata) => fs.setxattr(ctx, idata.ino(), name, value, flags),
-        }
+        };
+        self.modified(inode, res)
     }
 
     fn getxattr(
@@ -465,6 +929,7 @@ impl FileSystem for Vfs {
     ) -> Result<GetxattrReply> {
         validate_path_component(name)?;
 
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
         match self.get_real_rootfs(inode)? {
             (Left(fs), idata) => fs.getxattr(ctx, idata.ino(), name, size),
             (Right(fs), idata) => fs.getxattr(ctx, idata.ino(), name, size),
@@ -472,6 +937,7 @@ impl FileSystem for Vfs {
     }
 
     fn listxattr(&self, ctx: &Context, inode: VfsInode, size: u32) -> Result<ListxattrReply> {
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
         match self.get_real_rootfs(inode)? {
             (Left(fs), idata) => fs.listxattr(ctx, idata.ino(), size),
             (Right(fs), idata) => fs.listxattr(ctx, idata.ino(), size),
@@ -481,10 +947,12 @@ impl FileSystem for Vfs {
     fn removexattr(&self, ctx: &Context, inode: VfsInode, name: &CStr) -> Result<()> {
         validate_path_component(name)?;
 
-        match self.get_real_rootfs(inode)? {
+        let ctx = &self.mount_ctx(ctx, inode, true)?;
+        let res = match self.get_real_rootfs(inode)? {
             (Left(fs), idata) => fs.removexattr(ctx, idata.ino(), name),
             (Right(fs), idata) => fs.removexattr(ctx, idata.ino(), name),
-        }
+        };
+        self.modified(inode, res)
     }
 
     fn opendir(
@@ -496,11 +964,13 @@ impl FileSystem for Vfs {
         if self.opts.load().no_opendir {
             Err(Error::from_raw_os_error(libc::ENOSYS))
         } else {
+            let ctx = &self.mount_ctx(ctx, inode, false)?;
             match self.get_real_rootfs(inode)? {
                 (Left(fs), idata) => fs.opendir(ctx, idata.ino(), flags),
-                (Right(fs), idata) => fs
-                    .opendir(ctx, idata.ino(), flags)
-                    .map(|(h, opt)| (h.map(Into::into), opt)),
+                (Right(fs), idata) => fs.opendir(ctx, idata.ino(), flags).map(|(h, opt)| {
+                    self.idle.open(idata.fs_idx());
+                    (h.map(Into::into), opt)
+                }),
             }
         }
     }
@@ -514,7 +984,8 @@ impl FileSystem for Vfs {
         offset: u64,
         add_entry: &mut dyn FnMut(DirEntry) -> Result<usize>,
     ) -> Result<()> {
-        match self.get_real_rootfs(inode)? {
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
+        match self.get_handle_rootfs(inode)? {
             (Left(fs), idata) => {
                 fs.readdir(
                     ctx,
@@ -526,7 +997,7 @@ impl FileSystem for Vfs {
                         match self.mountpoints.load().get(&dir_entry.ino) {
                             // cross mountpoint, return mount root entry
                             Some(mnt) => {
-                                dir_entry.ino = self.convert_inode(mnt.fs_idx, mnt.ino)?;
+                                dir_entry.ino = mnt.root_entry.inode;
                             }
                             None => {
                                 dir_entry.ino =
@@ -551,7 +1022,8 @@ impl FileSystem for Vfs {
         offset: u64,
         add_entry: &mut dyn FnMut(DirEntry, Entry) -> Result<usize>,
     ) -> Result<()> {
-        match self.get_real_rootfs(inode)? {
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
+        match self.get_handle_rootfs(inode)? {
             (Left(fs), idata) => fs.readdirplus(
                 ctx,
                 idata.ino(),
@@ -562,8 +1034,12 @@ impl FileSystem for Vfs {
                     match self.mountpoints.load().get(&dir_entry.ino) {
                         Some(mnt) => {
                             // cross mountpoint, return mount root entry
-                            dir_entry.ino = self.convert_inode(mnt.fs_idx, mnt.ino)?;
+                            fs.forget(ctx, dir_entry.ino, 1);
+                            dir_entry.ino = mnt.root_entry.inode;
                             entry = mnt.root_entry;
+                            self.transform_attr(mnt.fs_idx, &mut entry.attr);
+                            self.override_entry_timeouts(mnt.fs_idx, &mut entry);
+                            self.inode_refs.get(entry.inode);
                         }
                         None => {
                             dir_entry.ino = self.convert_inode(idata.fs_idx(), dir_entry.ino)?;
@@ -571,7 +1047,7 @@ impl FileSystem for Vfs {
                         }
                     }
 
-                    add_entry(dir_entry, entry)
+                    self.readdirplus_entry(dir_entry, entry, add_entry)
                 },
             ),
 
@@ -582,28 +1058,34 @@ impl FileSystem for Vfs {
                 size,
                 offset,
                 &mut |dir_entry, mut entry| {
-                    entry.inode = self.convert_inode(idata.fs_idx(), entry.inode)?;
-                    add_entry(dir_entry, entry)
+                    self.convert_entry(idata.fs_idx(), &mut entry)?;
+                    self.record_origin(inode, &entry);
+                    self.readdirplus_entry(dir_entry, entry, add_entry)
                 },
             ),
         }
     }
 
     fn fsyncdir(&self, ctx: &Context, inode: VfsInode, datasync: bool, handle: u64) -> Result<()> {
-        match self.get_real_rootfs(inode)? {
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
+        match self.get_handle_rootfs(inode)? {
             (Left(fs), idata) => fs.fsyncdir(ctx, idata.ino(), datasync, handle),
             (Right(fs), idata) => fs.fsyncdir(ctx, idata.ino(), datasync, handle),
         }
     }
 
     fn releasedir(&self, ctx: &Context, inode: VfsInode, flags: u32, handle: u64) -> Result<()> {
-        match self.get_real_rootfs(inode)? {
+        match self.get_handle_rootfs(inode)? {
             (Left(fs), idata) => fs.releasedir(ctx, idata.ino(), flags, handle),
-            (Right(fs), idata) => fs.releasedir(ctx, idata.ino(), flags, handle),
+            (Right(fs), idata) => {
+                self.idle.release(idata.fs_idx());
+                fs.releasedir(ctx, idata.ino(), flags, handle)
+            }
         }
     }
 
     fn access(&self, ctx: &Context, inode: VfsInode, mask: u32) -> Result<()> {
+        let ctx = &self.mount_ctx(ctx, inode, mask & libc::W_OK as u32 != 0)?;
         match self.get_real_rootfs(inode)? {
             (Left(fs), idata) => fs.access(ctx, idata.ino(), mask),
             (Right(fs), idata) => fs.access(ctx, idata.ino(), mask),
@@ -622,7 +1104,8 @@ impl FileSystem for Vfs {
         moffset: u64,
         req: &mut dyn FsCacheReqHandler,
     ) -> Result<()> {
-        match self.get_real_rootfs(inode)? {
+        let ctx = &self.mount_ctx(ctx, inode, false)?;
+        match self.get_handle_rootfs(inode)? {
             (Left(fs), idata) => {
                 fs.setupmapping(ctx, idata.ino(), handle, foffset, len, flags, moffset, req)
             }
@@ -640,7 +1123,7 @@ impl FileSystem for Vfs {
         requests: Vec<virtio_fs::RemovemappingOne>,
         req: &mut dyn FsCacheReqHandler,
     ) -> Result<()> {
-        match self.get_real_rootfs(inode)? {
+        match self.get_handle_rootfs(inode)? {
             (Left(fs), idata) => fs.removemapping(ctx, idata.ino(), requests, req),
             (Right(fs), idata) => fs.removemapping(ctx, idata.ino(), requests, req),
         }
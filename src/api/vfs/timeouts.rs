@@ -0,0 +1,302 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Per backend overrides of the timeouts of entry and attribute replies.
+//!
+//! Backends mounted side by side often deserve very different cache timeouts: an immutable image
+//! may be cached by the guest forever, while a shared read-write directory needs short timeouts
+//! to see changes made by the host. [BackendTimeouts] hinted by [BackendFileSystem::timeouts],
+//! or installed by [Vfs::set_timeouts], replace the timeouts returned by a backend in every
+//! entry and attribute reply crossing the Vfs, including the root entries of mountpoints looked
+//! up from the pseudo fs. Timeouts left unset are passed through from the backend.
+//!
+//! A negative entry timeout turns `ENOENT` failures of lookups on the backend into negative
+//! entries, so the guest caches the absence of names for that long instead of asking again.
+//!
+//! [BackendFileSystem::timeouts]: super::BackendFileSystem::timeouts
+
+use std::collections::HashMap;
+use std::io::{Error, Result};
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{BackFileSystem, Vfs, VfsIndex, VfsResult};
+use crate::api::errno::errno_of;
+use crate::api::filesystem::Entry;
+
+/// Timeouts replacing the ones returned by a backend file system.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BackendTimeouts {
+    /// Timeout of entries replied to lookup, create, mknod, mkdir, symlink, link and readdirplus.
+    pub entry: Option<Duration>,
+    /// Timeout of attributes of entries, and of attributes replied to getattr and setattr.
+    pub attr: Option<Duration>,
+    /// Timeout of negative entries replied to lookups failing with `ENOENT`, `None` or zero to
+    /// fail such lookups instead.
+    pub negative: Option<Duration>,
+}
+
+impl Vfs {
+    /// Set the timeouts of replies of the backend file system mounted at `path`, replacing the
+    /// ones hinted by the backend at mount time.
+    ///
+    /// Entries already cached by the guest keep the timeouts they were replied with. The
+    /// timeouts get removed when the backend is umounted.
+    pub fn set_timeouts(&self, path: &str, timeouts: BackendTimeouts) -> VfsResult<()> {
+        // Serialize mount operations. Do not expect poisoned lock here.
+        let _guard = self.lock.lock().unwrap();
+        let fs_idx = self.mounted_fs_idx(path)?;
+        self.store_timeouts(fs_idx, timeouts);
+        // Cached entries expire by the previous timeouts.
+        if let Some(cache) = self.lookup_cache.as_ref() {
+            cache.evict_fs(fs_idx);
+        }
+
+        Ok(())
+    }
+
+    // Install the timeouts hinted by backend `fs` newly mounted as `fs_idx`.
+    pub(super) fn mounted_timeouts(&self, fs_idx: VfsIndex, fs: &BackFileSystem) {
+        self.store_timeouts(fs_idx, fs.timeouts());
+    }
+
+    fn store_timeouts(&self, fs_idx: VfsIndex, timeouts: BackendTimeouts) {
+        let current = self.timeouts.load();
+        if current.get(&fs_idx).copied().unwrap_or_default() == timeouts {
+            return;
+        }
+        let mut all: HashMap<VfsIndex, BackendTimeouts> = current.deref().deref().clone();
+        if timeouts == BackendTimeouts::default() {
+            all.remove(&fs_idx);
+        } else {
+            all.insert(fs_idx, timeouts);
+        }
+        self.timeouts.store(Arc::new(all));
+    }
+
+    // Apply the timeouts of backend `fs_idx` to `entry`.
+    pub(super) fn override_entry_timeouts(&self, fs_idx: VfsIndex, entry: &mut Entry) {
+        if let Some(timeouts) = self.timeouts.load().get(&fs_idx) {
+            if let Some(timeout) = timeouts.entry {
+                entry.entry_timeout = timeout;
+            }
+            if let Some(timeout) = timeouts.attr {
+                entry.attr_timeout = timeout;
+            }
+        }
+    }
+
+    // Get the attribute timeout of backend `fs_idx` replacing `timeout`.
+    pub(super) fn override_attr_timeout(&self, fs_idx: VfsIndex, timeout: Duration) -> Duration {
+        self.timeouts
+            .load()
+            .get(&fs_idx)
+            .and_then(|t| t.attr)
+            .unwrap_or(timeout)
+    }
+
+    // Turn the failure `err` of a lookup on backend `fs_idx` into a negative entry if the
+    // backend has a negative entry timeout.
+    pub(super) fn negative_entry(&self, fs_idx: VfsIndex, err: Error) -> Result<Entry> {
+        let timeout = self.timeouts.load().get(&fs_idx).and_then(|t| t.negative);
+        match timeout {
+            Some(timeout) if !timeout.is_zero() && errno_of(&err) == Some(libc::ENOENT) => {
+                Ok(Entry {
+                    inode: 0,
+                    entry_timeout: timeout,
+                    ..Default::default()
+                })
+            }
+            _ => Err(err),
+        }
+    }
+
+    // Drop the timeouts of backend `fs_idx` once it's umounted.
+    pub(super) fn evict_timeouts(&self, fs_idx: VfsIndex) {
+        self.store_timeouts(fs_idx, BackendTimeouts::default());
+    }
+}
+
+#[cfg(all(test, not(feature = "async-io")))]
+mod tests {
+    use super::*;
+    use crate::abi::fuse_abi::{stat64, CreateIn, ROOT_ID};
+    use crate::api::filesystem::{Context, DirEntry, FileSystem, OpenOptions};
+    use crate::api::BackendFileSystem;
+    use crate::api::VfsOptions;
+    use std::any::Any;
+    use std::ffi::{CStr, CString};
+
+    // Backend with a single file `f`, replying with one second timeouts unless hinted otherwise.
+    struct TimeoutFs(BackendTimeouts);
+
+    impl TimeoutFs {
+        fn entry(inode: u64) -> Entry {
+            let mut entry = Entry {
+                inode,
+                entry_timeout: Duration::from_secs(1),
+                attr_timeout: Duration::from_secs(1),
+                ..Default::default()
+            };
+            entry.attr.st_ino = inode;
+            entry.attr.st_mode = if inode == ROOT_ID {
+                libc::S_IFDIR | 0o755
+            } else {
+                libc::S_IFREG | 0o644
+            };
+            entry
+        }
+    }
+
+    impl FileSystem for TimeoutFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn lookup(&self, _: &Context, _: u64, name: &CStr) -> Result<Entry> {
+            match name.to_bytes() {
+                b"f" => Ok(Self::entry(2)),
+                b"e" => Err(Error::from_raw_os_error(libc::EACCES)),
+                _ => Err(Error::from_raw_os_error(libc::ENOENT)),
+            }
+        }
+
+        fn getattr(&self, _: &Context, inode: u64, _: Option<u64>) -> Result<(stat64, Duration)> {
+            Ok((Self::entry(inode).attr, Duration::from_secs(1)))
+        }
+
+        fn create(
+            &self,
+            _: &Context,
+            _: u64,
+            _: &CStr,
+            _: CreateIn,
+        ) -> Result<(Entry, Option<u64>, OpenOptions)> {
+            Ok((Self::entry(3), None, OpenOptions::empty()))
+        }
+
+        fn readdirplus(
+            &self,
+            _: &Context,
+            _: u64,
+            _: u64,
+            _: u32,
+            offset: u64,
+            add_entry: &mut dyn FnMut(DirEntry, Entry) -> Result<usize>,
+        ) -> Result<()> {
+            if offset == 0 {
+                let dir_entry = DirEntry {
+                    ino: 2,
+                    offset: 1,
+                    type_: libc::DT_REG as u32,
+                    name: b"f",
+                };
+                add_entry(dir_entry, Self::entry(2))?;
+            }
+            Ok(())
+        }
+    }
+
+    impl BackendFileSystem for TimeoutFs {
+        fn mount(&self) -> Result<(Entry, u64)> {
+            Ok((Self::entry(ROOT_ID), 1 << 20))
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn timeouts(&self) -> BackendTimeouts {
+            self.0
+        }
+    }
+
+    fn lookup(vfs: &Vfs, parent: u64, name: &str) -> Result<Entry> {
+        let name = CString::new(name).unwrap();
+        vfs.lookup(&Context::default(), parent.into(), &name)
+    }
+
+    #[test]
+    fn test_backend_timeouts() {
+        let vfs = Vfs::default();
+        let forever = Duration::from_secs(u64::MAX);
+        let hint = BackendTimeouts {
+            entry: Some(forever),
+            attr: Some(forever),
+            negative: Some(Duration::from_secs(10)),
+        };
+        vfs.mount(Box::new(TimeoutFs(hint)), "/image").unwrap();
+        vfs.mount(Box::new(TimeoutFs(BackendTimeouts::default())), "/share")
+            .unwrap();
+        let ctx = Context::default();
+
+        // The root entry crossing the mountpoint gets the timeouts of the backend.
+        let image = lookup(&vfs, ROOT_ID, "image").unwrap();
+        assert_eq!(image.entry_timeout, forever);
+        assert_eq!(image.attr_timeout, forever);
+        let share = lookup(&vfs, ROOT_ID, "share").unwrap();
+        assert_eq!(share.entry_timeout, Duration::from_secs(1));
+
+        let entry = lookup(&vfs, image.inode, "f").unwrap();
+        assert_eq!(entry.entry_timeout, forever);
+        assert_eq!(entry.attr_timeout, forever);
+        let (_, timeout) = vfs.getattr(&ctx, entry.inode.into(), None).unwrap();
+        assert_eq!(timeout, forever);
+        let name = CString::new("g").unwrap();
+        let args = CreateIn::default();
+        let (entry, _, _) = vfs.create(&ctx, image.inode.into(), &name, args).unwrap();
+        assert_eq!(entry.entry_timeout, forever);
+        let mut timeouts = Vec::new();
+        vfs.readdirplus(&ctx, image.inode.into(), 0, 4096, 0, &mut |_, entry| {
+            timeouts.push(entry.entry_timeout);
+            Ok(1)
+        })
+        .unwrap();
+        assert_eq!(timeouts, vec![forever]);
+
+        // Missing names are negative entries, other failures are passed through.
+        let entry = lookup(&vfs, image.inode, "missing").unwrap();
+        assert_eq!(entry.inode, 0);
+        assert_eq!(entry.entry_timeout, Duration::from_secs(10));
+        let err = lookup(&vfs, image.inode, "e").err().unwrap();
+        assert_eq!(errno_of(&err), Some(libc::EACCES));
+        let err = lookup(&vfs, share.inode, "missing").err().unwrap();
+        assert_eq!(errno_of(&err), Some(libc::ENOENT));
+
+        // Overrides replace the hint, unset timeouts are passed through from the backend.
+        assert!(vfs.set_timeouts("/none", hint).is_err());
+        let short = BackendTimeouts {
+            attr: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        vfs.set_timeouts("/share", short).unwrap();
+        let entry = lookup(&vfs, share.inode, "f").unwrap();
+        assert_eq!(entry.entry_timeout, Duration::from_secs(1));
+        assert_eq!(entry.attr_timeout, Duration::ZERO);
+        let (_, timeout) = vfs.getattr(&ctx, entry.inode.into(), None).unwrap();
+        assert_eq!(timeout, Duration::ZERO);
+
+        vfs.set_timeouts("/image", BackendTimeouts::default())
+            .unwrap();
+        let entry = lookup(&vfs, image.inode, "f").unwrap();
+        assert_eq!(entry.entry_timeout, Duration::from_secs(1));
+        assert!(lookup(&vfs, image.inode, "missing").is_err());
+
+        // Timeouts of umounted backends are dropped.
+        vfs.umount("/share").unwrap();
+        assert!(vfs.timeouts.load().is_empty());
+
+        // Cached entries of backends with infinite timeouts don't expire.
+        let vfs = Vfs::new(VfsOptions {
+            lookup_cache_size: 16,
+            ..Default::default()
+        });
+        vfs.mount(Box::new(TimeoutFs(hint)), "/image").unwrap();
+        let image = lookup(&vfs, ROOT_ID, "image").unwrap();
+        for _ in 0..2 {
+            let entry = lookup(&vfs, image.inode, "f").unwrap();
+            assert!(entry.entry_timeout > forever / 2);
+        }
+        assert_eq!(vfs.lookup_cache_stats().unwrap().pending, 1);
+    }
+}
@@ -0,0 +1,1199 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reusable building blocks of a multi-threaded fusedev daemon.
+//!
+//! A [Daemon] serves a set of passthrough backends, each one mounted at a path of a [Vfs], over
+//! a single fusedev mountpoint. It drives the whole lifecycle of the service:
+//! 1. build the backends and the [Vfs] from a [DaemonConfig],
+//! 2. mount the [FuseSession] and spawn worker threads serving the channels,
+//! 3. wait for signals, logging a [MetricsSnapshot] on `SIGUSR1` and stopping on `SIGINT` or
+//!    `SIGTERM`, while a [Watchdog] logs requests hung for longer than configured,
+//! 4. shut down the session, drain in-flight requests, destroy the backends and umount by
+//!    [GracefulShutdown], then join the workers.
+//!
+//! Backends can't be nested, a backend mounted at `/` must be the only one. The configuration is
+//! read from a TOML file, only the subset of TOML needed by the daemon is
+//! supported: `key = value` pairs with string, integer or boolean values, comments, and an array
+//! of `[[backend]]` tables.
+//!
+//! ```toml
+//! mountpoint = "/mnt/shared"
+//! threads = 4
+//! hung_request_ms = 30_000
+//!
+//! [[backend]]
+//! path = "/shared"
+//! source = "/srv/shared"
+//!
+//! [[backend]]
+//! path = "/logs"
+//! source = "/var/log/app"
+//! xattr = true
+//! cache = "never"
+//! ```
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::signalfd::SignalFd;
+
+use crate::abi::fuse_abi::{InHeader, Opcode};
+use crate::api::errno::errno_of;
+use crate::api::server::{
+    BackendLimits, BackendScheduler, GracefulShutdown, InflightRequest, Server, ShutdownSession,
+    Watchdog, WatchdogMonitor,
+};
+use crate::api::{
+    DrainShedder, Vfs, VfsIndex, VfsOptions, WriteAccounting, WriteStats, YieldPoints,
+};
+use crate::passthrough::{CachePolicy, Config, PassthroughFs};
+use crate::transport::{FuseBuf, FuseChannel, FuseDevWriter, FuseSession, Reader};
+
+/// Configuration of a passthrough backend mounted in the [Vfs].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackendConfig {
+    /// Path of the backend in the [Vfs], `/` for the root.
+    pub path: String,
+    /// Host directory shared by the backend.
+    pub source: String,
+    /// Whether to support extended attributes.
+    pub xattr: bool,
+    /// Whether to enable writeback caching.
+    pub writeback: bool,
+    /// Caching policy of file data.
+    pub cache: CachePolicy,
+}
+
+/// Configuration of a [Daemon].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DaemonConfig {
+    /// Host directory where the fusedev file system is mounted.
+    pub mountpoint: PathBuf,
+    /// Name of the file system reported in the mount table.
+    pub fsname: String,
+    /// Number of worker threads serving requests.
+    pub threads: usize,
+    /// Whether to mount the file system read-only.
+    pub readonly: bool,
+    /// How long to wait for in-flight requests when stopping.
+    pub drain_timeout: Duration,
+    /// Max number of in-flight requests of each backend, further requests of the backend are
+    /// deferred so that a slow backend doesn't occupy all workers. `None` doesn't limit backends.
+    pub backend_inflight: Option<usize>,
+    /// Log requests in flight for longer than this, `None` doesn't watch requests.
+    pub hung_request_threshold: Option<Duration>,
+    /// Backends to mount in the [Vfs].
+    pub backends: Vec<BackendConfig>,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            mountpoint: PathBuf::new(),
+            fsname: String::from("passthrough"),
+            threads: 4,
+            readonly: false,
+            drain_timeout: Duration::from_secs(5),
+            backend_inflight: None,
+            hung_request_threshold: None,
+            backends: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    Str(String),
+    Int(u64),
+    Bool(bool),
+}
+
+impl Value {
+    fn parse(s: &str) -> Option<(Value, &str)> {
+        if let Some(s) = s.strip_prefix('"') {
+            let mut out = String::new();
+            let mut chars = s.char_indices();
+            while let Some((pos, c)) = chars.next() {
+                match c {
+                    '"' => return Some((Value::Str(out), &s[pos + 1..])),
+                    '\\' => match chars.next()?.1 {
+                        '"' => out.push('"'),
+                        '\\' => out.push('\\'),
+                        'n' => out.push('\n'),
+                        't' => out.push('\t'),
+                        _ => return None,
+                    },
+                    c => out.push(c),
+                }
+            }
+            return None;
+        }
+
+        let end = s
+            .find(|c: char| c.is_whitespace() || c == '#')
+            .unwrap_or(s.len());
+        let value = match &s[..end] {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            v => Value::Int(v.replace('_', "").parse().ok()?),
+        };
+        Some((value, &s[end..]))
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "string",
+            Value::Int(_) => "integer",
+            Value::Bool(_) => "boolean",
+        }
+    }
+}
+
+fn parse_error(line: usize, msg: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("daemon config line {}: {}", line, msg),
+    )
+}
+
+macro_rules! expect_value {
+    ($line:expr, $key:expr, $value:expr, $kind:ident) => {
+        match $value {
+            Value::$kind(v) => v,
+            v => {
+                return Err(parse_error(
+                    $line,
+                    format!("unexpected {} value of key {}", v.kind(), $key),
+                ))
+            }
+        }
+    };
+}
+
+impl DaemonConfig {
+    /// Read the configuration from TOML file `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        Self::from_toml(&content)
+    }
+
+    /// Parse the configuration from TOML text `content`.
+    pub fn from_toml(content: &str) -> io::Result<Self> {
+        let mut cfg = DaemonConfig::default();
+        let mut mountpoint = None;
+        let mut backend: Option<(usize, BackendConfig, Vec<String>)> = None;
+        let mut seen = Vec::new();
+
+        for (idx, raw) in content.lines().enumerate() {
+            let line = idx + 1;
+            let text = raw.trim();
+            if text.is_empty() || text.starts_with('#') {
+                continue;
+            }
+
+            if text.starts_with('[') {
+                let header = text.split('#').next().unwrap_or("").trim();
+                if header != "[[backend]]" {
+                    return Err(parse_error(line, format!("unsupported table {}", header)));
+                }
+                if let Some((start, b, _)) = backend.take() {
+                    cfg.backends.push(Self::check_backend(start, b)?);
+                }
+                backend = Some((
+                    line,
+                    BackendConfig {
+                        path: String::new(),
+                        source: String::new(),
+                        xattr: false,
+                        writeback: false,
+                        cache: CachePolicy::Auto,
+                    },
+                    Vec::new(),
+                ));
+                continue;
+            }
+
+            let (key, rest) = text
+                .split_once('=')
+                .ok_or_else(|| parse_error(line, String::from("expected key = value")))?;
+            let key = key.trim();
+            if key.is_empty()
+                || !key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                return Err(parse_error(line, format!("invalid key {:?}", key)));
+            }
+            let (value, trailing) = Value::parse(rest.trim_start())
+                .ok_or_else(|| parse_error(line, format!("invalid value of key {}", key)))?;
+            let trailing = trailing.trim_start();
+            if !trailing.is_empty() && !trailing.starts_with('#') {
+                return Err(parse_error(line, format!("trailing data {:?}", trailing)));
+            }
+
+            let keys = match backend.as_mut() {
+                Some((_, _, keys)) => keys,
+                None => &mut seen,
+            };
+            if keys.iter().any(|k| k == key) {
+                return Err(parse_error(line, format!("duplicated key {}", key)));
+            }
+            keys.push(key.to_string());
+
+            match (backend.as_mut(), key) {
+                (None, "mountpoint") => {
+                    mountpoint = Some(PathBuf::from(expect_value!(line, key, value, Str)))
+                }
+                (None, "fsname") => cfg.fsname = expect_value!(line, key, value, Str),
+                (None, "threads") => cfg.threads = expect_value!(line, key, value, Int) as usize,
+                (None, "readonly") => cfg.readonly = expect_value!(line, key, value, Bool),
+                (None, "drain_timeout_ms") => {
+                    cfg.drain_timeout = Duration::from_millis(expect_value!(line, key, value, Int))
+                }
+                (None, "backend_inflight") => {
+                    cfg.backend_inflight = Some(expect_value!(line, key, value, Int) as usize)
+                }
+                (None, "hung_request_ms") => {
+                    cfg.hung_request_threshold =
+                        Some(Duration::from_millis(expect_value!(line, key, value, Int)))
+                }
+                (Some((_, b, _)), "path") => b.path = expect_value!(line, key, value, Str),
+                (Some((_, b, _)), "source") => b.source = expect_value!(line, key, value, Str),
+                (Some((_, b, _)), "xattr") => b.xattr = expect_value!(line, key, value, Bool),
+                (Some((_, b, _)), "writeback") => {
+                    b.writeback = expect_value!(line, key, value, Bool)
+                }
+                (Some((_, b, _)), "cache") => {
+                    b.cache = expect_value!(line, key, value, Str)
+                        .parse()
+                        .map_err(|e: &str| parse_error(line, format!("{} of key {}", e, key)))?
+                }
+                _ => return Err(parse_error(line, format!("unknown key {}", key))),
+            }
+        }
+        if let Some((start, b, _)) = backend.take() {
+            cfg.backends.push(Self::check_backend(start, b)?);
+        }
+
+        cfg.mountpoint = mountpoint.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "daemon config: missing mountpoint",
+            )
+        })?;
+        cfg.validate()?;
+
+        Ok(cfg)
+    }
+
+    fn check_backend(line: usize, backend: BackendConfig) -> io::Result<BackendConfig> {
+        if !backend.path.starts_with('/') {
+            return Err(parse_error(
+                line,
+                format!("backend path {:?} is not absolute", backend.path),
+            ));
+        }
+        if backend.source.is_empty() {
+            return Err(parse_error(line, String::from("missing backend source")));
+        }
+        Ok(backend)
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+
+        if self.threads == 0 {
+            return Err(invalid(String::from("no worker thread")));
+        }
+        if self.backends.is_empty() {
+            return Err(invalid(String::from("no backend")));
+        }
+        if self.backend_inflight == Some(0) {
+            return Err(invalid(String::from(
+                "no in-flight request allowed per backend",
+            )));
+        }
+        if self.hung_request_threshold == Some(Duration::ZERO) {
+            return Err(invalid(String::from("all requests considered hung")));
+        }
+        // Backends can't be nested, the Vfs only mounts backends on its pseudo directories.
+        for (idx, b) in self.backends.iter().enumerate() {
+            if let Some(o) = self.backends[..idx].iter().find(|o| {
+                Path::new(&o.path).starts_with(&b.path) || Path::new(&b.path).starts_with(&o.path)
+            }) {
+                return Err(invalid(format!(
+                    "backend paths {} and {} overlap",
+                    o.path, b.path
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Metrics of a backend of a [Daemon].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackendMetrics {
+    /// Path of the backend in the [Vfs].
+    pub path: String,
+    /// Time since the last request routed to the backend, `None` if it's not mounted anymore.
+    pub idle_time: Option<Duration>,
+}
+
+/// Snapshot of the metrics of a [Daemon].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Number of requests being handled.
+    pub inflight_requests: usize,
+    /// Requests being handled, oldest first, if watched for hung requests.
+    pub requests: Vec<InflightRequest>,
+    /// Metrics of the backends.
+    pub backends: Vec<BackendMetrics>,
+    /// Bytes written by the guest and to the backends.
+    pub writes: WriteStats,
+}
+
+impl fmt::Display for MetricsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "inflight requests {}", self.inflight_requests)?;
+        for r in self.requests.iter() {
+            write!(
+                f,
+                ", request {} {:?} of inode {} for {}ms",
+                r.unique,
+                Opcode::from(r.opcode),
+                r.nodeid,
+                r.elapsed.as_millis()
+            )?;
+        }
+        for b in self.backends.iter() {
+            match b.idle_time {
+                Some(t) => write!(f, ", {} idle {}ms", b.path, t.as_millis())?,
+                None => write!(f, ", {} unmounted", b.path)?,
+            }
+        }
+        write!(
+            f,
+            ", guest writes {} bytes, backend writes {} bytes (copy-up {}, bounce {})",
+            self.writes.guest_write_bytes,
+            self.writes.backend_write_bytes,
+            self.writes.copyup_bytes,
+            self.writes.bounce_bytes
+        )
+    }
+}
+
+/// State of a [Daemon].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DaemonState {
+    /// Backends are ready, the session is not mounted yet.
+    Created,
+    /// The session is mounted and served by worker threads.
+    Running,
+    /// The daemon has been stopped, it can't be started again.
+    Stopped,
+}
+
+/// A multi-threaded fusedev daemon serving passthrough backends.
+pub struct Daemon {
+    cfg: DaemonConfig,
+    vfs: Arc<Vfs>,
+    server: Arc<Server<Arc<Vfs>>>,
+    scheduler: Option<Arc<Scheduler>>,
+    backends: Vec<(String, VfsIndex)>,
+    writes: Arc<WriteAccounting>,
+    watchdog: Option<Arc<Watchdog>>,
+    monitor: Option<WatchdogMonitor>,
+    session: Option<FuseSession>,
+    workers: Vec<JoinHandle<()>>,
+    state: DaemonState,
+}
+
+impl Daemon {
+    /// Create a daemon and mount the configured backends in its [Vfs].
+    pub fn new(cfg: DaemonConfig) -> io::Result<Self> {
+        cfg.validate()?;
+
+        let writes = Arc::new(WriteAccounting::default());
+        // Long running operations of backends are aborted once the server drains for shutdown.
+        let shedder = Arc::new(DrainShedder::default());
+        let vfs = Arc::new(
+            Vfs::new(VfsOptions {
+                no_open: false,
+                no_opendir: false,
+                ..Default::default()
+            })
+            .with_write_accounting(writes.clone()),
+        );
+        let mut backends = Vec::with_capacity(cfg.backends.len());
+        for b in cfg.backends.iter() {
+            let fs_cfg = Config {
+                root_dir: b.source.clone(),
+                xattr: b.xattr,
+                writeback: b.writeback,
+                cache_policy: b.cache,
+                do_import: false,
+                ..Default::default()
+            };
+            let fs = PassthroughFs::<()>::new(fs_cfg)?
+                .with_write_accounting(writes.clone())
+                .with_yield_points(YieldPoints::new(shedder.clone()));
+            fs.import()?;
+            let idx = vfs.mount(Box::new(fs), &b.path).map_err(|e| {
+                io::Error::other(format!("mount backend {} at {}: {:?}", b.source, b.path, e))
+            })?;
+            info!("daemon: backend {} mounted at {}", b.source, b.path);
+            backends.push((b.path.clone(), idx));
+        }
+
+        let scheduler = cfg.backend_inflight.map(|max_inflight| {
+            let vfs = vfs.clone();
+            Arc::new(Scheduler::new(
+                BackendLimits { max_inflight },
+                Arc::new(move |nodeid| vfs.backend_of(nodeid)),
+            ))
+        });
+
+        let watchdog = cfg.hung_request_threshold.map(|threshold| {
+            Arc::new(Watchdog::new(
+                threshold,
+                Arc::new(|req: &InflightRequest| {
+                    warn!(
+                        "daemon: request {} {:?} of inode {} hung for {}ms",
+                        req.unique,
+                        Opcode::from(req.opcode),
+                        req.nodeid,
+                        req.elapsed.as_millis()
+                    )
+                }),
+            ))
+        });
+        let mut server = Server::new(vfs.clone())
+            .with_write_accounting(writes.clone())
+            .with_drain_shedder(shedder);
+        if let Some(watchdog) = watchdog.as_ref() {
+            server = server.with_watchdog(watchdog.clone());
+        }
+
+        Ok(Daemon {
+            cfg,
+            server: Arc::new(server),
+            scheduler,
+            vfs,
+            backends,
+            writes,
+            watchdog,
+            monitor: None,
+            session: None,
+            workers: Vec::new(),
+            state: DaemonState::Created,
+        })
+    }
+
+    /// Get the [Vfs] of the daemon.
+    pub fn vfs(&self) -> &Arc<Vfs> {
+        &self.vfs
+    }
+
+    /// Get the [Server] of the daemon.
+    pub fn server(&self) -> &Arc<Server<Arc<Vfs>>> {
+        &self.server
+    }
+
+    /// Get the state of the daemon.
+    pub fn state(&self) -> DaemonState {
+        self.state
+    }
+
+    /// Take a snapshot of the metrics of the daemon.
+    pub fn metrics(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            inflight_requests: self.server.inflight_requests(),
+            requests: self.server.inflight_snapshot(),
+            backends: self
+                .backends
+                .iter()
+                .map(|(path, idx)| BackendMetrics {
+                    path: path.clone(),
+                    idle_time: self.vfs.idle_time(*idx),
+                })
+                .collect(),
+            writes: self.writes.stats(),
+        }
+    }
+
+    /// Mount the session and spawn the worker threads.
+    pub fn start(&mut self) -> io::Result<()> {
+        if self.state != DaemonState::Created {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let mut session = FuseSession::new(
+            &self.cfg.mountpoint,
+            &self.cfg.fsname,
+            "",
+            self.cfg.readonly,
+        )
+        .map_err(transport_error)?;
+        session.mount().map_err(transport_error)?;
+        // Keep the session, so it gets stopped properly if any worker fails to spawn.
+        self.session = Some(session);
+        self.state = DaemonState::Running;
+
+        for idx in 0..self.cfg.threads {
+            let session = self.session.as_ref().unwrap();
+            let ch = session.new_channel().map_err(transport_error)?;
+            let server = self.server.clone();
+            let scheduler = self.scheduler.clone();
+            let worker = thread::Builder::new()
+                .name(format!("fuse_worker_{}", idx))
+                .spawn(move || serve(server, ch, scheduler))?;
+            self.workers.push(worker);
+        }
+        if let Some(watchdog) = self.watchdog.clone() {
+            // Check twice per threshold, so hung requests are logged with a delay of half of it.
+            let interval = self.cfg.hung_request_threshold.unwrap_or_default() / 2;
+            self.monitor = Some(watchdog.spawn_monitor(interval)?);
+        }
+        info!(
+            "daemon: serving {:?} with {} workers",
+            self.cfg.mountpoint, self.cfg.threads
+        );
+
+        Ok(())
+    }
+
+    /// Shut down the session, drain in-flight requests, destroy the backends, umount the
+    /// session and join the worker threads, in order.
+    pub fn stop(&mut self) -> io::Result<()> {
+        let state = std::mem::replace(&mut self.state, DaemonState::Stopped);
+        let mut session = match (state, self.session.take()) {
+            (DaemonState::Running, Some(session)) => session,
+            _ => return Ok(()),
+        };
+
+        let mut shutdown = GracefulShutdown::new().with_drain_timeout(self.cfg.drain_timeout);
+        let workers = std::mem::take(&mut self.workers);
+        let res = stop_session(&mut shutdown, &mut session, &self.server, workers);
+        self.monitor.take();
+        res
+    }
+
+    /// Start the daemon, then serve until `SIGINT` or `SIGTERM` and stop the daemon.
+    ///
+    /// A snapshot of the metrics, including the requests in flight if watched for hung requests,
+    /// is logged on `SIGUSR1`. The signals are blocked in the calling thread, and in the worker
+    /// threads which inherit its signal mask.
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut mask = SigSet::empty();
+        mask.add(Signal::SIGINT);
+        mask.add(Signal::SIGTERM);
+        mask.add(Signal::SIGUSR1);
+        mask.thread_block()?;
+        let mut sfd = SignalFd::new(&mask)?;
+
+        self.start()?;
+        loop {
+            let info = match sfd.read_signal() {
+                Ok(Some(info)) => info,
+                Ok(None) | Err(nix::errno::Errno::EINTR) => continue,
+                Err(e) => {
+                    error!("daemon: failed to read signals, {}", e);
+                    break;
+                }
+            };
+            match Signal::try_from(info.ssi_signo as i32) {
+                Ok(Signal::SIGUSR1) => info!("daemon: metrics: {}", self.metrics()),
+                Ok(s) => {
+                    info!("daemon: received {}, stopping", s);
+                    break;
+                }
+                Err(e) => warn!("daemon: unexpected signal {}, {}", info.ssi_signo, e),
+            }
+        }
+
+        self.stop()
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            error!("daemon: failed to stop, {}", e);
+        }
+    }
+}
+
+fn transport_error(e: crate::transport::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+// Copy of a request deferred by the backend scheduler, with the fd of the channel it has been
+// read from. The kernel only accepts the reply on the same fd.
+struct DeferredRequest {
+    fd: RawFd,
+    buf: Vec<u8>,
+}
+
+type Scheduler = BackendScheduler<DeferredRequest>;
+
+impl DeferredRequest {
+    fn copy(fd: RawFd, reader: &Reader) -> Self {
+        let mut reader = reader.clone();
+        let mut buf = vec![0u8; reader.available_bytes()];
+        if let Err(e) = reader.read_exact(&mut buf) {
+            error!("daemon: failed to copy deferred fuse request, {}", e);
+        }
+        DeferredRequest { fd, buf }
+    }
+
+    fn handle(mut self, server: &Server<Arc<Vfs>>, reply_buf: &mut [u8]) -> bool {
+        let reader = match Reader::from_fuse_buffer(FuseBuf::new(&mut self.buf)) {
+            Ok(r) => r,
+            Err(e) => {
+                error!("daemon: invalid deferred fuse request, {}", e);
+                return true;
+            }
+        };
+        match FuseDevWriter::new(self.fd, reply_buf) {
+            Ok(writer) => handle(server, reader, writer),
+            Err(e) => {
+                error!("daemon: failed to reply deferred fuse request, {}", e);
+                true
+            }
+        }
+    }
+}
+
+// Handle a request, return false if the connection has been aborted or umounted.
+fn handle(server: &Server<Arc<Vfs>>, reader: Reader, writer: FuseDevWriter) -> bool {
+    match server.handle_message(reader, writer.into(), None, None) {
+        Ok(_) => true,
+        // Replies to requests interrupted meanwhile fail with ENOENT, which doesn't affect other
+        // requests, only a dead connection stops serving.
+        Err(crate::Error::EncodeMessage(e))
+            if matches!(errno_of(&e), Some(libc::EBADF) | Some(libc::ENODEV)) =>
+        {
+            false
+        }
+        Err(e) => {
+            error!("daemon: failed to handle fuse message, {}", e);
+            true
+        }
+    }
+}
+
+// Handle a request read from the channel `fd`, unless its backend is at its cap, then the
+// requests of the backend deferred meanwhile. Return false if the connection has been aborted or
+// umounted.
+fn dispatch(
+    server: &Server<Arc<Vfs>>,
+    scheduler: Option<&Scheduler>,
+    fd: RawFd,
+    reader: Reader,
+    writer: FuseDevWriter,
+    reply_buf: &mut Vec<u8>,
+) -> bool {
+    let scheduler = match scheduler {
+        Some(s) => s,
+        None => return handle(server, reader, writer),
+    };
+
+    if reply_buf.len() < writer.available_bytes() {
+        reply_buf.resize(writer.available_bytes(), 0);
+    }
+    // Malformed requests are left to the server to reject.
+    let nodeid = reader
+        .clone()
+        .read_obj::<InHeader>()
+        .map(|h| h.nodeid)
+        .unwrap_or(0);
+    let mut slot = match scheduler.submit(nodeid, || DeferredRequest::copy(fd, &reader)) {
+        Some(slot) => slot,
+        None => return true,
+    };
+
+    let mut alive = handle(server, reader, writer);
+    while let Some((next, req)) = scheduler.complete(slot) {
+        slot = next;
+        // Deferred requests are dropped once the connection is gone.
+        if alive {
+            alive = req.handle(server, reply_buf);
+        }
+    }
+    alive
+}
+
+// Serve requests from channel `ch` until the session gets shut down or the connection is lost.
+fn serve(server: Arc<Server<Arc<Vfs>>>, mut ch: FuseChannel, scheduler: Option<Arc<Scheduler>>) {
+    let fd = ch.as_raw_fd();
+    let mut reply_buf = Vec::new();
+    loop {
+        match ch.get_request() {
+            Ok(Some((reader, writer))) => {
+                if !dispatch(
+                    &server,
+                    scheduler.as_deref(),
+                    fd,
+                    reader,
+                    writer,
+                    &mut reply_buf,
+                ) {
+                    break;
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                error!("daemon: failed to get fuse request, {}", e);
+                break;
+            }
+        }
+    }
+    debug!("daemon: worker exits");
+}
+
+// Workers exit once the session is shut down, they must be joined after umounting the session
+// because requests may still be in flight until then.
+fn stop_session<S: ShutdownSession>(
+    shutdown: &mut GracefulShutdown,
+    session: &mut S,
+    server: &Server<Arc<Vfs>>,
+    workers: Vec<JoinHandle<()>>,
+) -> io::Result<()> {
+    let res = shutdown.run(session, server);
+    for worker in workers {
+        if worker.join().is_err() {
+            error!("daemon: worker panicked");
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::server::ShutdownEvent;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_daemon_config_parse() {
+        let cfg = DaemonConfig::from_toml(
+            r#"
+            # Shared directories.
+            mountpoint = "/mnt/shared" # trailing comment
+            fsname = "app \"fs\""
+            threads = 8
+            readonly = true
+            drain_timeout_ms = 1_000
+            backend_inflight = 2
+            hung_request_ms = 30_000
+
+            [[backend]]
+            path = "/shared"
+            source = "/srv/shared"
+
+            [[backend]]
+            path = "/logs"
+            source = "/var/log/app#1"
+            xattr = true
+            writeback = true
+            cache = "never"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(cfg.mountpoint, PathBuf::from("/mnt/shared"));
+        assert_eq!(cfg.fsname, "app \"fs\"");
+        assert_eq!(cfg.threads, 8);
+        assert!(cfg.readonly);
+        assert_eq!(cfg.drain_timeout, Duration::from_secs(1));
+        assert_eq!(cfg.backend_inflight, Some(2));
+        assert_eq!(cfg.hung_request_threshold, Some(Duration::from_secs(30)));
+        assert_eq!(
+            cfg.backends,
+            vec![
+                BackendConfig {
+                    path: String::from("/shared"),
+                    source: String::from("/srv/shared"),
+                    xattr: false,
+                    writeback: false,
+                    cache: CachePolicy::Auto,
+                },
+                BackendConfig {
+                    path: String::from("/logs"),
+                    source: String::from("/var/log/app#1"),
+                    xattr: true,
+                    writeback: true,
+                    cache: CachePolicy::Never,
+                },
+            ]
+        );
+
+        let cfg = DaemonConfig::from_toml(
+            "mountpoint = \"/mnt\"\n[[backend]]\npath = \"/\"\nsource = \"/srv\"\n",
+        )
+        .unwrap();
+        assert_eq!(cfg.threads, 4);
+        assert_eq!(cfg.fsname, "passthrough");
+        assert_eq!(cfg.backend_inflight, None);
+        assert_eq!(cfg.hung_request_threshold, None);
+    }
+
+    #[test]
+    fn test_daemon_config_parse_errors() {
+        let backend = "[[backend]]\npath = \"/\"\nsource = \"/srv\"\n";
+        let invalid = [
+            // Missing or invalid top level keys.
+            String::from(backend),
+            format!("mountpoint = \"/mnt\"\nthreads = 0\n{}", backend),
+            format!("mountpoint = \"/mnt\"\nbackend_inflight = 0\n{}", backend),
+            format!("mountpoint = \"/mnt\"\nhung_request_ms = 0\n{}", backend),
+            format!("mountpoint = \"/mnt\"\nthreads = \"4\"\n{}", backend),
+            format!("mountpoint = \"/mnt\"\nmountpoint = \"/mnt\"\n{}", backend),
+            format!("mountpoint = \"/mnt\"\nunknown = 1\n{}", backend),
+            format!("mountpoint = \"/mnt\" x\n{}", backend),
+            format!("mountpoint = \"/mnt\n{}", backend),
+            format!("mountpoint\n{}", backend),
+            format!("[daemon]\nmountpoint = \"/mnt\"\n{}", backend),
+            // Missing or invalid backends.
+            String::from("mountpoint = \"/mnt\"\n"),
+            String::from("mountpoint = \"/mnt\"\n[[backend]]\npath = \"/\"\n"),
+            String::from("mountpoint = \"/mnt\"\n[[backend]]\npath = \"a\"\nsource = \"/s\"\n"),
+            format!("mountpoint = \"/mnt\"\n{}cache = \"sometimes\"\n", backend),
+            format!("mountpoint = \"/mnt\"\n{}{}", backend, backend),
+            format!(
+                "mountpoint = \"/mnt\"\n{}[[backend]]\npath = \"/a\"\nsource = \"/s\"\n",
+                backend
+            ),
+        ];
+        for content in invalid.iter() {
+            assert!(
+                DaemonConfig::from_toml(content).is_err(),
+                "{:?} should be invalid",
+                content
+            );
+        }
+
+        let err = DaemonConfig::from_toml("mountpoint = \"/mnt\"\nthreads = yes\n").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("line 2"));
+    }
+
+    #[test]
+    fn test_daemon_lifecycle() {
+        let source = TempDir::new().unwrap();
+        let cfg = DaemonConfig {
+            mountpoint: PathBuf::from("/nonexistent"),
+            backends: vec![BackendConfig {
+                path: String::from("/"),
+                source: source.as_path().to_str().unwrap().to_string(),
+                xattr: false,
+                writeback: false,
+                cache: CachePolicy::Auto,
+            }],
+            hung_request_threshold: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        let mut daemon = Daemon::new(cfg).unwrap();
+        assert_eq!(daemon.state(), DaemonState::Created);
+        let metrics = daemon.metrics();
+        assert_eq!(metrics.inflight_requests, 0);
+        assert!(metrics.requests.is_empty());
+        assert_eq!(metrics.backends.len(), 1);
+        assert_eq!(metrics.backends[0].path, "/");
+        assert!(metrics.backends[0].idle_time.is_some());
+        assert_eq!(metrics.writes, WriteStats::default());
+
+        // A stopped daemon can't be started again.
+        daemon.stop().unwrap();
+        assert_eq!(daemon.state(), DaemonState::Stopped);
+        let err = daemon.start().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[derive(Default)]
+    struct FakeSession {
+        shutdown: Arc<AtomicBool>,
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl ShutdownSession for FakeSession {
+        fn shutdown(&self) -> io::Result<()> {
+            self.shutdown.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn abort(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn umount(&mut self) -> io::Result<()> {
+            self.events.lock().unwrap().push(String::from("umount"));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_daemon_stop_order() {
+        let source = TempDir::new().unwrap();
+        let cfg = DaemonConfig {
+            backends: vec![BackendConfig {
+                path: String::from("/"),
+                source: source.as_path().to_str().unwrap().to_string(),
+                xattr: false,
+                writeback: false,
+                cache: CachePolicy::Auto,
+            }],
+            ..Default::default()
+        };
+        let daemon = Daemon::new(cfg).unwrap();
+        let mut session = FakeSession::default();
+        let events = session.events.clone();
+
+        // Workers exit once the session is shut down.
+        let workers = (0..2)
+            .map(|_| {
+                let shutdown = session.shutdown.clone();
+                let events = events.clone();
+                thread::spawn(move || {
+                    while !shutdown.load(Ordering::SeqCst) {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    events.lock().unwrap().push(String::from("worker exit"));
+                })
+            })
+            .collect();
+
+        let observed = events.clone();
+        let mut shutdown = GracefulShutdown::new().with_observer(move |e: ShutdownEvent| {
+            observed.lock().unwrap().push(format!("{:?}", e));
+        });
+        stop_session(&mut shutdown, &mut session, daemon.server(), workers).unwrap();
+
+        // Workers may exit at any time after the session is shut down, but are joined before
+        // returning.
+        let events = events.lock().unwrap();
+        let (exits, steps): (Vec<_>, Vec<_>) = events.iter().partition(|e| *e == "worker exit");
+        assert_eq!(exits.len(), 2);
+        assert_eq!(
+            steps,
+            vec![
+                "SessionShutdown",
+                "Drained",
+                "PrepareDestroy",
+                "Destroyed",
+                "umount",
+                "Unmounted"
+            ]
+        );
+    }
+
+    // Backend whose getattr blocks until its gate opens, if it has one. Backends must implement
+    // `AsyncFileSystem` with async-io, so tests using it are only built without.
+    #[cfg(not(feature = "async-io"))]
+    struct GatedFs {
+        gate: Option<Arc<(Mutex<bool>, std::sync::Condvar)>>,
+        served: std::sync::mpsc::SyncSender<()>,
+    }
+
+    #[cfg(not(feature = "async-io"))]
+    impl crate::api::filesystem::FileSystem for GatedFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn getattr(
+            &self,
+            _: &crate::api::filesystem::Context,
+            _: u64,
+            _: Option<u64>,
+        ) -> io::Result<(crate::abi::fuse_abi::stat64, Duration)> {
+            if let Some(gate) = self.gate.as_ref() {
+                let mut open = gate.0.lock().unwrap();
+                while !*open {
+                    open = gate.1.wait(open).unwrap();
+                }
+            }
+            let _ = self.served.send(());
+            Ok((unsafe { std::mem::zeroed() }, Duration::from_secs(1)))
+        }
+    }
+
+    #[cfg(not(feature = "async-io"))]
+    impl crate::api::BackendFileSystem for GatedFs {
+        fn mount(&self) -> io::Result<(crate::api::filesystem::Entry, u64)> {
+            let entry = crate::api::filesystem::Entry {
+                inode: crate::api::filesystem::ROOT_ID,
+                ..Default::default()
+            };
+            Ok((entry, crate::api::VFS_MAX_INO))
+        }
+
+        fn as_any(&self) -> &dyn std::any::Any {
+            self
+        }
+    }
+
+    // Serve getattr requests of a slow and a fast backend with two workers, while the slow
+    // backend is blocked. Return whether the fast backend has been served meanwhile.
+    #[cfg(not(feature = "async-io"))]
+    fn serve_slow_and_fast(scheduler: Option<Scheduler>) -> bool {
+        use crate::abi::fuse_abi::{GetattrIn, Opcode};
+        use std::sync::mpsc;
+        use vm_memory::ByteValued;
+
+        let gate = Arc::new((Mutex::new(false), std::sync::Condvar::new()));
+        let (served_tx, served) = mpsc::sync_channel(16);
+        let vfs = Arc::new(Vfs::default());
+        let slow = vfs
+            .mount(
+                Box::new(GatedFs {
+                    gate: Some(gate.clone()),
+                    served: served_tx.clone(),
+                }),
+                "/slow",
+            )
+            .unwrap();
+        let fast = vfs
+            .mount(
+                Box::new(GatedFs {
+                    gate: None,
+                    served: served_tx,
+                }),
+                "/fast",
+            )
+            .unwrap();
+        let server = Arc::new(Server::new(vfs.clone()));
+        let scheduler = scheduler.map(Arc::new);
+        let reply = Arc::new(vmm_sys_util::tempfile::TempFile::new().unwrap().into_file());
+
+        // Requests queued by the kernel, served by the workers in order.
+        let (queue_tx, queue) = mpsc::channel::<Vec<u8>>();
+        let queue = Arc::new(Mutex::new(queue));
+        let workers: Vec<_> = (0..2)
+            .map(|_| {
+                let (server, scheduler, reply, queue) = (
+                    server.clone(),
+                    scheduler.clone(),
+                    reply.clone(),
+                    queue.clone(),
+                );
+                thread::spawn(move || {
+                    let mut reply_buf = Vec::new();
+                    loop {
+                        let mut buf = match queue.lock().unwrap().recv() {
+                            Ok(buf) => buf,
+                            Err(_) => break,
+                        };
+                        let mut w_buf = vec![0u8; 4096];
+                        let reader = Reader::from_fuse_buffer(FuseBuf::new(&mut buf)).unwrap();
+                        let writer = FuseDevWriter::new(reply.as_raw_fd(), &mut w_buf).unwrap();
+                        let fd = reply.as_raw_fd();
+                        dispatch(
+                            &server,
+                            scheduler.as_deref(),
+                            fd,
+                            reader,
+                            writer,
+                            &mut reply_buf,
+                        );
+                    }
+                })
+            })
+            .collect();
+        let getattr = |idx: VfsIndex, unique: u64| {
+            let body = GetattrIn::default();
+            let header = InHeader {
+                len: (std::mem::size_of::<InHeader>() + body.as_slice().len()) as u32,
+                opcode: Opcode::Getattr as u32,
+                unique,
+                nodeid: ((idx as u64) << 56) | crate::api::filesystem::ROOT_ID,
+                ..Default::default()
+            };
+            let mut buf = header.as_slice().to_vec();
+            buf.extend_from_slice(body.as_slice());
+            buf
+        };
+
+        for unique in 1..=3 {
+            queue_tx.send(getattr(slow, unique)).unwrap();
+        }
+        queue_tx.send(getattr(fast, 4)).unwrap();
+        let fast_served = served
+            .recv_timeout(Duration::from_millis(if scheduler.is_some() {
+                5000
+            } else {
+                200
+            }))
+            .is_ok();
+        if let Some(scheduler) = scheduler.as_ref() {
+            assert_eq!(scheduler.deferred(slow), 2);
+        }
+
+        // All requests of the slow backend complete once it's unblocked.
+        *gate.0.lock().unwrap() = true;
+        gate.1.notify_all();
+        let remaining = if fast_served { 3 } else { 4 };
+        for _ in 0..remaining {
+            served.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        drop(queue_tx);
+        for w in workers {
+            w.join().unwrap();
+        }
+        fast_served
+    }
+
+    #[test]
+    #[cfg(not(feature = "async-io"))]
+    fn test_daemon_backend_fairness() {
+        // Without limits, requests of the slow backend occupy both workers.
+        assert!(!serve_slow_and_fast(None));
+
+        let classify = |nodeid: u64| (nodeid != 0).then_some((nodeid >> 56) as VfsIndex);
+        let scheduler = Scheduler::new(BackendLimits { max_inflight: 1 }, Arc::new(classify));
+        assert!(serve_slow_and_fast(Some(scheduler)));
+    }
+
+    #[test]
+    fn test_daemon_reply_errors() {
+        use crate::abi::fuse_abi::{GetattrIn, Opcode};
+        use std::fs::{File, OpenOptions};
+        use vm_memory::ByteValued;
+
+        let server = Server::new(Arc::new(Vfs::default()));
+        let serve = |file: &File| {
+            let body = GetattrIn::default();
+            let header = InHeader {
+                len: (std::mem::size_of::<InHeader>() + body.as_slice().len()) as u32,
+                opcode: Opcode::Getattr as u32,
+                unique: 1,
+                nodeid: crate::api::filesystem::ROOT_ID,
+                ..Default::default()
+            };
+            let mut buf = header.as_slice().to_vec();
+            buf.extend_from_slice(body.as_slice());
+            let mut w_buf = vec![0u8; 4096];
+            let reader = Reader::from_fuse_buffer(FuseBuf::new(&mut buf)).unwrap();
+            let writer = FuseDevWriter::new(file.as_raw_fd(), &mut w_buf).unwrap();
+            handle(&server, reader, writer)
+        };
+
+        // Failing replies other than for a dead connection don't stop serving.
+        let full = OpenOptions::new().write(true).open("/dev/full").unwrap();
+        assert!(serve(&full));
+        // EBADF means the connection is gone.
+        let readonly = File::open("/dev/null").unwrap();
+        assert!(!serve(&readonly));
+    }
+}
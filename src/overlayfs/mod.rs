@@ -0,0 +1,1216 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Overlay of a writable file system over read-only file systems.
+//!
+//! [OverlayFs] merges the trees of its layers in the daemon, like the overlay file system of the
+//! Linux kernel does on the host:
+//! - A name resolves to the object of the topmost layer having it. Directories found in several
+//!   layers are merged, down to the first opaque directory.
+//! - Objects of lower layers are copied up to the upper layer, after their parent directories,
+//!   when they are opened for write or modified.
+//! - Removing a name existing in a lower layer leaves a whiteout in the upper layer hiding it, and
+//!   directories created over a whiteout are marked opaque.
+//! - Inode numbers are allocated from a table keyed by the layer and inode number of the origin
+//!   of objects, the lower object for copied up ones, so they don't change on copy-up.
+//!
+//! Lower layers are never modified. Extended attributes aren't copied up, and only directories
+//! of the upper layer alone may be renamed, others fail with `EXDEV` like in the kernel without
+//! `redirect_dir`.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CStr, CString};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::abi::fuse_abi::{stat64, CreateIn, SetattrValid, ROOT_ID};
+use crate::api::errno::errno_of;
+use crate::api::filesystem::{Context, Entry, FileSystem, GetxattrReply, SliceReader, VecWriter};
+
+mod sync_io;
+
+/// A layer of an [OverlayFs].
+pub type Layer = Arc<dyn FileSystem<Inode = u64, Handle = u64> + Send + Sync>;
+
+const WHITEOUT_XATTR: &[u8] = b"trusted.overlay.whiteout\0";
+const OPAQUE_XATTR: &[u8] = b"trusted.overlay.opaque\0";
+
+// Index of the upper layer, lower layers follow from the topmost one.
+const UPPER: usize = 0;
+
+// Size of the chunks data is copied up in.
+const COPY_CHUNK: usize = 128 * 1024;
+
+// Size of the requests reading directories of layers.
+const LIST_CHUNK: u32 = 64 * 1024;
+
+/// Representation of whiteouts in the upper layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WhiteoutFormat {
+    /// Character devices with device number 0:0, like the overlay file system of the kernel.
+    #[default]
+    CharDevice,
+    /// Empty regular files with the `trusted.overlay.whiteout` xattr, for upper layers which
+    /// can't create device nodes.
+    Xattr,
+}
+
+/// Options of an [OverlayFs].
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// How whiteouts are created in the upper layer. Character devices 0:0 are recognized as
+    /// whiteouts in all layers, empty regular files with the whiteout xattr only with
+    /// `WhiteoutFormat::Xattr`.
+    ///
+    /// The default value for this option is `WhiteoutFormat::CharDevice`.
+    pub whiteout: WhiteoutFormat,
+}
+
+// Object of the merged tree, with the objects of layers it consists of.
+struct OvlInode {
+    ino: u64,
+    // Parent directory and name, the last ones known for hard links.
+    loc: Mutex<(u64, CString)>,
+    is_dir: bool,
+    // Object in the upper layer, once created or copied up.
+    upper: Mutex<Option<u64>>,
+    // Objects in lower layers by layer index, the topmost first. Only merged directories have
+    // several ones.
+    lowers: Vec<(usize, u64)>,
+    nlookup: AtomicU64,
+    // Number of changes of the entries of the directory made through the overlay, to merge the
+    // entries again for its open handles.
+    version: AtomicU64,
+}
+
+impl OvlInode {
+    // Get the layer index and inode of the topmost object.
+    fn top(&self) -> (usize, u64) {
+        match *self.upper.lock().unwrap() {
+            Some(inode) => (UPPER, inode),
+            None => self.lowers[0],
+        }
+    }
+
+    // Get the objects of all layers, the topmost first.
+    fn layers(&self) -> Vec<(usize, u64)> {
+        let upper = *self.upper.lock().unwrap();
+        upper
+            .map(|inode| (UPPER, inode))
+            .into_iter()
+            .chain(self.lowers.iter().copied())
+            .collect()
+    }
+}
+
+// Inode numbers of the merged tree. Entries are never removed, so objects keep their number
+// after being forgotten by the kernel.
+#[derive(Default)]
+struct InoMap {
+    next: u64,
+    // Inode numbers by layer index and inode number of origins.
+    origins: HashMap<(usize, u64), u64>,
+    // Inode numbers by inode number of copied up objects in the upper layer.
+    copied_up: HashMap<u64, u64>,
+}
+
+impl InoMap {
+    fn get(&mut self, layer: usize, st_ino: u64) -> u64 {
+        if layer == UPPER {
+            if let Some(ino) = self.copied_up.get(&st_ino) {
+                return *ino;
+            }
+        }
+        let next = &mut self.next;
+        *self.origins.entry((layer, st_ino)).or_insert_with(|| {
+            *next += 1;
+            *next
+        })
+    }
+}
+
+#[derive(Clone)]
+struct OvlDirEntry {
+    ino: u64,
+    type_: u32,
+    name: Vec<u8>,
+}
+
+enum HandleData {
+    File {
+        layer: usize,
+        inode: u64,
+        handle: u64,
+    },
+    Dir(DirHandle),
+}
+
+// Merged entries of an open directory, with their readdir cookies.
+//
+// Offsets replied to readdir are cookies of entries rather than indexes, so that readdir
+// continues at the right entry after entries are added or removed. Entries are merged again
+// when the directory changed through the overlay since the last merge. Entries keep their cookie
+// across merges, identified by their name and inode number, which is derived from the layer and
+// inode of their origin so it doesn't change on copy-up. Removed entries are just missing from
+// the next merge, without shifting the cookies of later entries, and new entries get cookies
+// larger than all previous ones.
+struct DirHandle {
+    node: Arc<OvlInode>,
+    state: Mutex<DirState>,
+}
+
+struct DirState {
+    // Version of the directory the entries were merged at.
+    version: u64,
+    // Entries sorted by cookie.
+    entries: Vec<(u64, OvlDirEntry)>,
+    // Cookies by name and inode number of entries, kept after entries are removed.
+    cookies: HashMap<(Vec<u8>, u64), u64>,
+    next_cookie: u64,
+}
+
+impl DirHandle {
+    // Cookies of "." and "..", which are always the first entries.
+    const DOT_COOKIES: u64 = 2;
+
+    fn new(fs: &OverlayFs, ctx: &Context, node: Arc<OvlInode>) -> io::Result<Self> {
+        let mut state = DirState {
+            version: 0,
+            entries: Vec::new(),
+            cookies: HashMap::new(),
+            next_cookie: Self::DOT_COOKIES + 1,
+        };
+        Self::merge(fs, ctx, &node, &mut state)?;
+
+        Ok(DirHandle {
+            node,
+            state: Mutex::new(state),
+        })
+    }
+
+    fn merge(
+        fs: &OverlayFs,
+        ctx: &Context,
+        node: &OvlInode,
+        state: &mut DirState,
+    ) -> io::Result<()> {
+        // Read the version first, so changes made during the merge are merged again.
+        let version = node.version.load(Ordering::Acquire);
+        let entries = fs.read_dir(ctx, node)?;
+        let mut merged = Vec::with_capacity(entries.len());
+        for (idx, d) in entries.into_iter().enumerate() {
+            let cookie = if idx < Self::DOT_COOKIES as usize {
+                idx as u64 + 1
+            } else {
+                let next = &mut state.next_cookie;
+                *state
+                    .cookies
+                    .entry((d.name.clone(), d.ino))
+                    .or_insert_with(|| {
+                        *next += 1;
+                        *next - 1
+                    })
+            };
+            merged.push((cookie, d));
+        }
+        merged.sort_by_key(|(cookie, _)| *cookie);
+        state.entries = merged;
+        state.version = version;
+
+        Ok(())
+    }
+
+    // Call `f` with the entries following the cookie `offset`, with their cookie, until it
+    // returns false.
+    fn read(
+        &self,
+        fs: &OverlayFs,
+        ctx: &Context,
+        offset: u64,
+        mut f: impl FnMut(u64, &OvlDirEntry) -> io::Result<bool>,
+    ) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.version != self.node.version.load(Ordering::Acquire) {
+            Self::merge(fs, ctx, &self.node, &mut state)?;
+        }
+        // Cookies are allocated in sequence, so any offset below the next cookie has been
+        // replied, maybe for an entry removed since. Restart from the first entry on others,
+        // which POSIX allows for offsets not returned by telldir.
+        let offset = if offset < state.next_cookie {
+            offset
+        } else {
+            0
+        };
+        let start = state
+            .entries
+            .partition_point(|(cookie, _)| *cookie <= offset);
+        for (cookie, d) in state.entries[start..].iter() {
+            if !f(*cookie, d)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A file system merging a writable upper layer over read-only lower layers.
+pub struct OverlayFs {
+    upper: Layer,
+    lowers: Vec<Layer>,
+    cfg: Config,
+    inodes: RwLock<HashMap<u64, Arc<OvlInode>>>,
+    ino_map: Mutex<InoMap>,
+    handles: RwLock<HashMap<u64, Arc<HandleData>>>,
+    next_handle: AtomicU64,
+    // Serialize copy-ups, so parent directories are copied up once.
+    copy_up_lock: Mutex<()>,
+}
+
+impl OverlayFs {
+    /// Create an overlay of `upper` over `lowers`, the topmost lower layer first.
+    pub fn new(lowers: Vec<Layer>, upper: Layer, cfg: Config) -> Self {
+        let root = OvlInode {
+            ino: ROOT_ID,
+            loc: Mutex::new((ROOT_ID, CString::default())),
+            is_dir: true,
+            upper: Mutex::new(Some(ROOT_ID)),
+            lowers: (1..=lowers.len()).map(|layer| (layer, ROOT_ID)).collect(),
+            nlookup: AtomicU64::new(2),
+            version: AtomicU64::new(0),
+        };
+        let mut inodes = HashMap::new();
+        inodes.insert(ROOT_ID, Arc::new(root));
+
+        OverlayFs {
+            upper,
+            lowers,
+            cfg,
+            inodes: RwLock::new(inodes),
+            ino_map: Mutex::new(InoMap {
+                next: ROOT_ID,
+                ..Default::default()
+            }),
+            handles: RwLock::new(HashMap::new()),
+            next_handle: AtomicU64::new(1),
+            copy_up_lock: Mutex::new(()),
+        }
+    }
+
+    fn layer(&self, index: usize) -> &Layer {
+        if index == UPPER {
+            &self.upper
+        } else {
+            &self.lowers[index - 1]
+        }
+    }
+
+    fn node(&self, ino: u64) -> io::Result<Arc<OvlInode>> {
+        self.inodes
+            .read()
+            .unwrap()
+            .get(&ino)
+            .cloned()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))
+    }
+
+    fn dir_node(&self, ino: u64) -> io::Result<Arc<OvlInode>> {
+        let node = self.node(ino)?;
+        if !node.is_dir {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+        Ok(node)
+    }
+
+    // Drop the lookup references of objects of layers.
+    fn put_refs(&self, refs: &[(usize, u64)]) {
+        let ctx = Context::new();
+        for (layer, inode) in refs {
+            self.layer(*layer).forget(&ctx, *inode, 1);
+        }
+    }
+
+    fn forget_node(&self, ino: u64, count: u64) {
+        if ino == ROOT_ID {
+            return;
+        }
+        let mut inodes = self.inodes.write().unwrap();
+        let node = match inodes.get(&ino) {
+            Some(node) => node.clone(),
+            None => return,
+        };
+        let nlookup = node.nlookup.load(Ordering::Relaxed).saturating_sub(count);
+        node.nlookup.store(nlookup, Ordering::Relaxed);
+        if nlookup == 0 {
+            inodes.remove(&ino);
+            drop(inodes);
+            self.put_refs(&node.layers());
+        }
+    }
+
+    // Look `name` up in the layers of `parent`, collecting the entries merged into the object,
+    // the topmost first.
+    fn lookup_layers(
+        &self,
+        ctx: &Context,
+        parent: &OvlInode,
+        name: &CStr,
+        found: &mut Vec<(usize, Entry)>,
+    ) -> io::Result<()> {
+        for (layer, dir) in parent.layers() {
+            let entry = match self.layer(layer).lookup(ctx, dir, name) {
+                Ok(entry) if entry.inode != 0 => entry,
+                Ok(_) => continue,
+                Err(e) if errno_of(&e) == Some(libc::ENOENT) => continue,
+                Err(e) => return Err(e),
+            };
+            let is_dir = entry.attr.st_mode & libc::S_IFMT == libc::S_IFDIR;
+            // Whiteouts hide lower objects, and only directories are merged.
+            if self.is_whiteout(layer, &entry) || (!found.is_empty() && !is_dir) {
+                self.put_refs(&[(layer, entry.inode)]);
+                return Ok(());
+            }
+            found.push((layer, entry));
+            if !is_dir || self.is_opaque(layer, entry.inode) {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    // Get the inode number of the object merged from `found`, the first lower object being the
+    // origin of copied up ones.
+    fn ino_of(&self, found: &[(usize, Entry)]) -> u64 {
+        let (layer, entry) = found
+            .iter()
+            .find(|(layer, _)| *layer != UPPER)
+            .unwrap_or(&found[0]);
+        self.ino_map.lock().unwrap().get(*layer, entry.attr.st_ino)
+    }
+
+    // Look `name` up in `parent`, increasing the lookup count of the object found.
+    fn lookup_node(
+        &self,
+        ctx: &Context,
+        parent: &OvlInode,
+        name: &CStr,
+    ) -> io::Result<(Arc<OvlInode>, Entry)> {
+        let mut found = Vec::new();
+        let res = self.lookup_layers(ctx, parent, name, &mut found);
+        let refs: Vec<(usize, u64)> = found.iter().map(|(l, e)| (*l, e.inode)).collect();
+        if let Err(e) = res {
+            self.put_refs(&refs);
+            return Err(e);
+        }
+        if found.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        }
+
+        let ino = self.ino_of(&found);
+        let mut entry = found[0].1;
+        entry.inode = ino;
+        entry.generation = 0;
+        entry.attr.st_ino = ino;
+
+        let mut inodes = self.inodes.write().unwrap();
+        if let Some(node) = inodes.get(&ino) {
+            node.nlookup.fetch_add(1, Ordering::Relaxed);
+            let node = node.clone();
+            drop(inodes);
+            self.put_refs(&refs);
+            return Ok((node, entry));
+        }
+        let (upper, lowers) = match refs.split_first() {
+            Some(((UPPER, inode), lowers)) => (Some(*inode), lowers.to_vec()),
+            _ => (None, refs),
+        };
+        let node = Arc::new(OvlInode {
+            ino,
+            loc: Mutex::new((parent.ino, name.to_owned())),
+            is_dir: entry.attr.st_mode & libc::S_IFMT == libc::S_IFDIR,
+            upper: Mutex::new(upper),
+            lowers,
+            nlookup: AtomicU64::new(1),
+            version: AtomicU64::new(0),
+        });
+        inodes.insert(ino, node.clone());
+
+        Ok((node, entry))
+    }
+
+    fn getxattr_of(&self, layer: usize, inode: u64, name: &[u8]) -> Option<Vec<u8>> {
+        let name = CStr::from_bytes_with_nul(name).unwrap();
+        match self
+            .layer(layer)
+            .getxattr(&Context::new(), inode, name, 256)
+        {
+            Ok(GetxattrReply::Value(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn is_whiteout(&self, layer: usize, entry: &Entry) -> bool {
+        match entry.attr.st_mode & libc::S_IFMT {
+            libc::S_IFCHR => entry.attr.st_rdev == 0,
+            libc::S_IFREG => {
+                self.cfg.whiteout == WhiteoutFormat::Xattr
+                    && entry.attr.st_size == 0
+                    && self
+                        .getxattr_of(layer, entry.inode, WHITEOUT_XATTR)
+                        .is_some()
+            }
+            _ => false,
+        }
+    }
+
+    fn is_opaque(&self, layer: usize, inode: u64) -> bool {
+        self.getxattr_of(layer, inode, OPAQUE_XATTR).as_deref() == Some(b"y")
+    }
+
+    // Create a whiteout named `name` in the upper directory `parent`.
+    fn create_whiteout(&self, parent: u64, name: &CStr) -> io::Result<()> {
+        let ctx = Context::new();
+        let entry = match self.cfg.whiteout {
+            WhiteoutFormat::CharDevice => {
+                self.upper.mknod(&ctx, parent, name, libc::S_IFCHR, 0, 0)?
+            }
+            WhiteoutFormat::Xattr => {
+                let args = CreateIn {
+                    flags: (libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL) as u32,
+                    ..Default::default()
+                };
+                let (entry, handle, _) = self.upper.create(&ctx, parent, name, args)?;
+                let _ = self.upper.release(
+                    &ctx,
+                    entry.inode,
+                    libc::O_WRONLY as u32,
+                    handle.unwrap_or(0),
+                    false,
+                    false,
+                    None,
+                );
+                let xattr = CStr::from_bytes_with_nul(WHITEOUT_XATTR).unwrap();
+                if let Err(e) = self.upper.setxattr(&ctx, entry.inode, xattr, b"y", 0) {
+                    let _ = self.upper.unlink(&ctx, parent, name);
+                    self.upper.forget(&ctx, entry.inode, 1);
+                    return Err(e);
+                }
+                entry
+            }
+        };
+        self.upper.forget(&ctx, entry.inode, 1);
+        Ok(())
+    }
+
+    // Remove the whiteout named `name` in the upper directory `parent`, if any.
+    fn remove_whiteout(&self, parent: u64, name: &CStr) -> io::Result<bool> {
+        let ctx = Context::new();
+        let entry = match self.upper.lookup(&ctx, parent, name) {
+            Ok(entry) if entry.inode != 0 => entry,
+            Ok(_) => return Ok(false),
+            Err(e) if errno_of(&e) == Some(libc::ENOENT) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let whiteout = self.is_whiteout(UPPER, &entry);
+        let res = if whiteout {
+            self.upper.unlink(&ctx, parent, name)
+        } else {
+            Ok(())
+        };
+        self.upper.forget(&ctx, entry.inode, 1);
+        res.map(|_| whiteout)
+    }
+
+    fn set_opaque(&self, inode: u64) -> io::Result<()> {
+        let xattr = CStr::from_bytes_with_nul(OPAQUE_XATTR).unwrap();
+        self.upper.setxattr(&Context::new(), inode, xattr, b"y", 0)
+    }
+
+    // Copy `node` up to the upper layer with its parent directories, returning its upper inode.
+    fn copy_up(&self, node: &OvlInode) -> io::Result<u64> {
+        if let Some(inode) = *node.upper.lock().unwrap() {
+            return Ok(inode);
+        }
+        let _guard = self.copy_up_lock.lock().unwrap();
+        self.copy_up_locked(node)
+    }
+
+    fn copy_up_locked(&self, node: &OvlInode) -> io::Result<u64> {
+        if let Some(inode) = *node.upper.lock().unwrap() {
+            return Ok(inode);
+        }
+        let (parent, name) = node.loc.lock().unwrap().clone();
+        let parent = self.node(parent)?;
+        let parent_upper = self.copy_up_locked(&parent)?;
+
+        let ctx = Context::new();
+        let (layer, inode) = node.lowers[0];
+        let lower = self.layer(layer);
+        let (st, _) = lower.getattr(&ctx, inode, None)?;
+        let fmt = st.st_mode & libc::S_IFMT;
+        let entry = match fmt {
+            libc::S_IFDIR => self
+                .upper
+                .mkdir(&ctx, parent_upper, &name, st.st_mode & 0o7777, 0)?,
+            libc::S_IFREG => self.copy_up_file(lower, inode, parent_upper, &name, &st)?,
+            libc::S_IFLNK => {
+                let target = CString::new(lower.readlink(&ctx, inode)?)?;
+                self.upper.symlink(&ctx, &target, parent_upper, &name)?
+            }
+            _ => self
+                .upper
+                .mknod(&ctx, parent_upper, &name, st.st_mode, st.st_rdev as u32, 0)?,
+        };
+
+        let mut valid =
+            SetattrValid::UID | SetattrValid::GID | SetattrValid::ATIME | SetattrValid::MTIME;
+        if fmt != libc::S_IFLNK {
+            valid |= SetattrValid::MODE;
+        }
+        if let Err(e) = self.upper.setattr(&ctx, entry.inode, st, None, valid) {
+            if fmt != libc::S_IFLNK {
+                self.discard(parent_upper, &name, &entry);
+                return Err(e);
+            }
+        }
+
+        self.ino_map
+            .lock()
+            .unwrap()
+            .copied_up
+            .insert(entry.attr.st_ino, node.ino);
+        *node.upper.lock().unwrap() = Some(entry.inode);
+
+        Ok(entry.inode)
+    }
+
+    fn copy_up_file(
+        &self,
+        lower: &Layer,
+        inode: u64,
+        parent: u64,
+        name: &CStr,
+        st: &stat64,
+    ) -> io::Result<Entry> {
+        let ctx = Context::new();
+        let (handle, _) = lower.open(&ctx, inode, libc::O_RDONLY as u32, 0)?;
+        let handle = handle.unwrap_or(0);
+        let args = CreateIn {
+            flags: (libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL) as u32,
+            mode: st.st_mode & 0o7777,
+            ..Default::default()
+        };
+        let res = self
+            .upper
+            .create(&ctx, parent, name, args)
+            .and_then(|(entry, h, _)| {
+                let h = h.unwrap_or(0);
+                let res = copy_data(&ctx, (lower, inode, handle), (&self.upper, entry.inode, h));
+                let _ = self.upper.release(
+                    &ctx,
+                    entry.inode,
+                    libc::O_WRONLY as u32,
+                    h,
+                    true,
+                    false,
+                    None,
+                );
+                if res.is_err() {
+                    self.discard(parent, name, &entry);
+                }
+                res.map(|_| entry)
+            });
+        let _ = lower.release(
+            &ctx,
+            inode,
+            libc::O_RDONLY as u32,
+            handle,
+            false,
+            false,
+            None,
+        );
+
+        res
+    }
+
+    // Remove the object `entry` created in the upper layer by a failed copy-up.
+    fn discard(&self, parent: u64, name: &CStr, entry: &Entry) {
+        let ctx = Context::new();
+        let _ = if entry.attr.st_mode & libc::S_IFMT == libc::S_IFDIR {
+            self.upper.rmdir(&ctx, parent, name)
+        } else {
+            self.upper.unlink(&ctx, parent, name)
+        };
+        self.upper.forget(&ctx, entry.inode, 1);
+    }
+
+    // Read the entries of the directory `dir` in a layer.
+    fn list_layer(&self, ctx: &Context, layer: usize, dir: u64) -> io::Result<Vec<OvlDirEntry>> {
+        let fs = self.layer(layer);
+        let (handle, _) = fs.opendir(ctx, dir, libc::O_RDONLY as u32)?;
+        let handle = handle.unwrap_or(0);
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        let res = loop {
+            let count = entries.len();
+            let res = fs.readdir(ctx, dir, handle, LIST_CHUNK, offset, &mut |d| {
+                offset = d.offset;
+                if d.name != b"." && d.name != b".." {
+                    entries.push(OvlDirEntry {
+                        ino: d.ino,
+                        type_: d.type_,
+                        name: d.name.to_vec(),
+                    });
+                }
+                Ok(1)
+            });
+            if let Err(e) = res {
+                break Err(e);
+            }
+            if entries.len() == count {
+                break Ok(entries);
+            }
+        };
+        let _ = fs.releasedir(ctx, dir, 0, handle);
+
+        res
+    }
+
+    fn dirent_is_whiteout(&self, layer: usize, dir: u64, d: &OvlDirEntry) -> io::Result<bool> {
+        let maybe = match d.type_ as u8 {
+            libc::DT_CHR => true,
+            libc::DT_REG => self.cfg.whiteout == WhiteoutFormat::Xattr,
+            _ => false,
+        };
+        if !maybe {
+            return Ok(false);
+        }
+        let ctx = Context::new();
+        let name = CString::new(d.name.clone())?;
+        let entry = self.layer(layer).lookup(&ctx, dir, &name)?;
+        if entry.inode == 0 {
+            return Ok(false);
+        }
+        let whiteout = self.is_whiteout(layer, &entry);
+        self.layer(layer).forget(&ctx, entry.inode, 1);
+
+        Ok(whiteout)
+    }
+
+    // Merge the entries of the layers of the directory `node`, calling `f` with the layer of each
+    // visible entry, the entry and whether it's a directory merged with lower ones.
+    fn merge_dir(
+        &self,
+        ctx: &Context,
+        node: &OvlInode,
+        mut f: impl FnMut(usize, &OvlDirEntry, bool) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let layers = node.layers();
+        let mut listings = Vec::with_capacity(layers.len());
+        for (layer, dir) in layers.iter() {
+            listings.push(self.list_layer(ctx, *layer, *dir)?);
+        }
+        let mut seen = HashSet::new();
+        for (pos, (&(layer, dir), listing)) in layers.iter().zip(listings.iter()).enumerate() {
+            for d in listing {
+                if !seen.insert(d.name.as_slice()) || self.dirent_is_whiteout(layer, dir, d)? {
+                    continue;
+                }
+                let merged = d.type_ == libc::DT_DIR as u32
+                    && listings[pos + 1..].iter().any(|l| {
+                        l.iter()
+                            .any(|e| e.name == d.name && e.type_ == libc::DT_DIR as u32)
+                    });
+                f(layer, d, merged)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Read the merged entries of the directory `node`, with "." and "..".
+    fn read_dir(&self, ctx: &Context, node: &OvlInode) -> io::Result<Vec<OvlDirEntry>> {
+        let parent = node.loc.lock().unwrap().0;
+        let mut entries = vec![
+            OvlDirEntry {
+                ino: node.ino,
+                type_: libc::DT_DIR as u32,
+                name: b".".to_vec(),
+            },
+            OvlDirEntry {
+                ino: parent,
+                type_: libc::DT_DIR as u32,
+                name: b"..".to_vec(),
+            },
+        ];
+        self.merge_dir(ctx, node, |layer, d, merged| {
+            let ino = if merged {
+                // Number directories merged with lower ones like lookups do.
+                self.merged_ino(ctx, node, &d.name)?
+            } else {
+                self.ino_map.lock().unwrap().get(layer, d.ino)
+            };
+            entries.push(OvlDirEntry {
+                ino,
+                type_: d.type_,
+                name: d.name.clone(),
+            });
+            Ok(())
+        })?;
+
+        Ok(entries)
+    }
+
+    // Fix the link count of the attributes `st` of `node`, which are the ones of its topmost
+    // object. The link count of a directory merged from several layers is the number of its
+    // subdirectories in the merged tree, plus 2 for "." and the entry in its parent.
+    fn fix_nlink(&self, ctx: &Context, node: &OvlInode, st: &mut stat64) -> io::Result<()> {
+        if !node.is_dir || node.layers().len() < 2 {
+            return Ok(());
+        }
+        let mut subdirs = 0;
+        self.merge_dir(ctx, node, |_, d, _| {
+            if d.type_ == libc::DT_DIR as u32 {
+                subdirs += 1;
+            }
+            Ok(())
+        })?;
+        st.st_nlink = 2 + subdirs;
+
+        Ok(())
+    }
+
+    fn merged_ino(&self, ctx: &Context, parent: &OvlInode, name: &[u8]) -> io::Result<u64> {
+        let name = CString::new(name)?;
+        let mut found = Vec::new();
+        let res = self.lookup_layers(ctx, parent, &name, &mut found);
+        let ino = res.and_then(|_| match found.is_empty() {
+            true => Err(io::Error::from_raw_os_error(libc::ENOENT)),
+            false => Ok(self.ino_of(&found)),
+        });
+        let refs: Vec<(usize, u64)> = found.iter().map(|(l, e)| (*l, e.inode)).collect();
+        self.put_refs(&refs);
+
+        ino
+    }
+
+    // Record a change of the entries of the directory `node`.
+    fn dir_changed(&self, node: &OvlInode) {
+        node.version.fetch_add(1, Ordering::AcqRel);
+    }
+
+    fn new_handle(&self, data: HandleData) -> u64 {
+        let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        self.handles.write().unwrap().insert(handle, Arc::new(data));
+        handle
+    }
+
+    fn get_handle(&self, handle: u64) -> io::Result<Arc<HandleData>> {
+        self.handles
+            .read()
+            .unwrap()
+            .get(&handle)
+            .cloned()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))
+    }
+
+    // Get the layer index, inode and handle in the layer of the file handle `handle`.
+    fn file_handle(&self, handle: u64) -> io::Result<(usize, u64, u64)> {
+        match *self.get_handle(handle)? {
+            HandleData::File {
+                layer,
+                inode,
+                handle,
+            } => Ok((layer, inode, handle)),
+            HandleData::Dir(_) => Err(io::Error::from_raw_os_error(libc::EISDIR)),
+        }
+    }
+
+    // Create `name` in the upper directory of `parent` with `make`, which returns the entry of
+    // the new object in the upper layer, then look it up in the merged tree.
+    fn create_upper<T>(
+        &self,
+        ctx: &Context,
+        parent: u64,
+        name: &CStr,
+        make: impl FnOnce(u64) -> io::Result<(Entry, T)>,
+    ) -> io::Result<(Entry, T)> {
+        let parent = self.dir_node(parent)?;
+        match self.lookup_node(ctx, &parent, name) {
+            Ok((node, _)) => {
+                self.forget_node(node.ino, 1);
+                return Err(io::Error::from_raw_os_error(libc::EEXIST));
+            }
+            Err(e) if errno_of(&e) == Some(libc::ENOENT) => {}
+            Err(e) => return Err(e),
+        }
+        let parent_upper = self.copy_up(&parent)?;
+        let whiteout = self.remove_whiteout(parent_upper, name)?;
+        let (upper_entry, data) = match make(parent_upper) {
+            Ok(res) => res,
+            Err(e) => {
+                if whiteout {
+                    let _ = self.create_whiteout(parent_upper, name);
+                }
+                return Err(e);
+            }
+        };
+
+        self.dir_changed(&parent);
+
+        // Hide lower directories behind the whiteout which was replaced.
+        let is_dir = upper_entry.attr.st_mode & libc::S_IFMT == libc::S_IFDIR;
+        let res = match whiteout && is_dir {
+            true => self.set_opaque(upper_entry.inode),
+            false => Ok(()),
+        };
+        let res = res.and_then(|_| self.lookup_node(ctx, &parent, name));
+        self.upper.forget(ctx, upper_entry.inode, 1);
+
+        res.map(|(_, entry)| (entry, data))
+    }
+}
+
+// Copy data from the file `from` to the file `to`, as layer, inode and handle.
+fn copy_data(ctx: &Context, from: (&Layer, u64, u64), to: (&Layer, u64, u64)) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(COPY_CHUNK);
+    let mut offset = 0;
+    loop {
+        buf.clear();
+        let mut w = VecWriter::new(&mut buf).with_limit(COPY_CHUNK);
+        let count = from.0.read(
+            ctx,
+            from.1,
+            from.2,
+            &mut w,
+            COPY_CHUNK as u32,
+            offset,
+            None,
+            0,
+        )?;
+        if count == 0 {
+            return Ok(());
+        }
+        let mut done = 0;
+        while done < count {
+            let mut r = SliceReader::new(&buf[done..count]);
+            let size = (count - done) as u32;
+            let at = offset + done as u64;
+            match to
+                .0
+                .write(ctx, to.1, to.2, &mut r, size, at, None, false, 0, 0)?
+            {
+                0 => return Err(io::Error::from_raw_os_error(libc::EIO)),
+                n => done += n,
+            }
+        }
+        offset += count as u64;
+    }
+}
+
+#[cfg(all(
+    test,
+    target_os = "linux",
+    any(feature = "fusedev", feature = "virtiofs")
+))]
+mod tests {
+    use super::*;
+    use crate::abi::fuse_abi::FsOptions;
+    use crate::api::filesystem::DirEntry;
+    use crate::passthrough::{Config as PassthroughConfig, PassthroughFs};
+    use std::fs;
+    use std::os::unix::fs::FileTypeExt;
+    use vmm_sys_util::tempdir::TempDir;
+
+    fn layer(dir: &TempDir) -> Layer {
+        let cfg = PassthroughConfig {
+            root_dir: dir.as_path().to_str().unwrap().to_string(),
+            xattr: true,
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        fs.import().unwrap();
+        Arc::new(fs)
+    }
+
+    // Create an overlay of a new upper directory over `lower`.
+    fn overlay(lower: &TempDir, cfg: Config) -> (TempDir, OverlayFs) {
+        let upper = TempDir::new().unwrap();
+        let fs = OverlayFs::new(vec![layer(lower)], layer(&upper), cfg);
+        fs.init(FsOptions::empty()).unwrap();
+        (upper, fs)
+    }
+
+    fn cstr(name: &str) -> CString {
+        CString::new(name).unwrap()
+    }
+
+    fn list(fs: &OverlayFs, dir: u64) -> Vec<(String, u64)> {
+        let ctx = Context::new();
+        let (handle, _) = fs.opendir(&ctx, dir, 0).unwrap();
+        let mut names = Vec::new();
+        fs.readdir(&ctx, dir, handle.unwrap(), 4096, 0, &mut |d: DirEntry| {
+            names.push((String::from_utf8(d.name.to_vec()).unwrap(), d.ino));
+            Ok(1)
+        })
+        .unwrap();
+        fs.releasedir(&ctx, dir, 0, handle.unwrap()).unwrap();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_overlay_merged_lookup() {
+        let lower = TempDir::new().unwrap();
+        fs::create_dir(lower.as_path().join("dir")).unwrap();
+        fs::write(lower.as_path().join("dir/low"), b"lower").unwrap();
+        let (upper, fs) = overlay(&lower, Config::default());
+        fs::create_dir(upper.as_path().join("dir")).unwrap();
+        fs::write(upper.as_path().join("dir/up"), b"upper").unwrap();
+        let ctx = Context::new();
+
+        let dir = fs.lookup(&ctx, ROOT_ID, &cstr("dir")).unwrap();
+        assert_eq!(dir.attr.st_ino, dir.inode);
+        let low = fs.lookup(&ctx, dir.inode, &cstr("low")).unwrap();
+        let up = fs.lookup(&ctx, dir.inode, &cstr("up")).unwrap();
+        assert_ne!(low.inode, up.inode);
+        let names = list(&fs, dir.inode);
+        assert_eq!(
+            names,
+            vec![
+                (".".to_string(), dir.inode),
+                ("..".to_string(), ROOT_ID),
+                ("low".to_string(), low.inode),
+                ("up".to_string(), up.inode),
+            ]
+        );
+        let root = list(&fs, ROOT_ID);
+        assert_eq!(root[2], ("dir".to_string(), dir.inode));
+    }
+
+    #[test]
+    fn test_overlay_merged_nlink() {
+        // Two lower layers and the upper one, with overlapping subdirectories.
+        let (lower1, lower2, upper) = (
+            TempDir::new().unwrap(),
+            TempDir::new().unwrap(),
+            TempDir::new().unwrap(),
+        );
+        for (layer, subdirs) in [
+            (&upper, &["a", "b"][..]),
+            (&lower1, &["b", "c", "gone"][..]),
+            (&lower2, &["a", "c", "d"][..]),
+        ] {
+            for subdir in subdirs {
+                fs::create_dir_all(layer.as_path().join("dir").join(subdir)).unwrap();
+            }
+            fs::write(layer.as_path().join("dir/file"), b"data").unwrap();
+        }
+        let fs = OverlayFs::new(
+            vec![layer(&lower1), layer(&lower2)],
+            layer(&upper),
+            Config::default(),
+        );
+        fs.init(FsOptions::empty()).unwrap();
+        let ctx = Context::new();
+
+        // "a", "b", "c", "d" and "gone".
+        let dir = fs.lookup(&ctx, ROOT_ID, &cstr("dir")).unwrap();
+        assert_eq!(dir.attr.st_nlink, 7);
+        let (st, _) = fs.getattr(&ctx, dir.inode, None).unwrap();
+        assert_eq!(st.st_nlink, 7);
+        let subdirs = list(&fs, dir.inode)
+            .iter()
+            .filter(|(name, _)| !name.starts_with('.') && name != "file")
+            .count();
+        assert_eq!(st.st_nlink as usize, subdirs + 2);
+
+        // Whiteouts hide subdirectories, new ones are counted once.
+        fs.rmdir(&ctx, dir.inode, &cstr("gone")).unwrap();
+        fs.mkdir(&ctx, dir.inode, &cstr("e"), 0o755, 0).unwrap();
+        let (st, _) = fs.getattr(&ctx, dir.inode, None).unwrap();
+        assert_eq!(st.st_nlink, 7);
+
+        // Directories of a single layer keep the link count of the layer.
+        let d = fs.lookup(&ctx, dir.inode, &cstr("d")).unwrap();
+        assert_eq!(d.attr.st_nlink, 2);
+    }
+
+    // Read at most `count` entries of the open directory `handle` after `offset`, returning
+    // their names and the offset to continue at.
+    fn read_some(fs: &OverlayFs, handle: u64, offset: u64, count: usize) -> (Vec<String>, u64) {
+        let (mut names, mut next) = (Vec::new(), offset);
+        fs.readdir(
+            &Context::new(),
+            0,
+            handle,
+            4096,
+            offset,
+            &mut |d: DirEntry| {
+                if names.len() == count {
+                    return Ok(0);
+                }
+                names.push(String::from_utf8(d.name.to_vec()).unwrap());
+                next = d.offset;
+                Ok(1)
+            },
+        )
+        .unwrap();
+        (names, next)
+    }
+
+    #[test]
+    fn test_overlay_readdir_cookies() {
+        let lower = TempDir::new().unwrap();
+        for i in 0..40 {
+            fs::write(lower.as_path().join(format!("l{:02}", i)), b"lower").unwrap();
+        }
+        let (upper, fs) = overlay(&lower, Config::default());
+        for i in 0..20 {
+            fs::write(upper.as_path().join(format!("u{:02}", i)), b"upper").unwrap();
+        }
+        let ctx = Context::new();
+        let (handle, _) = fs.opendir(&ctx, ROOT_ID, 0).unwrap();
+        let handle = handle.unwrap();
+        let all: HashSet<String> = list(&fs, ROOT_ID).into_iter().map(|(n, _)| n).collect();
+        assert_eq!(all.len(), 62);
+
+        // Interleave reads of the directory with unlinks of entries, already read or not, from
+        // both layers.
+        let (mut seen, mut removed) = (Vec::new(), HashSet::new());
+        let mut offset = 0;
+        loop {
+            let (names, next) = read_some(&fs, handle, offset, 7);
+            if names.is_empty() {
+                break;
+            }
+            offset = next;
+            seen.extend(names);
+            let victims: Vec<String> = all
+                .iter()
+                .filter(|n| !n.starts_with('.') && !removed.contains(*n))
+                .take(3)
+                .cloned()
+                .collect();
+            for name in victims {
+                fs.unlink(&ctx, ROOT_ID, &cstr(&name)).unwrap();
+                removed.insert(name);
+            }
+        }
+
+        // No duplicates, and no misses among the surviving entries.
+        let unique: HashSet<String> = seen.iter().cloned().collect();
+        assert_eq!(unique.len(), seen.len());
+        for name in all.difference(&removed) {
+            assert!(unique.contains(name), "{} missed", name);
+        }
+        // Removed entries not read before their removal are absent.
+        assert!(unique.is_subset(&all));
+        assert!(!removed.is_empty() && unique.len() < all.len());
+
+        // Entries created while reading are returned after the existing ones.
+        fs.mknod(&ctx, ROOT_ID, &cstr("new"), libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+        let (names, _) = read_some(&fs, handle, offset, 100);
+        assert_eq!(names, vec!["new".to_string()]);
+
+        // Unknown cookies restart from the first entry.
+        let (names, _) = read_some(&fs, handle, u64::MAX, 2);
+        assert_eq!(names, vec![".".to_string(), "..".to_string()]);
+        fs.releasedir(&ctx, ROOT_ID, 0, handle).unwrap();
+    }
+
+    #[test]
+    fn test_overlay_copy_up() {
+        let lower = TempDir::new().unwrap();
+        fs::create_dir(lower.as_path().join("dir")).unwrap();
+        fs::write(lower.as_path().join("dir/file"), b"lower data").unwrap();
+        let (upper, fs) = overlay(&lower, Config::default());
+        let ctx = Context::new();
+
+        let dir = fs.lookup(&ctx, ROOT_ID, &cstr("dir")).unwrap();
+        let file = fs.lookup(&ctx, dir.inode, &cstr("file")).unwrap();
+        let (handle, _) = fs.open(&ctx, file.inode, libc::O_WRONLY as u32, 0).unwrap();
+        let handle = handle.unwrap();
+        let mut r = SliceReader::new(b"upper");
+        fs.write(&ctx, file.inode, handle, &mut r, 5, 0, None, false, 0, 0)
+            .unwrap();
+        fs.release(&ctx, file.inode, 0, handle, true, false, None)
+            .unwrap();
+
+        assert_eq!(
+            fs::read(upper.as_path().join("dir/file")).unwrap(),
+            b"upper data"
+        );
+        assert_eq!(
+            fs::read(lower.as_path().join("dir/file")).unwrap(),
+            b"lower data"
+        );
+        // The inode number doesn't change once the copy is forgotten and looked up again.
+        let (st, _) = fs.getattr(&ctx, file.inode, None).unwrap();
+        assert_eq!(st.st_ino, file.inode);
+        fs.forget(&ctx, file.inode, 1);
+        let again = fs.lookup(&ctx, dir.inode, &cstr("file")).unwrap();
+        assert_eq!(again.inode, file.inode);
+        assert_eq!(list(&fs, dir.inode)[2], ("file".to_string(), file.inode));
+    }
+
+    #[test]
+    fn test_overlay_whiteout() {
+        let lower = TempDir::new().unwrap();
+        fs::write(lower.as_path().join("file"), b"lower").unwrap();
+        fs::create_dir(lower.as_path().join("dir")).unwrap();
+        fs::write(lower.as_path().join("dir/child"), b"lower").unwrap();
+        let (upper, fs) = overlay(&lower, Config::default());
+        let ctx = Context::new();
+
+        let err = fs.rmdir(&ctx, ROOT_ID, &cstr("dir")).err().unwrap();
+        assert_eq!(errno_of(&err), Some(libc::ENOTEMPTY));
+        let dir = fs.lookup(&ctx, ROOT_ID, &cstr("dir")).unwrap();
+        fs.unlink(&ctx, dir.inode, &cstr("child")).unwrap();
+        fs.rmdir(&ctx, ROOT_ID, &cstr("dir")).unwrap();
+        fs.unlink(&ctx, ROOT_ID, &cstr("file")).unwrap();
+
+        let meta = fs::symlink_metadata(upper.as_path().join("file")).unwrap();
+        assert!(meta.file_type().is_char_device());
+        assert!(lower.as_path().join("file").exists());
+        assert!(lower.as_path().join("dir/child").exists());
+        let err = fs.lookup(&ctx, ROOT_ID, &cstr("file")).err().unwrap();
+        assert_eq!(errno_of(&err), Some(libc::ENOENT));
+        assert_eq!(list(&fs, ROOT_ID).len(), 2);
+
+        // A directory created over the whiteout hides the lower one.
+        let new = fs.mkdir(&ctx, ROOT_ID, &cstr("dir"), 0o755, 0).unwrap();
+        assert_eq!(list(&fs, new.inode).len(), 2);
+        let err = fs.lookup(&ctx, new.inode, &cstr("child")).err().unwrap();
+        assert_eq!(errno_of(&err), Some(libc::ENOENT));
+    }
+
+    #[test]
+    fn test_overlay_xattr_whiteout() {
+        let lower = TempDir::new().unwrap();
+        fs::write(lower.as_path().join("file"), b"lower").unwrap();
+        let cfg = Config {
+            whiteout: WhiteoutFormat::Xattr,
+        };
+        let (upper, fs) = overlay(&lower, cfg);
+        let ctx = Context::new();
+
+        fs.unlink(&ctx, ROOT_ID, &cstr("file")).unwrap();
+        let meta = fs::metadata(upper.as_path().join("file")).unwrap();
+        assert!(meta.is_file() && meta.len() == 0);
+        let err = fs.lookup(&ctx, ROOT_ID, &cstr("file")).err().unwrap();
+        assert_eq!(errno_of(&err), Some(libc::ENOENT));
+
+        let args = CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            ..Default::default()
+        };
+        let (entry, handle, _) = fs.create(&ctx, ROOT_ID, &cstr("file"), args).unwrap();
+        fs.release(&ctx, entry.inode, 0, handle.unwrap(), false, false, None)
+            .unwrap();
+        assert_eq!(list(&fs, ROOT_ID)[2], ("file".to_string(), entry.inode));
+    }
+}
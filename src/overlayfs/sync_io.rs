@@ -0,0 +1,565 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! FileSystem trait implementation of [OverlayFs].
+
+use std::ffi::CStr;
+use std::io;
+use std::time::Duration;
+
+use super::*;
+use crate::abi::fuse_abi::{statvfs64, FsOptions, OpenOptions};
+use crate::api::filesystem::{DirEntry, ListxattrReply, ZeroCopyReader, ZeroCopyWriter};
+
+impl OverlayFs {
+    #[allow(clippy::too_many_arguments)]
+    fn do_rename(
+        &self,
+        ctx: &Context,
+        old_parent: &OvlInode,
+        oldname: &CStr,
+        node: &OvlInode,
+        new_parent: &OvlInode,
+        newname: &CStr,
+        target: Option<&OvlInode>,
+        flags: u32,
+    ) -> io::Result<()> {
+        if node.is_dir && !node.lowers.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::EXDEV));
+        }
+        if let Some(target) = target {
+            if flags & libc::RENAME_NOREPLACE != 0 {
+                return Err(io::Error::from_raw_os_error(libc::EEXIST));
+            }
+            if target.ino == node.ino {
+                return Ok(());
+            }
+            match (node.is_dir, target.is_dir) {
+                (false, true) => return Err(io::Error::from_raw_os_error(libc::EISDIR)),
+                (true, false) => return Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+                (true, true) if !target.lowers.is_empty() => {
+                    return Err(io::Error::from_raw_os_error(libc::EXDEV))
+                }
+                _ => {}
+            }
+        }
+
+        let src = self.copy_up(node)?;
+        let old_upper = self.copy_up(old_parent)?;
+        let new_upper = self.copy_up(new_parent)?;
+        let whiteout = self.remove_whiteout(new_upper, newname)?;
+        if let Err(e) = self
+            .upper
+            .rename(ctx, old_upper, oldname, new_upper, newname, 0)
+        {
+            if whiteout {
+                let _ = self.create_whiteout(new_upper, newname);
+            }
+            return Err(e);
+        }
+        if node.is_dir && whiteout {
+            self.set_opaque(src)?;
+        }
+        if !node.lowers.is_empty() {
+            self.create_whiteout(old_upper, oldname)?;
+        }
+        *node.loc.lock().unwrap() = (new_parent.ino, newname.to_owned());
+
+        Ok(())
+    }
+
+    fn do_unlink(
+        &self,
+        ctx: &Context,
+        parent: &OvlInode,
+        node: &OvlInode,
+        name: &CStr,
+    ) -> io::Result<()> {
+        if node.is_dir {
+            return Err(io::Error::from_raw_os_error(libc::EISDIR));
+        }
+        let parent_upper = self.copy_up(parent)?;
+        if node.upper.lock().unwrap().is_some() {
+            self.upper.unlink(ctx, parent_upper, name)?;
+        }
+        if !node.lowers.is_empty() {
+            self.create_whiteout(parent_upper, name)?;
+        }
+        Ok(())
+    }
+
+    fn do_rmdir(
+        &self,
+        ctx: &Context,
+        parent: &OvlInode,
+        node: &OvlInode,
+        name: &CStr,
+    ) -> io::Result<()> {
+        if !node.is_dir {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+        if self.read_dir(ctx, node)?.len() > 2 {
+            return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY));
+        }
+        let parent_upper = self.copy_up(parent)?;
+        let upper = *node.upper.lock().unwrap();
+        if let Some(dir) = upper {
+            // The directory holds whiteouts only, as it's empty.
+            let root = Context::new();
+            for d in self.list_layer(&root, UPPER, dir)? {
+                self.upper.unlink(&root, dir, &CString::new(d.name)?)?;
+            }
+            self.upper.rmdir(ctx, parent_upper, name)?;
+        }
+        if !node.lowers.is_empty() {
+            self.create_whiteout(parent_upper, name)?;
+        }
+        Ok(())
+    }
+
+    // Get the handle in the upper layer of the file handle `handle`, if it's in the upper layer.
+    fn upper_handle(&self, handle: Option<u64>) -> Option<u64> {
+        match handle.map(|h| self.file_handle(h)) {
+            Some(Ok((UPPER, _, handle))) => Some(handle),
+            _ => None,
+        }
+    }
+}
+
+impl FileSystem for OverlayFs {
+    type Inode = u64;
+    type Handle = u64;
+
+    fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
+        let mut opts = self.upper.init(capable)?;
+        for lower in self.lowers.iter() {
+            opts &= lower.init(capable)?;
+        }
+        // Entries of directories are merged without their attributes.
+        opts.remove(FsOptions::DO_READDIRPLUS | FsOptions::READDIRPLUS_AUTO);
+
+        Ok(opts)
+    }
+
+    fn destroy(&self) {
+        self.handles.write().unwrap().clear();
+        self.inodes
+            .write()
+            .unwrap()
+            .retain(|ino, _| *ino == ROOT_ID);
+        self.upper.destroy();
+        for lower in self.lowers.iter() {
+            lower.destroy();
+        }
+    }
+
+    fn lookup(&self, ctx: &Context, parent: u64, name: &CStr) -> io::Result<Entry> {
+        let parent = self.dir_node(parent)?;
+        let (node, mut entry) = self.lookup_node(ctx, &parent, name)?;
+        if let Err(e) = self.fix_nlink(ctx, &node, &mut entry.attr) {
+            self.forget_node(node.ino, 1);
+            return Err(e);
+        }
+
+        Ok(entry)
+    }
+
+    fn forget(&self, _ctx: &Context, inode: u64, count: u64) {
+        self.forget_node(inode, count)
+    }
+
+    fn getattr(
+        &self,
+        ctx: &Context,
+        inode: u64,
+        _handle: Option<u64>,
+    ) -> io::Result<(stat64, Duration)> {
+        let node = self.node(inode)?;
+        let (layer, real) = node.top();
+        let (mut st, timeout) = self.layer(layer).getattr(ctx, real, None)?;
+        st.st_ino = node.ino;
+        self.fix_nlink(ctx, &node, &mut st)?;
+
+        Ok((st, timeout))
+    }
+
+    fn setattr(
+        &self,
+        ctx: &Context,
+        inode: u64,
+        attr: stat64,
+        handle: Option<u64>,
+        valid: SetattrValid,
+    ) -> io::Result<(stat64, Duration)> {
+        let node = self.node(inode)?;
+        let upper = self.copy_up(&node)?;
+        let handle = self.upper_handle(handle);
+        let (mut st, timeout) = self.upper.setattr(ctx, upper, attr, handle, valid)?;
+        st.st_ino = node.ino;
+        self.fix_nlink(ctx, &node, &mut st)?;
+
+        Ok((st, timeout))
+    }
+
+    fn readlink(&self, ctx: &Context, inode: u64) -> io::Result<Vec<u8>> {
+        let (layer, real) = self.node(inode)?.top();
+        self.layer(layer).readlink(ctx, real)
+    }
+
+    fn symlink(
+        &self,
+        ctx: &Context,
+        linkname: &CStr,
+        parent: u64,
+        name: &CStr,
+    ) -> io::Result<Entry> {
+        self.create_upper(ctx, parent, name, |dir| {
+            Ok((self.upper.symlink(ctx, linkname, dir, name)?, ()))
+        })
+        .map(|(entry, _)| entry)
+    }
+
+    fn mknod(
+        &self,
+        ctx: &Context,
+        parent: u64,
+        name: &CStr,
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+    ) -> io::Result<Entry> {
+        self.create_upper(ctx, parent, name, |dir| {
+            Ok((self.upper.mknod(ctx, dir, name, mode, rdev, umask)?, ()))
+        })
+        .map(|(entry, _)| entry)
+    }
+
+    fn mkdir(
+        &self,
+        ctx: &Context,
+        parent: u64,
+        name: &CStr,
+        mode: u32,
+        umask: u32,
+    ) -> io::Result<Entry> {
+        self.create_upper(ctx, parent, name, |dir| {
+            Ok((self.upper.mkdir(ctx, dir, name, mode, umask)?, ()))
+        })
+        .map(|(entry, _)| entry)
+    }
+
+    fn unlink(&self, ctx: &Context, parent: u64, name: &CStr) -> io::Result<()> {
+        let parent = self.dir_node(parent)?;
+        let (node, _) = self.lookup_node(ctx, &parent, name)?;
+        let res = self.do_unlink(ctx, &parent, &node, name);
+        self.dir_changed(&parent);
+        self.forget_node(node.ino, 1);
+
+        res
+    }
+
+    fn rmdir(&self, ctx: &Context, parent: u64, name: &CStr) -> io::Result<()> {
+        let parent = self.dir_node(parent)?;
+        let (node, _) = self.lookup_node(ctx, &parent, name)?;
+        let res = self.do_rmdir(ctx, &parent, &node, name);
+        self.dir_changed(&parent);
+        self.forget_node(node.ino, 1);
+
+        res
+    }
+
+    fn rename(
+        &self,
+        ctx: &Context,
+        olddir: u64,
+        oldname: &CStr,
+        newdir: u64,
+        newname: &CStr,
+        flags: u32,
+    ) -> io::Result<()> {
+        if flags & !libc::RENAME_NOREPLACE != 0 {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let old_parent = self.dir_node(olddir)?;
+        let new_parent = self.dir_node(newdir)?;
+        let (node, _) = self.lookup_node(ctx, &old_parent, oldname)?;
+        let target = match self.lookup_node(ctx, &new_parent, newname) {
+            Ok((target, _)) => Some(target),
+            Err(e) if errno_of(&e) == Some(libc::ENOENT) => None,
+            Err(e) => {
+                self.forget_node(node.ino, 1);
+                return Err(e);
+            }
+        };
+        let res = self.do_rename(
+            ctx,
+            &old_parent,
+            oldname,
+            &node,
+            &new_parent,
+            newname,
+            target.as_deref(),
+            flags,
+        );
+        self.dir_changed(&old_parent);
+        self.dir_changed(&new_parent);
+        self.forget_node(node.ino, 1);
+        if let Some(target) = target {
+            self.forget_node(target.ino, 1);
+        }
+
+        res
+    }
+
+    fn link(&self, ctx: &Context, inode: u64, newparent: u64, newname: &CStr) -> io::Result<Entry> {
+        let node = self.node(inode)?;
+        if node.is_dir {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        self.create_upper(ctx, newparent, newname, |dir| {
+            let upper = self.copy_up(&node)?;
+            Ok((self.upper.link(ctx, upper, dir, newname)?, ()))
+        })
+        .map(|(entry, _)| entry)
+    }
+
+    fn open(
+        &self,
+        ctx: &Context,
+        inode: u64,
+        flags: u32,
+        fuse_flags: u32,
+    ) -> io::Result<(Option<u64>, OpenOptions)> {
+        let node = self.node(inode)?;
+        let write =
+            flags as i32 & libc::O_ACCMODE != libc::O_RDONLY || flags as i32 & libc::O_TRUNC != 0;
+        let (layer, real) = match write {
+            true => (UPPER, self.copy_up(&node)?),
+            false => node.top(),
+        };
+        let (handle, opts) = self.layer(layer).open(ctx, real, flags, fuse_flags)?;
+        let handle = self.new_handle(HandleData::File {
+            layer,
+            inode: real,
+            handle: handle.unwrap_or(0),
+        });
+
+        Ok((Some(handle), opts))
+    }
+
+    fn create(
+        &self,
+        ctx: &Context,
+        parent: u64,
+        name: &CStr,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<u64>, OpenOptions)> {
+        let (entry, (inode, handle, opts)) = self.create_upper(ctx, parent, name, |dir| {
+            let (entry, handle, opts) = self.upper.create(ctx, dir, name, args)?;
+            Ok((entry, (entry.inode, handle, opts)))
+        })?;
+        let handle = self.new_handle(HandleData::File {
+            layer: UPPER,
+            inode,
+            handle: handle.unwrap_or(0),
+        });
+
+        Ok((entry, Some(handle), opts))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn read(
+        &self,
+        ctx: &Context,
+        _inode: u64,
+        handle: u64,
+        w: &mut dyn ZeroCopyWriter,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        flags: u32,
+    ) -> io::Result<usize> {
+        let (layer, inode, handle) = self.file_handle(handle)?;
+        self.layer(layer)
+            .read(ctx, inode, handle, w, size, offset, lock_owner, flags)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write(
+        &self,
+        ctx: &Context,
+        _inode: u64,
+        handle: u64,
+        r: &mut dyn ZeroCopyReader,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        delayed_write: bool,
+        flags: u32,
+        fuse_flags: u32,
+    ) -> io::Result<usize> {
+        let (layer, inode, handle) = self.file_handle(handle)?;
+        self.layer(layer).write(
+            ctx,
+            inode,
+            handle,
+            r,
+            size,
+            offset,
+            lock_owner,
+            delayed_write,
+            flags,
+            fuse_flags,
+        )
+    }
+
+    fn flush(&self, ctx: &Context, _inode: u64, handle: u64, lock_owner: u64) -> io::Result<()> {
+        let (layer, inode, handle) = self.file_handle(handle)?;
+        self.layer(layer).flush(ctx, inode, handle, lock_owner)
+    }
+
+    fn fsync(&self, ctx: &Context, _inode: u64, datasync: bool, handle: u64) -> io::Result<()> {
+        let (layer, inode, handle) = self.file_handle(handle)?;
+        self.layer(layer).fsync(ctx, inode, datasync, handle)
+    }
+
+    fn fallocate(
+        &self,
+        ctx: &Context,
+        _inode: u64,
+        handle: u64,
+        mode: u32,
+        offset: u64,
+        length: u64,
+    ) -> io::Result<()> {
+        let (layer, inode, handle) = self.file_handle(handle)?;
+        self.layer(layer)
+            .fallocate(ctx, inode, handle, mode, offset, length)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn release(
+        &self,
+        ctx: &Context,
+        _inode: u64,
+        flags: u32,
+        handle: u64,
+        flush: bool,
+        flock_release: bool,
+        lock_owner: Option<u64>,
+    ) -> io::Result<()> {
+        let (layer, inode, real) = self.file_handle(handle)?;
+        self.handles.write().unwrap().remove(&handle);
+        self.layer(layer)
+            .release(ctx, inode, flags, real, flush, flock_release, lock_owner)
+    }
+
+    fn lseek(
+        &self,
+        ctx: &Context,
+        _inode: u64,
+        handle: u64,
+        offset: u64,
+        whence: u32,
+    ) -> io::Result<u64> {
+        let (layer, inode, handle) = self.file_handle(handle)?;
+        self.layer(layer).lseek(ctx, inode, handle, offset, whence)
+    }
+
+    fn statfs(&self, ctx: &Context, _inode: u64) -> io::Result<statvfs64> {
+        self.upper.statfs(ctx, ROOT_ID)
+    }
+
+    fn setxattr(
+        &self,
+        ctx: &Context,
+        inode: u64,
+        name: &CStr,
+        value: &[u8],
+        flags: u32,
+    ) -> io::Result<()> {
+        let upper = self.copy_up(&*self.node(inode)?)?;
+        self.upper.setxattr(ctx, upper, name, value, flags)
+    }
+
+    fn getxattr(
+        &self,
+        ctx: &Context,
+        inode: u64,
+        name: &CStr,
+        size: u32,
+    ) -> io::Result<GetxattrReply> {
+        let (layer, real) = self.node(inode)?.top();
+        self.layer(layer).getxattr(ctx, real, name, size)
+    }
+
+    fn listxattr(&self, ctx: &Context, inode: u64, size: u32) -> io::Result<ListxattrReply> {
+        let (layer, real) = self.node(inode)?.top();
+        self.layer(layer).listxattr(ctx, real, size)
+    }
+
+    fn removexattr(&self, ctx: &Context, inode: u64, name: &CStr) -> io::Result<()> {
+        let upper = self.copy_up(&*self.node(inode)?)?;
+        self.upper.removexattr(ctx, upper, name)
+    }
+
+    fn opendir(
+        &self,
+        ctx: &Context,
+        inode: u64,
+        _flags: u32,
+    ) -> io::Result<(Option<u64>, OpenOptions)> {
+        let node = self.dir_node(inode)?;
+        let dir = DirHandle::new(self, ctx, node)?;
+
+        Ok((
+            Some(self.new_handle(HandleData::Dir(dir))),
+            OpenOptions::empty(),
+        ))
+    }
+
+    fn readdir(
+        &self,
+        ctx: &Context,
+        _inode: u64,
+        handle: u64,
+        _size: u32,
+        offset: u64,
+        add_entry: &mut dyn FnMut(DirEntry) -> io::Result<usize>,
+    ) -> io::Result<()> {
+        let data = self.get_handle(handle)?;
+        let dir = match &*data {
+            HandleData::Dir(dir) => dir,
+            HandleData::File { .. } => return Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+        };
+        dir.read(self, ctx, offset, |cookie, d| {
+            let entry = DirEntry {
+                ino: d.ino,
+                offset: cookie,
+                type_: d.type_,
+                name: &d.name,
+            };
+            Ok(add_entry(entry)? != 0)
+        })
+    }
+
+    fn fsyncdir(
+        &self,
+        _ctx: &Context,
+        _inode: u64,
+        _datasync: bool,
+        _handle: u64,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn releasedir(&self, _ctx: &Context, _inode: u64, _flags: u32, handle: u64) -> io::Result<()> {
+        self.handles.write().unwrap().remove(&handle);
+        Ok(())
+    }
+
+    fn access(&self, ctx: &Context, inode: u64, mask: u32) -> io::Result<()> {
+        let (layer, real) = self.node(inode)?.top();
+        self.layer(layer).access(ctx, real, mask)
+    }
+}
@@ -5,8 +5,11 @@
 #![allow(dead_code)]
 #![allow(unused_imports)]
 
+use std::future::Future;
 use std::io;
 use std::mem::ManuallyDrop;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
 
 use async_trait::async_trait;
 
@@ -14,9 +17,11 @@ use super::*;
 use crate::abi::fuse_abi::{
     CreateIn, OpenOptions, SetattrValid, FOPEN_IN_KILL_SUIDGID, WRITE_KILL_PRIV,
 };
+use crate::api::executor::{run_blocking, run_blocking_scoped, Executor};
 use crate::api::filesystem::{
     AsyncFileSystem, AsyncZeroCopyReader, AsyncZeroCopyWriter, Context, FileSystem,
 };
+use crate::transport::{AsyncFileReadWriteVolatile, FileVolatileBuf};
 
 impl<S: BitmapSlice + Send + Sync + 'static> BackendFileSystem for PassthroughFs<S> {
     fn mount(&self) -> io::Result<(Entry, u64)> {
@@ -27,291 +32,139 @@ impl<S: BitmapSlice + Send + Sync + 'static> BackendFileSystem for PassthroughFs
     fn as_any(&self) -> &dyn Any {
         self
     }
-}
 
-impl<'a> InodeData {
-    async fn async_get_file(&self, mount_fds: &MountFds) -> io::Result<InodeFile<'_>> {
-        // The io_uring doesn't support open_by_handle_at yet, so use sync io.
-        self.get_file(mount_fds)
+    fn push_file_as(
+        &self,
+        notifier: &dyn Notifier,
+        inode: u64,
+        nodeid: u64,
+        offset: u64,
+        size: u64,
+    ) -> io::Result<u64> {
+        self.do_push_file(notifier, inode, nodeid, offset, size)
     }
 }
 
-impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
-    /*
-    async fn async_open_file(
-        &self,
-        ctx: &Context,
-        dir_fd: i32,
-        pathname: &'_ CStr,
-        flags: i32,
-        mode: u32,
-    ) -> io::Result<File> {
-        AsyncUtil::open_at(drive, dir_fd, pathname, flags, mode)
-            .await
-            .map(|fd| unsafe { File::from_raw_fd(fd as i32) })
-        }
-
-        async fn async_open_proc_file(
-            &self,
-            ctx: &Context,
-            fd: RawFd,
-            flags: i32,
-            mode: u32,
-        ) -> io::Result<File> {
-            if !is_safe_inode(mode) {
-                return Err(ebadf());
-            }
+// A file on the io_uring of the tokio-uring runtime polling the request.
+//
+// tokio-uring files may only be used from the thread of their runtime. The asynchronous request
+// path handles each request on a single-threaded tokio-uring worker and never moves it to other
+// threads, see `Executor::uring()`, so asserting `Send` for the file and for futures using it
+// is fine.
+struct UringFile(tokio_uring::fs::File);
 
-            let pathname = CString::new(format!("{}", fd))
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-
-            // We don't really check `flags` because if the kernel can't handle poorly specified flags
-            // then we have much bigger problems. Also, clear the `O_NOFOLLOW` flag if it is set since
-            // we need to follow the `/proc/self/fd` symlink to get the file.
-            self.async_open_file(
-                ctx,
-                self.proc_self_fd.as_raw_fd(),
-                pathname.as_c_str(),
-                (flags | libc::O_CLOEXEC) & (!libc::O_NOFOLLOW),
-                0,
-            )
-            .await
-        }
+unsafe impl Send for UringFile {}
+unsafe impl Sync for UringFile {}
 
-        /// Create a File or File Handle for `name` under directory `dir_fd` to support `lookup()`.
-        async fn async_open_file_or_handle<F>(
-            &self,
-            ctx: &Context,
-            dir_fd: RawFd,
-            name: &CStr,
-            reopen_dir: F,
-        ) -> io::Result<(FileOrHandle, InodeStat, InodeAltKey, Option<InodeAltKey>)>
-        where
-            F: FnOnce(RawFd, libc::c_int, u32) -> io::Result<File>,
-        {
-            let handle = if self.cfg.inode_file_handles {
-                FileHandle::from_name_at_with_mount_fds(dir_fd, name, &self.mount_fds, reopen_dir)
-            } else {
-                Err(io::Error::from_raw_os_error(libc::ENOTSUP))
-            };
+#[async_trait(?Send)]
+impl AsyncFileReadWriteVolatile for UringFile {
+    async fn async_read_at_volatile(
+        &self,
+        buf: FileVolatileBuf,
+        offset: u64,
+    ) -> (io::Result<usize>, FileVolatileBuf) {
+        self.0.async_read_at_volatile(buf, offset).await
+    }
 
-            // Ignore errors, because having a handle is optional
-            let file_or_handle = if let Ok(h) = handle {
-                FileOrHandle::Handle(h)
-            } else {
-                let f = self
-                    .async_open_file(
-                        ctx,
-                        dir_fd,
-                        name,
-                        libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
-                        0,
-                    )
-                    .await?;
-
-                FileOrHandle::File(f)
-            };
+    async fn async_read_vectored_at_volatile(
+        &self,
+        bufs: Vec<FileVolatileBuf>,
+        offset: u64,
+    ) -> (io::Result<usize>, Vec<FileVolatileBuf>) {
+        self.0.async_read_vectored_at_volatile(bufs, offset).await
+    }
 
-            let st = match &file_or_handle {
-                FileOrHandle::File(f) => {
-                    // TODO: use statx(2) to query mntid when 5.8 kernel or later are widely used.
-                    //
-                    // Some filesystems don't support file handle, for example overlayfs mounted
-                    // without index feature, if so just use mntid 0 in that case.
-                    let mnt_id = match FileHandle::from_name_at(dir_fd, name) {
-                        Ok(h) => h.mnt_id,
-                        Err(_) => 0,
-                    };
-                    InodeStat {
-                        stat: self.async_stat(ctx, f, None).await?,
-                        mnt_id,
-                    }
-                }
-                FileOrHandle::Handle(h) => InodeStat {
-                    stat: self.async_stat_fd(ctx, dir_fd, Some(name)).await?,
-                    mnt_id: h.mnt_id,
-                },
-            };
-            let ids_altkey = InodeAltKey::ids_from_stat(&st);
+    async fn async_write_at_volatile(
+        &self,
+        buf: FileVolatileBuf,
+        offset: u64,
+    ) -> (io::Result<usize>, FileVolatileBuf) {
+        self.0.async_write_at_volatile(buf, offset).await
+    }
 
-            // Note that this will always be `None` if `cfg.inode_file_handles` is false, but we only
-            // really need this alt key when we do not have an `O_PATH` fd open for every inode.  So if
-            // `cfg.inode_file_handles` is false, we do not need this key anyway.
-            let handle_altkey = file_or_handle.handle().map(|h| InodeAltKey::Handle(*h));
+    async fn async_write_vectored_at_volatile(
+        &self,
+        bufs: Vec<FileVolatileBuf>,
+        offset: u64,
+    ) -> (io::Result<usize>, Vec<FileVolatileBuf>) {
+        self.0.async_write_vectored_at_volatile(bufs, offset).await
+    }
+}
 
-            Ok((file_or_handle, st, ids_altkey, handle_altkey))
-        }
+// A future of the tokio-uring runtime asserted to be `Send`, see `UringFile`.
+struct LocalFuture<F>(F);
 
-        async fn async_open_inode(
-            &self,
-            ctx: &Context,
-            inode: Inode,
-            mut flags: i32,
-        ) -> io::Result<File> {
-            // When writeback caching is enabled, the kernel may send read requests even if the
-            // userspace program opened the file write-only. So we need to ensure that we have opened
-            // the file for reading as well as writing.
-            let writeback = self.writeback.load(Ordering::Relaxed);
-            if writeback && flags & libc::O_ACCMODE == libc::O_WRONLY {
-                flags &= !libc::O_ACCMODE;
-                flags |= libc::O_RDWR;
-            }
+unsafe impl<F> Send for LocalFuture<F> {}
 
-            // When writeback caching is enabled the kernel is responsible for handling `O_APPEND`.
-            // However, this breaks atomicity as the file may have changed on disk, invalidating the
-            // cached copy of the data in the kernel and the offset that the kernel thinks is the end of
-            // the file. Just allow this for now as it is the user's responsibility to enable writeback
-            // caching only for directories that are not shared. It also means that we need to clear the
-            // `O_APPEND` flag.
-            if writeback && flags & libc::O_APPEND != 0 {
-                flags &= !libc::O_APPEND;
-            }
+impl<F: Future + Unpin> Future for LocalFuture<F> {
+    type Output = F::Output;
 
-            let data = self.inode_map.get(inode)?;
-            let file = data.async_get_file(&self.mount_fds).await?;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<F::Output> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
 
-            self.async_open_proc_file(ctx, file.as_raw_fd(), flags, data.mode)
-                .await
-        }
+impl<'a> InodeData {
+    async fn async_get_file(&self, mount_fds: &MountFds) -> io::Result<InodeFile<'_>> {
+        // The io_uring doesn't support open_by_handle_at yet, so use sync io.
+        self.get_file(mount_fds)
+    }
+}
 
-        async fn async_do_open(
-            &self,
-            ctx: &Context,
-            inode: Inode,
-            flags: u32,
-            fuse_flags: u32,
-        ) -> io::Result<(Option<Handle>, OpenOptions)> {
-            let killpriv = if self.killpriv_v2.load(Ordering::Relaxed)
-                && (fuse_flags & FOPEN_IN_KILL_SUIDGID != 0)
-            {
-                self::drop_cap_fsetid()?
-            } else {
-                None
-            };
-            let file = self.async_open_inode(ctx, inode, flags as i32).await?;
-            drop(killpriv);
-
-            let data = HandleData::new(inode, file);
-            let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
-            let mut opts = OpenOptions::empty();
-
-            self.handle_map.insert(handle, data);
-            match self.cfg.cache_policy {
-                // We only set the direct I/O option on files.
-                CachePolicy::Never => opts.set(
-                    OpenOptions::DIRECT_IO,
-                    flags & (libc::O_DIRECTORY as u32) == 0,
-                ),
-                CachePolicy::Always => opts |= OpenOptions::KEEP_CACHE,
-                _ => {}
-            };
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    /// Set the executor to run blocking calls of the asynchronous request path,
+    /// [TokioUringExecutor](crate::api::executor::TokioUringExecutor) by default.
+    pub fn with_executor(mut self, executor: Arc<dyn Executor>) -> Self {
+        self.executor = executor;
+        self
+    }
 
-            Ok((Some(handle), opts))
+    // Reopen the file of handle `data` on the io_uring of the runtime, for reading or writing.
+    //
+    // tokio-uring can't take a borrowed fd, so the file is reopened through `/proc/self/fd`,
+    // keeping `O_APPEND`. Return `None` if the IO must be done synchronously: without an io_uring
+    // runtime or `/proc/self/fd`, for `O_DIRECT` handles as the flag can't be passed to
+    // tokio-uring, or if reopening fails.
+    async fn uring_file(&self, data: &HandleData, write: bool) -> Option<Arc<UringFile>> {
+        if !self.executor.uring() || self.proc_self_fd.is_none() {
+            return None;
         }
-        */
-
-    async fn async_do_getattr(
-        &self,
-        ctx: &Context,
-        inode: Inode,
-        handle: Option<<Self as FileSystem>::Handle>,
-    ) -> io::Result<(libc::stat64, Duration)> {
-        unimplemented!()
-        /*
-        let st;
-        let fd;
-        let data = self.inode_map.get(inode).map_err(|e| {
-            error!("fuse: do_getattr ino {} Not find err {:?}", inode, e);
-            e
-        })?;
-
-        // kernel sends 0 as handle in case of no_open, and it depends on fuse server to handle
-        // this case correctly.
-        if !self.no_open.load(Ordering::Relaxed) && handle.is_some() {
-            // Safe as we just checked handle
-            let hd = self.handle_map.get(handle.unwrap(), inode)?;
-            fd = hd.get_handle_raw_fd();
-            st = self.async_stat_fd(ctx, fd, None).await;
-        } else {
-            match &data.file_or_handle {
-                FileOrHandle::File(f) => {
-                    fd = f.as_raw_fd();
-                    st = self.async_stat_fd(ctx, fd, None).await;
-                }
-                FileOrHandle::Handle(_h) => {
-                    let file = data.async_get_file(&self.mount_fds).await?;
-                    fd = file.as_raw_fd();
-                    st = self.async_stat_fd(ctx, fd, None).await;
-                }
-            }
+        let fd = data.get_handle_raw_fd();
+        // Safe because this doesn't modify any memory and we check the return value.
+        let status = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if status < 0 || status & libc::O_DIRECT != 0 {
+            return None;
         }
 
-        let st = st.map_err(|e| {
-            error!(
-                "fuse: do_getattr stat failed ino {} fd: {:?} err {:?}",
-                inode, fd, e
-            );
-            e
-        })?;
-
-        Ok((st, self.cfg.attr_timeout))
-        */
-    }
-
-    /*
-    async fn async_stat(
-        &self,
-        ctx: &Context,
-        dir: &impl AsRawFd,
-        path: Option<&CStr>,
-    ) -> io::Result<libc::stat64> {
-        self.async_stat_fd(ctx, dir.as_raw_fd(), path).await
-    }
-
-    async fn async_stat_fd(
-        &self,
-        _ctx: &Context,
-        dir_fd: RawFd,
-        path: Option<&CStr>,
-    ) -> io::Result<libc::stat64> {
-        // Safe because this is a constant value and a valid C string.
-        let pathname =
-            path.unwrap_or_else(|| unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) });
-        let mut st = MaybeUninit::<libc::stat64>::zeroed();
-
-        // Safe because the kernel will only write data in `st` and we check the return value.
-        let res = unsafe {
-            libc::fstatat64(
-                dir_fd,
-                pathname.as_ptr(),
-                st.as_mut_ptr(),
-                libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
-            )
-        };
-        if res >= 0 {
-            // Safe because the kernel guarantees that the struct is now fully initialized.
-            Ok(unsafe { st.assume_init() })
-        } else {
-            Err(io::Error::last_os_error())
+        let path = sandbox::proc_self_path(format_args!("fd/{}", fd));
+        let open = Box::pin(async move {
+            tokio_uring::fs::OpenOptions::new()
+                .read(!write)
+                .write(write)
+                .append(write && status & libc::O_APPEND != 0)
+                .open(path)
+                .await
+                .map(|f| Arc::new(UringFile(f)))
+        });
+        match LocalFuture(open).await {
+            Ok(file) => Some(file),
+            Err(e) => {
+                debug!("passthrough: failed to reopen fd {} on io_uring, {}", fd, e);
+                None
+            }
         }
     }
 
-    async fn async_get_data(
-        &self,
-        ctx: &Context,
-        handle: Handle,
-        inode: Inode,
-        flags: libc::c_int,
-    ) -> io::Result<Arc<HandleData>> {
-        let no_open = self.no_open.load(Ordering::Relaxed);
-        if !no_open {
-            self.handle_map.get(handle, inode)
-        } else {
-            let file = self.async_open_inode(ctx, inode, flags as i32).await?;
-            Ok(Arc::new(HandleData::new(inode, file)))
-        }
+    // Run `f`, which blocks on syscalls, off the thread polling requests by
+    // `Executor::spawn_blocking()`, letting it borrow the file system and the request.
+    async fn blocking<T, F>(&self, f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> io::Result<T> + Send,
+    {
+        // Safe because futures of the asynchronous request path are never leaked.
+        unsafe { run_blocking_scoped(&*self.executor, f) }.await?
     }
-     */
 }
 
 #[async_trait]
@@ -322,117 +175,7 @@ impl<S: BitmapSlice + Send + Sync> AsyncFileSystem for PassthroughFs<S> {
         parent: <Self as FileSystem>::Inode,
         name: &CStr,
     ) -> io::Result<Entry> {
-        unimplemented!()
-        /*
-        // Don't use is_safe_path_component(), allow "." and ".." for NFS export support
-        if name.to_bytes_with_nul().contains(&SLASH_ASCII) {
-            return Err(io::Error::from_raw_os_error(libc::EINVAL));
-        }
-
-        let dir = self.inode_map.get(parent)?;
-        let dir_file = dir.async_get_file(&self.mount_fds).await?;
-        let (file_or_handle, st, ids_altkey, handle_altkey) = self
-            .async_open_file_or_handle(ctx, dir_file.as_raw_fd(), name, |fd, flags, mode| {
-                Self::open_proc_file(&self.proc_self_fd, fd, flags, mode)
-            })
-            .await?;
-
-        let mut attr_flags: u32 = 0;
-        if let Some(dax_file_size) = self.cfg.dax_file_size {
-            // st.stat.st_size is i64
-            if self.perfile_dax.load(Ordering::Relaxed)
-                && st.stat.st_size >= 0x0
-                && st.stat.st_size as u64 >= dax_file_size
-            {
-                attr_flags |= fuse::FUSE_ATTR_DAX;
-            }
-        }
-
-        let mut found = None;
-        'search: loop {
-            match self.inode_map.get_alt(&ids_altkey, handle_altkey.as_ref()) {
-                // No existing entry found
-                None => break 'search,
-                Some(data) => {
-                    let curr = data.refcount.load(Ordering::Acquire);
-                    // forgot_one() has just destroyed the entry, retry...
-                    if curr == 0 {
-                        continue 'search;
-                    }
-
-                    // Saturating add to avoid integer overflow, it's not realistic to saturate u64.
-                    let new = curr.saturating_add(1);
-
-                    // Synchronizes with the forgot_one()
-                    if data
-                        .refcount
-                        .compare_exchange(curr, new, Ordering::AcqRel, Ordering::Acquire)
-                        .is_ok()
-                    {
-                        found = Some(data.inode);
-                        break;
-                    }
-                }
-            }
-        }
-
-        let inode = if let Some(v) = found {
-            v
-        } else {
-            // Write guard get_alt_locked() and insert_lock() to avoid race conditions.
-            let mut inodes = self.inode_map.get_map_mut();
-
-            // Lookup inode_map again after acquiring the inode_map lock, as there might be another
-            // racing thread already added an inode with the same altkey while we're not holding
-            // the lock. If so just use the newly added inode, otherwise the inode will be replaced
-            // and results in EBADF.
-            match InodeMap::get_alt_locked(inodes.deref(), &ids_altkey, handle_altkey.as_ref()) {
-                Some(data) => {
-                    trace!(
-                        "fuse: do_lookup sees existing inode {} ids_altkey {:?}",
-                        data.inode,
-                        ids_altkey
-                    );
-                    data.refcount.fetch_add(1, Ordering::Relaxed);
-                    data.inode
-                }
-                None => {
-                    let inode = self.next_inode.fetch_add(1, Ordering::Relaxed);
-                    if inode > VFS_MAX_INO {
-                        error!("fuse: max inode number reached: {}", VFS_MAX_INO);
-                        return Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            format!("max inode number reached: {}", VFS_MAX_INO),
-                        ));
-                    }
-                    trace!(
-                        "fuse: do_lookup adds new inode {} ids_altkey {:?} handle_altkey {:?}",
-                        inode,
-                        ids_altkey,
-                        handle_altkey
-                    );
-
-                    InodeMap::insert_locked(
-                        inodes.deref_mut(),
-                        inode,
-                        InodeData::new(inode, file_or_handle, 1, ids_altkey, st.get_stat().st_mode),
-                        ids_altkey,
-                        handle_altkey,
-                    );
-                    inode
-                }
-            }
-        };
-
-        Ok(Entry {
-            inode,
-            generation: 0,
-            attr: st.get_stat(),
-            attr_flags,
-            attr_timeout: self.cfg.attr_timeout,
-            entry_timeout: self.cfg.entry_timeout,
-        })
-        */
+        self.blocking(|| self.lookup(ctx, parent, name)).await
     }
 
     async fn async_getattr(
@@ -441,7 +184,7 @@ impl<S: BitmapSlice + Send + Sync> AsyncFileSystem for PassthroughFs<S> {
         inode: <Self as FileSystem>::Inode,
         handle: Option<<Self as FileSystem>::Handle>,
     ) -> io::Result<(libc::stat64, Duration)> {
-        self.async_do_getattr(ctx, inode, handle).await
+        self.blocking(|| self.getattr(ctx, inode, handle)).await
     }
 
     async fn async_setattr(
@@ -452,145 +195,8 @@ impl<S: BitmapSlice + Send + Sync> AsyncFileSystem for PassthroughFs<S> {
         handle: Option<<Self as FileSystem>::Handle>,
         valid: SetattrValid,
     ) -> io::Result<(libc::stat64, Duration)> {
-        unimplemented!()
-        /*
-        enum Data {
-            Handle(Arc<HandleData>, RawFd),
-            ProcPath(CString),
-        }
-
-        let inode_data = self.inode_map.get(inode)?;
-        let file = inode_data.async_get_file(&self.mount_fds).await?;
-        let data = if self.no_open.load(Ordering::Relaxed) {
-            let pathname = CString::new(format!("self/fd/{}", file.as_raw_fd()))
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-            Data::ProcPath(pathname)
-        } else {
-            // If we have a handle then use it otherwise get a new fd from the inode.
-            if let Some(handle) = handle {
-                let hd = self.handle_map.get(handle, inode)?;
-                let fd = hd.get_handle_raw_fd();
-                Data::Handle(hd, fd)
-            } else {
-                let pathname = CString::new(format!("self/fd/{}", file.as_raw_fd()))
-                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-                Data::ProcPath(pathname)
-            }
-        };
-
-        if valid.contains(SetattrValid::MODE) {
-            // Safe because this doesn't modify any memory and we check the return value.
-            let res = unsafe {
-                match data {
-                    Data::Handle(_, fd) => libc::fchmod(fd, attr.st_mode),
-                    Data::ProcPath(ref p) => {
-                        libc::fchmodat(self.proc_self_fd.as_raw_fd(), p.as_ptr(), attr.st_mode, 0)
-                    }
-                }
-            };
-            if res < 0 {
-                return Err(io::Error::last_os_error());
-            }
-        }
-
-        if valid.intersects(SetattrValid::UID | SetattrValid::GID) {
-            let uid = if valid.contains(SetattrValid::UID) {
-                attr.st_uid
-            } else {
-                // Cannot use -1 here because these are unsigned values.
-                ::std::u32::MAX
-            };
-            let gid = if valid.contains(SetattrValid::GID) {
-                attr.st_gid
-            } else {
-                // Cannot use -1 here because these are unsigned values.
-                ::std::u32::MAX
-            };
-
-            // Safe because this is a constant value and a valid C string.
-            let empty = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
-
-            // Safe because this doesn't modify any memory and we check the return value.
-            let res = unsafe {
-                libc::fchownat(
-                    file.as_raw_fd(),
-                    empty.as_ptr(),
-                    uid,
-                    gid,
-                    libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
-                )
-            };
-            if res < 0 {
-                return Err(io::Error::last_os_error());
-            }
-        }
-
-        if valid.contains(SetattrValid::SIZE) {
-            // Cap restored when _killpriv is dropped
-            let _killpriv = if self.killpriv_v2.load(Ordering::Relaxed)
-                && valid.contains(SetattrValid::KILL_SUIDGID)
-            {
-                self::drop_cap_fsetid()?
-            } else {
-                None
-            };
-
-            // Safe because this doesn't modify any memory and we check the return value.
-            let res = match data {
-                Data::Handle(_, fd) => unsafe { libc::ftruncate(fd, attr.st_size) },
-                Data::ProcPath(_) => {
-                    // There is no `ftruncateat` so we need to get a new fd and truncate it.
-                    let f = self
-                        .async_open_inode(ctx, inode, libc::O_NONBLOCK | libc::O_RDWR)
-                        .await?;
-                    unsafe { libc::ftruncate(f.as_raw_fd(), attr.st_size) }
-                }
-            };
-            if res < 0 {
-                return Err(io::Error::last_os_error());
-            }
-        }
-
-        if valid.intersects(SetattrValid::ATIME | SetattrValid::MTIME) {
-            let mut tvs = [
-                libc::timespec {
-                    tv_sec: 0,
-                    tv_nsec: libc::UTIME_OMIT,
-                },
-                libc::timespec {
-                    tv_sec: 0,
-                    tv_nsec: libc::UTIME_OMIT,
-                },
-            ];
-
-            if valid.contains(SetattrValid::ATIME_NOW) {
-                tvs[0].tv_nsec = libc::UTIME_NOW;
-            } else if valid.contains(SetattrValid::ATIME) {
-                tvs[0].tv_sec = attr.st_atime;
-                tvs[0].tv_nsec = attr.st_atime_nsec;
-            }
-
-            if valid.contains(SetattrValid::MTIME_NOW) {
-                tvs[1].tv_nsec = libc::UTIME_NOW;
-            } else if valid.contains(SetattrValid::MTIME) {
-                tvs[1].tv_sec = attr.st_mtime;
-                tvs[1].tv_nsec = attr.st_mtime_nsec;
-            }
-
-            // Safe because this doesn't modify any memory and we check the return value.
-            let res = match data {
-                Data::Handle(_, fd) => unsafe { libc::futimens(fd, tvs.as_ptr()) },
-                Data::ProcPath(ref p) => unsafe {
-                    libc::utimensat(self.proc_self_fd.as_raw_fd(), p.as_ptr(), tvs.as_ptr(), 0)
-                },
-            };
-            if res < 0 {
-                return Err(io::Error::last_os_error());
-            }
-        }
-
-        self.async_do_getattr(ctx, inode, handle).await
-         */
+        self.blocking(|| self.setattr(ctx, inode, attr, handle, valid))
+            .await
     }
 
     async fn async_open(
@@ -600,15 +206,8 @@ impl<S: BitmapSlice + Send + Sync> AsyncFileSystem for PassthroughFs<S> {
         flags: u32,
         fuse_flags: u32,
     ) -> io::Result<(Option<<Self as FileSystem>::Handle>, OpenOptions)> {
-        unimplemented!()
-        /*
-        if self.no_open.load(Ordering::Relaxed) {
-            info!("fuse: open is not supported.");
-            Err(io::Error::from_raw_os_error(libc::ENOSYS))
-        } else {
-            self.async_do_open(ctx, inode, flags, fuse_flags).await
-        }
-         */
+        self.blocking(|| self.open(ctx, inode, flags, fuse_flags))
+            .await
     }
 
     async fn async_create(
@@ -618,65 +217,7 @@ impl<S: BitmapSlice + Send + Sync> AsyncFileSystem for PassthroughFs<S> {
         name: &CStr,
         args: CreateIn,
     ) -> io::Result<(Entry, Option<<Self as FileSystem>::Handle>, OpenOptions)> {
-        unimplemented!()
-        /*
-        self.validate_path_component(name)?;
-
-        let dir = self.inode_map.get(parent)?;
-        let dir_file = dir.async_get_file(&self.mount_fds).await?;
-
-        let new_file = {
-            let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
-
-            Self::create_file_excl(
-                dir_file.as_raw_fd(),
-                name,
-                args.flags as i32,
-                args.mode & !(args.umask & 0o777),
-            )?
-        };
-
-        let entry = self.async_lookup(ctx, parent, name).await?;
-        let file = match new_file {
-            // File didn't exist, now created by create_file_excl()
-            Some(f) => f,
-            // File exists, and args.flags doesn't contain O_EXCL. Now let's open it with
-            // open_inode().
-            None => {
-                // Cap restored when _killpriv is dropped
-                let _killpriv = if self.killpriv_v2.load(Ordering::Relaxed)
-                    && (args.fuse_flags & FOPEN_IN_KILL_SUIDGID != 0)
-                {
-                    self::drop_cap_fsetid()?
-                } else {
-                    None
-                };
-
-                let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
-                self.async_open_inode(ctx, entry.inode, args.flags as i32)
-                    .await?
-            }
-        };
-
-        let ret_handle = if !self.no_open.load(Ordering::Relaxed) {
-            let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
-            let data = HandleData::new(entry.inode, file);
-
-            self.handle_map.insert(handle, data);
-            Some(handle)
-        } else {
-            None
-        };
-
-        let mut opts = OpenOptions::empty();
-        match self.cfg.cache_policy {
-            CachePolicy::Never => opts |= OpenOptions::DIRECT_IO,
-            CachePolicy::Always => opts |= OpenOptions::KEEP_CACHE,
-            _ => {}
-        };
-
-        Ok((entry, ret_handle, opts))
-         */
+        self.blocking(|| self.create(ctx, parent, name, args)).await
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -688,21 +229,29 @@ impl<S: BitmapSlice + Send + Sync> AsyncFileSystem for PassthroughFs<S> {
         w: &mut (dyn AsyncZeroCopyWriter + Send),
         size: u32,
         offset: u64,
-        _lock_owner: Option<u64>,
-        _flags: u32,
+        lock_owner: Option<u64>,
+        flags: u32,
     ) -> io::Result<usize> {
-        unimplemented!()
-        /*
-        let data = self
-            .async_get_data(ctx, handle, inode, libc::O_RDONLY)
-            .await?;
-        let drive = ctx
-            .get_drive::<D>()
-            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
-
-        w.async_write_from(drive, data.get_handle_raw_fd(), size as usize, offset)
+        self.validate_inode(ctx, inode)?;
+        let data = self.get_data(handle, inode, libc::O_RDONLY)?;
+        // Block devices are read through aligned buffers of the daemon.
+        let file = if self.is_passthrough_blockdev(self.inode_map.get(inode)?.mode) {
+            None
+        } else {
+            self.uring_file(&data, false).await
+        };
+        let file = match file {
+            Some(file) => file,
+            None => return self.read(ctx, inode, handle, w, size, offset, lock_owner, flags),
+        };
+
+        let count = LocalFuture(w.async_write_from(file, size as usize, offset))
             .await
-         */
+            .with_errno_context(|| format!("read inode {} offset {}", inode, offset))?;
+        if count < size as usize {
+            self.check_short_read(inode, data.get_handle_raw_fd(), offset + count as u64);
+        }
+        Ok(count)
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -714,37 +263,55 @@ impl<S: BitmapSlice + Send + Sync> AsyncFileSystem for PassthroughFs<S> {
         r: &mut (dyn AsyncZeroCopyReader + Send),
         size: u32,
         offset: u64,
-        _lock_owner: Option<u64>,
-        _delayed_write: bool,
-        _flags: u32,
+        lock_owner: Option<u64>,
+        delayed_write: bool,
+        flags: u32,
         fuse_flags: u32,
     ) -> io::Result<usize> {
-        unimplemented!()
-        /*
-        let data = self
-            .async_get_data(ctx, handle, inode, libc::O_RDWR)
-            .await?;
-
-        // Fallback to sync io if KILLPRIV_V2 is enabled to work around a limitation of io_uring.
-        if self.killpriv_v2.load(Ordering::Relaxed) && (fuse_flags & WRITE_KILL_PRIV != 0) {
-            // Manually implement File::try_clone() by borrowing fd of data.file instead of dup().
-            // It's safe because the `data` variable's lifetime spans the whole function,
-            // so data.file won't be closed.
-            let f = unsafe { File::from_raw_fd(data.get_handle_raw_fd()) };
-            let mut f = ManuallyDrop::new(f);
-            // Cap restored when _killpriv is dropped
-            let _killpriv = self::drop_cap_fsetid()?;
-
-            r.read_to(&mut *f, size as usize, offset)
+        self.validate_inode(ctx, inode)?;
+        let data = self.get_data(handle, inode, libc::O_RDWR)?;
+        // Writes killing privileges need CAP_FSETID dropped from the thread doing the write,
+        // which io_uring workers don't inherit, and splicing is synchronous. So are writes to be
+        // synced by their flags.
+        let killpriv = self.killpriv_v2.load(Ordering::Relaxed)
+            && (fuse_flags & WRITE_KILL_PRIV != 0)
+            && !delayed_write;
+        let sync = flags as i32 & libc::O_DSYNC != 0 && !delayed_write;
+        let file = if killpriv || sync || self.cfg.use_splice_write {
+            None
         } else {
-            let drive = ctx
-                .get_drive::<D>()
-                .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+            self.uring_file(&data, true).await
+        };
+        let file = match file {
+            Some(file) => file,
+            None => {
+                return self.write(
+                    ctx,
+                    inode,
+                    handle,
+                    r,
+                    size,
+                    offset,
+                    lock_owner,
+                    delayed_write,
+                    flags,
+                    fuse_flags,
+                )
+            }
+        };
 
-            r.async_read_to(drive, data.get_handle_raw_fd(), size as usize, offset)
-                .await
+        if !delayed_write {
+            let inode_data = self.inode_map.get(inode)?;
+            self.kill_capability(ctx, &inode_data, data.get_handle_raw_fd(), Some(&data))?;
+        }
+
+        let count = LocalFuture(r.async_read_to(file, size as usize, offset))
+            .await
+            .with_errno_context(|| format!("write inode {} offset {}", inode, offset))?;
+        if let Some(acct) = self.write_accounting.as_ref() {
+            acct.add_backend(count as u64);
         }
-         */
+        Ok(count)
     }
 
     async fn async_fsync(
@@ -754,17 +321,28 @@ impl<S: BitmapSlice + Send + Sync> AsyncFileSystem for PassthroughFs<S> {
         datasync: bool,
         handle: <Self as FileSystem>::Handle,
     ) -> io::Result<()> {
-        unimplemented!()
-        /*
-        let data = self
-            .async_get_data(ctx, handle, inode, libc::O_RDONLY)
-            .await?;
-        let drive = ctx
-            .get_drive::<D>()
-            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
-
-        AsyncUtil::fsync(drive, data.get_handle_raw_fd(), datasync).await
-         */
+        let data = self.get_data(handle, inode, libc::O_RDONLY)?;
+
+        // Flushing may block for long, so keep it off the thread polling requests. Moving `data`
+        // into the closure keeps the fd valid.
+        run_blocking(&*self.executor, move || {
+            let fd = data.get_handle_raw_fd();
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res = unsafe {
+                if datasync {
+                    libc::fdatasync(fd)
+                } else {
+                    libc::fsync(fd)
+                }
+            };
+            if res == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        })
+        .await?
+        .with_errno_context(|| format!("fsync inode {}", inode))
     }
 
     async fn async_fallocate(
@@ -776,18 +354,8 @@ impl<S: BitmapSlice + Send + Sync> AsyncFileSystem for PassthroughFs<S> {
         offset: u64,
         length: u64,
     ) -> io::Result<()> {
-        unimplemented!()
-        /*
-        // Let the Arc<HandleData> in scope, otherwise fd may get invalid.
-        let data = self
-            .async_get_data(ctx, handle, inode, libc::O_RDWR)
-            .await?;
-        let drive = ctx
-            .get_drive::<D>()
-            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
-
-        AsyncUtil::fallocate(drive, data.get_handle_raw_fd(), offset, length, mode).await
-         */
+        self.blocking(|| self.fallocate(ctx, inode, handle, mode, offset, length))
+            .await
     }
 
     async fn async_fsyncdir(
@@ -799,4 +367,100 @@ impl<S: BitmapSlice + Send + Sync> AsyncFileSystem for PassthroughFs<S> {
     ) -> io::Result<()> {
         self.async_fsync(ctx, inode, datasync, handle).await
     }
+
+    async fn async_syncfs(
+        &self,
+        ctx: &Context,
+        inode: <Self as FileSystem>::Inode,
+    ) -> io::Result<()> {
+        let file = self.open_inode(inode, libc::O_RDONLY)?;
+
+        // Flushing a whole file system blocks for long, keep it off the thread polling requests.
+        run_blocking(&*self.executor, move || sync_host_fs(&file))
+            .await?
+            .with_errno_context(|| format!("syncfs inode {}", inode))
+    }
+}
+
+#[cfg(all(test, feature = "fusedev"))]
+mod tests {
+    use super::super::tests::prepare_passthroughfs_with;
+    use super::*;
+    use crate::abi::fuse_abi::{InHeader, Opcode, OutHeader, ReadIn, WriteIn, WriteOut};
+    use crate::api::server::Server;
+    use crate::transport::{FuseBuf, FuseDevWriter, Reader};
+    use std::mem::size_of;
+    use vmm_sys_util::tempfile::TempFile;
+
+    // Handle the request `opcode` of handle 1 of inode `inode` on the io_uring runtime, return
+    // the reply.
+    fn request(server: &Server<PassthroughFs>, inode: u64, opcode: Opcode, arg: &[u8]) -> Vec<u8> {
+        let in_header = InHeader {
+            len: (size_of::<InHeader>() + arg.len()) as u32,
+            opcode: opcode as u32,
+            unique: 1,
+            nodeid: inode,
+            ..Default::default()
+        };
+        let mut r_buf = in_header.as_slice().to_vec();
+        r_buf.extend_from_slice(arg);
+        let out = TempFile::new().unwrap().into_file();
+        let mut w_buf = vec![0u8; 1 << 20];
+        tokio_uring::start(async {
+            let r = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut r_buf)).unwrap();
+            let w = FuseDevWriter::<()>::new(out.as_raw_fd(), &mut w_buf)
+                .unwrap()
+                .into();
+            unsafe { server.async_handle_message(r, w, None, None).await }.unwrap();
+        });
+        let mut reply = Vec::new();
+        io::Seek::seek(&mut &out, io::SeekFrom::Start(0)).unwrap();
+        io::Read::read_to_end(&mut &out, &mut reply).unwrap();
+        reply
+    }
+
+    #[test]
+    fn test_async_read_write_uring() {
+        let ctx = Context::default();
+        let (source, fs) = prepare_passthroughfs_with(|_| {});
+        let data: Vec<u8> = (0..300 << 10).map(|i| (i % 251) as u8).collect();
+        std::fs::write(source.as_path().join("file"), &data).unwrap();
+        let name = CString::new("file").unwrap();
+        let entry = fs.lookup(&ctx, fuse::ROOT_ID, &name).unwrap();
+        let (fh, _) = fs.open(&ctx, entry.inode, libc::O_RDWR as u32, 0).unwrap();
+        assert_eq!(fh, Some(1));
+        let server = Server::new(fs);
+
+        // A 128K read across pages, and a read short at the end of the file.
+        for (offset, size) in [(4000u64, 128u32 << 10), ((300 << 10) - 100, 4096)] {
+            let read_in = ReadIn {
+                fh: 1,
+                offset,
+                size,
+                ..Default::default()
+            };
+            let reply = request(&server, entry.inode, Opcode::Read, read_in.as_slice());
+            let out = OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap();
+            assert_eq!(out.error, 0);
+            let end = (offset as usize + size as usize).min(data.len());
+            assert_eq!(reply[size_of::<OutHeader>()..], data[offset as usize..end]);
+        }
+
+        let payload = vec![0x5au8; 64 << 10];
+        let write_in = WriteIn {
+            fh: 1,
+            offset: 8192,
+            size: payload.len() as u32,
+            ..Default::default()
+        };
+        let mut arg = write_in.as_slice().to_vec();
+        arg.extend_from_slice(&payload);
+        let reply = request(&server, entry.inode, Opcode::Write, &arg);
+        let out = WriteOut::from_slice(&reply[size_of::<OutHeader>()..]).unwrap();
+        assert_eq!(out.size as usize, payload.len());
+        let content = std::fs::read(source.as_path().join("file")).unwrap();
+        assert_eq!(content[8192..8192 + payload.len()], payload[..]);
+        assert_eq!(content[..8192], data[..8192]);
+        assert_eq!(content.len(), data.len());
+    }
 }
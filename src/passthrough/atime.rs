@@ -0,0 +1,436 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Filtering of access time updates, see `Config::atime_policy`.
+//!
+//! The kernel sends a setattr request updating the atime after guest reads, unless the guest
+//! mounts with `noatime`. Forwarding all of them makes read-heavy workloads dirty host inodes at
+//! a high rate. So atime-only updates are dropped or forwarded according to the policy, judged
+//! by the timestamps last reported to the guest, which saves stat(2)ing the backing file for each
+//! update.
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::Mutex;
+
+use super::{AtimePolicy, CStr};
+use crate::api::filesystem::SetattrValid;
+
+// Timestamps of an inode last reported to the guest, as (seconds, nanoseconds).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(super) struct InodeTimes {
+    atime: (i64, i64),
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+}
+
+impl InodeTimes {
+    pub(super) fn from_stat(st: &libc::stat64) -> Self {
+        InodeTimes {
+            atime: (st.st_atime, st.st_atime_nsec),
+            mtime: (st.st_mtime, st.st_mtime_nsec),
+            ctime: (st.st_ctime, st.st_ctime_nsec),
+        }
+    }
+}
+
+// Cache of `InodeTimes`, updated each time attributes of the inode are replied.
+#[derive(Debug, Default)]
+pub(super) struct TimesCache(Mutex<InodeTimes>);
+
+impl TimesCache {
+    pub(super) fn new(st: &libc::stat64) -> Self {
+        TimesCache(Mutex::new(InodeTimes::from_stat(st)))
+    }
+
+    pub(super) fn get(&self) -> InodeTimes {
+        *self.0.lock().unwrap()
+    }
+
+    pub(super) fn set(&self, st: &libc::stat64) {
+        *self.0.lock().unwrap() = InodeTimes::from_stat(st);
+    }
+}
+
+impl AtimePolicy {
+    // Seconds after which `relatime` updates the atime unconditionally.
+    const RELATIME_INTERVAL: i64 = 24 * 60 * 60;
+
+    // Filter the `valid` bits of a setattr request, with `times` the cached timestamps of the
+    // inode and `now` the current time in seconds since epoch. Only atime-only requests are
+    // affected.
+    pub(super) fn filter_setattr(
+        &self,
+        valid: SetattrValid,
+        times: &InodeTimes,
+        now: i64,
+    ) -> SetattrValid {
+        let atime = SetattrValid::ATIME | SetattrValid::ATIME_NOW;
+        if *self == AtimePolicy::Passthrough
+            || !valid.intersects(atime)
+            || !(valid - atime).is_empty()
+        {
+            return valid;
+        }
+
+        let forward = match self {
+            AtimePolicy::Relatime => {
+                times.atime <= times.mtime
+                    || times.atime <= times.ctime
+                    || now.saturating_sub(times.atime.0) >= Self::RELATIME_INTERVAL
+            }
+            _ => false,
+        };
+
+        if forward {
+            valid
+        } else {
+            valid - atime
+        }
+    }
+}
+
+// Syscalls setting the atime and mtime of backing files, abstracted for testing.
+pub(super) trait UtimensSyscalls: Send + Sync {
+    fn futimens(&self, fd: RawFd, times: &[libc::timespec; 2]) -> io::Result<()>;
+    fn utimensat(&self, dir: RawFd, path: &CStr, times: &[libc::timespec; 2]) -> io::Result<()>;
+}
+
+pub(super) struct LibcUtimensSyscalls;
+
+impl UtimensSyscalls for LibcUtimensSyscalls {
+    fn futimens(&self, fd: RawFd, times: &[libc::timespec; 2]) -> io::Result<()> {
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe { libc::futimens(fd, times.as_ptr()) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn utimensat(&self, dir: RawFd, path: &CStr, times: &[libc::timespec; 2]) -> io::Result<()> {
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe { libc::utimensat(dir, path.as_ptr(), times.as_ptr(), 0) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abi::fuse_abi::ROOT_ID;
+    use crate::api::clock::ManualClock;
+    use crate::api::filesystem::{Context, FileSystem};
+    use crate::passthrough::proc_fd::{LibcProcSyscalls, ProcSyscalls};
+    use crate::passthrough::tests::prepare_passthroughfs_with;
+    use crate::passthrough::PassthroughFs;
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::path::Path;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+    use vmm_sys_util::tempdir::TempDir;
+
+    // The atime and mtime set, as (seconds, nanoseconds).
+    type Times = [(i64, i64); 2];
+
+    // Record the atime and mtime set, without touching the backing files.
+    #[derive(Clone, Default)]
+    struct MockUtimens(Arc<Mutex<Vec<Times>>>);
+
+    impl MockUtimens {
+        fn record(&self, times: &[libc::timespec; 2]) -> io::Result<()> {
+            let times = times.map(|t| (t.tv_sec, t.tv_nsec));
+            self.0.lock().unwrap().push(times);
+            Ok(())
+        }
+
+        fn take(&self) -> Vec<Times> {
+            std::mem::take(&mut *self.0.lock().unwrap())
+        }
+    }
+
+    impl UtimensSyscalls for MockUtimens {
+        fn futimens(&self, _fd: RawFd, times: &[libc::timespec; 2]) -> io::Result<()> {
+            self.record(times)
+        }
+
+        fn utimensat(
+            &self,
+            _dir: RawFd,
+            _path: &CStr,
+            times: &[libc::timespec; 2],
+        ) -> io::Result<()> {
+            self.record(times)
+        }
+    }
+
+    // Fail reopening with `O_NOATIME` like for files not owned by the caller, and record the
+    // flags of all reopens.
+    #[derive(Clone, Default)]
+    struct NoatimeDenied(Arc<Mutex<Vec<i32>>>);
+
+    impl ProcSyscalls for NoatimeDenied {
+        fn open_proc_self_fd(&self) -> io::Result<File> {
+            LibcProcSyscalls.open_proc_self_fd()
+        }
+
+        fn reopen(&self, proc: RawFd, fd: RawFd, flags: i32) -> io::Result<File> {
+            self.0.lock().unwrap().push(flags);
+            if flags & libc::O_NOATIME != 0 {
+                return Err(io::Error::from_raw_os_error(libc::EPERM));
+            }
+            LibcProcSyscalls.reopen(proc, fd, flags)
+        }
+    }
+
+    // Seconds since epoch of the clock used by the tests, far in the future of files created by
+    // the tests, so their ctime, bumped when timestamps are set, is never newer than their atime.
+    const NOW: i64 = 4_000_000_000;
+
+    // Set the atime and mtime of `path` in seconds.
+    fn set_times(path: &Path, atime: i64, mtime: i64) {
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        let times = [
+            libc::timespec {
+                tv_sec: atime,
+                tv_nsec: 0,
+            },
+            libc::timespec {
+                tv_sec: mtime,
+                tv_nsec: 0,
+            },
+        ];
+        LibcUtimensSyscalls
+            .utimensat(libc::AT_FDCWD, &path, &times)
+            .unwrap();
+    }
+
+    fn atime_fs(policy: AtimePolicy) -> (TempDir, PassthroughFs, MockUtimens) {
+        let (source, fs) = prepare_passthroughfs_with(|cfg| cfg.atime_policy = policy);
+        let clock = Arc::new(ManualClock::new(
+            UNIX_EPOCH + Duration::from_secs(NOW as u64),
+        ));
+        let mut fs = fs.with_clock(clock);
+        let utimens = MockUtimens::default();
+        fs.utimens_sys = Box::new(utimens.clone());
+        (source, fs, utimens)
+    }
+
+    // Look up `name` in the root directory and set its atime with an atime-only setattr, then
+    // with an atime and mtime setattr. Return the timestamps forwarded to the backing file.
+    fn setattr_atime(fs: &PassthroughFs, utimens: &MockUtimens, name: &str) -> Vec<Times> {
+        let ctx = Context::default();
+        let entry = fs
+            .lookup(&ctx, ROOT_ID, &CString::new(name).unwrap())
+            .unwrap();
+        let mut attr = entry.attr;
+        attr.st_atime = NOW;
+        attr.st_atime_nsec = 0;
+        attr.st_mtime = NOW;
+        attr.st_mtime_nsec = 0;
+
+        let (st, _) = fs
+            .setattr(&ctx, entry.inode, attr, None, SetattrValid::ATIME)
+            .unwrap();
+        assert_eq!(st.st_ino, entry.attr.st_ino);
+        fs.setattr(
+            &ctx,
+            entry.inode,
+            attr,
+            None,
+            SetattrValid::ATIME | SetattrValid::MTIME,
+        )
+        .unwrap();
+        utimens.take()
+    }
+
+    const OMIT: (i64, i64) = (0, libc::UTIME_OMIT);
+
+    #[test]
+    fn test_filter_setattr() {
+        let day = 24 * 60 * 60;
+        let now = 10 * day;
+        let mut times = InodeTimes {
+            mtime: (now - 200, 0),
+            ctime: (now - 200, 0),
+            // atime newer than mtime/ctime and less than 24 hours old.
+            atime: (now - 100, 0),
+        };
+
+        let atime = SetattrValid::ATIME;
+        let atime_now = SetattrValid::ATIME | SetattrValid::ATIME_NOW;
+        let mixed = SetattrValid::ATIME | SetattrValid::MTIME;
+
+        let policy = AtimePolicy::Passthrough;
+        assert_eq!(policy.filter_setattr(atime, &times, now), atime);
+        assert_eq!(policy.filter_setattr(atime_now, &times, now), atime_now);
+        assert_eq!(policy.filter_setattr(mixed, &times, now), mixed);
+
+        let policy = AtimePolicy::NoAtime;
+        assert!(policy.filter_setattr(atime, &times, now).is_empty());
+        assert!(policy.filter_setattr(atime_now, &times, now).is_empty());
+        assert_eq!(policy.filter_setattr(mixed, &times, now), mixed);
+        assert_eq!(
+            policy.filter_setattr(SetattrValid::MODE, &times, now),
+            SetattrValid::MODE
+        );
+
+        let policy = AtimePolicy::Relatime;
+        assert!(policy.filter_setattr(atime, &times, now).is_empty());
+        assert!(policy.filter_setattr(atime_now, &times, now).is_empty());
+        assert_eq!(policy.filter_setattr(mixed, &times, now), mixed);
+        // atime older than mtime.
+        let mut times2 = times;
+        times2.mtime = (now - 50, 0);
+        assert_eq!(policy.filter_setattr(atime, &times2, now), atime);
+        // atime older than ctime, by nanoseconds.
+        let mut times2 = times;
+        times2.ctime = (now - 100, 1);
+        assert_eq!(policy.filter_setattr(atime, &times2, now), atime);
+        // atime more than 24 hours old.
+        times.atime = (now - day, 0);
+        times.mtime = (now - 2 * day, 0);
+        times.ctime = (now - 2 * day, 0);
+        assert_eq!(policy.filter_setattr(atime_now, &times, now), atime_now);
+
+        assert_eq!(
+            AtimePolicy::from_str("relatime").unwrap(),
+            AtimePolicy::Relatime
+        );
+        assert_eq!(
+            AtimePolicy::from_str("noatime").unwrap(),
+            AtimePolicy::NoAtime
+        );
+        assert!(AtimePolicy::from_str("strictatime").is_err());
+    }
+
+    #[test]
+    fn test_setattr_passthrough() {
+        let (source, fs, utimens) = atime_fs(AtimePolicy::Passthrough);
+        std::fs::write(source.as_path().join("f"), b"f").unwrap();
+        set_times(&source.as_path().join("f"), NOW - 10, NOW - 100);
+
+        assert_eq!(
+            setattr_atime(&fs, &utimens, "f"),
+            vec![[(NOW, 0), OMIT], [(NOW, 0), (NOW, 0)]]
+        );
+    }
+
+    #[test]
+    fn test_setattr_noatime() {
+        let (source, fs, utimens) = atime_fs(AtimePolicy::NoAtime);
+        std::fs::write(source.as_path().join("f"), b"f").unwrap();
+        // Even an atime older than the mtime isn't updated.
+        set_times(&source.as_path().join("f"), NOW - 100, NOW - 10);
+
+        assert_eq!(
+            setattr_atime(&fs, &utimens, "f"),
+            vec![[(NOW, 0), (NOW, 0)]]
+        );
+    }
+
+    #[test]
+    fn test_setattr_relatime() {
+        let (source, fs, utimens) = atime_fs(AtimePolicy::Relatime);
+        let path = source.as_path().join("f");
+        std::fs::write(&path, b"f").unwrap();
+
+        // Recent atime newer than mtime and ctime.
+        set_times(&path, NOW - 10, NOW - 100);
+        assert_eq!(
+            setattr_atime(&fs, &utimens, "f"),
+            vec![[(NOW, 0), (NOW, 0)]]
+        );
+
+        // atime older than mtime.
+        set_times(&path, NOW - 100, NOW - 10);
+        assert_eq!(
+            setattr_atime(&fs, &utimens, "f"),
+            vec![[(NOW, 0), OMIT], [(NOW, 0), (NOW, 0)]]
+        );
+
+        // atime more than 24 hours old.
+        let day = 24 * 60 * 60;
+        set_times(&path, NOW - day, NOW - 2 * day);
+        assert_eq!(
+            setattr_atime(&fs, &utimens, "f"),
+            vec![[(NOW, 0), OMIT], [(NOW, 0), (NOW, 0)]]
+        );
+    }
+
+    #[test]
+    fn test_setattr_relatime_cached_times() {
+        let (source, fs, utimens) = atime_fs(AtimePolicy::Relatime);
+        let path = source.as_path().join("f");
+        std::fs::write(&path, b"f").unwrap();
+        set_times(&path, NOW - 10, NOW - 100);
+
+        let ctx = Context::default();
+        let name = CString::new("f").unwrap();
+        let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+        // Changes on the host are only seen once reported to the guest.
+        set_times(&path, NOW - 100, NOW - 10);
+        fs.setattr(&ctx, entry.inode, entry.attr, None, SetattrValid::ATIME)
+            .unwrap();
+        assert!(utimens.take().is_empty());
+
+        fs.getattr(&ctx, entry.inode, None).unwrap();
+        fs.setattr(&ctx, entry.inode, entry.attr, None, SetattrValid::ATIME)
+            .unwrap();
+        assert_eq!(utimens.take().len(), 1);
+    }
+
+    #[test]
+    fn test_open_noatime() {
+        let (source, fs) =
+            prepare_passthroughfs_with(|cfg| cfg.atime_policy = AtimePolicy::NoAtime);
+        std::fs::write(source.as_path().join("f"), b"f").unwrap();
+        let ctx = Context::default();
+        let name = CString::new("f").unwrap();
+        let inode = fs.lookup(&ctx, ROOT_ID, &name).unwrap().inode;
+
+        // Files owned by us are opened with O_NOATIME.
+        let (handle, _) = fs.open(&ctx, inode, libc::O_RDONLY as u32, 0).unwrap();
+        let hd = fs.handle_map.get(handle.unwrap(), inode).unwrap();
+        let fd = hd.get_handle_raw_fd();
+        // Safe because this doesn't modify any memory and we check the return value.
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        assert!(flags >= 0);
+        assert_ne!(flags & libc::O_NOATIME, 0);
+        drop(hd);
+        fs.release(&ctx, inode, 0, handle.unwrap(), false, false, None)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_open_noatime_denied() {
+        let (source, mut fs) =
+            prepare_passthroughfs_with(|cfg| cfg.atime_policy = AtimePolicy::NoAtime);
+        std::fs::write(source.as_path().join("f"), b"f").unwrap();
+        let ctx = Context::default();
+        let name = CString::new("f").unwrap();
+        let inode = fs.lookup(&ctx, ROOT_ID, &name).unwrap().inode;
+
+        let proc = NoatimeDenied::default();
+        fs.proc_sys = Box::new(proc.clone());
+        // EPERM from O_NOATIME falls back to a normal open.
+        let (handle, _) = fs.open(&ctx, inode, libc::O_RDONLY as u32, 0).unwrap();
+        let reopens = proc.0.lock().unwrap().clone();
+        assert_eq!(reopens.len(), 2);
+        assert_ne!(reopens[0] & libc::O_NOATIME, 0);
+        assert_eq!(reopens[1] & libc::O_NOATIME, 0);
+
+        let hd = fs.handle_map.get(handle.unwrap(), inode).unwrap();
+        // Safe because this doesn't modify any memory and we check the return value.
+        let flags = unsafe { libc::fcntl(hd.get_handle_raw_fd(), libc::F_GETFL) };
+        assert_eq!(flags & libc::O_NOATIME, 0);
+        drop(hd);
+        fs.release(&ctx, inode, 0, handle.unwrap(), false, false, None)
+            .unwrap();
+    }
+}
@@ -0,0 +1,317 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Passthrough of the content of block device nodes.
+//!
+//! Device nodes are never opened by default, the guest would get access to whatever device the
+//! node refers to. With `Config::allow_blockdev_read`, block device nodes may be opened read-only
+//! so imaging tools in guests can read raw devices. Their `st_size` is 0, so getattr and lookup
+//! report the size of the device got by `BLKGETSIZE64` instead, otherwise guests would see empty
+//! files.
+//!
+//! Reads are done by `pread()` into a buffer aligned to the logical block size of the device,
+//! covering the requested range rounded to whole blocks, because guests opening devices with
+//! `O_DIRECT` get fds only accepting aligned IO. Opening for writing fails with `EROFS` unless
+//! `Config::allow_blockdev_write` is set too.
+
+use std::alloc::{self, Layout};
+use std::slice;
+
+use super::*;
+use crate::api::filesystem::ZeroCopyWriter;
+
+// _IOR(0x12, 114, size_t)
+const BLKGETSIZE64: u64 = 0x8008_1272;
+// _IO(0x12, 104)
+const BLKSSZGET: u64 = 0x1268;
+
+// Syscalls reading block devices, abstracted for testing.
+pub(super) trait BlockdevSyscalls: Send + Sync {
+    // Get the size of the device in bytes.
+    fn size(&self, fd: RawFd) -> io::Result<u64>;
+
+    // Get the logical block size of the device.
+    fn block_size(&self, fd: RawFd) -> io::Result<u32>;
+
+    // Read into `buf` at `offset` of the device.
+    fn pread(&self, fd: RawFd, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+}
+
+pub(super) struct LibcBlockdevSyscalls;
+
+impl BlockdevSyscalls for LibcBlockdevSyscalls {
+    fn size(&self, fd: RawFd) -> io::Result<u64> {
+        let mut size = 0u64;
+        // Safe because the kernel only writes a u64 into `size` and we check the return value.
+        let res = unsafe { libc::ioctl(fd, BLKGETSIZE64 as _, &mut size) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(size)
+    }
+
+    fn block_size(&self, fd: RawFd) -> io::Result<u32> {
+        let mut size: libc::c_int = 0;
+        // Safe because the kernel only writes an int into `size` and we check the return value.
+        let res = unsafe { libc::ioctl(fd, BLKSSZGET as _, &mut size) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(size as u32)
+    }
+
+    fn pread(&self, fd: RawFd, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        // Safe because the kernel writes at most `buf.len()` bytes into `buf` and we check the
+        // return value.
+        let res = unsafe {
+            libc::pread64(
+                fd,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                offset as libc::off64_t,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(res as usize)
+    }
+}
+
+// Zeroed buffer aligned to the logical block size of a device.
+struct AlignedBuf {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuf {
+    fn new(len: usize, align: usize) -> io::Result<Self> {
+        let layout = Layout::from_size_align(len, align)
+            .map_err(|_| fuse_errno(libc::EINVAL, "invalid block device buffer layout"))?;
+        // Safe because `len` is never 0, reads of block devices cover at least one block.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            return Err(fuse_errno(
+                libc::ENOMEM,
+                "no memory for block device buffer",
+            ));
+        }
+        Ok(AlignedBuf { ptr, layout })
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // Safe because `ptr` points to `layout.size()` initialized bytes owned by the buffer.
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        // Safe because `ptr` was allocated with `layout`.
+        unsafe { alloc::dealloc(self.ptr, self.layout) };
+    }
+}
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    // Whether an inode of type `mode` is a block device whose content is passed through.
+    pub(super) fn is_passthrough_blockdev(&self, mode: u32) -> bool {
+        self.cfg.allow_blockdev_read && mode & libc::S_IFMT == libc::S_IFBLK
+    }
+
+    // Check that a block device may be opened with `flags`.
+    pub(super) fn check_blockdev_open(&self, flags: i32) -> io::Result<()> {
+        let writing = flags & libc::O_PATH == 0
+            && (flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0);
+        if writing && !self.cfg.allow_blockdev_write {
+            return Err(fuse_errno(libc::EROFS, "block devices are read-only"));
+        }
+        Ok(())
+    }
+
+    // Report the size of the device in `st` if `inode` is a passed through block device.
+    pub(super) fn set_blockdev_size(&self, inode: Inode, st: &mut libc::stat64) {
+        if !self.is_passthrough_blockdev(st.st_mode) {
+            return;
+        }
+        match self
+            .open_inode(inode, libc::O_RDONLY | libc::O_NONBLOCK)
+            .and_then(|f| self.blockdev_sys.size(f.as_raw_fd()))
+        {
+            Ok(size) => {
+                st.st_size = size as libc::off64_t;
+                st.st_blocks = size.div_ceil(512) as libc::blkcnt64_t;
+            }
+            Err(e) => warn!(
+                "passthrough: failed to get size of block device inode {}, {}",
+                inode, e
+            ),
+        }
+    }
+
+    // Read `size` bytes at `offset` of the block device opened as `fd` into `w`.
+    pub(super) fn read_blockdev(
+        &self,
+        fd: RawFd,
+        w: &mut dyn ZeroCopyWriter,
+        size: u32,
+        offset: u64,
+    ) -> io::Result<usize> {
+        let dev_size = self.blockdev_sys.size(fd)?;
+        let end = cmp::min(offset.saturating_add(size as u64), dev_size);
+        if offset >= end {
+            return Ok(0);
+        }
+
+        let block = self.blockdev_sys.block_size(fd)? as u64;
+        if !block.is_power_of_two() {
+            return Err(fuse_errno(
+                libc::EIO,
+                format!("invalid block device block size {}", block),
+            ));
+        }
+        let start = offset & !(block - 1);
+        let aligned_end = end.saturating_add(block - 1) & !(block - 1);
+        let mut buf = AlignedBuf::new((aligned_end - start) as usize, block as usize)?;
+        let buf = buf.as_mut_slice();
+
+        // The last block may go past the end of the device, stop once the requested range is read.
+        let needed = (end - start) as usize;
+        let mut filled = 0;
+        while filled < needed {
+            let count = self
+                .blockdev_sys
+                .pread(fd, &mut buf[filled..], start + filled as u64)?;
+            if count == 0 {
+                break;
+            }
+            filled += count;
+        }
+
+        let begin = (offset - start) as usize;
+        let stop = cmp::min(needed, filled);
+        if begin >= stop {
+            return Ok(0);
+        }
+        w.write_all(&buf[begin..stop])?;
+        Ok(stop - begin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{passthroughfs_in, prepare_passthroughfs_with};
+    use super::*;
+    use crate::api::errno::errno_of;
+    use crate::api::filesystem::{Context, FileSystem, VecWriter};
+
+    // A device holding `data` with `block` bytes logical blocks, which only accepts aligned reads.
+    struct MockBlockdev {
+        data: Vec<u8>,
+        block: u32,
+    }
+
+    impl BlockdevSyscalls for MockBlockdev {
+        fn size(&self, _: RawFd) -> io::Result<u64> {
+            Ok(self.data.len() as u64)
+        }
+
+        fn block_size(&self, _: RawFd) -> io::Result<u32> {
+            Ok(self.block)
+        }
+
+        fn pread(&self, _: RawFd, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            let block = self.block as usize;
+            if (offset as usize | buf.len() | buf.as_ptr() as usize) & (block - 1) != 0 {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            let data = self.data.get(offset as usize..).unwrap_or_default();
+            let count = cmp::min(data.len(), buf.len());
+            buf[..count].copy_from_slice(&data[..count]);
+            Ok(count)
+        }
+    }
+
+    #[test]
+    fn test_blockdev_read() {
+        let ctx = Context::default();
+        let name = CString::new("dev").unwrap();
+        let (source, mut fs) = prepare_passthroughfs_with(|cfg| cfg.allow_blockdev_read = true);
+        let path = CString::new(source.as_path().join("dev").to_str().unwrap()).unwrap();
+        // Make a node of the loop device 0, mknod needs CAP_MKNOD.
+        // Safe because `path` is a valid C string and we check the return value.
+        if unsafe { libc::mknod(path.as_ptr(), libc::S_IFBLK | 0o600, libc::makedev(7, 0)) } < 0 {
+            return;
+        }
+        let data: Vec<u8> = (0..3 * 4096 + 1024).map(|i| (i % 251) as u8).collect();
+        fs.blockdev_sys = Box::new(MockBlockdev {
+            data: data.clone(),
+            block: 4096,
+        });
+
+        let entry = fs.lookup(&ctx, fuse::ROOT_ID, &name).unwrap();
+        assert_eq!(entry.attr.st_size, data.len() as i64);
+        let (st, _) = fs.getattr(&ctx, entry.inode, None).unwrap();
+        assert_eq!(st.st_size, data.len() as i64);
+
+        let (fh, _) = fs
+            .open(&ctx, entry.inode, libc::O_RDONLY as u32, 0)
+            .unwrap();
+        let fh = fh.unwrap();
+        let read = |size: u32, offset: u64| {
+            let mut buf = Vec::new();
+            fs.read(
+                &ctx,
+                entry.inode,
+                fh,
+                &mut VecWriter::new(&mut buf),
+                size,
+                offset,
+                None,
+                0,
+            )
+            .unwrap();
+            buf
+        };
+        // Unaligned reads, across blocks and across the end of the device.
+        assert_eq!(read(100, 10), data[10..110]);
+        assert_eq!(read(8192, 4000), data[4000..12192]);
+        assert_eq!(read(4096, 12000), data[12000..]);
+        assert!(read(4096, data.len() as u64).is_empty());
+
+        // Writes are refused.
+        let err = fs
+            .open(&ctx, entry.inode, libc::O_RDWR as u32, 0)
+            .unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::EROFS));
+        fs.release(&ctx, entry.inode, 0, fh, false, false, None)
+            .unwrap();
+
+        // Device nodes can't be opened by default.
+        let fs = passthroughfs_in(source.as_path(), |_| {});
+        let entry = fs.lookup(&ctx, fuse::ROOT_ID, &name).unwrap();
+        assert_eq!(entry.attr.st_size, 0);
+        assert!(fs
+            .open(&ctx, entry.inode, libc::O_RDONLY as u32, 0)
+            .is_err());
+    }
+
+    #[test]
+    fn test_blockdev_open_flags() {
+        let (_source, fs) = prepare_passthroughfs_with(|cfg| cfg.allow_blockdev_read = true);
+        fs.check_blockdev_open(libc::O_RDONLY).unwrap();
+        fs.check_blockdev_open(libc::O_PATH | libc::O_WRONLY)
+            .unwrap();
+        for flags in [libc::O_WRONLY, libc::O_RDWR, libc::O_RDONLY | libc::O_TRUNC] {
+            let err = fs.check_blockdev_open(flags).unwrap_err();
+            assert_eq!(errno_of(&err), Some(libc::EROFS));
+        }
+
+        let (_source, fs) = prepare_passthroughfs_with(|cfg| {
+            cfg.allow_blockdev_read = true;
+            cfg.allow_blockdev_write = true;
+        });
+        fs.check_blockdev_open(libc::O_RDWR).unwrap();
+        assert!(fs.is_passthrough_blockdev(libc::S_IFBLK | 0o600));
+        assert!(!fs.is_passthrough_blockdev(libc::S_IFCHR | 0o600));
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Loading and saving of [Config] with serde, enabled by the `config-serde` feature.
+//!
+//! Fields missing from a loaded config take their default value, so configs written by older
+//! versions keep loading as options are added. Unknown fields are ignored on purpose, rather
+//! than denied, so configs written by newer versions load too, without the options this version
+//! doesn't know. Renamed fields keep their old names as aliases: `root_dir` may be given as
+//! `shared_dir` and `cache_policy` as `cache`, like in virtiofsd.
+//!
+//! Loaded configs are checked by [Config::validate], so invalid ones fail to load rather than
+//! when creating the file system. `Config::open_policy` is a callback and is never serialized.
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::Config;
+
+impl Serialize for Config {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Config::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Config {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let cfg = Config::deserialize(deserializer)?;
+        cfg.validate().map_err(D::Error::custom)?;
+        Ok(cfg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::server::XattrLimits;
+    use crate::passthrough::{AtimePolicy, CachePolicy, IdRange, RetryPolicy, UidGidMap, XattrMap};
+    use std::time::Duration;
+
+    #[test]
+    fn test_config_serde_round_trip() {
+        let cfg = Config {
+            entry_timeout: Duration::from_millis(1500),
+            cache_policy: CachePolicy::Always,
+            writeback: true,
+            root_dir: String::from("/srv/shared"),
+            xattr: true,
+            xattr_map: Some(":map:trusted.:user.virtiofs.:".parse::<XattrMap>().unwrap()),
+            xattr_limits: XattrLimits {
+                size_max: 4096,
+                ..Default::default()
+            },
+            atime_policy: AtimePolicy::NoAtime,
+            fscreate_labels: vec![(String::from("*.log"), String::from("log_t"))],
+            uid_gid_map: Some(
+                UidGidMap::new(
+                    vec![IdRange {
+                        guest: 0,
+                        host: 100000,
+                        count: 65536,
+                    }],
+                    Vec::new(),
+                )
+                .unwrap()
+                .with_overflow_ids(1, 2),
+            ),
+            retry_policy: Some(RetryPolicy::default()),
+            blksize: Some(8192),
+            time_gran: Some(1000),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&cfg).unwrap();
+        assert_eq!(serde_json::from_str::<Config>(&json).unwrap(), cfg);
+
+        // Missing fields take their default value.
+        assert_eq!(
+            serde_json::from_str::<Config>("{}").unwrap(),
+            Config::default()
+        );
+    }
+
+    #[test]
+    fn test_config_serde_compat() {
+        // Aliases of renamed fields, and unknown fields of newer versions.
+        let cfg: Config = serde_json::from_str(
+            r#"{
+                "shared_dir": "/srv/shared",
+                "cache": "none",
+                "atime_policy": "relatime",
+                "retry_policy": { "max_attempts": 5 },
+                "option_of_a_future_version": { "enabled": true }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(cfg.root_dir, "/srv/shared");
+        assert_eq!(cfg.cache_policy, CachePolicy::Never);
+        assert_eq!(cfg.atime_policy, AtimePolicy::Relatime);
+        let retry = cfg.retry_policy.unwrap();
+        assert_eq!(retry.max_attempts, 5);
+        assert_eq!(retry.backoff, RetryPolicy::default().backoff);
+    }
+
+    #[test]
+    fn test_config_serde_validate() {
+        for json in [
+            r#"{ "blksize": 1000 }"#,
+            r#"{ "time_gran": 3 }"#,
+            r#"{ "retry_policy": { "max_attempts": 0 } }"#,
+            r#"{ "cache_policy": "sometimes" }"#,
+            r#"{ "uid_gid_map": { "uids": [
+                { "guest": 0, "host": 1000, "count": 10 },
+                { "guest": 5, "host": 2000, "count": 10 }
+            ] } }"#,
+        ] {
+            assert!(serde_json::from_str::<Config>(json).is_err(), "{}", json);
+        }
+    }
+}
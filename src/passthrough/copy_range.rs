@@ -0,0 +1,544 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Copy of file ranges, with a fallback for copies the host kernel can't offload.
+//!
+//! copy_file_range(2) fails with `EXDEV` when the files live on different mounts, and with
+//! `EOPNOTSUPP` when the backing file system can't copy between the files. Passed through, the
+//! guest kernel falls back to copying through its page cache, which is much slower over
+//! virtio-fs. With `Config::enable_xdev_copy_fallback`, such copies are done by reading and
+//! writing the files in the daemon instead, skipping holes of the source found by `SEEK_DATA`
+//! and `SEEK_HOLE`, so sparse files stay sparse.
+//!
+//! Such copies are done in chunks of `YieldChunks::copy_bytes`, consulting the [YieldPoints] of
+//! the file system between chunks. A copy aborted there fails with `EINTR` whose context reports
+//! the number of bytes copied so far: the destination then holds the start of the requested
+//! range up to that number of bytes, and is left as is past it.
+
+use std::cmp;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+
+use crate::api::accounting::WriteAccounting;
+use crate::api::errno::ErrnoContext;
+use crate::api::shedder::YieldPoints;
+
+// Size of the buffer used to copy data.
+const COPY_CHUNK: usize = 128 << 10;
+
+// Syscalls used to copy file ranges, abstracted for testing.
+pub(super) trait CopySyscalls: Send + Sync {
+    fn copy_file_range(
+        &self,
+        fd_in: RawFd,
+        offset_in: &mut i64,
+        fd_out: RawFd,
+        offset_out: &mut i64,
+        len: usize,
+        flags: u32,
+    ) -> io::Result<usize>;
+}
+
+pub(super) struct LibcCopySyscalls;
+
+impl CopySyscalls for LibcCopySyscalls {
+    fn copy_file_range(
+        &self,
+        fd_in: RawFd,
+        offset_in: &mut i64,
+        fd_out: RawFd,
+        offset_out: &mut i64,
+        len: usize,
+        flags: u32,
+    ) -> io::Result<usize> {
+        // Safe because this only modifies the offsets and we check the return value.
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_copy_file_range,
+                fd_in,
+                offset_in as *mut libc::off64_t,
+                fd_out,
+                offset_out as *mut libc::off64_t,
+                len,
+                flags as libc::c_uint,
+            )
+        };
+        if res < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(res as usize)
+        }
+    }
+}
+
+/// Copy file ranges, falling back to reading and writing the files if enabled.
+pub(super) struct CopyHelper {
+    sys: Box<dyn CopySyscalls>,
+    fallback: bool,
+    acct: Option<Arc<WriteAccounting>>,
+    points: YieldPoints,
+}
+
+impl CopyHelper {
+    pub(super) fn new(fallback: bool) -> Self {
+        Self::with_syscalls(Box::new(LibcCopySyscalls), fallback)
+    }
+
+    pub(super) fn with_syscalls(sys: Box<dyn CopySyscalls>, fallback: bool) -> Self {
+        CopyHelper {
+            sys,
+            fallback,
+            acct: None,
+            points: YieldPoints::default(),
+        }
+    }
+
+    // Account bytes copied in `acct`.
+    pub(super) fn with_accounting(mut self, acct: Arc<WriteAccounting>) -> Self {
+        self.acct = Some(acct);
+        self
+    }
+
+    // Consult `points` between chunks of copies done by the fallback.
+    pub(super) fn with_yield_points(mut self, points: YieldPoints) -> Self {
+        self.points = points;
+        self
+    }
+
+    /// Copy up to `len` bytes at `offset_in` of `fd_in` to `offset_out` of `fd_out`.
+    pub(super) fn copy(
+        &self,
+        fd_in: RawFd,
+        offset_in: u64,
+        fd_out: RawFd,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> io::Result<usize> {
+        // The number of bytes copied is replied as u32.
+        let len = cmp::min(len, u32::MAX as u64);
+        let mut off_in = offset_in as i64;
+        let mut off_out = offset_out as i64;
+        let (res, bounced) = match self.sys.copy_file_range(
+            fd_in,
+            &mut off_in,
+            fd_out,
+            &mut off_out,
+            len as usize,
+            flags as u32,
+        ) {
+            // The kernel rejects flags before checking the files, so errors of unknown flags
+            // are passed through.
+            Err(e) if self.fallback && flags == 0 && is_uncopyable(&e) => {
+                debug!("fuse: copy_file_range falls back to read and write, {}", e);
+                (
+                    copy_fallback(fd_in, offset_in, fd_out, offset_out, len, &self.points),
+                    true,
+                )
+            }
+            res => (res, false),
+        };
+        if let (Ok(count), Some(acct)) = (res.as_ref(), self.acct.as_ref()) {
+            acct.add_backend(*count as u64);
+            if bounced {
+                acct.add_bounce(*count as u64);
+            }
+        }
+        res
+    }
+}
+
+// Check whether `err` means the kernel can't copy between the files.
+fn is_uncopyable(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EXDEV) | Some(libc::EOPNOTSUPP)
+    )
+}
+
+fn file_size(fd: RawFd) -> io::Result<u64> {
+    let mut st = MaybeUninit::<libc::stat64>::zeroed();
+
+    // Safe because the kernel will only write data in `st` and we check the return value.
+    let res = unsafe { libc::fstat64(fd, st.as_mut_ptr()) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safe because the kernel guarantees that the struct is now fully initialized.
+    Ok(unsafe { st.assume_init() }.st_size as u64)
+}
+
+fn seek(fd: RawFd, offset: u64, whence: i32) -> io::Result<u64> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    let res = unsafe { libc::lseek64(fd, offset as libc::off64_t, whence) };
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res as u64)
+    }
+}
+
+// Find the first range of data of `fd` in `pos..end`, or `None` if there's only a hole.
+fn next_data(fd: RawFd, pos: u64, end: u64) -> io::Result<Option<(u64, u64)>> {
+    let start = match seek(fd, pos, libc::SEEK_DATA) {
+        Ok(start) => start,
+        Err(e) if e.raw_os_error() == Some(libc::ENXIO) => return Ok(None),
+        // Without hole detection, the whole range is data.
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return Ok(Some((pos, end))),
+        Err(e) => return Err(e),
+    };
+    if start >= end {
+        return Ok(None);
+    }
+    let hole = seek(fd, start, libc::SEEK_HOLE)?;
+    Ok(Some((start, hole.min(end))))
+}
+
+// Make `len` bytes at `offset` of `fd` read as zeros.
+fn zero_range(fd: RawFd, offset: u64, len: u64, buf: &mut [u8]) -> io::Result<()> {
+    // Nothing to clear beyond the end of the file, it's extended with zeros.
+    let len = len.min(file_size(fd)?.saturating_sub(offset));
+    if len == 0 {
+        return Ok(());
+    }
+
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    // Safe because this doesn't modify any memory and we check the return value.
+    let res = unsafe { libc::fallocate64(fd, mode, offset as i64, len as i64) };
+    if res == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    if !matches!(
+        err.raw_os_error(),
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS)
+    ) {
+        return Err(err);
+    }
+
+    buf.fill(0);
+    let mut done = 0;
+    while done < len {
+        let size = (len - done).min(buf.len() as u64) as usize;
+        write_all(fd, &buf[..size], offset + done)?;
+        done += size as u64;
+    }
+    Ok(())
+}
+
+fn write_all(fd: RawFd, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        // Safe because the kernel only reads `buf` and we check the return value.
+        let res = unsafe {
+            libc::pwrite64(
+                fd,
+                buf.as_ptr() as *const libc::c_void,
+                buf.len(),
+                offset as libc::off64_t,
+            )
+        };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        if res == 0 {
+            return Err(io::Error::from_raw_os_error(libc::EIO));
+        }
+        buf = &buf[res as usize..];
+        offset += res as u64;
+    }
+    Ok(())
+}
+
+// Copy `len` bytes of data, return the number of bytes copied, less if the source shrinks.
+fn copy_data(
+    fd_in: RawFd,
+    offset_in: u64,
+    fd_out: RawFd,
+    offset_out: u64,
+    len: u64,
+    buf: &mut [u8],
+) -> io::Result<u64> {
+    let mut done = 0;
+    while done < len {
+        let size = (len - done).min(buf.len() as u64) as usize;
+        // Safe because the kernel only writes `size` bytes to `buf` and we check the return value.
+        let res = unsafe {
+            libc::pread64(
+                fd_in,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                size,
+                (offset_in + done) as libc::off64_t,
+            )
+        };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        if res == 0 {
+            break;
+        }
+        write_all(fd_out, &buf[..res as usize], offset_out + done)?;
+        done += res as u64;
+    }
+    Ok(done)
+}
+
+/// Copy like copy_file_range(2) by reading and writing the files, keeping holes of `fd_in`, and
+/// consulting `points` between chunks.
+pub(super) fn copy_fallback(
+    fd_in: RawFd,
+    offset_in: u64,
+    fd_out: RawFd,
+    offset_out: u64,
+    len: u64,
+    points: &YieldPoints,
+) -> io::Result<usize> {
+    // Like copy_file_range(2), copy up to the end of the source file.
+    let len = len.min(isize::MAX as u64);
+    let end = file_size(fd_in)?.min(offset_in.saturating_add(len));
+    if offset_in >= end {
+        return Ok(0);
+    }
+
+    let mut buf = vec![0u8; COPY_CHUNK.min((end - offset_in) as usize)];
+    let out = |pos: u64| offset_out + (pos - offset_in);
+    let chunk = points.chunks().copy_bytes.max(1);
+    let mut pos = offset_in;
+    while pos < end {
+        if pos > offset_in {
+            points
+                .check()
+                .with_errno_context(|| format!("copy aborted after {} bytes", pos - offset_in))?;
+        }
+        let (start, stop) = next_data(fd_in, pos, end)?.unwrap_or((end, end));
+        let stop = stop.min(start.saturating_add(chunk));
+        if start > pos {
+            zero_range(fd_out, out(pos), start - pos, &mut buf)?;
+            pos = start;
+        }
+        if start == stop {
+            break;
+        }
+        let copied = copy_data(fd_in, start, fd_out, out(start), stop - start, &mut buf)?;
+        pos = start + copied;
+        if copied < stop - start {
+            break;
+        }
+    }
+
+    // Extend the destination if the copied range ends with a hole.
+    if file_size(fd_out)? < out(pos) {
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe { libc::ftruncate64(fd_out, out(pos) as libc::off64_t) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok((pos - offset_in) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::errno::errno_of;
+    use std::fs::File;
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::io::AsRawFd;
+    use vmm_sys_util::tempfile::TempFile;
+
+    // Syscalls of files on different mounts.
+    struct XdevSyscalls(i32);
+
+    impl CopySyscalls for XdevSyscalls {
+        fn copy_file_range(
+            &self,
+            _: RawFd,
+            _: &mut i64,
+            _: RawFd,
+            _: &mut i64,
+            _: usize,
+            _: u32,
+        ) -> io::Result<usize> {
+            Err(io::Error::from_raw_os_error(self.0))
+        }
+    }
+
+    // Syscalls claiming to copy everything requested.
+    struct CopyAllSyscalls;
+
+    impl CopySyscalls for CopyAllSyscalls {
+        fn copy_file_range(
+            &self,
+            _: RawFd,
+            _: &mut i64,
+            _: RawFd,
+            _: &mut i64,
+            len: usize,
+            _: u32,
+        ) -> io::Result<usize> {
+            Ok(len)
+        }
+    }
+
+    fn file(data: &[(u64, &[u8])]) -> (TempFile, File) {
+        let tmp = TempFile::new().unwrap();
+        let file = tmp.as_file().try_clone().unwrap();
+        for (offset, buf) in data {
+            file.write_all_at(buf, *offset).unwrap();
+        }
+        (tmp, file)
+    }
+
+    fn content(file: &File) -> Vec<u8> {
+        let mut buf = vec![0u8; file.metadata().unwrap().len() as usize];
+        file.read_exact_at(&mut buf, 0).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_copy_same_fs() {
+        let (_t1, src) = file(&[(0, b"hello world")]);
+        let (_t2, dst) = file(&[]);
+        let helper = CopyHelper::new(true);
+        let copied = helper
+            .copy(src.as_raw_fd(), 6, dst.as_raw_fd(), 2, 100, 0)
+            .unwrap();
+        assert_eq!(copied, 5);
+        assert_eq!(content(&dst), b"\0\0world");
+    }
+
+    #[test]
+    fn test_copy_len_limit() {
+        // Copies are capped so that the number of bytes copied fits in the reply.
+        let helper = CopyHelper::with_syscalls(Box::new(CopyAllSyscalls), false);
+        assert_eq!(
+            helper.copy(0, 0, 1, 0, u64::MAX, 0).unwrap(),
+            u32::MAX as usize
+        );
+        assert_eq!(helper.copy(0, 0, 1, 0, 4096, 0).unwrap(), 4096);
+    }
+
+    #[test]
+    fn test_copy_cross_fs() {
+        let (_t1, src) = file(&[(0, b"hello world")]);
+        let (_t2, dst) = file(&[(0, b"0123456789abcdef")]);
+        let (fd_in, fd_out) = (src.as_raw_fd(), dst.as_raw_fd());
+
+        // Errors are passed through unless the fallback is enabled.
+        let helper = CopyHelper::with_syscalls(Box::new(XdevSyscalls(libc::EXDEV)), false);
+        let err = helper.copy(fd_in, 0, fd_out, 0, 5, 0).unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::EXDEV));
+
+        let helper = CopyHelper::with_syscalls(Box::new(XdevSyscalls(libc::EXDEV)), true);
+        assert_eq!(helper.copy(fd_in, 0, fd_out, 4, 5, 0).unwrap(), 5);
+        assert_eq!(content(&dst), b"0123hello9abcdef");
+        let err = helper.copy(fd_in, 0, fd_out, 0, 5, 1).unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::EXDEV));
+
+        let helper = CopyHelper::with_syscalls(Box::new(XdevSyscalls(libc::EOPNOTSUPP)), true);
+        assert_eq!(helper.copy(fd_in, 6, fd_out, 12, 100, 0).unwrap(), 5);
+        assert_eq!(content(&dst), b"0123hello9abworld");
+        assert_eq!(helper.copy(fd_in, 11, fd_out, 0, 100, 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_copy_accounting() {
+        let (_t1, src) = file(&[(0, b"hello world")]);
+        let (_t2, dst) = file(&[]);
+        let (fd_in, fd_out) = (src.as_raw_fd(), dst.as_raw_fd());
+        let acct = Arc::new(WriteAccounting::default());
+
+        // Only copies done by the fallback go through the buffer of the daemon.
+        let helper = CopyHelper::new(true).with_accounting(acct.clone());
+        assert_eq!(helper.copy(fd_in, 0, fd_out, 0, 5, 0).unwrap(), 5);
+        let helper = CopyHelper::with_syscalls(Box::new(XdevSyscalls(libc::EXDEV)), true)
+            .with_accounting(acct.clone());
+        assert_eq!(helper.copy(fd_in, 6, fd_out, 5, 100, 0).unwrap(), 5);
+        helper.copy(fd_in, 0, fd_out, 0, 5, 1).unwrap_err();
+        assert_eq!(content(&dst), b"helloworld");
+        let stats = acct.stats();
+        assert_eq!(stats.backend_write_bytes, 10);
+        assert_eq!(stats.bounce_bytes, 5);
+        assert_eq!(stats.guest_write_bytes, 0);
+    }
+
+    #[test]
+    fn test_copy_sparse() {
+        const MB: u64 = 1 << 20;
+        let data = vec![0x5au8; 4096];
+        let (_t1, src) = file(&[(0, &data), (2 * MB, &data)]);
+        src.set_len(4 * MB).unwrap();
+        let (_t2, dst) = file(&[(MB, &data)]);
+        let helper = CopyHelper::with_syscalls(Box::new(XdevSyscalls(libc::EXDEV)), true);
+
+        let copied = helper
+            .copy(src.as_raw_fd(), 0, dst.as_raw_fd(), 0, 8 * MB, 0)
+            .unwrap();
+        assert_eq!(copied as u64, 4 * MB);
+        assert_eq!(content(&dst), content(&src));
+
+        // Holes are kept, where the backing file system supports them.
+        let fd = dst.as_raw_fd();
+        if seek(fd, 0, libc::SEEK_HOLE).unwrap() < 4 * MB {
+            assert_eq!(seek(fd, 4096, libc::SEEK_DATA).unwrap(), 2 * MB);
+            assert_eq!(dst.metadata().unwrap().len(), 4 * MB);
+        }
+    }
+    #[test]
+    fn test_copy_abort() {
+        use crate::api::shedder::{LoadShedder, ShedDecision, YieldChunks};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Abort at the `n`th yield point.
+        struct AbortAt(usize, AtomicUsize);
+
+        impl LoadShedder for AbortAt {
+            fn check(&self) -> ShedDecision {
+                if self.1.fetch_add(1, Ordering::Relaxed) + 1 >= self.0 {
+                    ShedDecision::Abort
+                } else {
+                    ShedDecision::Continue
+                }
+            }
+        }
+
+        let data: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+        let (_t1, src) = file(&[(0, &data)]);
+        let (_t2, dst) = file(&[]);
+        let (fd_in, fd_out) = (src.as_raw_fd(), dst.as_raw_fd());
+        let chunks = YieldChunks {
+            copy_bytes: 4096,
+            ..Default::default()
+        };
+        let points =
+            YieldPoints::new(Arc::new(AbortAt(2, AtomicUsize::new(0)))).with_chunks(chunks);
+        let acct = Arc::new(WriteAccounting::default());
+        let helper = CopyHelper::with_syscalls(Box::new(XdevSyscalls(libc::EXDEV)), true)
+            .with_accounting(acct.clone())
+            .with_yield_points(points);
+
+        // The destination holds the chunks copied before the abort, and isn't accounted.
+        let err = helper.copy(fd_in, 0, fd_out, 0, 10000, 0).unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::EINTR));
+        assert!(err.to_string().contains("copy aborted after 8192 bytes"));
+        assert_eq!(content(&dst), data[..8192]);
+        assert_eq!(acct.stats().backend_write_bytes, 0);
+
+        // Copies done by the kernel aren't chunked.
+        let helper = CopyHelper::new(true).with_yield_points(
+            YieldPoints::new(Arc::new(AbortAt(1, AtomicUsize::new(0)))).with_chunks(chunks),
+        );
+        assert_eq!(helper.copy(fd_in, 0, fd_out, 0, 10000, 0).unwrap(), 10000);
+        assert_eq!(content(&dst), data);
+    }
+}
@@ -0,0 +1,321 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Switching of thread credentials around syscalls creating files.
+//!
+//! Files are owned by the effective uid and gid of the thread creating them, so the effective ids
+//! of the thread are switched to the caller's around such syscalls, and back to the daemon's
+//! afterward. Callers usually have the same ids as the daemon, like in single user development
+//! containers, and the switch is skipped then, saving two syscalls per changed id. Like for callers
+//! with the same ids, the id of a root caller is never switched to, so a daemon running as an
+//! unprivileged user, which can't switch to root, still serves requests of root. Daemons running
+//! with a fixed identity may disable switching by `Config::switch_creds`, and files are always
+//! owned by the daemon.
+//!
+//! Callers creating files in directories owned by one of their supplementary groups need that
+//! group to pass permission checks of the host. With `FsOptions::CREATE_SUPP_GROUP` negotiated,
+//! the kernel sends it with the request, and the supplementary groups of the thread are replaced
+//! by it around the syscall, which requires `CAP_SETGID`.
+
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::PassthroughFs;
+use crate::api::filesystem::Context;
+use crate::BitmapSlice;
+
+macro_rules! scoped_cred {
+    ($name:ident, $ty:ty, $syscall_nr:expr) => {
+        #[derive(Debug)]
+        struct $name {
+            restore: $ty,
+        }
+
+        impl $name {
+            // Changes the effective uid/gid of the current thread to `val`.  Changes
+            // the thread's credentials back to `restore` when the returned struct is dropped.
+            fn new(val: $ty, restore: $ty) -> io::Result<$name> {
+                // We want credential changes to be per-thread because otherwise
+                // we might interfere with operations being carried out on other
+                // threads with different uids/gids.  However, posix requires that
+                // all threads in a process share the same credentials.  To do this
+                // libc uses signals to ensure that when one thread changes its
+                // credentials the other threads do the same thing.
+                //
+                // So instead we invoke the syscall directly in order to get around
+                // this limitation.  Another option is to use the setfsuid and
+                // setfsgid systems calls.   However since those calls have no way to
+                // return an error, it's preferable to do this instead.
+
+                // This call is safe because it doesn't modify any memory and we
+                // check the return value.
+                let res = unsafe { libc::syscall($syscall_nr, -1, val, -1) };
+                if res == 0 {
+                    Ok($name { restore })
+                } else {
+                    Err(io::Error::last_os_error())
+                }
+            }
+        }
+
+        impl Drop for $name {
+            fn drop(&mut self) {
+                let res = unsafe { libc::syscall($syscall_nr, -1, self.restore, -1) };
+                if res < 0 {
+                    error!(
+                        "fuse: failed to change credentials back to {}: {}",
+                        self.restore,
+                        io::Error::last_os_error(),
+                    );
+                }
+            }
+        }
+    };
+}
+scoped_cred!(ScopedUid, libc::uid_t, libc::SYS_setresuid);
+scoped_cred!(ScopedGid, libc::gid_t, libc::SYS_setresgid);
+
+// Supplementary groups of the current thread, switched back to `restore` when dropped.
+#[derive(Debug)]
+struct ScopedGroups<'a> {
+    restore: &'a [libc::gid_t],
+}
+
+impl<'a> ScopedGroups<'a> {
+    fn new(gid: libc::gid_t, restore: &'a [libc::gid_t]) -> io::Result<Self> {
+        // Like the uid and gid, invoke the syscall directly to only change the groups of the
+        // current thread. This call is safe because it only reads `gid` and we check the return
+        // value.
+        let res = unsafe { libc::syscall(libc::SYS_setgroups, 1, &gid as *const libc::gid_t) };
+        if res == 0 {
+            Ok(ScopedGroups { restore })
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+impl Drop for ScopedGroups<'_> {
+    fn drop(&mut self) {
+        // Safe because it only reads `restore`.
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_setgroups,
+                self.restore.len(),
+                self.restore.as_ptr(),
+            )
+        };
+        if res < 0 {
+            error!(
+                "fuse: failed to change supplementary groups back to {:?}: {}",
+                self.restore,
+                io::Error::last_os_error(),
+            );
+        }
+    }
+}
+
+// Get the supplementary groups of the current thread.
+fn thread_groups() -> io::Result<Vec<libc::gid_t>> {
+    // Safe because a zero size doesn't write anything.
+    let n = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut groups = vec![0; n as usize];
+    // Safe because the kernel writes at most `n` groups and we check the return value.
+    let n = unsafe { libc::getgroups(n, groups.as_mut_ptr()) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    groups.truncate(n as usize);
+    Ok(groups)
+}
+
+/// Statistics of credential switching around syscalls creating files.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CredSwitchStats {
+    /// Number of operations run with the caller's uid or gid.
+    pub switched: u64,
+    /// Number of operations run with the daemon's credentials, because the caller has the same
+    /// ids or switching is disabled.
+    pub skipped: u64,
+}
+
+/// Credentials of the caller, switched back to the daemon's when dropped.
+#[derive(Debug)]
+pub(super) struct ScopedCreds<'a> {
+    // The uid is restored first, to regain the capability to restore the gid and the groups.
+    _uid: Option<ScopedUid>,
+    _gid: Option<ScopedGid>,
+    _groups: Option<ScopedGroups<'a>>,
+}
+
+pub(super) struct CredSwitcher {
+    enabled: bool,
+    // Effective ids and supplementary groups of the daemon, when the switcher was created.
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    groups: Vec<libc::gid_t>,
+    switched: AtomicU64,
+    skipped: AtomicU64,
+}
+
+impl CredSwitcher {
+    pub(super) fn new(enabled: bool) -> Self {
+        // Safe because these calls don't modify any memory and always succeed.
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let groups = thread_groups().unwrap_or_default();
+        CredSwitcher {
+            enabled,
+            uid,
+            gid,
+            groups,
+            switched: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+        }
+    }
+
+    /// Switch the credentials of the current thread to `uid` and `gid`, unless they already match
+    /// or are root's, and its supplementary groups to `supp_gid` if any.
+    pub(super) fn set(
+        &self,
+        uid: libc::uid_t,
+        gid: libc::gid_t,
+        supp_gid: Option<libc::gid_t>,
+    ) -> io::Result<ScopedCreds<'_>> {
+        let switch_uid = uid != 0 && uid != self.uid;
+        let switch_gid = gid != 0 && gid != self.gid;
+        if !self.enabled || (!switch_uid && !switch_gid && supp_gid.is_none()) {
+            self.skipped.fetch_add(1, Ordering::Relaxed);
+            return Ok(ScopedCreds {
+                _uid: None,
+                _gid: None,
+                _groups: None,
+            });
+        }
+
+        // We have to change the groups and the gid before we change the uid because if we change
+        // the uid first then we lose the capability to change them.
+        let groups = supp_gid
+            .map(|g| ScopedGroups::new(g, &self.groups))
+            .transpose()?;
+        let gid = switch_gid
+            .then(|| ScopedGid::new(gid, self.gid))
+            .transpose()?;
+        let uid = switch_uid
+            .then(|| ScopedUid::new(uid, self.uid))
+            .transpose()?;
+        self.switched.fetch_add(1, Ordering::Relaxed);
+
+        Ok(ScopedCreds {
+            _uid: uid,
+            _gid: gid,
+            _groups: groups,
+        })
+    }
+
+    pub(super) fn stats(&self) -> CredSwitchStats {
+        CredSwitchStats {
+            switched: self.switched.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    // Switch the credentials of the current thread to the caller's, including its supplementary
+    // group when `FsOptions::CREATE_SUPP_GROUP` is enabled, translated into host ids.
+    pub(super) fn set_creds(&self, ctx: &Context) -> io::Result<ScopedCreds<'_>> {
+        let supp_gid = ctx
+            .supp_gid
+            .filter(|_| self.supp_group.load(Ordering::Relaxed));
+        match self.cfg.uid_gid_map.as_ref() {
+            Some(map) => self.creds.set(
+                map.host_uid(ctx.uid),
+                map.host_gid(ctx.gid),
+                supp_gid.map(|g| map.host_gid(g)),
+            ),
+            None => self.creds.set(ctx.uid, ctx.gid, supp_gid),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cred_switcher_skip() {
+        // Safe because these calls don't modify any memory and always succeed.
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+
+        let creds = CredSwitcher::new(true);
+        let guard = creds.set(uid, gid, None).unwrap();
+        assert!(guard._uid.is_none() && guard._gid.is_none());
+        let creds = CredSwitcher::new(false);
+        creds.set(uid + 1, gid + 1, Some(gid + 2)).unwrap();
+        creds.set(uid, gid, None).unwrap();
+        assert_eq!(
+            creds.stats(),
+            CredSwitchStats {
+                switched: 0,
+                skipped: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_cred_switcher_unprivileged_root_caller() {
+        // Only the ids of the current thread change, run in a dedicated one.
+        std::thread::spawn(|| {
+            // Safe because this call doesn't modify any memory and always succeeds.
+            let privileged = unsafe { libc::geteuid() } == 0;
+            if privileged {
+                // Drop the effective ids to nobody's, keeping the real and saved ids of root to
+                // switch back. These calls are safe because they don't modify any memory.
+                unsafe {
+                    assert_eq!(libc::syscall(libc::SYS_setresgid, -1, 65534, -1), 0);
+                    assert_eq!(libc::syscall(libc::SYS_setresuid, -1, 65534, -1), 0);
+                }
+            }
+
+            let creds = CredSwitcher::new(true);
+            let ctx = Context::default();
+            assert_eq!((ctx.uid, ctx.gid), (0, 0));
+            let guard = creds.set(ctx.uid, ctx.gid, None).unwrap();
+            assert!(guard._uid.is_none() && guard._gid.is_none());
+            drop(guard);
+            assert_eq!(creds.stats().skipped, 1);
+
+            if privileged {
+                // Safe because these calls don't modify any memory.
+                unsafe {
+                    assert_eq!(libc::syscall(libc::SYS_setresuid, -1, 0, -1), 0);
+                    assert_eq!(libc::syscall(libc::SYS_setresgid, -1, 0, -1), 0);
+                }
+            }
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_cred_switcher_supp_group() {
+        // Only the groups of the current thread change, run in a dedicated one.
+        std::thread::spawn(|| {
+            let before = thread_groups().unwrap();
+            let creds = CredSwitcher::new(true);
+            // Safe because these calls don't modify any memory and always succeed.
+            let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+            {
+                let _guard = creds.set(uid, gid, Some(4242)).unwrap();
+                assert_eq!(thread_groups().unwrap(), vec![4242]);
+            }
+            assert_eq!(thread_groups().unwrap(), before);
+            assert_eq!(creds.stats().switched, 1);
+        })
+        .join()
+        .unwrap();
+    }
+}
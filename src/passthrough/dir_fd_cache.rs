@@ -0,0 +1,468 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Cache of directory file descriptors.
+//!
+//! Inodes referenced by file handles have no fd open, so each lookup or `*at` syscall in such a
+//! directory first opens it again by its handle, and readdir without opendir opens it for each
+//! request. Workloads like build systems stat thousands of entries in the same few directories,
+//! spending much of their time opening and closing them. `Config::dir_fd_cache_size` keeps that
+//! many directories open for reading, least recently used first out, shared with the handles of
+//! opendir so hot directories don't hold two fds.
+//!
+//! Entries are dropped when their inode is forgotten, and when the directory is removed or
+//! renamed over by the guest. Directories removed on the host are kept until evicted, which is
+//! harmless as lookups in them fail like they would through their inode.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CStr;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::{
+    FileOrHandle, Handle, HandleData, Inode, InodeAltKey, InodeData, InodeFile, PassthroughFs,
+};
+use crate::api::filesystem::OpenOptions;
+use crate::BitmapSlice;
+
+/// Bounded LRU map of directory inodes to open handles of them.
+pub(super) struct DirFdCache {
+    capacity: usize,
+    inner: Mutex<LruMap>,
+    // Number of directories opened to fill the cache.
+    opens: AtomicU64,
+}
+
+#[derive(Default)]
+struct LruMap {
+    entries: HashMap<Inode, CachedDir>,
+    // Inodes by the stamp of their last use, least recent first.
+    order: BTreeMap<u64, Inode>,
+    next_stamp: u64,
+}
+
+struct CachedDir {
+    data: Arc<HandleData>,
+    // Device and inode number of the directory on the host.
+    host: (u64, u64),
+    stamp: u64,
+}
+
+impl DirFdCache {
+    /// Create a cache of at most `capacity` directories, disabled if zero.
+    pub(super) fn new(capacity: usize) -> Self {
+        DirFdCache {
+            capacity,
+            inner: Mutex::new(LruMap::default()),
+            opens: AtomicU64::new(0),
+        }
+    }
+
+    pub(super) fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Get the handle of `inode`, marking it as the most recently used.
+    pub(super) fn get(&self, inode: Inode) -> Option<Arc<HandleData>> {
+        if !self.enabled() {
+            return None;
+        }
+        let mut map = self.inner.lock().unwrap();
+        let stamp = map.bump();
+        let entry = map.entries.get_mut(&inode)?;
+        let old = std::mem::replace(&mut entry.stamp, stamp);
+        let data = entry.data.clone();
+        map.order.remove(&old);
+        map.order.insert(stamp, inode);
+        Some(data)
+    }
+
+    /// Cache `data`, the directory `host` opened for `inode`, evicting the least recently used
+    /// directories beyond the capacity.
+    ///
+    /// Return the handle cached for `inode`, the one of a racing insert if any, so each inode
+    /// keeps a single fd.
+    pub(super) fn insert(
+        &self,
+        inode: Inode,
+        host: (u64, u64),
+        data: Arc<HandleData>,
+    ) -> Arc<HandleData> {
+        if !self.enabled() {
+            return data;
+        }
+        self.opens.fetch_add(1, Ordering::Relaxed);
+        let mut map = self.inner.lock().unwrap();
+        if let Some(entry) = map.entries.get(&inode) {
+            return entry.data.clone();
+        }
+        let stamp = map.bump();
+        map.order.insert(stamp, inode);
+        map.entries.insert(
+            inode,
+            CachedDir {
+                data: data.clone(),
+                host,
+                stamp,
+            },
+        );
+        while map.entries.len() > self.capacity {
+            let (_, victim) = map.order.pop_first().unwrap();
+            map.entries.remove(&victim);
+        }
+        data
+    }
+
+    /// Drop the directory of `inode`.
+    pub(super) fn remove(&self, inode: Inode) {
+        if !self.enabled() {
+            return;
+        }
+        let mut map = self.inner.lock().unwrap();
+        if let Some(entry) = map.entries.remove(&inode) {
+            map.order.remove(&entry.stamp);
+        }
+    }
+
+    /// Drop the directory with inode number `ino` on the host device `dev`.
+    pub(super) fn remove_host(&self, dev: u64, ino: u64) {
+        if !self.enabled() {
+            return;
+        }
+        let mut map = self.inner.lock().unwrap();
+        let victims = map
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.host == (dev, ino))
+            .map(|(inode, _)| *inode)
+            .collect::<Vec<_>>();
+        for inode in victims {
+            if let Some(entry) = map.entries.remove(&inode) {
+                map.order.remove(&entry.stamp);
+            }
+        }
+    }
+
+    /// Drop all directories, when all inodes are dropped.
+    pub(super) fn clear(&self) {
+        *self.inner.lock().unwrap() = LruMap::default();
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.inner.lock().unwrap().entries.is_empty()
+    }
+
+    /// Number of directories opened to fill the cache.
+    pub(super) fn opens(&self) -> u64 {
+        self.opens.load(Ordering::Relaxed)
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+}
+
+impl LruMap {
+    fn bump(&mut self) -> u64 {
+        self.next_stamp += 1;
+        self.next_stamp
+    }
+}
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    /// Get the number of directories opened to fill the cache of `Config::dir_fd_cache_size`,
+    /// growing with each miss, to tune its size.
+    pub fn dir_fd_cache_opens(&self) -> u64 {
+        self.dir_fds.opens()
+    }
+
+    // Get the cached handle of the directory `data`, opening it on a miss. Return `None` if the
+    // cache is disabled or `data` isn't a directory.
+    pub(super) fn cached_dir(&self, data: &InodeData) -> io::Result<Option<Arc<HandleData>>> {
+        if !self.dir_fds.enabled() || data.mode & libc::S_IFMT != libc::S_IFDIR {
+            return Ok(None);
+        }
+        if let Some(dir) = self.dir_fds.get(data.inode) {
+            return Ok(Some(dir));
+        }
+
+        let file = self.open_inode(data.inode, libc::O_RDONLY | libc::O_DIRECTORY)?;
+        let host = match data.altkey {
+            InodeAltKey::Ids { ino, dev, .. } => (dev, ino),
+            InodeAltKey::Handle(_) => {
+                let st = Self::stat(&file, None)?;
+                (st.st_dev, st.st_ino)
+            }
+        };
+        let dir = self.dir_fds.insert(
+            data.inode,
+            host,
+            Arc::new(HandleData::new(data.inode, file)),
+        );
+        // Don't keep the directory open if the inode was forgotten in the meantime.
+        if self.inode_map.get(data.inode).is_err() {
+            self.dir_fds.remove(data.inode);
+        }
+        Ok(Some(dir))
+    }
+
+    // Get a file of the directory `data` for lookups and `*at` syscalls. Directories referenced
+    // by file handles use the cached one, others their own `O_PATH` fd which costs no open.
+    pub(super) fn dir_file<'a>(&self, data: &'a InodeData) -> io::Result<InodeFile<'a>> {
+        if let FileOrHandle::Handle(_) = data.file_or_handle {
+            // Fall back to opening the handle, for directories the daemon can't read.
+            if let Ok(Some(dir)) = self.cached_dir(data) {
+                return Ok(InodeFile::Dir(dir));
+            }
+        }
+        data.get_file(&self.mount_fds)
+    }
+
+    // Open a handle of the directory `inode` sharing the fd of the cache. Return `None` when
+    // handles need their own state to snapshot entries, or the directory isn't cached.
+    pub(super) fn open_cached_dir(
+        &self,
+        inode: Inode,
+        flags: u32,
+    ) -> io::Result<Option<(Option<Handle>, OpenOptions)>> {
+        if self.cfg.snapshot_readdir {
+            return Ok(None);
+        }
+        let data = self.inode_map.get(inode)?;
+        let dir = match self.cached_dir(&data) {
+            Ok(Some(dir)) => dir,
+            _ => return Ok(None),
+        };
+        let opts = self.open_options(&dir.file, flags, data.mode, None);
+        let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        self.handle_map.insert_shared(handle, dir);
+        Ok(Some((Some(handle), opts)))
+    }
+
+    // Get the host ids of the entry `name` of the directory `dir` if it's a cached directory, to
+    // drop it from the cache once it's removed or replaced.
+    pub(super) fn cached_victim(&self, dir: RawFd, name: &CStr) -> Option<(u64, u64)> {
+        if self.dir_fds.is_empty() {
+            return None;
+        }
+        let st = Self::stat_fd(dir, Some(name)).ok()?;
+        if st.st_mode & libc::S_IFMT == libc::S_IFDIR {
+            Some((st.st_dev, st.st_ino))
+        } else {
+            None
+        }
+    }
+
+    // Drop the directory removed or replaced, got by `cached_victim()`, from the cache.
+    pub(super) fn drop_victim(&self, victim: Option<(u64, u64)>) {
+        if let Some((dev, ino)) = victim {
+            self.dir_fds.remove_host(dev, ino);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::prepare_passthroughfs_with;
+    use super::*;
+    use crate::abi::fuse_abi::ROOT_ID;
+    use crate::api::filesystem::{Context, FileSystem, FsOptions};
+    use std::ffi::CString;
+    use std::fs::{self, File};
+
+    fn dir(inode: Inode) -> Arc<HandleData> {
+        Arc::new(HandleData::new(inode, File::open("/").unwrap()))
+    }
+
+    #[test]
+    fn test_dir_fd_cache_lru() {
+        let cache = DirFdCache::new(2);
+        let a = cache.insert(2, (1, 20), dir(2));
+        cache.insert(3, (1, 30), dir(3));
+        assert!(Arc::ptr_eq(&cache.get(2).unwrap(), &a));
+
+        // The least recently used directory is evicted.
+        cache.insert(4, (1, 40), dir(4));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(3).is_none());
+        assert!(cache.get(2).is_some());
+        assert!(cache.get(4).is_some());
+
+        // Racing inserts keep the first handle.
+        let b = cache.insert(2, (1, 20), dir(2));
+        assert!(Arc::ptr_eq(&b, &a));
+        assert_eq!(cache.opens(), 4);
+
+        cache.remove(2);
+        assert!(cache.get(2).is_none());
+        cache.remove_host(1, 40);
+        assert!(cache.is_empty());
+
+        cache.insert(5, (1, 50), dir(5));
+        cache.clear();
+        assert!(cache.get(5).is_none());
+    }
+
+    #[test]
+    fn test_dir_fd_cache_disabled() {
+        let cache = DirFdCache::new(0);
+        assert!(!cache.enabled());
+        cache.insert(2, (1, 20), dir(2));
+        assert!(cache.get(2).is_none());
+        assert_eq!(cache.opens(), 0);
+    }
+
+    fn readdir_names(fs: &PassthroughFs, inode: Inode, handle: Handle) -> Vec<Vec<u8>> {
+        let mut names = Vec::new();
+        fs.readdir(&Context::default(), inode, handle, 4096, 0, &mut |e| {
+            names.push(e.name.to_vec());
+            Ok(1)
+        })
+        .unwrap();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_dir_fd_cache_lookups() {
+        let (source, fs) = prepare_passthroughfs_with(|cfg| {
+            cfg.inode_file_handles = true;
+            cfg.dir_fd_cache_size = 4;
+        });
+        fs.init(FsOptions::empty()).unwrap();
+        fs::create_dir(source.as_path().join("d")).unwrap();
+        for i in 0..10 {
+            fs::write(source.as_path().join(format!("d/f{}", i)), b"data").unwrap();
+        }
+        let ctx = Context::default();
+        let d = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("d").unwrap())
+            .unwrap();
+        let data = fs.inode_map.get(d.inode).unwrap();
+        if !matches!(data.file_or_handle, FileOrHandle::Handle(_)) {
+            println!("directories aren't referenced by file handles");
+            return;
+        }
+
+        // The directory is opened once for all lookups in it.
+        let opens = fs.dir_fds.opens();
+        for i in 0..1000 {
+            let name = CString::new(format!("f{}", i % 10)).unwrap();
+            let entry = fs.lookup(&ctx, d.inode, &name).unwrap();
+            fs.forget(&ctx, entry.inode, 1);
+        }
+        assert_eq!(fs.dir_fds.opens(), opens + 1);
+
+        // Forgetting the directory closes it.
+        assert!(fs.dir_fds.get(d.inode).is_some());
+        fs.forget(&ctx, d.inode, 1);
+        assert!(fs.dir_fds.get(d.inode).is_none());
+    }
+
+    #[test]
+    fn test_dir_fd_cache_opendir() {
+        let (source, fs) = prepare_passthroughfs_with(|cfg| cfg.dir_fd_cache_size = 4);
+        fs.init(FsOptions::empty()).unwrap();
+        fs::create_dir(source.as_path().join("d")).unwrap();
+        fs::write(source.as_path().join("d/a"), b"a").unwrap();
+        fs::write(source.as_path().join("d/b"), b"b").unwrap();
+        let ctx = Context::default();
+        let d = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("d").unwrap())
+            .unwrap()
+            .inode;
+
+        // Handles of opendir share the fd of the cache.
+        let h1 = fs
+            .opendir(&ctx, d, libc::O_RDONLY as u32)
+            .unwrap()
+            .0
+            .unwrap();
+        let h2 = fs
+            .opendir(&ctx, d, libc::O_RDONLY as u32)
+            .unwrap()
+            .0
+            .unwrap();
+        let cached = fs.dir_fds.get(d).unwrap();
+        assert!(Arc::ptr_eq(&fs.handle_map.get(h1, d).unwrap(), &cached));
+        assert!(Arc::ptr_eq(&fs.handle_map.get(h2, d).unwrap(), &cached));
+        assert_eq!(
+            readdir_names(&fs, d, h1),
+            vec![b"a".to_vec(), b"b".to_vec()]
+        );
+        assert_eq!(readdir_names(&fs, d, h2).len(), 2);
+        fs.releasedir(&ctx, d, 0, h1).unwrap();
+        fs.releasedir(&ctx, d, 0, h2).unwrap();
+        assert!(fs.dir_fds.get(d).is_some());
+
+        // Readdir without opendir reuses it too.
+        fs.no_opendir.store(true, Ordering::Relaxed);
+        assert_eq!(readdir_names(&fs, d, 0).len(), 2);
+        assert_eq!(readdir_names(&fs, d, 0).len(), 2);
+        assert_eq!(fs.dir_fds.opens(), 1);
+
+        // Handles keep their own fd to snapshot entries.
+        let (source, fs) = prepare_passthroughfs_with(|cfg| {
+            cfg.dir_fd_cache_size = 4;
+            cfg.snapshot_readdir = true;
+        });
+        fs.init(FsOptions::empty()).unwrap();
+        fs::create_dir(source.as_path().join("d")).unwrap();
+        let d = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("d").unwrap())
+            .unwrap()
+            .inode;
+        fs.opendir(&ctx, d, libc::O_RDONLY as u32).unwrap();
+        assert!(fs.dir_fds.is_empty());
+    }
+
+    #[test]
+    fn test_dir_fd_cache_invalidation() {
+        let (source, fs) = prepare_passthroughfs_with(|cfg| cfg.dir_fd_cache_size = 4);
+        fs.init(FsOptions::empty()).unwrap();
+        for dir in ["d", "d/sub", "d/a", "d/b"] {
+            fs::create_dir(source.as_path().join(dir)).unwrap();
+        }
+        let ctx = Context::default();
+        let lookup = |parent, name: &str| {
+            fs.lookup(&ctx, parent, &CString::new(name).unwrap())
+                .unwrap()
+                .inode
+        };
+        let cache = |inode| {
+            let h = fs.opendir(&ctx, inode, libc::O_RDONLY as u32).unwrap().0;
+            fs.releasedir(&ctx, inode, 0, h.unwrap()).unwrap();
+            assert!(fs.dir_fds.get(inode).is_some());
+        };
+        let d = lookup(ROOT_ID, "d");
+        let sub = lookup(d, "sub");
+        let a = lookup(d, "a");
+        let b = lookup(d, "b");
+        for inode in [d, sub, a, b] {
+            cache(inode);
+        }
+
+        fs.rmdir(&ctx, d, &CString::new("sub").unwrap()).unwrap();
+        assert!(fs.dir_fds.get(sub).is_none());
+
+        // Renamed directories stay valid, the ones renamed over are dropped.
+        fs.rename(
+            &ctx,
+            d,
+            &CString::new("a").unwrap(),
+            d,
+            &CString::new("b").unwrap(),
+            0,
+        )
+        .unwrap();
+        assert!(fs.dir_fds.get(b).is_none());
+        assert!(fs.dir_fds.get(a).is_some());
+        assert!(fs.dir_fds.get(d).is_some());
+
+        fs.forget_all();
+        assert!(fs.dir_fds.is_empty());
+    }
+}
@@ -0,0 +1,350 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Snapshots of directory entries, to serve readdir continuations consistently.
+//!
+//! Each readdir request reads host entries from the offset of the previous one, so entries
+//! created or removed on the host while a guest iterates a directory may be returned twice or
+//! never. With `Config::snapshot_readdir`, all entries of a directory handle are read when the
+//! handle is first read, and continuations are served from the snapshot until the handle is
+//! released. Offsets of entries in a snapshot are their positions, starting from 1.
+//!
+//! Directories with more entries than `Config::snapshot_readdir_max_entries`, or whose snapshot
+//! doesn't fit in the memory left of `Config::snapshot_readdir_memory` shared by all handles, are
+//! read from the host as without snapshots, and counted as fallbacks.
+//!
+//! Snapshots of large directories consult the [YieldPoints] of the file system each
+//! `YieldChunks::snapshot_entries` entries, and fail with `EINTR` if aborted there.
+
+use std::ffi::CString;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use vm_memory::ByteValued;
+
+use super::dirent::DirSyscalls;
+use super::LinuxDirent64;
+use crate::api::errno::ErrnoContext;
+use crate::api::shedder::YieldPoints;
+use crate::api::{CURRENT_DIR_CSTR, PARENT_DIR_CSTR};
+use crate::bytes_to_cstr;
+
+// Size of the buffer to read host entries into when taking snapshots.
+const SNAPSHOT_BUF_SIZE: usize = 0x8000;
+
+/// Memory budget shared by all directory snapshots of a file system.
+pub(super) struct SnapshotBudget {
+    limit: usize,
+    used: AtomicUsize,
+    fallbacks: AtomicU64,
+}
+
+impl SnapshotBudget {
+    pub(super) fn new(limit: usize) -> Self {
+        SnapshotBudget {
+            limit,
+            used: AtomicUsize::new(0),
+            fallbacks: AtomicU64::new(0),
+        }
+    }
+
+    /// Get the number of bytes used by snapshots.
+    pub(super) fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of directory handles read without snapshots.
+    pub(super) fn fallbacks(&self) -> u64 {
+        self.fallbacks.load(Ordering::Relaxed)
+    }
+
+    fn try_reserve(&self, bytes: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(bytes).filter(|total| *total <= self.limit)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::AcqRel);
+    }
+
+    fn fall_back(&self, dir: RawFd, reason: &str) {
+        if self.fallbacks.fetch_add(1, Ordering::Relaxed) == 0 {
+            warn!(
+                "fuse: directory {} is too large to snapshot ({}), reading it from the host",
+                dir, reason
+            );
+        } else {
+            debug!(
+                "fuse: directory {} is too large to snapshot ({})",
+                dir, reason
+            );
+        }
+    }
+}
+
+/// An entry of a directory snapshot.
+pub(super) struct SnapshotEntry {
+    pub ino: u64,
+    pub type_: u32,
+    pub name: CString,
+}
+
+/// Entries of a directory, with their memory accounted in the shared budget.
+pub(super) struct DirSnapshot {
+    entries: Vec<SnapshotEntry>,
+    bytes: usize,
+    budget: Arc<SnapshotBudget>,
+}
+
+impl DirSnapshot {
+    /// Get entries following `offset`, the offset of the last entry returned.
+    pub(super) fn entries_from(&self, offset: u64) -> impl Iterator<Item = (u64, &SnapshotEntry)> {
+        let start = std::cmp::min(offset, self.entries.len() as u64) as usize;
+        self.entries[start..]
+            .iter()
+            .zip(start as u64 + 1..)
+            .map(|(e, off)| (off, e))
+    }
+}
+
+impl Drop for DirSnapshot {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes);
+    }
+}
+
+/// How readdir requests of a directory handle are served.
+pub(super) enum DirState {
+    // The handle hasn't been read yet.
+    Unread,
+    Snapshot(Arc<DirSnapshot>),
+    // Read from the host, the directory being too large to snapshot.
+    Streaming,
+}
+
+// Memory charged for an entry named `name`.
+fn entry_size(name: &CString) -> usize {
+    size_of::<SnapshotEntry>() + name.as_bytes_with_nul().len()
+}
+
+/// Read all entries of `dir`, except "." and "..".
+///
+/// Return `None` if `dir` has more than `max_entries` entries or its snapshot doesn't fit in
+/// `budget`, counted as a fallback.
+pub(super) fn take_snapshot(
+    sys: &dyn DirSyscalls,
+    dir: RawFd,
+    max_entries: usize,
+    budget: &Arc<SnapshotBudget>,
+    points: &YieldPoints,
+) -> io::Result<Option<DirSnapshot>> {
+    // Reserved memory is released if reading fails or gives up, when the snapshot is dropped.
+    let mut snapshot = DirSnapshot {
+        entries: Vec::new(),
+        bytes: 0,
+        budget: budget.clone(),
+    };
+    let mut buf = Vec::with_capacity(SNAPSHOT_BUF_SIZE);
+    let mut offset = 0;
+    let chunk = points.chunks().snapshot_entries.max(1);
+    let mut checked = 0;
+
+    loop {
+        if snapshot.entries.len() >= checked + chunk {
+            points.check().errno_context("directory snapshot aborted")?;
+            checked = snapshot.entries.len();
+        }
+        buf.clear();
+        sys.getdents(dir, offset, &mut buf)?;
+        if buf.is_empty() {
+            return Ok(Some(snapshot));
+        }
+
+        let mut rem = &buf[..];
+        let mut bytes = 0;
+        while rem.len() >= size_of::<LinuxDirent64>() {
+            let (front, back) = rem.split_at(size_of::<LinuxDirent64>());
+            let dirent64 = LinuxDirent64::from_slice(front)
+                .expect("fuse: unable to get LinuxDirent64 from slice");
+            let reclen = dirent64.d_reclen as usize;
+            if reclen < size_of::<LinuxDirent64>() || reclen > rem.len() {
+                return Err(io::Error::from_raw_os_error(libc::EIO));
+            }
+            let name = &back[..reclen - size_of::<LinuxDirent64>()];
+            rem = &rem[reclen..];
+            offset = dirent64.d_off as u64;
+
+            if name.starts_with(CURRENT_DIR_CSTR) || name.starts_with(PARENT_DIR_CSTR) {
+                continue;
+            }
+            if snapshot.entries.len() >= max_entries {
+                budget.fall_back(dir, "too many entries");
+                return Ok(None);
+            }
+            let name = bytes_to_cstr(name)
+                .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?
+                .to_owned();
+            bytes += entry_size(&name);
+            snapshot.entries.push(SnapshotEntry {
+                ino: dirent64.d_ino,
+                type_: u32::from(dirent64.d_ty),
+                name,
+            });
+        }
+
+        if !budget.try_reserve(bytes) {
+            budget.fall_back(dir, "out of memory budget");
+            return Ok(None);
+        }
+        snapshot.bytes += bytes;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use std::sync::Mutex;
+
+    // A directory with entries "." and "..", followed by `names`.
+    struct MockDir {
+        names: Mutex<Vec<&'static str>>,
+    }
+
+    impl DirSyscalls for MockDir {
+        fn getdents(&self, _dir: RawFd, offset: u64, buf: &mut Vec<u8>) -> io::Result<()> {
+            let names = self.names.lock().unwrap();
+            let all = [".", ".."].iter().chain(names.iter());
+            // Return at most two entries per call.
+            for (off, name) in all.enumerate().skip(offset as usize).take(2) {
+                let mut name = name.as_bytes().to_vec();
+                name.resize((name.len() + 8) & !7, 0);
+                let dirent = LinuxDirent64 {
+                    d_ino: off as u64 + 10,
+                    d_off: off as i64 + 1,
+                    d_reclen: (size_of::<LinuxDirent64>() + name.len()) as u16,
+                    d_ty: libc::DT_REG,
+                };
+                buf.extend_from_slice(dirent.as_slice());
+                buf.extend_from_slice(&name);
+            }
+            Ok(())
+        }
+
+        fn entry_type(&self, _dir: RawFd, _name: &CStr) -> io::Result<u32> {
+            Ok(libc::DT_UNKNOWN as u32)
+        }
+    }
+
+    fn mock_dir(names: &[&'static str]) -> MockDir {
+        MockDir {
+            names: Mutex::new(names.to_vec()),
+        }
+    }
+
+    #[test]
+    fn test_take_snapshot() {
+        let budget = Arc::new(SnapshotBudget::new(4096));
+        let dir = mock_dir(&["a", "b", "c"]);
+        let snapshot = take_snapshot(&dir, 3, 16, &budget, &YieldPoints::default())
+            .unwrap()
+            .unwrap();
+        assert!(budget.used() > 0);
+
+        // Later changes of the directory aren't visible.
+        dir.names.lock().unwrap().push("d");
+        let names = |offset| {
+            snapshot
+                .entries_from(offset)
+                .map(|(off, e)| (off, e.name.to_str().unwrap().to_string()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(0),
+            vec![
+                (1, "a".to_string()),
+                (2, "b".to_string()),
+                (3, "c".to_string())
+            ]
+        );
+        assert_eq!(names(2), vec![(3, "c".to_string())]);
+        assert!(names(3).is_empty());
+        assert!(names(100).is_empty());
+        assert_eq!(snapshot.entries_from(0).next().unwrap().1.ino, 12);
+
+        drop(snapshot);
+        assert_eq!(budget.used(), 0);
+        assert_eq!(budget.fallbacks(), 0);
+    }
+
+    #[test]
+    fn test_take_snapshot_fallback() {
+        let dir = mock_dir(&["a", "b", "c", "d"]);
+
+        let budget = Arc::new(SnapshotBudget::new(4096));
+        assert!(take_snapshot(&dir, 3, 3, &budget, &YieldPoints::default())
+            .unwrap()
+            .is_none());
+        assert_eq!(budget.fallbacks(), 1);
+        assert_eq!(budget.used(), 0);
+
+        // Two entries fit, but not the next two.
+        let small = Arc::new(SnapshotBudget::new(2 * size_of::<SnapshotEntry>() + 4));
+        assert!(take_snapshot(&dir, 3, 16, &small, &YieldPoints::default())
+            .unwrap()
+            .is_none());
+        assert_eq!(small.fallbacks(), 1);
+        assert_eq!(small.used(), 0);
+
+        // Memory of snapshots is shared.
+        let budget = Arc::new(SnapshotBudget::new(4 * size_of::<SnapshotEntry>() + 8));
+        let snapshot = take_snapshot(&dir, 3, 16, &budget, &YieldPoints::default())
+            .unwrap()
+            .unwrap();
+        assert!(take_snapshot(&dir, 3, 16, &budget, &YieldPoints::default())
+            .unwrap()
+            .is_none());
+        drop(snapshot);
+        assert!(take_snapshot(&dir, 3, 16, &budget, &YieldPoints::default())
+            .unwrap()
+            .is_some());
+        assert_eq!(budget.fallbacks(), 1);
+    }
+
+    #[test]
+    fn test_take_snapshot_abort() {
+        use crate::api::errno::errno_of;
+        use crate::api::shedder::{DrainShedder, YieldChunks};
+
+        let budget = Arc::new(SnapshotBudget::new(4096));
+        let dir = mock_dir(&["a", "b", "c", "d", "e"]);
+        let shedder = Arc::new(DrainShedder::default());
+        let chunks = YieldChunks {
+            snapshot_entries: 2,
+            ..Default::default()
+        };
+        let points = YieldPoints::new(shedder.clone()).with_chunks(chunks);
+        assert!(take_snapshot(&dir, 3, 16, &budget, &points)
+            .unwrap()
+            .is_some());
+
+        // Reserved memory is released once aborted.
+        shedder.drain();
+        let err = take_snapshot(&dir, 3, 16, &budget, &points).err().unwrap();
+        assert_eq!(errno_of(&err), Some(libc::EINTR));
+        assert_eq!(budget.used(), 0);
+        assert_eq!(budget.fallbacks(), 0);
+
+        // Small directories never reach a yield point.
+        let dir = mock_dir(&["a"]);
+        assert!(take_snapshot(&dir, 3, 16, &budget, &points)
+            .unwrap()
+            .is_some());
+    }
+}
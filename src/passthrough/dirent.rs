@@ -0,0 +1,215 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Read directory entries, filling in types unknown to the host file system.
+//!
+//! Some host file systems, such as XFS without `ftype` and some network file systems, return
+//! `DT_UNKNOWN` as type of directory entries from getdents64(2). Guest programs relying on
+//! `d_type`, like `find -type f`, then misbehave or fall back to stat every entry. So types of
+//! such entries are queried by statx(2) with `STATX_TYPE`, at most `Config::dtype_fallback_budget`
+//! entries per readdir request to bound the cost for huge directories. Entries beyond the budget
+//! keep `DT_UNKNOWN`.
+//!
+//! With `FsOptions::READDIRPLUS_AUTO` the guest switches between readdir and readdirplus per
+//! directory, so both requests are served with buffers sized for their own reply entries, and
+//! counted to let operators evaluate the choices made by the guest.
+
+use std::ffi::CStr;
+use std::io;
+use std::mem::{size_of, MaybeUninit};
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::LinuxDirent64;
+use crate::abi::fuse_abi::EntryOut;
+
+// Estimated size of a host directory entry with a short name.
+const DIRENT64_ESTIMATE: usize = 64;
+// Large enough for a host directory entry with the longest name, getdents64(2) fails otherwise.
+const MIN_GETDENTS_BUFFER: usize = 512;
+
+/// Get the size of the buffer to read host directory entries for a reply of `size` bytes.
+///
+/// Entries of readdir replies are about the size of host entries, while readdirplus replies also
+/// carry the attributes of each entry, so fewer host entries are read for them. Entries read but
+/// not fitting in the reply would be read again by the next request.
+pub(super) fn getdents_buf_size(size: u32, plus: bool) -> usize {
+    let size = size as usize;
+    if !plus {
+        return size;
+    }
+    let scaled = size * DIRENT64_ESTIMATE / (DIRENT64_ESTIMATE + size_of::<EntryOut>());
+    scaled.max(MIN_GETDENTS_BUFFER).min(size)
+}
+
+/// Statistics of directory listing requests.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReaddirStats {
+    /// Number of readdir requests.
+    pub readdir: u64,
+    /// Number of readdirplus requests.
+    pub readdirplus: u64,
+}
+
+#[derive(Default)]
+pub(super) struct ReaddirCounters {
+    readdir: AtomicU64,
+    readdirplus: AtomicU64,
+}
+
+impl ReaddirCounters {
+    pub(super) fn count(&self, plus: bool) {
+        let counter = if plus {
+            &self.readdirplus
+        } else {
+            &self.readdir
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn stats(&self) -> ReaddirStats {
+        ReaddirStats {
+            readdir: self.readdir.load(Ordering::Relaxed),
+            readdirplus: self.readdirplus.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Syscalls used to read directory entries, abstracted for testing.
+pub(super) trait DirSyscalls: Send + Sync {
+    // Read entries of `dir` from `offset` into `buf`, filling at most `buf.capacity()` bytes.
+    fn getdents(&self, dir: RawFd, offset: u64, buf: &mut Vec<u8>) -> io::Result<()>;
+
+    // Return the `DT_*` type of entry `name` of `dir`.
+    fn entry_type(&self, dir: RawFd, name: &CStr) -> io::Result<u32>;
+}
+
+pub(super) struct LibcDirSyscalls;
+
+impl DirSyscalls for LibcDirSyscalls {
+    fn getdents(&self, dir: RawFd, offset: u64, buf: &mut Vec<u8>) -> io::Result<()> {
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe { libc::lseek64(dir, offset as libc::off64_t, libc::SEEK_SET) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safe because the kernel guarantees that it will only write to `buf` and we check the
+        // return value.
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_getdents64,
+                dir,
+                buf.as_mut_ptr() as *mut LinuxDirent64,
+                buf.capacity() as libc::c_int,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safe because we trust the value returned by kernel.
+        unsafe { buf.set_len(res as usize) };
+        Ok(())
+    }
+
+    fn entry_type(&self, dir: RawFd, name: &CStr) -> io::Result<u32> {
+        let mut stx = MaybeUninit::<libc::statx>::zeroed();
+
+        // Safe because the kernel will only write data in `stx` and we check the return value.
+        let res = unsafe {
+            libc::statx(
+                dir,
+                name.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+                libc::STATX_TYPE,
+                stx.as_mut_ptr(),
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safe because the kernel guarantees that the struct is now fully initialized.
+        let stx = unsafe { stx.assume_init() };
+        if stx.stx_mask & libc::STATX_TYPE == 0 {
+            return Ok(libc::DT_UNKNOWN as u32);
+        }
+        Ok(mode_to_dtype(stx.stx_mode as u32))
+    }
+}
+
+/// Convert the file type bits of `mode` to a `DT_*` directory entry type.
+pub(super) fn mode_to_dtype(mode: u32) -> u32 {
+    (mode & libc::S_IFMT) >> 12
+}
+
+/// Fill in unknown types of entries returned by a readdir request, within a budget.
+pub(super) struct TypeFallback<'a> {
+    sys: &'a dyn DirSyscalls,
+    budget: u32,
+}
+
+impl<'a> TypeFallback<'a> {
+    pub(super) fn new(sys: &'a dyn DirSyscalls, budget: u32) -> Self {
+        TypeFallback { sys, budget }
+    }
+
+    /// Return the real type of entry `name` of `dir` if `type_` is `DT_UNKNOWN` and the budget
+    /// isn't exhausted, otherwise return `type_`.
+    pub(super) fn resolve(&mut self, dir: RawFd, name: &CStr, type_: u32) -> u32 {
+        if type_ != libc::DT_UNKNOWN as u32 || self.budget == 0 {
+            return type_;
+        }
+        self.budget -= 1;
+        match self.sys.entry_type(dir, name) {
+            Ok(t) => t,
+            Err(e) => {
+                debug!("fuse: failed to query type of entry {:?}, {}", name, e);
+                type_
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mode_to_dtype() {
+        assert_eq!(mode_to_dtype(libc::S_IFREG | 0o644), libc::DT_REG as u32);
+        assert_eq!(mode_to_dtype(libc::S_IFDIR | 0o755), libc::DT_DIR as u32);
+        assert_eq!(mode_to_dtype(libc::S_IFLNK | 0o777), libc::DT_LNK as u32);
+        assert_eq!(mode_to_dtype(libc::S_IFIFO), libc::DT_FIFO as u32);
+        assert_eq!(mode_to_dtype(libc::S_IFSOCK), libc::DT_SOCK as u32);
+        assert_eq!(mode_to_dtype(libc::S_IFCHR), libc::DT_CHR as u32);
+        assert_eq!(mode_to_dtype(libc::S_IFBLK), libc::DT_BLK as u32);
+        assert_eq!(mode_to_dtype(0), libc::DT_UNKNOWN as u32);
+    }
+
+    #[test]
+    fn test_getdents_buf_size() {
+        assert_eq!(getdents_buf_size(4096, false), 4096);
+        assert_eq!(getdents_buf_size(100, false), 100);
+        assert_eq!(getdents_buf_size(4096, true), 1365);
+        assert_eq!(getdents_buf_size(1 << 20, true), (1 << 20) / 3);
+        assert_eq!(getdents_buf_size(1024, true), MIN_GETDENTS_BUFFER);
+        assert_eq!(getdents_buf_size(100, true), 100);
+    }
+
+    #[test]
+    fn test_readdir_counters() {
+        let counters = ReaddirCounters::default();
+        counters.count(false);
+        counters.count(true);
+        counters.count(true);
+        assert_eq!(
+            counters.stats(),
+            ReaddirStats {
+                readdir: 1,
+                readdirplus: 2,
+            }
+        );
+    }
+}
@@ -0,0 +1,625 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Validation of fallocate(2) modes, and tracking of modes unsupported by backing file systems.
+//!
+//! Modes of `FUSE_FALLOCATE` requests are checked by the rules of `vfs_fallocate()` in the Linux
+//! kernel before reaching the host, so invalid combinations, like `FALLOC_FL_COLLAPSE_RANGE` with
+//! `FALLOC_FL_KEEP_SIZE`, fail the same way whatever the backing file system is. The only
+//! exception is `FALLOC_FL_PUNCH_HOLE` without `FALLOC_FL_KEEP_SIZE`, which is forbidden by the
+//! protocol and fails with `EINVAL` rather than `EOPNOTSUPP`, so guests don't take it for a mode
+//! the backing file system lacks.
+//!
+//! Backing file systems report unsupported modes inconsistently, with `EOPNOTSUPP` or `ENOSYS`,
+//! so such errors are replied to the guest as `EOPNOTSUPP`. `ENOSYS` means the file system lacks
+//! the operation altogether, so the first time a mode fails with it, it's remembered for the
+//! backing device, and later requests with the mode fail without issuing the syscall.
+//! `EOPNOTSUPP` may depend on the file, like a mode unsupported for some file types or flags only,
+//! so it's not remembered.
+//!
+//! With `Config::emulate_fallocate`, unsupported punch hole and zero range requests are emulated
+//! by writing zeros over the part of the range inside the file, and extending the file when the
+//! size isn't kept. Collapse and insert range move data around, which can't be emulated safely,
+//! so they keep failing with `EOPNOTSUPP`. Zeros are written through a new fd of the file rather
+//! than the fd of the request, which may be opened with `O_APPEND`, making pwrite(2) append them
+//! whatever the offset.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+// Modes known by the kernel, FALLOC_FL_SUPPORTED_MASK.
+const SUPPORTED_MODES: i32 = libc::FALLOC_FL_KEEP_SIZE
+    | libc::FALLOC_FL_PUNCH_HOLE
+    | libc::FALLOC_FL_COLLAPSE_RANGE
+    | libc::FALLOC_FL_ZERO_RANGE
+    | libc::FALLOC_FL_INSERT_RANGE
+    | libc::FALLOC_FL_UNSHARE_RANGE;
+
+// Operation bit of plain allocation, which has no mode flag.
+const ALLOCATE_OP: u32 = 1 << 31;
+
+// Size of the chunks of zeros written by the emulation.
+const ZERO_CHUNK: usize = 64 << 10;
+
+static ZEROES: [u8; ZERO_CHUNK] = [0; ZERO_CHUNK];
+
+// Syscalls used to preallocate space, abstracted for testing.
+pub(super) trait FallocateSyscalls: Send + Sync {
+    fn fallocate(&self, fd: RawFd, mode: i32, offset: i64, length: i64) -> io::Result<()>;
+
+    // Return the device of the file system `fd` belongs to.
+    fn device(&self, fd: RawFd) -> io::Result<libc::dev_t>;
+
+    // Return the size of the file `fd`.
+    fn size(&self, fd: RawFd) -> io::Result<u64>;
+
+    fn pwrite(&self, fd: RawFd, buf: &[u8], offset: i64) -> io::Result<usize>;
+
+    fn ftruncate(&self, fd: RawFd, size: i64) -> io::Result<()>;
+}
+
+fn fstat(fd: RawFd) -> io::Result<libc::stat64> {
+    let mut st = MaybeUninit::<libc::stat64>::zeroed();
+
+    // Safe because the kernel will only write data in `st` and we check the return value.
+    let res = unsafe { libc::fstat64(fd, st.as_mut_ptr()) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safe because the kernel guarantees that the struct is now fully initialized.
+    Ok(unsafe { st.assume_init() })
+}
+
+pub(super) struct LibcFallocateSyscalls;
+
+impl FallocateSyscalls for LibcFallocateSyscalls {
+    fn fallocate(&self, fd: RawFd, mode: i32, offset: i64, length: i64) -> io::Result<()> {
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe { libc::fallocate64(fd, mode, offset, length) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn device(&self, fd: RawFd) -> io::Result<libc::dev_t> {
+        Ok(fstat(fd)?.st_dev)
+    }
+
+    fn size(&self, fd: RawFd) -> io::Result<u64> {
+        Ok(fstat(fd)?.st_size as u64)
+    }
+
+    fn pwrite(&self, fd: RawFd, buf: &[u8], offset: i64) -> io::Result<usize> {
+        // Safe because this only reads `buf` and we check the return value.
+        let res =
+            unsafe { libc::pwrite64(fd, buf.as_ptr() as *const libc::c_void, buf.len(), offset) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(res as usize)
+    }
+
+    fn ftruncate(&self, fd: RawFd, size: i64) -> io::Result<()> {
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe { libc::ftruncate64(fd, size) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Check `mode`, `offset` and `length` of a fallocate request like `vfs_fallocate()`.
+pub(super) fn validate_mode(mode: u32, offset: u64, length: u64) -> io::Result<()> {
+    let err = |errno| Err(io::Error::from_raw_os_error(errno));
+    let mode = mode as i32;
+
+    if offset > i64::MAX as u64 || length == 0 || length > i64::MAX as u64 {
+        return err(libc::EINVAL);
+    }
+    if mode & !SUPPORTED_MODES != 0 {
+        return err(libc::EOPNOTSUPP);
+    }
+    // Punch hole and zero range are mutually exclusive.
+    let punch_zero = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_ZERO_RANGE;
+    if mode & punch_zero == punch_zero {
+        return err(libc::EOPNOTSUPP);
+    }
+    // Punch hole must have keep size set.
+    if mode & libc::FALLOC_FL_PUNCH_HOLE != 0 && mode & libc::FALLOC_FL_KEEP_SIZE == 0 {
+        return err(libc::EINVAL);
+    }
+    // Collapse range and insert range should only be used exclusively.
+    for op in [libc::FALLOC_FL_COLLAPSE_RANGE, libc::FALLOC_FL_INSERT_RANGE] {
+        if mode & op != 0 && mode & !op != 0 {
+            return err(libc::EINVAL);
+        }
+    }
+    // Unshare range should only be used with allocate mode.
+    let unshare = libc::FALLOC_FL_UNSHARE_RANGE;
+    if mode & unshare != 0 && mode & !(unshare | libc::FALLOC_FL_KEEP_SIZE) != 0 {
+        return err(libc::EINVAL);
+    }
+    if offset + length > i64::MAX as u64 {
+        return err(libc::EFBIG);
+    }
+
+    Ok(())
+}
+
+// Get the operation of a valid `mode`, keeping the size or not doesn't matter.
+fn operation(mode: u32) -> u32 {
+    match mode & !(libc::FALLOC_FL_KEEP_SIZE as u32) {
+        0 => ALLOCATE_OP,
+        op => op,
+    }
+}
+
+// Check whether the operation of a valid `mode` is emulated by writing zeros.
+fn is_emulated(mode: u32) -> bool {
+    let op = operation(mode) as i32;
+    op == libc::FALLOC_FL_PUNCH_HOLE || op == libc::FALLOC_FL_ZERO_RANGE
+}
+
+// Check whether `err` means the backing file system doesn't support the requested mode.
+fn is_unsupported(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS)
+    )
+}
+
+/// Preallocate space of backing files, with modes validated and unsupported modes remembered.
+pub(super) struct FallocHelper {
+    sys: Box<dyn FallocateSyscalls>,
+    // Set once some mode is found unsupported, to skip looking up the device otherwise.
+    probed: AtomicBool,
+    // Operations found unsupported by ENOSYS for each backing device.
+    unsupported: Mutex<HashMap<libc::dev_t, u32>>,
+    // Whether unsupported punch hole and zero range requests are emulated.
+    emulate: bool,
+}
+
+impl FallocHelper {
+    pub(super) fn new(emulate: bool) -> Self {
+        Self::with_syscalls(Box::new(LibcFallocateSyscalls), emulate)
+    }
+
+    pub(super) fn with_syscalls(sys: Box<dyn FallocateSyscalls>, emulate: bool) -> Self {
+        FallocHelper {
+            sys,
+            probed: AtomicBool::new(false),
+            unsupported: Mutex::new(HashMap::new()),
+            emulate,
+        }
+    }
+
+    /// Preallocate or deallocate `length` bytes at `offset` of `fd` as requested by `mode`.
+    ///
+    /// When emulated, zeros are written to the file returned by `reopen`, a writable fd of the
+    /// same file opened without `O_APPEND`.
+    pub(super) fn fallocate(
+        &self,
+        fd: RawFd,
+        mode: u32,
+        offset: u64,
+        length: u64,
+        reopen: impl FnOnce() -> io::Result<File>,
+    ) -> io::Result<()> {
+        validate_mode(mode, offset, length)?;
+
+        match self.allocate(fd, mode, offset, length) {
+            Err(e)
+                if self.emulate
+                    && is_emulated(mode)
+                    && e.raw_os_error() == Some(libc::EOPNOTSUPP) =>
+            {
+                let file = reopen()?;
+                self.write_zeroes(file.as_raw_fd(), mode, offset, length)
+            }
+            res => res,
+        }
+    }
+
+    // Issue fallocate(2) with a valid `mode`, unless the backing device is known to lack it.
+    fn allocate(&self, fd: RawFd, mode: u32, offset: u64, length: u64) -> io::Result<()> {
+        let op = operation(mode);
+        let mut dev = None;
+        if self.probed.load(Ordering::Acquire) {
+            let d = self.sys.device(fd)?;
+            let unsupported = self.unsupported.lock().unwrap();
+            if unsupported.get(&d).is_some_and(|ops| ops & op != 0) {
+                return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+            }
+            dev = Some(d);
+        }
+
+        match self
+            .sys
+            .fallocate(fd, mode as i32, offset as i64, length as i64)
+        {
+            Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => {
+                let dev = match dev {
+                    Some(d) => d,
+                    None => self.sys.device(fd)?,
+                };
+                info!(
+                    "fuse: fallocate mode {:#x} is not supported by device {}",
+                    mode, dev
+                );
+                *self.unsupported.lock().unwrap().entry(dev).or_insert(0) |= op;
+                self.probed.store(true, Ordering::Release);
+                Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+            }
+            Err(e) if is_unsupported(&e) => Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP)),
+            res => res,
+        }
+    }
+
+    // Emulate punch hole or zero range by writing zeros. A range past the end of file already
+    // reads as zeros, so it's only covered by extending the file when the size isn't kept.
+    fn write_zeroes(&self, fd: RawFd, mode: u32, offset: u64, length: u64) -> io::Result<()> {
+        let size = self.sys.size(fd)?;
+        let end = offset + length;
+        let mut pos = offset;
+        while pos < cmp::min(end, size) {
+            let len = cmp::min(cmp::min(end, size) - pos, ZERO_CHUNK as u64) as usize;
+            match self.sys.pwrite(fd, &ZEROES[..len], pos as i64) {
+                Ok(0) => return Err(io::Error::from_raw_os_error(libc::EIO)),
+                Ok(count) => pos += count as u64,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if mode & libc::FALLOC_FL_KEEP_SIZE as u32 == 0 && end > size {
+            self.sys.ftruncate(fd, end as i64)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abi::fuse_abi::ROOT_ID;
+    use crate::api::filesystem::{Context, FileSystem};
+    use crate::passthrough::tests::prepare_passthroughfs_with;
+    use std::ffi::CString;
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+    use std::sync::Arc;
+    use vmm_sys_util::{tempdir::TempDir, tempfile::TempFile};
+
+    use libc::{
+        FALLOC_FL_COLLAPSE_RANGE as COLLAPSE, FALLOC_FL_INSERT_RANGE as INSERT,
+        FALLOC_FL_KEEP_SIZE as KEEP_SIZE, FALLOC_FL_PUNCH_HOLE as PUNCH,
+        FALLOC_FL_UNSHARE_RANGE as UNSHARE, FALLOC_FL_ZERO_RANGE as ZERO,
+    };
+
+    // Syscalls of a file system on device `fd`, which fails modes of `unsupported` with `errno`.
+    struct MockSyscalls {
+        unsupported: i32,
+        errno: i32,
+        calls: Arc<Mutex<Vec<(RawFd, i32)>>>,
+    }
+
+    impl FallocateSyscalls for MockSyscalls {
+        fn fallocate(&self, fd: RawFd, mode: i32, _: i64, _: i64) -> io::Result<()> {
+            self.calls.lock().unwrap().push((fd, mode));
+            if fd == 1 && mode & self.unsupported != 0 {
+                return Err(io::Error::from_raw_os_error(self.errno));
+            }
+            Ok(())
+        }
+
+        fn device(&self, fd: RawFd) -> io::Result<libc::dev_t> {
+            Ok(fd as libc::dev_t)
+        }
+
+        fn size(&self, _: RawFd) -> io::Result<u64> {
+            Ok(0)
+        }
+
+        fn pwrite(&self, _: RawFd, buf: &[u8], _: i64) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn ftruncate(&self, _: RawFd, _: i64) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Syscalls of the host, failing modes of `unsupported` with `EOPNOTSUPP` like file systems
+    // lacking them.
+    struct UnsupportedSyscalls {
+        unsupported: i32,
+    }
+
+    impl FallocateSyscalls for UnsupportedSyscalls {
+        fn fallocate(&self, fd: RawFd, mode: i32, offset: i64, length: i64) -> io::Result<()> {
+            if mode & self.unsupported != 0 {
+                return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+            }
+            LibcFallocateSyscalls.fallocate(fd, mode, offset, length)
+        }
+
+        fn device(&self, fd: RawFd) -> io::Result<libc::dev_t> {
+            LibcFallocateSyscalls.device(fd)
+        }
+
+        fn size(&self, fd: RawFd) -> io::Result<u64> {
+            LibcFallocateSyscalls.size(fd)
+        }
+
+        fn pwrite(&self, fd: RawFd, buf: &[u8], offset: i64) -> io::Result<usize> {
+            LibcFallocateSyscalls.pwrite(fd, buf, offset)
+        }
+
+        fn ftruncate(&self, fd: RawFd, size: i64) -> io::Result<()> {
+            LibcFallocateSyscalls.ftruncate(fd, size)
+        }
+    }
+
+    // Reopen of requests never emulated.
+    fn no_reopen() -> io::Result<File> {
+        panic!("fallocate unexpectedly emulated");
+    }
+
+    // Reopen `file` for writing, like `PassthroughFs` does for the emulation.
+    fn reopen(file: &File) -> io::Result<File> {
+        fs::OpenOptions::new()
+            .write(true)
+            .open(format!("/proc/self/fd/{}", file.as_raw_fd()))
+    }
+
+    fn errno(mode: i32) -> Option<i32> {
+        validate_mode(mode as u32, 0, 4096)
+            .err()
+            .and_then(|e| e.raw_os_error())
+    }
+
+    #[test]
+    fn test_validate_mode() {
+        for mode in [
+            0,
+            KEEP_SIZE,
+            PUNCH | KEEP_SIZE,
+            ZERO,
+            ZERO | KEEP_SIZE,
+            COLLAPSE,
+            INSERT,
+            UNSHARE,
+            UNSHARE | KEEP_SIZE,
+        ] {
+            assert_eq!(errno(mode), None, "mode {:#x}", mode);
+        }
+
+        assert_eq!(errno(0x100), Some(libc::EOPNOTSUPP));
+        assert_eq!(errno(PUNCH), Some(libc::EINVAL));
+        assert_eq!(errno(PUNCH | ZERO | KEEP_SIZE), Some(libc::EOPNOTSUPP));
+        assert_eq!(errno(COLLAPSE | KEEP_SIZE), Some(libc::EINVAL));
+        assert_eq!(errno(COLLAPSE | PUNCH | KEEP_SIZE), Some(libc::EINVAL));
+        assert_eq!(errno(INSERT | KEEP_SIZE), Some(libc::EINVAL));
+        assert_eq!(errno(INSERT | COLLAPSE), Some(libc::EINVAL));
+        assert_eq!(errno(UNSHARE | ZERO), Some(libc::EINVAL));
+
+        let errno = |offset, length| {
+            validate_mode(0, offset, length)
+                .err()
+                .and_then(|e| e.raw_os_error())
+        };
+        assert_eq!(errno(0, 0), Some(libc::EINVAL));
+        assert_eq!(errno(1 << 63, 1), Some(libc::EINVAL));
+        assert_eq!(errno(0, 1 << 63), Some(libc::EINVAL));
+        assert_eq!(errno(i64::MAX as u64, 1), Some(libc::EFBIG));
+        assert_eq!(errno(i64::MAX as u64 - 1, 1), None);
+    }
+
+    #[test]
+    fn test_fallocate_unsupported() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let helper = FallocHelper::with_syscalls(
+            Box::new(MockSyscalls {
+                unsupported: COLLAPSE | INSERT,
+                errno: libc::ENOSYS,
+                calls: calls.clone(),
+            }),
+            false,
+        );
+        let fallocate = |fd, mode: i32| {
+            helper
+                .fallocate(fd, mode as u32, 0, 4096, no_reopen)
+                .err()
+                .and_then(|e| e.raw_os_error())
+        };
+
+        // Invalid modes never reach the backing file system.
+        assert_eq!(fallocate(1, COLLAPSE | KEEP_SIZE), Some(libc::EINVAL));
+        assert!(calls.lock().unwrap().is_empty());
+
+        // Unsupported modes are normalized, and remembered per device.
+        assert_eq!(fallocate(1, COLLAPSE), Some(libc::EOPNOTSUPP));
+        assert_eq!(fallocate(1, COLLAPSE), Some(libc::EOPNOTSUPP));
+        assert_eq!(fallocate(1, PUNCH | KEEP_SIZE), None);
+        assert_eq!(fallocate(2, COLLAPSE), None);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![(1, COLLAPSE), (1, PUNCH | KEEP_SIZE), (2, COLLAPSE)]
+        );
+
+        // Insert range hasn't been tried yet.
+        assert_eq!(fallocate(1, INSERT), Some(libc::EOPNOTSUPP));
+        assert_eq!(calls.lock().unwrap().len(), 4);
+        assert_eq!(fallocate(1, INSERT), Some(libc::EOPNOTSUPP));
+        assert_eq!(calls.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_fallocate_eopnotsupp() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let helper = FallocHelper::with_syscalls(
+            Box::new(MockSyscalls {
+                unsupported: COLLAPSE,
+                errno: libc::EOPNOTSUPP,
+                calls: calls.clone(),
+            }),
+            false,
+        );
+
+        // EOPNOTSUPP of a file says nothing about other files of the device, so it's not
+        // remembered.
+        for _ in 0..2 {
+            let err = helper
+                .fallocate(1, COLLAPSE as u32, 0, 4096, no_reopen)
+                .unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP));
+        }
+        assert_eq!(calls.lock().unwrap().len(), 2);
+        assert!(helper.unsupported.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_fallocate_errors() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let helper = FallocHelper::with_syscalls(
+            Box::new(MockSyscalls {
+                unsupported: ZERO,
+                errno: libc::ENOSPC,
+                calls: calls.clone(),
+            }),
+            true,
+        );
+
+        // Other errors are passed through, and not remembered, nor emulated.
+        for _ in 0..2 {
+            let err = helper
+                .fallocate(1, ZERO as u32, 0, 4096, no_reopen)
+                .unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+        }
+        assert_eq!(calls.lock().unwrap().len(), 2);
+    }
+
+    // Fill a file of `size` bytes in `dir` with ones, and apply `mode` to 8 KiB at 4 KiB with
+    // `helper`, returning the contents.
+    fn fallocate_file(dir: &Path, helper: &FallocHelper, mode: i32, size: usize) -> Vec<u8> {
+        let file = TempFile::new_in(dir).unwrap().into_file();
+        (&file).write_all(&vec![1u8; size]).unwrap();
+        helper
+            .fallocate(file.as_raw_fd(), mode as u32, 4096, 8192, || reopen(&file))
+            .unwrap();
+        fs::read(format!("/proc/self/fd/{}", file.as_raw_fd())).unwrap()
+    }
+
+    #[test]
+    fn test_fallocate_emulation() {
+        let mut dirs = vec![TempDir::new().unwrap()];
+        if let Ok(shm) = TempDir::new_in(Path::new("/dev/shm")) {
+            dirs.push(shm);
+        }
+        let native = FallocHelper::new(true);
+        let emulated = FallocHelper::with_syscalls(
+            Box::new(UnsupportedSyscalls {
+                unsupported: PUNCH | ZERO,
+            }),
+            true,
+        );
+
+        for dir in dirs.iter() {
+            let dir = dir.as_path();
+            for mode in [PUNCH | KEEP_SIZE, ZERO, ZERO | KEEP_SIZE] {
+                for size in [16384, 6000] {
+                    // The file is zeroed from 4 KiB to the end of the range or the file.
+                    let mut expected = vec![1u8; size];
+                    expected[4096..].iter_mut().take(8192).for_each(|b| *b = 0);
+                    if mode & KEEP_SIZE == 0 {
+                        expected.resize(cmp::max(size, 12288), 0);
+                    }
+
+                    // Tmpfs has no zero range, which is emulated by the native helper as well.
+                    let data = fallocate_file(dir, &native, mode, size);
+                    assert_eq!(data, expected, "mode {:#x} size {}", mode, size);
+                    let data = fallocate_file(dir, &emulated, mode, size);
+                    assert_eq!(data, expected, "mode {:#x} size {}", mode, size);
+                }
+            }
+        }
+
+        // Modes moving data are never emulated.
+        let helper = FallocHelper::with_syscalls(
+            Box::new(UnsupportedSyscalls {
+                unsupported: COLLAPSE | INSERT,
+            }),
+            true,
+        );
+        let file = TempFile::new().unwrap().into_file();
+        (&file).write_all(&[1u8; 16384]).unwrap();
+        for mode in [COLLAPSE, INSERT] {
+            let err = helper
+                .fallocate(file.as_raw_fd(), mode as u32, 4096, 4096, no_reopen)
+                .unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP));
+        }
+        assert_eq!(file.metadata().unwrap().len(), 16384);
+
+        // Unsupported modes aren't emulated unless configured.
+        let helper = FallocHelper::with_syscalls(
+            Box::new(UnsupportedSyscalls { unsupported: PUNCH }),
+            false,
+        );
+        let err = helper
+            .fallocate(
+                file.as_raw_fd(),
+                (PUNCH | KEEP_SIZE) as u32,
+                0,
+                4096,
+                no_reopen,
+            )
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP));
+    }
+
+    #[test]
+    fn test_fallocate_emulation_append() {
+        let (source, mut pfs) = prepare_passthroughfs_with(|cfg| cfg.emulate_fallocate = true);
+        pfs.falloc_helper = FallocHelper::with_syscalls(
+            Box::new(UnsupportedSyscalls {
+                unsupported: PUNCH | ZERO,
+            }),
+            true,
+        );
+        let path = source.as_path().join("f");
+        let ctx = Context::default();
+
+        for (mode, size, len) in [(PUNCH | KEEP_SIZE, 16384, 16384), (ZERO, 6000, 12288)] {
+            fs::write(&path, vec![1u8; size]).unwrap();
+            let inode = pfs
+                .lookup(&ctx, ROOT_ID, &CString::new("f").unwrap())
+                .unwrap()
+                .inode;
+            // Zeros written through the handle itself would be appended to the file.
+            let flags = libc::O_WRONLY | libc::O_APPEND;
+            let (handle, _) = pfs.open(&ctx, inode, flags as u32, 0).unwrap();
+            let handle = handle.unwrap();
+            pfs.fallocate(&ctx, inode, handle, mode as u32, 4096, 8192)
+                .unwrap();
+            pfs.release(&ctx, inode, 0, handle, false, false, None)
+                .unwrap();
+
+            let mut expected = vec![1u8; size];
+            expected.resize(len, 0);
+            expected[4096..].iter_mut().take(8192).for_each(|b| *b = 0);
+            assert_eq!(fs::read(&path).unwrap(), expected, "mode {:#x}", mode);
+        }
+    }
+}
@@ -150,7 +150,12 @@ impl FileHandle {
         F: FnOnce(RawFd, libc::c_int, u32) -> io::Result<File>,
     {
         let handle = Self::from_name_at(dir_fd, path).map_err(|e| {
-            error!("from_name_at failed error {:?}", e);
+            // File systems without file handles are expected, their inodes keep `O_PATH` fds.
+            if e.raw_os_error() == Some(libc::EOPNOTSUPP) {
+                debug!("from_name_at failed error {:?}", e);
+            } else {
+                error!("from_name_at failed error {:?}", e);
+            }
             e
         })?;
 
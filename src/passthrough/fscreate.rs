@@ -0,0 +1,249 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! SELinux labeling of inodes created on the host.
+//!
+//! On hosts with SELinux, files created by the daemon get labeled with the daemon's context,
+//! which breaks shared directories also accessed by host services expecting specific labels.
+//! When `Config::host_setfscreate` is enabled, the context configured for the parent directory
+//! is written to `/proc/self/task/<tid>/attr/fscreate` before creating an inode, so the kernel
+//! labels the new inode atomically, and the attribute is reset afterwards. The attribute is per
+//! thread, so concurrent requests served by other threads are not affected.
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+
+use super::sandbox::proc_self_path;
+use super::*;
+
+/// Writer of the SELinux fscreate attribute of threads.
+pub trait FsCreateWriter: Send + Sync {
+    /// Whether SELinux is enabled on the host.
+    fn enabled(&self) -> bool;
+
+    /// Write `context` to the fscreate attribute of thread `tid`, `None` resets the attribute.
+    fn write(&self, tid: libc::pid_t, context: Option<&CStr>) -> io::Result<()>;
+}
+
+/// [FsCreateWriter] writing to `/proc/self/task/<tid>/attr/fscreate`.
+pub struct ProcFsCreateWriter {
+    enabled: bool,
+}
+
+impl Default for ProcFsCreateWriter {
+    fn default() -> Self {
+        ProcFsCreateWriter {
+            enabled: Path::new("/sys/fs/selinux/enforce").exists(),
+        }
+    }
+}
+
+impl FsCreateWriter for ProcFsCreateWriter {
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn write(&self, tid: libc::pid_t, context: Option<&CStr>) -> io::Result<()> {
+        let path = proc_self_path(format_args!("task/{}/attr/fscreate", tid));
+        let mut f = OpenOptions::new().write(true).open(path)?;
+        // An empty write resets the attribute, as done by libselinux.
+        let buf = context.map(|c| c.to_bytes_with_nul()).unwrap_or(&[]);
+        f.write(buf).map(|_| ())
+    }
+}
+
+// Reset the fscreate attribute of the thread when dropped.
+pub(super) struct FsCreateGuard<'a> {
+    writer: &'a dyn FsCreateWriter,
+    tid: libc::pid_t,
+}
+
+impl Drop for FsCreateGuard<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.writer.write(self.tid, None) {
+            error!("fuse: failed to reset fscreate context: {}", e);
+        }
+    }
+}
+
+fn gettid() -> libc::pid_t {
+    // Safe because this doesn't modify any memory and always succeeds.
+    unsafe { libc::syscall(libc::SYS_gettid) as libc::pid_t }
+}
+
+// Find the context of the longest directory prefix of `path` in `labels`.
+fn find_label<'a>(labels: &'a [(String, String)], path: &Path) -> Option<&'a str> {
+    labels
+        .iter()
+        .filter(|(dir, _)| path.starts_with(dir))
+        .max_by_key(|(dir, _)| Path::new(dir).components().count())
+        .map(|(_, context)| context.as_str())
+}
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    /// Write the SELinux fscreate attribute with `writer`, instead of the procfs writer.
+    pub fn with_fscreate_writer(mut self, writer: Arc<dyn FsCreateWriter>) -> Self {
+        self.fscreate_writer = writer;
+        self
+    }
+
+    // Set the fscreate attribute of the current thread to the context configured for directory
+    // `dir`, before creating an inode in it. The attribute is reset when the guard is dropped.
+    pub(super) fn set_fscreate(&self, dir: &impl AsRawFd) -> io::Result<Option<FsCreateGuard<'_>>> {
+        if !self.cfg.host_setfscreate
+            || self.cfg.fscreate_labels.is_empty()
+            || !self.fscreate_writer.enabled()
+        {
+            return Ok(None);
+        }
+
+        let pathname = CString::new(format!("{}", dir.as_raw_fd()))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let path = Self::readlinkat(self.proc_self_fd()?, &pathname)?;
+        let context = match find_label(&self.cfg.fscreate_labels, &path) {
+            Some(c) => CString::new(c).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?,
+            None => return Ok(None),
+        };
+
+        let tid = gettid();
+        self.fscreate_writer.write(tid, Some(&context))?;
+        Ok(Some(FsCreateGuard {
+            writer: self.fscreate_writer.as_ref(),
+            tid,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::filesystem::{Context, FileSystem};
+    use crate::passthrough::tests::passthroughfs_in;
+    use std::sync::Mutex;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[derive(Default)]
+    struct MockWriter {
+        disabled: bool,
+        writes: Mutex<Vec<(libc::pid_t, Option<CString>)>>,
+    }
+
+    impl FsCreateWriter for MockWriter {
+        fn enabled(&self) -> bool {
+            !self.disabled
+        }
+
+        fn write(&self, tid: libc::pid_t, context: Option<&CStr>) -> io::Result<()> {
+            self.writes
+                .lock()
+                .unwrap()
+                .push((tid, context.map(CStr::to_owned)));
+            Ok(())
+        }
+    }
+
+    fn prepare_fs(source: &TempDir, writer: Arc<MockWriter>) -> PassthroughFs {
+        let root = source.as_path().to_str().unwrap().to_string();
+        std::fs::create_dir(source.as_path().join("web")).unwrap();
+        passthroughfs_in(source.as_path(), |cfg| {
+            cfg.host_setfscreate = true;
+            cfg.fscreate_labels = vec![
+                (
+                    root.clone(),
+                    "system_u:object_r:container_file_t:s0".to_string(),
+                ),
+                (
+                    format!("{}/web", root),
+                    "system_u:object_r:httpd_sys_content_t:s0".to_string(),
+                ),
+            ];
+        })
+        .with_fscreate_writer(writer)
+    }
+
+    #[test]
+    fn test_find_label() {
+        let labels = vec![
+            ("/a".to_string(), "a".to_string()),
+            ("/a/b/c".to_string(), "c".to_string()),
+            ("/a/b".to_string(), "b".to_string()),
+        ];
+        assert_eq!(find_label(&labels, Path::new("/a/b/c/d")), Some("c"));
+        assert_eq!(find_label(&labels, Path::new("/a/b")), Some("b"));
+        assert_eq!(find_label(&labels, Path::new("/a/bc")), Some("a"));
+        assert_eq!(find_label(&labels, Path::new("/x")), None);
+    }
+
+    #[test]
+    fn test_set_fscreate_sequence() {
+        let source = TempDir::new().unwrap();
+        let writer = Arc::new(MockWriter::default());
+        let fs = prepare_fs(&source, writer.clone());
+        let ctx = Context::default();
+
+        let web = fs
+            .lookup(&ctx, fuse::ROOT_ID, &CString::new("web").unwrap())
+            .unwrap();
+        fs.mkdir(&ctx, web.inode, &CString::new("d").unwrap(), 0o755, 0)
+            .unwrap();
+        fs.symlink(
+            &ctx,
+            &CString::new("d").unwrap(),
+            fuse::ROOT_ID,
+            &CString::new("l").unwrap(),
+        )
+        .unwrap();
+
+        let tid = gettid();
+        let httpd = CString::new("system_u:object_r:httpd_sys_content_t:s0").unwrap();
+        let container = CString::new("system_u:object_r:container_file_t:s0").unwrap();
+        assert_eq!(
+            *writer.writes.lock().unwrap(),
+            vec![
+                (tid, Some(httpd)),
+                (tid, None),
+                (tid, Some(container)),
+                (tid, None),
+            ]
+        );
+
+        // Each thread writes its own attribute.
+        let fs = Arc::new(fs);
+        let fs2 = fs.clone();
+        let tid2 = std::thread::spawn(move || {
+            fs2.mknod(
+                &Context::default(),
+                fuse::ROOT_ID,
+                &CString::new("f").unwrap(),
+                libc::S_IFREG | 0o644,
+                0,
+                0,
+            )
+            .unwrap();
+            gettid()
+        })
+        .join()
+        .unwrap();
+        assert_ne!(tid, tid2);
+        let writes = writer.writes.lock().unwrap();
+        assert_eq!(writes.len(), 6);
+        assert_eq!(writes[4].0, tid2);
+        assert_eq!(writes[5], (tid2, None));
+    }
+
+    #[test]
+    fn test_set_fscreate_disabled() {
+        let source = TempDir::new().unwrap();
+        let writer = Arc::new(MockWriter {
+            disabled: true,
+            ..Default::default()
+        });
+        let fs = prepare_fs(&source, writer.clone());
+        let ctx = Context::default();
+
+        fs.mkdir(&ctx, fuse::ROOT_ID, &CString::new("d").unwrap(), 0o755, 0)
+            .unwrap();
+        assert!(writer.writes.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,300 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Passthrough of the `FS_IOC_FSGETXATTR` and `FS_IOC_FSSETXATTR` ioctls, and of the
+//! `FS_IOC_GETFLAGS` and `FS_IOC_SETFLAGS` ioctls.
+//!
+//! Backup and quota tooling in guests reads and sets project ids and inode flags with the first
+//! ioctl pair, `lsattr` and `chattr` use the second one. The request is forwarded to the fd of the
+//! handle, or to the inode reopened read-only when the ioctl isn't issued on an open handle.
+//! Changes by the guest are checked against a [FsxattrPolicy] or a mask of inode flags first, so
+//! guests can't set flags like `FS_XFLAG_IMMUTABLE` on the host unless allowed.
+//!
+//! `struct fsxattr` only has fixed size fields and no padding, its layout is the same for 32-bit
+//! and 64-bit guests, so the ioctl numbers are the same too and the struct is passed unchanged.
+//! `FS_IOC_GETFLAGS` and `FS_IOC_SETFLAGS` are declared with a `long` argument, so 32-bit guests
+//! use other numbers, but the kernel always passes an `int`.
+
+use std::mem;
+
+use super::*;
+use crate::api::filesystem::IoctlReply;
+
+/// Immutable file flag of `struct fsxattr`.
+pub const FS_XFLAG_IMMUTABLE: u32 = 0x8;
+/// Append only file flag of `struct fsxattr`.
+pub const FS_XFLAG_APPEND: u32 = 0x10;
+/// Immutable file flag of `FS_IOC_SETFLAGS`.
+pub const FS_IMMUTABLE_FL: u32 = 0x10;
+/// Append only file flag of `FS_IOC_SETFLAGS`.
+pub const FS_APPEND_FL: u32 = 0x20;
+
+// struct fsxattr from <linux/fs.h>.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Fsxattr {
+    pub(super) fsx_xflags: u32,
+    pub(super) fsx_extsize: u32,
+    pub(super) fsx_nextents: u32,
+    pub(super) fsx_projid: u32,
+    pub(super) fsx_cowextsize: u32,
+    pub(super) fsx_pad: [u8; 8],
+}
+
+// Safe because Fsxattr only contains plain data.
+unsafe impl ByteValued for Fsxattr {}
+
+// _IOR('X', 31, struct fsxattr)
+pub(super) const FS_IOC_FSGETXATTR: u32 = 0x801c_581f;
+// _IOW('X', 32, struct fsxattr)
+pub(super) const FS_IOC_FSSETXATTR: u32 = 0x401c_5820;
+// _IOR('f', 1, long) and _IOW('f', 2, long) of 64-bit guests.
+pub(super) const FS_IOC_GETFLAGS: u32 = 0x8008_6601;
+pub(super) const FS_IOC_SETFLAGS: u32 = 0x4008_6602;
+// _IOR('f', 1, int) and _IOW('f', 2, int) of 32-bit guests.
+pub(super) const FS_IOC32_GETFLAGS: u32 = 0x8004_6601;
+pub(super) const FS_IOC32_SETFLAGS: u32 = 0x4004_6602;
+
+/// Fields of `struct fsxattr` guests may change with `FS_IOC_FSSETXATTR`.
+///
+/// Requests changing other fields fail with `EPERM` without reaching the host. Extent size hints
+/// may always be changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config-serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct FsxattrPolicy {
+    /// Whether guests may change the project id.
+    pub project_id: bool,
+    /// Mask of the flags of `fsx_xflags` guests may set or clear.
+    pub xflags: u32,
+}
+
+impl Default for FsxattrPolicy {
+    fn default() -> Self {
+        FsxattrPolicy {
+            project_id: true,
+            xflags: !(FS_XFLAG_IMMUTABLE | FS_XFLAG_APPEND),
+        }
+    }
+}
+
+impl FsxattrPolicy {
+    // Check whether the change from `old` to `new` is allowed.
+    fn check(&self, old: &Fsxattr, new: &Fsxattr) -> io::Result<()> {
+        if (old.fsx_xflags ^ new.fsx_xflags) & !self.xflags != 0 {
+            return Err(fuse_errno(libc::EPERM, "fsxattr flag change not allowed"));
+        }
+        if !self.project_id && old.fsx_projid != new.fsx_projid {
+            return Err(fuse_errno(
+                libc::EPERM,
+                "fsxattr project id change not allowed",
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Syscalls getting and setting `struct fsxattr` and inode flags, abstracted for testing.
+pub(super) trait FsxattrSyscalls: Send + Sync {
+    fn get(&self, fd: RawFd) -> io::Result<Fsxattr>;
+    fn set(&self, fd: RawFd, attr: &Fsxattr) -> io::Result<()>;
+    fn get_flags(&self, fd: RawFd) -> io::Result<u32>;
+    fn set_flags(&self, fd: RawFd, flags: u32) -> io::Result<()>;
+}
+
+pub(super) struct LibcFsxattrSyscalls;
+
+impl FsxattrSyscalls for LibcFsxattrSyscalls {
+    fn get(&self, fd: RawFd) -> io::Result<Fsxattr> {
+        let mut attr = Fsxattr::default();
+        // Safe because the kernel only writes to `attr` and we check the return value.
+        let res = unsafe { libc::ioctl(fd, FS_IOC_FSGETXATTR as _, &mut attr) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(attr)
+    }
+
+    fn set(&self, fd: RawFd, attr: &Fsxattr) -> io::Result<()> {
+        // Safe because the kernel only reads `attr` and we check the return value.
+        let res = unsafe { libc::ioctl(fd, FS_IOC_FSSETXATTR as _, attr) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn get_flags(&self, fd: RawFd) -> io::Result<u32> {
+        let mut flags: libc::c_int = 0;
+        // Safe because the kernel only writes an int to `flags` and we check the return value.
+        let res = unsafe { libc::ioctl(fd, libc::FS_IOC_GETFLAGS, &mut flags) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(flags as u32)
+    }
+
+    fn set_flags(&self, fd: RawFd, flags: u32) -> io::Result<()> {
+        let flags = flags as libc::c_int;
+        // Safe because the kernel only reads an int from `flags` and we check the return value.
+        let res = unsafe { libc::ioctl(fd, libc::FS_IOC_SETFLAGS, &flags) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+// Unprivileged daemons can't change project ids nor some flags on the host.
+fn map_set_error(e: io::Error) -> io::Error {
+    if e.raw_os_error() == Some(libc::EACCES) {
+        io::Error::from_raw_os_error(libc::EPERM)
+    } else {
+        e
+    }
+}
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    pub(super) fn do_flags_ioctl(
+        &self,
+        inode: Inode,
+        handle: Handle,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+    ) -> io::Result<IoctlReply> {
+        let supported = match cmd {
+            FS_IOC_FSGETXATTR | FS_IOC_FSSETXATTR => self.cfg.fsxattr.is_some(),
+            FS_IOC_GETFLAGS | FS_IOC_SETFLAGS | FS_IOC32_GETFLAGS | FS_IOC32_SETFLAGS => {
+                self.cfg.inode_flags.is_some()
+            }
+            _ => false,
+        };
+        if !supported {
+            return Err(io::Error::from_raw_os_error(libc::ENOTTY));
+        }
+
+        // Don't register anything, ioctls on handles of the inode use the handle's fd.
+        let handle_data = self.handle_map.get(handle, inode).ok();
+        let reopened;
+        let fd = match handle_data.as_ref() {
+            Some(hd) => hd.get_handle_raw_fd(),
+            None => {
+                let data = self.inode_map.get(inode)?;
+                let file = data.get_file(&self.mount_fds)?;
+                reopened = self.reopen_fd(
+                    file.as_raw_fd(),
+                    libc::O_RDONLY | libc::O_NONBLOCK,
+                    data.mode,
+                )?;
+                reopened.as_raw_fd()
+            }
+        };
+
+        let size = mem::size_of::<Fsxattr>();
+        let flags_size = mem::size_of::<u32>();
+        match cmd {
+            FS_IOC_FSGETXATTR => {
+                if (out_size as usize) < size {
+                    return Err(fuse_errno(libc::EINVAL, "fsxattr output too small"));
+                }
+                let attr = self.fsxattr_sys.get(fd)?;
+                Ok(IoctlReply::Done {
+                    result: 0,
+                    data: attr.as_slice().to_vec(),
+                })
+            }
+            FS_IOC_FSSETXATTR => {
+                if in_data.len() < size {
+                    return Err(fuse_errno(libc::EINVAL, "fsxattr input too small"));
+                }
+                // The input isn't aligned, copy it out.
+                let mut attr = Fsxattr::default();
+                attr.as_mut_slice().copy_from_slice(&in_data[..size]);
+
+                let old = self.fsxattr_sys.get(fd)?;
+                // Checked above.
+                self.cfg.fsxattr.as_ref().unwrap().check(&old, &attr)?;
+                self.fsxattr_sys.set(fd, &attr).map_err(map_set_error)?;
+                Ok(IoctlReply::done(0))
+            }
+            FS_IOC_GETFLAGS | FS_IOC32_GETFLAGS => {
+                if (out_size as usize) < flags_size {
+                    return Err(fuse_errno(libc::EINVAL, "inode flags output too small"));
+                }
+                let flags = self.fsxattr_sys.get_flags(fd)?;
+                Ok(IoctlReply::Done {
+                    result: 0,
+                    data: flags.to_ne_bytes().to_vec(),
+                })
+            }
+            FS_IOC_SETFLAGS | FS_IOC32_SETFLAGS => {
+                if in_data.len() < flags_size {
+                    return Err(fuse_errno(libc::EINVAL, "inode flags input too small"));
+                }
+                let mut flags = [0u8; 4];
+                flags.copy_from_slice(&in_data[..flags_size]);
+                let flags = u32::from_ne_bytes(flags);
+
+                let old = self.fsxattr_sys.get_flags(fd)?;
+                // Checked above.
+                let mask = self.cfg.inode_flags.unwrap();
+                if (old ^ flags) & !mask != 0 {
+                    return Err(fuse_errno(libc::EPERM, "inode flag change not allowed"));
+                }
+                self.fsxattr_sys
+                    .set_flags(fd, flags)
+                    .map_err(map_set_error)?;
+                Ok(IoctlReply::done(0))
+            }
+            _ => Err(io::Error::from_raw_os_error(libc::ENOTTY)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fsxattr_layout() {
+        // Same size on 32-bit and 64-bit, encoded in the ioctl numbers.
+        assert_eq!(mem::size_of::<Fsxattr>(), 28);
+        assert_eq!((FS_IOC_FSGETXATTR >> 16) & 0x3fff, 28);
+        assert_eq!((FS_IOC_FSSETXATTR >> 16) & 0x3fff, 28);
+        assert_eq!(FS_IOC32_SETFLAGS as libc::Ioctl, libc::FS_IOC32_SETFLAGS);
+    }
+
+    #[test]
+    fn test_fsxattr_policy() {
+        let policy = FsxattrPolicy::default();
+        let old = Fsxattr::default();
+        let mut new = Fsxattr {
+            fsx_projid: 7,
+            fsx_xflags: 0x200,
+            fsx_extsize: 4096,
+            ..Default::default()
+        };
+        policy.check(&old, &new).unwrap();
+
+        new.fsx_xflags |= FS_XFLAG_IMMUTABLE;
+        let err = policy.check(&old, &new).unwrap_err();
+        assert_eq!(crate::api::errno::errno_of(&err), Some(libc::EPERM));
+        // Unchanged gated flags are fine.
+        policy.check(&new, &new).unwrap();
+
+        let policy = FsxattrPolicy {
+            project_id: false,
+            xflags: 0,
+        };
+        assert!(policy.check(&old, &new).is_err());
+        let new = Fsxattr {
+            fsx_extsize: 4096,
+            ..Default::default()
+        };
+        policy.check(&old, &new).unwrap();
+    }
+}
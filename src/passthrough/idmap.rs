@@ -0,0 +1,261 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Shifting of uids and gids between the guest and the host.
+//!
+//! Rootless containers run the daemon in a user namespace whose ids are a shifted range of the
+//! host ones, guest uid 0 being host uid 100000 for example. Like idmapped mounts, but in
+//! userspace, a [UidGidMap] translates the ids of callers into host ids when switching
+//! credentials and changing owners by `chown`, and the owners of host files into guest ids in
+//! replies of getattr, setattr, lookup and readdirplus. Ids outside of the mapped ranges are
+//! translated into the overflow ids, like the kernel does for unmapped ids, rather than failing.
+//!
+//! The textual syntax mirrors the one of user namespace mappings, comma separated options
+//! repeated for each range:
+//!
+//! * `uidmap=<guest>:<host>:<count>`, guest uids from `guest` map to host uids from `host`.
+//! * `gidmap=<guest>:<host>:<count>`, the same for gids.
+//! * `overflowuid=<id>` and `overflowgid=<id>`, ids unmapped ids translate into, 65534 by default.
+//!
+//! Ids stored in POSIX ACLs aren't translated.
+
+use std::str::FromStr;
+
+/// Default id unmapped ids translate into, the one of `nobody`.
+pub const DEFAULT_OVERFLOW_ID: u32 = 65534;
+
+/// A range of ids mapped between the guest and the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "config-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IdRange {
+    /// First id of the range in the guest.
+    pub guest: u32,
+    /// First id of the range on the host.
+    pub host: u32,
+    /// Number of ids of the range.
+    pub count: u32,
+}
+
+impl IdRange {
+    fn contains(start: u32, count: u32, id: u32) -> bool {
+        id >= start && id - start < count
+    }
+
+    fn overlaps(start: u32, other: u32, count: u32, other_count: u32) -> bool {
+        (start as u64) < other as u64 + other_count as u64
+            && (other as u64) < start as u64 + count as u64
+    }
+}
+
+/// Ranges of uids and gids mapped between the guest and the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config-serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "UncheckedUidGidMap")
+)]
+pub struct UidGidMap {
+    uids: Vec<IdRange>,
+    gids: Vec<IdRange>,
+    overflow_uid: u32,
+    overflow_gid: u32,
+}
+
+impl UidGidMap {
+    /// Create a map of the uid ranges `uids` and the gid ranges `gids`, translating unmapped
+    /// ids into [DEFAULT_OVERFLOW_ID].
+    ///
+    /// Ranges of the same kind must not overlap, neither in the guest nor on the host, so ids
+    /// translate back and forth.
+    pub fn new(uids: Vec<IdRange>, gids: Vec<IdRange>) -> Result<Self, &'static str> {
+        for ranges in [&uids, &gids] {
+            for (idx, r) in ranges.iter().enumerate() {
+                if r.count == 0
+                    || r.guest.checked_add(r.count - 1).is_none()
+                    || r.host.checked_add(r.count - 1).is_none()
+                {
+                    return Err("invalid id map range");
+                }
+                if ranges[..idx].iter().any(|o| {
+                    IdRange::overlaps(r.guest, o.guest, r.count, o.count)
+                        || IdRange::overlaps(r.host, o.host, r.count, o.count)
+                }) {
+                    return Err("overlapping id map ranges");
+                }
+            }
+        }
+
+        Ok(UidGidMap {
+            uids,
+            gids,
+            overflow_uid: DEFAULT_OVERFLOW_ID,
+            overflow_gid: DEFAULT_OVERFLOW_ID,
+        })
+    }
+
+    /// Translate unmapped uids into `uid` and unmapped gids into `gid`.
+    pub fn with_overflow_ids(mut self, uid: u32, gid: u32) -> Self {
+        self.overflow_uid = uid;
+        self.overflow_gid = gid;
+        self
+    }
+
+    /// Translate the guest uid `uid` into the host uid.
+    pub fn host_uid(&self, uid: u32) -> u32 {
+        Self::to_host(&self.uids, uid).unwrap_or(self.overflow_uid)
+    }
+
+    /// Translate the guest gid `gid` into the host gid.
+    pub fn host_gid(&self, gid: u32) -> u32 {
+        Self::to_host(&self.gids, gid).unwrap_or(self.overflow_gid)
+    }
+
+    /// Translate the host uid `uid` into the guest uid.
+    pub fn guest_uid(&self, uid: u32) -> u32 {
+        Self::to_guest(&self.uids, uid).unwrap_or(self.overflow_uid)
+    }
+
+    /// Translate the host gid `gid` into the guest gid.
+    pub fn guest_gid(&self, gid: u32) -> u32 {
+        Self::to_guest(&self.gids, gid).unwrap_or(self.overflow_gid)
+    }
+
+    fn to_host(ranges: &[IdRange], id: u32) -> Option<u32> {
+        ranges
+            .iter()
+            .find(|r| IdRange::contains(r.guest, r.count, id))
+            .map(|r| r.host + (id - r.guest))
+    }
+
+    fn to_guest(ranges: &[IdRange], id: u32) -> Option<u32> {
+        ranges
+            .iter()
+            .find(|r| IdRange::contains(r.host, r.count, id))
+            .map(|r| r.guest + (id - r.host))
+    }
+}
+
+// Deserialized map, checked by `UidGidMap::new` like parsed ones.
+#[cfg(feature = "config-serde")]
+#[derive(serde::Deserialize)]
+struct UncheckedUidGidMap {
+    #[serde(default)]
+    uids: Vec<IdRange>,
+    #[serde(default)]
+    gids: Vec<IdRange>,
+    #[serde(default = "default_overflow_id")]
+    overflow_uid: u32,
+    #[serde(default = "default_overflow_id")]
+    overflow_gid: u32,
+}
+
+#[cfg(feature = "config-serde")]
+fn default_overflow_id() -> u32 {
+    DEFAULT_OVERFLOW_ID
+}
+
+#[cfg(feature = "config-serde")]
+impl std::convert::TryFrom<UncheckedUidGidMap> for UidGidMap {
+    type Error = &'static str;
+
+    fn try_from(map: UncheckedUidGidMap) -> Result<Self, Self::Error> {
+        Ok(UidGidMap::new(map.uids, map.gids)?
+            .with_overflow_ids(map.overflow_uid, map.overflow_gid))
+    }
+}
+
+impl FromStr for UidGidMap {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut uids, mut gids) = (Vec::new(), Vec::new());
+        let (mut overflow_uid, mut overflow_gid) = (DEFAULT_OVERFLOW_ID, DEFAULT_OVERFLOW_ID);
+        for opt in s.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+            let (key, value) = opt.split_once('=').ok_or("invalid id map option")?;
+            let id = |v: &str| v.trim().parse::<u32>().map_err(|_| "invalid id map number");
+            match key.trim() {
+                "uidmap" | "gidmap" => {
+                    let fields = value.split(':').map(id).collect::<Result<Vec<_>, _>>()?;
+                    let range = match fields[..] {
+                        [guest, host, count] => IdRange { guest, host, count },
+                        _ => return Err("invalid id map range"),
+                    };
+                    if key.trim() == "uidmap" {
+                        uids.push(range);
+                    } else {
+                        gids.push(range);
+                    }
+                }
+                "overflowuid" => overflow_uid = id(value)?,
+                "overflowgid" => overflow_gid = id(value)?,
+                _ => return Err("unknown id map option"),
+            }
+        }
+
+        if uids.is_empty() && gids.is_empty() {
+            return Err("empty id map");
+        }
+        Ok(UidGidMap::new(uids, gids)?.with_overflow_ids(overflow_uid, overflow_gid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uid_gid_map() {
+        let map: UidGidMap = "uidmap=0:100000:65536, gidmap=0:200000:1000,gidmap=1000:1000:1"
+            .parse()
+            .unwrap();
+
+        // Guest to host.
+        assert_eq!(map.host_uid(0), 100000);
+        assert_eq!(map.host_uid(65535), 165535);
+        assert_eq!(map.host_uid(65536), DEFAULT_OVERFLOW_ID);
+        assert_eq!(map.host_gid(999), 200999);
+        assert_eq!(map.host_gid(1000), 1000);
+        assert_eq!(map.host_gid(1001), DEFAULT_OVERFLOW_ID);
+
+        // Host to guest.
+        assert_eq!(map.guest_uid(100000), 0);
+        assert_eq!(map.guest_uid(165535), 65535);
+        assert_eq!(map.guest_uid(0), DEFAULT_OVERFLOW_ID);
+        assert_eq!(map.guest_gid(200999), 999);
+        assert_eq!(map.guest_gid(1000), 1000);
+        assert_eq!(map.guest_gid(200000 + 1000), DEFAULT_OVERFLOW_ID);
+
+        let map: UidGidMap = "uidmap=0:1000:1,overflowuid=99,overflowgid=98"
+            .parse()
+            .unwrap();
+        assert_eq!(map.host_uid(0), 1000);
+        assert_eq!(map.host_uid(1), 99);
+        assert_eq!(map.guest_uid(0), 99);
+        // No gid range, all gids overflow.
+        assert_eq!(map.host_gid(0), 98);
+        assert_eq!(map.guest_gid(1000), 98);
+    }
+
+    #[test]
+    fn test_uid_gid_map_parse_errors() {
+        for s in [
+            "",
+            "uidmap=0:100000",
+            "uidmap=0:100000:1:2",
+            "uidmap=0:100000:0",
+            "uidmap=0:x:1",
+            "uidmap=4294967295:0:2",
+            "uidmap=0:100000:10,uidmap=5:200000:10",
+            "uidmap=0:100000:10,uidmap=20:100005:10",
+            "overflowuid=1",
+            "uidmap",
+            "size=1",
+        ] {
+            assert!(s.parse::<UidGidMap>().is_err(), "{:?} should be invalid", s);
+        }
+        // Ranges may overlap across uids and gids.
+        assert!("uidmap=0:100000:10,gidmap=0:100000:10"
+            .parse::<UidGidMap>()
+            .is_ok());
+    }
+}
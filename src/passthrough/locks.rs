@@ -0,0 +1,416 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Forwarding of POSIX record locks and flock(2) locks to the host, see `Config::posix_locks`.
+//!
+//! Record locks of the guest belong to lock owners, one per guest process, while record locks
+//! taken by the daemon would all belong to the daemon process, and be released by any close.
+//! So the locks of each owner are taken as open file description (OFD) locks, on a file
+//! reopened for the owner the first time it locks an inode and shared by all its handles of the
+//! inode. Locks of different owners conflict with each other and with the ones of host
+//! processes. When an owner closes a file, which the kernel tells by a flush, its reopened file
+//! is closed, which releases its locks like POSIX requires.
+//!
+//! flock(2) locks belong to open files, they are taken on the fd of the handle.
+//!
+//! Waiting for a lock would block a worker thread until the lock is released, possibly forever.
+//! So blocking requests poll for the lock, with increasing pauses, until they get it or get
+//! interrupted by `FUSE_INTERRUPT`, with interrupts enabled by `Server::with_interrupts`.
+
+use std::collections::HashMap;
+use std::mem;
+use std::thread;
+
+use super::*;
+use crate::api::filesystem::{Context, FileLock};
+
+// Largest offset of lock ranges, ranges ending there extend to the end of the file.
+const OFFSET_MAX: u64 = i64::MAX as u64;
+
+// Longest pause between two attempts to take a contended lock.
+const MAX_LOCK_POLL: Duration = Duration::from_millis(100);
+
+// Requests waiting for a lock, and whether they were interrupted.
+#[derive(Default)]
+pub(super) struct LockWaiters {
+    waiters: Mutex<HashMap<u64, bool>>,
+}
+
+impl LockWaiters {
+    fn enter(&self, unique: u64) -> LockWaiter<'_> {
+        self.waiters.lock().unwrap().insert(unique, false);
+        LockWaiter {
+            waiters: self,
+            unique,
+        }
+    }
+
+    // Interrupt request `unique` if it's waiting for a lock.
+    pub(super) fn interrupt(&self, unique: u64) {
+        if let Some(interrupted) = self.waiters.lock().unwrap().get_mut(&unique) {
+            *interrupted = true;
+        }
+    }
+}
+
+struct LockWaiter<'a> {
+    waiters: &'a LockWaiters,
+    unique: u64,
+}
+
+impl LockWaiter<'_> {
+    fn interrupted(&self) -> bool {
+        self.waiters.waiters.lock().unwrap()[&self.unique]
+    }
+}
+
+impl Drop for LockWaiter<'_> {
+    fn drop(&mut self) {
+        self.waiters.waiters.lock().unwrap().remove(&self.unique);
+    }
+}
+
+// Convert a lock of the kernel, whose range ends inclusively, into a `struct flock`.
+fn to_flock(lock: &FileLock) -> io::Result<libc::flock> {
+    if lock.start > OFFSET_MAX || lock.end > OFFSET_MAX || lock.end < lock.start {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    // Safe because all fields of `flock` are plain integers.
+    let mut fl: libc::flock = unsafe { mem::zeroed() };
+    fl.l_type = lock.lock_type as libc::c_short;
+    fl.l_whence = libc::SEEK_SET as libc::c_short;
+    fl.l_start = lock.start as libc::off_t;
+    fl.l_len = if lock.end == OFFSET_MAX {
+        0
+    } else {
+        (lock.end - lock.start + 1) as libc::off_t
+    };
+    Ok(fl)
+}
+
+// Convert a conflicting lock returned by `F_OFD_GETLK`. Its owner isn't a guest process.
+fn from_flock(fl: &libc::flock) -> FileLock {
+    let start = fl.l_start as u64;
+    FileLock {
+        start,
+        end: if fl.l_len == 0 {
+            OFFSET_MAX
+        } else {
+            start + fl.l_len as u64 - 1
+        },
+        lock_type: fl.l_type as u32,
+        pid: 0,
+    }
+}
+
+fn fcntl_lock(fd: RawFd, cmd: libc::c_int, fl: &mut libc::flock) -> io::Result<()> {
+    // Safe because the kernel only writes to `fl` and we check the return value.
+    if unsafe { libc::fcntl(fd, cmd, fl as *mut libc::flock) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn flock(fd: RawFd, op: libc::c_int) -> io::Result<()> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    if unsafe { libc::flock(fd, op) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn is_contended(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EAGAIN) | Some(libc::EACCES))
+}
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    fn lock_handle(&self, inode: Inode, handle: Handle) -> io::Result<Arc<HandleData>> {
+        if !self.cfg.posix_locks || self.no_open.load(Ordering::Relaxed) {
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+        }
+        self.handle_map.get(handle, inode)
+    }
+
+    // Get the file holding the record locks of `owner` on `data`, reopened from `handle` the
+    // first time.
+    fn owner_lock_file(
+        &self,
+        data: &InodeData,
+        handle: &HandleData,
+        owner: u64,
+    ) -> io::Result<Arc<File>> {
+        let mut files = data.lock_files.lock().unwrap();
+        if let Some(file) = files.get(&owner) {
+            return Ok(file.clone());
+        }
+
+        // Read and write locks need a file opened for reading and writing respectively. The
+        // owner may take both through different handles, so prefer a file open for both.
+        let fd = handle.get_handle_raw_fd();
+        let file = self
+            .reopen_fd(fd, libc::O_RDWR, data.mode)
+            .or_else(|_| {
+                // Safe because this doesn't modify any memory and we check the return value.
+                let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+                if flags < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                self.reopen_fd(fd, flags & libc::O_ACCMODE, data.mode)
+            })
+            .map(Arc::new)?;
+        files.insert(owner, file.clone());
+        Ok(file)
+    }
+
+    pub(super) fn do_getlk(
+        &self,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: FileLock,
+    ) -> io::Result<FileLock> {
+        let hd = self.lock_handle(inode, handle)?;
+        let data = self.inode_map.get(inode)?;
+        let mut fl = to_flock(&lock)?;
+
+        // Locks of the owner don't conflict, unless it holds none.
+        let owner_file = data.lock_files.lock().unwrap().get(&owner).cloned();
+        let fd = owner_file
+            .as_ref()
+            .map_or(hd.get_handle_raw_fd(), |f| f.as_raw_fd());
+        fcntl_lock(fd, libc::F_OFD_GETLK, &mut fl)?;
+
+        Ok(from_flock(&fl))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn do_setlk(
+        &self,
+        ctx: &Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+        wait: bool,
+    ) -> io::Result<()> {
+        let hd = self.lock_handle(inode, handle)?;
+
+        if flags & fuse::LK_FLOCK != 0 {
+            let op = match lock.lock_type as libc::c_int {
+                libc::F_RDLCK => libc::LOCK_SH,
+                libc::F_WRLCK => libc::LOCK_EX,
+                libc::F_UNLCK => libc::LOCK_UN,
+                _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+            };
+            let fd = hd.get_handle_raw_fd();
+            return self.poll_lock(ctx, wait, || flock(fd, op | libc::LOCK_NB));
+        }
+
+        let data = self.inode_map.get(inode)?;
+        let mut fl = to_flock(&lock)?;
+        let file = if fl.l_type == libc::F_UNLCK as libc::c_short {
+            // An owner without file holds no lock.
+            match data.lock_files.lock().unwrap().get(&owner) {
+                Some(file) => file.clone(),
+                None => return Ok(()),
+            }
+        } else {
+            self.owner_lock_file(&data, &hd, owner)?
+        };
+        self.poll_lock(ctx, wait, || {
+            fcntl_lock(file.as_raw_fd(), libc::F_OFD_SETLK, &mut fl)
+        })
+    }
+
+    // Call `try_lock` until the lock isn't contended if `wait`, or the request is interrupted.
+    fn poll_lock(
+        &self,
+        ctx: &Context,
+        wait: bool,
+        mut try_lock: impl FnMut() -> io::Result<()>,
+    ) -> io::Result<()> {
+        if !wait {
+            return try_lock();
+        }
+
+        let waiter = self.lock_waiters.enter(ctx.unique);
+        let mut pause = Duration::from_millis(1);
+        loop {
+            match try_lock() {
+                Err(e) if is_contended(&e) => {
+                    if waiter.interrupted() {
+                        return Err(io::Error::from_raw_os_error(libc::EINTR));
+                    }
+                    thread::sleep(pause);
+                    pause = cmp::min(pause * 2, MAX_LOCK_POLL);
+                }
+                res => return res,
+            }
+        }
+    }
+
+    // Release the record locks of `owner` on `inode`, when it closes the file.
+    pub(super) fn release_owner_locks(&self, inode: Inode, owner: u64) {
+        if let Ok(data) = self.inode_map.get(inode) {
+            data.lock_files.lock().unwrap().remove(&owner);
+        }
+    }
+
+    // Release the flock(2) lock of `handle`, when the kernel releases it.
+    pub(super) fn release_flock(&self, inode: Inode, handle: Handle) -> io::Result<()> {
+        let hd = self.lock_handle(inode, handle)?;
+        flock(hd.get_handle_raw_fd(), libc::LOCK_UN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abi::fuse_abi::{LK_FLOCK, ROOT_ID};
+    use crate::api::filesystem::{FileSystem, FsOptions};
+    use crate::passthrough::tests::prepare_passthroughfs_with;
+
+    fn lock(lock_type: libc::c_int, start: u64, end: u64) -> FileLock {
+        FileLock {
+            start,
+            end,
+            lock_type: lock_type as u32,
+            pid: 0,
+        }
+    }
+
+    #[test]
+    fn test_flock_conversion() {
+        let fl = to_flock(&lock(libc::F_WRLCK, 10, 19)).unwrap();
+        assert_eq!((fl.l_start, fl.l_len), (10, 10));
+        let back = from_flock(&fl);
+        assert_eq!((back.start, back.end), (10, 19));
+
+        let fl = to_flock(&lock(libc::F_RDLCK, 5, OFFSET_MAX)).unwrap();
+        assert_eq!((fl.l_start, fl.l_len), (5, 0));
+        assert_eq!(from_flock(&fl).end, OFFSET_MAX);
+
+        assert!(to_flock(&lock(libc::F_RDLCK, 5, 4)).is_err());
+        assert!(to_flock(&lock(libc::F_RDLCK, 0, u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn test_passthroughfs_locks() {
+        let (source, fs) = prepare_passthroughfs_with(|cfg| cfg.posix_locks = true);
+        std::fs::write(source.as_path().join("f"), b"0123456789").unwrap();
+        fs.init(FsOptions::POSIX_LOCKS | FsOptions::FLOCK_LOCKS)
+            .unwrap();
+
+        let ctx = Context::default();
+        let inode = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("f").unwrap())
+            .unwrap()
+            .inode;
+        let open = || {
+            fs.open(&ctx, inode, libc::O_RDWR as u32, 0)
+                .unwrap()
+                .0
+                .unwrap()
+        };
+        let (h1, h2) = (open(), open());
+
+        // Owners conflict with each other, but not with themselves, across handles.
+        fs.setlk(&ctx, inode, h1, 1, lock(libc::F_WRLCK, 0, 4), 0)
+            .unwrap();
+        fs.setlk(&ctx, inode, h2, 1, lock(libc::F_WRLCK, 2, 6), 0)
+            .unwrap();
+        let e = fs
+            .setlk(&ctx, inode, h2, 2, lock(libc::F_RDLCK, 6, 6), 0)
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EAGAIN));
+        fs.setlk(&ctx, inode, h2, 2, lock(libc::F_RDLCK, 7, OFFSET_MAX), 0)
+            .unwrap();
+
+        let l = fs
+            .getlk(&ctx, inode, h1, 2, lock(libc::F_WRLCK, 0, OFFSET_MAX), 0)
+            .unwrap();
+        assert_eq!(l.lock_type, libc::F_WRLCK as u32);
+        assert_eq!((l.start, l.end), (0, 6));
+        let l = fs
+            .getlk(&ctx, inode, h1, 1, lock(libc::F_WRLCK, 0, 6), 0)
+            .unwrap();
+        assert_eq!(l.lock_type, libc::F_UNLCK as u32);
+
+        // Host processes see the locks.
+        let host = std::fs::File::open(source.as_path().join("f")).unwrap();
+        let mut fl = to_flock(&lock(libc::F_RDLCK, 0, 0)).unwrap();
+        fcntl_lock(host.as_raw_fd(), libc::F_OFD_GETLK, &mut fl).unwrap();
+        assert_eq!(fl.l_type, libc::F_WRLCK as libc::c_short);
+
+        // Closing a file releases the locks of its owner.
+        fs.flush(&ctx, inode, h1, 1).unwrap();
+        fs.setlk(&ctx, inode, h2, 2, lock(libc::F_WRLCK, 0, 6), 0)
+            .unwrap();
+
+        // flock(2) locks belong to handles.
+        fs.setlk(&ctx, inode, h1, 1, lock(libc::F_WRLCK, 0, 0), LK_FLOCK)
+            .unwrap();
+        let e = fs
+            .setlk(&ctx, inode, h2, 2, lock(libc::F_RDLCK, 0, 0), LK_FLOCK)
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EAGAIN));
+        fs.release(&ctx, inode, 0, h1, false, true, Some(1))
+            .unwrap();
+        fs.setlk(&ctx, inode, h2, 2, lock(libc::F_RDLCK, 0, 0), LK_FLOCK)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_passthroughfs_setlkw() {
+        let (source, fs) = prepare_passthroughfs_with(|cfg| cfg.posix_locks = true);
+        std::fs::write(source.as_path().join("f"), b"f").unwrap();
+
+        let ctx = Context::default();
+        let inode = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("f").unwrap())
+            .unwrap()
+            .inode;
+        let open = || {
+            fs.open(&ctx, inode, libc::O_RDWR as u32, 0)
+                .unwrap()
+                .0
+                .unwrap()
+        };
+        let (h1, h2) = (open(), open());
+        let whole = lock(libc::F_WRLCK, 0, OFFSET_MAX);
+        fs.setlk(&ctx, inode, h1, 1, whole, 0).unwrap();
+
+        thread::scope(|s| {
+            // Interrupted while waiting.
+            let waiter = s.spawn(|| {
+                let ctx = Context {
+                    unique: 7,
+                    ..Default::default()
+                };
+                fs.setlkw(&ctx, inode, h2, 2, whole, 0)
+            });
+            while !fs.lock_waiters.waiters.lock().unwrap().contains_key(&7) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            fs.interrupt(&ctx, 6);
+            fs.interrupt(&ctx, 7);
+            let e = waiter.join().unwrap().unwrap_err();
+            assert_eq!(e.raw_os_error(), Some(libc::EINTR));
+
+            // Granted once released.
+            let waiter = s.spawn(|| fs.setlkw(&ctx, inode, h2, 2, whole, 0));
+            thread::sleep(Duration::from_millis(20));
+            fs.setlk(&ctx, inode, h1, 1, lock(libc::F_UNLCK, 0, OFFSET_MAX), 0)
+                .unwrap();
+            waiter.join().unwrap().unwrap();
+        });
+        assert!(fs.lock_waiters.waiters.lock().unwrap().is_empty());
+
+        // Without the option, locks are left to the guest kernel.
+        let (_source, fs) = prepare_passthroughfs_with(|_| {});
+        let root = fs.opendir(&ctx, ROOT_ID, 0).unwrap().0.unwrap();
+        let e = fs.setlk(&ctx, ROOT_ID, root, 1, whole, 0).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOSYS));
+    }
+}
@@ -12,25 +12,34 @@
 //! with heavy modification/enhancements from Alibaba Cloud OS team.
 
 use std::any::Any;
+use std::cmp;
 use std::collections::{btree_map, BTreeMap};
 use std::ffi::{CStr, CString, OsString};
 use std::fs::File;
-use std::io;
+use std::io::{self, Write};
 use std::marker::PhantomData;
 use std::mem::MaybeUninit;
 use std::ops::{Deref, DerefMut};
-use std::os::unix::ffi::OsStringExt;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockWriteGuard};
 use std::time::Duration;
 
+use arc_swap::ArcSwap;
 use vm_memory::ByteValued;
 
 use crate::abi::fuse_abi as fuse;
-use crate::api::filesystem::Entry;
+use crate::api::accounting::WriteAccounting;
+use crate::api::attr_cache::Notifier;
+use crate::api::clock::{Clock, SystemClock};
+use crate::api::errno::{fuse_errno, ErrnoContext};
+use crate::api::filesystem::{Context, Entry, OpenOptions};
+use crate::api::scratch;
+use crate::api::server::XattrLimits;
+use crate::api::shedder::YieldPoints;
 use crate::api::{
     validate_path_component, BackendFileSystem, CURRENT_DIR_CSTR, EMPTY_CSTR, PARENT_DIR_CSTR,
     PROC_SELF_FD_CSTR, SLASH_ASCII, VFS_MAX_INO,
@@ -39,12 +48,74 @@ use crate::BitmapSlice;
 
 #[cfg(feature = "async-io")]
 mod async_io;
+mod atime;
+mod blockdev;
+#[cfg(feature = "config-serde")]
+mod config_serde;
+mod copy_range;
+mod creds;
+mod dir_fd_cache;
+mod dir_snapshot;
+mod dirent;
+mod fallocate;
 mod file_handle;
+mod fscreate;
+mod fsxattr;
+mod idmap;
+mod locks;
 mod multikey;
+mod path_hints;
+#[cfg(feature = "persist")]
+mod persist;
+mod posix_acl;
+mod proc_fd;
+mod quota;
+mod reconfig;
+mod retry;
+mod root;
+mod sandbox;
+mod special_nodes;
+mod statx;
 mod sync_io;
-
+mod time_gran;
+mod validate;
+mod walk;
+mod xattrmap;
+
+use atime::{LibcUtimensSyscalls, TimesCache, UtimensSyscalls};
+use blockdev::{BlockdevSyscalls, LibcBlockdevSyscalls};
+use copy_range::CopyHelper;
+pub use creds::CredSwitchStats;
+use creds::CredSwitcher;
+use dir_fd_cache::DirFdCache;
+use dir_snapshot::{DirState, SnapshotBudget};
+pub use dirent::ReaddirStats;
+use dirent::{DirSyscalls, LibcDirSyscalls, ReaddirCounters};
+use fallocate::FallocHelper;
 use file_handle::{FileHandle, MountFds};
+pub use fscreate::{FsCreateWriter, ProcFsCreateWriter};
+pub use fsxattr::{
+    FsxattrPolicy, FS_APPEND_FL, FS_IMMUTABLE_FL, FS_XFLAG_APPEND, FS_XFLAG_IMMUTABLE,
+};
+use fsxattr::{FsxattrSyscalls, LibcFsxattrSyscalls};
+pub use idmap::{IdRange, UidGidMap, DEFAULT_OVERFLOW_ID};
+use locks::LockWaiters;
 use multikey::MultikeyBTreeMap;
+use path_hints::PathHints;
+use proc_fd::{LibcProcSyscalls, ProcSyscalls};
+#[cfg(feature = "project-quota")]
+pub use quota::ProjectQuotaProvider;
+use quota::QuotaCache;
+pub use quota::{QuotaConfig, QuotaId, QuotaInfo, QuotaProvider, QUOTA_XATTR_NAME};
+pub use reconfig::RuntimeConfig;
+use retry::Retrier;
+pub use retry::{RetryPolicy, RetryStats};
+pub use sandbox::SANDBOX_CAPABILITIES;
+pub use statx::MntIdStrategy;
+use statx::StatHelper;
+use time_gran::{LibcTimeGranSyscalls, TimeGranSyscalls};
+pub use walk::WalkGuard;
+pub use xattrmap::{XattrMap, XattrRule, XattrRuleType};
 
 type Inode = u64;
 type Handle = u64;
@@ -101,10 +172,11 @@ impl FileOrHandle {
     }
 }
 
-#[derive(Debug)]
 enum InodeFile<'a> {
     Owned(File),
     Ref(&'a File),
+    // Directory kept open by `Config::dir_fd_cache_size`.
+    Dir(Arc<HandleData>),
 }
 
 impl AsRawFd for InodeFile<'_> {
@@ -114,6 +186,7 @@ impl AsRawFd for InodeFile<'_> {
         match self {
             Self::Owned(file) => file.as_raw_fd(),
             Self::Ref(file_ref) => file_ref.as_raw_fd(),
+            Self::Dir(data) => data.get_handle_raw_fd(),
         }
     }
 }
@@ -122,11 +195,18 @@ struct InodeData {
     inode: Inode,
     // Most of these aren't actually files but ¯\_(ツ)_/¯.
     file_or_handle: FileOrHandle,
-    #[allow(dead_code)]
     altkey: InodeAltKey,
     refcount: AtomicU64,
     // File type and mode, not used for now
     mode: u32,
+    // Size of the file last reported to the guest, to detect truncation on the host.
+    size: AtomicU64,
+    // Files holding the record locks of each lock owner, see `Config::posix_locks`.
+    lock_files: Mutex<BTreeMap<u64, Arc<File>>>,
+    // Bumped when `security.capability` is set, to invalidate `HandleData::no_caps`.
+    caps_gen: AtomicU64,
+    // Timestamps last reported to the guest, see `Config::atime_policy`.
+    times: TimesCache,
 }
 
 // Returns true if it's safe to open this inode without O_PATH.
@@ -137,16 +217,32 @@ fn is_safe_inode(mode: u32) -> bool {
 }
 
 impl<'a> InodeData {
-    fn new(inode: Inode, f: FileOrHandle, refcount: u64, altkey: InodeAltKey, mode: u32) -> Self {
+    fn new(
+        inode: Inode,
+        f: FileOrHandle,
+        refcount: u64,
+        altkey: InodeAltKey,
+        st: &libc::stat64,
+    ) -> Self {
         InodeData {
             inode,
             file_or_handle: f,
             altkey,
             refcount: AtomicU64::new(refcount),
-            mode,
+            mode: st.st_mode,
+            size: AtomicU64::new(st.st_size as u64),
+            lock_files: Mutex::new(BTreeMap::new()),
+            caps_gen: AtomicU64::new(0),
+            times: TimesCache::new(st),
         }
     }
 
+    // Record the attributes `st` reported to the guest.
+    fn update_attr(&self, st: &libc::stat64) {
+        self.size.store(st.st_size as u64, Ordering::Relaxed);
+        self.times.set(st);
+    }
+
     fn get_file(&self, mount_fds: &MountFds) -> io::Result<InodeFile<'_>> {
         match &self.file_or_handle {
             FileOrHandle::File(f) => Ok(InodeFile::Ref(f)),
@@ -158,31 +254,134 @@ impl<'a> InodeData {
     }
 }
 
+// Path of a file descriptor under `/proc/self/fd`, built without allocation.
+struct ProcFdPath {
+    buf: [u8; 32],
+}
+
+impl ProcFdPath {
+    fn new(fd: RawFd) -> Self {
+        let mut buf = [0u8; 32];
+        // The longest path "/proc/self/fd/-2147483648" fits in the buffer, with the nul terminator.
+        if sandbox::proc_self_fd_cwd() {
+            write!(&mut buf[..], "{}", fd).unwrap();
+        } else {
+            write!(&mut buf[..], "/proc/self/fd/{}", fd).unwrap();
+        }
+        ProcFdPath { buf }
+    }
+
+    fn as_ptr(&self) -> *const libc::c_char {
+        self.buf.as_ptr() as *const libc::c_char
+    }
+}
+
+// Number of shards of the inode and handle maps.
+const MAP_SHARDS: usize = 64;
+
+// Maximum number of forgets applied to a shard of the inode map while holding its lock once.
+const FORGET_CHUNK: usize = 1024;
+
+const SECURITY_CAPABILITY: &[u8] = b"security.capability\0";
+
 /// Data structures to manage accessed inodes.
+///
+/// Inodes are spread over shards, each with its own lock, so lookups and forgets of different
+/// inodes rarely contend. An inode and all its alt keys live in the shard picked by the hash of
+/// its ids alt key, and inode numbers are allocated so that the shard of an inode is its number
+/// modulo the number of shards. The root inode is the exception, it lives in the shard of its
+/// alt key which is recorded in `root_shard`.
+///
+/// Entries are only added or removed with the lock of their shard held for writing, so the
+/// refcount protocol between `do_lookup()` and `forget_one()` holds within each shard: a lookup
+/// never revives an inode whose refcount dropped to zero.
 struct InodeMap {
-    inodes: RwLock<MultiKeyMap>,
+    shards: Vec<RwLock<MultiKeyMap>>,
+    root_shard: AtomicUsize,
+    // Sequence number of the next allocated inode, shared by all shards.
+    next_seq: AtomicU64,
+    // Number of times a shard was locked for writing.
+    #[cfg(test)]
+    write_locks: AtomicUsize,
 }
 
 impl InodeMap {
     fn new() -> Self {
+        Self::with_shards(MAP_SHARDS)
+    }
+
+    fn with_shards(shards: usize) -> Self {
         InodeMap {
-            inodes: RwLock::new(MultikeyBTreeMap::new()),
+            shards: (0..shards)
+                .map(|_| RwLock::new(MultikeyBTreeMap::new()))
+                .collect(),
+            root_shard: AtomicUsize::new(0),
+            next_seq: AtomicU64::new(fuse::ROOT_ID + 1),
+            #[cfg(test)]
+            write_locks: AtomicUsize::new(0),
         }
     }
 
     fn clear(&self) {
         // Do not expect poisoned lock here, so safe to unwrap().
-        self.inodes.write().unwrap().clear();
+        for shard in self.shards.iter() {
+            shard.write().unwrap().clear();
+        }
+    }
+
+    // Remove all inodes except the root.
+    fn clear_except_root(&self) {
+        for inodes in self.get_all_mut().iter_mut() {
+            let keys = inodes
+                .keys()
+                .copied()
+                .filter(|inode| *inode != fuse::ROOT_ID)
+                .collect::<Vec<_>>();
+            for inode in keys.iter() {
+                inodes.remove(inode);
+            }
+        }
+    }
+
+    // Get the shard of the inode number `inode`.
+    fn shard_of(&self, inode: Inode) -> usize {
+        if inode == fuse::ROOT_ID {
+            self.root_shard.load(Ordering::Acquire)
+        } else {
+            (inode % self.shards.len() as u64) as usize
+        }
+    }
+
+    // Get the shard of inodes with the ids alt key `ids_altkey`.
+    fn shard_of_alt(&self, ids_altkey: &InodeAltKey) -> usize {
+        let hash = match ids_altkey {
+            InodeAltKey::Ids { ino, dev, mnt } => (ino ^ dev.rotate_left(21) ^ mnt.rotate_left(42))
+                .wrapping_mul(0x9e37_79b9_7f4a_7c15),
+            // Ids alt keys are always ids.
+            InodeAltKey::Handle(_) => 0,
+        };
+        ((hash >> 32) % self.shards.len() as u64) as usize
+    }
+
+    // Allocate a new inode number in `shard`.
+    fn next_inode(&self, shard: usize) -> Inode {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        seq.saturating_mul(self.shards.len() as u64)
+            .saturating_add(shard as u64)
     }
 
     fn get(&self, inode: Inode) -> io::Result<Arc<InodeData>> {
-        // Do not expect poisoned lock here, so safe to unwrap().
-        self.inodes
-            .read()
-            .unwrap()
-            .get(&inode)
-            .map(Arc::clone)
-            .ok_or_else(ebadf)
+        loop {
+            let shard = self.shard_of(inode);
+            // Do not expect poisoned lock here, so safe to unwrap().
+            if let Some(data) = self.shards[shard].read().unwrap().get(&inode) {
+                return Ok(Arc::clone(data));
+            }
+            // The root inode may have moved to another shard in the meantime.
+            if inode != fuse::ROOT_ID || shard == self.shard_of(inode) {
+                return Err(ebadf());
+            }
+        }
     }
 
     fn get_alt(
@@ -191,7 +390,7 @@ impl InodeMap {
         handle_altkey: Option<&InodeAltKey>,
     ) -> Option<Arc<InodeData>> {
         // Do not expect poisoned lock here, so safe to unwrap().
-        let inodes = self.inodes.read().unwrap();
+        let inodes = self.shards[self.shard_of_alt(ids_altkey)].read().unwrap();
 
         Self::get_alt_locked(inodes.deref(), ids_altkey, handle_altkey)
     }
@@ -219,9 +418,42 @@ impl InodeMap {
             .map(Arc::clone)
     }
 
-    fn get_map_mut(&self) -> RwLockWriteGuard<MultiKeyMap> {
+    // Lock the shard holding `inode` for writing.
+    fn get_map_mut(&self, inode: Inode) -> RwLockWriteGuard<'_, MultiKeyMap> {
+        self.get_shard_mut(self.shard_of(inode))
+    }
+
+    // Lock the shard `shard` for writing.
+    fn get_shard_mut(&self, shard: usize) -> RwLockWriteGuard<'_, MultiKeyMap> {
+        #[cfg(test)]
+        self.write_locks.fetch_add(1, Ordering::Relaxed);
+        // Do not expect poisoned lock here, so safe to unwrap().
+        self.shards[shard].write().unwrap()
+    }
+
+    // Lock the shard of inodes with the ids alt key `ids_altkey` for writing, return its index
+    // for `next_inode()`.
+    fn get_alt_map_mut(
+        &self,
+        ids_altkey: &InodeAltKey,
+    ) -> (usize, RwLockWriteGuard<'_, MultiKeyMap>) {
+        let shard = self.shard_of_alt(ids_altkey);
+        // Do not expect poisoned lock here, so safe to unwrap().
+        (shard, self.shards[shard].write().unwrap())
+    }
+
+    // Lock all shards for writing, in order.
+    fn get_all_mut(&self) -> Vec<RwLockWriteGuard<'_, MultiKeyMap>> {
         // Do not expect poisoned lock here, so safe to unwrap().
-        self.inodes.write().unwrap()
+        self.shards.iter().map(|s| s.write().unwrap()).collect()
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| s.read().unwrap().keys().count())
+            .sum()
     }
 
     fn insert(
@@ -231,7 +463,10 @@ impl InodeMap {
         ids_altkey: InodeAltKey,
         handle_altkey: Option<InodeAltKey>,
     ) {
-        let mut inodes = self.get_map_mut();
+        let (shard, mut inodes) = self.get_alt_map_mut(&ids_altkey);
+        if inode == fuse::ROOT_ID {
+            self.root_shard.store(shard, Ordering::Release);
+        }
 
         Self::insert_locked(inodes.deref_mut(), inode, data, ids_altkey, handle_altkey)
     }
@@ -255,6 +490,11 @@ struct HandleData {
     inode: Inode,
     file: File,
     lock: Mutex<()>,
+    // Snapshot of entries of directory handles, used by `Config::snapshot_readdir`.
+    dir_state: Mutex<DirState>,
+    // `caps_gen` of the inode plus one when the file was found without `security.capability`,
+    // zero if unknown.
+    no_caps: AtomicU64,
 }
 
 impl HandleData {
@@ -263,6 +503,8 @@ impl HandleData {
             inode,
             file,
             lock: Mutex::new(()),
+            dir_state: Mutex::new(DirState::Unread),
+            no_caps: AtomicU64::new(0),
         }
     }
 
@@ -279,30 +521,48 @@ impl HandleData {
     }
 }
 
+// Open handles, spread over shards by handle number.
 struct HandleMap {
-    handles: RwLock<BTreeMap<Handle, Arc<HandleData>>>,
+    shards: Vec<RwLock<BTreeMap<Handle, Arc<HandleData>>>>,
 }
 
 impl HandleMap {
     fn new() -> Self {
         HandleMap {
-            handles: RwLock::new(BTreeMap::new()),
+            shards: (0..MAP_SHARDS)
+                .map(|_| RwLock::new(BTreeMap::new()))
+                .collect(),
         }
     }
 
+    fn shard(&self, handle: Handle) -> &RwLock<BTreeMap<Handle, Arc<HandleData>>> {
+        &self.shards[(handle % self.shards.len() as u64) as usize]
+    }
+
     fn clear(&self) {
         // Do not expect poisoned lock here, so safe to unwrap().
-        self.handles.write().unwrap().clear();
+        for shard in self.shards.iter() {
+            shard.write().unwrap().clear();
+        }
     }
 
     fn insert(&self, handle: Handle, data: HandleData) {
         // Do not expect poisoned lock here, so safe to unwrap().
-        self.handles.write().unwrap().insert(handle, Arc::new(data));
+        self.shard(handle)
+            .write()
+            .unwrap()
+            .insert(handle, Arc::new(data));
+    }
+
+    // Insert the handle `data` also referenced elsewhere, like by the directory fd cache.
+    fn insert_shared(&self, handle: Handle, data: Arc<HandleData>) {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        self.shard(handle).write().unwrap().insert(handle, data);
     }
 
     fn release(&self, handle: Handle, inode: Inode) -> io::Result<()> {
         // Do not expect poisoned lock here, so safe to unwrap().
-        let mut handles = self.handles.write().unwrap();
+        let mut handles = self.shard(handle).write().unwrap();
 
         if let btree_map::Entry::Occupied(e) = handles.entry(handle) {
             if e.get().inode == inode {
@@ -318,7 +578,7 @@ impl HandleMap {
 
     fn get(&self, handle: Handle, inode: Inode) -> io::Result<Arc<HandleData>> {
         // Do not expect poisoned lock here, so safe to unwrap().
-        self.handles
+        self.shard(handle)
             .read()
             .unwrap()
             .get(&handle)
@@ -341,11 +601,17 @@ unsafe impl ByteValued for LinuxDirent64 {}
 /// The caching policy that the file system should report to the FUSE client. By default the FUSE
 /// protocol uses close-to-open consistency. This means that any cached contents of the file are
 /// invalidated the next time that file is opened.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config-serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
 pub enum CachePolicy {
     /// The client should never cache file data and all I/O should be directly forwarded to the
     /// server. This policy must be selected when file contents may change without the knowledge of
     /// the FUSE client (i.e., the file system does not have exclusive access to the directory).
+    #[cfg_attr(feature = "config-serde", serde(alias = "none"))]
     Never,
 
     /// The client is free to choose when and how to cache file data. This is the default policy and
@@ -378,8 +644,135 @@ impl Default for CachePolicy {
     }
 }
 
+/// The policy to handle access time updates of backing files.
+///
+/// With atime updates enabled on the host, read-heavy workloads may generate massive inode
+/// writeback on the host, so the policy could be used to reduce atime updates.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(
+    feature = "config-serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum AtimePolicy {
+    /// Forward all atime updates to the backing filesystem.
+    #[default]
+    Passthrough,
+
+    /// Emulate `relatime`: atime-only updates are only forwarded to the backing filesystem when
+    /// the current atime is older than mtime/ctime, or is more than 24 hours old.
+    Relatime,
+
+    /// Open backing files with `O_NOATIME` when permitted and ignore atime-only updates.
+    NoAtime,
+}
+
+impl FromStr for AtimePolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "passthrough" | "Passthrough" | "PASSTHROUGH" => Ok(AtimePolicy::Passthrough),
+            "relatime" | "Relatime" | "RELATIME" => Ok(AtimePolicy::Relatime),
+            "noatime" | "NoAtime" | "NOATIME" => Ok(AtimePolicy::NoAtime),
+            _ => Err("invalid atime policy"),
+        }
+    }
+}
+
+/// Information about an open or create request, passed to an [OpenPolicy].
+#[derive(Debug, Clone, Copy)]
+pub struct OpenRequest<'a> {
+    /// Open flags used to open the backing file.
+    pub flags: u32,
+    /// File type and mode of the backing file.
+    pub mode: u32,
+    /// The configured cache policy.
+    pub cache_policy: CachePolicy,
+    /// Name of the file, which is best-effort and may be unavailable.
+    pub name: Option<&'a CStr>,
+}
+
+impl OpenRequest<'_> {
+    /// Check whether the request opens a directory.
+    pub fn is_dir(&self) -> bool {
+        self.flags & (libc::O_DIRECTORY as u32) != 0 || self.mode & libc::S_IFMT == libc::S_IFDIR
+    }
+}
+
+/// Callback to choose the `OpenOptions` replied to open and create requests.
+///
+/// The callback is invoked on every open, so it should be cheap. Bits not applicable to the
+/// opened file are masked out from the returned options, see [OpenPolicy::allowed_options].
+#[derive(Clone)]
+pub struct OpenPolicy(Arc<dyn Fn(&OpenRequest) -> OpenOptions + Send + Sync>);
+
+impl OpenPolicy {
+    /// Create an open policy from a callback.
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&OpenRequest) -> OpenOptions + Send + Sync + 'static,
+    {
+        OpenPolicy(Arc::new(f))
+    }
+
+    /// The default policy, derived from the cache policy only.
+    ///
+    /// `CachePolicy::Never` enables direct I/O for non-directories and `CachePolicy::Always`
+    /// keeps the page cache across opens, also caching directory entries for directories.
+    /// Custom policies may delegate to this one.
+    pub fn default_options(req: &OpenRequest) -> OpenOptions {
+        match req.cache_policy {
+            CachePolicy::Never if !req.is_dir() => OpenOptions::DIRECT_IO,
+            CachePolicy::Always if req.is_dir() => OpenOptions::KEEP_CACHE | OpenOptions::CACHE_DIR,
+            CachePolicy::Always => OpenOptions::KEEP_CACHE,
+            _ => OpenOptions::empty(),
+        }
+    }
+
+    /// Get the options allowed to be replied for the request.
+    ///
+    /// `CACHE_DIR` only applies to directories, and `DIRECT_IO`, `NONSEEKABLE` and `STREAM`
+    /// only apply to non-directories.
+    pub fn allowed_options(req: &OpenRequest) -> OpenOptions {
+        if req.is_dir() {
+            OpenOptions::KEEP_CACHE | OpenOptions::CACHE_DIR
+        } else {
+            OpenOptions::DIRECT_IO
+                | OpenOptions::KEEP_CACHE
+                | OpenOptions::NONSEEKABLE
+                | OpenOptions::STREAM
+        }
+    }
+
+    /// Apply the policy to a request.
+    pub fn options(&self, req: &OpenRequest) -> OpenOptions {
+        (self.0)(req) & Self::allowed_options(req)
+    }
+}
+
+impl std::fmt::Debug for OpenPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("OpenPolicy")
+    }
+}
+
+impl PartialEq for OpenPolicy {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
 /// Options that configure the behavior of the passthrough fuse file system.
+///
+/// With the `config-serde` feature, configs may be loaded from any serde format, see
+/// [Config::validate] for the checks run on load.
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "config-serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(remote = "Self", default)
+)]
 pub struct Config {
     /// How long the FUSE client should consider directory entries to be valid. If the contents of a
     /// directory can only be modified by the FUSE client (i.e., the file system has exclusive
@@ -397,6 +790,7 @@ pub struct Config {
 
     /// The caching policy the file system should use. See the documentation of `CachePolicy` for
     /// more details.
+    #[cfg_attr(feature = "config-serde", serde(alias = "cache"))]
     pub cache_policy: CachePolicy,
 
     /// Whether the file system should enabled writeback caching. This can improve performance as it
@@ -405,7 +799,8 @@ pub struct Config {
     /// contents can change without the knowledge of the FUSE client (i.e., the server does **NOT**
     /// have exclusive access). Additionally, the file system should have read access to all files
     /// in the directory it is serving as the FUSE client may send read requests even for files
-    /// opened with `O_WRONLY`.
+    /// opened with `O_WRONLY`. Files are opened and created without `O_APPEND` on the host, as the
+    /// FUSE client computes the offsets of appends itself.
     ///
     /// Therefore callers should only enable this option when they can guarantee that: 1) the file
     /// system has exclusive access to the directory and 2) the file system has read permissions for
@@ -414,9 +809,19 @@ pub struct Config {
     /// The default value for this option is `false`.
     pub writeback: bool,
 
+    /// Whether to move the data of writes into files with `splice(2)`, when the transport leaves
+    /// it in a pipe, instead of copying it from the request buffer. See
+    /// `FuseSession::set_splice_write`.
+    ///
+    /// Writes fall back to copying the data if the file doesn't support splice.
+    ///
+    /// The default value for this option is `false`.
+    pub use_splice_write: bool,
+
     /// The path of the root directory.
     ///
     /// The default is `/`.
+    #[cfg_attr(feature = "config-serde", serde(alias = "shared_dir"))]
     pub root_dir: String,
 
     /// Whether the file system should support Extended Attributes (xattr). Enabling this feature may
@@ -426,6 +831,18 @@ pub struct Config {
     /// The default value for this options is `false`.
     pub xattr: bool,
 
+    /// Rules to translate xattr names between the guest and the host, for example to store
+    /// `security.*` xattrs as `user.*` ones when running unprivileged. See [XattrMap].
+    ///
+    /// The default value for this option is `None`.
+    pub xattr_map: Option<XattrMap>,
+
+    /// Limits of the sizes of xattr names, values and name lists, checked before reaching the
+    /// host so buffers allocated for xattrs are bounded.
+    ///
+    /// The default value for this option is the limits of the Linux VFS.
+    pub xattr_limits: XattrLimits,
+
     /// To be compatible with Vfs and PseudoFs, PassthroughFs needs to prepare
     /// root inode before accepting INIT request.
     ///
@@ -444,9 +861,42 @@ pub struct Config {
 
     /// Control whether kill_priv_v2 is enabled.
     ///
+    /// With kill_priv_v2, setuid and setgid bits are cleared by writes, truncations and opens with
+    /// `O_TRUNC` the guest asks privileges to be killed for, by dropping `CAP_FSETID` around the
+    /// syscall so the host kernel clears them. Without a Vfs, `FUSE_HANDLE_KILLPRIV_V2` is only
+    /// advertised when this option is set. Under a Vfs, it's negotiated by `VfsOptions::killpriv_v2`
+    /// instead, and the file system follows the negotiated options.
+    ///
     /// The default value for this option is `false`.
     pub killpriv_v2: bool,
 
+    /// Whether to negotiate `FsOptions::CREATE_SUPP_GROUP`, and switch the supplementary groups
+    /// of threads creating files to the group sent by the kernel, so callers may create files in
+    /// directories owned by one of their supplementary groups. Switching groups requires
+    /// `CAP_SETGID`, and only happens with `switch_creds`. Kernels not supporting the option
+    /// don't send groups, and files are created with the caller's uid and gid only.
+    ///
+    /// The default value for this option is `false`.
+    pub create_supp_group: bool,
+
+    /// Whether to negotiate `FsOptions::POSIX_ACL` and `FsOptions::DONT_MASK`, so the guest
+    /// kernel enforces posix ACLs. The umask of callers is then applied by the file system,
+    /// unless the parent directory has a default ACL, and ACLs are set with the credentials of
+    /// the caller. ACLs are stored in xattrs, so it's only negotiated with `xattr` enabled.
+    ///
+    /// The default value for this option is `false`.
+    pub posix_acl: bool,
+
+    /// Whether to negotiate `FsOptions::POSIX_LOCKS` and `FsOptions::FLOCK_LOCKS`, forwarding
+    /// fcntl(2) record locks and flock(2) locks of the guest to the host, so they conflict with
+    /// locks of other guests and of host processes. Otherwise locks are only enforced by the
+    /// guest kernel. Record locks of each guest process are taken as OFD locks on a file
+    /// reopened for it, and released when it closes the file. Locks don't survive a restart of
+    /// the daemon. Under a `Vfs`, the options must be in `VfsOptions::out_opts` too.
+    ///
+    /// The default value for this option is `false`.
+    pub posix_locks: bool,
+
     /// Whether to use file handles to reference inodes.  We need to be able to open file
     /// descriptors for arbitrary inodes, and by default that is done by storing an `O_PATH` FD in
     /// `InodeData`.  Not least because there is a maximum number of FDs a process can have open
@@ -456,6 +906,10 @@ pub struct Config {
     /// will store `O_PATH` FDs.  Otherwise, we will attempt to generate and store a file handle
     /// instead.
     ///
+    /// Inodes on file systems without file handles keep `O_PATH` FDs. Handles are only opened with
+    /// `CAP_DAC_READ_SEARCH`, `O_PATH` FDs are used for all inodes if `import()` finds the handle of
+    /// the root directory can't be opened.
+    ///
     /// The default is `false`.
     pub inode_file_handles: bool,
 
@@ -463,12 +917,269 @@ pub struct Config {
     /// directory is empty even if it has children.
     pub no_readdir: bool,
 
+    /// Whether to disable readdirplus, so the guest lists directories with plain readdir.
+    ///
+    /// Readdirplus looks up each listed entry, huge directories scanned once fill the inode map
+    /// with entries forgotten later in storms. Without a Vfs, `FsOptions::DO_READDIRPLUS` and
+    /// `FsOptions::READDIRPLUS_AUTO` aren't negotiated when this option is set. Under a Vfs, they
+    /// are negotiated by `VfsOptions::out_opts` instead.
+    ///
+    /// The default value for this option is `false`.
+    pub no_readdirplus: bool,
+
+    /// Whether to let the guest kernel choose between readdir and readdirplus, by negotiating
+    /// `FsOptions::READDIRPLUS_AUTO`. Otherwise the guest always uses readdirplus, unless
+    /// `no_readdirplus` is set.
+    ///
+    /// The default value for this option is `true`.
+    pub readdirplus_auto: bool,
+
     /// What size file supports dax
     /// * If dax_file_size == None, DAX will disable to all files.
     /// * If dax_file_size == 0, DAX will enable all files.
     /// * If dax_file_size == N, DAX will enable only when the file size is greater than or equal
     /// to N Bytes.
     pub dax_file_size: Option<u64>,
+
+    /// The policy to handle access time updates. See the documentation of `AtimePolicy` for more
+    /// details.
+    ///
+    /// The default value for this option is `AtimePolicy::Passthrough`.
+    pub atime_policy: AtimePolicy,
+
+    /// Policy to choose the `OpenOptions` replied to open and create requests. See the
+    /// documentation of `OpenPolicy` for more details.
+    ///
+    /// The default value for this option is `None`, which uses `OpenPolicy::default_options`.
+    /// Open policies are callbacks, so they're never serialized.
+    #[cfg_attr(feature = "config-serde", serde(skip))]
+    pub open_policy: Option<OpenPolicy>,
+
+    /// Whether to reopen the root directory automatically when it goes stale, for example when
+    /// the shared directory is deleted and recreated on the host. See
+    /// `PassthroughFs::reopen_root()` for more details.
+    ///
+    /// The default value for this option is `false`.
+    pub reopen_stale_root: bool,
+
+    /// Whether to set the SELinux context of inodes created on the host, by writing the context
+    /// to the fscreate attribute of the serving thread before creating them. It's a no-op when
+    /// SELinux is disabled on the host.
+    ///
+    /// The default value for this option is `false`.
+    pub host_setfscreate: bool,
+
+    /// SELinux contexts of inodes created by `host_setfscreate`, as pairs of a host directory
+    /// and a context. Inodes get the context of the longest directory containing their parent,
+    /// inodes outside of all directories keep the default labeling.
+    ///
+    /// The default value for this option is empty.
+    pub fscreate_labels: Vec<(String, String)>,
+
+    /// Max length in bytes of symlink targets. Creating symlinks with longer targets fails with
+    /// `ENAMETOOLONG`, and so does reading host symlinks with longer targets, instead of
+    /// returning truncated targets.
+    ///
+    /// The default value for this option is `libc::PATH_MAX - 1`.
+    pub max_symlink_target: usize,
+
+    /// Max number of directory entries per readdir request whose type is queried by statx(2),
+    /// when the host file system reports them as `DT_UNKNOWN`. Entries beyond the budget are
+    /// returned as `DT_UNKNOWN`, and 0 disables the fallback. Readdirplus requests take types from
+    /// the attributes of the entries instead, regardless of the budget.
+    ///
+    /// The default value for this option is 128.
+    pub dtype_fallback_budget: u32,
+
+    /// Whether to read all entries of a directory handle when it's first read, and serve later
+    /// readdir requests of the handle from the snapshot until it's released. Guests then see a
+    /// consistent view of directories changed on the host while being iterated, without entries
+    /// duplicated or missed.
+    ///
+    /// The default value for this option is `false`.
+    pub snapshot_readdir: bool,
+
+    /// Max number of entries of a directory snapshot. Larger directories are read from the host
+    /// by each readdir request, as if `snapshot_readdir` were disabled.
+    ///
+    /// The default value for this option is 65536.
+    pub snapshot_readdir_max_entries: usize,
+
+    /// Max bytes of memory used by directory snapshots of all handles. Directories whose snapshot
+    /// doesn't fit in the memory left are read from the host by each readdir request.
+    ///
+    /// The default value for this option is 64MB.
+    pub snapshot_readdir_memory: usize,
+
+    /// Granularity of timestamps of the backing file system in nanoseconds, a power of ten of at
+    /// most one second. It's replied to the kernel, and timestamps set by the guest are truncated
+    /// to it. `None` means to detect it when initializing the file system, falling back to one
+    /// nanosecond if the root directory doesn't support temporary files.
+    ///
+    /// The default value for this option is `None`.
+    pub time_gran: Option<u32>,
+
+    /// Whether to switch the effective uid and gid of the thread to the caller's when creating
+    /// files, so they're owned by the caller. The switch is skipped when the caller has the same
+    /// ids as the daemon. Daemons running with a fixed identity may disable it, then all files are
+    /// created with the daemon's credentials.
+    ///
+    /// The default value for this option is true.
+    pub switch_creds: bool,
+
+    /// Ranges of uids and gids shifted between the guest and the host, for rootless containers.
+    /// Callers' ids and owners set by `chown` are translated into host ids, owners of host files
+    /// into guest ids. See [UidGidMap].
+    ///
+    /// The default value for this option is `None`, which passes ids through unchanged.
+    pub uid_gid_map: Option<UidGidMap>,
+
+    /// Policy to retry operations only reading the host, stat, open, getxattr and readdir, when
+    /// they fail with transient errors like `ESTALE` returned by network file systems during
+    /// failover. Operations modifying the host are never retried.
+    ///
+    /// The default value for this option is `None`, which passes through all errors immediately.
+    pub retry_policy: Option<RetryPolicy>,
+
+    /// Copy file ranges by reading and writing the files in the daemon when the host kernel can't
+    /// copy them, like files on different mounts, instead of failing `FUSE_COPY_FILE_RANGE` with
+    /// `EXDEV` or `EOPNOTSUPP`. Guests then fall back to much slower copies through their page
+    /// cache. Holes of sparse files are kept.
+    ///
+    /// The default value for this option is false.
+    pub enable_xdev_copy_fallback: bool,
+
+    /// Fields guests may change by the `FS_IOC_FSSETXATTR` ioctl, to set project ids and inode
+    /// flags of host files. `FS_IOC_FSGETXATTR` and `FS_IOC_FSSETXATTR` fail with `ENOTTY` when
+    /// unset. See [FsxattrPolicy].
+    ///
+    /// The default value for this option is `None`.
+    pub fsxattr: Option<FsxattrPolicy>,
+
+    /// Mask of the inode flags guests may set or clear by the `FS_IOC_SETFLAGS` ioctl, like
+    /// [FS_IMMUTABLE_FL] for `chattr +i`. `FS_IOC_GETFLAGS` and `FS_IOC_SETFLAGS` fail with
+    /// `ENOTTY` when unset.
+    ///
+    /// The default value for this option is `None`.
+    pub inode_flags: Option<u32>,
+
+    /// Allow opening block device nodes read-only, to serve the content of the devices. Their size
+    /// is reported by getattr and lookup instead of the `st_size` of the node. Other device nodes
+    /// still can't be opened.
+    ///
+    /// The default value for this option is false.
+    pub allow_blockdev_read: bool,
+
+    /// Also allow opening block device nodes for writing when `allow_blockdev_read` is set,
+    /// otherwise such opens fail with `EROFS`.
+    ///
+    /// The default value for this option is false.
+    pub allow_blockdev_write: bool,
+
+    /// Block size reported in `st_blksize` of all inodes instead of the one of the host file
+    /// system, a power of two of at least 512, for workloads needing a stable value when files
+    /// move between backing file systems. `st_blocks` is always counted in 512 bytes units, so
+    /// it's left as is.
+    ///
+    /// The default value for this option is `None`.
+    pub blksize: Option<u32>,
+
+    /// Allocate inode numbers and handles deterministically, for tests comparing replies across
+    /// runs and hosts. Inode numbers are allocated sequentially in lookup order, instead of
+    /// depending on the ids of host files, and handles sequentially from 1. Inodes then share a
+    /// single lock. Only allocation choices change, not the wire format.
+    ///
+    /// The default value for this option is false.
+    pub deterministic: bool,
+
+    /// Announce directories which are roots of other host mounts, like bind mounts in the shared
+    /// directory, by `FUSE_ATTR_SUBMOUNT` in lookup and readdirplus replies when the kernel
+    /// supports `FsOptions::SUBMOUNTS`. The guest then mounts them as separate file systems with
+    /// their own `st_dev`, so `find -xdev` and container engines see mount boundaries. Mount
+    /// boundaries are detected by the mount ids of directories and their parents, or their
+    /// `st_dev` when mount ids are synthesized.
+    ///
+    /// The default value for this option is false.
+    pub announce_submounts: bool,
+
+    /// Reply `ENOSYS` to statx requests, for shared directories on file systems where statx(2)
+    /// is slow. The guest then falls back to getattr, without creation times.
+    ///
+    /// The default value for this option is false.
+    pub no_statx: bool,
+
+    /// Don't stat inodes again after renames, hard links and extended attribute changes.
+    ///
+    /// Attributes replied to the guest are recorded for each inode, to detect truncation of
+    /// backing files on the host and for `atime_policy`. Renames, hard links and extended
+    /// attribute changes update the ctime, and the link count or the mtime of directories, without
+    /// replying attributes, so the inodes involved are stat()ed again through their fds to record
+    /// the attributes after the change. Setattr and link reply attributes read after the change
+    /// whatever the option, through the handle fd when the request has one.
+    ///
+    /// The default value for this option is false.
+    pub no_restat: bool,
+
+    /// Open no absolute path once imported, for daemons confined by strict seccomp policies or
+    /// by `PassthroughFs::enter_sandbox()`. `import()` captures fds of the shared directory and
+    /// of `/proc/self/fd`, and changes the working directory of the process to the latter.
+    /// Files are then only opened relative to them. Fails to import if `/proc/self/fd` is
+    /// unavailable.
+    ///
+    /// The default value for this option is false.
+    pub sandbox: bool,
+
+    /// Honour `O_DIRECT` in guest open and create requests, opening host files with it and
+    /// replying `FOPEN_DIRECT_IO` so the guest page cache is bypassed as well. Misaligned
+    /// requests then fail with the `EINVAL` of the host file system. Otherwise `O_DIRECT` is
+    /// dropped from host opens, as host file systems like tmpfs may not support it.
+    ///
+    /// The default value for this option is false.
+    pub allow_direct_io: bool,
+
+    /// Emulate `FALLOC_FL_PUNCH_HOLE` and `FALLOC_FL_ZERO_RANGE` by writing zeros when the
+    /// backing file system doesn't support them, instead of failing with `EOPNOTSUPP`. The
+    /// emulation doesn't deallocate space, and isn't atomic with respect to concurrent writes of
+    /// the range. Other modes, like `FALLOC_FL_COLLAPSE_RANGE`, are never emulated.
+    ///
+    /// The default value for this option is false.
+    pub emulate_fallocate: bool,
+
+    /// Number of directories kept open for reading, least recently used first out, to resolve
+    /// lookups and `*at` syscalls in directories referenced by `Config::inode_file_handles`
+    /// without opening them again, and to serve readdir without opendir. Handles of opendir
+    /// share the fds of cached directories, unless `Config::snapshot_readdir` is set. Zero
+    /// disables the cache.
+    ///
+    /// The default value for this option is 0.
+    pub dir_fd_cache_size: usize,
+
+    /// Check before opening, reading, writing and setting attributes of a file that the name it
+    /// was last looked up with still refers to it on the host, failing with `ESTALE` otherwise so
+    /// the guest looks it up again. Without it, files removed and created again on the host are
+    /// served from the removed file until the entry times out in the guest. Each checked request
+    /// costs an open and a stat of the name.
+    ///
+    /// The default value for this option is false.
+    pub validate_inodes: bool,
+
+    /// Fail mknod of character and block devices with `EPERM` whatever the capabilities of the
+    /// caller, and opening device nodes in the shared directory with `ENXIO`, unless block
+    /// devices are passed through by `allow_blockdev_read`. Privileged daemons should set it, so
+    /// that guests can't reach host devices through the nodes they create.
+    ///
+    /// The default value for this option is false.
+    pub no_device_nodes: bool,
+
+    /// Fail mknod of FIFOs with `EPERM`.
+    ///
+    /// The default value for this option is false.
+    pub no_fifo_nodes: bool,
+
+    /// Fail mknod of sockets with `EPERM`.
+    ///
+    /// The default value for this option is false.
+    pub no_socket_nodes: bool,
 }
 
 impl Default for Config {
@@ -478,16 +1189,84 @@ impl Default for Config {
             attr_timeout: Duration::from_secs(5),
             cache_policy: Default::default(),
             writeback: false,
+            use_splice_write: false,
             root_dir: String::from("/"),
             xattr: false,
+            xattr_map: None,
+            xattr_limits: XattrLimits::default(),
             do_import: true,
             no_open: false,
             no_opendir: false,
             killpriv_v2: false,
+            create_supp_group: false,
+            posix_acl: false,
+            posix_locks: false,
             inode_file_handles: false,
             no_readdir: false,
+            no_readdirplus: false,
+            readdirplus_auto: true,
             dax_file_size: None,
+            atime_policy: AtimePolicy::Passthrough,
+            open_policy: None,
+            reopen_stale_root: false,
+            host_setfscreate: false,
+            fscreate_labels: Vec::new(),
+            max_symlink_target: libc::PATH_MAX as usize - 1,
+            dtype_fallback_budget: 128,
+            snapshot_readdir: false,
+            snapshot_readdir_max_entries: 65536,
+            snapshot_readdir_memory: 64 << 20,
+            time_gran: None,
+            switch_creds: true,
+            uid_gid_map: None,
+            retry_policy: None,
+            enable_xdev_copy_fallback: false,
+            fsxattr: None,
+            inode_flags: None,
+            allow_blockdev_read: false,
+            allow_blockdev_write: false,
+            blksize: None,
+            deterministic: false,
+            announce_submounts: false,
+            no_statx: false,
+            no_restat: false,
+            sandbox: false,
+            allow_direct_io: false,
+            emulate_fallocate: false,
+            dir_fd_cache_size: 0,
+            validate_inodes: false,
+            no_device_nodes: false,
+            no_fifo_nodes: false,
+            no_socket_nodes: false,
+        }
+    }
+}
+
+impl Config {
+    /// Check that the options are consistent, failing with `EINVAL` otherwise.
+    ///
+    /// [PassthroughFs::new] fails on invalid options, and with the `config-serde` feature,
+    /// deserializing an invalid config fails too.
+    pub fn validate(&self) -> io::Result<()> {
+        if matches!(self.retry_policy, Some(p) if p.max_attempts == 0) {
+            return Err(fuse_errno(
+                libc::EINVAL,
+                "retry policy needs at least one attempt",
+            ));
+        }
+        if let Some(size) = self.blksize.filter(|s| *s < 512 || !s.is_power_of_two()) {
+            return Err(fuse_errno(
+                libc::EINVAL,
+                format!("invalid reported block size {}", size),
+            ));
         }
+        if let Some(gran) = self.time_gran.filter(|g| !time_gran::is_valid(*g)) {
+            return Err(fuse_errno(
+                libc::EINVAL,
+                format!("invalid timestamp granularity {}", gran),
+            ));
+        }
+        Ok(())
     }
 }
 
@@ -504,7 +1283,6 @@ pub struct PassthroughFs<S: BitmapSlice + Send + Sync = ()> {
     // documentation of the `O_PATH` flag in `open(2)` for more details on what one can and cannot
     // do with an fd opened with this flag.
     inode_map: InodeMap,
-    next_inode: AtomicU64,
 
     // File descriptors for open files and directories. Unlike the fds in `inodes`, these _can_ be
     // used for reading and writing data.
@@ -517,11 +1295,17 @@ pub struct PassthroughFs<S: BitmapSlice + Send + Sync = ()> {
     // File descriptor pointing to the `/proc/self/fd` directory. This is used to convert an fd from
     // `inodes` into one that can go into `handles`. This is accomplished by reading the
     // `/proc/self/fd/{}` symlink. We keep an open fd here in case the file system tree that we are meant
-    // to be serving doesn't have access to `/proc/self/fd`.
-    proc_self_fd: File,
+    // to be serving doesn't have access to `/proc/self/fd`. `None` if `/proc/self/fd` is unavailable.
+    proc_self_fd: Option<File>,
+
+    // Syscalls reopening inodes through `/proc/self/fd`.
+    proc_sys: Box<dyn ProcSyscalls>,
+
+    // File descriptor of the shared directory, captured by `import()` in sandbox mode.
+    sandbox_root: RwLock<Option<File>>,
 
     // Whether writeback caching is enabled for this directory. This will only be true when
-    // `cfg.writeback` is true and `init` was called with `FsOptions::WRITEBACK_CACHE`.
+    // `RuntimeConfig::writeback` is true and `init` was called with `FsOptions::WRITEBACK_CACHE`.
     writeback: AtomicBool,
 
     // Whether no_open is enabled.
@@ -533,6 +1317,12 @@ pub struct PassthroughFs<S: BitmapSlice + Send + Sync = ()> {
     // Whether kill_priv_v2 is enabled.
     killpriv_v2: AtomicBool,
 
+    // Whether supplementary groups sent by `FsOptions::CREATE_SUPP_GROUP` are switched.
+    supp_group: AtomicBool,
+
+    // Whether posix ACLs are enabled.
+    posix_acl: AtomicBool,
+
     // Whether no_readdir is enabled.
     no_readdir: AtomicBool,
 
@@ -540,7 +1330,77 @@ pub struct PassthroughFs<S: BitmapSlice + Send + Sync = ()> {
     // Init from guest kernel Init cmd of fuse fs.
     perfile_dax: AtomicBool,
 
+    // Whether `FUSE_ATTR_SUBMOUNT` is set on roots of other mounts, negotiated by init.
+    announce_submounts: AtomicBool,
+
+    // Whether inodes are referenced by file handles, cleared by `import()` when the handles can't
+    // be opened.
+    inode_file_handles: AtomicBool,
+
     cfg: Config,
+    // Options changed at runtime by `update_config()`, initialized from `cfg`.
+    runtime_cfg: ArcSwap<RuntimeConfig>,
+    // Serialize updates of `runtime_cfg` with `init()`.
+    runtime_lock: Mutex<()>,
+    // Whether `init()` has negotiated the options of the connection.
+    initialized: AtomicBool,
+
+    // Quota reporting, enabled by `with_quota_provider()`.
+    quota: Option<QuotaCache>,
+
+    // Query attributes and mount ids of backing files.
+    stat_helper: StatHelper,
+    // Read directory entries of backing directories.
+    dir_sys: Box<dyn DirSyscalls>,
+    // Memory budget of directory snapshots.
+    dir_snapshots: Arc<SnapshotBudget>,
+    // Count readdir and readdirplus requests.
+    readdir_counters: ReaddirCounters,
+    // Preallocate space of backing files.
+    falloc_helper: FallocHelper,
+    // Parent directory and name hints of inodes.
+    path_hints: PathHints,
+    // Directories kept open by `Config::dir_fd_cache_size`.
+    dir_fds: DirFdCache,
+    // Probe the timestamp granularity of the backing file system.
+    time_gran_sys: Box<dyn TimeGranSyscalls>,
+    // Timestamp granularity in nanoseconds, set by `init()`.
+    time_gran: AtomicU32,
+    // Get and set `struct fsxattr` of host files.
+    fsxattr_sys: Box<dyn FsxattrSyscalls>,
+    // Get the size of and read block devices passed through.
+    blockdev_sys: Box<dyn BlockdevSyscalls>,
+    // Set timestamps of backing files.
+    utimens_sys: Box<dyn UtimensSyscalls>,
+    // Switch credentials of threads creating files.
+    creds: CredSwitcher,
+    // Retry idempotent operations failing with transient errors.
+    retry: Retrier,
+    // Time source of the atime policy, retry backoff and the quota cache.
+    clock: Arc<dyn Clock>,
+    // Copy file ranges, with a fallback for files the kernel can't copy between.
+    copy_helper: CopyHelper,
+    // Bytes written to host files.
+    write_accounting: Option<Arc<WriteAccounting>>,
+    // Yield points of snapshots of directories and mapping of xattr lists.
+    yield_points: YieldPoints,
+
+    // Generation of the root directory, bumped each time the root is reopened.
+    root_generation: AtomicU64,
+    // Serialize reopening of the root directory.
+    root_lock: Mutex<()>,
+    // Notifier to invalidate inodes dropped when reopening the root directory.
+    notifier: Option<Arc<dyn Notifier>>,
+
+    // Requests waiting for a lock, interrupted by `FUSE_INTERRUPT`.
+    lock_waiters: LockWaiters,
+
+    // Writer of the SELinux fscreate attribute, used by `Config::host_setfscreate`.
+    fscreate_writer: Arc<dyn FsCreateWriter>,
+
+    // Executor of blocking calls in the asynchronous request path.
+    #[cfg(feature = "async-io")]
+    executor: Arc<dyn crate::api::executor::Executor>,
 
     phantom: PhantomData<S>,
 }
@@ -548,53 +1408,125 @@ pub struct PassthroughFs<S: BitmapSlice + Send + Sync = ()> {
 impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
     /// Create a Passthrough file system instance.
     pub fn new(cfg: Config) -> io::Result<PassthroughFs<S>> {
-        // Safe because this is a constant value and a valid C string.
-        let proc_self_fd_cstr = unsafe { CStr::from_bytes_with_nul_unchecked(PROC_SELF_FD_CSTR) };
-        let proc_self_fd = Self::open_file(
-            libc::AT_FDCWD,
-            proc_self_fd_cstr,
-            libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
-            0,
-        )?;
+        let proc_sys = Box::new(LibcProcSyscalls);
+        let proc_self_fd = proc_fd::probe_proc_self_fd(&*proc_sys);
+        let dir_snapshots = Arc::new(SnapshotBudget::new(cfg.snapshot_readdir_memory));
+        let creds = CredSwitcher::new(cfg.switch_creds);
+        cfg.validate()?;
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock::default());
+        let retry = Retrier::new(cfg.retry_policy, clock.clone());
+        let copy_helper = CopyHelper::new(cfg.enable_xdev_copy_fallback);
+        let falloc_helper = FallocHelper::new(cfg.emulate_fallocate);
+        let dir_fds = DirFdCache::new(cfg.dir_fd_cache_size);
 
         Ok(PassthroughFs {
-            inode_map: InodeMap::new(),
-            next_inode: AtomicU64::new(fuse::ROOT_ID + 1),
+            inode_map: if cfg.deterministic {
+                InodeMap::with_shards(1)
+            } else {
+                InodeMap::new()
+            },
 
             handle_map: HandleMap::new(),
             next_handle: AtomicU64::new(1),
             mount_fds: MountFds::new(),
 
             proc_self_fd,
+            proc_sys,
+            sandbox_root: RwLock::new(None),
 
             writeback: AtomicBool::new(false),
             no_open: AtomicBool::new(false),
             no_opendir: AtomicBool::new(false),
             killpriv_v2: AtomicBool::new(false),
+            supp_group: AtomicBool::new(false),
+            posix_acl: AtomicBool::new(false),
             no_readdir: AtomicBool::new(cfg.no_readdir),
             perfile_dax: AtomicBool::new(false),
+            announce_submounts: AtomicBool::new(false),
+            inode_file_handles: AtomicBool::new(cfg.inode_file_handles),
+            runtime_cfg: ArcSwap::new(Arc::new(RuntimeConfig::from(&cfg))),
+            runtime_lock: Mutex::new(()),
+            initialized: AtomicBool::new(false),
             cfg,
 
+            quota: None,
+            stat_helper: StatHelper::default(),
+            dir_sys: Box::new(LibcDirSyscalls),
+            dir_snapshots,
+            readdir_counters: ReaddirCounters::default(),
+            falloc_helper,
+            path_hints: PathHints::default(),
+            dir_fds,
+            time_gran_sys: Box::new(LibcTimeGranSyscalls),
+            time_gran: AtomicU32::new(1),
+            fsxattr_sys: Box::new(LibcFsxattrSyscalls),
+            blockdev_sys: Box::new(LibcBlockdevSyscalls),
+            utimens_sys: Box::new(LibcUtimensSyscalls),
+            creds,
+            retry,
+            clock,
+            copy_helper,
+            write_accounting: None,
+            yield_points: YieldPoints::default(),
+
+            root_generation: AtomicU64::new(0),
+            root_lock: Mutex::new(()),
+            notifier: None,
+
+            lock_waiters: LockWaiters::default(),
+
+            fscreate_writer: Arc::new(ProcFsCreateWriter::default()),
+
+            #[cfg(feature = "async-io")]
+            executor: Arc::new(crate::api::executor::TokioUringExecutor),
+
             phantom: PhantomData,
         })
     }
 
+    /// Read time from `clock` instead of the system clock, for the atime policy, retry backoff
+    /// and the quota cache.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.retry = self.retry.with_clock(clock.clone());
+        self.quota = self.quota.map(|q| q.with_clock(clock.clone()));
+        self.clock = clock;
+        self
+    }
+
+    /// Account bytes written to host files in `acct`, including copies of file ranges, and bytes
+    /// copied through the buffer of `Config::enable_xdev_copy_fallback`.
+    pub fn with_write_accounting(mut self, acct: Arc<WriteAccounting>) -> Self {
+        self.copy_helper = self.copy_helper.with_accounting(acct.clone());
+        self.write_accounting = Some(acct);
+        self
+    }
+
+    /// Consult `points` between chunks of long running operations: copies of file ranges done by
+    /// `Config::enable_xdev_copy_fallback`, snapshots of directories and mapping of xattr lists.
+    pub fn with_yield_points(mut self, points: YieldPoints) -> Self {
+        self.copy_helper = self.copy_helper.with_yield_points(points.clone());
+        self.yield_points = points;
+        self
+    }
+
     /// Initialize the Passthrough file system.
     pub fn import(&self) -> io::Result<()> {
-        let root = CString::new(self.cfg.root_dir.as_str()).expect("CString::new failed");
-
-        let (file_or_handle, st, ids_altkey, handle_altkey) = Self::open_file_or_handle(
-            self.cfg.inode_file_handles,
-            libc::AT_FDCWD,
-            &root,
-            &self.mount_fds,
-            |fd, flags, _mode| {
-                let pathname = CString::new(format!("{}", fd))
-                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-                Self::open_file(self.proc_self_fd.as_raw_fd(), &pathname, flags, 0)
-            },
-        )
-        .map_err(|e| {
+        if self.cfg.sandbox {
+            self.setup_sandbox()?;
+        }
+        let mut root = self.open_root();
+        if let Ok((FileOrHandle::Handle(h), ..)) = &root {
+            // Handles are created by anyone, but only opened with CAP_DAC_READ_SEARCH.
+            if let Err(e) = h.open_with_mount_fds(&self.mount_fds, libc::O_PATH) {
+                warn!(
+                    "fuse: import: file handles can't be opened, using O_PATH fds: {:?}",
+                    e
+                );
+                self.inode_file_handles.store(false, Ordering::Relaxed);
+                root = self.open_root();
+            }
+        }
+        let (file_or_handle, st, ids_altkey, handle_altkey) = root.map_err(|e| {
             error!("fuse: import: failed to get file or handle: {:?}", e);
             e
         })?;
@@ -607,13 +1539,7 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         // Not sure why the root inode gets a refcount of 2 but that's what libfuse does.
         self.inode_map.insert(
             fuse::ROOT_ID,
-            InodeData::new(
-                fuse::ROOT_ID,
-                file_or_handle,
-                2,
-                ids_altkey,
-                st.get_stat().st_mode,
-            ),
+            InodeData::new(fuse::ROOT_ID, file_or_handle, 2, ids_altkey, &st.get_stat()),
             ids_altkey,
             handle_altkey,
         );
@@ -621,9 +1547,32 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         Ok(())
     }
 
+    // Resolve the configured root directory.
+    fn open_root(&self) -> io::Result<(FileOrHandle, InodeStat, InodeAltKey, Option<InodeAltKey>)> {
+        let root = CString::new(self.cfg.root_dir.as_str()).expect("CString::new failed");
+        // In sandbox mode, the root is `.` of the fd captured by `import()`.
+        let (dir, root) = match self.sandbox_root_fd() {
+            Some(fd) => (fd, CString::new(".").unwrap()),
+            None => (libc::AT_FDCWD, root),
+        };
+
+        Self::open_file_or_handle(
+            self.inode_file_handles.load(Ordering::Relaxed),
+            dir,
+            &root,
+            &self.mount_fds,
+            &self.stat_helper,
+            |fd, flags, mode| self.reopen_fd(fd, flags, mode),
+        )
+    }
+
     /// Get the list of file descriptors which should be reserved across live upgrade.
     pub fn keep_fds(&self) -> Vec<RawFd> {
-        vec![self.proc_self_fd.as_raw_fd()]
+        self.proc_self_fd
+            .iter()
+            .map(|f| f.as_raw_fd())
+            .chain(self.sandbox_root_fd())
+            .collect()
     }
 
     fn readlinkat(dfd: i32, pathname: &CStr) -> io::Result<PathBuf> {
@@ -639,8 +1588,7 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             )
         };
         if buf_read < 0 {
-            error!("fuse: readlinkat error");
-            return Err(io::Error::last_os_error());
+            return Err(io::Error::last_os_error()).errno_context("readlinkat");
         }
 
         // Safe because we know buf len
@@ -662,22 +1610,167 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         Err(io::Error::from_raw_os_error(libc::EOVERFLOW))
     }
 
-    /// Get the file pathname corresponding to the Inode
-    /// This function is used by Nydus blobfs
-    pub fn readlinkat_proc_file(&self, inode: Inode) -> io::Result<PathBuf> {
-        let data = self.inode_map.get(inode)?;
-        let file = data.get_file(&self.mount_fds)?;
-        let pathname = CString::new(format!("{}", file.as_raw_fd()))
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    /// Get the strategies in use to obtain mount ids of backing files, keyed by backing device.
+    pub fn mnt_id_strategies(&self) -> Vec<(u64, MntIdStrategy)> {
+        self.stat_helper.strategies()
+    }
 
-        Self::readlinkat(self.proc_self_fd.as_raw_fd(), &pathname)
+    /// Get the number of directory handles read from the host without snapshots, because they're
+    /// too large for `Config::snapshot_readdir_max_entries` or `Config::snapshot_readdir_memory`.
+    pub fn readdir_snapshot_fallbacks(&self) -> u64 {
+        self.dir_snapshots.fallbacks()
     }
 
-    fn stat(dir: &impl AsRawFd, path: Option<&CStr>) -> io::Result<libc::stat64> {
-        Self::stat_fd(dir.as_raw_fd(), path)
+    /// Get the number of readdir and readdirplus requests, to evaluate which one the guest chooses
+    /// with `Config::readdirplus_auto`.
+    pub fn readdir_stats(&self) -> ReaddirStats {
+        self.readdir_counters.stats()
     }
 
-    fn stat_fd(dir_fd: RawFd, path: Option<&CStr>) -> io::Result<libc::stat64> {
+    /// Get the bytes of memory used by snapshots of directory handles.
+    pub fn readdir_snapshot_memory(&self) -> usize {
+        self.dir_snapshots.used()
+    }
+
+    /// Get the path of `inode` relative to the root directory, derived from the names it was last
+    /// looked up, created or renamed with.
+    ///
+    /// The path is a best-effort hint: changes made on the host aren't seen until the inode is
+    /// looked up again. Return `None` if the inode or one of its ancestors isn't known by name.
+    pub fn path_hint(&self, inode: Inode) -> Option<PathBuf> {
+        self.path_hints.path(inode)
+    }
+
+    /// Get statistics of credential switching around syscalls creating files.
+    pub fn cred_switch_stats(&self) -> CredSwitchStats {
+        self.creds.stats()
+    }
+
+    /// Get statistics of operations retried after transient errors of the host.
+    pub fn retry_stats(&self) -> RetryStats {
+        self.retry.stats()
+    }
+
+    /// Get the file pathname corresponding to the Inode
+    /// This function is used by Nydus blobfs
+    pub fn readlinkat_proc_file(&self, inode: Inode) -> io::Result<PathBuf> {
+        let data = self.inode_map.get(inode)?;
+        let file = data.get_file(&self.mount_fds)?;
+        let pathname = CString::new(format!("{}", file.as_raw_fd()))
+            .map_err(|_| fuse_errno(libc::EINVAL, "invalid proc fd path"))?;
+
+        Self::readlinkat(self.proc_self_fd()?, &pathname)
+    }
+
+    // Choose options for the newly opened `file` with the configured open policy.
+    fn open_options(&self, file: &File, flags: u32, mode: u32, name: Option<&CStr>) -> OpenOptions {
+        let mut req = OpenRequest {
+            flags,
+            mode,
+            cache_policy: self.runtime_cfg.load().cache_policy,
+            name,
+        };
+        let direct_io = self.direct_io_requested(flags) && !req.is_dir();
+        let opts = match self.cfg.open_policy.as_ref() {
+            None => OpenPolicy::default_options(&req),
+            Some(policy) => {
+                // Only resolve the name when a custom policy is installed.
+                let path = match name {
+                    Some(_) => None,
+                    None => CString::new(format!("{}", file.as_raw_fd()))
+                        .ok()
+                        .and_then(|p| Self::readlinkat(self.proc_self_fd().ok()?, &p).ok())
+                        .and_then(|p| p.file_name().map(|n| n.as_bytes().to_vec()))
+                        .and_then(|n| CString::new(n).ok()),
+                };
+                if let Some(path) = path.as_ref() {
+                    req.name = Some(path);
+                }
+                policy.options(&req)
+            }
+        };
+        if direct_io {
+            opts | OpenOptions::DIRECT_IO
+        } else {
+            opts
+        }
+    }
+
+    fn direct_io_requested(&self, flags: u32) -> bool {
+        self.cfg.allow_direct_io && flags & libc::O_DIRECT as u32 != 0
+    }
+
+    // Adjust the flags of guest opens and creates for the host open. `O_DIRECT` is dropped unless
+    // allowed by the configuration.
+    //
+    // When writeback caching is enabled, the kernel may send read requests even if the userspace
+    // program opened the file write-only, so the file is opened for reading as well as writing.
+    // The kernel is also responsible for handling `O_APPEND` then, writing at offsets computed
+    // from its cached size, which the host would ignore for files opened with `O_APPEND`. This
+    // breaks atomicity as the file may have changed on disk, invalidating the offset that the
+    // kernel thinks is the end of the file. Just allow this for now as it is the user's
+    // responsibility to enable writeback caching only for directories that are not shared.
+    fn host_open_flags(&self, mut flags: i32) -> i32 {
+        if self.writeback.load(Ordering::Relaxed) {
+            if flags & libc::O_ACCMODE == libc::O_WRONLY {
+                flags = (flags & !libc::O_ACCMODE) | libc::O_RDWR;
+            }
+            flags &= !libc::O_APPEND;
+        }
+        if self.cfg.allow_direct_io {
+            flags
+        } else {
+            flags & !libc::O_DIRECT
+        }
+    }
+
+    // Remove `security.capability` of the file open as `fd` before modifying its data on behalf
+    // of a user other than root, like the kernel does. Only done once kill_priv_v2 is negotiated,
+    // otherwise the guest kernel removes the attribute itself. Files found without the attribute
+    // are remembered in the handle `hint` until the attribute is set again.
+    fn kill_capability(
+        &self,
+        ctx: &Context,
+        inode: &InodeData,
+        fd: RawFd,
+        hint: Option<&HandleData>,
+    ) -> io::Result<()> {
+        if !self.killpriv_v2.load(Ordering::Relaxed) || ctx.uid == 0 {
+            return Ok(());
+        }
+        let no_caps = inode.caps_gen.load(Ordering::Acquire) + 1;
+        if hint.map(|h| h.no_caps.load(Ordering::Acquire)) == Some(no_caps) {
+            return Ok(());
+        }
+
+        let name = SECURITY_CAPABILITY.as_ptr() as *const libc::c_char;
+        // Safe because these don't modify any memory, a zero size only queries the size of the
+        // value, and we check the return values.
+        let res = unsafe {
+            if libc::fgetxattr(fd, name, std::ptr::null_mut(), 0) < 0
+                || libc::fremovexattr(fd, name) < 0
+            {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        };
+        match res {
+            Err(e) if !matches!(e.raw_os_error(), Some(libc::ENODATA | libc::ENOTSUP)) => Err(e),
+            _ => {
+                if let Some(h) = hint {
+                    h.no_caps.store(no_caps, Ordering::Release);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn stat(dir: &impl AsRawFd, path: Option<&CStr>) -> io::Result<libc::stat64> {
+        Self::stat_fd(dir.as_raw_fd(), path)
+    }
+
+    fn stat_fd(dir_fd: RawFd, path: Option<&CStr>) -> io::Result<libc::stat64> {
         // Safe because this is a constant value and a valid C string.
         let pathname =
             path.unwrap_or_else(|| unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) });
@@ -747,31 +1840,13 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         Ok(unsafe { File::from_raw_fd(fd) })
     }
 
-    fn open_proc_file(proc: &File, fd: RawFd, flags: i32, mode: u32) -> io::Result<File> {
-        if !is_safe_inode(mode) {
-            return Err(ebadf());
-        }
-
-        let pathname = CString::new(format!("{}", fd))
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-
-        // We don't really check `flags` because if the kernel can't handle poorly specified flags
-        // then we have much bigger problems. Also, clear the `O_NOFOLLOW` flag if it is set since
-        // we need to follow the `/proc/self/fd` symlink to get the file.
-        Self::open_file(
-            proc.as_raw_fd(),
-            &pathname,
-            (flags | libc::O_CLOEXEC) & (!libc::O_NOFOLLOW),
-            0,
-        )
-    }
-
     /// Create a File or File Handle for `name` under directory `dir_fd` to support `lookup()`.
     fn open_file_or_handle<F>(
         use_handle: bool,
         dir_fd: RawFd,
         name: &CStr,
         mount_fds: &MountFds,
+        stat_helper: &StatHelper,
         reopen_dir: F,
     ) -> io::Result<(FileOrHandle, InodeStat, InodeAltKey, Option<InodeAltKey>)>
     where
@@ -798,20 +1873,9 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         };
 
         let inode_stat = match &file_or_handle {
-            FileOrHandle::File(f) => {
-                // TODO: use statx(2) to query mntid when 5.8 kernel or later are widely used.
-                //
-                // Some filesystems don't support file handle, for example overlayfs mounted
-                // without index feature, if so just use mntid 0 in that case.
-                let mnt_id = match FileHandle::from_name_at(dir_fd, name) {
-                    Ok(h) => h.mnt_id,
-                    Err(_) => 0,
-                };
-                InodeStat {
-                    stat: Self::stat(f, None)?,
-                    mnt_id,
-                }
-            }
+            // The mount id from statx(2) or its fallbacks matches the one of file handles, so
+            // `InodeAltKey::Ids` stays the same no matter how the inode is opened.
+            FileOrHandle::File(f) => stat_helper.stat(f)?,
             FileOrHandle::Handle(h) => InodeStat {
                 stat: Self::stat_fd(dir_fd, Some(name))?,
                 mnt_id: h.mnt_id,
@@ -829,6 +1893,16 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
     }
 
     fn do_lookup(&self, parent: Inode, name: &CStr) -> io::Result<Entry> {
+        match self.do_lookup_once(parent, name) {
+            Err(e) if parent == fuse::ROOT_ID && self.is_root_stale(&e) => {
+                self.reopen_root()?;
+                self.do_lookup_once(parent, name)
+            }
+            res => res,
+        }
+    }
+
+    fn do_lookup_once(&self, parent: Inode, name: &CStr) -> io::Result<Entry> {
         let name =
             if parent == fuse::ROOT_ID && name.to_bytes_with_nul().starts_with(PARENT_DIR_CSTR) {
                 // Safe as this is a constant value and a valid C string.
@@ -838,14 +1912,17 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             };
 
         let dir = self.inode_map.get(parent)?;
-        let dir_file = dir.get_file(&self.mount_fds)?;
-        let (file_or_handle, st, ids_altkey, handle_altkey) = Self::open_file_or_handle(
-            self.cfg.inode_file_handles,
-            dir_file.as_raw_fd(),
-            name,
-            &self.mount_fds,
-            |fd, flags, mode| Self::open_proc_file(&self.proc_self_fd, fd, flags, mode),
-        )?;
+        let dir_file = self.dir_file(&dir)?;
+        let (file_or_handle, st, ids_altkey, handle_altkey) = self.retry.run(|| {
+            Self::open_file_or_handle(
+                self.inode_file_handles.load(Ordering::Relaxed),
+                dir_file.as_raw_fd(),
+                name,
+                &self.mount_fds,
+                &self.stat_helper,
+                |fd, flags, mode| self.reopen_fd(fd, flags, mode),
+            )
+        })?;
 
         // Whether to enable file DAX according to the value of dax_file_size
         let mut attr_flags: u32 = 0;
@@ -858,6 +1935,9 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
                 attr_flags |= fuse::FUSE_ATTR_DAX;
             }
         }
+        if self.is_submount(&dir, name, &st) {
+            attr_flags |= fuse::ATTR_SUBMOUNT;
+        }
 
         let mut found = None;
         'search: loop {
@@ -880,6 +1960,7 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
                         .compare_exchange(curr, new, Ordering::AcqRel, Ordering::Acquire)
                         .is_ok()
                     {
+                        data.update_attr(&st.get_stat());
                         found = Some(data.inode);
                         break;
                     }
@@ -891,7 +1972,7 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             v
         } else {
             // Write guard get_alt_locked() and insert_lock() to avoid race conditions.
-            let mut inodes = self.inode_map.get_map_mut();
+            let (shard, mut inodes) = self.inode_map.get_alt_map_mut(&ids_altkey);
 
             // Lookup inode_map again after acquiring the inode_map lock, as there might be another
             // racing thread already added an inode with the same altkey while we're not holding
@@ -905,14 +1986,15 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
                         ids_altkey
                     );
                     data.refcount.fetch_add(1, Ordering::Relaxed);
+                    data.update_attr(&st.get_stat());
                     data.inode
                 }
                 None => {
-                    let inode = self.next_inode.fetch_add(1, Ordering::Relaxed);
+                    let inode = self.inode_map.next_inode(shard);
                     if inode > VFS_MAX_INO {
                         error!("fuse: max inode number reached: {}", VFS_MAX_INO);
-                        return Err(io::Error::new(
-                            io::ErrorKind::Other,
+                        return Err(fuse_errno(
+                            libc::ENFILE,
                             format!("max inode number reached: {}", VFS_MAX_INO),
                         ));
                     }
@@ -926,7 +2008,7 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
                     InodeMap::insert_locked(
                         inodes.deref_mut(),
                         inode,
-                        InodeData::new(inode, file_or_handle, 1, ids_altkey, st.get_stat().st_mode),
+                        InodeData::new(inode, file_or_handle, 1, ids_altkey, &st.get_stat()),
                         ids_altkey,
                         handle_altkey,
                     );
@@ -935,20 +2017,102 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             }
         };
 
+        self.path_hints.record(inode, parent, name);
+        let mut attr = st.get_stat();
+        self.report_attr(inode, &mut attr);
+
+        let runtime = self.runtime_config();
         Ok(Entry {
             inode,
-            generation: 0,
-            attr: st.get_stat(),
+            generation: self.root_generation.load(Ordering::Acquire),
+            attr,
             attr_flags,
-            attr_timeout: self.cfg.attr_timeout,
-            entry_timeout: self.cfg.entry_timeout,
+            attr_timeout: runtime.attr_timeout,
+            entry_timeout: runtime.entry_timeout,
+        })
+    }
+
+    // Check whether the entry `name` of the directory `dir` with attributes `st` is the root of
+    // another mount to announce to the guest. Mount ids synthesized from `st_dev` differ across
+    // devices, so comparing them also detects boundaries when statx(2) isn't supported.
+    fn is_submount(&self, dir: &InodeData, name: &CStr, st: &InodeStat) -> bool {
+        if !self.announce_submounts.load(Ordering::Relaxed)
+            || st.stat.st_mode & libc::S_IFMT != libc::S_IFDIR
+            || name.to_bytes() == b".."
+        {
+            return false;
+        }
+        match dir.altkey {
+            InodeAltKey::Ids { dev, mnt, .. } => mnt != st.mnt_id || dev != st.stat.st_dev,
+            InodeAltKey::Handle(_) => false,
+        }
+    }
+
+    // Adjust attributes of `inode` got from the host before replying them to the guest.
+    pub(super) fn report_attr(&self, inode: Inode, st: &mut libc::stat64) {
+        self.set_blockdev_size(inode, st);
+        self.guest_owner(st);
+        if let Some(size) = self.cfg.blksize {
+            st.st_blksize = size as libc::blksize_t;
+        }
+    }
+
+    // Translate the owner of a host file into guest ids.
+    pub(super) fn guest_owner(&self, st: &mut libc::stat64) {
+        if let Some(map) = self.cfg.uid_gid_map.as_ref() {
+            st.st_uid = map.guest_uid(st.st_uid);
+            st.st_gid = map.guest_gid(st.st_gid);
+        }
+    }
+
+    // Register the unnamed file `file` opened with `O_TMPFILE` as a new inode. It stays in the
+    // inode map while the guest holds lookups on it, so it may be given a name by `link()`.
+    fn register_tmpfile(&self, file: &File) -> io::Result<Entry> {
+        let st = self.stat_helper.stat(file)?;
+        let ids_altkey = InodeAltKey::ids_from_stat(&st);
+        let path_file = self.reopen_fd(file.as_raw_fd(), libc::O_PATH, st.stat.st_mode)?;
+
+        let (shard, mut inodes) = self.inode_map.get_alt_map_mut(&ids_altkey);
+        let inode = self.inode_map.next_inode(shard);
+        if inode > VFS_MAX_INO {
+            error!("fuse: max inode number reached: {}", VFS_MAX_INO);
+            return Err(fuse_errno(
+                libc::ENFILE,
+                format!("max inode number reached: {}", VFS_MAX_INO),
+            ));
+        }
+        InodeMap::insert_locked(
+            inodes.deref_mut(),
+            inode,
+            InodeData::new(
+                inode,
+                FileOrHandle::File(path_file),
+                1,
+                ids_altkey,
+                &st.get_stat(),
+            ),
+            ids_altkey,
+            None,
+        );
+
+        let mut attr = st.get_stat();
+        self.report_attr(inode, &mut attr);
+        let runtime = self.runtime_config();
+        Ok(Entry {
+            inode,
+            generation: self.root_generation.load(Ordering::Acquire),
+            attr,
+            attr_flags: 0,
+            attr_timeout: runtime.attr_timeout,
+            entry_timeout: runtime.entry_timeout,
         })
     }
 
-    fn forget_one(inodes: &mut MultiKeyMap, inode: Inode, count: u64) {
+    // Return whether the inode is dropped.
+    fn forget_one(inodes: &mut MultiKeyMap, inode: Inode, count: u64) -> bool {
         // ROOT_ID should not be forgotten, or we're not able to access to files any more.
         if inode == fuse::ROOT_ID {
-            return;
+            return false;
         }
 
         if let Some(data) = inodes.get(&inode) {
@@ -981,10 +2145,11 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
                         // We just removed the last refcount for this inode.
                         inodes.remove(&inode);
                     }
-                    break;
+                    return new == 0;
                 }
             }
         }
+        false
     }
 
     fn do_release(&self, inode: Inode, handle: Handle) -> io::Result<()> {
@@ -1000,6 +2165,61 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         }
         validate_path_component(name)
     }
+
+    // Validate the target of a symlink to create, before it reaches the backing file system
+    // which may truncate long targets silently.
+    fn validate_symlink_target(&self, linkname: &CStr) -> io::Result<()> {
+        let len = linkname.to_bytes().len();
+        if len == 0 {
+            Err(io::Error::from_raw_os_error(libc::ENOENT))
+        } else if len > self.cfg.max_symlink_target {
+            Err(io::Error::from_raw_os_error(libc::ENAMETOOLONG))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Read the target of symlink `fd` into a scratch buffer of at least `size` bytes. The buffer
+    // is grown and the read retried when the target fills it, as the symlink may have been
+    // replaced since `size` was got from its attributes.
+    fn readlink_fd(fd: RawFd, size: usize, max: usize) -> io::Result<Vec<u8>> {
+        // Safe because this is a constant value and a valid C string.
+        let empty = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
+        // One more byte to tell a target of `max` bytes from a truncated longer target.
+        let mut size = cmp::min(size, max) + 1;
+
+        loop {
+            let mut buf = scratch::take(size);
+            // Safe because this will only modify the contents of `buf` and we check the return
+            // value.
+            let res = unsafe {
+                libc::readlinkat(
+                    fd,
+                    empty.as_ptr(),
+                    buf.as_mut_ptr() as *mut libc::c_char,
+                    buf.capacity(),
+                )
+            };
+            if res < 0 {
+                let e = io::Error::last_os_error();
+                scratch::recycle(buf);
+                return Err(e);
+            }
+
+            let len = res as usize;
+            if len < buf.capacity() && len <= max {
+                // Safe because we trust the value returned by kernel.
+                unsafe { buf.set_len(len) };
+                return Ok(buf);
+            }
+            let capacity = buf.capacity();
+            scratch::recycle(buf);
+            if len > max {
+                return Err(io::Error::from_raw_os_error(libc::ENAMETOOLONG));
+            }
+            size = cmp::min(capacity * 2, max + 1);
+        }
+    }
 }
 
 #[cfg(not(feature = "async-io"))]
@@ -1012,60 +2232,32 @@ impl<S: BitmapSlice + Send + Sync + 'static> BackendFileSystem for PassthroughFs
     fn as_any(&self) -> &dyn Any {
         self
     }
-}
 
-macro_rules! scoped_cred {
-    ($name:ident, $ty:ty, $syscall_nr:expr) => {
-        #[derive(Debug)]
-        pub(crate) struct $name;
-
-        impl $name {
-            // Changes the effective uid/gid of the current thread to `val`.  Changes
-            // the thread's credentials back to root when the returned struct is dropped.
-            fn new(val: $ty) -> io::Result<Option<$name>> {
-                if val == 0 {
-                    // Nothing to do since we are already uid 0.
-                    return Ok(None);
-                }
+    fn push_file_as(
+        &self,
+        notifier: &dyn Notifier,
+        inode: u64,
+        nodeid: u64,
+        offset: u64,
+        size: u64,
+    ) -> io::Result<u64> {
+        self.do_push_file(notifier, inode, nodeid, offset, size)
+    }
 
-                // We want credential changes to be per-thread because otherwise
-                // we might interfere with operations being carried out on other
-                // threads with different uids/gids.  However, posix requires that
-                // all threads in a process share the same credentials.  To do this
-                // libc uses signals to ensure that when one thread changes its
-                // credentials the other threads do the same thing.
-                //
-                // So instead we invoke the syscall directly in order to get around
-                // this limitation.  Another option is to use the setfsuid and
-                // setfsgid systems calls.   However since those calls have no way to
-                // return an error, it's preferable to do this instead.
-
-                // This call is safe because it doesn't modify any memory and we
-                // check the return value.
-                let res = unsafe { libc::syscall($syscall_nr, -1, val, -1) };
-                if res == 0 {
-                    Ok(Some($name))
-                } else {
-                    Err(io::Error::last_os_error())
-                }
-            }
-        }
+    #[cfg(feature = "persist")]
+    fn save_state(&self) -> io::Result<Option<(Vec<u8>, Vec<RawFd>)>> {
+        self.save().map(Some)
+    }
 
-        impl Drop for $name {
-            fn drop(&mut self) {
-                let res = unsafe { libc::syscall($syscall_nr, -1, 0, -1) };
-                if res < 0 {
-                    error!(
-                        "fuse: failed to change credentials back to root: {}",
-                        io::Error::last_os_error(),
-                    );
-                }
-            }
-        }
-    };
+    #[cfg(feature = "persist")]
+    fn restore_state(&self, state: &[u8], files: Vec<File>) -> io::Result<()> {
+        // Imported as a fresh file system instead, left unchanged by the failed restore.
+        self.restore(state, files).map_err(|e| match self.import() {
+            Ok(()) => e,
+            Err(err) => err,
+        })
+    }
 }
-scoped_cred!(ScopedUid, libc::uid_t, libc::SYS_setresuid);
-scoped_cred!(ScopedGid, libc::gid_t, libc::SYS_setresgid);
 
 struct CapFsetid {}
 
@@ -1079,26 +2271,51 @@ impl Drop for CapFsetid {
 
 fn drop_cap_fsetid() -> io::Result<Option<CapFsetid>> {
     if !caps::has_cap(None, caps::CapSet::Effective, caps::Capability::CAP_FSETID)
-        .map_err(|_e| io::Error::new(io::ErrorKind::PermissionDenied, "no CAP_FSETID capability"))?
+        .map_err(|_e| fuse_errno(libc::EPERM, "no CAP_FSETID capability"))?
     {
         return Ok(None);
     }
-    caps::drop(None, caps::CapSet::Effective, caps::Capability::CAP_FSETID).map_err(|_e| {
-        io::Error::new(
-            io::ErrorKind::PermissionDenied,
-            "failed to drop CAP_FSETID capability",
-        )
-    })?;
+    caps::drop(None, caps::CapSet::Effective, caps::Capability::CAP_FSETID)
+        .map_err(|_e| fuse_errno(libc::EPERM, "failed to drop CAP_FSETID capability"))?;
     Ok(Some(CapFsetid {}))
 }
 
-fn set_creds(
-    uid: libc::uid_t,
-    gid: libc::gid_t,
-) -> io::Result<(Option<ScopedUid>, Option<ScopedGid>)> {
-    // We have to change the gid before we change the uid because if we change the uid first then we
-    // lose the capability to change the gid.  However changing back can happen in any order.
-    ScopedGid::new(gid).and_then(|gid| Ok((ScopedUid::new(uid)?, gid)))
+// Flush the host file system containing `file`, which must not be an `O_PATH` fd.
+fn sync_host_fs(file: &File) -> io::Result<()> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    if unsafe { libc::syncfs(file.as_raw_fd()) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+// Flush data written to `fd` as requested by the flags of a guest write, which carry `O_SYNC`
+// and `O_DSYNC` for writes with `RWF_SYNC` and `RWF_DSYNC` too. Nothing is done if `fd` has been
+// opened with the flags already.
+fn sync_written(fd: RawFd, flags: u32) -> io::Result<()> {
+    let flags = flags as i32;
+    if flags & libc::O_DSYNC == 0 {
+        return Ok(());
+    }
+    // Safe because this doesn't modify any memory and we check the return value.
+    let status = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if status < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because this doesn't modify any memory and we check the return value.
+    let res = if flags & libc::O_SYNC == libc::O_SYNC && status & libc::O_SYNC != libc::O_SYNC {
+        unsafe { libc::fsync(fd) }
+    } else if status & libc::O_DSYNC == 0 {
+        unsafe { libc::fdatasync(fd) }
+    } else {
+        0
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
 }
 
 fn ebadf() -> io::Error {
@@ -1113,6 +2330,7 @@ mod tests {
     use caps::{CapSet, Capability};
     use log;
     use std::ops::Deref;
+    use std::path::Path;
     use vmm_sys_util::{tempdir::TempDir, tempfile::TempFile};
 
     fn prepare_passthroughfs() -> PassthroughFs {
@@ -1140,6 +2358,32 @@ mod tests {
         fs
     }
 
+    // Create and import a PassthroughFs over `dir`, with the default config changed by `tweak`.
+    pub(super) fn passthroughfs_in(dir: &Path, tweak: impl FnOnce(&mut Config)) -> PassthroughFs {
+        let mut fs_cfg = Config {
+            root_dir: dir.to_str().expect("source path to string").to_string(),
+            // Cheap enough to be left on, to catch inodes served after their files are replaced.
+            validate_inodes: true,
+            ..Default::default()
+        };
+        tweak(&mut fs_cfg);
+        let fs = PassthroughFs::<()>::new(fs_cfg).unwrap();
+        fs.import().unwrap();
+
+        fs
+    }
+
+    // Like `passthroughfs_in()`, over a new temporary directory, which is returned to keep it
+    // alive.
+    pub(super) fn prepare_passthroughfs_with(
+        tweak: impl FnOnce(&mut Config),
+    ) -> (TempDir, PassthroughFs) {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let fs = passthroughfs_in(source.as_path(), tweak);
+
+        (source, fs)
+    }
+
     fn passthroughfs_no_open(cfg: bool) {
         let opts = VfsOptions {
             no_open: cfg,
@@ -1239,11 +2483,97 @@ mod tests {
         //assert_eq!(matches!(data.file_or_handle, FileOrHandle::Handle(_)), true);
 
         let (_, duration) = fs.getattr(&ctx, c_entry.inode, None).unwrap();
-        assert_eq!(duration, fs.cfg.attr_timeout);
+        assert_eq!(duration, fs.runtime_config().attr_timeout);
 
         fs.destroy();
     }
 
+    #[test]
+    fn test_passthroughfs_inode_file_handles_fallback() {
+        let has_cap = |cap| caps::has_cap(None, CapSet::Effective, cap).unwrap_or(false);
+        if !has_cap(Capability::CAP_DAC_READ_SEARCH) {
+            println!("invoking open_by_handle_at needs CAP_DAC_READ_SEARCH");
+            return;
+        }
+        let (source, fs) = prepare_passthroughfs_with(|cfg| cfg.inode_file_handles = true);
+        let root = fs.inode_map.get(ROOT_ID).unwrap();
+        if !matches!(root.file_or_handle, FileOrHandle::Handle(_)) {
+            println!("the host file system doesn't support file handles");
+            return;
+        }
+        std::fs::write(source.as_path().join("f"), b"f").unwrap();
+
+        // Capabilities are per thread, handles can't be opened after dropping it in this one.
+        std::thread::spawn(move || {
+            caps::drop(None, CapSet::Effective, Capability::CAP_DAC_READ_SEARCH).unwrap();
+            let fs = passthroughfs_in(source.as_path(), |cfg| cfg.inode_file_handles = true);
+            assert!(!fs.inode_file_handles.load(Ordering::Relaxed));
+
+            let ctx = Context::default();
+            let name = CString::new("f").unwrap();
+            let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+            let data = fs.inode_map.get(entry.inode).unwrap();
+            assert!(matches!(data.file_or_handle, FileOrHandle::File(_)));
+            fs.getattr(&ctx, entry.inode, None).unwrap();
+        })
+        .join()
+        .unwrap();
+    }
+
+    // Look up more files than the soft limit of open fds allows, changing the limit of the whole
+    // process, run with `cargo test -- --ignored --test-threads=1 stress_passthroughfs_handles`.
+    #[test]
+    #[ignore]
+    fn stress_passthroughfs_handles() {
+        const LIMIT: libc::rlim_t = 256;
+
+        if !caps::has_cap(None, CapSet::Effective, Capability::CAP_DAC_READ_SEARCH).unwrap() {
+            println!("invoking open_by_handle_at needs CAP_DAC_READ_SEARCH");
+            return;
+        }
+        let source = prepare_files(LIMIT as usize * 4);
+        let fs = passthroughfs_in(source.as_path(), |cfg| cfg.inode_file_handles = true);
+        let root = fs.inode_map.get(ROOT_ID).unwrap();
+        if !matches!(root.file_or_handle, FileOrHandle::Handle(_)) {
+            println!("the host file system doesn't support file handles");
+            return;
+        }
+
+        let mut limit = MaybeUninit::<libc::rlimit>::zeroed();
+        // Safe because the kernel only writes `limit` and we check the return value.
+        assert_eq!(
+            unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, limit.as_mut_ptr()) },
+            0
+        );
+        // Safe because getrlimit() succeeded.
+        let saved = unsafe { limit.assume_init() };
+        let lowered = libc::rlimit {
+            rlim_cur: LIMIT,
+            ..saved
+        };
+        // Safe because this doesn't modify any memory and we check the return value.
+        assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &lowered) }, 0);
+
+        // Inodes only hold handles, fds are opened per operation and closed again.
+        let ctx = Context::default();
+        let res = (0..LIMIT as usize * 4)
+            .map(|i| {
+                let name = CString::new(format!("f{}", i)).unwrap();
+                let entry = fs.lookup(&ctx, ROOT_ID, &name)?;
+                fs.getattr(&ctx, entry.inode, None).map(|_| entry.inode)
+            })
+            .collect::<io::Result<Vec<_>>>();
+
+        // Safe because this doesn't modify any memory.
+        unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &saved) };
+        let inodes = res.unwrap();
+        assert_eq!(fs.inode_map.len(), inodes.len() + 1);
+        for inode in inodes {
+            fs.forget(&ctx, inode, 1);
+        }
+        assert_eq!(fs.inode_map.len(), 1);
+    }
+
     #[test]
     fn test_lookup_escape_root() {
         let fs = prepare_passthroughfs();
@@ -1277,4 +2607,3059 @@ mod tests {
         let mode = libc::S_IFSOCK;
         assert!(!is_safe_inode(mode));
     }
+
+    #[test]
+    fn test_passthroughfs_vfs_readonly() {
+        use crate::api::errno::errno_of;
+        use crate::api::ReadonlyPolicy;
+
+        match caps::has_cap(None, CapSet::Effective, Capability::CAP_SYS_ADMIN) {
+            Ok(false) | Err(_) => {
+                println!("mounting tmpfs needs CAP_SYS_ADMIN");
+                return;
+            }
+            Ok(true) => {}
+        }
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let dir = CString::new(source.as_path().to_str().unwrap()).unwrap();
+        let tmpfs = CString::new("tmpfs").unwrap();
+        let mount = |flags| unsafe {
+            libc::mount(
+                tmpfs.as_ptr(),
+                dir.as_ptr(),
+                tmpfs.as_ptr(),
+                flags,
+                std::ptr::null(),
+            )
+        };
+        assert_eq!(mount(0), 0);
+        std::fs::write(source.as_path().join("a"), b"hello").unwrap();
+        assert_eq!(mount(libc::MS_REMOUNT | libc::MS_RDONLY), 0);
+
+        let fs = passthroughfs_in(source.as_path(), |cfg| cfg.no_open = true);
+        let vfs = Vfs::default().with_readonly_policy(ReadonlyPolicy {
+            auto_readonly: true,
+            callback: None,
+        });
+        vfs.init(FsOptions::ZERO_MESSAGE_OPEN).unwrap();
+        let idx = vfs.mount(Box::new(fs), "/m").unwrap();
+
+        // Without open requests, fallocate reopens the file for writing and fails with a
+        // wrapped EROFS.
+        let ctx = Context::default();
+        let m = vfs
+            .lookup(&ctx, fuse::ROOT_ID.into(), &CString::new("m").unwrap())
+            .unwrap()
+            .inode;
+        let ino = vfs
+            .lookup(&ctx, m.into(), &CString::new("a").unwrap())
+            .unwrap()
+            .inode;
+        let res = vfs.fallocate(&ctx, ino.into(), 0, 0, 0, 4096);
+        let readonly = vfs.is_readonly(idx);
+        vfs.umount("/m").unwrap();
+        unsafe { libc::umount2(dir.as_ptr(), libc::MNT_DETACH) };
+
+        let err = res.unwrap_err();
+        assert_eq!(err.raw_os_error(), None);
+        assert_eq!(errno_of(&err), Some(libc::EROFS));
+        assert_eq!(readonly, Some(true));
+    }
+
+    #[test]
+    fn test_passthroughfs_vfs_lookup_cache() {
+        let (source, fs) = prepare_passthroughfs_with(|_| {});
+        std::fs::write(source.as_path().join("a"), b"hello").unwrap();
+        std::fs::write(source.as_path().join("b"), b"world").unwrap();
+
+        let vfs = Vfs::new(VfsOptions {
+            lookup_cache_size: 16,
+            ..Default::default()
+        });
+        vfs.mount(Box::new(fs), "/m").unwrap();
+        let rootfs = vfs.get_rootfs("/m").unwrap().unwrap();
+        let backend = rootfs.as_any().downcast_ref::<PassthroughFs>().unwrap();
+
+        let ctx = Context::default();
+        let a = CString::new("a").unwrap();
+        let m = vfs
+            .lookup(&ctx, fuse::ROOT_ID.into(), &CString::new("m").unwrap())
+            .unwrap()
+            .inode;
+
+        // The second lookup is served from cache, without taking a backend reference.
+        let ino = vfs.lookup(&ctx, m.into(), &a).unwrap().inode;
+        assert_eq!(vfs.lookup(&ctx, m.into(), &a).unwrap().inode, ino);
+        let stats = vfs.lookup_cache_stats().unwrap();
+        assert_eq!((stats.entries, stats.inodes, stats.pending), (1, 1, 1));
+        assert_eq!(vfs.debug_nlookup(ino.into()), Some(2));
+        assert_eq!(backend.debug_nlookup(ino & VFS_MAX_INO), Some(1));
+
+        // Forgets are applied to cache hits first.
+        vfs.forget(&ctx, ino.into(), 1);
+        assert_eq!(vfs.debug_nlookup(ino.into()), Some(1));
+        assert_eq!(backend.debug_nlookup(ino & VFS_MAX_INO), Some(1));
+        vfs.forget(&ctx, ino.into(), 1);
+        assert_eq!(backend.debug_nlookup(ino & VFS_MAX_INO), Some(0));
+        assert_eq!(vfs.lookup_cache_stats().unwrap().inodes, 0);
+
+        // The backend has released the inode, so the cached entry must not be served.
+        let ino = vfs.lookup(&ctx, m.into(), &a).unwrap().inode;
+        assert_eq!(backend.debug_nlookup(ino & VFS_MAX_INO), Some(1));
+
+        // Attribute changes invalidate cached entries.
+        let mut attr: libc::stat64 = unsafe { std::mem::zeroed() };
+        attr.st_size = 1;
+        vfs.setattr(&ctx, ino.into(), attr, None, SetattrValid::SIZE)
+            .unwrap();
+        let entry = vfs.lookup(&ctx, m.into(), &a).unwrap();
+        assert_eq!(entry.attr.st_size, 1);
+        assert_eq!(backend.debug_nlookup(ino & VFS_MAX_INO), Some(2));
+
+        // Namespace changes invalidate cached entries immediately.
+        vfs.unlink(&ctx, m.into(), &a).unwrap();
+        assert_eq!(
+            vfs.lookup(&ctx, m.into(), &a).err().unwrap().raw_os_error(),
+            Some(libc::ENOENT)
+        );
+
+        let b = CString::new("b").unwrap();
+        let c = CString::new("c").unwrap();
+        let ino_b = vfs.lookup(&ctx, m.into(), &b).unwrap().inode;
+        vfs.rename(&ctx, m.into(), &b, m.into(), &c, 0).unwrap();
+        assert!(vfs.lookup(&ctx, m.into(), &b).is_err());
+        assert_eq!(vfs.lookup(&ctx, m.into(), &c).unwrap().inode, ino_b);
+
+        let args = fuse::CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            ..Default::default()
+        };
+        let (entry, _, _) = vfs.create(&ctx, m.into(), &b, args).unwrap();
+        assert_ne!(entry.inode, ino_b);
+        assert_eq!(vfs.lookup(&ctx, m.into(), &b).unwrap().inode, entry.inode);
+
+        // Umount drops all cached state of the backend.
+        vfs.umount("/m").unwrap();
+        let stats = vfs.lookup_cache_stats().unwrap();
+        assert_eq!((stats.entries, stats.inodes), (0, 0));
+    }
+
+    #[derive(Default)]
+    struct RecordNotifier {
+        limit: usize,
+        stored: std::sync::Mutex<Vec<(u64, u64, Vec<u8>)>>,
+        invalidated: std::sync::Mutex<Vec<u64>>,
+    }
+
+    impl Notifier for RecordNotifier {
+        fn inval_inode(&self, ino: u64) {
+            self.invalidated.lock().unwrap().push(ino);
+        }
+
+        fn inval_entry(&self, _parent: u64, _name: &std::ffi::CStr) {}
+
+        fn store(
+            &self,
+            inode: u64,
+            offset: u64,
+            data: &[crate::transport::FileVolatileSlice],
+        ) -> io::Result<usize> {
+            let mut stored = self.stored.lock().unwrap();
+            let total: usize = stored.iter().map(|s| s.2.len()).sum();
+            let mut buf = Vec::new();
+            for slice in data {
+                buf.extend_from_slice(unsafe {
+                    std::slice::from_raw_parts(slice.as_ptr() as *const u8, slice.len())
+                });
+            }
+            buf.truncate(self.limit.saturating_sub(total));
+            let count = buf.len();
+            stored.push((inode, offset, buf));
+            Ok(count)
+        }
+    }
+
+    // Attributes of `path` on the host.
+    fn host_stat(path: &Path) -> libc::stat64 {
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        PassthroughFs::<()>::stat_fd(libc::AT_FDCWD, Some(&path)).unwrap()
+    }
+
+    // Attributes of `st` changed by metadata operations.
+    fn changed_attr(st: &libc::stat64) -> (u32, u32, u32, i64, (i64, i64), (i64, i64)) {
+        (
+            st.st_mode,
+            st.st_uid,
+            st.st_gid,
+            st.st_size,
+            (st.st_mtime, st.st_mtime_nsec),
+            (st.st_ctime, st.st_ctime_nsec),
+        )
+    }
+
+    #[test]
+    fn test_passthroughfs_setattr_ctime() {
+        let (source, fs) = prepare_passthroughfs_with(|cfg| cfg.xattr = true);
+        let path = source.as_path().join("a");
+        std::fs::write(&path, b"hello").unwrap();
+
+        let vfs = Vfs::new(VfsOptions {
+            lookup_cache_size: 16,
+            no_open: false,
+            ..Default::default()
+        });
+        vfs.mount(Box::new(fs), "/m").unwrap();
+        let ctx = Context::default();
+        let a = CString::new("a").unwrap();
+        let m = vfs
+            .lookup(&ctx, fuse::ROOT_ID.into(), &CString::new("m").unwrap())
+            .unwrap()
+            .inode;
+        let ino = vfs.lookup(&ctx, m.into(), &a).unwrap().inode;
+        // Replies carry the attributes of the host file after the change.
+        let host = || changed_attr(&host_stat(&path));
+
+        let mut attr: libc::stat64 = unsafe { std::mem::zeroed() };
+        attr.st_mode = 0o600;
+        let (st, _) = vfs
+            .setattr(&ctx, ino.into(), attr, None, SetattrValid::MODE)
+            .unwrap();
+        assert_eq!(st.st_mode & 0o777, 0o600);
+        assert_eq!(changed_attr(&st), host());
+
+        // Changing the group to our own is permitted without privileges.
+        attr.st_gid = unsafe { libc::getegid() };
+        let (st, _) = vfs
+            .setattr(&ctx, ino.into(), attr, None, SetattrValid::GID)
+            .unwrap();
+        assert_eq!(changed_attr(&st), host());
+
+        // Cached entries don't hide ctime changes of extended attributes.
+        let name = CString::new("user.test").unwrap();
+        match vfs.setxattr(&ctx, ino.into(), &name, b"v", 0) {
+            Ok(()) => {
+                let entry = vfs.lookup(&ctx, m.into(), &a).unwrap();
+                assert_eq!(changed_attr(&entry.attr), host());
+            }
+            Err(e) => assert_eq!(e.raw_os_error(), Some(libc::EOPNOTSUPP)),
+        }
+
+        // Truncation through a handle is reflected in size and mtime.
+        let (fh, _) = vfs.open(&ctx, ino.into(), libc::O_RDWR as u32, 0).unwrap();
+        attr.st_size = 2;
+        let (st, _) = vfs
+            .setattr(&ctx, ino.into(), attr, fh, SetattrValid::SIZE)
+            .unwrap();
+        assert_eq!(st.st_size, 2);
+        assert_eq!(changed_attr(&st), host());
+        let entry = vfs.lookup(&ctx, m.into(), &a).unwrap();
+        assert_eq!(changed_attr(&entry.attr), host());
+    }
+
+    // Whether the attributes recorded for `inode` are the ones of `path` on the host.
+    fn is_recorded(fs: &PassthroughFs, inode: Inode, path: &Path) -> bool {
+        let st = host_stat(path);
+        let data = fs.inode_map.get(inode).unwrap();
+        data.size.load(Ordering::Relaxed) == st.st_size as u64
+            && data.times.get() == atime::InodeTimes::from_stat(&st)
+    }
+
+    #[test]
+    fn test_passthroughfs_restat() {
+        for no_restat in [false, true] {
+            let (source, fs) = prepare_passthroughfs_with(|cfg| {
+                cfg.xattr = true;
+                cfg.no_restat = no_restat;
+            });
+            let dir = source.as_path();
+            let ctx = Context::default();
+            let lookup = |name: &str| {
+                fs.lookup(&ctx, ROOT_ID, &CString::new(name).unwrap())
+                    .unwrap()
+                    .inode
+            };
+            // Grow the file on the host, unseen until the inode is stat()ed again.
+            let grow = |name: &str| {
+                let mut f = std::fs::OpenOptions::new()
+                    .append(true)
+                    .open(dir.join(name))
+                    .unwrap();
+                f.write_all(b"more").unwrap();
+            };
+
+            std::fs::write(dir.join("a"), b"a").unwrap();
+            let a = lookup("a");
+            grow("a");
+            let name = CString::new("user.test").unwrap();
+            if fs.setxattr(&ctx, a, &name, b"v", 0).is_ok() {
+                assert_eq!(is_recorded(&fs, a, &dir.join("a")), !no_restat);
+                grow("a");
+                fs.removexattr(&ctx, a, &name).unwrap();
+                assert_eq!(is_recorded(&fs, a, &dir.join("a")), !no_restat);
+            }
+
+            // Both the renamed inode and the directory are stat()ed again.
+            grow("a");
+            let b = CString::new("b").unwrap();
+            fs.rename(&ctx, ROOT_ID, &CString::new("a").unwrap(), ROOT_ID, &b, 0)
+                .unwrap();
+            assert_eq!(is_recorded(&fs, a, &dir.join("b")), !no_restat);
+            // Timestamps of the directory may not change within their granularity, so it's only
+            // checked to be up to date when stat()ed again.
+            assert!(no_restat || is_recorded(&fs, ROOT_ID, dir));
+
+            // Links reply attributes after the change whatever the option.
+            grow("b");
+            let entry = fs
+                .link(&ctx, a, ROOT_ID, &CString::new("c").unwrap())
+                .unwrap();
+            assert_eq!(
+                changed_attr(&entry.attr),
+                changed_attr(&host_stat(&dir.join("c")))
+            );
+            assert_eq!(entry.attr.st_nlink, 2);
+            assert!(is_recorded(&fs, a, &dir.join("c")));
+            assert!(no_restat || is_recorded(&fs, ROOT_ID, dir));
+        }
+    }
+
+    #[test]
+    fn test_passthroughfs_write_flags() {
+        use crate::abi::fuse_abi::{WRITE_CACHE, WRITE_KILL_PRIV, WRITE_LOCKOWNER};
+        use std::os::unix::fs::PermissionsExt;
+
+        let (source, fs) = prepare_passthroughfs_with(|cfg| cfg.killpriv_v2 = true);
+        let path = source.as_path().join("a");
+        std::fs::write(&path, b"data").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o4755)).unwrap();
+        fs.init(FsOptions::HANDLE_KILLPRIV_V2).unwrap();
+        let ctx = Context::default();
+        let ino = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap()
+            .inode;
+        let (fh, _) = fs.open(&ctx, ino, libc::O_RDWR as u32, 0).unwrap();
+        let fh = fh.unwrap();
+        let write = |fuse_flags: u32, lock_owner: Option<u64>| {
+            let mut r = SliceReader::new(b"DATA");
+            let delayed_write = fuse_flags & WRITE_CACHE != 0;
+            fs.write(
+                &ctx,
+                ino,
+                fh,
+                &mut r,
+                4,
+                0,
+                lock_owner,
+                delayed_write,
+                0,
+                fuse_flags,
+            )
+            .unwrap()
+        };
+        let suid = || std::fs::metadata(&path).unwrap().permissions().mode() & 0o4000 != 0;
+
+        // Flushing cached pages doesn't kill privileges, nor does the lock owner change anything.
+        assert_eq!(write(WRITE_CACHE | WRITE_KILL_PRIV, None), 4);
+        assert!(suid());
+        assert_eq!(write(WRITE_LOCKOWNER, Some(0x1234)), 4);
+        assert!(suid());
+        assert_eq!(std::fs::read(&path).unwrap(), b"DATA");
+
+        // Privileges are killed for writes on behalf of processes.
+        assert_eq!(write(WRITE_KILL_PRIV | WRITE_LOCKOWNER, Some(0x1234)), 4);
+        assert!(!suid());
+    }
+
+    #[test]
+    fn test_passthroughfs_killpriv_v2() {
+        use crate::abi::fuse_abi::{FOPEN_IN_KILL_SUIDGID, WRITE_KILL_PRIV};
+        use std::os::unix::fs::PermissionsExt;
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let path = source.as_path().join("a");
+        std::fs::write(&path, b"data").unwrap();
+        let new_fs = |killpriv_v2| {
+            let fs = passthroughfs_in(source.as_path(), |cfg| cfg.killpriv_v2 = killpriv_v2);
+            let opts = fs.init(FsOptions::HANDLE_KILLPRIV_V2).unwrap();
+            assert_eq!(opts.contains(FsOptions::HANDLE_KILLPRIV_V2), killpriv_v2);
+            fs
+        };
+        let reset = || {
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o6777)).unwrap();
+        };
+        let mode = || std::fs::metadata(&path).unwrap().permissions().mode() & 0o7777;
+
+        // Requests on behalf of a user other than the owner.
+        let fs = new_fs(true);
+        let ctx = Context {
+            uid: 1000,
+            gid: 1000,
+            ..Default::default()
+        };
+        let ino = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap()
+            .inode;
+        let (fh, _) = fs.open(&ctx, ino, libc::O_RDWR as u32, 0).unwrap();
+        let fh = fh.unwrap();
+        let write = |fuse_flags| {
+            let mut r = SliceReader::new(b"DATA");
+            fs.write(&ctx, ino, fh, &mut r, 4, 0, None, false, 0, fuse_flags)
+                .unwrap();
+        };
+        let truncate = |valid| {
+            let mut attr: libc::stat64 = unsafe { std::mem::zeroed() };
+            attr.st_size = 2;
+            fs.setattr(&ctx, ino, attr, None, SetattrValid::SIZE | valid)
+                .unwrap();
+        };
+
+        reset();
+        write(0);
+        assert_eq!(mode(), 0o6777);
+        write(WRITE_KILL_PRIV);
+        assert_eq!(mode(), 0o777);
+
+        reset();
+        truncate(SetattrValid::empty());
+        assert_eq!(mode(), 0o6777);
+        truncate(SetattrValid::KILL_SUIDGID);
+        assert_eq!(mode(), 0o777);
+
+        reset();
+        let flags = (libc::O_RDWR | libc::O_TRUNC) as u32;
+        fs.open(&ctx, ino, flags, 0).unwrap();
+        assert_eq!(mode(), 0o6777);
+        fs.open(&ctx, ino, flags, FOPEN_IN_KILL_SUIDGID).unwrap();
+        assert_eq!(mode(), 0o777);
+
+        // The setgid bit of files not executable by the group isn't a privilege.
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o6767)).unwrap();
+        write(WRITE_KILL_PRIV);
+        assert_eq!(mode(), 0o2767);
+
+        // Without kill_priv_v2 negotiated, flags sent anyway are ignored.
+        let fs = new_fs(false);
+        let ino = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap()
+            .inode;
+        reset();
+        fs.open(&ctx, ino, flags, FOPEN_IN_KILL_SUIDGID).unwrap();
+        assert_eq!(mode(), 0o6777);
+    }
+
+    #[test]
+    fn test_passthroughfs_kill_capability() {
+        let (source, fs) = prepare_passthroughfs_with(|cfg| {
+            cfg.killpriv_v2 = true;
+            cfg.xattr = true;
+        });
+        let path = source.as_path().join("a");
+        std::fs::write(&path, b"data").unwrap();
+        fs.init(FsOptions::HANDLE_KILLPRIV_V2).unwrap();
+        let cpath = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let name = CStr::from_bytes_with_nul(SECURITY_CAPABILITY).unwrap();
+        // A version 2 capability set granting CAP_NET_BIND_SERVICE.
+        let caps: Vec<u8> = [0x0200_0001u32, 1 << 10, 0, 0, 0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes().to_vec())
+            .collect();
+        let set_caps = || {
+            let res = unsafe {
+                libc::setxattr(
+                    cpath.as_ptr(),
+                    name.as_ptr(),
+                    caps.as_ptr() as *const libc::c_void,
+                    caps.len(),
+                    0,
+                )
+            };
+            res == 0
+        };
+        let has_caps = || {
+            let size =
+                unsafe { libc::getxattr(cpath.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+            size > 0
+        };
+        if !set_caps() {
+            // The file system of the temporary directory doesn't support capabilities.
+            return;
+        }
+
+        let root = Context::default();
+        let ctx = Context {
+            uid: 1000,
+            gid: 1000,
+            ..Default::default()
+        };
+        let ino = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap()
+            .inode;
+        let (fh, _) = fs.open(&ctx, ino, libc::O_RDWR as u32, 0).unwrap();
+        let fh = fh.unwrap();
+        let inode = fs.inode_map.get(ino).unwrap();
+        let handle = fs.handle_map.get(fh, ino).unwrap();
+        let fd = handle.get_handle_raw_fd();
+
+        // Root keeps the capabilities, other users don't.
+        fs.kill_capability(&root, &inode, fd, Some(&handle))
+            .unwrap();
+        assert!(has_caps());
+        let mut r = SliceReader::new(b"DATA");
+        fs.write(&ctx, ino, fh, &mut r, 4, 0, None, false, 0, 0)
+            .unwrap();
+        assert!(!has_caps());
+
+        // Once the handle is known to have no capabilities, the file isn't probed anymore.
+        assert!(set_caps());
+        fs.kill_capability(&ctx, &inode, fd, Some(&handle)).unwrap();
+        assert!(has_caps());
+        fs.kill_capability(&ctx, &inode, fd, None).unwrap();
+        assert!(!has_caps());
+
+        // Setting capabilities through the file system invalidates the hint.
+        fs.setxattr(&root, ino, name, &caps, 0).unwrap();
+        assert!(has_caps());
+        fs.fallocate(&ctx, ino, fh, 0, 0, 8).unwrap();
+        assert!(!has_caps());
+
+        fs.setxattr(&root, ino, name, &caps, 0).unwrap();
+        let mut attr: libc::stat64 = unsafe { std::mem::zeroed() };
+        attr.st_size = 2;
+        fs.setattr(&ctx, ino, attr, Some(fh), SetattrValid::SIZE)
+            .unwrap();
+        assert!(!has_caps());
+    }
+
+    #[test]
+    fn test_passthroughfs_create_supp_group() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let shared = source.as_path().join("shared");
+        std::fs::create_dir(&shared).unwrap();
+        std::os::unix::fs::chown(&shared, Some(0), Some(1234)).unwrap();
+        std::fs::set_permissions(&shared, std::fs::Permissions::from_mode(0o770)).unwrap();
+        let new_fs = |create_supp_group| {
+            let fs = passthroughfs_in(source.as_path(), |cfg| {
+                cfg.create_supp_group = create_supp_group
+            });
+            let opts = fs.init(FsOptions::CREATE_SUPP_GROUP).unwrap();
+            assert_eq!(
+                opts.contains(FsOptions::CREATE_SUPP_GROUP),
+                create_supp_group
+            );
+            fs
+        };
+        let mkdir = |fs: &PassthroughFs, supp_gid, name: &str| {
+            let ctx = Context {
+                uid: 1000,
+                gid: 1000,
+                supp_gid,
+                ..Default::default()
+            };
+            let dir = fs
+                .lookup(&ctx, ROOT_ID, &CString::new("shared").unwrap())
+                .unwrap()
+                .inode;
+            fs.mkdir(&ctx, dir, &CString::new(name).unwrap(), 0o755, 0)
+                .map_err(|e| e.raw_os_error())
+        };
+
+        // The caller is a member of the group owning the directory.
+        let fs = new_fs(true);
+        let entry = mkdir(&fs, Some(1234), "a").unwrap();
+        assert_eq!((entry.attr.st_uid, entry.attr.st_gid), (1000, 1000));
+        assert!(std::fs::metadata(shared.join("a")).unwrap().is_dir());
+        // Kernels not sending the group.
+        assert_eq!(mkdir(&fs, None, "b").err(), Some(Some(libc::EACCES)));
+
+        // Groups aren't switched unless configured.
+        let fs = new_fs(false);
+        assert_eq!(mkdir(&fs, Some(1234), "c").err(), Some(Some(libc::EACCES)));
+    }
+
+    // Keep `struct fsxattr` and inode flags of all files in memory, failing sets with `set_err`
+    // if any.
+    struct MockFsxattr {
+        attr: Arc<Mutex<fsxattr::Fsxattr>>,
+        flags: Arc<Mutex<u32>>,
+        set_err: Option<i32>,
+    }
+
+    impl FsxattrSyscalls for MockFsxattr {
+        fn get(&self, _fd: RawFd) -> io::Result<fsxattr::Fsxattr> {
+            Ok(*self.attr.lock().unwrap())
+        }
+
+        fn set(&self, _fd: RawFd, attr: &fsxattr::Fsxattr) -> io::Result<()> {
+            if let Some(e) = self.set_err {
+                return Err(io::Error::from_raw_os_error(e));
+            }
+            *self.attr.lock().unwrap() = *attr;
+            Ok(())
+        }
+
+        fn get_flags(&self, _fd: RawFd) -> io::Result<u32> {
+            Ok(*self.flags.lock().unwrap())
+        }
+
+        fn set_flags(&self, _fd: RawFd, flags: u32) -> io::Result<()> {
+            if let Some(e) = self.set_err {
+                return Err(io::Error::from_raw_os_error(e));
+            }
+            *self.flags.lock().unwrap() = flags;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_passthroughfs_fsxattr() {
+        use fsxattr::{Fsxattr, FS_IOC_FSGETXATTR, FS_IOC_FSSETXATTR};
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("a"), b"a").unwrap();
+        let ctx = Context::default();
+        let size = std::mem::size_of::<Fsxattr>() as u32;
+        let get = |fs: &PassthroughFs, ino, fh| {
+            let out = fs.ioctl(&ctx, ino, fh, 0, FS_IOC_FSGETXATTR, 0, &[], size)?;
+            let mut attr = Fsxattr::default();
+            match out {
+                IoctlReply::Done { data, .. } => attr.as_mut_slice().copy_from_slice(&data),
+                IoctlReply::Retry { .. } => panic!("unexpected retry"),
+            }
+            Ok::<_, io::Error>(attr)
+        };
+        let set = |fs: &PassthroughFs, ino, fh, attr: &Fsxattr| {
+            fs.ioctl(&ctx, ino, fh, 0, FS_IOC_FSSETXATTR, 0, attr.as_slice(), 0)
+                .map(|_| ())
+        };
+        let errno = |res: io::Result<()>| res.err().and_then(|e| crate::api::errno::errno_of(&e));
+
+        // Unsupported unless configured.
+        let fs = passthroughfs_in(source.as_path(), |_| {});
+        let ino = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap()
+            .inode;
+        assert_eq!(errno(get(&fs, ino, 0).map(|_| ())), Some(libc::ENOTTY));
+
+        let mut fs = passthroughfs_in(source.as_path(), |cfg| {
+            cfg.fsxattr = Some(FsxattrPolicy::default())
+        });
+        let host = Arc::new(Mutex::new(Fsxattr::default()));
+        fs.fsxattr_sys = Box::new(MockFsxattr {
+            attr: host.clone(),
+            flags: Default::default(),
+            set_err: None,
+        });
+        let ino = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap()
+            .inode;
+        let (fh, _) = fs.open(&ctx, ino, libc::O_RDONLY as u32, 0).unwrap();
+        let fh = fh.unwrap();
+
+        // Set a project id through the handle, read it back through a reopened inode.
+        let mut attr = get(&fs, ino, fh).unwrap();
+        attr.fsx_projid = 42;
+        set(&fs, ino, fh, &attr).unwrap();
+        assert_eq!(host.lock().unwrap().fsx_projid, 42);
+        assert_eq!(get(&fs, ino, 0).unwrap().fsx_projid, 42);
+        assert_eq!(
+            errno(
+                fs.ioctl(&ctx, ino, fh, 0, FS_IOC_FSGETXATTR, 0, &[], 8)
+                    .map(|_| ())
+            ),
+            Some(libc::EINVAL)
+        );
+
+        // Gated flags can't be changed.
+        attr.fsx_xflags |= FS_XFLAG_IMMUTABLE;
+        assert_eq!(errno(set(&fs, ino, fh, &attr)), Some(libc::EPERM));
+        assert_eq!(host.lock().unwrap().fsx_xflags, 0);
+
+        // The host refusing the change degrades to EPERM.
+        fs.fsxattr_sys = Box::new(MockFsxattr {
+            attr: host.clone(),
+            flags: Default::default(),
+            set_err: Some(libc::EACCES),
+        });
+        attr.fsx_xflags = 0;
+        attr.fsx_projid = 43;
+        assert_eq!(errno(set(&fs, ino, fh, &attr)), Some(libc::EPERM));
+        assert_eq!(get(&fs, ino, fh).unwrap().fsx_projid, 42);
+
+        // Inode flags ioctls stay unsupported unless configured.
+        assert_eq!(
+            errno(
+                fs.ioctl(&ctx, ino, fh, 0, fsxattr::FS_IOC_GETFLAGS, 0, &[], 8)
+                    .map(|_| ())
+            ),
+            Some(libc::ENOTTY)
+        );
+    }
+
+    #[test]
+    fn test_passthroughfs_poll() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("a"), b"a").unwrap();
+        let fs = passthroughfs_in(source.as_path(), |_| {});
+        let ctx = Context::default();
+        let ino = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap()
+            .inode;
+        let (fh, _) = fs.open(&ctx, ino, libc::O_RDONLY as u32, 0).unwrap();
+        let fh = fh.unwrap();
+
+        // Regular files are always ready, only the requested events are reported.
+        let events = (libc::POLLIN | libc::POLLOUT) as u32;
+        assert_eq!(fs.poll(&ctx, ino, fh, 1, 0, events).unwrap(), events);
+        let events = libc::POLLIN as u32;
+        assert_eq!(
+            fs.poll(&ctx, ino, fh, 1, fuse::POLL_SCHEDULE_NOTIFY, events)
+                .unwrap(),
+            events
+        );
+        assert_eq!(
+            fs.poll(&ctx, ino, fh + 100, 1, 0, events)
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EBADF)
+        );
+    }
+
+    #[test]
+    fn test_passthroughfs_inode_flags() {
+        use fsxattr::{FS_IOC32_GETFLAGS, FS_IOC32_SETFLAGS, FS_IOC_GETFLAGS, FS_IOC_SETFLAGS};
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("a"), b"a").unwrap();
+        let ctx = Context::default();
+        let mut fs = passthroughfs_in(source.as_path(), |cfg| {
+            cfg.inode_flags = Some(FS_IMMUTABLE_FL)
+        });
+        let host = Arc::new(Mutex::new(0x80000));
+        fs.fsxattr_sys = Box::new(MockFsxattr {
+            attr: Default::default(),
+            flags: host.clone(),
+            set_err: None,
+        });
+        let ino = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap()
+            .inode;
+        let (fh, _) = fs.open(&ctx, ino, libc::O_RDONLY as u32, 0).unwrap();
+        let fh = fh.unwrap();
+        let get = |fs: &PassthroughFs, cmd, fh| match fs.ioctl(&ctx, ino, fh, 0, cmd, 0, &[], 8)? {
+            IoctlReply::Done { data, .. } => {
+                let mut flags = [0u8; 4];
+                flags.copy_from_slice(&data);
+                Ok::<_, io::Error>(u32::from_ne_bytes(flags))
+            }
+            IoctlReply::Retry { .. } => panic!("unexpected retry"),
+        };
+        let set = |fs: &PassthroughFs, cmd, flags: u32| {
+            fs.ioctl(&ctx, ino, fh, 0, cmd, 0, &flags.to_ne_bytes(), 0)
+                .map(|_| ())
+        };
+        let errno = |res: io::Result<()>| res.err().and_then(|e| crate::api::errno::errno_of(&e));
+
+        // `chattr +i` from 64-bit and 32-bit guests, through handles and reopened inodes.
+        assert_eq!(get(&fs, FS_IOC_GETFLAGS, fh).unwrap(), 0x80000);
+        set(&fs, FS_IOC_SETFLAGS, 0x80000 | FS_IMMUTABLE_FL).unwrap();
+        assert_eq!(*host.lock().unwrap(), 0x80000 | FS_IMMUTABLE_FL);
+        assert_eq!(
+            get(&fs, FS_IOC32_GETFLAGS, 0).unwrap(),
+            0x80000 | FS_IMMUTABLE_FL
+        );
+        set(&fs, FS_IOC32_SETFLAGS, 0x80000).unwrap();
+        assert_eq!(*host.lock().unwrap(), 0x80000);
+
+        // Flags out of the mask can't be changed, nor short input accepted.
+        assert_eq!(
+            errno(set(&fs, FS_IOC_SETFLAGS, 0x80000 | FS_APPEND_FL)),
+            Some(libc::EPERM)
+        );
+        assert_eq!(
+            errno(
+                fs.ioctl(&ctx, ino, fh, 0, FS_IOC_SETFLAGS, 0, &[0; 2], 0)
+                    .map(|_| ())
+            ),
+            Some(libc::EINVAL)
+        );
+
+        // The host refusing the change degrades to EPERM.
+        fs.fsxattr_sys = Box::new(MockFsxattr {
+            attr: Default::default(),
+            flags: host.clone(),
+            set_err: Some(libc::EACCES),
+        });
+        assert_eq!(
+            errno(set(&fs, FS_IOC_SETFLAGS, 0x80000 | FS_IMMUTABLE_FL)),
+            Some(libc::EPERM)
+        );
+        assert_eq!(*host.lock().unwrap(), 0x80000);
+
+        // Fsxattr ioctls stay unsupported unless configured.
+        assert_eq!(
+            errno(
+                fs.ioctl(&ctx, ino, fh, 0, fsxattr::FS_IOC_FSGETXATTR, 0, &[], 28)
+                    .map(|_| ())
+            ),
+            Some(libc::ENOTTY)
+        );
+    }
+
+    #[test]
+    fn test_passthroughfs_blksize() {
+        use std::os::unix::fs::{FileExt, MetadataExt};
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let cfg = Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            blksize: Some(1000),
+            ..Default::default()
+        };
+        let err = PassthroughFs::<()>::new(cfg).err().unwrap();
+        assert_eq!(crate::api::errno::errno_of(&err), Some(libc::EINVAL));
+
+        // A sparse file with a single page of data, and a dense one of the same size.
+        const SIZE: u64 = 1 << 20;
+        let sparse = std::fs::File::create(source.as_path().join("sparse")).unwrap();
+        sparse.set_len(SIZE).unwrap();
+        sparse.write_all_at(&[1u8; 4096], 0).unwrap();
+        std::fs::write(source.as_path().join("dense"), vec![1u8; SIZE as usize]).unwrap();
+
+        let ctx = Context::default();
+        let host_blksize = sparse.metadata().unwrap().blksize() as i64;
+        for blksize in [None, Some(65536)] {
+            let fs = passthroughfs_in(source.as_path(), |cfg| cfg.blksize = blksize);
+            let expected = blksize.map(|b| b as i64).unwrap_or(host_blksize);
+            let du = |name: &str| {
+                let path = source.as_path().join(name);
+                let entry = fs
+                    .lookup(&ctx, ROOT_ID, &CString::new(name).unwrap())
+                    .unwrap();
+                let (st, _) = fs.getattr(&ctx, entry.inode, None).unwrap();
+                assert_eq!(entry.attr.st_blksize, expected);
+                assert_eq!(st.st_blksize, expected);
+                // Blocks are the host's in 512 bytes units, whatever the block size reported.
+                assert_eq!(
+                    st.st_blocks as u64,
+                    std::fs::metadata(path).unwrap().blocks()
+                );
+                st.st_blocks as u64 * 512
+            };
+
+            assert!(du("dense") >= SIZE);
+            // Holes are kept, where the backing file system supports them.
+            let sparse_du = du("sparse");
+            if sparse_du < SIZE {
+                assert!(sparse_du >= 4096);
+                // Filling the holes allocates them.
+                sparse.write_all_at(&vec![1u8; SIZE as usize], 0).unwrap();
+                assert!(du("sparse") >= SIZE);
+                sparse.set_len(0).unwrap();
+                sparse.set_len(SIZE).unwrap();
+                sparse.write_all_at(&[1u8; 4096], 0).unwrap();
+            }
+        }
+    }
+
+    // Truncate probed timestamps to a granularity.
+    struct MockTimeGran(i64);
+
+    impl TimeGranSyscalls for MockTimeGran {
+        fn probe(&self, _dir: RawFd, nsec: i64) -> io::Result<i64> {
+            Ok(nsec - nsec % self.0)
+        }
+    }
+
+    #[test]
+    fn test_passthroughfs_statfs() {
+        use crate::abi::fuse_abi::Kstatfs;
+
+        let (source, fs) = prepare_passthroughfs_with(|_| {});
+        let st = fs.statfs(&Context::default(), ROOT_ID).unwrap();
+        let path = std::ffi::CString::new(source.as_path().to_str().unwrap()).unwrap();
+        let mut host = MaybeUninit::<libc::statvfs64>::zeroed();
+        // Safe because this will only modify `host` and we check the return value.
+        assert_eq!(
+            unsafe { libc::statvfs64(path.as_ptr(), host.as_mut_ptr()) },
+            0
+        );
+        // Safe because the kernel guarantees that `host` has been initialized.
+        let host = unsafe { host.assume_init() };
+        assert_eq!(st.f_namemax, host.f_namemax);
+        assert_eq!(st.f_frsize, host.f_frsize);
+        assert_eq!(st.f_bsize, host.f_bsize);
+        assert_eq!(st.f_blocks, host.f_blocks);
+
+        // All fields known by the protocol are replied.
+        let out = Kstatfs::from(st);
+        assert_eq!(out.namelen as u64, host.f_namemax);
+        assert_eq!(out.frsize as u64, host.f_frsize);
+        assert_eq!(out.bsize as u64, host.f_bsize);
+        assert_eq!(out.files, host.f_files);
+    }
+
+    #[test]
+    fn test_passthroughfs_time_gran() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("a"), b"a").unwrap();
+        let cfg = Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            time_gran: Some(3),
+            ..Default::default()
+        };
+        let err = PassthroughFs::<()>::new(cfg).err().unwrap();
+        assert_eq!(crate::api::errno::errno_of(&err), Some(libc::EINVAL));
+
+        // The granularity is probed at init, unless configured.
+        let mut fs = passthroughfs_in(source.as_path(), |_| {});
+        fs.time_gran_sys = Box::new(MockTimeGran(1_000_000));
+        assert_eq!(fs.time_gran(), 1);
+        fs.init(FsOptions::empty()).unwrap();
+        assert_eq!(fs.time_gran(), 1_000_000);
+
+        let mut fs = passthroughfs_in(source.as_path(), |cfg| cfg.time_gran = Some(1000));
+        fs.time_gran_sys = Box::new(MockTimeGran(1_000_000));
+        fs.init(FsOptions::empty()).unwrap();
+        assert_eq!(fs.time_gran(), 1000);
+
+        // Timestamps set by the guest are truncated to the granularity.
+        let ctx = Context::default();
+        let ino = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap()
+            .inode;
+        let mut attr: libc::stat64 = unsafe { std::mem::zeroed() };
+        attr.st_atime = 1000;
+        attr.st_atime_nsec = 123_456_789;
+        attr.st_mtime = 2000;
+        attr.st_mtime_nsec = 987_654_321;
+        let (st, _) = fs
+            .setattr(
+                &ctx,
+                ino,
+                attr,
+                None,
+                SetattrValid::ATIME | SetattrValid::MTIME,
+            )
+            .unwrap();
+        assert_eq!((st.st_atime, st.st_atime_nsec), (1000, 123_456_000));
+        assert_eq!((st.st_mtime, st.st_mtime_nsec), (2000, 987_654_000));
+
+        // A Vfs advertises the coarsest granularity of its backends.
+        let vfs = Vfs::new(VfsOptions::default());
+        vfs.mount(Box::new(fs), "/a").unwrap();
+        let fs = passthroughfs_in(source.as_path(), |cfg| cfg.time_gran = Some(1_000_000_000));
+        vfs.mount(Box::new(fs), "/b").unwrap();
+        vfs.init(FsOptions::empty()).unwrap();
+        assert_eq!(vfs.time_gran(), 1_000_000_000);
+    }
+
+    #[test]
+    fn test_inode_map_shards() {
+        let map = InodeMap::new();
+        let altkey = |ino| InodeAltKey::Ids {
+            ino,
+            dev: 1,
+            mnt: 1,
+        };
+        for ino in 0..256 {
+            let (shard, _inodes) = map.get_alt_map_mut(&altkey(ino));
+            let inode = map.next_inode(shard);
+            assert!(inode > ROOT_ID);
+            assert_eq!(map.shard_of(inode), shard);
+        }
+        // Alt keys spread over the shards.
+        let shards: std::collections::HashSet<_> =
+            (0..256).map(|ino| map.shard_of_alt(&altkey(ino))).collect();
+        assert!(shards.len() > MAP_SHARDS / 2);
+
+        // A single shard keeps allocating inodes from ROOT_ID + 1.
+        let map = InodeMap::with_shards(1);
+        assert_eq!(map.next_inode(0), ROOT_ID + 1);
+    }
+
+    // Look up and forget `files` files from `threads` threads, return the time spent.
+    fn lookup_forget_loop(
+        fs: &PassthroughFs,
+        threads: usize,
+        files: usize,
+        rounds: usize,
+    ) -> std::time::Duration {
+        let start = std::time::Instant::now();
+        std::thread::scope(|s| {
+            for t in 0..threads {
+                s.spawn(move || {
+                    let ctx = Context::default();
+                    for i in 0..rounds {
+                        let name = CString::new(format!("f{}", (t + i) % files)).unwrap();
+                        let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+                        // A looked up inode is never dropped under us by racing forgets.
+                        let (st, _) = fs.getattr(&ctx, entry.inode, None).unwrap();
+                        assert_eq!(st.st_ino, entry.attr.st_ino);
+                        fs.forget(&ctx, entry.inode, 1);
+                    }
+                });
+            }
+        });
+        start.elapsed()
+    }
+
+    fn prepare_files(files: usize) -> TempDir {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        for i in 0..files {
+            std::fs::write(source.as_path().join(format!("f{}", i)), b"f").unwrap();
+        }
+        source
+    }
+
+    fn sharded_passthroughfs(source: &TempDir, shards: usize) -> PassthroughFs {
+        let fs_cfg = Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let mut fs = PassthroughFs::<()>::new(fs_cfg).unwrap();
+        fs.inode_map = InodeMap::with_shards(shards);
+        fs.import().unwrap();
+        fs
+    }
+
+    #[test]
+    fn test_passthroughfs_lookup_forget_race() {
+        let source = prepare_files(4);
+        let fs = sharded_passthroughfs(&source, MAP_SHARDS);
+
+        // Few files looked up and forgotten by many threads, so refcounts often drop to zero
+        // while other threads look the same files up.
+        lookup_forget_loop(&fs, 8, 4, 500);
+        assert_eq!(fs.inode_map.len(), 1);
+
+        let ctx = Context::default();
+        let name = CString::new("f0").unwrap();
+        let inode = fs.lookup(&ctx, ROOT_ID, &name).unwrap().inode;
+        assert_eq!(fs.lookup(&ctx, ROOT_ID, &name).unwrap().inode, inode);
+        assert_eq!(fs.debug_nlookup(inode), Some(2));
+        fs.batch_forget(&ctx, vec![(inode, 2)]);
+        assert_eq!(fs.inode_map.len(), 1);
+    }
+
+    #[test]
+    fn test_passthroughfs_batch_forget() {
+        let source = prepare_files(128);
+        let fs = sharded_passthroughfs(&source, MAP_SHARDS);
+        let ctx = Context::default();
+
+        let mut inodes = Vec::new();
+        for i in 0..128 {
+            let name = CString::new(format!("f{}", i)).unwrap();
+            let inode = fs.lookup(&ctx, ROOT_ID, &name).unwrap().inode;
+            for _ in 1..512 {
+                fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+            }
+            inodes.push(inode);
+        }
+        // One more reference to keep the first inode.
+        fs.lookup(&ctx, ROOT_ID, &CString::new("f0").unwrap())
+            .unwrap();
+
+        // Interleave the inodes so the batch jumps between shards.
+        let requests = (0..512)
+            .flat_map(|_| inodes.iter().map(|inode| (*inode, 1)))
+            .collect::<Vec<_>>();
+        assert_eq!(requests.len(), 65536);
+        let locks = fs.inode_map.write_locks.load(Ordering::Relaxed);
+        fs.batch_forget(&ctx, requests);
+        let locks = fs.inode_map.write_locks.load(Ordering::Relaxed) - locks;
+        assert!(
+            locks <= MAP_SHARDS + 65536 / FORGET_CHUNK,
+            "{} locks",
+            locks
+        );
+
+        assert_eq!(fs.debug_nlookup(inodes[0]), Some(1));
+        assert!(inodes[1..]
+            .iter()
+            .all(|inode| fs.debug_nlookup(*inode) == Some(0)));
+        assert_eq!(fs.inode_map.len(), 2);
+    }
+
+    // Compare the lookup throughput of a single shard and of the default shards, run with
+    // `cargo test --release -- --ignored --nocapture bench_passthroughfs_lookup`.
+    #[test]
+    #[ignore]
+    fn bench_passthroughfs_lookup() {
+        let threads = std::thread::available_parallelism().map_or(8, |n| n.get());
+        let source = prepare_files(1024);
+        for shards in [1, MAP_SHARDS] {
+            let fs = sharded_passthroughfs(&source, shards);
+            let elapsed = lookup_forget_loop(&fs, threads, 1024, 20000);
+            println!(
+                "{} shards, {} threads: {:.0} lookups/s",
+                shards,
+                threads,
+                (threads * 20000) as f64 / elapsed.as_secs_f64()
+            );
+        }
+    }
+
+    #[test]
+    fn test_passthroughfs_rename_flags() {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+        let (source, fs) = prepare_passthroughfs_with(|_| {});
+        let path = |name: &str| source.as_path().join(name);
+        std::fs::write(path("a"), b"a").unwrap();
+        std::fs::write(path("b"), b"b").unwrap();
+        let ctx = Context::default();
+        let cs = |s: &str| CString::new(s).unwrap();
+        let rename = |old: &str, new: &str, flags| {
+            fs.rename(&ctx, ROOT_ID, &cs(old), ROOT_ID, &cs(new), flags)
+        };
+
+        // Existing targets are kept.
+        let err = rename("a", "b", libc::RENAME_NOREPLACE).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+        assert_eq!(std::fs::read(path("b")).unwrap(), b"b");
+        rename("a", "c", libc::RENAME_NOREPLACE).unwrap();
+
+        // Both entries must exist to be exchanged.
+        rename("b", "c", libc::RENAME_EXCHANGE).unwrap();
+        assert_eq!(std::fs::read(path("b")).unwrap(), b"a");
+        assert_eq!(std::fs::read(path("c")).unwrap(), b"b");
+        let err = rename("b", "d", libc::RENAME_EXCHANGE).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        let err = rename("b", "c", libc::RENAME_EXCHANGE | libc::RENAME_NOREPLACE).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+
+        // A whiteout takes the place of the source, unless the host refuses the flag.
+        match rename("b", "d", libc::RENAME_WHITEOUT) {
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EINVAL | libc::EPERM)) => {}
+            res => {
+                res.unwrap();
+                let st = std::fs::symlink_metadata(path("b")).unwrap();
+                assert!(st.file_type().is_char_device());
+                assert_eq!(st.rdev(), 0);
+                assert_eq!(std::fs::read(path("d")).unwrap(), b"a");
+            }
+        }
+    }
+
+    #[test]
+    fn test_passthroughfs_path_hint() {
+        let (source, fs) = prepare_passthroughfs_with(|_| {});
+        std::fs::create_dir(source.as_path().join("d")).unwrap();
+        std::fs::write(source.as_path().join("d/a"), b"a").unwrap();
+        std::fs::write(source.as_path().join("b"), b"b").unwrap();
+        let ctx = Context::default();
+        let cs = |s: &str| CString::new(s).unwrap();
+        let hint = |ino| fs.path_hint(ino).map(|p| p.to_str().unwrap().to_string());
+        let d = fs.lookup(&ctx, ROOT_ID, &cs("d")).unwrap().inode;
+        let a = fs.lookup(&ctx, d, &cs("a")).unwrap().inode;
+        let b = fs.lookup(&ctx, ROOT_ID, &cs("b")).unwrap().inode;
+        assert_eq!(hint(a).as_deref(), Some("d/a"));
+
+        // Descendants follow renamed directories.
+        fs.rename(&ctx, ROOT_ID, &cs("d"), ROOT_ID, &cs("e"), 0)
+            .unwrap();
+        assert_eq!(hint(a).as_deref(), Some("e/a"));
+
+        // Renaming over an entry drops the hint of the replaced inode.
+        fs.rename(&ctx, d, &cs("a"), ROOT_ID, &cs("b"), 0).unwrap();
+        assert_eq!(hint(a).as_deref(), Some("b"));
+        assert!(hint(b).is_none());
+
+        fs.unlink(&ctx, ROOT_ID, &cs("b")).unwrap();
+        assert!(hint(a).is_none());
+        fs.forget(&ctx, d, 1);
+        assert!(hint(d).is_none());
+    }
+
+    #[test]
+    fn test_passthroughfs_switch_creds() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::set_permissions(source.as_path(), std::fs::Permissions::from_mode(0o777)).unwrap();
+        // Safe because these calls don't modify any memory and always succeed.
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let owner = |name: &str| {
+            let md = std::fs::metadata(source.as_path().join(name)).unwrap();
+            (md.uid(), md.gid())
+        };
+        let mkdir = |switch_creds: bool, ctx: Context, name: &str| {
+            let fs = passthroughfs_in(source.as_path(), |cfg| cfg.switch_creds = switch_creds);
+            let name = CString::new(name).unwrap();
+            fs.mkdir(&ctx, ROOT_ID, &name, 0o755, 0).unwrap();
+            fs.cred_switch_stats()
+        };
+        let daemon = Context {
+            uid,
+            gid,
+            ..Default::default()
+        };
+        let other = Context {
+            uid: uid + 1000,
+            gid: gid + 1000,
+            ..Default::default()
+        };
+
+        // Callers with the daemon's ids create files without switching.
+        let stats = mkdir(true, daemon, "a");
+        assert_eq!(stats.switched, 0);
+        assert_eq!(stats.skipped, 1);
+        assert_eq!(owner("a"), (uid, gid));
+
+        // With switching disabled, files are always owned by the daemon.
+        let stats = mkdir(false, other, "b");
+        assert_eq!(stats.skipped, 1);
+        assert_eq!(owner("b"), (uid, gid));
+
+        // Only privileged daemons may create files owned by other users.
+        if uid == 0 {
+            let stats = mkdir(true, other, "c");
+            assert_eq!(stats.switched, 1);
+            assert_eq!(stats.skipped, 0);
+            assert_eq!(owner("c"), (other.uid, other.gid));
+            // Credentials of the thread are restored.
+            assert_eq!(unsafe { (libc::geteuid(), libc::getegid()) }, (uid, gid));
+        }
+    }
+
+    #[test]
+    fn test_passthroughfs_uid_gid_map() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::set_permissions(source.as_path(), std::fs::Permissions::from_mode(0o777)).unwrap();
+        std::fs::write(source.as_path().join("f"), b"").unwrap();
+        // Safe because these calls don't modify any memory and always succeed.
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let owner = |name: &str| {
+            let md = std::fs::metadata(source.as_path().join(name)).unwrap();
+            (md.uid(), md.gid())
+        };
+        let ctx = Context::default();
+        let guest_owner = |fs: &PassthroughFs, name: &str| {
+            let entry = fs
+                .lookup(&ctx, ROOT_ID, &CString::new(name).unwrap())
+                .unwrap();
+            let (st, _) = fs.getattr(&ctx, entry.inode, None).unwrap();
+            assert_eq!(
+                (st.st_uid, st.st_gid),
+                (entry.attr.st_uid, entry.attr.st_gid)
+            );
+            (st.st_uid, st.st_gid)
+        };
+
+        // Files of the daemon are owned by guest root, others by the overflow ids.
+        let map = format!("uidmap=0:{}:1,gidmap=0:{}:1", uid, gid);
+        let fs = passthroughfs_in(source.as_path(), |cfg| {
+            cfg.uid_gid_map = Some(map.parse().unwrap())
+        });
+        assert_eq!(guest_owner(&fs, "f"), (0, 0));
+        let mut attrs = Vec::new();
+        let (handle, _) = fs.opendir(&ctx, ROOT_ID, 0).unwrap();
+        fs.readdirplus(&ctx, ROOT_ID, handle.unwrap(), 4096, 0, &mut |_, e| {
+            attrs.push((e.attr.st_uid, e.attr.st_gid));
+            Ok(1)
+        })
+        .unwrap();
+        assert_eq!(attrs, vec![(0, 0)]);
+
+        // Only privileged daemons may own files by shifted ids.
+        if uid != 0 {
+            return;
+        }
+        let fs = passthroughfs_in(source.as_path(), |cfg| {
+            cfg.uid_gid_map = Some(
+                "uidmap=0:100000:65536,gidmap=0:200000:65536"
+                    .parse()
+                    .unwrap(),
+            )
+        });
+        assert_eq!(
+            guest_owner(&fs, "f"),
+            (DEFAULT_OVERFLOW_ID, DEFAULT_OVERFLOW_ID)
+        );
+        nix::unistd::chown(
+            &source.as_path().join("f"),
+            Some(nix::unistd::Uid::from_raw(100123)),
+            Some(nix::unistd::Gid::from_raw(200456)),
+        )
+        .unwrap();
+        assert_eq!(guest_owner(&fs, "f"), (123, 456));
+
+        // Owners changed by the guest are shifted on the host.
+        let entry = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("f").unwrap())
+            .unwrap();
+        let mut attr = entry.attr;
+        attr.st_uid = 5;
+        attr.st_gid = 6;
+        let valid = SetattrValid::UID | SetattrValid::GID;
+        let (st, _) = fs.setattr(&ctx, entry.inode, attr, None, valid).unwrap();
+        assert_eq!((st.st_uid, st.st_gid), (5, 6));
+        assert_eq!(owner("f"), (100005, 200006));
+
+        // Files are created with the shifted ids of the caller.
+        let caller = Context {
+            uid: 7,
+            gid: 8,
+            ..Default::default()
+        };
+        let entry = fs
+            .mkdir(&caller, ROOT_ID, &CString::new("d").unwrap(), 0o755, 0)
+            .unwrap();
+        assert_eq!((entry.attr.st_uid, entry.attr.st_gid), (7, 8));
+        assert_eq!(owner("d"), (100007, 200008));
+        assert_eq!(unsafe { (libc::geteuid(), libc::getegid()) }, (uid, gid));
+    }
+
+    #[test]
+    fn test_passthroughfs_announce_submounts() {
+        match caps::has_cap(None, CapSet::Effective, Capability::CAP_SYS_ADMIN) {
+            Ok(false) | Err(_) => {
+                println!("bind mounting needs CAP_SYS_ADMIN");
+                return;
+            }
+            Ok(true) => {}
+        }
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        for dir in ["plain", "bind"] {
+            std::fs::create_dir(source.as_path().join(dir)).unwrap();
+        }
+        std::fs::write(source.as_path().join("plain/f"), b"").unwrap();
+        let path =
+            |name: &str| CString::new(source.as_path().join(name).to_str().unwrap()).unwrap();
+        let (plain, bind) = (path("plain"), path("bind"));
+        // Safe because the arguments are valid C strings.
+        let res = unsafe {
+            libc::mount(
+                plain.as_ptr(),
+                bind.as_ptr(),
+                std::ptr::null(),
+                libc::MS_BIND,
+                std::ptr::null(),
+            )
+        };
+        assert_eq!(res, 0);
+
+        let ctx = Context::default();
+        let lookup = |fs: &PassthroughFs, parent, name: &str| {
+            fs.lookup(&ctx, parent, &CString::new(name).unwrap())
+                .unwrap()
+        };
+        let flags = |fs: &PassthroughFs| {
+            let bind = lookup(fs, ROOT_ID, "bind");
+            (
+                lookup(fs, ROOT_ID, "plain").attr_flags,
+                bind.attr_flags,
+                lookup(fs, bind.inode, "..").attr_flags,
+            )
+        };
+
+        // Not announced unless both enabled and supported by the kernel.
+        let fs = passthroughfs_in(source.as_path(), |cfg| cfg.announce_submounts = true);
+        assert!(!fs
+            .init(FsOptions::empty())
+            .unwrap()
+            .contains(FsOptions::SUBMOUNTS));
+        let res_unsupported = flags(&fs);
+        let fs = passthroughfs_in(source.as_path(), |_| {});
+        assert!(!fs
+            .init(FsOptions::SUBMOUNTS)
+            .unwrap()
+            .contains(FsOptions::SUBMOUNTS));
+        let res_disabled = flags(&fs);
+
+        let fs = passthroughfs_in(source.as_path(), |cfg| cfg.announce_submounts = true);
+        assert!(fs
+            .init(FsOptions::SUBMOUNTS)
+            .unwrap()
+            .contains(FsOptions::SUBMOUNTS));
+        let res = flags(&fs);
+        // The same host file on both mounts is two inodes.
+        let f = lookup(&fs, lookup(&fs, ROOT_ID, "plain").inode, "f");
+        let bind_f = lookup(&fs, lookup(&fs, ROOT_ID, "bind").inode, "f");
+
+        unsafe { libc::umount2(bind.as_ptr(), libc::MNT_DETACH) };
+        assert_eq!(res_unsupported, (0, 0, 0));
+        assert_eq!(res_disabled, (0, 0, 0));
+        // The root of the bind mount is announced, its parent directory isn't.
+        assert_eq!(res, (0, fuse::ATTR_SUBMOUNT, 0));
+        assert_eq!(f.attr.st_ino, bind_f.attr.st_ino);
+        assert_ne!(f.inode, bind_f.inode);
+    }
+
+    // Zero copy reader over a transport reader, splicing data left in a pipe.
+    #[cfg(feature = "fusedev")]
+    struct PipeReader<'a>(crate::transport::Reader<'a>);
+
+    #[cfg(feature = "fusedev")]
+    impl io::Read for PipeReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    impl ZeroCopyReader for PipeReader<'_> {
+        fn read_to(
+            &mut self,
+            f: &mut dyn crate::transport::FileReadWriteVolatile,
+            count: usize,
+            off: u64,
+        ) -> io::Result<usize> {
+            self.0.read_to_at(f, count, off)
+        }
+
+        fn splice_to(&mut self, fd: RawFd, count: usize, off: u64) -> io::Result<Option<usize>> {
+            self.0.splice_to_at(fd, count, off)
+        }
+    }
+
+    #[cfg(feature = "fusedev")]
+    #[test]
+    fn test_passthroughfs_splice_write() {
+        use crate::transport::{FuseBuf, Reader};
+
+        let (source, fs) = prepare_passthroughfs_with(|cfg| cfg.use_splice_write = true);
+        let path = source.as_path().join("a");
+        let ctx = Context::default();
+        let payload: Vec<u8> = (0..8192u32).map(|i| i as u8).collect();
+
+        // Files opened for appending don't support splice, their data is copied.
+        for flags in [libc::O_RDWR, libc::O_RDWR | libc::O_APPEND] {
+            std::fs::write(&path, b"").unwrap();
+            let ino = fs
+                .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+                .unwrap()
+                .inode;
+            let (fh, _) = fs.open(&ctx, ino, flags as u32, 0).unwrap();
+
+            let (rd, wr) = nix::unistd::pipe().unwrap();
+            nix::unistd::write(wr, &payload).unwrap();
+            let reader =
+                Reader::<()>::from_fuse_pipe(FuseBuf::new(&mut []), rd, payload.len()).unwrap();
+            let mut r = PipeReader(reader);
+            let n = fs
+                .write(&ctx, ino, fh.unwrap(), &mut r, 8192, 0, None, false, 0, 0)
+                .unwrap();
+            assert_eq!(n, 8192);
+            assert_eq!(r.0.available_bytes(), 0);
+            assert_eq!(std::fs::read(&path).unwrap(), payload);
+            nix::unistd::close(rd).unwrap();
+            nix::unistd::close(wr).unwrap();
+        }
+    }
+
+    // Reader splicing at most 4096 bytes, then failing to copy anything.
+    struct ShortSpliceReader<'a> {
+        data: &'a [u8],
+        spliced: bool,
+    }
+
+    impl io::Read for ShortSpliceReader<'_> {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::from_raw_os_error(libc::EIO))
+        }
+    }
+
+    impl ZeroCopyReader for ShortSpliceReader<'_> {
+        fn read_to(
+            &mut self,
+            _: &mut dyn crate::transport::FileReadWriteVolatile,
+            _: usize,
+            _: u64,
+        ) -> io::Result<usize> {
+            Err(io::Error::from_raw_os_error(libc::EIO))
+        }
+
+        fn splice_to(&mut self, fd: RawFd, count: usize, off: u64) -> io::Result<Option<usize>> {
+            use std::os::unix::fs::FileExt;
+
+            if self.spliced {
+                return Err(io::Error::from_raw_os_error(libc::EIO));
+            }
+            // Safe because the file is never dropped, the caller owns `fd`.
+            let file = std::mem::ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+            let count = count.min(4096);
+            file.write_all_at(&self.data[..count], off)?;
+            self.spliced = true;
+            Ok(Some(count))
+        }
+    }
+
+    #[test]
+    fn test_passthroughfs_splice_short_write() {
+        let (source, fs) = prepare_passthroughfs_with(|cfg| cfg.use_splice_write = true);
+        let path = source.as_path().join("a");
+        std::fs::write(&path, b"").unwrap();
+        let ctx = Context::default();
+        let ino = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap()
+            .inode;
+        let (fh, _) = fs.open(&ctx, ino, libc::O_RDWR as u32, 0).unwrap();
+        let payload: Vec<u8> = (0..8192u32).map(|i| i as u8).collect();
+
+        // Failing to copy data after some got spliced is a short write.
+        let mut r = ShortSpliceReader {
+            data: &payload,
+            spliced: false,
+        };
+        let n = fs
+            .write(&ctx, ino, fh.unwrap(), &mut r, 8192, 0, None, false, 0, 0)
+            .unwrap();
+        assert_eq!(n, 4096);
+        assert_eq!(std::fs::read(&path).unwrap(), &payload[..4096]);
+
+        // Nothing spliced, the copy error is returned.
+        let err = fs
+            .write(&ctx, ino, fh.unwrap(), &mut r, 8192, 0, None, false, 0, 0)
+            .unwrap_err();
+        assert_eq!(crate::api::errno::errno_of(&err), Some(libc::EIO));
+    }
+
+    #[test]
+    fn test_passthroughfs_read_write() {
+        let (source, fs) = prepare_passthroughfs_with(|_| {});
+        let path = source.as_path().join("a");
+        std::fs::write(&path, b"hello").unwrap();
+        let ctx = Context::default();
+        let ino = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap()
+            .inode;
+        let (fh, _) = fs.open(&ctx, ino, libc::O_RDWR as u32, 0).unwrap();
+        let fh = fh.unwrap();
+
+        let mut r = SliceReader::new(b", world!");
+        let n = fs
+            .write(&ctx, ino, fh, &mut r, 8, 5, None, false, 0, 0)
+            .unwrap();
+        assert_eq!(n, 8);
+        assert_eq!(r.available_bytes(), 0);
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello, world!");
+
+        // Data of the request shorter than its size is written as is.
+        let mut r = SliceReader::new(b"H");
+        let n = fs
+            .write(&ctx, ino, fh, &mut r, 4, 0, None, false, 0, 0)
+            .unwrap();
+        assert_eq!(n, 1);
+
+        // Reads are short at the end of file, and fail without space for the reply.
+        let mut buf = Vec::new();
+        let n = fs
+            .read(
+                &ctx,
+                ino,
+                fh,
+                &mut VecWriter::new(&mut buf),
+                100,
+                7,
+                None,
+                0,
+            )
+            .unwrap();
+        assert_eq!((n, buf.as_slice()), (6, &b"world!"[..]));
+        let mut buf = Vec::new();
+        let mut w = VecWriter::new(&mut buf).with_limit(4);
+        fs.read(&ctx, ino, fh, &mut w, 5, 0, None, 0).unwrap_err();
+        assert_eq!(fs.read(&ctx, ino, fh, &mut w, 4, 0, None, 0).unwrap(), 4);
+        assert_eq!(buf, b"Hell");
+    }
+
+    #[test]
+    fn test_passthroughfs_append() {
+        use std::io::Write;
+
+        const COUNT: usize = 200;
+        let (source, fs) = prepare_passthroughfs_with(|_| {});
+        fs.init(FsOptions::empty()).unwrap();
+        let path = source.as_path().join("a");
+        std::fs::write(&path, b"").unwrap();
+        let ctx = Context::default();
+        let ino = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap()
+            .inode;
+        let flags = (libc::O_WRONLY | libc::O_APPEND) as u32;
+        let (fh, _) = fs.open(&ctx, ino, flags, 0).unwrap();
+        let fh = fh.unwrap();
+
+        // Without writeback caching, appends of the guest and of the host don't overwrite each
+        // other, whatever the offset the guest kernel computed.
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let mut f = std::fs::OpenOptions::new()
+                    .append(true)
+                    .open(&path)
+                    .unwrap();
+                for _ in 0..COUNT {
+                    f.write_all(b"host\n").unwrap();
+                }
+            });
+            for _ in 0..COUNT {
+                let mut r = SliceReader::new(b"guest\n");
+                let n = fs
+                    .write(&ctx, ino, fh, &mut r, 6, 0, None, false, flags, 0)
+                    .unwrap();
+                assert_eq!(n, 6);
+            }
+        });
+        let data = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(data.lines().filter(|l| *l == "host").count(), COUNT);
+        assert_eq!(data.lines().filter(|l| *l == "guest").count(), COUNT);
+        assert_eq!(data.len(), COUNT * 11);
+    }
+
+    #[test]
+    fn test_passthroughfs_writeback_append() {
+        use crate::abi::fuse_abi::CreateIn;
+
+        const COUNT: u64 = 100;
+        let (source, fs) = prepare_passthroughfs_with(|cfg| cfg.writeback = true);
+        fs.init(FsOptions::WRITEBACK_CACHE).unwrap();
+        let ctx = Context::default();
+        let flags = (libc::O_WRONLY | libc::O_APPEND) as u32;
+        let args = CreateIn {
+            flags,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        let (entry, fh, _) = fs
+            .create(&ctx, ROOT_ID, &CString::new("a").unwrap(), args)
+            .unwrap();
+        let (ino, fh) = (entry.inode, fh.unwrap());
+
+        // The guest kernel computes the offsets of appends, which the host must not ignore, even
+        // when writes of flushed pages race.
+        std::thread::scope(|s| {
+            for half in 0..2 {
+                let fs = &fs;
+                let ctx = &ctx;
+                s.spawn(move || {
+                    for i in (half..COUNT).step_by(2) {
+                        let line = format!("{:07}\n", i);
+                        let mut r = SliceReader::new(line.as_bytes());
+                        let n = fs
+                            .write(ctx, ino, fh, &mut r, 8, i * 8, None, true, flags, 0)
+                            .unwrap();
+                        assert_eq!(n, 8);
+                    }
+                });
+            }
+        });
+        let expected: String = (0..COUNT).map(|i| format!("{:07}\n", i)).collect();
+        let path = source.as_path().join("a");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), expected);
+
+        // Files opened write-only are readable, to fill the page cache of the guest.
+        let mut buf = Vec::new();
+        let n = fs
+            .read(&ctx, ino, fh, &mut VecWriter::new(&mut buf), 8, 8, None, 0)
+            .unwrap();
+        assert_eq!((n, buf.as_slice()), (8, &b"0000001\n"[..]));
+        let (fh2, _) = fs.open(&ctx, ino, flags, 0).unwrap();
+        let mut buf = Vec::new();
+        fs.read(
+            &ctx,
+            ino,
+            fh2.unwrap(),
+            &mut VecWriter::new(&mut buf),
+            8,
+            0,
+            None,
+            0,
+        )
+        .unwrap();
+        assert_eq!(buf, b"0000000\n");
+
+        // Writes of processes asking for synchronous IO are synced.
+        for sync in [libc::O_DSYNC, libc::O_SYNC] {
+            let mut r = SliceReader::new(b"sync");
+            let n = fs
+                .write(
+                    &ctx,
+                    ino,
+                    fh,
+                    &mut r,
+                    4,
+                    0,
+                    None,
+                    false,
+                    flags | sync as u32,
+                    0,
+                )
+                .unwrap();
+            assert_eq!(n, 4);
+        }
+        assert_eq!(&std::fs::read(&path).unwrap()[..8], b"sync000\n");
+    }
+
+    #[test]
+    fn test_passthroughfs_tmpfile() {
+        let (source, fs) = prepare_passthroughfs_with(|_| {});
+        let ctx = Context::default();
+        let args = fuse::CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o640,
+            umask: 0o022,
+            fuse_flags: 0,
+        };
+        let (entry, fh, _) = match fs.tmpfile(&ctx, ROOT_ID, args) {
+            // The backing file system doesn't support O_TMPFILE.
+            Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => return,
+            res => res.unwrap(),
+        };
+        let fh = fh.unwrap();
+        assert_eq!(entry.attr.st_nlink, 0);
+        assert_eq!(entry.attr.st_mode, libc::S_IFREG | 0o640);
+        assert_eq!(std::fs::read_dir(source.as_path()).unwrap().count(), 0);
+
+        let mut r = SliceReader::new(b"data");
+        fs.write(&ctx, entry.inode, fh, &mut r, 4, 0, None, false, 0, 0)
+            .unwrap();
+        let (st, _) = fs.getattr(&ctx, entry.inode, Some(fh)).unwrap();
+        assert_eq!(st.st_size, 4);
+
+        // The unnamed file is given a name by a link, as the same inode.
+        let name = CString::new("t").unwrap();
+        let linked = fs.link(&ctx, entry.inode, ROOT_ID, &name).unwrap();
+        assert_eq!(linked.inode, entry.inode);
+        assert_eq!(linked.attr.st_nlink, 1);
+        assert_eq!(std::fs::read(source.as_path().join("t")).unwrap(), b"data");
+        fs.release(&ctx, entry.inode, 0, fh, false, false, None)
+            .unwrap();
+        fs.forget(&ctx, entry.inode, 2);
+        assert!(fs.inode_map.get(entry.inode).is_err());
+
+        // Files opened with O_EXCL can't be linked.
+        let args = fuse::CreateIn {
+            flags: (libc::O_WRONLY | libc::O_EXCL) as u32,
+            ..args
+        };
+        let (entry, _, _) = fs.tmpfile(&ctx, ROOT_ID, args).unwrap();
+        let name = CString::new("u").unwrap();
+        assert!(fs.link(&ctx, entry.inode, ROOT_ID, &name).is_err());
+    }
+
+    #[test]
+    fn test_passthroughfs_atomic_open() {
+        let (source, fs) = prepare_passthroughfs_with(|_| {});
+        let ctx = Context::default();
+        let name = CString::new("f").unwrap();
+        let args = fuse::CreateIn {
+            flags: (libc::O_RDWR | libc::O_CREAT) as u32,
+            mode: 0o640,
+            umask: 0o022,
+            fuse_flags: 0,
+        };
+
+        // Files are created, looked up and opened at once.
+        let (entry, fh, _) = fs.atomic_open(&ctx, ROOT_ID, &name, args).unwrap();
+        assert_eq!(entry.attr.st_mode, libc::S_IFREG | 0o640);
+        let mut r = SliceReader::new(b"data");
+        fs.write(
+            &ctx,
+            entry.inode,
+            fh.unwrap(),
+            &mut r,
+            4,
+            0,
+            None,
+            false,
+            0,
+            0,
+        )
+        .unwrap();
+        assert_eq!(std::fs::read(source.as_path().join("f")).unwrap(), b"data");
+
+        // Existing files are opened as the same inode, unless created exclusively.
+        let open = fuse::CreateIn {
+            flags: libc::O_RDONLY as u32,
+            ..args
+        };
+        let (same, fh, _) = fs.atomic_open(&ctx, ROOT_ID, &name, open).unwrap();
+        assert_eq!(same.inode, entry.inode);
+        let mut buf = Vec::new();
+        let mut w = VecWriter::new(&mut buf);
+        fs.read(&ctx, same.inode, fh.unwrap(), &mut w, 4, 0, None, 0)
+            .unwrap();
+        assert_eq!(buf, b"data");
+        let excl = fuse::CreateIn {
+            flags: (libc::O_RDWR | libc::O_CREAT | libc::O_EXCL) as u32,
+            ..args
+        };
+        let err = fs.atomic_open(&ctx, ROOT_ID, &name, excl).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+        let missing = CString::new("missing").unwrap();
+        let err = fs.atomic_open(&ctx, ROOT_ID, &missing, open).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+
+        // Symlinks are looked up without being opened.
+        std::os::unix::fs::symlink("f", source.as_path().join("l")).unwrap();
+        let link = CString::new("l").unwrap();
+        let (entry, fh, _) = fs.atomic_open(&ctx, ROOT_ID, &link, open).unwrap();
+        assert_eq!(entry.attr.st_mode & libc::S_IFMT, libc::S_IFLNK);
+        assert!(fh.is_none());
+    }
+
+    #[cfg(feature = "async-io")]
+    #[test]
+    fn test_passthroughfs_async_fsync_executor() {
+        use crate::api::executor::TokioExecutor;
+
+        let (source, fs) = prepare_passthroughfs_with(|_| {});
+        std::fs::write(source.as_path().join("a"), b"data").unwrap();
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .build()
+            .unwrap();
+        let fs = fs.with_executor(Arc::new(TokioExecutor::new(rt.handle().clone())));
+        let ctx = Context::default();
+        let ino = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap()
+            .inode;
+        let (fh, _) = fs.open(&ctx, ino, libc::O_RDWR as u32, 0).unwrap();
+        let fh = fh.unwrap();
+
+        rt.block_on(async {
+            fs.async_fsync(&ctx, ino, false, fh).await.unwrap();
+            fs.async_fsyncdir(&ctx, ino, true, fh).await.unwrap();
+            let err = fs.async_fsync(&ctx, ino, false, fh + 1).await.unwrap_err();
+            assert_eq!(crate::api::errno::errno_of(&err), Some(libc::EBADF));
+        });
+    }
+
+    #[test]
+    fn test_passthroughfs_short_read_truncated() {
+        let (source, fs) = prepare_passthroughfs_with(|_| {});
+        let path = source.as_path().join("a");
+        std::fs::write(&path, vec![1u8; 0x3000]).unwrap();
+        let notifier = Arc::new(RecordNotifier::default());
+        let fs = fs.with_notifier(notifier.clone());
+        fs.init(FsOptions::empty()).unwrap();
+        let ctx = Context::default();
+        let ino = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap()
+            .inode;
+        let (fh, _) = fs.open(&ctx, ino, libc::O_RDONLY as u32, 0).unwrap();
+        let fh = fh.unwrap();
+        let read = |size: u32, offset: u64| {
+            let mut buf = Vec::new();
+            fs.read(
+                &ctx,
+                ino,
+                fh,
+                &mut VecWriter::new(&mut buf),
+                size,
+                offset,
+                None,
+                0,
+            )
+            .unwrap();
+            buf
+        };
+
+        // Reads ending at the known end of file are expected to be short.
+        assert_eq!(read(0x1000, 0x2800).len(), 0x800);
+        assert!(notifier.invalidated.lock().unwrap().is_empty());
+
+        // Truncate on the host between reads.
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(0x1800)
+            .unwrap();
+        assert_eq!(read(0x1000, 0x1000).len(), 0x800);
+        assert_eq!(*notifier.invalidated.lock().unwrap(), vec![ino]);
+
+        // The new size is known now, later reads don't notify again.
+        assert_eq!(read(0x1000, 0x1000).len(), 0x800);
+        assert!(read(0x1000, 0x2000).is_empty());
+        assert_eq!(notifier.invalidated.lock().unwrap().len(), 1);
+        let (st, _) = fs.getattr(&ctx, ino, Some(fh)).unwrap();
+        assert_eq!(st.st_size, 0x1800);
+    }
+
+    #[test]
+    fn test_passthroughfs_symlink_target() {
+        let (source, fs) = prepare_passthroughfs_with(|_| {});
+        let ctx = Context::default();
+
+        // Long targets round trip without truncation.
+        let target = "t/".repeat(2000);
+        let linkname = CString::new(target.clone()).unwrap();
+        let entry = fs
+            .symlink(&ctx, &linkname, ROOT_ID, &CString::new("l").unwrap())
+            .unwrap();
+        assert_eq!(entry.attr.st_size, 4000);
+        assert_eq!(fs.readlink(&ctx, entry.inode).unwrap(), target.as_bytes());
+
+        // Retry with a larger buffer when the target fills it, as if the link had been replaced
+        // after getting its size.
+        let data = fs.inode_map.get(entry.inode).unwrap();
+        let file = data.get_file(&fs.mount_fds).unwrap();
+        let buf = PassthroughFs::<()>::readlink_fd(file.as_raw_fd(), 10, 4095).unwrap();
+        assert_eq!(buf, target.as_bytes());
+        let err = PassthroughFs::<()>::readlink_fd(file.as_raw_fd(), 10, 3999).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENAMETOOLONG));
+
+        let err = fs
+            .symlink(
+                &ctx,
+                &CString::new("t".repeat(4096)).unwrap(),
+                ROOT_ID,
+                &CString::new("m").unwrap(),
+            )
+            .err()
+            .unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::ENAMETOOLONG));
+        let err = fs
+            .symlink(
+                &ctx,
+                &CString::new("").unwrap(),
+                ROOT_ID,
+                &CString::new("m").unwrap(),
+            )
+            .err()
+            .unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+
+        // Host symlinks longer than the configured max are not truncated.
+        let fs = passthroughfs_in(source.as_path(), |cfg| cfg.max_symlink_target = 100);
+        let ino = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("l").unwrap())
+            .unwrap()
+            .inode;
+        let err = fs.readlink(&ctx, ino).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENAMETOOLONG));
+    }
+
+    // Report all entries as `DT_UNKNOWN`, and count type queries.
+    struct UnknownTypeDirSyscalls(Arc<std::sync::atomic::AtomicUsize>);
+
+    impl DirSyscalls for UnknownTypeDirSyscalls {
+        fn getdents(&self, dir: RawFd, offset: u64, buf: &mut Vec<u8>) -> io::Result<()> {
+            LibcDirSyscalls.getdents(dir, offset, buf)?;
+            let mut pos = 0;
+            while pos < buf.len() {
+                let end = pos + size_of::<LinuxDirent64>();
+                let mut dirent = LinuxDirent64::from_slice(&buf[pos..end]).copied().unwrap();
+                dirent.d_ty = libc::DT_UNKNOWN;
+                buf[pos..end].copy_from_slice(dirent.as_slice());
+                pos += dirent.d_reclen as usize;
+            }
+            Ok(())
+        }
+
+        fn entry_type(&self, dir: RawFd, name: &CStr) -> io::Result<u32> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            LibcDirSyscalls.entry_type(dir, name)
+        }
+    }
+
+    #[test]
+    fn test_passthroughfs_dtype_fallback() {
+        let (source, mut fs) = prepare_passthroughfs_with(|cfg| cfg.dtype_fallback_budget = 3);
+        for i in 0..4 {
+            std::fs::write(source.as_path().join(format!("f{}", i)), b"").unwrap();
+        }
+        std::fs::create_dir(source.as_path().join("d")).unwrap();
+        let queries = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        fs.dir_sys = Box::new(UnknownTypeDirSyscalls(queries.clone()));
+        let ctx = Context::default();
+        let expected = |name: &[u8]| {
+            if name == b"d" {
+                libc::DT_DIR as u32
+            } else {
+                libc::DT_REG as u32
+            }
+        };
+
+        // Types of entries within the budget are filled, others are left unknown.
+        let (handle, _) = fs.opendir(&ctx, ROOT_ID, 0).unwrap();
+        let mut types = Vec::new();
+        fs.readdir(&ctx, ROOT_ID, handle.unwrap(), 4096, 0, &mut |e| {
+            types.push((e.name.to_vec(), e.type_));
+            Ok(1)
+        })
+        .unwrap();
+        assert_eq!(types.len(), 5);
+        for (name, type_) in &types[..3] {
+            assert_eq!(*type_, expected(name));
+        }
+        for (_, type_) in &types[3..] {
+            assert_eq!(*type_, libc::DT_UNKNOWN as u32);
+        }
+        assert_eq!(queries.load(Ordering::SeqCst), 3);
+
+        // Readdirplus reuses attributes of entries.
+        let mut types = Vec::new();
+        fs.readdirplus(&ctx, ROOT_ID, handle.unwrap(), 4096, 0, &mut |e, _| {
+            types.push((e.name.to_vec(), e.type_));
+            Ok(1)
+        })
+        .unwrap();
+        assert_eq!(types.len(), 5);
+        for (name, type_) in &types {
+            assert_eq!(*type_, expected(name));
+        }
+        assert_eq!(queries.load(Ordering::SeqCst), 3);
+    }
+
+    // Fail reading entries with `ESTALE` a number of times, like NFS during failover.
+    struct StaleDirSyscalls(std::sync::atomic::AtomicUsize);
+
+    impl DirSyscalls for StaleDirSyscalls {
+        fn getdents(&self, dir: RawFd, offset: u64, buf: &mut Vec<u8>) -> io::Result<()> {
+            let stale = self.0.load(Ordering::SeqCst);
+            if stale > 0 {
+                self.0.store(stale - 1, Ordering::SeqCst);
+                return Err(io::Error::from_raw_os_error(libc::ESTALE));
+            }
+            LibcDirSyscalls.getdents(dir, offset, buf)
+        }
+
+        fn entry_type(&self, dir: RawFd, name: &CStr) -> io::Result<u32> {
+            LibcDirSyscalls.entry_type(dir, name)
+        }
+    }
+
+    #[test]
+    fn test_passthroughfs_retry_policy() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("a"), b"").unwrap();
+        let policy = RetryPolicy {
+            backoff: Duration::ZERO,
+            ..Default::default()
+        };
+        let ctx = Context::default();
+        let readdir = |fs: &PassthroughFs<()>| {
+            let (handle, _) = fs.opendir(&ctx, ROOT_ID, 0).unwrap();
+            let mut names = Vec::new();
+            fs.readdir(&ctx, ROOT_ID, handle.unwrap(), 4096, 0, &mut |e| {
+                names.push(e.name.to_vec());
+                Ok(1)
+            })
+            .map(|_| names)
+        };
+
+        // Two transient errors are hidden by retries.
+        let mut fs = passthroughfs_in(source.as_path(), |cfg| cfg.retry_policy = Some(policy));
+        fs.dir_sys = Box::new(StaleDirSyscalls(2.into()));
+        let names = readdir(&fs).unwrap();
+        assert!(names.contains(&b"a".to_vec()));
+        let stats = fs.retry_stats();
+        assert_eq!(stats.retries, 2);
+        assert_eq!(stats.recovered, 1);
+
+        // The error is surfaced once attempts are exhausted.
+        fs.dir_sys = Box::new(StaleDirSyscalls(3.into()));
+        let err = readdir(&fs).unwrap_err();
+        assert_eq!(crate::api::errno::errno_of(&err), Some(libc::ESTALE));
+        assert_eq!(fs.retry_stats().exhausted, 1);
+
+        // Without a policy, errors are passed through immediately.
+        let mut fs = passthroughfs_in(source.as_path(), |_| {});
+        fs.dir_sys = Box::new(StaleDirSyscalls(1.into()));
+        assert_eq!(readdir(&fs).unwrap_err().raw_os_error(), Some(libc::ESTALE));
+        assert_eq!(fs.retry_stats(), RetryStats::default());
+
+        let policy = RetryPolicy {
+            max_attempts: 0,
+            ..Default::default()
+        };
+        let fs_cfg = Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            retry_policy: Some(policy),
+            ..Default::default()
+        };
+        assert!(PassthroughFs::<()>::new(fs_cfg).is_err());
+    }
+
+    #[test]
+    fn test_passthroughfs_lseek() {
+        use std::os::unix::fs::FileExt;
+
+        let (source, fs) = prepare_passthroughfs_with(|_| {});
+        let file = std::fs::File::create(source.as_path().join("sparse")).unwrap();
+        file.write_all_at(&[1u8; 4096], 1 << 20).unwrap();
+        file.set_len(2 << 20).unwrap();
+
+        // Requests reach the backend through the Vfs.
+        let vfs = Vfs::new(VfsOptions {
+            no_open: false,
+            ..Default::default()
+        });
+        vfs.mount(Box::new(fs), "/").unwrap();
+        let ctx = Context::default();
+        let name = CString::new("sparse").unwrap();
+        let ino = vfs.lookup(&ctx, fuse::ROOT_ID.into(), &name).unwrap().inode;
+        let (handle, _) = vfs
+            .open(&ctx, ino.into(), libc::O_RDONLY as u32, 0)
+            .unwrap();
+        let handle = handle.unwrap();
+        let lseek =
+            |offset, whence: i32| vfs.lseek(&ctx, ino.into(), handle, offset, whence as u32);
+
+        assert_eq!(lseek(0, libc::SEEK_DATA).unwrap(), 1 << 20);
+        // Holes may not be reported by the backing file system, the end of file always is.
+        let hole = lseek(1 << 20, libc::SEEK_HOLE).unwrap();
+        assert!(((1 << 20) + 4096..=2 << 20).contains(&hole));
+        assert_eq!(
+            lseek(2 << 20, libc::SEEK_HOLE).unwrap_err().raw_os_error(),
+            Some(libc::ENXIO)
+        );
+        if hole < 2 << 20 {
+            let err = lseek(hole, libc::SEEK_DATA).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::ENXIO));
+        }
+        assert!(lseek(0, libc::SEEK_DATA + 100).is_err());
+    }
+
+    #[cfg(feature = "virtiofs")]
+    #[test]
+    fn test_passthroughfs_setupmapping() {
+        use crate::abi::virtio_fs::{RemovemappingOne, SetupmappingFlags};
+        use crate::transport::FsCacheReqHandler;
+        use std::os::unix::io::RawFd;
+
+        // Records the access mode of mapped fds and the unmapped ranges.
+        #[derive(Default)]
+        struct Window {
+            modes: Vec<i32>,
+            unmapped: Vec<(u64, u64)>,
+        }
+
+        impl FsCacheReqHandler for Window {
+            fn map(&mut self, _: u64, _: u64, _: u64, _: u64, fd: RawFd) -> io::Result<()> {
+                // Safe because we just query the flags of a valid fd.
+                let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+                self.modes.push(flags & libc::O_ACCMODE);
+                Ok(())
+            }
+
+            fn unmap(&mut self, requests: Vec<RemovemappingOne>) -> io::Result<()> {
+                self.unmapped
+                    .extend(requests.iter().map(|r| (r.moffset, r.len)));
+                Ok(())
+            }
+        }
+
+        let (source, fs) = prepare_passthroughfs_with(|_| {});
+        std::fs::write(source.as_path().join("file"), [0u8; 8192]).unwrap();
+        let ctx = Context::default();
+        let name = CString::new("file").unwrap();
+        let ino = fs.lookup(&ctx, fuse::ROOT_ID, &name).unwrap().inode;
+        let mut window = Window::default();
+
+        // Writable mappings get a writable fd, whatever the mode the file was opened with.
+        fs.setupmapping(&ctx, ino, 0, 0, 4096, 0, 0, &mut window)
+            .unwrap();
+        let write = SetupmappingFlags::WRITE.bits();
+        fs.setupmapping(&ctx, ino, 0, 4096, 4096, write, 0x1000, &mut window)
+            .unwrap();
+        assert_eq!(window.modes, vec![libc::O_RDONLY, libc::O_RDWR]);
+
+        // All ranges of a request are removed at once.
+        let requests = (0..3)
+            .map(|i| RemovemappingOne {
+                moffset: i * 0x1000,
+                len: 0x1000,
+            })
+            .collect();
+        fs.removemapping(&ctx, ino, requests, &mut window).unwrap();
+        assert_eq!(
+            window.unmapped,
+            vec![(0, 0x1000), (0x1000, 0x1000), (0x2000, 0x1000)]
+        );
+    }
+
+    #[test]
+    fn test_passthroughfs_no_readdirplus() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::create_dir(source.as_path().join("d")).unwrap();
+        std::fs::write(source.as_path().join("d/a"), b"a").unwrap();
+        std::fs::write(source.as_path().join("d/b"), b"b").unwrap();
+        let plus = FsOptions::DO_READDIRPLUS | FsOptions::READDIRPLUS_AUTO;
+
+        for (no_readdirplus, readdirplus_auto, negotiated) in [
+            (false, true, plus),
+            (false, false, FsOptions::DO_READDIRPLUS),
+            (true, true, FsOptions::empty()),
+        ] {
+            let fs = passthroughfs_in(source.as_path(), |cfg| {
+                cfg.no_readdirplus = no_readdirplus;
+                cfg.readdirplus_auto = readdirplus_auto;
+            });
+            let opts = fs.init(plus).unwrap();
+            assert_eq!(opts & plus, negotiated);
+
+            // Listing works with either request, "." and ".." aren't looked up.
+            let ctx = Context::default();
+            let d = fs
+                .lookup(&ctx, ROOT_ID, &CString::new("d").unwrap())
+                .unwrap();
+            let (handle, _) = fs.opendir(&ctx, d.inode, 0).unwrap();
+            let handle = handle.unwrap();
+            let mut names = Vec::new();
+            fs.readdir(&ctx, d.inode, handle, 4096, 0, &mut |e| {
+                names.push(e.name.to_vec());
+                Ok(1)
+            })
+            .unwrap();
+            let mut plus_names = Vec::new();
+            fs.readdirplus(&ctx, d.inode, handle, 4096, 0, &mut |e, _| {
+                plus_names.push(e.name.to_vec());
+                Ok(1)
+            })
+            .unwrap();
+            names.sort();
+            plus_names.sort();
+            assert_eq!(names, vec![b"a".to_vec(), b"b".to_vec()]);
+            assert_eq!(plus_names, names);
+            assert_eq!(fs.debug_nlookup(d.inode), Some(1));
+            assert_eq!(fs.inode_map.len(), 4);
+        }
+    }
+
+    #[test]
+    fn test_passthroughfs_readdir_modes() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        for i in 0..300 {
+            let name = format!("{}{}", "f".repeat(i % 40 + 1), i);
+            std::fs::write(source.as_path().join(name), b"").unwrap();
+        }
+        std::fs::create_dir(source.as_path().join("d")).unwrap();
+        let fs = passthroughfs_in(source.as_path(), |_| {});
+        let ctx = Context::default();
+        let (handle, _) = fs.opendir(&ctx, ROOT_ID, 0).unwrap();
+        let handle = handle.unwrap();
+
+        // List the whole directory by requests of 4096 bytes, return entries and request count.
+        let list = |plus: bool| {
+            let mut entries = Vec::new();
+            let mut requests = 0;
+            let mut offset = 0;
+            loop {
+                let mut batch = Vec::new();
+                let mut add = |e: DirEntry| {
+                    batch.push((e.name.to_vec(), e.ino, e.type_, e.offset));
+                    Ok(1)
+                };
+                if plus {
+                    fs.readdirplus(&ctx, ROOT_ID, handle, 4096, offset, &mut |e, _| add(e))
+                } else {
+                    fs.readdir(&ctx, ROOT_ID, handle, 4096, offset, &mut add)
+                }
+                .unwrap();
+                requests += 1;
+                match batch.last() {
+                    Some(last) => offset = last.3,
+                    None => break,
+                }
+                entries.append(&mut batch);
+            }
+            entries.sort();
+            (entries, requests)
+        };
+
+        let (entries, requests) = list(false);
+        let (plus_entries, plus_requests) = list(true);
+        assert_eq!(entries.len(), 301);
+        assert_eq!(plus_entries, entries);
+        let d = entries.iter().find(|e| e.0 == b"d").unwrap();
+        assert_eq!(d.2, libc::DT_DIR as u32);
+        // Readdirplus reads fewer host entries per request, sized for its bigger reply entries.
+        assert!(plus_requests > requests);
+        assert_eq!(
+            fs.readdir_stats(),
+            ReaddirStats {
+                readdir: requests,
+                readdirplus: plus_requests,
+            }
+        );
+    }
+
+    // Count the fds of this process referring to files under `dir`.
+    fn count_fds_under(dir: &std::path::Path) -> usize {
+        std::fs::read_dir("/proc/self/fd")
+            .unwrap()
+            .filter_map(|e| std::fs::read_link(e.ok()?.path()).ok())
+            .filter(|target| target.starts_with(dir))
+            .count()
+    }
+
+    #[test]
+    fn test_passthroughfs_forget_all() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let dir = std::fs::canonicalize(source.as_path()).unwrap();
+        std::fs::create_dir(dir.join("d")).unwrap();
+        std::fs::write(dir.join("d/a"), b"a").unwrap();
+        let fs = passthroughfs_in(&dir, |_| {});
+        fs.init(FsOptions::empty()).unwrap();
+        let baseline = count_fds_under(&dir);
+
+        let ctx = Context::default();
+        let open_files = |fs: &PassthroughFs| {
+            let d = fs
+                .lookup(&ctx, ROOT_ID, &CString::new("d").unwrap())
+                .unwrap();
+            let a = fs
+                .lookup(&ctx, d.inode, &CString::new("a").unwrap())
+                .unwrap();
+            fs.opendir(&ctx, d.inode, 0).unwrap();
+            fs.open(&ctx, a.inode, libc::O_RDONLY as u32, 0).unwrap();
+            assert!(count_fds_under(&dir) > baseline);
+            assert_eq!(fs.inode_map.len(), 3);
+        };
+
+        // Inodes and handles are dropped without forgets, the root is still usable.
+        open_files(&fs);
+        fs.forget_all();
+        assert_eq!(count_fds_under(&dir), baseline);
+        assert_eq!(fs.inode_map.len(), 1);
+        fs.getattr(&ctx, ROOT_ID, None).unwrap();
+
+        open_files(&fs);
+        fs.forget_all();
+        fs.destroy();
+        assert_eq!(count_fds_under(&dir), baseline);
+    }
+
+    #[test]
+    fn test_passthroughfs_snapshot_readdir() {
+        use std::collections::HashSet;
+
+        let (source, fs) = prepare_passthroughfs_with(|cfg| {
+            cfg.snapshot_readdir = true;
+            cfg.snapshot_readdir_max_entries = 14;
+        });
+        let path = |name: &str| source.as_path().join(name);
+        for i in 0..10 {
+            std::fs::write(path(&format!("f{}", i)), b"").unwrap();
+        }
+        std::fs::create_dir(path("big")).unwrap();
+        for i in 0..5 {
+            std::fs::write(path(&format!("big/f{}", i)), b"").unwrap();
+        }
+        let ctx = Context::default();
+
+        // Read at most 3 entries from `offset`, return their names and the next offset.
+        let read = |ino: Inode, handle: Handle, offset: u64| {
+            let mut names = Vec::new();
+            let mut next = offset;
+            fs.readdir(&ctx, ino, handle, 4096, offset, &mut |e| {
+                if names.len() == 3 {
+                    return Ok(0);
+                }
+                names.push(String::from_utf8(e.name.to_vec()).unwrap());
+                next = e.offset;
+                Ok(1)
+            })
+            .unwrap();
+            (names, next)
+        };
+
+        let (handle, _) = fs.opendir(&ctx, ROOT_ID, 0).unwrap();
+        let handle = handle.unwrap();
+        let (mut seen, mut offset) = read(ROOT_ID, handle, 0);
+        assert_eq!(seen.len(), 3);
+        assert!(fs.readdir_snapshot_memory() > 0);
+
+        // Mutate the host directory between continuations.
+        for i in 10..20 {
+            std::fs::write(path(&format!("f{}", i)), b"").unwrap();
+        }
+        for i in 0..10 {
+            let name = format!("f{}", i);
+            if !seen.contains(&name) {
+                std::fs::remove_file(path(&name)).unwrap();
+            }
+        }
+        loop {
+            let (names, next) = read(ROOT_ID, handle, offset);
+            if names.is_empty() {
+                break;
+            }
+            seen.extend(names);
+            offset = next;
+        }
+
+        let expected: HashSet<String> = (0..10)
+            .map(|i| format!("f{}", i))
+            .chain(Some("big".to_string()))
+            .collect();
+        assert_eq!(seen.len(), expected.len());
+        assert_eq!(seen.into_iter().collect::<HashSet<_>>(), expected);
+
+        // Rewinding still reads the snapshot, until the handle is released.
+        assert_eq!(read(ROOT_ID, handle, 0).0.len(), 3);
+        fs.releasedir(&ctx, ROOT_ID, 0, handle).unwrap();
+        assert_eq!(fs.readdir_snapshot_memory(), 0);
+        let (handle, _) = fs.opendir(&ctx, ROOT_ID, 0).unwrap();
+        let handle = handle.unwrap();
+        let mut count = 0;
+        fs.readdir(&ctx, ROOT_ID, handle, 4096, 0, &mut |_| {
+            count += 1;
+            Ok(1)
+        })
+        .unwrap();
+        fs.releasedir(&ctx, ROOT_ID, 0, handle).unwrap();
+        // 3 entries read before mutation, 10 new entries and "big".
+        assert_eq!(count, 14);
+        assert_eq!(fs.readdir_snapshot_fallbacks(), 0);
+
+        // Directories with too many entries are read from the host.
+        let big = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("big").unwrap())
+            .unwrap()
+            .inode;
+        for i in 5..15 {
+            std::fs::write(path(&format!("big/f{}", i)), b"").unwrap();
+        }
+        let (handle, _) = fs.opendir(&ctx, big, 0).unwrap();
+        let (names, _) = read(big, handle.unwrap(), 0);
+        assert_eq!(names.len(), 3);
+        assert_eq!(fs.readdir_snapshot_fallbacks(), 1);
+        assert_eq!(fs.readdir_snapshot_memory(), 0);
+    }
+
+    #[test]
+    fn test_passthroughfs_push_file() {
+        let (source, fs) = prepare_passthroughfs_with(|_| {});
+        let content: Vec<u8> = (0..0x30000u32).map(|v| v as u8).collect();
+        std::fs::write(source.as_path().join("a"), &content).unwrap();
+        let ctx = Context::default();
+        let a = CString::new("a").unwrap();
+        let ino = fs.lookup(&ctx, ROOT_ID, &a).unwrap().inode;
+
+        // Data is pushed in chunks and stops at the end of file.
+        let notifier = RecordNotifier {
+            limit: usize::MAX,
+            ..Default::default()
+        };
+        assert_eq!(
+            fs.push_file(&notifier, ino, 0x100, 0x40000).unwrap(),
+            0x2ff00
+        );
+        let stored = notifier.stored.lock().unwrap();
+        assert_eq!(stored.len(), 2);
+        assert_eq!((stored[0].0, stored[0].1), (ino, 0x100));
+        assert_eq!((stored[1].0, stored[1].1), (ino, 0x20100));
+        let data: Vec<u8> = stored.iter().flat_map(|s| s.2.clone()).collect();
+        assert_eq!(&data[..], &content[0x100..]);
+        drop(stored);
+
+        // Stop once the guest refuses to store more.
+        let notifier = RecordNotifier {
+            limit: 10,
+            ..Default::default()
+        };
+        assert_eq!(fs.push_file(&notifier, ino, 0, 0x30000).unwrap(), 10);
+        assert_eq!(notifier.stored.lock().unwrap().len(), 1);
+
+        // Push through vfs uses the fuse inode as nodeid.
+        let vfs = Vfs::new(VfsOptions::default());
+        vfs.mount(Box::new(fs), "/m").unwrap();
+        let m = vfs
+            .lookup(&ctx, fuse::ROOT_ID.into(), &CString::new("m").unwrap())
+            .unwrap()
+            .inode;
+        let ino = vfs.lookup(&ctx, m.into(), &a).unwrap().inode;
+        let notifier = RecordNotifier {
+            limit: usize::MAX,
+            ..Default::default()
+        };
+        assert_eq!(vfs.push_file(&notifier, ino, 0, 4).unwrap(), 4);
+        let stored = notifier.stored.lock().unwrap();
+        assert_eq!((stored[0].0, stored[0].1), (ino, 0));
+        assert_eq!(&stored[0].2[..], &content[..4]);
+        assert_eq!(
+            vfs.push_file(&notifier, fuse::ROOT_ID, 0, 4)
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EISDIR)
+        );
+    }
+
+    #[test]
+    fn test_passthroughfs_open_policy() {
+        let policy = OpenPolicy::new(|req| {
+            let ctl = req.name.map(|n| n.to_bytes().ends_with(b".ctl"));
+            if ctl == Some(true) {
+                // CACHE_DIR doesn't apply to files and gets masked.
+                OpenOptions::DIRECT_IO | OpenOptions::CACHE_DIR
+            } else if req.is_dir() {
+                OpenOptions::CACHE_DIR | OpenOptions::DIRECT_IO
+            } else {
+                OpenPolicy::default_options(req)
+            }
+        });
+        let (source, fs) = prepare_passthroughfs_with(|cfg| {
+            cfg.cache_policy = CachePolicy::Always;
+            cfg.open_policy = Some(policy);
+        });
+        std::fs::write(source.as_path().join("a.ctl"), b"ctl").unwrap();
+        std::fs::write(source.as_path().join("b"), b"data").unwrap();
+        let ctx = Context::default();
+
+        let ino = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("a.ctl").unwrap())
+            .unwrap()
+            .inode;
+        let (_, opts) = fs.open(&ctx, ino, libc::O_RDONLY as u32, 0).unwrap();
+        assert_eq!(opts, OpenOptions::DIRECT_IO);
+
+        let ino = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("b").unwrap())
+            .unwrap()
+            .inode;
+        let (_, opts) = fs.open(&ctx, ino, libc::O_RDONLY as u32, 0).unwrap();
+        assert_eq!(opts, OpenOptions::KEEP_CACHE);
+
+        let (_, opts) = fs.opendir(&ctx, ROOT_ID, libc::O_RDONLY as u32).unwrap();
+        assert_eq!(opts, OpenOptions::CACHE_DIR);
+
+        let args = fuse::CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            ..Default::default()
+        };
+        let name = CString::new("new.ctl").unwrap();
+        let (_, _, opts) = fs.create(&ctx, ROOT_ID, &name, args).unwrap();
+        assert_eq!(opts, OpenOptions::DIRECT_IO);
+    }
+
+    #[test]
+    fn test_passthroughfs_xattr_map() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let path = source.as_path().join("a");
+        std::fs::write(&path, b"a").unwrap();
+        let cpath = CString::new(path.to_str().unwrap()).unwrap();
+        let host_getxattr = |name: &str| {
+            let name = CString::new(name).unwrap();
+            let mut buf = [0u8; 16];
+            // Safe because all pointers are valid.
+            let res = unsafe {
+                libc::getxattr(
+                    cpath.as_ptr(),
+                    name.as_ptr(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            (res >= 0).then(|| buf[..res as usize].to_vec())
+        };
+        let name = CString::new("user.plain").unwrap();
+        // Safe because all pointers are valid.
+        if unsafe { libc::setxattr(cpath.as_ptr(), name.as_ptr(), b"p".as_ptr() as _, 1, 0) } != 0 {
+            // The backing file system doesn't support user xattrs.
+            return;
+        }
+
+        let fs = passthroughfs_in(source.as_path(), |cfg| {
+            cfg.xattr = true;
+            cfg.xattr_map = Some(":map:trusted.:user.virtiofsd.:".parse().unwrap());
+        });
+        let ctx = Context::default();
+        let ino = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap()
+            .inode;
+        let trusted = CString::new("trusted.x").unwrap();
+
+        fs.setxattr(&ctx, ino, &trusted, b"t", 0).unwrap();
+        assert_eq!(
+            host_getxattr("user.virtiofsd.trusted.x"),
+            Some(b"t".to_vec())
+        );
+        match fs.getxattr(&ctx, ino, &trusted, 16).unwrap() {
+            GetxattrReply::Value(v) => assert_eq!(v, b"t"),
+            GetxattrReply::Count(_) => panic!("unexpected count reply"),
+        }
+        let direct = CString::new("user.virtiofsd.trusted.x").unwrap();
+        let e = fs.getxattr(&ctx, ino, &direct, 16).err().unwrap();
+        assert_eq!(e.raw_os_error(), Some(libc::EPERM));
+
+        // Host names are mapped back, the size of the mapped list is reported.
+        let listed = match fs.listxattr(&ctx, ino, 0x1000).unwrap() {
+            ListxattrReply::Names(v) => v,
+            ListxattrReply::Count(_) => panic!("unexpected count reply"),
+        };
+        let names: Vec<&[u8]> = listed.split(|b| *b == 0).collect();
+        assert!(names.contains(&&b"trusted.x"[..]));
+        assert!(names.contains(&&b"user.plain"[..]));
+        assert!(!names.iter().any(|n| n.starts_with(b"user.virtiofsd.")));
+        match fs.listxattr(&ctx, ino, 0).unwrap() {
+            ListxattrReply::Count(c) => assert_eq!(c as usize, listed.len()),
+            ListxattrReply::Names(_) => panic!("unexpected names reply"),
+        }
+        let e = fs
+            .listxattr(&ctx, ino, listed.len() as u32 - 1)
+            .err()
+            .unwrap();
+        assert_eq!(e.raw_os_error(), Some(libc::ERANGE));
+
+        fs.removexattr(&ctx, ino, &trusted).unwrap();
+        assert_eq!(host_getxattr("user.virtiofsd.trusted.x"), None);
+    }
+
+    #[test]
+    fn test_passthroughfs_xattr_limits() {
+        use crate::api::errno::errno_of;
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let path = source.as_path().join("a");
+        std::fs::write(&path, b"a").unwrap();
+        let cpath = CString::new(path.to_str().unwrap()).unwrap();
+        let host_set = |name: &str, len: usize| {
+            let name = CString::new(name).unwrap();
+            let value = vec![b'v'; len];
+            // Safe because all pointers are valid.
+            unsafe { libc::setxattr(cpath.as_ptr(), name.as_ptr(), value.as_ptr() as _, len, 0) }
+        };
+        if host_set("user.abc", 16) != 0 || host_set("user.big", 17) != 0 {
+            // The backing file system doesn't support user xattrs.
+            return;
+        }
+        // Safe because this doesn't modify any memory.
+        let list_len = unsafe { libc::listxattr(cpath.as_ptr(), std::ptr::null_mut(), 0) } as u32;
+
+        let prepare = |list_max| {
+            let fs = passthroughfs_in(source.as_path(), |cfg| {
+                cfg.xattr = true;
+                cfg.xattr_limits = XattrLimits {
+                    name_max: 8,
+                    size_max: 16,
+                    list_max,
+                };
+            });
+            let ctx = Context::default();
+            let ino = fs
+                .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+                .unwrap();
+            (fs, ino.inode)
+        };
+        let ctx = Context::default();
+        let cs = |s: &str| CString::new(s).unwrap();
+        let errno = |e: io::Error| errno_of(&e).unwrap();
+
+        let (fs, ino) = prepare(list_len as usize);
+        fs.setxattr(&ctx, ino, &cs("user.xyz"), &[0; 16], 0)
+            .unwrap();
+        let e = fs.setxattr(&ctx, ino, &cs("user.xyzw"), &[0; 16], 0);
+        assert_eq!(e.map_err(errno).err(), Some(libc::ERANGE));
+        let e = fs.setxattr(&ctx, ino, &cs("user.xyz"), &[0; 17], 0);
+        assert_eq!(e.map_err(errno).err(), Some(libc::E2BIG));
+        let e = fs.removexattr(&ctx, ino, &cs("user.xyzw"));
+        assert_eq!(e.map_err(errno).err(), Some(libc::ERANGE));
+        fs.removexattr(&ctx, ino, &cs("user.xyz")).unwrap();
+
+        let get = |name: &str, size| match fs.getxattr(&ctx, ino, &cs(name), size) {
+            Ok(GetxattrReply::Value(v)) => Ok(v.len() as u32),
+            Ok(GetxattrReply::Count(c)) => Ok(c),
+            Err(e) => Err(errno(e)),
+        };
+        assert_eq!(get("user.abc", 16), Ok(16));
+        assert_eq!(get("user.abc", 15), Err(libc::ERANGE));
+        assert_eq!(get("user.abc", 0), Ok(16));
+        assert_eq!(get("user.abcd", 16), Err(libc::ERANGE));
+        assert_eq!(get("user.big", 17), Err(libc::E2BIG));
+        assert_eq!(get("user.big", 0), Err(libc::E2BIG));
+
+        let list = |fs: &PassthroughFs, size| match fs.listxattr(&ctx, ino, size) {
+            Ok(ListxattrReply::Names(v)) => Ok(v.len() as u32),
+            Ok(ListxattrReply::Count(c)) => Ok(c),
+            Err(e) => Err(errno(e)),
+        };
+        assert_eq!(list(&fs, list_len), Ok(list_len));
+        assert_eq!(list(&fs, list_len + 1), Ok(list_len));
+        assert_eq!(list(&fs, 0), Ok(list_len));
+        let (fs, _) = prepare(list_len as usize - 1);
+        assert_eq!(list(&fs, list_len), Err(libc::E2BIG));
+        assert_eq!(list(&fs, list_len - 1), Err(libc::ERANGE));
+        assert_eq!(list(&fs, 0), Err(libc::E2BIG));
+    }
+
+    #[test]
+    fn test_passthroughfs_setxattr_flags() {
+        use crate::api::errno::{errno_of, ENOATTR};
+
+        let (source, fs) = prepare_passthroughfs_with(|cfg| {
+            cfg.xattr = true;
+            cfg.xattr_map = Some(":map:trusted.:user.virtiofsd.:".parse().unwrap());
+        });
+        let path = source.as_path().join("a");
+        std::fs::write(&path, b"a").unwrap();
+        let cpath = CString::new(path.to_str().unwrap()).unwrap();
+        let name = CString::new("user.probe").unwrap();
+        // Safe because all pointers are valid.
+        if unsafe { libc::setxattr(cpath.as_ptr(), name.as_ptr(), b"p".as_ptr() as _, 1, 0) } != 0 {
+            // The backing file system doesn't support user xattrs.
+            return;
+        }
+
+        // Requests reach the backend through the Vfs, names are remapped or not.
+        let vfs = Vfs::default();
+        vfs.mount(Box::new(fs), "/").unwrap();
+        let ctx = Context::default();
+        let ino = vfs
+            .lookup(&ctx, ROOT_ID.into(), &CString::new("a").unwrap())
+            .unwrap()
+            .inode;
+        let errno = |res: io::Result<()>| res.err().map(|e| errno_of(&e).unwrap());
+
+        for name in ["user.x", "trusted.x"] {
+            let name = CString::new(name).unwrap();
+            let set = |v: &[u8], flags| errno(vfs.setxattr(&ctx, ino.into(), &name, v, flags));
+            let get = || match vfs.getxattr(&ctx, ino.into(), &name, 16) {
+                Ok(GetxattrReply::Value(v)) => Ok(v),
+                Ok(GetxattrReply::Count(_)) => panic!("unexpected count reply"),
+                Err(e) => Err(errno_of(&e).unwrap()),
+            };
+            let create = libc::XATTR_CREATE as u32;
+            let replace = libc::XATTR_REPLACE as u32;
+
+            assert_eq!(get(), Err(ENOATTR));
+            assert_eq!(set(b"1", replace), Some(ENOATTR));
+            assert_eq!(get(), Err(ENOATTR));
+            assert_eq!(set(b"1", create), None);
+            assert_eq!(set(b"2", create), Some(libc::EEXIST));
+            assert_eq!(get(), Ok(b"1".to_vec()));
+            assert_eq!(set(b"2", replace), None);
+            assert_eq!(get(), Ok(b"2".to_vec()));
+
+            vfs.removexattr(&ctx, ino.into(), &name).unwrap();
+            let e = vfs.removexattr(&ctx, ino.into(), &name).err().unwrap();
+            assert_eq!(errno_of(&e), Some(ENOATTR));
+        }
+    }
+
+    #[test]
+    fn test_passthroughfs_large_xattr_readlink() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let path = source.as_path().join("a");
+        std::fs::write(&path, b"a").unwrap();
+        std::os::unix::fs::symlink("a".repeat(3000), source.as_path().join("l")).unwrap();
+
+        // Larger than the initial scratch buffer.
+        let value = vec![0x5au8; 10000];
+        let cpath = CString::new(path.to_str().unwrap()).unwrap();
+        let mut names = Vec::new();
+        for i in 0..300 {
+            let name = CString::new(format!("user.scratch.{:04}", i)).unwrap();
+            // Safe because all pointers are valid.
+            let res = unsafe {
+                libc::setxattr(
+                    cpath.as_ptr(),
+                    name.as_ptr(),
+                    value.as_ptr() as *const libc::c_void,
+                    if i == 0 { value.len() } else { 1 },
+                    0,
+                )
+            };
+            if res != 0 {
+                // The backing file system doesn't support user xattrs.
+                return;
+            }
+            names.extend_from_slice(name.as_bytes_with_nul());
+        }
+
+        let fs = passthroughfs_in(source.as_path(), |cfg| cfg.xattr = true);
+        let ctx = Context::default();
+        let ino = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap()
+            .inode;
+        let name = CString::new("user.scratch.0000").unwrap();
+
+        match fs.getxattr(&ctx, ino, &name, 0).unwrap() {
+            GetxattrReply::Count(c) => assert_eq!(c as usize, value.len()),
+            GetxattrReply::Value(_) => panic!("unexpected value reply"),
+        }
+        match fs.getxattr(&ctx, ino, &name, 0x10000).unwrap() {
+            GetxattrReply::Value(v) => assert_eq!(v, value),
+            GetxattrReply::Count(_) => panic!("unexpected count reply"),
+        }
+        let e = fs.getxattr(&ctx, ino, &name, 5000).err().unwrap();
+        assert_eq!(e.raw_os_error(), Some(libc::ERANGE));
+
+        // The name list is larger than the initial scratch buffer too, and may contain xattrs
+        // from security modules.
+        match fs.listxattr(&ctx, ino, 0x10000).unwrap() {
+            ListxattrReply::Names(v) => {
+                let listed: Vec<&[u8]> = v.split(|b| *b == 0).collect();
+                for name in names.split(|b| *b == 0).filter(|n| !n.is_empty()) {
+                    assert!(listed.contains(&name));
+                }
+            }
+            ListxattrReply::Count(_) => panic!("unexpected count reply"),
+        }
+        let e = fs.listxattr(&ctx, ino, 100).err().unwrap();
+        assert_eq!(e.raw_os_error(), Some(libc::ERANGE));
+
+        let ino = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("l").unwrap())
+            .unwrap()
+            .inode;
+        assert_eq!(
+            fs.readlink(&ctx, ino).unwrap(),
+            "a".repeat(3000).into_bytes()
+        );
+    }
+
+    #[test]
+    fn test_passthroughfs_xattr_tmpfs() {
+        use crate::api::errno::errno_of;
+        use crate::api::server::XATTR_SIZE_MAX;
+
+        // Tmpfs supports values and name lists of up to the limits of the Linux VFS.
+        let source = match TempDir::new_in(Path::new("/dev/shm")) {
+            Ok(source) => source,
+            Err(_) => return,
+        };
+        let path = source.as_path().join("a");
+        std::fs::write(&path, b"a").unwrap();
+        let cpath = CString::new(path.to_str().unwrap()).unwrap();
+        let host_set = |name: &CStr, value: &[u8]| {
+            // Safe because all pointers are valid.
+            unsafe {
+                libc::setxattr(
+                    cpath.as_ptr(),
+                    name.as_ptr(),
+                    value.as_ptr() as *const libc::c_void,
+                    value.len(),
+                    0,
+                )
+            }
+        };
+        let value: Vec<u8> = (0..XATTR_SIZE_MAX).map(|i| i as u8).collect();
+        let big = CString::new("user.big").unwrap();
+        if host_set(&big, &value) != 0 {
+            // The backing file system doesn't support user xattrs.
+            return;
+        }
+        let mut names = Vec::new();
+        for i in 0..3000 {
+            let name = CString::new(format!("user.n.{:04}", i)).unwrap();
+            assert_eq!(host_set(&name, b""), 0);
+            names.push(name);
+        }
+
+        let fs = Arc::new(passthroughfs_in(source.as_path(), |cfg| cfg.xattr = true));
+        let ctx = Context::default();
+        let ino = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap()
+            .inode;
+        let get = |name: &CStr, size| match fs.getxattr(&ctx, ino, name, size) {
+            Ok(GetxattrReply::Value(v)) => Ok(v),
+            Ok(GetxattrReply::Count(c)) => Ok(vec![0; c as usize]),
+            Err(e) => Err(errno_of(&e).unwrap()),
+        };
+        assert_eq!(get(&big, 0).unwrap().len(), XATTR_SIZE_MAX);
+        assert_eq!(get(&big, 100 << 10), Ok(value.clone()));
+        assert_eq!(get(&big, XATTR_SIZE_MAX as u32 - 1), Err(libc::ERANGE));
+        // Values beyond the limit of the Linux VFS are too big for the host anyway.
+        let e = fs.setxattr(&ctx, ino, &big, &vec![0; 100 << 10], 0);
+        assert_eq!(errno_of(&e.unwrap_err()), Some(libc::E2BIG));
+
+        let list_len = match fs.listxattr(&ctx, ino, 0).unwrap() {
+            ListxattrReply::Count(c) => c,
+            ListxattrReply::Names(_) => panic!("unexpected names reply"),
+        };
+        match fs.listxattr(&ctx, ino, list_len).unwrap() {
+            ListxattrReply::Names(v) => {
+                assert_eq!(v.len() as u32, list_len);
+                let listed: Vec<&[u8]> = v.split(|b| *b == 0).collect();
+                for name in names.iter() {
+                    assert!(listed.contains(&name.as_bytes()));
+                }
+            }
+            ListxattrReply::Count(_) => panic!("unexpected count reply"),
+        }
+
+        // Values growing and shrinking on the host are probed again until they fit.
+        let grow = CString::new("user.grow").unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let (cpath, grow, stop) = (cpath.clone(), grow.clone(), stop.clone());
+            std::thread::spawn(move || {
+                let mut large = false;
+                while !stop.load(Ordering::Relaxed) {
+                    let len = if large { 60000 } else { 1000 };
+                    let v = vec![b'g'; len];
+                    // Safe because all pointers are valid.
+                    unsafe {
+                        libc::setxattr(cpath.as_ptr(), grow.as_ptr(), v.as_ptr() as _, len, 0)
+                    };
+                    large = !large;
+                }
+            })
+        };
+        for _ in 0..200 {
+            match get(&grow, XATTR_SIZE_MAX as u32) {
+                Ok(v) => assert!(v.len() == 1000 || v.len() == 60000),
+                Err(e) => assert_eq!(e, crate::api::errno::ENOATTR),
+            }
+        }
+        stop.store(true, Ordering::Relaxed);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn test_open_policy_default_options() {
+        let mut req = OpenRequest {
+            flags: libc::O_RDONLY as u32,
+            mode: libc::S_IFREG,
+            cache_policy: CachePolicy::Never,
+            name: None,
+        };
+        assert_eq!(OpenPolicy::default_options(&req), OpenOptions::DIRECT_IO);
+        req.cache_policy = CachePolicy::Auto;
+        assert_eq!(OpenPolicy::default_options(&req), OpenOptions::empty());
+        req.cache_policy = CachePolicy::Always;
+        assert_eq!(OpenPolicy::default_options(&req), OpenOptions::KEEP_CACHE);
+        req.cache_policy = CachePolicy::Never;
+        req.mode = libc::S_IFDIR;
+        assert_eq!(OpenPolicy::default_options(&req), OpenOptions::empty());
+        req.cache_policy = CachePolicy::Always;
+        assert_eq!(
+            OpenPolicy::default_options(&req),
+            OpenOptions::KEEP_CACHE | OpenOptions::CACHE_DIR
+        );
+    }
+
+    #[test]
+    fn test_passthroughfs_direct_io() {
+        use crate::api::errno::errno_of;
+
+        let (source, fs) = prepare_passthroughfs_with(|cfg| cfg.allow_direct_io = true);
+        std::fs::write(source.as_path().join("f"), vec![0u8; 8192]).unwrap();
+        let ctx = Context::default();
+        let ino = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("f").unwrap())
+            .unwrap()
+            .inode;
+
+        let flags = (libc::O_RDONLY | libc::O_DIRECT) as u32;
+        let (fh, opts) = match fs.open(&ctx, ino, flags, 0) {
+            // The host file system doesn't support O_DIRECT.
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return,
+            res => res.unwrap(),
+        };
+        assert!(opts.contains(OpenOptions::DIRECT_IO));
+        let fh = fh.unwrap();
+        let data = fs.handle_map.get(fh, ino).unwrap();
+        // Safe because we just query the flags of a valid fd.
+        let fl = unsafe { libc::fcntl(data.get_handle_raw_fd(), libc::F_GETFL) };
+        assert_ne!(fl & libc::O_DIRECT, 0);
+
+        // Alignment errors of the host are passed on.
+        let mut buf = Vec::new();
+        let mut w = VecWriter::new(&mut buf);
+        let err = fs
+            .read(&ctx, ino, fh, &mut w, 4096, 1, None, 0)
+            .unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::EINVAL));
+
+        let args = fuse::CreateIn {
+            flags: (libc::O_RDWR | libc::O_DIRECT) as u32,
+            mode: 0o644,
+            ..Default::default()
+        };
+        let name = CString::new("g").unwrap();
+        let (_, _, opts) = fs.create(&ctx, ROOT_ID, &name, args).unwrap();
+        assert!(opts.contains(OpenOptions::DIRECT_IO));
+    }
+
+    #[test]
+    fn test_passthroughfs_direct_io_dropped() {
+        let (source, fs) = prepare_passthroughfs_with(|_| {});
+        std::fs::write(source.as_path().join("f"), b"data").unwrap();
+        let ctx = Context::default();
+        let ino = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("f").unwrap())
+            .unwrap()
+            .inode;
+
+        let flags = (libc::O_RDONLY | libc::O_DIRECT) as u32;
+        let (fh, opts) = fs.open(&ctx, ino, flags, 0).unwrap();
+        assert!(!opts.contains(OpenOptions::DIRECT_IO));
+        let data = fs.handle_map.get(fh.unwrap(), ino).unwrap();
+        // Safe because we just query the flags of a valid fd.
+        let fl = unsafe { libc::fcntl(data.get_handle_raw_fd(), libc::F_GETFL) };
+        assert_eq!(fl & libc::O_DIRECT, 0);
+    }
+
+    #[test]
+    fn test_passthroughfs_statx() {
+        let (source, fs) = prepare_passthroughfs_with(|_| {});
+        let path = source.as_path().join("f");
+        std::fs::write(&path, b"abc").unwrap();
+
+        let ctx = Context::default();
+        let entry = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("f").unwrap())
+            .unwrap();
+        let (stx, _) = fs
+            .statx(
+                &ctx,
+                entry.inode,
+                None,
+                0,
+                fuse::STATX_BASIC_STATS | fuse::STATX_BTIME,
+            )
+            .unwrap();
+        assert_eq!(stx.size, 3);
+        assert_eq!(stx.ino, entry.attr.st_ino);
+
+        let host = CString::new(path.to_str().unwrap()).unwrap();
+        let mut host_stx = MaybeUninit::<libc::statx>::zeroed();
+        // Safe because the path is a valid C string and the buffer is large enough.
+        let res = unsafe {
+            libc::statx(
+                libc::AT_FDCWD,
+                host.as_ptr(),
+                0,
+                libc::STATX_BTIME,
+                host_stx.as_mut_ptr(),
+            )
+        };
+        assert_eq!(res, 0);
+        // Safe because statx() succeeded.
+        let host_stx = unsafe { host_stx.assume_init() };
+        if host_stx.stx_mask & libc::STATX_BTIME != 0 {
+            assert_ne!(stx.mask & fuse::STATX_BTIME, 0);
+            assert_eq!(stx.btime.tv_sec, host_stx.stx_btime.tv_sec);
+            assert_eq!(stx.btime.tv_nsec, host_stx.stx_btime.tv_nsec);
+        }
+
+        let (_source, fs) = prepare_passthroughfs_with(|cfg| cfg.no_statx = true);
+        let e = fs
+            .statx(&ctx, ROOT_ID, None, 0, fuse::STATX_BASIC_STATS)
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOSYS));
+    }
 }
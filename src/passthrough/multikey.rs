@@ -138,6 +138,17 @@ where
         }
     }
 
+    /// Gets an iterator over the main keys of the map, in sorted order.
+    pub fn keys(&self) -> impl Iterator<Item = &K1> {
+        self.main.keys()
+    }
+
+    /// Gets an iterator over the values of the map, sorted by main key.
+    #[cfg(feature = "persist")]
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.main.values().map(|(_, v)| v)
+    }
+
     /// Clears the map, removing all values.
     pub fn clear(&mut self) {
         self.alt.clear();
@@ -0,0 +1,255 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Hints of the parent directory and name of inodes.
+//!
+//! Backing files are tracked by file descriptors or handles, not by paths, but knowing where an
+//! inode was last seen is useful for logging and auditing. Each inode known by its name is hinted
+//! with the parent inode and name it was last looked up, created or renamed with, and its path is
+//! derived lazily by following the parents up to the root.
+//!
+//! Hints are updated by renames under the write lock held across the rename syscall, so
+//! concurrent renames are applied in the order the host performed them. Descendants of a renamed
+//! directory keep hinting at the directory inode, so their paths follow the directory without
+//! walking the subtree. Hints are best effort: changes made on the host aren't seen until the
+//! inode is looked up again, and inodes whose chain of parents is broken have no path.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::sync::{RwLock, RwLockWriteGuard};
+
+use super::Inode;
+use crate::abi::fuse_abi::ROOT_ID;
+use crate::api::{CURRENT_DIR_CSTR, PARENT_DIR_CSTR};
+
+/// Parent directory and name hints of inodes.
+#[derive(Default)]
+pub(super) struct PathHints {
+    inner: RwLock<HintMap>,
+}
+
+impl PathHints {
+    /// Hint `inode` to be named `name` in the directory `parent`.
+    pub(super) fn record(&self, inode: Inode, parent: Inode, name: &CStr) {
+        let bytes = name.to_bytes_with_nul();
+        if inode == ROOT_ID || bytes == CURRENT_DIR_CSTR || bytes == PARENT_DIR_CSTR {
+            return;
+        }
+        // Most lookups find the hint unchanged, so don't take the write lock nor copy the name.
+        if let Some((p, n)) = self.inner.read().unwrap().by_inode.get(&inode) {
+            if *p == parent && n.as_c_str() == name {
+                return;
+            }
+        }
+        self.lock().insert(inode, parent, name.to_owned());
+    }
+
+    /// Drop the hint of the entry `name` removed from `parent`.
+    pub(super) fn remove_entry(&self, parent: Inode, name: &CStr) {
+        let mut map = self.lock();
+        if let Some(inode) = map.by_name.get(&(parent, name.to_owned())).copied() {
+            map.remove(inode);
+        }
+    }
+
+    /// Drop the hint of the forgotten `inode`.
+    pub(super) fn forget(&self, inode: Inode) {
+        self.lock().remove(inode);
+    }
+
+    /// Drop all hints, when all inodes are dropped.
+    pub(super) fn clear(&self) {
+        *self.lock() = HintMap::default();
+    }
+
+    /// Lock the hints for update, to be held across a rename syscall.
+    pub(super) fn lock(&self) -> RwLockWriteGuard<'_, HintMap> {
+        self.inner.write().unwrap()
+    }
+
+    /// Get the parent directory and name hinted for `inode`.
+    pub(super) fn get(&self, inode: Inode) -> Option<(Inode, CString)> {
+        self.inner.read().unwrap().by_inode.get(&inode).cloned()
+    }
+
+    /// Derive the path of `inode` relative to the root directory.
+    ///
+    /// Return `None` if the inode or one of its ancestors has no hint.
+    pub(super) fn path(&self, inode: Inode) -> Option<PathBuf> {
+        let map = self.inner.read().unwrap();
+        let mut names = Vec::new();
+        let mut curr = inode;
+        while curr != ROOT_ID {
+            // A chain longer than the number of hints has a cycle, left by a racing lookup.
+            if names.len() >= map.by_inode.len() {
+                return None;
+            }
+            let (parent, name) = map.by_inode.get(&curr)?;
+            names.push(name.as_bytes());
+            curr = *parent;
+        }
+
+        Some(names.iter().rev().map(|n| OsStr::from_bytes(n)).collect())
+    }
+}
+
+/// Hints locked for update.
+#[derive(Default)]
+pub(super) struct HintMap {
+    by_inode: HashMap<Inode, (Inode, CString)>,
+    by_name: HashMap<(Inode, CString), Inode>,
+}
+
+impl HintMap {
+    /// Update hints after the entry `oldname` of `olddir` is renamed to `newname` of `newdir`.
+    ///
+    /// The inode previously hinted as `newname` is replaced, or moved to `oldname` if the entries
+    /// are exchanged. Return the inodes hinted as moved, the one of `oldname` and the one of
+    /// `newname` if exchanged.
+    pub(super) fn renamed(
+        &mut self,
+        olddir: Inode,
+        oldname: &CStr,
+        newdir: Inode,
+        newname: &CStr,
+        exchange: bool,
+    ) -> [Option<Inode>; 2] {
+        let old_key = (olddir, oldname.to_owned());
+        let new_key = (newdir, newname.to_owned());
+        let moved = self.by_name.get(&old_key).copied();
+        let target = self.by_name.get(&new_key).copied();
+
+        if let Some(target) = target {
+            self.remove(target);
+        }
+        if let Some(moved) = moved {
+            self.remove(moved);
+            self.insert(moved, new_key.0, new_key.1);
+        }
+        if let (Some(target), true) = (target, exchange) {
+            self.insert(target, old_key.0, old_key.1);
+        }
+
+        [moved, target.filter(|_| exchange)]
+    }
+
+    fn insert(&mut self, inode: Inode, parent: Inode, name: CString) {
+        self.remove(inode);
+        // Another inode hinted with the name was replaced on the host.
+        let key = (parent, name);
+        if let Some(other) = self.by_name.get(&key).copied() {
+            self.remove(other);
+        }
+        self.by_inode.insert(inode, key.clone());
+        self.by_name.insert(key, inode);
+    }
+
+    fn remove(&mut self, inode: Inode) {
+        if let Some(key) = self.by_inode.remove(&inode) {
+            self.by_name.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    fn path(hints: &PathHints, inode: Inode) -> Option<String> {
+        hints.path(inode).map(|p| p.to_str().unwrap().to_string())
+    }
+
+    // Build root/{d1/{a, d2/b}, e}.
+    fn tree() -> PathHints {
+        let hints = PathHints::default();
+        hints.record(2, ROOT_ID, &name("d1"));
+        hints.record(3, 2, &name("a"));
+        hints.record(4, 2, &name("d2"));
+        hints.record(5, 4, &name("b"));
+        hints.record(6, ROOT_ID, &name("e"));
+        hints
+    }
+
+    #[test]
+    fn test_path_hints() {
+        let hints = tree();
+        assert_eq!(path(&hints, 5).as_deref(), Some("d1/d2/b"));
+        assert_eq!(path(&hints, ROOT_ID).as_deref(), Some(""));
+        assert_eq!(hints.get(3), Some((2, name("a"))));
+        assert!(path(&hints, 7).is_none());
+
+        // Dot entries and the root aren't hinted.
+        hints.record(2, 4, &name(".."));
+        hints.record(ROOT_ID, 6, &name("x"));
+        assert_eq!(path(&hints, 5).as_deref(), Some("d1/d2/b"));
+
+        // A new name of a hard link replaces the old one.
+        hints.record(3, ROOT_ID, &name("link"));
+        assert_eq!(path(&hints, 3).as_deref(), Some("link"));
+        hints.remove_entry(2, &name("a"));
+        assert_eq!(path(&hints, 3).as_deref(), Some("link"));
+        hints.remove_entry(ROOT_ID, &name("link"));
+        assert!(hints.get(3).is_none());
+
+        // Descendants of forgotten directories have no path.
+        hints.forget(4);
+        assert!(path(&hints, 5).is_none());
+        assert_eq!(hints.get(5), Some((4, name("b"))));
+    }
+
+    #[test]
+    fn test_path_hints_rename() {
+        let hints = tree();
+
+        // Cross-directory rename.
+        let moved = hints.lock().renamed(2, &name("a"), 4, &name("c"), false);
+        assert_eq!(moved, [Some(3), None]);
+        assert_eq!(path(&hints, 3).as_deref(), Some("d1/d2/c"));
+
+        // Rename over an existing entry drops the hint of the replaced inode.
+        hints
+            .lock()
+            .renamed(4, &name("c"), ROOT_ID, &name("e"), false);
+        assert_eq!(path(&hints, 3).as_deref(), Some("e"));
+        assert!(hints.get(6).is_none());
+        hints.record(6, ROOT_ID, &name("f"));
+
+        // Exchanged entries swap their hints.
+        let moved = hints
+            .lock()
+            .renamed(ROOT_ID, &name("e"), ROOT_ID, &name("f"), true);
+        assert_eq!(moved, [Some(3), Some(6)]);
+        assert_eq!(path(&hints, 3).as_deref(), Some("f"));
+        assert_eq!(path(&hints, 6).as_deref(), Some("e"));
+
+        // Renaming an entry without hint forgets the replaced one.
+        let moved = hints
+            .lock()
+            .renamed(ROOT_ID, &name("x"), ROOT_ID, &name("e"), false);
+        assert_eq!(moved, [None, None]);
+        assert!(hints.get(6).is_none());
+    }
+
+    #[test]
+    fn test_path_hints_rename_dir() {
+        let hints = tree();
+
+        // Cached descendants follow a renamed directory without being updated.
+        hints
+            .lock()
+            .renamed(ROOT_ID, &name("d1"), 6, &name("d3"), false);
+        assert_eq!(path(&hints, 5).as_deref(), Some("e/d3/d2/b"));
+        assert_eq!(hints.get(5), Some((4, name("b"))));
+        assert_eq!(path(&hints, 3).as_deref(), Some("e/d3/a"));
+
+        // Cycles left by racing lookups don't loop forever.
+        hints.record(6, 5, &name("loop"));
+        assert!(path(&hints, 5).is_none());
+    }
+}
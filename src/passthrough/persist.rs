@@ -0,0 +1,544 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Save and restore the state of the passthrough file system, for live upgrade.
+//!
+//! The saved state has the inodes known by the guest with their lookup counts, and the open
+//! handles. Inodes are reopened by the new daemon, by file handle if they were saved with one, or
+//! by their path under `/proc/self/fd` otherwise, and must still be the same files on the same
+//! devices. Open handles can't be reopened by path without changing their semantics, so their
+//! file descriptors are passed to the new daemon instead, for example by `SCM_RIGHTS` over the
+//! same unix socket as the fuse session fd.
+
+use std::collections::HashSet;
+use std::os::unix::ffi::OsStrExt;
+
+use super::file_handle::{CFileHandle, MAX_HANDLE_SZ};
+use super::*;
+use crate::api::persist::{StateReader, StateWriter};
+
+// Magic number and format version of saved passthrough states.
+const PASSTHROUGH_STATE_MAGIC: u32 = 0x4655_5350;
+const PASSTHROUGH_STATE_FORMAT: u32 = 1;
+
+// How an inode is reopened.
+const REOPEN_BY_PATH: u32 = 0;
+const REOPEN_BY_HANDLE: u32 = 1;
+
+struct SavedInode {
+    inode: Inode,
+    ino: u64,
+    dev: u64,
+    mnt_id: u64,
+    refcount: u64,
+    size: u64,
+    path: Option<CString>,
+    handle: Option<FileHandle>,
+}
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    /// Encode the state of the file system to be restored by [PassthroughFs::restore] of a new
+    /// daemon.
+    ///
+    /// Return the state and the file descriptors of open handles, which must be passed to the
+    /// new daemon in order. The file descriptors are owned by the file system, and stay valid
+    /// until it's dropped. Requests must not be handled while the state is saved.
+    pub fn save(&self) -> io::Result<(Vec<u8>, Vec<RawFd>)> {
+        let mut w = StateWriter::new(PASSTHROUGH_STATE_MAGIC, PASSTHROUGH_STATE_FORMAT);
+        w.put_u32(self.inode_map.shards.len() as u32);
+        w.put_u64(self.inode_map.next_seq.load(Ordering::Relaxed));
+        w.put_u64(self.next_handle.load(Ordering::Relaxed));
+
+        // Mount fds to open file handles with.
+        let mount_fds = self.mount_fds.map.read().unwrap();
+        w.put_u32(mount_fds.len() as u32);
+        for (mnt_id, file) in mount_fds.iter() {
+            w.put_u64(*mnt_id);
+            w.put_bytes(self.fd_path(file.as_raw_fd())?.as_os_str().as_bytes());
+        }
+        drop(mount_fds);
+
+        let mut inodes = Vec::new();
+        for shard in self.inode_map.shards.iter() {
+            inodes.extend(shard.read().unwrap().values().map(Arc::clone));
+        }
+        inodes.sort_by_key(|data| data.inode);
+        w.put_u32(inodes.len() as u32);
+        for data in inodes {
+            let (ino, dev, mnt) = match data.altkey {
+                InodeAltKey::Ids { ino, dev, mnt } => (ino, dev, mnt),
+                InodeAltKey::Handle(_) => {
+                    return Err(fuse_errno(libc::EINVAL, "inode without ids alt key"))
+                }
+            };
+            w.put_u64(data.inode);
+            w.put_u64(ino);
+            w.put_u64(dev);
+            w.put_u64(mnt);
+            w.put_u64(data.refcount.load(Ordering::Relaxed));
+            w.put_u64(data.size.load(Ordering::Relaxed));
+            match &data.file_or_handle {
+                FileOrHandle::File(f) => {
+                    w.put_u32(REOPEN_BY_PATH);
+                    w.put_bytes(self.fd_path(f.as_raw_fd())?.as_os_str().as_bytes());
+                }
+                FileOrHandle::Handle(h) => {
+                    let len = h.handle.handle_bytes as usize;
+                    let bytes: Vec<u8> =
+                        h.handle.f_handle[..len].iter().map(|b| *b as u8).collect();
+                    w.put_u32(REOPEN_BY_HANDLE);
+                    w.put_u32(h.handle.handle_type as u32);
+                    w.put_bytes(&bytes);
+                }
+            }
+        }
+
+        let mut handles = Vec::new();
+        for shard in self.handle_map.shards.iter() {
+            handles.extend(
+                shard
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .map(|(h, d)| (*h, Arc::clone(d))),
+            );
+        }
+        w.put_u32(handles.len() as u32);
+        let mut fds = Vec::with_capacity(handles.len());
+        for (handle, data) in handles {
+            w.put_u64(handle);
+            w.put_u64(data.inode);
+            fds.push(data.get_handle_raw_fd());
+        }
+
+        Ok((w.finish(), fds))
+    }
+
+    /// Restore a state encoded by [PassthroughFs::save], instead of [PassthroughFs::import].
+    ///
+    /// `files` are the file descriptors of open handles returned by `save()`, in order. Fail with
+    /// `ESTALE` if the root directory, or any inode known by the guest, can't be reopened as the
+    /// same file on the same device, and with `EBUSY` if the file system has been imported. The
+    /// file system is left unchanged on failure.
+    pub fn restore(&self, state: &[u8], files: Vec<File>) -> io::Result<()> {
+        let mut r = StateReader::new(
+            state,
+            PASSTHROUGH_STATE_MAGIC,
+            PASSTHROUGH_STATE_FORMAT,
+            "passthrough",
+        )?;
+        let invalid = |msg: &str| fuse_errno(libc::EINVAL, format!("{} in passthrough state", msg));
+
+        let shards = r.u32()? as usize;
+        let next_seq = r.u64()?;
+        let next_handle = r.u64()?;
+        let mut mounts = Vec::new();
+        for _ in 0..r.u32()? {
+            mounts.push((r.u64()?, Self::path_cstring(r.bytes()?)?));
+        }
+        let mut inodes = Vec::new();
+        for _ in 0..r.u32()? {
+            let mut inode = SavedInode {
+                inode: r.u64()?,
+                ino: r.u64()?,
+                dev: r.u64()?,
+                mnt_id: r.u64()?,
+                refcount: r.u64()?,
+                size: r.u64()?,
+                path: None,
+                handle: None,
+            };
+            match r.u32()? {
+                REOPEN_BY_PATH => inode.path = Some(Self::path_cstring(r.bytes()?)?),
+                REOPEN_BY_HANDLE => {
+                    let handle_type = r.u32()? as libc::c_int;
+                    let bytes = r.bytes()?;
+                    if bytes.len() > MAX_HANDLE_SZ {
+                        return Err(invalid("invalid file handle"));
+                    }
+                    let mut handle = CFileHandle {
+                        handle_bytes: bytes.len() as libc::c_uint,
+                        handle_type,
+                        f_handle: [0; MAX_HANDLE_SZ],
+                    };
+                    for (d, s) in handle.f_handle.iter_mut().zip(bytes) {
+                        *d = *s as libc::c_char;
+                    }
+                    inode.handle = Some(FileHandle {
+                        mnt_id: inode.mnt_id,
+                        handle,
+                    });
+                }
+                _ => return Err(invalid("invalid inode kind")),
+            }
+            inodes.push(inode);
+        }
+        let mut handles = Vec::new();
+        for _ in 0..r.u32()? {
+            handles.push((r.u64()?, r.u64()?));
+        }
+        r.finish()?;
+
+        if shards != self.inode_map.shards.len() {
+            return Err(invalid("unsupported number of inode map shards"));
+        }
+        if handles.len() != files.len() {
+            return Err(fuse_errno(
+                libc::EINVAL,
+                format!("{} handles saved but {} passed", handles.len(), files.len()),
+            ));
+        }
+        if self
+            .inode_map
+            .shards
+            .iter()
+            .any(|s| s.read().unwrap().keys().next().is_some())
+        {
+            return Err(fuse_errno(
+                libc::EBUSY,
+                "passthrough fs is already imported",
+            ));
+        }
+
+        // Make sure the backing file system is still the same before reopening anything.
+        let root = inodes
+            .iter()
+            .find(|i| i.inode == fuse::ROOT_ID)
+            .ok_or_else(|| invalid("missing root inode"))?;
+        let (_, st, _, _) = self.open_root()?;
+        let st = st.get_stat();
+        if st.st_dev != root.dev || st.st_ino != root.ino {
+            return Err(fuse_errno(
+                libc::ESTALE,
+                format!("backing file system of {} changed", self.cfg.root_dir),
+            ));
+        }
+
+        let mount_fds = MountFds::new();
+        for (mnt_id, path) in mounts {
+            let file = self.reopen_mount_fd(mnt_id, &path).map_err(|e| {
+                fuse_errno(
+                    libc::ESTALE,
+                    format!("failed to reopen mount {} at {:?}: {}", mnt_id, path, e),
+                )
+            })?;
+            mount_fds.map.write().unwrap().insert(mnt_id, file);
+        }
+
+        let mut restored = Vec::with_capacity(inodes.len());
+        for saved in inodes {
+            let inode = saved.inode;
+            if inode != fuse::ROOT_ID
+                && self.inode_map.shard_of(inode)
+                    != self.inode_map.shard_of_alt(&InodeAltKey::Ids {
+                        ino: saved.ino,
+                        dev: saved.dev,
+                        mnt: saved.mnt_id,
+                    })
+            {
+                return Err(invalid("unsupported inode map layout"));
+            }
+            let data = self.reopen_inode(saved, &mount_fds).map_err(|e| {
+                fuse_errno(
+                    libc::ESTALE,
+                    format!("failed to reopen inode {}: {}", inode, e),
+                )
+            })?;
+            restored.push(data);
+        }
+        let known: HashSet<Inode> = restored.iter().map(|(d, _, _)| d.inode).collect();
+        if handles.iter().any(|(_, inode)| !known.contains(inode)) {
+            return Err(invalid("handle of unknown inode"));
+        }
+
+        // Safe because this doesn't modify any memory and always succeeds, see `import()`.
+        unsafe { libc::umask(0o000) };
+        *self.mount_fds.map.write().unwrap() = mount_fds.map.into_inner().unwrap();
+        for (data, ids_altkey, handle_altkey) in restored {
+            self.inode_map
+                .insert(data.inode, data, ids_altkey, handle_altkey);
+        }
+        self.inode_map.next_seq.store(next_seq, Ordering::Relaxed);
+        for ((handle, inode), file) in handles.into_iter().zip(files) {
+            self.handle_map.insert(handle, HandleData::new(inode, file));
+        }
+        self.next_handle.store(next_handle, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    // Get the path of `fd` as seen by `/proc/self/fd`.
+    fn fd_path(&self, fd: RawFd) -> io::Result<PathBuf> {
+        let pathname = CString::new(format!("{}", fd))
+            .map_err(|_| fuse_errno(libc::EINVAL, "invalid proc fd path"))?;
+        Self::readlinkat(self.proc_self_fd()?, &pathname)
+    }
+
+    fn path_cstring(bytes: &[u8]) -> io::Result<CString> {
+        CString::new(bytes)
+            .map_err(|_| fuse_errno(libc::EINVAL, "invalid path in passthrough state"))
+    }
+
+    // Reopen the mount fd of `mnt_id` to open file handles with, like `MountFds` does.
+    fn reopen_mount_fd(&self, mnt_id: u64, path: &CStr) -> io::Result<File> {
+        let f = Self::open_file(
+            libc::AT_FDCWD,
+            path,
+            libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            0,
+        )?;
+        let st = self.stat_helper.stat(&f)?;
+        if st.get_mnt_id() != mnt_id {
+            return Err(io::Error::from_raw_os_error(libc::ESTALE));
+        }
+        self.reopen_fd(
+            f.as_raw_fd(),
+            libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            st.get_stat().st_mode,
+        )
+    }
+
+    // Reopen a saved inode, and check that it's still the same file.
+    fn reopen_inode(
+        &self,
+        saved: SavedInode,
+        mount_fds: &MountFds,
+    ) -> io::Result<(InodeData, InodeAltKey, Option<InodeAltKey>)> {
+        let (file_or_handle, st) = match (saved.path, saved.handle) {
+            (_, Some(h)) => {
+                let f = h.open_with_mount_fds(mount_fds, libc::O_PATH)?;
+                let st = Self::stat_fd(f.as_raw_fd(), None)?;
+                (FileOrHandle::Handle(h), st)
+            }
+            (Some(path), None) => {
+                let f = Self::open_file(
+                    libc::AT_FDCWD,
+                    &path,
+                    libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                    0,
+                )?;
+                let st = self.stat_helper.stat(&f)?;
+                if st.get_mnt_id() != saved.mnt_id {
+                    return Err(io::Error::from_raw_os_error(libc::ESTALE));
+                }
+                (FileOrHandle::File(f), st.get_stat())
+            }
+            (None, None) => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+        if st.st_ino != saved.ino || st.st_dev != saved.dev {
+            return Err(io::Error::from_raw_os_error(libc::ESTALE));
+        }
+
+        let ids_altkey = InodeAltKey::Ids {
+            ino: saved.ino,
+            dev: saved.dev,
+            mnt: saved.mnt_id,
+        };
+        let handle_altkey = file_or_handle.handle().map(|h| InodeAltKey::Handle(*h));
+        let data = InodeData::new(saved.inode, file_or_handle, saved.refcount, ids_altkey, &st);
+        data.size.store(saved.size, Ordering::Relaxed);
+
+        Ok((data, ids_altkey, handle_altkey))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::prepare_passthroughfs_with;
+    use super::*;
+    use crate::api::errno::errno_of;
+    use crate::api::filesystem::{Context, FileSystem, VecWriter, ROOT_ID};
+    use std::os::unix::io::FromRawFd;
+    use vmm_sys_util::tempdir::TempDir;
+
+    fn dup_files(fds: &[RawFd]) -> Vec<File> {
+        fds.iter()
+            .map(|fd| unsafe { File::from_raw_fd(libc::dup(*fd)) })
+            .collect()
+    }
+
+    fn fresh_fs(dir: &std::path::Path) -> PassthroughFs {
+        PassthroughFs::<()>::new(Config {
+            root_dir: dir.to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_passthroughfs_save_restore() {
+        let (source, fs) = prepare_passthroughfs_with(|_| {});
+        std::fs::create_dir(source.as_path().join("d")).unwrap();
+        std::fs::write(source.as_path().join("d/f"), b"hello").unwrap();
+        let ctx = Context::default();
+        let d = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("d").unwrap())
+            .unwrap()
+            .inode;
+        let f = fs
+            .lookup(&ctx, d, &CString::new("f").unwrap())
+            .unwrap()
+            .inode;
+        fs.lookup(&ctx, d, &CString::new("f").unwrap()).unwrap();
+        let (fh, _) = fs.open(&ctx, f, libc::O_RDONLY as u32, 0).unwrap();
+        let fh = fh.unwrap();
+        let (state, fds) = fs.save().unwrap();
+        assert_eq!(fds.len(), 1);
+
+        let restored = fresh_fs(source.as_path());
+        restored.restore(&state, dup_files(&fds)).unwrap();
+        drop(fs);
+        assert_eq!(
+            restored
+                .inode_map
+                .get(f)
+                .unwrap()
+                .refcount
+                .load(Ordering::Relaxed),
+            2
+        );
+        assert_eq!(
+            restored
+                .lookup(&ctx, d, &CString::new("f").unwrap())
+                .unwrap()
+                .inode,
+            f
+        );
+        let mut buf = Vec::new();
+        let n = restored
+            .read(&ctx, f, fh, &mut VecWriter::new(&mut buf), 5, 0, None, 0)
+            .unwrap();
+        assert_eq!((n, buf.as_slice()), (5, &b"hello"[..]));
+        // New inodes and handles don't collide with restored ones.
+        std::fs::write(source.as_path().join("g"), b"").unwrap();
+        let g = restored
+            .lookup(&ctx, ROOT_ID, &CString::new("g").unwrap())
+            .unwrap()
+            .inode;
+        assert!(g != d && g != f);
+        let (gh, _) = restored.open(&ctx, g, libc::O_RDONLY as u32, 0).unwrap();
+        assert_ne!(gh.unwrap(), fh);
+
+        // The restored file system is in use.
+        let (state, fds) = restored.save().unwrap();
+        let err = restored.restore(&state, dup_files(&fds)).unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::EBUSY));
+        let err = fresh_fs(source.as_path())
+            .restore(&state, Vec::new())
+            .unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn test_passthroughfs_restore_stale() {
+        let (source, fs) = prepare_passthroughfs_with(|_| {});
+        std::fs::write(source.as_path().join("f"), b"").unwrap();
+        let ctx = Context::default();
+        fs.lookup(&ctx, ROOT_ID, &CString::new("f").unwrap())
+            .unwrap();
+        let (state, _) = fs.save().unwrap();
+
+        // Another root directory.
+        let other = TempDir::new().unwrap();
+        let err = fresh_fs(other.as_path())
+            .restore(&state, Vec::new())
+            .unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::ESTALE));
+
+        // The file has been replaced.
+        std::fs::remove_file(source.as_path().join("f")).unwrap();
+        std::fs::write(source.as_path().join("f"), b"").unwrap();
+        drop(fs);
+        let restored = fresh_fs(source.as_path());
+        let err = restored.restore(&state, Vec::new()).unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::ESTALE));
+        restored.import().unwrap();
+    }
+
+    #[cfg(not(feature = "async-io"))]
+    #[test]
+    fn test_passthroughfs_vfs_save_restore() {
+        use crate::abi::fuse_abi::FsOptions;
+        use crate::api::{BackFileSystem, Vfs};
+
+        // Backends under a Vfs are imported or restored before the Vfs initializes them.
+        let backend = |dir: &std::path::Path| {
+            let fs = PassthroughFs::<()>::new(Config {
+                root_dir: dir.to_str().unwrap().to_string(),
+                do_import: false,
+                ..Default::default()
+            })
+            .unwrap();
+            Ok(Box::new(fs) as BackFileSystem)
+        };
+        let vfs_in = || {
+            let vfs = Vfs::default();
+            vfs.init(FsOptions::ASYNC_READ).unwrap();
+            vfs
+        };
+        let (source, fs) = prepare_passthroughfs_with(|cfg| cfg.do_import = false);
+        std::fs::write(source.as_path().join("f"), b"hello").unwrap();
+        let vfs = vfs_in();
+        let idx = vfs.mount(Box::new(fs), "/p").unwrap();
+        let ctx = Context::default();
+        let p = vfs
+            .lookup(&ctx, ROOT_ID.into(), &CString::new("p").unwrap())
+            .unwrap()
+            .inode;
+        let f = vfs
+            .lookup(&ctx, p.into(), &CString::new("f").unwrap())
+            .unwrap()
+            .inode;
+        let (fh, _) = vfs.open(&ctx, f.into(), libc::O_RDONLY as u32, 0).unwrap();
+        let fh = fh.unwrap();
+        let (state, fds) = vfs.save_with_backends().unwrap();
+        assert_eq!(fds.len(), 1);
+        let (files, spare) = (dup_files(&fds), dup_files(&fds));
+
+        // The backend is restored with the Vfs, the open handle survives.
+        let restored = vfs_in();
+        let failed = restored
+            .restore_with_backends(&state, files, |_, _| backend(source.as_path()))
+            .unwrap();
+        assert!(failed.is_empty());
+        drop(vfs);
+        let mut buf = Vec::new();
+        let n = restored
+            .read(
+                &ctx,
+                f.into(),
+                fh,
+                &mut VecWriter::new(&mut buf),
+                5,
+                0,
+                None,
+                0,
+            )
+            .unwrap();
+        assert_eq!((n, buf.as_slice()), (5, &b"hello"[..]));
+        let entry = restored
+            .lookup(&ctx, p.into(), &CString::new("f").unwrap())
+            .unwrap();
+        assert_eq!(entry.inode, f);
+
+        // A backend failing to restore its state is mounted fresh, and reported.
+        let other = TempDir::new().unwrap();
+        std::fs::write(other.as_path().join("g"), b"").unwrap();
+        let fresh = vfs_in();
+        let failed = fresh
+            .restore_with_backends(&state, spare, |_, _| backend(other.as_path()))
+            .unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, idx);
+        assert_eq!(errno_of(&failed[0].1), Some(libc::ESTALE));
+        fresh
+            .lookup(&ctx, p.into(), &CString::new("g").unwrap())
+            .unwrap();
+
+        // File descriptors must match the saved backends.
+        let err = vfs_in()
+            .restore_with_backends(&state, Vec::new(), |_, _| backend(source.as_path()))
+            .unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::EINVAL));
+    }
+}
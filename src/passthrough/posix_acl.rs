@@ -0,0 +1,210 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Posix ACLs of files shared with the guest.
+//!
+//! With `FsOptions::POSIX_ACL` negotiated, the guest kernel enforces ACLs read from the
+//! `system.posix_acl_*` xattrs, and leaves the umask of the caller to the file system when
+//! creating files, as ACL aware file systems ignore it in directories with a default ACL. The
+//! daemon runs with a zero umask, so it applies the umask itself unless the parent directory has
+//! a default ACL, and the host kernel then inherits the default ACL as usual.
+//!
+//! ACLs are set with the credentials of the caller, so the host kernel checks the caller owns
+//! the file, and clears the setgid bit if the caller isn't a member of the owning group.
+
+use std::io;
+
+use super::creds::ScopedCreds;
+use super::*;
+use crate::api::filesystem::Context;
+
+const POSIX_ACL_ACCESS: &[u8] = b"system.posix_acl_access\0";
+const POSIX_ACL_DEFAULT: &[u8] = b"system.posix_acl_default\0";
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    // Mode of a file created with `mode` by a caller with `umask` in directory `dir`.
+    pub(super) fn create_mode(&self, dir: RawFd, mode: u32, umask: u32) -> u32 {
+        if self.posix_acl.load(Ordering::Relaxed) && Self::has_default_acl(dir) {
+            mode
+        } else {
+            mode & !(umask & 0o777)
+        }
+    }
+
+    // Switch to the caller's credentials to set or remove the xattr `name`, if it's an ACL.
+    pub(super) fn acl_creds(
+        &self,
+        ctx: &Context,
+        name: &CStr,
+    ) -> io::Result<Option<ScopedCreds<'_>>> {
+        let name = name.to_bytes_with_nul();
+        if self.posix_acl.load(Ordering::Relaxed)
+            && (name == POSIX_ACL_ACCESS || name == POSIX_ACL_DEFAULT)
+        {
+            self.set_creds(ctx).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn has_default_acl(dir: RawFd) -> bool {
+        let pathname = ProcFdPath::new(dir);
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe {
+            libc::getxattr(
+                pathname.as_ptr(),
+                POSIX_ACL_DEFAULT.as_ptr() as *const libc::c_char,
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        res > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abi::fuse_abi::{FsOptions, ROOT_ID};
+    use crate::api::filesystem::FileSystem;
+    use crate::passthrough::tests::passthroughfs_in;
+    use std::os::unix::fs::PermissionsExt;
+    use vmm_sys_util::tempdir::TempDir;
+
+    // A minimal ACL granting `perm` to the owner, the group and others, in the xattr format.
+    fn acl(perm: u16) -> Vec<u8> {
+        let mut buf = 2u32.to_le_bytes().to_vec();
+        for tag in [0x01u16, 0x04, 0x20] {
+            buf.extend_from_slice(&tag.to_le_bytes());
+            buf.extend_from_slice(&perm.to_le_bytes());
+            buf.extend_from_slice(&u32::MAX.to_le_bytes());
+        }
+        buf
+    }
+
+    fn new_fs(source: &TempDir, posix_acl: bool) -> PassthroughFs {
+        let fs = passthroughfs_in(source.as_path(), |cfg| {
+            cfg.xattr = true;
+            cfg.posix_acl = posix_acl;
+        });
+        let opts = fs
+            .init(FsOptions::POSIX_ACL | FsOptions::DONT_MASK)
+            .unwrap();
+        assert_eq!(opts.contains(FsOptions::POSIX_ACL), posix_acl);
+        assert_eq!(opts.contains(FsOptions::DONT_MASK), posix_acl);
+        fs
+    }
+
+    #[test]
+    fn test_posix_acl_negotiation() {
+        let source = TempDir::new().unwrap();
+        new_fs(&source, false);
+        new_fs(&source, true);
+
+        // ACLs need xattrs.
+        let fs = passthroughfs_in(source.as_path(), |cfg| cfg.posix_acl = true);
+        let opts = fs.init(FsOptions::POSIX_ACL).unwrap();
+        assert!(!opts.contains(FsOptions::POSIX_ACL));
+    }
+
+    #[test]
+    fn test_posix_acl_umask() {
+        let source = TempDir::new().unwrap();
+        let dir = source.as_path().join("acl");
+        std::fs::create_dir(&dir).unwrap();
+        let cdir = CString::new(dir.to_str().unwrap()).unwrap();
+        let value = acl(7);
+        let name = CStr::from_bytes_with_nul(POSIX_ACL_DEFAULT).unwrap();
+        // Safe because all pointers are valid.
+        let res = unsafe {
+            libc::setxattr(
+                cdir.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        if res != 0 {
+            // The backing file system doesn't support ACLs.
+            return;
+        }
+
+        let ctx = Context::default();
+        let mode = |path: &str| {
+            std::fs::metadata(source.as_path().join(path))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777
+        };
+        let mkdirs = |fs: &PassthroughFs, name: &str| {
+            let name = CString::new(name).unwrap();
+            let acl_dir = fs
+                .lookup(&ctx, ROOT_ID, &CString::new("acl").unwrap())
+                .unwrap()
+                .inode;
+            fs.mkdir(&ctx, ROOT_ID, &name, 0o777, 0o022).unwrap();
+            fs.mkdir(&ctx, acl_dir, &name, 0o777, 0o022).unwrap();
+            fs.mknod(
+                &ctx,
+                acl_dir,
+                &CString::new("n").unwrap(),
+                libc::S_IFREG | 0o666,
+                0,
+                0o022,
+            )
+            .unwrap();
+        };
+
+        // The umask is ignored in directories with a default ACL.
+        let fs = new_fs(&source, true);
+        mkdirs(&fs, "a");
+        assert_eq!(mode("a"), 0o755);
+        assert_eq!(mode("acl/a"), 0o777);
+        assert_eq!(mode("acl/n"), 0o666);
+
+        // Without ACLs, the umask is always applied.
+        std::fs::remove_file(dir.join("n")).unwrap();
+        let fs = new_fs(&source, false);
+        mkdirs(&fs, "b");
+        assert_eq!(mode("b"), 0o755);
+        assert_eq!(mode("acl/b"), 0o755);
+        assert_eq!(mode("acl/n"), 0o644);
+    }
+
+    #[test]
+    fn test_posix_acl_creds() {
+        let source = TempDir::new().unwrap();
+        let path = source.as_path().join("f");
+        std::fs::write(&path, b"f").unwrap();
+        std::os::unix::fs::chown(&path, Some(1000), Some(1000)).unwrap();
+        let name = CStr::from_bytes_with_nul(POSIX_ACL_ACCESS).unwrap();
+        let value = acl(6);
+
+        let setacl = |fs: &PassthroughFs, uid| {
+            let ctx = Context {
+                uid,
+                gid: uid,
+                ..Default::default()
+            };
+            let ino = fs
+                .lookup(&ctx, ROOT_ID, &CString::new("f").unwrap())
+                .unwrap()
+                .inode;
+            fs.setxattr(&ctx, ino, name, &value, 0)
+                .map_err(|e| e.raw_os_error())
+        };
+
+        let fs = new_fs(&source, true);
+        match setacl(&fs, 1000) {
+            // The backing file system doesn't support ACLs.
+            Err(Some(libc::EOPNOTSUPP)) => return,
+            res => res.unwrap(),
+        }
+        // Only the owner may set ACLs.
+        assert_eq!(setacl(&fs, 2000), Err(Some(libc::EPERM)));
+        let fs = new_fs(&source, false);
+        setacl(&fs, 2000).unwrap();
+    }
+}
@@ -0,0 +1,217 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reopening of inodes through `/proc/self/fd`.
+//!
+//! Inodes are referenced by `O_PATH` fds, which are reopened through `/proc/self/fd` to get fds
+//! able to read, write or list them. Some container sandboxes mask `/proc` or mount it with
+//! `hidepid`, and reopening fails at runtime although the daemon started fine. So a reopen is
+//! probed when the file system is created, and without `/proc/self/fd`, inodes are reopened by
+//! other means where possible: `O_PATH` fds are duplicated, and directories are reopened by
+//! opening `.` relative to them. Other operations fail with `EOPNOTSUPP`, with a context telling
+//! `/proc/self/fd` is unavailable.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use super::*;
+
+// Syscalls used to reopen inodes, abstracted for testing.
+pub(super) trait ProcSyscalls: Send + Sync {
+    // Open `/proc/self/fd` with `O_PATH`.
+    fn open_proc_self_fd(&self) -> io::Result<File>;
+
+    // Reopen `fd` with `flags` through `proc`, an fd of `/proc/self/fd`.
+    fn reopen(&self, proc: RawFd, fd: RawFd, flags: i32) -> io::Result<File>;
+}
+
+pub(super) struct LibcProcSyscalls;
+
+impl ProcSyscalls for LibcProcSyscalls {
+    fn open_proc_self_fd(&self) -> io::Result<File> {
+        // Safe because this is a constant value and a valid C string.
+        let pathname = unsafe { CStr::from_bytes_with_nul_unchecked(PROC_SELF_FD_CSTR) };
+        open_at(
+            libc::AT_FDCWD,
+            pathname,
+            libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+        )
+    }
+
+    fn reopen(&self, proc: RawFd, fd: RawFd, flags: i32) -> io::Result<File> {
+        let pathname = CString::new(format!("{}", fd))
+            .map_err(|_| fuse_errno(libc::EINVAL, "invalid proc fd path"))?;
+        open_at(proc, &pathname, flags)
+    }
+}
+
+fn open_at(dir: RawFd, pathname: &CStr, flags: i32) -> io::Result<File> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    let fd = unsafe { libc::openat(dir, pathname.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we just opened this fd.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Probe whether inodes can be reopened through `/proc/self/fd`, by reopening the root
+/// directory. Return `/proc/self/fd` if so.
+pub(super) fn probe_proc_self_fd(sys: &dyn ProcSyscalls) -> Option<File> {
+    let probe = || -> io::Result<File> {
+        let proc = sys.open_proc_self_fd()?;
+        let root = open_at(
+            libc::AT_FDCWD,
+            CStr::from_bytes_with_nul(b"/\0").unwrap(),
+            libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        )?;
+        sys.reopen(
+            proc.as_raw_fd(),
+            root.as_raw_fd(),
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        )?;
+        Ok(proc)
+    };
+
+    match probe() {
+        Ok(proc) => Some(proc),
+        Err(e) => {
+            warn!(
+                "passthrough: /proc/self/fd is unavailable, only directories can be reopened, {}",
+                e
+            );
+            None
+        }
+    }
+}
+
+// Error of operations needing `/proc/self/fd` when it's unavailable.
+pub(super) fn proc_unavailable() -> io::Error {
+    fuse_errno(
+        libc::EOPNOTSUPP,
+        "/proc/self/fd is unavailable, /proc may be masked or mounted with hidepid",
+    )
+}
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    /// Whether inodes are reopened through `/proc/self/fd`, as probed when the file system was
+    /// created.
+    ///
+    /// Without `/proc/self/fd`, only directories are reopened, and operations reopening other
+    /// inodes, like opening regular files, fail with `EOPNOTSUPP`.
+    pub fn proc_self_fd_available(&self) -> bool {
+        self.proc_self_fd.is_some()
+    }
+
+    // Get the fd of `/proc/self/fd`, failing if it's unavailable.
+    pub(super) fn proc_self_fd(&self) -> io::Result<RawFd> {
+        self.proc_self_fd
+            .as_ref()
+            .map(|f| f.as_raw_fd())
+            .ok_or_else(proc_unavailable)
+    }
+
+    // Reopen `fd` referring to an inode of type `mode` with `flags`.
+    pub(super) fn reopen_fd(&self, fd: RawFd, flags: i32, mode: u32) -> io::Result<File> {
+        self.check_special_open(mode, flags)?;
+        if self.is_passthrough_blockdev(mode) {
+            self.check_blockdev_open(flags)?;
+        } else if !is_safe_inode(mode) {
+            return Err(ebadf());
+        }
+
+        // We don't really check `flags` because if the kernel can't handle poorly specified flags
+        // then we have much bigger problems. Also, clear the `O_NOFOLLOW` flag if it is set since
+        // we need to follow the `/proc/self/fd` symlink to get the file.
+        let flags = (flags | libc::O_CLOEXEC) & !libc::O_NOFOLLOW;
+        match self.proc_self_fd.as_ref() {
+            Some(proc) => self.proc_sys.reopen(proc.as_raw_fd(), fd, flags),
+            None if flags & libc::O_PATH != 0 => {
+                // Safe because this doesn't modify any memory and we check the return value.
+                let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+                if dup < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // Safe because we just duplicated this fd.
+                Ok(unsafe { File::from_raw_fd(dup) })
+            }
+            None if mode & libc::S_IFMT == libc::S_IFDIR => open_at(
+                fd,
+                CStr::from_bytes_with_nul(b".\0").unwrap(),
+                flags | libc::O_DIRECTORY,
+            ),
+            None => Err(proc_unavailable()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abi::fuse_abi::ROOT_ID;
+    use crate::api::errno::errno_of;
+    use crate::api::filesystem::{Context, FileSystem};
+    use crate::passthrough::tests::prepare_passthroughfs_with;
+
+    // `/proc` masked by an empty mount, or `/proc/self/fd` hidden by `hidepid`.
+    struct MaskedProc {
+        masked_dir: bool,
+    }
+
+    impl ProcSyscalls for MaskedProc {
+        fn open_proc_self_fd(&self) -> io::Result<File> {
+            if self.masked_dir {
+                Err(io::Error::from_raw_os_error(libc::ENOENT))
+            } else {
+                LibcProcSyscalls.open_proc_self_fd()
+            }
+        }
+
+        fn reopen(&self, _proc: RawFd, _fd: RawFd, _flags: i32) -> io::Result<File> {
+            Err(io::Error::from_raw_os_error(libc::EACCES))
+        }
+    }
+
+    #[test]
+    fn test_probe_proc_self_fd() {
+        assert!(probe_proc_self_fd(&LibcProcSyscalls).is_some());
+        assert!(probe_proc_self_fd(&MaskedProc { masked_dir: true }).is_none());
+        assert!(probe_proc_self_fd(&MaskedProc { masked_dir: false }).is_none());
+    }
+
+    #[test]
+    fn test_reopen_without_proc() {
+        let (source, mut fs) = prepare_passthroughfs_with(|_| {});
+        std::fs::write(source.as_path().join("f"), b"f").unwrap();
+        std::fs::create_dir(source.as_path().join("d")).unwrap();
+        assert!(fs.proc_self_fd_available());
+        fs.proc_self_fd = probe_proc_self_fd(&MaskedProc { masked_dir: false });
+        assert!(!fs.proc_self_fd_available());
+
+        let ctx = Context::default();
+        let lookup = |name: &str| {
+            fs.lookup(&ctx, ROOT_ID, &CString::new(name).unwrap())
+                .unwrap()
+                .inode
+        };
+        // Directories are reopened by opening `.`.
+        let dir = lookup("d");
+        let (handle, _) = fs.opendir(&ctx, dir, 0).unwrap();
+        fs.releasedir(&ctx, dir, 0, handle.unwrap()).unwrap();
+
+        // Regular files can't be reopened.
+        let file = lookup("f");
+        let e = fs.open(&ctx, file, libc::O_RDONLY as u32, 0).unwrap_err();
+        assert_eq!(errno_of(&e), Some(libc::EOPNOTSUPP));
+        assert!(e.to_string().contains("/proc/self/fd is unavailable"));
+
+        // O_PATH fds are duplicated.
+        let data = fs.inode_map.get(file).unwrap();
+        let f = data.get_file(&fs.mount_fds).unwrap();
+        let dup = fs
+            .reopen_fd(f.as_raw_fd(), libc::O_PATH, libc::S_IFREG)
+            .unwrap();
+        assert_ne!(dup.as_raw_fd(), f.as_raw_fd());
+    }
+}
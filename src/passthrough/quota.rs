@@ -0,0 +1,605 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Guest visible quota reporting for the passthrough file system.
+//!
+//! Quotas are usually enforced on the host, for example by project quotas, so `df` in the guest
+//! reports usage of the whole backing file system. A [QuotaProvider] may be installed to report
+//! quota limits of the project/user owning an inode instead, both by rewriting `statfs` replies
+//! and by a virtual `user.fuse.quota` extended attribute.
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fmt::Write;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::{is_safe_inode, Inode, PassthroughFs};
+use crate::api::clock::Clock;
+use crate::api::errno::ENOATTR;
+use crate::api::filesystem::GetxattrReply;
+use crate::BitmapSlice;
+
+/// Name of the virtual extended attribute to retrieve a quota snapshot.
+pub const QUOTA_XATTR_NAME: &str = "user.fuse.quota";
+
+/// Identity an inode is accounted to by quotas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct QuotaId {
+    /// Project id of the inode, `0` if it doesn't belong to a project.
+    pub project: u32,
+    /// Owner of the inode.
+    pub uid: u32,
+}
+
+/// Quota usage and limits, a limit of `0` means unlimited.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaInfo {
+    /// Space used, in bytes.
+    pub space_used: u64,
+    /// Space limit, in bytes.
+    pub space_limit: u64,
+    /// Number of inodes used.
+    pub inodes_used: u64,
+    /// Limit of inodes.
+    pub inodes_limit: u64,
+}
+
+impl QuotaInfo {
+    /// Rewrite `statvfs` reply of the backing file system to report the quota instead.
+    ///
+    /// Free space and free inodes never exceed what's available on the backing file system.
+    pub fn apply(&self, st: &mut libc::statvfs64) {
+        if self.space_limit > 0 {
+            let bsize = if st.f_frsize > 0 {
+                st.f_frsize
+            } else {
+                st.f_bsize.max(1)
+            };
+            let free = self.space_limit.saturating_sub(self.space_used) / bsize;
+            st.f_blocks = self.space_limit / bsize;
+            st.f_bfree = free.min(st.f_bfree);
+            st.f_bavail = free.min(st.f_bavail);
+        }
+        if self.inodes_limit > 0 {
+            let free = self.inodes_limit.saturating_sub(self.inodes_used);
+            st.f_files = self.inodes_limit;
+            st.f_ffree = free.min(st.f_ffree);
+            st.f_favail = free.min(st.f_favail);
+        }
+    }
+
+    /// Serialize the quota snapshot as `key=value` lines, the format of the virtual xattr.
+    pub fn format(&self, id: &QuotaId) -> String {
+        let mut out = String::new();
+        // Writing to a String never fails.
+        let _ = write!(
+            out,
+            "project={}\nuid={}\nspace_used={}\nspace_limit={}\ninodes_used={}\ninodes_limit={}\n",
+            id.project,
+            id.uid,
+            self.space_used,
+            self.space_limit,
+            self.inodes_used,
+            self.inodes_limit
+        );
+        out
+    }
+}
+
+/// Source of quota information, for example `quotactl(2)` or an external quota service.
+pub trait QuotaProvider: Send + Sync {
+    /// Get quota of `id`, or `None` if there's no quota for it.
+    fn get_quota(&self, id: &QuotaId) -> io::Result<Option<QuotaInfo>>;
+}
+
+/// Options for quota reporting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaConfig {
+    /// How long results of the quota provider are cached.
+    ///
+    /// The default value for this option is 1 second.
+    pub ttl: Duration,
+
+    /// Whether to expose the virtual `user.fuse.quota` extended attribute.
+    ///
+    /// The default value for this option is `false`.
+    pub xattr: bool,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        QuotaConfig {
+            ttl: Duration::from_secs(1),
+            xattr: false,
+        }
+    }
+}
+
+pub(super) struct QuotaCache {
+    provider: Arc<dyn QuotaProvider>,
+    cfg: QuotaConfig,
+    clock: Arc<dyn Clock>,
+    entries: Mutex<HashMap<QuotaId, (Duration, Option<QuotaInfo>)>>,
+}
+
+impl QuotaCache {
+    pub(super) fn new(
+        provider: Arc<dyn QuotaProvider>,
+        cfg: QuotaConfig,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        QuotaCache {
+            provider,
+            cfg,
+            clock,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(super) fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub(super) fn xattr(&self) -> bool {
+        self.cfg.xattr
+    }
+
+    pub(super) fn get(&self, id: &QuotaId) -> io::Result<Option<QuotaInfo>> {
+        let now = self.clock.monotonic();
+        if let Some((deadline, info)) = self.entries.lock().unwrap().get(id) {
+            if *deadline > now {
+                return Ok(*info);
+            }
+        }
+
+        // Don't hold the lock while calling into the provider, concurrent misses may query the
+        // provider more than once, which is harmless.
+        let info = self.provider.get_quota(id)?;
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (deadline, _)| *deadline > now);
+        entries.insert(*id, (now + self.cfg.ttl, info));
+
+        Ok(info)
+    }
+}
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    /// Report quotas from `provider` to the guest.
+    ///
+    /// `statfs` replies get rewritten with quota of the project and owner of the inode, and the
+    /// virtual `user.fuse.quota` extended attribute may be enabled by `cfg.xattr`.
+    pub fn with_quota_provider(
+        mut self,
+        provider: Arc<dyn QuotaProvider>,
+        cfg: QuotaConfig,
+    ) -> Self {
+        self.quota = Some(QuotaCache::new(provider, cfg, self.clock.clone()));
+        self
+    }
+
+    // Resolve the project id and owner of an inode.
+    fn quota_id(&self, inode: Inode) -> io::Result<QuotaId> {
+        let data = self.inode_map.get(inode)?;
+        let file = data.get_file(&self.mount_fds)?;
+        let st = Self::stat(&file, None)?;
+
+        // The FS_IOC_FSGETXATTR ioctl doesn't work on `O_PATH` fds, and file systems without
+        // project quota support don't have project ids. Opening FIFOs, sockets or devices may
+        // have side effects, so they are accounted to no project instead of being reopened.
+        if !is_safe_inode(st.st_mode) {
+            return Ok(QuotaId {
+                project: 0,
+                uid: st.st_uid,
+            });
+        }
+        let project = match self.reopen_fd(
+            file.as_raw_fd(),
+            libc::O_RDONLY | libc::O_NONBLOCK,
+            st.st_mode,
+        ) {
+            Ok(f) => self
+                .fsxattr_sys
+                .get(f.as_raw_fd())
+                .map_or(0, |attr| attr.fsx_projid),
+            Err(_) => 0,
+        };
+
+        Ok(QuotaId {
+            project,
+            uid: st.st_uid,
+        })
+    }
+
+    pub(super) fn is_quota_xattr(&self, name: &CStr) -> bool {
+        self.quota.as_ref().map(|q| q.xattr()).unwrap_or(false)
+            && name.to_bytes() == QUOTA_XATTR_NAME.as_bytes()
+    }
+
+    pub(super) fn get_quota_xattr(&self, inode: Inode, size: u32) -> io::Result<GetxattrReply> {
+        let value = match self.get_quota(inode) {
+            Some((id, info)) => info.format(&id).into_bytes(),
+            None => return Err(io::Error::from_raw_os_error(ENOATTR)),
+        };
+
+        if size == 0 {
+            Ok(GetxattrReply::Count(value.len() as u32))
+        } else if (size as usize) < value.len() {
+            Err(io::Error::from_raw_os_error(libc::ERANGE))
+        } else {
+            Ok(GetxattrReply::Value(value))
+        }
+    }
+
+    /// Get quota of the project and owner of `inode`, if quota reporting is enabled.
+    pub(super) fn get_quota(&self, inode: Inode) -> Option<(QuotaId, QuotaInfo)> {
+        let quota = self.quota.as_ref()?;
+        let res = self
+            .quota_id(inode)
+            .and_then(|id| quota.get(&id).map(|info| info.map(|i| (id, i))));
+
+        match res {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("fuse: failed to get quota of inode {}, {:?}", inode, e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(feature = "project-quota")]
+pub use project::ProjectQuotaProvider;
+
+#[cfg(feature = "project-quota")]
+mod project {
+    use std::ffi::CString;
+    use std::io;
+
+    use super::{QuotaId, QuotaInfo, QuotaProvider};
+
+    // struct if_dqblk from <linux/quota.h>.
+    #[repr(C)]
+    #[derive(Default)]
+    struct IfDqblk {
+        dqb_bhardlimit: u64,
+        dqb_bsoftlimit: u64,
+        dqb_curspace: u64,
+        dqb_ihardlimit: u64,
+        dqb_isoftlimit: u64,
+        dqb_curinodes: u64,
+        dqb_btime: u64,
+        dqb_itime: u64,
+        dqb_valid: u32,
+    }
+
+    const Q_GETQUOTA: i32 = 0x80_0007;
+    const PRJQUOTA: i32 = 2;
+    // Block limits are in units of QIF_DQBLKSIZE bytes.
+    const QIF_DQBLKSIZE: u64 = 1024;
+
+    /// Quota provider for Linux project quotas, by `quotactl(2)` on the block device of the
+    /// backing file system.
+    pub struct ProjectQuotaProvider {
+        device: CString,
+    }
+
+    impl ProjectQuotaProvider {
+        /// Create a provider querying project quotas of the file system on block device `device`.
+        pub fn new(device: &str) -> io::Result<Self> {
+            let device =
+                CString::new(device).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            Ok(ProjectQuotaProvider { device })
+        }
+    }
+
+    impl QuotaProvider for ProjectQuotaProvider {
+        fn get_quota(&self, id: &QuotaId) -> io::Result<Option<QuotaInfo>> {
+            if id.project == 0 {
+                return Ok(None);
+            }
+
+            let mut dq = IfDqblk::default();
+            // QCMD(Q_GETQUOTA, PRJQUOTA)
+            let cmd = (Q_GETQUOTA << 8) | PRJQUOTA;
+            // Safe because the kernel only writes to `dq` and we check the return value.
+            let res = unsafe {
+                libc::quotactl(
+                    cmd,
+                    self.device.as_ptr(),
+                    id.project as i32,
+                    &mut dq as *mut IfDqblk as *mut libc::c_char,
+                )
+            };
+            if res < 0 {
+                let e = io::Error::last_os_error();
+                return match e.raw_os_error() {
+                    Some(libc::ENOENT) | Some(libc::ESRCH) => Ok(None),
+                    _ => Err(e),
+                };
+            }
+
+            let limit = |hard: u64, soft: u64| if hard > 0 { hard } else { soft };
+            Ok(Some(QuotaInfo {
+                space_used: dq.dqb_curspace,
+                space_limit: limit(dq.dqb_bhardlimit, dq.dqb_bsoftlimit) * QIF_DQBLKSIZE,
+                inodes_used: dq.dqb_curinodes,
+                inodes_limit: limit(dq.dqb_ihardlimit, dq.dqb_isoftlimit),
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::clock::ManualClock;
+    use crate::passthrough::fsxattr::{Fsxattr, FsxattrSyscalls};
+    use std::os::unix::io::RawFd;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    struct MockQuotaProvider {
+        calls: AtomicU64,
+        info: Option<QuotaInfo>,
+    }
+
+    impl QuotaProvider for MockQuotaProvider {
+        fn get_quota(&self, _id: &QuotaId) -> io::Result<Option<QuotaInfo>> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            Ok(self.info)
+        }
+    }
+
+    #[test]
+    fn test_quota_apply() {
+        let mut st: libc::statvfs64 = unsafe { std::mem::zeroed() };
+        st.f_bsize = 4096;
+        st.f_frsize = 4096;
+        st.f_blocks = 1 << 30;
+        st.f_bfree = 1 << 29;
+        st.f_bavail = 100;
+        st.f_files = 1 << 20;
+        st.f_ffree = 1 << 19;
+        st.f_favail = 1 << 19;
+
+        let info = QuotaInfo {
+            space_used: 4096 * 10,
+            space_limit: 4096 * 1000,
+            inodes_used: 5,
+            inodes_limit: 100,
+        };
+        info.apply(&mut st);
+        assert_eq!(st.f_blocks, 1000);
+        assert_eq!(st.f_bfree, 990);
+        // Never report more space than available on the backing file system.
+        assert_eq!(st.f_bavail, 100);
+        assert_eq!(st.f_files, 100);
+        assert_eq!(st.f_ffree, 95);
+        assert_eq!(st.f_favail, 95);
+
+        // Unlimited quotas don't touch the reply.
+        let mut st2 = st;
+        QuotaInfo::default().apply(&mut st2);
+        assert_eq!(st2.f_blocks, st.f_blocks);
+        assert_eq!(st2.f_files, st.f_files);
+    }
+
+    #[test]
+    fn test_quota_format() {
+        let info = QuotaInfo {
+            space_used: 1,
+            space_limit: 2,
+            inodes_used: 3,
+            inodes_limit: 4,
+        };
+        let id = QuotaId {
+            project: 7,
+            uid: 1000,
+        };
+        assert_eq!(
+            info.format(&id),
+            "project=7\nuid=1000\nspace_used=1\nspace_limit=2\ninodes_used=3\ninodes_limit=4\n"
+        );
+    }
+
+    #[test]
+    fn test_quota_cache_ttl() {
+        let provider = Arc::new(MockQuotaProvider {
+            calls: AtomicU64::new(0),
+            info: Some(QuotaInfo::default()),
+        });
+        let id = QuotaId { project: 1, uid: 0 };
+
+        let clock = Arc::new(ManualClock::default());
+        let cache = QuotaCache::new(provider.clone(), QuotaConfig::default(), clock.clone());
+        cache.get(&id).unwrap();
+        cache.get(&id).unwrap();
+        assert_eq!(provider.calls.load(Ordering::Relaxed), 1);
+        cache.get(&QuotaId { project: 2, uid: 0 }).unwrap();
+        assert_eq!(provider.calls.load(Ordering::Relaxed), 2);
+        clock.advance(Duration::from_secs(1));
+        cache.get(&id).unwrap();
+        assert_eq!(provider.calls.load(Ordering::Relaxed), 3);
+
+        let cache = QuotaCache::new(
+            provider.clone(),
+            QuotaConfig {
+                ttl: Duration::from_secs(0),
+                xattr: false,
+            },
+            clock,
+        );
+        cache.get(&id).unwrap();
+        cache.get(&id).unwrap();
+        assert_eq!(provider.calls.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn test_passthroughfs_quota() {
+        use crate::abi::fuse_abi::ROOT_ID;
+        use crate::api::filesystem::{Context, FileSystem};
+        use crate::passthrough::tests::prepare_passthroughfs_with;
+        use std::ffi::CString;
+
+        let (_source, fs) = prepare_passthroughfs_with(|_| {});
+        let info = QuotaInfo {
+            space_used: 1 << 20,
+            space_limit: 1 << 30,
+            inodes_used: 10,
+            inodes_limit: 1000,
+        };
+        let provider = Arc::new(MockQuotaProvider {
+            calls: AtomicU64::new(0),
+            info: Some(info),
+        });
+        let fs = fs.with_quota_provider(
+            provider.clone(),
+            QuotaConfig {
+                ttl: Duration::from_secs(60),
+                xattr: true,
+            },
+        );
+        let ctx = Context::default();
+
+        let st = fs.statfs(&ctx, ROOT_ID).unwrap();
+        let bsize = if st.f_frsize > 0 {
+            st.f_frsize
+        } else {
+            st.f_bsize
+        };
+        assert_eq!(st.f_blocks, (1 << 30) / bsize);
+        assert!(st.f_bavail <= ((1 << 30) - (1 << 20)) / bsize);
+        assert_eq!(st.f_files, 1000);
+        assert!(st.f_ffree <= 990);
+        fs.statfs(&ctx, ROOT_ID).unwrap();
+        assert_eq!(provider.calls.load(Ordering::Relaxed), 1);
+
+        // The virtual xattr is served even though xattr support is disabled.
+        let name = CString::new(QUOTA_XATTR_NAME).unwrap();
+        let expected = info.format(&fs.quota_id(ROOT_ID).unwrap());
+        match fs.getxattr(&ctx, ROOT_ID, &name, 0).unwrap() {
+            GetxattrReply::Count(n) => assert_eq!(n as usize, expected.len()),
+            _ => panic!("unexpected getxattr reply"),
+        }
+        match fs.getxattr(&ctx, ROOT_ID, &name, 4096).unwrap() {
+            GetxattrReply::Value(v) => assert_eq!(v, expected.as_bytes()),
+            _ => panic!("unexpected getxattr reply"),
+        }
+        assert_eq!(
+            fs.getxattr(&ctx, ROOT_ID, &name, 1)
+                .err()
+                .unwrap()
+                .raw_os_error(),
+            Some(libc::ERANGE)
+        );
+        assert_eq!(
+            fs.setxattr(&ctx, ROOT_ID, &name, b"x", 0)
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EPERM)
+        );
+        let other = CString::new("user.other").unwrap();
+        assert_eq!(
+            fs.getxattr(&ctx, ROOT_ID, &other, 0)
+                .err()
+                .unwrap()
+                .raw_os_error(),
+            Some(libc::ENOSYS)
+        );
+    }
+
+    #[test]
+    fn test_passthroughfs_no_quota() {
+        use crate::abi::fuse_abi::ROOT_ID;
+        use crate::api::filesystem::{Context, FileSystem};
+        use crate::passthrough::tests::prepare_passthroughfs_with;
+        use std::ffi::CString;
+
+        let (source, fs) = prepare_passthroughfs_with(|_| {});
+        let provider = Arc::new(MockQuotaProvider {
+            calls: AtomicU64::new(0),
+            info: None,
+        });
+        let fs = fs.with_quota_provider(provider, QuotaConfig::default());
+        let ctx = Context::default();
+
+        // Without quota the statfs reply of the backing file system is passed through.
+        let mut expected: libc::statvfs64 = unsafe { std::mem::zeroed() };
+        let path = CString::new(source.as_path().to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::statvfs64(path.as_ptr(), &mut expected) }, 0);
+        let st = fs.statfs(&ctx, ROOT_ID).unwrap();
+        assert_eq!(st.f_blocks, expected.f_blocks);
+
+        // The virtual xattr is not enabled.
+        let name = CString::new(QUOTA_XATTR_NAME).unwrap();
+        assert_eq!(
+            fs.getxattr(&ctx, ROOT_ID, &name, 0)
+                .err()
+                .unwrap()
+                .raw_os_error(),
+            Some(libc::ENOSYS)
+        );
+    }
+
+    // Fsxattr syscalls reporting project 7 for any file, counting calls.
+    struct MockFsxattr(Arc<AtomicU64>);
+
+    impl FsxattrSyscalls for MockFsxattr {
+        fn get(&self, _fd: RawFd) -> io::Result<Fsxattr> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(Fsxattr {
+                fsx_projid: 7,
+                ..Default::default()
+            })
+        }
+
+        fn set(&self, _fd: RawFd, _attr: &Fsxattr) -> io::Result<()> {
+            Err(io::Error::from_raw_os_error(libc::ENOSYS))
+        }
+
+        fn get_flags(&self, _fd: RawFd) -> io::Result<u32> {
+            Err(io::Error::from_raw_os_error(libc::ENOSYS))
+        }
+
+        fn set_flags(&self, _fd: RawFd, _flags: u32) -> io::Result<()> {
+            Err(io::Error::from_raw_os_error(libc::ENOSYS))
+        }
+    }
+
+    #[test]
+    fn test_passthroughfs_quota_special_file() {
+        use crate::abi::fuse_abi::ROOT_ID;
+        use crate::api::filesystem::{Context, FileSystem};
+        use crate::passthrough::tests::prepare_passthroughfs_with;
+        use std::ffi::CString;
+
+        let (source, fs) = prepare_passthroughfs_with(|_| {});
+        std::fs::write(source.as_path().join("file"), b"a").unwrap();
+        let fifo = CString::new(source.as_path().join("fifo").to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+        let provider = Arc::new(MockQuotaProvider {
+            calls: AtomicU64::new(0),
+            info: Some(QuotaInfo::default()),
+        });
+        let mut fs = fs.with_quota_provider(provider, QuotaConfig::default());
+        let calls = Arc::new(AtomicU64::new(0));
+        fs.fsxattr_sys = Box::new(MockFsxattr(calls.clone()));
+        let ctx = Context::default();
+        let lookup = |name: &str| {
+            let name = CString::new(name).unwrap();
+            fs.lookup(&ctx, ROOT_ID, &name).unwrap().inode
+        };
+
+        assert_eq!(fs.quota_id(lookup("file")).unwrap().project, 7);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        // FIFOs are never reopened to get their project id.
+        let fifo = lookup("fifo");
+        assert_eq!(fs.quota_id(fifo).unwrap().project, 0);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        fs.statfs(&ctx, fifo).unwrap();
+    }
+}
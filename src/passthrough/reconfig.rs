@@ -0,0 +1,168 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reconfiguration of the passthrough file system at runtime.
+//!
+//! Workloads of a mount change over its life, so the cache policy, the timeouts of entries and
+//! attributes and xattr support may be changed without remounting, for example by a control API
+//! of the daemon. New values are applied to the following lookups and opens, while replies
+//! already in flight use the old ones. Data already cached by the guest is kept until it times
+//! out, so daemons should push invalidations with a `Notifier` when they need the guest to
+//! converge sooner.
+
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{CachePolicy, Config, PassthroughFs};
+use crate::BitmapSlice;
+
+/// Options of the passthrough file system able to be changed at runtime by
+/// `PassthroughFs::update_config()`. Their initial values are the ones of `Config`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RuntimeConfig {
+    /// See `Config::entry_timeout`.
+    pub entry_timeout: Duration,
+    /// See `Config::attr_timeout`.
+    pub attr_timeout: Duration,
+    /// See `Config::cache_policy`. Only applied to files opened after the change.
+    pub cache_policy: CachePolicy,
+    /// See `Config::writeback`. Negotiated by `FUSE_INIT`, so it can't be changed after init.
+    pub writeback: bool,
+    /// See `Config::xattr`. The kernel stops sending xattr requests once they fail with
+    /// `ENOSYS`, so enabling it again only takes effect on the next mount. It can't be
+    /// disabled after init when POSIX ACLs were negotiated.
+    pub xattr: bool,
+}
+
+impl From<&Config> for RuntimeConfig {
+    fn from(cfg: &Config) -> Self {
+        RuntimeConfig {
+            entry_timeout: cfg.entry_timeout,
+            attr_timeout: cfg.attr_timeout,
+            cache_policy: cfg.cache_policy,
+            writeback: cfg.writeback,
+            xattr: cfg.xattr,
+        }
+    }
+}
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    /// Get the options currently applied to new requests.
+    pub fn runtime_config(&self) -> RuntimeConfig {
+        **self.runtime_cfg.load()
+    }
+
+    /// Apply `cfg` to the following requests.
+    ///
+    /// Fails with `EBUSY` if `cfg` changes an option negotiated by `FUSE_INIT` after init, in
+    /// which case no option is changed.
+    pub fn update_config(&self, cfg: RuntimeConfig) -> io::Result<()> {
+        let _guard = self.runtime_lock.lock().unwrap();
+        let old = self.runtime_config();
+        if self.initialized.load(Ordering::Acquire) {
+            let acl = self.posix_acl.load(Ordering::Relaxed);
+            if cfg.writeback != old.writeback || (acl && old.xattr && !cfg.xattr) {
+                return Err(io::Error::from_raw_os_error(libc::EBUSY));
+            }
+        }
+        self.runtime_cfg.store(Arc::new(cfg));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::prepare_passthroughfs_with;
+    use super::*;
+    use crate::api::errno::errno_of;
+    use crate::api::filesystem::{Context, FileSystem, FsOptions, OpenOptions};
+    use std::ffi::CString;
+    use std::fs;
+
+    #[test]
+    fn test_update_config_timeouts() {
+        let (source, fs) = prepare_passthroughfs_with(|_| {});
+        fs.init(FsOptions::empty()).unwrap();
+        fs::write(source.as_path().join("file"), b"data").unwrap();
+        let ctx = Context::default();
+        let name = CString::new("file").unwrap();
+
+        let entry = fs.lookup(&ctx, 1, &name).unwrap();
+        assert_eq!(entry.entry_timeout, Duration::from_secs(5));
+        assert_eq!(entry.attr_timeout, Duration::from_secs(5));
+
+        let mut cfg = fs.runtime_config();
+        cfg.entry_timeout = Duration::from_secs(60);
+        cfg.attr_timeout = Duration::from_millis(10);
+        fs.update_config(cfg).unwrap();
+        assert_eq!(fs.runtime_config(), cfg);
+
+        let entry = fs.lookup(&ctx, 1, &name).unwrap();
+        assert_eq!(entry.entry_timeout, Duration::from_secs(60));
+        assert_eq!(entry.attr_timeout, Duration::from_millis(10));
+        let (_, timeout) = fs.getattr(&ctx, entry.inode, None).unwrap();
+        assert_eq!(timeout, Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_update_config_cache_policy() {
+        let (source, fs) = prepare_passthroughfs_with(|cfg| cfg.cache_policy = CachePolicy::Never);
+        fs.init(FsOptions::empty()).unwrap();
+        fs::write(source.as_path().join("file"), b"data").unwrap();
+        let ctx = Context::default();
+        let name = CString::new("file").unwrap();
+        let inode = fs.lookup(&ctx, 1, &name).unwrap().inode;
+
+        let (_, opts) = fs.open(&ctx, inode, libc::O_RDONLY as u32, 0).unwrap();
+        assert!(opts.contains(OpenOptions::DIRECT_IO));
+
+        let mut cfg = fs.runtime_config();
+        cfg.cache_policy = CachePolicy::Always;
+        fs.update_config(cfg).unwrap();
+        let (_, opts) = fs.open(&ctx, inode, libc::O_RDONLY as u32, 0).unwrap();
+        assert!(!opts.contains(OpenOptions::DIRECT_IO));
+        assert!(opts.contains(OpenOptions::KEEP_CACHE));
+    }
+
+    #[test]
+    fn test_update_config_init_options() {
+        let (_source, fs) = prepare_passthroughfs_with(|_| {});
+
+        // Changes before init are negotiated by it.
+        let mut cfg = fs.runtime_config();
+        cfg.writeback = true;
+        fs.update_config(cfg).unwrap();
+        let opts = fs.init(FsOptions::WRITEBACK_CACHE).unwrap();
+        assert!(opts.contains(FsOptions::WRITEBACK_CACHE));
+
+        cfg.writeback = false;
+        cfg.attr_timeout = Duration::from_secs(1);
+        assert_eq!(
+            errno_of(&fs.update_config(cfg).unwrap_err()),
+            Some(libc::EBUSY)
+        );
+        assert!(fs.runtime_config().writeback);
+        assert_eq!(fs.runtime_config().attr_timeout, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_update_config_xattr() {
+        let (source, fs) = prepare_passthroughfs_with(|cfg| cfg.xattr = true);
+        fs.init(FsOptions::empty()).unwrap();
+        fs::write(source.as_path().join("file"), b"data").unwrap();
+        let ctx = Context::default();
+        let inode = fs
+            .lookup(&ctx, 1, &CString::new("file").unwrap())
+            .unwrap()
+            .inode;
+        assert!(fs.listxattr(&ctx, inode, 0).is_ok());
+
+        let mut cfg = fs.runtime_config();
+        cfg.xattr = false;
+        fs.update_config(cfg).unwrap();
+        let err = fs.listxattr(&ctx, inode, 0).err().unwrap();
+        assert_eq!(errno_of(&err), Some(libc::ENOSYS));
+    }
+}
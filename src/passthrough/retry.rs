@@ -0,0 +1,218 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bounded retry of idempotent operations failing with transient errors.
+//!
+//! Network file systems like NFS return `ESTALE`, and sometimes `EIO`, for a few seconds during
+//! server failover, and guests see random failures if they are passed through immediately. So
+//! operations only reading the host, stat, open, getxattr and readdir, are retried with
+//! exponential backoff for errnos allowed by [RetryPolicy], at most `max_attempts` times.
+//! Operations modifying the host, like write, create or rename, are never retried, as a failed
+//! attempt may have taken effect.
+
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::api::clock::Clock;
+use crate::api::errno::{errno_of, ErrnoContext};
+
+/// Policy to retry idempotent operations failing with transient errors, see
+/// `Config::retry_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config-serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct RetryPolicy {
+    /// Max number of attempts of an operation, including the first one, at least one.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled before each following retry.
+    pub backoff: Duration,
+    /// Whether to retry operations failing with `EIO`, besides `ESTALE`.
+    pub retry_eio: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(10),
+            retry_eio: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn is_transient(&self, err: &io::Error) -> bool {
+        match errno_of(err) {
+            Some(libc::ESTALE) => true,
+            Some(libc::EIO) => self.retry_eio,
+            _ => false,
+        }
+    }
+}
+
+/// Statistics of operations retried after transient errors.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RetryStats {
+    /// Number of retries of all operations.
+    pub retries: u64,
+    /// Number of operations succeeding after being retried.
+    pub recovered: u64,
+    /// Number of operations still failing after the max number of attempts.
+    pub exhausted: u64,
+}
+
+pub(super) struct Retrier {
+    policy: Option<RetryPolicy>,
+    clock: Arc<dyn Clock>,
+    retries: AtomicU64,
+    recovered: AtomicU64,
+    exhausted: AtomicU64,
+}
+
+impl Retrier {
+    pub(super) fn new(policy: Option<RetryPolicy>, clock: Arc<dyn Clock>) -> Self {
+        Retrier {
+            policy,
+            clock,
+            retries: AtomicU64::new(0),
+            recovered: AtomicU64::new(0),
+            exhausted: AtomicU64::new(0),
+        }
+    }
+
+    pub(super) fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Run the idempotent operation `op`, retrying it on transient errors.
+    pub(super) fn run<T>(&self, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let policy = match self.policy.as_ref() {
+            Some(policy) => policy,
+            None => return op(),
+        };
+
+        let mut backoff = policy.backoff;
+        let mut attempts = 1;
+        loop {
+            match op() {
+                Err(e) if policy.is_transient(&e) => {
+                    if attempts >= policy.max_attempts {
+                        if attempts > 1 {
+                            self.exhausted.fetch_add(1, Ordering::Relaxed);
+                            warn!("fuse: giving up after {} attempts, {}", attempts, e);
+                            return Err(e).with_errno_context(|| {
+                                format!("transient error after {} attempts", attempts)
+                            });
+                        }
+                        return Err(e);
+                    }
+                    self.retries.fetch_add(1, Ordering::Relaxed);
+                    self.clock.sleep(backoff);
+                    backoff *= 2;
+                    attempts += 1;
+                }
+                Ok(v) if attempts > 1 => {
+                    self.recovered.fetch_add(1, Ordering::Relaxed);
+                    return Ok(v);
+                }
+                res => return res,
+            }
+        }
+    }
+
+    pub(super) fn stats(&self) -> RetryStats {
+        RetryStats {
+            retries: self.retries.load(Ordering::Relaxed),
+            recovered: self.recovered.load(Ordering::Relaxed),
+            exhausted: self.exhausted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::clock::ManualClock;
+
+    fn policy(retry_eio: bool) -> Option<RetryPolicy> {
+        Some(RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(10),
+            retry_eio,
+        })
+    }
+
+    // Run an operation failing with `errnos`, then succeeding, and return its result and the
+    // number of attempts.
+    fn run(retrier: &Retrier, errnos: &[i32]) -> (io::Result<()>, usize) {
+        let mut attempts = 0;
+        let res = retrier.run(|| {
+            attempts += 1;
+            match errnos.get(attempts - 1) {
+                Some(errno) => Err(io::Error::from_raw_os_error(*errno)),
+                None => Ok(()),
+            }
+        });
+        (res, attempts)
+    }
+
+    #[test]
+    fn test_retrier() {
+        let clock = Arc::new(ManualClock::default());
+        let retrier = Retrier::new(policy(false), clock.clone());
+        let (res, attempts) = run(&retrier, &[libc::ESTALE, libc::ESTALE]);
+        assert!(res.is_ok());
+        assert_eq!(attempts, 3);
+        // Backoff doubles before each retry.
+        assert_eq!(clock.monotonic(), Duration::from_millis(30));
+
+        // Errors not allowed by the policy aren't retried.
+        let (res, attempts) = run(&retrier, &[libc::EIO]);
+        assert_eq!(errno_of(&res.unwrap_err()), Some(libc::EIO));
+        assert_eq!(attempts, 1);
+        let (res, attempts) = run(&retrier, &[libc::ESTALE, libc::ENOENT]);
+        assert_eq!(errno_of(&res.unwrap_err()), Some(libc::ENOENT));
+        assert_eq!(attempts, 2);
+
+        // The errno is kept when giving up.
+        let (res, attempts) = run(&retrier, &[libc::ESTALE; 4]);
+        let err = res.unwrap_err();
+        assert_eq!(errno_of(&err), Some(libc::ESTALE));
+        assert!(err.to_string().contains("after 3 attempts"));
+        assert_eq!(attempts, 3);
+        assert_eq!(
+            retrier.stats(),
+            RetryStats {
+                retries: 5,
+                recovered: 1,
+                exhausted: 1,
+            }
+        );
+
+        // Transient errors wrapped with a context message are retried too.
+        let mut attempts = 0;
+        let res = retrier.run(|| {
+            attempts += 1;
+            match attempts {
+                1 => Err(io::Error::from_raw_os_error(libc::ESTALE)).errno_context("stat"),
+                _ => Ok(()),
+            }
+        });
+        assert!(res.is_ok());
+        assert_eq!(attempts, 2);
+
+        let retrier = Retrier::new(policy(true), clock.clone());
+        assert!(run(&retrier, &[libc::EIO, libc::ESTALE]).0.is_ok());
+        let retrier = Retrier::new(None, clock.clone());
+        let now = clock.monotonic();
+        assert_eq!(run(&retrier, &[libc::ESTALE]).1, 1);
+        assert_eq!(clock.monotonic(), now);
+        assert_eq!(retrier.stats(), RetryStats::default());
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Recovery of the root directory when the shared directory is replaced on the host.
+
+use super::*;
+use crate::api::errno::errno_of;
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    /// Send invalidations for inodes dropped by `reopen_root()`, or truncated on the host, to
+    /// `notifier`.
+    ///
+    /// Inode numbers passed to the notifier are the ones of this file system, callers mounting
+    /// it through a `Vfs` should translate them.
+    pub fn with_notifier(mut self, notifier: Arc<dyn Notifier>) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    /// Get the generation of the root directory, which is bumped by `reopen_root()`.
+    pub fn root_generation(&self) -> u64 {
+        self.root_generation.load(Ordering::Acquire)
+    }
+
+    /// Re-resolve the configured root directory and switch to it.
+    ///
+    /// When the shared directory is unlinked and recreated on the host, the root fd keeps
+    /// pointing to the deleted directory and all requests fail until the root is reopened.
+    /// The root inode is swapped atomically, all other inodes hang off the old tree and are
+    /// dropped, so requests referencing them fail with `EBADF` instead of reaching the wrong
+    /// tree. The root generation is bumped, and the attached notifier, if any, is asked to
+    /// invalidate the root and the dropped inodes.
+    pub fn reopen_root(&self) -> io::Result<()> {
+        if self.cfg.sandbox {
+            return Err(fuse_errno(
+                libc::EOPNOTSUPP,
+                "the root directory isn't resolved again in sandbox mode",
+            ));
+        }
+        let _guard = self.root_lock.lock().unwrap();
+
+        let (file_or_handle, st, ids_altkey, handle_altkey) = self.open_root().map_err(|e| {
+            error!("fuse: reopen_root: failed to get file or handle: {:?}", e);
+            e
+        })?;
+
+        let dropped: Vec<Inode> = {
+            let mut shards = self.inode_map.get_all_mut();
+            let root_shard = self.inode_map.shard_of(fuse::ROOT_ID);
+            let refcount = shards[root_shard]
+                .remove(&fuse::ROOT_ID)
+                .map(|data| data.refcount.load(Ordering::Acquire))
+                .unwrap_or(2);
+            let mut dropped = Vec::new();
+            for inodes in shards.iter_mut() {
+                let inodes_of_shard = inodes.keys().copied().collect::<Vec<_>>();
+                for inode in inodes_of_shard.iter() {
+                    inodes.remove(inode);
+                }
+                dropped.extend(inodes_of_shard);
+            }
+            self.path_hints.clear();
+            self.dir_fds.clear();
+            let root_shard = self.inode_map.shard_of_alt(&ids_altkey);
+            InodeMap::insert_locked(
+                shards[root_shard].deref_mut(),
+                fuse::ROOT_ID,
+                InodeData::new(
+                    fuse::ROOT_ID,
+                    file_or_handle,
+                    refcount,
+                    ids_altkey,
+                    &st.get_stat(),
+                ),
+                ids_altkey,
+                handle_altkey,
+            );
+            self.inode_map
+                .root_shard
+                .store(root_shard, Ordering::Release);
+            self.root_generation.fetch_add(1, Ordering::AcqRel);
+            dropped
+        };
+
+        info!(
+            "fuse: reopened root {}, dropped {} inodes",
+            self.cfg.root_dir,
+            dropped.len()
+        );
+        if let Some(notifier) = self.notifier.as_ref() {
+            notifier.inval_inode(fuse::ROOT_ID);
+            for inode in dropped {
+                notifier.inval_inode(inode);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Check whether `err` from an operation on the root directory is caused by a stale root.
+    pub(super) fn is_root_stale(&self, err: &io::Error) -> bool {
+        if !self.cfg.reopen_stale_root {
+            return false;
+        }
+
+        match errno_of(err) {
+            Some(libc::ESTALE) => true,
+            // Lookups in a deleted directory fail with ENOENT.
+            Some(libc::ENOENT) => self
+                .inode_map
+                .get(fuse::ROOT_ID)
+                .and_then(|data| data.get_file(&self.mount_fds).map(|f| Self::stat(&f, None)))
+                .map_or(true, |st| st.map_or(true, |st| st.st_nlink == 0)),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::filesystem::{Context, FileSystem};
+    use crate::passthrough::tests::passthroughfs_in;
+    use std::ffi::CString;
+    use std::fs;
+    use std::sync::Mutex;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[derive(Default)]
+    struct RecordNotifier {
+        inodes: Mutex<Vec<u64>>,
+    }
+
+    impl Notifier for RecordNotifier {
+        fn inval_inode(&self, ino: u64) {
+            self.inodes.lock().unwrap().push(ino);
+        }
+
+        fn inval_entry(&self, _parent: u64, _name: &CStr) {}
+    }
+
+    fn prepare_fs(reopen_stale_root: bool) -> (TempDir, PassthroughFs) {
+        let parent = TempDir::new().unwrap();
+        let source = parent.as_path().join("shared");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("file"), b"old").unwrap();
+
+        let fs = passthroughfs_in(&source, |cfg| cfg.reopen_stale_root = reopen_stale_root);
+
+        (parent, fs)
+    }
+
+    fn recreate_root(fs: &PassthroughFs) {
+        fs::remove_dir_all(&fs.cfg.root_dir).unwrap();
+        fs::create_dir(&fs.cfg.root_dir).unwrap();
+        fs::write(format!("{}/file", fs.cfg.root_dir), b"new").unwrap();
+    }
+
+    #[test]
+    fn test_reopen_root() {
+        let (_parent, fs) = prepare_fs(false);
+        let notifier = Arc::new(RecordNotifier::default());
+        let fs = fs.with_notifier(notifier.clone());
+        let ctx = Context::default();
+        let name = CString::new("file").unwrap();
+
+        let old = fs.lookup(&ctx, fuse::ROOT_ID, &name).unwrap();
+        assert_eq!(old.generation, 0);
+        assert_eq!(old.attr.st_size, 3);
+
+        recreate_root(&fs);
+        let err = fs.lookup(&ctx, fuse::ROOT_ID, &name).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+
+        fs.reopen_root().unwrap();
+        assert_eq!(fs.root_generation(), 1);
+        assert_eq!(
+            *notifier.inodes.lock().unwrap(),
+            vec![fuse::ROOT_ID, old.inode]
+        );
+
+        // Stale inodes fail cleanly instead of reaching the old tree.
+        let err = fs.getattr(&ctx, old.inode, None).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+        // The kernel may still forget the dropped inode.
+        fs.forget(&ctx, old.inode, 1);
+
+        let new = fs.lookup(&ctx, fuse::ROOT_ID, &name).unwrap();
+        assert_eq!(new.generation, 1);
+        assert_ne!(new.inode, old.inode);
+        assert!(fs.getattr(&ctx, fuse::ROOT_ID, None).is_ok());
+    }
+
+    #[test]
+    fn test_reopen_stale_root_automatically() {
+        let (_parent, fs) = prepare_fs(true);
+        let ctx = Context::default();
+        let name = CString::new("file").unwrap();
+
+        let old = fs.lookup(&ctx, fuse::ROOT_ID, &name).unwrap();
+        recreate_root(&fs);
+
+        let new = fs.lookup(&ctx, fuse::ROOT_ID, &name).unwrap();
+        assert_eq!(fs.root_generation(), 1);
+        assert_eq!(new.generation, 1);
+        assert_ne!(new.inode, old.inode);
+
+        // Missing entries in a live root don't trigger reopening.
+        let missing = CString::new("missing").unwrap();
+        let err = fs.lookup(&ctx, fuse::ROOT_ID, &missing).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        assert_eq!(fs.root_generation(), 1);
+    }
+}
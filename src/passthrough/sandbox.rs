@@ -0,0 +1,245 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Sandbox mode, serving requests without opening absolute paths.
+//!
+//! Security conscious deployments run the daemon under strict seccomp policies, denying opens of
+//! absolute paths once it serves requests. With `Config::sandbox`, `import()` captures an
+//! `O_PATH` fd of the shared directory, next to the one of `/proc/self/fd` probed when the file
+//! system was created, and changes the working directory of the process to `/proc/self/fd`.
+//! From then on, files are only opened relative to those fds: the root directory is opened as
+//! `.` of its fd, inodes are reopened by their fd number under `/proc/self/fd`, and syscalls
+//! taking paths, like the ones of extended attributes, get fd numbers relative to the working
+//! directory. Other paths under `/proc/self` are built relative to `/proc/self/fd` too.
+//!
+//! [PassthroughFs::enter_sandbox] then confines the process to the shared directory, so absolute
+//! paths don't resolve to host files anymore.
+
+use std::fmt;
+
+use caps::{CapSet, Capability, CapsHashSet};
+
+use super::*;
+
+/// Capabilities needed to serve requests of all guest users in sandbox mode: switching
+/// credentials, bypassing permission checks of the host, changing owners, creating device nodes
+/// and opening file handles.
+pub const SANDBOX_CAPABILITIES: &[Capability] = &[
+    Capability::CAP_CHOWN,
+    Capability::CAP_DAC_OVERRIDE,
+    Capability::CAP_DAC_READ_SEARCH,
+    Capability::CAP_FOWNER,
+    Capability::CAP_FSETID,
+    Capability::CAP_MKNOD,
+    Capability::CAP_SETGID,
+    Capability::CAP_SETUID,
+];
+
+// Set once the working directory of the process is `/proc/self/fd`, paths under `/proc/self`
+// are then built relative to it.
+static PROC_SELF_FD_CWD: AtomicBool = AtomicBool::new(false);
+
+// Whether paths of fds are relative to `/proc/self/fd` as working directory.
+pub(super) fn proc_self_fd_cwd() -> bool {
+    PROC_SELF_FD_CWD.load(Ordering::Relaxed)
+}
+
+// Get the path of `/proc/self/<name>`, relative to the working directory in sandbox mode.
+pub(super) fn proc_self_path(name: fmt::Arguments) -> String {
+    if proc_self_fd_cwd() {
+        format!("../{}", name)
+    } else {
+        format!("/proc/self/{}", name)
+    }
+}
+
+fn caps_error(e: caps::errors::Error) -> io::Error {
+    fuse_errno(libc::EPERM, format!("failed to drop capabilities, {}", e))
+}
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    // Capture the fd of the shared directory, the last absolute path opened, and move to
+    // `/proc/self/fd`.
+    pub(super) fn setup_sandbox(&self) -> io::Result<()> {
+        let proc = self.proc_self_fd()?;
+        let root = CString::new(self.cfg.root_dir.as_str())
+            .map_err(|_| fuse_errno(libc::EINVAL, "invalid root directory path"))?;
+        let root = Self::open_file(
+            libc::AT_FDCWD,
+            &root,
+            libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            0,
+        )?;
+        // Safe because this doesn't modify any memory and we check the return value.
+        if unsafe { libc::fchdir(proc) } < 0 {
+            return Err(io::Error::last_os_error()).errno_context("fchdir to /proc/self/fd");
+        }
+        PROC_SELF_FD_CWD.store(true, Ordering::Relaxed);
+        *self.sandbox_root.write().unwrap() = Some(root);
+
+        Ok(())
+    }
+
+    // Get the fd of the shared directory captured by `import()` in sandbox mode.
+    pub(super) fn sandbox_root_fd(&self) -> Option<RawFd> {
+        self.sandbox_root
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|f| f.as_raw_fd())
+    }
+
+    /// Confine the process to the shared directory, once imported in sandbox mode.
+    ///
+    /// The root directory of the process is changed to the shared directory, and all
+    /// capabilities but `keep` are dropped from the calling thread, including from its bounding
+    /// set, so they can't be regained. Threads created afterwards inherit the capabilities,
+    /// daemons should call it before spawning the threads of their service loop, once their
+    /// transport is set up and any saved state restored. [SANDBOX_CAPABILITIES] are the ones
+    /// needed to serve requests of all guest users.
+    ///
+    /// Restoring a saved state opens host paths, it fails once in the sandbox. `reopen_root()`
+    /// isn't supported in sandbox mode.
+    pub fn enter_sandbox(&self, keep: &[Capability]) -> io::Result<()> {
+        let root = self.sandbox_root_fd().ok_or_else(|| {
+            fuse_errno(
+                libc::EINVAL,
+                "not imported in sandbox mode, no root to enter",
+            )
+        })?;
+        let proc = self.proc_self_fd()?;
+
+        // Safe because these don't modify any memory and we check the return values.
+        unsafe {
+            if libc::fchdir(root) < 0 {
+                return Err(io::Error::last_os_error()).errno_context("fchdir to the root");
+            }
+            let dot = CStr::from_bytes_with_nul_unchecked(CURRENT_DIR_CSTR);
+            if libc::chroot(dot.as_ptr()) < 0 {
+                return Err(io::Error::last_os_error()).errno_context("chroot");
+            }
+            if libc::fchdir(proc) < 0 {
+                return Err(io::Error::last_os_error()).errno_context("fchdir to /proc/self/fd");
+            }
+        }
+
+        let keep: CapsHashSet = keep.iter().copied().collect();
+        for cap in caps::all().difference(&keep) {
+            caps::drop(None, CapSet::Bounding, *cap).map_err(caps_error)?;
+        }
+        caps::clear(None, CapSet::Ambient).map_err(caps_error)?;
+        let permitted = caps::read(None, CapSet::Permitted).map_err(caps_error)?;
+        let kept = permitted
+            .intersection(&keep)
+            .copied()
+            .collect::<CapsHashSet>();
+        caps::set(None, CapSet::Inheritable, CapsHashSet::new()).map_err(caps_error)?;
+        caps::set(None, CapSet::Effective, kept.clone()).map_err(caps_error)?;
+        caps::set(None, CapSet::Permitted, kept.clone()).map_err(caps_error)?;
+        info!(
+            "passthrough: entered sandbox {}, kept capabilities {:?}",
+            self.cfg.root_dir, kept
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abi::fuse_abi::ROOT_ID;
+    use crate::api::errno::errno_of;
+    use crate::api::filesystem::{Context, FileSystem, GetxattrReply, SetattrValid};
+    use crate::passthrough::tests::passthroughfs_in;
+    use std::path::Path;
+    use std::process::Command;
+    use vmm_sys_util::tempdir::TempDir;
+
+    const SANDBOX_DIR_ENV: &str = "FUSE_BACKEND_SANDBOX_TEST_DIR";
+
+    // Serve requests in a process confined to the directory passed by `test_sandbox()`. Absolute
+    // paths resolve within the shared directory, where `/proc` doesn't exist, so any of them
+    // makes the requests fail.
+    #[test]
+    #[ignore] // it's run in its own process by test_sandbox()
+    fn sandboxed_requests() {
+        let dir = match std::env::var(SANDBOX_DIR_ENV) {
+            Ok(dir) => dir,
+            Err(_) => return,
+        };
+        let fs = passthroughfs_in(Path::new(&dir), |cfg| {
+            cfg.sandbox = true;
+            cfg.xattr = true;
+        });
+        fs.enter_sandbox(SANDBOX_CAPABILITIES).unwrap();
+        assert!(!Path::new("/proc/self/fd").exists());
+        assert!(Path::new("/f").exists());
+        assert!(!caps::has_cap(None, CapSet::Effective, Capability::CAP_SYS_CHROOT).unwrap());
+        assert!(caps::has_cap(None, CapSet::Effective, Capability::CAP_CHOWN).unwrap());
+
+        let ctx = Context::default();
+        let name = |n: &str| CString::new(n).unwrap();
+        let entry = fs.lookup(&ctx, ROOT_ID, &name("f")).unwrap();
+        let (handle, _) = fs.open(&ctx, entry.inode, libc::O_RDWR as u32, 0).unwrap();
+        fs.release(&ctx, entry.inode, 0, handle.unwrap(), false, false, None)
+            .unwrap();
+        let (handle, _) = fs.opendir(&ctx, ROOT_ID, 0).unwrap();
+        fs.releasedir(&ctx, ROOT_ID, 0, handle.unwrap()).unwrap();
+
+        fs.setxattr(&ctx, entry.inode, &name("user.k"), b"v", 0)
+            .unwrap();
+        match fs.getxattr(&ctx, entry.inode, &name("user.k"), 16).unwrap() {
+            GetxattrReply::Value(v) => assert_eq!(v, b"v"),
+            _ => panic!("unexpected getxattr reply"),
+        }
+        fs.removexattr(&ctx, entry.inode, &name("user.k")).unwrap();
+
+        let mut attr = entry.attr;
+        attr.st_mode = 0o600;
+        fs.setattr(&ctx, entry.inode, attr, None, SetattrValid::MODE)
+            .unwrap();
+        fs.mkdir(&ctx, ROOT_ID, &name("d"), 0o755, 0).unwrap();
+        assert!(fs.reopen_root().is_err());
+    }
+
+    #[test]
+    fn test_sandbox() {
+        match caps::has_cap(None, CapSet::Effective, Capability::CAP_SYS_CHROOT) {
+            Ok(false) | Err(_) => {
+                println!("entering the sandbox needs CAP_SYS_CHROOT");
+                return;
+            }
+            Ok(true) => {}
+        }
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("f"), b"f").unwrap();
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "passthrough::sandbox::tests::sandboxed_requests",
+                "--ignored",
+                "--test-threads=1",
+            ])
+            .env(SANDBOX_DIR_ENV, source.as_path())
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}", stdout);
+        assert!(stdout.contains("1 passed"), "{}", stdout);
+        assert!(source.as_path().join("d").is_dir());
+    }
+
+    #[test]
+    fn test_proc_self_path() {
+        assert_eq!(
+            proc_self_path(format_args!("fdinfo/{}", 3)),
+            "/proc/self/fdinfo/3"
+        );
+        // Not imported in sandbox mode.
+        let fs = passthroughfs_in(Path::new("/"), |_| {});
+        let e = fs.enter_sandbox(&[]).unwrap_err();
+        assert_eq!(errno_of(&e), Some(libc::EINVAL));
+    }
+}
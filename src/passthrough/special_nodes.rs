@@ -0,0 +1,126 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Restrictions of special files created and opened by guests.
+//!
+//! Guests with `CAP_MKNOD` may create device nodes in the shared directory by mknod, which lets
+//! them reach host devices through the node whenever the daemon or another host process opens
+//! it. With `Config::no_device_nodes`, mknod of character and block devices fails with `EPERM`
+//! whatever the capabilities of the caller, and opening device nodes already in the shared
+//! directory fails with `ENXIO`: the `O_PATH` fd of the inode is never upgraded to an fd of the
+//! device, unless block devices are explicitly passed through by `Config::allow_blockdev_read`.
+//!
+//! FIFOs and sockets are harmless and may be created by default, `Config::no_fifo_nodes` and
+//! `Config::no_socket_nodes` make their mknod fail with `EPERM` too.
+
+use std::io;
+
+use super::PassthroughFs;
+use crate::BitmapSlice;
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    // Check that the guest may create a special file of `mode` by mknod.
+    pub(super) fn check_mknod(&self, mode: u32) -> io::Result<()> {
+        let denied = match mode & libc::S_IFMT {
+            libc::S_IFCHR | libc::S_IFBLK => self.cfg.no_device_nodes,
+            libc::S_IFIFO => self.cfg.no_fifo_nodes,
+            libc::S_IFSOCK => self.cfg.no_socket_nodes,
+            _ => false,
+        };
+        if denied {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        Ok(())
+    }
+
+    // Check that the `O_PATH` fd of an inode of `mode` may be upgraded to an fd opened with
+    // `flags`.
+    pub(super) fn check_special_open(&self, mode: u32, flags: i32) -> io::Result<()> {
+        let device = matches!(mode & libc::S_IFMT, libc::S_IFCHR | libc::S_IFBLK);
+        if device
+            && flags & libc::O_PATH == 0
+            && self.cfg.no_device_nodes
+            && !self.is_passthrough_blockdev(mode)
+        {
+            return Err(io::Error::from_raw_os_error(libc::ENXIO));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::prepare_passthroughfs_with;
+    use super::*;
+    use crate::abi::fuse_abi::ROOT_ID;
+    use crate::api::errno::errno_of;
+    use crate::api::filesystem::{Context, FileSystem, FsOptions};
+    use std::ffi::CString;
+
+    fn mknod(fs: &PassthroughFs, name: &str, mode: u32, rdev: u32) -> Option<i32> {
+        let name = CString::new(name).unwrap();
+        fs.mknod(&Context::default(), ROOT_ID, &name, mode | 0o600, rdev, 0)
+            .err()
+            .and_then(|e| errno_of(&e))
+    }
+
+    // makedev(1, 3), `/dev/null`.
+    const DEV_NULL: u32 = (1 << 8) | 3;
+
+    #[test]
+    fn test_no_device_nodes_mknod() {
+        let (source, fs) = prepare_passthroughfs_with(|cfg| cfg.no_device_nodes = true);
+        fs.init(FsOptions::empty()).unwrap();
+        assert_eq!(mknod(&fs, "c", libc::S_IFCHR, DEV_NULL), Some(libc::EPERM));
+        assert_eq!(mknod(&fs, "b", libc::S_IFBLK, DEV_NULL), Some(libc::EPERM));
+        assert!(!source.as_path().join("c").exists());
+        assert!(!source.as_path().join("b").exists());
+        // FIFOs, sockets and regular files are still allowed.
+        assert_eq!(mknod(&fs, "p", libc::S_IFIFO, 0), None);
+        assert_eq!(mknod(&fs, "s", libc::S_IFSOCK, 0), None);
+        assert_eq!(mknod(&fs, "f", libc::S_IFREG, 0), None);
+
+        let (_source, fs) = prepare_passthroughfs_with(|cfg| {
+            cfg.no_fifo_nodes = true;
+            cfg.no_socket_nodes = true;
+        });
+        fs.init(FsOptions::empty()).unwrap();
+        assert_eq!(mknod(&fs, "p", libc::S_IFIFO, 0), Some(libc::EPERM));
+        assert_eq!(mknod(&fs, "s", libc::S_IFSOCK, 0), Some(libc::EPERM));
+        assert_eq!(mknod(&fs, "f", libc::S_IFREG, 0), None);
+    }
+
+    #[test]
+    fn test_no_device_nodes_open() {
+        let (source, fs) = prepare_passthroughfs_with(|cfg| cfg.no_device_nodes = true);
+        fs.init(FsOptions::empty()).unwrap();
+        let path = CString::new(source.as_path().join("null").to_str().unwrap()).unwrap();
+        // Safe because this doesn't modify any memory and we check the return value.
+        if unsafe { libc::mknod(path.as_ptr(), libc::S_IFCHR | 0o666, DEV_NULL as _) } != 0 {
+            // Creating device nodes needs CAP_MKNOD.
+            return;
+        }
+        std::fs::write(source.as_path().join("f"), b"data").unwrap();
+
+        let ctx = Context::default();
+        let lookup = |name: &str| {
+            fs.lookup(&ctx, ROOT_ID, &CString::new(name).unwrap())
+                .unwrap()
+                .inode
+        };
+        let null = lookup("null");
+        for flags in [libc::O_RDONLY, libc::O_WRONLY, libc::O_RDWR] {
+            let e = fs.open(&ctx, null, flags as u32, 0).err().unwrap();
+            assert_eq!(errno_of(&e), Some(libc::ENXIO));
+        }
+        // Attributes of the node are still served.
+        fs.getattr(&ctx, null, None).unwrap();
+
+        // Regular files are unaffected.
+        let f = lookup("f");
+        let (fh, _) = fs.open(&ctx, f, libc::O_RDWR as u32, 0).unwrap();
+        fs.release(&ctx, f, 0, fh.unwrap(), false, false, None)
+            .unwrap();
+        assert_eq!(std::fs::read(source.as_path().join("f")).unwrap(), b"data");
+    }
+}
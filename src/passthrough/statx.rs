@@ -0,0 +1,547 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Query file attributes together with the mount id of backing files.
+//!
+//! The mount id is part of the key identifying inodes, and statx(2) is the cheapest way to get
+//! it. But statx(2) is unavailable on old kernels, and some file systems, such as FUSE-on-FUSE
+//! and network file systems, reject it or omit the mount id. So [StatHelper] falls back to
+//! fstatat(2) and gets the mount id from `/proc/self/fdinfo`, or synthesizes one from `st_dev`.
+//! The fallback strategy is detected once per backing device and recorded for diagnostics.
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fs;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use super::sandbox::proc_self_path;
+use super::InodeStat;
+use crate::abi::fuse_abi::{Statx, SxTime};
+use crate::api::EMPTY_CSTR;
+
+// Flag of mount ids synthesized from `st_dev`, to avoid conflicts with real mount ids.
+const DEVICE_MNT_ID_FLAG: u64 = 1 << 63;
+
+/// How the mount id of backing files is obtained.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MntIdStrategy {
+    /// Query attributes and mount id by statx(2).
+    Statx,
+    /// Parse the mount id from `/proc/self/fdinfo`.
+    FdInfo,
+    /// Synthesize the mount id from `st_dev`.
+    Device,
+}
+
+// Syscalls used to query attributes, abstracted for testing.
+pub(super) trait StatSyscalls: Send + Sync {
+    // Return attributes of `fd`, and the mount id if supported by the file system.
+    fn statx(&self, fd: RawFd) -> io::Result<(libc::stat64, Option<u64>)>;
+
+    // Return the `mask` fields of `fd` synchronized as told by `flags`, for replies to statx.
+    fn statx_fields(&self, fd: RawFd, flags: i32, mask: u32) -> io::Result<libc::statx>;
+
+    fn fstat(&self, fd: RawFd) -> io::Result<libc::stat64>;
+
+    // Return content of `/proc/self/fdinfo/<fd>`.
+    fn fdinfo(&self, fd: RawFd) -> io::Result<String>;
+}
+
+pub(super) struct LibcStatSyscalls;
+
+impl StatSyscalls for LibcStatSyscalls {
+    fn statx(&self, fd: RawFd) -> io::Result<(libc::stat64, Option<u64>)> {
+        let stx = self.statx_fields(fd, 0, libc::STATX_BASIC_STATS | libc::STATX_MNT_ID)?;
+        let mnt_id = if stx.stx_mask & libc::STATX_MNT_ID != 0 {
+            Some(stx.stx_mnt_id)
+        } else {
+            None
+        };
+
+        Ok((statx_to_stat64(&stx), mnt_id))
+    }
+
+    fn statx_fields(&self, fd: RawFd, flags: i32, mask: u32) -> io::Result<libc::statx> {
+        // Safe because this is a constant value and a valid C string.
+        let empty = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
+        let mut stx = MaybeUninit::<libc::statx>::zeroed();
+
+        // Safe because the kernel will only write data in `stx` and we check the return value.
+        let res = unsafe {
+            libc::statx(
+                fd,
+                empty.as_ptr(),
+                libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW | flags,
+                mask,
+                stx.as_mut_ptr(),
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safe because the kernel guarantees that the struct is now fully initialized.
+        Ok(unsafe { stx.assume_init() })
+    }
+
+    fn fstat(&self, fd: RawFd) -> io::Result<libc::stat64> {
+        // Safe because this is a constant value and a valid C string.
+        let empty = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
+        let mut st = MaybeUninit::<libc::stat64>::zeroed();
+
+        // Safe because the kernel will only write data in `st` and we check the return value.
+        let res = unsafe {
+            libc::fstatat64(
+                fd,
+                empty.as_ptr(),
+                st.as_mut_ptr(),
+                libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
+            )
+        };
+        if res >= 0 {
+            // Safe because the kernel guarantees that the struct is now fully initialized.
+            Ok(unsafe { st.assume_init() })
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    fn fdinfo(&self, fd: RawFd) -> io::Result<String> {
+        fs::read_to_string(proc_self_path(format_args!("fdinfo/{}", fd)))
+    }
+}
+
+// Same encoding as `makedev()` of glibc.
+fn makedev(major: u32, minor: u32) -> libc::dev_t {
+    let major = major as libc::dev_t;
+    let minor = minor as libc::dev_t;
+    ((major & 0xffff_f000) << 32)
+        | ((major & 0x0000_0fff) << 8)
+        | ((minor & 0xffff_ff00) << 12)
+        | (minor & 0x0000_00ff)
+}
+
+fn statx_to_stat64(stx: &libc::statx) -> libc::stat64 {
+    // Safe because all fields of `stat64` are plain integers.
+    let mut st: libc::stat64 = unsafe { std::mem::zeroed() };
+    st.st_dev = makedev(stx.stx_dev_major, stx.stx_dev_minor);
+    st.st_ino = stx.stx_ino;
+    st.st_nlink = stx.stx_nlink as _;
+    st.st_mode = stx.stx_mode as _;
+    st.st_uid = stx.stx_uid;
+    st.st_gid = stx.stx_gid;
+    st.st_rdev = makedev(stx.stx_rdev_major, stx.stx_rdev_minor);
+    st.st_size = stx.stx_size as _;
+    st.st_blksize = stx.stx_blksize as _;
+    st.st_blocks = stx.stx_blocks as _;
+    st.st_atime = stx.stx_atime.tv_sec;
+    st.st_atime_nsec = stx.stx_atime.tv_nsec as _;
+    st.st_mtime = stx.stx_mtime.tv_sec;
+    st.st_mtime_nsec = stx.stx_mtime.tv_nsec as _;
+    st.st_ctime = stx.stx_ctime.tv_sec;
+    st.st_ctime_nsec = stx.stx_ctime.tv_nsec as _;
+    st
+}
+
+fn statx_to_fuse(stx: &libc::statx) -> Statx {
+    let time = |t: &libc::statx_timestamp| SxTime {
+        tv_sec: t.tv_sec,
+        tv_nsec: t.tv_nsec,
+        reserved: 0,
+    };
+    Statx {
+        mask: stx.stx_mask,
+        blksize: stx.stx_blksize,
+        attributes: stx.stx_attributes,
+        nlink: stx.stx_nlink,
+        uid: stx.stx_uid,
+        gid: stx.stx_gid,
+        mode: stx.stx_mode,
+        ino: stx.stx_ino,
+        size: stx.stx_size,
+        blocks: stx.stx_blocks,
+        attributes_mask: stx.stx_attributes_mask,
+        atime: time(&stx.stx_atime),
+        btime: time(&stx.stx_btime),
+        ctime: time(&stx.stx_ctime),
+        mtime: time(&stx.stx_mtime),
+        rdev_major: stx.stx_rdev_major,
+        rdev_minor: stx.stx_rdev_minor,
+        dev_major: stx.stx_dev_major,
+        dev_minor: stx.stx_dev_minor,
+        ..Default::default()
+    }
+}
+
+fn parse_fdinfo_mnt_id(fdinfo: &str) -> Option<u64> {
+    fdinfo
+        .lines()
+        .find_map(|l| l.strip_prefix("mnt_id:"))
+        .and_then(|v| v.trim().parse().ok())
+}
+
+fn is_statx_unsupported(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) | Some(libc::EINVAL) | Some(libc::EPERM)
+    )
+}
+
+/// Query attributes and mount id of files, falling back gracefully if statx(2) is unusable.
+pub(super) struct StatHelper {
+    sys: Box<dyn StatSyscalls>,
+    // Set once statx(2) is found unimplemented by the kernel.
+    no_statx: AtomicBool,
+    // Strategy in use for each backing device.
+    strategies: Mutex<HashMap<libc::dev_t, MntIdStrategy>>,
+}
+
+impl Default for StatHelper {
+    fn default() -> Self {
+        Self::with_syscalls(Box::new(LibcStatSyscalls))
+    }
+}
+
+impl StatHelper {
+    pub(super) fn with_syscalls(sys: Box<dyn StatSyscalls>) -> Self {
+        StatHelper {
+            sys,
+            no_statx: AtomicBool::new(false),
+            strategies: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get attributes and mount id of `file`.
+    pub(super) fn stat(&self, file: &impl AsRawFd) -> io::Result<InodeStat> {
+        let fd = file.as_raw_fd();
+        if !self.no_statx.load(Ordering::Relaxed) {
+            match self.sys.statx(fd) {
+                Ok((stat, Some(mnt_id))) => {
+                    self.record(stat.st_dev, MntIdStrategy::Statx);
+                    return Ok(InodeStat { stat, mnt_id });
+                }
+                Ok((stat, None)) => {
+                    let mnt_id = self.fallback_mnt_id(fd, &stat);
+                    return Ok(InodeStat { stat, mnt_id });
+                }
+                Err(e) if is_statx_unsupported(&e) => {
+                    if e.raw_os_error() == Some(libc::ENOSYS) {
+                        warn!("fuse: statx is not implemented, fall back to fstatat");
+                        self.no_statx.store(true, Ordering::Relaxed);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let stat = self.sys.fstat(fd)?;
+        let mnt_id = self.fallback_mnt_id(fd, &stat);
+        Ok(InodeStat { stat, mnt_id })
+    }
+
+    /// Get the `mask` fields of `file` for a reply to statx, synchronized as told by the
+    /// `AT_STATX_*` `flags`. Only the basic fields are filled without statx(2).
+    pub(super) fn statx(&self, file: &impl AsRawFd, flags: u32, mask: u32) -> io::Result<Statx> {
+        let fd = file.as_raw_fd();
+        if !self.no_statx.load(Ordering::Relaxed) {
+            let flags = flags as i32 & libc::AT_STATX_SYNC_TYPE;
+            match self
+                .sys
+                .statx_fields(fd, flags, mask | libc::STATX_BASIC_STATS)
+            {
+                Ok(stx) => return Ok(statx_to_fuse(&stx)),
+                Err(e) if is_statx_unsupported(&e) => {
+                    if e.raw_os_error() == Some(libc::ENOSYS) {
+                        warn!("fuse: statx is not implemented, fall back to fstatat");
+                        self.no_statx.store(true, Ordering::Relaxed);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.sys.fstat(fd).map(Statx::from)
+    }
+
+    /// Get mount id strategies in use, keyed by backing device.
+    pub(super) fn strategies(&self) -> Vec<(libc::dev_t, MntIdStrategy)> {
+        let mut strategies: Vec<_> = self
+            .strategies
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(dev, s)| (*dev, *s))
+            .collect();
+        strategies.sort_unstable_by_key(|(dev, _)| *dev);
+        strategies
+    }
+
+    fn fallback_mnt_id(&self, fd: RawFd, stat: &libc::stat64) -> u64 {
+        let known = self.strategies.lock().unwrap().get(&stat.st_dev).copied();
+        if known != Some(MntIdStrategy::Device) {
+            let mnt_id = self
+                .sys
+                .fdinfo(fd)
+                .ok()
+                .and_then(|info| parse_fdinfo_mnt_id(&info));
+            if let Some(mnt_id) = mnt_id {
+                self.record(stat.st_dev, MntIdStrategy::FdInfo);
+                return mnt_id;
+            }
+        }
+
+        self.record(stat.st_dev, MntIdStrategy::Device);
+        DEVICE_MNT_ID_FLAG | stat.st_dev
+    }
+
+    fn record(&self, dev: libc::dev_t, strategy: MntIdStrategy) {
+        let mut strategies = self.strategies.lock().unwrap();
+        let old = strategies.insert(dev, strategy);
+        if old != Some(strategy) {
+            info!(
+                "fuse: use mount id strategy {:?} for device 0x{:x}",
+                strategy, dev
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::passthrough::InodeAltKey;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[derive(Default)]
+    struct MockStatSyscalls {
+        statx_errno: Option<i32>,
+        no_statx_mnt_id: bool,
+        no_fdinfo: bool,
+        statx_calls: Arc<AtomicUsize>,
+    }
+
+    impl StatSyscalls for MockStatSyscalls {
+        fn statx(&self, fd: RawFd) -> io::Result<(libc::stat64, Option<u64>)> {
+            self.statx_calls.fetch_add(1, Ordering::SeqCst);
+            if let Some(errno) = self.statx_errno {
+                return Err(io::Error::from_raw_os_error(errno));
+            }
+            let (st, mnt_id) = LibcStatSyscalls.statx(fd)?;
+            Ok((st, mnt_id.filter(|_| !self.no_statx_mnt_id)))
+        }
+
+        fn statx_fields(&self, fd: RawFd, flags: i32, mask: u32) -> io::Result<libc::statx> {
+            self.statx_calls.fetch_add(1, Ordering::SeqCst);
+            if let Some(errno) = self.statx_errno {
+                return Err(io::Error::from_raw_os_error(errno));
+            }
+            LibcStatSyscalls.statx_fields(fd, flags, mask)
+        }
+
+        fn fstat(&self, fd: RawFd) -> io::Result<libc::stat64> {
+            LibcStatSyscalls.fstat(fd)
+        }
+
+        fn fdinfo(&self, fd: RawFd) -> io::Result<String> {
+            if self.no_fdinfo {
+                Err(io::Error::from_raw_os_error(libc::ENOENT))
+            } else {
+                LibcStatSyscalls.fdinfo(fd)
+            }
+        }
+    }
+
+    // Stat two files through `helper`, and check identity keys of them.
+    fn check_identity(helper: &StatHelper) -> u64 {
+        let file1 = TempFile::new().unwrap().into_file();
+        let file2 = TempFile::new().unwrap().into_file();
+        let st1 = helper.stat(&file1).unwrap();
+        let st1_dup = helper.stat(&file1.try_clone().unwrap()).unwrap();
+        let st2 = helper.stat(&file2).unwrap();
+
+        let expected = LibcStatSyscalls.fstat(file1.as_raw_fd()).unwrap();
+        assert_eq!(st1.get_stat().st_ino, expected.st_ino);
+        assert_eq!(st1.get_stat().st_dev, expected.st_dev);
+        assert_eq!(st1.get_stat().st_mode, expected.st_mode);
+        assert_eq!(
+            InodeAltKey::ids_from_stat(&st1),
+            InodeAltKey::ids_from_stat(&st1_dup)
+        );
+        assert_ne!(
+            InodeAltKey::ids_from_stat(&st1),
+            InodeAltKey::ids_from_stat(&st2)
+        );
+        assert_eq!(st1.get_mnt_id(), st2.get_mnt_id());
+        st1.get_mnt_id()
+    }
+
+    fn strategy(helper: &StatHelper) -> MntIdStrategy {
+        let strategies = helper.strategies();
+        assert_eq!(strategies.len(), 1);
+        strategies[0].1
+    }
+
+    #[test]
+    fn test_parse_fdinfo_mnt_id() {
+        let info = "pos:\t0\nflags:\t02400000\nmnt_id:\t29\nino:\t1234\n";
+        assert_eq!(parse_fdinfo_mnt_id(info), Some(29));
+        assert_eq!(parse_fdinfo_mnt_id("pos:\t0\n"), None);
+        assert_eq!(parse_fdinfo_mnt_id("mnt_id:\tx\n"), None);
+    }
+
+    #[test]
+    fn test_stat_helper_statx() {
+        let helper = StatHelper::default();
+        let mnt_id = check_identity(&helper);
+        assert_eq!(strategy(&helper), MntIdStrategy::Statx);
+        assert_eq!(mnt_id & DEVICE_MNT_ID_FLAG, 0);
+
+        // All strategies agree on the mount id.
+        let helper = StatHelper::with_syscalls(Box::new(MockStatSyscalls {
+            no_statx_mnt_id: true,
+            ..Default::default()
+        }));
+        assert_eq!(check_identity(&helper), mnt_id);
+        assert_eq!(strategy(&helper), MntIdStrategy::FdInfo);
+    }
+
+    #[test]
+    fn test_stat_helper_no_statx() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let helper = StatHelper::with_syscalls(Box::new(MockStatSyscalls {
+            statx_errno: Some(libc::ENOSYS),
+            statx_calls: calls.clone(),
+            ..Default::default()
+        }));
+        check_identity(&helper);
+        assert_eq!(strategy(&helper), MntIdStrategy::FdInfo);
+        // statx is only probed once if unimplemented by the kernel.
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let helper = StatHelper::with_syscalls(Box::new(MockStatSyscalls {
+            statx_errno: Some(libc::EOPNOTSUPP),
+            statx_calls: calls.clone(),
+            ..Default::default()
+        }));
+        check_identity(&helper);
+        assert_eq!(strategy(&helper), MntIdStrategy::FdInfo);
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        let helper = StatHelper::with_syscalls(Box::new(MockStatSyscalls {
+            statx_errno: Some(libc::EBADF),
+            ..Default::default()
+        }));
+        let file = TempFile::new().unwrap().into_file();
+        assert_eq!(
+            helper.stat(&file).err().unwrap().raw_os_error(),
+            Some(libc::EBADF)
+        );
+    }
+
+    #[test]
+    fn test_stat_helper_statx_fields() {
+        use crate::abi::fuse_abi::{STATX_BASIC_STATS, STATX_BTIME};
+
+        let file = TempFile::new().unwrap().into_file();
+        let host = LibcStatSyscalls
+            .statx_fields(file.as_raw_fd(), 0, libc::STATX_ALL)
+            .unwrap();
+
+        let helper = StatHelper::default();
+        let stx = helper.statx(&file, 0, STATX_BTIME).unwrap();
+        assert_eq!(stx.mask & STATX_BASIC_STATS, STATX_BASIC_STATS);
+        assert_eq!(stx.mask & STATX_BTIME, host.stx_mask & libc::STATX_BTIME);
+        assert_eq!(stx.ino, host.stx_ino);
+        assert_eq!(
+            (stx.btime.tv_sec, stx.btime.tv_nsec),
+            (host.stx_btime.tv_sec, host.stx_btime.tv_nsec)
+        );
+
+        // Without statx(2), only the basic fields are filled.
+        let calls = Arc::new(AtomicUsize::new(0));
+        let helper = StatHelper::with_syscalls(Box::new(MockStatSyscalls {
+            statx_errno: Some(libc::ENOSYS),
+            statx_calls: calls.clone(),
+            ..Default::default()
+        }));
+        for _ in 0..2 {
+            let stx = helper.statx(&file, 0, STATX_BTIME).unwrap();
+            assert_eq!(stx.mask, STATX_BASIC_STATS);
+            assert_eq!(stx.ino, host.stx_ino);
+            assert_eq!(stx.btime.tv_sec, 0);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_stat_helper_device() {
+        let helper = StatHelper::with_syscalls(Box::new(MockStatSyscalls {
+            statx_errno: Some(libc::EOPNOTSUPP),
+            no_fdinfo: true,
+            ..Default::default()
+        }));
+        let mnt_id = check_identity(&helper);
+        assert_eq!(strategy(&helper), MntIdStrategy::Device);
+        let file = TempFile::new().unwrap().into_file();
+        let st = LibcStatSyscalls.fstat(file.as_raw_fd()).unwrap();
+        assert_eq!(mnt_id, DEVICE_MNT_ID_FLAG | st.st_dev);
+
+        let helper = StatHelper::with_syscalls(Box::new(MockStatSyscalls {
+            no_statx_mnt_id: true,
+            no_fdinfo: true,
+            ..Default::default()
+        }));
+        assert_eq!(check_identity(&helper), mnt_id);
+        assert_eq!(strategy(&helper), MntIdStrategy::Device);
+    }
+
+    #[test]
+    fn test_passthroughfs_inode_identity_fallback() {
+        use crate::api::filesystem::{Context, FileSystem};
+        use crate::passthrough::{Config, PassthroughFs};
+        use std::ffi::CString;
+        use vmm_sys_util::tempdir::TempDir;
+
+        for (statx_errno, no_fdinfo, expected) in [
+            (None, false, MntIdStrategy::Statx),
+            (Some(libc::ENOSYS), false, MntIdStrategy::FdInfo),
+            (Some(libc::EOPNOTSUPP), true, MntIdStrategy::Device),
+        ] {
+            let source = TempDir::new().unwrap();
+            std::fs::write(source.as_path().join("a"), b"a").unwrap();
+            std::fs::hard_link(source.as_path().join("a"), source.as_path().join("b")).unwrap();
+            std::fs::write(source.as_path().join("c"), b"c").unwrap();
+            let cfg = Config {
+                root_dir: source.as_path().to_str().unwrap().to_string(),
+                ..Default::default()
+            };
+            let mut fs = PassthroughFs::<()>::new(cfg).unwrap();
+            fs.stat_helper = StatHelper::with_syscalls(Box::new(MockStatSyscalls {
+                statx_errno,
+                no_fdinfo,
+                ..Default::default()
+            }));
+            fs.import().unwrap();
+
+            let ctx = Context::default();
+            let lookup = |name: &str| {
+                fs.lookup(&ctx, 1, &CString::new(name).unwrap())
+                    .unwrap()
+                    .inode
+            };
+            let a = lookup("a");
+            assert_eq!(lookup("a"), a);
+            assert_eq!(lookup("b"), a);
+            assert_ne!(lookup("c"), a);
+            let strategies = fs.mnt_id_strategies();
+            assert_eq!(strategies.len(), 1);
+            assert_eq!(strategies[0].1, expected);
+        }
+    }
+}
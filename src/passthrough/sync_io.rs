@@ -9,48 +9,71 @@ use std::ffi::{CStr, CString};
 use std::fs::File;
 use std::io;
 use std::mem::{self, size_of, ManuallyDrop, MaybeUninit};
+use std::os::unix::fs::FileExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, UNIX_EPOCH};
 
+use super::dir_snapshot::{take_snapshot, DirSnapshot, DirState};
+use super::dirent::{getdents_buf_size, mode_to_dtype, TypeFallback};
 use super::*;
-use crate::abi::fuse_abi::{CreateIn, FOPEN_IN_KILL_SUIDGID, WRITE_KILL_PRIV};
+use crate::abi::fuse_abi::{CreateIn, Statx, FOPEN_IN_KILL_SUIDGID, WRITE_KILL_PRIV};
 #[cfg(any(feature = "vhost-user-fs", feature = "virtiofs"))]
 use crate::abi::virtio_fs;
+use crate::api::attr_cache::Notifier;
+use crate::api::errno::errno_of;
 use crate::api::filesystem::{
-    Context, DirEntry, Entry, FileSystem, FsOptions, GetxattrReply, ListxattrReply, OpenOptions,
-    SetattrValid, ZeroCopyReader, ZeroCopyWriter,
+    Context, DirEntry, Entry, FileLock, FileSystem, FsOptions, GetxattrReply, IoctlReply,
+    ListxattrReply, OpenOptions, SetattrValid, ZeroCopyReader, ZeroCopyWriter,
 };
+use crate::api::scratch;
 use crate::bytes_to_cstr;
+use crate::transport::FileVolatileSlice;
 #[cfg(any(feature = "vhost-user-fs", feature = "virtiofs"))]
 use crate::transport::FsCacheReqHandler;
 
 impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
-    fn open_inode(&self, inode: Inode, mut flags: i32) -> io::Result<File> {
-        // When writeback caching is enabled, the kernel may send read requests even if the
-        // userspace program opened the file write-only. So we need to ensure that we have opened
-        // the file for reading as well as writing.
-        let writeback = self.writeback.load(Ordering::Relaxed);
-        if writeback && flags & libc::O_ACCMODE == libc::O_WRONLY {
-            flags &= !libc::O_ACCMODE;
-            flags |= libc::O_RDWR;
-        }
-
-        // When writeback caching is enabled the kernel is responsible for handling `O_APPEND`.
-        // However, this breaks atomicity as the file may have changed on disk, invalidating the
-        // cached copy of the data in the kernel and the offset that the kernel thinks is the end of
-        // the file. Just allow this for now as it is the user's responsibility to enable writeback
-        // caching only for directories that are not shared. It also means that we need to clear the
-        // `O_APPEND` flag.
-        if writeback && flags & libc::O_APPEND != 0 {
-            flags &= !libc::O_APPEND;
-        }
+    pub(super) fn open_inode(&self, inode: Inode, flags: i32) -> io::Result<File> {
+        let flags = self.host_open_flags(flags);
 
         let data = self.inode_map.get(inode)?;
-        let file = data.get_file(&self.mount_fds)?;
+        self.retry
+            .run(|| {
+                let file = data.get_file(&self.mount_fds)?;
+
+                // O_NOATIME is only permitted for the owner of the file or with CAP_FOWNER,
+                // silently fall back to a normal open if it's not permitted.
+                if self.cfg.atime_policy == AtimePolicy::NoAtime {
+                    match self.reopen_fd(file.as_raw_fd(), flags | libc::O_NOATIME, data.mode) {
+                        Err(e) if e.raw_os_error() == Some(libc::EPERM) => {}
+                        res => return res,
+                    }
+                }
 
-        Self::open_proc_file(&self.proc_self_fd, file.as_raw_fd(), flags, data.mode)
+                self.reopen_fd(file.as_raw_fd(), flags, data.mode)
+            })
+            .with_errno_context(|| format!("open inode {}", inode))
+    }
+
+    // Probe the timestamp granularity of the root directory, falling back to nanoseconds.
+    fn detect_time_gran(&self) -> u32 {
+        let res = self
+            .open_inode(fuse::ROOT_ID, libc::O_RDONLY | libc::O_DIRECTORY)
+            .and_then(|dir| time_gran::probe_time_gran(&*self.time_gran_sys, dir.as_raw_fd()));
+        match res {
+            Ok(gran) => {
+                info!(
+                    "fuse: timestamp granularity of backing file system is {}ns",
+                    gran
+                );
+                gran
+            }
+            Err(e) => {
+                warn!("fuse: failed to detect timestamp granularity, {}", e);
+                1
+            }
+        }
     }
 
     fn do_readdir(
@@ -59,44 +82,60 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         handle: Handle,
         size: u32,
         offset: u64,
+        resolve_type: bool,
         add_entry: &mut dyn FnMut(DirEntry, RawFd) -> io::Result<usize>,
     ) -> io::Result<()> {
         if size == 0 {
             return Ok(());
         }
 
-        let mut buf = Vec::<u8>::with_capacity(size as usize);
         let data = self.get_dirdata(handle, inode, libc::O_RDONLY)?;
+        let budget = if resolve_type {
+            self.cfg.dtype_fallback_budget
+        } else {
+            0
+        };
+        let mut types = TypeFallback::new(self.dir_sys.as_ref(), budget);
+
+        // Handles opened for each request, without opendir, can't keep snapshots.
+        if self.cfg.snapshot_readdir && !self.no_opendir.load(Ordering::Relaxed) {
+            if let Some(snapshot) = self.dir_snapshot(&data)? {
+                let dir = data.get_handle_raw_fd();
+                for (i, (off, entry)) in snapshot.entries_from(offset).enumerate() {
+                    let type_ = types.resolve(dir, &entry.name, entry.type_);
+                    let res = add_entry(
+                        DirEntry {
+                            ino: entry.ino,
+                            offset: off,
+                            type_,
+                            name: entry.name.to_bytes(),
+                        },
+                        dir,
+                    );
+                    match res {
+                        Ok(0) => break,
+                        Ok(_) => {}
+                        // Entries removed from the host since can't be looked up by readdirplus.
+                        Err(e) if errno_of(&e) == Some(libc::ENOENT) => {}
+                        // Same as below, errors can only be signaled before storing any entry.
+                        Err(e) if i == 0 => return Err(e),
+                        Err(_) => break,
+                    }
+                }
+                return Ok(());
+            }
+        }
 
+        // Readdir requests resolve types themselves, readdirplus requests get them from lookups.
+        let plus = !resolve_type;
+        let mut buf = Vec::<u8>::with_capacity(getdents_buf_size(size, plus));
         {
             // Since we are going to work with the kernel offset, we have to acquire the file lock
             // for both the `lseek64` and `getdents64` syscalls to ensure that no other thread
             // changes the kernel offset while we are using it.
             let (guard, dir) = data.get_file_mut();
-
-            // Safe because this doesn't modify any memory and we check the return value.
-            let res =
-                unsafe { libc::lseek64(dir.as_raw_fd(), offset as libc::off64_t, libc::SEEK_SET) };
-            if res < 0 {
-                return Err(io::Error::last_os_error());
-            }
-
-            // Safe because the kernel guarantees that it will only write to `buf` and we check the
-            // return value.
-            let res = unsafe {
-                libc::syscall(
-                    libc::SYS_getdents64,
-                    dir.as_raw_fd(),
-                    buf.as_mut_ptr() as *mut LinuxDirent64,
-                    size as libc::c_int,
-                )
-            };
-            if res < 0 {
-                return Err(io::Error::last_os_error());
-            }
-
-            // Safe because we trust the value returned by kernel.
-            unsafe { buf.set_len(res as usize) };
+            self.retry
+                .run(|| self.dir_sys.getdents(dir.as_raw_fd(), offset, &mut buf))?;
 
             // Explicitly drop the lock so that it's not held while we fill in the fuse buffer.
             mem::drop(guard);
@@ -136,21 +175,21 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
                 // name without null terminators, the dentry with more than 1
                 // null terminators added by readdirplus doesn't satisfy the
                 // path walking.
-                let name = bytes_to_cstr(name)
-                    .map_err(|e| {
-                        error!("fuse: do_readdir: {:?}", e);
-                        io::Error::from_raw_os_error(libc::EINVAL)
-                    })?
-                    .to_bytes();
+                let name = bytes_to_cstr(name).map_err(|e| {
+                    error!("fuse: do_readdir: {:?}", e);
+                    io::Error::from_raw_os_error(libc::EINVAL)
+                })?;
+                let dir = data.get_handle_raw_fd();
+                let type_ = types.resolve(dir, name, u32::from(dirent64.d_ty));
 
                 add_entry(
                     DirEntry {
                         ino: dirent64.d_ino,
                         offset: dirent64.d_off as u64,
-                        type_: u32::from(dirent64.d_ty),
-                        name,
+                        type_,
+                        name: name.to_bytes(),
                     },
-                    data.get_handle_raw_fd(),
+                    dir,
                 )
             };
 
@@ -174,6 +213,31 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         Ok(())
     }
 
+    // Get the snapshot of directory handle `data`, taken if it's not read yet. Return `None` if
+    // the directory is too large to snapshot.
+    fn dir_snapshot(&self, data: &HandleData) -> io::Result<Option<Arc<DirSnapshot>>> {
+        let mut state = data.dir_state.lock().unwrap();
+        if let DirState::Unread = *state {
+            // Hold the file lock as the kernel offset of the handle is changed.
+            let (_guard, dir) = data.get_file_mut();
+            *state = match take_snapshot(
+                self.dir_sys.as_ref(),
+                dir.as_raw_fd(),
+                self.cfg.snapshot_readdir_max_entries,
+                &self.dir_snapshots,
+                &self.yield_points,
+            )? {
+                Some(snapshot) => DirState::Snapshot(Arc::new(snapshot)),
+                None => DirState::Streaming,
+            };
+        }
+
+        match &*state {
+            DirState::Snapshot(snapshot) => Ok(Some(snapshot.clone())),
+            _ => Ok(None),
+        }
+    }
+
     fn do_open(
         &self,
         inode: Inode,
@@ -190,74 +254,171 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         let file = self.open_inode(inode, flags as i32)?;
         drop(killpriv);
 
+        let mode = self.inode_map.get(inode)?.mode;
+        let opts = self.open_options(&file, flags, mode, None);
+
         let data = HandleData::new(inode, file);
         let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
         self.handle_map.insert(handle, data);
 
-        let mut opts = OpenOptions::empty();
-        match self.cfg.cache_policy {
-            // We only set the direct I/O option on files.
-            CachePolicy::Never => opts.set(
-                OpenOptions::DIRECT_IO,
-                flags & (libc::O_DIRECTORY as u32) == 0,
-            ),
-            CachePolicy::Always => opts |= OpenOptions::KEEP_CACHE,
-            _ => {}
-        };
-
         Ok((Some(handle), opts))
     }
 
-    fn do_getattr(
+    // Call `stat` on a descriptor of `inode`, the one of `handle` unless the guest opens files
+    // without handles.
+    fn stat_inode<T>(
         &self,
+        data: &InodeData,
         inode: Inode,
         handle: Option<Handle>,
-    ) -> io::Result<(libc::stat64, Duration)> {
-        let st;
-        let fd;
-        let data = self.inode_map.get(inode).map_err(|e| {
-            error!("fuse: do_getattr ino {} Not find err {:?}", inode, e);
-            e
-        })?;
-
+        stat: impl Fn(RawFd) -> io::Result<T>,
+    ) -> io::Result<T> {
         // kernel sends 0 as handle in case of no_open, and it depends on fuse server to handle
         // this case correctly.
         if !self.no_open.load(Ordering::Relaxed) && handle.is_some() {
             // Safe as we just checked handle
             let hd = self.handle_map.get(handle.unwrap(), inode)?;
-            fd = hd.get_handle_raw_fd();
-            st = Self::stat_fd(fd, None);
+            let fd = hd.get_handle_raw_fd();
+            self.retry.run(|| stat(fd))
         } else {
             match &data.file_or_handle {
-                FileOrHandle::File(f) => {
-                    fd = f.as_raw_fd();
-                    st = Self::stat_fd(fd, None);
-                }
+                FileOrHandle::File(f) => self.retry.run(|| stat(f.as_raw_fd())),
                 FileOrHandle::Handle(_h) => {
                     let file = data.get_file(&self.mount_fds)?;
-                    fd = file.as_raw_fd();
-                    st = Self::stat_fd(fd, None);
+                    self.retry.run(|| stat(file.as_raw_fd()))
                 }
             }
         }
+    }
 
-        let st = st.map_err(|e| {
-            error!(
-                "fuse: do_getattr stat failed ino {} fd: {:?} err {:?}",
-                inode, fd, e
-            );
+    // Stat `data` again after a change not replying attributes, to record the attributes after
+    // the change, unless disabled by `Config::no_restat`. The change succeeded, so failures are
+    // ignored.
+    fn restat(&self, data: &InodeData) {
+        if self.cfg.no_restat {
+            return;
+        }
+        if let Ok(mut st) = self.stat_inode(data, data.inode, None, |fd| Self::stat_fd(fd, None)) {
+            self.report_attr(data.inode, &mut st);
+            data.update_attr(&st);
+        }
+    }
+
+    fn do_getattr(
+        &self,
+        inode: Inode,
+        handle: Option<Handle>,
+    ) -> io::Result<(libc::stat64, Duration)> {
+        let data = self.inode_map.get(inode).map_err(|e| {
+            error!("fuse: do_getattr ino {} Not find err {:?}", inode, e);
             e
         })?;
 
-        Ok((st, self.cfg.attr_timeout))
+        let mut st = self
+            .stat_inode(&data, inode, handle, |fd| Self::stat_fd(fd, None))
+            .map_err(|e| {
+                error!("fuse: do_getattr stat failed ino {} err {:?}", inode, e);
+                e
+            })?;
+        self.report_attr(inode, &mut st);
+        data.update_attr(&st);
+
+        Ok((st, self.runtime_config().attr_timeout))
+    }
+
+    fn do_statx(
+        &self,
+        inode: Inode,
+        handle: Option<Handle>,
+        flags: u32,
+        mask: u32,
+    ) -> io::Result<(Statx, Duration)> {
+        if self.cfg.no_statx {
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+        }
+        let data = self.inode_map.get(inode)?;
+        let stx = self.stat_inode(&data, inode, handle, |fd| {
+            self.stat_helper.statx(&fd, flags, mask)
+        })?;
+
+        // Owners, block size and size of block devices are reported like getattr does.
+        let mut st = libc::stat64::from(stx);
+        self.report_attr(inode, &mut st);
+        data.update_attr(&st);
+
+        Ok((stx.with_stat(st), self.runtime_config().attr_timeout))
+    }
+
+    // A read coming up short of both the request and the size last reported to the guest means
+    // the backing file has been truncated on the host, and the guest page cache may still hold
+    // stale pages beyond the new end of file. Refresh the size and ask the guest to drop them.
+    pub(super) fn check_short_read(&self, inode: Inode, fd: RawFd, end: u64) {
+        let data = match self.inode_map.get(inode) {
+            Ok(data) => data,
+            Err(_) => return,
+        };
+        if end >= data.size.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let size = match Self::stat_fd(fd, None) {
+            Ok(st) => st.st_size as u64,
+            Err(e) => {
+                warn!("fuse: short read on inode {} but stat failed: {}", inode, e);
+                return;
+            }
+        };
+        let old = data.size.swap(size, Ordering::Relaxed);
+        if size < old {
+            debug!(
+                "fuse: inode {} truncated on host from {} to {} bytes",
+                inode, old, size
+            );
+            if let Some(notifier) = self.notifier.as_ref() {
+                notifier.inval_inode(inode);
+            }
+        }
+    }
+
+    // Move at most `size` bytes of write data into `fd` at `offset` with splice, return the number
+    // of bytes moved. The rest must be copied, if the data isn't in a pipe or the file doesn't
+    // support splice.
+    fn splice_write(
+        &self,
+        r: &mut dyn ZeroCopyReader,
+        fd: RawFd,
+        size: usize,
+        offset: u64,
+    ) -> io::Result<usize> {
+        let mut done = 0;
+        while done < size {
+            match r.splice_to(fd, size - done, offset + done as u64) {
+                Ok(Some(0)) | Ok(None) => break,
+                Ok(Some(n)) => done += n,
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) || done > 0 => {
+                    trace!("fuse: splice write fall back to copy, {}", e);
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(done)
     }
 
     fn do_unlink(&self, parent: Inode, name: &CStr, flags: libc::c_int) -> io::Result<()> {
         let data = self.inode_map.get(parent)?;
-        let file = data.get_file(&self.mount_fds)?;
+        let file = self.dir_file(&data)?;
+        let victim = if flags & libc::AT_REMOVEDIR != 0 {
+            self.cached_victim(file.as_raw_fd(), name)
+        } else {
+            None
+        };
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe { libc::unlinkat(file.as_raw_fd(), name.as_ptr(), flags) };
         if res == 0 {
+            self.path_hints.remove_entry(parent, name);
+            self.drop_victim(victim);
             Ok(())
         } else {
             Err(io::Error::last_os_error())
@@ -274,12 +435,15 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
         if !no_open {
             self.handle_map.get(handle, inode)
         } else {
+            if let Some(dir) = self.cached_dir(&*self.inode_map.get(inode)?)? {
+                return Ok(dir);
+            }
             let file = self.open_inode(inode, (flags | libc::O_DIRECTORY) as i32)?;
             Ok(Arc::new(HandleData::new(inode, file)))
         }
     }
 
-    fn get_data(
+    pub(super) fn get_data(
         &self,
         handle: Handle,
         inode: Inode,
@@ -293,6 +457,52 @@ impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
             Ok(Arc::new(HandleData::new(inode, file)))
         }
     }
+
+    /// Push `size` bytes at `offset` of `inode` into the guest page cache through `notifier`,
+    /// to warm it up before the guest reads them.
+    ///
+    /// Return the number of bytes pushed, which is less than requested at the end of the file or
+    /// if the guest doesn't have the inode cached.
+    pub fn push_file(
+        &self,
+        notifier: &dyn Notifier,
+        inode: Inode,
+        offset: u64,
+        size: u64,
+    ) -> io::Result<u64> {
+        self.do_push_file(notifier, inode, inode, offset, size)
+    }
+
+    pub(super) fn do_push_file(
+        &self,
+        notifier: &dyn Notifier,
+        inode: Inode,
+        nodeid: u64,
+        offset: u64,
+        size: u64,
+    ) -> io::Result<u64> {
+        const PUSH_CHUNK_SIZE: u64 = 0x20000;
+
+        let file = self.open_inode(inode, libc::O_RDONLY)?;
+        let mut buf = vec![0u8; size.min(PUSH_CHUNK_SIZE) as usize];
+        let mut pushed = 0;
+        while pushed < size {
+            let len = (size - pushed).min(buf.len() as u64) as usize;
+            let count = file.read_at(&mut buf[..len], offset + pushed)?;
+            if count == 0 {
+                break;
+            }
+            // Safe because `buf` outlives the slice.
+            let data = unsafe { FileVolatileSlice::new(buf.as_mut_ptr(), count) };
+            let stored = notifier.store(nodeid, offset + pushed, &[data])?;
+            pushed += stored as u64;
+            if stored < count {
+                break;
+            }
+        }
+
+        Ok(pushed)
+    }
 }
 
 impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
@@ -300,14 +510,23 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
     type Handle = Handle;
 
     fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
+        // Options negotiated here can't change until the next mount.
+        let _guard = self.runtime_lock.lock().unwrap();
+        let runtime = self.runtime_config();
         if self.cfg.do_import {
             self.import()?;
         }
 
-        let mut opts = FsOptions::DO_READDIRPLUS | FsOptions::READDIRPLUS_AUTO;
+        let mut opts = FsOptions::empty();
+        if !self.cfg.no_readdirplus {
+            opts |= FsOptions::DO_READDIRPLUS;
+            if self.cfg.readdirplus_auto {
+                opts |= FsOptions::READDIRPLUS_AUTO;
+            }
+        }
         // !cfg.do_import means we are under vfs, in which case capable is already
         // negotiated and must be honored.
-        if (!self.cfg.do_import || self.cfg.writeback)
+        if (!self.cfg.do_import || runtime.writeback)
             && capable.contains(FsOptions::WRITEBACK_CACHE)
         {
             opts |= FsOptions::WRITEBACK_CACHE;
@@ -334,17 +553,73 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
             self.killpriv_v2.store(true, Ordering::Relaxed);
         }
 
+        // Switching supplementary groups needs privileges, so it's opt-in even under a Vfs.
+        if self.cfg.create_supp_group && capable.contains(FsOptions::CREATE_SUPP_GROUP) {
+            opts |= FsOptions::CREATE_SUPP_GROUP;
+            self.supp_group.store(true, Ordering::Relaxed);
+        }
+
+        if self.cfg.posix_acl && runtime.xattr && capable.contains(FsOptions::POSIX_ACL) {
+            opts |= FsOptions::POSIX_ACL;
+            if capable.contains(FsOptions::DONT_MASK) {
+                opts |= FsOptions::DONT_MASK;
+            }
+            self.posix_acl.store(true, Ordering::Relaxed);
+        }
+
         if capable.contains(FsOptions::PERFILE_DAX) {
             opts |= FsOptions::PERFILE_DAX;
             self.perfile_dax.store(true, Ordering::Relaxed);
         }
 
+        if capable.contains(FsOptions::ATOMIC_OPEN) {
+            opts |= FsOptions::ATOMIC_OPEN;
+        }
+
+        if self.cfg.posix_locks && capable.contains(FsOptions::POSIX_LOCKS) {
+            opts |= FsOptions::POSIX_LOCKS;
+            if capable.contains(FsOptions::FLOCK_LOCKS) {
+                opts |= FsOptions::FLOCK_LOCKS;
+            }
+        }
+
+        if self.cfg.announce_submounts && capable.contains(FsOptions::SUBMOUNTS) {
+            opts |= FsOptions::SUBMOUNTS;
+            self.announce_submounts.store(true, Ordering::Relaxed);
+        }
+
+        let gran = match self.cfg.time_gran {
+            Some(gran) => gran,
+            None => self.detect_time_gran(),
+        };
+        self.time_gran.store(gran, Ordering::Relaxed);
+        self.initialized.store(true, Ordering::Release);
+
         Ok(opts)
     }
 
+    fn time_gran(&self) -> u32 {
+        self.time_gran.load(Ordering::Relaxed)
+    }
+
+    fn interrupt(&self, _ctx: &Context, unique: u64) {
+        self.lock_waiters.interrupt(unique);
+    }
+
+    fn forget_all(&self) {
+        // Dropping the handles and inodes closes their fds, the root stays usable.
+        self.handle_map.clear();
+        self.inode_map.clear_except_root();
+        self.path_hints.clear();
+        self.dir_fds.clear();
+    }
+
     fn destroy(&self) {
+        self.initialized.store(false, Ordering::Release);
         self.handle_map.clear();
         self.inode_map.clear();
+        self.path_hints.clear();
+        self.dir_fds.clear();
 
         if let Err(e) = self.import() {
             error!("fuse: failed to destroy instance, {:?}", e);
@@ -357,11 +632,23 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         let file = data.get_file(&self.mount_fds)?;
 
         // Safe because this will only modify `out` and we check the return value.
-        match unsafe { libc::fstatvfs64(file.as_raw_fd(), out.as_mut_ptr()) } {
+        let mut st = match unsafe { libc::fstatvfs64(file.as_raw_fd(), out.as_mut_ptr()) } {
             // Safe because the kernel guarantees that `out` has been initialized.
-            0 => Ok(unsafe { out.assume_init() }),
-            _ => Err(io::Error::last_os_error()),
+            0 => unsafe { out.assume_init() },
+            _ => return Err(io::Error::last_os_error()),
+        };
+        if let Some((_, info)) = self.get_quota(inode) {
+            info.apply(&mut st);
         }
+
+        Ok(st)
+    }
+
+    fn syncfs(&self, _ctx: &Context, inode: Inode) -> io::Result<()> {
+        // Inodes are kept as `O_PATH` fds, which syncfs(2) rejects. The kernel sends roots of
+        // mounts, which are directories and may be opened for reading.
+        let file = self.open_inode(inode, libc::O_RDONLY)?;
+        sync_host_fs(&file).with_errno_context(|| format!("syncfs inode {}", inode))
     }
 
     fn lookup(&self, _ctx: &Context, parent: Inode, name: &CStr) -> io::Result<Entry> {
@@ -373,17 +660,59 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
     }
 
     fn forget(&self, _ctx: &Context, inode: Inode, count: u64) {
-        let mut inodes = self.inode_map.get_map_mut();
+        let mut inodes = self.inode_map.get_map_mut(inode);
 
-        Self::forget_one(&mut inodes, inode, count)
+        if Self::forget_one(&mut inodes, inode, count) {
+            self.path_hints.forget(inode);
+            self.dir_fds.remove(inode);
+        }
     }
 
-    fn batch_forget(&self, _ctx: &Context, requests: Vec<(Inode, u64)>) {
-        let mut inodes = self.inode_map.get_map_mut();
+    fn batch_forget(&self, _ctx: &Context, mut requests: Vec<(Inode, u64)>) {
+        // Group the requests by shard, so each shard is locked once per chunk of forgets
+        // instead of once per forget. The root inode is never forgotten and may move between
+        // shards, so skip it.
+        requests.retain(|(inode, _)| *inode != fuse::ROOT_ID);
+        requests.sort_by_key(|(inode, _)| self.inode_map.shard_of(*inode));
+
+        let mut dropped = Vec::new();
+        let mut rest = &requests[..];
+        while let Some((first, _)) = rest.first() {
+            let shard = self.inode_map.shard_of(*first);
+            let len = rest
+                .iter()
+                .take(FORGET_CHUNK)
+                .take_while(|(inode, _)| self.inode_map.shard_of(*inode) == shard)
+                .count();
+            let (chunk, tail) = rest.split_at(len);
+            rest = tail;
+
+            let mut inodes = self.inode_map.get_shard_mut(shard);
+            for (inode, count) in chunk {
+                if Self::forget_one(&mut inodes, *inode, *count) {
+                    dropped.push(*inode);
+                }
+            }
+        }
+
+        for inode in dropped {
+            self.path_hints.forget(inode);
+            self.dir_fds.remove(inode);
+        }
+    }
 
-        for (inode, count) in requests {
-            Self::forget_one(&mut inodes, inode, count)
+    fn debug_nlookup(&self, inode: Inode) -> Option<u64> {
+        // The root inode is never forgotten, so its refcount is meaningless.
+        if inode == fuse::ROOT_ID {
+            return None;
         }
+
+        Some(
+            self.inode_map
+                .get(inode)
+                .map(|data| data.refcount.load(Ordering::Acquire))
+                .unwrap_or(0),
+        )
     }
 
     fn opendir(
@@ -395,6 +724,8 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         if self.no_opendir.load(Ordering::Relaxed) {
             info!("fuse: opendir is not supported.");
             Err(io::Error::from_raw_os_error(libc::ENOSYS))
+        } else if let Some(res) = self.open_cached_dir(inode, flags)? {
+            Ok(res)
         } else {
             self.do_open(inode, flags | (libc::O_DIRECTORY as u32), 0)
         }
@@ -423,11 +754,14 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         let data = self.inode_map.get(parent)?;
 
         let res = {
-            let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+            let file = self.dir_file(&data)?;
+            let mode = self.create_mode(file.as_raw_fd(), mode, umask);
+            // Context reset when _fscreate is dropped, after credentials are restored.
+            let _fscreate = self.set_fscreate(&file)?;
+            let _creds = self.set_creds(ctx)?;
 
-            let file = data.get_file(&self.mount_fds)?;
             // Safe because this doesn't modify any memory and we check the return value.
-            unsafe { libc::mkdirat(file.as_raw_fd(), name.as_ptr(), mode & !umask) }
+            unsafe { libc::mkdirat(file.as_raw_fd(), name.as_ptr(), mode) }
         };
         if res == 0 {
             self.do_lookup(parent, name)
@@ -450,26 +784,43 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         offset: u64,
         add_entry: &mut dyn FnMut(DirEntry) -> io::Result<usize>,
     ) -> io::Result<()> {
+        self.readdir_counters.count(false);
         if self.no_readdir.load(Ordering::Relaxed) {
             return Ok(());
         }
-        self.do_readdir(inode, handle, size, offset, &mut |mut dir_entry, dir| {
-            dir_entry.ino = {
-                // Safe because do_readdir() has ensured dir_entry.name is a
-                // valid [u8] generated by CStr::to_bytes().
-                let name = unsafe {
-                    CStr::from_bytes_with_nul_unchecked(std::slice::from_raw_parts(
-                        &dir_entry.name[0],
-                        dir_entry.name.len() + 1,
-                    ))
+        self.do_readdir(
+            inode,
+            handle,
+            size,
+            offset,
+            true,
+            &mut |mut dir_entry, dir| {
+                dir_entry.ino = {
+                    // Safe because do_readdir() has ensured dir_entry.name is a
+                    // valid [u8] generated by CStr::to_bytes().
+                    let name = unsafe {
+                        CStr::from_bytes_with_nul_unchecked(std::slice::from_raw_parts(
+                            &dir_entry.name[0],
+                            dir_entry.name.len() + 1,
+                        ))
+                    };
+
+                    match Self::stat(&dir, Some(name)) {
+                        Ok(st) => st.st_ino,
+                        // Keep entries of snapshots removed from the host since.
+                        Err(e)
+                            if self.cfg.snapshot_readdir
+                                && e.raw_os_error() == Some(libc::ENOENT) =>
+                        {
+                            dir_entry.ino
+                        }
+                        Err(e) => return Err(e),
+                    }
                 };
 
-                let st = Self::stat(&dir, Some(name))?;
-                st.st_ino
-            };
-
-            add_entry(dir_entry)
-        })
+                add_entry(dir_entry)
+            },
+        )
     }
 
     fn readdirplus(
@@ -481,37 +832,51 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         offset: u64,
         add_entry: &mut dyn FnMut(DirEntry, Entry) -> io::Result<usize>,
     ) -> io::Result<()> {
+        self.readdir_counters.count(true);
         if self.no_readdir.load(Ordering::Relaxed) {
             return Ok(());
         }
-        self.do_readdir(inode, handle, size, offset, &mut |mut dir_entry, _dir| {
-            // Safe because do_readdir() has ensured dir_entry.name is a
-            // valid [u8] generated by CStr::to_bytes().
-            let name = unsafe {
-                CStr::from_bytes_with_nul_unchecked(std::slice::from_raw_parts(
-                    &dir_entry.name[0],
-                    dir_entry.name.len() + 1,
-                ))
-            };
-            let entry = self.do_lookup(inode, name)?;
-            let ino = entry.inode;
-            dir_entry.ino = entry.attr.st_ino;
-
-            add_entry(dir_entry, entry).map(|r| {
-                // true when size is not large enough to hold entry.
-                if r == 0 {
-                    // Release the refcount acquired by self.do_lookup().
-                    let mut inodes = self.inode_map.get_map_mut();
-                    Self::forget_one(&mut inodes, ino, 1);
+        self.do_readdir(
+            inode,
+            handle,
+            size,
+            offset,
+            false,
+            &mut |mut dir_entry, _dir| {
+                // Safe because do_readdir() has ensured dir_entry.name is a
+                // valid [u8] generated by CStr::to_bytes().
+                let name = unsafe {
+                    CStr::from_bytes_with_nul_unchecked(std::slice::from_raw_parts(
+                        &dir_entry.name[0],
+                        dir_entry.name.len() + 1,
+                    ))
+                };
+                let entry = self.do_lookup(inode, name)?;
+                let ino = entry.inode;
+                dir_entry.ino = entry.attr.st_ino;
+                if dir_entry.type_ == libc::DT_UNKNOWN as u32 {
+                    dir_entry.type_ = mode_to_dtype(entry.attr.st_mode);
                 }
-                r
-            })
-        })
+
+                add_entry(dir_entry, entry).map(|r| {
+                    // true when size is not large enough to hold entry.
+                    if r == 0 {
+                        // Release the refcount acquired by self.do_lookup().
+                        let mut inodes = self.inode_map.get_map_mut(ino);
+                        if Self::forget_one(&mut inodes, ino, 1) {
+                            self.path_hints.forget(ino);
+                            self.dir_fds.remove(ino);
+                        }
+                    }
+                    r
+                })
+            },
+        )
     }
 
     fn open(
         &self,
-        _ctx: &Context,
+        ctx: &Context,
         inode: Inode,
         flags: u32,
         fuse_flags: u32,
@@ -520,6 +885,7 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
             info!("fuse: open is not supported.");
             Err(io::Error::from_raw_os_error(libc::ENOSYS))
         } else {
+            self.validate_inode(ctx, inode)?;
             self.do_open(inode, flags, fuse_flags)
         }
     }
@@ -531,12 +897,15 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         _flags: u32,
         handle: Handle,
         _flush: bool,
-        _flock_release: bool,
+        flock_release: bool,
         _lock_owner: Option<u64>,
     ) -> io::Result<()> {
         if self.no_open.load(Ordering::Relaxed) {
             Err(io::Error::from_raw_os_error(libc::ENOSYS))
         } else {
+            if flock_release && self.cfg.posix_locks {
+                self.release_flock(inode, handle)?;
+            }
             self.do_release(inode, handle)
         }
     }
@@ -551,17 +920,16 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         self.validate_path_component(name)?;
 
         let dir = self.inode_map.get(parent)?;
-        let dir_file = dir.get_file(&self.mount_fds)?;
+        let dir_file = self.dir_file(&dir)?;
 
         let new_file = {
-            let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
-
-            Self::create_file_excl(
-                dir_file.as_raw_fd(),
-                name,
-                args.flags as i32,
-                args.mode & !(args.umask & 0o777),
-            )?
+            let mode = self.create_mode(dir_file.as_raw_fd(), args.mode, args.umask);
+            // Context reset when _fscreate is dropped, after credentials are restored.
+            let _fscreate = self.set_fscreate(&dir_file)?;
+            let _creds = self.set_creds(ctx)?;
+
+            let flags = self.host_open_flags(args.flags as i32);
+            Self::create_file_excl(dir_file.as_raw_fd(), name, flags, mode)?
         };
 
         let entry = self.do_lookup(parent, name)?;
@@ -580,11 +948,13 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
                     None
                 };
 
-                let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+                let _creds = self.set_creds(ctx)?;
                 self.open_inode(entry.inode, args.flags as i32)?
             }
         };
 
+        let opts = self.open_options(&file, args.flags, entry.attr.st_mode, Some(name));
+
         let ret_handle = if !self.no_open.load(Ordering::Relaxed) {
             let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
             let data = HandleData::new(entry.inode, file);
@@ -595,11 +965,123 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
             None
         };
 
-        let mut opts = OpenOptions::empty();
-        match self.cfg.cache_policy {
-            CachePolicy::Never => opts |= OpenOptions::DIRECT_IO,
-            CachePolicy::Always => opts |= OpenOptions::KEEP_CACHE,
-            _ => {}
+        Ok((entry, ret_handle, opts))
+    }
+
+    fn atomic_open(
+        &self,
+        ctx: &Context,
+        parent: Inode,
+        name: &CStr,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
+        self.validate_path_component(name)?;
+
+        let flags = args.flags as i32;
+        let new_file = if flags & libc::O_CREAT != 0 {
+            let dir = self.inode_map.get(parent)?;
+            let dir_file = self.dir_file(&dir)?;
+            let mode = self.create_mode(dir_file.as_raw_fd(), args.mode, args.umask);
+            // Context reset when _fscreate is dropped, after credentials are restored.
+            let _fscreate = self.set_fscreate(&dir_file)?;
+            let _creds = self.set_creds(ctx)?;
+
+            let flags = self.host_open_flags(flags | libc::O_CLOEXEC);
+            Self::create_file_excl(dir_file.as_raw_fd(), name, flags, mode)?
+        } else {
+            None
+        };
+
+        // Opened by `O_PATH` and stat()ed on the parent directory, as for lookup().
+        let entry = self.do_lookup(parent, name)?;
+        let file = match new_file {
+            Some(f) => f,
+            // Let the kernel follow symlinks and open special files by itself.
+            None if entry.attr.st_mode & libc::S_IFMT != libc::S_IFREG => {
+                return Ok((entry, None, OpenOptions::empty()));
+            }
+            None => {
+                // Cap restored when _killpriv is dropped
+                let _killpriv = if self.killpriv_v2.load(Ordering::Relaxed)
+                    && (args.fuse_flags & FOPEN_IN_KILL_SUIDGID != 0)
+                {
+                    self::drop_cap_fsetid()?
+                } else {
+                    None
+                };
+
+                let _creds = self.set_creds(ctx)?;
+                let flags = flags & !(libc::O_CREAT | libc::O_EXCL);
+                match self.open_inode(entry.inode, flags) {
+                    Ok(f) => f,
+                    Err(e) => {
+                        self.forget(ctx, entry.inode, 1);
+                        return Err(e);
+                    }
+                }
+            }
+        };
+
+        let opts = self.open_options(&file, args.flags, entry.attr.st_mode, Some(name));
+
+        let ret_handle = if !self.no_open.load(Ordering::Relaxed) {
+            let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
+            let data = HandleData::new(entry.inode, file);
+
+            self.handle_map.insert(handle, data);
+            Some(handle)
+        } else {
+            None
+        };
+
+        Ok((entry, ret_handle, opts))
+    }
+
+    fn tmpfile(
+        &self,
+        ctx: &Context,
+        parent: Inode,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
+        let dir = self.inode_map.get(parent)?;
+        let dir_file = self.dir_file(&dir)?;
+
+        let file = {
+            let mode = self.create_mode(dir_file.as_raw_fd(), args.mode, args.umask);
+            // `O_CREAT` is refused with `O_TMPFILE`, which the kernel may or may not pass on.
+            let flags = (args.flags as i32 & !libc::O_CREAT) | libc::O_TMPFILE | libc::O_CLOEXEC;
+            let flags = self.host_open_flags(flags);
+            // Context reset when _fscreate is dropped, after credentials are restored.
+            let _fscreate = self.set_fscreate(&dir_file)?;
+            let _creds = self.set_creds(ctx)?;
+
+            // Safe because this doesn't modify any memory and we check the return value.
+            let fd = unsafe {
+                libc::openat(
+                    dir_file.as_raw_fd(),
+                    CURRENT_DIR_CSTR.as_ptr() as *const libc::c_char,
+                    flags,
+                    mode,
+                )
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Safe because we just opened this fd.
+            unsafe { File::from_raw_fd(fd) }
+        };
+
+        let entry = self.register_tmpfile(&file)?;
+        let opts = self.open_options(&file, args.flags, entry.attr.st_mode, None);
+
+        let ret_handle = if !self.no_open.load(Ordering::Relaxed) {
+            let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
+            let data = HandleData::new(entry.inode, file);
+
+            self.handle_map.insert(handle, data);
+            Some(handle)
+        } else {
+            None
         };
 
         Ok((entry, ret_handle, opts))
@@ -650,7 +1132,7 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
 
     fn read(
         &self,
-        _ctx: &Context,
+        ctx: &Context,
         inode: Inode,
         handle: Handle,
         w: &mut dyn ZeroCopyWriter,
@@ -659,7 +1141,13 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         _lock_owner: Option<u64>,
         _flags: u32,
     ) -> io::Result<usize> {
+        self.validate_inode(ctx, inode)?;
         let data = self.get_data(handle, inode, libc::O_RDONLY)?;
+        if self.is_passthrough_blockdev(self.inode_map.get(inode)?.mode) {
+            return self
+                .read_blockdev(data.get_handle_raw_fd(), w, size, offset)
+                .with_errno_context(|| format!("read inode {} offset {}", inode, offset));
+        }
 
         // Manually implement File::try_clone() by borrowing fd of data.file instead of dup().
         // It's safe because the `data` variable's lifetime spans the whole function,
@@ -667,22 +1155,37 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         let f = unsafe { File::from_raw_fd(data.get_handle_raw_fd()) };
         let mut f = ManuallyDrop::new(f);
 
-        w.write_from(&mut *f, size as usize, offset)
+        let count = w
+            .write_from(&mut *f, size as usize, offset)
+            .with_errno_context(|| format!("read inode {} offset {}", inode, offset))?;
+        if count < size as usize {
+            self.check_short_read(inode, data.get_handle_raw_fd(), offset + count as u64);
+        }
+
+        Ok(count)
     }
 
     fn write(
         &self,
-        _ctx: &Context,
+        ctx: &Context,
         inode: Inode,
         handle: Handle,
         r: &mut dyn ZeroCopyReader,
         size: u32,
         offset: u64,
-        _lock_owner: Option<u64>,
-        _delayed_write: bool,
-        _flags: u32,
+        lock_owner: Option<u64>,
+        delayed_write: bool,
+        flags: u32,
         fuse_flags: u32,
     ) -> io::Result<usize> {
+        // POSIX locks are not implemented, so there's no conflicting lock to check for the owner.
+        trace!(
+            "fuse: write ino {} lock_owner {:?} delayed_write {}",
+            inode,
+            lock_owner,
+            delayed_write
+        );
+        self.validate_inode(ctx, inode)?;
         let data = self.get_data(handle, inode, libc::O_RDWR)?;
 
         // Manually implement File::try_clone() by borrowing fd of data.file instead of dup().
@@ -691,15 +1194,58 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         let f = unsafe { File::from_raw_fd(data.get_handle_raw_fd()) };
         let mut f = ManuallyDrop::new(f);
 
-        // Cap restored when _killpriv is dropped
-        let _killpriv =
-            if self.killpriv_v2.load(Ordering::Relaxed) && (fuse_flags & WRITE_KILL_PRIV != 0) {
-                self::drop_cap_fsetid()?
-            } else {
-                None
-            };
+        // Cap restored when _killpriv is dropped. Pages flushed from the writeback cache don't
+        // kill privileges, which has been done when the pages got dirtied.
+        let _killpriv = if self.killpriv_v2.load(Ordering::Relaxed)
+            && (fuse_flags & WRITE_KILL_PRIV != 0)
+            && !delayed_write
+        {
+            self::drop_cap_fsetid()?
+        } else {
+            None
+        };
+
+        // Writes flushed from the guest page cache are issued by the kernel, which removes the
+        // capabilities itself when the data is written to the cache.
+        if !delayed_write {
+            let inode_data = self.inode_map.get(inode)?;
+            self.kill_capability(ctx, &inode_data, data.get_handle_raw_fd(), Some(&data))?;
+        }
 
-        r.read_to(&mut *f, size as usize, offset)
+        let size = size as usize;
+        let spliced = if self.cfg.use_splice_write {
+            self.splice_write(r, data.get_handle_raw_fd(), size, offset)
+                .with_errno_context(|| format!("splice inode {} offset {}", inode, offset))?
+        } else {
+            0
+        };
+        let res = if spliced == size {
+            Ok(size)
+        } else {
+            match r.read_to(&mut *f, size - spliced, offset + spliced as u64) {
+                Ok(count) => Ok(spliced + count),
+                // Data spliced has reached the file already, report a short write like write(2).
+                Err(e) if spliced > 0 => {
+                    debug!(
+                        "fuse: write inode {} stops after splicing {} bytes, {}",
+                        inode, spliced, e
+                    );
+                    Ok(spliced)
+                }
+                res => {
+                    res.with_errno_context(|| format!("write inode {} offset {}", inode, offset))
+                }
+            }
+        };
+        if let (Ok(count), Some(acct)) = (res.as_ref(), self.write_accounting.as_ref()) {
+            acct.add_backend(*count as u64);
+        }
+        // Pages flushed from the writeback cache are synced by fsync requests of the kernel.
+        if matches!(res, Ok(count) if count > 0) && !delayed_write {
+            sync_written(data.get_handle_raw_fd(), flags)
+                .with_errno_context(|| format!("sync inode {}", inode))?;
+        }
+        res
     }
 
     fn getattr(
@@ -711,16 +1257,47 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         self.do_getattr(inode, handle)
     }
 
-    fn setattr(
+    fn statx(
         &self,
         _ctx: &Context,
         inode: Inode,
+        handle: Option<Handle>,
+        flags: u32,
+        mask: u32,
+    ) -> io::Result<(Statx, Duration)> {
+        self.do_statx(inode, handle, flags, mask)
+    }
+
+    fn setattr(
+        &self,
+        ctx: &Context,
+        inode: Inode,
         attr: libc::stat64,
         handle: Option<Handle>,
         valid: SetattrValid,
     ) -> io::Result<(libc::stat64, Duration)> {
+        self.validate_inode(ctx, inode)?;
         let inode_data = self.inode_map.get(inode)?;
 
+        let valid = if self.cfg.atime_policy != AtimePolicy::Passthrough
+            && valid.intersects(SetattrValid::ATIME | SetattrValid::ATIME_NOW)
+        {
+            let now = self
+                .clock
+                .now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or(0);
+            let times = inode_data.times.get();
+            let valid = self.cfg.atime_policy.filter_setattr(valid, &times, now);
+            if valid.is_empty() {
+                return self.do_getattr(inode, handle);
+            }
+            valid
+        } else {
+            valid
+        };
+
         enum Data {
             Handle(Arc<HandleData>, RawFd),
             ProcPath(CString),
@@ -729,7 +1306,7 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         let file = inode_data.get_file(&self.mount_fds)?;
         let data = if self.no_open.load(Ordering::Relaxed) {
             let pathname = CString::new(format!("{}", file.as_raw_fd()))
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                .map_err(|_| fuse_errno(libc::EINVAL, "invalid proc fd path"))?;
             Data::ProcPath(pathname)
         } else {
             // If we have a handle then use it otherwise get a new fd from the inode.
@@ -739,7 +1316,7 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
                 Data::Handle(hd, fd)
             } else {
                 let pathname = CString::new(format!("{}", file.as_raw_fd()))
-                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    .map_err(|_| fuse_errno(libc::EINVAL, "invalid proc fd path"))?;
                 Data::ProcPath(pathname)
             }
         };
@@ -750,7 +1327,7 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
                 match data {
                     Data::Handle(_, fd) => libc::fchmod(fd, attr.st_mode),
                     Data::ProcPath(ref p) => {
-                        libc::fchmodat(self.proc_self_fd.as_raw_fd(), p.as_ptr(), attr.st_mode, 0)
+                        libc::fchmodat(self.proc_self_fd()?, p.as_ptr(), attr.st_mode, 0)
                     }
                 }
             };
@@ -760,14 +1337,15 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         }
 
         if valid.intersects(SetattrValid::UID | SetattrValid::GID) {
+            let map = self.cfg.uid_gid_map.as_ref();
             let uid = if valid.contains(SetattrValid::UID) {
-                attr.st_uid
+                map.map_or(attr.st_uid, |m| m.host_uid(attr.st_uid))
             } else {
                 // Cannot use -1 here because these are unsigned values.
                 ::std::u32::MAX
             };
             let gid = if valid.contains(SetattrValid::GID) {
-                attr.st_gid
+                map.map_or(attr.st_gid, |m| m.host_gid(attr.st_gid))
             } else {
                 // Cannot use -1 here because these are unsigned values.
                 ::std::u32::MAX
@@ -803,10 +1381,14 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
 
             // Safe because this doesn't modify any memory and we check the return value.
             let res = match data {
-                Data::Handle(_, fd) => unsafe { libc::ftruncate(fd, attr.st_size) },
+                Data::Handle(ref hd, fd) => {
+                    self.kill_capability(ctx, &inode_data, fd, Some(hd))?;
+                    unsafe { libc::ftruncate(fd, attr.st_size) }
+                }
                 _ => {
                     // There is no `ftruncateat` so we need to get a new fd and truncate it.
                     let f = self.open_inode(inode, libc::O_NONBLOCK | libc::O_RDWR)?;
+                    self.kill_capability(ctx, &inode_data, f.as_raw_fd(), None)?;
                     unsafe { libc::ftruncate(f.as_raw_fd(), attr.st_size) }
                 }
             };
@@ -827,29 +1409,28 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
                 },
             ];
 
+            // Timestamps set by the guest are truncated to what the backing file system stores,
+            // so they compare equal to the ones read back later.
+            let gran = self.time_gran.load(Ordering::Relaxed);
             if valid.contains(SetattrValid::ATIME_NOW) {
                 tvs[0].tv_nsec = libc::UTIME_NOW;
             } else if valid.contains(SetattrValid::ATIME) {
                 tvs[0].tv_sec = attr.st_atime;
-                tvs[0].tv_nsec = attr.st_atime_nsec;
+                tvs[0].tv_nsec = time_gran::truncate_nsec(attr.st_atime_nsec, gran);
             }
 
             if valid.contains(SetattrValid::MTIME_NOW) {
                 tvs[1].tv_nsec = libc::UTIME_NOW;
             } else if valid.contains(SetattrValid::MTIME) {
                 tvs[1].tv_sec = attr.st_mtime;
-                tvs[1].tv_nsec = attr.st_mtime_nsec;
+                tvs[1].tv_nsec = time_gran::truncate_nsec(attr.st_mtime_nsec, gran);
             }
 
-            // Safe because this doesn't modify any memory and we check the return value.
-            let res = match data {
-                Data::Handle(_, fd) => unsafe { libc::futimens(fd, tvs.as_ptr()) },
-                Data::ProcPath(ref p) => unsafe {
-                    libc::utimensat(self.proc_self_fd.as_raw_fd(), p.as_ptr(), tvs.as_ptr(), 0)
-                },
-            };
-            if res < 0 {
-                return Err(io::Error::last_os_error());
+            match data {
+                Data::Handle(_, fd) => self.utimens_sys.futimens(fd, &tvs)?,
+                Data::ProcPath(ref p) => {
+                    self.utimens_sys.utimensat(self.proc_self_fd()?, p, &tvs)?
+                }
             }
         }
 
@@ -870,9 +1451,18 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
 
         let old_inode = self.inode_map.get(olddir)?;
         let new_inode = self.inode_map.get(newdir)?;
-        let old_file = old_inode.get_file(&self.mount_fds)?;
-        let new_file = new_inode.get_file(&self.mount_fds)?;
+        let old_file = self.dir_file(&old_inode)?;
+        let new_file = self.dir_file(&new_inode)?;
+        let exchange = flags & libc::RENAME_EXCHANGE != 0;
+        let victim = if exchange {
+            None
+        } else {
+            self.cached_victim(new_file.as_raw_fd(), newname)
+        };
 
+        // Hold the hints locked across the syscall, so concurrent renames update them in the order
+        // the host renamed the entries.
+        let mut hints = self.path_hints.lock();
         // Safe because this doesn't modify any memory and we check the return value.
         // TODO: Switch to libc::renameat2 once https://github.com/rust-lang/libc/pull/1508 lands
         // and we have glibc 2.28.
@@ -887,6 +1477,19 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
             )
         };
         if res == 0 {
+            let moved = hints.renamed(olddir, oldname, newdir, newname, exchange);
+            drop(hints);
+            self.drop_victim(victim);
+            // The ctime of renamed inodes changes on most file systems.
+            for inode in moved.iter().flatten() {
+                if let Ok(data) = self.inode_map.get(*inode) {
+                    self.restat(&data);
+                }
+            }
+            self.restat(&old_inode);
+            if newdir != olddir {
+                self.restat(&new_inode);
+            }
             Ok(())
         } else {
             Err(io::Error::last_os_error())
@@ -903,19 +1506,23 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         umask: u32,
     ) -> io::Result<Entry> {
         self.validate_path_component(name)?;
+        self.check_mknod(mode)?;
 
         let data = self.inode_map.get(parent)?;
-        let file = data.get_file(&self.mount_fds)?;
+        let file = self.dir_file(&data)?;
 
         let res = {
-            let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+            let mode = self.create_mode(file.as_raw_fd(), mode, umask);
+            // Context reset when _fscreate is dropped, after credentials are restored.
+            let _fscreate = self.set_fscreate(&file)?;
+            let _creds = self.set_creds(ctx)?;
 
             // Safe because this doesn't modify any memory and we check the return value.
             unsafe {
                 libc::mknodat(
                     file.as_raw_fd(),
                     name.as_ptr(),
-                    (mode & !umask) as libc::mode_t,
+                    mode as libc::mode_t,
                     u64::from(rdev),
                 )
             }
@@ -939,7 +1546,7 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         let data = self.inode_map.get(inode)?;
         let new_inode = self.inode_map.get(newparent)?;
         let file = data.get_file(&self.mount_fds)?;
-        let new_file = new_inode.get_file(&self.mount_fds)?;
+        let new_file = self.dir_file(&new_inode)?;
 
         // Safe because this is a constant value and a valid C string.
         let empty = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
@@ -955,7 +1562,10 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
             )
         };
         if res == 0 {
-            self.do_lookup(newparent, newname)
+            // The entry is replied with attributes of the inode read after the change.
+            let entry = self.do_lookup(newparent, newname)?;
+            self.restat(&new_inode);
+            Ok(entry)
         } else {
             Err(io::Error::last_os_error())
         }
@@ -969,13 +1579,16 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         name: &CStr,
     ) -> io::Result<Entry> {
         self.validate_path_component(name)?;
+        self.validate_symlink_target(linkname)?;
 
         let data = self.inode_map.get(parent)?;
 
         let res = {
-            let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+            let file = self.dir_file(&data)?;
+            // Context reset when _fscreate is dropped, after credentials are restored.
+            let _fscreate = self.set_fscreate(&file)?;
+            let _creds = self.set_creds(ctx)?;
 
-            let file = data.get_file(&self.mount_fds)?;
             // Safe because this doesn't modify any memory and we check the return value.
             unsafe { libc::symlinkat(linkname.as_ptr(), file.as_raw_fd(), name.as_ptr()) }
         };
@@ -987,29 +1600,15 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
     }
 
     fn readlink(&self, _ctx: &Context, inode: Inode) -> io::Result<Vec<u8>> {
-        // Safe because this is a constant value and a valid C string.
-        let empty = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
-        let mut buf = Vec::<u8>::with_capacity(libc::PATH_MAX as usize);
         let data = self.inode_map.get(inode)?;
         let file = data.get_file(&self.mount_fds)?;
-
-        // Safe because this will only modify the contents of `buf` and we check the return value.
-        let res = unsafe {
-            libc::readlinkat(
-                file.as_raw_fd(),
-                empty.as_ptr(),
-                buf.as_mut_ptr() as *mut libc::c_char,
-                libc::PATH_MAX as usize,
-            )
+        // Some file systems, procfs for example, report a size of 0 for symlinks.
+        let size = match Self::stat_fd(file.as_raw_fd(), None)?.st_size as usize {
+            0 => libc::PATH_MAX as usize,
+            n => n,
         };
-        if res < 0 {
-            return Err(io::Error::last_os_error());
-        }
 
-        // Safe because we trust the value returned by kernel.
-        unsafe { buf.set_len(res as usize) };
-
-        Ok(buf)
+        Self::readlink_fd(file.as_raw_fd(), size, self.cfg.max_symlink_target)
     }
 
     fn flush(
@@ -1017,13 +1616,16 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         _ctx: &Context,
         inode: Inode,
         handle: Handle,
-        _lock_owner: u64,
+        lock_owner: u64,
     ) -> io::Result<()> {
         if self.no_open.load(Ordering::Relaxed) {
             return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
 
         let data = self.handle_map.get(handle, inode)?;
+        if self.cfg.posix_locks {
+            self.release_owner_locks(inode, lock_owner);
+        }
 
         // Since this method is called whenever an fd is closed in the client, we can emulate that
         // behavior by doing the same thing (dup-ing the fd and then immediately closing it). Safe
@@ -1042,6 +1644,42 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         }
     }
 
+    fn getlk(
+        &self,
+        _ctx: &Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        _flags: u32,
+    ) -> io::Result<FileLock> {
+        self.do_getlk(inode, handle, owner, lock)
+    }
+
+    fn setlk(
+        &self,
+        ctx: &Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.do_setlk(ctx, inode, handle, owner, lock, flags, false)
+    }
+
+    fn setlkw(
+        &self,
+        ctx: &Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.do_setlk(ctx, inode, handle, owner, lock, flags, true)
+    }
+
     fn fsync(
         &self,
         _ctx: &Context,
@@ -1063,7 +1701,7 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         if res == 0 {
             Ok(())
         } else {
-            Err(io::Error::last_os_error())
+            Err(io::Error::last_os_error()).with_errno_context(|| format!("fsync inode {}", inode))
         }
     }
 
@@ -1079,7 +1717,9 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
 
     fn access(&self, ctx: &Context, inode: Inode, mask: u32) -> io::Result<()> {
         let data = self.inode_map.get(inode)?;
-        let st = Self::stat(&data.get_file(&self.mount_fds)?, None)?;
+        let mut st = Self::stat(&data.get_file(&self.mount_fds)?, None)?;
+        // Permissions are checked against the caller's ids in the guest.
+        self.guest_owner(&mut st);
         let mode = mask as i32 & (libc::R_OK | libc::W_OK | libc::X_OK);
 
         if mode == libc::F_OK {
@@ -1121,23 +1761,29 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
 
     fn setxattr(
         &self,
-        _ctx: &Context,
+        ctx: &Context,
         inode: Inode,
         name: &CStr,
         value: &[u8],
         flags: u32,
     ) -> io::Result<()> {
-        if !self.cfg.xattr {
+        if self.is_quota_xattr(name) {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        if !self.runtime_config().xattr {
             return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
+        self.cfg.xattr_limits.check_name(name)?;
+        self.cfg.xattr_limits.check_value(value.len())?;
+        let name = self.map_client_xattr(name)?;
 
         let data = self.inode_map.get(inode)?;
         let file = data.get_file(&self.mount_fds)?;
-        let pathname = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd()))
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let pathname = ProcFdPath::new(file.as_raw_fd());
 
         // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
         // need to use the {set,get,remove,list}xattr variants.
+        let _creds = self.acl_creds(ctx, &name)?;
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe {
             libc::setxattr(
@@ -1149,6 +1795,10 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
             )
         };
         if res == 0 {
+            if name.to_bytes_with_nul() == SECURITY_CAPABILITY {
+                data.caps_gen.fetch_add(1, Ordering::AcqRel);
+            }
+            self.restat(&data);
             Ok(())
         } else {
             Err(io::Error::last_os_error())
@@ -1162,89 +1812,123 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         name: &CStr,
         size: u32,
     ) -> io::Result<GetxattrReply> {
-        if !self.cfg.xattr {
+        if self.is_quota_xattr(name) {
+            return self.get_quota_xattr(inode, size);
+        }
+        if !self.runtime_config().xattr {
             return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
+        let limits = &self.cfg.xattr_limits;
+        limits.check_name(name)?;
+        let name = self.map_client_xattr(name)?;
 
         let data = self.inode_map.get(inode)?;
         let file = data.get_file(&self.mount_fds)?;
-        let mut buf = Vec::<u8>::with_capacity(size as usize);
-        let pathname = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd(),))
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let pathname = ProcFdPath::new(file.as_raw_fd());
 
         // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
         // need to use the {set,get,remove,list}xattr variants.
-        // Safe because this will only modify the contents of `buf`.
-        let res = unsafe {
+        // Safe because this will only modify the contents of the buffer.
+        let getxattr = |buf: *mut u8, len: usize| unsafe {
             libc::getxattr(
                 pathname.as_ptr(),
                 name.as_ptr(),
-                buf.as_mut_ptr() as *mut libc::c_void,
-                size as libc::size_t,
+                buf as *mut libc::c_void,
+                len as libc::size_t,
             )
         };
-        if res < 0 {
-            return Err(io::Error::last_os_error());
-        }
 
         if size == 0 {
+            let res = self.retry.run(|| {
+                let res = getxattr(std::ptr::null_mut(), 0);
+                if res < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(res)
+            })?;
+            limits.check_value(res as usize)?;
             Ok(GetxattrReply::Count(res as u32))
         } else {
-            // Safe because we trust the value returned by kernel.
-            unsafe { buf.set_len(res as usize) };
-            Ok(GetxattrReply::Value(buf))
+            // Buffers are bounded by the limit, larger values fail with E2BIG.
+            let len = limits.value_buf_size(size) as usize;
+            let res = self.retry.run(|| scratch::fill(len, getxattr));
+            limits.value_result(size, res).map(GetxattrReply::Value)
         }
     }
 
     fn listxattr(&self, _ctx: &Context, inode: Inode, size: u32) -> io::Result<ListxattrReply> {
-        if !self.cfg.xattr {
+        if !self.runtime_config().xattr {
             return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
 
         let data = self.inode_map.get(inode)?;
         let file = data.get_file(&self.mount_fds)?;
-        let mut buf = Vec::<u8>::with_capacity(size as usize);
-        let pathname = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd()))
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let pathname = ProcFdPath::new(file.as_raw_fd());
 
         // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
         // need to use the {set,get,remove,list}xattr variants.
-        // Safe because this will only modify the contents of `buf`.
-        let res = unsafe {
+        // Safe because this will only modify the contents of the buffer.
+        let listxattr = |buf: *mut u8, len: usize| unsafe {
             libc::listxattr(
                 pathname.as_ptr(),
-                buf.as_mut_ptr() as *mut libc::c_char,
-                size as libc::size_t,
+                buf as *mut libc::c_char,
+                len as libc::size_t,
             )
         };
-        if res < 0 {
-            return Err(io::Error::last_os_error());
+
+        let limits = &self.cfg.xattr_limits;
+        if let Some(map) = self.cfg.xattr_map.as_ref() {
+            // Sizes of host lists don't tell the size of mapped lists, always get the full list,
+            // host lists larger than the limit fail with E2BIG.
+            let host_names = scratch::fill(limits.list_max, listxattr);
+            let names = map.map_server_list_with(
+                &limits.list_result(u32::MAX, host_names)?,
+                &self.yield_points,
+            )?;
+            return if size == 0 {
+                Ok(ListxattrReply::Count(names.len() as u32))
+            } else if names.len() > size as usize {
+                Err(io::Error::from_raw_os_error(libc::ERANGE))
+            } else {
+                Ok(ListxattrReply::Names(names))
+            };
         }
 
         if size == 0 {
+            let res = listxattr(std::ptr::null_mut(), 0);
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            limits.check_list(res as usize)?;
             Ok(ListxattrReply::Count(res as u32))
         } else {
-            // Safe because we trust the value returned by kernel.
-            unsafe { buf.set_len(res as usize) };
-            Ok(ListxattrReply::Names(buf))
+            // Buffers are bounded by the limit, larger lists fail with E2BIG.
+            let res = scratch::fill(limits.list_buf_size(size) as usize, listxattr);
+            limits.list_result(size, res).map(ListxattrReply::Names)
         }
     }
 
-    fn removexattr(&self, _ctx: &Context, inode: Inode, name: &CStr) -> io::Result<()> {
-        if !self.cfg.xattr {
+    fn removexattr(&self, ctx: &Context, inode: Inode, name: &CStr) -> io::Result<()> {
+        if self.is_quota_xattr(name) {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        if !self.runtime_config().xattr {
             return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
+        self.cfg.xattr_limits.check_name(name)?;
+        let name = self.map_client_xattr(name)?;
 
         let data = self.inode_map.get(inode)?;
         let file = data.get_file(&self.mount_fds)?;
-        let pathname = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd()))
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let pathname = ProcFdPath::new(file.as_raw_fd());
 
         // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
         // need to use the {set,get,remove,list}xattr variants.
+        let _creds = self.acl_creds(ctx, &name)?;
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe { libc::removexattr(pathname.as_ptr(), name.as_ptr()) };
         if res == 0 {
+            self.restat(&data);
             Ok(())
         } else {
             Err(io::Error::last_os_error())
@@ -1253,7 +1937,7 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
 
     fn fallocate(
         &self,
-        _ctx: &Context,
+        ctx: &Context,
         inode: Inode,
         handle: Handle,
         mode: u32,
@@ -1263,21 +1947,14 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         // Let the Arc<HandleData> in scope, otherwise fd may get invalid.
         let data = self.get_data(handle, inode, libc::O_RDWR)?;
         let fd = data.get_handle_raw_fd();
+        let inode_data = self.inode_map.get(inode)?;
+        self.kill_capability(ctx, &inode_data, fd, Some(&data))?;
 
-        // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe {
-            libc::fallocate64(
-                fd,
-                mode as libc::c_int,
-                offset as libc::off64_t,
-                length as libc::off64_t,
-            )
-        };
-        if res == 0 {
-            Ok(())
-        } else {
-            Err(io::Error::last_os_error())
-        }
+        self.falloc_helper
+            .fallocate(fd, mode, offset, length, || {
+                self.open_inode(inode, libc::O_WRONLY)
+            })
+            .with_errno_context(|| format!("fallocate inode {}", inode))
     }
 
     fn lseek(
@@ -1289,14 +1966,15 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
         whence: u32,
     ) -> io::Result<u64> {
         // Let the Arc<HandleData> in scope, otherwise fd may get invalid.
-        let data = self.handle_map.get(handle, inode)?;
+        let data = self.get_data(handle, inode, libc::O_RDONLY)?;
 
         // Acquire the lock to get exclusive access, otherwise it may break do_readdir().
         let (_guard, file) = data.get_file_mut();
 
-        // Safe because this doesn't modify any memory and we check the return value.
+        // Safe because this doesn't modify any memory and we check the return value. `ENXIO`
+        // is a valid answer of `SEEK_DATA` and `SEEK_HOLE`, past the last data or hole.
         let res = unsafe {
-            libc::lseek(
+            libc::lseek64(
                 file.as_raw_fd(),
                 offset as libc::off64_t,
                 whence as libc::c_int,
@@ -1308,4 +1986,70 @@ impl<S: BitmapSlice + Send + Sync> FileSystem for PassthroughFs<S> {
             Ok(res as u64)
         }
     }
+
+    fn ioctl(
+        &self,
+        _ctx: &Context,
+        inode: Inode,
+        handle: Handle,
+        _flags: u32,
+        cmd: u32,
+        _arg: u64,
+        in_data: &[u8],
+        out_size: u32,
+    ) -> io::Result<IoctlReply> {
+        self.do_flags_ioctl(inode, handle, cmd, in_data, out_size)
+    }
+
+    fn poll(
+        &self,
+        _ctx: &Context,
+        inode: Inode,
+        handle: Handle,
+        _khandle: u64,
+        _flags: u32,
+        events: u32,
+    ) -> io::Result<u32> {
+        // Let the Arc<HandleData> in scope, otherwise fd may get invalid.
+        let data = self.get_data(handle, inode, libc::O_RDONLY)?;
+        let mut pollfd = libc::pollfd {
+            fd: data.get_handle_raw_fd(),
+            events: events as libc::c_short,
+            revents: 0,
+        };
+
+        // Safe because this only writes `pollfd` and we check the return value. With a zero
+        // timeout it doesn't block, and guests waiting for a wakeup are only woken by timeouts.
+        let res = unsafe { libc::poll(&mut pollfd, 1, 0) };
+        if res < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(pollfd.revents as u16 as u32)
+        }
+    }
+
+    fn copyfilerange(
+        &self,
+        _ctx: &Context,
+        inode_in: Inode,
+        handle_in: Handle,
+        offset_in: u64,
+        inode_out: Inode,
+        handle_out: Handle,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> io::Result<usize> {
+        // Let the Arc<HandleData> in scope, otherwise fd may get invalid.
+        let data_in = self.get_data(handle_in, inode_in, libc::O_RDONLY)?;
+        let data_out = self.get_data(handle_out, inode_out, libc::O_WRONLY)?;
+        self.copy_helper.copy(
+            data_in.get_handle_raw_fd(),
+            offset_in,
+            data_out.get_handle_raw_fd(),
+            offset_out,
+            len,
+            flags,
+        )
+    }
 }
@@ -0,0 +1,167 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Detection of the timestamp granularity of backing file systems.
+//!
+//! The kernel keeps timestamps of fuse inodes at the granularity replied by `FUSE_INIT`, while
+//! some backing file systems only store microseconds or seconds. Without a matching granularity,
+//! timestamps set by the guest are cached with nanoseconds but read back truncated once the inode
+//! is evicted, and tools comparing timestamps see files change. So the granularity is detected at
+//! init, by setting a timestamp with nanoseconds on a temporary file of the root directory and
+//! reading it back, unless configured by `Config::time_gran`.
+
+use std::fs::File;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+/// Granularity of timestamps stored only in seconds.
+pub(super) const MAX_TIME_GRAN: u32 = 1_000_000_000;
+
+// Nanoseconds set by the probe, truncated differently by each granularity.
+const PROBE_NSEC: i64 = 999_999_999;
+// Seconds set by the probe, an arbitrary time representable by all file systems.
+const PROBE_SEC: i64 = 1_000_000_000;
+
+// Syscalls used to probe the granularity, abstracted for testing.
+pub(super) trait TimeGranSyscalls: Send + Sync {
+    // Set the mtime of a temporary file in `dir` to `nsec` nanoseconds, return the nanoseconds
+    // of the mtime read back.
+    fn probe(&self, dir: RawFd, nsec: i64) -> io::Result<i64>;
+}
+
+pub(super) struct LibcTimeGranSyscalls;
+
+impl TimeGranSyscalls for LibcTimeGranSyscalls {
+    fn probe(&self, dir: RawFd, nsec: i64) -> io::Result<i64> {
+        // Safe because this doesn't modify any memory and we check the return value.
+        let fd = unsafe {
+            libc::openat(
+                dir,
+                ".\0".as_ptr() as *const libc::c_char,
+                libc::O_TMPFILE | libc::O_RDWR | libc::O_CLOEXEC,
+                0o600,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because we just opened this fd.
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        let tvs = [
+            libc::timespec {
+                tv_sec: 0,
+                tv_nsec: libc::UTIME_OMIT,
+            },
+            libc::timespec {
+                tv_sec: PROBE_SEC,
+                tv_nsec: nsec,
+            },
+        ];
+        // Safe because this doesn't modify any memory and we check the return value.
+        if unsafe { libc::futimens(file.as_raw_fd(), tvs.as_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut st = MaybeUninit::<libc::stat64>::zeroed();
+        // Safe because the kernel will only write data in `st` and we check the return value.
+        if unsafe { libc::fstat64(file.as_raw_fd(), st.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because the kernel guarantees that the struct is now fully initialized.
+        Ok(unsafe { st.assume_init() }.st_mtime_nsec)
+    }
+}
+
+/// Check whether `gran` is a granularity able to be replied to the kernel, a power of ten of at
+/// most one second.
+pub(super) fn is_valid(gran: u32) -> bool {
+    gran_values().any(|g| g == gran)
+}
+
+// All valid granularities, from the finest.
+fn gran_values() -> impl Iterator<Item = u32> {
+    std::iter::successors(Some(1u32), |gran| {
+        (*gran < MAX_TIME_GRAN).then(|| gran * 10)
+    })
+}
+
+/// Truncate nanoseconds of a timestamp to the granularity `gran`, like the kernel does.
+pub(super) fn truncate_nsec(nsec: i64, gran: u32) -> i64 {
+    nsec - nsec % gran as i64
+}
+
+/// Detect the timestamp granularity of the file system of the directory `dir`.
+pub(super) fn probe_time_gran(sys: &dyn TimeGranSyscalls, dir: RawFd) -> io::Result<u32> {
+    let nsec = sys.probe(dir, PROBE_NSEC)?;
+    gran_values()
+        .find(|gran| truncate_nsec(PROBE_NSEC, *gran) == nsec)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected nanoseconds {} of probed timestamp", nsec),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempdir::TempDir;
+
+    // Truncate timestamps to `gran`, or fail with `errno`.
+    struct MockFs {
+        gran: i64,
+        errno: Option<i32>,
+    }
+
+    impl TimeGranSyscalls for MockFs {
+        fn probe(&self, _dir: RawFd, nsec: i64) -> io::Result<i64> {
+            match self.errno {
+                Some(errno) => Err(io::Error::from_raw_os_error(errno)),
+                None => Ok(nsec - nsec % self.gran),
+            }
+        }
+    }
+
+    fn probe(gran: i64) -> io::Result<u32> {
+        probe_time_gran(&MockFs { gran, errno: None }, -1)
+    }
+
+    #[test]
+    fn test_probe_time_gran() {
+        assert_eq!(probe(1).unwrap(), 1);
+        assert_eq!(probe(1000).unwrap(), 1000);
+        assert_eq!(probe(1_000_000_000).unwrap(), MAX_TIME_GRAN);
+        // Granularities which aren't powers of ten can't be replied to the kernel.
+        assert!(probe(2_000_000).is_err());
+
+        let sys = MockFs {
+            gran: 1,
+            errno: Some(libc::EOPNOTSUPP),
+        };
+        let err = probe_time_gran(&sys, -1).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP));
+    }
+
+    #[test]
+    fn test_probe_time_gran_host() {
+        let dir = TempDir::new().unwrap();
+        let file = File::open(dir.as_path()).unwrap();
+        // Temporary files may be unsupported by the file system of the test directory.
+        if let Ok(gran) = probe_time_gran(&LibcTimeGranSyscalls, file.as_raw_fd()) {
+            assert!(is_valid(gran));
+        }
+        assert_eq!(std::fs::read_dir(dir.as_path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_truncate_nsec() {
+        assert!(is_valid(1) && is_valid(1000) && is_valid(MAX_TIME_GRAN));
+        assert!(!is_valid(0) && !is_valid(2) && !is_valid(2 * MAX_TIME_GRAN));
+        assert_eq!(truncate_nsec(123_456_789, 1), 123_456_789);
+        assert_eq!(truncate_nsec(123_456_789, 1000), 123_456_000);
+        assert_eq!(truncate_nsec(123_456_789, MAX_TIME_GRAN), 0);
+    }
+}
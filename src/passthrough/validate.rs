@@ -0,0 +1,182 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Validation of the identity of inodes before using them.
+//!
+//! Inodes keep the host file they were looked up with open, so a file replaced on the host, by
+//! removing it and creating another one with the same name, is still served from the old one
+//! until the guest looks the name up again. `Config::validate_inodes` checks before opening,
+//! reading, writing and setting attributes of an inode that the name it was last seen with still
+//! refers to the same file, comparing the ids of its alt key got by the same statx(2) and mount
+//! id machinery as lookups. Requests on replaced or removed files fail with `ESTALE` instead, so
+//! the guest looks them up again.
+//!
+//! Inodes without a name known by their hint, like the root directory, files unlinked or
+//! replaced by the guest itself and unnamed temporary files, aren't validated. The result is
+//! cached for the request, so each request validates an inode at most once.
+
+use std::cell::Cell;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use super::{Inode, InodeAltKey, PassthroughFs};
+use crate::abi::fuse_abi as fuse;
+use crate::api::errno::errno_of;
+use crate::api::filesystem::Context;
+use crate::BitmapSlice;
+
+thread_local! {
+    // Unique id of the request and inode validated last by this thread.
+    static VALIDATED: Cell<(u64, Inode)> = const { Cell::new((0, 0)) };
+}
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    // Check that the file last seen with the name of `inode` is still the one of `inode`.
+    pub(super) fn validate_inode(&self, ctx: &Context, inode: Inode) -> io::Result<()> {
+        if !self.cfg.validate_inodes || inode == fuse::ROOT_ID {
+            return Ok(());
+        }
+        // Requests made by the daemon itself have no unique id to cache the result with.
+        let key = (ctx.unique, inode);
+        if ctx.unique != 0 && VALIDATED.with(|v| v.get()) == key {
+            return Ok(());
+        }
+        let (parent, name) = match self.path_hints.get(inode) {
+            Some(hint) => hint,
+            None => return Ok(()),
+        };
+        let data = self.inode_map.get(inode)?;
+        let dir = match self.inode_map.get(parent) {
+            Ok(dir) => dir,
+            Err(_) => return Ok(()),
+        };
+
+        let dir_file = self.dir_file(&dir)?;
+        let current = Self::open_file(
+            dir_file.as_raw_fd(),
+            &name,
+            libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            0,
+        )
+        .and_then(|f| self.stat_helper.stat(&f));
+        let stale = match current {
+            Ok(st) => InodeAltKey::ids_from_stat(&st) != data.altkey,
+            Err(e) if matches!(errno_of(&e), Some(libc::ENOENT) | Some(libc::ENOTDIR)) => true,
+            Err(e) => return Err(e),
+        };
+        if stale {
+            warn!(
+                "fuse: inode {} named {:?} in {} was replaced on the host",
+                inode, name, parent
+            );
+            return Err(io::Error::from_raw_os_error(libc::ESTALE));
+        }
+
+        VALIDATED.with(|v| v.set(key));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::prepare_passthroughfs_with;
+    use super::*;
+    use crate::api::filesystem::{FileSystem, FsOptions, SetattrValid, VecWriter};
+    use std::ffi::CString;
+    use std::fs;
+
+    fn read(fs: &PassthroughFs, ctx: &Context, inode: Inode, handle: u64) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        fs.read(
+            ctx,
+            inode,
+            handle,
+            &mut VecWriter::new(&mut buf),
+            4,
+            0,
+            None,
+            0,
+        )?;
+        Ok(buf)
+    }
+
+    fn is_estale<T>(res: io::Result<T>) -> bool {
+        errno_of(&res.err().unwrap()) == Some(libc::ESTALE)
+    }
+
+    #[test]
+    fn test_validate_inodes() {
+        let (source, fs) = prepare_passthroughfs_with(|_| {});
+        fs.init(FsOptions::empty()).unwrap();
+        let path = source.as_path().join("f");
+        fs::write(&path, b"old1").unwrap();
+        let ctx = Context {
+            unique: 1,
+            ..Default::default()
+        };
+        let name = CString::new("f").unwrap();
+        let ino = fs.lookup(&ctx, fuse::ROOT_ID, &name).unwrap().inode;
+        let (fh, _) = fs.open(&ctx, ino, libc::O_RDWR as u32, 0).unwrap();
+        let fh = fh.unwrap();
+        assert_eq!(read(&fs, &ctx, ino, fh).unwrap(), b"old1");
+
+        // Replace the file on the host mid-stream.
+        fs::remove_file(&path).unwrap();
+        fs::write(&path, b"new2").unwrap();
+
+        // The result is cached for the request already validated.
+        assert_eq!(read(&fs, &ctx, ino, fh).unwrap(), b"old1");
+
+        let ctx = Context {
+            unique: 2,
+            ..Default::default()
+        };
+        assert!(is_estale(read(&fs, &ctx, ino, fh)));
+        assert!(is_estale(fs.open(&ctx, ino, libc::O_RDONLY as u32, 0)));
+        // Safe because we are zero-initializing a struct with only POD fields.
+        let attr: libc::stat64 = unsafe { std::mem::zeroed() };
+        assert!(is_estale(fs.setattr(
+            &ctx,
+            ino,
+            attr,
+            None,
+            SetattrValid::MODE
+        )));
+
+        // Looking the name up again gets the new file.
+        let new = fs.lookup(&ctx, fuse::ROOT_ID, &name).unwrap().inode;
+        assert_ne!(new, ino);
+        let (fh, _) = fs.open(&ctx, new, libc::O_RDONLY as u32, 0).unwrap();
+        assert_eq!(read(&fs, &ctx, new, fh.unwrap()).unwrap(), b"new2");
+
+        // Files removed by the host are stale too, not the ones removed by the guest.
+        let ctx = Context {
+            unique: 3,
+            ..Default::default()
+        };
+        fs::remove_file(&path).unwrap();
+        assert!(is_estale(fs.open(&ctx, new, libc::O_RDONLY as u32, 0)));
+        fs::write(&path, b"last").unwrap();
+        let last = fs.lookup(&ctx, fuse::ROOT_ID, &name).unwrap().inode;
+        let (fh, _) = fs.open(&ctx, last, libc::O_RDONLY as u32, 0).unwrap();
+        fs.unlink(&ctx, fuse::ROOT_ID, &name).unwrap();
+        assert_eq!(read(&fs, &ctx, last, fh.unwrap()).unwrap(), b"last");
+    }
+
+    #[test]
+    fn test_validate_inodes_disabled() {
+        let (source, fs) = prepare_passthroughfs_with(|cfg| cfg.validate_inodes = false);
+        fs.init(FsOptions::empty()).unwrap();
+        let path = source.as_path().join("f");
+        fs::write(&path, b"old1").unwrap();
+        let ctx = Context::default();
+        let name = CString::new("f").unwrap();
+        let ino = fs.lookup(&ctx, fuse::ROOT_ID, &name).unwrap().inode;
+        let (fh, _) = fs.open(&ctx, ino, libc::O_RDONLY as u32, 0).unwrap();
+
+        // The removed file keeps being served.
+        fs::remove_file(&path).unwrap();
+        fs::write(&path, b"new2").unwrap();
+        assert_eq!(read(&fs, &ctx, ino, fh.unwrap()).unwrap(), b"old1");
+    }
+}
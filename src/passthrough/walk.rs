@@ -0,0 +1,220 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Resolution of paths in the shared directory for management operations.
+//!
+//! Management tooling operates on paths ("invalidate /a/b/c", "prefetch /x"), while the file
+//! system only exposes operations on inodes. [PassthroughFs::walk] looks up each component of a
+//! path the way the guest does, and returns a [WalkGuard] which gives back the lookup counts
+//! taken by the walk when dropped, so walks don't leak inodes. [PassthroughFs::resolve] opens a
+//! path from the root directory without registering inodes at all.
+//!
+//! Paths are relative to the shared directory, `..` components are refused and symlinks aren't
+//! followed, so a walk never escapes the shared directory.
+
+use std::path::{Component, Path};
+
+use super::*;
+
+/// Inode resolved by [PassthroughFs::walk].
+///
+/// The lookup counts taken by the walk are released when the guard is dropped.
+pub struct WalkGuard<'a, S: BitmapSlice + Send + Sync = ()> {
+    fs: &'a PassthroughFs<S>,
+    // Inodes looked up by the walk, each holding one lookup count.
+    inodes: Vec<Inode>,
+}
+
+impl<S: BitmapSlice + Send + Sync> WalkGuard<'_, S> {
+    /// Get the resolved inode.
+    pub fn inode(&self) -> Inode {
+        self.inodes.last().copied().unwrap_or(fuse::ROOT_ID)
+    }
+}
+
+impl<S: BitmapSlice + Send + Sync> Drop for WalkGuard<'_, S> {
+    fn drop(&mut self) {
+        for inode in self.inodes.drain(..).rev() {
+            let mut inodes = self.fs.inode_map.get_map_mut(inode);
+            if PassthroughFs::<S>::forget_one(&mut inodes, inode, 1) {
+                self.fs.path_hints.forget(inode);
+                self.fs.dir_fds.remove(inode);
+            }
+        }
+    }
+}
+
+// Split `path` into the names to look up from the root directory.
+fn components(path: &Path) -> io::Result<Vec<CString>> {
+    let mut names = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::RootDir | Component::CurDir => {}
+            Component::Normal(name) => {
+                let name = CString::new(name.as_bytes())
+                    .map_err(|_| fuse_errno(libc::EINVAL, "invalid path component"))?;
+                validate_path_component(&name)?;
+                names.push(name);
+            }
+            _ => {
+                return Err(fuse_errno(
+                    libc::EINVAL,
+                    "path escapes the shared directory",
+                ))
+            }
+        }
+    }
+    Ok(names)
+}
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    /// Look up `path`, relative to the shared directory, component by component.
+    ///
+    /// Inodes of the path are registered as if the guest looked them up, the returned guard
+    /// releases the lookup counts when dropped.
+    pub fn walk<P: AsRef<Path>>(&self, path: P) -> io::Result<WalkGuard<'_, S>> {
+        let names = components(path.as_ref())?;
+        // Lookups done before a failure are released when the guard is dropped.
+        let mut guard = WalkGuard {
+            fs: self,
+            inodes: Vec::with_capacity(names.len()),
+        };
+        for name in names.iter() {
+            let entry = self.do_lookup(guard.inode(), name)?;
+            guard.inodes.push(entry.inode);
+        }
+        Ok(guard)
+    }
+
+    /// Open `path`, relative to the shared directory, with `flags`.
+    ///
+    /// The path is resolved with `openat()` from the root directory, without registering any
+    /// inode nor touching lookup counts.
+    pub fn resolve<P: AsRef<Path>>(&self, path: P, flags: i32) -> io::Result<File> {
+        let names = components(path.as_ref())?;
+        let root = self.inode_map.get(fuse::ROOT_ID)?;
+        let root_file = root.get_file(&self.mount_fds)?;
+
+        let (last, dirs) = match names.split_last() {
+            Some((last, dirs)) => (last.as_c_str(), dirs),
+            None => (
+                CStr::from_bytes_with_nul(CURRENT_DIR_CSTR).unwrap(),
+                &names[..],
+            ),
+        };
+        let dir_flags = libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+        let mut dir: Option<File> = None;
+        for name in dirs {
+            let dfd = dir
+                .as_ref()
+                .map_or(root_file.as_raw_fd(), |d| d.as_raw_fd());
+            dir = Some(Self::open_file(dfd, name, dir_flags, 0)?);
+        }
+        let dfd = dir
+            .as_ref()
+            .map_or(root_file.as_raw_fd(), |d| d.as_raw_fd());
+        Self::open_file(dfd, last, flags | libc::O_NOFOLLOW | libc::O_CLOEXEC, 0)
+    }
+
+    /// Ask the host to read the regular file at `path`, relative to the shared directory, into
+    /// the page cache.
+    pub fn prefetch<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let file = self.resolve(path, libc::O_RDONLY | libc::O_NONBLOCK)?;
+        if !file.metadata()?.is_file() {
+            return Err(fuse_errno(libc::EINVAL, "prefetch of a non regular file"));
+        }
+        // Safe because we just opened this fd and don't touch memory.
+        let ret = unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_WILLNEED) };
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::errno::errno_of;
+    use crate::api::filesystem::{Context, FileSystem};
+    use crate::passthrough::tests::passthroughfs_in;
+    use std::fs;
+    use vmm_sys_util::tempdir::TempDir;
+
+    fn prepare_fs() -> (TempDir, PassthroughFs) {
+        let source = TempDir::new().unwrap();
+        let path = source.as_path();
+        fs::create_dir_all(path.join("a/b")).unwrap();
+        fs::write(path.join("a/b/c"), b"data").unwrap();
+        std::os::unix::fs::symlink("a", path.join("link")).unwrap();
+
+        let fs = passthroughfs_in(path, |_| {});
+        (source, fs)
+    }
+
+    fn errno<T>(res: io::Result<T>) -> Option<i32> {
+        res.err().and_then(|e| errno_of(&e))
+    }
+
+    #[test]
+    fn test_walk_refcount() {
+        let (_source, fs) = prepare_fs();
+        let ctx = Context::default();
+        let name = CString::new("a").unwrap();
+        let a = fs.lookup(&ctx, fuse::ROOT_ID, &name).unwrap().inode;
+        let inodes = fs.inode_map.len();
+
+        {
+            let guard = fs.walk("/a/b/c").unwrap();
+            assert_eq!(fs.debug_nlookup(a), Some(2));
+            let c = guard.inode();
+            assert_eq!(fs.debug_nlookup(c), Some(1));
+            assert!(fs.inode_map.get(c).unwrap().mode & libc::S_IFMT == libc::S_IFREG);
+        }
+        assert_eq!(fs.debug_nlookup(a), Some(1));
+        assert_eq!(fs.inode_map.len(), inodes);
+
+        // Lookups done before a failure are released too.
+        assert_eq!(errno(fs.walk("a/b/missing")), Some(libc::ENOENT));
+        assert_eq!(errno(fs.walk("a/b/c/d")), Some(libc::ENOTDIR));
+        assert_eq!(fs.debug_nlookup(a), Some(1));
+        assert_eq!(fs.inode_map.len(), inodes);
+
+        // The root doesn't need lookups, paths can't escape the shared directory.
+        assert_eq!(fs.walk("/").unwrap().inode(), fuse::ROOT_ID);
+        assert_eq!(errno(fs.walk("a/../..")), Some(libc::EINVAL));
+        assert_eq!(errno(fs.walk("link/b")), Some(libc::ENOTDIR));
+        assert_eq!(fs.inode_map.len(), inodes);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let (_source, fs) = prepare_fs();
+        let inodes = fs.inode_map.len();
+
+        let file = fs.resolve("./a/b/c", libc::O_RDONLY).unwrap();
+        assert_eq!(
+            fs::read_to_string(format!("/proc/self/fd/{}", file.as_raw_fd())).unwrap(),
+            "data"
+        );
+        assert!(fs
+            .resolve("", libc::O_RDONLY)
+            .unwrap()
+            .metadata()
+            .unwrap()
+            .is_dir());
+        assert_eq!(
+            errno(fs.resolve("link/b", libc::O_RDONLY)),
+            Some(libc::ENOTDIR)
+        );
+        assert_eq!(errno(fs.resolve("link", libc::O_RDONLY)), Some(libc::ELOOP));
+        assert_eq!(
+            errno(fs.resolve("../a", libc::O_RDONLY)),
+            Some(libc::EINVAL)
+        );
+
+        fs.prefetch("/a/b/c").unwrap();
+        assert_eq!(errno(fs.prefetch("/a")), Some(libc::EINVAL));
+        assert_eq!(fs.inode_map.len(), inodes);
+    }
+}
@@ -0,0 +1,337 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Remapping and filtering of extended attribute names.
+//!
+//! Unprivileged daemons can't set `security.*` or `trusted.*` xattrs on the host, so guests
+//! setting them fail with `EPERM`. An [XattrMap] translates names between the guest and the host,
+//! for example to store `security.capability` as `user.virtiofsd.security.capability`, and hides
+//! host names which the guest shouldn't see.
+//!
+//! Rules are tried in order. Names sent by the guest are matched against the `key` of rules with
+//! client scope, names listed on the host against rules with server scope. Names matching no rule
+//! are refused with `EPERM` when sent by the guest, and hidden when listed on the host.
+//!
+//! The textual syntax is the one of the `xattrmap` option of virtiofsd. Each rule starts with a
+//! separator character, which also separates and terminates its fields:
+//!
+//! * `:prefix:<scope>:<key>:<prepend>:`, client names starting with `key` are prefixed with
+//!   `prepend`, server names starting with `prepend` get it stripped.
+//! * `:ok:<scope>:<key>::`, names starting with `key` pass unchanged.
+//! * `:bad:<scope>:<key>::`, client names starting with `key` are refused with `EPERM`, server
+//!   names are hidden.
+//! * `:unsupported:<scope>:<key>::`, like `bad` but client names are refused with `ENOTSUP`.
+//! * `:map:<key>:<prepend>:`, shorthand prefixing names starting with `key` both ways, hiding
+//!   other host names starting with `prepend` and passing the remaining names unchanged. With an
+//!   empty `key` all names are prefixed and other host names are hidden.
+//!
+//! `scope` is one of `client`, `server` or `all`, rules are separated by optional whitespaces.
+
+use std::borrow::Cow;
+
+use super::*;
+
+/// Action of an [XattrRule] on matching names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config-serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum XattrRuleType {
+    /// Names pass unchanged.
+    Ok,
+    /// Client names are refused with `EPERM`, server names are hidden.
+    Bad,
+    /// Client names are refused with `ENOTSUP`, server names are hidden.
+    Unsupported,
+    /// Client names get `prepend` prefixed, server names get it stripped.
+    Prefix,
+}
+
+/// Rule of an [XattrMap].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "config-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct XattrRule {
+    /// Action on matching names.
+    pub rule_type: XattrRuleType,
+    /// Whether the rule applies to names sent by the guest.
+    pub client: bool,
+    /// Whether the rule applies to names listed on the host.
+    pub server: bool,
+    /// Prefix of matching names.
+    pub key: String,
+    /// Prefix of host names for [XattrRuleType::Prefix] rules.
+    pub prepend: String,
+}
+
+/// Ordered rules to translate xattr names between the guest and the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config-serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+pub struct XattrMap {
+    rules: Vec<XattrRule>,
+}
+
+impl XattrMap {
+    /// Create a map from `rules`, tried in order.
+    pub fn new(rules: Vec<XattrRule>) -> Self {
+        XattrMap { rules }
+    }
+
+    /// Get the rules of the map.
+    pub fn rules(&self) -> &[XattrRule] {
+        &self.rules
+    }
+
+    /// Translate the xattr name `name` sent by the guest into the host name.
+    pub fn map_client<'a>(&self, name: &'a CStr) -> io::Result<Cow<'a, CStr>> {
+        let bytes = name.to_bytes();
+        let rule = self
+            .rules
+            .iter()
+            .find(|r| r.client && bytes.starts_with(r.key.as_bytes()));
+        match rule.map(|r| (r.rule_type, r)) {
+            Some((XattrRuleType::Ok, _)) => Ok(Cow::Borrowed(name)),
+            Some((XattrRuleType::Prefix, r)) => {
+                let mut mapped = r.prepend.as_bytes().to_vec();
+                mapped.extend_from_slice(bytes);
+                CString::new(mapped)
+                    .map(Cow::Owned)
+                    .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))
+            }
+            Some((XattrRuleType::Unsupported, _)) => {
+                Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+            }
+            Some((XattrRuleType::Bad, _)) | None => Err(io::Error::from_raw_os_error(libc::EPERM)),
+        }
+    }
+
+    /// Translate the xattr name `name` listed on the host into the name shown to the guest,
+    /// return `None` if it's hidden.
+    pub fn map_server<'a>(&self, name: &'a [u8]) -> Option<&'a [u8]> {
+        for r in self.rules.iter().filter(|r| r.server) {
+            match r.rule_type {
+                XattrRuleType::Prefix if name.starts_with(r.prepend.as_bytes()) => {
+                    return Some(&name[r.prepend.len()..]);
+                }
+                XattrRuleType::Prefix => {}
+                XattrRuleType::Ok if name.starts_with(r.key.as_bytes()) => return Some(name),
+                _ if name.starts_with(r.key.as_bytes()) => return None,
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Translate the nul terminated names of `list` got by listxattr(2) on the host into the
+    /// list shown to the guest.
+    pub fn map_server_list(&self, list: &[u8]) -> Vec<u8> {
+        self.map_server_list_with(list, &YieldPoints::default())
+            .expect("yield points without a shedder never fail")
+    }
+
+    /// Like [XattrMap::map_server_list], consulting `points` each `YieldChunks::xattr_names`
+    /// names, and failing with `EINTR` if aborted there.
+    pub fn map_server_list_with(&self, list: &[u8], points: &YieldPoints) -> io::Result<Vec<u8>> {
+        let chunk = points.chunks().xattr_names.max(1);
+        let mut mapped = Vec::with_capacity(list.len());
+        let mut pending = chunk;
+        for name in list.split(|b| *b == 0).filter(|n| !n.is_empty()) {
+            if pending == 0 {
+                points
+                    .check()
+                    .errno_context("mapping of xattr list aborted")?;
+                pending = chunk;
+            }
+            pending -= 1;
+            if let Some(name) = self.map_server(name) {
+                mapped.extend_from_slice(name);
+                mapped.push(0);
+            }
+        }
+        Ok(mapped)
+    }
+}
+
+impl FromStr for XattrMap {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rules = Vec::new();
+        let mut rest = s.trim_start();
+        while let Some(sep) = rest.chars().next() {
+            rest = &rest[sep.len_utf8()..];
+            let mut field = || -> Result<&str, Self::Err> {
+                let end = rest.find(sep).ok_or("unterminated xattr map rule")?;
+                let field = &rest[..end];
+                rest = &rest[end + sep.len_utf8()..];
+                Ok(field)
+            };
+
+            let rule_type = field()?;
+            if rule_type == "map" {
+                let key = field()?.to_string();
+                let prepend = field()?.to_string();
+                let rule = |rule_type, key: &str, prepend: &str| XattrRule {
+                    rule_type,
+                    client: true,
+                    server: true,
+                    key: key.to_string(),
+                    prepend: prepend.to_string(),
+                };
+                rules.push(rule(XattrRuleType::Prefix, &key, &prepend));
+                rules.push(rule(XattrRuleType::Bad, &prepend, ""));
+                if key.is_empty() {
+                    rules.push(rule(XattrRuleType::Bad, "", ""));
+                } else {
+                    rules.push(rule(XattrRuleType::Ok, "", ""));
+                }
+            } else {
+                let rule_type = match rule_type {
+                    "ok" => XattrRuleType::Ok,
+                    "bad" => XattrRuleType::Bad,
+                    "unsupported" => XattrRuleType::Unsupported,
+                    "prefix" => XattrRuleType::Prefix,
+                    _ => return Err("invalid xattr map rule type"),
+                };
+                let (client, server) = match field()? {
+                    "client" => (true, false),
+                    "server" => (false, true),
+                    "all" => (true, true),
+                    _ => return Err("invalid xattr map rule scope"),
+                };
+                let key = field()?.to_string();
+                let prepend = field()?.to_string();
+                rules.push(XattrRule {
+                    rule_type,
+                    client,
+                    server,
+                    key,
+                    prepend,
+                });
+            }
+            rest = rest.trim_start();
+        }
+
+        if rules.is_empty() {
+            return Err("empty xattr map");
+        }
+        if rules
+            .iter()
+            .any(|r| r.key.contains('\0') || r.prepend.contains('\0'))
+        {
+            return Err("invalid xattr map rule prefix");
+        }
+        Ok(XattrMap { rules })
+    }
+}
+
+impl<S: BitmapSlice + Send + Sync> PassthroughFs<S> {
+    // Translate the xattr name `name` sent by the guest into the host name.
+    pub(super) fn map_client_xattr<'a>(&self, name: &'a CStr) -> io::Result<Cow<'a, CStr>> {
+        match self.cfg.xattr_map.as_ref() {
+            Some(map) => map.map_client(name),
+            None => Ok(Cow::Borrowed(name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(map: &XattrMap, name: &str) -> Result<String, i32> {
+        let name = CString::new(name).unwrap();
+        map.map_client(&name)
+            .map(|n| n.to_str().unwrap().to_string())
+            .map_err(|e| e.raw_os_error().unwrap())
+    }
+
+    #[test]
+    fn test_xattr_map_parse() {
+        let map: XattrMap =
+            " :prefix:client:trusted.:user.virtiofs.: \n /ok/all/user.// :bad:all:::"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            map.rules()[0],
+            XattrRule {
+                rule_type: XattrRuleType::Prefix,
+                client: true,
+                server: false,
+                key: "trusted.".to_string(),
+                prepend: "user.virtiofs.".to_string(),
+            }
+        );
+        assert_eq!(map.rules()[1].rule_type, XattrRuleType::Ok);
+        assert_eq!(map.rules()[1].key, "user.");
+        assert_eq!(map.rules()[2].rule_type, XattrRuleType::Bad);
+        assert_eq!(map.rules().len(), 3);
+
+        assert_eq!(
+            ":map:security.:user.virtiofsd.:"
+                .parse::<XattrMap>()
+                .unwrap()
+                .rules()
+                .len(),
+            3
+        );
+        for s in [
+            "",
+            "  ",
+            ":ok:all:",
+            ":foo:all:::",
+            ":ok:everyone:::",
+            ":map:a:",
+        ] {
+            assert!(s.parse::<XattrMap>().is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn test_xattr_map_rules() {
+        let map: XattrMap = ":map:security.:user.virtiofsd.: :unsupported:all:system.::"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            client(&map, "security.capability"),
+            Ok("user.virtiofsd.security.capability".to_string())
+        );
+        assert_eq!(client(&map, "user.foo"), Ok("user.foo".to_string()));
+        // Guests can't reach the mapped names directly.
+        assert_eq!(client(&map, "user.virtiofsd.x"), Err(libc::EPERM));
+
+        let host = b"user.virtiofsd.security.capability\0user.foo\0user.virtiofsd\0";
+        assert_eq!(
+            map.map_server_list(host),
+            b"security.capability\0user.foo\0user.virtiofsd\0".to_vec()
+        );
+
+        // Names matching no rule are refused and hidden.
+        let map: XattrMap =
+            ":ok:client:user.:: :prefix:server::host.: :unsupported:client:system.::"
+                .parse()
+                .unwrap();
+        assert_eq!(client(&map, "trusted.x"), Err(libc::EPERM));
+        assert_eq!(client(&map, "system.posix_acl_access"), Err(libc::ENOTSUP));
+        assert_eq!(
+            map.map_server_list(b"host.user.a\0user.b\0"),
+            b"user.a\0".to_vec()
+        );
+        assert!(map.map_server_list(b"").is_empty());
+
+        let map: XattrMap = ":map::user.guest.:".parse().unwrap();
+        assert_eq!(
+            client(&map, "trusted.x"),
+            Ok("user.guest.trusted.x".to_string())
+        );
+        assert_eq!(
+            map.map_server_list(b"user.guest.a\0user.b\0"),
+            b"a\0".to_vec()
+        );
+    }
+}
@@ -452,9 +452,9 @@ pub use async_io::AsyncFileReadWriteVolatile;
 
 #[cfg(feature = "async-io")]
 mod async_io {
-    use futures::join;
+    use futures::future::join_all;
     use std::sync::Arc;
-    use tokio_uring::buf::IoBuf;
+    use tokio_uring::buf::{IoBuf, IoBufMut};
     use tokio_uring::fs::File;
 
     use super::*;
@@ -520,152 +520,10 @@ mod async_io {
 
         async fn async_read_vectored_at_volatile(
             &self,
-            mut bufs: Vec<FileVolatileBuf>,
-            mut offset: u64,
+            bufs: Vec<FileVolatileBuf>,
+            offset: u64,
         ) -> (Result<usize>, Vec<FileVolatileBuf>) {
-            if bufs.len() == 0 {
-                return (Ok(0), bufs);
-            } else if bufs.len() == 1 {
-                let (res, buf) = self.async_read_at_volatile(bufs[0], offset).await;
-                bufs[0] = buf;
-                return (res, bufs);
-            }
-
-            let mut count = 0;
-            let mut pos = 0;
-            while bufs.len() - pos >= 4 {
-                let op1 = self.async_read_at_volatile(bufs[pos], offset);
-                offset += bufs[pos].bytes_total() as u64;
-                let op2 = self.async_read_at_volatile(bufs[pos + 1], offset);
-                offset += bufs[pos + 1].bytes_total() as u64;
-                let op3 = self.async_read_at_volatile(bufs[pos + 2], offset);
-                offset += bufs[pos + 2].bytes_total() as u64;
-                let op4 = self.async_read_at_volatile(bufs[pos + 3], offset);
-                offset += bufs[pos + 3].bytes_total() as u64;
-                let (res1, res2, res3, res4) = join!(op1, op2, op3, op4);
-
-                match res1 {
-                    (Err(e), _) => return (Err(e), bufs),
-                    (Ok(cnt), buf) => {
-                        count += cnt;
-                        bufs[pos] = buf;
-                        if cnt < bufs[pos].bytes_total() {
-                            return (Ok(count), bufs);
-                        }
-                    }
-                }
-                match res2 {
-                    (Err(e), _) => return (Err(e), bufs),
-                    (Ok(cnt), buf) => {
-                        count += cnt;
-                        bufs[pos + 1] = buf;
-                        if cnt < bufs[pos + 1].bytes_total() {
-                            return (Ok(count), bufs);
-                        }
-                    }
-                }
-                match res3 {
-                    (Err(e), _) => return (Err(e), bufs),
-                    (Ok(cnt), buf) => {
-                        count += cnt;
-                        bufs[pos + 2] = buf;
-                        if cnt < bufs[pos + 2].bytes_total() {
-                            return (Ok(count), bufs);
-                        }
-                    }
-                }
-                match res4 {
-                    (Err(e), _) => return (Err(e), bufs),
-                    (Ok(cnt), buf) => {
-                        count += cnt;
-                        bufs[pos + 3] = buf;
-                        if cnt < bufs[pos + 3].bytes_total() {
-                            return (Ok(count), bufs);
-                        }
-                    }
-                }
-                pos += 4;
-            }
-
-            if bufs.len() - pos == 3 {
-                let op1 = self.async_read_at_volatile(bufs[pos], offset);
-                offset += bufs[pos].bytes_total() as u64;
-                let op2 = self.async_read_at_volatile(bufs[pos + 1], offset);
-                offset += bufs[pos + 1].bytes_total() as u64;
-                let op3 = self.async_read_at_volatile(bufs[pos + 2], offset);
-                let (res1, res2, res3) = join!(op1, op2, op3);
-
-                match res1 {
-                    (Ok(cnt), buf) => {
-                        count += cnt;
-                        bufs[pos] = buf;
-                        if cnt < bufs[pos].bytes_total() {
-                            return (Ok(count), bufs);
-                        }
-                    }
-                    (Err(e), _) => return (Err(e), bufs),
-                }
-                match res2 {
-                    (Ok(cnt), buf) => {
-                        count += cnt;
-                        bufs[pos + 1] = buf;
-                        if cnt < bufs[pos + 1].bytes_total() {
-                            return (Ok(count), bufs);
-                        }
-                    }
-                    (Err(e), _) => return (Err(e), bufs),
-                }
-                match res3 {
-                    (Ok(cnt), buf) => {
-                        count += cnt;
-                        bufs[pos + 2] = buf;
-                        if cnt < bufs[pos + 2].bytes_total() {
-                            return (Ok(count), bufs);
-                        }
-                    }
-                    (Err(e), _) => return (Err(e), bufs),
-                }
-            } else if bufs.len() - pos == 2 {
-                let op1 = self.async_read_at_volatile(bufs[pos], offset);
-                offset += bufs[pos].bytes_total() as u64;
-                let op2 = self.async_read_at_volatile(bufs[pos + 1], offset);
-                let (res1, res2) = join!(op1, op2);
-
-                match res1 {
-                    (Ok(cnt), buf) => {
-                        count += cnt;
-                        bufs[pos] = buf;
-                        if cnt < bufs[pos].bytes_total() {
-                            return (Ok(count), bufs);
-                        }
-                    }
-                    (Err(e), _) => return (Err(e), bufs),
-                }
-                match res2 {
-                    (Ok(cnt), buf) => {
-                        count += cnt;
-                        bufs[pos + 1] = buf;
-                        if cnt < bufs[pos + 1].bytes_total() {
-                            return (Ok(count), bufs);
-                        }
-                    }
-                    (Err(e), _) => return (Err(e), bufs),
-                }
-            } else if bufs.len() - pos == 1 {
-                let res1 = self.async_read_at_volatile(bufs[pos], offset).await;
-                match res1 {
-                    (Ok(cnt), buf) => {
-                        count += cnt;
-                        bufs[pos] = buf;
-                        if cnt < bufs[pos].bytes_total() {
-                            return (Ok(count), bufs);
-                        }
-                    }
-                    (Err(e), _) => return (Err(e), bufs),
-                }
-            }
-
-            (Ok(count), bufs)
+            read_vectored_chain(self, bufs, offset).await
         }
 
         async fn async_write_at_volatile(
@@ -678,153 +536,133 @@ mod async_io {
 
         async fn async_write_vectored_at_volatile(
             &self,
-            mut bufs: Vec<FileVolatileBuf>,
-            mut offset: u64,
+            bufs: Vec<FileVolatileBuf>,
+            offset: u64,
         ) -> (Result<usize>, Vec<FileVolatileBuf>) {
-            if bufs.len() == 0 {
-                return (Ok(0), bufs);
-            } else if bufs.len() == 1 {
-                let (res, buf) = self.async_write_at_volatile(bufs[0], offset).await;
-                bufs[0] = buf;
-                return (res, bufs);
-            }
+            write_vectored_chain(self, bufs, offset).await
+        }
+    }
 
-            let mut count = 0;
-            let mut pos = 0;
-            while bufs.len() - pos >= 4 {
-                let op1 = self.async_write_at_volatile(bufs[pos], offset);
-                offset += bufs[pos].bytes_total() as u64;
-                let op2 = self.async_write_at_volatile(bufs[pos + 1], offset);
-                offset += bufs[pos + 1].bytes_total() as u64;
-                let op3 = self.async_write_at_volatile(bufs[pos + 2], offset);
-                offset += bufs[pos + 2].bytes_total() as u64;
-                let op4 = self.async_write_at_volatile(bufs[pos + 3], offset);
-                offset += bufs[pos + 3].bytes_total() as u64;
-                let (res1, res2, res3, res4) = join!(op1, op2, op3, op4);
-
-                match res1 {
-                    (Err(e), _) => return (Err(e), bufs),
-                    (Ok(cnt), buf) => {
-                        count += cnt;
-                        bufs[pos] = buf;
-                        if cnt < bufs[pos].bytes_total() {
-                            return (Ok(count), bufs);
-                        }
-                    }
-                }
-                match res2 {
-                    (Err(e), _) => return (Err(e), bufs),
-                    (Ok(cnt), buf) => {
-                        count += cnt;
-                        bufs[pos + 1] = buf;
-                        if cnt < bufs[pos + 1].bytes_total() {
-                            return (Ok(count), bufs);
-                        }
-                    }
-                }
-                match res3 {
-                    (Err(e), _) => return (Err(e), bufs),
-                    (Ok(cnt), buf) => {
-                        count += cnt;
-                        bufs[pos + 2] = buf;
-                        if cnt < bufs[pos + 2].bytes_total() {
-                            return (Ok(count), bufs);
-                        }
-                    }
-                }
-                match res4 {
-                    (Err(e), _) => return (Err(e), bufs),
-                    (Ok(cnt), buf) => {
-                        count += cnt;
-                        bufs[pos + 3] = buf;
-                        if cnt < bufs[pos + 3].bytes_total() {
-                            return (Ok(count), bufs);
-                        }
-                    }
+    // Get offsets of `bufs` laid out back to back from `offset`.
+    fn chain_offsets(bufs: &[FileVolatileBuf], offset: u64) -> Vec<u64> {
+        bufs.iter()
+            .scan(offset, |pos, buf| {
+                let off = *pos;
+                *pos += buf.bytes_total() as u64;
+                Some(off)
+            })
+            .collect()
+    }
+
+    /// Read into `bufs` from `f` at `offset`, like a single read into the buffers concatenated.
+    ///
+    /// Reads of all buffers are submitted as a chain of operations at once. Buffers read short
+    /// get the rest resubmitted at the adjusted offset until full or the end of the file, and
+    /// `bytes_init()` of each buffer tells the number of bytes read into it. Errors are only
+    /// returned if no byte was read.
+    async fn read_vectored_chain<F: AsyncFileReadWriteVolatile + ?Sized>(
+        f: &F,
+        mut bufs: Vec<FileVolatileBuf>,
+        offset: u64,
+    ) -> (Result<usize>, Vec<FileVolatileBuf>) {
+        let offsets = chain_offsets(&bufs, offset);
+        let ops = bufs
+            .iter()
+            .zip(offsets.iter())
+            .map(|(buf, off)| f.async_read_at_volatile(*buf, *off));
+        let results = join_all(ops).await;
+
+        let mut count = 0;
+        let mut eof = false;
+        for (idx, (res, mut buf)) in results.into_iter().enumerate() {
+            let total = buf.bytes_total();
+            let mut done = match res {
+                // Data past the end of the file may come from a concurrent extension, drop it
+                // like a single read would.
+                _ if eof => 0,
+                Ok(cnt) => cnt,
+                Err(e) if count == 0 => return (Err(e), bufs),
+                Err(_) => 0,
+            };
+            while !eof && done > 0 && done < total {
+                // Safe because the rest of the buffer is stable as long as the buffer is.
+                let rest = unsafe {
+                    FileVolatileBuf::from_raw(buf.stable_mut_ptr().add(done), 0, total - done)
+                };
+                match f
+                    .async_read_at_volatile(rest, offsets[idx] + done as u64)
+                    .await
+                {
+                    (Ok(0), _) | (Err(_), _) => break,
+                    (Ok(cnt), _) => done += cnt,
                 }
-                pos += 4;
             }
+            // Safe because `done` bytes of the buffer have been read.
+            unsafe { buf.set_init(done) };
+            bufs[idx] = buf;
+            count += done;
+            eof = eof || done < total;
+        }
 
-            if bufs.len() - pos == 3 {
-                let op1 = self.async_write_at_volatile(bufs[pos], offset);
-                offset += bufs[pos].bytes_total() as u64;
-                let op2 = self.async_write_at_volatile(bufs[pos + 1], offset);
-                offset += bufs[pos + 1].bytes_total() as u64;
-                let op3 = self.async_write_at_volatile(bufs[pos + 2], offset);
-                let (res1, res2, res3) = join!(op1, op2, op3);
-
-                match res1 {
-                    (Ok(cnt), buf) => {
-                        count += cnt;
-                        bufs[pos] = buf;
-                        if cnt < bufs[pos].bytes_total() {
-                            return (Ok(count), bufs);
-                        }
-                    }
-                    (Err(e), _) => return (Err(e), bufs),
-                }
-                match res2 {
-                    (Ok(cnt), buf) => {
-                        count += cnt;
-                        bufs[pos + 1] = buf;
-                        if cnt < bufs[pos + 1].bytes_total() {
-                            return (Ok(count), bufs);
-                        }
-                    }
-                    (Err(e), _) => return (Err(e), bufs),
-                }
-                match res3 {
-                    (Ok(cnt), buf) => {
-                        count += cnt;
-                        bufs[pos + 2] = buf;
-                        if cnt < bufs[pos + 2].bytes_total() {
-                            return (Ok(count), bufs);
-                        }
-                    }
-                    (Err(e), _) => return (Err(e), bufs),
-                }
-            } else if bufs.len() - pos == 2 {
-                let op1 = self.async_write_at_volatile(bufs[pos], offset);
-                offset += bufs[pos].bytes_total() as u64;
-                let op2 = self.async_write_at_volatile(bufs[pos + 1], offset);
-                let (res1, res2) = join!(op1, op2);
-
-                match res1 {
-                    (Ok(cnt), buf) => {
-                        count += cnt;
-                        bufs[pos] = buf;
-                        if cnt < bufs[pos].bytes_total() {
-                            return (Ok(count), bufs);
-                        }
-                    }
-                    (Err(e), _) => return (Err(e), bufs),
-                }
-                match res2 {
-                    (Ok(cnt), buf) => {
-                        count += cnt;
-                        bufs[pos + 1] = buf;
-                        if cnt < bufs[pos + 1].bytes_total() {
-                            return (Ok(count), bufs);
-                        }
-                    }
-                    (Err(e), _) => return (Err(e), bufs),
-                }
-            } else if bufs.len() - pos == 1 {
-                let res1 = self.async_write_at_volatile(bufs[pos], offset).await;
-                match res1 {
-                    (Ok(cnt), buf) => {
-                        count += cnt;
-                        bufs[pos] = buf;
-                        if cnt < bufs[pos].bytes_total() {
-                            return (Ok(count), bufs);
-                        }
+        (Ok(count), bufs)
+    }
+
+    /// Write `bufs` to `f` at `offset`, like a single write of the buffers concatenated.
+    ///
+    /// Writes of all buffers are submitted as a chain of operations at once. Buffers written
+    /// short get the rest resubmitted at the adjusted offset until fully written. The number of
+    /// bytes written is the one of the leading buffers fully written and the first one which
+    /// isn't, data of following buffers may have reached the file anyway. Errors are only
+    /// returned if no byte was written.
+    async fn write_vectored_chain<F: AsyncFileReadWriteVolatile + ?Sized>(
+        f: &F,
+        bufs: Vec<FileVolatileBuf>,
+        offset: u64,
+    ) -> (Result<usize>, Vec<FileVolatileBuf>) {
+        let offsets = chain_offsets(&bufs, offset);
+        let ops = bufs
+            .iter()
+            .zip(offsets.iter())
+            .map(|(buf, off)| f.async_write_at_volatile(*buf, *off));
+        let results = join_all(ops).await;
+
+        let mut count = 0;
+        for (idx, (res, buf)) in results.into_iter().enumerate() {
+            let total = buf.bytes_init();
+            let mut done = match res {
+                Ok(cnt) => cnt,
+                Err(e) if count == 0 => return (Err(e), bufs),
+                Err(_) => return (Ok(count), bufs),
+            };
+            while done < total {
+                // Safe because the rest of the buffer is stable as long as the buffer is.
+                let rest = unsafe {
+                    FileVolatileBuf::from_raw(
+                        buf.stable_ptr().add(done) as *mut u8,
+                        total - done,
+                        total - done,
+                    )
+                };
+                match f
+                    .async_write_at_volatile(rest, offsets[idx] + done as u64)
+                    .await
+                {
+                    (Ok(0), _) if count + done == 0 => {
+                        return (Err(Error::from(std::io::ErrorKind::WriteZero)), bufs)
                     }
-                    (Err(e), _) => return (Err(e), bufs),
+                    (Ok(0), _) => break,
+                    (Ok(cnt), _) => done += cnt,
+                    (Err(e), _) if count + done == 0 => return (Err(e), bufs),
+                    (Err(_), _) => break,
                 }
             }
-
-            (Ok(count), bufs)
+            count += done;
+            if done < total {
+                break;
+            }
         }
+
+        (Ok(count), bufs)
     }
 
     #[async_trait::async_trait(?Send)]
@@ -834,7 +672,7 @@ mod async_io {
             buf: FileVolatileBuf,
             offset: u64,
         ) -> (Result<usize>, FileVolatileBuf) {
-            self.async_read_at_volatile(buf, offset).await
+            (**self).async_read_at_volatile(buf, offset).await
         }
 
         async fn async_read_vectored_at_volatile(
@@ -842,7 +680,7 @@ mod async_io {
             bufs: Vec<FileVolatileBuf>,
             offset: u64,
         ) -> (Result<usize>, Vec<FileVolatileBuf>) {
-            self.async_read_vectored_at_volatile(bufs, offset).await
+            (**self).async_read_vectored_at_volatile(bufs, offset).await
         }
 
         async fn async_write_at_volatile(
@@ -850,7 +688,7 @@ mod async_io {
             buf: FileVolatileBuf,
             offset: u64,
         ) -> (Result<usize>, FileVolatileBuf) {
-            self.async_write_at_volatile(buf, offset).await
+            (**self).async_write_at_volatile(buf, offset).await
         }
 
         async fn async_write_vectored_at_volatile(
@@ -858,7 +696,9 @@ mod async_io {
             bufs: Vec<FileVolatileBuf>,
             offset: u64,
         ) -> (Result<usize>, Vec<FileVolatileBuf>) {
-            self.async_write_vectored_at_volatile(bufs, offset).await
+            (**self)
+                .async_write_vectored_at_volatile(bufs, offset)
+                .await
         }
     }
 
@@ -1045,6 +885,93 @@ mod async_io {
                 assert_eq!(vbufs.len(), 3);
             });
         }
+
+        // A file in memory completing each operation with at most 3 bytes.
+        struct ShortIoFile(std::cell::RefCell<Vec<u8>>);
+
+        #[async_trait::async_trait(?Send)]
+        impl AsyncFileReadWriteVolatile for ShortIoFile {
+            async fn async_read_at_volatile(
+                &self,
+                mut buf: FileVolatileBuf,
+                offset: u64,
+            ) -> (Result<usize>, FileVolatileBuf) {
+                let data = self.0.borrow();
+                let src = data.get(offset as usize..).unwrap_or_default();
+                let cnt = src.len().min(buf.bytes_total()).min(3);
+                unsafe {
+                    std::ptr::copy_nonoverlapping(src.as_ptr(), buf.stable_mut_ptr(), cnt);
+                    buf.set_init(cnt);
+                }
+                (Ok(cnt), buf)
+            }
+
+            async fn async_read_vectored_at_volatile(
+                &self,
+                bufs: Vec<FileVolatileBuf>,
+                offset: u64,
+            ) -> (Result<usize>, Vec<FileVolatileBuf>) {
+                read_vectored_chain(self, bufs, offset).await
+            }
+
+            async fn async_write_at_volatile(
+                &self,
+                buf: FileVolatileBuf,
+                offset: u64,
+            ) -> (Result<usize>, FileVolatileBuf) {
+                let mut data = self.0.borrow_mut();
+                let cnt = buf.bytes_init().min(3);
+                let end = offset as usize + cnt;
+                if data.len() < end {
+                    data.resize(end, 0);
+                }
+                let src = unsafe { std::slice::from_raw_parts(buf.stable_ptr(), cnt) };
+                data[offset as usize..end].copy_from_slice(src);
+                (Ok(cnt), buf)
+            }
+
+            async fn async_write_vectored_at_volatile(
+                &self,
+                bufs: Vec<FileVolatileBuf>,
+                offset: u64,
+            ) -> (Result<usize>, Vec<FileVolatileBuf>) {
+                write_vectored_chain(self, bufs, offset).await
+            }
+        }
+
+        #[test]
+        fn test_vectored_chain_short_io() {
+            let file = ShortIoFile(std::cell::RefCell::new(b"0123456789abcdef".to_vec()));
+            let mut buf1 = vec![0u8; 8];
+            let mut buf2 = vec![0u8; 8];
+
+            // Short reads are resubmitted, up to the end of the file.
+            let bufs = unsafe {
+                vec![
+                    FileVolatileBuf::new(&mut buf1),
+                    FileVolatileBuf::new(&mut buf2),
+                ]
+            };
+            let (res, bufs) =
+                futures::executor::block_on(file.async_read_vectored_at_volatile(bufs, 2));
+            assert_eq!(res.unwrap(), 14);
+            assert_eq!(bufs[0].bytes_init(), 8);
+            assert_eq!(bufs[1].bytes_init(), 6);
+            assert_eq!(&buf1, b"23456789");
+            assert_eq!(&buf2[..6], b"abcdef");
+
+            // Short writes are resubmitted until buffers are fully written.
+            let bufs = unsafe {
+                vec![
+                    FileVolatileBuf::from_raw(b"ABCDE".as_ptr() as *mut u8, 5, 5),
+                    FileVolatileBuf::from_raw(b"FGHIJKL".as_ptr() as *mut u8, 7, 7),
+                ]
+            };
+            let (res, _) =
+                futures::executor::block_on(file.async_write_vectored_at_volatile(bufs, 10));
+            assert_eq!(res.unwrap(), 12);
+            assert_eq!(&*file.0.borrow(), b"0123456789ABCDEFGHIJKL");
+        }
     }
 }
 
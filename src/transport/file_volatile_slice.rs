@@ -141,6 +141,40 @@ impl<'a> FileVolatileSlice<'a> {
             .ok_or(Error::OutOfBounds { addr: new_addr })?;
         unsafe { Ok(Self::new(new_addr as *mut u8, new_size)) }
     }
+
+    /// Return a subslice of this [FileVolatileSlice] of `len` bytes starting at `offset`.
+    pub fn subslice(&self, offset: usize, len: usize) -> Result<Self, Error> {
+        let end = offset.checked_add(len).ok_or(Error::Overflow {
+            base: offset,
+            offset: len,
+        })?;
+        if end > self.size {
+            return Err(Error::OutOfBounds { addr: end });
+        }
+        // Safe because the subslice is within the bounds of this slice.
+        unsafe { Ok(Self::new(self.as_ptr().add(offset), len)) }
+    }
+
+    /// Copy as many bytes as possible from this slice to `dst`, which may overlap with this
+    /// slice, and return the number of bytes copied.
+    pub fn copy_to_volatile_slice(&self, dst: FileVolatileSlice) -> usize {
+        self.as_volatile_slice()
+            .copy_to_volatile_slice(dst.as_volatile_slice());
+        self.size.min(dst.size)
+    }
+
+    /// Copy as many bytes as possible from this slice to `buf`, and return the number of bytes
+    /// copied.
+    pub fn copy_to_slice(&self, buf: &mut [u8]) -> usize {
+        self.as_volatile_slice().copy_to(buf)
+    }
+
+    /// Copy as many bytes as possible from `buf` to this slice, and return the number of bytes
+    /// copied.
+    pub fn copy_from_slice(&self, buf: &[u8]) -> usize {
+        self.as_volatile_slice().copy_from(buf);
+        self.size.min(buf.len())
+    }
 }
 
 impl<'a> Bytes<usize> for FileVolatileSlice<'a> {
@@ -159,7 +193,7 @@ impl<'a> Bytes<usize> for FileVolatileSlice<'a> {
     }
 
     fn read_slice(&self, buf: &mut [u8], addr: usize) -> Result<(), Self::E> {
-        VolatileSlice::write_slice(&self.as_volatile_slice(), buf, addr)
+        VolatileSlice::read_slice(&self.as_volatile_slice(), buf, addr)
     }
 
     fn read_from<F>(&self, addr: usize, src: &mut F, count: usize) -> Result<usize, Self::E>
@@ -321,4 +355,71 @@ mod tests {
 
         assert_eq!(buffer[0x10], 1);
     }
+
+    #[test]
+    fn test_file_volatile_slice_read_slice() {
+        let mut buffer = [0u8; 16];
+        let s = unsafe { FileVolatileSlice::new(buffer.as_mut_ptr(), buffer.len()) };
+        s.write_slice(b"abcd", 4).unwrap();
+
+        let mut buf = [0u8; 4];
+        s.read_slice(&mut buf, 4).unwrap();
+        assert_eq!(&buf, b"abcd");
+        assert!(s.read_slice(&mut buf, 14).is_err());
+        assert_eq!(&buffer[4..8], b"abcd");
+    }
+
+    #[test]
+    fn test_file_volatile_slice_subslice() {
+        let mut buffer: Vec<u8> = (0..16).collect();
+        let s = unsafe { FileVolatileSlice::new(buffer.as_mut_ptr(), buffer.len()) };
+
+        let sub = s.subslice(4, 8).unwrap();
+        assert_eq!(sub.len(), 8);
+        assert_eq!(sub.as_ptr(), unsafe { s.as_ptr().add(4) });
+        assert_eq!(s.subslice(16, 0).unwrap().len(), 0);
+        assert_eq!(s.subslice(0, 16).unwrap().len(), 16);
+        assert!(matches!(
+            s.subslice(8, 9),
+            Err(Error::OutOfBounds { addr: 17 })
+        ));
+        assert!(matches!(
+            s.subslice(17, 0),
+            Err(Error::OutOfBounds { addr: 17 })
+        ));
+        assert!(matches!(
+            s.subslice(1, usize::MAX),
+            Err(Error::Overflow { .. })
+        ));
+        assert!(sub.subslice(4, 5).is_err());
+    }
+
+    #[test]
+    fn test_file_volatile_slice_copy() {
+        let mut buffer: Vec<u8> = (0..16).collect();
+        let s = unsafe { FileVolatileSlice::new(buffer.as_mut_ptr(), buffer.len()) };
+
+        let mut buf = [0u8; 4];
+        assert_eq!(s.subslice(2, 2).unwrap().copy_to_slice(&mut buf), 2);
+        assert_eq!(buf, [2, 3, 0, 0]);
+        assert_eq!(s.copy_to_slice(&mut buf), 4);
+        assert_eq!(buf, [0, 1, 2, 3]);
+        assert_eq!(s.subslice(14, 2).unwrap().copy_from_slice(b"xyz"), 2);
+        assert_eq!(s.copy_from_slice(&[]), 0);
+
+        // Overlapping copies move the data forward and backward.
+        let (src, dst) = (s.subslice(0, 8).unwrap(), s.subslice(2, 8).unwrap());
+        assert_eq!(src.copy_to_volatile_slice(dst), 8);
+        // Empty slices copy nothing.
+        assert_eq!(src.copy_to_volatile_slice(s.subslice(0, 0).unwrap()), 0);
+        assert_eq!(s.subslice(4, 0).unwrap().copy_to_volatile_slice(src), 0);
+        assert_eq!(
+            buffer,
+            [0, 1, 0, 1, 2, 3, 4, 5, 6, 7, 10, 11, 12, 13, b'x', b'y']
+        );
+        let s = unsafe { FileVolatileSlice::new(buffer.as_mut_ptr(), buffer.len()) };
+        let (src, dst) = (s.subslice(4, 12).unwrap(), s.subslice(0, 4).unwrap());
+        assert_eq!(src.copy_to_volatile_slice(dst), 4);
+        assert_eq!(&buffer[..6], [2, 3, 4, 5, 2, 3]);
+    }
 }
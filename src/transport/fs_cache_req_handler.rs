@@ -7,7 +7,10 @@
 pub trait FsCacheReqHandler {}
 
 #[cfg(feature = "virtiofs")]
-pub use virtiofs::FsCacheReqHandler;
+pub use virtiofs::{
+    FsCacheReqHandler, FsSlaveChannel, FsSlaveMsg, SlaveFsCacheMapper, FS_SLAVE_ENTRIES,
+    FS_SLAVE_MAP_R, FS_SLAVE_MAP_W,
+};
 
 #[cfg(feature = "virtiofs")]
 mod virtiofs {
@@ -15,15 +18,19 @@ mod virtiofs {
     use std::os::unix::io::RawFd;
 
     #[cfg(feature = "vhost-user-fs")]
-    use vhost::vhost_user::message::{
-        VhostUserFSSlaveMsg, VhostUserFSSlaveMsgFlags, VHOST_USER_FS_SLAVE_ENTRIES,
-    };
+    use vhost::vhost_user::message::{VhostUserFSSlaveMsg, VhostUserFSSlaveMsgFlags};
     #[cfg(feature = "vhost-user-fs")]
     use vhost::vhost_user::{SlaveFsCacheReq, VhostUserMasterReqHandler};
 
-    use crate::abi::virtio_fs::RemovemappingOne;
-    #[cfg(feature = "vhost-user-fs")]
-    use crate::abi::virtio_fs::SetupmappingFlags;
+    use crate::abi::virtio_fs::{RemovemappingOne, SetupmappingFlags};
+    use crate::api::errno::errno_of;
+
+    /// Max number of entries carried by one slave channel map/unmap message.
+    pub const FS_SLAVE_ENTRIES: usize = 8;
+    /// Map the DAX window range with read permission.
+    pub const FS_SLAVE_MAP_R: u64 = 0x1;
+    /// Map the DAX window range with write permission.
+    pub const FS_SLAVE_MAP_W: u64 = 0x2;
 
     /// Trait to support virtio-fs DAX Window operations.
     ///
@@ -53,6 +60,177 @@ mod virtiofs {
         fn unmap(&mut self, requests: Vec<RemovemappingOne>) -> io::Result<()>;
     }
 
+    /// Map/unmap message forwarded to the VMM over the vhost-user slave channel.
+    ///
+    /// The layout mirrors `VHOST_USER_SLAVE_FS_MAP/UNMAP` messages: up to [FS_SLAVE_ENTRIES]
+    /// ranges, unused entries are left zeroed.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct FsSlaveMsg {
+        /// File offset.
+        pub fd_offset: [u64; FS_SLAVE_ENTRIES],
+        /// Offset into the DAX window.
+        pub cache_offset: [u64; FS_SLAVE_ENTRIES],
+        /// Size of region to map.
+        pub len: [u64; FS_SLAVE_ENTRIES],
+        /// `FS_SLAVE_MAP_R`/`FS_SLAVE_MAP_W` permissions of the mapping.
+        pub flags: [u64; FS_SLAVE_ENTRIES],
+    }
+
+    impl FsSlaveMsg {
+        fn is_valid(&self) -> bool {
+            (0..FS_SLAVE_ENTRIES).all(|i| {
+                self.flags[i] & !(FS_SLAVE_MAP_R | FS_SLAVE_MAP_W) == 0
+                    && self.fd_offset[i].checked_add(self.len[i]).is_some()
+                    && self.cache_offset[i].checked_add(self.len[i]).is_some()
+            })
+        }
+    }
+
+    /// Channel to send DAX window map/unmap requests to the VMM.
+    ///
+    /// The returned value is the reply from the VMM, zero on success and a negated errno
+    /// otherwise.
+    pub trait FsSlaveChannel: Send + Sync + 'static {
+        /// Send a map request, `fds` holds the file descriptors backing the entries.
+        fn fs_slave_map(&mut self, msg: &FsSlaveMsg, fds: &[RawFd]) -> io::Result<u64>;
+
+        /// Send an unmap request.
+        fn fs_slave_unmap(&mut self, msg: &FsSlaveMsg) -> io::Result<u64>;
+    }
+
+    /// [FsCacheReqHandler] forwarding DAX window requests over a [FsSlaveChannel].
+    ///
+    /// Instead of mmapping the file locally, map/unmap requests are formatted into slave
+    /// channel messages, split into batches of at most [FS_SLAVE_ENTRIES] entries, and VMM
+    /// error replies are translated into errnos for the FUSE reply.
+    pub struct SlaveFsCacheMapper<C> {
+        channel: C,
+    }
+
+    impl<C: FsSlaveChannel> SlaveFsCacheMapper<C> {
+        /// Create a mapper on top of the slave `channel`.
+        pub fn new(channel: C) -> Self {
+            SlaveFsCacheMapper { channel }
+        }
+
+        /// Get a reference to the underlying slave channel.
+        pub fn channel(&self) -> &C {
+            &self.channel
+        }
+    }
+
+    impl<C: FsSlaveChannel> FsCacheReqHandler for SlaveFsCacheMapper<C> {
+        fn map(
+            &mut self,
+            foffset: u64,
+            moffset: u64,
+            len: u64,
+            flags: u64,
+            fd: RawFd,
+        ) -> io::Result<()> {
+            send_map(&mut self.channel, foffset, moffset, len, flags, fd)
+        }
+
+        fn unmap(&mut self, requests: Vec<RemovemappingOne>) -> io::Result<()> {
+            send_unmap(&mut self.channel, &requests)
+        }
+    }
+
+    fn send_map<C: FsSlaveChannel + ?Sized>(
+        channel: &mut C,
+        foffset: u64,
+        moffset: u64,
+        len: u64,
+        flags: u64,
+        fd: RawFd,
+    ) -> io::Result<()> {
+        let mut msg = FsSlaveMsg::default();
+        msg.fd_offset[0] = foffset;
+        msg.cache_offset[0] = moffset;
+        msg.len[0] = len;
+        msg.flags[0] = if (flags & SetupmappingFlags::WRITE.bits()) != 0 {
+            FS_SLAVE_MAP_W | FS_SLAVE_MAP_R
+        } else {
+            FS_SLAVE_MAP_R
+        };
+        if !msg.is_valid() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        translate_reply(channel.fs_slave_map(&msg, &[fd]))
+    }
+
+    fn send_unmap<C: FsSlaveChannel + ?Sized>(
+        channel: &mut C,
+        requests: &[RemovemappingOne],
+    ) -> io::Result<()> {
+        for chunk in requests.chunks(FS_SLAVE_ENTRIES) {
+            let mut msg = FsSlaveMsg::default();
+
+            for (ind, req) in chunk.iter().enumerate() {
+                msg.len[ind] = req.len;
+                msg.cache_offset[ind] = req.moffset;
+            }
+            if !msg.is_valid() {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+
+            translate_reply(channel.fs_slave_unmap(&msg))?;
+        }
+
+        Ok(())
+    }
+
+    // Convert the VMM reply into a result carrying an errno suitable for the FUSE reply.
+    fn translate_reply(reply: io::Result<u64>) -> io::Result<()> {
+        match reply {
+            Ok(0) => Ok(()),
+            Ok(v) => {
+                let errno = (v as i64).checked_neg().unwrap_or(0);
+                if errno > 0 && errno < 4096 {
+                    Err(io::Error::from_raw_os_error(errno as i32))
+                } else {
+                    Err(io::Error::from_raw_os_error(libc::EIO))
+                }
+            }
+            Err(e) => match errno_of(&e) {
+                Some(_) => Err(e),
+                None => {
+                    error!("fuse: slave channel request failed: {}", e);
+                    Err(io::Error::from_raw_os_error(libc::EIO))
+                }
+            },
+        }
+    }
+
+    #[cfg(feature = "vhost-user-fs")]
+    fn to_vhost_msg(msg: &FsSlaveMsg) -> VhostUserFSSlaveMsg {
+        let mut vmsg = VhostUserFSSlaveMsg {
+            fd_offset: msg.fd_offset,
+            cache_offset: msg.cache_offset,
+            len: msg.len,
+            ..Default::default()
+        };
+        for (ind, flags) in msg.flags.iter().enumerate() {
+            vmsg.flags[ind] = VhostUserFSSlaveMsgFlags::from_bits_truncate(*flags);
+        }
+        vmsg
+    }
+
+    #[cfg(feature = "vhost-user-fs")]
+    impl FsSlaveChannel for SlaveFsCacheReq {
+        fn fs_slave_map(&mut self, msg: &FsSlaveMsg, fds: &[RawFd]) -> io::Result<u64> {
+            let fd = fds
+                .first()
+                .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+            VhostUserMasterReqHandler::fs_slave_map(self, &to_vhost_msg(msg), fd)
+        }
+
+        fn fs_slave_unmap(&mut self, msg: &FsSlaveMsg) -> io::Result<u64> {
+            VhostUserMasterReqHandler::fs_slave_unmap(self, &to_vhost_msg(msg))
+        }
+    }
+
     #[cfg(feature = "vhost-user-fs")]
     impl FsCacheReqHandler for SlaveFsCacheReq {
         fn map(
@@ -63,34 +241,129 @@ mod virtiofs {
             flags: u64,
             fd: RawFd,
         ) -> io::Result<()> {
-            let mut msg: VhostUserFSSlaveMsg = Default::default();
-            msg.fd_offset[0] = foffset;
-            msg.cache_offset[0] = moffset;
-            msg.len[0] = len;
-            msg.flags[0] = if (flags & SetupmappingFlags::WRITE.bits()) != 0 {
-                VhostUserFSSlaveMsgFlags::MAP_W | VhostUserFSSlaveMsgFlags::MAP_R
-            } else {
-                VhostUserFSSlaveMsgFlags::MAP_R
-            };
+            send_map(self, foffset, moffset, len, flags, fd)
+        }
+
+        fn unmap(&mut self, requests: Vec<RemovemappingOne>) -> io::Result<()> {
+            send_unmap(self, &requests)
+        }
+    }
 
-            self.fs_slave_map(&msg, &fd)?;
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::sync::{Arc, Mutex};
 
-            Ok(())
+        #[derive(Default)]
+        struct MockState {
+            maps: Vec<(FsSlaveMsg, Vec<RawFd>)>,
+            unmaps: Vec<FsSlaveMsg>,
+            replies: Vec<io::Result<u64>>,
         }
 
-        fn unmap(&mut self, requests: Vec<RemovemappingOne>) -> io::Result<()> {
-            for chunk in requests.chunks(VHOST_USER_FS_SLAVE_ENTRIES) {
-                let mut msg: VhostUserFSSlaveMsg = Default::default();
+        #[derive(Clone, Default)]
+        struct MockChannel(Arc<Mutex<MockState>>);
 
-                for (ind, req) in chunk.iter().enumerate() {
-                    msg.len[ind] = req.len;
-                    msg.cache_offset[ind] = req.moffset;
+        impl MockChannel {
+            fn reply(&self) -> io::Result<u64> {
+                let mut state = self.0.lock().unwrap();
+                if state.replies.is_empty() {
+                    Ok(0)
+                } else {
+                    state.replies.remove(0)
                 }
+            }
+        }
 
-                self.fs_slave_unmap(&msg)?;
+        impl FsSlaveChannel for MockChannel {
+            fn fs_slave_map(&mut self, msg: &FsSlaveMsg, fds: &[RawFd]) -> io::Result<u64> {
+                self.0.lock().unwrap().maps.push((*msg, fds.to_vec()));
+                self.reply()
             }
 
-            Ok(())
+            fn fs_slave_unmap(&mut self, msg: &FsSlaveMsg) -> io::Result<u64> {
+                self.0.lock().unwrap().unmaps.push(*msg);
+                self.reply()
+            }
+        }
+
+        #[test]
+        fn test_slave_map_message() {
+            let channel = MockChannel::default();
+            let mut mapper = SlaveFsCacheMapper::new(channel.clone());
+
+            mapper.map(0x1000, 0x2000, 0x3000, 0, 5).unwrap();
+            mapper
+                .map(0, 0x4000, 0x1000, SetupmappingFlags::WRITE.bits(), 6)
+                .unwrap();
+
+            let state = channel.0.lock().unwrap();
+            assert_eq!(state.maps.len(), 2);
+            let (msg, fds) = &state.maps[0];
+            assert_eq!(fds, &[5]);
+            assert_eq!(msg.fd_offset[0], 0x1000);
+            assert_eq!(msg.cache_offset[0], 0x2000);
+            assert_eq!(msg.len[0], 0x3000);
+            assert_eq!(msg.flags[0], FS_SLAVE_MAP_R);
+            assert_eq!(msg.len[1], 0);
+            let (msg, fds) = &state.maps[1];
+            assert_eq!(fds, &[6]);
+            assert_eq!(msg.flags[0], FS_SLAVE_MAP_R | FS_SLAVE_MAP_W);
+
+            drop(state);
+            let err = mapper.map(u64::MAX, 0, 2, 0, 5).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+            assert_eq!(channel.0.lock().unwrap().maps.len(), 2);
+        }
+
+        #[test]
+        fn test_slave_unmap_batching() {
+            let channel = MockChannel::default();
+            let mut mapper = SlaveFsCacheMapper::new(channel.clone());
+
+            let requests = (0..(FS_SLAVE_ENTRIES as u64 * 2 + 3))
+                .map(|i| RemovemappingOne {
+                    moffset: i * 0x1000,
+                    len: 0x1000,
+                })
+                .collect();
+            mapper.unmap(requests).unwrap();
+
+            let state = channel.0.lock().unwrap();
+            assert_eq!(state.unmaps.len(), 3);
+            assert_eq!(state.unmaps[0].cache_offset[7], 7 * 0x1000);
+            assert_eq!(state.unmaps[1].cache_offset[0], 8 * 0x1000);
+            assert_eq!(state.unmaps[2].cache_offset[2], 18 * 0x1000);
+            assert_eq!(state.unmaps[2].len[2], 0x1000);
+            assert_eq!(state.unmaps[2].len[3], 0);
+
+            drop(state);
+            mapper.unmap(Vec::new()).unwrap();
+            assert_eq!(channel.0.lock().unwrap().unmaps.len(), 3);
+        }
+
+        #[test]
+        fn test_slave_error_translation() {
+            let channel = MockChannel::default();
+            let mut mapper = SlaveFsCacheMapper::new(channel.clone());
+
+            channel.0.lock().unwrap().replies = vec![
+                Ok((-libc::ENOSPC as i64) as u64),
+                Ok(1),
+                Err(io::Error::from(io::ErrorKind::Other)),
+                Err(io::Error::from_raw_os_error(libc::ENOSYS)),
+            ];
+            let errno = |r: io::Result<()>| r.unwrap_err().raw_os_error();
+            assert_eq!(errno(mapper.map(0, 0, 0x1000, 0, 3)), Some(libc::ENOSPC));
+            assert_eq!(errno(mapper.map(0, 0, 0x1000, 0, 3)), Some(libc::EIO));
+            assert_eq!(errno(mapper.map(0, 0, 0x1000, 0, 3)), Some(libc::EIO));
+            assert_eq!(errno(mapper.map(0, 0, 0x1000, 0, 3)), Some(libc::ENOSYS));
+
+            // A failed batch stops the remaining ones from being sent.
+            channel.0.lock().unwrap().replies = vec![Ok(0), Ok((-libc::EINVAL as i64) as u64)];
+            let requests = vec![RemovemappingOne::default(); FS_SLAVE_ENTRIES * 3];
+            assert_eq!(errno(mapper.unmap(requests)), Some(libc::EINVAL));
+            assert_eq!(channel.0.lock().unwrap().unmaps.len(), 2);
         }
     }
 }
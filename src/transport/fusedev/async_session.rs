@@ -0,0 +1,535 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Serve fuse sessions from a tokio runtime.
+//!
+//! [FuseChannel](super::FuseChannel) blocks a thread in epoll for each channel, which doesn't fit
+//! daemons otherwise running on a tokio runtime. An [AsyncFuseChannel] instead registers the fuse
+//! device with the reactor of the runtime, and handles each request it reads by
+//! [Server::async_handle_message], concurrently with the other requests of the channel. A single
+//! threaded runtime then serves many requests at once, by awaiting the IO of the backend instead
+//! of blocking worker threads.
+//!
+//! Channels stop reading requests when a shutdown future completes, or when the session is
+//! umounted, and wait for the requests in flight to complete before returning.
+//!
+//! ## Examples
+//! ```ignore
+//! let session = AsyncFuseSession::new(session);
+//! tokio_uring::start(async {
+//!     let (tx, rx) = futures::channel::oneshot::channel::<()>();
+//!     // Send to `tx` to stop serving.
+//!     session.run(&server, async move {
+//!         let _ = rx.await;
+//!     })
+//!     .await
+//! })?;
+//! ```
+
+use std::fs::File;
+use std::future::Future;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use futures::future::{pending, FutureExt};
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::{pin_mut, select_biased};
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::unistd::read;
+use tokio::io::unix::AsyncFd;
+
+use super::{Error::SessionFailure, FuseBuf, FuseDevWriter, FuseSession, Reader, Result};
+use crate::api::filesystem::AsyncFileSystem;
+use crate::api::server::Server;
+
+// Default number of requests handled concurrently by a channel.
+const DEFAULT_MAX_REQUESTS: usize = 64;
+
+/// A fuse session served from a tokio runtime.
+///
+/// The runtime must have the IO driver enabled, like the runtimes of `tokio_uring::start()`, or
+/// tokio runtimes built with `enable_io()`. Channels don't react to
+/// [FuseSession::shutdown](super::FuseSession::shutdown), which only wakes the blocking ones, but
+/// complete when the shutdown future given to them completes.
+pub struct AsyncFuseSession {
+    session: FuseSession,
+}
+
+impl AsyncFuseSession {
+    /// Serve `session`, which should be mounted before creating channels.
+    pub fn new(session: FuseSession) -> Self {
+        AsyncFuseSession { session }
+    }
+
+    /// Get the underlying session.
+    pub fn session(&self) -> &FuseSession {
+        &self.session
+    }
+
+    /// Get the underlying session mutably, to mount or umount it.
+    pub fn session_mut(&mut self) -> &mut FuseSession {
+        &mut self.session
+    }
+
+    /// Get back the underlying session.
+    pub fn into_inner(self) -> FuseSession {
+        self.session
+    }
+
+    /// Create a new channel, registered with the reactor of the current runtime.
+    pub fn new_channel(&self) -> Result<AsyncFuseChannel> {
+        AsyncFuseChannel::new(self.session.channel_file()?, self.session.bufsize())
+    }
+
+    /// Serve requests by `server` on a new channel, until `shutdown` completes or the session
+    /// is umounted.
+    pub async fn run<F, G>(&self, server: &Server<F>, shutdown: G) -> Result<()>
+    where
+        F: AsyncFileSystem + Sync,
+        G: Future<Output = ()>,
+    {
+        self.new_channel()?.serve(server, shutdown).await
+    }
+}
+
+/// A channel reading requests from the fuse device without blocking the runtime.
+pub struct AsyncFuseChannel {
+    file: AsyncFd<File>,
+    bufsize: usize,
+    max_requests: usize,
+}
+
+// What happened while serving a channel.
+enum Event {
+    Shutdown,
+    Handled,
+    Read(Result<Option<usize>>),
+}
+
+impl AsyncFuseChannel {
+    /// Create a channel reading requests of at most `bufsize` bytes from the fuse device `file`,
+    /// registered with the reactor of the current runtime.
+    pub fn new(file: File, bufsize: usize) -> Result<Self> {
+        fcntl(file.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
+            .map_err(|e| SessionFailure(format!("set fd nonblocking: {}", e)))?;
+        let file = AsyncFd::new(file)
+            .map_err(|e| SessionFailure(format!("register channel fd: {}", e)))?;
+
+        Ok(AsyncFuseChannel {
+            file,
+            bufsize,
+            max_requests: DEFAULT_MAX_REQUESTS,
+        })
+    }
+
+    /// Handle at most `max` requests concurrently, each holding two buffers of the size of the
+    /// channel. Further requests are left in the fuse device until some complete.
+    ///
+    /// The default value for this option is 64.
+    pub fn with_max_requests(mut self, max: usize) -> Self {
+        self.max_requests = max.max(1);
+        self
+    }
+
+    /// Get the size of the buffers receiving requests.
+    pub fn bufsize(&self) -> usize {
+        self.bufsize
+    }
+
+    /// Serve requests by `server` until `shutdown` completes or the session is umounted, then
+    /// wait for the requests in flight to complete.
+    pub async fn serve<F, G>(&self, server: &Server<F>, shutdown: G) -> Result<()>
+    where
+        F: AsyncFileSystem + Sync,
+        G: Future<Output = ()>,
+    {
+        let fd = self.file.as_raw_fd();
+        let shutdown = shutdown.fuse();
+        pin_mut!(shutdown);
+        let mut requests = FuturesUnordered::new();
+        let mut buf = Vec::new();
+        let mut res = Ok(());
+
+        loop {
+            if buf.is_empty() {
+                buf = vec![0u8; self.bufsize];
+            }
+            let event = {
+                let full = requests.len() >= self.max_requests;
+                let read = async {
+                    if full {
+                        pending().await
+                    } else {
+                        self.read_request(&mut buf).await
+                    }
+                }
+                .fuse();
+                pin_mut!(read);
+                select_biased! {
+                    _ = shutdown => Event::Shutdown,
+                    _ = requests.select_next_some() => Event::Handled,
+                    r = read => Event::Read(r),
+                }
+            };
+            match event {
+                Event::Shutdown => {
+                    info!("fuse: async channel shutting down");
+                    break;
+                }
+                Event::Handled => {}
+                Event::Read(Ok(Some(len))) => {
+                    let buf = std::mem::take(&mut buf);
+                    requests.push(Self::handle_request(server, fd, buf, len, self.bufsize));
+                }
+                Event::Read(Ok(None)) => break,
+                Event::Read(Err(e)) => {
+                    res = Err(e);
+                    break;
+                }
+            }
+        }
+
+        while requests.next().await.is_some() {}
+        res
+    }
+
+    // Read the next request into `buf`, return `None` if the session is umounted.
+    async fn read_request(&self, buf: &mut [u8]) -> Result<Option<usize>> {
+        loop {
+            let mut guard = self
+                .file
+                .readable()
+                .await
+                .map_err(|e| SessionFailure(format!("poll channel fd: {}", e)))?;
+            let res = guard.try_io(|file| read(file.as_raw_fd(), buf).map_err(io::Error::from));
+            match res {
+                Ok(Ok(0)) => {
+                    info!("fuse: channel fd closed");
+                    return Ok(None);
+                }
+                Ok(Ok(len)) => return Ok(Some(len)),
+                Ok(Err(e)) => match Errno::from_i32(e.raw_os_error().unwrap_or(0)) {
+                    // ENOENT means the operation was interrupted, it's safe to restart.
+                    Errno::ENOENT | Errno::EINTR => continue,
+                    Errno::ENODEV => {
+                        info!("fuse filesystem umounted");
+                        return Ok(None);
+                    }
+                    e => {
+                        warn!(
+                            "read fuse dev failed on fd {}: {}",
+                            self.file.as_raw_fd(),
+                            e
+                        );
+                        return Err(SessionFailure(format!("read new request: {:?}", e)));
+                    }
+                },
+                // Another channel has fetched the request.
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    // Handle the request of `len` bytes in `buf`, replying to `fd`.
+    async fn handle_request<F: AsyncFileSystem + Sync>(
+        server: &Server<F>,
+        fd: RawFd,
+        mut buf: Vec<u8>,
+        len: usize,
+        bufsize: usize,
+    ) {
+        // Requests are handled concurrently, so replies can't reuse the buffer of the request
+        // like blocking channels do.
+        let mut out = vec![0u8; bufsize];
+        let reader = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut buf[..len]));
+        let writer = FuseDevWriter::<()>::new(fd, &mut out);
+        let (reader, writer) = match (reader, writer) {
+            (Ok(r), Ok(w)) => (r, w),
+            (Err(e), _) | (_, Err(e)) => {
+                error!("fuse: failed to prepare request buffers, {}", e);
+                return;
+            }
+        };
+        // Safe because both buffers are owned by this future, so they are valid until the
+        // request completes.
+        let res = unsafe {
+            server
+                .async_handle_message(reader, writer.into(), None, None)
+                .await
+        };
+        if let Err(e) = res {
+            error!("failed to handle fuse request, {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+    use std::mem::size_of;
+    use std::os::unix::io::FromRawFd;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use nix::sys::socket::{socketpair, AddressFamily, SockFlag, SockType};
+    use vm_memory::ByteValued;
+
+    use super::*;
+    use crate::abi::fuse_abi::{
+        stat64, CreateIn, GetattrIn, InHeader, Opcode, OutHeader, SetattrValid,
+    };
+    use crate::api::filesystem::{
+        AsyncZeroCopyReader, AsyncZeroCopyWriter, Context, Entry, FileSystem, OpenOptions,
+    };
+
+    // File system whose getattr of inode 3 waits for `gate`, like a slow backend.
+    #[derive(Default)]
+    struct Backend {
+        gate: tokio::sync::Notify,
+        waiting: AtomicBool,
+    }
+
+    #[derive(Default)]
+    struct SlowFs(Arc<Backend>);
+
+    impl FileSystem for SlowFs {
+        type Inode = u64;
+        type Handle = u64;
+    }
+
+    fn enosys<T>() -> io::Result<T> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    #[async_trait]
+    impl AsyncFileSystem for SlowFs {
+        async fn async_lookup(&self, _: &Context, _: u64, _: &CStr) -> io::Result<Entry> {
+            enosys()
+        }
+
+        async fn async_getattr(
+            &self,
+            _: &Context,
+            inode: u64,
+            _: Option<u64>,
+        ) -> io::Result<(stat64, Duration)> {
+            if inode == 3 {
+                self.0.waiting.store(true, Ordering::SeqCst);
+                self.0.gate.notified().await;
+            }
+            let mut st: stat64 = unsafe { std::mem::zeroed() };
+            st.st_ino = inode;
+            Ok((st, Duration::from_secs(1)))
+        }
+
+        async fn async_setattr(
+            &self,
+            _: &Context,
+            _: u64,
+            _: stat64,
+            _: Option<u64>,
+            _: SetattrValid,
+        ) -> io::Result<(stat64, Duration)> {
+            enosys()
+        }
+
+        async fn async_open(
+            &self,
+            _: &Context,
+            _: u64,
+            _: u32,
+            _: u32,
+        ) -> io::Result<(Option<u64>, OpenOptions)> {
+            enosys()
+        }
+
+        async fn async_create(
+            &self,
+            _: &Context,
+            _: u64,
+            _: &CStr,
+            _: CreateIn,
+        ) -> io::Result<(Entry, Option<u64>, OpenOptions)> {
+            enosys()
+        }
+
+        async fn async_read(
+            &self,
+            _: &Context,
+            _: u64,
+            _: u64,
+            _: &mut (dyn AsyncZeroCopyWriter + Send),
+            _: u32,
+            _: u64,
+            _: Option<u64>,
+            _: u32,
+        ) -> io::Result<usize> {
+            enosys()
+        }
+
+        async fn async_write(
+            &self,
+            _: &Context,
+            _: u64,
+            _: u64,
+            _: &mut (dyn AsyncZeroCopyReader + Send),
+            _: u32,
+            _: u64,
+            _: Option<u64>,
+            _: bool,
+            _: u32,
+            _: u32,
+        ) -> io::Result<usize> {
+            enosys()
+        }
+
+        async fn async_fsync(&self, _: &Context, _: u64, _: bool, _: u64) -> io::Result<()> {
+            enosys()
+        }
+
+        async fn async_fallocate(
+            &self,
+            _: &Context,
+            _: u64,
+            _: u64,
+            _: u32,
+            _: u64,
+            _: u64,
+        ) -> io::Result<()> {
+            enosys()
+        }
+
+        async fn async_fsyncdir(&self, _: &Context, _: u64, _: bool, _: u64) -> io::Result<()> {
+            enosys()
+        }
+    }
+
+    // Create a pair of connected sockets keeping message boundaries like the fuse device, the
+    // first one for the channel and the other one for the kernel side.
+    fn loopback() -> (File, File) {
+        let (a, b) = socketpair(
+            AddressFamily::Unix,
+            SockType::SeqPacket,
+            None,
+            SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+        )
+        .unwrap();
+        // Safe because we own the new fds.
+        unsafe { (File::from_raw_fd(a), File::from_raw_fd(b)) }
+    }
+
+    fn send_getattr(kernel: &AsyncFd<File>, unique: u64, nodeid: u64) {
+        let in_header = InHeader {
+            len: (size_of::<InHeader>() + size_of::<GetattrIn>()) as u32,
+            opcode: Opcode::Getattr as u32,
+            unique,
+            nodeid,
+            ..Default::default()
+        };
+        let mut msg = in_header.as_slice().to_vec();
+        msg.extend_from_slice(GetattrIn::default().as_slice());
+        nix::unistd::write(kernel.as_raw_fd(), &msg).unwrap();
+    }
+
+    // Receive the next reply, return its unique id.
+    async fn recv_reply(kernel: &AsyncFd<File>) -> u64 {
+        let mut buf = vec![0u8; 0x1000];
+        loop {
+            let mut guard = kernel.readable().await.unwrap();
+            let res = guard.try_io(|f| read(f.as_raw_fd(), &mut buf).map_err(io::Error::from));
+            if let Ok(len) = res {
+                let len = len.unwrap();
+                let out = OutHeader::from_slice(&buf[..size_of::<OutHeader>()]).unwrap();
+                assert_eq!(out.len as usize, len);
+                assert_eq!(out.error, 0);
+                return out.unique;
+            }
+        }
+    }
+
+    #[test]
+    fn test_async_channel_concurrent_requests() {
+        let fs = SlowFs::default();
+        let backend = fs.0.clone();
+        let server = Server::new(fs);
+        let (file, kernel) = loopback();
+
+        tokio_uring::start(async {
+            let kernel = AsyncFd::new(kernel).unwrap();
+            let channel = AsyncFuseChannel::new(file, 0x1000).unwrap();
+            let (tx, rx) = futures::channel::oneshot::channel::<()>();
+            let serve = channel.serve(&server, async move {
+                let _ = rx.await;
+            });
+            let kernel_side = async {
+                // The getattr of inode 3 waits for the backend, the one of the root completes
+                // meanwhile.
+                send_getattr(&kernel, 1, 3);
+                send_getattr(&kernel, 2, 1);
+                assert_eq!(recv_reply(&kernel).await, 2);
+                assert!(backend.waiting.load(Ordering::SeqCst));
+
+                backend.gate.notify_one();
+                assert_eq!(recv_reply(&kernel).await, 1);
+                tx.send(()).unwrap();
+            };
+            let (res, _) = futures::join!(serve, kernel_side);
+            res.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_async_channel_shutdown() {
+        let fs = SlowFs::default();
+        let backend = fs.0.clone();
+        let server = Server::new(fs);
+        let (file, kernel) = loopback();
+
+        tokio_uring::start(async {
+            let kernel = AsyncFd::new(kernel).unwrap();
+            let channel = AsyncFuseChannel::new(file, 0x1000).unwrap();
+            let (tx, rx) = futures::channel::oneshot::channel::<()>();
+            let serve = channel.serve(&server, async move {
+                let _ = rx.await;
+            });
+            let kernel_side = async {
+                // Requests in flight complete after shutting down, but no more are read.
+                send_getattr(&kernel, 1, 3);
+                while !backend.waiting.load(Ordering::SeqCst) {
+                    tokio::task::yield_now().await;
+                }
+                tx.send(()).unwrap();
+                tokio::task::yield_now().await;
+                send_getattr(&kernel, 2, 1);
+                backend.gate.notify_one();
+                assert_eq!(recv_reply(&kernel).await, 1);
+            };
+            let (res, _) = futures::join!(serve, kernel_side);
+            res.unwrap();
+            assert_eq!(server.inflight_requests(), 0);
+
+            // The request left in the device is read by the next channel.
+            let mut buf = vec![0u8; 0x1000];
+            let len = read(channel.file.as_raw_fd(), &mut buf).unwrap();
+            let in_header = InHeader::from_slice(&buf[..size_of::<InHeader>()]).unwrap();
+            assert_eq!(len, in_header.len as usize);
+            assert_eq!(in_header.unique, 2);
+        });
+    }
+
+    #[test]
+    fn test_async_channel_closed() {
+        let server = Server::new(SlowFs::default());
+        let (file, kernel) = loopback();
+
+        tokio_uring::start(async {
+            let kernel = AsyncFd::new(kernel).unwrap();
+            let channel = AsyncFuseChannel::new(file, 0x1000).unwrap();
+            drop(kernel);
+            channel.serve(&server, pending()).await.unwrap();
+        });
+    }
+}
@@ -7,14 +7,21 @@
 //! A FUSE channel is a FUSE request handling context that takes care of handling FUSE requests
 //! sequentially. A FUSE session is a connection from a FUSE mountpoint to a FUSE server daemon.
 //! A FUSE session can have multiple FUSE channels so that FUSE requests are handled in parallel.
+//!
+//! Channels share the fd of the session by default, so all of them fetch requests from the same
+//! queue of the kernel. With [FuseSession::set_clone_fd], each channel gets its own fd cloned by
+//! the `FUSE_DEV_IOC_CLONE` ioctl, and the kernel replies to requests on the fd they are read from.
 
 use mio::{Events, Poll, Token, Waker};
 use std::fs::{File, OpenOptions};
+use std::io;
 use std::ops::Deref;
-use std::os::unix::fs::PermissionsExt;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use nix::errno::Errno;
 use nix::fcntl::{fcntl, FcntlArg, OFlag};
@@ -23,28 +30,95 @@ use nix::poll::{poll, PollFd, PollFlags};
 use nix::sys::epoll::{epoll_ctl, EpollEvent, EpollFlags, EpollOp};
 use nix::unistd::{getgid, getuid, read};
 
-use super::{super::pagesize, Error::SessionFailure, FuseBuf, FuseDevWriter, Reader, Result};
+use crate::abi::fuse_abi::{InHeader, Opcode, WriteIn};
+use crate::api::server::{InflightTracker, ShutdownSession, MAX_REQ_PAGES};
+
+use super::umount::{umount_escalate, LibcMountSyscalls, MountSyscalls};
+use super::{
+    super::pagesize, resolve_mountpoint, Error::SessionFailure, FuseBuf, FuseDevNotifier,
+    FuseDevWriter, Reader, Result, SymlinkPolicy, UmountPolicy, UmountReport,
+};
 
 // These follows definition from libfuse.
-const FUSE_KERN_BUF_SIZE: usize = 256;
+const FUSE_KERN_BUF_SIZE: usize = MAX_REQ_PAGES as usize;
 const FUSE_HEADER_SIZE: usize = 0x1000;
 const POLL_EVENTS_CAPACITY: usize = 1024;
 
 const FUSE_DEVICE: &str = "/dev/fuse";
+const MOUNTINFO: &str = "/proc/self/mountinfo";
 const FUSE_FSTYPE: &str = "fuse";
 
 const EXIT_FUSE_EVENT: Token = Token(0);
 const FUSE_DEV_EVENT: Token = Token(1);
 
+mod ioctl {
+    use nix::ioctl_read;
+
+    // #define FUSE_DEV_IOC_CLONE _IOR(229, 0, uint32_t)
+    const FUSE_DEV_IOC_MAGIC: u8 = 229;
+    const FUSE_DEV_IOC_CLONE: u8 = 0;
+    ioctl_read!(clone_fuse_fd, FUSE_DEV_IOC_MAGIC, FUSE_DEV_IOC_CLONE, u32);
+}
+
+/// Mount propagation type applied to the fuse mount.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MountPropagation {
+    /// Keep the propagation type inherited from the parent mount.
+    #[default]
+    Inherit,
+    /// Neither receive nor forward mount events, `MS_PRIVATE`.
+    Private,
+    /// Receive and forward mount events within the peer group, `MS_SHARED`.
+    Shared,
+    /// Receive mount events from the master but don't forward them, `MS_SLAVE`.
+    Slave,
+}
+
+impl MountPropagation {
+    fn flags(self) -> Option<MsFlags> {
+        match self {
+            MountPropagation::Inherit => None,
+            MountPropagation::Private => Some(MsFlags::MS_PRIVATE),
+            MountPropagation::Shared => Some(MsFlags::MS_SHARED),
+            MountPropagation::Slave => Some(MsFlags::MS_SLAVE),
+        }
+    }
+}
+
+/// Options to validate the mountpoint and to mount the fuse file system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MountOptions {
+    /// How to treat a mountpoint which is a symbolic link.
+    pub symlinks: SymlinkPolicy,
+    /// Unmount an existing fuse file system at the mountpoint instead of failing to mount.
+    pub force: bool,
+    /// Mount propagation type applied to the fuse mount.
+    pub propagation: MountPropagation,
+}
+
 /// A fuse session manager to manage the connection with the in kernel fuse driver.
 pub struct FuseSession {
     mountpoint: PathBuf,
+    opts: MountOptions,
     fsname: String,
     subtype: String,
     file: Option<File>,
     bufsize: usize,
     readonly: bool,
     wakers: Mutex<Vec<Arc<Waker>>>,
+    shutdown: AtomicBool,
+    drain: Arc<DrainState>,
+    splice_write: bool,
+    clone_fd: bool,
+}
+
+// State shared by a session and its channels to drain them.
+#[derive(Default)]
+struct DrainState {
+    // Whether channels stop fetching requests.
+    draining: AtomicBool,
+    // Requests fetched by channels which haven't asked for the next one yet.
+    requests: InflightTracker,
 }
 
 impl FuseSession {
@@ -55,21 +129,42 @@ impl FuseSession {
         subtype: &str,
         readonly: bool,
     ) -> Result<FuseSession> {
-        let dest = mountpoint
-            .canonicalize()
-            .map_err(|_| SessionFailure(format!("invalid mountpoint {:?}", mountpoint)))?;
-        if !dest.is_dir() {
-            return Err(SessionFailure(format!("{:?} is not a directory", dest)));
-        }
+        Self::new_with_options(
+            mountpoint,
+            fsname,
+            subtype,
+            readonly,
+            MountOptions::default(),
+        )
+    }
+
+    /// Create a new fuse session with mount options, without mounting/connecting to the in kernel
+    /// fuse driver.
+    ///
+    /// The mountpoint is resolved to a canonical absolute path, so the session doesn't depend on
+    /// the current directory when it's umounted later.
+    pub fn new_with_options(
+        mountpoint: &Path,
+        fsname: &str,
+        subtype: &str,
+        readonly: bool,
+        opts: MountOptions,
+    ) -> Result<FuseSession> {
+        let dest = resolve_mountpoint(mountpoint, opts.symlinks)?;
 
         Ok(FuseSession {
             mountpoint: dest,
+            opts,
             fsname: fsname.to_owned(),
             subtype: subtype.to_owned(),
             file: None,
             bufsize: FUSE_KERN_BUF_SIZE * pagesize() + FUSE_HEADER_SIZE,
             readonly,
             wakers: Mutex::new(Vec::new()),
+            shutdown: AtomicBool::new(false),
+            drain: Arc::new(DrainState::default()),
+            splice_write: false,
+            clone_fd: false,
         })
     }
 
@@ -79,7 +174,24 @@ impl FuseSession {
         if self.readonly {
             flags |= MsFlags::MS_RDONLY;
         }
+        self.check_mounted()?;
         let file = fuse_kern_mount(&self.mountpoint, &self.fsname, &self.subtype, flags)?;
+        if let Some(flags) = self.opts.propagation.flags() {
+            let res = mount(
+                None::<&str>,
+                &self.mountpoint,
+                None::<&str>,
+                flags,
+                None::<&str>,
+            );
+            if let Err(e) = res {
+                let _ = fuse_kern_umount(&self.mountpoint.to_string_lossy(), file);
+                return Err(SessionFailure(format!(
+                    "failed to set propagation of {:?}: {}",
+                    self.mountpoint, e
+                )));
+            }
+        }
 
         fcntl(file.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
             .map_err(|e| SessionFailure(format!("set fd nonblocking: {}", e)))?;
@@ -88,6 +200,32 @@ impl FuseSession {
         Ok(())
     }
 
+    // Fail if a fuse file system is mounted at the mountpoint already, or unmount it if forced.
+    fn check_mounted(&self) -> Result<()> {
+        let mountinfo = std::fs::read_to_string(MOUNTINFO)
+            .map_err(|e| SessionFailure(format!("read {}: {}", MOUNTINFO, e)))?;
+        if parse_conn_id(&mountinfo, &self.mountpoint).is_none() {
+            return Ok(());
+        }
+        if !self.opts.force {
+            return Err(SessionFailure(format!(
+                "a fuse file system is mounted at {:?} already",
+                self.mountpoint
+            )));
+        }
+        warn!(
+            "fuse: umount existing fuse file system at {:?}",
+            self.mountpoint
+        );
+        umount2(&self.mountpoint, MntFlags::MNT_DETACH)
+            .map_err(|e| SessionFailure(format!("failed to umount {:?}: {}", self.mountpoint, e)))
+    }
+
+    /// Get the mount options of the session.
+    pub fn mount_options(&self) -> MountOptions {
+        self.opts
+    }
+
     /// Expose the associated FUSE session file.
     pub fn get_fuse_file(&mut self) -> Option<&File> {
         self.file.as_ref()
@@ -111,6 +249,27 @@ impl FuseSession {
         }
     }
 
+    /// Umount the session in bounded time, escalating through the steps allowed by `policy` if
+    /// the mount is busy or the umount is stuck.
+    ///
+    /// Unlike [FuseSession::umount], which always detaches the mount lazily, the mount is only
+    /// detached if it's busy, and the connection is aborted if the umount doesn't complete in
+    /// time. Return the steps taken, or an error listing them.
+    pub fn umount_with_policy(&mut self, policy: UmountPolicy) -> Result<UmountReport> {
+        let file = match self.file.take() {
+            Some(file) => file,
+            None => return Ok(UmountReport::default()),
+        };
+        if fuse_kern_disconnected(&file) {
+            return Ok(UmountReport::default());
+        }
+        // Take the connection id while mounted, to abort the connection later.
+        let conn = fuse_kern_conn_id(&self.mountpoint).ok();
+        drop(file);
+
+        umount_escalate(Arc::new(LibcMountSyscalls), &self.mountpoint, conn, policy)
+    }
+
     /// Get the mountpoint of the session.
     pub fn mountpoint(&self) -> &Path {
         &self.mountpoint
@@ -131,22 +290,106 @@ impl FuseSession {
         self.bufsize
     }
 
+    /// Size the buffers of channels created later for writes of up to `max_write` bytes.
+    ///
+    /// The value is rounded up to pages and clamped to [4KB, 1MB]. The server must not negotiate
+    /// a larger `max_write`, so configure it by
+    /// [Server::with_max_write](crate::api::server::Server::with_max_write) with
+    /// [FuseSession::max_write]. The default value for this option is 1MB.
+    pub fn set_max_write(&mut self, max_write: u32) {
+        let pages = (max_write as usize).div_ceil(pagesize());
+        self.bufsize = pages.clamp(1, FUSE_KERN_BUF_SIZE) * pagesize() + FUSE_HEADER_SIZE;
+    }
+
+    /// Get the max size of writes fitting into the buffers of channels.
+    pub fn max_write(&self) -> u32 {
+        (self.bufsize - FUSE_HEADER_SIZE) as u32
+    }
+
+    /// Enable or disable splicing requests through a pipe for channels created later.
+    ///
+    /// The data of large writes then stays in the pipe of the channel, and file systems may move
+    /// it into the target files with [Reader::splice_to_at] instead of copying it. Channels
+    /// fall back to reading requests into their buffer if the pipe can't be set up.
+    pub fn set_splice_write(&mut self, enable: bool) {
+        self.splice_write = enable;
+    }
+
+    /// Check whether channels splice requests through a pipe.
+    pub fn splice_write(&self) -> bool {
+        self.splice_write
+    }
+
+    /// Enable or disable cloning the session fd for channels created later.
+    ///
+    /// Each channel then fetches requests through its own fd, instead of all channels contending
+    /// on the fd of the session. Channels fall back to sharing the session fd if the kernel doesn't
+    /// support cloning it.
+    pub fn set_clone_fd(&mut self, enable: bool) {
+        self.clone_fd = enable;
+    }
+
+    /// Check whether channels get their own cloned fd.
+    pub fn clone_fd(&self) -> bool {
+        self.clone_fd
+    }
+
     /// Create a new fuse message channel.
     pub fn new_channel(&self) -> Result<FuseChannel> {
-        if let Some(file) = &self.file {
-            let file = file
-                .try_clone()
-                .map_err(|e| SessionFailure(format!("dup fd: {}", e)))?;
-            let channel = FuseChannel::new(file, self.bufsize)?;
-            let waker = channel.get_waker();
-            self.add_waker(waker)?;
+        let file = self.channel_file()?;
+        let mut channel = FuseChannel::new(file, self.bufsize)?;
+        channel.drain = Some(self.drain.clone());
+        if self.splice_write {
+            match SplicePipe::new(self.bufsize) {
+                Ok(pipe) => channel.pipe = Some(pipe),
+                Err(e) => warn!("fuse: splice write disabled for channel, {}", e),
+            }
+        }
+        let waker = channel.get_waker();
+        self.add_waker(waker)?;
 
-            Ok(channel)
+        Ok(channel)
+    }
+
+    // Get a handle of the fuse device for a new channel, a clone of the session fd if enabled.
+    pub(super) fn channel_file(&self) -> Result<File> {
+        if self.is_shutdown() {
+            return Err(SessionFailure("fuse session is shut down".to_string()));
+        }
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Err(SessionFailure("invalid fuse session".to_string())),
+        };
+        let cloned = if self.clone_fd {
+            fuse_kern_clone_fd(file)
+                .map_err(|e| warn!("fuse: share session fd with channel, {}", e))
+                .ok()
         } else {
-            Err(SessionFailure("invalid fuse session".to_string()))
+            None
+        };
+        match cloned {
+            Some(file) => Ok(file),
+            None => file
+                .try_clone()
+                .map_err(|e| SessionFailure(format!("dup fd: {}", e))),
         }
     }
 
+    /// Create a notifier to push cache invalidations to the in kernel fuse driver.
+    ///
+    /// The notifier holds its own handle of the fuse device, so it may outlive the session, but
+    /// the kernel rejects notifications once the session is umounted.
+    pub fn notifier(&self) -> Result<FuseDevNotifier> {
+        let file = self
+            .file
+            .as_ref()
+            .ok_or_else(|| SessionFailure("invalid fuse session".to_string()))?
+            .try_clone()
+            .map_err(|e| SessionFailure(format!("dup fd: {}", e)))?;
+        let max_write = self.bufsize - FUSE_HEADER_SIZE;
+        Ok(FuseDevNotifier::new(file, max_write as u32))
+    }
+
     fn add_waker(&self, waker: Arc<Waker>) -> Result<()> {
         let mut wakers = self
             .wakers
@@ -169,6 +412,84 @@ impl FuseSession {
         }
         Ok(())
     }
+
+    /// Stop serving the session: refuse new channels and wake all channel loops to exit.
+    ///
+    /// Requests already fetched from the channels are not affected, and the session stays
+    /// mounted until `umount()` is called.
+    pub fn shutdown(&self) -> Result<()> {
+        self.shutdown.store(true, Ordering::Release);
+        self.wake()
+    }
+
+    /// Check whether `shutdown()` has been called on the session.
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::Acquire)
+    }
+
+    /// Stop channels from fetching new requests, and wait up to `timeout` for the requests they
+    /// fetched already to be handled. Return false on timeout.
+    ///
+    /// Channels check the state of the session before reading each request, so requests queued
+    /// by the kernel afterwards stay in the fuse device, to be read by another process taking
+    /// over the session fd from [FuseSession::get_fuse_file] or failed by the umount. A request
+    /// is handled once its channel asks for the next one or is dropped, so channel loops must
+    /// keep calling [FuseChannel::get_request] until it returns `Ok(None)`. Like `shutdown()`,
+    /// new channels are refused and idle channel loops are woken to exit.
+    pub fn quiesce(&self, timeout: Duration) -> Result<bool> {
+        self.drain.draining.store(true, Ordering::SeqCst);
+        self.shutdown()?;
+        Ok(self.drain.requests.wait_drained(timeout))
+    }
+
+    /// Drain the session for a graceful exit: stop fetching new requests, wait up to `timeout`
+    /// for requests fetched already to be handled, then umount the session and wake all channel
+    /// loops. Return false if requests were still being handled on timeout.
+    ///
+    /// Daemons handing the session over to another process should call
+    /// [FuseSession::quiesce] instead, which leaves the session mounted.
+    pub fn drain(&mut self, timeout: Duration) -> Result<bool> {
+        let drained = self.quiesce(timeout)?;
+        if !drained {
+            warn!(
+                "fuse: {} requests still being handled on drain",
+                self.outstanding_requests()
+            );
+        }
+        self.umount()?;
+        self.wake()?;
+        Ok(drained)
+    }
+
+    /// Get the number of requests fetched by channels of the session and not handled yet.
+    pub fn outstanding_requests(&self) -> usize {
+        self.drain.requests.count()
+    }
+
+    /// Forcibly abort the connection with the in kernel fuse driver.
+    ///
+    /// All pending and future requests from the kernel fail with `ECONNABORTED`, which is done by
+    /// writing to `/sys/fs/fuse/connections/<id>/abort` and requires the fusectl file system.
+    pub fn abort(&self) -> Result<()> {
+        let dev = fuse_kern_conn_id(&self.mountpoint)?;
+        LibcMountSyscalls
+            .abort(dev)
+            .map_err(|e| SessionFailure(format!("abort connection {}: {}", dev, e)))
+    }
+}
+
+impl ShutdownSession for FuseSession {
+    fn shutdown(&self) -> io::Result<()> {
+        FuseSession::shutdown(self).map_err(io::Error::other)
+    }
+
+    fn abort(&self) -> io::Result<()> {
+        FuseSession::abort(self).map_err(io::Error::other)
+    }
+
+    fn umount(&mut self) -> io::Result<()> {
+        FuseSession::umount(self).map_err(io::Error::other)
+    }
 }
 
 impl Drop for FuseSession {
@@ -185,6 +506,16 @@ pub struct FuseChannel {
     poll: Poll,
     waker: Arc<Waker>,
     buf: Vec<u8>,
+    pipe: Option<SplicePipe>,
+    drain: Option<Arc<DrainState>>,
+    // Whether the last fetched request is accounted in `drain`.
+    busy: bool,
+}
+
+impl AsRawFd for FuseChannel {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
 }
 
 impl FuseChannel {
@@ -212,23 +543,90 @@ impl FuseChannel {
             poll,
             waker,
             buf: vec![0x0u8; bufsize],
+            pipe: None,
+            drain: None,
+            busy: false,
         })
     }
 
+    // Whether the session is draining, otherwise account the request about to be read.
+    fn begin_request(&mut self) -> bool {
+        match self.drain.as_ref() {
+            Some(drain) => {
+                drain.requests.begin();
+                if drain.draining.load(Ordering::SeqCst) {
+                    drain.requests.exit();
+                    return false;
+                }
+                self.busy = true;
+                true
+            }
+            None => true,
+        }
+    }
+
+    // Complete the request accounted by `begin_request()`.
+    fn end_request(&mut self) {
+        if self.busy {
+            self.busy = false;
+            if let Some(drain) = self.drain.as_ref() {
+                drain.requests.exit();
+            }
+        }
+    }
+
+    fn is_draining(&self) -> bool {
+        self.drain
+            .as_ref()
+            .map(|d| d.draining.load(Ordering::SeqCst))
+            .unwrap_or(false)
+    }
+
     fn get_waker(&self) -> Arc<Waker> {
         self.waker.clone()
     }
 
+    /// Get the size of the buffer receiving requests.
+    pub fn bufsize(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Resize the buffer receiving requests to `bufsize` bytes.
+    ///
+    /// Channels are created before `FUSE_INIT` is negotiated, with buffers sized for the max
+    /// write of the session. Resizing them to
+    /// [Server::max_request_size](crate::api::server::Server::max_request_size) once the
+    /// connection is initialized releases the memory the kernel never fills. Channels splicing
+    /// requests fall back to reading them into the buffer if the pipe can't be resized.
+    pub fn set_bufsize(&mut self, bufsize: usize) {
+        let bufsize = bufsize.max(FUSE_HEADER_SIZE);
+        if let Some(pipe) = self.pipe.as_ref() {
+            if let Err(e) = pipe.set_size(bufsize) {
+                warn!("fuse: splice write disabled for channel, {}", e);
+                self.pipe = None;
+            }
+        }
+        self.buf = vec![0x0u8; bufsize];
+    }
+
     /// Get next available FUSE request from the underlying fuse device file.
     ///
     /// Returns:
     /// - Ok(None): signal has pending on the exiting event channel
     /// - Ok(Some((reader, writer))): reader to receive request and writer to send reply
     /// - Err(e): error message
+    ///
+    /// The request returned by the previous call is considered handled, see
+    /// [FuseSession::quiesce]. Once the session is draining, no more requests are read.
     pub fn get_request(&mut self) -> Result<Option<(Reader, FuseDevWriter)>> {
+        self.end_request();
         let mut events = Events::with_capacity(POLL_EVENTS_CAPACITY);
         let mut need_exit = false;
         loop {
+            if self.is_draining() {
+                info!("Will exit from fuse service, session draining");
+                return Ok(None);
+            }
             let mut fusereq_available = false;
             match self.poll.poll(&mut events, None) {
                 Ok(_) => {}
@@ -268,9 +666,19 @@ impl FuseChannel {
                 return Ok(None);
             }
             if fusereq_available {
+                if !self.begin_request() {
+                    continue;
+                }
                 let fd = self.file.as_raw_fd();
-                match read(fd, &mut self.buf) {
-                    Ok(len) => {
+                let res = match self.pipe.as_ref() {
+                    Some(pipe) => pipe.read_request(fd, &mut self.buf),
+                    None => read(fd, &mut self.buf).map(|len| (len, 0)),
+                };
+                if res.is_err() {
+                    self.end_request();
+                }
+                match res {
+                    Ok((len, piped)) => {
                         // ###############################################
                         // Note: it's a heavy hack to reuse the same underlying data
                         // buffer for both Reader and Writer, in order to reduce memory
@@ -281,8 +689,15 @@ impl FuseChannel {
                             std::slice::from_raw_parts_mut(self.buf.as_mut_ptr(), self.buf.len())
                         };
                         // Reader::new() and Writer::new() should always return success.
-                        let reader =
-                            Reader::from_fuse_buffer(FuseBuf::new(&mut self.buf[..len])).unwrap();
+                        let reader = match self.pipe.as_ref() {
+                            Some(pipe) if piped > 0 => Reader::from_fuse_pipe(
+                                FuseBuf::new(&mut self.buf[..len]),
+                                pipe.rd.as_raw_fd(),
+                                piped,
+                            ),
+                            _ => Reader::from_fuse_buffer(FuseBuf::new(&mut self.buf[..len])),
+                        }
+                        .unwrap();
                         let writer = FuseDevWriter::new(fd, buf).unwrap();
                         return Ok(Some((reader, writer)));
                     }
@@ -297,6 +712,10 @@ impl FuseChannel {
                             trace!("syscall interrupted");
                             continue;
                         }
+                        Errno::EAGAIN => {
+                            // Another channel has fetched the request.
+                            continue;
+                        }
                         Errno::ENODEV => {
                             info!("fuse filesystem umounted");
                             return Ok(None);
@@ -312,6 +731,109 @@ impl FuseChannel {
     }
 }
 
+impl Drop for FuseChannel {
+    fn drop(&mut self) {
+        self.end_request();
+    }
+}
+
+// Pipe requests are spliced into from the fuse device, so that the data of writes may be spliced
+// into the target files instead of being read into the channel buffer.
+struct SplicePipe {
+    rd: File,
+    wr: File,
+}
+
+impl SplicePipe {
+    fn new(bufsize: usize) -> io::Result<Self> {
+        let mut fds = [0 as RawFd; 2];
+        // Safe because the kernel only writes two fds to `fds` and we check the return value.
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because we own the new fds.
+        let (rd, wr) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        let pipe = SplicePipe { rd, wr };
+        pipe.set_size(bufsize)?;
+
+        Ok(pipe)
+    }
+
+    // A request is only spliced from the fuse device if it fits into the pipe.
+    fn set_size(&self, bufsize: usize) -> io::Result<()> {
+        // Safe because the fd is valid and we check the return value.
+        let size = unsafe {
+            libc::fcntl(
+                self.wr.as_raw_fd(),
+                libc::F_SETPIPE_SZ,
+                bufsize as libc::c_int,
+            )
+        };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Splice a request from the fuse device `fd` into the pipe and read it into `buf`. The data of
+    // writes larger than a page stays in the pipe, return the number of bytes read into `buf` and
+    // left in the pipe.
+    fn read_request(&self, fd: RawFd, buf: &mut [u8]) -> nix::Result<(usize, usize)> {
+        // Drop data of the previous request not consumed by the file system.
+        while self.read(buf)? > 0 {}
+
+        // Safe because the fds are valid and we check the return value.
+        let len = unsafe {
+            libc::splice(
+                fd,
+                std::ptr::null_mut(),
+                self.wr.as_raw_fd(),
+                std::ptr::null_mut(),
+                buf.len(),
+                0,
+            )
+        };
+        let len = Errno::result(len)? as usize;
+
+        let header = std::mem::size_of::<InHeader>();
+        if len < header {
+            self.read_exact(&mut buf[..len])?;
+            return Ok((len, 0));
+        }
+        self.read_exact(&mut buf[..header])?;
+        // Safe because `buf` holds a whole `InHeader` of plain data, read unaligned.
+        let ih = unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const InHeader) };
+        let write_header = header + std::mem::size_of::<WriteIn>();
+        let head = if ih.opcode == Opcode::Write as u32 && len >= write_header + pagesize() {
+            write_header
+        } else {
+            len
+        };
+        self.read_exact(&mut buf[header..head])?;
+
+        Ok((head, len - head))
+    }
+
+    // Read from the pipe, return 0 if it's empty.
+    fn read(&self, buf: &mut [u8]) -> nix::Result<usize> {
+        match read(self.rd.as_raw_fd(), buf) {
+            Err(Errno::EAGAIN) => Ok(0),
+            res => res,
+        }
+    }
+
+    fn read_exact(&self, mut buf: &mut [u8]) -> nix::Result<()> {
+        while !buf.is_empty() {
+            match self.read(buf)? {
+                0 => return Err(Errno::EIO),
+                n => buf = &mut buf[n..],
+            }
+        }
+        Ok(())
+    }
+}
+
+// Resolve `mountpoint` to a canonical absolute path according to the symlink `policy`.
 /// Mount a fuse file system
 fn fuse_kern_mount(mountpoint: &Path, fsname: &str, subtype: &str, flags: MsFlags) -> Result<File> {
     let file = OpenOptions::new()
@@ -358,18 +880,29 @@ fn fuse_kern_mount(mountpoint: &Path, fsname: &str, subtype: &str, flags: MsFlag
     Ok(file)
 }
 
+// Open a new fuse device fd attached to the connection of the session fd `file`. Requests are
+// read from the new fd independently, and the connection is aborted for all fds on umount.
+fn fuse_kern_clone_fd(file: &File) -> Result<File> {
+    let clone = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_CLOEXEC)
+        .open(FUSE_DEVICE)
+        .map_err(|e| SessionFailure(format!("open {}: {}", FUSE_DEVICE, e)))?;
+    let mut fd = file.as_raw_fd() as u32;
+    // Safe because the kernel only reads `fd` and we check the return value.
+    unsafe { ioctl::clone_fuse_fd(clone.as_raw_fd(), &mut fd) }
+        .map_err(|e| SessionFailure(format!("clone fuse fd: {}", e)))?;
+    fcntl(clone.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
+        .map_err(|e| SessionFailure(format!("set fd nonblocking: {}", e)))?;
+
+    Ok(clone)
+}
+
 /// Umount a fuse file system
 fn fuse_kern_umount(mountpoint: &str, file: File) -> Result<()> {
-    let mut fds = [PollFd::new(file.as_raw_fd(), PollFlags::empty())];
-
-    if poll(&mut fds, 0).is_ok() {
-        // POLLERR means the file system is already umounted,
-        // or the connection has been aborted via /sys/fs/fuse/connections/NNN/abort
-        if let Some(event) = fds[0].revents() {
-            if event == PollFlags::POLLERR {
-                return Ok(());
-            }
-        }
+    if fuse_kern_disconnected(&file) {
+        return Ok(());
     }
 
     // Drop to close fuse session fd, otherwise synchronous umount can recurse into filesystem and
@@ -379,6 +912,65 @@ fn fuse_kern_umount(mountpoint: &str, file: File) -> Result<()> {
         .map_err(|e| SessionFailure(format!("failed to umount {}: {}", mountpoint, e)))
 }
 
+// Check whether the file system of the session fd `file` is already umounted, or its connection
+// has been aborted via /sys/fs/fuse/connections/NNN/abort, which poll reports as POLLERR.
+fn fuse_kern_disconnected(file: &File) -> bool {
+    let mut fds = [PollFd::new(file.as_raw_fd(), PollFlags::empty())];
+
+    poll(&mut fds, 0).is_ok() && fds[0].revents() == Some(PollFlags::POLLERR)
+}
+
+// Get the fuse connection id of the mountpoint, which is the device number of the mounted file
+// system. Parse mountinfo instead of stat()ing the mountpoint, which may hang if the file system
+// isn't responding.
+fn fuse_kern_conn_id(mountpoint: &Path) -> Result<u64> {
+    let mountinfo = std::fs::read_to_string(MOUNTINFO)
+        .map_err(|e| SessionFailure(format!("read {}: {}", MOUNTINFO, e)))?;
+    parse_conn_id(&mountinfo, mountpoint)
+        .ok_or_else(|| SessionFailure(format!("no fuse connection for {:?}", mountpoint)))
+}
+
+// A fuse mount parsed from a line of mountinfo.
+#[derive(Debug, PartialEq, Eq)]
+struct FuseMount {
+    // Device number of the mounted file system, which is the fuse connection id.
+    dev: u64,
+    // Absolute path of the mountpoint.
+    mountpoint: String,
+    // Optional fields of the mount, such as its propagation type `shared:N`.
+    optional: Vec<String>,
+}
+
+// Parse a line of mountinfo, return `None` if it's malformed or not a fuse mount.
+fn parse_fuse_mount(line: &str) -> Option<FuseMount> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    // Optional fields are terminated by a single hyphen, followed by the fs type.
+    let sep = fields.iter().position(|f| *f == "-")?;
+    let fstype = fields.get(sep + 1)?;
+    if !(*fstype == FUSE_FSTYPE || fstype.starts_with("fuse.")) {
+        return None;
+    }
+    let (major, minor) = fields.get(2)?.split_once(':')?;
+    let major: u64 = major.parse().ok()?;
+    let minor: u64 = minor.parse().ok()?;
+    Some(FuseMount {
+        dev: (major << 20) | minor,
+        mountpoint: fields.get(4)?.replace("\\040", " "),
+        optional: fields.get(6..sep)?.iter().map(|f| f.to_string()).collect(),
+    })
+}
+
+fn parse_conn_id(mountinfo: &str, mountpoint: &Path) -> Option<u64> {
+    let mountpoint = mountpoint.to_str()?;
+    // Later entries overmount earlier ones, so take the last match.
+    mountinfo
+        .lines()
+        .rev()
+        .filter_map(parse_fuse_mount)
+        .find(|m| m.mountpoint == mountpoint)
+        .map(|m| m.dev)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -397,11 +989,230 @@ mod tests {
         assert!(se.is_ok());
     }
 
+    #[test]
+    fn test_session_shutdown() {
+        let dir = TempDir::new().unwrap();
+        let se = FuseSession::new(dir.as_path(), "foo", "bar", false).unwrap();
+        assert!(!se.is_shutdown());
+        se.shutdown().unwrap();
+        assert!(se.is_shutdown());
+        assert!(se.new_channel().is_err());
+    }
+
+    #[test]
+    fn test_session_quiesce() {
+        use std::io::{Read, Write};
+
+        let dir = TempDir::new().unwrap();
+        let mut se = FuseSession::new(dir.as_path(), "foo", "bar", false).unwrap();
+        // Requests are read from a pipe standing in for the fuse device.
+        let (rd, wr) = nix::unistd::pipe2(OFlag::O_NONBLOCK).unwrap();
+        let mut rd = unsafe { File::from_raw_fd(rd) };
+        let mut wr = unsafe { File::from_raw_fd(wr) };
+        se.set_fuse_file(rd.try_clone().unwrap());
+        let mut ch = se.new_channel().unwrap();
+
+        wr.write_all(b"before").unwrap();
+        let (reader, _) = ch.get_request().unwrap().unwrap();
+        assert_eq!(reader.available_bytes(), 6);
+        assert_eq!(se.outstanding_requests(), 1);
+        assert!(!se.quiesce(Duration::from_millis(10)).unwrap());
+        assert!(se.new_channel().is_err());
+
+        std::thread::scope(|s| {
+            let drained = s.spawn(|| se.quiesce(Duration::from_secs(5)).unwrap());
+            wr.write_all(b"after").unwrap();
+            // The request fetched before draining is handled, the next one is never read.
+            assert!(ch.get_request().unwrap().is_none());
+            assert!(drained.join().unwrap());
+        });
+        assert!(ch.get_request().unwrap().is_none());
+        assert_eq!(se.outstanding_requests(), 0);
+        let mut buf = Vec::new();
+        let _ = rd.read_to_end(&mut buf);
+        assert_eq!(buf, b"after");
+    }
+
+    #[test]
+    fn test_clone_fd_fallback() {
+        let dir = TempDir::new().unwrap();
+        let mut se = FuseSession::new(dir.as_path(), "foo", "bar", false).unwrap();
+        se.set_clone_fd(true);
+        assert!(se.clone_fd());
+
+        // Files other than fuse devices can't be cloned, channels share the session fd.
+        let (rd, wr) = nix::unistd::pipe().unwrap();
+        let file = unsafe { File::from_raw_fd(rd) };
+        assert!(fuse_kern_clone_fd(&file).is_err());
+        se.set_fuse_file(file);
+        se.new_channel().unwrap();
+        nix::unistd::close(wr).unwrap();
+    }
+
+    #[test]
+    fn test_parse_conn_id() {
+        let mountinfo = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+45 22 0:44 / /mnt/fuse rw,nosuid,nodev,relatime shared:25 - fuse.foo foo rw,user_id=0
+46 45 0:45 / /mnt/fuse rw,nosuid,nodev,relatime - fuse bar rw,user_id=0
+47 22 0:46 / /mnt/with\\040space rw,relatime - fuse foo rw,user_id=0
+48 22 259:3 / /mnt/fuseblk rw,relatime - fuseblk /dev/nvme0n1p3 rw,user_id=0
+";
+        assert_eq!(parse_conn_id(mountinfo, Path::new("/mnt/fuse")), Some(45));
+        assert_eq!(
+            parse_conn_id(mountinfo, Path::new("/mnt/with space")),
+            Some(46)
+        );
+        assert_eq!(parse_conn_id(mountinfo, Path::new("/")), None);
+        assert_eq!(parse_conn_id(mountinfo, Path::new("/mnt/fuseblk")), None);
+    }
+
+    #[test]
+    fn test_parse_fuse_mount() {
+        assert_eq!(
+            parse_fuse_mount(
+                "45 22 0:44 / /mnt/fuse rw,nosuid shared:25 master:3 - fuse.foo foo rw,user_id=0"
+            ),
+            Some(FuseMount {
+                dev: 44,
+                mountpoint: "/mnt/fuse".to_string(),
+                optional: vec!["shared:25".to_string(), "master:3".to_string()],
+            })
+        );
+        assert_eq!(
+            parse_fuse_mount("47 22 1:46 / /mnt/a\\040b rw - fuse foo rw"),
+            Some(FuseMount {
+                dev: (1 << 20) | 46,
+                mountpoint: "/mnt/a b".to_string(),
+                optional: vec![],
+            })
+        );
+        assert_eq!(
+            parse_fuse_mount("22 1 8:1 / / rw - ext4 /dev/sda1 rw"),
+            None
+        );
+        assert_eq!(parse_fuse_mount("22 1 8:1 / / rw fuse foo rw"), None);
+        assert_eq!(parse_fuse_mount("22 1 8 / / rw - fuse foo rw"), None);
+        assert_eq!(parse_fuse_mount(""), None);
+    }
+
+    #[test]
+    fn test_resolve_mountpoint() {
+        let dir = TempDir::new().unwrap();
+        let target = dir.as_path().join("target");
+        let link = dir.as_path().join("link");
+        std::fs::create_dir(&target).unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        let target = target.canonicalize().unwrap();
+
+        for policy in [SymlinkPolicy::Resolve, SymlinkPolicy::Reject] {
+            assert_eq!(resolve_mountpoint(&target, policy).unwrap(), target);
+            // Paths are absolute, without "." or ".." components.
+            let dotted = dir.as_path().join("./target/../target");
+            assert_eq!(resolve_mountpoint(&dotted, policy).unwrap(), target);
+            // Symlinks in parents are resolved either way.
+            let parent = dir.as_path().join("parent");
+            std::os::unix::fs::symlink(dir.as_path(), &parent).unwrap();
+            let via_parent = parent.join("target");
+            assert_eq!(resolve_mountpoint(&via_parent, policy).unwrap(), target);
+            std::fs::remove_file(&parent).unwrap();
+        }
+        assert_eq!(
+            resolve_mountpoint(&link, SymlinkPolicy::Resolve).unwrap(),
+            target
+        );
+        resolve_mountpoint(&link, SymlinkPolicy::Reject).unwrap_err();
+
+        let file = dir.as_path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        resolve_mountpoint(&file, SymlinkPolicy::Resolve).unwrap_err();
+        resolve_mountpoint(&dir.as_path().join("missing"), SymlinkPolicy::Resolve).unwrap_err();
+
+        let se = FuseSession::new_with_options(
+            &link,
+            "foo",
+            "bar",
+            false,
+            MountOptions {
+                force: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(se.mountpoint(), target);
+        assert!(se.mount_options().force);
+    }
+
+    #[test]
+    fn test_splice_pipe_read_request() {
+        use crate::abi::fuse_abi::GetattrIn;
+        use std::io::Write;
+        use vm_memory::ByteValued;
+
+        let request = |opcode: Opcode, arg: &[u8], data_len: usize| {
+            let len = std::mem::size_of::<InHeader>() + arg.len() + data_len;
+            let ih = InHeader {
+                len: len as u32,
+                opcode: opcode as u32,
+                ..Default::default()
+            };
+            let mut msg = ih.as_slice().to_vec();
+            msg.extend_from_slice(arg);
+            msg.resize(len, 0xa5);
+            msg
+        };
+        let (src_rd, src_wr) = nix::unistd::pipe().unwrap();
+        let mut src_wr = unsafe { File::from_raw_fd(src_wr) };
+        let pipe = SplicePipe::new(0x10_0000).unwrap();
+        let mut buf = vec![0u8; 0x10_0000];
+        let header = std::mem::size_of::<InHeader>() + std::mem::size_of::<WriteIn>();
+
+        // The data of large writes stays in the pipe.
+        let write = WriteIn {
+            size: 8192,
+            ..Default::default()
+        };
+        let msg = request(Opcode::Write, write.as_slice(), 8192);
+        src_wr.write_all(&msg).unwrap();
+        assert_eq!(pipe.read_request(src_rd, &mut buf).unwrap(), (header, 8192));
+        assert_eq!(&buf[..header], &msg[..header]);
+
+        // Data left by the previous request is dropped, other requests are read whole.
+        let msg = request(Opcode::Getattr, GetattrIn::default().as_slice(), 0);
+        src_wr.write_all(&msg).unwrap();
+        assert_eq!(pipe.read_request(src_rd, &mut buf).unwrap(), (msg.len(), 0));
+        assert_eq!(&buf[..msg.len()], &msg[..]);
+
+        // So are small writes.
+        let msg = request(Opcode::Write, write.as_slice(), 100);
+        src_wr.write_all(&msg).unwrap();
+        assert_eq!(pipe.read_request(src_rd, &mut buf).unwrap(), (msg.len(), 0));
+        assert_eq!(&buf[..msg.len()], &msg[..]);
+        nix::unistd::close(src_rd).unwrap();
+    }
+
     #[test]
     fn test_new_channel() {
         let fd = nix::unistd::dup(std::io::stdout().as_raw_fd()).unwrap();
         let file = unsafe { File::from_raw_fd(fd) };
-        let _ = FuseChannel::new(file, 3).unwrap();
+        let mut channel = FuseChannel::new(file, 3).unwrap();
+        channel.set_bufsize(0x11000);
+        assert_eq!(channel.bufsize(), 0x11000);
+    }
+
+    #[test]
+    fn test_session_max_write() {
+        let dir = TempDir::new().unwrap();
+        let mut se = FuseSession::new(dir.as_path(), "foo", "bar", false).unwrap();
+        assert_eq!(se.max_write(), (FUSE_KERN_BUF_SIZE * pagesize()) as u32);
+
+        se.set_max_write(pagesize() as u32 + 1);
+        assert_eq!(se.max_write(), 2 * pagesize() as u32);
+        assert_eq!(se.bufsize(), 2 * pagesize() + FUSE_HEADER_SIZE);
+        se.set_max_write(0);
+        assert_eq!(se.max_write(), pagesize() as u32);
+        se.set_max_write(u32::MAX);
+        assert_eq!(se.max_write(), (FUSE_KERN_BUF_SIZE * pagesize()) as u32);
     }
 }
 
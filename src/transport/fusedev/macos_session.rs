@@ -7,17 +7,25 @@
 //! A FUSE channel is a FUSE request handling context that takes care of handling FUSE requests
 //! sequentially. A FUSE session is a connection from a FUSE mountpoint to a FUSE server daemon.
 //! A FUSE session can have multiple FUSE channels so that FUSE requests are handled in parallel.
+//!
+//! Two implementations of FUSE serve mounts on macOS, selected by [MountBackend]:
+//! - macFUSE, a kernel extension. `mount_macfuse` opens the `/dev/macfuse` device, mounts it and
+//!   passes the device fd back over a socket. Each read of the device returns one request.
+//! - fuse-t, a user space NFS server. `go-nfsv4` mounts itself and exchanges FUSE messages with
+//!   the session over the socket passed to it, a stream without message boundaries, so requests
+//!   are read header first, one channel at a time.
 
 use core_foundation_sys::base::{CFAllocatorRef, CFIndex, CFRelease};
 use core_foundation_sys::string::{kCFStringEncodingUTF8, CFStringCreateWithBytes};
 use core_foundation_sys::url::{kCFURLPOSIXPathStyle, CFURLCreateWithFileSystemPath, CFURLRef};
 use std::ffi::CString;
 use std::fs::File;
-use std::io::IoSliceMut;
+use std::io::{self, IoSliceMut};
+use std::mem::size_of;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
 use std::sync::{Arc, Mutex};
 
 use libc::{c_void, proc_pidpath, PROC_PIDPATHINFO_MAXSIZE};
@@ -25,13 +33,18 @@ use nix::errno::Errno;
 use nix::fcntl::{fcntl, FdFlag, F_SETFD};
 use nix::sys::signal::{signal, SigHandler, Signal};
 use nix::sys::socket::{
-    recvmsg, socketpair, AddressFamily, ControlMessageOwned, MsgFlags, RecvMsg, SockFlag, SockType,
-    UnixAddr,
+    recvmsg, shutdown, socketpair, AddressFamily, ControlMessageOwned, MsgFlags, RecvMsg, Shutdown,
+    SockFlag, SockType, UnixAddr,
 };
 use nix::unistd::{close, execv, fork, getpid, read, ForkResult};
 use nix::{cmsg_space, NixPath};
 
-use super::{Error::IoError, Error::SessionFailure, FuseBuf, FuseDevWriter, Reader, Result};
+use super::{
+    resolve_mountpoint, Error::IoError, Error::SessionFailure, FuseBuf, FuseDevNotifier,
+    FuseDevWriter, Reader, Result, SymlinkPolicy,
+};
+use crate::abi::fuse_abi::InHeader;
+use crate::api::server::ShutdownSession;
 use crate::transport::pagesize;
 
 // These follows definition from libfuse.
@@ -39,6 +52,7 @@ const FUSE_KERN_BUF_SIZE: usize = 256;
 const FUSE_HEADER_SIZE: usize = 0x1000;
 
 const OSXFUSE_MOUNT_PROG: &str = "/Library/Filesystems/macfuse.fs/Contents/Resources/mount_macfuse";
+const FUSE_T_MOUNT_PROG: &str = "/usr/local/bin/go-nfsv4";
 
 static K_DADISK_UNMOUNT_OPTION_FORCE: u64 = 524288;
 
@@ -80,16 +94,67 @@ mod ioctl {
     ioctl_write_ptr!(set_fuse_fd_dead, FUSE_FD_DEAD_MAGIC, FUSE_FD_DEAD, u32);
 }
 
+/// Implementation of FUSE serving the mount.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MountBackend {
+    /// macFUSE if it's installed, fuse-t otherwise.
+    #[default]
+    Auto,
+    /// The macFUSE kernel extension, through the `/dev/macfuse` device.
+    MacFuse,
+    /// The fuse-t user space NFS server, through a unix socket.
+    FuseT,
+}
+
+impl MountBackend {
+    // Pick the installed implementation if `Auto`.
+    fn resolve(self) -> Result<MountBackend> {
+        match self {
+            MountBackend::Auto if Path::new(OSXFUSE_MOUNT_PROG).exists() => {
+                Ok(MountBackend::MacFuse)
+            }
+            MountBackend::Auto if Path::new(FUSE_T_MOUNT_PROG).exists() => Ok(MountBackend::FuseT),
+            MountBackend::Auto => Err(SessionFailure(
+                "neither macFUSE nor fuse-t is installed".to_string(),
+            )),
+            backend => Ok(backend),
+        }
+    }
+
+    fn mount_prog(self) -> &'static str {
+        match self {
+            MountBackend::FuseT => FUSE_T_MOUNT_PROG,
+            _ => OSXFUSE_MOUNT_PROG,
+        }
+    }
+}
+
+/// Options to validate the mountpoint and to mount the fuse file system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MountOptions {
+    /// How to treat a mountpoint which is a symbolic link.
+    pub symlinks: SymlinkPolicy,
+    /// Implementation of FUSE serving the mount.
+    pub backend: MountBackend,
+}
+
 /// A fuse session manager to manage the connection with the in kernel fuse driver.
 pub struct FuseSession {
     mountpoint: PathBuf,
+    opts: MountOptions,
     fsname: String,
     subtype: String,
     file: Option<File>,
+    backend: Option<MountBackend>,
     bufsize: usize,
     disk: Arc<Mutex<Option<DADiskRef>>>,
     dasession: Arc<AtomicPtr<c_void>>,
     readonly: bool,
+    shutdown: Arc<AtomicBool>,
+    // Serializes reads of requests from the fuse-t socket by the channels.
+    read_lock: Arc<Mutex<()>>,
+    splice_write: bool,
+    clone_fd: bool,
 }
 
 unsafe impl Send for FuseSession {}
@@ -102,45 +167,77 @@ impl FuseSession {
         subtype: &str,
         readonly: bool,
     ) -> Result<FuseSession> {
-        let dest = mountpoint
-            .canonicalize()
-            .map_err(|_| SessionFailure(format!("invalid mountpoint {:?}", mountpoint)))?;
-        if !dest.is_dir() {
-            return Err(SessionFailure(format!("{:?} is not a directory", dest)));
-        }
+        Self::new_with_options(
+            mountpoint,
+            fsname,
+            subtype,
+            readonly,
+            MountOptions::default(),
+        )
+    }
+
+    /// Create a new fuse session with mount options, without mounting/connecting to the in kernel
+    /// fuse driver.
+    ///
+    /// The mountpoint is resolved to a canonical absolute path, so the session doesn't depend on
+    /// the current directory when it's umounted later.
+    pub fn new_with_options(
+        mountpoint: &Path,
+        fsname: &str,
+        subtype: &str,
+        readonly: bool,
+        opts: MountOptions,
+    ) -> Result<FuseSession> {
+        let dest = resolve_mountpoint(mountpoint, opts.symlinks)?;
 
         Ok(FuseSession {
             mountpoint: dest,
+            opts,
             fsname: fsname.to_owned(),
             subtype: subtype.to_owned(),
             file: None,
+            backend: None,
             bufsize: FUSE_KERN_BUF_SIZE * pagesize() + FUSE_HEADER_SIZE,
             disk: Arc::new(Mutex::new(None)),
             dasession: Arc::new(AtomicPtr::new(unsafe {
                 DASessionCreate(std::ptr::null()) as *mut c_void
             })),
             readonly,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            read_lock: Arc::new(Mutex::new(())),
+            splice_write: false,
+            clone_fd: false,
         })
     }
 
     /// Mount the fuse mountpoint, building connection with the in kernel fuse driver.
     pub fn mount(&mut self) -> Result<()> {
+        let backend = self.opts.backend.resolve()?;
         let mut disk = self.disk.lock().expect("lock disk failed");
-        let file = fuse_kern_mount(&self.mountpoint, &self.fsname, &self.subtype, self.readonly)?;
+        let opts = mount_opts(&self.fsname, &self.subtype, self.readonly);
+        let file = fuse_kern_mount(backend, &self.mountpoint, &opts)?;
         let session = self.dasession.load(Ordering::SeqCst);
         let mount_disk = create_disk(&self.mountpoint, session as DASessionRef);
         self.file = Some(file);
+        self.backend = Some(backend);
         *disk = Some(mount_disk);
 
         Ok(())
     }
 
+    /// Get the mount options of the session.
+    pub fn mount_options(&self) -> MountOptions {
+        self.opts
+    }
+
     /// Expose the associated FUSE session file.
     pub fn get_fuse_file(&mut self) -> Option<&File> {
         self.file.as_ref()
     }
 
     /// Force setting the associated FUSE session file.
+    ///
+    /// The file is taken as a macFUSE device unless the session has been mounted by fuse-t.
     pub fn set_fuse_file(&mut self, file: File) {
         self.file = Some(file);
     }
@@ -150,7 +247,7 @@ impl FuseSession {
         if let Some(file) = self.file.take() {
             if self.mountpoint.to_str().is_some() {
                 let mut disk = self.disk.lock().expect("lock disk failed");
-                fuse_kern_umount(file, disk.take())
+                fuse_kern_umount(self.backend, file, disk.take())
             } else {
                 Err(SessionFailure("invalid mountpoint".to_string()))
             }
@@ -179,24 +276,111 @@ impl FuseSession {
         self.bufsize
     }
 
+    /// Enable or disable splicing requests through a pipe for channels created later.
+    ///
+    /// macOS has no splice, so channels always read requests into their buffer.
+    pub fn set_splice_write(&mut self, enable: bool) {
+        self.splice_write = enable;
+    }
+
+    /// Check whether channels splice requests through a pipe.
+    pub fn splice_write(&self) -> bool {
+        self.splice_write
+    }
+
+    /// Enable or disable cloning the session fd for channels created later.
+    ///
+    /// Neither macFUSE nor fuse-t supports cloning the session fd, so channels always share it.
+    pub fn set_clone_fd(&mut self, enable: bool) {
+        self.clone_fd = enable;
+    }
+
+    /// Check whether channels get their own cloned fd.
+    pub fn clone_fd(&self) -> bool {
+        self.clone_fd
+    }
+
     /// Create a new fuse message channel.
     pub fn new_channel(&self) -> Result<FuseChannel> {
+        if self.is_shutdown() {
+            return Err(SessionFailure("fuse session is shut down".to_string()));
+        }
         if let Some(file) = &self.file {
             let file = file
                 .try_clone()
                 .map_err(|e| SessionFailure(format!("dup fd: {}", e)))?;
-            FuseChannel::new(file, self.bufsize)
+            let read_lock = match self.backend {
+                Some(MountBackend::FuseT) => Some(self.read_lock.clone()),
+                _ => None,
+            };
+            FuseChannel::new(file, self.bufsize, read_lock, self.shutdown.clone())
         } else {
             Err(SessionFailure("invalid fuse session".to_string()))
         }
     }
 
+    /// Create a notifier to push cache invalidations to the fuse driver.
+    ///
+    /// The notifier holds its own handle of the fuse device, so it may outlive the session, but
+    /// the driver rejects notifications once the session is umounted.
+    pub fn notifier(&self) -> Result<FuseDevNotifier> {
+        let file = self
+            .file
+            .as_ref()
+            .ok_or_else(|| SessionFailure("invalid fuse session".to_string()))?
+            .try_clone()
+            .map_err(|e| SessionFailure(format!("dup fd: {}", e)))?;
+        let max_write = self.bufsize - FUSE_HEADER_SIZE;
+        Ok(FuseDevNotifier::new(file, max_write as u32))
+    }
+
     /// Wake channel loop
     /// After macfuse unmount, read will throw ENODEV
     /// So wakers is no need for macfuse to interrupt channel
     pub fn wake(&self) -> Result<()> {
         Ok(())
     }
+
+    /// Stop serving the session: refuse new channels, and let channel loops exit before fetching
+    /// their next request.
+    ///
+    /// Requests already fetched from the channels are not affected, and the session stays
+    /// mounted until `umount()` is called.
+    pub fn shutdown(&self) -> Result<()> {
+        self.shutdown.store(true, Ordering::Release);
+        self.wake()
+    }
+
+    /// Check whether `shutdown()` has been called on the session.
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::Acquire)
+    }
+
+    /// Forcibly abort the connection with the fuse driver.
+    ///
+    /// The macFUSE device is marked dead, and the fuse-t socket is shut down, so all pending and
+    /// future requests fail.
+    pub fn abort(&self) -> Result<()> {
+        let file = self
+            .file
+            .as_ref()
+            .ok_or_else(|| SessionFailure("invalid fuse session".to_string()))?;
+        abort_conn(self.backend, file.as_raw_fd())
+    }
+}
+
+impl ShutdownSession for FuseSession {
+    fn shutdown(&self) -> io::Result<()> {
+        FuseSession::shutdown(self).map_err(io::Error::other)
+    }
+
+    fn abort(&self) -> io::Result<()> {
+        FuseSession::abort(self).map_err(io::Error::other)
+    }
+
+    fn umount(&mut self) -> io::Result<()> {
+        FuseSession::umount(self).map_err(io::Error::other)
+    }
 }
 
 impl Drop for FuseSession {
@@ -209,13 +393,29 @@ impl Drop for FuseSession {
 pub struct FuseChannel {
     file: File,
     buf: Vec<u8>,
+    // Lock shared by the channels reading framed requests from the fuse-t socket.
+    read_lock: Option<Arc<Mutex<()>>>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl AsRawFd for FuseChannel {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
 }
 
 impl FuseChannel {
-    fn new(file: File, bufsize: usize) -> Result<Self> {
+    fn new(
+        file: File,
+        bufsize: usize,
+        read_lock: Option<Arc<Mutex<()>>>,
+        shutdown: Arc<AtomicBool>,
+    ) -> Result<Self> {
         Ok(FuseChannel {
             file,
             buf: vec![0x0u8; bufsize],
+            read_lock,
+            shutdown,
         })
     }
 
@@ -228,7 +428,17 @@ impl FuseChannel {
     pub fn get_request(&mut self) -> Result<Option<(Reader, FuseDevWriter)>> {
         let fd = self.file.as_raw_fd();
         loop {
-            match read(fd, &mut self.buf) {
+            if self.shutdown.load(Ordering::Acquire) {
+                return Ok(None);
+            }
+            let res = match &self.read_lock {
+                Some(lock) => {
+                    let _guard = lock.lock().expect("lock fuse-t socket failed");
+                    read_framed(fd, &mut self.buf)
+                }
+                None => read(fd, &mut self.buf),
+            };
+            match res {
                 Ok(len) => {
                     // ###############################################
                     // Note: it's a heavy hack to reuse the same underlying data
@@ -273,6 +483,73 @@ impl FuseChannel {
     }
 }
 
+// Read a request from the fuse-t socket: the header first, then the rest of the `len` bytes it
+// announces. The socket closed by fuse-t reads as `ENODEV`, like an umounted macFUSE device.
+fn read_framed(fd: RawFd, buf: &mut [u8]) -> nix::Result<usize> {
+    let header = size_of::<InHeader>();
+    if buf.len() < header {
+        return Err(Errno::EINVAL);
+    }
+    read_exact(fd, &mut buf[..header])?;
+    let len = u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    if len < header || len > buf.len() {
+        return Err(Errno::EPROTO);
+    }
+    read_exact(fd, &mut buf[header..len])?;
+    Ok(len)
+}
+
+fn read_exact(fd: RawFd, mut buf: &mut [u8]) -> nix::Result<()> {
+    while !buf.is_empty() {
+        match read(fd, buf) {
+            Ok(0) => return Err(Errno::ENODEV),
+            Ok(n) => buf = &mut std::mem::take(&mut buf)[n..],
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// Escape `,` and `\` in the value of a mount option, as libfuse does.
+fn escape_opt(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == ',' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Compose the comma separated options passed to the mount program with `-o`.
+fn mount_opts(fsname: &str, subtype: &str, readonly: bool) -> String {
+    let mut opts = vec![
+        "nodev".to_string(),
+        "nosuid".to_string(),
+        "noatime".to_string(),
+        format!("fsname={}", escape_opt(fsname)),
+    ];
+    if !subtype.is_empty() {
+        opts.push(format!("subtype={}", escape_opt(subtype)));
+    }
+    if readonly {
+        opts.push("ro".to_string());
+    }
+    opts.join(",")
+}
+
+// Compose the command line of the mount program of `backend`.
+fn mount_args(backend: MountBackend, mountpoint: &str, opts: &str) -> Vec<String> {
+    vec![
+        backend.mount_prog().to_string(),
+        "-o".to_string(),
+        opts.to_string(),
+        mountpoint.to_string(),
+    ]
+}
+
 /// Mount a fuse file system
 fn receive_fd(sock_fd: RawFd) -> Result<RawFd> {
     let mut buffer = vec![0u8; 4];
@@ -296,7 +573,21 @@ fn receive_fd(sock_fd: RawFd) -> Result<RawFd> {
     Err(SessionFailure(String::from("not get fd")))
 }
 
-fn fuse_kern_mount(mountpoint: &Path, fsname: &str, subtype: &str, rd_only: bool) -> Result<File> {
+fn fuse_kern_mount(backend: MountBackend, mountpoint: &Path, opts: &str) -> Result<File> {
+    let mountpoint = mountpoint.to_str().ok_or_else(|| {
+        SessionFailure(format!(
+            "convert mountpoint {:?} to string failed",
+            mountpoint
+        ))
+    })?;
+    let mut c_args: Vec<CString> = Vec::new();
+    for arg in mount_args(backend, mountpoint, opts) {
+        let c_arg = CString::new(arg.as_str()).map_err(|e| {
+            SessionFailure(format!("parse option {:?} to cstring failed {:?}", arg, e))
+        })?;
+        c_args.push(c_arg);
+    }
+
     unsafe { signal(Signal::SIGCHLD, SigHandler::SigDfl) }
         .map_err(|e| SessionFailure(format!("fail to reset SIGCHLD handler{:?}", e)))?;
 
@@ -308,73 +599,45 @@ fn fuse_kern_mount(mountpoint: &Path, fsname: &str, subtype: &str, rd_only: bool
     )
     .map_err(|e| SessionFailure(format!("create socket failed {:?}", e)))?;
     let file: File = unsafe {
-        match fork().map_err(|e| SessionFailure(format!("fork mount_macfuse failed {:?}", e)))? {
+        match fork().map_err(|e| SessionFailure(format!("fork mount program failed {:?}", e)))? {
             ForkResult::Parent { .. } => {
                 close(fd0)
                     .map_err(|e| SessionFailure(format!("parent close fd0 failed {:?}", e)))?;
-                let fd = receive_fd(fd1)?;
-                File::from_raw_fd(fd)
+                match backend {
+                    // fuse-t exchanges FUSE messages over the socket itself.
+                    MountBackend::FuseT => File::from_raw_fd(fd1),
+                    _ => {
+                        let fd = receive_fd(fd1);
+                        let _ = close(fd1);
+                        File::from_raw_fd(fd?)
+                    }
+                }
             }
             ForkResult::Child => {
                 close(fd1)
                     .map_err(|e| SessionFailure(format!("child close fd1 failed {:?}", e)))?;
                 fcntl(fd0, F_SETFD(FdFlag::empty()))
                     .map_err(|e| SessionFailure(format!("child fcntl fd0 failed {:?}", e)))?;
-                let mut daemon_path: Vec<u8> =
-                    Vec::with_capacity(PROC_PIDPATHINFO_MAXSIZE as usize);
-                if proc_pidpath(
-                    getpid().as_raw(),
-                    daemon_path.as_mut_ptr() as *mut libc::c_void,
-                    PROC_PIDPATHINFO_MAXSIZE as u32,
-                ) != 0
-                {
-                    let daemon_path = String::from_utf8(daemon_path)
-                        .map_err(|e| SessionFailure(format!("get pid path failed {:?}", e)))?;
-                    std::env::set_var("_FUSE_DAEMON_PATH", daemon_path);
+                if backend == MountBackend::MacFuse {
+                    let mut daemon_path: Vec<u8> =
+                        Vec::with_capacity(PROC_PIDPATHINFO_MAXSIZE as usize);
+                    if proc_pidpath(
+                        getpid().as_raw(),
+                        daemon_path.as_mut_ptr() as *mut libc::c_void,
+                        PROC_PIDPATHINFO_MAXSIZE as u32,
+                    ) != 0
+                    {
+                        let daemon_path = String::from_utf8(daemon_path)
+                            .map_err(|e| SessionFailure(format!("get pid path failed {:?}", e)))?;
+                        std::env::set_var("_FUSE_DAEMON_PATH", daemon_path);
+                    }
+                    std::env::set_var("_FUSE_COMMVERS", "2");
+                    std::env::set_var("_FUSE_CALL_BY_LIB", "1");
                 }
                 std::env::set_var("_FUSE_COMMFD", format!("{}", fd0));
-                std::env::set_var("_FUSE_COMMVERS", "2");
-                std::env::set_var("_FUSE_CALL_BY_LIB", "1");
-
-                // TODO impl -o
-                let prog_path = CString::new(OSXFUSE_MOUNT_PROG).map_err(|e| {
-                    SessionFailure(format!("create mount_macfuse cstring failed: {:?}", e))
-                })?;
-                let mountpoint = mountpoint.to_str().ok_or_else(|| {
-                    SessionFailure(format!(
-                        "convert mountpoint {:?} to string failed",
-                        mountpoint
-                    ))
-                })?;
-                let fsname_opt = format!("fsname={}", fsname);
-                let subtype_opt = format!("subtype={}", subtype);
-                let mut args: Vec<&str> = vec![
-                    OSXFUSE_MOUNT_PROG,
-                    "-o",
-                    "nodev",
-                    "-o",
-                    "nosuid",
-                    "-o",
-                    "noatime",
-                    "-o",
-                    &fsname_opt,
-                    "-o",
-                    &subtype_opt,
-                ];
-                if rd_only {
-                    args.push("-o");
-                    args.push("-ro");
-                }
-                args.push(mountpoint);
-                let mut c_args: Vec<CString> = Vec::with_capacity(args.len());
-                for arg in args {
-                    let c_arg = CString::new(String::from(arg)).map_err(|e| {
-                        SessionFailure(format!("parse option {:?} to cstring failed {:?}", arg, e))
-                    })?;
-                    c_args.push(c_arg);
-                }
-                execv(&prog_path, &c_args)
-                    .map_err(|e| SessionFailure(format!("exec mount_macfuse failed {:?}", e)))?;
+
+                execv(&c_args[0], &c_args)
+                    .map_err(|e| SessionFailure(format!("exec mount program failed {:?}", e)))?;
                 panic!("never arrive here")
             }
         }
@@ -404,13 +667,12 @@ fn create_disk(mountpoint: &Path, dasession: DASessionRef) -> DADiskRef {
 }
 
 /// Umount a fuse file system
-fn fuse_kern_umount(file: File, disk: Option<DADiskRef>) -> Result<()> {
-    if let Err(e) = set_fuse_fd_dead(file.as_raw_fd()) {
-        return Err(SessionFailure(format!(
-            "ioctl set fuse deamon dead failed: {}",
-            e
-        )));
-    }
+fn fuse_kern_umount(
+    backend: Option<MountBackend>,
+    file: File,
+    disk: Option<DADiskRef>,
+) -> Result<()> {
+    abort_conn(backend, file.as_raw_fd())?;
     drop(file);
 
     if let Some(disk) = disk {
@@ -427,6 +689,22 @@ fn fuse_kern_umount(file: File, disk: Option<DADiskRef>) -> Result<()> {
     Ok(())
 }
 
+// Fail all pending and future requests of the connection through `fd`.
+fn abort_conn(backend: Option<MountBackend>, fd: RawFd) -> Result<()> {
+    match backend {
+        Some(MountBackend::FuseT) => match shutdown(fd, Shutdown::Both) {
+            // fuse-t may have closed the socket already.
+            Ok(()) | Err(Errno::ENOTCONN) => Ok(()),
+            Err(e) => Err(SessionFailure(format!(
+                "shutdown fuse-t socket failed: {}",
+                e
+            ))),
+        },
+        _ => set_fuse_fd_dead(fd)
+            .map_err(|e| SessionFailure(format!("ioctl set fuse deamon dead failed: {}", e))),
+    }
+}
+
 fn set_fuse_fd_dead(fd: RawFd) -> std::io::Result<()> {
     unsafe {
         match ioctl::set_fuse_fd_dead(fd, std::mem::transmute(&fd)) {
@@ -440,8 +718,11 @@ fn set_fuse_fd_dead(fd: RawFd) -> std::io::Result<()> {
 mod tests {
     use super::*;
     use std::fs::File;
+    use std::io::Write;
     use std::os::unix::io::FromRawFd;
+    use std::os::unix::net::UnixStream;
     use std::path::Path;
+    use vm_memory::ByteValued;
     use vmm_sys_util::tempdir::TempDir;
 
     #[test]
@@ -456,9 +737,60 @@ mod tests {
 
     #[test]
     fn test_new_channel() {
-        let ch = FuseChannel::new(unsafe { File::from_raw_fd(0) }, 3);
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let ch = FuseChannel::new(unsafe { File::from_raw_fd(0) }, 3, None, shutdown);
         assert!(ch.is_ok());
     }
+
+    #[test]
+    fn test_mount_opts() {
+        assert_eq!(
+            mount_opts("foo", "bar", false),
+            "nodev,nosuid,noatime,fsname=foo,subtype=bar"
+        );
+        assert_eq!(
+            mount_opts("a,b\\c", "", true),
+            "nodev,nosuid,noatime,fsname=a\\,b\\\\c,ro"
+        );
+
+        let args = mount_args(MountBackend::MacFuse, "/mnt", "ro");
+        assert_eq!(args, vec![OSXFUSE_MOUNT_PROG, "-o", "ro", "/mnt"]);
+        let args = mount_args(MountBackend::FuseT, "/mnt", "ro");
+        assert_eq!(args, vec![FUSE_T_MOUNT_PROG, "-o", "ro", "/mnt"]);
+    }
+
+    #[test]
+    fn test_read_framed() {
+        let (mut peer, sock) = UnixStream::pair().unwrap();
+        let header = InHeader {
+            len: size_of::<InHeader>() as u32 + 3,
+            unique: 1,
+            ..Default::default()
+        };
+        let mut msg = header.as_slice().to_vec();
+        msg.extend_from_slice(b"abc");
+        // Two messages in one write are read one at a time.
+        peer.write_all(&[msg.as_slice(), msg.as_slice()].concat())
+            .unwrap();
+
+        let mut buf = vec![0u8; 0x1000];
+        let fd = sock.as_raw_fd();
+        assert_eq!(read_framed(fd, &mut buf).unwrap(), msg.len());
+        assert_eq!(&buf[..msg.len()], msg.as_slice());
+        assert_eq!(read_framed(fd, &mut buf).unwrap(), msg.len());
+
+        // Buffers shorter than a header are rejected.
+        assert_eq!(read_framed(fd, &mut buf[..8]), Err(Errno::EINVAL));
+        // Messages larger than the buffer are rejected.
+        let header = InHeader {
+            len: 0x2000,
+            ..Default::default()
+        };
+        peer.write_all(header.as_slice()).unwrap();
+        assert_eq!(read_framed(fd, &mut buf), Err(Errno::EPROTO));
+        drop(peer);
+        assert_eq!(read_framed(fd, &mut buf), Err(Errno::ENODEV));
+    }
 }
 
 #[cfg(feature = "async-io")]
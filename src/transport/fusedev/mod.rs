@@ -13,24 +13,113 @@ use std::io::{self, IoSlice, Write};
 use std::marker::PhantomData;
 use std::mem::ManuallyDrop;
 use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
 
+use nix::errno::Errno;
 use nix::sys::uio::writev;
 use nix::unistd::write;
 use vm_memory::{ByteValued, VolatileMemory, VolatileSlice};
 
-use super::{Error, FileReadWriteVolatile, FileVolatileSlice, IoBuffers, Reader, Result, Writer};
+use super::{
+    Error, FileReadWriteVolatile, FileVolatileSlice, IoBuffers, PipePayload, Reader, Result, Writer,
+};
 use crate::BitmapSlice;
 
 #[cfg(target_os = "linux")]
 mod linux_session;
 #[cfg(target_os = "linux")]
 pub use linux_session::*;
+#[cfg(all(target_os = "linux", feature = "async-io"))]
+mod async_session;
+#[cfg(all(target_os = "linux", feature = "async-io"))]
+pub use async_session::{AsyncFuseChannel, AsyncFuseSession};
+#[cfg(target_os = "linux")]
+mod umount;
+#[cfg(target_os = "linux")]
+pub use umount::{UmountPolicy, UmountReport, UmountStep};
 
 #[cfg(target_os = "macos")]
 mod macos_session;
 #[cfg(target_os = "macos")]
 pub use macos_session::*;
 
+mod notifier;
+pub use notifier::FuseDevNotifier;
+
+/// How to treat a mountpoint which is a symbolic link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+    /// Mount over the target of the link.
+    #[default]
+    Resolve,
+    /// Fail to create the session.
+    Reject,
+}
+
+// Resolve `mountpoint` to a canonical absolute path of a directory, following `policy` if it's
+// a symbolic link.
+fn resolve_mountpoint(mountpoint: &Path, policy: SymlinkPolicy) -> Result<PathBuf> {
+    let meta = mountpoint
+        .symlink_metadata()
+        .map_err(|_| Error::SessionFailure(format!("invalid mountpoint {:?}", mountpoint)))?;
+    if meta.file_type().is_symlink() && policy == SymlinkPolicy::Reject {
+        return Err(Error::SessionFailure(format!(
+            "mountpoint {:?} is a symbolic link",
+            mountpoint
+        )));
+    }
+    let dest = mountpoint
+        .canonicalize()
+        .map_err(|_| Error::SessionFailure(format!("invalid mountpoint {:?}", mountpoint)))?;
+    if !dest.is_dir() {
+        return Err(Error::SessionFailure(format!(
+            "{:?} is not a directory",
+            dest
+        )));
+    }
+    Ok(dest)
+}
+
+/// Check whether `err` is caused by a partial write of a message to the fuse device.
+///
+/// The fuse device consumes each message in one shot, so a partial write means the connection
+/// is in an unexpected state and the request will never be answered. Sessions should abort the
+/// connection deliberately instead of continuing to serve requests.
+pub fn is_partial_write(err: &io::Error) -> bool {
+    matches!(
+        err.get_ref().and_then(|e| e.downcast_ref::<Error>()),
+        Some(Error::PartialWrite(_, _))
+    )
+}
+
+// Write a whole message to the fuse device with `op`, retrying if interrupted by signals.
+fn write_message<F>(fd: RawFd, bufs: &[IoSlice], mut op: F) -> io::Result<usize>
+where
+    F: FnMut(&[IoSlice]) -> nix::Result<usize>,
+{
+    let expected = bufs.iter().map(|b| b.len()).sum();
+    loop {
+        match op(bufs) {
+            Ok(n) if n == expected => return Ok(n),
+            Ok(n) => {
+                error!(
+                    "partial write to fuse device fd {}, {} of {} bytes",
+                    fd, n, expected
+                );
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    Error::PartialWrite(expected, n),
+                ));
+            }
+            Err(Errno::EINTR) => continue,
+            Err(e) => {
+                error!("fail to write to fuse device fd {}: {}", fd, e);
+                return Err(io::Error::from_raw_os_error(e as i32));
+            }
+        }
+    }
+}
+
 /// A buffer reference wrapper for fuse requests.
 #[derive(Debug)]
 pub struct FuseBuf<'a> {
@@ -60,8 +149,21 @@ impl<'a, S: BitmapSlice + Default> Reader<'a, S> {
                 buffers,
                 bytes_consumed: 0,
             },
+            pipe: None,
         })
     }
+
+    /// Construct a new Reader wrapper over a request partially read into `buf`, with its
+    /// remaining `len` bytes left in the pipe `pipe_fd`.
+    ///
+    /// The data in the pipe is read after `buf`, and may be moved into files by
+    /// [Reader::splice_to_at] without being copied. The caller must ensure `pipe_fd` is valid
+    /// during the lifetime of the returned object.
+    pub fn from_fuse_pipe(buf: FuseBuf<'a>, pipe_fd: RawFd, len: usize) -> Result<Reader<'a, S>> {
+        let mut reader = Self::from_fuse_buffer(buf)?;
+        reader.pipe = Some(PipePayload { fd: pipe_fd, len });
+        Ok(reader)
+    }
 }
 
 /// Writer to send FUSE reply to the FUSE driver.
@@ -71,11 +173,18 @@ impl<'a, S: BitmapSlice + Default> Reader<'a, S> {
 /// 2. If the writer is split, a final commit() MUST be called to issue the
 ///    device write operation.
 /// 3. Concurrency, caller should not write to the writer concurrently.
+///
+/// The final commit gathers the header, the buffered data and the data segments appended by
+/// [FuseDevWriter::write_ref] into one iovec array, written by a single `writev`.
 #[derive(Debug, PartialEq, Eq)]
 pub struct FuseDevWriter<'a, S: BitmapSlice = ()> {
     fd: RawFd,
     buffered: bool,
     buf: ManuallyDrop<Vec<u8>>,
+    // Data segments written by reference, with the length of `buf` when they were appended.
+    refs: Vec<(usize, &'a [u8])>,
+    // Size of the buffer the writer was created on, no reply is larger.
+    max_reply: usize,
     bitmapslice: S,
     phantom: PhantomData<&'a mut [S]>,
 }
@@ -88,6 +197,8 @@ impl<'a, S: BitmapSlice + Default> FuseDevWriter<'a, S> {
             fd,
             buffered: false,
             buf: ManuallyDrop::new(buf),
+            refs: Vec::new(),
+            max_reply: data_buf.len(),
             bitmapslice: S::default(),
             phantom: PhantomData,
         })
@@ -99,11 +210,14 @@ impl<'a, S: BitmapSlice> FuseDevWriter<'a, S> {
     ///
     /// After the split, `self` will be able to write up to `offset` bytes while the returned
     /// `Writer` can write up to `available_bytes() - offset` bytes.  Returns an error if
-    /// `offset > self.available_bytes()`.
+    /// `offset > self.available_bytes()`, or if data was written by reference.
     pub fn split_at(&mut self, offset: usize) -> Result<FuseDevWriter<'a, S>> {
         if self.buf.capacity() < offset {
             return Err(Error::SplitOutOfBounds(offset));
         }
+        if !self.refs.is_empty() {
+            return Err(Error::InvalidParameter);
+        }
 
         let (len1, len2) = if self.buf.len() > offset {
             (offset, self.buf.len() - offset)
@@ -122,45 +236,113 @@ impl<'a, S: BitmapSlice> FuseDevWriter<'a, S> {
             fd: self.fd,
             buffered: true,
             buf,
+            refs: Vec::new(),
+            max_reply: self.max_reply,
             bitmapslice: self.bitmapslice.clone(),
             phantom: PhantomData,
         })
     }
 
+    // Get a writer on the space left in the writer, to account data written there in place by
+    // other means. Safe as long as the writers don't write to the space concurrently.
+    pub(crate) unsafe fn alias(&self) -> FuseDevWriter<'a, S> {
+        let len = self.buf.len();
+        let ptr = (self.buf.as_ptr() as *mut u8).add(len);
+        let buf = ManuallyDrop::new(Vec::from_raw_parts(ptr, 0, self.buf.capacity() - len));
+        FuseDevWriter {
+            fd: self.fd,
+            buffered: self.buffered,
+            buf,
+            refs: Vec::new(),
+            max_reply: self.max_reply,
+            bitmapslice: self.bitmapslice.clone(),
+            phantom: PhantomData,
+        }
+    }
+
     /// Compose the FUSE reply message and send the message to `/dev/fuse`.
+    ///
+    /// The buffers of the writer and of `other`, which follows it in the reply, are written by a
+    /// single `writev`. Replies larger than the buffer the writer was created on, sized after
+    /// the negotiated `max_write`, fail with [Error::ReplyTooLarge] before anything is written.
     pub fn commit(&mut self, other: Option<&Writer<'a, S>>) -> io::Result<usize> {
         if !self.buffered {
             return Ok(0);
         }
 
-        let o = match other {
-            Some(Writer::FuseDev(w)) => w.buf.as_slice(),
-            _ => &[],
-        };
-        let res = match (self.buf.len(), o.len()) {
-            (0, 0) => Ok(0),
-            (0, _) => write(self.fd, o),
-            (_, 0) => write(self.fd, self.buf.as_slice()),
-            (_, _) => {
-                let bufs = [IoSlice::new(self.buf.as_slice()), IoSlice::new(o)];
-                writev(self.fd, &bufs)
+        let fd = self.fd;
+        self.write_reply(other, |b| writev(fd, b))
+    }
+
+    // Gather the segments of the reply made of the writer followed by `other`, and write them
+    // with `op`.
+    fn write_reply<F>(&self, other: Option<&Writer<'a, S>>, op: F) -> io::Result<usize>
+    where
+        F: FnMut(&[IoSlice]) -> nix::Result<usize>,
+    {
+        let mut bufs = Vec::with_capacity(2 * (self.refs.len() + 1));
+        self.gather(&mut bufs);
+        if let Some(Writer::FuseDev(w)) = other {
+            w.gather(&mut bufs);
+        }
+
+        let len = bufs.iter().map(|b| b.len()).sum::<usize>();
+        if len == 0 {
+            return Ok(0);
+        }
+        if len > self.max_reply {
+            error!(
+                "fuse reply of {} bytes exceeds the max reply size {}",
+                len, self.max_reply
+            );
+            return Err(io::Error::other(Error::ReplyTooLarge(len, self.max_reply)));
+        }
+        write_message(self.fd, &bufs, op)
+    }
+
+    // Append the non-empty segments of the writer to `bufs`, in order.
+    fn gather<'b>(&'b self, bufs: &mut Vec<IoSlice<'b>>) {
+        let mut start = 0;
+        for (pos, data) in self.refs.iter() {
+            if *pos > start {
+                bufs.push(IoSlice::new(&self.buf[start..*pos]));
             }
-        };
+            bufs.push(IoSlice::new(data));
+            start = *pos;
+        }
+        if self.buf.len() > start {
+            bufs.push(IoSlice::new(&self.buf[start..]));
+        }
+    }
 
-        res.map_err(|e| {
-            error! {"fail to write to fuse device on commit: {}", e};
-            io::Error::from_raw_os_error(e as i32)
-        })
+    /// Append `data` to the writer by reference, without copying it.
+    ///
+    /// Split writers keep the reference until the final commit, which sends it along with the
+    /// rest of the reply. Other writers send it to `/dev/fuse` right away, like
+    /// [io::Write::write]. Offset writes aren't supported once data was written by reference.
+    pub fn write_ref(&mut self, data: &'a [u8]) -> io::Result<usize> {
+        if !self.buffered {
+            return self.write(data);
+        }
+        self.check_available_space(data.len())?;
+        if !data.is_empty() {
+            self.refs.push((self.buf.len(), data));
+        }
+        Ok(data.len())
+    }
+
+    fn ref_bytes(&self) -> usize {
+        self.refs.iter().map(|(_, data)| data.len()).sum()
     }
 
     /// Return number of bytes already written to the internal buffer.
     pub fn bytes_written(&self) -> usize {
-        self.buf.len()
+        self.buf.len() + self.ref_bytes()
     }
 
     /// Return number of bytes available for writing.
     pub fn available_bytes(&self) -> usize {
-        self.buf.capacity() - self.buf.len()
+        self.buf.capacity() - self.bytes_written()
     }
 
     fn account_written(&mut self, count: usize) {
@@ -231,6 +413,49 @@ impl<'a, S: BitmapSlice> FuseDevWriter<'a, S> {
         }
     }
 
+    /// Write data at offset `buf_offset` from the current position from a File at offset `off`,
+    /// without advancing the current position.
+    ///
+    /// Only a split writer supports writing at an offset. Return the number of bytes written.
+    pub fn write_from_at_offset<F: FileReadWriteVolatile>(
+        &mut self,
+        mut src: F,
+        count: usize,
+        off: u64,
+        buf_offset: usize,
+    ) -> io::Result<usize> {
+        if !self.buffered || !self.refs.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let end = buf_offset
+            .checked_add(count)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "buffer size is too big"))?;
+        self.check_available_space(end)?;
+
+        src.read_vectored_at_volatile(
+            // Safe because we have made sure buf has at least `buf_offset + count` capacity above
+            unsafe {
+                &[FileVolatileSlice::new(
+                    self.buf.as_mut_ptr().add(self.buf.len() + buf_offset),
+                    count,
+                )]
+            },
+            off,
+        )
+    }
+
+    /// Advance the current position by `count` bytes, accounting data written by
+    /// [FuseDevWriter::write_from_at_offset].
+    pub fn advance(&mut self, count: usize) -> io::Result<()> {
+        if !self.buffered || !self.refs.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        self.check_available_space(count)?;
+        self.account_written(count);
+
+        Ok(())
+    }
+
     /// Write all data to the writer from a file descriptor.
     pub fn write_all_from<F: FileReadWriteVolatile>(
         &mut self,
@@ -273,10 +498,7 @@ impl<'a, S: BitmapSlice> FuseDevWriter<'a, S> {
     }
 
     fn do_write(fd: RawFd, data: &[u8]) -> io::Result<usize> {
-        write(fd, data).map_err(|e| {
-            error! {"fail to write to fuse device fd {}: {}, {:?}", fd, e, data};
-            io::Error::new(io::ErrorKind::Other, format!("{}", e))
-        })
+        write_message(fd, &[IoSlice::new(data)], |b| write(fd, &b[0]))
     }
 }
 
@@ -309,15 +531,11 @@ impl<'a, S: BitmapSlice> io::Write for FuseDevWriter<'a, S> {
             if bufs.is_empty() {
                 return Ok(0);
             }
-            writev(self.fd, bufs)
-                .map(|x| {
-                    self.account_written(x);
-                    x
-                })
-                .map_err(|e| {
-                    error! {"fail to write to fuse device on commit: {}", e};
-                    io::Error::new(io::ErrorKind::Other, format!("{}", e))
-                })
+            let fd = self.fd;
+            write_message(fd, bufs, |b| writev(fd, b)).map(|x| {
+                self.account_written(x);
+                x
+            })
         }
     }
 
@@ -338,6 +556,18 @@ mod async_io {
     use crate::transport::AsyncFileReadWriteVolatile;
 
     impl<'a, S: BitmapSlice> FuseDevWriter<'a, S> {
+        // Get a buffer on at most `count` bytes of the space left in the writer, for asynchronous
+        // IO to write to. The buffer must not outlive the writer.
+        pub(crate) unsafe fn prepare_io_buf(&self, count: usize) -> Vec<FileVolatileBuf> {
+            let len = self.buf.len();
+            let count = std::cmp::min(count, self.buf.capacity() - len);
+            if count == 0 {
+                return Vec::new();
+            }
+            let ptr = (self.buf.as_ptr() as *mut u8).add(len);
+            vec![FileVolatileBuf::from_raw(ptr, 0, count)]
+        }
+
         /// Write data from a buffer into this writer in asynchronous mode.
         ///
         /// Return the number of bytes written to the writer.
@@ -349,15 +579,14 @@ mod async_io {
                 self.buf.extend_from_slice(data);
                 Ok(data.len())
             } else {
-                nix::sys::uio::pwrite(self.fd, data, 0)
-                    .map(|x| {
-                        self.account_written(x);
-                        x
-                    })
-                    .map_err(|e| {
-                        error! {"fail to write to fuse device fd {}: {}", self.fd, e};
-                        io::Error::new(io::ErrorKind::Other, format!("{}", e))
-                    })
+                let fd = self.fd;
+                write_message(fd, &[IoSlice::new(data)], |b| {
+                    nix::sys::uio::pwrite(fd, &b[0], 0)
+                })
+                .map(|x| {
+                    self.account_written(x);
+                    x
+                })
             }
         }
 
@@ -375,15 +604,11 @@ mod async_io {
                 Ok(len)
             } else {
                 let bufs = [std::io::IoSlice::new(data), std::io::IoSlice::new(data2)];
-                writev(self.fd, &bufs)
-                    .map(|x| {
-                        self.account_written(x);
-                        x
-                    })
-                    .map_err(|e| {
-                        error! {"fail to write to fuse device fd {}: {}", self.fd, e};
-                        io::Error::new(io::ErrorKind::Other, format!("{}", e))
-                    })
+                let fd = self.fd;
+                write_message(fd, &bufs, |b| writev(fd, b)).map(|x| {
+                    self.account_written(x);
+                    x
+                })
             }
         }
 
@@ -411,15 +636,11 @@ mod async_io {
                     std::io::IoSlice::new(data2),
                     std::io::IoSlice::new(data3),
                 ];
-                writev(self.fd, &bufs)
-                    .map(|x| {
-                        self.account_written(x);
-                        x
-                    })
-                    .map_err(|e| {
-                        error! {"fail to write to fuse device fd {}: {}", self.fd, e};
-                        io::Error::new(io::ErrorKind::Other, format!("{}", e))
-                    })
+                let fd = self.fd;
+                write_message(fd, &bufs, |b| writev(fd, b)).map(|x| {
+                    self.account_written(x);
+                    x
+                })
             }
         }
 
@@ -453,8 +674,31 @@ mod async_io {
         ) -> io::Result<usize> {
             self.check_available_space(count)?;
 
-            let buf = unsafe { FileVolatileBuf::from_raw(self.buf.as_mut_ptr(), 0, count) };
-            let (res, _) = src.async_read_at_volatile(buf, off).await;
+            // Reads completing short are resubmitted for the rest, until the end of the file.
+            let base = self.buf.len();
+            let mut done = 0;
+            let mut res = Ok(0);
+            while done < count {
+                // Safe because `check_available_space()` ensures the spare capacity of the buffer
+                // holds `count` bytes, and the buffer isn't touched while reading.
+                let buf = unsafe {
+                    FileVolatileBuf::from_raw(
+                        self.buf.as_mut_ptr().add(base + done),
+                        0,
+                        count - done,
+                    )
+                };
+                match src.async_read_at_volatile(buf, off + done as u64).await {
+                    (Ok(0), _) => break,
+                    (Ok(cnt), _) => done += cnt,
+                    (Err(e), _) if done == 0 => {
+                        res = Err(e);
+                        break;
+                    }
+                    (Err(_), _) => break,
+                }
+                res = Ok(done);
+            }
             match res {
                 Ok(cnt) => {
                     self.account_written(cnt);
@@ -462,9 +706,9 @@ mod async_io {
                         Ok(cnt)
                     } else {
                         // write to fd, can only happen once per instance
-                        nix::sys::uio::pwrite(self.fd, &self.buf[..cnt], 0).map_err(|e| {
-                            error! {"fail to write to fuse device fd {}: {}", self.fd, e};
-                            io::Error::new(io::ErrorKind::Other, format!("{}", e))
+                        let fd = self.fd;
+                        write_message(fd, &[IoSlice::new(&self.buf[..cnt])], |b| {
+                            nix::sys::uio::pwrite(fd, &b[0], 0)
                         })
                     }
                 }
@@ -476,36 +720,14 @@ mod async_io {
         ///
         /// We need this because the lifetime of others is usually shorter than self.
         pub async fn async_commit(&mut self, other: Option<&Writer<'a, S>>) -> io::Result<usize> {
-            let o = match other {
-                Some(Writer::FuseDev(w)) => w.buf.as_slice(),
-                _ => &[],
-            };
-
-            let res = match (self.buf.len(), o.len()) {
-                (0, 0) => Ok(0),
-                (0, _) => nix::sys::uio::pwrite(self.fd, o, 0).map_err(|e| {
-                    error! {"fail to write to fuse device fd {}: {}", self.fd, e};
-                    io::Error::new(io::ErrorKind::Other, format!("{}", e))
-                }),
-                (_, 0) => nix::sys::uio::pwrite(self.fd, self.buf.as_slice(), 0).map_err(|e| {
-                    error! {"fail to write to fuse device fd {}: {}", self.fd, e};
-                    io::Error::new(io::ErrorKind::Other, format!("{}", e))
-                }),
-                (_, _) => {
-                    let bufs = [
-                        std::io::IoSlice::new(self.buf.as_slice()),
-                        std::io::IoSlice::new(o),
-                    ];
-                    writev(self.fd, &bufs).map_err(|e| {
-                        error! {"fail to write to fuse device fd {}: {}", self.fd, e};
-                        io::Error::new(io::ErrorKind::Other, format!("{}", e))
-                    })
-                }
-            };
+            if !self.buffered {
+                return Ok(0);
+            }
 
-            res.map_err(|e| {
-                error! {"fail to write to fuse device on commit: {}", e};
-                e
+            let fd = self.fd;
+            self.write_reply(other, |b| match b {
+                [b] => nix::sys::uio::pwrite(fd, b, 0),
+                _ => writev(fd, b),
             })
         }
     }
@@ -518,6 +740,214 @@ mod tests {
     use std::os::unix::io::AsRawFd;
     use vmm_sys_util::tempfile::TempFile;
 
+    // Mock fuse device write syscall, returning results from `seq` in order.
+    fn mock_write(seq: Vec<nix::Result<usize>>) -> impl FnMut(&[IoSlice]) -> nix::Result<usize> {
+        let mut seq = seq.into_iter();
+        move |_| seq.next().expect("unexpected write")
+    }
+
+    #[test]
+    fn test_write_message_retry_eintr() {
+        let data = [0u8; 16];
+        let bufs = [IoSlice::new(&data[..10]), IoSlice::new(&data[10..])];
+
+        let op = mock_write(vec![Err(Errno::EINTR), Err(Errno::EINTR), Ok(16)]);
+        assert_eq!(write_message(0, &bufs, op).unwrap(), 16);
+
+        let op = mock_write(vec![Err(Errno::EINTR), Err(Errno::ENOENT)]);
+        let e = write_message(0, &bufs, op).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOENT));
+        assert!(!is_partial_write(&e));
+    }
+
+    // Create a pipe holding `data`, return its read and write ends.
+    #[cfg(target_os = "linux")]
+    fn pipe_with(data: &[u8]) -> (std::fs::File, std::fs::File) {
+        use std::os::unix::io::FromRawFd;
+
+        let (rd, wr) = nix::unistd::pipe().unwrap();
+        let (rd, mut wr) = unsafe {
+            (
+                std::fs::File::from_raw_fd(rd),
+                std::fs::File::from_raw_fd(wr),
+            )
+        };
+        wr.write_all(data).unwrap();
+        (rd, wr)
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_reader_fuse_pipe() {
+        let mut head = [1u8, 2, 3, 4];
+        let payload: Vec<u8> = (0..8192u32).map(|i| i as u8).collect();
+        let (rd, _wr) = pipe_with(&payload);
+        let mut reader =
+            Reader::<()>::from_fuse_pipe(FuseBuf::new(&mut head), rd.as_raw_fd(), payload.len())
+                .unwrap();
+        assert_eq!(reader.available_bytes(), 4 + 8192);
+
+        // Data in the buffer is read first, and can't be spliced.
+        let mut file = TempFile::new().unwrap().into_file();
+        assert_eq!(reader.splice_to_at(file.as_raw_fd(), 16, 0).unwrap(), None);
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+
+        // Data in the pipe is spliced or copied.
+        assert_eq!(
+            reader.splice_to_at(file.as_raw_fd(), 4096, 0).unwrap(),
+            Some(4096)
+        );
+        assert_eq!(reader.read_to_at(&mut file, 100, 4096).unwrap(), 100);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, payload[4196..]);
+        assert_eq!(reader.available_bytes(), 0);
+        assert_eq!(reader.splice_to_at(file.as_raw_fd(), 16, 0).unwrap(), None);
+
+        let mut content = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut content).unwrap();
+        assert_eq!(content, payload[..4196]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_reader_fuse_pipe_fallback() {
+        let payload = vec![0x5au8; 4096];
+        let (rd, _wr) = pipe_with(&payload);
+        let mut reader =
+            Reader::<()>::from_fuse_pipe(FuseBuf::new(&mut []), rd.as_raw_fd(), payload.len())
+                .unwrap();
+
+        // Splicing into files opened for appending isn't supported, the data stays in the pipe.
+        let tmp = TempFile::new().unwrap();
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(tmp.as_path())
+            .unwrap();
+        let e = reader.splice_to_at(file.as_raw_fd(), 4096, 0).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EINVAL));
+        assert_eq!(reader.available_bytes(), 4096);
+        assert_eq!(reader.read_to(&mut file, 4096).unwrap(), 4096);
+        assert_eq!(std::fs::read(tmp.as_path()).unwrap(), payload);
+    }
+
+    #[test]
+    fn test_write_message_partial() {
+        let data = [0u8; 16];
+        let bufs = [IoSlice::new(&data)];
+
+        let op = mock_write(vec![Err(Errno::EINTR), Ok(10)]);
+        let e = write_message(0, &bufs, op).unwrap_err();
+        assert!(is_partial_write(&e));
+        match e.get_ref().unwrap().downcast_ref::<Error>() {
+            Some(Error::PartialWrite(expected, written)) => {
+                assert_eq!(*expected, 16);
+                assert_eq!(*written, 10);
+            }
+            _ => panic!("expect Error::PartialWrite"),
+        }
+        assert!(!is_partial_write(&io::Error::from_raw_os_error(libc::EIO)));
+    }
+
+    // The pipe stands for the fuse device, each reply written by a single writev of its segments.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_commit_single_writev() {
+        let (mut rd, wr) = pipe_with(&[]);
+        let mut buf = vec![0u8; 0x1000];
+        let mut header = FuseDevWriter::<()>::new(wr.as_raw_fd(), &mut buf).unwrap();
+        let mut data = header.split_at(16).unwrap();
+
+        let refs = [vec![0xaau8; 512], vec![0xbbu8; 256]];
+        data.write_all(&[1u8; 8]).unwrap();
+        assert_eq!(data.write_ref(&refs[0]).unwrap(), 512);
+        data.write_all(&[2u8; 8]).unwrap();
+        assert_eq!(data.write_ref(&refs[1]).unwrap(), 256);
+        assert_eq!(data.write_ref(&[]).unwrap(), 0);
+        assert_eq!(data.bytes_written(), 784);
+        assert_eq!(data.available_bytes(), 0x1000 - 16 - 784);
+        assert!(data.split_at(8).is_err());
+        assert!(data.advance(1).is_err());
+        header.write_all(&[0u8; 16]).unwrap();
+        let data = Writer::from(data);
+
+        let mut calls = Vec::new();
+        let n = header
+            .write_reply(Some(&data), |b| {
+                calls.push(b.iter().map(|b| b.len()).collect::<Vec<_>>());
+                Ok(b.iter().map(|b| b.len()).sum())
+            })
+            .unwrap();
+        assert_eq!(n, 800);
+        assert_eq!(calls, vec![vec![16, 8, 512, 8, 256]]);
+
+        assert_eq!(header.commit(Some(&data)).unwrap(), 800);
+        let mut reply = vec![0u8; 800];
+        rd.read_exact(&mut reply).unwrap();
+        assert_eq!(reply[..16], [0u8; 16]);
+        assert_eq!(reply[16..24], [1u8; 8]);
+        assert_eq!(reply[24..536], refs[0][..]);
+        assert_eq!(reply[536..544], [2u8; 8]);
+        assert_eq!(reply[544..], refs[1][..]);
+    }
+
+    #[test]
+    fn test_commit_reply_too_large() {
+        let mut buf = [0u8; 32];
+        let mut big = [0u8; 64];
+        let mut header = FuseDevWriter::<()>::new(-1, &mut buf).unwrap();
+        let _ = header.split_at(16).unwrap();
+        header.write_all(&[0u8; 16]).unwrap();
+        let mut data = FuseDevWriter::<()>::new(-1, &mut big).unwrap();
+        let mut data = data.split_at(0).unwrap();
+        data.write_all(&[0u8; 32]).unwrap();
+
+        let e = header
+            .write_reply(Some(&data.into()), |_| panic!("unexpected write"))
+            .unwrap_err();
+        match e.get_ref().unwrap().downcast_ref::<Error>() {
+            Some(Error::ReplyTooLarge(len, max)) => assert_eq!((*len, *max), (48, 32)),
+            _ => panic!("expect Error::ReplyTooLarge"),
+        }
+        assert!(!is_partial_write(&e));
+    }
+
+    // The payload of a short read is sent right after the header, whatever the space reserved.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_reserve_header() {
+        let (mut rd, wr) = pipe_with(&[]);
+        let mut file = TempFile::new().unwrap().into_file();
+        file.write_all(&[0xa5u8; 100]).unwrap();
+        let mut buf = vec![0u8; 0x1000];
+        let writer = Writer::from(FuseDevWriter::<()>::new(wr.as_raw_fd(), &mut buf).unwrap());
+
+        let (header, mut data) = writer.reserve_header(16).unwrap();
+        assert_eq!(data.available_bytes(), 0x1000 - 16);
+        assert_eq!(data.write_from_at(&mut file, 512, 0).unwrap(), 100);
+        assert_eq!(header.commit(&[1u8; 16], 100).unwrap(), 116);
+        let mut reply = vec![0u8; 116];
+        rd.read_exact(&mut reply).unwrap();
+        assert_eq!(reply[..16], [1u8; 16]);
+        assert_eq!(reply[16..], [0xa5u8; 100]);
+
+        // The header must be reserved first, and filled in exactly.
+        let mut buf = vec![0u8; 0x1000];
+        let mut writer = Writer::from(FuseDevWriter::<()>::new(wr.as_raw_fd(), &mut buf).unwrap());
+        writer.write_all(&[0u8; 8]).unwrap();
+        assert!(writer.reserve_header(16).is_err());
+        let mut buf = vec![0u8; 0x1000];
+        let writer = Writer::from(FuseDevWriter::<()>::new(wr.as_raw_fd(), &mut buf).unwrap());
+        assert!(writer.reserve_header(0x1001).is_err());
+        let mut buf = vec![0u8; 0x1000];
+        let writer = Writer::from(FuseDevWriter::<()>::new(wr.as_raw_fd(), &mut buf).unwrap());
+        let (header, _) = writer.reserve_header(16).unwrap();
+        assert!(header.commit(&[1u8; 8], 0).is_err());
+    }
+
     #[test]
     fn reader_test_simple_chain() {
         let mut buf = [0u8; 106];
@@ -819,6 +1249,34 @@ mod tests {
         assert_eq!(reader.bytes_read(), 48);
     }
 
+    #[test]
+    fn read_to_at_offset() {
+        let mut buf2: Vec<u8> = (0..48u8).collect();
+        let reader = Reader::<()>::from_fuse_buffer(FuseBuf::new(&mut buf2)).unwrap();
+        let mut file = TempFile::new().unwrap().into_file();
+
+        // Consume segments out of order without moving the cursor.
+        for (off, len) in [(32usize, 16usize), (0, 20), (20, 12)] {
+            assert_eq!(
+                reader
+                    .read_to_at_offset(&mut file, len, off as u64, off)
+                    .unwrap(),
+                len
+            );
+        }
+        assert_eq!(reader.available_bytes(), 48);
+        assert_eq!(reader.bytes_read(), 0);
+        reader.read_to_at_offset(&mut file, 17, 0, 32).unwrap_err();
+        reader
+            .read_to_at_offset(&mut file, 1, 0, usize::MAX)
+            .unwrap_err();
+
+        let mut content = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut content).unwrap();
+        assert_eq!(content, (0..48u8).collect::<Vec<_>>());
+    }
+
     #[test]
     fn write_obj() {
         let file1 = TempFile::new().unwrap().into_file();
@@ -931,6 +1389,40 @@ mod tests {
         assert_eq!(writer.bytes_written(), 40);
     }
 
+    #[test]
+    fn write_from_at_offset() {
+        let file1 = TempFile::new().unwrap().into_file();
+        let mut buf = vec![0x0u8; 58];
+        let mut writer = FuseDevWriter::<()>::new(file1.as_raw_fd(), &mut buf).unwrap();
+        writer
+            .write_from_at_offset(&mut file1.try_clone().unwrap(), 1, 0, 0)
+            .unwrap_err();
+        let mut other = writer.split_at(10).unwrap();
+        let mut file = TempFile::new().unwrap().into_file();
+        let content: Vec<u8> = (0..48u8).collect();
+        file.write_all(&content).unwrap();
+
+        // Assemble segments out of order without moving the cursor.
+        other.write_all(&[0xff; 8]).unwrap();
+        for (off, len) in [(24usize, 16usize), (0, 10), (10, 14)] {
+            assert_eq!(
+                other
+                    .write_from_at_offset(&mut file, len, off as u64, off)
+                    .unwrap(),
+                len
+            );
+        }
+        assert_eq!(other.bytes_written(), 8);
+        other.write_from_at_offset(&mut file, 1, 0, 40).unwrap_err();
+
+        other.advance(40).unwrap();
+        assert_eq!(other.bytes_written(), 48);
+        assert_eq!(other.available_bytes(), 0);
+        assert_eq!(&other.buf[..8], &[0xff; 8]);
+        assert_eq!(&other.buf[8..], &content[..40]);
+        other.advance(1).unwrap_err();
+    }
+
     #[cfg(feature = "async-io")]
     mod async_io {
         use tokio_uring::fs::OpenOptions;
@@ -1055,5 +1547,30 @@ mod tests {
             let res = tokio_uring::start(async { writer.async_commit(Some(&other.into())).await });
             let _ = res.unwrap();
         }
+
+        #[test]
+        fn async_reserve_header() {
+            let (mut rd, wr) = pipe_with(&[]);
+            let dir = TempDir::new().unwrap();
+            let path = dir.as_path().to_path_buf().join("test.txt");
+            std::fs::write(&path, [0x5au8; 100]).unwrap();
+            let mut buf = vec![0u8; 0x1000];
+            let writer = Writer::from(FuseDevWriter::<()>::new(wr.as_raw_fd(), &mut buf).unwrap());
+
+            let (header, data) = writer.reserve_header(16).unwrap();
+            let mut bufs = unsafe { data.into_io_bufs(512) };
+            assert_eq!(bufs.len(), 1);
+            let count = tokio_uring::start(async {
+                let file = OpenOptions::new().read(true).open(&path).await.unwrap();
+                let (res, _) = file.read_at(bufs.remove(0), 0).await;
+                res.unwrap()
+            });
+            assert_eq!(count, 100);
+            assert_eq!(header.commit(&[1u8; 16], count).unwrap(), 116);
+            let mut reply = vec![0u8; 116];
+            rd.read_exact(&mut reply).unwrap();
+            assert_eq!(reply[..16], [1u8; 16]);
+            assert_eq!(reply[16..], [0x5au8; 100]);
+        }
     }
 }
@@ -0,0 +1,346 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Send notifications to the in kernel fuse driver through the fuse device.
+
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, IoSlice};
+use std::os::unix::io::AsRawFd;
+
+use nix::sys::uio::writev;
+
+use super::write_message;
+use crate::api::attr_cache::Notifier;
+use crate::api::server::NotifyMessage;
+use crate::transport::FileVolatileSlice;
+
+/// A [Notifier] writing notification messages directly to the fuse device.
+///
+/// Invalidations of inodes or directory entries unknown to the kernel are silently ignored, as
+/// there's nothing to invalidate. Data pushed by `store()` is split into messages of at most
+/// `max_write` bytes. Flags of entry invalidations are only sent to kernels speaking the protocol
+/// minor version set by [FuseDevNotifier::with_minor].
+pub struct FuseDevNotifier {
+    file: File,
+    max_write: usize,
+    minor: u32,
+}
+
+impl FuseDevNotifier {
+    /// Create a notifier writing to the fuse device `file`, with the `max_write` negotiated with
+    /// the kernel.
+    pub fn new(file: File, max_write: u32) -> Self {
+        FuseDevNotifier {
+            file,
+            max_write: (max_write as usize).max(1),
+            minor: 0,
+        }
+    }
+
+    /// Encode notifications for the protocol minor version `minor` negotiated by `FUSE_INIT`,
+    /// as reported by [Server::connection_info](crate::api::server::Server::connection_info).
+    ///
+    /// The default value for this option is 0, which never sends flags the kernel may not know.
+    pub fn with_minor(mut self, minor: u32) -> Self {
+        self.minor = minor;
+        self
+    }
+
+    fn send<F>(&self, msg: &NotifyMessage, op: F) -> io::Result<usize>
+    where
+        F: FnMut(&[IoSlice]) -> nix::Result<usize>,
+    {
+        write_message(self.file.as_raw_fd(), &msg.bufs(), op)
+    }
+
+    fn send_inval(&self, msg: NotifyMessage) {
+        let fd = self.file.as_raw_fd();
+        match self.send(&msg, |b| writev(fd, b)) {
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {}
+            Err(e) => warn!(
+                "fuse: failed to send {:?} notification, {}",
+                msg.opcode(),
+                e
+            ),
+            Ok(_) => {}
+        }
+    }
+
+    fn store_with<F>(
+        &self,
+        inode: u64,
+        offset: u64,
+        data: &[FileVolatileSlice],
+        mut op: F,
+    ) -> io::Result<usize>
+    where
+        F: FnMut(&[IoSlice]) -> nix::Result<usize>,
+    {
+        // Safe because the slices are valid during the call.
+        let data: Vec<&[u8]> = data
+            .iter()
+            .map(|s| unsafe { std::slice::from_raw_parts(s.as_ptr() as *const u8, s.len()) })
+            .collect();
+
+        let mut stored = 0;
+        for msg in NotifyMessage::store(inode, offset, &data, self.max_write) {
+            match self.send(&msg, &mut op) {
+                Ok(_) => stored += msg.payload_len(),
+                // The kernel doesn't have the inode cached.
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(stored)
+    }
+}
+
+impl Notifier for FuseDevNotifier {
+    fn inval_inode(&self, ino: u64) {
+        self.inval_inode_range(ino, 0, 0);
+    }
+
+    fn inval_inode_range(&self, ino: u64, off: i64, len: i64) {
+        self.send_inval(NotifyMessage::inval_inode(ino, off, len));
+    }
+
+    fn inval_entry(&self, parent: u64, name: &CStr) {
+        self.inval_entry_flags(parent, name, 0);
+    }
+
+    fn inval_entry_flags(&self, parent: u64, name: &CStr, flags: u32) {
+        self.send_inval(NotifyMessage::inval_entry(parent, name, flags, self.minor));
+    }
+
+    fn delete(&self, parent: u64, child: u64, name: &CStr) {
+        self.send_inval(NotifyMessage::delete(parent, child, name));
+    }
+
+    fn store(&self, inode: u64, offset: u64, data: &[FileVolatileSlice]) -> io::Result<usize> {
+        let fd = self.file.as_raw_fd();
+        self.store_with(inode, offset, data, |b| writev(fd, b))
+    }
+
+    fn retrieve(&self, notify_unique: u64, inode: u64, offset: u64, size: u32) -> io::Result<()> {
+        let fd = self.file.as_raw_fd();
+        let msg = NotifyMessage::retrieve(notify_unique, inode, offset, size);
+        self.send(&msg, |b| writev(fd, b)).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abi::fuse_abi::{
+        NotifyDeleteOut, NotifyInvalEntryOut, NotifyInvalInodeOut, NotifyOpcode, NotifyStoreOut,
+        Notify_Retrieve_Out, OutHeader,
+    };
+    use nix::errno::Errno;
+    use std::io::{Read, Seek, SeekFrom};
+    use std::mem::size_of;
+    use vm_memory::ByteValued;
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn slices(bufs: &mut [Vec<u8>]) -> Vec<FileVolatileSlice<'_>> {
+        bufs.iter_mut()
+            // Safe because each slice covers the memory of a buffer, which is borrowed for the
+            // lifetime of the slices.
+            .map(|b| unsafe { FileVolatileSlice::new(b.as_mut_ptr(), b.len()) })
+            .collect()
+    }
+
+    // Messages may be unaligned in the buffer, so copy them out.
+    fn read_obj<T: ByteValued + Default>(buf: &[u8]) -> T {
+        let mut obj = T::default();
+        obj.as_mut_slice().copy_from_slice(&buf[..size_of::<T>()]);
+        obj
+    }
+
+    // Decode a notify store message into (nodeid, offset, data).
+    fn decode_store(msg: &[u8]) -> (u64, u64, Vec<u8>) {
+        let header: OutHeader = read_obj(msg);
+        assert_eq!(header.len as usize, msg.len());
+        assert_eq!(header.error, NotifyOpcode::Store as i32);
+        assert_eq!(header.unique, 0);
+        let body = &msg[size_of::<OutHeader>()..];
+        let out: NotifyStoreOut = read_obj(body);
+        let data = body[size_of::<NotifyStoreOut>()..].to_vec();
+        assert_eq!(out.size as usize, data.len());
+        (out.nodeid, out.offset, data)
+    }
+
+    #[test]
+    fn test_notify_store_chunks() {
+        let file = TempFile::new().unwrap().into_file();
+        let notifier = FuseDevNotifier::new(file, 4);
+        let mut bufs = vec![b"abc".to_vec(), Vec::new(), b"defghij".to_vec()];
+        let data = slices(&mut bufs);
+
+        let mut msgs = Vec::new();
+        let stored = notifier
+            .store_with(5, 100, &data, |b| {
+                msgs.push(b.iter().flat_map(|s| s.to_vec()).collect::<Vec<u8>>());
+                Ok(b.iter().map(|s| s.len()).sum())
+            })
+            .unwrap();
+        assert_eq!(stored, 10);
+
+        let msgs: Vec<_> = msgs.iter().map(|m| decode_store(m)).collect();
+        assert_eq!(
+            msgs,
+            vec![
+                (5, 100, b"abcd".to_vec()),
+                (5, 104, b"efgh".to_vec()),
+                (5, 108, b"ij".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_notify_store_errors() {
+        let file = TempFile::new().unwrap().into_file();
+        let notifier = FuseDevNotifier::new(file, 4);
+        let mut bufs = vec![b"abcdefghij".to_vec()];
+        let data = slices(&mut bufs);
+
+        // Interrupted writes are retried, and the guest not caching the inode is tolerated.
+        let mut results = vec![Ok(44), Err(Errno::EINTR), Err(Errno::ENOENT)].into_iter();
+        let stored = notifier
+            .store_with(5, 0, &data, |_| results.next().unwrap())
+            .unwrap();
+        assert_eq!(stored, 4);
+
+        let mut results = vec![Err(Errno::EINVAL)].into_iter();
+        let e = notifier
+            .store_with(5, 0, &data, |_| results.next().unwrap())
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn test_notify_fuse_dev() {
+        let mut file = TempFile::new().unwrap().into_file();
+        let notifier = FuseDevNotifier::new(file.try_clone().unwrap(), 1 << 20);
+        let mut bufs = vec![b"hello".to_vec()];
+        let data = slices(&mut bufs);
+
+        assert_eq!(notifier.store(3, 8, &data).unwrap(), 5);
+        notifier.inval_inode(3);
+
+        let mut msgs = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut msgs).unwrap();
+        let store_len = size_of::<OutHeader>() + size_of::<NotifyStoreOut>() + 5;
+        assert_eq!(decode_store(&msgs[..store_len]), (3, 8, b"hello".to_vec()));
+
+        let inval = &msgs[store_len..];
+        let header: OutHeader = read_obj(inval);
+        assert_eq!(header.len as usize, inval.len());
+        assert_eq!(header.error, NotifyOpcode::InvalInode as i32);
+        let out: NotifyInvalInodeOut = read_obj(&inval[size_of::<OutHeader>()..]);
+        assert_eq!((out.ino, out.off, out.len), (3, 0, 0));
+    }
+
+    #[test]
+    fn test_notify_inval_inode_range() {
+        let mut file = TempFile::new().unwrap().into_file();
+        let notifier = FuseDevNotifier::new(file.try_clone().unwrap(), 1 << 20);
+        notifier.inval_inode_range(3, 4096, 8192);
+
+        let mut msg = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut msg).unwrap();
+        assert_eq!(
+            msg.len(),
+            size_of::<OutHeader>() + size_of::<NotifyInvalInodeOut>()
+        );
+        let header: OutHeader = read_obj(&msg);
+        assert_eq!(header.len as usize, msg.len());
+        assert_eq!(header.error, NotifyOpcode::InvalInode as i32);
+        assert_eq!(header.unique, 0);
+        let out: NotifyInvalInodeOut = read_obj(&msg[size_of::<OutHeader>()..]);
+        assert_eq!((out.ino, out.off, out.len), (3, 4096, 8192));
+    }
+
+    #[test]
+    fn test_notify_inval_entry_flags() {
+        use crate::abi::fuse_abi::FUSE_EXPIRE_ONLY;
+
+        let name = std::ffi::CString::new("abc").unwrap();
+        let flags = |notifier: FuseDevNotifier, mut file: File| {
+            notifier.inval_entry_flags(1, &name, FUSE_EXPIRE_ONLY);
+            let mut msg = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut msg).unwrap();
+            assert_eq!(
+                msg.len(),
+                size_of::<OutHeader>() + size_of::<NotifyInvalEntryOut>() + 4
+            );
+            let header: OutHeader = read_obj(&msg);
+            assert_eq!(header.error, NotifyOpcode::InvalEntry as i32);
+            let out: NotifyInvalEntryOut = read_obj(&msg[size_of::<OutHeader>()..]);
+            assert_eq!((out.parent, out.namelen), (1, 3));
+            out.flags
+        };
+
+        let file = TempFile::new().unwrap().into_file();
+        let notifier = FuseDevNotifier::new(file.try_clone().unwrap(), 1 << 20).with_minor(38);
+        assert_eq!(flags(notifier, file), FUSE_EXPIRE_ONLY);
+        // Flags are dropped unless the kernel is known to speak 7.38.
+        let file = TempFile::new().unwrap().into_file();
+        let notifier = FuseDevNotifier::new(file.try_clone().unwrap(), 1 << 20).with_minor(37);
+        assert_eq!(flags(notifier, file), 0);
+        let file = TempFile::new().unwrap().into_file();
+        let notifier = FuseDevNotifier::new(file.try_clone().unwrap(), 1 << 20);
+        assert_eq!(flags(notifier, file), 0);
+    }
+
+    #[test]
+    fn test_notify_delete() {
+        let mut file = TempFile::new().unwrap().into_file();
+        let notifier = FuseDevNotifier::new(file.try_clone().unwrap(), 1 << 20);
+        notifier.delete(1, 5, &std::ffi::CString::new("abc").unwrap());
+
+        let mut msg = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut msg).unwrap();
+        let header: OutHeader = read_obj(&msg);
+        assert_eq!(header.len as usize, msg.len());
+        assert_eq!(
+            msg.len(),
+            size_of::<OutHeader>() + size_of::<NotifyDeleteOut>() + 4
+        );
+        assert_eq!(header.error, NotifyOpcode::Delete as i32);
+        assert_eq!(header.unique, 0);
+        let body = &msg[size_of::<OutHeader>()..];
+        let out: NotifyDeleteOut = read_obj(body);
+        assert_eq!((out.parent, out.child, out.namelen), (1, 5, 3));
+        assert_eq!(&body[size_of::<NotifyDeleteOut>()..], b"abc\0");
+    }
+
+    #[test]
+    fn test_notify_retrieve() {
+        let mut file = TempFile::new().unwrap().into_file();
+        let notifier = FuseDevNotifier::new(file.try_clone().unwrap(), 1 << 20);
+        notifier.retrieve(7, 3, 4096, 8192).unwrap();
+
+        let mut msg = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut msg).unwrap();
+        assert_eq!(
+            msg.len(),
+            size_of::<OutHeader>() + size_of::<Notify_Retrieve_Out>()
+        );
+        let header: OutHeader = read_obj(&msg);
+        assert_eq!(header.len as usize, msg.len());
+        assert_eq!(header.error, NotifyOpcode::Retrieve as i32);
+        assert_eq!(header.unique, 0);
+        let out: Notify_Retrieve_Out = read_obj(&msg[size_of::<OutHeader>()..]);
+        assert_eq!(
+            (out.notify_unique, out.nodeid, out.offset, out.size),
+            (7, 3, 4096, 8192)
+        );
+    }
+}
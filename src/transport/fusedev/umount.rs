@@ -0,0 +1,258 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Umount of fuse sessions in bounded time.
+//!
+//! A plain `umount2()` fails with `EBUSY` while files of the file system are open, and may block
+//! while requests are stuck in the connection, for example when the daemon serving it is dead.
+//! [FuseSession::umount_with_policy] escalates through the steps allowed by an [UmountPolicy]:
+//! a plain umount, then a lazy one with `MNT_DETACH` if the mount is busy, then aborting the
+//! connection through fusectl if the umount doesn't complete in time, which fails the requests
+//! blocking it.
+//!
+//! [FuseSession::umount_with_policy]: super::FuseSession::umount_with_policy
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use nix::mount::{umount2, MntFlags};
+
+use super::{Error::SessionFailure, Result};
+
+const FUSE_CONNECTIONS: &str = "/sys/fs/fuse/connections";
+
+/// Steps allowed to umount a busy or hung fuse session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UmountPolicy {
+    /// Detach the mount with `MNT_DETACH` if a plain umount fails with `EBUSY`.
+    ///
+    /// The default value for this option is `true`.
+    pub lazy: bool,
+    /// Abort the connection if the umount doesn't complete within `timeout`.
+    ///
+    /// The default value for this option is `true`.
+    pub abort: bool,
+    /// Time given to the umount before aborting the connection, and again after aborting it.
+    ///
+    /// The default value for this option is 5 seconds.
+    pub timeout: Duration,
+}
+
+impl Default for UmountPolicy {
+    fn default() -> Self {
+        UmountPolicy {
+            lazy: true,
+            abort: true,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A step taken to umount a fuse session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UmountStep {
+    /// Plain `umount2()`.
+    Umount,
+    /// Lazy `umount2()` with `MNT_DETACH`.
+    Detach,
+    /// Abort of the connection through fusectl.
+    Abort,
+}
+
+/// Steps taken to umount a fuse session, in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UmountReport {
+    /// Steps taken, empty if the session wasn't mounted any more.
+    pub steps: Vec<UmountStep>,
+    /// Time taken by the umount.
+    pub elapsed: Duration,
+}
+
+// Syscalls used to umount sessions, abstracted for testing.
+pub(super) trait MountSyscalls: Send + Sync {
+    fn umount(&self, mountpoint: &Path, flags: MntFlags) -> nix::Result<()>;
+
+    // Abort the fuse connection `conn`.
+    fn abort(&self, conn: u64) -> io::Result<()>;
+}
+
+pub(super) struct LibcMountSyscalls;
+
+impl MountSyscalls for LibcMountSyscalls {
+    fn umount(&self, mountpoint: &Path, flags: MntFlags) -> nix::Result<()> {
+        umount2(mountpoint, flags)
+    }
+
+    fn abort(&self, conn: u64) -> io::Result<()> {
+        std::fs::write(format!("{}/{}/abort", FUSE_CONNECTIONS, conn), "1")
+    }
+}
+
+enum Progress {
+    Step(UmountStep),
+    Done(nix::Result<()>),
+}
+
+// Umount `mountpoint` escalating through the steps of `policy`. The connection `conn` is
+// aborted if the umount is stuck, its id must be taken while mounted.
+pub(super) fn umount_escalate(
+    sys: Arc<dyn MountSyscalls>,
+    mountpoint: &Path,
+    conn: Option<u64>,
+    policy: UmountPolicy,
+) -> Result<UmountReport> {
+    let start = Instant::now();
+    let mut report = UmountReport::default();
+    let fail = |report: &UmountReport, msg: String| {
+        SessionFailure(format!(
+            "failed to umount {:?} after {:?}: {}",
+            mountpoint, report.steps, msg
+        ))
+    };
+
+    // Umount from another thread, as it may block on the connection until it's aborted. The
+    // thread is left behind if the umount is still stuck after aborting.
+    let (tx, rx) = mpsc::channel();
+    let path = PathBuf::from(mountpoint);
+    let lazy = policy.lazy;
+    let worker = sys.clone();
+    thread::Builder::new()
+        .name("fuse_umount".to_string())
+        .spawn(move || {
+            let _ = tx.send(Progress::Step(UmountStep::Umount));
+            let mut res = worker.umount(&path, MntFlags::empty());
+            if res == Err(Errno::EBUSY) && lazy {
+                let _ = tx.send(Progress::Step(UmountStep::Detach));
+                res = worker.umount(&path, MntFlags::MNT_DETACH);
+            }
+            let _ = tx.send(Progress::Done(res));
+        })
+        .map_err(|e| fail(&report, format!("spawn thread: {}", e)))?;
+
+    let mut deadline = start + policy.timeout;
+    loop {
+        let wait = deadline.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(wait) {
+            Ok(Progress::Step(step)) => report.steps.push(step),
+            Ok(Progress::Done(res)) => {
+                report.elapsed = start.elapsed();
+                return match res {
+                    Ok(()) => Ok(report),
+                    Err(e) => Err(fail(&report, e.to_string())),
+                };
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if !policy.abort || report.steps.contains(&UmountStep::Abort) {
+                    return Err(fail(&report, "timed out".to_string()));
+                }
+                report.steps.push(UmountStep::Abort);
+                let conn = conn.ok_or_else(|| fail(&report, "no fuse connection".to_string()))?;
+                sys.abort(conn)
+                    .map_err(|e| fail(&report, format!("abort connection {}: {}", conn, e)))?;
+                deadline = Instant::now() + policy.timeout;
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(fail(&report, "umount thread exited".to_string()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Condvar, Mutex};
+
+    // Mount which is busy for plain umounts, and whose lazy umounts block until it's aborted if
+    // `hung`.
+    #[derive(Default)]
+    struct MockMount {
+        hung: bool,
+        aborted: Mutex<bool>,
+        cond: Condvar,
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl MountSyscalls for MockMount {
+        fn umount(&self, _: &Path, flags: MntFlags) -> nix::Result<()> {
+            if !flags.contains(MntFlags::MNT_DETACH) {
+                self.calls.lock().unwrap().push("umount".to_string());
+                return Err(Errno::EBUSY);
+            }
+            self.calls.lock().unwrap().push("detach".to_string());
+            let mut aborted = self.aborted.lock().unwrap();
+            while self.hung && !*aborted {
+                aborted = self.cond.wait(aborted).unwrap();
+            }
+            Ok(())
+        }
+
+        fn abort(&self, conn: u64) -> io::Result<()> {
+            self.calls.lock().unwrap().push(format!("abort {}", conn));
+            *self.aborted.lock().unwrap() = true;
+            self.cond.notify_all();
+            Ok(())
+        }
+    }
+
+    fn policy(lazy: bool, abort: bool) -> UmountPolicy {
+        UmountPolicy {
+            lazy,
+            abort,
+            timeout: Duration::from_millis(100),
+        }
+    }
+
+    fn umount(mount: &Arc<MockMount>, policy: UmountPolicy) -> Result<UmountReport> {
+        umount_escalate(mount.clone(), Path::new("/mnt"), Some(7), policy)
+    }
+
+    #[test]
+    fn test_umount_busy() {
+        let mount = Arc::new(MockMount::default());
+        let report = umount(&mount, policy(true, true)).unwrap();
+        assert_eq!(report.steps, [UmountStep::Umount, UmountStep::Detach]);
+        assert!(report.elapsed < Duration::from_millis(100));
+        assert_eq!(*mount.calls.lock().unwrap(), ["umount", "detach"]);
+
+        let mount = Arc::new(MockMount::default());
+        let err = umount(&mount, policy(false, true)).unwrap_err();
+        assert!(err.to_string().contains("[Umount]"), "{}", err);
+        assert_eq!(*mount.calls.lock().unwrap(), ["umount"]);
+    }
+
+    #[test]
+    fn test_umount_abort_hung() {
+        let mount = Arc::new(MockMount {
+            hung: true,
+            ..Default::default()
+        });
+        let report = umount(&mount, policy(true, true)).unwrap();
+        assert_eq!(
+            report.steps,
+            [UmountStep::Umount, UmountStep::Detach, UmountStep::Abort]
+        );
+        assert!(report.elapsed >= Duration::from_millis(100));
+        assert!(report.elapsed < Duration::from_secs(5));
+        assert_eq!(
+            *mount.calls.lock().unwrap(),
+            ["umount", "detach", "abort 7"]
+        );
+
+        // Without aborting, the umount times out and is left behind.
+        let mount = Arc::new(MockMount {
+            hung: true,
+            ..Default::default()
+        });
+        let start = Instant::now();
+        let err = umount(&mount, policy(true, false)).unwrap_err();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(err.to_string().contains("timed out"), "{}", err);
+        mount.abort(7).unwrap();
+    }
+}
@@ -18,6 +18,7 @@ use std::collections::VecDeque;
 use std::io::{self, IoSlice, Read};
 use std::marker::PhantomData;
 use std::mem::{size_of, MaybeUninit};
+use std::os::unix::io::RawFd;
 use std::ptr::copy_nonoverlapping;
 use std::{cmp, fmt};
 
@@ -42,10 +43,29 @@ pub use self::file_traits::{FileReadWriteVolatile, FileSetLen};
 pub use self::file_volatile_slice::FileVolatileBuf;
 pub use self::file_volatile_slice::FileVolatileSlice;
 pub use self::fs_cache_req_handler::FsCacheReqHandler;
+#[cfg(feature = "virtiofs")]
+pub use self::fs_cache_req_handler::{
+    FsSlaveChannel, FsSlaveMsg, SlaveFsCacheMapper, FS_SLAVE_ENTRIES, FS_SLAVE_MAP_R,
+    FS_SLAVE_MAP_W,
+};
 #[cfg(feature = "fusedev")]
-pub use self::fusedev::{FuseBuf, FuseChannel, FuseDevWriter, FuseSession};
+pub use self::fusedev::{
+    is_partial_write, FuseBuf, FuseChannel, FuseDevNotifier, FuseDevWriter, FuseSession,
+};
+#[cfg(all(feature = "fusedev", feature = "async-io", target_os = "linux"))]
+pub use self::fusedev::{AsyncFuseChannel, AsyncFuseSession};
+#[cfg(all(feature = "fusedev", target_os = "linux"))]
+pub use self::fusedev::{UmountPolicy, UmountReport, UmountStep};
+#[cfg(all(feature = "async-io", feature = "virtiofs"))]
+pub use self::virtiofs::{process_queue, VirtioQueue};
 #[cfg(feature = "virtiofs")]
-pub use self::virtiofs::VirtioFsWriter;
+pub use self::virtiofs::{
+    split_descriptor_chain, VirtioFsNotifier, VirtioFsWriter, VIRTIO_FS_F_NOTIFICATION,
+};
+#[cfg(feature = "vhost-user-backend")]
+pub use self::virtiofs::{
+    VhostUserFsBackend, VIRTIO_F_VERSION_1, VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC,
+};
 
 /// Transport layer specific error codes.
 #[derive(Debug)]
@@ -67,12 +87,28 @@ pub enum Error {
     #[cfg(feature = "fusedev")]
     /// Session errors
     SessionFailure(String),
+    #[cfg(feature = "fusedev")]
+    /// Partial write of a message to the fuse device, as (expected, written) bytes.
+    PartialWrite(usize, usize),
+    #[cfg(feature = "fusedev")]
+    /// Reply larger than the max reply size of the fuse device, as (reply, max) bytes.
+    ReplyTooLarge(usize, usize),
     #[cfg(feature = "virtiofs")]
     /// Failed to access guest memory.
     GuestMemoryError(vm_memory::GuestMemoryError),
     #[cfg(feature = "virtiofs")]
     /// Invalid Indirect Virtio descriptors.
     ConvertIndirectDescriptor(virtio_queue::Error),
+    #[cfg(feature = "virtiofs")]
+    /// Device-readable descriptor following a device-writable one.
+    UnorderedChain,
+    #[cfg(feature = "virtiofs")]
+    /// Descriptor chain ending on a descriptor with a next one, linking out of its table, looping
+    /// or referring to an invalid indirect table.
+    TruncatedChain,
+    #[cfg(feature = "virtiofs")]
+    /// Device-readable descriptors of a chain exceeding the max request size, in bytes.
+    ChainTooLarge(usize),
 }
 
 impl fmt::Display for Error {
@@ -93,11 +129,29 @@ impl fmt::Display for Error {
 
             #[cfg(feature = "fusedev")]
             SessionFailure(e) => write!(f, "fuse session failure: {}", e),
+            #[cfg(feature = "fusedev")]
+            PartialWrite(expected, written) => write!(
+                f,
+                "partial write to fuse device, {} of {} bytes written",
+                written, expected
+            ),
+            #[cfg(feature = "fusedev")]
+            ReplyTooLarge(len, max) => write!(
+                f,
+                "reply of {} bytes larger than the max reply size {} of fuse device",
+                len, max
+            ),
 
             #[cfg(feature = "virtiofs")]
             ConvertIndirectDescriptor(e) => write!(f, "invalid indirect descriptor: {}", e),
             #[cfg(feature = "virtiofs")]
             GuestMemoryError(e) => write!(f, "descriptor guest memory error: {}", e),
+            #[cfg(feature = "virtiofs")]
+            UnorderedChain => write!(f, "readable descriptor after writable descriptors"),
+            #[cfg(feature = "virtiofs")]
+            TruncatedChain => write!(f, "truncated descriptor chain"),
+            #[cfg(feature = "virtiofs")]
+            ChainTooLarge(len) => write!(f, "descriptor chain too large: {} bytes", len),
         }
     }
 }
@@ -290,17 +344,27 @@ impl<S: BitmapSlice> IoBuffers<'_, S> {
     where
         F: FnOnce(&[FileVolatileSlice]) -> io::Result<usize>,
     {
-        let bufs = self.allocate_file_volatile_slice(count);
-        if bufs.is_empty() {
-            Ok(0)
-        } else {
-            let bytes_consumed = f(&*bufs)?;
-            if mark_dirty {
-                self.mark_dirty(bytes_consumed);
+        let bytes_consumed = match self.buffers.front() {
+            // Don't allocate for the common case of small objects within the first buffer.
+            Some(buf) if count > 0 && buf.len() >= count => {
+                // Safe because we just check count <= buf.len()
+                let slice =
+                    FileVolatileSlice::new_from_volatile_slice(&buf.subslice(0, count).unwrap());
+                f(&[slice])?
+            }
+            _ => {
+                let bufs = self.allocate_file_volatile_slice(count);
+                if bufs.is_empty() {
+                    return Ok(0);
+                }
+                f(&*bufs)?
             }
-            self.mark_used(bytes_consumed)?;
-            Ok(bytes_consumed)
+        };
+        if mark_dirty {
+            self.mark_dirty(bytes_consumed);
         }
+        self.mark_used(bytes_consumed)?;
+        Ok(bytes_consumed)
     }
 
     fn consume_for_read<F>(&mut self, count: usize, f: F) -> io::Result<usize>
@@ -310,6 +374,70 @@ impl<S: BitmapSlice> IoBuffers<'_, S> {
         self.consume(false, count, f)
     }
 
+    /// Accesses `count` bytes at `offset` from the current position without consuming them.
+    /// Callers must provide a function that takes a `&[FileVolatileSlice]` and returns the total
+    /// number of bytes accessed. `mark_dirty` is used for tracing dirty pages.
+    ///
+    /// # Errors
+    ///
+    /// Return an error if `offset + count` is beyond the available bytes.
+    fn peek<F>(&self, mark_dirty: bool, offset: usize, count: usize, f: F) -> io::Result<usize>
+    where
+        F: FnOnce(&[FileVolatileSlice]) -> io::Result<usize>,
+    {
+        let available = self.available_bytes();
+        if !matches!(offset.checked_add(count), Some(end) if end <= available) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "data out of range, available {} requested {} at offset {}",
+                    available, count, offset
+                ),
+            ));
+        }
+
+        let mut skip = offset;
+        let mut rem = count;
+        let mut slices = Vec::with_capacity(self.buffers.len());
+        for buf in &self.buffers {
+            if rem == 0 {
+                break;
+            }
+            if skip >= buf.len() {
+                skip -= buf.len();
+                continue;
+            }
+
+            let len = cmp::min(buf.len() - skip, rem);
+            // Safe because `skip + len <= buf.len()`.
+            slices.push(buf.subslice(skip, len).unwrap());
+            skip = 0;
+            rem -= len;
+        }
+        if slices.is_empty() {
+            return Ok(0);
+        }
+
+        let bufs: Vec<FileVolatileSlice> = slices
+            .iter()
+            .map(FileVolatileSlice::new_from_volatile_slice)
+            .collect();
+        let bytes_accessed = f(&bufs)?;
+        if mark_dirty {
+            let mut rem = bytes_accessed;
+            for slice in &slices {
+                if rem == 0 {
+                    break;
+                }
+                let len = cmp::min(slice.len(), rem);
+                slice.bitmap().mark_dirty(0, len);
+                rem -= len;
+            }
+        }
+
+        Ok(bytes_accessed)
+    }
+
     fn split_at(&mut self, offset: usize) -> Result<Self> {
         let mut rem = offset;
         let pos = self.buffers.iter().position(|buf| {
@@ -357,16 +485,25 @@ impl<S: BitmapSlice> IoBuffers<'_, S> {
 #[derive(Clone)]
 pub struct Reader<'a, S = ()> {
     buffers: IoBuffers<'a, S>,
+    pipe: Option<PipePayload>,
 }
 
 impl<S: BitmapSlice> Default for Reader<'_, S> {
     fn default() -> Self {
         Reader {
             buffers: IoBuffers::default(),
+            pipe: None,
         }
     }
 }
 
+// Data of a request left in a pipe by the transport, read after the buffers.
+#[derive(Clone, Copy, Debug)]
+struct PipePayload {
+    fd: RawFd,
+    len: usize,
+}
+
 impl<S: BitmapSlice> Reader<'_, S> {
     /// Reads an object from the descriptor chain buffer.
     pub fn read_obj<T: ByteValued>(&mut self) -> io::Result<T> {
@@ -394,6 +531,9 @@ impl<S: BitmapSlice> Reader<'_, S> {
         mut dst: F,
         count: usize,
     ) -> io::Result<usize> {
+        if self.pipe_next() {
+            return self.read_pipe_to(count, |slice, _| dst.write_volatile(slice));
+        }
         self.buffers
             .consume_for_read(count, |bufs| dst.write_vectored_volatile(bufs))
     }
@@ -408,10 +548,33 @@ impl<S: BitmapSlice> Reader<'_, S> {
         count: usize,
         off: u64,
     ) -> io::Result<usize> {
+        if self.pipe_next() {
+            return self.read_pipe_to(count, |slice, done| {
+                dst.write_at_volatile(slice, off + done as u64)
+            });
+        }
         self.buffers
             .consume_for_read(count, |bufs| dst.write_vectored_at_volatile(bufs, off))
     }
 
+    /// Reads data at offset `buf_offset` from the current position of the descriptor chain buffer
+    /// into a File at offset `off`, without consuming the data.
+    ///
+    /// Segments of the buffer may be read out of order or in parallel. Returns the number of bytes
+    /// read from the descriptor chain buffer, or an error if `buf_offset + count` is beyond the
+    /// available bytes. Data left in a pipe by the transport can't be read this way.
+    pub fn read_to_at_offset<F: FileReadWriteVolatile>(
+        &self,
+        mut dst: F,
+        count: usize,
+        off: u64,
+        buf_offset: usize,
+    ) -> io::Result<usize> {
+        self.buffers.peek(false, buf_offset, count, |bufs| {
+            dst.write_vectored_at_volatile(bufs, off)
+        })
+    }
+
     /// Reads exactly size of data from the descriptor chain buffer into a file descriptor.
     pub fn read_exact_to<F: FileReadWriteVolatile>(
         &mut self,
@@ -440,7 +603,7 @@ impl<S: BitmapSlice> Reader<'_, S> {
     /// May return an error if the combined lengths of all the buffers in the DescriptorChain
     /// would cause an integer overflow.
     pub fn available_bytes(&self) -> usize {
-        self.buffers.available_bytes()
+        self.buffers.available_bytes() + self.pipe.map_or(0, |p| p.len)
     }
 
     /// Returns number of bytes already read from the descriptor chain buffer.
@@ -452,15 +615,119 @@ impl<S: BitmapSlice> Reader<'_, S> {
     /// After the split, `self` will be able to read up to `offset` bytes while the returned
     /// `Reader` can read up to `available_bytes() - offset` bytes.  Returns an error if
     /// `offset > self.available_bytes()`.
+    ///
+    /// Data left in a pipe by the transport goes to the returned `Reader`, so `offset` must not be
+    /// beyond the data in the buffer.
     pub fn split_at(&mut self, offset: usize) -> Result<Self> {
-        self.buffers
-            .split_at(offset)
-            .map(|buffers| Reader { buffers })
+        self.buffers.split_at(offset).map(|buffers| Reader {
+            buffers,
+            pipe: self.pipe.take(),
+        })
+    }
+
+    /// Moves at most `count` bytes of data left in a pipe by the transport into the file `fd` at
+    /// offset `off` with `splice(2)`, without copying them through the buffer.
+    ///
+    /// Returns `Ok(None)` if the data to read next isn't in a pipe. Files not supporting splice
+    /// fail with `EINVAL` and the data stays in the pipe, so it may still be read otherwise.
+    #[cfg(target_os = "linux")]
+    pub fn splice_to_at(&mut self, fd: RawFd, count: usize, off: u64) -> io::Result<Option<usize>> {
+        if !self.pipe_next() {
+            return Ok(None);
+        }
+        let pipe = self.pipe.as_mut().unwrap();
+        let len = cmp::min(count, pipe.len);
+        let mut off = off as libc::loff_t;
+        let mut done = 0;
+        while done < len {
+            // Safe because the kernel only updates `off` and we check the return value.
+            let res = unsafe {
+                libc::splice(
+                    pipe.fd,
+                    std::ptr::null_mut(),
+                    fd,
+                    &mut off,
+                    len - done,
+                    libc::SPLICE_F_MOVE,
+                )
+            };
+            if res < 0 {
+                let e = io::Error::last_os_error();
+                match e.kind() {
+                    io::ErrorKind::Interrupted => continue,
+                    // Report the data moved already, the caller will retry the rest.
+                    _ if done > 0 => break,
+                    _ => return Err(e),
+                }
+            }
+            if res == 0 {
+                break;
+            }
+            done += res as usize;
+            pipe.len -= res as usize;
+        }
+
+        Ok(Some(done))
+    }
+
+    // Check whether data is read from the pipe next.
+    fn pipe_next(&self) -> bool {
+        self.buffers.available_bytes() == 0 && self.pipe.is_some_and(|p| p.len > 0)
+    }
+
+    // Read at most `buf.len()` bytes from the pipe.
+    fn read_pipe(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let pipe = match self.pipe.as_mut() {
+            Some(pipe) => pipe,
+            None => return Ok(0),
+        };
+        let len = cmp::min(buf.len(), pipe.len);
+        loop {
+            // Safe because the kernel only writes `len` bytes to `buf` and we check the return
+            // value.
+            let res = unsafe { libc::read(pipe.fd, buf.as_mut_ptr() as *mut libc::c_void, len) };
+            if res < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            pipe.len = if res == 0 { 0 } else { pipe.len - res as usize };
+            return Ok(res as usize);
+        }
+    }
+
+    // Copy at most `count` bytes from the pipe into memory and write them all by `write`, which
+    // gets the number of bytes written so far. The data has left the pipe once read, so it's lost
+    // if writing it fails.
+    fn read_pipe_to<F>(&mut self, count: usize, mut write: F) -> io::Result<usize>
+    where
+        F: FnMut(FileVolatileSlice, usize) -> io::Result<usize>,
+    {
+        let mut buf = vec![0u8; cmp::min(count, self.available_bytes())];
+        let len = self.read_pipe(&mut buf)?;
+        let mut done = 0;
+        while done < len {
+            // Safe because `buf` outlives the slice.
+            let slice = unsafe { FileVolatileSlice::new(buf[done..].as_mut_ptr(), len - done) };
+            match write(slice, done) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(n) => done += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(len)
     }
 }
 
 impl<S: BitmapSlice> io::Read for Reader<'_, S> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pipe_next() {
+            return self.read_pipe(buf);
+        }
         self.buffers.consume_for_read(buf.len(), |bufs| {
             let mut rem = buf;
             let mut total = 0;
@@ -543,6 +810,41 @@ impl<'a, S: BitmapSlice> Writer<'a, S> {
         }
     }
 
+    /// Write data at offset `buf_offset` from the current position of the descriptor chain buffer
+    /// from a File at offset `off`, without advancing the current position.
+    ///
+    /// Segments of the buffer may be written out of order, and [Writer::advance] should be called
+    /// to account the written data once the segments have been assembled. Return the number of
+    /// bytes written to the descriptor chain buffer, or an error if `buf_offset + count` is beyond
+    /// the available bytes.
+    pub fn write_from_at_offset<F: FileReadWriteVolatile>(
+        &mut self,
+        src: F,
+        count: usize,
+        off: u64,
+        buf_offset: usize,
+    ) -> io::Result<usize> {
+        match self {
+            #[cfg(feature = "fusedev")]
+            Writer::FuseDev(w) => w.write_from_at_offset(src, count, off, buf_offset),
+            #[cfg(feature = "virtiofs")]
+            Writer::VirtioFs(w) => w.write_from_at_offset(src, count, off, buf_offset),
+            _ => Err(std::io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+
+    /// Advance the current position by `count` bytes, accounting data written by
+    /// [Writer::write_from_at_offset].
+    pub fn advance(&mut self, count: usize) -> io::Result<()> {
+        match self {
+            #[cfg(feature = "fusedev")]
+            Writer::FuseDev(w) => w.advance(count),
+            #[cfg(feature = "virtiofs")]
+            Writer::VirtioFs(w) => w.advance(count),
+            _ => Err(std::io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+
     /// Split this `Writer` into two at the given offset in the `DescriptorChain` buffer.
     ///
     /// After the split, `self` will be able to write up to `offset` bytes while the returned
@@ -595,6 +897,75 @@ impl<'a, S: BitmapSlice> Writer<'a, S> {
     }
 }
 
+impl<'a, S: BitmapSlice> Writer<'a, S> {
+    /// Reserve the first `len` bytes of the writer for the header of a reply, to be written once
+    /// the length of the payload following it is known.
+    ///
+    /// Return the writer of the header and the writer of the payload. The payload must be written
+    /// in place from its start, by the payload writer without [FuseDevWriter::write_ref], or by
+    /// asynchronous IO on the buffers of [Writer::into_io_bufs], and is sent along with the header
+    /// by [HeaderWriter::commit]. Return an error if data was written to the writer already, or if
+    /// `len > self.available_bytes()`.
+    pub fn reserve_header(mut self, len: usize) -> Result<(HeaderWriter<'a, S>, Writer<'a, S>)> {
+        if self.bytes_written() != 0 {
+            return Err(Error::InvalidParameter);
+        }
+        let data = self.split_at(len)?;
+        let payload = match &data {
+            #[cfg(feature = "fusedev")]
+            // Safe because the payload writer only accounts data once the data writer is done.
+            Writer::FuseDev(w) => Writer::FuseDev(unsafe { w.alias() }),
+            #[cfg(feature = "virtiofs")]
+            Writer::VirtioFs(w) => Writer::VirtioFs(w.clone()),
+            _ => return Err(Error::InvalidParameter),
+        };
+
+        Ok((
+            HeaderWriter {
+                header: self,
+                payload,
+                len,
+            },
+            data,
+        ))
+    }
+}
+
+/// Writer of the header of a reply, reserved ahead of the payload by [Writer::reserve_header].
+pub struct HeaderWriter<'a, S: BitmapSlice = ()> {
+    header: Writer<'a, S>,
+    // Writer on the space of the payload, accounting the payload written there in place.
+    payload: Writer<'a, S>,
+    len: usize,
+}
+
+impl<'a, S: BitmapSlice> HeaderWriter<'a, S> {
+    /// Write `header`, as long as the space reserved, and commit the reply made of the header
+    /// followed by the first `count` bytes of the payload.
+    ///
+    /// Return the length of the reply, which is the used length of the descriptor chain for
+    /// virtiofs. Replies to `/dev/fuse` are sent by a single `writev`.
+    pub fn commit(mut self, header: &[u8], count: usize) -> io::Result<usize> {
+        if header.len() != self.len {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        match &mut self.payload {
+            #[cfg(feature = "fusedev")]
+            Writer::FuseDev(w) => w.advance(count)?,
+            #[cfg(feature = "virtiofs")]
+            Writer::VirtioFs(w) => w.advance_dirty(count)?,
+            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+        io::Write::write_all(&mut self.header, header)?;
+
+        match &mut self.header {
+            #[cfg(feature = "fusedev")]
+            Writer::FuseDev(w) => w.commit(Some(&self.payload)),
+            _ => Ok(self.len + count),
+        }
+    }
+}
+
 impl<'a, S: BitmapSlice> io::Write for Writer<'a, S> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         match self {
@@ -696,6 +1067,23 @@ impl<'a, S: BitmapSlice> Writer<'a, S> {
         }
     }
 
+    /// Convert the writer into buffers on at most `count` bytes of its available space, for
+    /// asynchronous IO to write the payload of a reply reserved by [Writer::reserve_header].
+    ///
+    /// # Safety
+    ///
+    /// The buffers refer to the memory of the request without borrowing it, so the caller must not
+    /// use them once the reply is committed.
+    pub unsafe fn into_io_bufs(self, count: usize) -> Vec<FileVolatileBuf> {
+        match self {
+            #[cfg(feature = "fusedev")]
+            Writer::FuseDev(w) => w.prepare_io_buf(count),
+            #[cfg(feature = "virtiofs")]
+            Writer::VirtioFs(w) => w.prepare_io_buf(count),
+            _ => Vec::new(),
+        }
+    }
+
     /// Commit all internal buffers of self and others
     pub async fn async_commit(&mut self, other: Option<&Writer<'a, S>>) -> io::Result<usize> {
         match self {
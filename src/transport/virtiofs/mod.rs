@@ -52,6 +52,19 @@ use vm_memory::{
 
 use super::{Error, FileReadWriteVolatile, FileVolatileSlice, IoBuffers, Reader, Result, Writer};
 
+mod notifier;
+pub use self::notifier::{VirtioFsNotifier, VIRTIO_FS_F_NOTIFICATION};
+#[cfg(feature = "async-io")]
+mod queue;
+#[cfg(feature = "async-io")]
+pub use self::queue::{process_queue, VirtioQueue};
+#[cfg(feature = "vhost-user-backend")]
+mod vhost_user;
+#[cfg(feature = "vhost-user-backend")]
+pub use self::vhost_user::{
+    VhostUserFsBackend, VIRTIO_F_VERSION_1, VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC,
+};
+
 impl<S: BitmapSlice> IoBuffers<'_, S> {
     /// Consumes for write.
     fn consume_for_write<F>(&mut self, count: usize, f: F) -> io::Result<usize>
@@ -62,6 +75,76 @@ impl<S: BitmapSlice> IoBuffers<'_, S> {
     }
 }
 
+// Buffers of the device-readable and of the device-writable descriptors of a chain.
+type ChainBuffers<'a, B> = (
+    VecDeque<VolatileSlice<'a, B>>,
+    VecDeque<VolatileSlice<'a, B>>,
+);
+
+// Walk all descriptors of `desc_chain`, including the ones of an indirect descriptor table, into
+// the buffers of its readable and of its writable descriptors.
+//
+// Readable descriptors must precede writable ones (2.6.4.2 in Virtio Spec v1.1), and readable
+// buffers must not exceed `max_readable` bytes. The iterator of the descriptor chain ends early,
+// without telling why, on a descriptor linking to one out of the table, a loop or an invalid
+// indirect table, so chains ending on a descriptor with a next one are truncated.
+fn chain_buffers<'a, M>(
+    mem: &'a M::Target,
+    desc_chain: DescriptorChain<M>,
+    max_readable: usize,
+) -> Result<ChainBuffers<'a, MS<'a, M::Target>>>
+where
+    M: Deref,
+    M::Target: GuestMemory + Sized,
+{
+    let (mut readable, mut writable) = (VecDeque::new(), VecDeque::new());
+    let (mut read_len, mut write_len) = (0usize, 0usize);
+    let (mut last, mut seen_writable) = (None, false);
+    for desc in desc_chain {
+        last = Some(desc);
+        let (buffers, total_len) = if desc.is_write_only() {
+            seen_writable = true;
+            (&mut writable, &mut write_len)
+        } else if seen_writable {
+            return Err(Error::UnorderedChain);
+        } else {
+            (&mut readable, &mut read_len)
+        };
+        // Verify that summing the descriptor sizes does not overflow.
+        // This can happen if a driver tricks a device into accessing more data than
+        // fits in a `usize`.
+        *total_len = total_len
+            .checked_add(desc.len() as usize)
+            .ok_or(Error::DescriptorChainOverflow)?;
+        if desc.len() == 0 {
+            continue;
+        }
+
+        let region = mem
+            .find_region(desc.addr())
+            .ok_or(Error::FindMemoryRegion)?;
+        let offset = desc
+            .addr()
+            .checked_sub(region.start_addr().raw_value())
+            .unwrap();
+        let slice = region
+            .get_slice(MemoryRegionAddress(offset.raw_value()), desc.len() as usize)
+            .map_err(Error::GuestMemoryError)?;
+        buffers.push_back(slice);
+    }
+
+    match last {
+        None => return Err(Error::InvalidChain),
+        Some(desc) if desc.has_next() => return Err(Error::TruncatedChain),
+        Some(_) => {}
+    }
+    if read_len > max_readable {
+        return Err(Error::ChainTooLarge(read_len));
+    }
+
+    Ok((readable, writable))
+}
+
 impl<'a> Reader<'a> {
     /// Construct a new Reader wrapper over `desc_chain`.
     pub fn from_descriptor_chain<M>(
@@ -72,48 +155,60 @@ impl<'a> Reader<'a> {
         M: Deref,
         M::Target: GuestMemory + Sized,
     {
-        let mut total_len: usize = 0;
-        let buffers = desc_chain
-            .readable()
-            .map(|desc| {
-                // Verify that summing the descriptor sizes does not overflow.
-                // This can happen if a driver tricks a device into reading more data than
-                // fits in a `usize`.
-                total_len = total_len
-                    .checked_add(desc.len() as usize)
-                    .ok_or(Error::DescriptorChainOverflow)?;
-
-                let region = mem
-                    .find_region(desc.addr())
-                    .ok_or(Error::FindMemoryRegion)?;
-                let offset = desc
-                    .addr()
-                    .checked_sub(region.start_addr().raw_value())
-                    .unwrap();
-                region
-                    .get_slice(MemoryRegionAddress(offset.raw_value()), desc.len() as usize)
-                    .map_err(Error::GuestMemoryError)
-            })
-            .collect::<Result<VecDeque<VolatileSlice<'a, MS<M::Target>>>>>()?;
-
-        Ok(Reader {
+        let (buffers, _) = chain_buffers(mem, desc_chain, usize::MAX)?;
+        Ok(Self::from_buffers(buffers))
+    }
+
+    fn from_buffers<B>(buffers: VecDeque<VolatileSlice<'a, B>>) -> Reader<'a, B> {
+        Reader {
             buffers: IoBuffers {
                 buffers,
                 bytes_consumed: 0,
             },
-        })
+            pipe: None,
+        }
     }
 }
 
+// Reader of the request and writer of the reply of a descriptor chain.
+type SplitChain<'a, B> = (Reader<'a, B>, VirtioFsWriter<'a, B>);
+
+/// Split `desc_chain` into a [Reader] of the request and a [VirtioFsWriter] of the reply, walking
+/// the chain once.
+///
+/// Chains whose readable descriptors exceed `max_request` bytes fail with
+/// [Error::ChainTooLarge], pass [Server::max_request_size] to reject requests larger than the
+/// negotiated `max_write`. Readable descriptors following writable ones fail with
+/// [Error::UnorderedChain], chains with broken links or invalid indirect tables with
+/// [Error::TruncatedChain].
+///
+/// [Server::max_request_size]: crate::api::server::Server::max_request_size
+pub fn split_descriptor_chain<'a, M>(
+    mem: &'a M::Target,
+    desc_chain: DescriptorChain<M>,
+    max_request: usize,
+) -> Result<SplitChain<'a, MS<'a, M::Target>>>
+where
+    M: Deref,
+    M::Target: GuestMemory + Sized,
+{
+    let head_index = desc_chain.head_index();
+    let (readable, writable) = chain_buffers(mem, desc_chain, max_request)?;
+    Ok((
+        Reader::from_buffers(readable),
+        VirtioFsWriter::from_buffers(head_index, writable),
+    ))
+}
+
 /// Provide high-level interface over the sequence of memory regions
 /// defined by writable descriptors in the Virtio descriptor chain.
 ///
 /// Note that virtio spec requires driver to place any device-writable
 /// descriptors after any device-readable descriptors (2.6.4.2 in Virtio Spec v1.1).
-/// Writer will start iterating the descriptors from the first writable one and will
-/// assume that all following descriptors are writable.
+/// Chains violating it are rejected with [Error::UnorderedChain].
 #[derive(Clone)]
 pub struct VirtioFsWriter<'a, S = ()> {
+    head_index: u16,
     buffers: IoBuffers<'a, S>,
 }
 
@@ -127,36 +222,22 @@ impl<'a> VirtioFsWriter<'a> {
         M: Deref,
         M::Target: GuestMemory + Sized,
     {
-        let mut total_len: usize = 0;
-        let buffers = desc_chain
-            .writable()
-            .map(|desc| {
-                // Verify that summing the descriptor sizes does not overflow.
-                // This can happen if a driver tricks a device into writing more data than
-                // fits in a `usize`.
-                total_len = total_len
-                    .checked_add(desc.len() as usize)
-                    .ok_or(Error::DescriptorChainOverflow)?;
-
-                let region = mem
-                    .find_region(desc.addr())
-                    .ok_or(Error::FindMemoryRegion)?;
-                let offset = desc
-                    .addr()
-                    .checked_sub(region.start_addr().raw_value())
-                    .unwrap();
-                region
-                    .get_slice(MemoryRegionAddress(offset.raw_value()), desc.len() as usize)
-                    .map_err(Error::GuestMemoryError)
-            })
-            .collect::<Result<VecDeque<VolatileSlice<'a, MS<M::Target>>>>>()?;
-
-        Ok(VirtioFsWriter {
+        let head_index = desc_chain.head_index();
+        let (_, buffers) = chain_buffers(mem, desc_chain, usize::MAX)?;
+        Ok(VirtioFsWriter::from_buffers(head_index, buffers))
+    }
+
+    fn from_buffers<B>(
+        head_index: u16,
+        buffers: VecDeque<VolatileSlice<'a, B>>,
+    ) -> VirtioFsWriter<'a, B> {
+        VirtioFsWriter {
+            head_index,
             buffers: IoBuffers {
                 buffers,
                 bytes_consumed: 0,
             },
-        })
+        }
     }
 }
 
@@ -193,6 +274,29 @@ impl<'a, S: BitmapSlice> VirtioFsWriter<'a, S> {
             .consume_for_write(count, |bufs| src.read_vectored_at_volatile(bufs, off))
     }
 
+    /// Write data at offset `buf_offset` from the current position of the descriptor chain buffer
+    /// from a File at offset `off`, without advancing the current position.
+    ///
+    /// Return the number of bytes written to the descriptor chain buffer.
+    pub fn write_from_at_offset<F: FileReadWriteVolatile>(
+        &mut self,
+        mut src: F,
+        count: usize,
+        off: u64,
+        buf_offset: usize,
+    ) -> io::Result<usize> {
+        self.buffers.peek(true, buf_offset, count, |bufs| {
+            src.read_vectored_at_volatile(bufs, off)
+        })
+    }
+
+    /// Advance the current position by `count` bytes, accounting data written by
+    /// [VirtioFsWriter::write_from_at_offset].
+    pub fn advance(&mut self, count: usize) -> io::Result<()> {
+        self.check_available_space(count, 0, 0)?;
+        self.buffers.mark_used(count)
+    }
+
     /// Write all data to the descriptor chain buffer from a file descriptor.
     pub fn write_all_from<F: FileReadWriteVolatile>(
         &mut self,
@@ -235,9 +339,31 @@ impl<'a, S: BitmapSlice> VirtioFsWriter<'a, S> {
     /// `Writer` can write up to `available_bytes() - offset` bytes.  Returns an error if
     /// `offset > self.available_bytes()`.
     pub fn split_at(&mut self, offset: usize) -> Result<Self> {
-        self.buffers
-            .split_at(offset)
-            .map(|buffers| VirtioFsWriter { buffers })
+        self.buffers.split_at(offset).map(|buffers| VirtioFsWriter {
+            head_index: self.head_index,
+            buffers,
+        })
+    }
+
+    // Account `count` bytes written in place to the buffers by other means, like asynchronous IO
+    // on the buffers of [Writer::into_io_bufs].
+    pub(crate) fn advance_dirty(&mut self, count: usize) -> io::Result<()> {
+        self.check_available_space(count, 0, 0)?;
+        self.buffers.mark_dirty(count);
+        self.buffers.mark_used(count)
+    }
+
+    // Get buffers on at most `count` bytes of the space left in the writer, for asynchronous IO to
+    // write to. The buffers must not outlive the guest memory.
+    #[cfg(feature = "async-io")]
+    pub(crate) unsafe fn prepare_io_buf(&self, count: usize) -> Vec<super::FileVolatileBuf> {
+        self.buffers.prepare_mut_io_buf(count)
+    }
+
+    /// Get the index of the head descriptor of the chain, to put the chain into the used ring
+    /// with the number of bytes written once the request completes.
+    pub fn head_index(&self) -> u16 {
+        self.head_index
     }
 
     /// Commit all internal buffers of self and others
@@ -382,6 +508,202 @@ mod async_io {
     }
 }
 
+#[cfg(test)]
+mod chain_tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use virtio_queue::defs::{VIRTQ_DESC_F_INDIRECT, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+    use virtio_queue::mock::{DescriptorTable, MockSplitQueue};
+    use virtio_queue::Descriptor;
+    use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+    const NEXT: u16 = VIRTQ_DESC_F_NEXT;
+    const WRITE: u16 = VIRTQ_DESC_F_WRITE;
+
+    fn memory() -> GuestMemoryMmap {
+        GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap()
+    }
+
+    // Make `descs` the descriptor table of a queue with a single available chain starting at the
+    // descriptor `head`, and pop the chain.
+    fn pop_chain<'a>(
+        mem: &'a GuestMemoryMmap,
+        head: u16,
+        descs: &[Descriptor],
+    ) -> DescriptorChain<&'a GuestMemoryMmap> {
+        let vq = MockSplitQueue::new(mem, 16);
+        for (idx, desc) in descs.iter().enumerate() {
+            vq.desc_table().store(idx as u16, *desc);
+        }
+        vq.avail().ring().ref_at(0).store(head);
+        vq.avail().idx().store(1);
+        let mut queue = vq.create_queue(mem);
+        let chain = queue.iter().unwrap().next().unwrap();
+        chain
+    }
+
+    #[test]
+    fn test_split_descriptor_chain() {
+        let mem = memory();
+        mem.write_slice(b"hello world", GuestAddress(0x1000))
+            .unwrap();
+        let chain = pop_chain(
+            &mem,
+            1,
+            &[
+                Descriptor::new(0x1006, 5, NEXT, 3),
+                Descriptor::new(0x1000, 6, NEXT, 2),
+                // Zero-length descriptors are skipped, wherever they point to.
+                Descriptor::new(0xffff_0000, 0, NEXT, 0),
+                Descriptor::new(0x2000, 8, NEXT | WRITE, 4),
+                Descriptor::new(0x3000, 0, NEXT | WRITE, 5),
+                Descriptor::new(0x2008, 8, WRITE, 0),
+            ],
+        );
+
+        let (mut reader, mut writer) = split_descriptor_chain(&mem, chain, 11).unwrap();
+        assert_eq!(writer.head_index(), 1);
+        assert_eq!(reader.available_bytes(), 11);
+        let mut buf = [0u8; 11];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello world");
+
+        assert_eq!(writer.available_bytes(), 16);
+        writer.write_all(&[0xa5; 16]).unwrap();
+        assert_eq!(writer.bytes_written(), 16);
+        assert_eq!(
+            mem.read_obj::<u64>(GuestAddress(0x2008)).unwrap(),
+            !0 / 0xff * 0xa5
+        );
+
+        // The reply of the same chain may be built on its own.
+        let chain = pop_chain(&mem, 1, &[Descriptor::new(0x2000, 8, WRITE, 0)]);
+        let writer = VirtioFsWriter::new(&mem, chain).unwrap();
+        assert_eq!(writer.available_bytes(), 8);
+    }
+
+    #[test]
+    fn test_indirect_descriptor_chain() {
+        let mem = memory();
+        let table = DescriptorTable::new(&mem, GuestAddress(0x4000), 4);
+        table.store(0, Descriptor::new(0x1000, 16, NEXT, 2));
+        table.store(2, Descriptor::new(0x1010, 0, NEXT, 1));
+        table.store(1, Descriptor::new(0x1020, 16, NEXT, 3));
+        table.store(3, Descriptor::new(0x2000, 64, WRITE, 0));
+        let chain = pop_chain(
+            &mem,
+            0,
+            &[Descriptor::new(0x4000, 64, VIRTQ_DESC_F_INDIRECT, 0)],
+        );
+
+        let (reader, writer) = split_descriptor_chain(&mem, chain, 32).unwrap();
+        assert_eq!(writer.head_index(), 0);
+        assert_eq!(reader.available_bytes(), 32);
+        assert_eq!(writer.available_bytes(), 64);
+
+        // Indirect tables must not refer to another one.
+        table.store(3, Descriptor::new(0x4000, 64, VIRTQ_DESC_F_INDIRECT, 0));
+        let chain = pop_chain(
+            &mem,
+            0,
+            &[Descriptor::new(0x4000, 64, VIRTQ_DESC_F_INDIRECT, 0)],
+        );
+        assert!(matches!(
+            split_descriptor_chain(&mem, chain, 32),
+            Err(Error::TruncatedChain)
+        ));
+
+        // Nor be misaligned.
+        let chain = pop_chain(
+            &mem,
+            0,
+            &[Descriptor::new(0x4000, 60, VIRTQ_DESC_F_INDIRECT, 0)],
+        );
+        assert!(matches!(
+            split_descriptor_chain(&mem, chain, 32),
+            Err(Error::InvalidChain)
+        ));
+    }
+
+    #[test]
+    fn test_reserve_header() {
+        let mem = memory();
+        let chain = pop_chain(
+            &mem,
+            0,
+            &[
+                Descriptor::new(0x2000, 10, NEXT | WRITE, 1),
+                Descriptor::new(0x3000, 64, WRITE, 0),
+            ],
+        );
+        let writer = Writer::from(VirtioFsWriter::new(&mem, chain).unwrap());
+
+        // The header spans both descriptors, and the payload of a short read only part of the
+        // space reserved for it.
+        let (header, mut data) = writer.reserve_header(16).unwrap();
+        assert_eq!(data.available_bytes(), 58);
+        data.write_all(&[0xa5; 20]).unwrap();
+        assert_eq!(header.commit(&[1u8; 16], 20).unwrap(), 36);
+
+        let mut buf = [0u8; 64];
+        mem.read_slice(&mut buf[..10], GuestAddress(0x2000))
+            .unwrap();
+        assert_eq!(buf[..10], [1u8; 10]);
+        mem.read_slice(&mut buf, GuestAddress(0x3000)).unwrap();
+        assert_eq!(buf[..6], [1u8; 6]);
+        assert_eq!(buf[6..26], [0xa5; 20]);
+        assert_eq!(buf[26..], [0u8; 38]);
+    }
+
+    #[test]
+    fn test_malformed_descriptor_chain() {
+        let mem = memory();
+        let split = |descs: &[Descriptor], max_request| {
+            split_descriptor_chain(&mem, pop_chain(&mem, 0, descs), max_request).map(|_| ())
+        };
+
+        // Readable descriptors after writable ones.
+        let res = split(
+            &[
+                Descriptor::new(0x1000, 16, NEXT | WRITE, 1),
+                Descriptor::new(0x2000, 16, 0, 0),
+            ],
+            0x1000,
+        );
+        assert!(matches!(res, Err(Error::UnorderedChain)));
+
+        // Links out of the descriptor table.
+        let res = split(&[Descriptor::new(0x1000, 16, NEXT, 100)], 0x1000);
+        assert!(matches!(res, Err(Error::TruncatedChain)));
+
+        // Loops.
+        let res = split(
+            &[
+                Descriptor::new(0x1000, 16, NEXT, 1),
+                Descriptor::new(0x2000, 16, NEXT, 0),
+            ],
+            0x1000,
+        );
+        assert!(matches!(res, Err(Error::TruncatedChain)));
+
+        // Buffers out of the guest memory.
+        let res = split(&[Descriptor::new(0x10_0000, 16, 0, 0)], 0x1000);
+        assert!(matches!(res, Err(Error::FindMemoryRegion)));
+        let res = split(&[Descriptor::new(0xfff0, 32, 0, 0)], 0x1000);
+        assert!(matches!(res, Err(Error::GuestMemoryError(_))));
+
+        // Requests larger than the max request size, replies aren't limited.
+        let descs = [
+            Descriptor::new(0x1000, 0x800, NEXT, 1),
+            Descriptor::new(0x2000, 0x801, NEXT, 2),
+            Descriptor::new(0x3000, 0x2000, WRITE, 0),
+        ];
+        let res = split(&descs, 0x1000);
+        assert!(matches!(res, Err(Error::ChainTooLarge(0x1001))));
+        assert!(split(&descs, 0x1001).is_ok());
+    }
+}
+
 /// Disabled since vm-virtio doesn't export any DescriptorChain constructors.
 /// Should re-enable once it does.
 #[cfg(testff)]
@@ -1063,6 +1385,89 @@ mod tests {
         assert_eq!(writer.bytes_written(), 48);
     }
 
+    #[test]
+    fn read_to_at_offset() {
+        use DescriptorType::*;
+
+        let memory_start_addr = GuestAddress(0x0);
+        let memory = GuestMemoryMmap::from_ranges(&vec![(memory_start_addr, 0x10000)]).unwrap();
+        let content: Vec<u8> = (0..48u8).collect();
+        memory.write_slice(&content, GuestAddress(0x100)).unwrap();
+
+        let chain = create_descriptor_chain(
+            &memory,
+            GuestAddress(0x0),
+            GuestAddress(0x100),
+            vec![(Readable, 16), (Readable, 16), (Readable, 16)],
+            0,
+        )
+        .expect("create_descriptor_chain failed");
+        let reader =
+            Reader::from_descriptor_chain(&memory, chain).expect("failed to create Reader");
+
+        // Consume segments crossing descriptors out of order without moving the cursor.
+        let mut file = TempFile::new().unwrap().into_file();
+        for (off, len) in [(40usize, 8usize), (0, 20), (20, 20)] {
+            assert_eq!(
+                reader
+                    .read_to_at_offset(&mut file, len, off as u64, off)
+                    .unwrap(),
+                len
+            );
+        }
+        assert_eq!(reader.available_bytes(), 48);
+        assert_eq!(reader.bytes_read(), 0);
+        reader.read_to_at_offset(&mut file, 9, 0, 40).unwrap_err();
+
+        let mut data = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut data).unwrap();
+        assert_eq!(data, content);
+    }
+
+    #[test]
+    fn write_from_at_offset() {
+        use DescriptorType::*;
+
+        let memory_start_addr = GuestAddress(0x0);
+        let memory = GuestMemoryMmap::from_ranges(&vec![(memory_start_addr, 0x10000)]).unwrap();
+
+        let chain = create_descriptor_chain(
+            &memory,
+            GuestAddress(0x0),
+            GuestAddress(0x100),
+            vec![(Writable, 16), (Writable, 16), (Writable, 16)],
+            0,
+        )
+        .expect("create_descriptor_chain failed");
+        let mut writer = VirtioFsWriter::new(&memory, chain).expect("failed to create Writer");
+
+        let mut file = TempFile::new().unwrap().into_file();
+        let content: Vec<u8> = (0..48u8).collect();
+        file.write_all(&content).unwrap();
+
+        // Assemble segments crossing descriptors out of order without moving the cursor.
+        for (off, len) in [(30usize, 18usize), (0, 7), (7, 23)] {
+            assert_eq!(
+                writer
+                    .write_from_at_offset(&mut file, len, off as u64, off)
+                    .unwrap(),
+                len
+            );
+        }
+        assert_eq!(writer.bytes_written(), 0);
+        writer
+            .write_from_at_offset(&mut file, 1, 0, 48)
+            .unwrap_err();
+
+        writer.advance(48).unwrap();
+        assert_eq!(writer.bytes_written(), 48);
+        assert_eq!(writer.available_bytes(), 0);
+        let mut data = vec![0u8; 48];
+        memory.read_slice(&mut data, GuestAddress(0x100)).unwrap();
+        assert_eq!(data, content);
+    }
+
     #[cfg(feature = "async-io")]
     mod async_io {
         use futures::executor::{block_on, ThreadPool};
@@ -0,0 +1,259 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Send notifications to the guest kernel through the notification queue of a virtio-fs device.
+//!
+//! Devices offering `VIRTIO_FS_F_NOTIFICATION` have a notification queue, next to the hiprio
+//! queue, into which the driver makes device-writable buffers available. Each notification is
+//! written into the next available buffer, which is then put into the used ring. Unlike with the
+//! fuse device, the driver handles notifications asynchronously, so the device never learns
+//! whether they succeeded.
+
+use std::ffi::CStr;
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+use virtio_queue::Queue;
+use vm_memory::GuestAddressSpace;
+
+use super::VirtioFsWriter;
+use crate::api::attr_cache::Notifier;
+use crate::api::server::NotifyMessage;
+use crate::transport::FileVolatileSlice;
+
+/// Feature bit of virtio-fs devices with a notification queue.
+pub const VIRTIO_FS_F_NOTIFICATION: u64 = 1 << 0;
+
+/// A [Notifier] writing notification messages into the notification queue of a virtio-fs device.
+///
+/// `signal` is called to notify the driver of used buffers, typically by writing to the call
+/// eventfd of the queue. Notifications are dropped when the driver has no buffer available.
+/// Data pushed by `store()` is split into messages of at most `max_write` bytes, and flags of
+/// entry invalidations are only sent to drivers speaking the protocol minor version set by
+/// [VirtioFsNotifier::with_minor].
+pub struct VirtioFsNotifier<M: GuestAddressSpace> {
+    queue: Mutex<Queue<M>>,
+    signal: Box<dyn Fn() -> io::Result<()> + Send + Sync>,
+    max_write: usize,
+    minor: u32,
+}
+
+impl<M: GuestAddressSpace> VirtioFsNotifier<M> {
+    /// Create a notifier writing into the notification queue `queue`, with the `max_write`
+    /// negotiated with the driver.
+    pub fn new<F>(queue: Queue<M>, signal: F, max_write: u32) -> Self
+    where
+        F: Fn() -> io::Result<()> + Send + Sync + 'static,
+    {
+        VirtioFsNotifier {
+            queue: Mutex::new(queue),
+            signal: Box::new(signal),
+            max_write: (max_write as usize).max(1),
+            minor: 0,
+        }
+    }
+
+    /// Encode notifications for the protocol minor version `minor` negotiated by `FUSE_INIT`.
+    ///
+    /// The default value for this option is 0, which never sends flags the driver may not know.
+    pub fn with_minor(mut self, minor: u32) -> Self {
+        self.minor = minor;
+        self
+    }
+
+    // Write `msg` into the next available buffer of the queue.
+    fn send(&self, msg: &NotifyMessage) -> io::Result<()> {
+        let mut queue = self.queue.lock().unwrap();
+        let mem = queue.mem.memory();
+        let chain = queue
+            .iter()
+            .map_err(queue_error)?
+            .next()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOBUFS))?;
+        let head_index = chain.head_index();
+
+        let res = VirtioFsWriter::new(&*mem, chain)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+            .and_then(|mut w| {
+                if w.available_bytes() < msg.len() {
+                    return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+                }
+                for buf in msg.bufs() {
+                    w.write_all(&buf)?;
+                }
+                Ok(w.bytes_written())
+            });
+        // Buffers are returned to the driver even if the message didn't fit.
+        let len = *res.as_ref().unwrap_or(&0);
+        queue
+            .add_used(head_index, len as u32)
+            .map_err(queue_error)?;
+        if queue.needs_notification().map_err(queue_error)? {
+            (self.signal)()?;
+        }
+        res.map(|_| ())
+    }
+
+    fn send_inval(&self, msg: NotifyMessage) {
+        if let Err(e) = self.send(&msg) {
+            warn!(
+                "virtio-fs: failed to send {:?} notification, {}",
+                msg.opcode(),
+                e
+            );
+        }
+    }
+}
+
+fn queue_error(e: virtio_queue::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+impl<M> Notifier for VirtioFsNotifier<M>
+where
+    M: GuestAddressSpace + Send,
+{
+    fn inval_inode(&self, ino: u64) {
+        self.inval_inode_range(ino, 0, 0);
+    }
+
+    fn inval_inode_range(&self, ino: u64, off: i64, len: i64) {
+        self.send_inval(NotifyMessage::inval_inode(ino, off, len));
+    }
+
+    fn inval_entry(&self, parent: u64, name: &CStr) {
+        self.inval_entry_flags(parent, name, 0);
+    }
+
+    fn inval_entry_flags(&self, parent: u64, name: &CStr, flags: u32) {
+        self.send_inval(NotifyMessage::inval_entry(parent, name, flags, self.minor));
+    }
+
+    fn delete(&self, parent: u64, child: u64, name: &CStr) {
+        self.send_inval(NotifyMessage::delete(parent, child, name));
+    }
+
+    fn store(&self, inode: u64, offset: u64, data: &[FileVolatileSlice]) -> io::Result<usize> {
+        // Safe because the slices are valid during the call.
+        let data: Vec<&[u8]> = data
+            .iter()
+            .map(|s| unsafe { std::slice::from_raw_parts(s.as_ptr() as *const u8, s.len()) })
+            .collect();
+
+        let mut stored = 0;
+        for msg in NotifyMessage::store(inode, offset, &data, self.max_write) {
+            match self.send(&msg) {
+                Ok(()) => stored += msg.payload_len(),
+                // Out of buffers, the rest can't be stored.
+                Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) && stored > 0 => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(stored)
+    }
+
+    fn retrieve(&self, notify_unique: u64, inode: u64, offset: u64, size: u32) -> io::Result<()> {
+        self.send(&NotifyMessage::retrieve(notify_unique, inode, offset, size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abi::fuse_abi::{
+        NotifyInvalEntryOut, NotifyOpcode, NotifyStoreOut, Notify_Retrieve_Out, OutHeader,
+    };
+    use std::mem::size_of;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use virtio_queue::defs::VIRTQ_DESC_F_WRITE;
+    use virtio_queue::mock::MockSplitQueue;
+    use virtio_queue::Descriptor;
+    use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap};
+
+    const BUF_SIZE: u32 = 0x100;
+
+    // Read the message written into the buffer of used element `index`.
+    fn used_msg(
+        mem: &GuestMemoryMmap,
+        vq: &MockSplitQueue<GuestMemoryMmap>,
+        index: u64,
+    ) -> Vec<u8> {
+        let elem = vq.used_addr().unchecked_add(4 + index * 8);
+        let id: u32 = mem.read_obj(elem).unwrap();
+        let len: u32 = mem.read_obj(elem.unchecked_add(4)).unwrap();
+        let addr = GuestAddress(0x4000 + id as u64 * BUF_SIZE as u64);
+        let mut msg = vec![0u8; len as usize];
+        mem.read_slice(&mut msg, addr).unwrap();
+        let header: OutHeader = mem.read_obj(addr).unwrap();
+        assert_eq!(header.len, len);
+        assert_eq!(header.unique, 0);
+        msg
+    }
+
+    #[test]
+    fn test_virtio_fs_notifier() {
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = MockSplitQueue::new(&mem, 16);
+        // The driver makes 3 writable buffers available.
+        for i in 0..3u16 {
+            let addr = 0x4000 + i as u64 * BUF_SIZE as u64;
+            vq.desc_table()
+                .store(i, Descriptor::new(addr, BUF_SIZE, VIRTQ_DESC_F_WRITE, 0));
+            vq.avail().ring().ref_at(i as usize).store(i);
+        }
+        vq.avail().idx().store(3);
+
+        let signaled = Arc::new(AtomicUsize::new(0));
+        let counter = signaled.clone();
+        let notifier = VirtioFsNotifier::new(
+            vq.create_queue(&mem),
+            move || {
+                counter.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            },
+            4,
+        )
+        .with_minor(38);
+
+        notifier.inval_entry_flags(1, &std::ffi::CString::new("abc").unwrap(), 1);
+        notifier.retrieve(7, 3, 4096, 8192).unwrap();
+        // Only one buffer is left for the two messages of the data.
+        let mut data = b"abcdefg".to_vec();
+        // Safe because the slice covers `data`, which outlives it.
+        let slice = unsafe { FileVolatileSlice::new(data.as_mut_ptr(), data.len()) };
+        assert_eq!(notifier.store(5, 100, &[slice]).unwrap(), 4);
+        assert_eq!(
+            notifier.retrieve(7, 3, 0, 1).unwrap_err().raw_os_error(),
+            Some(libc::ENOBUFS)
+        );
+        assert_eq!(vq.used().idx().load(), 3);
+        assert_eq!(signaled.load(Ordering::Relaxed), 3);
+
+        let msg = used_msg(&mem, &vq, 0);
+        let header: OutHeader = mem.read_obj(GuestAddress(0x4000)).unwrap();
+        assert_eq!(header.error, NotifyOpcode::InvalEntry as i32);
+        let out: NotifyInvalEntryOut = mem
+            .read_obj(GuestAddress(0x4000 + size_of::<OutHeader>() as u64))
+            .unwrap();
+        assert_eq!((out.parent, out.namelen, out.flags), (1, 3, 1));
+        assert_eq!(&msg[msg.len() - 4..], b"abc\0");
+
+        used_msg(&mem, &vq, 1);
+        let out: Notify_Retrieve_Out = mem
+            .read_obj(GuestAddress(0x4100 + size_of::<OutHeader>() as u64))
+            .unwrap();
+        assert_eq!(
+            (out.notify_unique, out.nodeid, out.offset, out.size),
+            (7, 3, 4096, 8192)
+        );
+
+        let msg = used_msg(&mem, &vq, 2);
+        let out: NotifyStoreOut = mem
+            .read_obj(GuestAddress(0x4200 + size_of::<OutHeader>() as u64))
+            .unwrap();
+        assert_eq!((out.nodeid, out.offset, out.size), (5, 100, 4));
+        assert_eq!(&msg[msg.len() - 4..], b"abcd");
+    }
+}
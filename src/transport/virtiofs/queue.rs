@@ -0,0 +1,267 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Concurrent processing of the requests of a virtio-fs queue by the asynchronous server.
+//!
+//! Awaiting each request before popping the next descriptor chain serializes the whole queue
+//! behind its slowest request, although the guest submitted independent requests. Virtio lets
+//! devices return used descriptor chains in any order, so [process_queue] pops all available
+//! chains, runs one future per chain, at most `max_inflight` of them at a time, and puts each
+//! chain into the used ring as soon as its request completes:
+//!
+//! ```ignore
+//! let handled = process_queue(&mut queue, 64, |chain| {
+//!     let mem = mem.clone();
+//!     async move {
+//!         let max_request = server.max_request_size();
+//!         let (reader, writer) = match split_descriptor_chain(&mem, chain, max_request) {
+//!             Ok(rw) => rw,
+//!             Err(_) => return 0,
+//!         };
+//!         // Safe because `mem` outlives the future.
+//!         unsafe { server.async_handle_message(reader, Writer::VirtioFs(writer), None, None) }
+//!             .await
+//!             .map_or(0, |len| len as u32)
+//!     }
+//! }, &|| call_eventfd.write(1))
+//! .await?;
+//! ```
+//!
+//! The futures run on the task calling [process_queue], which also does all accesses to the
+//! queue, so used ring updates of requests completing concurrently are serialized. Requests
+//! completing together are published with a single notification check, which keeps interrupt
+//! suppression by `VIRTIO_RING_F_EVENT_IDX` working. Driver notifications are disabled while
+//! requests are processed, chains made available meanwhile are popped as requests complete.
+
+use std::future::Future;
+use std::io;
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::FutureExt;
+use virtio_queue::{DescriptorChain, Queue, QueueState};
+use vm_memory::GuestAddressSpace;
+
+/// Virtqueue operations used by [process_queue].
+pub trait VirtioQueue {
+    /// Descriptor chain popped from the queue.
+    type Chain;
+
+    /// Pop the next available descriptor chain, with the index of its head descriptor.
+    fn pop_chain(&mut self) -> io::Result<Option<(u16, Self::Chain)>>;
+
+    /// Put the chain with head descriptor `head_index` into the used ring, `len` bytes of it
+    /// were written.
+    fn add_used(&mut self, head_index: u16, len: u32) -> io::Result<()>;
+
+    /// Disable notifications of available chains from the driver.
+    fn disable_notification(&mut self) -> io::Result<()>;
+
+    /// Enable notifications of available chains from the driver, return whether chains were
+    /// made available before they got enabled.
+    fn enable_notification(&mut self) -> io::Result<bool>;
+
+    /// Check whether the driver needs to be notified of the chains put into the used ring.
+    fn needs_notification(&mut self) -> io::Result<bool>;
+}
+
+fn queue_error(e: virtio_queue::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+impl<M: GuestAddressSpace> VirtioQueue for Queue<M, QueueState> {
+    type Chain = DescriptorChain<M::T>;
+
+    fn pop_chain(&mut self) -> io::Result<Option<(u16, Self::Chain)>> {
+        let chain = self.iter().map_err(queue_error)?.next();
+        Ok(chain.map(|c| (c.head_index(), c)))
+    }
+
+    fn add_used(&mut self, head_index: u16, len: u32) -> io::Result<()> {
+        Queue::add_used(self, head_index, len).map_err(queue_error)
+    }
+
+    fn disable_notification(&mut self) -> io::Result<()> {
+        Queue::disable_notification(self).map_err(queue_error)
+    }
+
+    fn enable_notification(&mut self) -> io::Result<bool> {
+        Queue::enable_notification(self).map_err(queue_error)
+    }
+
+    fn needs_notification(&mut self) -> io::Result<bool> {
+        Queue::needs_notification(self).map_err(queue_error)
+    }
+}
+
+/// Process all available descriptor chains of `queue`, return the number of processed chains.
+///
+/// `handler` returns the future handling a chain, which resolves to the number of bytes written
+/// into the chain. At most `max_inflight` futures run concurrently. Chains are put into the used
+/// ring in completion order, and `notify` is called when the driver needs to be notified of used
+/// chains. Returns when no chain is available nor in flight, with driver notifications enabled.
+pub async fn process_queue<Q, F, Fut>(
+    queue: &mut Q,
+    max_inflight: usize,
+    mut handler: F,
+    notify: &dyn Fn() -> io::Result<()>,
+) -> io::Result<usize>
+where
+    Q: VirtioQueue,
+    F: FnMut(Q::Chain) -> Fut,
+    Fut: Future<Output = u32>,
+{
+    let max_inflight = max_inflight.max(1);
+    let mut inflight = FuturesUnordered::new();
+    let mut handled = 0;
+
+    queue.disable_notification()?;
+    loop {
+        while inflight.len() < max_inflight {
+            match queue.pop_chain()? {
+                Some((head_index, chain)) => {
+                    let request = handler(chain);
+                    inflight.push(async move { (head_index, request.await) });
+                }
+                None => break,
+            }
+        }
+
+        if inflight.is_empty() {
+            // Chains made available before notifications got enabled wouldn't be notified.
+            if queue.enable_notification()? {
+                queue.disable_notification()?;
+                continue;
+            }
+            return Ok(handled);
+        }
+
+        // Publish the first completed request, and the ones completed with it.
+        let mut completed = inflight.next().await;
+        while let Some((head_index, len)) = completed {
+            queue.add_used(head_index, len)?;
+            handled += 1;
+            completed = inflight.next().now_or_never().flatten();
+        }
+        if queue.needs_notification()? {
+            notify()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::oneshot;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+    use std::task::{Context, Poll};
+
+    #[derive(Default)]
+    struct MockState {
+        avail: VecDeque<(u16, oneshot::Receiver<u32>)>,
+        used: Vec<(u16, u32)>,
+        // Number of used chains after which the driver wants a notification, as `used_event`.
+        used_event: usize,
+        signalled: usize,
+        notifications_enabled: bool,
+        popped: usize,
+    }
+
+    #[derive(Clone, Default)]
+    struct MockQueue(Rc<RefCell<MockState>>);
+
+    impl MockQueue {
+        fn push(&self, head_index: u16) -> oneshot::Sender<u32> {
+            let (tx, rx) = oneshot::channel();
+            self.0.borrow_mut().avail.push_back((head_index, rx));
+            tx
+        }
+    }
+
+    impl VirtioQueue for MockQueue {
+        type Chain = oneshot::Receiver<u32>;
+
+        fn pop_chain(&mut self) -> io::Result<Option<(u16, Self::Chain)>> {
+            let mut state = self.0.borrow_mut();
+            let chain = state.avail.pop_front();
+            state.popped += chain.is_some() as usize;
+            Ok(chain)
+        }
+
+        fn add_used(&mut self, head_index: u16, len: u32) -> io::Result<()> {
+            self.0.borrow_mut().used.push((head_index, len));
+            Ok(())
+        }
+
+        fn disable_notification(&mut self) -> io::Result<()> {
+            self.0.borrow_mut().notifications_enabled = false;
+            Ok(())
+        }
+
+        fn enable_notification(&mut self) -> io::Result<bool> {
+            let mut state = self.0.borrow_mut();
+            state.notifications_enabled = true;
+            Ok(!state.avail.is_empty())
+        }
+
+        fn needs_notification(&mut self) -> io::Result<bool> {
+            // Like event idx: notify once the used ring moved past `used_event`.
+            let mut state = self.0.borrow_mut();
+            let old = state.signalled;
+            state.signalled = state.used.len();
+            Ok(old <= state.used_event && state.used_event < state.used.len())
+        }
+    }
+
+    #[test]
+    fn test_process_queue_out_of_order() {
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mock = MockQueue::default();
+        mock.0.borrow_mut().used_event = 1;
+        let mut senders: Vec<_> = (0..3).map(|i| mock.push(i)).collect();
+        let notified = RefCell::new(0);
+        let notify = || {
+            *notified.borrow_mut() += 1;
+            Ok(())
+        };
+
+        let mut queue = mock.clone();
+        let mut fut = Box::pin(process_queue(
+            &mut queue,
+            2,
+            |rx| rx.map(|len| len.unwrap()),
+            &notify,
+        ));
+        assert!(fut.poll_unpin(&mut cx).is_pending());
+        // Only `max_inflight` chains are popped.
+        assert_eq!(mock.0.borrow().popped, 2);
+        assert!(!mock.0.borrow().notifications_enabled);
+
+        // The second request completes first, and frees a slot for the third one.
+        senders.remove(1).send(20).unwrap();
+        assert!(fut.poll_unpin(&mut cx).is_pending());
+        assert_eq!(mock.0.borrow().used, vec![(1, 20)]);
+        assert_eq!(mock.0.borrow().popped, 3);
+        assert_eq!(*notified.borrow(), 0);
+
+        // Requests completing together are published with one notification.
+        senders.remove(1).send(30).unwrap();
+        senders.remove(0).send(10).unwrap();
+        let late = mock.push(3);
+        assert!(fut.poll_unpin(&mut cx).is_pending());
+        let mut used = mock.0.borrow().used[1..].to_vec();
+        used.sort_unstable();
+        assert_eq!(used, vec![(0, 10), (2, 30)]);
+        assert_eq!(*notified.borrow(), 1);
+
+        // Chains made available while processing are handled before returning.
+        late.send(0).unwrap();
+        assert!(matches!(fut.poll_unpin(&mut cx), Poll::Ready(Ok(4))));
+        assert_eq!(mock.0.borrow().used[3], (3, 0));
+        assert!(mock.0.borrow().notifications_enabled);
+        // The driver asked to be notified once only.
+        assert_eq!(*notified.borrow(), 1);
+    }
+}
@@ -0,0 +1,829 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Vhost-user-fs device backend, serving virtio-fs queues of a VMM over a vhost-user socket.
+//!
+//! [VhostUserFsBackend] implements the slave side of the vhost-user protocol for a virtio-fs
+//! device: it maps the guest memory shared by the VMM, tracks the configuration of the virtqueues
+//! and spawns one handler thread per queue once the VMM provides its kick eventfd. The device has
+//! the hiprio queue, index 0, for `FUSE_FORGET`, `FUSE_BATCH_FORGET` and `FUSE_INTERRUPT`, and
+//! `num_request_queues` request queues. Handler threads build the [Reader] and [Writer] of each
+//! available descriptor chain over the guest memory, pass them to [Server::handle_message] and
+//! put the chain into the used ring, signaling the call eventfd of the queue when the driver
+//! needs to be notified. The slave channel set up by the VMM is handed to the server, so
+//! `FUSE_SETUPMAPPING` and `FUSE_REMOVEMAPPING` work with a DAX window.
+//!
+//! Guest memory is mapped with the bitmap `B`, all replies written into guest memory mark the
+//! pages they touch. With an [AtomicBitmap](vm_memory::bitmap::AtomicBitmap), the VMM side of a
+//! live migration gets the pages dirtied by the backend from the regions of
+//! [VhostUserFsBackend::memory].
+//!
+//! ```ignore
+//! let server = Arc::new(Server::new(passthrough_fs));
+//! let backend = VhostUserFsBackend::<_, ()>::new(server)
+//!     .with_request_queues(2)
+//!     .with_tag("myfs")?;
+//! backend.serve("/tmp/vhost-fs.sock")?;
+//! ```
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token, Waker};
+use vhost::vhost_user::message::{
+    VhostUserConfigFlags, VhostUserInflight, VhostUserMemoryRegion, VhostUserProtocolFeatures,
+    VhostUserSingleMemoryRegion, VhostUserVirtioFeatures, VhostUserVringAddrFlags,
+    VhostUserVringState,
+};
+use vhost::vhost_user::{
+    Error as VhostUserError, Listener, Result as VhostUserResult, SlaveFsCacheReq, SlaveListener,
+    VhostUserSlaveReqHandlerMut,
+};
+use virtio_queue::Queue;
+use vm_memory::bitmap::{Bitmap, BitmapSlice};
+use vm_memory::mmap::NewBitmap;
+use vm_memory::{
+    FileOffset, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap,
+    GuestRegionMmap, MmapRegion,
+};
+
+use super::split_descriptor_chain;
+use crate::api::filesystem::FileSystem;
+use crate::api::server::Server;
+use crate::transport::{FsCacheReqHandler, Reader, Writer};
+
+/// Feature bit of virtio 1.0 devices.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+/// Feature bit of indirect descriptor tables.
+pub const VIRTIO_RING_F_INDIRECT_DESC: u64 = 1 << 28;
+/// Feature bit of notification suppression by `used_event` and `avail_event`.
+pub const VIRTIO_RING_F_EVENT_IDX: u64 = 1 << 29;
+
+// Size of the tag in the configuration space of virtio-fs devices.
+const VIRTIO_FS_TAG_LEN: usize = 36;
+const DEFAULT_QUEUE_SIZE: u16 = 1024;
+
+const KICK: Token = Token(0);
+const WAKE: Token = Token(1);
+
+type GuestMemory<B> = GuestMemoryAtomic<GuestMemoryMmap<B>>;
+
+// Virtqueue configured by the VMM, with its eventfds.
+struct Vring<B: Bitmap + 'static> {
+    queue: Queue<GuestMemory<B>>,
+    kick: Option<File>,
+    call: Option<File>,
+    err: Option<File>,
+    enabled: bool,
+}
+
+impl<B: Bitmap + 'static> Vring<B> {
+    fn signal_used(&self) -> io::Result<()> {
+        if let Some(mut call) = self.call.as_ref() {
+            call.write_all(&1u64.to_ne_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+// Handler thread of a virtqueue.
+struct Worker {
+    waker: Arc<Waker>,
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+// Mapping of a region of guest memory into the address space of the VMM.
+struct VmmMapping {
+    vmm_addr: u64,
+    size: u64,
+    guest_addr: u64,
+}
+
+/// Vhost-user-fs device backend serving the virtqueues of a VMM with a [Server].
+///
+/// Memory regions are mapped with the bitmap `B`, see the [module documentation](self).
+pub struct VhostUserFsBackend<F: FileSystem + Send + Sync + 'static, B: Bitmap + 'static = ()> {
+    server: Arc<Server<F>>,
+    queue_size: u16,
+    tag: [u8; VIRTIO_FS_TAG_LEN],
+    acked_features: u64,
+    mem: GuestMemory<B>,
+    mappings: Vec<VmmMapping>,
+    vrings: Vec<Arc<Mutex<Vring<B>>>>,
+    workers: Vec<Option<Worker>>,
+    slave_req: Arc<Mutex<Option<SlaveFsCacheReq>>>,
+}
+
+fn vhost_error(e: io::Error) -> VhostUserError {
+    VhostUserError::ReqHandlerError(e)
+}
+
+impl<F, B> VhostUserFsBackend<F, B>
+where
+    F: FileSystem + Send + Sync + 'static,
+    B: Bitmap + NewBitmap + Clone + Send + Sync + 'static,
+{
+    /// Create a backend of a device with a single request queue, serving requests with `server`.
+    pub fn new(server: Arc<Server<F>>) -> Self {
+        let mut backend = VhostUserFsBackend {
+            server,
+            queue_size: DEFAULT_QUEUE_SIZE,
+            tag: [0; VIRTIO_FS_TAG_LEN],
+            acked_features: 0,
+            mem: GuestMemoryAtomic::new(GuestMemoryMmap::new()),
+            mappings: Vec::new(),
+            vrings: Vec::new(),
+            workers: Vec::new(),
+            slave_req: Arc::new(Mutex::new(None)),
+        };
+        backend.set_queues(2);
+        backend
+    }
+
+    /// Serve `num` request queues, next to the hiprio queue.
+    ///
+    /// The default value for this option is 1.
+    pub fn with_request_queues(mut self, num: usize) -> Self {
+        self.set_queues(num.max(1) + 1);
+        self
+    }
+
+    /// Accept virtqueues of up to `size` descriptors.
+    ///
+    /// The default value for this option is 1024.
+    pub fn with_queue_size(mut self, size: u16) -> Self {
+        self.queue_size = size;
+        let num = self.vrings.len();
+        self.set_queues(num);
+        self
+    }
+
+    /// Set the tag of the device in its configuration space, which guests mount, of up to 36
+    /// bytes.
+    ///
+    /// The default value for this option is an empty tag, the VMM provides one then.
+    pub fn with_tag(mut self, tag: &str) -> io::Result<Self> {
+        if tag.len() > VIRTIO_FS_TAG_LEN {
+            return Err(io::Error::from_raw_os_error(libc::ENAMETOOLONG));
+        }
+        self.tag = [0; VIRTIO_FS_TAG_LEN];
+        self.tag[..tag.len()].copy_from_slice(tag.as_bytes());
+        Ok(self)
+    }
+
+    /// Get the guest memory shared by the VMM, whose regions track the pages written by the
+    /// backend in their bitmap.
+    pub fn memory(&self) -> GuestMemory<B> {
+        self.mem.clone()
+    }
+
+    /// Serve the VMM connecting to the vhost-user socket at `path`, until it disconnects.
+    pub fn serve(self, path: &str) -> io::Result<()> {
+        let listener = Listener::new(path, true).map_err(io::Error::other)?;
+        let backend = Arc::new(Mutex::new(self));
+        let mut slave = SlaveListener::new(listener, backend.clone()).map_err(io::Error::other)?;
+        let mut handler = loop {
+            if let Some(handler) = slave.accept().map_err(io::Error::other)? {
+                break handler;
+            }
+        };
+
+        let res = loop {
+            match handler.handle_request() {
+                Ok(()) => {}
+                // The VMM closed the connection.
+                Err(VhostUserError::SocketBroken(_)) | Err(VhostUserError::PartialMessage) => {
+                    break Ok(())
+                }
+                Err(e) => break Err(io::Error::other(e)),
+            }
+        };
+        backend.lock().unwrap().stop_workers();
+        res
+    }
+
+    fn set_queues(&mut self, num: usize) {
+        self.stop_workers();
+        self.vrings = (0..num)
+            .map(|_| {
+                Arc::new(Mutex::new(Vring {
+                    queue: Queue::new(self.mem.clone(), self.queue_size),
+                    kick: None,
+                    call: None,
+                    err: None,
+                    enabled: false,
+                }))
+            })
+            .collect();
+        self.workers = (0..num).map(|_| None).collect();
+    }
+
+    fn vring(&self, index: u32) -> VhostUserResult<&Arc<Mutex<Vring<B>>>> {
+        self.vrings
+            .get(index as usize)
+            .ok_or(VhostUserError::InvalidParam)
+    }
+
+    fn protocol_features_acked(&self) -> bool {
+        self.acked_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() != 0
+    }
+
+    // Translate an address in the address space of the VMM into a guest physical address.
+    fn vmm_to_guest_addr(&self, addr: u64) -> VhostUserResult<u64> {
+        self.mappings
+            .iter()
+            .find(|m| addr >= m.vmm_addr && addr - m.vmm_addr < m.size)
+            .map(|m| addr - m.vmm_addr + m.guest_addr)
+            .ok_or(VhostUserError::InvalidParam)
+    }
+
+    fn start_worker(&mut self, index: usize) -> io::Result<()> {
+        let vring = self.vrings[index].clone();
+        let kick = match vring.lock().unwrap().kick.as_ref() {
+            Some(kick) => kick.try_clone()?,
+            None => return Ok(()),
+        };
+        let poll = Poll::new()?;
+        poll.registry()
+            .register(&mut SourceFd(&kick.as_raw_fd()), KICK, Interest::READABLE)?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKE)?);
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let server = self.server.clone();
+        let slave_req = self.slave_req.clone();
+        let thread_stop = stop.clone();
+        let thread = thread::Builder::new()
+            .name(format!("vhost-user-fs-q{}", index))
+            .spawn(move || {
+                if let Err(e) = run_worker(poll, kick, &vring, &server, &slave_req, &thread_stop) {
+                    error!("vhost-user-fs: queue {} failed, {}", index, e);
+                }
+            })?;
+        self.workers[index] = Some(Worker {
+            waker,
+            stop,
+            thread,
+        });
+
+        Ok(())
+    }
+
+    fn stop_worker(&mut self, index: usize) {
+        if let Some(worker) = self.workers.get_mut(index).and_then(|w| w.take()) {
+            worker.stop.store(true, Ordering::Release);
+            let _ = worker.waker.wake();
+            let _ = worker.thread.join();
+        }
+    }
+
+    fn stop_workers(&mut self) {
+        for index in 0..self.workers.len() {
+            self.stop_worker(index);
+        }
+    }
+
+    // Let the worker of queue `index` process chains made available while it was disabled.
+    fn wake_worker(&self, index: usize) {
+        if let Some(worker) = &self.workers[index] {
+            let _ = worker.waker.wake();
+        }
+    }
+
+    fn config_space(&self) -> Vec<u8> {
+        let mut config = self.tag.to_vec();
+        let num_request_queues = self.vrings.len() as u32 - 1;
+        config.extend_from_slice(&num_request_queues.to_le_bytes());
+        config
+    }
+}
+
+impl<F: FileSystem + Send + Sync + 'static, B: Bitmap + 'static> Drop for VhostUserFsBackend<F, B> {
+    fn drop(&mut self) {
+        for worker in self.workers.iter_mut().filter_map(|w| w.take()) {
+            worker.stop.store(true, Ordering::Release);
+            let _ = worker.waker.wake();
+            let _ = worker.thread.join();
+        }
+    }
+}
+
+// Wait for kicks of the driver and process the available chains of `vring`, until stopped.
+fn run_worker<F: FileSystem + Sync, B: Bitmap + 'static>(
+    mut poll: Poll,
+    mut kick: File,
+    vring: &Mutex<Vring<B>>,
+    server: &Server<F>,
+    slave_req: &Mutex<Option<SlaveFsCacheReq>>,
+    stop: &AtomicBool,
+) -> io::Result<()> {
+    let mut events = Events::with_capacity(2);
+    // Chains may have been made available before the kick eventfd was set.
+    process_vring(vring, server, slave_req)?;
+    loop {
+        match poll.poll(&mut events, None) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+        if stop.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        if events.iter().any(|e| e.token() == KICK) {
+            let mut count = [0u8; 8];
+            match kick.read(&mut count) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        process_vring(vring, server, slave_req)?;
+    }
+}
+
+fn queue_error(e: virtio_queue::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+// Handle all available chains of `vring`, return the number of handled chains.
+fn process_vring<F: FileSystem + Sync, B: Bitmap + 'static>(
+    vring: &Mutex<Vring<B>>,
+    server: &Server<F>,
+    slave_req: &Mutex<Option<SlaveFsCacheReq>>,
+) -> io::Result<usize> {
+    let mut vring = vring.lock().unwrap();
+    if !vring.enabled || !vring.queue.is_valid() {
+        return Ok(0);
+    }
+    let mem = vring.queue.mem.memory();
+    let mut slave_req = slave_req.lock().unwrap().clone();
+    let mut handled = 0;
+
+    loop {
+        vring.queue.disable_notification().map_err(queue_error)?;
+        while let Some(chain) = vring.queue.iter().map_err(queue_error)?.next() {
+            let head_index = chain.head_index();
+            let len = match split_descriptor_chain(&*mem, chain, server.max_request_size()) {
+                Ok((reader, writer)) => {
+                    let vu_req = slave_req.as_mut().map(|r| r as &mut dyn FsCacheReqHandler);
+                    handle_chain(server, reader, Writer::VirtioFs(writer), vu_req)
+                }
+                Err(e) => {
+                    warn!(
+                        "vhost-user-fs: invalid descriptor chain {}, {}",
+                        head_index, e
+                    );
+                    0
+                }
+            };
+            vring.queue.add_used(head_index, len).map_err(queue_error)?;
+            handled += 1;
+            if vring.queue.needs_notification().map_err(queue_error)? {
+                vring.signal_used()?;
+            }
+        }
+        // Chains made available before notifications got enabled wouldn't be notified.
+        if !vring.queue.enable_notification().map_err(queue_error)? {
+            return Ok(handled);
+        }
+    }
+}
+
+fn handle_chain<F: FileSystem + Sync, S: BitmapSlice>(
+    server: &Server<F>,
+    reader: Reader<'_, S>,
+    writer: Writer<'_, S>,
+    vu_req: Option<&mut dyn FsCacheReqHandler>,
+) -> u32 {
+    match server.handle_message(reader, writer, vu_req, None) {
+        Ok(len) => len as u32,
+        Err(e) => {
+            warn!("vhost-user-fs: failed to handle request, {}", e);
+            0
+        }
+    }
+}
+
+impl<F, B> VhostUserSlaveReqHandlerMut for VhostUserFsBackend<F, B>
+where
+    F: FileSystem + Send + Sync + 'static,
+    B: Bitmap + NewBitmap + Clone + Send + Sync + 'static,
+{
+    fn set_owner(&mut self) -> VhostUserResult<()> {
+        Ok(())
+    }
+
+    fn reset_owner(&mut self) -> VhostUserResult<()> {
+        let num = self.vrings.len();
+        self.set_queues(num);
+        self.acked_features = 0;
+        Ok(())
+    }
+
+    fn get_features(&mut self) -> VhostUserResult<u64> {
+        Ok(VIRTIO_F_VERSION_1
+            | VIRTIO_RING_F_INDIRECT_DESC
+            | VIRTIO_RING_F_EVENT_IDX
+            | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits())
+    }
+
+    fn set_features(&mut self, features: u64) -> VhostUserResult<()> {
+        if features & !self.get_features()? != 0 {
+            return Err(VhostUserError::InvalidParam);
+        }
+        self.acked_features = features;
+        let event_idx = features & VIRTIO_RING_F_EVENT_IDX != 0;
+        // Rings are enabled by SET_VRING_ENABLE once protocol features are negotiated.
+        let enabled = !self.protocol_features_acked();
+        for vring in self.vrings.iter() {
+            let mut vring = vring.lock().unwrap();
+            vring.queue.set_event_idx(event_idx);
+            vring.enabled = enabled;
+        }
+        Ok(())
+    }
+
+    fn set_mem_table(
+        &mut self,
+        ctx: &[VhostUserMemoryRegion],
+        files: Vec<File>,
+    ) -> VhostUserResult<()> {
+        if ctx.len() != files.len() {
+            return Err(VhostUserError::InvalidParam);
+        }
+        let mut regions = Vec::with_capacity(ctx.len());
+        let mut mappings = Vec::with_capacity(ctx.len());
+        for (region, file) in ctx.iter().zip(files) {
+            let mmap = MmapRegion::<B>::from_file(
+                FileOffset::new(file, region.mmap_offset),
+                region.memory_size as usize,
+            )
+            .map_err(|e| vhost_error(io::Error::other(e)))?;
+            let region_mmap = GuestRegionMmap::new(mmap, GuestAddress(region.guest_phys_addr))
+                .map_err(|e| vhost_error(io::Error::other(e)))?;
+            regions.push(region_mmap);
+            mappings.push(VmmMapping {
+                vmm_addr: region.user_addr,
+                size: region.memory_size,
+                guest_addr: region.guest_phys_addr,
+            });
+        }
+        let mem =
+            GuestMemoryMmap::from_regions(regions).map_err(|e| vhost_error(io::Error::other(e)))?;
+        self.mem
+            .lock()
+            .map_err(|_| VhostUserError::SlaveInternalError)?
+            .replace(mem);
+        self.mappings = mappings;
+        Ok(())
+    }
+
+    fn set_vring_num(&mut self, index: u32, num: u32) -> VhostUserResult<()> {
+        if num == 0 || num > self.queue_size as u32 {
+            return Err(VhostUserError::InvalidParam);
+        }
+        self.vring(index)?
+            .lock()
+            .unwrap()
+            .queue
+            .set_size(num as u16);
+        Ok(())
+    }
+
+    fn set_vring_addr(
+        &mut self,
+        index: u32,
+        _flags: VhostUserVringAddrFlags,
+        descriptor: u64,
+        used: u64,
+        available: u64,
+        _log: u64,
+    ) -> VhostUserResult<()> {
+        let desc = self.vmm_to_guest_addr(descriptor)?;
+        let avail = self.vmm_to_guest_addr(available)?;
+        let used = self.vmm_to_guest_addr(used)?;
+        let mut vring = self.vring(index)?.lock().unwrap();
+        vring
+            .queue
+            .set_desc_table_address(Some(desc as u32), Some((desc >> 32) as u32));
+        vring
+            .queue
+            .set_avail_ring_address(Some(avail as u32), Some((avail >> 32) as u32));
+        vring
+            .queue
+            .set_used_ring_address(Some(used as u32), Some((used >> 32) as u32));
+        Ok(())
+    }
+
+    fn set_vring_base(&mut self, index: u32, base: u32) -> VhostUserResult<()> {
+        self.vring(index)?
+            .lock()
+            .unwrap()
+            .queue
+            .set_next_avail(base as u16);
+        Ok(())
+    }
+
+    fn get_vring_base(&mut self, index: u32) -> VhostUserResult<VhostUserVringState> {
+        self.vring(index)?;
+        // The ring stops, the VMM may migrate or hand it over to another backend.
+        self.stop_worker(index as usize);
+        let mut vring = self.vrings[index as usize].lock().unwrap();
+        vring.queue.set_ready(false);
+        vring.kick = None;
+        vring.call = None;
+        Ok(VhostUserVringState::new(
+            index,
+            vring.queue.next_avail() as u32,
+        ))
+    }
+
+    fn set_vring_kick(&mut self, index: u8, fd: Option<File>) -> VhostUserResult<()> {
+        self.vring(index as u32)?;
+        self.stop_worker(index as usize);
+        {
+            let mut vring = self.vrings[index as usize].lock().unwrap();
+            vring.kick = fd;
+            vring.queue.set_ready(true);
+        }
+        self.start_worker(index as usize).map_err(vhost_error)
+    }
+
+    fn set_vring_call(&mut self, index: u8, fd: Option<File>) -> VhostUserResult<()> {
+        self.vring(index as u32)?.lock().unwrap().call = fd;
+        Ok(())
+    }
+
+    fn set_vring_err(&mut self, index: u8, fd: Option<File>) -> VhostUserResult<()> {
+        self.vring(index as u32)?.lock().unwrap().err = fd;
+        Ok(())
+    }
+
+    fn get_protocol_features(&mut self) -> VhostUserResult<VhostUserProtocolFeatures> {
+        Ok(VhostUserProtocolFeatures::MQ
+            | VhostUserProtocolFeatures::REPLY_ACK
+            | VhostUserProtocolFeatures::CONFIG
+            | VhostUserProtocolFeatures::SLAVE_REQ
+            | VhostUserProtocolFeatures::SLAVE_SEND_FD)
+    }
+
+    fn set_protocol_features(&mut self, _features: u64) -> VhostUserResult<()> {
+        // The slave request handler checks messages against the negotiated protocol features.
+        Ok(())
+    }
+
+    fn get_queue_num(&mut self) -> VhostUserResult<u64> {
+        Ok(self.vrings.len() as u64)
+    }
+
+    fn set_vring_enable(&mut self, index: u32, enable: bool) -> VhostUserResult<()> {
+        if !self.protocol_features_acked() {
+            return Err(VhostUserError::InvalidOperation);
+        }
+        self.vring(index)?.lock().unwrap().enabled = enable;
+        if enable {
+            self.wake_worker(index as usize);
+        }
+        Ok(())
+    }
+
+    fn get_config(
+        &mut self,
+        offset: u32,
+        size: u32,
+        _flags: VhostUserConfigFlags,
+    ) -> VhostUserResult<Vec<u8>> {
+        let config = self.config_space();
+        let end = offset
+            .checked_add(size)
+            .filter(|end| *end as usize <= config.len())
+            .ok_or(VhostUserError::InvalidParam)?;
+        Ok(config[offset as usize..end as usize].to_vec())
+    }
+
+    fn set_config(
+        &mut self,
+        _offset: u32,
+        _buf: &[u8],
+        _flags: VhostUserConfigFlags,
+    ) -> VhostUserResult<()> {
+        // The configuration space of virtio-fs devices is read-only.
+        Err(VhostUserError::InvalidOperation)
+    }
+
+    fn set_slave_req_fd(&mut self, vu_req: SlaveFsCacheReq) {
+        *self.slave_req.lock().unwrap() = Some(vu_req);
+    }
+
+    fn get_inflight_fd(
+        &mut self,
+        _inflight: &VhostUserInflight,
+    ) -> VhostUserResult<(VhostUserInflight, File)> {
+        Err(VhostUserError::InvalidOperation)
+    }
+
+    fn set_inflight_fd(
+        &mut self,
+        _inflight: &VhostUserInflight,
+        _file: File,
+    ) -> VhostUserResult<()> {
+        Err(VhostUserError::InvalidOperation)
+    }
+
+    fn get_max_mem_slots(&mut self) -> VhostUserResult<u64> {
+        Err(VhostUserError::InvalidOperation)
+    }
+
+    fn add_mem_region(
+        &mut self,
+        _region: &VhostUserSingleMemoryRegion,
+        _fd: File,
+    ) -> VhostUserResult<()> {
+        Err(VhostUserError::InvalidOperation)
+    }
+
+    fn remove_mem_region(&mut self, _region: &VhostUserSingleMemoryRegion) -> VhostUserResult<()> {
+        Err(VhostUserError::InvalidOperation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abi::fuse_abi::{GetattrIn, InHeader, Opcode, OutHeader, ROOT_ID};
+    use std::mem::size_of;
+    use std::os::unix::io::FromRawFd;
+    use virtio_queue::defs::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+    use virtio_queue::mock::MockSplitQueue;
+    use virtio_queue::Descriptor;
+    use vm_memory::bitmap::AtomicBitmap;
+    use vm_memory::{Address, ByteValued, Bytes, GuestMemory, GuestMemoryRegion};
+
+    const MEM_SIZE: u64 = 0x10000;
+    // Address of the guest memory in the address space of the VMM.
+    const VMM_ADDR: u64 = 0x7f00_0000_0000;
+
+    struct NoopFs;
+
+    impl FileSystem for NoopFs {
+        type Inode = u64;
+        type Handle = u64;
+    }
+
+    fn eventfd() -> File {
+        // Safe because we check the returned fd, which we own.
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        assert!(fd >= 0);
+        unsafe { File::from_raw_fd(fd) }
+    }
+
+    fn memfd() -> File {
+        let name = std::ffi::CString::new("guest").unwrap();
+        // Safe because we check the returned fd, which we own.
+        let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+        assert!(fd >= 0);
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.set_len(MEM_SIZE).unwrap();
+        file
+    }
+
+    fn backend() -> VhostUserFsBackend<NoopFs, AtomicBitmap> {
+        VhostUserFsBackend::new(Arc::new(Server::new(NoopFs)))
+            .with_request_queues(2)
+            .with_queue_size(16)
+            .with_tag("myfs")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_vhost_user_fs_config() {
+        let mut backend = backend();
+        assert_eq!(backend.get_queue_num().unwrap(), 3);
+        let config = backend
+            .get_config(0, 40, VhostUserConfigFlags::empty())
+            .unwrap();
+        assert_eq!(&config[..5], b"myfs\0");
+        assert_eq!(&config[36..], &2u32.to_le_bytes());
+        assert!(backend
+            .get_config(36, 8, VhostUserConfigFlags::empty())
+            .is_err());
+        assert!(
+            VhostUserFsBackend::<NoopFs>::new(Arc::new(Server::new(NoopFs)))
+                .with_tag(&"t".repeat(37))
+                .is_err()
+        );
+
+        // Rings are enabled explicitly once protocol features are negotiated only.
+        assert!(matches!(
+            backend.set_vring_enable(1, true),
+            Err(VhostUserError::InvalidOperation)
+        ));
+        assert!(matches!(
+            backend.set_vring_num(1, 32),
+            Err(VhostUserError::InvalidParam)
+        ));
+        assert!(matches!(
+            backend.set_vring_addr(1, VhostUserVringAddrFlags::empty(), 0, 0, 0, 0),
+            Err(VhostUserError::InvalidParam)
+        ));
+        assert!(matches!(
+            backend.set_features(1 << 2),
+            Err(VhostUserError::InvalidParam)
+        ));
+    }
+
+    #[test]
+    fn test_vhost_user_fs_request_queue() {
+        let file = memfd();
+        let region = MmapRegion::<()>::from_file(
+            FileOffset::new(file.try_clone().unwrap(), 0),
+            MEM_SIZE as usize,
+        )
+        .unwrap();
+        let mem =
+            GuestMemoryMmap::from_regions(vec![
+                GuestRegionMmap::new(region, GuestAddress(0)).unwrap()
+            ])
+            .unwrap();
+        let vq = MockSplitQueue::new(&mem, 16);
+
+        let mut backend = backend();
+        let features = VIRTIO_F_VERSION_1 | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
+        backend.set_features(features).unwrap();
+        let protocol_features = backend.get_protocol_features().unwrap();
+        backend
+            .set_protocol_features(protocol_features.bits())
+            .unwrap();
+        let regions = [VhostUserMemoryRegion::new(0, MEM_SIZE, VMM_ADDR, 0)];
+        backend.set_mem_table(&regions, vec![file]).unwrap();
+        backend.set_vring_num(1, 16).unwrap();
+        backend
+            .set_vring_addr(
+                1,
+                VhostUserVringAddrFlags::empty(),
+                VMM_ADDR + vq.desc_table_addr().0,
+                VMM_ADDR + vq.used_addr().0,
+                VMM_ADDR + vq.avail_addr().0,
+                0,
+            )
+            .unwrap();
+        backend.set_vring_base(1, 0).unwrap();
+        let (kick, call) = (eventfd(), eventfd());
+        backend
+            .set_vring_call(1, Some(call.try_clone().unwrap()))
+            .unwrap();
+        backend
+            .set_vring_kick(1, Some(kick.try_clone().unwrap()))
+            .unwrap();
+        backend.set_vring_enable(1, true).unwrap();
+
+        // A getattr request, failing with ENOSYS.
+        let header = InHeader {
+            len: (size_of::<InHeader>() + size_of::<GetattrIn>()) as u32,
+            opcode: Opcode::Getattr as u32,
+            unique: 0x42,
+            nodeid: ROOT_ID,
+            ..Default::default()
+        };
+        let mut request = header.as_slice().to_vec();
+        request.extend_from_slice(GetattrIn::default().as_slice());
+        mem.write_slice(&request, GuestAddress(0x4000)).unwrap();
+        vq.desc_table().store(
+            0,
+            Descriptor::new(0x4000, request.len() as u32, VIRTQ_DESC_F_NEXT, 1),
+        );
+        vq.desc_table()
+            .store(1, Descriptor::new(0x5000, 0x100, VIRTQ_DESC_F_WRITE, 0));
+        vq.avail().ring().ref_at(0).store(0);
+        vq.avail().idx().store(1);
+        (&kick).write_all(&1u64.to_ne_bytes()).unwrap();
+
+        let mut count = [0u8; 8];
+        (&call).read_exact(&mut count).unwrap();
+        assert_eq!(vq.used().idx().load(), 1);
+        let reply_len = size_of::<OutHeader>() as u32;
+        assert_eq!(
+            mem.read_obj::<u32>(vq.used_addr().unchecked_add(8))
+                .unwrap(),
+            reply_len
+        );
+        let reply: OutHeader = mem.read_obj(GuestAddress(0x5000)).unwrap();
+        assert_eq!(reply.unique, 0x42);
+        assert_eq!(reply.error, -libc::ENOSYS);
+        assert_eq!(reply.len, reply_len);
+
+        // The reply dirtied the page it was written to, for live migration.
+        let memory = backend.memory().memory();
+        let region = memory.find_region(GuestAddress(0x5000)).unwrap();
+        assert!(region.bitmap().dirty_at(0x5000));
+        assert!(!region.bitmap().dirty_at(0x8000));
+
+        let state = backend.get_vring_base(1).unwrap();
+        assert_eq!({ state.num }, 1);
+    }
+}
@@ -8,16 +8,20 @@ use std::path::Path;
 use std::sync::Arc;
 use std::thread;
 
-use fuse_backend_rs::api::{server::Server, Vfs, VfsOptions};
+use fuse_backend_rs::api::server::{MetricsHook, Server};
+use fuse_backend_rs::api::{Vfs, VfsOptions};
 use fuse_backend_rs::passthrough::{Config, PassthroughFs};
-use fuse_backend_rs::transport::{FuseChannel, FuseSession};
+use fuse_backend_rs::transport::{FuseChannel, FuseDevNotifier, FuseSession};
 
 /// A fusedev daemon example
 #[allow(dead_code)]
 pub struct Daemon {
     mountpoint: String,
-    server: Arc<Server<Arc<Vfs>>>,
+    vfs: Arc<Vfs>,
     thread_cnt: u32,
+    clone_fd: bool,
+    max_write: Option<u32>,
+    hook: Option<Arc<dyn MetricsHook>>,
     session: Option<FuseSession>,
 }
 
@@ -44,21 +48,48 @@ impl Daemon {
 
         Ok(Daemon {
             mountpoint: mountpoint.to_string(),
-            server: Arc::new(Server::new(Arc::new(vfs))),
+            vfs: Arc::new(vfs),
             thread_cnt,
+            clone_fd: false,
+            max_write: None,
+            hook: None,
             session: None,
         })
     }
 
+    /// Gives each service thread its own cloned fuse fd.
+    pub fn set_clone_fd(&mut self, enable: bool) {
+        self.clone_fd = enable;
+    }
+
+    /// Negotiates writes of up to `max_write` bytes with the kernel.
+    pub fn set_max_write(&mut self, max_write: u32) {
+        self.max_write = Some(max_write);
+    }
+
+    /// Reports the requests served to `hook`.
+    pub fn set_metrics_hook(&mut self, hook: Arc<dyn MetricsHook>) {
+        self.hook = Some(hook);
+    }
+
     /// Mounts a fusedev daemon to the mountpoint, then start service threads to handle
     /// FUSE requests.
     pub fn mount(&mut self) -> Result<()> {
         let mut se =
             FuseSession::new(Path::new(&self.mountpoint), "passthru_example", "", false).unwrap();
+        se.set_clone_fd(self.clone_fd);
+        if let Some(max_write) = self.max_write {
+            se.set_max_write(max_write);
+        }
         se.mount().unwrap();
+        let mut server = Server::new(self.vfs.clone()).with_max_write(se.max_write());
+        if let Some(hook) = self.hook.clone() {
+            server = server.with_metrics_hook(hook);
+        }
+        let server = Arc::new(server);
         for _ in 0..self.thread_cnt {
             let mut server = FuseServer {
-                server: self.server.clone(),
+                server: server.clone(),
                 ch: se.new_channel().unwrap(),
             };
             let _thread = thread::Builder::new()
@@ -74,6 +105,11 @@ impl Daemon {
         Ok(())
     }
 
+    /// Creates a notifier to push cache invalidations to the kernel, once mounted.
+    pub fn notifier(&self) -> Option<FuseDevNotifier> {
+        self.session.as_ref().and_then(|se| se.notifier().ok())
+    }
+
     /// Umounts and destroies a fusedev daemon
     pub fn umount(&mut self) -> Result<()> {
         if let Some(mut se) = self.session.take() {
@@ -119,6 +155,13 @@ impl FuseServer {
                         }
                     }
                 }
+                // Fit the buffer to the requests negotiated by FUSE_INIT.
+                if self.server.connection_info().is_some() {
+                    let bufsize = self.server.max_request_size();
+                    if self.ch.bufsize() != bufsize {
+                        self.ch.set_bufsize(bufsize);
+                    }
+                }
             } else {
                 info!("fuse server exits");
                 break;
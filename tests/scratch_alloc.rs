@@ -0,0 +1,179 @@
+// Copyright 2026 The fuse-backend-rs Authors. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+// Verify that passthrough getxattr, and lookup and getattr requests through the server, don't
+// allocate at steady state. This lives in its own test binary because it installs a counting
+// global allocator.
+
+#[cfg(all(feature = "fusedev", target_os = "linux"))]
+mod passthrough_tests {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::ffi::CString;
+    use std::mem::size_of;
+    use std::os::unix::io::AsRawFd;
+
+    use fuse_backend_rs::abi::fuse_abi::{
+        AttrOut, EntryOut, GetattrIn, InHeader, Opcode, OutHeader, ROOT_ID,
+    };
+    use fuse_backend_rs::api::filesystem::{Context, FileSystem, GetxattrReply};
+    use fuse_backend_rs::api::scratch;
+    use fuse_backend_rs::api::server::Server;
+    use fuse_backend_rs::passthrough::{Config, PassthroughFs};
+    use fuse_backend_rs::transport::{FuseBuf, FuseDevWriter, Reader};
+    use vm_memory::ByteValued;
+    use vmm_sys_util::tempdir::TempDir;
+
+    struct CountingAlloc;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|c| c.set(c.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|c| c.set(c.get() + 1));
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: CountingAlloc = CountingAlloc;
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(|c| c.get())
+    }
+
+    #[test]
+    fn test_getxattr_no_allocation() {
+        let source = TempDir::new().unwrap();
+        let path = source.as_path().join("a");
+        std::fs::write(&path, b"a").unwrap();
+        let cpath = CString::new(path.to_str().unwrap()).unwrap();
+        let name = CString::new("user.scratch").unwrap();
+        let value = vec![0x5au8; 10000];
+        // Safe because all pointers are valid.
+        let res = unsafe {
+            libc::setxattr(
+                cpath.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        if res != 0 {
+            // The backing file system doesn't support user xattrs.
+            return;
+        }
+
+        let cfg = Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            xattr: true,
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        fs.import().unwrap();
+        let ctx = Context::default();
+        let ino = fs
+            .lookup(&ctx, 1, &CString::new("a").unwrap())
+            .unwrap()
+            .inode;
+
+        let getxattr = || match fs.getxattr(&ctx, ino, &name, 0x10000).unwrap() {
+            GetxattrReply::Value(v) => {
+                assert_eq!(v.len(), value.len());
+                // The server gives the reply buffer back once it's sent.
+                scratch::recycle(v);
+            }
+            GetxattrReply::Count(_) => panic!("unexpected count reply"),
+        };
+
+        // Warm up the scratch buffer.
+        getxattr();
+
+        let before = allocations();
+        for _ in 0..100 {
+            getxattr();
+        }
+        assert_eq!(allocations() - before, 0);
+    }
+
+    // Encode a request into `buf`, reusing its capacity.
+    fn encode_request(buf: &mut Vec<u8>, opcode: Opcode, nodeid: u64, body: &[u8]) {
+        let in_header = InHeader {
+            len: (size_of::<InHeader>() + body.len()) as u32,
+            opcode: opcode as u32,
+            unique: 2,
+            nodeid,
+            ..Default::default()
+        };
+        buf.clear();
+        buf.extend_from_slice(in_header.as_slice());
+        buf.extend_from_slice(body);
+    }
+
+    #[test]
+    fn test_lookup_getattr_no_allocation() {
+        let source = TempDir::new().unwrap();
+        std::fs::write(source.as_path().join("a"), b"a").unwrap();
+        let cfg = Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<()>::new(cfg).unwrap();
+        fs.import().unwrap();
+        let server = Server::new(fs);
+        let dev = std::fs::OpenOptions::new()
+            .write(true)
+            .open("/dev/null")
+            .unwrap();
+
+        let mut r_buf = Vec::with_capacity(256);
+        let mut w_buf = vec![0u8; 256];
+        // Count allocations of the server only, as the transport allocates to set up readers.
+        let server_allocations = Cell::new(0);
+        let mut send = |r_buf: &mut Vec<u8>| -> usize {
+            let r = Reader::<()>::from_fuse_buffer(FuseBuf::new(r_buf)).unwrap();
+            let w = FuseDevWriter::<()>::new(dev.as_raw_fd(), &mut w_buf)
+                .unwrap()
+                .into();
+            let before = allocations();
+            let len = server.handle_message(r, w, None, None).unwrap();
+            server_allocations.set(server_allocations.get() + allocations() - before);
+            len
+        };
+
+        let mut lookup_getattr = || {
+            encode_request(&mut r_buf, Opcode::Lookup, ROOT_ID, b"a\0");
+            assert_eq!(
+                send(&mut r_buf),
+                size_of::<OutHeader>() + size_of::<EntryOut>()
+            );
+            let getattr = GetattrIn::default();
+            encode_request(&mut r_buf, Opcode::Getattr, ROOT_ID, getattr.as_slice());
+            assert_eq!(
+                send(&mut r_buf),
+                size_of::<OutHeader>() + size_of::<AttrOut>()
+            );
+        };
+
+        // Warm up the inode map, the path hints and the scratch buffer.
+        lookup_getattr();
+
+        let before = server_allocations.get();
+        for _ in 0..100 {
+            lookup_getattr();
+        }
+        assert_eq!(server_allocations.get() - before, 0);
+    }
+}
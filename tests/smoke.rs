@@ -106,4 +106,221 @@ mod fusedev_tests {
         daemon.umount().unwrap();
         Ok(())
     }
+
+    #[test]
+    #[ignore] // it depends on privileged mode to pass through /dev/fuse
+    fn integration_test_clone_fd() -> Result<()> {
+        let src = TempDir::new().unwrap();
+        let mnt = TempDir::new().unwrap();
+        let data = |i: usize| vec![i as u8; 0x10000 + i];
+        for i in 0..16 {
+            std::fs::write(src.as_path().join(format!("f{}", i)), data(i))?;
+        }
+
+        let mut daemon = passthroughfs::Daemon::new(
+            src.as_path().to_str().unwrap(),
+            mnt.as_path().to_str().unwrap(),
+            4,
+        )
+        .unwrap();
+        daemon.set_clone_fd(true);
+        daemon.mount().unwrap();
+
+        // Read concurrently through several channels.
+        let readers: Vec<_> = (0..16)
+            .map(|i| {
+                let path = mnt.as_path().join(format!("f{}", i));
+                std::thread::spawn(move || {
+                    for _ in 0..8 {
+                        assert_eq!(std::fs::read(&path).unwrap(), data(i));
+                    }
+                })
+            })
+            .collect();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        daemon.umount().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    #[ignore] // it depends on privileged mode to pass through /dev/fuse
+    fn integration_test_notify_inval_entry() -> Result<()> {
+        use fuse_backend_rs::abi::fuse_abi::ROOT_ID;
+        use fuse_backend_rs::api::attr_cache::Notifier;
+        use std::ffi::CString;
+
+        let src = TempDir::new().unwrap();
+        let mnt = TempDir::new().unwrap();
+        std::fs::write(src.as_path().join("a"), b"a")?;
+
+        let mut daemon = passthroughfs::Daemon::new(
+            src.as_path().to_str().unwrap(),
+            mnt.as_path().to_str().unwrap(),
+            2,
+        )
+        .unwrap();
+        daemon.mount().unwrap();
+        assert!(mnt.as_path().join("a").exists());
+
+        // The kernel keeps the dentry cached after the host renames the file behind its back.
+        std::fs::rename(src.as_path().join("a"), src.as_path().join("b"))?;
+        assert!(mnt.as_path().join("a").exists());
+
+        let notifier = daemon.notifier().unwrap();
+        notifier.inval_entry(ROOT_ID, &CString::new("a").unwrap());
+        assert!(!mnt.as_path().join("a").exists());
+        assert_eq!(std::fs::read(mnt.as_path().join("b"))?, b"a");
+        daemon.umount().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    #[ignore] // it depends on privileged mode to pass through /dev/fuse
+    fn integration_test_statx_btime() -> Result<()> {
+        let src = TempDir::new().unwrap();
+        let mnt = TempDir::new().unwrap();
+        std::fs::write(src.as_path().join("f"), b"f")?;
+
+        let mut daemon = passthroughfs::Daemon::new(
+            src.as_path().to_str().unwrap(),
+            mnt.as_path().to_str().unwrap(),
+            2,
+        )
+        .unwrap();
+        daemon.mount().unwrap();
+
+        // std queries creation times by statx(2), guests of 7.39 kernels forward it.
+        if let Ok(created) = std::fs::metadata(src.as_path().join("f"))?.created() {
+            let guest = std::fs::metadata(mnt.as_path().join("f"))?;
+            match guest.created() {
+                Ok(guest_created) => assert_eq!(guest_created, created),
+                Err(e) => info!("guest kernel doesn't support FUSE_STATX: {}", e),
+            }
+        }
+        daemon.umount().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    #[ignore] // it depends on privileged mode to pass through /dev/fuse
+    fn integration_test_max_pages_write() -> Result<()> {
+        use fuse_backend_rs::abi::fuse_abi::{InHeader, Opcode, OutHeader};
+        use fuse_backend_rs::api::server::MetricsHook;
+        use std::io::Write;
+        use std::sync::{Arc, Mutex};
+
+        // Record the lengths of write requests.
+        #[derive(Default)]
+        struct Writes(Mutex<Vec<u32>>);
+
+        impl MetricsHook for Writes {
+            fn collect(&self, ih: &InHeader) {
+                if ih.opcode == Opcode::Write as u32 {
+                    self.0.lock().unwrap().push(ih.len);
+                }
+            }
+
+            fn release(&self, _oh: Option<&OutHeader>) {}
+        }
+
+        let src = TempDir::new().unwrap();
+        let mnt = TempDir::new().unwrap();
+        let writes = Arc::new(Writes::default());
+        let mut daemon = passthroughfs::Daemon::new(
+            src.as_path().to_str().unwrap(),
+            mnt.as_path().to_str().unwrap(),
+            2,
+        )
+        .unwrap();
+        daemon.set_max_write(1 << 20);
+        daemon.set_metrics_hook(writes.clone());
+        daemon.mount().unwrap();
+
+        let data = vec![0x5au8; 1 << 20];
+        let mut file = std::fs::File::create(mnt.as_path().join("f"))?;
+        file.write_all(&data)?;
+        drop(file);
+        assert_eq!(std::fs::read(src.as_path().join("f"))?, data);
+
+        // The megabyte arrives as a single request, with its headers.
+        let writes = writes.0.lock().unwrap().clone();
+        assert_eq!(writes.len(), 1, "{:?}", writes);
+        assert!(writes[0] as usize > data.len());
+        daemon.umount().unwrap();
+        Ok(())
+    }
+
+    #[cfg(feature = "daemon")]
+    #[test]
+    #[ignore] // it depends on privileged mode to pass through /dev/fuse
+    fn integration_test_daemon_multiple_backends() -> Result<()> {
+        use fuse_backend_rs::daemon::{Daemon, DaemonConfig, DaemonState};
+
+        let src_a = TempDir::new().unwrap();
+        let src_b = TempDir::new().unwrap();
+        let mnt = TempDir::new().unwrap();
+        std::fs::write(src_a.as_path().join("a"), b"a").unwrap();
+        std::fs::write(src_b.as_path().join("b"), b"b").unwrap();
+        let cfg = DaemonConfig::from_toml(&format!(
+            "mountpoint = {:?}\nthreads = 2\n\
+             [[backend]]\npath = \"/a\"\nsource = {:?}\n\
+             [[backend]]\npath = \"/b\"\nsource = {:?}\n",
+            mnt.as_path(),
+            src_a.as_path(),
+            src_b.as_path()
+        ))?;
+
+        let mut daemon = Daemon::new(cfg)?;
+        daemon.start()?;
+        assert_eq!(std::fs::read(mnt.as_path().join("a/a"))?, b"a");
+        assert_eq!(std::fs::read(mnt.as_path().join("b/b"))?, b"b");
+        info!("daemon metrics: {}", daemon.metrics());
+        daemon.stop()?;
+        assert_eq!(daemon.state(), DaemonState::Stopped);
+        Ok(())
+    }
+
+    #[cfg(feature = "daemon")]
+    #[test]
+    #[ignore] // it depends on privileged mode to pass through /dev/fuse
+    fn integration_test_passthrough_daemon_example() -> Result<()> {
+        use std::process::Stdio;
+        use std::time::{Duration, Instant};
+
+        // Examples are built by `cargo test` next to the directory of the test binaries.
+        let exe = std::env::current_exe()?;
+        let example = exe.parent().unwrap().parent().unwrap();
+        let example = example.join("examples/passthrough_daemon");
+        let src = TempDir::new().unwrap();
+        let mnt = TempDir::new().unwrap();
+        std::fs::write(src.as_path().join("a"), b"a")?;
+
+        let child = Command::new(&example)
+            .arg("--shared-dir")
+            .arg(src.as_path())
+            .arg("--mountpoint")
+            .arg(mnt.as_path())
+            .args(["--thread-pool-size", "2", "--cache", "never", "--xattr"])
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let start = Instant::now();
+        while !mnt.as_path().join("a").exists() {
+            assert!(start.elapsed() < Duration::from_secs(10), "not mounted");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(std::fs::read(mnt.as_path().join("a"))?, b"a");
+        std::fs::write(mnt.as_path().join("b"), b"b")?;
+        assert_eq!(std::fs::read(src.as_path().join("b"))?, b"b");
+
+        // Safe because it only sends a signal to the child.
+        assert_eq!(unsafe { libc::kill(child.id() as i32, libc::SIGTERM) }, 0);
+        let output = child.wait_with_output()?;
+        let log = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "{}", log);
+        assert!(log.contains("FUSE INIT"), "{}", log);
+        assert!(!mnt.as_path().join("a").exists());
+        Ok(())
+    }
 }